@@ -33,6 +33,7 @@ pub enum MethodError {
     InvalidArguments(String),
     RequestTooLarge,
     StateMismatch,
+    CannotCalculateChanges,
     AnchorNotFound,
     UnsupportedFilter(String),
     UnsupportedSort(String),
@@ -68,6 +69,7 @@ impl Display for MethodError {
             MethodError::InvalidArguments(err) => write!(f, "Invalid arguments: {}", err),
             MethodError::RequestTooLarge => write!(f, "Request too large"),
             MethodError::StateMismatch => write!(f, "State mismatch"),
+            MethodError::CannotCalculateChanges => write!(f, "Cannot calculate changes"),
             MethodError::AnchorNotFound => write!(f, "Anchor not found"),
             MethodError::UnsupportedFilter(err) => write!(f, "Unsupported filter: {}", err),
             MethodError::UnsupportedSort(err) => write!(f, "Unsupported sort: {}", err),
@@ -114,6 +116,13 @@ impl Serialize for MethodError {
                     "it does not match the current state."
                 ),
             ),
+            MethodError::CannotCalculateChanges => (
+                "cannotCalculateChanges",
+                concat!(
+                    "The server cannot calculate the changes from the state ",
+                    "string given by the client, please resynchronize."
+                ),
+            ),
             MethodError::AnchorNotFound => (
                 "anchorNotFound",
                 concat!(
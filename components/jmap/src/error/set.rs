@@ -42,6 +42,32 @@ pub struct SetError<U> {
     #[serde(rename = "existingId")]
     #[serde(skip_serializing_if = "Option::is_none")]
     existing_id: Option<JMAPId>,
+
+    #[serde(rename = "invalidRecipients")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    invalid_recipients: Option<Vec<String>>,
+
+    #[serde(rename = "invalidRecipientReasons")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    invalid_recipient_reasons: Option<Vec<InvalidRecipient>>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InvalidRecipient {
+    pub email: String,
+    pub reason: InvalidRecipientReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggestion: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum InvalidRecipientReason {
+    #[serde(rename = "invalidSyntax")]
+    InvalidSyntax,
+    #[serde(rename = "domainNotFound")]
+    DomainNotFound,
+    #[serde(rename = "blockedDomain")]
+    BlockedDomain,
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -96,6 +122,8 @@ pub enum SetErrorType {
     InvalidScript,
     #[serde(rename = "scriptIsActive")]
     ScriptIsActive,
+    #[serde(rename = "rolledBack")]
+    RolledBack,
 }
 
 impl SetErrorType {
@@ -126,6 +154,7 @@ impl SetErrorType {
             SetErrorType::AlreadyExists => "alreadyExists",
             SetErrorType::InvalidScript => "invalidScript",
             SetErrorType::ScriptIsActive => "scriptIsActive",
+            SetErrorType::RolledBack => "rolledBack",
         }
     }
 }
@@ -137,6 +166,8 @@ impl<U> SetError<U> {
             description: None,
             properties: None,
             existing_id: None,
+            invalid_recipients: None,
+            invalid_recipient_reasons: None,
         }
     }
 
@@ -160,6 +191,16 @@ impl<U> SetError<U> {
         self
     }
 
+    pub fn with_invalid_recipients(mut self, recipients: Vec<InvalidRecipient>) -> Self {
+        self.invalid_recipients = recipients
+            .iter()
+            .map(|r| r.email.to_string())
+            .collect::<Vec<_>>()
+            .into();
+        self.invalid_recipient_reasons = recipients.into();
+        self
+    }
+
     pub fn invalid_properties() -> Self {
         Self::new(SetErrorType::InvalidProperties)
     }
@@ -23,12 +23,13 @@
 
 use super::Object;
 use crate::{
+    error::method::MethodError,
     request::changes::{ChangesRequest, ChangesResponse},
     types::json_pointer::JSONPointerEval,
     types::state::JMAPState,
 };
 use store::{
-    core::{collection::Collection, error::StoreError},
+    core::collection::Collection,
     log::changes::{Change, Query},
     AccountId, JMAPStore, Store,
 };
@@ -96,11 +97,7 @@ where
                     collection,
                     Query::Since(*change_id),
                 )?
-                .ok_or_else(|| {
-                    StoreError::InvalidArguments(
-                        "The specified stateId does could not be found.".to_string(),
-                    )
-                })?,
+                .ok_or(MethodError::CannotCalculateChanges)?,
             ),
             JMAPState::Intermediate(intermediate_state) => {
                 let mut changelog = self
@@ -109,11 +106,7 @@ where
                         collection,
                         Query::RangeInclusive(intermediate_state.from_id, intermediate_state.to_id),
                     )?
-                    .ok_or_else(|| {
-                        StoreError::InvalidArguments(
-                            "The specified stateId does could not be found.".to_string(),
-                        )
-                    })?;
+                    .ok_or(MethodError::CannotCalculateChanges)?;
                 if intermediate_state.items_sent >= changelog.changes.len() {
                     (
                         0,
@@ -122,11 +115,7 @@ where
                             collection,
                             Query::Since(intermediate_state.to_id),
                         )?
-                        .ok_or_else(|| {
-                            StoreError::InvalidArguments(
-                                "The specified stateId does could not be found.".to_string(),
-                            )
-                        })?,
+                        .ok_or(MethodError::CannotCalculateChanges)?,
                     )
                 } else {
                     changelog.changes.drain(
@@ -136,6 +125,7 @@ where
                     (intermediate_state.items_sent, changelog)
                 }
             }
+            JMAPState::Invalid => return Err(MethodError::CannotCalculateChanges),
         };
 
         let has_more_changes = if max_changes > 0 && changelog.changes.len() > max_changes {
@@ -150,6 +140,7 @@ where
         let mut created = Vec::new();
         let mut updated = Vec::new();
         let mut destroyed = Vec::new();
+        let mut children_updated = Vec::new();
         let mut items_changed = false;
 
         let total_changes = changelog.changes.len();
@@ -162,7 +153,10 @@ where
                         updated.push(item.into())
                     }
                     Change::Delete(item) => destroyed.push(item.into()),
-                    Change::ChildUpdate(item) => updated.push(item.into()),
+                    Change::ChildUpdate(item) => {
+                        children_updated.push(item.into());
+                        updated.push(item.into())
+                    }
                 };
             }
         }
@@ -171,6 +165,7 @@ where
             account_id: request.account_id,
             total_changes,
             has_children_changes: !updated.is_empty() && !items_changed,
+            children_updated,
             has_more_changes,
             old_state: request.since_state,
             new_state: if has_more_changes {
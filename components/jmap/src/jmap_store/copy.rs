@@ -79,6 +79,10 @@ where
             ));
         }
 
+        if request.create.len() > store.config.max_objects_in_set {
+            return Err(MethodError::RequestTooLarge);
+        }
+
         let old_state = store.get_state(account_id, collection)?;
         if let Some(if_in_state) = request.if_in_state.take() {
             if old_state != if_in_state {
@@ -168,4 +168,46 @@ where
         }
         Ok(self.response)
     }
+
+    /// Processes the requested ids in batches of `chunk_size`. The data loaded by
+    /// `chunk_fnc` for a batch is dropped before the next batch is fetched, which
+    /// bounds the memory used by requests with large id lists.
+    pub fn get_chunked<C>(
+        mut self,
+        chunk_size: usize,
+        mut chunk_fnc: impl FnMut(&[JMAPId]) -> crate::Result<C>,
+        mut get_fnc: impl FnMut(JMAPId, usize, &[O::Property], &mut C) -> crate::Result<Option<O>>,
+    ) -> crate::Result<GetResponse<O>> {
+        let mut request_ids = std::mem::take(&mut self.request_ids);
+        if self.validate_ids {
+            let document_ids = &self.document_ids;
+            let not_found = &mut self.response.not_found;
+            request_ids.retain(|id| {
+                if document_ids.contains(id.get_document_id()) {
+                    true
+                } else {
+                    not_found.push(*id);
+                    false
+                }
+            });
+        }
+
+        for ids in request_ids.chunks(std::cmp::max(chunk_size, 1)) {
+            let mut chunk = chunk_fnc(ids)?;
+            for (pos, id) in ids.iter().enumerate() {
+                match get_fnc(*id, pos, &self.properties, &mut chunk) {
+                    Ok(Some(result)) => {
+                        self.response.list.push(result);
+                        continue;
+                    }
+                    Ok(None) | Err(MethodError::NotFound) => (),
+                    Err(err) => {
+                        return Err(err);
+                    }
+                }
+                self.response.not_found.push(*id);
+            }
+        }
+        Ok(self.response)
+    }
 }
@@ -21,7 +21,11 @@
  * for more details.
 */
 
-use std::sync::Arc;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
 
 use store::{
     core::JMAPIdPrefix,
@@ -55,6 +59,7 @@ where
     pub request: QueryRequest<O>,
     pub filter: Filter,
     pub comparator: Comparator,
+    pub snapshot_id: Option<u64>,
 }
 
 pub trait QueryObject: Object {
@@ -65,6 +70,14 @@ pub trait QueryObject: Object {
 
 pub type ExtraFilterFnc = fn(Vec<JMAPId>) -> crate::Result<Vec<JMAPId>>;
 
+/// Documents changed since the results of a query were last evaluated, along
+/// with the unchanged documents that are known to match it.
+#[derive(Debug, Default)]
+pub struct QueryDelta {
+    pub changed: RoaringBitmap,
+    pub matching: RoaringBitmap,
+}
+
 struct QueryState<O: QueryObject> {
     op: LogicalOperator,
     terms: Vec<Filter>,
@@ -85,6 +98,21 @@ where
     ) -> crate::Result<Self> {
         let account_id = request.account_id.into();
         let acl = request.acl.as_ref().unwrap();
+
+        // Snapshots are identified by the query, the pinned state and the
+        // requesting principal, as shared results depend on its permissions.
+        let snapshot_id = request.pinned_query_state.as_ref().map(|state| {
+            let mut hasher = DefaultHasher::new();
+            O::collection().hash(&mut hasher);
+            acl.member_of.hash(&mut hasher);
+            format!(
+                "{:?}{:?}{:?}{}",
+                request.filter, request.sort, request.arguments, state
+            )
+            .hash(&mut hasher);
+            hasher.finish()
+        });
+
         Ok(QueryHelper {
             store,
             account_id,
@@ -98,6 +126,7 @@ where
             request,
             filter: Filter::None,
             comparator: Comparator::None,
+            snapshot_id,
         })
     }
 
@@ -164,6 +193,16 @@ where
         Ok(())
     }
 
+    /// Evaluates the filter against the changed documents only, so that the
+    /// results of a query can be updated without running it again.
+    pub fn apply_delta(&mut self, delta: QueryDelta) {
+        let changed = match std::mem::take(&mut self.filter) {
+            Filter::None => Filter::DocumentSet(delta.changed),
+            filter => Filter::and(vec![filter, Filter::DocumentSet(delta.changed)]),
+        };
+        self.filter = Filter::or(vec![changed, Filter::DocumentSet(delta.matching)]);
+    }
+
     pub fn parse_comparator(
         &mut self,
         mut parse_fnc: impl FnMut(query::Comparator<O::Comparator>) -> crate::Result<Comparator>,
@@ -179,7 +218,7 @@ where
     }
 
     pub fn query<X, W>(
-        self,
+        mut self,
         filter_map_fnc: X,
         extra_filters: Option<W>,
     ) -> crate::Result<QueryResponse>
@@ -206,6 +245,52 @@ where
             }
         }
 
+        // Paginate pinned queries over the results as they were at the pinned state
+        if let Some(snapshot_id) = self.snapshot_id {
+            let pinned_state = self.request.pinned_query_state.clone().unwrap();
+            let snapshot_key = (self.account_id, snapshot_id);
+            let cached_snapshot = self.store.query_snapshots.get(&snapshot_key);
+
+            // Without a snapshot, one can only be taken if nothing changed since the
+            // pinned state. Otherwise the current results are returned and the client
+            // notices the new queryState.
+            if cached_snapshot.is_some() || pinned_state == result.query_state {
+                let snapshot = if let Some(snapshot) = cached_snapshot {
+                    snapshot
+                } else {
+                    let shared_documents = self.shared_documents.clone();
+                    let mut results = self
+                        .store
+                        .query_store::<X>(
+                            self.account_id,
+                            collection,
+                            std::mem::replace(&mut self.filter, Filter::None),
+                            std::mem::replace(&mut self.comparator, Comparator::None),
+                        )?
+                        .set_filter_map(filter_map_fnc)
+                        .into_iter()
+                        .filter(|id| {
+                            shared_documents
+                                .as_ref()
+                                .map_or(true, |s| s.has_access(id.get_document_id()))
+                        })
+                        .map(|id| id.into())
+                        .collect::<Vec<JMAPId>>();
+                    if let Some(mut extra_filters) = extra_filters {
+                        results = extra_filters(results)?;
+                    }
+                    let snapshot = Arc::new(results.into_iter().map(u64::from).collect());
+                    self.store
+                        .query_snapshots
+                        .insert(snapshot_key, snapshot.clone());
+                    snapshot
+                };
+
+                result.query_state = pinned_state;
+                return self.paginate_snapshot(result, &snapshot);
+            }
+        }
+
         let results_it = self.store.query_store::<X>(
             self.account_id,
             collection,
@@ -312,6 +397,44 @@ where
     }
 }
 
+impl<'y, O, T> QueryHelper<'y, O, T>
+where
+    T: for<'x> Store<'x> + 'static,
+    O: QueryObject,
+{
+    fn paginate_snapshot(
+        self,
+        mut result: QueryResponse,
+        snapshot: &[store::JMAPId],
+    ) -> crate::Result<QueryResponse> {
+        let total_results = snapshot.len();
+        let limit = match self.request.limit {
+            Some(limit) if limit > 0 => std::cmp::min(limit, self.store.config.query_max_results),
+            Some(_) => 0,
+            None => self.store.config.query_max_results,
+        };
+
+        if limit > 0 {
+            result.paginate(
+                snapshot.iter().map(|id| JMAPId::new(*id)),
+                limit,
+                self.request.position.unwrap_or(0),
+                self.request.anchor,
+                self.request.anchor_offset.unwrap_or(0),
+            )?;
+            if limit < total_results {
+                result.limit = limit.into();
+            }
+        }
+
+        if self.request.calculate_total.unwrap_or(false) {
+            result.total = Some(total_results);
+        }
+
+        Ok(result)
+    }
+}
+
 impl QueryResponse {
     pub fn paginate<W>(
         &mut self,
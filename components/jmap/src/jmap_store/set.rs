@@ -98,6 +98,13 @@ where
             .take()
             .and_then(|d| d.unwrap_value())
             .unwrap_or_default();
+        if request.create.as_ref().map_or(0, |v| v.len())
+            + request.update.as_ref().map_or(0, |v| v.len())
+            + will_destroy.len()
+            > store.config.max_objects_in_set
+        {
+            return Err(MethodError::RequestTooLarge);
+        }
         Ok(SetHelper {
             store,
             lock: store.lock_collection(account_id, collection),
@@ -320,6 +327,13 @@ where
         Ok(self.response)
     }
 
+    /// Returns the response without writing the pending changes, which the
+    /// caller commits together with the changes of a linked call.
+    pub fn into_deferred_response(mut self) -> (SetResponse<O>, WriteBatch) {
+        let changes = std::mem::replace(&mut self.changes, WriteBatch::new(self.account_id));
+        (self.response, changes)
+    }
+
     pub fn set_created_property(
         &mut self,
         create_id: &str,
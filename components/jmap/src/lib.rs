@@ -32,24 +32,54 @@ pub mod types;
 use error::method::MethodError;
 use store::AccountId;
 
-#[derive(Debug, Clone, serde::Serialize, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub enum URI {
-    #[serde(rename(serialize = "urn:ietf:params:jmap:core"))]
     Core,
-    #[serde(rename(serialize = "urn:ietf:params:jmap:mail"))]
     Mail,
-    #[serde(rename(serialize = "urn:ietf:params:jmap:submission"))]
     Submission,
-    #[serde(rename(serialize = "urn:ietf:params:jmap:vacationresponse"))]
     VacationResponse,
-    #[serde(rename(serialize = "urn:ietf:params:jmap:contacts"))]
     Contacts,
-    #[serde(rename(serialize = "urn:ietf:params:jmap:calendars"))]
     Calendars,
-    #[serde(rename(serialize = "urn:ietf:params:jmap:websocket"))]
     WebSocket,
-    #[serde(rename(serialize = "urn:ietf:params:jmap:sieve"))]
     Sieve,
+    Annotations,
+    ThreadPreview,
+    MailboxCounters,
+    QuerySubscriptions,
+    LinkedSets,
+    Forwarding,
+    Custom(String),
+}
+
+impl URI {
+    pub fn as_str(&self) -> &str {
+        match self {
+            URI::Core => "urn:ietf:params:jmap:core",
+            URI::Mail => "urn:ietf:params:jmap:mail",
+            URI::Submission => "urn:ietf:params:jmap:submission",
+            URI::VacationResponse => "urn:ietf:params:jmap:vacationresponse",
+            URI::Contacts => "urn:ietf:params:jmap:contacts",
+            URI::Calendars => "urn:ietf:params:jmap:calendars",
+            URI::WebSocket => "urn:ietf:params:jmap:websocket",
+            URI::Sieve => "urn:ietf:params:jmap:sieve",
+            URI::Annotations => "urn:stalwart:params:jmap:annotations",
+            URI::ThreadPreview => "urn:stalwart:params:jmap:threadpreview",
+            URI::MailboxCounters => "urn:stalwart:params:jmap:mailboxcounters",
+            URI::QuerySubscriptions => "urn:stalwart:params:jmap:querysubscriptions",
+            URI::LinkedSets => "urn:stalwart:params:jmap:linkedsets",
+            URI::Forwarding => "urn:stalwart:params:jmap:forwarding",
+            URI::Custom(uri) => uri,
+        }
+    }
+}
+
+impl serde::Serialize for URI {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
 }
 
 pub type Result<T> = std::result::Result<T, MethodError>;
@@ -50,6 +50,18 @@ impl Display for Property {
             Property::Members => f.write_str("members"),
             Property::Aliases => f.write_str("aliases"),
             Property::ACL => f.write_str("acl"),
+            Property::OtpAuth => f.write_str("otpAuth"),
+            Property::RecoveryCodes => f.write_str("recoveryCodes"),
+            Property::AppPasswords => f.write_str("appPasswords"),
+            Property::ExpungeTrashDays => f.write_str("expungeTrashDays"),
+            Property::ExpungeJunkDays => f.write_str("expungeJunkDays"),
+            Property::SubAddresses => f.write_str("subAddresses"),
+            Property::Forwarding => f.write_str("forwarding"),
+            Property::LegalHold => f.write_str("legalHold"),
+            Property::Locale => f.write_str("locale"),
+            Property::Onboarded => f.write_str("onboarded"),
+            Property::Passkeys => f.write_str("passkeys"),
+            Property::OtpLastStep => f.write_str("otpLastStep"),
             Property::Invalid => Ok(()),
         }
     }
@@ -78,6 +90,18 @@ impl From<FieldId> for Property {
             11 => Property::Picture,
             12 => Property::Members,
             13 => Property::ACL,
+            15 => Property::OtpAuth,
+            16 => Property::RecoveryCodes,
+            17 => Property::AppPasswords,
+            18 => Property::ExpungeTrashDays,
+            19 => Property::ExpungeJunkDays,
+            20 => Property::SubAddresses,
+            21 => Property::Forwarding,
+            22 => Property::LegalHold,
+            23 => Property::Locale,
+            24 => Property::Onboarded,
+            25 => Property::Passkeys,
+            26 => Property::OtpLastStep,
             _ => Property::Invalid,
         }
     }
@@ -111,6 +135,18 @@ impl Property {
             "picture" => Property::Picture,
             "members" => Property::Members,
             "acl" => Property::ACL,
+            "otpAuth" => Property::OtpAuth,
+            "recoveryCodes" => Property::RecoveryCodes,
+            "appPasswords" => Property::AppPasswords,
+            "expungeTrashDays" => Property::ExpungeTrashDays,
+            "expungeJunkDays" => Property::ExpungeJunkDays,
+            "subAddresses" => Property::SubAddresses,
+            "forwarding" => Property::Forwarding,
+            "legalHold" => Property::LegalHold,
+            "locale" => Property::Locale,
+            "onboarded" => Property::Onboarded,
+            "passkeys" => Property::Passkeys,
+            "otpLastStep" => Property::OtpLastStep,
             _ => Property::Invalid,
         }
     }
@@ -33,7 +33,9 @@ use crate::{
     types::{blob::JMAPBlob, jmap::JMAPId},
 };
 
-use super::schema::{Comparator, Filter, Patch, Principal, Property, Type, Value};
+use super::schema::{
+    Comparator, Filter, Forwarding, Patch, Principal, Property, SubAddress, Type, Value,
+};
 
 impl orm::Value for Value {
     fn index_as(&self) -> orm::Index {
@@ -86,12 +88,22 @@ impl orm::Value for Value {
             Value::Text { value } => value.len(),
             Value::TextList { value } => value.iter().fold(0, |acc, item| acc + item.len()),
             Value::Number { .. } => std::mem::size_of::<i64>(),
+            Value::Bool { .. } => std::mem::size_of::<bool>(),
             Value::Type { .. } => std::mem::size_of::<Type>(),
             Value::DKIM { value } => {
                 value.dkim_selector.as_ref().map(|s| s.len()).unwrap_or(0)
                     + std::mem::size_of::<i64>()
             }
             Value::Members { value } => value.len() * std::mem::size_of::<JMAPId>(),
+            Value::SubAddresses { value } => value.iter().fold(0, |acc, (k, _)| {
+                acc + k.len() + std::mem::size_of::<SubAddress>()
+            }),
+            Value::Forwarding { value } => value
+                .addresses
+                .iter()
+                .fold(std::mem::size_of::<Forwarding>(), |acc, item| {
+                    acc + item.len()
+                }),
             Value::ACL(value) => value.iter().fold(0, |acc, (k, v)| {
                 acc + k.len() + v.len() * std::mem::size_of::<ACL>()
             }),
@@ -167,8 +179,14 @@ impl Object for Principal {
             (Property::Capabilities, 100 * 10),
             (Property::Description, 512),
             (Property::Timezone, 100),
+            (Property::Locale, 35),
             (Property::Secret, 2048),
+            (Property::OtpAuth, 255),
+            (Property::RecoveryCodes, 100 * 64),
+            (Property::AppPasswords, 100 * (255 + 64)),
+            (Property::Passkeys, 20 * (255 + 2048 + 256)),
             (Property::DKIM, 100),
+            (Property::Onboarded, 255),
         ]
     }
 }
@@ -52,6 +52,18 @@ pub enum Property {
     Members = 12,
     ACL = 13,
     Invalid = 14,
+    OtpAuth = 15,
+    RecoveryCodes = 16,
+    AppPasswords = 17,
+    ExpungeTrashDays = 18,
+    ExpungeJunkDays = 19,
+    SubAddresses = 20,
+    Forwarding = 21,
+    LegalHold = 22,
+    Locale = 23,
+    Onboarded = 24,
+    Passkeys = 25,
+    OtpLastStep = 26,
 }
 
 pub const ACCOUNTS_TO_DELETE: u8 = u8::MAX;
@@ -82,6 +94,23 @@ pub struct DKIM {
     pub dkim_expiration: Option<i64>,
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubAddress {
+    #[serde(rename = "isDisabled")]
+    pub is_disabled: bool,
+    #[serde(rename = "autoFile")]
+    pub auto_file: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Forwarding {
+    #[serde(rename = "isEnabled")]
+    pub is_enabled: bool,
+    pub addresses: Vec<String>,
+    #[serde(rename = "keepCopy")]
+    pub keep_copy: bool,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Value {
     Id { value: JMAPId },
@@ -92,6 +121,9 @@ pub enum Value {
     Type { value: Type },
     DKIM { value: DKIM },
     Members { value: Vec<JMAPId> },
+    SubAddresses { value: VecMap<String, SubAddress> },
+    Forwarding { value: Forwarding },
+    Bool { value: bool },
     ACL(VecMap<String, Vec<ACL>>),
     Patch(Patch),
     Null,
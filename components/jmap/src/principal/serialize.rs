@@ -49,10 +49,13 @@ impl Serialize for Principal {
                 Value::Null => map.serialize_entry(name, &None::<&str>)?,
                 Value::TextList { value } => map.serialize_entry(name, value)?,
                 Value::Number { value } => map.serialize_entry(name, value)?,
+                Value::Bool { value } => map.serialize_entry(name, value)?,
                 Value::Type { value } => map.serialize_entry(name, value)?,
                 Value::Members { value } => map.serialize_entry(name, value)?,
                 Value::Blob { value } => map.serialize_entry(name, value)?,
                 Value::DKIM { value } => map.serialize_entry(name, value)?,
+                Value::SubAddresses { value } => map.serialize_entry(name, value)?,
+                Value::Forwarding { value } => map.serialize_entry(name, value)?,
                 Value::ACL(value) => map.serialize_entry(name, value)?,
                 Value::Patch(_) => (),
             }
@@ -112,6 +115,16 @@ impl<'de> serde::de::Visitor<'de> for PrincipalVisitor {
                         },
                     );
                 }
+                "locale" => {
+                    properties.append(
+                        Property::Locale,
+                        if let Some(value) = map.next_value::<Option<String>>()? {
+                            Value::Text { value }
+                        } else {
+                            Value::Null
+                        },
+                    );
+                }
                 "email" => {
                     properties.append(
                         Property::Email,
@@ -160,6 +173,46 @@ impl<'de> serde::de::Visitor<'de> for PrincipalVisitor {
                         },
                     );
                 }
+                "otpAuth" => {
+                    properties.append(
+                        Property::OtpAuth,
+                        if let Some(value) = map.next_value::<Option<String>>()? {
+                            Value::Text { value }
+                        } else {
+                            Value::Null
+                        },
+                    );
+                }
+                "appPasswords" => {
+                    properties.append(
+                        Property::AppPasswords,
+                        if let Some(value) = map.next_value::<Option<Vec<String>>>()? {
+                            Value::TextList { value }
+                        } else {
+                            Value::Null
+                        },
+                    );
+                }
+                "passkeys" => {
+                    properties.append(
+                        Property::Passkeys,
+                        if let Some(value) = map.next_value::<Option<Vec<String>>>()? {
+                            Value::TextList { value }
+                        } else {
+                            Value::Null
+                        },
+                    );
+                }
+                "onboarded" => {
+                    properties.append(
+                        Property::Onboarded,
+                        if let Some(value) = map.next_value::<Option<Vec<String>>>()? {
+                            Value::TextList { value }
+                        } else {
+                            Value::Null
+                        },
+                    );
+                }
                 "dkim" => {
                     properties.append(
                         Property::DKIM,
@@ -192,6 +245,32 @@ impl<'de> serde::de::Visitor<'de> for PrincipalVisitor {
                         },
                     );
                 }
+                "expungeTrashDays" | "expungeJunkDays" => {
+                    properties.append(
+                        if key == "expungeTrashDays" {
+                            Property::ExpungeTrashDays
+                        } else {
+                            Property::ExpungeJunkDays
+                        },
+                        if let Some(value) = map.next_value::<Option<u64>>()? {
+                            Value::Number {
+                                value: value as i64,
+                            }
+                        } else {
+                            Value::Null
+                        },
+                    );
+                }
+                "legalHold" => {
+                    properties.append(
+                        Property::LegalHold,
+                        if let Some(value) = map.next_value::<Option<bool>>()? {
+                            Value::Bool { value }
+                        } else {
+                            Value::Null
+                        },
+                    );
+                }
                 "picture" => {
                     properties.append(
                         Property::Picture,
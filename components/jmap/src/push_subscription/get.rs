@@ -43,6 +43,7 @@ impl GetObject for PushSubscription {
             Property::VerificationCode,
             Property::Expires,
             Property::Types,
+            Property::MailboxCounters,
         ]
     }
 
@@ -86,6 +87,9 @@ where
                     *property,
                     match property {
                         Property::Id => Value::Id { value: id },
+                        Property::MailboxCounters => fields
+                            .remove(property)
+                            .unwrap_or(Value::Bool { value: false }),
                         _ => fields.remove(property).unwrap_or_default(),
                     },
                 );
@@ -51,6 +51,7 @@ pub enum Value {
     Types { value: Vec<TypeState> },
     Keys { value: Keys },
     Null,
+    Bool { value: bool },
 }
 
 impl Value {
@@ -97,6 +98,7 @@ impl orm::Value for Value {
             Value::Types { value } => value.len() * std::mem::size_of::<TypeState>(),
             Value::Keys { value } => value.auth.len() + value.p256dh.len(),
             Value::Null => 0,
+            Value::Bool { .. } => std::mem::size_of::<bool>(),
         }
     }
 }
@@ -118,6 +120,7 @@ pub enum Property {
     Expires = 5,
     Types = 6,
     VerificationCode_ = 7,
+    MailboxCounters = 8,
 }
 
 impl Property {
@@ -130,6 +133,7 @@ impl Property {
             "verificationCode" => Property::VerificationCode,
             "expires" => Property::Expires,
             "types" => Property::Types,
+            "mailboxCounters" => Property::MailboxCounters,
             _ => Property::VerificationCode_,
         }
     }
@@ -145,6 +149,7 @@ impl Display for Property {
             Property::VerificationCode => write!(f, "verificationCode"),
             Property::Expires => write!(f, "expires"),
             Property::Types => write!(f, "types"),
+            Property::MailboxCounters => write!(f, "mailboxCounters"),
             Property::VerificationCode_ => Ok(()),
         }
     }
@@ -167,6 +172,7 @@ impl From<FieldId> for Property {
             5 => Property::Expires,
             6 => Property::Types,
             7 => Property::VerificationCode_,
+            8 => Property::MailboxCounters,
             _ => Property::VerificationCode_,
         }
     }
@@ -81,6 +81,7 @@ impl Serialize for PushSubscription {
                 Value::Types { value } => map.serialize_entry(name, value)?,
                 Value::Keys { value } => map.serialize_entry(name, value)?,
                 Value::Null => map.serialize_entry(name, &())?,
+                Value::Bool { value } => map.serialize_entry(name, value)?,
             }
         }
 
@@ -165,6 +166,16 @@ impl<'de> serde::de::Visitor<'de> for PushSubscriptionVisitor {
                         },
                     );
                 }
+                "mailboxCounters" => {
+                    properties.append(
+                        Property::MailboxCounters,
+                        if let Some(value) = map.next_value::<Option<bool>>()? {
+                            Value::Bool { value }
+                        } else {
+                            Value::Null
+                        },
+                    );
+                }
                 _ => {
                     map.next_value::<IgnoredAny>()?;
                 }
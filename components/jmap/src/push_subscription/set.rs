@@ -105,11 +105,13 @@ where
                             continue;
                         }
                         (Property::Types, value @ Value::Types { .. }) => value,
+                        (Property::MailboxCounters, value @ Value::Bool { .. }) => value,
                         (
                             Property::Keys
                             | Property::Expires
                             | Property::Types
-                            | Property::VerificationCode,
+                            | Property::VerificationCode
+                            | Property::MailboxCounters,
                             Value::Null,
                         ) => Value::Null,
                         (property, _) => {
@@ -173,6 +175,7 @@ where
                             continue;
                         }
                         (Property::Types, value @ Value::Types { .. }) => value,
+                        (Property::MailboxCounters, value @ Value::Bool { .. }) => value,
                         (Property::VerificationCode, Value::Text { value }) => {
                             if current_fields.get(&Property::VerificationCode_).map_or(
                                 false,
@@ -191,7 +194,7 @@ where
                             expires = (Utc::now().timestamp() + EXPIRES_MAX).into();
                             continue;
                         }
-                        (Property::Types, Value::Null) => Value::Null,
+                        (Property::Types | Property::MailboxCounters, Value::Null) => Value::Null,
                         (property, _) => {
                             return Err(SetError::invalid_properties()
                                 .with_property(property)
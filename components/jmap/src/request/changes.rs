@@ -74,6 +74,9 @@ pub struct ChangesResponse<O: ChangesObject> {
     pub total_changes: usize,
     #[serde(skip)]
     pub has_children_changes: bool,
+    // Updated ids whose only changes were to their children
+    #[serde(skip)]
+    pub children_updated: Vec<JMAPId>,
 }
 
 impl<O: ChangesObject> ChangesResponse<O> {
@@ -89,6 +92,7 @@ impl<O: ChangesObject> ChangesResponse<O> {
             arguments: O::ChangesResponse::default(),
             total_changes: 0,
             has_children_changes: false,
+            children_updated: Vec::with_capacity(0),
         }
     }
 }
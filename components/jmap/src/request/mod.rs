@@ -164,7 +164,7 @@ impl MaybeIdReference {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Method {
     Echo,
     CopyBlob,
@@ -200,9 +200,14 @@ pub enum Method {
     SetSieveScript,
     QuerySieveScript,
     ValidateSieveScript,
+    GetSubAddress,
+    SetSubAddress,
+    GetForwarding,
+    SetForwarding,
     GetPrincipal,
     SetPrincipal,
     QueryPrincipal,
+    Custom(String),
     Error,
 }
 
@@ -213,6 +218,7 @@ impl serde::Serialize for Method {
         S: serde::Serializer,
     {
         serializer.serialize_str(match self {
+            Method::Custom(name) => name,
             Method::Echo => "Core/echo",
             Method::CopyBlob => "Blob/copy",
             Method::GetPushSubscription => "PushSubscription/get",
@@ -247,6 +253,10 @@ impl serde::Serialize for Method {
             Method::SetSieveScript => "SieveScript/set",
             Method::QuerySieveScript => "SieveScript/query",
             Method::ValidateSieveScript => "SieveScript/validate",
+            Method::GetSubAddress => "SubAddress/get",
+            Method::SetSubAddress => "SubAddress/set",
+            Method::GetForwarding => "Forwarding/get",
+            Method::SetForwarding => "Forwarding/set",
             Method::GetPrincipal => "Principal/get",
             Method::SetPrincipal => "Principal/set",
             Method::QueryPrincipal => "Principal/query",
@@ -303,10 +313,15 @@ impl<'de> serde::de::Visitor<'de> for MethodVisitor {
             "SieveScript/set" => Method::SetSieveScript,
             "SieveScript/query" => Method::QuerySieveScript,
             "SieveScript/validate" => Method::ValidateSieveScript,
+            "SubAddress/get" => Method::GetSubAddress,
+            "SubAddress/set" => Method::SetSubAddress,
+            "Forwarding/get" => Method::GetForwarding,
+            "Forwarding/set" => Method::SetForwarding,
             "Principal/get" => Method::GetPrincipal,
             "Principal/set" => Method::SetPrincipal,
             "Principal/query" => Method::QueryPrincipal,
-            _ => Method::Error,
+            "error" => Method::Error,
+            _ => Method::Custom(v.to_string()),
         })
     }
 }
@@ -72,6 +72,11 @@ pub struct QueryRequest<O: QueryObject> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub calculate_total: Option<bool>,
 
+    // Non-standard: paginate over the results as they were at this query state
+    #[serde(rename = "pinnedQueryState")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned_query_state: Option<JMAPState>,
+
     #[serde(flatten)]
     pub arguments: O::QueryArguments,
 }
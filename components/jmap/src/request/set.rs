@@ -22,7 +22,7 @@
 */
 
 use crate::error::method::MethodError;
-use crate::error::set::SetError;
+use crate::error::set::{SetError, SetErrorType};
 use crate::jmap_store::set::SetObject;
 use crate::request::ArgumentDeserializer;
 use crate::types::jmap::JMAPId;
@@ -222,6 +222,32 @@ impl<O: SetObject> SetResponse<O> {
     pub fn next_call(&mut self) -> Option<O::NextCall> {
         self.next_call.take()
     }
+
+    /// Reports all changes in this response as not applied, used when they
+    /// were discarded together with a linked call that failed.
+    pub fn rollback(&mut self, description: &'static str) {
+        for (create_id, _) in std::mem::take(&mut self.created) {
+            self.not_created.append(
+                create_id,
+                SetError::new(SetErrorType::RolledBack).with_description(description),
+            );
+        }
+        for (id, _) in std::mem::take(&mut self.updated) {
+            self.not_updated.append(
+                id,
+                SetError::new(SetErrorType::RolledBack).with_description(description),
+            );
+        }
+        for id in std::mem::take(&mut self.destroyed) {
+            self.not_destroyed.append(
+                id,
+                SetError::new(SetErrorType::RolledBack).with_description(description),
+            );
+        }
+        self.new_state = self.old_state.clone();
+        self.change_id = None;
+        self.state_changes = None;
+    }
 }
 
 // Deserialize
@@ -25,6 +25,8 @@ use std::fmt;
 
 use serde::Deserialize;
 
+use super::jmap::JMAPId;
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize)]
 pub enum JSONPointer {
     Root,
@@ -38,6 +40,52 @@ pub trait JSONPointerEval {
     fn eval_json_pointer(&self, ptr: &JSONPointer) -> Option<Vec<u64>>;
 }
 
+// Used to evaluate result references to responses of custom methods,
+// all JMAP ids found at the pointer location are returned.
+impl JSONPointerEval for serde_json::Value {
+    fn eval_json_pointer(&self, ptr: &JSONPointer) -> Option<Vec<u64>> {
+        let path = match ptr {
+            JSONPointer::Path(path) => path.as_slice(),
+            JSONPointer::Root => &[],
+            _ => std::slice::from_ref(ptr),
+        };
+        let mut values = vec![self];
+
+        for item in path {
+            let mut next_values = Vec::with_capacity(values.len());
+            for value in values {
+                match (item, value) {
+                    (JSONPointer::String(key), serde_json::Value::Object(map)) => {
+                        next_values.push(map.get(key)?);
+                    }
+                    (JSONPointer::Number(pos), serde_json::Value::Array(list)) => {
+                        next_values.push(list.get(*pos as usize)?);
+                    }
+                    (JSONPointer::Wildcard, serde_json::Value::Array(list)) => {
+                        next_values.extend(list.iter());
+                    }
+                    _ => return None,
+                }
+            }
+            values = next_values;
+        }
+
+        let mut ids = Vec::new();
+        for value in values {
+            match value {
+                serde_json::Value::String(id) => ids.push(JMAPId::parse(id)?.into()),
+                serde_json::Value::Array(list) => {
+                    for id in list {
+                        ids.push(JMAPId::parse(id.as_str()?)?.into());
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(ids)
+    }
+}
+
 impl JSONPointer {
     pub fn parse(value: &str) -> Option<JSONPointer> {
         let mut path = Vec::new();
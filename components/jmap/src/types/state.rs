@@ -21,14 +21,27 @@
  * for more details.
 */
 
+use std::io::Write;
+
 use store::{
+    blake3,
     log::changes::ChangeId,
+    parking_lot::{const_rwlock, RwLock},
     serialize::{
         base32::{Base32Reader, Base32Writer},
-        leb128::{Leb128Iterator, Leb128Writer},
+        leb128::{Leb128Iterator, Leb128Vec},
     },
 };
 
+const SIGNATURE_LEN: usize = 8;
+
+static STATE_SIGNER: RwLock<Option<StateSigner>> = const_rwlock(None);
+
+struct StateSigner {
+    key: [u8; 32],
+    accept_unsigned: bool,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct JMAPIntermediateState {
     pub from_id: ChangeId,
@@ -41,6 +54,7 @@ pub enum JMAPState {
     Initial,
     Exact(ChangeId),
     Intermediate(JMAPIntermediateState),
+    Invalid,
 }
 
 impl Default for JMAPState {
@@ -76,19 +90,67 @@ impl JMAPState {
         match self {
             JMAPState::Exact(id) => *id,
             JMAPState::Intermediate(intermediate) => intermediate.to_id,
-            JMAPState::Initial => ChangeId::MAX,
+            JMAPState::Initial | JMAPState::Invalid => ChangeId::MAX,
         }
     }
 
+    /// Enables signing of state strings with a key derived from the server secret.
+    /// Unsigned states issued by previous versions are still accepted unless
+    /// `accept_unsigned` is disabled.
+    pub fn init_signing(secret: &str, accept_unsigned: bool) {
+        *STATE_SIGNER.write() = Some(StateSigner {
+            key: blake3::derive_key("Stalwart JMAP state signing key", secret.as_bytes()),
+            accept_unsigned,
+        });
+    }
+
     pub fn parse(id: &str) -> Option<Self> {
         match id.as_bytes().first()? {
             b'n' => JMAPState::Initial.into(),
-            b's' => {
-                JMAPState::Exact(Base32Reader::new(id.get(1..)?.as_bytes()).next_leb128()?).into()
+            b'v' => {
+                let bytes = Base32Reader::new(id.get(1..)?.as_bytes()).collect::<Vec<_>>();
+                if bytes.len() <= SIGNATURE_LEN {
+                    return JMAPState::Invalid.into();
+                }
+                let (payload, signature) = bytes.split_at(bytes.len() - SIGNATURE_LEN);
+                let is_valid = STATE_SIGNER.read().as_ref().map_or(false, |signer| {
+                    sign(&signer.key, payload)
+                        .iter()
+                        .zip(signature)
+                        .fold(0, |acc, (a, b)| acc | (a ^ b))
+                        == 0
+                });
+                if is_valid {
+                    JMAPState::parse_payload(payload[0], payload[1..].iter())
+                        .unwrap_or(JMAPState::Invalid)
+                        .into()
+                } else {
+                    JMAPState::Invalid.into()
+                }
             }
-            b'r' => {
-                let mut it = Base32Reader::new(id.get(1..)?.as_bytes());
+            prefix @ (b's' | b'r') => {
+                if STATE_SIGNER
+                    .read()
+                    .as_ref()
+                    .map_or(true, |signer| signer.accept_unsigned)
+                {
+                    JMAPState::parse_payload(*prefix, Base32Reader::new(id.get(1..)?.as_bytes()))
+                } else {
+                    JMAPState::Invalid.into()
+                }
+            }
+            _ => None,
+        }
+    }
 
+    fn parse_payload<I, T>(prefix: u8, mut it: T) -> Option<Self>
+    where
+        T: Leb128Iterator<I>,
+        I: std::borrow::Borrow<u8>,
+    {
+        match prefix {
+            b's' => JMAPState::Exact(it.next_leb128()?).into(),
+            b'r' => {
                 let from_id = it.next_leb128::<ChangeId>()?;
                 let to_id = from_id.checked_add(it.next_leb128()?)?;
                 let items_sent = it.next_leb128()?;
@@ -147,30 +209,47 @@ impl<'de> serde::Deserialize<'de> for JMAPState {
 
 impl std::fmt::Display for JMAPState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut writer = Base32Writer::with_capacity(10);
+        let mut payload = Vec::with_capacity(10);
 
         match self {
             JMAPState::Initial => {
-                writer.push_char('n');
+                return f.write_str("n");
             }
             JMAPState::Exact(id) => {
-                writer.push_char('s');
-                writer.write_leb128(*id).unwrap();
+                payload.push(b's');
+                payload.push_leb128(*id);
             }
             JMAPState::Intermediate(intermediate) => {
-                writer.push_char('r');
-                writer.write_leb128(intermediate.from_id).unwrap();
-                writer
-                    .write_leb128(intermediate.to_id - intermediate.from_id)
-                    .unwrap();
-                writer.write_leb128(intermediate.items_sent).unwrap();
+                payload.push(b'r');
+                payload.push_leb128(intermediate.from_id);
+                payload.push_leb128(intermediate.to_id - intermediate.from_id);
+                payload.push_leb128(intermediate.items_sent);
+            }
+            JMAPState::Invalid => {
+                return f.write_str("x");
             }
         }
 
+        let mut writer = Base32Writer::with_capacity(payload.len() * 2);
+        if let Some(signer) = STATE_SIGNER.read().as_ref() {
+            writer.push_char('v');
+            writer.write_all(&payload).unwrap();
+            writer.write_all(&sign(&signer.key, &payload)).unwrap();
+        } else {
+            writer.push_char(payload[0] as char);
+            writer.write_all(&payload[1..]).unwrap();
+        }
+
         f.write_str(&writer.finalize())
     }
 }
 
+fn sign(key: &[u8; 32], payload: &[u8]) -> [u8; SIGNATURE_LEN] {
+    let mut signature = [0u8; SIGNATURE_LEN];
+    signature.copy_from_slice(&blake3::keyed_hash(key, payload).as_bytes()[..SIGNATURE_LEN]);
+    signature
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -197,4 +276,37 @@ mod tests {
             assert_eq!(JMAPState::parse(&id.to_string()).unwrap(), id);
         }
     }
+
+    #[test]
+    fn test_signed_state_id() {
+        let legacy_id = JMAPState::new_exact(12345678).to_string();
+        JMAPState::init_signing("secret", true);
+
+        for id in [
+            JMAPState::new_exact(0),
+            JMAPState::new_exact(12345678),
+            JMAPState::new_intermediate(1024, 2048, 100),
+        ] {
+            let id_str = id.to_string();
+            assert!(id_str.starts_with('v'));
+            assert_eq!(JMAPState::parse(&id_str).unwrap(), id);
+
+            // Tampering with any character (except the trailing padding) invalidates the state
+            for pos in 1..id_str.len() - 1 {
+                let mut tampered = id_str.clone().into_bytes();
+                tampered[pos] = if tampered[pos] == b'a' { b'b' } else { b'a' };
+                let tampered = String::from_utf8(tampered).unwrap();
+                assert!(matches!(
+                    JMAPState::parse(&tampered),
+                    Some(JMAPState::Invalid) | None
+                ));
+            }
+        }
+
+        // States issued before signing was enabled are still accepted
+        assert_eq!(
+            JMAPState::parse(&legacy_id).unwrap(),
+            JMAPState::new_exact(12345678)
+        );
+    }
 }
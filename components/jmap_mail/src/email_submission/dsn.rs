@@ -0,0 +1,407 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use super::schema::{set_delivery_status, Delivered, EmailSubmission, Property, Value};
+use crate::mail::schema::{Email, Property as EmailProperty};
+use jmap::orm::{serialize::JMAPOrm, TinyORM};
+use jmap::types::blob::JMAPBlob;
+use mail_parser::{HeaderName, HeaderValue, Message, MessagePart, RfcHeader};
+use store::blob::BlobId;
+use store::core::collection::Collection;
+use store::core::document::Document;
+use store::write::batch::WriteBatch;
+use store::write::options::IndexOptions;
+use store::{blake3, AccountId, DocumentId, JMAPStore, Store};
+
+/// Delivery status notification (RFC 3464) extracted from a bounce message.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DeliveryReport {
+    pub envelope_id: Option<String>,
+    pub recipients: Vec<RecipientReport>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RecipientReport {
+    pub recipient: String,
+    pub action: String,
+    pub status: String,
+    pub diagnostic_code: Option<String>,
+}
+
+/// Submission a bounce was matched to, along with the thread and mailboxes
+/// of the original email. `mailbox_ids` is empty if the email was deleted.
+pub struct BouncedEmail {
+    pub email_submission_id: DocumentId,
+    pub thread_id: DocumentId,
+    pub mailbox_ids: Vec<DocumentId>,
+}
+
+impl DeliveryReport {
+    /// Parses the message/delivery-status part of a bounce.
+    pub fn parse(message: &Message, raw_message: &[u8]) -> Option<Self> {
+        message
+            .parts
+            .iter()
+            .skip(1)
+            .find_map(|part| match content_type(part) {
+                Some(("message", Some(subtype)))
+                    if subtype.eq_ignore_ascii_case("delivery-status")
+                        || subtype.eq_ignore_ascii_case("global-delivery-status") =>
+                {
+                    DeliveryReport::parse_status(
+                        raw_message.get(part.offset_body..part.offset_end)?,
+                    )
+                }
+                _ => None,
+            })
+    }
+
+    fn parse_status(contents: &[u8]) -> Option<Self> {
+        let contents = String::from_utf8_lossy(contents);
+        let mut groups = field_groups(&contents).into_iter();
+        let mut report = DeliveryReport::default();
+
+        for (name, value) in groups.next()? {
+            if name.eq_ignore_ascii_case("Original-Envelope-Id") {
+                report.envelope_id = value.into();
+            }
+        }
+
+        for fields in groups {
+            let mut recipient = RecipientReport::default();
+            let mut original_recipient = None;
+            for (name, value) in fields {
+                if name.eq_ignore_ascii_case("Final-Recipient") {
+                    recipient.recipient = field_address(&value);
+                } else if name.eq_ignore_ascii_case("Original-Recipient") {
+                    original_recipient = field_address(&value).into();
+                } else if name.eq_ignore_ascii_case("Action") {
+                    recipient.action = value.to_ascii_lowercase();
+                } else if name.eq_ignore_ascii_case("Status") {
+                    recipient.status = value;
+                } else if name.eq_ignore_ascii_case("Diagnostic-Code") {
+                    recipient.diagnostic_code = value
+                        .split_once(';')
+                        .map_or(value.as_str(), |(_, code)| code)
+                        .trim()
+                        .to_string()
+                        .into();
+                }
+            }
+            if recipient.recipient.is_empty() {
+                recipient.recipient = original_recipient.unwrap_or_default();
+            }
+            if !recipient.recipient.is_empty() && !recipient.action.is_empty() {
+                report.recipients.push(recipient);
+            }
+        }
+
+        if !report.recipients.is_empty() {
+            Some(report)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_failure(&self) -> bool {
+        self.recipients.iter().any(|r| r.action == "failed")
+    }
+}
+
+pub trait JMAPEmailSubmissionDsn<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn email_submission_bounce(
+        &self,
+        batch: &mut WriteBatch,
+        report: &DeliveryReport,
+        blob_id: &BlobId,
+    ) -> store::Result<Option<BouncedEmail>>;
+
+    fn email_submission_find_bounced(
+        &self,
+        account_id: AccountId,
+        report: &DeliveryReport,
+    ) -> store::Result<Option<(DocumentId, TinyORM<EmailSubmission>)>>;
+}
+
+impl<T> JMAPEmailSubmissionDsn<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn email_submission_bounce(
+        &self,
+        batch: &mut WriteBatch,
+        report: &DeliveryReport,
+        blob_id: &BlobId,
+    ) -> store::Result<Option<BouncedEmail>> {
+        let account_id = batch.account_id;
+        let (document_id, current_fields) =
+            if let Some(result) = self.email_submission_find_bounced(account_id, report)? {
+                result
+            } else {
+                return Ok(None);
+            };
+
+        // Ignore reports about addresses the message was not sent to
+        let rcpt_to = match current_fields.get(&Property::Envelope) {
+            Some(Value::Envelope { value }) => &value.rcpt_to,
+            _ => return Ok(None),
+        };
+        let recipients = report
+            .recipients
+            .iter()
+            .filter(|r| {
+                rcpt_to
+                    .iter()
+                    .any(|rcpt| rcpt.email.eq_ignore_ascii_case(&r.recipient))
+            })
+            .collect::<Vec<_>>();
+        if recipients.is_empty() {
+            return Ok(None);
+        }
+        let mut fields = TinyORM::track_changes(&current_fields);
+
+        // Mark failed recipients as not delivered
+        if let Some(Value::DeliveryStatus { value }) = current_fields.get(&Property::DeliveryStatus)
+        {
+            let mut delivery_status = value.clone();
+            for recipient in recipients.into_iter().filter(|r| r.action == "failed") {
+                if let Some(status) = delivery_status
+                    .iter_mut()
+                    .find(|(rcpt, _)| rcpt.eq_ignore_ascii_case(&recipient.recipient))
+                    .map(|(_, status)| status)
+                {
+                    status.delivered = Delivered::No;
+                    status.smtp_reply = recipient
+                        .diagnostic_code
+                        .as_ref()
+                        .unwrap_or(&recipient.status)
+                        .to_string();
+                    status.touch();
+                }
+            }
+            set_delivery_status(&current_fields, &mut fields, delivery_status);
+        }
+
+        // Keep the bounce as a DSN of this submission
+        let mut dsn_blob_ids = match current_fields.get(&Property::DsnBlobIds) {
+            Some(Value::BlobIds { value }) => value.clone(),
+            _ => Vec::new(),
+        };
+        let mut document = Document::new(Collection::EmailSubmission, document_id);
+        if !dsn_blob_ids.iter().any(|blob| &blob.id == blob_id) {
+            dsn_blob_ids.push(JMAPBlob::new(blob_id.clone()));
+            document.blob(blob_id.clone(), IndexOptions::new());
+        }
+        fields.set(
+            Property::DsnBlobIds,
+            Value::BlobIds {
+                value: dsn_blob_ids,
+            },
+        );
+
+        // Obtain the mailboxes and thread of the original email, if it still exists
+        let (thread_id, mailbox_ids) = match current_fields.get(&Property::EmailId) {
+            Some(Value::Id { value }) => (
+                value.get_prefix_id(),
+                self.get_orm::<Email>(account_id, value.get_document_id())?
+                    .and_then(|email| {
+                        email
+                            .get_tags(&EmailProperty::MailboxIds)
+                            .map(|tags| tags.iter().map(|tag| tag.as_id()).collect::<Vec<_>>())
+                    })
+                    .unwrap_or_default(),
+            ),
+            _ => (DocumentId::MAX, Vec::new()),
+        };
+
+        current_fields.merge(&mut document, fields)?;
+        if !document.is_empty() {
+            batch.update_document(document);
+            batch.log_update(Collection::EmailSubmission, document_id);
+        }
+
+        Ok(Some(BouncedEmail {
+            email_submission_id: document_id,
+            thread_id,
+            mailbox_ids,
+        }))
+    }
+
+    fn email_submission_find_bounced(
+        &self,
+        account_id: AccountId,
+        report: &DeliveryReport,
+    ) -> store::Result<Option<(DocumentId, TinyORM<EmailSubmission>)>> {
+        // Only envelope ids issued by this server are trusted, anyone can
+        // send a bounce quoting the Message-ID of a submitted email.
+        match report
+            .envelope_id
+            .as_deref()
+            .and_then(|envelope_id| parse_envelope_id(&self.config.srs_secret, envelope_id))
+        {
+            Some((envelope_account_id, document_id)) if envelope_account_id == account_id => {
+                Ok(self
+                    .get_orm::<EmailSubmission>(account_id, document_id)?
+                    .map(|fields| (document_id, fields)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Returns the envelope id (RFC 3461) a submission is relayed with. It is
+/// signed with the server secret so that bounces can be matched to the
+/// submission without trusting the contents of the report.
+pub fn envelope_id(secret: &str, account_id: AccountId, document_id: DocumentId) -> String {
+    format!(
+        "{:x}.{:x}.{}",
+        account_id,
+        document_id,
+        envelope_id_hash(secret, account_id, document_id)
+    )
+}
+
+fn parse_envelope_id(secret: &str, envelope_id: &str) -> Option<(AccountId, DocumentId)> {
+    if secret.is_empty() {
+        return None;
+    }
+    let mut parts = envelope_id.trim().splitn(3, '.');
+    let account_id = AccountId::from_str_radix(parts.next()?, 16).ok()?;
+    let document_id = DocumentId::from_str_radix(parts.next()?, 16).ok()?;
+    if parts.next()? == envelope_id_hash(secret, account_id, document_id) {
+        Some((account_id, document_id))
+    } else {
+        None
+    }
+}
+
+fn envelope_id_hash(secret: &str, account_id: AccountId, document_id: DocumentId) -> String {
+    let mut hasher = blake3::Hasher::new_keyed(blake3::hash(secret.as_bytes()).as_bytes());
+    hasher.update(b"envid");
+    hasher.update(&account_id.to_be_bytes());
+    hasher.update(&document_id.to_be_bytes());
+    hasher.finalize().as_bytes()[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn content_type<'x>(part: &'x MessagePart) -> Option<(&'x str, Option<&'x str>)> {
+    part.headers.iter().rev().find_map(|header| {
+        if let (HeaderName::Rfc(RfcHeader::ContentType), HeaderValue::ContentType(ct)) =
+            (&header.name, &header.value)
+        {
+            Some((ct.c_type.as_ref(), ct.c_subtype.as_deref()))
+        } else {
+            None
+        }
+    })
+}
+
+/// Splits a block of header-like fields into groups separated by blank lines,
+/// unfolding continuation lines.
+fn field_groups(contents: &str) -> Vec<Vec<(String, String)>> {
+    let mut groups = Vec::new();
+    let mut fields: Vec<(String, String)> = Vec::new();
+
+    for line in contents.lines() {
+        if line.trim().is_empty() {
+            if !fields.is_empty() {
+                groups.push(std::mem::take(&mut fields));
+            }
+        } else if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    if !fields.is_empty() {
+        groups.push(fields);
+    }
+
+    groups
+}
+
+/// Returns the address of a field such as `rfc822; jane@example.org`.
+fn field_address(value: &str) -> String {
+    value
+        .split_once(';')
+        .map_or(value, |(_, address)| address)
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeliveryReport;
+
+    #[test]
+    fn parse_delivery_status() {
+        let report = DeliveryReport::parse_status(
+            concat!(
+                "Reporting-MTA: dns; mx.example.org\r\n",
+                "Original-Envelope-Id: 1234abcd\r\n",
+                "\r\n",
+                "Final-Recipient: rfc822; jane@example.org\r\n",
+                "Action: failed\r\n",
+                "Status: 5.1.1\r\n",
+                "Diagnostic-Code: smtp; 550 5.1.1 <jane@example.org>:\r\n",
+                " Recipient address rejected\r\n",
+                "\r\n",
+                "Final-Recipient: rfc822; john@example.org\r\n",
+                "Action: delayed\r\n",
+                "Status: 4.4.1\r\n",
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(report.envelope_id.as_deref(), Some("1234abcd"));
+        assert_eq!(report.recipients.len(), 2);
+        assert_eq!(report.recipients[0].recipient, "jane@example.org");
+        assert_eq!(report.recipients[0].action, "failed");
+        assert_eq!(
+            report.recipients[0].diagnostic_code.as_deref(),
+            Some("550 5.1.1 <jane@example.org>: Recipient address rejected")
+        );
+        assert_eq!(report.recipients[1].action, "delayed");
+        assert!(report.is_failure());
+
+        let envelope_id = super::envelope_id("secret", 1, 2);
+        assert_eq!(
+            super::parse_envelope_id("secret", &envelope_id),
+            Some((1, 2))
+        );
+        assert_eq!(super::parse_envelope_id("other", &envelope_id), None);
+        assert_eq!(super::parse_envelope_id("", &envelope_id), None);
+        assert_eq!(super::parse_envelope_id("secret", "1.2.abcd"), None);
+    }
+}
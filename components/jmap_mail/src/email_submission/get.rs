@@ -94,16 +94,29 @@ where
                     StoreError::NotFound("EmailSubmission data not found".to_string())
                 })?;
             let mut email_submission = VecMap::with_capacity(properties.len());
+            let delivery_updated = fields.remove(&Property::DeliveryUpdated);
 
             for property in properties {
                 email_submission.append(
                     *property,
-                    if let Property::Id = property {
-                        Value::Id { value: id }
-                    } else if let Some(value) = fields.remove(property) {
-                        value
-                    } else {
-                        Value::Null
+                    match (property, fields.remove(property)) {
+                        (Property::Id, _) => Value::Id { value: id },
+                        (
+                            Property::DeliveryStatus,
+                            Some(Value::DeliveryStatus {
+                                value: mut statuses,
+                            }),
+                        ) => {
+                            // Add the update time of each recipient
+                            if let Some(Value::DeliveryUpdated { value }) = &delivery_updated {
+                                for (rcpt, status) in statuses.iter_mut() {
+                                    status.updated_at = value.get(rcpt).cloned();
+                                }
+                            }
+                            Value::DeliveryStatus { value: statuses }
+                        }
+                        (_, Some(value)) => value,
+                        (_, None) => Value::Null,
                     },
                 );
             }
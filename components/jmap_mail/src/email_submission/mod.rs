@@ -22,9 +22,11 @@
 */
 
 pub mod changes;
+pub mod dsn;
 pub mod get;
 pub mod query;
 pub mod raft;
+pub mod recipients;
 pub mod schema;
 pub mod serialize;
 pub mod set;
@@ -64,6 +66,8 @@ impl Object for EmailSubmission {
             (Property::IdentityId, <u64 as Options>::F_INDEX),
             (Property::ThreadId, <u64 as Options>::F_INDEX),
             (Property::SendAt, <u64 as Options>::F_INDEX),
+            (Property::DeliveryRetry, <u64 as Options>::F_INDEX),
+            (Property::UndoData, <u64 as Options>::F_INDEX),
         ]
     }
 
@@ -0,0 +1,324 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use jmap::error::set::{InvalidRecipient, InvalidRecipientReason, SetError, SetErrorType};
+use mail_parser::RfcHeader;
+use store::{
+    ahash::AHashSet, config::jmap::JMAPConfig, core::vec_map::VecMap, AccountId, JMAPStore, Store,
+};
+
+use crate::mail::{report::JMAPMailReport, HeaderValue};
+
+use super::schema::{Address, EmailSubmission, Property, Value};
+
+pub trait JMAPSubmissionRecipients<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn submission_recipient_domains(
+        &self,
+        account_id: AccountId,
+        submissions: &[EmailSubmission],
+    ) -> store::Result<AHashSet<String>>;
+}
+
+impl<T> JMAPSubmissionRecipients<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn submission_recipient_domains(
+        &self,
+        account_id: AccountId,
+        submissions: &[EmailSubmission],
+    ) -> store::Result<AHashSet<String>> {
+        let mut domains = AHashSet::default();
+        for submission in submissions {
+            let recipients = match (
+                submission.properties.get(&Property::Envelope),
+                submission.properties.get(&Property::EmailId),
+            ) {
+                (Some(Value::Envelope { value }), _) => value
+                    .rcpt_to
+                    .iter()
+                    .map(|addr| addr.email.trim().to_string())
+                    .collect(),
+                (_, Some(Value::Id { value })) => {
+                    if let Some(mut message_data) =
+                        self.mail_message_data(account_id, value.get_document_id())?
+                    {
+                        header_recipients(&mut message_data.headers)
+                    } else {
+                        continue;
+                    }
+                }
+                _ => continue,
+            };
+
+            for recipient in recipients {
+                if is_valid_address(&recipient) {
+                    if let Some(domain) = recipient_domain(&recipient) {
+                        if !domain.starts_with('[') {
+                            domains.insert(domain);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(domains)
+    }
+}
+
+/// Obtains the recipients of a message from its To and Cc headers.
+pub fn header_recipients(headers: &mut VecMap<RfcHeader, Vec<HeaderValue>>) -> AHashSet<String> {
+    let mut rcpt_to = AHashSet::default();
+    for header in [RfcHeader::To, RfcHeader::Cc] {
+        if let Some(values) = headers.remove(&header) {
+            for value in values {
+                if let Some(recipients) = value.into_addresses() {
+                    for recipient in recipients {
+                        rcpt_to.insert(recipient.email.trim().to_string());
+                    }
+                }
+            }
+        }
+    }
+    rcpt_to
+}
+
+/// Validates the envelope recipients, `undeliverable_domains` contains the
+/// domains found not to accept mail while looking up their MX records.
+pub fn validate_recipients(
+    rcpt_to: &[Address],
+    undeliverable_domains: &AHashSet<String>,
+    config: &JMAPConfig,
+) -> Result<(), SetError<Property>> {
+    let mut invalid_recipients = Vec::new();
+
+    for addr in rcpt_to {
+        let domain = recipient_domain(&addr.email);
+        let reason = if !is_valid_address(&addr.email) {
+            InvalidRecipientReason::InvalidSyntax
+        } else if domain.as_ref().map_or(false, |domain| {
+            config
+                .submission_blocked_domains
+                .iter()
+                .any(|blocked| is_subdomain_of(domain, blocked))
+        }) {
+            InvalidRecipientReason::BlockedDomain
+        } else if domain
+            .as_ref()
+            .map_or(false, |domain| undeliverable_domains.contains(domain))
+        {
+            InvalidRecipientReason::DomainNotFound
+        } else {
+            continue;
+        };
+
+        invalid_recipients.push(InvalidRecipient {
+            suggestion: if reason != InvalidRecipientReason::BlockedDomain {
+                suggest_address(&addr.email, &config.submission_suggest_domains)
+            } else {
+                None
+            },
+            email: addr.email.to_string(),
+            reason,
+        });
+    }
+
+    if invalid_recipients.is_empty() {
+        Ok(())
+    } else {
+        Err(SetError::new(SetErrorType::InvalidRecipients)
+            .with_property(Property::Envelope)
+            .with_description(format!(
+                "{} recipient(s) cannot receive e-mail.",
+                invalid_recipients.len()
+            ))
+            .with_invalid_recipients(invalid_recipients))
+    }
+}
+
+pub fn recipient_domain(email: &str) -> Option<String> {
+    email
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.trim_end_matches('.').to_lowercase())
+}
+
+pub fn is_valid_address(email: &str) -> bool {
+    let (local_part, domain) = if let Some(parts) = email.rsplit_once('@') {
+        parts
+    } else {
+        return false;
+    };
+
+    if local_part.is_empty()
+        || local_part.len() > 64
+        || domain.len() > 255
+        || local_part
+            .chars()
+            .any(|ch| ch.is_whitespace() || ch.is_control() || "<>()[],;:\\\"@".contains(ch))
+        || local_part.starts_with('.')
+        || local_part.ends_with('.')
+        || local_part.contains("..")
+    {
+        return false;
+    }
+
+    // Address literals
+    if let Some(literal) = domain
+        .strip_prefix('[')
+        .and_then(|domain| domain.strip_suffix(']'))
+    {
+        return literal
+            .strip_prefix("IPv6:")
+            .unwrap_or(literal)
+            .parse::<std::net::IpAddr>()
+            .is_ok();
+    }
+
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|ch| ch.is_alphanumeric() || ch == '-' || ch == '_')
+        })
+        && !domain
+            .rsplit('.')
+            .next()
+            .unwrap()
+            .chars()
+            .all(|ch| ch.is_ascii_digit())
+}
+
+fn is_subdomain_of(domain: &str, parent: &str) -> bool {
+    domain == parent
+        || (domain.len() > parent.len()
+            && domain.ends_with(parent)
+            && domain.as_bytes()[domain.len() - parent.len() - 1] == b'.')
+}
+
+// Suggests a correction for addresses whose domain is a likely misspelling
+// of a well-known domain.
+fn suggest_address(email: &str, known_domains: &[String]) -> Option<String> {
+    let (local_part, domain) = email.trim().rsplit_once('@')?;
+    let domain = domain.to_lowercase();
+    let domain = if !domain.contains('.') {
+        // Missing dot before the TLD, as in 'gmailcom'
+        known_domains
+            .iter()
+            .find(|known| known.replace('.', "") == domain)
+            .map(|known| known.as_str())
+    } else {
+        None
+    }
+    .or_else(|| {
+        known_domains
+            .iter()
+            .filter(|known| *known != &domain)
+            .map(|known| (edit_distance(&domain, known), known))
+            .filter(|(distance, _)| *distance <= 2)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, known)| known.as_str())
+    })?;
+
+    Some(format!("{}@{}", local_part, domain))
+}
+
+// Optimal string alignment distance, which counts transpositions as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for j in 0..=b.len() {
+        rows[0][j] = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            rows[i][j] = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                rows[i][j] = rows[i][j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    rows[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_address, suggest_address};
+
+    #[test]
+    fn validate_addresses() {
+        for (email, is_valid) in [
+            ("jdoe@example.org", true),
+            ("j.doe+tag@mail.example.org", true),
+            ("jdoe@other_domain.com", true),
+            ("jdoe@[192.168.1.1]", true),
+            ("jdoe@[IPv6:::1]", true),
+            ("jdoe@example.org.", true),
+            ("jdoe@example", false),
+            ("jdoe@-example.org", false),
+            ("jdoe@example..org", false),
+            ("jdoe@192.168.1.1", false),
+            ("j doe@example.org", false),
+            (".jdoe@example.org", false),
+            ("jdoe.example.org", false),
+            ("@example.org", false),
+        ] {
+            assert_eq!(is_valid_address(email), is_valid, "{}", email);
+        }
+
+        let known = ["gmail.com", "outlook.com", "yahoo.com"]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        for (email, suggestion) in [
+            ("jdoe@gmial.com", Some("jdoe@gmail.com")),
+            ("jdoe@gmailcom", Some("jdoe@gmail.com")),
+            ("jdoe@outlok.com", Some("jdoe@outlook.com")),
+            ("jdoe@yahoo.co", Some("jdoe@yahoo.com")),
+            ("jdoe@gmail.com", None),
+            ("jdoe@example.org", None),
+        ] {
+            assert_eq!(
+                suggest_address(email, &known).as_deref(),
+                suggestion,
+                "{}",
+                email
+            );
+        }
+    }
+}
@@ -21,10 +21,13 @@
  * for more details.
 */
 
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use jmap::{
-    orm,
+    orm::{self, TinyORM},
     request::ResultReference,
     types::{blob::JMAPBlob, date::JMAPDate, jmap::JMAPId},
 };
@@ -66,6 +69,15 @@ pub enum Value {
         value: ResultReference,
     },
     Null,
+    DeliveryRetry {
+        value: DeliveryRetry,
+    },
+    DeliveryUpdated {
+        value: AHashMap<String, JMAPDate>,
+    },
+    UndoData {
+        value: UndoData,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -89,6 +101,33 @@ impl Envelope {
     }
 }
 
+/// Internal state used to hold a submission during the undo window and
+/// to revert the onSuccessUpdateEmail changes if it gets canceled.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UndoData {
+    pub release_at: i64,
+    pub rollback: Option<EmailRollback>,
+}
+
+/// Internal state of a submission with recipients waiting for another
+/// delivery attempt after a temporary failure.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveryRetry {
+    pub recipients: Vec<String>,
+    pub attempts: u32,
+    pub next_attempt: u64,
+    pub last_error: String,
+    pub queued_at: u64,
+}
+
+/// Patch reverting the mailboxes and keywords changed by onSuccessUpdateEmail,
+/// leaving any other changes made to the e-mail since then in place.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmailRollback {
+    pub mailbox_ids: Vec<(JMAPId, bool)>,
+    pub keywords: Vec<(String, bool)>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Address {
     pub email: String,
@@ -131,16 +170,69 @@ pub struct DeliveryStatus {
 
     #[serde(rename = "displayed")]
     pub displayed: Displayed,
+
+    // Stored separately under Property::DeliveryUpdated, see set_delivery_status.
+    #[serde(skip)]
+    pub updated_at: Option<JMAPDate>,
 }
 
 impl DeliveryStatus {
     pub fn new(smtp_reply: String, delivered: Delivered, displayed: Displayed) -> Self {
-        DeliveryStatus {
+        let mut status = DeliveryStatus {
             smtp_reply,
             delivered,
             displayed,
+            updated_at: None,
+        };
+        status.touch();
+        status
+    }
+
+    pub fn touch(&mut self) {
+        self.updated_at = JMAPDate::from_timestamp(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64),
+        )
+        .into();
+    }
+}
+
+/// Sets the delivery status of a submission. The update time of each recipient
+/// is kept under its own field so that the stored DeliveryStatus records remain
+/// readable by nodes and databases that predate it. Recipients whose status
+/// carries no update time keep the one stored previously, if any.
+pub fn set_delivery_status(
+    current: &TinyORM<EmailSubmission>,
+    fields: &mut TinyORM<EmailSubmission>,
+    delivery_status: AHashMap<String, DeliveryStatus>,
+) {
+    let mut delivery_updated = match current.get(&Property::DeliveryUpdated) {
+        Some(Value::DeliveryUpdated { value }) => value
+            .iter()
+            .filter(|(rcpt, _)| delivery_status.contains_key(rcpt.as_str()))
+            .map(|(rcpt, updated_at)| (rcpt.clone(), updated_at.clone()))
+            .collect(),
+        _ => AHashMap::new(),
+    };
+    for (rcpt, status) in &delivery_status {
+        if let Some(updated_at) = &status.updated_at {
+            delivery_updated.insert(rcpt.clone(), updated_at.clone());
         }
     }
+
+    fields.set(
+        Property::DeliveryStatus,
+        Value::DeliveryStatus {
+            value: delivery_status,
+        },
+    );
+    fields.set(
+        Property::DeliveryUpdated,
+        Value::DeliveryUpdated {
+            value: delivery_updated,
+        },
+    );
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -177,6 +269,9 @@ pub enum Property {
     DsnBlobIds = 8,
     MdnBlobIds = 9,
     Invalid = 10,
+    UndoData = 11,
+    DeliveryRetry = 12,
+    DeliveryUpdated = 13,
 }
 
 impl Property {
@@ -210,7 +305,10 @@ impl Display for Property {
             Property::DeliveryStatus => write!(f, "deliveryStatus"),
             Property::DsnBlobIds => write!(f, "dsnBlobIds"),
             Property::MdnBlobIds => write!(f, "mdnBlobIds"),
-            Property::Invalid => Ok(()),
+            Property::Invalid
+            | Property::UndoData
+            | Property::DeliveryRetry
+            | Property::DeliveryUpdated => Ok(()),
         }
     }
 }
@@ -234,6 +332,9 @@ impl From<FieldId> for Property {
             7 => Property::DeliveryStatus,
             8 => Property::DsnBlobIds,
             9 => Property::MdnBlobIds,
+            11 => Property::UndoData,
+            12 => Property::DeliveryRetry,
+            13 => Property::DeliveryUpdated,
             _ => Property::Invalid,
         }
     }
@@ -282,6 +383,8 @@ impl orm::Value for Value {
                 UndoStatus::Final => "f".to_string().into(),
                 UndoStatus::Canceled => "c".to_string().into(),
             },
+            Value::DeliveryRetry { value } => value.next_attempt.into(),
+            Value::UndoData { value } => (value.release_at as u64).into(),
             _ => orm::Index::Null,
         }
     }
@@ -300,14 +403,29 @@ impl orm::Value for Value {
             Value::Text { value } => value.len(),
             Value::DateTime { .. } => std::mem::size_of::<JMAPDate>(),
             Value::UndoStatus { .. } => std::mem::size_of::<UndoStatus>(),
-            Value::DeliveryStatus { value } => value.keys().fold(0, |acc, x| {
-                acc + x.len() + std::mem::size_of::<DeliveryStatus>()
+            Value::DeliveryStatus { value } => value.iter().fold(0, |acc, (k, v)| {
+                acc + k.len() + v.smtp_reply.len() + std::mem::size_of::<DeliveryStatus>()
             }),
             Value::Envelope { value } => value.len(),
+            Value::UndoData { value } => {
+                std::mem::size_of::<UndoData>()
+                    + value.rollback.as_ref().map_or(0, |r| {
+                        r.mailbox_ids.len() * std::mem::size_of::<JMAPId>()
+                            + r.keywords.iter().map(|k| k.len()).sum::<usize>()
+                    })
+            }
             Value::BlobIds { value } => value.len() * std::mem::size_of::<JMAPBlob>(),
             Value::IdReference { value } => value.len(),
             Value::ResultReference { .. } => std::mem::size_of::<ResultReference>(),
             Value::Null => 0,
+            Value::DeliveryRetry { value } => {
+                std::mem::size_of::<DeliveryRetry>()
+                    + value.last_error.len()
+                    + value.recipients.iter().map(|r| r.len()).sum::<usize>()
+            }
+            Value::DeliveryUpdated { value } => value
+                .keys()
+                .fold(0, |acc, k| acc + k.len() + std::mem::size_of::<JMAPDate>()),
         }
     }
 }
@@ -343,3 +461,64 @@ impl Address {
         self.email.is_empty() && self.parameters.is_none()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use jmap::types::date::JMAPDate;
+    use store::{ahash::AHashMap, bincode, core::vec_map::VecMap};
+
+    use super::{Delivered, DeliveryStatus, Displayed, EmailSubmission, Property, Value};
+
+    #[test]
+    fn delivery_status_encoding() {
+        // Records written before update times existed only hold three fields
+        #[derive(serde::Serialize)]
+        struct DeliveryStatusV1 {
+            smtp_reply: String,
+            delivered: Delivered,
+            displayed: Displayed,
+        }
+        let status_v1 = bincode::serialize(&DeliveryStatusV1 {
+            smtp_reply: "250 OK".to_string(),
+            delivered: Delivered::Yes,
+            displayed: Displayed::Unknown,
+        })
+        .unwrap();
+        let mut status =
+            DeliveryStatus::new("250 OK".to_string(), Delivered::Yes, Displayed::Unknown);
+        assert_eq!(bincode::serialize(&status).unwrap(), status_v1);
+        status.updated_at = None;
+        assert_eq!(
+            bincode::deserialize::<DeliveryStatus>(&status_v1).unwrap(),
+            status
+        );
+
+        // The update time is only included when known
+        let mut submission = EmailSubmission {
+            properties: VecMap::new(),
+        };
+        submission.properties.append(
+            Property::DeliveryStatus,
+            Value::DeliveryStatus {
+                value: AHashMap::from_iter([("jdoe@example.org".to_string(), status.clone())]),
+            },
+        );
+        assert_eq!(
+            serde_json::to_string(&submission).unwrap(),
+            concat!(
+                "{\"deliveryStatus\":{\"jdoe@example.org\":{\"smtpReply\":\"250 OK\",",
+                "\"delivered\":\"yes\",\"displayed\":\"unknown\"}}}"
+            )
+        );
+        status.updated_at = JMAPDate::from_timestamp(0).into();
+        submission.properties.set(
+            Property::DeliveryStatus,
+            Value::DeliveryStatus {
+                value: AHashMap::from_iter([("jdoe@example.org".to_string(), status)]),
+            },
+        );
+        assert!(serde_json::to_string(&submission)
+            .unwrap()
+            .contains("\"updatedAt\":\"1970-01-01T00:00:00Z\""));
+    }
+}
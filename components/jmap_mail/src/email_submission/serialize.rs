@@ -25,10 +25,10 @@ use std::{borrow::Cow, fmt};
 
 use jmap::request::{query::FilterDeserializer, ArgumentDeserializer, MaybeIdReference};
 use serde::{de::IgnoredAny, ser::SerializeMap, Deserialize, Serialize};
-use store::core::vec_map::VecMap;
+use store::{ahash::AHashMap, core::vec_map::VecMap};
 
 use super::{
-    schema::{EmailSubmission, Envelope, Filter, Property, Value},
+    schema::{DeliveryStatus, EmailSubmission, Envelope, Filter, Property, Value},
     set::SetArguments,
 };
 
@@ -86,9 +86,18 @@ impl Serialize for EmailSubmission {
                 }
                 Value::DateTime { value } => map.serialize_entry(name, value)?,
                 Value::UndoStatus { value } => map.serialize_entry(name, value)?,
-                Value::DeliveryStatus { value } => map.serialize_entry(name, value)?,
+                Value::DeliveryStatus { value } => map.serialize_entry(
+                    name,
+                    &value
+                        .iter()
+                        .map(|(rcpt, status)| (rcpt, DeliveryStatusJSON(status)))
+                        .collect::<AHashMap<_, _>>(),
+                )?,
                 Value::BlobIds { value } => map.serialize_entry(name, value)?,
                 Value::Envelope { value } => map.serialize_entry(name, value)?,
+                Value::UndoData { .. }
+                | Value::DeliveryRetry { .. }
+                | Value::DeliveryUpdated { .. } => (),
             }
         }
 
@@ -96,6 +105,27 @@ impl Serialize for EmailSubmission {
     }
 }
 
+// The stored DeliveryStatus skips its update time, which is serialized here
+// only when known.
+struct DeliveryStatusJSON<'x>(&'x DeliveryStatus);
+
+impl Serialize for DeliveryStatusJSON<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let status = self.0;
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("smtpReply", &status.smtp_reply)?;
+        map.serialize_entry("delivered", &status.delivered)?;
+        map.serialize_entry("displayed", &status.displayed)?;
+        if let Some(updated_at) = &status.updated_at {
+            map.serialize_entry("updatedAt", updated_at)?;
+        }
+        map.end()
+    }
+}
+
 struct EmailSubmissionVisitor;
 
 impl<'de> serde::de::Visitor<'de> for EmailSubmissionVisitor {
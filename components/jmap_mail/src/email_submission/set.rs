@@ -21,10 +21,13 @@
  * for more details.
 */
 
-use super::schema::{Address, EmailSubmission, Envelope, Property, Value};
+use super::recipients::{header_recipients, validate_recipients};
+use super::schema::{
+    Address, EmailRollback, EmailSubmission, Envelope, Property, UndoData, UndoStatus, Value,
+};
 use crate::identity;
 use crate::identity::schema::Identity;
-use crate::mail::schema::Email;
+use crate::mail::schema::{Email, Keyword, Property as EmailProperty, Value as EmailValue};
 use crate::mail::{MessageData, MessageField};
 use jmap::error::set::{SetError, SetErrorType};
 use jmap::jmap_store::set::SetHelper;
@@ -35,14 +38,18 @@ use jmap::request::{MaybeIdReference, MaybeResultReference, ResultReference};
 use jmap::types::date::JMAPDate;
 use jmap::types::jmap::JMAPId;
 use jmap::{jmap_store::set::SetObject, request::set::SetRequest};
-use mail_parser::RfcHeader;
+use std::sync::Arc;
 use std::time::SystemTime;
 use store::ahash::{AHashMap, AHashSet};
 use store::blob::BlobId;
 use store::core::collection::Collection;
 use store::core::document::Document;
 use store::core::error::StoreError;
+use store::core::quota::{SubmissionQuota, SubmissionReservation};
 use store::core::vec_map::VecMap;
+use store::read::comparator::Comparator;
+use store::read::filter::{Filter, Query};
+use store::read::FilterMapper;
 use store::serialize::{StoreDeserialize, StoreSerialize};
 use store::write::options::{IndexOptions, Options};
 use store::{AccountId, JMAPStore, Store};
@@ -51,6 +58,7 @@ use store::{AccountId, JMAPStore, Store};
 pub struct SetArguments {
     pub on_success_update_email: Option<VecMap<MaybeIdReference, Email>>,
     pub on_success_destroy_email: Option<Vec<MaybeIdReference>>,
+    pub undeliverable_domains: AHashSet<String>,
 }
 
 impl SetObject for EmailSubmission {
@@ -102,6 +110,8 @@ where
         account_id: AccountId,
         document: &mut Document,
     ) -> store::Result<()>;
+
+    fn email_submission_quota(&self, account_id: AccountId) -> store::Result<Arc<SubmissionQuota>>;
 }
 
 impl<T> JMAPSetEmailSubmission<T> for JMAPStore<T>
@@ -128,6 +138,13 @@ where
                 .map_or(false, |p| !p.is_empty());
         let mut update_emails: VecMap<JMAPId, Email> = VecMap::new();
         let mut destroy_emails: Vec<JMAPId> = Vec::new();
+        let undo_window = self.config.submission_undo_window as i64;
+        let mut quota =
+            if helper.request.create.is_some() && SubmissionQuota::is_enabled(&self.config) {
+                SubmissionReservation::new(self.email_submission_quota(helper.account_id)?).into()
+            } else {
+                None
+            };
 
         helper.create(|create_id, item, helper, document| {
             let mut fields = TinyORM::<EmailSubmission>::new();
@@ -199,10 +216,12 @@ where
                 })?;
 
             // Make sure the envelope address matches the identity email address
-            let mut send_at = SystemTime::now()
+            let now = SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .map(|d| d.as_secs())
                 .unwrap_or(0) as i64;
+            let release_at = now + undo_window;
+            let mut send_at = release_at;
             let mut envelope = if let Some(envelope) = envelope {
                 if !envelope.mail_from.email.eq_ignore_ascii_case(&mail_from) {
                     return Err(SetError::invalid_properties()
@@ -284,18 +303,7 @@ where
 
             // Obtain recipients from e-mail if missing
             if envelope.rcpt_to.is_empty() {
-                let mut rcpt_to = AHashSet::default();
-                for header in [RfcHeader::To, RfcHeader::Cc] {
-                    if let Some(values) = message_data.headers.remove(&header) {
-                        for value in values {
-                            if let Some(recipients) = value.into_addresses() {
-                                for recipient in recipients {
-                                    rcpt_to.insert(recipient.email.trim().to_string());
-                                }
-                            }
-                        }
-                    }
-                }
+                let rcpt_to = header_recipients(&mut message_data.headers);
 
                 if !rcpt_to.is_empty() {
                     for addr in rcpt_to {
@@ -321,6 +329,25 @@ where
                     .collect::<Vec<_>>();
             }
 
+            // Reject recipients that cannot receive mail before the message is queued
+            validate_recipients(
+                &envelope.rcpt_to,
+                &helper.request.arguments.undeliverable_domains,
+                &helper.store.config,
+            )?;
+
+            // Enforce recipient limits
+            let max_recipients = helper.store.config.submission_max_recipients_message;
+            if max_recipients > 0 && envelope.rcpt_to.len() > max_recipients {
+                return Err(SetError::new(SetErrorType::TooManyRecipients)
+                    .with_property(Property::Envelope)
+                    .with_description(format!(
+                        "Messages cannot have more than {} recipients.",
+                        max_recipients
+                    )));
+            }
+            let num_recipients = envelope.rcpt_to.len();
+
             // Add and link blob
             document.binary(
                 Property::EmailId,
@@ -332,9 +359,44 @@ where
             // Insert envelope
             fields.set(Property::Envelope, Value::Envelope { value: envelope });
 
+            // Hold the message during the undo window, keeping the inverse of the
+            // onSuccessUpdateEmail changes so that they can be reverted.
+            if undo_window > 0 {
+                let rollback = if let Some(update) = helper
+                    .request
+                    .arguments
+                    .on_success_update_email
+                    .as_ref()
+                    .and_then(|p| p.get(&MaybeIdReference::Reference(create_id.to_string())))
+                {
+                    helper
+                        .store
+                        .get_orm::<Email>(helper.account_id, email_id.get_document_id())?
+                        .map(|email| email_rollback(&email, update))
+                } else {
+                    None
+                };
+                fields.set(
+                    Property::UndoData,
+                    Value::UndoData {
+                        value: UndoData {
+                            release_at,
+                            rollback,
+                        },
+                    },
+                );
+            }
+
             // Validate fields
             fields.insert_validate(document)?;
 
+            // Enforce submission quotas
+            if let Some(quota) = &mut quota {
+                quota.try_consume(num_recipients).map_err(|err| {
+                    SetError::new(SetErrorType::RateLimit).with_description(err.description())
+                })?;
+            }
+
             // Update onSuccess actions
             if has_on_success {
                 let id_ref = MaybeIdReference::Reference(create_id.to_string());
@@ -371,7 +433,75 @@ where
                     .ok_or_else(|| SetError::new(SetErrorType::NotFound))?;
                 let mut fields = TinyORM::track_changes(&current_fields);
 
+                // Submissions can only be canceled while they are still pending
+                if value != UndoStatus::Canceled {
+                    return Err(SetError::invalid_properties()
+                        .with_property(Property::UndoStatus)
+                        .with_description("undoStatus can only be set to canceled."));
+                } else if !matches!(
+                    current_fields.get(&Property::UndoStatus),
+                    Some(Value::UndoStatus {
+                        value: UndoStatus::Pending
+                    })
+                ) {
+                    return Err(SetError::new(SetErrorType::CannotUnsend)
+                        .with_description("The message has already been sent."));
+                }
+
+                if let Some(Value::UndoData { value: undo_data }) =
+                    current_fields.get(&Property::UndoData)
+                {
+                    // Cancellations are only guaranteed before the message is released
+                    let now = SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0) as i64;
+                    if now >= undo_data.release_at {
+                        return Err(SetError::new(SetErrorType::CannotUnsend)
+                            .with_description("The undo window for this message has expired."));
+                    }
+
+                    // Revert the changes made by onSuccessUpdateEmail
+                    if let (Some(rollback), Some(Value::Id { value: email_id })) =
+                        (&undo_data.rollback, current_fields.get(&Property::EmailId))
+                    {
+                        let mut email = Email::default();
+                        if !rollback.mailbox_ids.is_empty() {
+                            email.insert(
+                                EmailProperty::MailboxIds,
+                                EmailValue::MailboxIds {
+                                    value: rollback
+                                        .mailbox_ids
+                                        .iter()
+                                        .map(|(id, set)| (MaybeIdReference::Value(*id), *set))
+                                        .collect(),
+                                    set: false,
+                                },
+                            );
+                        }
+                        if !rollback.keywords.is_empty() {
+                            email.insert(
+                                EmailProperty::Keywords,
+                                EmailValue::Keywords {
+                                    value: rollback
+                                        .keywords
+                                        .iter()
+                                        .map(|(keyword, set)| (Keyword::parse(keyword), *set))
+                                        .collect(),
+                                    set: false,
+                                },
+                            );
+                        }
+                        if !email.properties.is_empty() {
+                            update_emails.append(*email_id, email);
+                        }
+                    }
+                }
+
                 fields.set(Property::UndoStatus, Value::UndoStatus { value });
+                if current_fields.has_property(&Property::DeliveryRetry) {
+                    fields.set(Property::DeliveryRetry, Value::Null);
+                }
 
                 // Merge changes
                 current_fields.merge_validate(document, fields)?;
@@ -386,29 +516,34 @@ where
 
         let account_id = JMAPId::from(helper.account_id);
         let acl = helper.acl.clone();
-        helper.into_response().map(|mut r| {
-            if has_on_success && (!update_emails.is_empty() || !destroy_emails.is_empty()) {
-                r.next_call = SetRequest {
-                    acl: acl.into(),
-                    account_id,
-                    if_in_state: None,
-                    create: None,
-                    update: if !update_emails.is_empty() {
-                        update_emails.into()
-                    } else {
-                        None
-                    },
-                    destroy: if !destroy_emails.is_empty() {
-                        MaybeResultReference::Value(destroy_emails).into()
-                    } else {
-                        None
-                    },
-                    arguments: (),
-                }
-                .into();
+        let mut response = helper.into_response()?;
+
+        // The submissions were written, keep the quota tokens they consumed
+        if let Some(quota) = quota {
+            quota.commit();
+        }
+
+        if !update_emails.is_empty() || !destroy_emails.is_empty() {
+            response.next_call = SetRequest {
+                acl: acl.into(),
+                account_id,
+                if_in_state: None,
+                create: None,
+                update: if !update_emails.is_empty() {
+                    update_emails.into()
+                } else {
+                    None
+                },
+                destroy: if !destroy_emails.is_empty() {
+                    MaybeResultReference::Value(destroy_emails).into()
+                } else {
+                    None
+                },
+                arguments: (),
             }
-            r
-        })
+            .into();
+        }
+        Ok(response)
     }
 
     fn email_submission_delete(
@@ -428,6 +563,13 @@ where
                 ))
             })?;
 
+        // Unlink received DSNs
+        if let Some(Value::BlobIds { value }) = email_submission.get(&Property::DsnBlobIds) {
+            for blob in value {
+                document.blob(blob.id.clone(), IndexOptions::new().clear());
+            }
+        }
+
         // Delete ORM
         email_submission.delete(document);
 
@@ -452,4 +594,201 @@ where
             )))
         }
     }
+
+    fn email_submission_quota(&self, account_id: AccountId) -> store::Result<Arc<SubmissionQuota>> {
+        self.submission_quotas
+            .try_get_with::<_, StoreError>(account_id, || {
+                // Replay the submissions of the last day, which might have been
+                // created while another node was the leader.
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                let mut history = Vec::new();
+                for id in self.query_store::<FilterMapper>(
+                    account_id,
+                    Collection::EmailSubmission,
+                    Filter::gt(
+                        Property::SendAt.into(),
+                        Query::LongInteger(now.saturating_sub(86400)),
+                    ),
+                    Comparator::None,
+                )? {
+                    if let Some(fields) =
+                        self.get_orm::<EmailSubmission>(account_id, id.get_document_id())?
+                    {
+                        if let (
+                            Some(Value::DateTime { value: send_at }),
+                            Some(Value::Envelope { value: envelope }),
+                        ) = (
+                            fields.get(&Property::SendAt),
+                            fields.get(&Property::Envelope),
+                        ) {
+                            history.push((send_at.timestamp() as u64, envelope.rcpt_to.len()));
+                        }
+                    }
+                }
+
+                Ok(Arc::new(SubmissionQuota::with_history(
+                    &self.config,
+                    history,
+                    now,
+                )))
+            })
+            .map_err(|err| err.as_ref().clone())
+    }
+}
+
+/// Builds the patch reverting the mailboxes and keywords the update changes,
+/// mailboxes created in the same request are left out.
+fn email_rollback(email: &TinyORM<Email>, update: &Email) -> EmailRollback {
+    let mut rollback = EmailRollback {
+        mailbox_ids: Vec::new(),
+        keywords: Vec::new(),
+    };
+
+    let mailbox_ids: Vec<JMAPId> = email
+        .get_tags(&EmailProperty::MailboxIds)
+        .map(|tags| tags.iter().map(|tag| tag.as_id().into()).collect())
+        .unwrap_or_default();
+    if let Some(EmailValue::MailboxIds { value, set }) =
+        update.properties.get(&EmailProperty::MailboxIds)
+    {
+        let changes = value.iter().filter_map(|(id, is_set)| match id {
+            MaybeIdReference::Value(id) => Some((*id, *is_set)),
+            MaybeIdReference::Reference(_) => None,
+        });
+        if *set {
+            for mailbox_id in &mailbox_ids {
+                if !value
+                    .get(&MaybeIdReference::Value(*mailbox_id))
+                    .copied()
+                    .unwrap_or(false)
+                {
+                    rollback.mailbox_ids.push((*mailbox_id, true));
+                }
+            }
+            for (mailbox_id, is_set) in changes {
+                if is_set && !mailbox_ids.contains(&mailbox_id) {
+                    rollback.mailbox_ids.push((mailbox_id, false));
+                }
+            }
+        } else {
+            for (mailbox_id, is_set) in changes {
+                if is_set != mailbox_ids.contains(&mailbox_id) {
+                    rollback.mailbox_ids.push((mailbox_id, !is_set));
+                }
+            }
+        }
+    }
+
+    let keywords: Vec<String> = email
+        .get_tags(&EmailProperty::Keywords)
+        .map(|tags| {
+            tags.iter()
+                .map(|tag| Keyword::from(tag).to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Some(EmailValue::Keywords { value, set }) =
+        update.properties.get(&EmailProperty::Keywords)
+    {
+        let changes = value
+            .iter()
+            .map(|(keyword, is_set)| (keyword.to_string(), *is_set))
+            .collect::<Vec<_>>();
+        if *set {
+            for keyword in &keywords {
+                if !changes.iter().any(|(k, is_set)| *is_set && k == keyword) {
+                    rollback.keywords.push((keyword.clone(), true));
+                }
+            }
+            for (keyword, is_set) in changes {
+                if is_set && !keywords.contains(&keyword) {
+                    rollback.keywords.push((keyword, false));
+                }
+            }
+        } else {
+            for (keyword, is_set) in changes {
+                if is_set != keywords.contains(&keyword) {
+                    rollback.keywords.push((keyword, !is_set));
+                }
+            }
+        }
+    }
+
+    rollback
+}
+
+#[cfg(test)]
+mod tests {
+    use super::email_rollback;
+    use crate::mail::schema::{Email, Keyword, Property, Value};
+    use jmap::orm::TinyORM;
+    use jmap::request::MaybeIdReference;
+    use jmap::types::jmap::JMAPId;
+    use store::core::tag::Tag;
+    use store::core::vec_map::VecMap;
+
+    fn update(mailbox_ids: &[(u32, bool)], keywords: &[(&str, bool)], set: bool) -> Email {
+        let mut email = Email::default();
+        email.properties.append(
+            Property::MailboxIds,
+            Value::MailboxIds {
+                value: mailbox_ids
+                    .iter()
+                    .map(|(id, is_set)| (MaybeIdReference::Value(JMAPId::from(*id)), *is_set))
+                    .collect::<VecMap<_, _>>(),
+                set,
+            },
+        );
+        email.properties.append(
+            Property::Keywords,
+            Value::Keywords {
+                value: keywords
+                    .iter()
+                    .map(|(keyword, is_set)| (Keyword::parse(keyword), *is_set))
+                    .collect::<VecMap<_, _>>(),
+                set,
+            },
+        );
+        email
+    }
+
+    #[test]
+    fn rollback_on_success_update_email() {
+        let mut email = TinyORM::<Email>::new();
+        email.tag(Property::MailboxIds, Tag::Id(1));
+        email.tag(Property::MailboxIds, Tag::Id(2));
+        email.tag(Property::Keywords, Keyword::parse("$seen").tag);
+
+        // Patches only revert what they actually changed
+        let rollback = email_rollback(
+            &email,
+            &update(
+                &[(3, true), (1, false), (4, false)],
+                &[("$seen", true), ("$flagged", true)],
+                false,
+            ),
+        );
+        assert_eq!(
+            rollback.mailbox_ids,
+            vec![(JMAPId::from(3u32), false), (JMAPId::from(1u32), true)]
+        );
+        assert_eq!(rollback.keywords, vec![("$flagged".to_string(), false)]);
+
+        // Replacing the whole set reverts both removals and additions
+        let rollback = email_rollback(
+            &email,
+            &update(&[(2, true), (3, true)], &[("$draft", true)], true),
+        );
+        assert_eq!(
+            rollback.mailbox_ids,
+            vec![(JMAPId::from(1u32), true), (JMAPId::from(3u32), false)]
+        );
+        assert_eq!(
+            rollback.keywords,
+            vec![("$seen".to_string(), true), ("$draft".to_string(), false)]
+        );
+    }
 }
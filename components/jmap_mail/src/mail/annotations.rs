@@ -0,0 +1,147 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use jmap::error::set::{SetError, SetErrorType};
+use store::{
+    bincode,
+    core::{collection::Collection, document::Document, vec_map::VecMap},
+    write::options::{IndexOptions, Options},
+    AccountId, DocumentId, JMAPStore, Store,
+};
+
+use super::schema::Property;
+
+pub trait JMAPMailAnnotations<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_annotations_get(
+        &self,
+        account_id: AccountId,
+        document_id: DocumentId,
+        principal_id: AccountId,
+    ) -> store::Result<VecMap<String, String>>;
+
+    fn mail_annotations_update(
+        &self,
+        account_id: AccountId,
+        principal_id: AccountId,
+        document: &mut Document,
+        changes: VecMap<String, Option<String>>,
+        replace: bool,
+    ) -> jmap::error::set::Result<(), Property>;
+
+    fn mail_annotations_delete(
+        &self,
+        account_id: AccountId,
+        document: &mut Document,
+    ) -> store::Result<()>;
+}
+
+impl<T> JMAPMailAnnotations<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_annotations_get(
+        &self,
+        account_id: AccountId,
+        document_id: DocumentId,
+        principal_id: AccountId,
+    ) -> store::Result<VecMap<String, String>> {
+        Ok(self
+            .get_annotation(account_id, Collection::Mail, document_id, principal_id)?
+            .and_then(|bytes| bincode::deserialize(&bytes).ok())
+            .unwrap_or_default())
+    }
+
+    fn mail_annotations_update(
+        &self,
+        account_id: AccountId,
+        principal_id: AccountId,
+        document: &mut Document,
+        changes: VecMap<String, Option<String>>,
+        replace: bool,
+    ) -> jmap::error::set::Result<(), Property> {
+        let current = self.mail_annotations_get(account_id, document.document_id, principal_id)?;
+        let mut annotations = if !replace {
+            current.clone()
+        } else {
+            VecMap::new()
+        };
+
+        for (name, value) in changes {
+            if name.is_empty() {
+                return Err(SetError::invalid_properties()
+                    .with_property(Property::Annotations)
+                    .with_description("Annotation names cannot be empty."));
+            }
+            if let Some(value) = value {
+                annotations.set(name, value);
+            } else {
+                annotations.remove(&name);
+            }
+        }
+
+        if annotations == current {
+            return Ok(());
+        }
+
+        let size = annotations
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum::<usize>();
+        if size > self.config.mail_annotations_max_size {
+            return Err(SetError::new(SetErrorType::TooLarge)
+                .with_property(Property::Annotations)
+                .with_description(format!(
+                    "Annotations cannot exceed {} bytes.",
+                    self.config.mail_annotations_max_size
+                )));
+        }
+
+        if !annotations.is_empty() {
+            document.annotation(
+                principal_id,
+                bincode::serialize(&annotations).unwrap_or_default(),
+                IndexOptions::new(),
+            );
+        } else {
+            document.annotation(principal_id, Vec::new(), IndexOptions::new().clear());
+        }
+
+        Ok(())
+    }
+
+    fn mail_annotations_delete(
+        &self,
+        account_id: AccountId,
+        document: &mut Document,
+    ) -> store::Result<()> {
+        for (principal_id, _) in
+            self.get_annotations(account_id, Collection::Mail, document.document_id)?
+        {
+            document.annotation(principal_id, Vec::new(), IndexOptions::new().clear());
+        }
+        Ok(())
+    }
+}
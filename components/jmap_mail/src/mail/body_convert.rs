@@ -0,0 +1,523 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::borrow::Cow;
+
+const SKIP_ELEMENTS: &[&str] = &["head", "script", "style", "title", "template"];
+const MAX_ENTITY_LEN: usize = 32;
+
+/// Body values generated when a client asks for the text or HTML bodies of a
+/// message that only has the other format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BodyConversion {
+    HtmlToText,
+    TextToHtml,
+}
+
+impl BodyConversion {
+    pub fn convert(&self, body: &str) -> String {
+        match self {
+            BodyConversion::HtmlToText => html_to_plain_text(body),
+            BodyConversion::TextToHtml => plain_text_to_html(body),
+        }
+    }
+
+    pub fn is_html(&self) -> bool {
+        matches!(self, BodyConversion::TextToHtml)
+    }
+}
+
+/// Converts an HTML body to plain text. Paragraphs, line breaks, lists and
+/// table rows are kept on their own lines, table cells are separated with
+/// `|`, blockquotes are prefixed with `>` and link targets are written next
+/// to their text.
+pub fn html_to_plain_text(html: &str) -> String {
+    let mut writer = TextWriter::default();
+    let mut lists: Vec<Option<usize>> = Vec::new();
+    let mut links: Vec<Option<(String, usize)>> = Vec::new();
+    let mut skip_depth: usize = 0;
+    let mut first_cell = true;
+    let mut rest = html;
+
+    while let Some(pos) = rest.find('<') {
+        if skip_depth == 0 {
+            writer.text(&decode_entities(&rest[..pos]));
+        }
+        rest = &rest[pos..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        } else if !rest[1..].starts_with(|ch: char| ch.is_ascii_alphabetic() || "/!?".contains(ch))
+        {
+            // A literal '<' that does not start a tag
+            if skip_depth == 0 {
+                writer.text("<");
+            }
+            rest = &rest[1..];
+            continue;
+        }
+
+        let end = tag_end(rest);
+        let tag = &rest[1..end];
+        rest = rest.get(end + 1..).unwrap_or_default();
+        let (is_closing, tag) = match tag.strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+        };
+        let name = tag
+            .split(|ch: char| !ch.is_ascii_alphanumeric())
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        if SKIP_ELEMENTS.contains(&name.as_str()) {
+            if !is_closing {
+                skip_depth += 1;
+            } else {
+                skip_depth = skip_depth.saturating_sub(1);
+            }
+            continue;
+        } else if skip_depth > 0 {
+            continue;
+        }
+
+        match (name.as_str(), is_closing) {
+            ("br", _) => writer.line_break(),
+            ("p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6", _) => writer.block(2),
+            ("div" | "table" | "dl" | "dt" | "dd" | "address" | "section" | "article", _) => {
+                writer.block(1)
+            }
+            ("pre", is_closing) => {
+                writer.block(2);
+                if !is_closing {
+                    writer.pre += 1;
+                } else {
+                    writer.pre = writer.pre.saturating_sub(1);
+                }
+            }
+            ("hr", _) => {
+                writer.block(1);
+                writer.raw("---");
+                writer.block(1);
+            }
+            ("blockquote", is_closing) => {
+                writer.block(1);
+                if !is_closing {
+                    writer.quote += 1;
+                } else {
+                    writer.quote = writer.quote.saturating_sub(1);
+                }
+            }
+            ("ul" | "ol", false) => {
+                writer.block(1);
+                lists.push(if name == "ol" {
+                    tag_attribute(tag, "start")
+                        .and_then(|start| start.parse().ok())
+                        .unwrap_or(1)
+                        .into()
+                } else {
+                    None
+                });
+            }
+            ("ul" | "ol", true) => {
+                writer.block(1);
+                lists.pop();
+            }
+            ("li", false) => {
+                writer.block(1);
+                let marker = match lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "- ".to_string(),
+                };
+                writer.raw(&format!(
+                    "{}{}",
+                    "  ".repeat(lists.len().saturating_sub(1)),
+                    marker
+                ));
+            }
+            ("tr", _) => {
+                writer.block(1);
+                first_cell = true;
+            }
+            ("td" | "th", false) => {
+                if !first_cell {
+                    writer.raw(" | ");
+                }
+                first_cell = false;
+            }
+            ("a", false) => {
+                links.push(
+                    tag_attribute(tag, "href")
+                        .map(|href| (decode_entities(href).trim().to_string(), writer.out.len())),
+                );
+            }
+            ("a", true) => {
+                if let Some(Some((href, start))) = links.pop() {
+                    let label = writer.out.get(start..).unwrap_or_default().trim();
+                    if !href.is_empty()
+                        && !href.starts_with('#')
+                        && !href.to_ascii_lowercase().starts_with("javascript:")
+                        && label != href
+                        && label != href.strip_prefix("mailto:").unwrap_or_default()
+                    {
+                        writer.space = true;
+                        writer.raw(&format!("<{}>", href));
+                    }
+                }
+            }
+            ("img", false) => {
+                if let Some(alt) = tag_attribute(tag, "alt").filter(|alt| !alt.trim().is_empty()) {
+                    writer.text(&format!("[{}]", decode_entities(alt).trim()));
+                }
+            }
+            _ => (),
+        }
+    }
+    if skip_depth == 0 {
+        writer.text(&decode_entities(rest));
+    }
+
+    writer.out.truncate(writer.out.trim_end().len());
+    writer.out
+}
+
+/// Converts a plain text body to HTML, escaping it, turning URLs into links
+/// and quoted lines into nested blockquotes.
+pub fn plain_text_to_html(text: &str) -> String {
+    let mut html = String::with_capacity(text.len() + (text.len() >> 2) + 32);
+    let mut depth = 0;
+
+    for line in text.trim_end().lines() {
+        let (level, line) = quote_level(line);
+        if level != depth {
+            while depth < level {
+                html.push_str("<blockquote type=\"cite\">");
+                depth += 1;
+            }
+            while depth > level {
+                html.push_str("</blockquote>");
+                depth -= 1;
+            }
+        }
+        write_linkified(&mut html, line);
+        html.push_str("<br>\n");
+    }
+    while depth > 0 {
+        html.push_str("</blockquote>");
+        depth -= 1;
+    }
+
+    html
+}
+
+// Accumulates plain text, collapsing whitespace and emitting line breaks
+// only when followed by more text.
+#[derive(Default)]
+struct TextWriter {
+    out: String,
+    quote: usize,
+    line_quote: usize,
+    pre: usize,
+    newlines: usize,
+    space: bool,
+}
+
+impl TextWriter {
+    fn text(&mut self, text: &str) {
+        let mut buf = [0u8; 4];
+        for ch in text.chars() {
+            if self.pre > 0 {
+                match ch {
+                    '\n' => self.newlines += 1,
+                    '\r' => (),
+                    _ => self.raw(ch.encode_utf8(&mut buf)),
+                }
+            } else if ch.is_whitespace() {
+                self.space = true;
+            } else {
+                self.raw(ch.encode_utf8(&mut buf));
+            }
+        }
+    }
+
+    fn raw(&mut self, text: &str) {
+        let line_start = self.newlines > 0 || self.out.is_empty() || self.out.ends_with('\n');
+        if !self.out.is_empty() {
+            // Blank lines between quoted lines keep the quote markers
+            let blank_line = "> ".repeat(self.quote.min(self.line_quote));
+            for pos in 0..self.newlines {
+                self.out.push('\n');
+                if pos + 1 < self.newlines {
+                    self.out.push_str(blank_line.trim_end());
+                }
+            }
+        }
+        self.newlines = 0;
+        self.line_quote = self.quote;
+
+        if line_start {
+            for _ in 0..self.quote {
+                self.out.push_str("> ");
+            }
+        } else if self.space && self.pre == 0 {
+            self.out.push(' ');
+        }
+        self.space = false;
+        self.out.push_str(text);
+    }
+
+    fn block(&mut self, newlines: usize) {
+        self.newlines = self.newlines.max(newlines);
+        self.space = false;
+    }
+
+    fn line_break(&mut self) {
+        self.newlines += 1;
+        self.space = false;
+    }
+}
+
+// Returns the position of the '>' that closes the tag, skipping quoted
+// attribute values.
+fn tag_end(tag: &str) -> usize {
+    let mut quote = None;
+    let mut last_ch = ' ';
+    for (pos, ch) in tag.char_indices() {
+        match quote {
+            Some(quote_ch) if ch == quote_ch => quote = None,
+            Some(_) => (),
+            None if ch == '>' => return pos,
+            None if (ch == '"' || ch == '\'') && last_ch == '=' => quote = Some(ch),
+            None => (),
+        }
+        if !ch.is_ascii_whitespace() {
+            last_ch = ch;
+        }
+    }
+    tag.len()
+}
+
+fn tag_attribute<'x>(tag: &'x str, name: &str) -> Option<&'x str> {
+    let lower_tag = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(pos) = lower_tag[from..].find(name) {
+        let start = from + pos;
+        from = start + name.len();
+        if !lower_tag[..start].ends_with(|ch: char| ch.is_ascii_whitespace()) {
+            continue;
+        }
+        if let Some(value) = lower_tag[from..].trim_start().strip_prefix('=') {
+            let value = &tag[tag.len() - value.trim_start().len()..];
+            return match value.chars().next() {
+                Some(quote @ ('"' | '\'')) => value[1..].split(quote).next(),
+                _ => value.split(|ch: char| ch.is_ascii_whitespace()).next(),
+            };
+        }
+    }
+    None
+}
+
+fn decode_entities(text: &str) -> Cow<'_, str> {
+    if !text.contains('&') {
+        return text.into();
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find('&') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        match rest
+            .find(';')
+            .filter(|end| *end <= MAX_ENTITY_LEN)
+            .and_then(|end| Some((end, decode_entity(&rest[1..end])?)))
+        {
+            Some((end, ch)) => {
+                result.push(ch);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result.into()
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    Some(match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" | "ensp" | "emsp" | "thinsp" => ' ',
+        "shy" | "zwnj" | "zwj" => return None,
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "hellip" => '…',
+        "mdash" => '—',
+        "ndash" => '–',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "laquo" => '«',
+        "raquo" => '»',
+        "bull" => '•',
+        "middot" => '·',
+        "euro" => '€',
+        "pound" => '£',
+        "yen" => '¥',
+        "cent" => '¢',
+        "deg" => '°',
+        "times" => '×',
+        "divide" => '÷',
+        "eacute" => 'é',
+        "egrave" => 'è',
+        "aacute" => 'á',
+        "agrave" => 'à',
+        "iacute" => 'í',
+        "oacute" => 'ó',
+        "uacute" => 'ú',
+        "ntilde" => 'ñ',
+        "ccedil" => 'ç',
+        "uuml" => 'ü',
+        "ouml" => 'ö',
+        "auml" => 'ä',
+        "szlig" => 'ß',
+        _ => {
+            let number = entity.strip_prefix('#')?;
+            char::from_u32(match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            })?
+        }
+    })
+}
+
+// Returns the quote depth of a line and the line without its quote markers.
+fn quote_level(line: &str) -> (usize, &str) {
+    let mut level = 0;
+    let mut rest = line;
+    while let Some(unquoted) = rest.strip_prefix('>') {
+        level += 1;
+        rest = unquoted.strip_prefix(' ').unwrap_or(unquoted);
+    }
+    (level, rest)
+}
+
+fn write_linkified(html: &mut String, mut text: &str) {
+    while let Some(start) = ["https://", "http://", "mailto:"]
+        .iter()
+        .filter_map(|scheme| text.find(scheme))
+        .min()
+    {
+        let url_len = text[start..]
+            .find(|ch: char| ch.is_whitespace() || "<>\"".contains(ch))
+            .unwrap_or(text.len() - start);
+        let url =
+            text[start..start + url_len].trim_end_matches(|ch: char| ".,;:!?)]'".contains(ch));
+        escape_html(html, &text[..start]);
+        if url.contains(|ch: char| ch.is_alphanumeric()) && !url.ends_with(':') {
+            html.push_str("<a href=\"");
+            escape_html(html, url);
+            html.push_str("\">");
+            escape_html(html, url);
+            html.push_str("</a>");
+        } else {
+            escape_html(html, url);
+        }
+        text = &text[start + url.len()..];
+    }
+    escape_html(html, text);
+}
+
+fn escape_html(html: &mut String, text: &str) {
+    for ch in text.chars() {
+        match ch {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\r' => (),
+            _ => html.push(ch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{html_to_plain_text, plain_text_to_html};
+
+    #[test]
+    fn convert_body_values() {
+        for (html, expected) in [
+            (
+                "<html><head><title>T</title><style>p{}</style></head><body><p>Hello  <b>world</b></p><p>Bye</p></body></html>",
+                "Hello world\n\nBye",
+            ),
+            (
+                "<p>See <a href=\"https://example.org/?a=1&amp;b=2\">our site</a> or <a href=\"https://example.org\">https://example.org</a></p>",
+                "See our site <https://example.org/?a=1&b=2> or https://example.org",
+            ),
+            (
+                "<table><tr><th>Name</th><th>Qty</th></tr><tr><td>Apples</td><td>3</td></tr></table>",
+                "Name | Qty\nApples | 3",
+            ),
+            (
+                "<p>On Monday you wrote:</p><blockquote><p>First</p><blockquote>Nested</blockquote></blockquote><p>Reply</p>",
+                "On Monday you wrote:\n\n> First\n>\n> > Nested\n\nReply",
+            ),
+            (
+                "<ul><li>One</li><li>Two<ol start=\"3\"><li>Three</li></ol></li></ul>",
+                "- One\n- Two\n  3. Three",
+            ),
+            ("a<br>b<br><br>c &lt;d&gt; &#233;&#x41; 1 < 2", "a\nb\n\nc <d> éA 1 < 2"),
+            ("<pre>  x = 1;\n  y = 2;</pre><p>ok</p>", "  x = 1;\n  y = 2;\n\nok"),
+            ("<img alt=\"Logo\"/><!-- hidden --><p>Text</p>", "[Logo]\n\nText"),
+        ] {
+            assert_eq!(html_to_plain_text(html), expected, "{}", html);
+        }
+
+        for (text, expected) in [
+            (
+                "Hi <you> & me\nVisit https://example.org/page.\n",
+                "Hi &lt;you&gt; &amp; me<br>\nVisit <a href=\"https://example.org/page\">https://example.org/page</a>.<br>\n",
+            ),
+            (
+                "Reply\n> quoted\n>> nested\ntail",
+                "Reply<br>\n<blockquote type=\"cite\">quoted<br>\n<blockquote type=\"cite\">nested<br>\n</blockquote></blockquote>tail<br>\n",
+            ),
+        ] {
+            assert_eq!(plain_text_to_html(text), expected, "{}", text);
+        }
+    }
+}
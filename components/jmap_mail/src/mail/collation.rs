@@ -0,0 +1,305 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use store::{
+    blob::BlobId,
+    core::{
+        collection::Collection,
+        document::{Document, MAX_SORT_FIELD_LENGTH},
+        error::StoreError,
+    },
+    read::{
+        comparator::Comparator,
+        filter::{Filter, Query},
+        FilterMapper,
+    },
+    serialize::StoreDeserialize,
+    tracing::debug,
+    write::{batch::WriteBatch, options::IndexOptions},
+    AccountId, JMAPStore, LongInteger, Store,
+};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use super::{template::JMAPMailTemplate, MessageData, MessageField};
+
+// Tailored letters are sorted after their base letter by appending a marker
+// that sorts after every ASCII letter, followed by their rank.
+const TAILORING_MARKER: char = '\u{7f}';
+
+/// Collation used to build the sort keys of a message. Keys are plain strings
+/// whose code point order matches the collation order, which allows them to
+/// be stored in the sort index as any other text value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Collation {
+    /// Lowercase code point order, used by messages indexed before
+    /// collations were introduced.
+    Legacy = 0,
+    Root = 1,
+    Danish = 2,
+    Swedish = 3,
+    Spanish = 4,
+    Turkish = 5,
+}
+
+impl Collation {
+    pub fn from_locale(locale: &str) -> Self {
+        match locale
+            .split(['-', '_'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "da" | "nb" | "nn" | "no" => Collation::Danish,
+            "sv" | "fi" => Collation::Swedish,
+            "es" => Collation::Spanish,
+            "tr" | "az" => Collation::Turkish,
+            _ => Collation::Root,
+        }
+    }
+
+    pub fn from_id(id: u64) -> Self {
+        match id {
+            1 => Collation::Root,
+            2 => Collation::Danish,
+            3 => Collation::Swedish,
+            4 => Collation::Spanish,
+            5 => Collation::Turkish,
+            _ => Collation::Legacy,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        *self as u64
+    }
+
+    /// Builds the sort key of a text, ignoring case and accents except for
+    /// the letters the collation sorts separately.
+    pub fn sort_key(&self, text: &str) -> String {
+        if *self == Collation::Legacy {
+            return text.to_string();
+        }
+
+        let mut key = String::with_capacity(text.len());
+        for ch in text.nfc().flat_map(char::to_lowercase) {
+            if let Some((base, rank)) = self.tailoring(ch) {
+                key.push(base);
+                key.push(TAILORING_MARKER);
+                key.push(rank);
+            } else if let Some(expanded) = expand(ch) {
+                key.push_str(expanded);
+            } else {
+                for ch in std::iter::once(ch).nfd() {
+                    if !is_combining_mark(ch) {
+                        key.push(ch);
+                    }
+                }
+            }
+
+            if key.len() >= MAX_SORT_FIELD_LENGTH {
+                let mut pos = MAX_SORT_FIELD_LENGTH;
+                while !key.is_char_boundary(pos) {
+                    pos -= 1;
+                }
+                key.truncate(pos);
+                break;
+            }
+        }
+        key
+    }
+
+    fn tailoring(&self, ch: char) -> Option<(char, char)> {
+        match (self, ch) {
+            (Collation::Danish, 'æ' | 'ä') => ('z', '1'),
+            (Collation::Danish, 'ø' | 'ö') => ('z', '2'),
+            (Collation::Danish, 'å') => ('z', '3'),
+            (Collation::Swedish, 'å') => ('z', '1'),
+            (Collation::Swedish, 'ä' | 'æ') => ('z', '2'),
+            (Collation::Swedish, 'ö' | 'ø') => ('z', '3'),
+            (Collation::Spanish, 'ñ') => ('n', '1'),
+            (Collation::Turkish, 'ç') => ('c', '1'),
+            (Collation::Turkish, 'ğ') => ('g', '1'),
+            (Collation::Turkish, 'ı') => ('h', '1'),
+            (Collation::Turkish, 'ö') => ('o', '1'),
+            (Collation::Turkish, 'ş') => ('s', '1'),
+            (Collation::Turkish, 'ü') => ('u', '1'),
+            _ => return None,
+        }
+        .into()
+    }
+}
+
+// Letters without a canonical decomposition that sort as their base letters
+fn expand(ch: char) -> Option<&'static str> {
+    match ch {
+        'æ' => "ae",
+        'œ' => "oe",
+        'ß' => "ss",
+        'ø' => "o",
+        'đ' | 'ð' => "d",
+        'ł' => "l",
+        'þ' => "th",
+        'ı' => "i",
+        _ => return None,
+    }
+    .into()
+}
+
+pub trait JMAPMailCollation<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_collation(&self, account_id: AccountId) -> store::Result<Collation>;
+    fn mail_reindex_collation(&self, account_id: AccountId) -> store::Result<usize>;
+}
+
+impl<T> JMAPMailCollation<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_collation(&self, account_id: AccountId) -> store::Result<Collation> {
+        Ok(Collation::from_locale(&self.mail_locale(account_id)?))
+    }
+
+    fn mail_reindex_collation(&self, account_id: AccountId) -> store::Result<usize> {
+        let collation = self.mail_collation(account_id)?;
+        let mut document_ids =
+            if let Some(document_ids) = self.get_document_ids(account_id, Collection::Mail)? {
+                document_ids
+            } else {
+                return Ok(0);
+            };
+
+        // Messages indexed before collations were introduced or before the
+        // locale of the account changed are sorted with their old keys.
+        document_ids -= self
+            .query_store::<FilterMapper>(
+                account_id,
+                Collection::Mail,
+                Filter::eq(
+                    MessageField::Collation.into(),
+                    Query::LongInteger(collation.id() as LongInteger),
+                ),
+                Comparator::None,
+            )?
+            .into_bitmap();
+
+        // Sort keys are derived from the message and rebuilt by each node, so
+        // they are written without logging a change.
+        let mut reindexed = 0;
+        for document_id in document_ids {
+            let _lock = self.lock_collection(account_id, Collection::Mail);
+            let metadata_blob_id = if let Some(metadata_blob_id) = self
+                .get_document_value::<BlobId>(
+                    account_id,
+                    Collection::Mail,
+                    document_id,
+                    MessageField::Metadata.into(),
+                )? {
+                metadata_blob_id
+            } else {
+                continue;
+            };
+            let current_collation = Collation::from_id(
+                self.get_document_value::<u64>(
+                    account_id,
+                    Collection::Mail,
+                    document_id,
+                    MessageField::Collation.into(),
+                )?
+                .unwrap_or_default(),
+            );
+            if current_collation == collation {
+                continue;
+            }
+            let message_data =
+                MessageData::deserialize(&self.blob_get(&metadata_blob_id)?.ok_or_else(|| {
+                    StoreError::NotFound(format!(
+                        "Message data blob for {}:{} not found.",
+                        account_id, document_id
+                    ))
+                })?)
+                .ok_or_else(|| {
+                    StoreError::DataCorruption(format!(
+                        "Failed to deserialize message data for {}:{}.",
+                        account_id, document_id
+                    ))
+                })?;
+
+            let mut document = Document::new(Collection::Mail, document_id);
+            message_data.build_sort_index(
+                &mut document,
+                current_collation,
+                IndexOptions::new().clear(),
+            );
+            message_data.build_sort_index(&mut document, collation, IndexOptions::new());
+            let mut batch = WriteBatch::new(account_id);
+            batch.update_document(document);
+            self.write(batch)?;
+            reindexed += 1;
+        }
+
+        if reindexed > 0 {
+            debug!(
+                "Rebuilt the sort keys of {} message(s) of account {}.",
+                reindexed, account_id
+            );
+        }
+
+        Ok(reindexed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Collation;
+
+    #[test]
+    fn collation_sort_key() {
+        for (collation, expected) in [
+            (
+                Collation::Root,
+                vec!["Andersson", "Ångström", "Émile", "Ösel", "Zoë"],
+            ),
+            (
+                Collation::Swedish,
+                vec!["Andersson", "Émile", "Zoë", "Ångström", "Ösel"],
+            ),
+        ] {
+            let mut names = vec!["Zoë", "Ösel", "Émile", "Ångström", "Andersson"];
+            names.sort_by_key(|name| collation.sort_key(name));
+            assert_eq!(names, expected, "{:?}", collation);
+        }
+
+        let mut words = vec!["ocho", "ñu", "nube"];
+        words.sort_by_key(|word| Collation::Spanish.sort_key(word));
+        assert_eq!(words, ["nube", "ñu", "ocho"]);
+
+        assert_eq!(Collation::Root.sort_key("Straße"), "strasse");
+        assert_eq!(Collation::Legacy.sort_key("Ésa"), "Ésa");
+        assert_eq!(Collation::from_locale("sv_SE"), Collation::Swedish);
+        assert_eq!(Collation::from_locale("nb-NO"), Collation::Danish);
+        assert_eq!(Collation::from_locale("en"), Collation::Root);
+    }
+}
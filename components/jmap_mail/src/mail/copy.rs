@@ -22,7 +22,9 @@
 */
 
 use super::{
-    import::JMAPMailImport,
+    collation::JMAPMailCollation,
+    import::{inserted_at, JMAPMailImport},
+    keywords::JMAPMailKeywords,
     schema::{Email, Property, Value},
     sharing::JMAPShareMail,
     MessageData, MessageField,
@@ -175,6 +177,11 @@ where
                     .with_description("Message has to belong to at least one mailbox."));
             }
 
+            // Enforce the custom keyword limit
+            if let Some(keywords) = fields.get_tags(&Property::Keywords) {
+                self.mail_keywords_register(helper.account_id, keywords)?;
+            }
+
             // Check ACL on target account
             if is_shared_target {
                 let allowed_folders = helper.store.mail_shared_folders(
@@ -240,7 +247,12 @@ where
             // Copy properties and build index
             let raw_blob = JMAPBlob::from(&message_data.raw_message);
             let size = message_data.size;
-            message_data.build_index(document, true)?;
+            message_data.build_index(
+                document,
+                self.mail_collation(helper.account_id)?,
+                inserted_at().into(),
+                true,
+            )?;
 
             // Link metadata blob
             document.binary(
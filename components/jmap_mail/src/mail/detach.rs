@@ -0,0 +1,205 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use jmap::types::blob::JMAPBlob;
+use mail_parser::{Header, HeaderName, Message, PartType};
+use store::blob::BlobId;
+
+pub const DETACHED_PART_HEADER: &str = "X-Detached-Part";
+
+pub struct DetachedMessage {
+    pub raw_message: Vec<u8>,
+    pub parts: Vec<(BlobId, Vec<u8>)>,
+}
+
+/// Moves the decoded contents of all top-level binary parts larger than
+/// `min_part_size` to standalone blobs. The stub headers are informational
+/// only, the blobs are linked to the message using the returned `parts`. Each detached part keeps its original
+/// headers, gets an empty body and an `X-Detached-Part: <blobId>; size=<n>`
+/// header pointing to the blob holding its contents.
+/// Returns `None` if the message could not be parsed or nothing was detached.
+pub fn mail_detach_parts(raw_message: &[u8], min_part_size: usize) -> Option<DetachedMessage> {
+    let message = Message::parse(raw_message)?;
+    let mut detached = Vec::new();
+
+    for part in message.parts.into_iter().skip(1) {
+        if part.headers.iter().any(is_detached_header) {
+            continue;
+        }
+        match part.body {
+            PartType::Binary(contents) | PartType::InlineBinary(contents)
+                if contents.len() >= min_part_size.max(1) =>
+            {
+                detached.push((
+                    part.offset_header,
+                    part.offset_body,
+                    part.offset_end,
+                    contents.into_owned(),
+                ));
+            }
+            _ => (),
+        }
+    }
+
+    if detached.is_empty() {
+        return None;
+    }
+
+    let mut result = DetachedMessage {
+        raw_message: Vec::with_capacity(raw_message.len()),
+        parts: Vec::with_capacity(detached.len()),
+    };
+    let mut pos = 0;
+    for (offset_header, offset_body, offset_end, contents) in detached {
+        if offset_header < pos || offset_body > offset_end || offset_end > raw_message.len() {
+            return None;
+        }
+        let blob_id = BlobId::new_external(&contents);
+
+        result
+            .raw_message
+            .extend_from_slice(&raw_message[pos..offset_header]);
+        result.raw_message.extend_from_slice(
+            format!(
+                "{}: {}; size={}\r\n",
+                DETACHED_PART_HEADER,
+                JMAPBlob::new(blob_id.clone()),
+                contents.len()
+            )
+            .as_bytes(),
+        );
+        result
+            .raw_message
+            .extend_from_slice(&raw_message[offset_header..offset_body]);
+        result.parts.push((blob_id, contents));
+        pos = offset_end;
+    }
+    result.raw_message.extend_from_slice(&raw_message[pos..]);
+
+    Some(result)
+}
+
+/// Removes all `X-Detached-Part` headers from a message received from an
+/// external sender, so that only stubs written by the server are ever present
+/// in stored messages. Returns `None` if no such header was found.
+pub fn mail_strip_detached_headers(raw_message: &[u8]) -> Option<Vec<u8>> {
+    if !raw_message
+        .windows(DETACHED_PART_HEADER.len())
+        .any(|w| w.eq_ignore_ascii_case(DETACHED_PART_HEADER.as_bytes()))
+    {
+        return None;
+    }
+
+    let message = Message::parse(raw_message)?;
+    let mut ranges = Vec::new();
+
+    for part in &message.parts {
+        for header in part.headers.iter().filter(|h| is_detached_header(h)) {
+            let mut offset_end = header.offset_end.min(raw_message.len());
+            if !raw_message[..offset_end].ends_with(b"\n") {
+                if raw_message[offset_end..].starts_with(b"\r\n") {
+                    offset_end += 2;
+                } else if raw_message[offset_end..].starts_with(b"\n") {
+                    offset_end += 1;
+                }
+            }
+            ranges.push((header.offset_field, offset_end));
+        }
+    }
+
+    if ranges.is_empty() {
+        return None;
+    }
+    ranges.sort_unstable();
+
+    let mut result = Vec::with_capacity(raw_message.len());
+    let mut pos = 0;
+    for (offset_start, offset_end) in ranges {
+        if offset_start < pos || offset_start > offset_end {
+            return None;
+        }
+        result.extend_from_slice(&raw_message[pos..offset_start]);
+        pos = offset_end;
+    }
+    result.extend_from_slice(&raw_message[pos..]);
+
+    Some(result)
+}
+
+fn is_detached_header(header: &Header) -> bool {
+    matches!(&header.name, HeaderName::Other(name) if name.eq_ignore_ascii_case(DETACHED_PART_HEADER))
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::Message;
+
+    use jmap::types::blob::JMAPBlob;
+
+    use super::{mail_detach_parts, mail_strip_detached_headers};
+
+    #[test]
+    fn detach_parts() {
+        let attachment = vec![b'A'; 300];
+        let raw_message = format!(
+            concat!(
+                "From: john@example.org\r\n",
+                "Subject: Detach test\r\n",
+                "Content-Type: multipart/mixed; boundary=\"xyz\"\r\n\r\n",
+                "--xyz\r\n",
+                "Content-Type: text/plain\r\n\r\n",
+                "Hello world\r\n",
+                "--xyz\r\n",
+                "Content-Type: application/octet-stream\r\n",
+                "Content-Disposition: attachment; filename=\"a.bin\"\r\n\r\n",
+                "{}\r\n",
+                "--xyz--\r\n"
+            ),
+            String::from_utf8(attachment.clone()).unwrap()
+        );
+
+        assert!(mail_detach_parts(raw_message.as_bytes(), 1000).is_none());
+
+        let detached = mail_detach_parts(raw_message.as_bytes(), 100).unwrap();
+        assert_eq!(detached.parts.len(), 1);
+        assert_eq!(detached.parts[0].1, attachment);
+        assert!(detached.raw_message.len() < raw_message.len());
+
+        let message = Message::parse(&detached.raw_message).unwrap();
+        assert_eq!(message.parts[1].get_contents(), b"Hello world");
+        assert!(String::from_utf8_lossy(&detached.raw_message)
+            .contains(&JMAPBlob::new(detached.parts[0].0.clone()).to_string()));
+        assert!(message.parts[2].get_contents().is_empty());
+
+        // Already detached parts are left untouched
+        assert!(mail_detach_parts(&detached.raw_message, 0).is_none());
+
+        // Stub headers supplied by a sender are removed
+        let stripped = mail_strip_detached_headers(&detached.raw_message).unwrap();
+        assert!(stripped.len() < detached.raw_message.len());
+        let message = Message::parse(&stripped).unwrap();
+        assert_eq!(message.parts.len(), 3);
+        assert!(!String::from_utf8_lossy(&stripped).contains("X-Detached-Part"));
+        assert!(mail_strip_detached_headers(raw_message.as_bytes()).is_none());
+    }
+}
@@ -0,0 +1,341 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::SystemTime;
+
+use jmap::{
+    orm::{serialize::JMAPOrm, TinyORM},
+    principal::schema::{Principal, Property as PrincipalProperty, Value as PrincipalValue},
+    types::jmap::JMAPId,
+    SUPERUSER_ID,
+};
+use store::{
+    core::{collection::Collection, document::Document, tag::Tag, JMAPIdPrefix},
+    log::changes::ChangeId,
+    read::{
+        comparator::Comparator,
+        filter::{Filter, Query},
+        FilterMapper,
+    },
+    tracing::{debug, info},
+    write::batch::WriteBatch,
+    AccountId, DocumentId, JMAPStore, LongInteger, Store,
+};
+
+use crate::mailbox::schema::{Mailbox, Property, Value};
+
+use super::{retention::JMAPMailRetention, schema::Email, set::JMAPSetMail, MessageField};
+
+/// Number of days after which messages are destroyed from the Trash and
+/// Junk mailboxes, zero disables expunging.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExpungePolicy {
+    pub trash_days: u32,
+    pub junk_days: u32,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ExpungeReport {
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    #[serde(rename = "dryRun")]
+    pub dry_run: bool,
+    pub mailboxes: Vec<MailboxExpunge>,
+    #[serde(skip)]
+    pub change_id: Option<ChangeId>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct MailboxExpunge {
+    pub id: JMAPId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(rename = "olderThanDays")]
+    pub days: u32,
+    pub destroyed: Vec<JMAPId>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub retained: Vec<JMAPId>,
+}
+
+impl ExpungePolicy {
+    fn apply(&mut self, fields: &TinyORM<Principal>) {
+        if let Some(PrincipalValue::Number { value }) =
+            fields.get(&PrincipalProperty::ExpungeTrashDays)
+        {
+            self.trash_days = (*value).clamp(0, u32::MAX as i64) as u32;
+        }
+        if let Some(PrincipalValue::Number { value }) =
+            fields.get(&PrincipalProperty::ExpungeJunkDays)
+        {
+            self.junk_days = (*value).clamp(0, u32::MAX as i64) as u32;
+        }
+    }
+}
+
+impl ExpungeReport {
+    pub fn total_destroyed(&self) -> usize {
+        self.mailboxes.iter().map(|m| m.destroyed.len()).sum()
+    }
+}
+
+pub trait JMAPMailExpunge<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_expunge_policy(&self, account_id: AccountId) -> store::Result<ExpungePolicy>;
+
+    fn mail_expunge(&self, account_id: AccountId, dry_run: bool) -> store::Result<ExpungeReport>;
+}
+
+impl<T> JMAPMailExpunge<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_expunge_policy(&self, account_id: AccountId) -> store::Result<ExpungePolicy> {
+        let mut policy = ExpungePolicy {
+            trash_days: self.config.mail_expunge_trash_days,
+            junk_days: self.config.mail_expunge_junk_days,
+        };
+        let fields = if let Some(fields) = self.get_orm::<Principal>(SUPERUSER_ID, account_id)? {
+            fields
+        } else {
+            return Ok(policy);
+        };
+
+        // Domain settings override the server defaults
+        if let Some(domain) = fields
+            .get(&PrincipalProperty::Email)
+            .and_then(|email| match email {
+                PrincipalValue::Text { value } => value
+                    .rsplit_once('@')
+                    .map(|(_, domain)| domain.to_lowercase()),
+                _ => None,
+            })
+        {
+            if let Some(domain_id) = self
+                .query_store::<FilterMapper>(
+                    SUPERUSER_ID,
+                    Collection::Principal,
+                    Filter::and(vec![
+                        Filter::eq(
+                            PrincipalProperty::Type.into(),
+                            Query::Keyword("d".to_string()),
+                        ),
+                        Filter::eq(PrincipalProperty::Name.into(), Query::Index(domain)),
+                    ]),
+                    Comparator::None,
+                )?
+                .next()
+            {
+                if let Some(domain_fields) =
+                    self.get_orm::<Principal>(SUPERUSER_ID, domain_id.get_document_id())?
+                {
+                    policy.apply(&domain_fields);
+                }
+            }
+        }
+
+        // Account settings override the domain settings
+        policy.apply(&fields);
+
+        Ok(policy)
+    }
+
+    fn mail_expunge(&self, account_id: AccountId, dry_run: bool) -> store::Result<ExpungeReport> {
+        let mut report = ExpungeReport {
+            account_id: account_id.into(),
+            dry_run,
+            mailboxes: Vec::new(),
+            change_id: None,
+        };
+        let mailbox_ids =
+            if let Some(mailbox_ids) = self.get_document_ids(account_id, Collection::Mailbox)? {
+                mailbox_ids
+            } else {
+                return Ok(report);
+            };
+        let policy = self.mail_expunge_policy(account_id)?;
+        let retention = self.mail_retention(account_id)?;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let _lock = if !dry_run {
+            self.lock_collection(account_id, Collection::Mail).into()
+        } else {
+            None
+        };
+
+        for mailbox_id in mailbox_ids {
+            let fields = if let Some(fields) = self.get_orm::<Mailbox>(account_id, mailbox_id)? {
+                fields
+            } else {
+                continue;
+            };
+
+            // A mailbox setting takes precedence over the role policy
+            let role = fields
+                .get(&Property::Role)
+                .and_then(|role| role.as_text())
+                .map(|role| role.to_string());
+            let days = match fields.get(&Property::ExpungeDays) {
+                Some(Value::Number { value }) => *value,
+                _ => match role.as_deref() {
+                    Some("trash") => policy.trash_days,
+                    Some("junk") => policy.junk_days,
+                    _ => 0,
+                },
+            };
+            if days == 0 {
+                continue;
+            }
+
+            // Messages are aged from the time they were stored rather than from their
+            // receivedAt, which clients can set. Messages stored before the insertion
+            // time was recorded fall back to receivedAt.
+            let cutoff = now.saturating_sub(days as u64 * 86400) as LongInteger;
+            let message_ids = self
+                .query_store::<FilterMapper>(
+                    account_id,
+                    Collection::Mail,
+                    Filter::and(vec![
+                        Filter::eq(
+                            MessageField::Mailbox.into(),
+                            Query::Tag(Tag::Id(mailbox_id)),
+                        ),
+                        Filter::or(vec![
+                            Filter::lt(MessageField::InsertedAt.into(), Query::LongInteger(cutoff)),
+                            Filter::and(vec![
+                                Filter::not(vec![Filter::ge(
+                                    MessageField::InsertedAt.into(),
+                                    Query::LongInteger(0),
+                                )]),
+                                Filter::lt(
+                                    MessageField::ReceivedAt.into(),
+                                    Query::LongInteger(cutoff),
+                                ),
+                            ]),
+                        ]),
+                    ]),
+                    Comparator::None,
+                )?
+                .into_bitmap();
+            if message_ids.is_empty() {
+                continue;
+            }
+
+            let mut batch = WriteBatch::new(account_id);
+            let mut destroyed = Vec::with_capacity(message_ids.len() as usize);
+            let mut retained = Vec::new();
+
+            for message_id in message_ids {
+                let thread_id = if let Some(thread_id) = self.get_document_value::<DocumentId>(
+                    account_id,
+                    Collection::Mail,
+                    message_id,
+                    MessageField::ThreadId.into(),
+                )? {
+                    thread_id
+                } else {
+                    debug!(
+                        "Failed to fetch threadId for {}:{}.",
+                        account_id, message_id
+                    );
+                    continue;
+                };
+                let id = JMAPId::from_parts(thread_id, message_id);
+
+                // Messages under legal hold or within their retention period are kept
+                if let Some(reason) = retention.check(message_id) {
+                    if !dry_run {
+                        info!(
+                            target: "audit",
+                            "Expunge skipped message {}:{} ({}).",
+                            account_id,
+                            message_id,
+                            reason.as_str()
+                        );
+                    }
+                    retained.push(id);
+                    continue;
+                }
+
+                destroyed.push(id);
+                if dry_run {
+                    continue;
+                }
+
+                let current_fields =
+                    if let Some(current_fields) = self.get_orm::<Email>(account_id, message_id)? {
+                        current_fields
+                    } else {
+                        debug!("Email ORM for {}:{} not found", account_id, message_id);
+                        continue;
+                    };
+
+                // If the message is in multiple mailboxes, untag it from the expunged mailbox,
+                // otherwise delete it.
+                let mut document = Document::new(Collection::Mail, message_id);
+                match current_fields.get_tags(&super::schema::Property::MailboxIds) {
+                    Some(tags) if tags.len() > 1 => {
+                        let mut fields = TinyORM::track_changes(&current_fields);
+                        fields.untag(&super::schema::Property::MailboxIds, &Tag::Id(mailbox_id));
+                        current_fields.merge(&mut document, fields)?;
+                        batch.update_document(document);
+                        batch.log_update(Collection::Mail, id);
+                        batch.log_child_update(Collection::Mailbox, mailbox_id);
+                    }
+                    _ => {
+                        if let Some(id) =
+                            self.mail_delete(account_id, Some(&mut batch), &mut document)?
+                        {
+                            batch.delete_document(document);
+                            batch.log_delete(Collection::Mail, id);
+                        }
+                    }
+                }
+            }
+
+            // Each mailbox is written separately, as a message could be expunged from
+            // more than one mailbox.
+            if !batch.is_empty() {
+                if let Some(changes) = self.write(batch)? {
+                    report.change_id = changes.change_id.into();
+                }
+            }
+
+            if !destroyed.is_empty() || !retained.is_empty() {
+                report.mailboxes.push(MailboxExpunge {
+                    id: mailbox_id.into(),
+                    role,
+                    days,
+                    destroyed,
+                    retained,
+                });
+            }
+        }
+
+        Ok(report)
+    }
+}
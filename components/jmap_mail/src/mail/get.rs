@@ -22,12 +22,15 @@
 */
 
 use super::{
+    annotations::JMAPMailAnnotations,
+    body_convert::BodyConversion,
     conv::IntoForm,
     schema::{
-        BodyProperty, Email, EmailBodyPart, EmailBodyValue, EmailHeader, HeaderForm,
-        HeaderProperty, Property, Value,
+        BodyProperty, Email, EmailBodyPart, EmailBodyValue, EmailHeader, EmailSnooze, HeaderForm,
+        Property, Value,
     },
     sharing::JMAPShareMail,
+    truncate::{truncate_body_html, truncate_body_text},
     GetRawHeader, HeaderName, MessagePart,
 };
 use crate::mail::{MessageData, MessageField, MimePart, MimePartType};
@@ -43,7 +46,7 @@ use jmap::{
     SUPERUSER_ID,
 };
 use mail_parser::{
-    parsers::preview::{preview_html, preview_text, truncate_html, truncate_text},
+    parsers::preview::{preview_html, preview_text},
     Encoding, HeaderValue, RfcHeader,
 };
 use std::{borrow::Cow, sync::Arc};
@@ -144,6 +147,23 @@ pub enum BlobResult {
     NotFound,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobAccess {
+    Granted,
+    Unauthorized,
+    NotFound,
+}
+
+pub enum CidResult {
+    Part {
+        bytes: Vec<u8>,
+        content_type: Option<String>,
+        name: Option<String>,
+    },
+    Unauthorized,
+    NotFound,
+}
+
 pub trait JMAPGetMail<T>
 where
     T: for<'x> Store<'x> + 'static,
@@ -155,6 +175,23 @@ where
         acl: &Arc<ACLToken>,
         blob: &JMAPBlob,
     ) -> store::Result<BlobResult>;
+
+    fn mail_blob_access(
+        &self,
+        account_id: AccountId,
+        acl: &Arc<ACLToken>,
+        blob_ids: &[&BlobId],
+    ) -> store::Result<Vec<BlobAccess>>;
+
+    fn mail_blob_fetch(&self, blob: &JMAPBlob) -> store::Result<Option<Vec<u8>>>;
+
+    fn mail_cid_get(
+        &self,
+        account_id: AccountId,
+        acl: &Arc<ACLToken>,
+        document_id: DocumentId,
+        cid: &str,
+    ) -> store::Result<CidResult>;
 }
 
 impl<T> JMAPGetMail<T> for JMAPStore<T>
@@ -217,16 +254,15 @@ where
         // Check whether any parts of the raw message need to be fetched
         let mut fetch_raw = FetchRaw::None;
         let mut has_id = false;
+        let mut has_body_parts = false;
         for property in &helper.properties {
             match property {
-                Property::Header(HeaderProperty {
-                    form: HeaderForm::Raw,
-                    ..
-                })
-                | Property::Header(HeaderProperty {
-                    header: HeaderName::Other(_),
-                    ..
-                }) => {
+                Property::Header(header) if header.needs_raw_header() => {
+                    if fetch_raw != FetchRaw::All {
+                        fetch_raw = FetchRaw::Header;
+                    }
+                }
+                Property::Headers => {
                     if fetch_raw != FetchRaw::All {
                         fetch_raw = FetchRaw::Header;
                     }
@@ -234,6 +270,9 @@ where
                 Property::BodyStructure | Property::BodyValues | Property::Preview => {
                     fetch_raw = FetchRaw::All;
                 }
+                Property::TextBody | Property::HtmlBody | Property::Attachments => {
+                    has_body_parts = true;
+                }
                 Property::Id => {
                     has_id = true;
                 }
@@ -242,6 +281,7 @@ where
         }
 
         if fetch_raw != FetchRaw::All
+            && has_body_parts
             && body_properties
                 .iter()
                 .any(|prop| matches!(prop, BodyProperty::Headers | BodyProperty::Header(_)))
@@ -254,148 +294,163 @@ where
             helper.properties.push(Property::Id);
         }
 
-        // Get items
-        helper.get(|id, properties| {
-            let document_id = id.get_document_id();
+        // Annotations are private to each principal
+        let principal_id = helper.acl.primary_id();
 
-            // Fetch message metadata
-            let message_data_bytes = self
-                .blob_get(
-                    &self
-                        .get_document_value::<BlobId>(
-                            account_id,
-                            Collection::Mail,
-                            document_id,
-                            MessageField::Metadata.into(),
+        // Get items in batches, fetching the metadata blob ids of each batch at once
+        let chunk_size = self.config.mail_get_chunk_size;
+        let chunk_fnc = |ids: &[JMAPId]| {
+            self.get_multi_document_value::<BlobId>(
+                account_id,
+                Collection::Mail,
+                ids.iter().map(|id| id.get_document_id()),
+                MessageField::Metadata.into(),
+            )
+            .map_err(MethodError::from)
+        };
+        helper.get_chunked(
+            chunk_size,
+            chunk_fnc,
+            |id, pos, properties, metadata_ids| {
+                let document_id = id.get_document_id();
+
+                // Fetch message metadata
+                let message_data_bytes = self
+                    .blob_get(
+                        &metadata_ids
+                            .get_mut(pos)
+                            .and_then(Option::take)
+                            .ok_or_else(|| {
+                                StoreError::NotFound(format!(
+                                    "Email metadata blobId for {}/{} does not exist.",
+                                    account_id, document_id
+                                ))
+                            })?,
+                    )?
+                    .ok_or_else(|| {
+                        StoreError::NotFound(format!(
+                            "Email metadata blob linked to {}/{} does not exist.",
+                            account_id, document_id
+                        ))
+                    })?;
+
+                // Deserialize message data
+                let mut message_data =
+                    MessageData::deserialize(&message_data_bytes).ok_or_else(|| {
+                        StoreError::DataCorruption(format!(
+                            "Failed to deserialize email metadata for {}/{}",
+                            account_id, document_id
+                        ))
+                    })?;
+
+                // Fetch raw message only if needed
+                let raw_message = match &fetch_raw {
+                    FetchRaw::All => {
+                        Some(self.blob_get(&message_data.raw_message)?.ok_or_else(|| {
+                            StoreError::NotFound(format!(
+                                "Raw email message not found for {}/{}.",
+                                account_id, document_id
+                            ))
+                        })?)
+                    }
+                    FetchRaw::Header => Some(
+                        self.blob_get_range(
+                            &message_data.raw_message,
+                            0..message_data.body_offset as u32,
                         )?
                         .ok_or_else(|| {
                             StoreError::NotFound(format!(
-                                "Email metadata blobId for {}/{} does not exist.",
+                                "Raw email message not found for {}/{}.",
                                 account_id, document_id
                             ))
                         })?,
-                )?
-                .ok_or_else(|| {
-                    StoreError::NotFound(format!(
-                        "Email metadata blob linked to {}/{} does not exist.",
-                        account_id, document_id
-                    ))
-                })?;
+                    ),
+                    FetchRaw::None => None,
+                };
 
-            // Deserialize message data
-            let mut message_data =
-                MessageData::deserialize(&message_data_bytes).ok_or_else(|| {
-                    StoreError::DataCorruption(format!(
-                        "Failed to deserialize email metadata for {}/{}",
-                        account_id, document_id
-                    ))
-                })?;
+                // Fetch ORM
+                let fields = self
+                    .get_orm::<Email>(account_id, document_id)?
+                    .ok_or_else(|| StoreError::NotFound("ORM not found for Email.".to_string()))?;
+                let blob_id = JMAPBlob::from(&message_data.raw_message);
 
-            // Fetch raw message only if needed
-            let raw_message = match &fetch_raw {
-                FetchRaw::All => {
-                    Some(self.blob_get(&message_data.raw_message)?.ok_or_else(|| {
-                        StoreError::NotFound(format!(
-                            "Raw email message not found for {}/{}.",
-                            account_id, document_id
-                        ))
-                    })?)
-                }
-                FetchRaw::Header => Some(
-                    self.blob_get_range(
-                        &message_data.raw_message,
-                        0..message_data.body_offset as u32,
-                    )?
-                    .ok_or_else(|| {
-                        StoreError::NotFound(format!(
-                            "Raw email message not found for {}/{}.",
-                            account_id, document_id
-                        ))
-                    })?,
-                ),
-                FetchRaw::None => None,
-            };
-
-            // Fetch ORM
-            let fields = self
-                .get_orm::<Email>(account_id, document_id)?
-                .ok_or_else(|| StoreError::NotFound("ORM not found for Email.".to_string()))?;
-            let blob_id = JMAPBlob::from(&message_data.raw_message);
-
-            // Add requested properties to result
-            let mut email = VecMap::with_capacity(properties.len());
-            for property in properties {
-                let value = match property {
-                    Property::Id => Value::Id { value: id }.into(),
-                    Property::BlobId => Value::Blob {
-                        value: blob_id.clone(),
-                    }
-                    .into(),
-                    Property::ThreadId => Value::Id {
-                        value: id.get_prefix_id().into(),
-                    }
-                    .into(),
-                    Property::MailboxIds => {
-                        fields
-                            .get_tags(&Property::MailboxIds)
-                            .map(|tags| Value::MailboxIds {
-                                value: tags
-                                    .iter()
-                                    .map(|tag| (MaybeIdReference::Value(tag.as_id().into()), true))
-                                    .collect(),
+                // Add requested properties to result
+                let mut email = VecMap::with_capacity(properties.len());
+                for property in properties {
+                    let value = match property {
+                        Property::Id => Value::Id { value: id }.into(),
+                        Property::BlobId => Value::Blob {
+                            value: blob_id.clone(),
+                        }
+                        .into(),
+                        Property::ThreadId => Value::Id {
+                            value: id.get_prefix_id().into(),
+                        }
+                        .into(),
+                        Property::MailboxIds => {
+                            fields
+                                .get_tags(&Property::MailboxIds)
+                                .map(|tags| Value::MailboxIds {
+                                    value: tags
+                                        .iter()
+                                        .map(|tag| {
+                                            (MaybeIdReference::Value(tag.as_id().into()), true)
+                                        })
+                                        .collect(),
+                                    set: true,
+                                })
+                        }
+                        Property::Keywords => fields
+                            .get_tags(&Property::Keywords)
+                            .map(|tags| Value::Keywords {
+                                value: tags.iter().map(|tag| (tag.into(), true)).collect(),
                                 set: true,
                             })
-                    }
-                    Property::Keywords => fields
-                        .get_tags(&Property::Keywords)
-                        .map(|tags| Value::Keywords {
-                            value: tags.iter().map(|tag| (tag.into(), true)).collect(),
-                            set: true,
-                        })
-                        .unwrap_or(Value::Keywords {
-                            value: VecMap::new(),
-                            set: true,
-                        })
+                            .unwrap_or(Value::Keywords {
+                                value: VecMap::new(),
+                                set: true,
+                            })
+                            .into(),
+                        Property::Size => Value::Size {
+                            value: message_data.size,
+                        }
                         .into(),
-                    Property::Size => Value::Size {
-                        value: message_data.size,
-                    }
-                    .into(),
-                    Property::ReceivedAt => Value::Date {
-                        value: JMAPDate::from_timestamp(message_data.received_at),
-                    }
-                    .into(),
-                    Property::MessageId | Property::InReplyTo | Property::References => {
-                        message_data.header(
+                        Property::ReceivedAt => Value::Date {
+                            value: JMAPDate::from_timestamp(message_data.received_at),
+                        }
+                        .into(),
+                        Property::MessageId | Property::InReplyTo | Property::References => {
+                            message_data.header(
+                                &property.as_rfc_header(),
+                                &HeaderForm::MessageIds,
+                                false,
+                            )
+                        }
+                        Property::Sender
+                        | Property::From
+                        | Property::To
+                        | Property::Cc
+                        | Property::Bcc
+                        | Property::ReplyTo => message_data.header(
                             &property.as_rfc_header(),
-                            &HeaderForm::MessageIds,
+                            &HeaderForm::Addresses,
                             false,
-                        )
-                    }
-                    Property::Sender
-                    | Property::From
-                    | Property::To
-                    | Property::Cc
-                    | Property::Bcc
-                    | Property::ReplyTo => message_data.header(
-                        &property.as_rfc_header(),
-                        &HeaderForm::Addresses,
-                        false,
-                    ),
-                    Property::Subject => {
-                        message_data.header(&RfcHeader::Subject, &HeaderForm::Text, false)
-                    }
-                    Property::SentAt => {
-                        message_data.header(&RfcHeader::Date, &HeaderForm::Date, false)
-                    }
-                    Property::HasAttachment => Value::Bool {
-                        value: message_data.has_attachments,
-                    }
-                    .into(),
-                    Property::Header(header) => {
-                        match (&header.header, &header.form, &raw_message) {
-                            (HeaderName::Other(_), _, Some(raw_message))
-                            | (HeaderName::Rfc(_), HeaderForm::Raw, Some(raw_message)) => {
+                        ),
+                        Property::Subject => {
+                            message_data.header(&RfcHeader::Subject, &HeaderForm::Text, false)
+                        }
+                        Property::SentAt => {
+                            message_data.header(&RfcHeader::Date, &HeaderForm::Date, false)
+                        }
+                        Property::HasAttachment => Value::Bool {
+                            value: message_data.has_attachments,
+                        }
+                        .into(),
+                        Property::Header(header) => match (&header.header, &raw_message) {
+                            (HeaderName::Rfc(header_name), _) if !header.needs_raw_header() => {
+                                message_data.header(header_name, &header.form, header.all)
+                            }
+                            (_, Some(raw_message)) => {
                                 if let Some(offsets) = message_data
                                     .mime_parts
                                     .first()
@@ -411,166 +466,217 @@ where
                                     None
                                 }
                             }
-                            (HeaderName::Rfc(header_name), _, _) => {
-                                message_data.header(header_name, &header.form, header.all)
-                            }
                             _ => None,
-                        }
-                    }
-                    Property::Headers => Value::Headers {
-                        value: if let Some(root_part) = message_data.mime_parts.first() {
-                            root_part.as_email_headers(raw_message.as_ref().unwrap())
-                        } else {
-                            Vec::new()
                         },
-                    }
-                    .into(),
-                    Property::Preview => {
-                        if !message_data.text_body.is_empty() || !message_data.html_body.is_empty()
-                        {
-                            let parts = if !message_data.text_body.is_empty() {
-                                &message_data.text_body
+                        Property::Headers => Value::Headers {
+                            value: if let Some(root_part) = message_data.mime_parts.first() {
+                                root_part.as_email_headers(raw_message.as_ref().unwrap())
                             } else {
-                                &message_data.html_body
-                            };
-
-                            let mime_part = if let Some(mime_part) =
-                                parts.first().and_then(|p| message_data.mime_parts.get(*p))
+                                Vec::new()
+                            },
+                        }
+                        .into(),
+                        Property::Preview => {
+                            if !message_data.text_body.is_empty()
+                                || !message_data.html_body.is_empty()
                             {
-                                mime_part
-                            } else {
-                                error!("Missing message part for {}/{}", account_id, document_id);
-                                continue;
-                            };
-
-                            #[allow(clippy::type_complexity)]
-                            let (preview_fnc, part): (
-                                fn(Cow<str>, usize) -> Cow<str>,
-                                _,
-                            ) = match &mime_part.mime_type {
-                                MimePartType::Text { part } => (preview_text, part),
-                                MimePartType::Html { part } => (preview_html, part),
-                                _ => {
-                                    return Err(StoreError::NotFound(format!(
-                                        "Message part blobId not found for {}/{}.",
+                                let parts = if !message_data.text_body.is_empty() {
+                                    &message_data.text_body
+                                } else {
+                                    &message_data.html_body
+                                };
+
+                                let mime_part = if let Some(mime_part) =
+                                    parts.first().and_then(|p| message_data.mime_parts.get(*p))
+                                {
+                                    mime_part
+                                } else {
+                                    error!(
+                                        "Missing message part for {}/{}",
                                         account_id, document_id
-                                    ))
-                                    .into());
-                                }
-                            };
+                                    );
+                                    continue;
+                                };
 
-                            Value::Text {
-                                value: preview_fnc(
-                                    part.decode_text(
-                                        raw_message.as_ref().unwrap(),
-                                        mime_part.charset.as_deref(),
-                                        true,
-                                    )
-                                    .unwrap_or_else(|| {
-                                        error!(
-                                            "Failed to decode part for {}/{}.",
-                                            account_id, document_id
-                                        );
-                                        "".to_string()
-                                    })
-                                    .into(),
-                                    256,
-                                )
-                                .into_owned(),
-                            }
-                            .into()
-                        } else {
-                            None
-                        }
-                    }
-                    Property::BodyValues => {
-                        let mut body_values = VecMap::new();
-                        for (part_id, mime_part) in message_data.mime_parts.iter().enumerate() {
-                            if (message_data.html_body.contains(&part_id)
-                                && (fetch_all_body_values || fetch_html_body_values))
-                                || (message_data.text_body.contains(&part_id)
-                                    && (fetch_all_body_values || fetch_text_body_values))
-                            {
-                                let text = mime_part
-                                    .mime_type
-                                    .part()
-                                    .ok_or_else(|| {
-                                        StoreError::NotFound(format!(
-                                            "BodyValue not found for {}/{}.",
+                                #[allow(clippy::type_complexity)]
+                                let (preview_fnc, part): (
+                                    fn(Cow<str>, usize) -> Cow<str>,
+                                    _,
+                                ) = match &mime_part.mime_type {
+                                    MimePartType::Text { part } => (preview_text, part),
+                                    MimePartType::Html { part } => (preview_html, part),
+                                    _ => {
+                                        return Err(StoreError::NotFound(format!(
+                                            "Message part blobId not found for {}/{}.",
                                             account_id, document_id
                                         ))
-                                    })?
-                                    .decode_text(
-                                        raw_message.as_ref().unwrap(),
-                                        mime_part.charset.as_deref(),
-                                        true,
+                                        .into());
+                                    }
+                                };
+
+                                Value::Text {
+                                    value: preview_fnc(
+                                        part.decode_text(
+                                            raw_message.as_ref().unwrap(),
+                                            mime_part.charset.as_deref(),
+                                            true,
+                                        )
+                                        .unwrap_or_else(|| {
+                                            error!(
+                                                "Failed to decode part for {}/{}.",
+                                                account_id, document_id
+                                            );
+                                            "".to_string()
+                                        })
+                                        .into(),
+                                        256,
                                     )
-                                    .unwrap_or_else(|| {
-                                        error!(
-                                            "Failed to decode BodyValue for {}/{}.",
-                                            account_id, document_id
+                                    .into_owned(),
+                                }
+                                .into()
+                            } else {
+                                None
+                            }
+                        }
+                        Property::BodyValues => {
+                            let mut body_values = VecMap::new();
+                            for (part_id, mime_part) in message_data.mime_parts.iter().enumerate() {
+                                let fetch_html = message_data.html_body.contains(&part_id)
+                                    && (fetch_all_body_values || fetch_html_body_values);
+                                let fetch_text = message_data.text_body.contains(&part_id)
+                                    && (fetch_all_body_values || fetch_text_body_values);
+                                if fetch_html || fetch_text {
+                                    // Parts only requested in the other format are converted
+                                    let conversion = match &mime_part.mime_type {
+                                        MimePartType::Html { .. } if !fetch_html => {
+                                            Some(BodyConversion::HtmlToText)
+                                        }
+                                        MimePartType::Text { .. } if !fetch_text => {
+                                            Some(BodyConversion::TextToHtml)
+                                        }
+                                        _ => None,
+                                    };
+                                    let cache_key = (blob_id.id.clone(), part_id as u32);
+                                    if let Some((conversion, body)) =
+                                        conversion.and_then(|conversion| {
+                                            self.body_conversions
+                                                .get(&cache_key)
+                                                .map(|body| (conversion, body))
+                                        })
+                                    {
+                                        body_values.append(
+                                            part_id.to_string(),
+                                            mime_part.as_converted_body_value(
+                                                body.as_ref().clone(),
+                                                conversion,
+                                                max_body_value_bytes,
+                                            ),
                                         );
-                                        "".to_string()
-                                    });
+                                        continue;
+                                    }
+
+                                    let text = mime_part
+                                        .mime_type
+                                        .part()
+                                        .ok_or_else(|| {
+                                            StoreError::NotFound(format!(
+                                                "BodyValue not found for {}/{}.",
+                                                account_id, document_id
+                                            ))
+                                        })?
+                                        .decode_text(
+                                            raw_message.as_ref().unwrap(),
+                                            mime_part.charset.as_deref(),
+                                            true,
+                                        )
+                                        .unwrap_or_else(|| {
+                                            error!(
+                                                "Failed to decode BodyValue for {}/{}.",
+                                                account_id, document_id
+                                            );
+                                            "".to_string()
+                                        });
 
-                                body_values.append(
-                                    part_id.to_string(),
-                                    mime_part.as_body_value(text, max_body_value_bytes),
-                                );
+                                    body_values.append(
+                                        part_id.to_string(),
+                                        if let Some(conversion) = conversion {
+                                            let body = Arc::new(conversion.convert(&text));
+                                            self.body_conversions.insert(cache_key, body.clone());
+                                            mime_part.as_converted_body_value(
+                                                body.as_ref().clone(),
+                                                conversion,
+                                                max_body_value_bytes,
+                                            )
+                                        } else {
+                                            mime_part.as_body_value(text, max_body_value_bytes)
+                                        },
+                                    );
+                                }
                             }
+                            Value::BodyValues { value: body_values }.into()
                         }
-                        Value::BodyValues { value: body_values }.into()
-                    }
-                    Property::TextBody => Some(
-                        message_data
-                            .mime_parts
-                            .as_body_parts(
-                                &message_data.text_body,
-                                &body_properties,
-                                raw_message.as_deref(),
-                                &blob_id,
-                            )
-                            .into(),
-                    ),
-                    Property::HtmlBody => Some(
-                        message_data
-                            .mime_parts
-                            .as_body_parts(
-                                &message_data.html_body,
-                                &body_properties,
-                                raw_message.as_deref(),
-                                &blob_id,
-                            )
-                            .into(),
-                    ),
-                    Property::Attachments => Some(
-                        message_data
+                        Property::TextBody => Some(
+                            message_data
+                                .mime_parts
+                                .as_body_parts(
+                                    &message_data.text_body,
+                                    &body_properties,
+                                    raw_message.as_deref(),
+                                    &blob_id,
+                                )
+                                .into(),
+                        ),
+                        Property::HtmlBody => Some(
+                            message_data
+                                .mime_parts
+                                .as_body_parts(
+                                    &message_data.html_body,
+                                    &body_properties,
+                                    raw_message.as_deref(),
+                                    &blob_id,
+                                )
+                                .into(),
+                        ),
+                        Property::Attachments => Some(
+                            message_data
+                                .mime_parts
+                                .as_body_parts(
+                                    &message_data.attachments,
+                                    &body_properties,
+                                    raw_message.as_deref(),
+                                    &blob_id,
+                                )
+                                .into(),
+                        ),
+                        Property::BodyStructure => message_data
                             .mime_parts
-                            .as_body_parts(
-                                &message_data.attachments,
-                                &body_properties,
-                                raw_message.as_deref(),
-                                &blob_id,
-                            )
-                            .into(),
-                    ),
-                    Property::BodyStructure => message_data
-                        .mime_parts
-                        .as_body_structure(&body_properties, raw_message.as_deref(), &blob_id)
-                        .map(|b| b.into()),
-                    Property::Invalid(property) => {
-                        return Err(MethodError::InvalidArguments(format!(
-                            "Unknown property {:?}",
-                            property
-                        )));
-                    }
-                };
+                            .as_body_structure(&body_properties, raw_message.as_deref(), &blob_id)
+                            .map(|b| b.into()),
+                        Property::Annotations => Value::Annotations {
+                            value: self
+                                .mail_annotations_get(account_id, document_id, principal_id)?
+                                .into_iter()
+                                .map(|(name, value)| (name, Some(value)))
+                                .collect(),
+                            set: true,
+                        }
+                        .into(),
+                        Property::Snoozed => EmailSnooze::from_fields(&fields)
+                            .map(|(value, _)| Value::Snoozed { value }),
+                        Property::Invalid(property) => {
+                            return Err(MethodError::InvalidArguments(format!(
+                                "Unknown property {:?}",
+                                property
+                            )));
+                        }
+                    };
 
-                email.append(property.clone(), value.unwrap_or_default());
-            }
+                    email.append(property.clone(), value.unwrap_or_default());
+                }
 
-            Ok(Some(Email { properties: email }))
-        })
+                Ok(Some(Email { properties: email }))
+            },
+        )
     }
 
     fn mail_blob_get(
@@ -598,6 +704,65 @@ where
             }
         }
 
+        Ok(self
+            .mail_blob_fetch(blob)?
+            .map(BlobResult::Blob)
+            .unwrap_or(BlobResult::NotFound))
+    }
+
+    fn mail_blob_access(
+        &self,
+        account_id: AccountId,
+        acl: &Arc<ACLToken>,
+        blob_ids: &[&BlobId],
+    ) -> store::Result<Vec<BlobAccess>> {
+        // Access is verified for all blobs at once, followed by a single
+        // existence check for the blobs that can be accessed.
+        let mut has_access = if !acl.is_member(SUPERUSER_ID) {
+            self.blob_account_has_access_multi(blob_ids, &acl.member_of)?
+        } else {
+            vec![true; blob_ids.len()]
+        };
+        if has_access.iter().any(|has_access| !has_access) {
+            if let Some(shared_ids) = self
+                .mail_shared_messages(account_id, &acl.member_of, ACL::ReadItems)?
+                .as_ref()
+            {
+                for (blob_id, has_access) in blob_ids.iter().zip(has_access.iter_mut()) {
+                    if !*has_access {
+                        *has_access = self.blob_document_has_access(
+                            blob_id,
+                            account_id,
+                            Collection::Mail,
+                            shared_ids,
+                        )?;
+                    }
+                }
+            }
+        }
+
+        let granted_ids = blob_ids
+            .iter()
+            .zip(has_access.iter())
+            .filter_map(|(blob_id, has_access)| if *has_access { Some(*blob_id) } else { None })
+            .collect::<Vec<_>>();
+        let mut exists = self.blob_exists_multi(&granted_ids)?.into_iter();
+
+        Ok(has_access
+            .into_iter()
+            .map(|has_access| {
+                if !has_access {
+                    BlobAccess::Unauthorized
+                } else if exists.next().unwrap_or(false) {
+                    BlobAccess::Granted
+                } else {
+                    BlobAccess::NotFound
+                }
+            })
+            .collect())
+    }
+
+    fn mail_blob_fetch(&self, blob: &JMAPBlob) -> store::Result<Option<Vec<u8>>> {
         Ok(if let Some(section) = &blob.section {
             self.blob_get_range(
                 &blob.id,
@@ -619,9 +784,103 @@ where
             })
         } else {
             self.blob_get(&blob.id)?
+        })
+    }
+
+    fn mail_cid_get(
+        &self,
+        account_id: AccountId,
+        acl: &Arc<ACLToken>,
+        document_id: DocumentId,
+        cid: &str,
+    ) -> store::Result<CidResult> {
+        // Make sure the message exists and can be read
+        if !self
+            .get_document_ids(account_id, Collection::Mail)?
+            .map_or(false, |ids| ids.contains(document_id))
+        {
+            return Ok(CidResult::NotFound);
+        } else if !acl.is_member(account_id)
+            && !acl.is_member(SUPERUSER_ID)
+            && !self
+                .mail_shared_messages(account_id, &acl.member_of, ACL::ReadItems)?
+                .as_ref()
+                .as_ref()
+                .map_or(false, |shared_ids| shared_ids.contains(document_id))
+        {
+            return Ok(CidResult::Unauthorized);
         }
-        .map(BlobResult::Blob)
-        .unwrap_or(BlobResult::NotFound))
+
+        // Fetch message metadata
+        let message_data = MessageData::deserialize(
+            &self
+                .blob_get(
+                    &self
+                        .get_document_value::<BlobId>(
+                            account_id,
+                            Collection::Mail,
+                            document_id,
+                            MessageField::Metadata.into(),
+                        )?
+                        .ok_or_else(|| {
+                            StoreError::NotFound(format!(
+                                "Email metadata blobId for {}/{} does not exist.",
+                                account_id, document_id
+                            ))
+                        })?,
+                )?
+                .ok_or_else(|| {
+                    StoreError::NotFound(format!(
+                        "Email metadata blob linked to {}/{} does not exist.",
+                        account_id, document_id
+                    ))
+                })?,
+        )
+        .ok_or_else(|| {
+            StoreError::DataCorruption(format!(
+                "Failed to deserialize email metadata for {}/{}",
+                account_id, document_id
+            ))
+        })?;
+
+        // Find the part referenced by the Content-ID
+        let cid = cid.trim_start_matches('<').trim_end_matches('>');
+        let (mime_part, part) = if let Some(result) =
+            message_data.mime_parts.iter().find_map(|mime_part| {
+                let part = mime_part.mime_type.part()?;
+                if mime_part.cid.as_deref().map_or(false, |part_cid| {
+                    part_cid.trim_start_matches('<').trim_end_matches('>') == cid
+                }) {
+                    Some((mime_part, part))
+                } else {
+                    None
+                }
+            }) {
+            result
+        } else {
+            return Ok(CidResult::NotFound);
+        };
+
+        Ok(
+            match self.mail_blob_get(
+                account_id,
+                acl,
+                &JMAPBlob::new_section(
+                    message_data.raw_message.clone(),
+                    part.offset_start,
+                    part.offset_end,
+                    part.encoding as u8,
+                ),
+            )? {
+                BlobResult::Blob(bytes) => CidResult::Part {
+                    bytes,
+                    content_type: mime_part.type_.clone(),
+                    name: mime_part.name.clone(),
+                },
+                BlobResult::Unauthorized => CidResult::Unauthorized,
+                BlobResult::NotFound => CidResult::NotFound,
+            },
+        )
     }
 }
 
@@ -817,15 +1076,38 @@ impl MimePart {
     }
 
     pub fn as_body_value(&self, body_value: String, max_body_value: usize) -> EmailBodyValue {
+        self.body_value(
+            body_value,
+            matches!(&self.mime_type, MimePartType::Html { .. }),
+            max_body_value,
+        )
+    }
+
+    /// Returns a body value generated from this part in the other format.
+    pub fn as_converted_body_value(
+        &self,
+        body_value: String,
+        conversion: BodyConversion,
+        max_body_value: usize,
+    ) -> EmailBodyValue {
+        self.body_value(body_value, conversion.is_html(), max_body_value)
+    }
+
+    fn body_value(
+        &self,
+        body_value: String,
+        is_html: bool,
+        max_body_value: usize,
+    ) -> EmailBodyValue {
         EmailBodyValue {
             is_encoding_problem: self.is_encoding_problem.into(),
             is_truncated: (max_body_value > 0 && body_value.len() > max_body_value).into(),
             value: if max_body_value == 0 || body_value.len() <= max_body_value {
                 body_value
-            } else if matches!(&self.mime_type, MimePartType::Html { .. }) {
-                truncate_html(body_value.into(), max_body_value).to_string()
+            } else if is_html {
+                truncate_body_html(body_value, max_body_value)
             } else {
-                truncate_text(body_value.into(), max_body_value).to_string()
+                truncate_body_text(body_value, max_body_value)
             },
         }
     }
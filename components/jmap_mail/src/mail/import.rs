@@ -37,7 +37,7 @@ use jmap::types::jmap::JMAPId;
 use jmap::types::state::JMAPState;
 use mail_parser::decoders::html::html_to_text;
 use mail_parser::parsers::fields::thread::thread_name;
-use mail_parser::{GetHeader, HeaderName, HeaderValue, Message, PartType, RfcHeader};
+use mail_parser::{Encoding, GetHeader, HeaderName, HeaderValue, Message, PartType, RfcHeader};
 use store::ahash::AHashMap;
 use store::ahash::AHashSet;
 use store::blob::BlobId;
@@ -62,11 +62,16 @@ use store::{AccountId, JMAPStore, SharedBitmap, Store, ThreadId};
 use store::{DocumentId, Integer, LongInteger};
 
 use crate::mail::MessageField;
+use crate::thread::preview::MessagePreview;
 
+use super::collation::{Collation, JMAPMailCollation};
 use super::conv::HeaderValueInto;
 use super::get::{BlobResult, JMAPGetMail};
+use super::keywords::JMAPMailKeywords;
+use super::limits::MimeLimits;
 use super::schema::{Email, Keyword, Property};
 use super::sharing::JMAPShareMail;
+use super::unsubscribe::list_id;
 use super::{MessageData, MessagePart, MimePart, MimePartType, MAX_MESSAGE_PARTS};
 
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -128,6 +133,7 @@ pub trait JMAPMailImport {
 
     fn mail_parse_item(
         &self,
+        account_id: AccountId,
         document: &mut Document,
         blob_id: BlobId,
         message: Message,
@@ -140,6 +146,13 @@ pub trait JMAPMailImport {
         document: &mut Document,
     ) -> store::Result<DocumentId>;
 
+    fn mail_find_threads(
+        &self,
+        account_id: AccountId,
+        thread_name: &str,
+        reference_ids: &[&str],
+    ) -> store::Result<AHashSet<ThreadId>>;
+
     fn mail_merge_threads(
         &self,
         documents: &mut WriteBatch,
@@ -152,6 +165,10 @@ where
     T: for<'x> Store<'x> + 'static,
 {
     fn mail_import(&self, request: EmailImportRequest) -> jmap::Result<EmailImportResponse> {
+        if request.emails.len() > self.config.mail_import_max_items {
+            return Err(MethodError::RequestTooLarge);
+        }
+
         let account_id = request.account_id.get_document_id();
         let mailbox_document_ids = self
             .get_document_ids(account_id, Collection::Mailbox)?
@@ -266,6 +283,21 @@ where
                     }
                 }
 
+                // Register custom keywords
+                let keywords = item
+                    .keywords
+                    .map(|keywords| {
+                        keywords
+                            .into_iter()
+                            .filter_map(|(k, set)| if set { k.tag.into() } else { None })
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                if let Err(err) = self.mail_keywords_register(account_id, &keywords) {
+                    not_created.append(id, err);
+                    continue;
+                }
+
                 match self.mail_blob_get(account_id, &acl, &item.blob_id)? {
                     BlobResult::Blob(blob) => {
                         created.append(
@@ -284,16 +316,7 @@ where
                                         }
                                     })
                                     .collect(),
-                                item.keywords
-                                    .map(|keywords| {
-                                        keywords
-                                            .into_iter()
-                                            .filter_map(
-                                                |(k, set)| if set { k.tag.into() } else { None },
-                                            )
-                                            .collect()
-                                    })
-                                    .unwrap_or_default(),
+                                keywords,
                                 item.received_at.map(|t| t.timestamp()),
                             )?,
                         );
@@ -363,9 +386,10 @@ where
         // Parse message
         let raw_blob: JMAPBlob = (&blob_id).into();
         self.mail_parse_item(
+            account_id,
             &mut document,
             blob_id,
-            Message::parse(blob).ok_or_else(|| {
+            MimeLimits::from(&self.config).parse(blob).ok_or_else(|| {
                 MethodError::InvalidArguments("Failed to parse e-mail message.".to_string())
             })?,
             received_at,
@@ -410,11 +434,17 @@ where
 
     fn mail_parse_item(
         &self,
+        account_id: AccountId,
         document: &mut Document,
         blob_id: BlobId,
         mut message: Message,
         received_at: Option<i64>,
     ) -> store::Result<()> {
+        // Messages exceeding the MIME limits were only parsed up to the offending part
+        let remainder = MimeLimits::from(&self.config)
+            .scan(&message.raw_message)
+            .filter(|truncation| message.get_root_part().offset_end <= truncation.offset)
+            .map(|truncation| truncation.offset..message.raw_message.len());
         let root_part = message.get_root_part();
         let mut message_data = MessageData {
             headers: VecMap::with_capacity(root_part.headers.len()),
@@ -523,6 +553,7 @@ where
                 encoding: message_part.encoding,
             };
             let part_language = message_part.get_language().unwrap_or(message_language);
+            let mut extract_binary = None;
             let (mime_type, part_size) = match message_part.body {
                 PartType::Html(html) => {
                     let field = if message_data.text_body.contains(&part_id)
@@ -566,7 +597,11 @@ where
                     if !has_attachments {
                         has_attachments = true;
                     }
-                    (MimePartType::Other { part }, binary.len())
+                    let binary_len = binary.len();
+                    if self.config.attachment_extractor.is_enabled() {
+                        extract_binary = Some(binary);
+                    }
+                    (MimePartType::Other { part }, binary_len)
                 }
                 PartType::InlineBinary(binary) => (MimePartType::Other { part }, binary.len()),
                 PartType::Message(mut nested_message) => {
@@ -581,12 +616,61 @@ where
                 PartType::Multipart(subparts) => (MimePartType::MultiPart { subparts }, 0),
             };
 
-            message_data.mime_parts.push(MimePart::from_headers(
+            let mime_part = MimePart::from_headers(
                 message_part.headers,
                 mime_type,
                 message_part.is_encoding_problem,
                 part_size,
-            ));
+            );
+
+            // Index the text contained in the attachment
+            if let Some(text) = extract_binary.take().and_then(|binary| {
+                self.config.attachment_extractor.extract(
+                    mime_part.type_.as_deref(),
+                    mime_part.name.as_deref(),
+                    &binary,
+                )
+            }) {
+                document.text(
+                    MessageField::Attachment,
+                    text,
+                    part_language,
+                    IndexOptions::new().full_text((part_id + 1) as u32),
+                );
+            }
+
+            message_data.mime_parts.push(mime_part);
+        }
+
+        // Keep the unparsed remainder as an opaque attachment
+        if let Some(remainder) = remainder {
+            let part_id = message_data.mime_parts.len();
+            let root_part = &mut message_data.mime_parts[0];
+            if let MimePartType::MultiPart { subparts } = &mut root_part.mime_type {
+                subparts.push(part_id);
+            } else {
+                root_part.mime_type = MimePartType::MultiPart {
+                    subparts: vec![part_id],
+                };
+                root_part.type_ = "multipart/mixed".to_string().into();
+                message_data.text_body.retain(|&id| id != 0);
+                message_data.html_body.retain(|&id| id != 0);
+            }
+            message_data.mime_parts.push(MimePart {
+                mime_type: MimePartType::Other {
+                    part: MessagePart {
+                        offset_start: remainder.start,
+                        offset_end: remainder.end,
+                        encoding: Encoding::None,
+                    },
+                },
+                type_: "application/octet-stream".to_string().into(),
+                disposition: "attachment".to_string().into(),
+                size: remainder.len(),
+                ..Default::default()
+            });
+            message_data.attachments.push(part_id);
+            has_attachments = true;
         }
 
         // Set attachment properties
@@ -609,7 +693,12 @@ where
         document.blob(metadata_blob_id, IndexOptions::new());
 
         // Build index
-        message_data.build_index(document, true)
+        message_data.build_index(
+            document,
+            self.mail_collation(account_id)?,
+            inserted_at().into(),
+            true,
+        )
     }
 
     fn mail_set_thread(
@@ -621,7 +710,9 @@ where
         let mut reference_ids = Vec::new();
         let mut thread_name = None;
         for field in &document.text_fields {
-            if field.field == MessageField::ThreadName as u8 {
+            if field.field == MessageField::ThreadName as u8
+                && field.options.get_text_options() == <u64 as Options>::F_KEYWORD
+            {
                 thread_name = field.value.text.as_str().into();
             } else if field.field == MessageField::MessageIdRef as u8 {
                 reference_ids.push(field.value.text.as_str());
@@ -631,41 +722,11 @@ where
         // Obtain thread id
         let thread_id = if !reference_ids.is_empty() {
             // Obtain thread ids for all matching document ids
-            let thread_ids = self
-                .get_multi_document_value(
-                    batch.account_id,
-                    Collection::Mail,
-                    self.query_store::<FilterMapper>(
-                        batch.account_id,
-                        Collection::Mail,
-                        Filter::and(vec![
-                            Filter::eq(
-                                MessageField::ThreadName.into(),
-                                Query::Keyword(thread_name.unwrap_or("!").to_string()),
-                            ),
-                            Filter::or(
-                                reference_ids
-                                    .iter()
-                                    .map(|id| {
-                                        Filter::eq(
-                                            MessageField::MessageIdRef.into(),
-                                            Query::Keyword(id.to_string()),
-                                        )
-                                    })
-                                    .collect(),
-                            ),
-                        ]),
-                        Comparator::None,
-                    )?
-                    .into_iter()
-                    .map(|id| id.get_document_id())
-                    .collect::<Vec<DocumentId>>()
-                    .into_iter(),
-                    MessageField::ThreadId.into(),
-                )?
-                .into_iter()
-                .flatten()
-                .collect::<AHashSet<ThreadId>>();
+            let thread_ids = self.mail_find_threads(
+                batch.account_id,
+                thread_name.unwrap_or("!"),
+                &reference_ids,
+            )?;
 
             match thread_ids.len() {
                 1 => {
@@ -706,6 +767,49 @@ where
         Ok(thread_id)
     }
 
+    fn mail_find_threads(
+        &self,
+        account_id: AccountId,
+        thread_name: &str,
+        reference_ids: &[&str],
+    ) -> store::Result<AHashSet<ThreadId>> {
+        Ok(self
+            .get_multi_document_value(
+                account_id,
+                Collection::Mail,
+                self.query_store::<FilterMapper>(
+                    account_id,
+                    Collection::Mail,
+                    Filter::and(vec![
+                        Filter::eq(
+                            MessageField::ThreadName.into(),
+                            Query::Keyword(thread_name.to_string()),
+                        ),
+                        Filter::or(
+                            reference_ids
+                                .iter()
+                                .map(|id| {
+                                    Filter::eq(
+                                        MessageField::MessageIdRef.into(),
+                                        Query::Keyword(id.to_string()),
+                                    )
+                                })
+                                .collect(),
+                        ),
+                    ]),
+                    Comparator::None,
+                )?
+                .into_iter()
+                .map(|id| id.get_document_id())
+                .collect::<Vec<DocumentId>>()
+                .into_iter(),
+                MessageField::ThreadId.into(),
+            )?
+            .into_iter()
+            .flatten()
+            .collect::<AHashSet<ThreadId>>())
+    }
+
     fn mail_merge_threads(
         &self,
         batch: &mut WriteBatch,
@@ -882,14 +986,40 @@ impl AddMessage for Document {
     }
 }
 
+pub fn inserted_at() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 impl MessageData {
-    pub fn build_index(self, document: &mut Document, is_insert: bool) -> store::Result<()> {
+    pub fn build_index(
+        self,
+        document: &mut Document,
+        collation: Collation,
+        inserted_at: Option<u64>,
+        is_insert: bool,
+    ) -> store::Result<()> {
         let options = if is_insert {
             IndexOptions::new()
         } else {
             IndexOptions::new().clear()
         };
 
+        MessagePreview::new(&self).build_index(document, is_insert);
+        self.build_sort_index(document, collation, options);
+
+        // Time the message was stored, unlike receivedAt it cannot be set by clients.
+        // Messages stored before it was recorded do not have one.
+        if let Some(inserted_at) = inserted_at {
+            document.number(
+                MessageField::InsertedAt,
+                inserted_at,
+                IndexOptions::new().store().index() | options,
+            );
+        }
+
         document.number(
             MessageField::Size,
             self.size as Integer,
@@ -947,55 +1077,17 @@ impl MessageData {
                 }
 
                 RfcHeader::From | RfcHeader::To | RfcHeader::Cc | RfcHeader::Bcc => {
-                    let mut sort_text = String::with_capacity(MAX_SORT_FIELD_LENGTH);
-                    let mut found_addr = false;
-                    let mut last_is_space = true;
-
                     for value in values {
-                        value.visit_addresses(|value, is_addr| {
-                            if !found_addr {
-                                if !sort_text.is_empty() {
-                                    sort_text.push(' ');
-                                    last_is_space = true;
-                                }
-                                found_addr = is_addr;
-                                'outer: for ch in value.chars() {
-                                    for ch in ch.to_lowercase() {
-                                        if sort_text.len() < MAX_SORT_FIELD_LENGTH {
-                                            let is_space = ch.is_whitespace();
-                                            if !is_space || !last_is_space {
-                                                sort_text.push(ch);
-                                                last_is_space = is_space;
-                                            }
-                                        } else {
-                                            found_addr = true;
-                                            break 'outer;
-                                        }
-                                    }
-                                }
-                            }
-
+                        value.visit_addresses(|value, _| {
                             document.text(
                                 header_name,
                                 value,
                                 Language::Unknown,
                                 IndexOptions::new().tokenize() | options,
                             );
-
                             true
                         });
                     }
-
-                    document.text(
-                        header_name,
-                        if !sort_text.is_empty() {
-                            sort_text
-                        } else {
-                            "!".to_string()
-                        },
-                        Language::Unknown,
-                        IndexOptions::new().index() | options,
-                    );
                 }
                 RfcHeader::Date => {
                     if let Some(timestamp) = values.pop().and_then(|t| t.unwrap_timestamp()) {
@@ -1008,17 +1100,16 @@ impl MessageData {
                 }
                 RfcHeader::Subject => {
                     if let Some(subject) = values.pop().and_then(|t| t.unwrap_text()) {
-                        // Obtain thread name
-                        let thread_name = thread_name(&subject);
+                        // Threading requires the exact thread name while
+                        // sorting uses its collation key.
                         document.text(
                             MessageField::ThreadName,
-                            if !thread_name.is_empty() {
-                                thread_name.to_string()
-                            } else {
-                                "!".to_string()
+                            match thread_name(&subject) {
+                                thread_name if !thread_name.is_empty() => thread_name.to_string(),
+                                _ => "!".to_string(),
                             },
                             Language::Unknown,
-                            IndexOptions::new().keyword().index() | options,
+                            IndexOptions::new().keyword() | options,
                         );
                     }
                 }
@@ -1038,6 +1129,22 @@ impl MessageData {
                         }
                     }
                 }
+                RfcHeader::ListId => {
+                    if let Some(id) = values
+                        .pop()
+                        .and_then(|t| t.unwrap_text())
+                        .and_then(|t| list_id(&t))
+                    {
+                        if id.len() <= MAX_ID_LENGTH {
+                            document.text(
+                                header_name,
+                                id,
+                                Language::Unknown,
+                                IndexOptions::new().keyword() | options,
+                            );
+                        }
+                    }
+                }
                 RfcHeader::Comments => {
                     for value in values {
                         if let Some(comments) = value.unwrap_textlist() {
@@ -1061,6 +1168,87 @@ impl MessageData {
 
         Ok(())
     }
+
+    /// Adds or removes the index entries used for sorting, which depend on
+    /// the collation of the account.
+    pub fn build_sort_index(
+        &self,
+        document: &mut Document,
+        collation: Collation,
+        options: IndexOptions,
+    ) {
+        if collation != Collation::Legacy {
+            document.number(
+                MessageField::Collation,
+                collation.id(),
+                IndexOptions::new().store().index() | options,
+            );
+        }
+
+        for (header_name, values) in self.headers.iter() {
+            match header_name {
+                RfcHeader::From | RfcHeader::To | RfcHeader::Cc | RfcHeader::Bcc => {
+                    let mut sort_text = String::with_capacity(MAX_SORT_FIELD_LENGTH);
+                    let mut found_addr = false;
+                    let mut last_is_space = true;
+
+                    for value in values {
+                        value.clone().visit_addresses(|value, is_addr| {
+                            if !found_addr {
+                                if !sort_text.is_empty() {
+                                    sort_text.push(' ');
+                                    last_is_space = true;
+                                }
+                                found_addr = is_addr;
+                                'outer: for ch in value.chars() {
+                                    for ch in ch.to_lowercase() {
+                                        if sort_text.len() < MAX_SORT_FIELD_LENGTH {
+                                            let is_space = ch.is_whitespace();
+                                            if !is_space || !last_is_space {
+                                                sort_text.push(ch);
+                                                last_is_space = is_space;
+                                            }
+                                        } else {
+                                            found_addr = true;
+                                            break 'outer;
+                                        }
+                                    }
+                                }
+                            }
+                            !found_addr
+                        });
+                    }
+
+                    document.text(
+                        *header_name,
+                        if !sort_text.is_empty() {
+                            collation.sort_key(&sort_text)
+                        } else {
+                            "!".to_string()
+                        },
+                        Language::Unknown,
+                        IndexOptions::new().index() | options,
+                    );
+                }
+                RfcHeader::Subject => {
+                    if let Some(subject) = values.last().and_then(|t| t.clone().unwrap_text()) {
+                        document.text(
+                            MessageField::ThreadName,
+                            match thread_name(&subject) {
+                                thread_name if !thread_name.is_empty() => {
+                                    collation.sort_key(thread_name)
+                                }
+                                _ => "!".to_string(),
+                            },
+                            Language::Unknown,
+                            IndexOptions::new().index() | options,
+                        );
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
 }
 
 impl EmailImportResponse {
@@ -0,0 +1,153 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use jmap::error::set::{SetError, SetErrorType};
+use jmap::orm::serialize::JMAPOrm;
+use store::ahash::AHashSet;
+use store::core::collection::Collection;
+use store::core::error::StoreError;
+use store::core::keyword::KeywordRegistry;
+use store::core::tag::Tag;
+use store::tracing::debug;
+use store::{AccountId, JMAPStore, Store};
+
+use super::schema::{Email, Property};
+use super::MessageField;
+
+pub trait JMAPMailKeywords {
+    fn mail_keywords(&self, account_id: AccountId) -> store::Result<Arc<KeywordRegistry>>;
+    fn mail_keywords_register<'x>(
+        &self,
+        account_id: AccountId,
+        tags: impl IntoIterator<Item = &'x Tag>,
+    ) -> Result<(), SetError<Property>>;
+    fn mail_keywords_purge(
+        &self,
+        account_id: AccountId,
+        registry: &KeywordRegistry,
+    ) -> store::Result<usize>;
+}
+
+impl<T> JMAPMailKeywords for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_keywords(&self, account_id: AccountId) -> store::Result<Arc<KeywordRegistry>> {
+        self.keywords
+            .try_get_with::<_, StoreError>(account_id, || {
+                // Keyword bitmaps are keyed by name, so the registry is
+                // rebuilt from the messages in the account.
+                let mut names = Vec::new();
+                for document_id in self
+                    .get_document_ids(account_id, Collection::Mail)?
+                    .unwrap_or_default()
+                {
+                    if let Some(tags) = self
+                        .get_orm::<Email>(account_id, document_id)?
+                        .as_ref()
+                        .and_then(|fields| fields.get_tags(&Property::Keywords))
+                    {
+                        for tag in tags {
+                            if let Tag::Text(name) = tag {
+                                names.push(name.to_string());
+                            }
+                        }
+                    }
+                }
+                Ok(Arc::new(KeywordRegistry::new(names)))
+            })
+            .map_err(|err| err.as_ref().clone())
+    }
+
+    fn mail_keywords_register<'x>(
+        &self,
+        account_id: AccountId,
+        tags: impl IntoIterator<Item = &'x Tag>,
+    ) -> Result<(), SetError<Property>> {
+        let names = tags
+            .into_iter()
+            .filter_map(|tag| match tag {
+                Tag::Text(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            return Ok(());
+        }
+
+        let max_keywords = self.config.mail_max_keywords;
+        let registry = self.mail_keywords(account_id)?;
+        if registry
+            .try_intern(names.iter().copied(), max_keywords)
+            .is_ok()
+        {
+            return Ok(());
+        }
+
+        // Remove unused keywords before rejecting the request
+        self.mail_keywords_purge(account_id, &registry)?;
+        registry
+            .try_intern(names.iter().copied(), max_keywords)
+            .map_err(|_| {
+                SetError::new(SetErrorType::TooManyKeywords)
+                    .with_property(Property::Keywords)
+                    .with_description(format!(
+                        "Accounts may not use more than {} custom keywords.",
+                        max_keywords
+                    ))
+            })
+    }
+
+    fn mail_keywords_purge(
+        &self,
+        account_id: AccountId,
+        registry: &KeywordRegistry,
+    ) -> store::Result<usize> {
+        let names = registry.names();
+        let in_use = self
+            .get_tags(
+                account_id,
+                Collection::Mail,
+                MessageField::Keyword.into(),
+                &names
+                    .iter()
+                    .map(|name| Tag::Text(name.clone()))
+                    .collect::<Vec<_>>(),
+            )?
+            .into_iter()
+            .zip(names.into_iter())
+            .filter_map(|(bitmap, name)| bitmap.map(|_| name))
+            .collect::<AHashSet<_>>();
+
+        let removed = registry.retain(|name| in_use.contains(name));
+        if removed > 0 {
+            debug!(
+                "Removed {} unused keywords from account {}.",
+                removed, account_id
+            );
+        }
+        Ok(removed)
+    }
+}
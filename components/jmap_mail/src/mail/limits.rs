@@ -0,0 +1,384 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{borrow::Cow, fmt::Display};
+
+use mail_parser::Message;
+use store::config::jmap::JMAPConfig;
+
+/// Limits enforced on the MIME structure of messages before they are parsed,
+/// as the parser keeps the whole tree in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MimeLimits {
+    pub max_depth: usize,
+    pub max_parts: usize,
+    pub max_headers: usize,
+    pub max_header_length: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MimeLimit {
+    Depth,
+    Parts,
+    Headers,
+    HeaderLength,
+}
+
+/// Offset at which parsing stops, the remainder of the message is stored as
+/// an opaque attachment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MimeTruncation {
+    pub offset: usize,
+    pub limit: MimeLimit,
+}
+
+enum NestedType<'x> {
+    Multipart(&'x [u8]),
+    Message,
+}
+
+impl From<&JMAPConfig> for MimeLimits {
+    fn from(config: &JMAPConfig) -> Self {
+        MimeLimits {
+            max_depth: config.mail_mime_max_depth,
+            max_parts: config.mail_mime_max_parts,
+            max_headers: config.mail_mime_max_headers,
+            max_header_length: config.mail_mime_max_header_length,
+        }
+    }
+}
+
+impl MimeLimits {
+    /// Parses a message, stopping at the first part that exceeds the limits.
+    /// The returned message still references the full raw message.
+    pub fn parse<'x>(&self, raw_message: &'x [u8]) -> Option<Message<'x>> {
+        match self.scan(raw_message) {
+            Some(truncation) if truncation.offset > 0 => {
+                let mut message = Message::parse(&raw_message[..truncation.offset])?;
+                message.raw_message = Cow::Borrowed(raw_message);
+                Some(message)
+            }
+            Some(_) => None,
+            None => Message::parse(raw_message),
+        }
+    }
+
+    /// Walks the header blocks and boundaries of a raw message using memory
+    /// proportional to the nesting depth only.
+    pub fn scan(&self, raw_message: &[u8]) -> Option<MimeTruncation> {
+        // Boundaries of the open multiparts along with the depth of their parts
+        let mut boundaries: Vec<(&[u8], usize)> = Vec::new();
+        let mut depth = 1;
+        let mut parts = 1;
+        let mut part_start = 0;
+
+        let mut in_headers = true;
+        let mut headers = 0;
+        let mut header_start = 0;
+        let mut header_length = 0;
+        let mut content_type: Option<(usize, usize)> = None;
+        let mut is_content_type = false;
+
+        let mut pos = 0;
+        while pos < raw_message.len() {
+            let (line_end, next_pos) = match raw_message[pos..].iter().position(|&ch| ch == b'\n') {
+                Some(offset) => (pos + offset, pos + offset + 1),
+                None => (raw_message.len(), raw_message.len()),
+            };
+            let line = &raw_message[pos..line_end];
+
+            if in_headers {
+                // Without an enclosing multipart, the valid headers are kept
+                let truncate_at = |offset: usize, limit: MimeLimit| MimeTruncation {
+                    offset: if boundaries.is_empty() {
+                        offset
+                    } else {
+                        part_start
+                    },
+                    limit,
+                };
+
+                if line.is_empty() || line == b"\r" {
+                    in_headers = false;
+                    match content_type
+                        .take()
+                        .and_then(|(start, end)| nested_type(&raw_message[start..end]))
+                    {
+                        Some(_) if depth >= self.max_depth => {
+                            return truncate_at(pos, MimeLimit::Depth).into();
+                        }
+                        Some(NestedType::Multipart(boundary)) => {
+                            boundaries.push((boundary, depth + 1));
+                        }
+                        Some(NestedType::Message) => {
+                            depth += 1;
+                            in_headers = true;
+                            headers = 0;
+                        }
+                        None => (),
+                    }
+                } else if matches!(line[0], b' ' | b'\t') && headers > 0 {
+                    header_length += next_pos - pos;
+                    if header_length > self.max_header_length {
+                        return truncate_at(header_start, MimeLimit::HeaderLength).into();
+                    }
+                    if is_content_type {
+                        if let Some((_, end)) = &mut content_type {
+                            *end = line_end;
+                        }
+                    }
+                } else {
+                    headers += 1;
+                    header_start = pos;
+                    header_length = next_pos - pos;
+                    if headers > self.max_headers {
+                        return truncate_at(pos, MimeLimit::Headers).into();
+                    } else if header_length > self.max_header_length {
+                        return truncate_at(pos, MimeLimit::HeaderLength).into();
+                    }
+                    is_content_type =
+                        line.len() > 13 && line[..13].eq_ignore_ascii_case(b"content-type:");
+                    if is_content_type {
+                        content_type = (pos + 13, line_end).into();
+                    }
+                }
+            } else if line.starts_with(b"--") && !boundaries.is_empty() {
+                let line = trim_end(&line[2..]);
+                if let Some((idx, is_closing)) =
+                    boundaries
+                        .iter()
+                        .enumerate()
+                        .rev()
+                        .find_map(|(idx, (boundary, _))| {
+                            let rest = line.strip_prefix(*boundary)?;
+                            if rest.is_empty() {
+                                Some((idx, false))
+                            } else if rest == b"--" {
+                                Some((idx, true))
+                            } else {
+                                None
+                            }
+                        })
+                {
+                    if !is_closing {
+                        parts += 1;
+                        if parts > self.max_parts {
+                            return MimeTruncation {
+                                offset: pos,
+                                limit: MimeLimit::Parts,
+                            }
+                            .into();
+                        }
+                        depth = boundaries[idx].1;
+                        boundaries.truncate(idx + 1);
+                        part_start = pos;
+                        in_headers = true;
+                        headers = 0;
+                    } else {
+                        depth = boundaries[idx].1 - 1;
+                        boundaries.truncate(idx);
+                    }
+                }
+            }
+
+            pos = next_pos;
+        }
+
+        None
+    }
+}
+
+fn nested_type(content_type: &[u8]) -> Option<NestedType<'_>> {
+    let value = trim_start(content_type);
+    if starts_with_ignore_case(value, b"multipart/") {
+        let pos = value
+            .windows(9)
+            .position(|window| window.eq_ignore_ascii_case(b"boundary="))?
+            + 9;
+        let boundary = &value[pos..];
+        let boundary = if let Some(boundary) = boundary.strip_prefix(b"\"") {
+            &boundary[..boundary.iter().position(|&ch| ch == b'"')?]
+        } else {
+            &boundary[..boundary
+                .iter()
+                .position(|&ch| ch == b';' || ch.is_ascii_whitespace())
+                .unwrap_or(boundary.len())]
+        };
+        if !boundary.is_empty() {
+            Some(NestedType::Multipart(boundary))
+        } else {
+            None
+        }
+    } else if starts_with_ignore_case(value, b"message/rfc822")
+        || starts_with_ignore_case(value, b"message/global")
+    {
+        Some(NestedType::Message)
+    } else {
+        None
+    }
+}
+
+fn starts_with_ignore_case(value: &[u8], prefix: &[u8]) -> bool {
+    value.len() >= prefix.len() && value[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+fn trim_start(value: &[u8]) -> &[u8] {
+    let start = value
+        .iter()
+        .position(|ch| !ch.is_ascii_whitespace())
+        .unwrap_or(value.len());
+    &value[start..]
+}
+
+fn trim_end(value: &[u8]) -> &[u8] {
+    let end = value
+        .iter()
+        .rposition(|ch| !ch.is_ascii_whitespace())
+        .map_or(0, |pos| pos + 1);
+    &value[..end]
+}
+
+impl Display for MimeLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MimeLimit::Depth => "maximum nesting depth",
+            MimeLimit::Parts => "maximum number of parts",
+            MimeLimit::Headers => "maximum number of headers",
+            MimeLimit::HeaderLength => "maximum header length",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MimeLimit, MimeLimits, MimeTruncation};
+
+    const LIMITS: MimeLimits = MimeLimits {
+        max_depth: 3,
+        max_parts: 4,
+        max_headers: 3,
+        max_header_length: 64,
+    };
+
+    #[test]
+    fn mime_limits() {
+        // Messages within the limits are parsed in full
+        let message = concat!(
+            "Subject: test\r\n",
+            "Content-Type: multipart/mixed;\r\n",
+            "  boundary=\"outer\"\r\n\r\n",
+            "--outer\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "Hello\r\n",
+            "--outer\r\n",
+            "Content-Type: message/rfc822\r\n\r\n",
+            "Subject: nested\r\n\r\n",
+            "Nested body\r\n",
+            "--outer--\r\n"
+        );
+        assert_eq!(LIMITS.scan(message.as_bytes()), None);
+        assert_eq!(
+            LIMITS.parse(message.as_bytes()).unwrap().parts.len(),
+            mail_parser::Message::parse(message.as_bytes())
+                .unwrap()
+                .parts
+                .len()
+        );
+
+        // Top-level headers are kept up to the offending one
+        let message = "A: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\n\r\nbody";
+        assert_eq!(
+            LIMITS.scan(message.as_bytes()),
+            Some(MimeTruncation {
+                offset: 18,
+                limit: MimeLimit::Headers
+            })
+        );
+        let parsed = LIMITS.parse(message.as_bytes()).unwrap();
+        assert_eq!(parsed.raw_message.len(), message.len());
+        assert_eq!(parsed.parts[0].headers.len(), 3);
+
+        let message = format!("Subject: {}\r\n\r\nbody", "a".repeat(20));
+        assert_eq!(LIMITS.scan(message.as_bytes()), None);
+        let message = format!("Subject: a\r\n {}\r\n\r\nbody", "a".repeat(60));
+        assert_eq!(
+            LIMITS.scan(message.as_bytes()),
+            Some(MimeTruncation {
+                offset: 0,
+                limit: MimeLimit::HeaderLength
+            })
+        );
+        assert!(LIMITS.parse(message.as_bytes()).is_none());
+
+        // Parts nested too deep are cut at their boundary
+        let message = concat!(
+            "Content-Type: multipart/mixed; boundary=a\r\n\r\n",
+            "--a\r\n",
+            "Content-Type: multipart/mixed; boundary=b\r\n\r\n",
+            "--b\r\n",
+            "Content-Type: multipart/mixed; boundary=c\r\n\r\n",
+            "--c\r\n\r\n",
+            "deep\r\n",
+            "--c--\r\n",
+            "--b--\r\n",
+            "--a--\r\n"
+        );
+        let offset = message.find("--b\r\n").unwrap();
+        assert_eq!(
+            LIMITS.scan(message.as_bytes()),
+            Some(MimeTruncation {
+                offset,
+                limit: MimeLimit::Depth
+            })
+        );
+        let parsed = LIMITS.parse(message.as_bytes()).unwrap();
+        assert!(parsed.parts.iter().all(|part| part.offset_end <= offset));
+
+        // Excess parts are cut at the boundary exceeding the limit
+        let message = format!(
+            "Content-Type: multipart/mixed; boundary=x\r\n\r\n{}--x--\r\n",
+            "--x\r\n\r\npart\r\n".repeat(5)
+        );
+        let offset = 45 + "--x\r\n\r\npart\r\n".len() * 3;
+        assert_eq!(
+            LIMITS.scan(message.as_bytes()),
+            Some(MimeTruncation {
+                offset,
+                limit: MimeLimit::Parts
+            })
+        );
+
+        // Headers of nested parts are cut at the start of the part
+        let message = format!(
+            "Content-Type: multipart/mixed; boundary=x\r\n\r\n--x\r\n{}\r\npart\r\n--x--\r\n",
+            "X: 1\r\n".repeat(4)
+        );
+        assert_eq!(
+            LIMITS.scan(message.as_bytes()),
+            Some(MimeTruncation {
+                offset: 45,
+                limit: MimeLimit::Headers
+            })
+        );
+    }
+}
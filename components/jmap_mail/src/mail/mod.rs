@@ -21,19 +21,36 @@
  * for more details.
 */
 
+pub mod annotations;
+pub mod body_convert;
 pub mod changes;
+pub mod collation;
 pub mod conv;
 pub mod copy;
+pub mod detach;
+pub mod expunge;
 pub mod get;
 pub mod import;
+pub mod keywords;
+pub mod limits;
 pub mod parse;
 pub mod query;
+pub mod query_subscription;
 pub mod raft;
+pub mod renumber;
+pub mod report;
+pub mod retention;
 pub mod schema;
 pub mod search_snippet;
 pub mod serialize;
 pub mod set;
+pub mod set_keywords;
 pub mod sharing;
+pub mod snooze;
+pub mod template;
+pub mod truncate;
+pub mod unsubscribe;
+pub mod validate;
 
 use jmap::{jmap_store::Object, types::jmap::JMAPId};
 use serde::{Deserialize, Serialize};
@@ -327,6 +344,11 @@ pub enum MessageField {
     ThreadId = 136,
     Mailbox = 137,
     HasHeader = 138,
+    Snoozed = 139,
+    Collation = 140,
+    ThreadPreview = 141,
+    SnoozedUntil = 142,
+    InsertedAt = 143,
 }
 
 impl From<MessageField> for FieldId {
@@ -24,6 +24,7 @@
 use super::{
     conv::{HeaderValueInto, IntoForm},
     get::{AsBodyParts, AsBodyStructure, AsEmailHeaders, BlobResult, JMAPGetMail},
+    limits::MimeLimits,
     schema::{BodyProperty, Email, HeaderForm, Property, Value},
     GetRawHeader, MessagePart,
 };
@@ -40,6 +41,7 @@ use mail_parser::{
 use std::sync::Arc;
 use store::{
     ahash::AHashSet,
+    blob::BlobId,
     core::{acl::ACLToken, vec_map::VecMap},
     JMAPStore, Store,
 };
@@ -146,7 +148,7 @@ where
         let account_id = request.account_id.get_document_id();
         for blob_id in request.blob_ids {
             if let BlobResult::Blob(blob) = self.mail_blob_get(account_id, &acl, &blob_id)? {
-                if let Some(message) = Message::parse(&blob) {
+                if let Some(message) = MimeLimits::from(&self.config).parse(&blob) {
                     let email = message.into_parsed_email(&parse_properties, &blob_id, &blob);
                     response.parsed.append(blob_id, email);
                 } else {
@@ -161,6 +163,25 @@ where
     }
 }
 
+/// Parses a raw message into an Email object with all body values, without storing it.
+pub fn mail_parse_raw(raw_message: &[u8], limits: &MimeLimits) -> Option<Email> {
+    let parse_properties = EmailParseProperties {
+        properties: Email::default_properties(),
+        body_properties: Email::default_body_properties(),
+        fetch_text_body_values: false,
+        fetch_html_body_values: false,
+        fetch_all_body_values: true,
+        max_body_value_bytes: 0,
+    };
+    limits.parse(raw_message).map(|message| {
+        message.into_parsed_email(
+            &parse_properties,
+            &JMAPBlob::new(BlobId::new_external(raw_message)),
+            raw_message,
+        )
+    })
+}
+
 trait IntoParsedEmail {
     fn into_parsed_email(
         self,
@@ -435,6 +456,8 @@ impl IntoParsedEmail for Message<'_> {
                 | Property::MailboxIds
                 | Property::Keywords
                 | Property::ReceivedAt
+                | Property::Annotations
+                | Property::Snoozed
                 | Property::Invalid(_) => None,
             };
 
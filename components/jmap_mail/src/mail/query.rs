@@ -23,9 +23,12 @@
 
 use super::schema::{Comparator, Email, Filter};
 use super::sharing::JMAPShareMail;
+use super::unsubscribe::list_id;
 use crate::mail::MessageField;
+use crate::mailbox::get::JMAPGetMailbox;
+use crate::mailbox::is_valid_role;
 use jmap::error::method::MethodError;
-use jmap::jmap_store::query::{ExtraFilterFnc, QueryHelper, QueryObject};
+use jmap::jmap_store::query::{ExtraFilterFnc, QueryDelta, QueryHelper, QueryObject};
 use jmap::request::query::{QueryRequest, QueryResponse};
 use jmap::types::jmap::JMAPId;
 use mail_parser::{HeaderName, RfcHeader};
@@ -35,9 +38,9 @@ use store::core::collection::Collection;
 use store::core::error::StoreError;
 use store::core::tag::Tag;
 use store::nlp::Language;
-use store::read::comparator::{self, DocumentSetComparator, FieldComparator};
+use store::read::comparator::{self, DocumentSetComparator, FieldComparator, RelevanceComparator};
 use store::read::filter::{self, Query};
-use store::{roaring::RoaringBitmap, AccountId, JMAPStore, Store};
+use store::{roaring::RoaringBitmap, AccountId, DocumentId, JMAPStore, Store};
 use store::{FieldId, Integer, LongInteger};
 
 #[derive(Debug, Clone, serde::Deserialize, Default)]
@@ -59,12 +62,29 @@ where
     T: for<'x> Store<'x> + 'static,
 {
     fn mail_query(&self, request: QueryRequest<Email>) -> jmap::Result<QueryResponse>;
+    fn mail_query_delta(
+        &self,
+        request: QueryRequest<Email>,
+        delta: Option<QueryDelta>,
+    ) -> jmap::Result<QueryResponse>;
     fn get_thread_keywords(
         &self,
         account_id: AccountId,
         keyword: Tag,
         match_all: bool,
     ) -> store::Result<RoaringBitmap>;
+    fn get_thread_tags(
+        &self,
+        account_id: AccountId,
+        field: FieldId,
+        tag: Tag,
+        match_all: bool,
+    ) -> store::Result<RoaringBitmap>;
+    fn mailbox_role_id(
+        &self,
+        account_id: AccountId,
+        role: &str,
+    ) -> store::Result<Option<DocumentId>>;
 }
 
 impl<T> JMAPMailQuery<T> for JMAPStore<T>
@@ -72,6 +92,14 @@ where
     T: for<'x> Store<'x> + 'static,
 {
     fn mail_query(&self, request: QueryRequest<Email>) -> jmap::Result<QueryResponse> {
+        self.mail_query_delta(request, None)
+    }
+
+    fn mail_query_delta(
+        &self,
+        request: QueryRequest<Email>,
+        delta: Option<QueryDelta>,
+    ) -> jmap::Result<QueryResponse> {
         let mut helper = QueryHelper::new(
             self,
             request,
@@ -85,6 +113,8 @@ where
         let mut document_ids = None;
         let mut is_immutable_filter = true;
         let mut is_immutable_sort = true;
+        let mut relevance_text = Vec::new();
+        let mut relevance_fields = Vec::new();
 
         helper.parse_filter(|filter| {
             Ok(match filter {
@@ -177,44 +207,37 @@ where
                         filter
                     }
                 }
-                Filter::Text { value } => filter::Filter::or(vec![
-                    filter::Filter::eq(RfcHeader::From.into(), Query::Tokenize(value.clone())),
-                    filter::Filter::eq(RfcHeader::To.into(), Query::Tokenize(value.clone())),
-                    filter::Filter::eq(RfcHeader::Cc.into(), Query::Tokenize(value.clone())),
-                    filter::Filter::eq(RfcHeader::Bcc.into(), Query::Tokenize(value.clone())),
-                    filter::Filter::eq(
-                        RfcHeader::Subject.into(),
-                        Query::match_text(value.clone(), Language::Unknown),
-                    ),
-                    filter::Filter::eq(
-                        MessageField::Body.into(),
-                        Query::match_text(value.clone(), Language::Unknown),
-                    ),
-                    filter::Filter::eq(
-                        MessageField::Attachment.into(),
-                        Query::match_text(value, Language::Unknown),
-                    ),
-                ]),
-                Filter::From { value } => {
-                    filter::Filter::eq(RfcHeader::From.into(), Query::Tokenize(value))
-                }
-                Filter::To { value } => {
-                    filter::Filter::eq(RfcHeader::To.into(), Query::Tokenize(value))
+                Filter::Text { value } => {
+                    add_relevance_terms(
+                        &mut relevance_text,
+                        &mut relevance_fields,
+                        &value,
+                        TEXT_FILTER_FIELDS,
+                    );
+                    expand_text_filter(value)
                 }
-                Filter::Cc { value } => {
-                    filter::Filter::eq(RfcHeader::Cc.into(), Query::Tokenize(value))
+                Filter::From { value } => field_filter(RfcHeader::From.into(), value),
+                Filter::To { value } => field_filter(RfcHeader::To.into(), value),
+                Filter::Cc { value } => field_filter(RfcHeader::Cc.into(), value),
+                Filter::Bcc { value } => field_filter(RfcHeader::Bcc.into(), value),
+                Filter::Subject { value } => {
+                    add_relevance_terms(
+                        &mut relevance_text,
+                        &mut relevance_fields,
+                        &value,
+                        &[(RfcHeader::Subject as FieldId, TextMatch::FullText)],
+                    );
+                    field_filter(RfcHeader::Subject.into(), value)
                 }
-                Filter::Bcc { value } => {
-                    filter::Filter::eq(RfcHeader::Bcc.into(), Query::Tokenize(value))
+                Filter::Body { value } => {
+                    add_relevance_terms(
+                        &mut relevance_text,
+                        &mut relevance_fields,
+                        &value,
+                        &[(MessageField::Body as FieldId, TextMatch::FullText)],
+                    );
+                    field_filter(MessageField::Body.into(), value)
                 }
-                Filter::Subject { value } => filter::Filter::eq(
-                    RfcHeader::Subject.into(),
-                    Query::match_text(value, Language::Unknown),
-                ),
-                Filter::Body { value } => filter::Filter::eq(
-                    MessageField::Body.into(),
-                    Query::match_text(value, Language::Unknown),
-                ),
                 Filter::Header { mut value } => {
                     let (value, header) = match value.len() {
                         1 => (None, value.pop().unwrap()),
@@ -247,7 +270,12 @@ where
                             } else {
                                 MessageField::MessageIdRef as FieldId
                             },
-                            Query::Keyword(value),
+                            match value.strip_suffix('*') {
+                                Some(prefix) if !prefix.is_empty() => {
+                                    Query::Prefix(prefix.to_string())
+                                }
+                                _ => Query::Keyword(value),
+                            },
                         )
                     } else {
                         filter::Filter::eq(
@@ -292,6 +320,60 @@ where
                         Query::Tag(Tag::Id(value.get_document_id())),
                     )
                 }
+                Filter::InMailboxRole { value } => {
+                    if is_immutable_filter {
+                        is_immutable_filter = false;
+                    }
+                    if let Some(mailbox_id) = self.mailbox_role_id(account_id, &value)? {
+                        filter::Filter::eq(
+                            MessageField::Mailbox.into(),
+                            Query::Tag(Tag::Id(mailbox_id)),
+                        )
+                    } else {
+                        filter::Filter::DocumentSet(RoaringBitmap::new())
+                    }
+                }
+                Filter::InMailboxRoleOtherThan { value } => {
+                    if is_immutable_filter {
+                        is_immutable_filter = false;
+                    }
+                    let mut conditions = Vec::with_capacity(value.len());
+                    for role in value {
+                        if let Some(mailbox_id) = self.mailbox_role_id(account_id, &role)? {
+                            conditions.push(filter::Filter::eq(
+                                MessageField::Mailbox.into(),
+                                Query::Tag(Tag::Id(mailbox_id)),
+                            ));
+                        }
+                    }
+                    filter::Filter::not(conditions)
+                }
+                Filter::SomeInThreadInMailbox { value } => {
+                    if is_immutable_filter {
+                        is_immutable_filter = false;
+                    }
+                    filter::Filter::DocumentSet(self.get_thread_tags(
+                        account_id,
+                        MessageField::Mailbox.into(),
+                        Tag::Id(value.get_document_id()),
+                        false,
+                    )?)
+                }
+                Filter::NoneInThreadInMailbox { value } => {
+                    if is_immutable_filter {
+                        is_immutable_filter = false;
+                    }
+                    filter::Filter::not(vec![filter::Filter::DocumentSet(self.get_thread_tags(
+                        account_id,
+                        MessageField::Mailbox.into(),
+                        Tag::Id(value.get_document_id()),
+                        false,
+                    )?)])
+                }
+                Filter::ListId { value } => filter::Filter::eq(
+                    RfcHeader::ListId.into(),
+                    Query::Keyword(list_id(&value).unwrap_or(value)),
+                ),
 
                 Filter::Unsupported { value } => {
                     return Err(MethodError::UnsupportedFilter(value));
@@ -299,6 +381,10 @@ where
             })
         })?;
 
+        if let Some(delta) = delta {
+            helper.apply_delta(delta);
+        }
+
         helper.parse_comparator(|comparator| {
             Ok(match comparator.property {
                 Comparator::ReceivedAt => comparator::Comparator::Field(FieldComparator {
@@ -365,6 +451,17 @@ where
                     field: RfcHeader::Cc.into(),
                     ascending: comparator.is_ascending,
                 }),
+                Comparator::Relevance => {
+                    if is_immutable_sort {
+                        is_immutable_sort = false;
+                    }
+                    comparator::Comparator::Relevance(RelevanceComparator {
+                        fields: std::mem::take(&mut relevance_fields),
+                        text: std::mem::take(&mut relevance_text).join(" "),
+                        language: Language::Unknown,
+                        ascending: comparator.is_ascending,
+                    })
+                }
             })
         })?;
 
@@ -403,12 +500,17 @@ where
         keyword: Tag,
         match_all: bool,
     ) -> store::Result<RoaringBitmap> {
-        if let Some(tagged_doc_ids) = self.get_tag(
-            account_id,
-            Collection::Mail,
-            MessageField::Keyword.into(),
-            keyword,
-        )? {
+        self.get_thread_tags(account_id, MessageField::Keyword.into(), keyword, match_all)
+    }
+
+    fn get_thread_tags(
+        &self,
+        account_id: AccountId,
+        field: FieldId,
+        tag: Tag,
+        match_all: bool,
+    ) -> store::Result<RoaringBitmap> {
+        if let Some(tagged_doc_ids) = self.get_tag(account_id, Collection::Mail, field, tag)? {
             let mut not_matched_ids = RoaringBitmap::new();
             let mut matched_ids = RoaringBitmap::new();
 
@@ -453,4 +555,183 @@ where
             Ok(RoaringBitmap::new())
         }
     }
+
+    fn mailbox_role_id(
+        &self,
+        account_id: AccountId,
+        role: &str,
+    ) -> store::Result<Option<DocumentId>> {
+        let role = role.to_ascii_lowercase();
+        if is_valid_role(&role) {
+            self.mailbox_get_by_role(account_id, &role)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// How the terms of a text filter are matched against an indexed field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TextMatch {
+    // Address headers are tokenized without stemming
+    Tokenize,
+    // Full-text fields are matched using the language stemmers
+    FullText,
+}
+
+/// Fields searched by the `text` filter (RFC 8621, section 4.4.1). The
+/// attachment field contains text parts not in the body, nested messages and,
+/// when `attachment-index` is enabled, the text extracted from documents.
+const TEXT_FILTER_FIELDS: &[(FieldId, TextMatch)] = &[
+    (RfcHeader::From as FieldId, TextMatch::Tokenize),
+    (RfcHeader::To as FieldId, TextMatch::Tokenize),
+    (RfcHeader::Cc as FieldId, TextMatch::Tokenize),
+    (RfcHeader::Bcc as FieldId, TextMatch::Tokenize),
+    (RfcHeader::Subject as FieldId, TextMatch::FullText),
+    (MessageField::Body as FieldId, TextMatch::FullText),
+    (MessageField::Attachment as FieldId, TextMatch::FullText),
+];
+
+impl TextMatch {
+    fn query(self, value: String) -> Query {
+        match self {
+            TextMatch::Tokenize => Query::Tokenize(value),
+            TextMatch::FullText => Query::match_text(value, Language::Unknown),
+        }
+    }
+}
+
+fn text_match(field: FieldId) -> TextMatch {
+    TEXT_FILTER_FIELDS
+        .iter()
+        .find(|(text_field, _)| *text_field == field)
+        .map_or(TextMatch::FullText, |(_, text_match)| *text_match)
+}
+
+/// Filters a single field using the same tokenization as the `text` filter.
+fn field_filter(field: FieldId, value: String) -> filter::Filter {
+    text_filter(field, value, text_match(field))
+}
+
+fn expand_text_filter(value: String) -> filter::Filter {
+    filter::Filter::or(
+        TEXT_FILTER_FIELDS
+            .iter()
+            .map(|(field, text_match)| text_filter(*field, value.clone(), *text_match))
+            .collect(),
+    )
+}
+
+/// Builds the condition for a text filter, a trailing `*` turns the last word
+/// of the value into a prefix matching any indexed term that starts with it.
+fn text_filter(field: FieldId, value: String, text_match: TextMatch) -> filter::Filter {
+    if let Some(text) = value.trim_end().strip_suffix('*') {
+        let (head, prefix) = text.rsplit_once(char::is_whitespace).unwrap_or(("", text));
+        let prefix = prefix.trim_matches(|c: char| !c.is_alphanumeric());
+        if !prefix.is_empty() {
+            let prefix = filter::Filter::eq(field, Query::Prefix(prefix.to_lowercase()));
+            return if !head.trim().is_empty() {
+                filter::Filter::and(vec![
+                    filter::Filter::eq(field, text_match.query(head.to_string())),
+                    prefix,
+                ])
+            } else {
+                prefix
+            };
+        }
+    }
+
+    filter::Filter::eq(field, text_match.query(value))
+}
+
+/// Only full-text fields contribute to the relevance score.
+fn add_relevance_terms(
+    text: &mut Vec<String>,
+    fields: &mut Vec<FieldId>,
+    value: &str,
+    value_fields: &[(FieldId, TextMatch)],
+) {
+    text.push(value.to_string());
+    for (field, text_match) in value_fields {
+        if *text_match == TextMatch::FullText && !fields.contains(field) {
+            fields.push(*field);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::RfcHeader;
+    use store::{
+        read::filter::{self, LogicalOperator, Query},
+        FieldId,
+    };
+
+    use crate::mail::MessageField;
+
+    use super::{expand_text_filter, field_filter};
+
+    fn conditions(filter: filter::Filter) -> Vec<(FieldId, String)> {
+        match filter {
+            filter::Filter::Operator(operator) => {
+                assert_eq!(operator.operator, LogicalOperator::Or);
+                operator
+                    .conditions
+                    .into_iter()
+                    .map(|condition| match condition {
+                        filter::Filter::Condition(condition) => (
+                            condition.field,
+                            match condition.value {
+                                Query::Tokenize(value) => format!("tokenize:{}", value),
+                                Query::Match(text) => format!("match:{}", text.text),
+                                Query::Prefix(value) => format!("prefix:{}", value),
+                                query => panic!("Unexpected query {:?}", query),
+                            },
+                        ),
+                        filter => panic!("Unexpected filter {:?}", filter),
+                    })
+                    .collect()
+            }
+            filter => panic!("Unexpected filter {:?}", filter),
+        }
+    }
+
+    #[test]
+    fn text_filter_expansion() {
+        assert_eq!(
+            conditions(expand_text_filter("jane doe".to_string())),
+            vec![
+                (RfcHeader::From as FieldId, "tokenize:jane doe".to_string()),
+                (RfcHeader::To as FieldId, "tokenize:jane doe".to_string()),
+                (RfcHeader::Cc as FieldId, "tokenize:jane doe".to_string()),
+                (RfcHeader::Bcc as FieldId, "tokenize:jane doe".to_string()),
+                (RfcHeader::Subject as FieldId, "match:jane doe".to_string()),
+                (MessageField::Body as FieldId, "match:jane doe".to_string()),
+                (
+                    MessageField::Attachment as FieldId,
+                    "match:jane doe".to_string()
+                ),
+            ]
+        );
+
+        // Prefix searches apply to every field
+        assert!(conditions(expand_text_filter("rep*".to_string()))
+            .into_iter()
+            .all(|(_, query)| query == "prefix:rep"));
+
+        // Single field filters match the same way as the text filter
+        for (field, expected) in [
+            (RfcHeader::From as FieldId, "tokenize:jane"),
+            (RfcHeader::Subject as FieldId, "match:jane"),
+            (MessageField::Body as FieldId, "match:jane"),
+        ] {
+            assert_eq!(
+                conditions(filter::Filter::or(vec![field_filter(
+                    field,
+                    "jane".to_string()
+                )])),
+                vec![(field, expected.to_string())]
+            );
+        }
+    }
 }
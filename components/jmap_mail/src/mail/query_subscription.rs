@@ -0,0 +1,405 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use jmap::{
+    jmap_store::query::QueryDelta,
+    request::{
+        query::{self, QueryRequest},
+        query_changes::{AddedItem, QueryChangesResponse},
+    },
+    types::{jmap::JMAPId, state::JMAPState},
+};
+use store::{
+    ahash::AHashMap,
+    core::{acl::ACLToken, collection::Collection, JMAPIdPrefix},
+    log::changes::{self, ChangeId},
+    roaring::RoaringBitmap,
+    tracing::error,
+    AccountId, JMAPStore, Store,
+};
+
+use super::{
+    query::JMAPMailQuery,
+    schema::{Comparator, Email, Filter},
+};
+
+/// An Email query registered by a push client, which is notified of the
+/// changes to its results rather than having to call Email/queryChanges.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct QuerySubscription {
+    #[serde(rename = "filter")]
+    pub filter: Option<query::Filter<Filter>>,
+
+    #[serde(rename = "sort")]
+    pub sort: Option<Vec<query::Comparator<Comparator>>>,
+}
+
+enum Delta {
+    Changes(RoaringBitmap, RoaringBitmap, ChangeId),
+    Reset(ChangeId),
+}
+
+/// The results of a subscribed query as last sent to the client.
+#[derive(Debug)]
+pub struct QueryResults {
+    pub id: String,
+    subscription: QuerySubscription,
+    ids: Vec<JMAPId>,
+    query_state: JMAPState,
+}
+
+pub trait JMAPMailQuerySubscription<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_query_subscribe(
+        &self,
+        acl: Arc<ACLToken>,
+        account_id: AccountId,
+        id: String,
+        subscription: QuerySubscription,
+    ) -> jmap::Result<(QueryResults, QueryChangesResponse)>;
+    fn mail_query_update(
+        &self,
+        acl: Arc<ACLToken>,
+        account_id: AccountId,
+        results: &mut [QueryResults],
+    ) -> Vec<(String, QueryChangesResponse)>;
+}
+
+impl<T> JMAPMailQuerySubscription<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_query_subscribe(
+        &self,
+        acl: Arc<ACLToken>,
+        account_id: AccountId,
+        id: String,
+        subscription: QuerySubscription,
+    ) -> jmap::Result<(QueryResults, QueryChangesResponse)> {
+        let response = self.mail_query(subscription.as_request(acl, account_id, true))?;
+
+        // The first delta sent to the client contains all the results
+        let changes = QueryChangesResponse {
+            account_id: response.account_id,
+            old_query_state: response.query_state.clone(),
+            new_query_state: response.query_state.clone(),
+            total: response.ids.len().into(),
+            removed: Vec::new(),
+            added: response
+                .ids
+                .iter()
+                .enumerate()
+                .map(|(index, id)| AddedItem::new(*id, index))
+                .collect(),
+        };
+
+        Ok((
+            QueryResults {
+                id,
+                subscription,
+                ids: response.ids,
+                query_state: response.query_state,
+            },
+            changes,
+        ))
+    }
+
+    fn mail_query_update(
+        &self,
+        acl: Arc<ACLToken>,
+        account_id: AccountId,
+        results: &mut [QueryResults],
+    ) -> Vec<(String, QueryChangesResponse)> {
+        let mut query_changes = Vec::new();
+        let mut deltas: AHashMap<ChangeId, Option<Delta>> = AHashMap::default();
+
+        // Results are only replaced once their changes were calculated, a failed
+        // update is retried on the next change.
+        for results in results {
+            match self.mail_query_update_results(acl.clone(), account_id, results, &mut deltas) {
+                Ok(Some(changes)) => {
+                    query_changes.push((results.id.clone(), changes));
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    error!(
+                        "Failed to update subscribed query {} for account {}: {:?}",
+                        results.id, account_id, err
+                    );
+                }
+            }
+        }
+
+        query_changes
+    }
+}
+
+trait JMAPMailQueryResults {
+    fn mail_query_update_results(
+        &self,
+        acl: Arc<ACLToken>,
+        account_id: AccountId,
+        results: &mut QueryResults,
+        deltas: &mut AHashMap<ChangeId, Option<Delta>>,
+    ) -> jmap::Result<Option<QueryChangesResponse>>;
+}
+
+impl<T> JMAPMailQueryResults for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_query_update_results(
+        &self,
+        acl: Arc<ACLToken>,
+        account_id: AccountId,
+        results: &mut QueryResults,
+        deltas: &mut AHashMap<ChangeId, Option<Delta>>,
+    ) -> jmap::Result<Option<QueryChangesResponse>> {
+        // Obtain the messages changed since the results were last updated,
+        // which are shared by subscriptions at the same state.
+        let since_change_id = results.query_state.get_change_id();
+        if !deltas.contains_key(&since_change_id) {
+            let delta = if let Some(changelog) = self.get_changes(
+                account_id,
+                Collection::Mail,
+                if since_change_id != ChangeId::MAX {
+                    changes::Query::Since(since_change_id)
+                } else {
+                    changes::Query::All
+                },
+            )? {
+                let document_ids = self
+                    .get_document_ids(account_id, Collection::Mail)?
+                    .unwrap_or_default();
+                let mut changed = RoaringBitmap::new();
+                let mut destroyed = RoaringBitmap::new();
+
+                for change in changelog.changes {
+                    match change {
+                        changes::Change::Insert(id)
+                        | changes::Change::Update(id)
+                        | changes::Change::ChildUpdate(id) => {
+                            changed.insert(id.get_document_id());
+                        }
+                        changes::Change::Delete(id) => {
+                            destroyed.insert(id.get_document_id());
+                        }
+                    }
+                }
+                changed &= document_ids;
+
+                if !changed.is_empty() || !destroyed.is_empty() {
+                    Some(Delta::Changes(changed, destroyed, changelog.to_change_id))
+                } else {
+                    None
+                }
+            } else {
+                // Changes cannot be calculated once the ids were renumbered
+                self.get_last_change_id(account_id, Collection::Mail)?
+                    .map(Delta::Reset)
+            };
+            deltas.insert(since_change_id, delta);
+        }
+
+        let (changed, destroyed, change_id) = match deltas.get(&since_change_id) {
+            Some(Some(Delta::Changes(changed, destroyed, change_id))) => {
+                (changed, destroyed, change_id)
+            }
+            Some(Some(Delta::Reset(change_id))) => {
+                // Send all the results again, replacing the old ids
+                let response =
+                    self.mail_query(results.subscription.as_request(acl, account_id, true))?;
+                let query_state = JMAPState::from(*change_id);
+                let changes = QueryChangesResponse {
+                    account_id: account_id.into(),
+                    old_query_state: std::mem::replace(
+                        &mut results.query_state,
+                        query_state.clone(),
+                    ),
+                    new_query_state: query_state,
+                    total: response.ids.len().into(),
+                    removed: std::mem::take(&mut results.ids),
+                    added: response
+                        .ids
+                        .iter()
+                        .enumerate()
+                        .map(|(index, id)| AddedItem::new(*id, index))
+                        .collect(),
+                };
+                results.ids = response.ids;
+                return Ok(Some(changes));
+            }
+            _ => return Ok(None),
+        };
+
+        // Only the changed messages are evaluated against the filter, unsorted
+        let mut new_matches = self
+            .mail_query_delta(
+                results
+                    .subscription
+                    .as_request(acl.clone(), account_id, false),
+                QueryDelta {
+                    changed: changed.clone(),
+                    matching: RoaringBitmap::new(),
+                }
+                .into(),
+            )?
+            .ids
+            .into_iter()
+            .map(|id| (id.get_document_id(), id))
+            .collect::<AHashMap<_, _>>();
+
+        // Changed messages that still match keep their position when the sort
+        // does not depend on mutable properties.
+        let is_immutable_sort = results.subscription.has_immutable_sort();
+        let mut removed = Vec::new();
+        let mut ids = Vec::with_capacity(results.ids.len());
+        for id in &results.ids {
+            let document_id = id.get_document_id();
+            if destroyed.contains(document_id) {
+                removed.push(*id);
+            } else if !changed.contains(document_id) {
+                ids.push(*id);
+            } else if is_immutable_sort && new_matches.get(&document_id) == Some(id) {
+                new_matches.remove(&document_id);
+                ids.push(*id);
+            } else {
+                removed.push(*id);
+            }
+        }
+
+        // The results are sorted again only when there are new matches to place
+        let mut added = Vec::new();
+        if !new_matches.is_empty() {
+            let matching = ids
+                .iter()
+                .map(|id| id.get_document_id())
+                .collect::<RoaringBitmap>();
+            ids = self
+                .mail_query_delta(
+                    results.subscription.as_request(acl, account_id, true),
+                    QueryDelta {
+                        changed: new_matches.keys().copied().collect(),
+                        matching,
+                    }
+                    .into(),
+                )?
+                .ids;
+            added = ids
+                .iter()
+                .enumerate()
+                .filter(|(_, id)| new_matches.contains_key(&id.get_document_id()))
+                .map(|(index, id)| AddedItem::new(*id, index))
+                .collect::<Vec<_>>();
+        }
+
+        let query_state = JMAPState::from(*change_id);
+        let changes = if !removed.is_empty() || !added.is_empty() {
+            Some(QueryChangesResponse {
+                account_id: account_id.into(),
+                old_query_state: std::mem::replace(&mut results.query_state, query_state.clone()),
+                new_query_state: query_state,
+                total: ids.len().into(),
+                removed,
+                added,
+            })
+        } else {
+            results.query_state = query_state;
+            None
+        };
+        results.ids = ids;
+
+        Ok(changes)
+    }
+}
+
+impl QuerySubscription {
+    /// Whether the results depend on other messages in the same thread, which
+    /// cannot be updated by evaluating the changed messages only.
+    pub fn is_thread_dependent(&self) -> bool {
+        fn filter_is_thread_dependent(filter: &query::Filter<Filter>) -> bool {
+            match filter {
+                query::Filter::FilterOperator(operator) => {
+                    operator.conditions.iter().any(filter_is_thread_dependent)
+                }
+                query::Filter::FilterCondition(condition) => matches!(
+                    condition,
+                    Filter::AllInThreadHaveKeyword { .. }
+                        | Filter::SomeInThreadHaveKeyword { .. }
+                        | Filter::NoneInThreadHaveKeyword { .. }
+                        | Filter::SomeInThreadInMailbox { .. }
+                        | Filter::NoneInThreadInMailbox { .. }
+                ),
+                query::Filter::Empty => false,
+            }
+        }
+
+        self.filter
+            .as_ref()
+            .map_or(false, filter_is_thread_dependent)
+            || self.sort.iter().flatten().any(|comparator| {
+                matches!(
+                    comparator.property,
+                    Comparator::AllInThreadHaveKeyword { .. }
+                        | Comparator::SomeInThreadHaveKeyword { .. }
+                )
+            })
+    }
+
+    fn has_immutable_sort(&self) -> bool {
+        !self.sort.iter().flatten().any(|comparator| {
+            matches!(
+                comparator.property,
+                Comparator::HasKeyword { .. }
+                    | Comparator::AllInThreadHaveKeyword { .. }
+                    | Comparator::SomeInThreadHaveKeyword { .. }
+                    | Comparator::Relevance
+            )
+        })
+    }
+
+    fn as_request(
+        &self,
+        acl: Arc<ACLToken>,
+        account_id: AccountId,
+        is_sorted: bool,
+    ) -> QueryRequest<Email> {
+        QueryRequest {
+            acl: acl.into(),
+            account_id: account_id.into(),
+            filter: self.filter.clone(),
+            sort: if is_sorted { self.sort.clone() } else { None },
+            position: None,
+            anchor: None,
+            anchor_offset: None,
+            limit: None,
+            calculate_total: None,
+            pinned_query_state: None,
+            arguments: Default::default(),
+        }
+    }
+}
@@ -34,6 +34,8 @@ use store::{
     JMAPStore, Store,
 };
 
+use super::collation::JMAPMailCollation;
+use super::import::inserted_at;
 use super::schema::Email;
 use super::MessageData;
 use super::MessageField;
@@ -71,7 +73,12 @@ where
                     document.document_id
                 ))
             })?
-            .build_index(document, true)?;
+            .build_index(
+                document,
+                store.mail_collation(write_batch.account_id)?,
+                inserted_at().into(),
+                true,
+            )?;
 
             // Add thread id
             let thread_id = jmap_id.get_prefix_id();
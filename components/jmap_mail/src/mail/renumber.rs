@@ -0,0 +1,263 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use jmap::{
+    orm::{serialize::JMAPOrm, TinyORM},
+    types::jmap::JMAPId,
+};
+use store::{
+    core::{collection::Collection, document::Document, error::StoreError, JMAPIdPrefix},
+    log::changes::ChangeId,
+    roaring::RoaringTreemap,
+    tracing::debug,
+    write::{
+        batch::WriteBatch,
+        renumber::{IdReference, RenumberReport},
+    },
+    AccountId, DocumentId, JMAPStore, Store,
+};
+
+use crate::email_submission::schema::{EmailSubmission, Property, UndoStatus, Value};
+
+use super::MessageField;
+
+#[derive(Debug, serde::Serialize)]
+pub struct MailRenumberReport {
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    #[serde(flatten)]
+    pub renumber: RenumberReport,
+    #[serde(rename = "updatedSubmissions")]
+    pub updated_submissions: u64,
+    #[serde(skip)]
+    pub submission_change_id: Option<ChangeId>,
+}
+
+pub trait JMAPMailRenumber<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_renumber(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+    ) -> store::Result<MailRenumberReport>;
+}
+
+impl<T> JMAPMailRenumber<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_renumber(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+    ) -> store::Result<MailRenumberReport> {
+        if !matches!(collection, Collection::Mail | Collection::Thread) {
+            return Err(StoreError::InvalidArguments(format!(
+                "{:?} ids cannot be renumbered.",
+                collection
+            )));
+        }
+        let _gate = self.block_writes(account_id)?;
+        let _lock = self.lock_collection(account_id, Collection::Mail);
+
+        // Canceling a submission rolls back changes to the email it sent
+        for document_id in self
+            .get_document_ids(account_id, Collection::EmailSubmission)?
+            .unwrap_or_default()
+        {
+            if let Some(Value::UndoStatus {
+                value: UndoStatus::Pending,
+            }) = self
+                .get_orm::<EmailSubmission>(account_id, document_id)?
+                .as_ref()
+                .and_then(|fields| fields.get(&Property::UndoStatus))
+            {
+                return Err(StoreError::InvalidArguments(
+                    "The account has pending submissions.".to_string(),
+                ));
+            }
+        }
+
+        // Email ids embed the id of their thread, so the changes of both are
+        // reset whichever collection is renumbered.
+        let renumber = self.renumber_documents(
+            account_id,
+            &[collection],
+            &[IdReference::new(
+                Collection::Mail,
+                MessageField::ThreadId,
+                Collection::Thread,
+            )],
+            |report| {
+                let mail_ids = report.ids(Collection::Mail);
+                let thread_ids = report.ids(Collection::Thread);
+                let document_ids = self
+                    .get_document_ids(account_id, Collection::Mail)?
+                    .unwrap_or_default();
+                let mut email_ids = RoaringTreemap::new();
+                for (old_id, thread_id) in
+                    document_ids
+                        .iter()
+                        .zip(self.get_multi_document_value::<DocumentId>(
+                            account_id,
+                            Collection::Mail,
+                            document_ids.iter(),
+                            MessageField::ThreadId.into(),
+                        )?)
+                {
+                    let document_id = match mail_ids {
+                        Some(mail_ids) => mail_ids.get(old_id),
+                        None => Some(old_id),
+                    };
+                    let thread_id = match thread_ids {
+                        Some(thread_ids) => thread_id.and_then(|id| thread_ids.get(id)),
+                        None => thread_id,
+                    };
+                    if let (Some(document_id), Some(thread_id)) = (document_id, thread_id) {
+                        email_ids.insert(store::JMAPId::from_parts(thread_id, document_id));
+                    }
+                }
+
+                Ok(vec![
+                    (Collection::Mail, email_ids),
+                    (
+                        Collection::Thread,
+                        match thread_ids {
+                            Some(thread_ids) => {
+                                thread_ids.iter().map(|(_, id)| id as u64).collect()
+                            }
+                            None => self
+                                .get_document_ids(account_id, Collection::Thread)?
+                                .unwrap_or_default()
+                                .iter()
+                                .map(|id| id as u64)
+                                .collect(),
+                        },
+                    ),
+                ])
+            },
+        )?;
+
+        let mut report = MailRenumberReport {
+            account_id: account_id.into(),
+            renumber,
+            updated_submissions: 0,
+            submission_change_id: None,
+        };
+        if report.renumber.change_id.is_some() {
+            self.mail_renumber_submissions(&mut report)?;
+        }
+
+        Ok(report)
+    }
+}
+
+trait JMAPMailRenumberSubmissions {
+    fn mail_renumber_submissions(&self, report: &mut MailRenumberReport) -> store::Result<()>;
+}
+
+impl<T> JMAPMailRenumberSubmissions for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Submissions point to the email they sent and its thread
+    fn mail_renumber_submissions(&self, report: &mut MailRenumberReport) -> store::Result<()> {
+        let account_id = report.account_id.get_document_id();
+        let mail_ids = report.renumber.ids(Collection::Mail);
+        let mut batch = WriteBatch::new(account_id);
+        let mut updated_submissions = 0;
+
+        for document_id in self
+            .get_document_ids(account_id, Collection::EmailSubmission)?
+            .unwrap_or_default()
+        {
+            let current_fields =
+                if let Some(fields) = self.get_orm::<EmailSubmission>(account_id, document_id)? {
+                    fields
+                } else {
+                    continue;
+                };
+            let email_id = match current_fields.get(&Property::EmailId) {
+                Some(Value::Id { value }) => *value,
+                _ => continue,
+            };
+
+            // The thread id is read after renumbering, so it is already the new one
+            let new_email_id = if let Some((mail_id, thread_id)) = mail_ids
+                .map_or(Some(email_id.get_document_id()), |mail_ids| {
+                    mail_ids.get(email_id.get_document_id())
+                })
+                .and_then(|mail_id| {
+                    self.get_document_value::<DocumentId>(
+                        account_id,
+                        Collection::Mail,
+                        mail_id,
+                        MessageField::ThreadId.into(),
+                    )
+                    .transpose()
+                    .map(|thread_id| thread_id.map(|thread_id| (mail_id, thread_id)))
+                })
+                .transpose()?
+            {
+                JMAPId::from_parts(thread_id, mail_id)
+            } else {
+                debug!(
+                    "Email {} of submission {}:{} not found.",
+                    email_id, account_id, document_id
+                );
+                continue;
+            };
+            if new_email_id == email_id {
+                continue;
+            }
+
+            let mut fields = TinyORM::track_changes(&current_fields);
+            fields.set(
+                Property::EmailId,
+                Value::Id {
+                    value: new_email_id,
+                },
+            );
+            fields.set(
+                Property::ThreadId,
+                Value::Id {
+                    value: new_email_id.get_prefix_id().into(),
+                },
+            );
+            let mut document = Document::new(Collection::EmailSubmission, document_id);
+            current_fields.merge(&mut document, fields)?;
+            batch.update_document(document);
+            batch.log_update(Collection::EmailSubmission, document_id);
+            updated_submissions += 1;
+        }
+
+        report.updated_submissions = updated_submissions;
+        if !batch.is_empty() {
+            report.submission_change_id = self.write(batch)?.map(|changes| changes.change_id);
+        }
+
+        Ok(())
+    }
+}
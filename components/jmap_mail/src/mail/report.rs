@@ -0,0 +1,446 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use jmap::{
+    orm::TinyORM,
+    types::{blob::JMAPBlob, jmap::JMAPId},
+};
+use mail_parser::RfcHeader;
+use store::{
+    ahash::AHashMap,
+    blob::BlobId,
+    core::{collection::Collection, error::StoreError, tag::Tag},
+    roaring::RoaringBitmap,
+    serialize::{key::IndexKey, StoreDeserialize},
+    AccountId, ColumnFamily, Direction, DocumentId, JMAPStore, Store,
+};
+
+use crate::mailbox::schema::{Mailbox, Property, Value};
+
+use super::{HeaderValue, MessageData, MessageField};
+
+const REPORT_BATCH_SIZE: usize = 256;
+
+#[derive(Debug, serde::Serialize)]
+pub struct StorageReport {
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    #[serde(rename = "totalMessages")]
+    pub total_messages: u64,
+    #[serde(rename = "totalSize")]
+    pub total_size: u64,
+    pub mailboxes: Vec<MailboxUsage>,
+    #[serde(rename = "largestMessages")]
+    pub largest_messages: Vec<MessageUsage>,
+    #[serde(rename = "attachmentTypes")]
+    pub attachment_types: Vec<AttachmentTypeUsage>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct StorageSummary {
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    #[serde(rename = "totalMessages")]
+    pub total_messages: u64,
+    #[serde(rename = "totalSize")]
+    pub total_size: u64,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct MailboxUsage {
+    pub id: JMAPId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "totalMessages")]
+    pub total_messages: u64,
+    #[serde(rename = "totalSize")]
+    pub total_size: u64,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct MessageUsage {
+    pub id: JMAPId,
+    #[serde(rename = "blobId")]
+    pub blob_id: JMAPBlob,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct AttachmentTypeUsage {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub count: u64,
+    pub size: u64,
+}
+
+pub trait JMAPMailReport<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_storage_report(
+        &self,
+        account_id: AccountId,
+        max_largest: usize,
+    ) -> store::Result<StorageReport>;
+
+    fn mail_storage_summary(&self, account_ids: &[AccountId])
+        -> store::Result<Vec<StorageSummary>>;
+
+    fn mail_message_data(
+        &self,
+        account_id: AccountId,
+        document_id: DocumentId,
+    ) -> store::Result<Option<MessageData>>;
+
+    fn mail_multi_message_data(
+        &self,
+        account_id: AccountId,
+        document_ids: &[DocumentId],
+    ) -> store::Result<Vec<Option<MessageData>>>;
+}
+
+impl<T> JMAPMailReport<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_storage_report(
+        &self,
+        account_id: AccountId,
+        max_largest: usize,
+    ) -> store::Result<StorageReport> {
+        let mut report = StorageReport {
+            account_id: account_id.into(),
+            total_messages: 0,
+            total_size: 0,
+            mailboxes: Vec::new(),
+            largest_messages: Vec::new(),
+            attachment_types: Vec::new(),
+        };
+        let document_ids =
+            if let Some(document_ids) = self.get_document_ids(account_id, Collection::Mail)? {
+                document_ids
+            } else {
+                return Ok(report);
+            };
+
+        // Walk the size index from the largest message down, the first entries
+        // found are the largest messages in the account.
+        let mut sizes = AHashMap::with_capacity(document_ids.len() as usize);
+        let mut largest = Vec::with_capacity(max_largest);
+        let mut scan = self.scan_prefix(
+            ColumnFamily::Indexes,
+            IndexKey::serialize_field(
+                account_id,
+                Collection::Mail.into(),
+                MessageField::Size.into(),
+            ),
+            Direction::Backward,
+        )?;
+        while let Some(entry) = scan.next()? {
+            let suffix = entry.key_suffix();
+            let (size, document_id) = if let (Some(size), Some(document_id)) = (
+                suffix
+                    .get(..std::mem::size_of::<u32>())
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .map(u32::from_be_bytes),
+                IndexKey::deserialize_document_id(suffix),
+            ) {
+                (size as u64, document_id)
+            } else {
+                return Err(StoreError::DataCorruption(format!(
+                    "Invalid size index key {:?}",
+                    entry.key
+                )));
+            };
+
+            if document_ids.contains(document_id) && sizes.insert(document_id, size).is_none() {
+                report.total_messages += 1;
+                report.total_size += size;
+                if largest.len() < max_largest {
+                    largest.push((document_id, size));
+                }
+            }
+        }
+        drop(scan);
+
+        // Mailbox totals are obtained from the mailbox membership bitmaps.
+        if let Some(mailbox_ids) = self.get_document_ids(account_id, Collection::Mailbox)? {
+            let mailbox_ids = mailbox_ids.into_iter().collect::<Vec<_>>();
+            let names = self.get_multi_document_value::<TinyORM<Mailbox>>(
+                account_id,
+                Collection::Mailbox,
+                mailbox_ids.iter().copied(),
+                TinyORM::<Mailbox>::FIELD_ID,
+            )?;
+            let message_ids = self.get_tags(
+                account_id,
+                Collection::Mail,
+                MessageField::Mailbox.into(),
+                &mailbox_ids
+                    .iter()
+                    .map(|id| Tag::Id(*id))
+                    .collect::<Vec<_>>(),
+            )?;
+
+            for ((mailbox_id, orm), message_ids) in
+                mailbox_ids.into_iter().zip(names).zip(message_ids)
+            {
+                let mut usage = MailboxUsage {
+                    id: mailbox_id.into(),
+                    name: orm.and_then(|mut orm| match orm.remove(&Property::Name) {
+                        Some(Value::Text { value }) => Some(value),
+                        _ => None,
+                    }),
+                    total_messages: 0,
+                    total_size: 0,
+                };
+                for message_id in message_ids.into_iter().flatten() {
+                    if let Some(size) = sizes.get(&message_id) {
+                        usage.total_messages += 1;
+                        usage.total_size += size;
+                    }
+                }
+                report.mailboxes.push(usage);
+            }
+            report
+                .mailboxes
+                .sort_unstable_by(|a, b| b.total_size.cmp(&a.total_size));
+        }
+
+        let thread_ids = self.get_multi_document_value::<DocumentId>(
+            account_id,
+            Collection::Mail,
+            largest.iter().map(|(document_id, _)| *document_id),
+            MessageField::ThreadId.into(),
+        )?;
+        let message_data = self.mail_multi_message_data(
+            account_id,
+            &largest
+                .iter()
+                .map(|(document_id, _)| *document_id)
+                .collect::<Vec<_>>(),
+        )?;
+        for (((document_id, size), thread_id), message_data) in
+            largest.into_iter().zip(thread_ids).zip(message_data)
+        {
+            let (thread_id, message_data) =
+                if let (Some(thread_id), Some(message_data)) = (thread_id, message_data) {
+                    (thread_id, message_data)
+                } else {
+                    continue;
+                };
+
+            report.largest_messages.push(MessageUsage {
+                id: JMAPId::from_parts(thread_id, document_id),
+                blob_id: JMAPBlob::from(&message_data.raw_message),
+                size,
+                subject: message_data
+                    .headers
+                    .get(&RfcHeader::Subject)
+                    .and_then(|values| values.last())
+                    .and_then(|value| match value {
+                        HeaderValue::Text(subject) => Some(subject.to_string()),
+                        _ => None,
+                    }),
+            });
+        }
+
+        // Attachment types are not indexed, scan the metadata of the
+        // messages flagged as having attachments.
+        if let Some(message_ids) = self.get_tag(
+            account_id,
+            Collection::Mail,
+            MessageField::Attachment.into(),
+            Tag::Default,
+        )? {
+            let mut attachment_types: AHashMap<String, (u64, u64)> = AHashMap::default();
+            let message_ids = (message_ids & &document_ids)
+                .into_iter()
+                .collect::<Vec<_>>();
+            for message_ids in message_ids.chunks(REPORT_BATCH_SIZE) {
+                for message_data in self
+                    .mail_multi_message_data(account_id, message_ids)?
+                    .into_iter()
+                    .flatten()
+                {
+                    for part_id in &message_data.attachments {
+                        if let Some(part) = message_data.mime_parts.get(*part_id) {
+                            let usage = attachment_types
+                                .entry(
+                                    part.type_
+                                        .as_deref()
+                                        .unwrap_or("application/octet-stream")
+                                        .to_ascii_lowercase(),
+                                )
+                                .or_insert((0, 0));
+                            usage.0 += 1;
+                            usage.1 += part.size as u64;
+                        }
+                    }
+                }
+            }
+            report.attachment_types = attachment_types
+                .into_iter()
+                .map(|(type_, (count, size))| AttachmentTypeUsage { type_, count, size })
+                .collect();
+            report
+                .attachment_types
+                .sort_unstable_by(|a, b| b.size.cmp(&a.size));
+        }
+
+        Ok(report)
+    }
+
+    fn mail_storage_summary(
+        &self,
+        account_ids: &[AccountId],
+    ) -> store::Result<Vec<StorageSummary>> {
+        // Index keys start with the big-endian account id, sorting the accounts
+        // keeps them in the same order as the scanned prefixes.
+        let mut account_ids = account_ids.to_vec();
+        account_ids.sort_unstable();
+        account_ids.dedup();
+
+        let mut report = Vec::with_capacity(account_ids.len());
+        for account_ids in account_ids.chunks(REPORT_BATCH_SIZE) {
+            let document_ids = self.get_multi_document_ids(account_ids, Collection::Mail)?;
+            let mut summaries = account_ids
+                .iter()
+                .map(|account_id| StorageSummary {
+                    account_id: (*account_id).into(),
+                    total_messages: 0,
+                    total_size: 0,
+                })
+                .collect::<Vec<_>>();
+            let mut seen_ids = vec![RoaringBitmap::new(); account_ids.len()];
+
+            let mut scan = self.scan_multi_prefix(
+                ColumnFamily::Indexes,
+                account_ids
+                    .iter()
+                    .map(|account_id| {
+                        IndexKey::serialize_field(
+                            *account_id,
+                            Collection::Mail.into(),
+                            MessageField::Size.into(),
+                        )
+                    })
+                    .collect(),
+            );
+            while let Some((pos, entry)) = scan.next()? {
+                let suffix = entry.key_suffix();
+                let (size, document_id) = if let (Some(size), Some(document_id)) = (
+                    suffix
+                        .get(..std::mem::size_of::<u32>())
+                        .and_then(|bytes| bytes.try_into().ok())
+                        .map(u32::from_be_bytes),
+                    IndexKey::deserialize_document_id(suffix),
+                ) {
+                    (size as u64, document_id)
+                } else {
+                    return Err(StoreError::DataCorruption(format!(
+                        "Invalid size index key {:?}",
+                        entry.key
+                    )));
+                };
+
+                if document_ids[pos]
+                    .as_ref()
+                    .map_or(false, |ids| ids.contains(document_id))
+                    && seen_ids[pos].insert(document_id)
+                {
+                    summaries[pos].total_messages += 1;
+                    summaries[pos].total_size += size;
+                }
+            }
+            report.extend(summaries);
+        }
+
+        Ok(report)
+    }
+
+    fn mail_multi_message_data(
+        &self,
+        account_id: AccountId,
+        document_ids: &[DocumentId],
+    ) -> store::Result<Vec<Option<MessageData>>> {
+        let mut result = Vec::with_capacity(document_ids.len());
+        for (document_id, metadata_blob_id) in
+            document_ids
+                .iter()
+                .zip(self.get_multi_document_value::<BlobId>(
+                    account_id,
+                    Collection::Mail,
+                    document_ids.iter().copied(),
+                    MessageField::Metadata.into(),
+                )?)
+        {
+            result.push(
+                if let Some(bytes) = metadata_blob_id
+                    .map(|blob_id| self.blob_get(&blob_id))
+                    .transpose()?
+                    .flatten()
+                {
+                    MessageData::deserialize(&bytes)
+                        .ok_or_else(|| {
+                            StoreError::DataCorruption(format!(
+                                "Failed to deserialize email metadata for {}/{}",
+                                account_id, document_id
+                            ))
+                        })?
+                        .into()
+                } else {
+                    None
+                },
+            );
+        }
+        Ok(result)
+    }
+
+    fn mail_message_data(
+        &self,
+        account_id: AccountId,
+        document_id: DocumentId,
+    ) -> store::Result<Option<MessageData>> {
+        if let Some(metadata_blob_id) = self.get_document_value::<BlobId>(
+            account_id,
+            Collection::Mail,
+            document_id,
+            MessageField::Metadata.into(),
+        )? {
+            if let Some(bytes) = self.blob_get(&metadata_blob_id)? {
+                return MessageData::deserialize(&bytes)
+                    .ok_or_else(|| {
+                        StoreError::DataCorruption(format!(
+                            "Failed to deserialize email metadata for {}/{}",
+                            account_id, document_id
+                        ))
+                    })
+                    .map(Some);
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,255 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::SystemTime;
+
+use jmap::{
+    error::set::SetError,
+    orm::{serialize::JMAPOrm, TinyORM},
+    principal::schema::{Principal, Property as PrincipalProperty, Value as PrincipalValue},
+    SUPERUSER_ID,
+};
+use store::{
+    core::{collection::Collection, tag::Tag},
+    read::{
+        comparator::Comparator,
+        filter::{Filter, Query},
+        FilterMapper,
+    },
+    roaring::RoaringBitmap,
+    tracing::info,
+    AccountId, DocumentId, JMAPStore, LongInteger, Store,
+};
+
+use crate::mailbox::schema::{Mailbox, Property, Value};
+
+use super::schema::{Email, Property as EmailProperty};
+use super::MessageField;
+
+/// Messages that cannot be destroyed, either because they are under a legal
+/// hold or because they have not reached the minimum retention period of
+/// one of their mailboxes.
+#[derive(Debug, Default)]
+pub struct MailRetention {
+    pub account_hold: bool,
+    pub held: RoaringBitmap,
+    pub retained: RoaringBitmap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionReason {
+    AccountHold,
+    MailboxHold,
+    MinimumRetention,
+}
+
+impl MailRetention {
+    pub fn check(&self, document_id: DocumentId) -> Option<RetentionReason> {
+        if self.account_hold {
+            Some(RetentionReason::AccountHold)
+        } else if self.held.contains(document_id) {
+            Some(RetentionReason::MailboxHold)
+        } else if self.retained.contains(document_id) {
+            Some(RetentionReason::MinimumRetention)
+        } else {
+            None
+        }
+    }
+
+    /// Returns an error for messages that cannot be destroyed, logging the
+    /// blocked attempt to the audit log.
+    pub fn assert_can_destroy<U>(
+        &self,
+        account_id: AccountId,
+        document_id: DocumentId,
+    ) -> Result<(), SetError<U>> {
+        if let Some(reason) = self.check(document_id) {
+            info!(
+                target: "audit",
+                "Blocked deletion of message {}:{} ({}).",
+                account_id,
+                document_id,
+                reason.as_str()
+            );
+            Err(SetError::forbidden().with_description(match reason {
+                RetentionReason::AccountHold | RetentionReason::MailboxHold => {
+                    "Message is under legal hold."
+                }
+                RetentionReason::MinimumRetention => {
+                    "Message has not reached its minimum retention period."
+                }
+            }))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl RetentionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RetentionReason::AccountHold => "account legal hold",
+            RetentionReason::MailboxHold => "mailbox legal hold",
+            RetentionReason::MinimumRetention => "minimum retention",
+        }
+    }
+}
+
+pub trait JMAPMailRetention<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_retention(&self, account_id: AccountId) -> store::Result<MailRetention>;
+    fn mailbox_holds(&self, account_id: AccountId) -> store::Result<RoaringBitmap>;
+    fn mail_is_held(&self, account_id: AccountId, document_id: DocumentId) -> store::Result<bool>;
+}
+
+impl<T> JMAPMailRetention<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_retention(&self, account_id: AccountId) -> store::Result<MailRetention> {
+        let mut retention = MailRetention::default();
+
+        // Holds on the account cover all its messages
+        if let Some(fields) = self.get_orm::<Principal>(SUPERUSER_ID, account_id)? {
+            if let Some(PrincipalValue::Bool { value: true }) =
+                fields.get(&PrincipalProperty::LegalHold)
+            {
+                retention.account_hold = true;
+                return Ok(retention);
+            }
+        }
+
+        let mailbox_ids =
+            if let Some(mailbox_ids) = self.get_document_ids(account_id, Collection::Mailbox)? {
+                mailbox_ids
+            } else {
+                return Ok(retention);
+            };
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        for mailbox_id in mailbox_ids {
+            let fields = if let Some(fields) = self.get_orm::<Mailbox>(account_id, mailbox_id)? {
+                fields
+            } else {
+                continue;
+            };
+
+            if is_held(&fields) {
+                if let Some(message_ids) = self.get_tag(
+                    account_id,
+                    Collection::Mail,
+                    MessageField::Mailbox.into(),
+                    Tag::Id(mailbox_id),
+                )? {
+                    retention.held |= message_ids;
+                }
+                continue;
+            }
+
+            let days = match fields.get(&Property::Role).and_then(|role| role.as_text()) {
+                Some(role) => self
+                    .config
+                    .mail_retention_min_days
+                    .iter()
+                    .find_map(|(r, days)| if r == role { Some(*days) } else { None })
+                    .unwrap_or(0),
+                None => 0,
+            };
+            if days > 0 {
+                retention.retained |= self
+                    .query_store::<FilterMapper>(
+                        account_id,
+                        Collection::Mail,
+                        Filter::and(vec![
+                            Filter::eq(
+                                MessageField::Mailbox.into(),
+                                Query::Tag(Tag::Id(mailbox_id)),
+                            ),
+                            Filter::ge(
+                                MessageField::ReceivedAt.into(),
+                                Query::LongInteger(
+                                    now.saturating_sub(days as u64 * 86400) as LongInteger
+                                ),
+                            ),
+                        ]),
+                        Comparator::None,
+                    )?
+                    .into_bitmap();
+            }
+        }
+
+        Ok(retention)
+    }
+    fn mailbox_holds(&self, account_id: AccountId) -> store::Result<RoaringBitmap> {
+        let mut holds = RoaringBitmap::new();
+        for mailbox_id in self
+            .get_document_ids(account_id, Collection::Mailbox)?
+            .unwrap_or_default()
+        {
+            if self
+                .get_orm::<Mailbox>(account_id, mailbox_id)?
+                .map_or(false, |fields| is_held(&fields))
+            {
+                holds.insert(mailbox_id);
+            }
+        }
+        Ok(holds)
+    }
+
+    fn mail_is_held(&self, account_id: AccountId, document_id: DocumentId) -> store::Result<bool> {
+        if let Some(Some(PrincipalValue::Bool { value: true })) = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .map(|fields| fields.get(&PrincipalProperty::LegalHold).cloned())
+        {
+            return Ok(true);
+        }
+
+        if let Some(mailbox_ids) = self
+            .get_orm::<Email>(account_id, document_id)?
+            .and_then(|fields| fields.get_tags(&EmailProperty::MailboxIds).cloned())
+        {
+            for mailbox_id in mailbox_ids {
+                if self
+                    .get_orm::<Mailbox>(account_id, mailbox_id.as_id())?
+                    .map_or(false, |fields| is_held(&fields))
+                {
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+fn is_held(fields: &TinyORM<Mailbox>) -> bool {
+    matches!(
+        fields.get(&Property::LegalHold),
+        Some(Value::Bool { value: true })
+    )
+}
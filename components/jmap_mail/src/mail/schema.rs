@@ -95,6 +95,15 @@ pub struct EmailAddressGroup {
     pub addresses: Vec<EmailAddress>,
 }
 
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, Eq, PartialEq)]
+pub struct EmailSnooze {
+    pub until: JMAPDate,
+
+    #[serde(rename = "moveToMailboxId")]
+    #[serde(default)]
+    pub move_to_mailbox_id: Option<JMAPId>,
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, Eq, PartialEq)]
 pub struct EmailHeader {
     pub name: String,
@@ -167,6 +176,31 @@ impl Keyword {
             )
         })
     }
+
+    /// Parses a flag set by a Sieve script, mapping IMAP system flags
+    /// such as `\Seen` onto their JMAP keyword counterparts.
+    pub fn from_sieve_flag(value: &str) -> Self {
+        if let Some(flag) = value.strip_prefix('\\') {
+            let tag = if flag.eq_ignore_ascii_case("seen") {
+                Self::SEEN
+            } else if flag.eq_ignore_ascii_case("draft") {
+                Self::DRAFT
+            } else if flag.eq_ignore_ascii_case("flagged") {
+                Self::FLAGGED
+            } else if flag.eq_ignore_ascii_case("answered") {
+                Self::ANSWERED
+            } else if flag.eq_ignore_ascii_case("recent") {
+                Self::RECENT
+            } else if flag.eq_ignore_ascii_case("deleted") {
+                Self::DELETED
+            } else {
+                return Keyword::parse(value);
+            };
+            Keyword::new(Tag::Static(tag))
+        } else {
+            Keyword::parse(value)
+        }
+    }
 }
 
 impl From<&Tag> for Keyword {
@@ -234,6 +268,8 @@ pub enum Property {
     BodyStructure,
     Headers,
     Header(HeaderProperty),
+    Annotations,
+    Snoozed,
     Invalid(String),
 }
 
@@ -266,6 +302,8 @@ impl Property {
             "attachments" => Property::Attachments,
             "bodyStructure" => Property::BodyStructure,
             "headers" => Property::Headers,
+            "annotations" => Property::Annotations,
+            "snoozed" => Property::Snoozed,
             _ if value.starts_with("header:") => {
                 if let Some(header) = HeaderProperty::parse(value) {
                     Property::Header(header)
@@ -329,6 +367,8 @@ impl Display for Property {
             Property::BodyStructure => write!(f, "bodyStructure"),
             Property::Headers => write!(f, "headers"),
             Property::Header(header) => header.fmt(f),
+            Property::Annotations => write!(f, "annotations"),
+            Property::Snoozed => write!(f, "snoozed"),
             Property::Invalid(value) => write!(f, "{}", value),
         }
     }
@@ -445,11 +485,43 @@ impl HeaderProperty {
                 _ => return None,
             }
         }
-        Some(HeaderProperty {
+        let property = HeaderProperty {
             form,
             header: header?,
             all,
-        })
+        };
+        if property.is_valid() {
+            Some(property)
+        } else {
+            None
+        }
+    }
+
+    /// Returns false when the requested form is not allowed for the header
+    /// field, as defined in RFC 8621 section 4.1.2.
+    pub fn is_valid(&self) -> bool {
+        match (&self.header, &self.form) {
+            (_, HeaderForm::Raw) | (HeaderName::Other(_), _) => true,
+            (HeaderName::Rfc(header), form) => match HeaderForm::from_rfc_header(header) {
+                Some(HeaderForm::Addresses) => {
+                    matches!(form, HeaderForm::Addresses | HeaderForm::GroupedAddresses)
+                }
+                Some(default_form) => &default_form == form,
+                // RFC 5322 fields without a parsed form can only be fetched raw
+                None => !matches!(header, RfcHeader::Received | RfcHeader::ReturnPath),
+            },
+        }
+    }
+
+    /// Returns true when the header has to be parsed from the raw message
+    /// rather than fetched from the stored message metadata.
+    pub fn needs_raw_header(&self) -> bool {
+        match (&self.header, &self.form) {
+            (HeaderName::Rfc(header), form) if form != &HeaderForm::Raw => {
+                HeaderForm::from_rfc_header(header).is_none()
+            }
+            _ => true,
+        }
     }
 }
 
@@ -493,6 +565,38 @@ impl HeaderForm {
             _ => None,
         }
     }
+
+    /// Returns the form in which an RFC header is stored in the message metadata.
+    pub fn from_rfc_header(header: &RfcHeader) -> Option<HeaderForm> {
+        match header {
+            RfcHeader::MessageId
+            | RfcHeader::InReplyTo
+            | RfcHeader::References
+            | RfcHeader::ResentMessageId => Some(HeaderForm::MessageIds),
+            RfcHeader::From
+            | RfcHeader::To
+            | RfcHeader::Cc
+            | RfcHeader::Bcc
+            | RfcHeader::ReplyTo
+            | RfcHeader::Sender
+            | RfcHeader::ResentTo
+            | RfcHeader::ResentFrom
+            | RfcHeader::ResentBcc
+            | RfcHeader::ResentCc
+            | RfcHeader::ResentSender => Some(HeaderForm::Addresses),
+            RfcHeader::Date | RfcHeader::ResentDate => Some(HeaderForm::Date),
+            RfcHeader::ListArchive
+            | RfcHeader::ListHelp
+            | RfcHeader::ListOwner
+            | RfcHeader::ListPost
+            | RfcHeader::ListSubscribe
+            | RfcHeader::ListUnsubscribe => Some(HeaderForm::URLs),
+            RfcHeader::Subject | RfcHeader::Comments | RfcHeader::Keywords | RfcHeader::ListId => {
+                Some(HeaderForm::Text)
+            }
+            _ => None,
+        }
+    }
 }
 
 impl Display for HeaderForm {
@@ -531,6 +635,13 @@ pub enum Value {
         value: VecMap<MaybeIdReference, bool>,
         set: bool,
     },
+    Annotations {
+        value: VecMap<String, Option<String>>,
+        set: bool,
+    },
+    Snoozed {
+        value: EmailSnooze,
+    },
     ResultReference {
         value: ResultReference,
     },
@@ -573,6 +684,9 @@ pub enum Value {
     Headers {
         value: Vec<EmailHeader>,
     },
+    Invalid {
+        expected: &'static str,
+    },
     Null,
 }
 
@@ -660,6 +774,13 @@ impl Value {
             _ => None,
         }
     }
+
+    pub fn get_annotations(&mut self) -> Option<&mut VecMap<String, Option<String>>> {
+        match self {
+            Value::Annotations { value, .. } => Some(value),
+            _ => None,
+        }
+    }
 }
 
 impl From<Property> for FieldId {
@@ -693,6 +814,8 @@ impl From<Property> for FieldId {
             Property::Headers => 22,
             Property::Header(_) => 23,
             Property::Invalid(_) => 24,
+            Property::Annotations => 25,
+            Property::Snoozed => MessageField::Snoozed.into(),
         }
     }
 }
@@ -723,9 +846,11 @@ impl From<FieldId> for Property {
             20 => Property::Attachments,
             21 => Property::BodyStructure,
             22 => Property::Headers,
+            25 => Property::Annotations,
             136 => Property::ThreadId,
             137 => Property::MailboxIds,
             132 => Property::Keywords,
+            139 => Property::Snoozed,
             _ => Property::Invalid("".into()),
         }
     }
@@ -771,6 +896,11 @@ pub enum Filter {
     SentBefore { value: JMAPDate },
     SentAfter { value: JMAPDate },
     InThread { value: JMAPId },
+    InMailboxRole { value: String },
+    InMailboxRoleOtherThan { value: Vec<String> },
+    SomeInThreadInMailbox { value: JMAPId },
+    NoneInThreadInMailbox { value: JMAPId },
+    ListId { value: String },
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
@@ -798,4 +928,6 @@ pub enum Comparator {
     // Non-standard
     #[serde(rename = "cc")]
     Cc,
+    #[serde(rename = "relevance")]
+    Relevance,
 }
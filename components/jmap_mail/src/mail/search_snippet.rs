@@ -47,7 +47,7 @@ use store::{
     JMAPStore, Store,
 };
 
-use super::{sharing::JMAPShareMail, MessageData, MessageField};
+use super::{limits::MimeLimits, sharing::JMAPShareMail, MessageData, MessageField};
 
 #[derive(Debug, Clone)]
 pub struct SearchSnippetGetRequest {
@@ -276,7 +276,12 @@ where
             // Tokenize and stem terms
             for term in &terms {
                 if !term.match_phrase {
-                    for token in Stemmer::new(&term.text, term.language, MAX_TOKEN_LENGTH) {
+                    for token in Stemmer::with_config(
+                        &term.text,
+                        term.language,
+                        MAX_TOKEN_LENGTH,
+                        &self.config.nlp,
+                    ) {
                         match_terms.push(term_index.get_match_term(
                             token.word.as_ref(),
                             token.stemmed_word.as_ref().map(|w| w.as_ref()),
@@ -284,7 +289,14 @@ where
                     }
                 } else {
                     match_phrase = true;
-                    for token in Tokenizer::new(&term.text, term.language, MAX_TOKEN_LENGTH) {
+                    for token in Tokenizer::with_config(
+                        &term.text,
+                        term.language,
+                        MAX_TOKEN_LENGTH,
+                        &self.config.nlp,
+                    )
+                    .full_text()
+                    {
                         match_terms.push(term_index.get_match_term(token.word.as_ref(), None));
                     }
                 }
@@ -360,8 +372,9 @@ where
                                     );
                                     Vec::new()
                                 });
-                            let message =
-                                Message::parse(&nested_raw_message).unwrap_or_else(|| {
+                            let message = MimeLimits::from(&self.config)
+                                .parse(&nested_raw_message)
+                                .unwrap_or_else(|| {
                                     error!(
                                         "Failed to parse nested message in blob {:?}.",
                                         message_data.raw_message
@@ -30,7 +30,11 @@ use jmap::{
     types::{blob::JMAPBlob, jmap::JMAPId},
     types::{date::JMAPDate, json_pointer::JSONPointer},
 };
-use serde::{de::IgnoredAny, ser::SerializeMap, Deserialize, Serialize};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    ser::SerializeMap,
+    Deserialize, Serialize,
+};
 use store::{ahash::AHashSet, core::vec_map::VecMap};
 
 use super::{
@@ -43,6 +47,26 @@ use super::{
     search_snippet::SearchSnippetGetRequest,
 };
 
+// Deserializes a property value, values of the wrong type are kept as
+// Value::Invalid so that Email/set can report each one of them.
+fn next_value<'de, A, T>(
+    map: &mut A,
+    expected: &'static str,
+    value: impl FnOnce(T) -> Value,
+) -> Result<Option<Value>, A::Error>
+where
+    A: serde::de::MapAccess<'de>,
+    T: DeserializeOwned,
+{
+    match map.next_value::<serde_json::Value>()? {
+        serde_json::Value::Null => Ok(None),
+        json => Ok(Some(match serde_json::from_value::<T>(json) {
+            Ok(result) => value(result),
+            Err(_) => Value::Invalid { expected },
+        })),
+    }
+}
+
 // Email de/serialization
 impl Serialize for Email {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -59,6 +83,8 @@ impl Serialize for Email {
                 Value::Bool { value } => map.serialize_entry(name, value)?,
                 Value::Keywords { value, .. } => map.serialize_entry(name, value)?,
                 Value::MailboxIds { value, .. } => map.serialize_entry(name, value)?,
+                Value::Annotations { value, .. } => map.serialize_entry(name, value)?,
+                Value::Snoozed { value } => map.serialize_entry(name, value)?,
                 Value::ResultReference { value } => map.serialize_entry(name, value)?,
                 Value::BodyPart { value } => map.serialize_entry(name, value)?,
                 Value::BodyPartList { value } => map.serialize_entry(name, value)?,
@@ -73,7 +99,7 @@ impl Serialize for Email {
                 Value::GroupedAddresses { value } => map.serialize_entry(name, value)?,
                 Value::GroupedAddressesList { value } => map.serialize_entry(name, value)?,
                 Value::Headers { value } => map.serialize_entry(name, value)?,
-                Value::Null => map.serialize_entry(name, &None::<&str>)?,
+                Value::Null | Value::Invalid { .. } => map.serialize_entry(name, &None::<&str>)?,
             }
         }
 
@@ -98,151 +124,195 @@ impl<'de> serde::de::Visitor<'de> for EmailVisitor {
         while let Some(key) = map.next_key::<Cow<str>>()? {
             match key.as_ref() {
                 "keywords" => {
-                    if let Some(value) = map.next_value::<Option<VecMap<Keyword, bool>>>()? {
-                        properties.append(Property::Keywords, Value::Keywords { value, set: true });
+                    if let Some(value) = next_value(&mut map, "String[Boolean]", |value| {
+                        Value::Keywords { value, set: true }
+                    })? {
+                        properties.append(Property::Keywords, value);
                     }
                 }
                 "mailboxIds" => {
-                    if let Some(value) =
-                        map.next_value::<Option<VecMap<MaybeIdReference, bool>>>()?
-                    {
-                        properties
-                            .append(Property::MailboxIds, Value::MailboxIds { value, set: true });
+                    if let Some(value) = next_value(&mut map, "Id[Boolean]", |value| {
+                        Value::MailboxIds { value, set: true }
+                    })? {
+                        properties.append(Property::MailboxIds, value);
                     }
                 }
+                "annotations" => {
+                    if let Some(value) = next_value(&mut map, "String[String]", |value| {
+                        Value::Annotations { value, set: true }
+                    })? {
+                        properties.append(Property::Annotations, value);
+                    }
+                }
+                "snoozed" => {
+                    // A null value wakes up a snoozed message
+                    properties.append(
+                        Property::Snoozed,
+                        next_value(&mut map, "EmailSnooze", |value| Value::Snoozed { value })?
+                            .unwrap_or_default(),
+                    );
+                }
                 "messageId" => {
-                    if let Some(value) = map.next_value::<Option<Vec<String>>>()? {
-                        properties.append(Property::MessageId, Value::TextList { value });
+                    if let Some(value) =
+                        next_value(&mut map, "String[]", |value| Value::TextList { value })?
+                    {
+                        properties.append(Property::MessageId, value);
                     }
                 }
                 "inReplyTo" => {
-                    if let Some(value) = map.next_value::<Option<Vec<String>>>()? {
-                        properties.append(Property::InReplyTo, Value::TextList { value });
+                    if let Some(value) =
+                        next_value(&mut map, "String[]", |value| Value::TextList { value })?
+                    {
+                        properties.append(Property::InReplyTo, value);
                     }
                 }
                 "references" => {
-                    if let Some(value) = map.next_value::<Option<Vec<String>>>()? {
-                        properties.append(Property::References, Value::TextList { value });
+                    if let Some(value) =
+                        next_value(&mut map, "String[]", |value| Value::TextList { value })?
+                    {
+                        properties.append(Property::References, value);
                     }
                 }
                 "sender" => {
-                    if let Some(value) = map.next_value::<Option<Vec<EmailAddress>>>()? {
-                        properties.append(Property::Sender, Value::Addresses { value });
+                    if let Some(value) = next_value(&mut map, "EmailAddress[]", |value| {
+                        Value::Addresses { value }
+                    })? {
+                        properties.append(Property::Sender, value);
                     }
                 }
                 "from" => {
-                    if let Some(value) = map.next_value::<Option<Vec<EmailAddress>>>()? {
-                        properties.append(Property::From, Value::Addresses { value });
+                    if let Some(value) = next_value(&mut map, "EmailAddress[]", |value| {
+                        Value::Addresses { value }
+                    })? {
+                        properties.append(Property::From, value);
                     }
                 }
                 "to" => {
-                    if let Some(value) = map.next_value::<Option<Vec<EmailAddress>>>()? {
-                        properties.append(Property::To, Value::Addresses { value });
+                    if let Some(value) = next_value(&mut map, "EmailAddress[]", |value| {
+                        Value::Addresses { value }
+                    })? {
+                        properties.append(Property::To, value);
                     }
                 }
                 "cc" => {
-                    if let Some(value) = map.next_value::<Option<Vec<EmailAddress>>>()? {
-                        properties.append(Property::Cc, Value::Addresses { value });
+                    if let Some(value) = next_value(&mut map, "EmailAddress[]", |value| {
+                        Value::Addresses { value }
+                    })? {
+                        properties.append(Property::Cc, value);
                     }
                 }
                 "bcc" => {
-                    if let Some(value) = map.next_value::<Option<Vec<EmailAddress>>>()? {
-                        properties.append(Property::Bcc, Value::Addresses { value });
+                    if let Some(value) = next_value(&mut map, "EmailAddress[]", |value| {
+                        Value::Addresses { value }
+                    })? {
+                        properties.append(Property::Bcc, value);
                     }
                 }
                 "replyTo" => {
-                    if let Some(value) = map.next_value::<Option<Vec<EmailAddress>>>()? {
-                        properties.append(Property::ReplyTo, Value::Addresses { value });
+                    if let Some(value) = next_value(&mut map, "EmailAddress[]", |value| {
+                        Value::Addresses { value }
+                    })? {
+                        properties.append(Property::ReplyTo, value);
                     }
                 }
                 "subject" => {
-                    if let Some(value) = map.next_value::<Option<String>>()? {
-                        properties.append(Property::Subject, Value::Text { value });
+                    if let Some(value) =
+                        next_value(&mut map, "String", |value| Value::Text { value })?
+                    {
+                        properties.append(Property::Subject, value);
                     }
                 }
                 "sentAt" => {
-                    if let Some(value) = map.next_value::<Option<JMAPDate>>()? {
-                        properties.append(Property::SentAt, Value::Date { value });
+                    if let Some(value) =
+                        next_value(&mut map, "UTCDate", |value| Value::Date { value })?
+                    {
+                        properties.append(Property::SentAt, value);
                     }
                 }
                 "receivedAt" => {
-                    if let Some(value) = map.next_value::<Option<JMAPDate>>()? {
-                        properties.append(Property::ReceivedAt, Value::Date { value });
+                    if let Some(value) =
+                        next_value(&mut map, "UTCDate", |value| Value::Date { value })?
+                    {
+                        properties.append(Property::ReceivedAt, value);
                     }
                 }
                 "preview" => {
-                    if let Some(value) = map.next_value::<Option<String>>()? {
-                        properties.append(Property::Preview, Value::Text { value });
+                    if let Some(value) =
+                        next_value(&mut map, "String", |value| Value::Text { value })?
+                    {
+                        properties.append(Property::Preview, value);
                     }
                 }
                 "textBody" => {
-                    properties.append(
-                        Property::TextBody,
-                        Value::BodyPartList {
-                            value: map.next_value()?,
-                        },
-                    );
+                    if let Some(value) = next_value(&mut map, "EmailBodyPart[]", |value| {
+                        Value::BodyPartList { value }
+                    })? {
+                        properties.append(Property::TextBody, value);
+                    }
                 }
                 "htmlBody" => {
-                    properties.append(
-                        Property::HtmlBody,
-                        Value::BodyPartList {
-                            value: map.next_value()?,
-                        },
-                    );
+                    if let Some(value) = next_value(&mut map, "EmailBodyPart[]", |value| {
+                        Value::BodyPartList { value }
+                    })? {
+                        properties.append(Property::HtmlBody, value);
+                    }
                 }
                 "attachments" => {
-                    properties.append(
-                        Property::Attachments,
-                        Value::BodyPartList {
-                            value: map.next_value()?,
-                        },
-                    );
+                    if let Some(value) = next_value(&mut map, "EmailBodyPart[]", |value| {
+                        Value::BodyPartList { value }
+                    })? {
+                        properties.append(Property::Attachments, value);
+                    }
                 }
                 "hasAttachment" => {
-                    if let Some(value) = map.next_value::<Option<bool>>()? {
-                        properties.append(Property::HasAttachment, Value::Bool { value });
+                    if let Some(value) =
+                        next_value(&mut map, "Boolean", |value| Value::Bool { value })?
+                    {
+                        properties.append(Property::HasAttachment, value);
                     }
                 }
                 "id" => {
-                    if let Some(value) = map.next_value::<Option<JMAPId>>()? {
-                        properties.append(Property::Id, Value::Id { value });
+                    if let Some(value) = next_value(&mut map, "Id", |value| Value::Id { value })? {
+                        properties.append(Property::Id, value);
                     }
                 }
                 "blobId" => {
-                    if let Some(value) = map.next_value::<Option<JMAPBlob>>()? {
-                        properties.append(Property::BlobId, Value::Blob { value });
+                    if let Some(value) = next_value(&mut map, "Id", |value| Value::Blob { value })?
+                    {
+                        properties.append(Property::BlobId, value);
                     }
                 }
                 "threadId" => {
-                    if let Some(value) = map.next_value::<Option<JMAPId>>()? {
-                        properties.append(Property::ThreadId, Value::Id { value });
+                    if let Some(value) = next_value(&mut map, "Id", |value| Value::Id { value })? {
+                        properties.append(Property::ThreadId, value);
                     }
                 }
                 "size" => {
-                    if let Some(value) = map.next_value::<Option<usize>>()? {
-                        properties.append(Property::Size, Value::Size { value });
+                    if let Some(value) =
+                        next_value(&mut map, "UnsignedInt", |value| Value::Size { value })?
+                    {
+                        properties.append(Property::Size, value);
                     }
                 }
                 "bodyValues" => {
-                    properties.append(
-                        Property::BodyValues,
-                        Value::BodyValues {
-                            value: map.next_value()?,
-                        },
-                    );
+                    if let Some(value) = next_value(&mut map, "String[EmailBodyValue]", |value| {
+                        Value::BodyValues { value }
+                    })? {
+                        properties.append(Property::BodyValues, value);
+                    }
                 }
                 "bodyStructure" => {
-                    properties.append(
-                        Property::BodyStructure,
-                        Value::BodyPart {
-                            value: map.next_value()?,
-                        },
-                    );
+                    if let Some(value) =
+                        next_value(&mut map, "EmailBodyPart", |value| Value::BodyPart { value })?
+                    {
+                        properties.append(Property::BodyStructure, value);
+                    }
                 }
                 "headers" => {
-                    if let Some(value) = map.next_value::<Option<Vec<EmailHeader>>>()? {
-                        properties.append(Property::Headers, Value::Headers { value });
+                    if let Some(value) =
+                        next_value(&mut map, "EmailHeader[]", |value| Value::Headers { value })?
+                    {
+                        properties.append(Property::Headers, value);
                     }
                 }
                 _ if key.starts_with('#') => {
@@ -262,61 +332,59 @@ impl<'de> serde::de::Visitor<'de> for EmailVisitor {
                         let header_value = match header.form {
                             HeaderForm::Raw | HeaderForm::Text => {
                                 if header.all {
-                                    Value::TextList {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "String[]", |value| Value::TextList {
+                                        value,
+                                    })?
                                 } else {
-                                    Value::Text {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "String", |value| Value::Text { value })?
                                 }
                             }
                             HeaderForm::Addresses => {
                                 if header.all {
-                                    Value::AddressesList {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "EmailAddress[][]", |value| {
+                                        Value::AddressesList { value }
+                                    })?
                                 } else {
-                                    Value::Addresses {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "EmailAddress[]", |value| {
+                                        Value::Addresses { value }
+                                    })?
                                 }
                             }
                             HeaderForm::GroupedAddresses => {
                                 if header.all {
-                                    Value::GroupedAddressesList {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "EmailAddressGroup[][]", |value| {
+                                        Value::GroupedAddressesList { value }
+                                    })?
                                 } else {
-                                    Value::GroupedAddresses {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "EmailAddressGroup[]", |value| {
+                                        Value::GroupedAddresses { value }
+                                    })?
                                 }
                             }
                             HeaderForm::MessageIds | HeaderForm::URLs => {
                                 if header.all {
-                                    Value::TextListMany {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "String[][]", |value| {
+                                        Value::TextListMany { value }
+                                    })?
                                 } else {
-                                    Value::TextList {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "String[]", |value| Value::TextList {
+                                        value,
+                                    })?
                                 }
                             }
                             HeaderForm::Date => {
                                 if header.all {
-                                    Value::DateList {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "Date[]", |value| Value::DateList {
+                                        value,
+                                    })?
                                 } else {
-                                    Value::Date {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "Date", |value| Value::Date { value })?
                                 }
                             }
                         };
-                        properties.append(Property::Header(header), header_value);
+                        if let Some(header_value) = header_value {
+                            properties.append(Property::Header(header), header_value);
+                        }
                     } else {
                         map.next_value::<IgnoredAny>()?;
                     }
@@ -330,9 +398,24 @@ impl<'de> serde::de::Visitor<'de> for EmailVisitor {
                                     Some(JSONPointer::String(id)),
                                 ) = (path.get(0), path.get(1))
                                 {
-                                    let value = map.next_value::<Option<bool>>()?.unwrap_or(false);
                                     match Property::parse(property) {
+                                        Property::Annotations => {
+                                            let value = map.next_value::<Option<String>>()?;
+                                            properties
+                                                .get_mut_or_insert_with(
+                                                    Property::Annotations,
+                                                    || Value::Annotations {
+                                                        value: VecMap::new(),
+                                                        set: false,
+                                                    },
+                                                )
+                                                .get_annotations()
+                                                .unwrap()
+                                                .append(id.to_string(), value);
+                                        }
                                         Property::MailboxIds => {
+                                            let value =
+                                                map.next_value::<Option<bool>>()?.unwrap_or(false);
                                             if let Some(id) = JMAPId::parse(id) {
                                                 properties
                                                     .get_mut_or_insert_with(
@@ -348,6 +431,8 @@ impl<'de> serde::de::Visitor<'de> for EmailVisitor {
                                             }
                                         }
                                         Property::Keywords => {
+                                            let value =
+                                                map.next_value::<Option<bool>>()?.unwrap_or(false);
                                             properties
                                                 .get_mut_or_insert_with(Property::Keywords, || {
                                                     Value::Keywords {
@@ -405,6 +490,8 @@ impl Serialize for EmailBodyPart {
                 Value::Bool { value } => map.serialize_entry(name, value)?,
                 Value::Keywords { value, .. } => map.serialize_entry(name, value)?,
                 Value::MailboxIds { value, .. } => map.serialize_entry(name, value)?,
+                Value::Annotations { value, .. } => map.serialize_entry(name, value)?,
+                Value::Snoozed { value } => map.serialize_entry(name, value)?,
                 Value::ResultReference { value } => map.serialize_entry(name, value)?,
                 Value::BodyPart { value } => map.serialize_entry(name, value)?,
                 Value::BodyPartList { value } => map.serialize_entry(name, value)?,
@@ -419,7 +506,7 @@ impl Serialize for EmailBodyPart {
                 Value::GroupedAddresses { value } => map.serialize_entry(name, value)?,
                 Value::GroupedAddressesList { value } => map.serialize_entry(name, value)?,
                 Value::Headers { value } => map.serialize_entry(name, value)?,
-                Value::Null => map.serialize_entry(name, &None::<&str>)?,
+                Value::Null | Value::Invalid { .. } => map.serialize_entry(name, &None::<&str>)?,
             }
         }
 
@@ -444,63 +531,86 @@ impl<'de> serde::de::Visitor<'de> for EmailBodyPartVisitor {
         while let Some(key) = map.next_key::<Cow<str>>()? {
             match key.as_ref() {
                 "partId" => {
-                    if let Some(value) = map.next_value::<Option<String>>()? {
-                        properties.append(BodyProperty::PartId, Value::Text { value });
+                    if let Some(value) =
+                        next_value(&mut map, "String", |value| Value::Text { value })?
+                    {
+                        properties.append(BodyProperty::PartId, value);
                     }
                 }
                 "blobId" => {
-                    if let Some(value) = map.next_value::<Option<JMAPBlob>>()? {
-                        properties.append(BodyProperty::BlobId, Value::Blob { value });
+                    if let Some(value) = next_value(&mut map, "Id", |value| Value::Blob { value })?
+                    {
+                        properties.append(BodyProperty::BlobId, value);
                     }
                 }
                 "size" => {
-                    if let Some(value) = map.next_value::<Option<usize>>()? {
-                        properties.append(BodyProperty::Size, Value::Size { value });
+                    if let Some(value) =
+                        next_value(&mut map, "UnsignedInt", |value| Value::Size { value })?
+                    {
+                        properties.append(BodyProperty::Size, value);
                     }
                 }
                 "name" => {
-                    if let Some(value) = map.next_value::<Option<String>>()? {
-                        properties.append(BodyProperty::Name, Value::Text { value });
+                    if let Some(value) =
+                        next_value(&mut map, "String", |value| Value::Text { value })?
+                    {
+                        properties.append(BodyProperty::Name, value);
                     }
                 }
                 "type" => {
-                    if let Some(value) = map.next_value::<Option<String>>()? {
-                        properties.append(BodyProperty::Type, Value::Text { value });
+                    if let Some(value) =
+                        next_value(&mut map, "String", |value| Value::Text { value })?
+                    {
+                        properties.append(BodyProperty::Type, value);
                     }
                 }
                 "charset" => {
-                    if let Some(value) = map.next_value::<Option<String>>()? {
-                        properties.append(BodyProperty::Charset, Value::Text { value });
+                    if let Some(value) =
+                        next_value(&mut map, "String", |value| Value::Text { value })?
+                    {
+                        properties.append(BodyProperty::Charset, value);
                     }
                 }
                 "disposition" => {
-                    if let Some(value) = map.next_value::<Option<String>>()? {
-                        properties.append(BodyProperty::Disposition, Value::Text { value });
+                    if let Some(value) =
+                        next_value(&mut map, "String", |value| Value::Text { value })?
+                    {
+                        properties.append(BodyProperty::Disposition, value);
                     }
                 }
                 "cid" => {
-                    if let Some(value) = map.next_value::<Option<String>>()? {
-                        properties.append(BodyProperty::Cid, Value::Text { value });
+                    if let Some(value) =
+                        next_value(&mut map, "String", |value| Value::Text { value })?
+                    {
+                        properties.append(BodyProperty::Cid, value);
                     }
                 }
                 "language" => {
-                    if let Some(value) = map.next_value::<Option<Vec<String>>>()? {
-                        properties.append(BodyProperty::Language, Value::TextList { value });
+                    if let Some(value) =
+                        next_value(&mut map, "String[]", |value| Value::TextList { value })?
+                    {
+                        properties.append(BodyProperty::Language, value);
                     }
                 }
                 "location" => {
-                    if let Some(value) = map.next_value::<Option<String>>()? {
-                        properties.append(BodyProperty::Location, Value::Text { value });
+                    if let Some(value) =
+                        next_value(&mut map, "String", |value| Value::Text { value })?
+                    {
+                        properties.append(BodyProperty::Location, value);
                     }
                 }
                 "subParts" => {
-                    if let Some(value) = map.next_value::<Option<Vec<EmailBodyPart>>>()? {
-                        properties.append(BodyProperty::Subparts, Value::BodyPartList { value });
+                    if let Some(value) = next_value(&mut map, "EmailBodyPart[]", |value| {
+                        Value::BodyPartList { value }
+                    })? {
+                        properties.append(BodyProperty::Subparts, value);
                     }
                 }
                 "headers" => {
-                    if let Some(value) = map.next_value::<Option<Vec<EmailHeader>>>()? {
-                        properties.append(BodyProperty::Headers, Value::Headers { value });
+                    if let Some(value) =
+                        next_value(&mut map, "EmailHeader[]", |value| Value::Headers { value })?
+                    {
+                        properties.append(BodyProperty::Headers, value);
                     }
                 }
                 _ if key.starts_with("header:") => {
@@ -508,61 +618,59 @@ impl<'de> serde::de::Visitor<'de> for EmailBodyPartVisitor {
                         let header_value = match header.form {
                             HeaderForm::Raw | HeaderForm::Text => {
                                 if header.all {
-                                    Value::TextList {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "String[]", |value| Value::TextList {
+                                        value,
+                                    })?
                                 } else {
-                                    Value::Text {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "String", |value| Value::Text { value })?
                                 }
                             }
                             HeaderForm::Addresses => {
                                 if header.all {
-                                    Value::AddressesList {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "EmailAddress[][]", |value| {
+                                        Value::AddressesList { value }
+                                    })?
                                 } else {
-                                    Value::Addresses {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "EmailAddress[]", |value| {
+                                        Value::Addresses { value }
+                                    })?
                                 }
                             }
                             HeaderForm::GroupedAddresses => {
                                 if header.all {
-                                    Value::GroupedAddressesList {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "EmailAddressGroup[][]", |value| {
+                                        Value::GroupedAddressesList { value }
+                                    })?
                                 } else {
-                                    Value::GroupedAddresses {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "EmailAddressGroup[]", |value| {
+                                        Value::GroupedAddresses { value }
+                                    })?
                                 }
                             }
                             HeaderForm::MessageIds | HeaderForm::URLs => {
                                 if header.all {
-                                    Value::TextListMany {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "String[][]", |value| {
+                                        Value::TextListMany { value }
+                                    })?
                                 } else {
-                                    Value::TextList {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "String[]", |value| Value::TextList {
+                                        value,
+                                    })?
                                 }
                             }
                             HeaderForm::Date => {
                                 if header.all {
-                                    Value::DateList {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "Date[]", |value| Value::DateList {
+                                        value,
+                                    })?
                                 } else {
-                                    Value::Date {
-                                        value: map.next_value()?,
-                                    }
+                                    next_value(&mut map, "Date", |value| Value::Date { value })?
                                 }
                             }
                         };
-                        properties.append(BodyProperty::Header(header), header_value);
+                        if let Some(header_value) = header_value {
+                            properties.append(BodyProperty::Header(header), header_value);
+                        }
                     }
                 }
                 _ => {
@@ -838,6 +946,21 @@ impl FilterDeserializer for Filter {
             "inThread" => Filter::InThread {
                 value: map.next_value().ok()?,
             },
+            "inMailboxRole" => Filter::InMailboxRole {
+                value: map.next_value().ok()?,
+            },
+            "inMailboxRoleOtherThan" => Filter::InMailboxRoleOtherThan {
+                value: map.next_value().ok()?,
+            },
+            "someInThreadInMailbox" => Filter::SomeInThreadInMailbox {
+                value: map.next_value().ok()?,
+            },
+            "noneInThreadInMailbox" => Filter::NoneInThreadInMailbox {
+                value: map.next_value().ok()?,
+            },
+            "listId" => Filter::ListId {
+                value: map.next_value().ok()?,
+            },
 
             unsupported => {
                 map.next_value::<IgnoredAny>().ok()?;
@@ -21,11 +21,18 @@
  * for more details.
 */
 
-use super::get::{BlobResult, JMAPGetMail};
+use super::annotations::JMAPMailAnnotations;
+use super::collation::Collation;
+use super::get::{BlobAccess, BlobResult, JMAPGetMail};
+use super::keywords::JMAPMailKeywords;
+use super::limits::MimeLimits;
+use super::retention::JMAPMailRetention;
 use super::schema::{
-    BodyProperty, Email, EmailBodyPart, EmailBodyValue, HeaderForm, Keyword, Property, Value,
+    BodyProperty, Email, EmailBodyPart, EmailBodyValue, EmailSnooze, HeaderForm, Keyword, Property,
+    Value,
 };
 use super::sharing::JMAPShareMail;
+use super::snooze::JMAPMailSnooze;
 use super::{HeaderName, MessageData, MessageField};
 use crate::mail::import::JMAPMailImport;
 use jmap::error::set::{SetError, SetErrorType};
@@ -44,9 +51,9 @@ use mail_builder::headers::text::Text;
 use mail_builder::headers::url::URL;
 use mail_builder::mime::{BodyPart, MimePart};
 use mail_builder::MessageBuilder;
-use mail_parser::{Message, RfcHeader};
+use mail_parser::RfcHeader;
 use std::sync::Arc;
-use store::ahash::AHashSet;
+use store::ahash::{AHashMap, AHashSet};
 use store::blob::BlobId;
 use store::core::acl::{ACLToken, ACL};
 use store::core::collection::Collection;
@@ -54,9 +61,10 @@ use store::core::document::Document;
 use store::core::error::StoreError;
 use store::core::tag::Tag;
 use store::core::vec_map::VecMap;
+use store::roaring::RoaringBitmap;
 use store::serialize::StoreDeserialize;
-use store::tracing::error;
-use store::write::batch::WriteBatch;
+use store::tracing::{error, info};
+use store::write::batch::{WriteAction, WriteBatch};
 use store::write::options::{IndexOptions, Options};
 use store::{AccountId, DocumentId, JMAPStore, SharedBitmap, Store};
 
@@ -117,6 +125,11 @@ where
     T: for<'x> Store<'x> + 'static,
 {
     fn mail_set(&self, request: SetRequest<Email>) -> jmap::Result<SetResponse<Email>>;
+    fn mail_set_linked(
+        &self,
+        request: SetRequest<Email>,
+        linked: Option<WriteBatch>,
+    ) -> jmap::Result<SetResponse<Email>>;
     fn mail_delete(
         &self,
         account_id: AccountId,
@@ -130,15 +143,40 @@ where
     T: for<'x> Store<'x> + 'static,
 {
     fn mail_set(&self, request: SetRequest<Email>) -> jmap::Result<SetResponse<Email>> {
+        self.mail_set_linked(request, None)
+    }
+
+    // Linked changes, such as the mailboxes created by a preceding Mailbox/set,
+    // are written along with the first change to the messages. Messages are still
+    // created one at a time so that each one is threaded with the ones before it.
+    // When no message is changed, the linked changes are discarded and the caller
+    // reports them as rolled back.
+    fn mail_set_linked(
+        &self,
+        request: SetRequest<Email>,
+        linked: Option<WriteBatch>,
+    ) -> jmap::Result<SetResponse<Email>> {
         let mut helper = SetHelper::new(self, request)?;
-        let mailbox_ids = self
+        let mut mailbox_ids = self
             .get_document_ids(helper.account_id, Collection::Mailbox)?
             .unwrap_or_default();
         let account_id = helper.account_id;
 
         helper.disable_write_batch();
+        if let Some(linked) = linked {
+            for document in &linked.documents {
+                if let WriteAction::Insert(document) = document {
+                    if document.collection == Collection::Mailbox {
+                        mailbox_ids.insert(document.document_id);
+                    }
+                }
+            }
+            helper.changes.add_linked_batch(linked);
+        }
 
         helper.create(|_create_id, item, helper, document| {
+            item.validate(true)?;
+
             let mut builder = MessageBuilder::new();
             let mut fields = TinyORM::<Email>::new();
 
@@ -153,6 +191,33 @@ where
             let max_size_attachments = helper.store.config.mail_attachments_max_size;
             let mut size_attachments = 0;
 
+            // Messages created from an uploaded RFC822 blob are stored as-is,
+            // only the properties that are not part of the message can be set.
+            let raw_message = match item.properties.get(&Property::BlobId) {
+                Some(Value::Blob { value }) => {
+                    if let Some(property) = item.properties.keys().find(|property| {
+                        !matches!(
+                            property,
+                            Property::BlobId
+                                | Property::MailboxIds
+                                | Property::Keywords
+                                | Property::ReceivedAt
+                                | Property::Annotations
+                                | Property::Snoozed
+                        )
+                    }) {
+                        return Err(SetError::invalid_properties()
+                            .with_property(property.clone())
+                            .with_description("Cannot be set together with blobId."));
+                    }
+                    Some(value)
+                }
+                _ => None,
+            };
+
+            // Validate all referenced blobs at once
+            let blob_access = item.blob_access(self, &helper.acl, account_id)?;
+
             for (property, value) in &item.properties {
                 match (property, value) {
                     (Property::MailboxIds, Value::MailboxIds { value, set }) => {
@@ -218,6 +283,20 @@ where
                     (Property::ReceivedAt, Value::Date { value }) => {
                         received_at = value.timestamp().into();
                     }
+                    (Property::Annotations, Value::Annotations { value, .. }) => {
+                        self.mail_annotations_update(
+                            account_id,
+                            helper.acl.primary_id(),
+                            document,
+                            value.clone(),
+                            true,
+                        )?;
+                    }
+                    (Property::Snoozed, Value::Snoozed { .. }) => {
+                        return Err(SetError::invalid_properties()
+                            .with_property(Property::Snoozed)
+                            .with_description("Only existing messages can be snoozed."));
+                    }
                     (
                         Property::MessageId | Property::InReplyTo | Property::References,
                         Value::TextList { value },
@@ -260,13 +339,7 @@ where
 
                         if let Some(body_part) = value.first() {
                             let text_body = body_part
-                                .parse(
-                                    self,
-                                    &helper.acl,
-                                    account_id,
-                                    body_values,
-                                    "text/plain".into(),
-                                )?
+                                .parse(self, &blob_access, body_values, "text/plain".into())?
                                 .0;
                             if max_size_attachments > 0 {
                                 size_attachments += text_body.size();
@@ -297,13 +370,7 @@ where
 
                         if let Some(body_part) = value.first() {
                             let html_body = body_part
-                                .parse(
-                                    self,
-                                    &helper.acl,
-                                    account_id,
-                                    body_values,
-                                    "text/html".into(),
-                                )?
+                                .parse(self, &blob_access, body_values, "text/html".into())?
                                 .0;
                             if max_size_attachments > 0 {
                                 size_attachments += html_body.size();
@@ -330,9 +397,8 @@ where
 
                         let mut attachments = Vec::with_capacity(value.len());
                         for attachment in value {
-                            let attachment = attachment
-                                .parse(self, &helper.acl, account_id, body_values, None)?
-                                .0;
+                            let attachment =
+                                attachment.parse(self, &blob_access, body_values, None)?.0;
                             if max_size_attachments > 0 {
                                 size_attachments += attachment.size();
                                 if size_attachments > max_size_attachments {
@@ -350,7 +416,7 @@ where
                     }
                     (Property::BodyStructure, Value::BodyPart { value }) => {
                         let (mut mime_part, sub_parts) =
-                            value.parse(self, &helper.acl, account_id, body_values, None)?;
+                            value.parse(self, &blob_access, body_values, None)?;
 
                         if let Some(sub_parts) = sub_parts {
                             let mut stack = Vec::new();
@@ -358,13 +424,8 @@ where
 
                             loop {
                                 while let Some(part) = it.next() {
-                                    let (sub_mime_part, sub_parts) = part.parse(
-                                        self,
-                                        &helper.acl,
-                                        account_id,
-                                        body_values,
-                                        None,
-                                    )?;
+                                    let (sub_mime_part, sub_parts) =
+                                        part.parse(self, &blob_access, body_values, None)?;
 
                                     if max_size_attachments > 0 {
                                         size_attachments += sub_mime_part.size();
@@ -484,6 +545,13 @@ where
                     .with_description("Message has to belong to at least one mailbox."));
             }
 
+            // Enforce the custom keyword limit
+            if let Some(keywords) = fields.get_tags(&Property::Keywords) {
+                helper
+                    .store
+                    .mail_keywords_register(helper.account_id, keywords)?;
+            }
+
             // Check ACLs
             if helper.acl.is_shared(helper.account_id) {
                 let allowed_folders = helper.store.mail_shared_folders(
@@ -503,34 +571,58 @@ where
                 }
             }
 
-            // Make sure the message is not empty
-            if builder.headers.is_empty()
-                && builder.body.is_none()
-                && builder.html_body.is_none()
-                && builder.text_body.is_none()
-                && builder.attachments.is_none()
-            {
-                return Err(SetError::invalid_properties()
-                    .with_description("Message has to have at least one header or body part."));
-            }
+            let (blob_id, blob, is_new_blob) = if let Some(raw_message) = raw_message {
+                // Fetch the uploaded message, sections of other blobs are stored as new blobs
+                match self.mail_blob_get(account_id, &helper.acl, raw_message)? {
+                    BlobResult::Blob(blob) if raw_message.section.is_none() => {
+                        (raw_message.id.clone(), blob, false)
+                    }
+                    BlobResult::Blob(blob) => (BlobId::new_external(&blob), blob, true),
+                    BlobResult::Unauthorized => {
+                        return Err(SetError::forbidden()
+                            .with_property(Property::BlobId)
+                            .with_description(format!(
+                                "You do not have access to blobId {}.",
+                                raw_message
+                            )));
+                    }
+                    BlobResult::NotFound => {
+                        return Err(SetError::new(SetErrorType::BlobNotFound)
+                            .with_property(Property::BlobId)
+                            .with_description(format!("BlobId {} not found.", raw_message)));
+                    }
+                }
+            } else {
+                // Make sure the message is not empty
+                if builder.headers.is_empty()
+                    && builder.body.is_none()
+                    && builder.html_body.is_none()
+                    && builder.text_body.is_none()
+                    && builder.attachments.is_none()
+                {
+                    return Err(SetError::invalid_properties().with_description(
+                        "Message has to have at least one header or body part.",
+                    ));
+                }
 
-            // In test, sort headers to avoid randomness
-            #[cfg(feature = "debug")]
-            {
-                builder
-                    .headers
-                    .sort_unstable_by(|a, b| match a.0.cmp(&b.0) {
-                        std::cmp::Ordering::Equal => a.1.cmp(&b.1),
-                        ord => ord,
-                    });
-            }
+                // In test, sort headers to avoid randomness
+                #[cfg(feature = "debug")]
+                {
+                    builder
+                        .headers
+                        .sort_unstable_by(|a, b| match a.0.cmp(&b.0) {
+                            std::cmp::Ordering::Equal => a.1.cmp(&b.1),
+                            ord => ord,
+                        });
+                }
 
-            // Write blob
-            let mut blob = Vec::with_capacity(1024);
-            builder.write_to(&mut blob).map_err(|_| {
-                StoreError::SerializeError("Failed to write to memory.".to_string())
-            })?;
-            let blob_id = BlobId::new_external(&blob);
+                // Write blob
+                let mut blob = Vec::with_capacity(1024);
+                builder.write_to(&mut blob).map_err(|_| {
+                    StoreError::SerializeError("Failed to write to memory.".to_string())
+                })?;
+                (BlobId::new_external(&blob), blob, true)
+            };
             let raw_blob: JMAPBlob = (&blob_id).into();
 
             // Add mailbox tags
@@ -543,21 +635,38 @@ where
             // Parse message
             let size = blob.len();
             self.mail_parse_item(
+                helper.account_id,
                 document,
                 blob_id.clone(),
-                Message::parse(&blob).ok_or_else(|| {
-                    SetError::invalid_properties().with_description("Failed to parse e-mail.")
+                MimeLimits::from(&self.config).parse(&blob).ok_or_else(|| {
+                    let err =
+                        SetError::invalid_properties().with_description("Failed to parse e-mail.");
+                    if raw_message.is_some() {
+                        err.with_property(Property::BlobId)
+                    } else {
+                        err
+                    }
                 })?,
                 received_at,
             )?;
             fields.insert(document)?;
 
             // Store blob
-            self.blob_store(&blob_id, blob)?;
+            if is_new_blob {
+                self.blob_store(&blob_id, blob)?;
+            }
 
             // Obtain thread Id
             let thread_id = self.mail_set_thread(&mut helper.changes, document)?;
 
+            // Annotations are logged apart so they do not change the Email state
+            if !document.annotations.is_empty() {
+                helper.changes.log_update(
+                    Collection::Annotation,
+                    JMAPId::from_parts(thread_id, document.document_id),
+                );
+            }
+
             // Build email result
             let mut email = Email::default();
             email.insert(
@@ -571,11 +680,25 @@ where
             Ok(email)
         })?;
 
+        let mailbox_holds = if helper
+            .request
+            .update
+            .as_ref()
+            .map_or(false, |u| !u.is_empty())
+        {
+            self.mailbox_holds(account_id)?
+        } else {
+            RoaringBitmap::new()
+        };
         helper.update(|id, item, helper, document| {
+            item.validate(false)?;
+
             let current_fields = self
                 .get_orm::<Email>(account_id, id.get_document_id())?
                 .ok_or_else(|| SetError::new(SetErrorType::NotFound))?;
             let mut fields = TinyORM::track_changes(&current_fields);
+            let mut snooze = None;
+            let mut annotations = Document::new(Collection::Mail, document.document_id);
 
             for (property, value) in item.properties {
                 match (property, value) {
@@ -646,16 +769,41 @@ where
                             }
                         }
                     }
+                    (Property::Annotations, Value::Annotations { value, set }) => {
+                        // Annotations are private, setting them only requires read access
+                        self.mail_annotations_update(
+                            account_id,
+                            helper.acl.primary_id(),
+                            &mut annotations,
+                            value,
+                            set,
+                        )?;
+                    }
+                    (Property::Snoozed, Value::Snoozed { value }) => {
+                        snooze = Some(Some(value));
+                    }
+                    (Property::Snoozed, Value::Null) => {
+                        snooze = Some(None);
+                    }
                     _ => (),
                 }
             }
 
+            // Snoozing overrides any mailboxIds set in the same update
+            self.mail_snooze_update(account_id, &mut fields, snooze, &mailbox_ids)?;
+
             // Make sure the message is at least in one mailbox
             if !fields.has_tags(&Property::MailboxIds) {
                 return Err(SetError::invalid_properties()
                     .with_property(Property::MailboxIds)
                     .with_description("Message has to belong to at least one mailbox."));
             }
+
+            // Enforce the custom keyword limit
+            helper.store.mail_keywords_register(
+                helper.account_id,
+                &current_fields.get_added_tags(&fields, &Property::Keywords),
+            )?;
             let changed_tags = current_fields.get_changed_tags(&fields, &Property::Keywords);
 
             // Check ACLs
@@ -715,6 +863,22 @@ where
                 }
             }
 
+            // Messages cannot be moved out of a mailbox under legal hold
+            for mailbox in current_fields.get_removed_tags(&fields, &Property::MailboxIds) {
+                if mailbox_holds.contains(mailbox.as_id()) {
+                    info!(
+                        target: "audit",
+                        "Blocked removal of message {}:{} from mailbox {} under legal hold.",
+                        account_id,
+                        document.document_id,
+                        mailbox.as_id()
+                    );
+                    return Err(SetError::forbidden()
+                        .with_property(Property::MailboxIds)
+                        .with_description("Message is under legal hold."));
+                }
+            }
+
             // Set all current mailboxes as changed if the Seen tag changed
             let mut changed_mailboxes = AHashSet::default();
             if changed_tags
@@ -742,12 +906,25 @@ where
                 }
             }
 
+            // Annotations are private to each user, changing them does not
+            // modify the message nor its Email state.
+            if !annotations.is_empty() {
+                helper.changes.update_document(annotations);
+                helper.changes.log_update(Collection::Annotation, id);
+            }
+
             // Merge changes
+            EmailSnooze::build_index(&current_fields, &fields, document);
             current_fields.merge_validate(document, fields)?;
 
             Ok(None)
         })?;
 
+        let retention = if !helper.will_destroy.is_empty() {
+            Some(self.mail_retention(account_id)?)
+        } else {
+            None
+        };
         helper.destroy(|_id, helper, document| {
             // Check ACLs
             if helper.acl.is_shared(helper.account_id)
@@ -764,10 +941,20 @@ where
                     .with_description("You are not allowed to delete this message."));
             }
 
+            // Legal holds and retention minimums prevent deletion
+            if let Some(retention) = &retention {
+                retention.assert_can_destroy(account_id, document.document_id)?;
+            }
+
             self.mail_delete(account_id, Some(&mut helper.changes), document)?;
             Ok(())
         })?;
 
+        // Linked changes not yet written are discarded when nothing else changed
+        if helper.changes.is_empty() {
+            helper.changes = WriteBatch::new(account_id);
+        }
+
         helper.into_response()
     }
 
@@ -778,6 +965,21 @@ where
         document: &mut Document,
     ) -> store::Result<Option<JMAPId>> {
         let document_id = document.document_id;
+
+        // Holds apply to every deletion path, not only to Email/set
+        if self.mail_is_held(account_id, document_id)? {
+            info!(
+                target: "audit",
+                "Blocked deletion of message {}:{} (legal hold).",
+                account_id,
+                document_id
+            );
+            return Err(StoreError::InvalidArguments(format!(
+                "Message {}:{} is under legal hold.",
+                account_id, document_id
+            )));
+        }
+
         let metadata_blob_id = if let Some(metadata_blob_id) = self.get_document_value::<BlobId>(
             account_id,
             Collection::Mail,
@@ -790,6 +992,15 @@ where
         };
 
         // Remove index entries
+        let collation = Collation::from_id(
+            self.get_document_value::<u64>(
+                account_id,
+                Collection::Mail,
+                document_id,
+                MessageField::Collation.into(),
+            )?
+            .unwrap_or_default(),
+        );
         MessageData::deserialize(&self.blob_get(&metadata_blob_id)?.ok_or_else(|| {
             StoreError::NotFound(format!(
                 "Message data blob for {}:{} not found.",
@@ -802,7 +1013,17 @@ where
                 account_id, document_id
             ))
         })?
-        .build_index(document, false)?;
+        .build_index(
+            document,
+            collation,
+            self.get_document_value::<u64>(
+                account_id,
+                Collection::Mail,
+                document_id,
+                MessageField::InsertedAt.into(),
+            )?,
+            false,
+        )?;
 
         // Remove thread related data
         let thread_id = self
@@ -829,6 +1050,9 @@ where
             IndexOptions::new().store().clear(),
         );
 
+        // Remove annotations
+        self.mail_annotations_delete(account_id, document)?;
+
         // Unlink metadata
         document.blob(metadata_blob_id, IndexOptions::new().clear());
         document.binary(
@@ -870,19 +1094,66 @@ where
             }
         }
 
-        // Delete ORM
+        // Delete ORM and the snooze index
+        EmailSnooze::build_index(&fields, &TinyORM::default(), document);
         fields.delete(document);
 
         Ok(JMAPId::from_parts(thread_id, document_id).into())
     }
 }
 
+impl Email {
+    fn blob_access<T>(
+        &self,
+        store: &JMAPStore<T>,
+        acl: &Arc<ACLToken>,
+        account_id: AccountId,
+    ) -> jmap::error::set::Result<AHashMap<BlobId, BlobAccess>, Property>
+    where
+        T: for<'x> Store<'x> + 'static,
+    {
+        let mut blob_ids = Vec::new();
+        let mut stack = Vec::new();
+        for value in self.properties.values() {
+            match value {
+                Value::BodyPartList { value } => stack.extend(value.iter()),
+                Value::BodyPart { value } => stack.push(value),
+                _ => (),
+            }
+        }
+        while let Some(part) = stack.pop() {
+            if let Some(blob) = part.get_blob(BodyProperty::BlobId) {
+                if !blob_ids.contains(&&blob.id) {
+                    blob_ids.push(&blob.id);
+                }
+            }
+            if let Some(Value::BodyPartList { value }) =
+                part.properties.get(&BodyProperty::Subparts)
+            {
+                stack.extend(value.iter());
+            }
+        }
+
+        if blob_ids.is_empty() {
+            return Ok(AHashMap::default());
+        }
+
+        match store.mail_blob_access(account_id, acl, &blob_ids) {
+            Ok(access) => Ok(blob_ids.into_iter().cloned().zip(access).collect()),
+            Err(err) => {
+                error!("Failed to validate blobs: {}", err);
+                Err(SetError::new(SetErrorType::BlobNotFound)
+                    .with_description("Failed to validate blobs.".to_string()))
+            }
+        }
+    }
+}
+
 impl EmailBodyPart {
     fn parse<'y, T>(
         &'y self,
         store: &JMAPStore<T>,
-        acl: &Arc<ACLToken>,
-        account_id: AccountId,
+        blob_access: &AHashMap<BlobId, BlobAccess>,
         body_values: Option<&'y VecMap<String, EmailBodyValue>>,
         strict_type: Option<&'static str>,
     ) -> jmap::error::set::Result<(MimePart<'y>, Option<&'y Vec<EmailBodyPart>>), Property>
@@ -936,19 +1207,27 @@ impl EmailBodyPart {
                         .into(),
                 )
             } else if let Some(blob_id) = self.get_blob(BodyProperty::BlobId) {
-                BodyPart::Binary(match store.mail_blob_get(account_id, acl, blob_id) {
-                    Ok(BlobResult::Blob(bytes)) => bytes.into(),
-                    Ok(BlobResult::NotFound) => {
-                        return Err(SetError::new(SetErrorType::BlobNotFound).with_description(
-                            format!("blob {} does not exist on this server.", blob_id),
-                        ));
-                    }
-                    Ok(BlobResult::Unauthorized) => {
+                match blob_access.get(&blob_id.id) {
+                    Some(BlobAccess::Granted) => (),
+                    Some(BlobAccess::Unauthorized) => {
                         return Err(SetError::forbidden().with_description(format!(
                             "You do not have access to blob {}.",
                             blob_id
                         )));
                     }
+                    _ => {
+                        return Err(SetError::new(SetErrorType::BlobNotFound).with_description(
+                            format!("blob {} does not exist on this server.", blob_id),
+                        ));
+                    }
+                }
+                BodyPart::Binary(match store.mail_blob_fetch(blob_id) {
+                    Ok(Some(bytes)) => bytes.into(),
+                    Ok(None) => {
+                        return Err(SetError::new(SetErrorType::BlobNotFound).with_description(
+                            format!("blob {} does not exist on this server.", blob_id),
+                        ));
+                    }
                     Err(err) => {
                         error!("Failed to retrieve blob: {}", err);
                         return Err(SetError::new(SetErrorType::BlobNotFound)
@@ -0,0 +1,377 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use jmap::{
+    error::method::MethodError,
+    jmap_store::changes::JMAPChanges,
+    orm::{serialize::JMAPOrm, TinyORM},
+    request::{
+        query::{Filter, FilterOperator, Operator, QueryRequest},
+        ACLEnforce,
+    },
+    types::{jmap::JMAPId, state::JMAPState},
+};
+use store::{
+    ahash::AHashSet,
+    core::{
+        acl::{ACLToken, ACL},
+        collection::Collection,
+        document::Document,
+        tag::Tag,
+        vec_map::VecMap,
+    },
+    log::changes::ChangeId,
+    roaring::RoaringBitmap,
+    tracing::debug,
+    write::batch::WriteBatch,
+    AccountId, JMAPStore, SharedBitmap, Store,
+};
+
+use super::{
+    keywords::JMAPMailKeywords,
+    query::{JMAPMailQuery, QueryArguments},
+    schema::{self, Email, Keyword, Property},
+    sharing::JMAPShareMail,
+};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct EmailSetKeywordsRequest {
+    #[serde(skip)]
+    pub acl: Option<Arc<ACLToken>>,
+
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+
+    #[serde(rename = "ifInState")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub if_in_state: Option<JMAPState>,
+
+    #[serde(rename = "filter")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter<schema::Filter>>,
+
+    #[serde(rename = "keywords")]
+    pub keywords: VecMap<Keyword, bool>,
+
+    #[serde(rename = "limit")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct EmailSetKeywordsResponse {
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+
+    #[serde(rename = "oldState")]
+    pub old_state: JMAPState,
+
+    #[serde(rename = "newState")]
+    pub new_state: JMAPState,
+
+    // Messages that matched the filter and required changes
+    #[serde(rename = "total")]
+    pub total: usize,
+
+    #[serde(rename = "updated")]
+    pub updated: usize,
+
+    // Messages the caller is not allowed to modify
+    #[serde(rename = "notUpdated")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub not_updated: Vec<JMAPId>,
+
+    // True when the limit was reached before all messages were updated
+    #[serde(rename = "hasMore")]
+    pub has_more: bool,
+
+    #[serde(skip)]
+    pub change_id: Option<ChangeId>,
+
+    #[serde(skip)]
+    pub has_mailbox_changes: bool,
+}
+
+pub trait JMAPMailSetKeywords<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_set_keywords(
+        &self,
+        request: EmailSetKeywordsRequest,
+    ) -> jmap::Result<EmailSetKeywordsResponse>;
+}
+
+impl<T> JMAPMailSetKeywords<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_set_keywords(
+        &self,
+        request: EmailSetKeywordsRequest,
+    ) -> jmap::Result<EmailSetKeywordsResponse> {
+        if request.keywords.is_empty() {
+            return Err(MethodError::InvalidArguments(
+                "At least one keyword has to be specified.".to_string(),
+            ));
+        }
+        let account_id = request.account_id.get_document_id();
+        let acl = request.acl.unwrap();
+        let max_items = request
+            .limit
+            .unwrap_or(usize::MAX)
+            .min(self.config.mail_set_keywords_max_items);
+        let chunk_size = self.config.mail_set_keywords_chunk_size;
+
+        // Custom keywords are registered before any message is modified
+        self.mail_keywords_register(
+            account_id,
+            request
+                .keywords
+                .iter()
+                .filter(|(_, set)| **set)
+                .map(|(keyword, _)| &keyword.tag),
+        )
+        .map_err(|_| {
+            MethodError::InvalidArguments(format!(
+                "Accounts may not use more than {} custom keywords.",
+                self.config.mail_max_keywords
+            ))
+        })?;
+
+        // Only match messages that require at least one change
+        let needs_change = Filter::FilterOperator(FilterOperator {
+            operator: Operator::Or,
+            conditions: request
+                .keywords
+                .iter()
+                .map(|(keyword, set)| {
+                    Filter::FilterCondition(if *set {
+                        schema::Filter::NotKeyword {
+                            value: keyword.clone(),
+                        }
+                    } else {
+                        schema::Filter::HasKeyword {
+                            value: keyword.clone(),
+                        }
+                    })
+                })
+                .collect(),
+        });
+        let filter = match request.filter {
+            Some(filter) => Filter::FilterOperator(FilterOperator {
+                operator: Operator::And,
+                conditions: vec![filter, needs_change],
+            }),
+            None => needs_change,
+        };
+
+        let old_state = self.get_state(account_id, Collection::Mail)?;
+        if let Some(if_in_state) = request.if_in_state {
+            if old_state != if_in_state {
+                return Err(MethodError::StateMismatch);
+            }
+        }
+        let allowed_messages = if acl.is_shared(account_id) {
+            self.mail_shared_messages(account_id, &acl.member_of, ACL::ModifyItems)?
+                .into()
+        } else {
+            None
+        };
+
+        let mut patch = KeywordPatch {
+            keywords: &request.keywords,
+            allowed_messages,
+            change_id: None,
+            skipped: AHashSet::default(),
+            updated: 0,
+            has_mailbox_changes: false,
+        };
+        let mut total = None;
+        let mut has_more = false;
+        let mut result = Ok(());
+
+        // Each chunk is written along with its log entries while holding the
+        // collection lock, other writers may proceed between chunks.
+        loop {
+            // Updated messages no longer match the filter, only skipped ones remain
+            let query = match self.mail_query(QueryRequest {
+                acl: acl.clone().into(),
+                account_id: request.account_id,
+                filter: filter.clone().into(),
+                sort: None,
+                position: None,
+                anchor: None,
+                anchor_offset: None,
+                limit: (chunk_size + patch.skipped.len()).into(),
+                calculate_total: total.is_none().into(),
+                pinned_query_state: None,
+                arguments: QueryArguments::default(),
+            }) {
+                Ok(query) => query,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            };
+            if total.is_none() {
+                total = query.total;
+            }
+
+            let ids = query
+                .ids
+                .into_iter()
+                .filter(|id| !patch.skipped.contains(id))
+                .take(chunk_size.min(max_items - patch.processed()))
+                .collect::<Vec<_>>();
+            if ids.is_empty() {
+                break;
+            }
+
+            if let Err(err) = patch.apply(self, account_id, &ids) {
+                result = Err(err.into());
+                break;
+            }
+
+            if patch.processed() >= max_items {
+                has_more = total.unwrap_or(0) > patch.processed();
+                break;
+            }
+        }
+
+        result?;
+        let new_state = if patch.change_id.is_some() {
+            self.get_state(account_id, Collection::Mail)?
+        } else {
+            old_state.clone()
+        };
+
+        let mut not_updated = patch.skipped.into_iter().collect::<Vec<_>>();
+        not_updated.sort_unstable_by_key(|id| id.get_document_id());
+
+        Ok(EmailSetKeywordsResponse {
+            account_id: request.account_id,
+            old_state,
+            new_state,
+            total: total.unwrap_or(0),
+            updated: patch.updated,
+            not_updated,
+            has_more,
+            change_id: patch.change_id,
+            has_mailbox_changes: patch.has_mailbox_changes,
+        })
+    }
+}
+
+struct KeywordPatch<'x> {
+    keywords: &'x VecMap<Keyword, bool>,
+    allowed_messages: Option<Arc<Option<RoaringBitmap>>>,
+    change_id: Option<ChangeId>,
+    skipped: AHashSet<JMAPId>,
+    updated: usize,
+    has_mailbox_changes: bool,
+}
+
+impl<'x> KeywordPatch<'x> {
+    fn processed(&self) -> usize {
+        self.updated + self.skipped.len()
+    }
+
+    fn apply<T>(
+        &mut self,
+        store: &JMAPStore<T>,
+        account_id: AccountId,
+        ids: &[JMAPId],
+    ) -> store::Result<()>
+    where
+        T: for<'y> Store<'y> + 'static,
+    {
+        let mut batch = WriteBatch::new(account_id);
+        let mut updated = 0;
+        let mut has_mailbox_changes = false;
+        let _lock = store.lock_collection(account_id, Collection::Mail);
+
+        for id in ids {
+            let document_id = id.get_document_id();
+            if self
+                .allowed_messages
+                .as_ref()
+                .map_or(false, |allowed| !allowed.has_access(document_id))
+            {
+                self.skipped.insert(*id);
+                continue;
+            }
+            let current_fields =
+                if let Some(current_fields) = store.get_orm::<Email>(account_id, document_id)? {
+                    current_fields
+                } else {
+                    debug!("Email ORM for {}:{} not found", account_id, document_id);
+                    self.skipped.insert(*id);
+                    continue;
+                };
+
+            let mut fields = TinyORM::track_changes(&current_fields);
+            for (keyword, set) in self.keywords.iter() {
+                if *set {
+                    fields.tag(Property::Keywords, keyword.tag.clone());
+                } else {
+                    fields.untag(&Property::Keywords, &keyword.tag);
+                }
+            }
+            let changed_tags = current_fields.get_changed_tags(&fields, &Property::Keywords);
+            if changed_tags.is_empty() {
+                self.skipped.insert(*id);
+                continue;
+            }
+
+            // Set all current mailboxes as changed if the Seen tag changed
+            if changed_tags
+                .iter()
+                .any(|keyword| matches!(keyword, Tag::Static(k_id) if k_id == &Keyword::SEEN))
+            {
+                for mailbox_tag in fields.get_tags(&Property::MailboxIds).into_iter().flatten() {
+                    batch.log_child_update(Collection::Mailbox, mailbox_tag.as_id());
+                }
+                has_mailbox_changes = true;
+            }
+
+            let mut document = Document::new(Collection::Mail, document_id);
+            current_fields.merge(&mut document, fields)?;
+            batch.update_document(document);
+            batch.log_update(Collection::Mail, *id);
+            updated += 1;
+        }
+
+        if !batch.is_empty() {
+            if let Some(changes) = store.write(batch)? {
+                self.change_id = changes.change_id.into();
+            }
+            self.updated += updated;
+            self.has_mailbox_changes |= has_mailbox_changes;
+        }
+
+        Ok(())
+    }
+}
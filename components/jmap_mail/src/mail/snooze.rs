@@ -0,0 +1,384 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::SystemTime;
+
+use jmap::{
+    error::set::SetError,
+    orm::{serialize::JMAPOrm, TinyORM},
+    types::{date::JMAPDate, jmap::JMAPId},
+};
+use store::{
+    core::{collection::Collection, document::Document, tag::Tag},
+    log::changes::ChangeId,
+    read::{
+        comparator::Comparator,
+        filter::{Filter, Query},
+        FilterMapper,
+    },
+    roaring::RoaringBitmap,
+    tracing::debug,
+    write::{
+        batch::WriteBatch,
+        options::{IndexOptions, Options},
+    },
+    AccountId, DocumentId, JMAPStore, LongInteger, Store,
+};
+
+use crate::mailbox::get::JMAPGetMailbox;
+
+use super::{
+    schema::{Email, EmailSnooze, Keyword, Property},
+    MessageField,
+};
+
+pub const SNOOZED_ROLE: &str = "snoozed";
+
+impl EmailSnooze {
+    /// Snooze details are kept in the Email ORM as a single text tag, which
+    /// makes them part of the replicated document state. The tag lists all the
+    /// mailboxes the message is moved back to, starting with moveToMailboxId.
+    pub fn as_tag(&self, move_to: &[DocumentId]) -> Tag {
+        Tag::Text(format!(
+            "{}:{}",
+            self.until.timestamp(),
+            move_to
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        ))
+    }
+
+    pub fn from_tag(tag: &Tag) -> Option<(Self, Vec<DocumentId>)> {
+        if let Tag::Text(value) = tag {
+            let (until, move_to) = value.split_once(':')?;
+            let move_to = move_to
+                .split(',')
+                .map(|id| id.parse::<DocumentId>().ok())
+                .collect::<Option<Vec<_>>>()?;
+            Some((
+                EmailSnooze {
+                    until: JMAPDate::from_timestamp(until.parse().ok()?),
+                    move_to_mailbox_id: Some((*move_to.first()?).into()),
+                },
+                move_to,
+            ))
+        } else {
+            None
+        }
+    }
+
+    pub fn from_fields(fields: &TinyORM<Email>) -> Option<(Self, Vec<DocumentId>)> {
+        fields
+            .get_tags(&Property::Snoozed)?
+            .iter()
+            .find_map(EmailSnooze::from_tag)
+    }
+
+    /// Wake-up times are kept in a sorted index, which is how due messages are
+    /// found and is rewritten along with the document ids on renumbering.
+    pub fn build_index(
+        current_fields: &TinyORM<Email>,
+        fields: &TinyORM<Email>,
+        document: &mut Document,
+    ) {
+        let current_until =
+            EmailSnooze::from_fields(current_fields).map(|(snooze, _)| snooze.until.timestamp());
+        let until = EmailSnooze::from_fields(fields).map(|(snooze, _)| snooze.until.timestamp());
+        if current_until != until {
+            if let Some(current_until) = current_until {
+                document.number(
+                    MessageField::SnoozedUntil,
+                    current_until as LongInteger,
+                    IndexOptions::new().index().clear(),
+                );
+            }
+            if let Some(until) = until {
+                document.number(
+                    MessageField::SnoozedUntil,
+                    until as LongInteger,
+                    IndexOptions::new().index(),
+                );
+            }
+        }
+    }
+}
+
+pub trait JMAPMailSnooze<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_snooze_update(
+        &self,
+        account_id: AccountId,
+        fields: &mut TinyORM<Email>,
+        snooze: Option<Option<EmailSnooze>>,
+        mailbox_ids: &RoaringBitmap,
+    ) -> jmap::error::set::Result<(), Property>;
+
+    fn mail_snooze_wake(&self, account_id: AccountId) -> store::Result<Option<ChangeId>>;
+}
+
+impl<T> JMAPMailSnooze<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_snooze_update(
+        &self,
+        account_id: AccountId,
+        fields: &mut TinyORM<Email>,
+        snooze: Option<Option<EmailSnooze>>,
+        mailbox_ids: &RoaringBitmap,
+    ) -> jmap::error::set::Result<(), Property> {
+        let current = EmailSnooze::from_fields(fields);
+        if snooze.is_none() && current.is_none() {
+            return Ok(());
+        }
+        let snoozed_mailbox_id = self.mailbox_get_by_role(account_id, SNOOZED_ROLE)?;
+
+        match snooze {
+            Some(Some(snooze)) => {
+                let snoozed_mailbox_id = snoozed_mailbox_id.ok_or_else(|| {
+                    SetError::invalid_properties()
+                        .with_property(Property::Snoozed)
+                        .with_description("No mailbox with role 'snoozed' exists.")
+                })?;
+
+                // Messages return to the requested mailbox and to all the mailboxes
+                // they were in before being snoozed.
+                let mut move_to = Vec::new();
+                if let Some(mailbox_id) = &snooze.move_to_mailbox_id {
+                    let mailbox_id = mailbox_id.get_document_id();
+                    if !mailbox_ids.contains(mailbox_id) || mailbox_id == snoozed_mailbox_id {
+                        return Err(SetError::invalid_properties()
+                            .with_property(Property::Snoozed)
+                            .with_description(format!(
+                                "Invalid moveToMailboxId {}.",
+                                JMAPId::from(mailbox_id)
+                            )));
+                    }
+                    move_to.push(mailbox_id);
+                }
+                let mut current_mailbox_ids = fields
+                    .get_tags(&Property::MailboxIds)
+                    .map(|tags| {
+                        tags.iter()
+                            .map(|tag| tag.as_id())
+                            .filter(|id| *id != snoozed_mailbox_id)
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                if current_mailbox_ids.is_empty() {
+                    // Snoozing again a message that is already snoozed
+                    if let Some((_, current_move_to)) = current {
+                        current_mailbox_ids = current_move_to;
+                    }
+                }
+                for mailbox_id in current_mailbox_ids {
+                    if !move_to.contains(&mailbox_id) {
+                        move_to.push(mailbox_id);
+                    }
+                }
+                if move_to.is_empty() {
+                    move_to.push(self.mailbox_get_by_role(account_id, "inbox")?.ok_or_else(
+                        || {
+                            SetError::invalid_properties()
+                                .with_property(Property::Snoozed)
+                                .with_description("Missing moveToMailboxId.")
+                        },
+                    )?);
+                }
+
+                fields.untag_all(&Property::Snoozed);
+                fields.tag(Property::Snoozed, snooze.as_tag(&move_to));
+                fields.untag_all(&Property::MailboxIds);
+                fields.tag(Property::MailboxIds, Tag::Id(snoozed_mailbox_id));
+            }
+            Some(None) => {
+                fields.untag_all(&Property::Snoozed);
+                if let (Some((_, move_to)), Some(snoozed_mailbox_id)) =
+                    (current, snoozed_mailbox_id)
+                {
+                    let move_to = self.mail_snooze_destination(account_id, move_to, mailbox_ids)?;
+                    unsnooze(fields, snoozed_mailbox_id, &move_to);
+                }
+            }
+            None => {
+                // Messages moved out of the snoozed mailbox are no longer snoozed
+                if snoozed_mailbox_id.map_or(true, |snoozed_mailbox_id| {
+                    !fields
+                        .get_tags(&Property::MailboxIds)
+                        .map_or(false, |tags| tags.contains(&Tag::Id(snoozed_mailbox_id)))
+                }) {
+                    fields.untag_all(&Property::Snoozed);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn mail_snooze_wake(&self, account_id: AccountId) -> store::Result<Option<ChangeId>> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let message_ids = self
+            .query_store::<FilterMapper>(
+                account_id,
+                Collection::Mail,
+                Filter::le(
+                    MessageField::SnoozedUntil.into(),
+                    Query::LongInteger(now as LongInteger),
+                ),
+                Comparator::None,
+            )?
+            .into_bitmap();
+        if message_ids.is_empty() {
+            return Ok(None);
+        }
+        let snoozed_mailbox_id = self.mailbox_get_by_role(account_id, SNOOZED_ROLE)?;
+        let mailbox_ids = self
+            .get_document_ids(account_id, Collection::Mailbox)?
+            .unwrap_or_default();
+
+        let _lock = self.lock_collection(account_id, Collection::Mail);
+        let mut batch = WriteBatch::new(account_id);
+
+        for message_id in message_ids {
+            let current_fields =
+                if let Some(current_fields) = self.get_orm::<Email>(account_id, message_id)? {
+                    current_fields
+                } else {
+                    continue;
+                };
+            let move_to = match EmailSnooze::from_fields(&current_fields) {
+                Some((snooze, move_to)) if snooze.until.timestamp() <= now as i64 => move_to,
+                _ => continue,
+            };
+            let thread_id = if let Some(thread_id) = self.get_document_value::<DocumentId>(
+                account_id,
+                Collection::Mail,
+                message_id,
+                MessageField::ThreadId.into(),
+            )? {
+                thread_id
+            } else {
+                debug!(
+                    "Failed to fetch threadId for {}:{}.",
+                    account_id, message_id
+                );
+                continue;
+            };
+
+            // Resurface the message as unread, in the Inbox if its original
+            // mailboxes were deleted in the meantime.
+            let mut fields = TinyORM::track_changes(&current_fields);
+            fields.untag_all(&Property::Snoozed);
+            fields.untag(&Property::Keywords, &Tag::Static(Keyword::SEEN));
+            let move_to = if let Some(snoozed_mailbox_id) = snoozed_mailbox_id {
+                unsnooze(
+                    &mut fields,
+                    snoozed_mailbox_id,
+                    &self.mail_snooze_destination(account_id, move_to, &mailbox_ids)?,
+                )
+            } else {
+                Vec::new()
+            };
+
+            let mut document = Document::new(Collection::Mail, message_id);
+            EmailSnooze::build_index(&current_fields, &fields, &mut document);
+            current_fields.merge(&mut document, fields)?;
+            batch.update_document(document);
+            batch.log_update(Collection::Mail, JMAPId::from_parts(thread_id, message_id));
+            if let Some(snoozed_mailbox_id) = snoozed_mailbox_id {
+                batch.log_child_update(Collection::Mailbox, snoozed_mailbox_id);
+            }
+            for move_to in move_to {
+                batch.log_child_update(Collection::Mailbox, move_to);
+            }
+        }
+
+        Ok(if !batch.is_empty() {
+            self.write(batch)?.map(|changes| changes.change_id)
+        } else {
+            None
+        })
+    }
+}
+
+trait JMAPMailSnoozeDestination {
+    fn mail_snooze_destination(
+        &self,
+        account_id: AccountId,
+        move_to: Vec<DocumentId>,
+        mailbox_ids: &RoaringBitmap,
+    ) -> store::Result<Vec<DocumentId>>;
+}
+
+impl<T> JMAPMailSnoozeDestination for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Mailboxes deleted while the message was snoozed are skipped, falling back
+    // to the Inbox if none of them is left.
+    fn mail_snooze_destination(
+        &self,
+        account_id: AccountId,
+        mut move_to: Vec<DocumentId>,
+        mailbox_ids: &RoaringBitmap,
+    ) -> store::Result<Vec<DocumentId>> {
+        move_to.retain(|mailbox_id| mailbox_ids.contains(*mailbox_id));
+        if move_to.is_empty() {
+            if let Some(inbox_id) = self.mailbox_get_by_role(account_id, "inbox")? {
+                move_to.push(inbox_id);
+            }
+        }
+        Ok(move_to)
+    }
+}
+
+/// Moves a message out of the snoozed mailbox, returns the destination
+/// mailboxes if the message was moved.
+fn unsnooze(
+    fields: &mut TinyORM<Email>,
+    snoozed_mailbox_id: DocumentId,
+    move_to: &[DocumentId],
+) -> Vec<DocumentId> {
+    let snoozed_tag = Tag::Id(snoozed_mailbox_id);
+    if move_to.is_empty()
+        || !fields
+            .get_tags(&Property::MailboxIds)
+            .map_or(false, |tags| tags.contains(&snoozed_tag))
+    {
+        // Keep the message in the snoozed mailbox rather than orphaning it
+        return Vec::new();
+    }
+
+    fields.untag(&Property::MailboxIds, &snoozed_tag);
+    for mailbox_id in move_to {
+        fields.tag(Property::MailboxIds, Tag::Id(*mailbox_id));
+    }
+    move_to.to_vec()
+}
@@ -0,0 +1,319 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use jmap::{
+    orm::serialize::JMAPOrm,
+    principal::schema::{Principal, Property, Value},
+    SUPERUSER_ID,
+};
+use mail_builder::{
+    headers::{content_type::ContentType, date::Date, message_id::MessageId, raw::Raw},
+    mime::{BodyPart, MimePart},
+    MessageBuilder,
+};
+use store::{
+    chrono::Utc, config::templates::RenderedTemplate, rand, write::ttl::now, AccountId, JMAPStore,
+    Store,
+};
+
+pub trait JMAPMailTemplate<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_locale(&self, account_id: AccountId) -> store::Result<String>;
+    fn mail_template_render(
+        &self,
+        account_id: AccountId,
+        name: &str,
+        variables: &[(&str, &str)],
+    ) -> store::Result<Option<RenderedTemplate>>;
+}
+
+impl<T> JMAPMailTemplate<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_locale(&self, account_id: AccountId) -> store::Result<String> {
+        Ok(
+            match self
+                .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+                .as_ref()
+                .and_then(|fields| fields.get(&Property::Locale))
+            {
+                Some(Value::Text { value }) => value.to_string(),
+                _ => self.config.mail_default_locale.clone(),
+            },
+        )
+    }
+
+    fn mail_template_render(
+        &self,
+        account_id: AccountId,
+        name: &str,
+        variables: &[(&str, &str)],
+    ) -> store::Result<Option<RenderedTemplate>> {
+        Ok(self
+            .config
+            .templates
+            .render(name, &self.mail_locale(account_id)?, variables))
+    }
+}
+
+/// Writes the MIME body of a rendered template, without any message headers.
+pub fn write_template_body(template: &RenderedTemplate, output: &mut Vec<u8>) {
+    let mut builder = MessageBuilder::new();
+    builder.body = template_part(template).into();
+    builder.write_body(output).ok();
+}
+
+/// Builds a non-delivery report as defined in RFC 3464 for the recipients
+/// that failed, including the headers of the original message.
+pub fn build_delivery_failure(
+    template: &RenderedTemplate,
+    reporting_domain: &str,
+    to: &str,
+    failures: &[(String, String)],
+    original_headers: &[u8],
+) -> Vec<u8> {
+    let mut status = format!(
+        "Reporting-MTA: dns; {}\r\nArrival-Date: {}\r\n",
+        reporting_domain,
+        Utc::now().to_rfc2822()
+    );
+    for (recipient, reason) in failures {
+        let reason = reason.replace(['\r', '\n'], " ");
+        status.push_str(&format!(
+            "\r\nFinal-Recipient: rfc822; {}\r\nAction: failed\r\nStatus: {}\r\nDiagnostic-Code: smtp; {}\r\n",
+            recipient,
+            enhanced_status_code(&reason).unwrap_or("5.0.0"),
+            reason.trim()
+        ));
+    }
+
+    let capacity = status.len() + original_headers.len() + 1024;
+    let from = format!("MAILER-DAEMON@{}", reporting_domain);
+    let mut builder = system_message(template, &from, to, "auto-replied");
+    builder.body = MimePart {
+        headers: vec![(
+            "Content-Type".into(),
+            ContentType::new("multipart/report")
+                .attribute("report-type", "delivery-status")
+                .into(),
+        )],
+        contents: BodyPart::Multipart(vec![
+            template_part(template),
+            MimePart {
+                headers: vec![(
+                    "Content-Type".into(),
+                    ContentType::new("message/delivery-status").into(),
+                )],
+                contents: BodyPart::Text(status.into()),
+            },
+            MimePart {
+                headers: vec![(
+                    "Content-Type".into(),
+                    ContentType::new("text/rfc822-headers").into(),
+                )],
+                contents: BodyPart::Text(String::from_utf8_lossy(original_headers)),
+            },
+        ]),
+    }
+    .into();
+
+    let mut message = Vec::with_capacity(capacity);
+    builder.write_to(&mut message).ok();
+    message
+}
+
+/// Builds a standalone message generated by the server, such as the welcome
+/// message delivered to new accounts.
+pub fn build_notification(template: &RenderedTemplate, from: &str, to: &str) -> Vec<u8> {
+    let mut builder = system_message(template, from, to, "auto-generated");
+    builder.body = template_part(template).into();
+    let mut message = Vec::with_capacity(template.text.len() + 1024);
+    builder.write_to(&mut message).ok();
+    message
+}
+
+/// Returns the header block of a raw message.
+pub fn message_headers(raw_message: &[u8]) -> &[u8] {
+    raw_message
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|pos| &raw_message[..pos + 2])
+        .or_else(|| {
+            raw_message
+                .windows(2)
+                .position(|window| window == b"\n\n")
+                .map(|pos| &raw_message[..pos + 1])
+        })
+        .unwrap_or(raw_message)
+}
+
+// Headers shared by all messages generated by the server.
+fn system_message<'x>(
+    template: &'x RenderedTemplate,
+    from: &'x str,
+    to: &'x str,
+    auto_submitted: &'static str,
+) -> MessageBuilder<'x> {
+    let now = now();
+    let mut builder = MessageBuilder::new()
+        .from(("Mail Delivery System", from))
+        .to(to)
+        .date(Date::new(now as i64))
+        .message_id(MessageId::new(format!(
+            "{:x}.{:x}@{}",
+            now,
+            rand::random::<u32>(),
+            from.rsplit_once('@')
+                .map_or("localhost", |(_, domain)| domain)
+        )))
+        .header("Auto-Submitted", Raw::new(auto_submitted));
+    if let Some(subject) = &template.subject {
+        builder = builder.subject(subject.as_str());
+    }
+    builder
+}
+
+fn template_part(template: &RenderedTemplate) -> MimePart<'_> {
+    let text = text_part("text/plain", &template.text);
+    if let Some(html) = &template.html {
+        MimePart {
+            headers: vec![(
+                "Content-Type".into(),
+                ContentType::new("multipart/alternative").into(),
+            )],
+            contents: BodyPart::Multipart(vec![text, text_part("text/html", html)]),
+        }
+    } else {
+        text
+    }
+}
+
+fn text_part<'x>(content_type: &'static str, text: &'x str) -> MimePart<'x> {
+    MimePart {
+        headers: vec![(
+            "Content-Type".into(),
+            ContentType::new(content_type)
+                .attribute("charset", "utf-8")
+                .into(),
+        )],
+        contents: BodyPart::Text(text.into()),
+    }
+}
+
+// Extracts an enhanced status code (RFC 3463) from an SMTP reply.
+fn enhanced_status_code(reply: &str) -> Option<&str> {
+    reply.split_ascii_whitespace().find(|token| {
+        let mut parts = token.split('.');
+        matches!(parts.next(), Some("4" | "5"))
+            && parts
+                .by_ref()
+                .take(2)
+                .filter(|part| !part.is_empty() && part.chars().all(|ch| ch.is_ascii_digit()))
+                .count()
+                == 2
+            && parts.next().is_none()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::Message;
+    use store::config::templates::RenderedTemplate;
+
+    use super::{
+        build_delivery_failure, build_notification, enhanced_status_code, message_headers,
+    };
+
+    #[test]
+    fn delivery_failure_report() {
+        assert_eq!(
+            enhanced_status_code("550 5.1.1 <jdoe@example.org>: Recipient unknown"),
+            Some("5.1.1")
+        );
+        assert_eq!(
+            enhanced_status_code("Connection refused (os error 111)"),
+            None
+        );
+
+        let original = b"From: jdoe@example.org\r\nSubject: Hi\r\n\r\nHello\r\n";
+        assert_eq!(
+            message_headers(original),
+            b"From: jdoe@example.org\r\nSubject: Hi\r\n"
+        );
+
+        let report = build_delivery_failure(
+            &RenderedTemplate {
+                subject: "Undelivered Mail".to_string().into(),
+                text: "Could not deliver.".to_string(),
+                html: "<p>Could not deliver.</p>".to_string().into(),
+            },
+            "example.org",
+            "jdoe@example.org",
+            &[(
+                "jane@example.com".to_string(),
+                "550 5.1.1 Recipient unknown\r\n".to_string(),
+            )],
+            message_headers(original),
+        );
+        let message = Message::parse(&report).unwrap();
+        assert_eq!(message.subject(), Some("Undelivered Mail"));
+        let report = String::from_utf8_lossy(&report);
+        for expected in [
+            "Auto-Submitted: auto-replied",
+            "MAILER-DAEMON@example.org",
+            "report-type=\"delivery-status\"",
+            "Final-Recipient: rfc822; jane@example.com",
+            "Status: 5.1.1",
+            "Diagnostic-Code: smtp; 550 5.1.1 Recipient unknown",
+            "multipart/alternative",
+            "Subject: Hi",
+        ] {
+            assert!(
+                report.contains(expected),
+                "{:?} not in {}",
+                expected,
+                report
+            );
+        }
+    }
+
+    #[test]
+    fn notification() {
+        let message = build_notification(
+            &RenderedTemplate {
+                subject: "Welcome".to_string().into(),
+                text: "Hello John,".to_string(),
+                html: None,
+            },
+            "postmaster@example.org",
+            "jdoe@example.org",
+        );
+        assert_eq!(Message::parse(&message).unwrap().subject(), Some("Welcome"));
+        let message = String::from_utf8_lossy(&message);
+        assert!(message.contains("Auto-Submitted: auto-generated"));
+        assert!(message.contains("Hello John,"));
+    }
+}
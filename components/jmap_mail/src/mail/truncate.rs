@@ -0,0 +1,227 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style", "textarea", "title"];
+const MAX_ENTITY_LEN: usize = 32;
+
+/// Truncates a plain text body value to at most `max_len` bytes without
+/// splitting a UTF-8 sequence.
+pub fn truncate_body_text(mut text: String, max_len: usize) -> String {
+    if text.len() > max_len {
+        let mut pos = max_len;
+        while !text.is_char_boundary(pos) {
+            pos -= 1;
+        }
+        text.truncate(pos);
+    }
+    text
+}
+
+/// Truncates an HTML body value to at most `max_len` bytes. The cut is only
+/// made between tags, comments and character references, and all elements
+/// left open at the cut point are closed, with the closing tags counted
+/// towards `max_len`.
+pub fn truncate_body_html(html: String, max_len: usize) -> String {
+    if html.len() <= max_len {
+        return html;
+    }
+
+    let bytes = html.as_bytes();
+    let mut stack: Vec<&str> = Vec::new();
+    let mut closing_len = 0;
+    let mut best_pos = 0;
+    let mut best_stack: Vec<&str> = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        // Every token boundary is a candidate cut point
+        if pos + closing_len > max_len {
+            break;
+        }
+        if best_stack != stack {
+            best_stack.clone_from(&stack);
+        }
+        best_pos = pos;
+
+        match bytes[pos] {
+            b'<' => match bytes.get(pos + 1) {
+                Some(b'!') if bytes[pos..].starts_with(b"<!--") => {
+                    pos = find_end(bytes, pos + 4, b"-->");
+                }
+                Some(b'!' | b'?') => {
+                    pos = find_end(bytes, pos + 2, b">");
+                }
+                Some(b'/') => {
+                    let (name, end) = parse_tag(&html, pos + 2);
+                    if let Some(idx) = stack.iter().rposition(|tag| tag.eq_ignore_ascii_case(name))
+                    {
+                        for tag in stack.drain(idx..) {
+                            closing_len -= tag.len() + 3;
+                        }
+                    }
+                    pos = end;
+                }
+                Some(ch) if ch.is_ascii_alphabetic() => {
+                    let (name, end) = parse_tag(&html, pos + 1);
+                    let is_self_closing = bytes[..end].ends_with(b"/>");
+                    pos = end;
+                    if RAW_TEXT_ELEMENTS
+                        .iter()
+                        .any(|tag| tag.eq_ignore_ascii_case(name))
+                    {
+                        // Raw text elements are kept or dropped as a whole
+                        pos = find_closing_tag(&html, pos, name);
+                    } else if !is_self_closing
+                        && !VOID_ELEMENTS
+                            .iter()
+                            .any(|tag| tag.eq_ignore_ascii_case(name))
+                    {
+                        stack.push(name);
+                        closing_len += name.len() + 3;
+                    }
+                }
+                _ => {
+                    pos += 1;
+                }
+            },
+            b'&' => {
+                pos += bytes[pos + 1..]
+                    .iter()
+                    .take(MAX_ENTITY_LEN)
+                    .position(|&ch| !ch.is_ascii_alphanumeric() && ch != b'#')
+                    .filter(|&len| bytes.get(pos + len + 1) == Some(&b';'))
+                    .map_or(1, |len| len + 2);
+            }
+            _ => {
+                pos += html[pos..].chars().next().map_or(1, |ch| ch.len_utf8());
+            }
+        }
+    }
+
+    if pos >= bytes.len() && pos + closing_len <= max_len {
+        best_pos = bytes.len();
+        best_stack = stack;
+    }
+
+    let mut result = String::with_capacity(max_len);
+    result.push_str(&html[..best_pos]);
+    for tag in best_stack.iter().rev() {
+        result.push_str("</");
+        result.push_str(tag);
+        result.push('>');
+    }
+    result
+}
+
+fn parse_tag(html: &str, start: usize) -> (&str, usize) {
+    let bytes = html.as_bytes();
+    let name_end = bytes[start..]
+        .iter()
+        .position(|ch| !ch.is_ascii_alphanumeric() && *ch != b'-' && *ch != b':')
+        .map_or(bytes.len(), |len| start + len);
+
+    let mut quote = None;
+    let mut end = bytes.len();
+    for (pos, &ch) in bytes.iter().enumerate().skip(name_end) {
+        match (ch, quote) {
+            (b'"' | b'\'', None) => quote = Some(ch),
+            (_, Some(q)) if ch == q => quote = None,
+            (b'>', None) => {
+                end = pos + 1;
+                break;
+            }
+            _ => (),
+        }
+    }
+
+    (&html[start..name_end], end)
+}
+
+fn find_end(bytes: &[u8], start: usize, needle: &[u8]) -> usize {
+    bytes
+        .get(start..)
+        .and_then(|bytes| {
+            bytes
+                .windows(needle.len())
+                .position(|window| window == needle)
+        })
+        .map_or(bytes.len(), |pos| start + pos + needle.len())
+}
+
+fn find_closing_tag(html: &str, start: usize, name: &str) -> usize {
+    let bytes = html.as_bytes();
+    let mut pos = start;
+    while let Some(offset) = bytes[pos..].windows(2).position(|w| w == b"</") {
+        let tag_start = pos + offset + 2;
+        let (tag_name, end) = parse_tag(html, tag_start);
+        if tag_name.eq_ignore_ascii_case(name) {
+            return end;
+        }
+        pos = tag_start;
+    }
+    bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{truncate_body_html, truncate_body_text};
+
+    #[test]
+    fn truncate_body_values() {
+        assert_eq!(truncate_body_text("héllo".to_string(), 2), "h");
+        assert_eq!(truncate_body_text("héllo".to_string(), 3), "hé");
+        assert_eq!(truncate_body_text("hello".to_string(), 10), "hello");
+
+        for (html, max_len, expected) in [
+            (
+                "<html><body><p>Hello world</p></body></html>",
+                40,
+                "<html><body><p>Hello w</p></body></html>",
+            ),
+            ("<p>Caf&eacute; au lait</p>", 18, "<p>Caf&eacute;</p>"),
+            ("<p>Caf&eacute; au lait</p>", 17, "<p>Caf</p>"),
+            (
+                "<div><a href=\"https://example.org/?a=1&b=2\">link</a> text</div>",
+                40,
+                "<div></div>",
+            ),
+            (
+                "<p>a<br>b<img src=\"x\"/>cdef</p>",
+                28,
+                "<p>a<br>b<img src=\"x\"/>c</p>",
+            ),
+            ("<p>日本語</p>", 14, "<p>日本</p>"),
+            ("<p>x<!-- comment -->yz</p>", 16, "<p>x</p>"),
+            ("<style>p { color: red }</style><p>text</p>", 20, ""),
+            ("<b>bold</i> text", 9, "<b>bo</b>"),
+        ] {
+            let result = truncate_body_html(html.to_string(), max_len);
+            assert_eq!(result, expected, "{}", html);
+            assert!(result.len() <= max_len);
+        }
+    }
+}
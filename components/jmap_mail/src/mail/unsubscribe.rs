@@ -0,0 +1,563 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use jmap::{
+    error::{
+        method::MethodError,
+        set::{SetError, SetErrorType},
+    },
+    jmap_store::{changes::JMAPChanges, Object},
+    orm::serialize::JMAPOrm,
+    principal::schema::{Principal, Property as PrincipalProperty, Value as PrincipalValue},
+    request::{set::SetRequest, MaybeResultReference},
+    types::jmap::JMAPId,
+    SUPERUSER_ID,
+};
+use mail_builder::MessageBuilder;
+use mail_parser::{HeaderName, HeaderValue, Message, RfcHeader};
+use store::{
+    blob::BlobId,
+    core::{acl::ACLToken, collection::Collection, tag::Tag, vec_map::VecMap},
+    log::changes::ChangeId,
+    tracing::info,
+    AccountId, DocumentId, JMAPStore, Store,
+};
+
+use crate::{
+    email_submission::{
+        schema::{
+            Address, EmailSubmission, Envelope, Property as SubmissionProperty,
+            Value as SubmissionValue,
+        },
+        set::{JMAPSetEmailSubmission, SetArguments as SubmissionSetArguments},
+    },
+    identity::schema::{Identity, Property as IdentityProperty, Value as IdentityValue},
+    mailbox::get::JMAPGetMailbox,
+};
+
+use super::{
+    import::JMAPMailImport,
+    report::JMAPMailReport,
+    schema::{Keyword, Property},
+    set::JMAPSetMail,
+};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct EmailUnsubscribeRequest {
+    #[serde(skip)]
+    pub acl: Option<Arc<ACLToken>>,
+
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+
+    #[serde(rename = "emailIds")]
+    pub email_ids: Vec<JMAPId>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum UnsubscribeMethod {
+    #[serde(rename = "https")]
+    Https,
+    #[serde(rename = "mailto")]
+    Mailto,
+}
+
+// Unsubscribe URIs of a message. One-click requests are sent by the caller
+// outside the store workers, mailto requests are submitted afterwards using
+// `mail_unsubscribe_mailto`.
+#[derive(Debug)]
+pub struct PendingUnsubscribe {
+    pub email_id: JMAPId,
+    pub list_id: String,
+    pub https_url: Option<String>,
+    pub mailto_url: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct EmailUnsubscribeResponse {
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+
+    #[serde(rename = "unsubscribed")]
+    #[serde(skip_serializing_if = "VecMap::is_empty")]
+    pub unsubscribed: VecMap<JMAPId, UnsubscribeMethod>,
+
+    #[serde(rename = "notUnsubscribed")]
+    #[serde(skip_serializing_if = "VecMap::is_empty")]
+    pub not_unsubscribed: VecMap<JMAPId, SetError<Property>>,
+
+    #[serde(skip)]
+    pub pending: Vec<PendingUnsubscribe>,
+
+    // Submissions created for mailto requests
+    #[serde(skip)]
+    pub submission_ids: Vec<DocumentId>,
+
+    #[serde(skip)]
+    pub change_id: Option<ChangeId>,
+}
+
+impl EmailUnsubscribeResponse {
+    pub fn unsubscribed(&mut self, pending: PendingUnsubscribe, method: UnsubscribeMethod) {
+        info!(
+            target: "audit",
+            "Account {} unsubscribed from list {} using message {} ({}).",
+            self.account_id.get_document_id(),
+            pending.list_id,
+            pending.email_id.get_document_id(),
+            if method == UnsubscribeMethod::Https {
+                "https"
+            } else {
+                "mailto"
+            }
+        );
+        self.unsubscribed.append(pending.email_id, method);
+    }
+
+    pub fn not_unsubscribed(&mut self, pending: PendingUnsubscribe) {
+        self.not_unsubscribed.append(
+            pending.email_id,
+            SetError::new(SetErrorType::Forbidden)
+                .with_description("The message does not offer a supported unsubscribe method."),
+        );
+    }
+}
+
+pub trait JMAPMailUnsubscribe<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_unsubscribe(
+        &self,
+        request: EmailUnsubscribeRequest,
+    ) -> jmap::Result<EmailUnsubscribeResponse>;
+
+    fn mail_unsubscribe_mailto(
+        &self,
+        acl: Arc<ACLToken>,
+        response: &mut EmailUnsubscribeResponse,
+        pending: Vec<PendingUnsubscribe>,
+    ) -> jmap::Result<()>;
+}
+
+impl<T> JMAPMailUnsubscribe<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_unsubscribe(
+        &self,
+        request: EmailUnsubscribeRequest,
+    ) -> jmap::Result<EmailUnsubscribeResponse> {
+        if request.email_ids.len() > self.config.max_objects_in_set {
+            return Err(MethodError::RequestTooLarge);
+        }
+        let account_id = request.account_id.get_document_id();
+        let mut response = EmailUnsubscribeResponse {
+            account_id: request.account_id,
+            unsubscribed: VecMap::with_capacity(request.email_ids.len()),
+            not_unsubscribed: VecMap::new(),
+            pending: Vec::with_capacity(request.email_ids.len()),
+            submission_ids: Vec::new(),
+            change_id: None,
+        };
+        let document_ids = self
+            .get_document_ids(account_id, Collection::Mail)?
+            .unwrap_or_default();
+
+        for email_id in request.email_ids {
+            let document_id = email_id.get_document_id();
+            let message_data = if document_ids.contains(document_id) {
+                self.mail_message_data(account_id, document_id)?
+            } else {
+                None
+            };
+            let message_data = if let Some(message_data) = message_data {
+                message_data
+            } else {
+                response
+                    .not_unsubscribed
+                    .append(email_id, SetError::new(SetErrorType::NotFound));
+                continue;
+            };
+
+            // Obtain the unsubscribe URIs and whether one-click is supported
+            let mut https_url = None;
+            let mut mailto_url = None;
+            for url in message_data
+                .headers
+                .get(&RfcHeader::ListUnsubscribe)
+                .into_iter()
+                .flatten()
+                .filter_map(|value| value.clone().unwrap_textlist())
+                .flatten()
+            {
+                if url.len() > 8 && url[..8].eq_ignore_ascii_case("https://") {
+                    https_url.get_or_insert(url);
+                } else if url.len() > 7 && url[..7].eq_ignore_ascii_case("mailto:") {
+                    mailto_url.get_or_insert(url);
+                }
+            }
+            if https_url.is_some()
+                && !self
+                    .blob_get_range(
+                        &message_data.raw_message,
+                        0..message_data.body_offset as u32,
+                    )?
+                    .map_or(false, |headers| has_one_click_header(&headers))
+            {
+                https_url = None;
+            }
+            let pending = PendingUnsubscribe {
+                email_id,
+                list_id: message_data
+                    .headers
+                    .get(&RfcHeader::ListId)
+                    .and_then(|values| values.last())
+                    .and_then(|value| value.clone().unwrap_text())
+                    .and_then(|value| list_id(&value))
+                    .unwrap_or_else(|| "unknown".to_string()),
+                https_url,
+                mailto_url,
+            };
+
+            if pending.https_url.is_some() || pending.mailto_url.is_some() {
+                response.pending.push(pending);
+            } else {
+                response.not_unsubscribed(pending);
+            }
+        }
+
+        Ok(response)
+    }
+
+    fn mail_unsubscribe_mailto(
+        &self,
+        acl: Arc<ACLToken>,
+        response: &mut EmailUnsubscribeResponse,
+        pending: Vec<PendingUnsubscribe>,
+    ) -> jmap::Result<()> {
+        let account_id = response.account_id.get_document_id();
+
+        // Requests are sent from one of the account's identities and
+        // a copy is kept in the Sent mailbox.
+        let (identity_id, mail_from, sent_id) = match (
+            self.mail_unsubscribe_identity(account_id)?,
+            self.mailbox_get_by_role(account_id, "sent")?,
+        ) {
+            (Some((identity_id, mail_from)), Some(sent_id)) => (identity_id, mail_from, sent_id),
+            (identity, _) => {
+                for pending in pending {
+                    response.not_unsubscribed.append(
+                        pending.email_id,
+                        SetError::new(SetErrorType::Forbidden).with_description(
+                            if identity.is_none() {
+                                "An identity is required to send unsubscribe requests."
+                            } else {
+                                "A Sent mailbox is required to send unsubscribe requests."
+                            },
+                        ),
+                    );
+                }
+                return Ok(());
+            }
+        };
+
+        let mut create = VecMap::with_capacity(pending.len());
+        let mut email_ids = VecMap::with_capacity(pending.len());
+        let mut requests = Vec::with_capacity(pending.len());
+        for pending in pending {
+            let (rcpt_to, message) = if let Some(message) = pending
+                .mailto_url
+                .as_deref()
+                .and_then(|url| mailto_message(&mail_from, url))
+            {
+                message
+            } else {
+                response.not_unsubscribed(pending);
+                continue;
+            };
+
+            let blob_id = BlobId::new_external(&message);
+            let message = self.blob_store(&blob_id, message)?;
+            let email = self.mail_import_item(
+                account_id,
+                blob_id,
+                &message,
+                vec![sent_id],
+                vec![Tag::Static(Keyword::SEEN)],
+                None,
+            )?;
+
+            let create_id = pending.email_id.to_string();
+            let mut submission = EmailSubmission::default();
+            submission.properties.append(
+                SubmissionProperty::EmailId,
+                SubmissionValue::Id {
+                    value: *email.id().unwrap(),
+                },
+            );
+            submission.properties.append(
+                SubmissionProperty::IdentityId,
+                SubmissionValue::Id {
+                    value: identity_id.into(),
+                },
+            );
+            submission.properties.append(
+                SubmissionProperty::Envelope,
+                SubmissionValue::Envelope {
+                    value: Envelope {
+                        mail_from: Address {
+                            email: mail_from.clone(),
+                            parameters: None,
+                        },
+                        rcpt_to: vec![Address {
+                            email: rcpt_to,
+                            parameters: None,
+                        }],
+                    },
+                },
+            );
+            create.append(create_id.clone(), submission);
+            email_ids.append(create_id, *email.id().unwrap());
+            requests.push(pending);
+        }
+
+        if requests.is_empty() {
+            return Ok(());
+        }
+        response.change_id = self
+            .get_state(account_id, Collection::Mail)?
+            .get_change_id()
+            .into();
+
+        // Submit the requests, enforcing the same identity, recipient
+        // and quota checks as EmailSubmission/set.
+        let mut submission_response = self.email_submission_set(SetRequest {
+            acl: acl.clone().into(),
+            account_id: response.account_id,
+            if_in_state: None,
+            create: create.into(),
+            update: None,
+            destroy: None,
+            arguments: SubmissionSetArguments::default(),
+        })?;
+        if let Some(change_id) = submission_response.has_changes() {
+            response.change_id = response.change_id.max(Some(change_id));
+        }
+
+        let mut failed_ids = Vec::new();
+        for pending in requests {
+            let create_id = pending.email_id.to_string();
+            if let Some(submission) = submission_response.created.remove(&create_id) {
+                if let Some(id) = submission.id() {
+                    response.submission_ids.push(id.get_document_id());
+                }
+                response.unsubscribed(pending, UnsubscribeMethod::Mailto);
+            } else {
+                let error_type = submission_response
+                    .not_created
+                    .remove(&create_id)
+                    .map_or(SetErrorType::Forbidden, |err| err.type_);
+                if let Some(email_id) = email_ids.remove(&create_id) {
+                    failed_ids.push(email_id);
+                }
+                response.not_unsubscribed.append(
+                    pending.email_id,
+                    SetError::new(error_type)
+                        .with_description("The unsubscribe request could not be submitted."),
+                );
+            }
+        }
+
+        // Remove the copies of the requests that were not submitted
+        if !failed_ids.is_empty() {
+            if let Some(change_id) = self
+                .mail_set(SetRequest {
+                    acl: acl.into(),
+                    account_id: response.account_id,
+                    if_in_state: None,
+                    create: None,
+                    update: None,
+                    destroy: MaybeResultReference::Value(failed_ids).into(),
+                    arguments: (),
+                })?
+                .has_changes()
+            {
+                response.change_id = response.change_id.max(Some(change_id));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+trait JMAPMailUnsubscribeIdentity {
+    fn mail_unsubscribe_identity(
+        &self,
+        account_id: AccountId,
+    ) -> store::Result<Option<(DocumentId, String)>>;
+}
+
+impl<T> JMAPMailUnsubscribeIdentity for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Prefers the identity using the account's primary address
+    fn mail_unsubscribe_identity(
+        &self,
+        account_id: AccountId,
+    ) -> store::Result<Option<(DocumentId, String)>> {
+        let account_email = if let Some(PrincipalValue::Text { value }) = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .and_then(|mut fields| fields.remove(&PrincipalProperty::Email))
+        {
+            value
+        } else {
+            return Ok(None);
+        };
+
+        let mut result = None;
+        for identity_id in self
+            .get_document_ids(account_id, Collection::Identity)?
+            .unwrap_or_default()
+        {
+            if let Some(IdentityValue::Text { value: email }) = self
+                .get_orm::<Identity>(account_id, identity_id)?
+                .and_then(|mut fields| fields.remove(&IdentityProperty::Email))
+            {
+                if email.eq_ignore_ascii_case(&account_email) {
+                    return Ok(Some((identity_id, email)));
+                } else if result.is_none() {
+                    result = Some((identity_id, email));
+                }
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+// Builds an unsubscribe request from a mailto URL as defined in RFC 6068
+fn mailto_message(mail_from: &str, url: &str) -> Option<(String, Vec<u8>)> {
+    let (rcpt_to, query) = url[7..].split_once('?').unwrap_or((&url[7..], ""));
+    let rcpt_to = percent_decode(rcpt_to).trim().to_string();
+    if !rcpt_to.contains('@') || rcpt_to.contains(',') {
+        return None;
+    }
+    let mut subject = "unsubscribe".to_string();
+    let mut body = "unsubscribe".to_string();
+    for param in query.split('&') {
+        if let Some((name, value)) = param.split_once('=') {
+            if name.eq_ignore_ascii_case("subject") {
+                subject = percent_decode(value);
+            } else if name.eq_ignore_ascii_case("body") {
+                body = percent_decode(value);
+            }
+        }
+    }
+
+    let mut message = Vec::with_capacity(body.len() + 512);
+    MessageBuilder::new()
+        .from(mail_from)
+        .to(rcpt_to.as_str())
+        .subject(subject)
+        .text_body(body)
+        .write_to(&mut message)
+        .ok()?;
+
+    Some((rcpt_to, message))
+}
+
+/// Returns the identifier of a mailing list from its List-Id header.
+pub fn list_id(value: &str) -> Option<String> {
+    let value = value
+        .rsplit_once('<')
+        .and_then(|(_, id)| id.split_once('>'))
+        .map_or(value, |(id, _)| id)
+        .trim();
+    if !value.is_empty() {
+        Some(value.to_lowercase())
+    } else {
+        None
+    }
+}
+
+// RFC 8058 requires the List-Unsubscribe-Post header to request a one-click POST.
+fn has_one_click_header(raw_headers: &[u8]) -> bool {
+    Message::parse(raw_headers).map_or(false, |message| {
+        message.parts.first().map_or(false, |part| {
+            part.headers.iter().any(|header| {
+                matches!(&header.name, HeaderName::Other(name) if name.eq_ignore_ascii_case("List-Unsubscribe-Post"))
+                    && matches!(&header.value, HeaderValue::Text(value) if value.trim().eq_ignore_ascii_case("List-Unsubscribe=One-Click"))
+            })
+        })
+    })
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if bytes[pos] == b'%' {
+            if let Some(byte) = value
+                .get(pos + 1..pos + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                result.push(byte);
+                pos += 3;
+                continue;
+            }
+        }
+        result.push(bytes[pos]);
+        pos += 1;
+    }
+    String::from_utf8(result).unwrap_or_else(|_| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{has_one_click_header, list_id, percent_decode};
+
+    #[test]
+    fn unsubscribe_headers() {
+        assert_eq!(
+            list_id("Stalwart Users <Users.Stalw.art>").as_deref(),
+            Some("users.stalw.art")
+        );
+        assert_eq!(
+            list_id("users.example.org").as_deref(),
+            Some("users.example.org")
+        );
+        assert_eq!(list_id(" <> "), None);
+        assert_eq!(percent_decode("Unsubscribe%20me%2"), "Unsubscribe me%2");
+        assert!(has_one_click_header(
+            b"List-Unsubscribe: <https://example.org/u/1>\r\nList-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n\r\n"
+        ));
+        assert!(!has_one_click_header(
+            b"List-Unsubscribe: <https://example.org/u/1>\r\n\r\n"
+        ));
+    }
+}
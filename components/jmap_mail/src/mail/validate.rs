@@ -0,0 +1,235 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use jmap::error::set::SetError;
+use mail_parser::RfcHeader;
+use store::core::vec_map::VecMap;
+
+use super::{
+    schema::{BodyProperty, Email, EmailBodyPart, EmailBodyValue, Property, Value},
+    HeaderName,
+};
+
+struct Violations {
+    items: Vec<(Property, String)>,
+}
+
+impl Email {
+    /// Validates an Email object before it is created or updated, reporting the
+    /// JSON pointer path of every invalid property (i.e. "bodyStructure/subParts/1/partId")
+    /// along with the expected type or the violated constraint.
+    pub fn validate(&self, is_create: bool) -> jmap::error::set::Result<(), Property> {
+        let mut violations = Violations { items: Vec::new() };
+
+        for (property, value) in self.properties.iter() {
+            if let Value::Invalid { expected } = value {
+                violations.add(property.clone(), format!("expected {}", expected));
+            }
+        }
+
+        if is_create {
+            let body_values = self
+                .properties
+                .get(&Property::BodyValues)
+                .and_then(|b| match b {
+                    Value::BodyValues { value } => Some(value),
+                    _ => None,
+                });
+            let has_body_structure = matches!(
+                self.properties.get(&Property::BodyStructure),
+                Some(Value::BodyPart { .. })
+            );
+
+            for (property, value) in self.properties.iter() {
+                match (property, value) {
+                    (
+                        Property::TextBody | Property::HtmlBody | Property::Attachments,
+                        Value::BodyPartList { value },
+                    ) => {
+                        let strict_type = match property {
+                            Property::TextBody => Some("text/plain"),
+                            Property::HtmlBody => Some("text/html"),
+                            _ => None,
+                        };
+                        if has_body_structure {
+                            violations.add(
+                                property.clone(),
+                                "cannot be set together with bodyStructure",
+                            );
+                        } else if strict_type.is_some() && value.len() > 1 {
+                            violations.add(property.clone(), "only one part is allowed");
+                        }
+                        for (pos, part) in value.iter().enumerate() {
+                            part.validate(
+                                &mut violations,
+                                &format!("{}/{}", property, pos),
+                                body_values,
+                                strict_type,
+                            );
+                        }
+                    }
+                    (Property::BodyStructure, Value::BodyPart { value }) => {
+                        value.validate(&mut violations, "bodyStructure", body_values, None);
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        violations.into_result()
+    }
+}
+
+impl EmailBodyPart {
+    fn validate(
+        &self,
+        violations: &mut Violations,
+        path: &str,
+        body_values: Option<&VecMap<String, EmailBodyValue>>,
+        strict_type: Option<&str>,
+    ) {
+        let content_type = self.get_text(BodyProperty::Type).unwrap_or("text/plain");
+        let is_multipart = content_type.starts_with("multipart/");
+        let has_part_id = self.properties.contains_key(&BodyProperty::PartId);
+
+        if let Some(strict_type) = strict_type {
+            if strict_type != content_type {
+                violations.add_path(
+                    path,
+                    BodyProperty::Type,
+                    format!("expected {}", strict_type),
+                );
+            }
+        }
+
+        if !is_multipart {
+            match (
+                self.get_text(BodyProperty::PartId),
+                self.get_blob(BodyProperty::BlobId),
+            ) {
+                (Some(part_id), None) => {
+                    if !body_values.map_or(false, |b| b.get(part_id).is_some()) {
+                        violations.add_path(
+                            path,
+                            BodyProperty::PartId,
+                            "no matching entry in bodyValues",
+                        );
+                    }
+                }
+                (Some(_), Some(_)) => {
+                    violations.add_path(
+                        path,
+                        BodyProperty::BlobId,
+                        "cannot be set together with partId",
+                    );
+                }
+                (None, None)
+                    if !self.properties.contains_key(&BodyProperty::PartId)
+                        && !self.properties.contains_key(&BodyProperty::BlobId) =>
+                {
+                    violations.add_path(path, BodyProperty::PartId, "expected partId or blobId");
+                }
+                _ => (),
+            }
+        }
+
+        for (property, value) in self.properties.iter() {
+            match (property, value) {
+                (_, Value::Invalid { expected }) => {
+                    violations.add_path(path, property.clone(), format!("expected {}", expected));
+                }
+                (BodyProperty::Charset | BodyProperty::Size, _) if has_part_id => {
+                    violations.add_path(
+                        path,
+                        property.clone(),
+                        "cannot be set together with partId",
+                    );
+                }
+                (BodyProperty::Headers, _) => {
+                    violations.add_path(
+                        path,
+                        BodyProperty::Headers,
+                        "headers have to be set individually",
+                    );
+                }
+                (BodyProperty::Header(header), _)
+                    if header.header == HeaderName::Rfc(RfcHeader::ContentTransferEncoding) =>
+                {
+                    violations.add_path(path, property.clone(), "cannot be set");
+                }
+                (BodyProperty::Subparts, Value::BodyPartList { value }) => {
+                    if is_multipart {
+                        for (pos, part) in value.iter().enumerate() {
+                            part.validate(
+                                violations,
+                                &format!("{}/subParts/{}", path, pos),
+                                body_values,
+                                None,
+                            );
+                        }
+                    } else {
+                        violations.add_path(
+                            path,
+                            BodyProperty::Subparts,
+                            "only allowed in multipart parts",
+                        );
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+impl Violations {
+    fn add(&mut self, property: Property, reason: impl Into<String>) {
+        self.items.push((property, reason.into()));
+    }
+
+    fn add_path(&mut self, path: &str, property: BodyProperty, reason: impl Into<String>) {
+        self.items.push((
+            Property::Invalid(format!(
+                "{}/{}",
+                path,
+                property.to_string().replace('~', "~0").replace('/', "~1")
+            )),
+            reason.into(),
+        ));
+    }
+
+    fn into_result(self) -> jmap::error::set::Result<(), Property> {
+        if self.items.is_empty() {
+            return Ok(());
+        }
+
+        let description = self
+            .items
+            .iter()
+            .map(|(property, reason)| format!("{}: {}", property, reason))
+            .collect::<Vec<_>>()
+            .join("; ");
+        Err(SetError::invalid_properties()
+            .with_properties(self.items.into_iter().map(|(property, _)| property))
+            .with_description(description))
+    }
+}
@@ -28,15 +28,19 @@ use jmap::{
     },
     request::{
         changes::{ChangesRequest, ChangesResponse},
+        query,
         query_changes::{QueryChangesRequest, QueryChangesResponse},
     },
-    types::json_pointer::{JSONPointer, JSONPointerEval},
+    types::{
+        jmap::JMAPId,
+        json_pointer::{JSONPointer, JSONPointerEval},
+    },
 };
-use store::{JMAPStore, Store};
+use store::{ahash::AHashSet, JMAPStore, Store};
 
 use super::{
     query::JMAPMailboxQuery,
-    schema::{Mailbox, Property},
+    schema::{Filter, Mailbox, Property},
 };
 
 #[derive(Debug, serde::Serialize, Default)]
@@ -81,9 +85,47 @@ where
         &self,
         request: QueryChangesRequest<Mailbox>,
     ) -> jmap::Result<QueryChangesResponse> {
+        let is_tree_query = request.arguments.sort_as_tree.unwrap_or(false)
+            || request.arguments.filter_as_tree.unwrap_or(false)
+            || request.filter.as_ref().map_or(false, has_ancestor_filter);
         let mut helper = QueryChangesHelper::new(self, request)?;
-        let has_changes = helper.has_changes();
+        let account_id = helper.account_id.get_document_id();
+        let changes = &mut helper.changes;
+
+        // Counter updates do not affect the position of a mailbox nor whether it
+        // matches a filter, only changes to its own properties do.
+        if !changes.children_updated.is_empty() {
+            let children_updated = std::mem::take(&mut changes.children_updated)
+                .into_iter()
+                .collect::<AHashSet<_>>();
+            changes.updated.retain(|id| !children_updated.contains(id));
+        }
+
+        // Renaming or moving a mailbox changes the position of its descendants
+        // when sorting as a tree, and whether they match when filtering as a tree
+        // or by ancestor.
+        if is_tree_query && !changes.updated.is_empty() {
+            let tree = self.mailbox_tree(account_id)?;
+            let mut ids = changes
+                .updated
+                .iter()
+                .chain(changes.created.iter())
+                .copied()
+                .collect::<AHashSet<_>>();
+            for id in changes.updated.clone() {
+                for document_id in tree.descendants(id.get_document_id()) {
+                    let child_id = JMAPId::from(document_id);
+                    if ids.insert(child_id) {
+                        changes.updated.push(child_id);
+                    }
+                }
+            }
+        }
 
+        changes.total_changes =
+            changes.created.len() + changes.updated.len() + changes.destroyed.len();
+
+        let has_changes = helper.has_changes();
         helper.query_changes(if let Some(has_changes) = has_changes {
             self.mailbox_query(has_changes)?.into()
         } else {
@@ -105,3 +147,14 @@ impl JSONPointerEval for ChangesResponseArguments {
         }
     }
 }
+
+// Whether a mailbox matches an ancestor filter depends on its position in the tree.
+fn has_ancestor_filter(filter: &query::Filter<Filter>) -> bool {
+    match filter {
+        query::Filter::FilterOperator(op) => op.conditions.iter().any(has_ancestor_filter),
+        query::Filter::FilterCondition(condition) => {
+            matches!(condition, Filter::AncestorId { .. })
+        }
+        query::Filter::Empty => false,
+    }
+}
@@ -92,6 +92,11 @@ where
         document_id: DocumentId,
         mail_document_ids: Option<&RoaringBitmap>,
     ) -> store::Result<Option<RoaringBitmap>>;
+    fn mailbox_unread_counts(
+        &self,
+        account_id: AccountId,
+        mailbox_ids: Option<Vec<DocumentId>>,
+    ) -> store::Result<Vec<(DocumentId, u64)>>;
     fn mailbox_get_by_name(
         &self,
         account_id: AccountId,
@@ -102,6 +107,12 @@ where
         account_id: AccountId,
         role: &str,
     ) -> store::Result<Option<DocumentId>>;
+    fn mailbox_is_subscribed(
+        &self,
+        account_id: AccountId,
+        document_id: DocumentId,
+        principal_id: AccountId,
+    ) -> store::Result<bool>;
 }
 
 impl<T> JMAPGetMailbox<T> for JMAPStore<T>
@@ -125,7 +136,10 @@ where
                     | Property::ParentId
                     | Property::Role
                     | Property::SortOrder
+                    | Property::ExpungeDays
+                    | Property::LegalHold
                     | Property::ACL
+                    | Property::IsSubscribed
             )
         });
         let account_id = helper.account_id;
@@ -154,7 +168,7 @@ where
             for property in properties {
                 let value = match property {
                     Property::Id => Value::Id { value: id },
-                    Property::Name | Property::Role => fields
+                    Property::Name | Property::Role | Property::ExpungeDays => fields
                         .as_mut()
                         .unwrap()
                         .remove(property)
@@ -164,6 +178,11 @@ where
                         .unwrap()
                         .remove(property)
                         .unwrap_or(Value::Number { value: 0 }),
+                    Property::LegalHold => fields
+                        .as_mut()
+                        .unwrap()
+                        .remove(property)
+                        .unwrap_or(Value::Bool { value: false }),
                     Property::ParentId => fields
                         .as_ref()
                         .unwrap()
@@ -219,17 +238,16 @@ where
                             MailboxRights::owner()
                         },
                     },
-                    Property::IsSubscribed => fields
-                        .as_ref()
-                        .unwrap()
-                        .get(property)
-                        .map(|parent_id| match parent_id {
-                            Value::Subscriptions { value } if value.contains(&acl.primary_id()) => {
-                                Value::Bool { value: true }
-                            }
-                            _ => Value::Bool { value: false },
-                        })
-                        .unwrap_or(Value::Bool { value: false }),
+                    Property::IsSubscribed => Value::Bool {
+                        value: self.mailbox_is_subscribed(
+                            account_id,
+                            document_id,
+                            acl.primary_id(),
+                        )? || matches!(
+                                fields.as_ref().unwrap().get(property),
+                                Some(Value::Subscriptions { value }) if value.contains(&acl.primary_id())
+                            ),
+                    },
                     Property::ACL
                         if acl.is_member(account_id)
                             || self
@@ -328,6 +346,32 @@ where
         }
     }
 
+    fn mailbox_unread_counts(
+        &self,
+        account_id: AccountId,
+        mailbox_ids: Option<Vec<DocumentId>>,
+    ) -> store::Result<Vec<(DocumentId, u64)>> {
+        let mailbox_ids = if let Some(mailbox_ids) = mailbox_ids {
+            mailbox_ids
+        } else if let Some(mailbox_ids) = self.get_document_ids(account_id, Collection::Mailbox)? {
+            mailbox_ids.into_iter().collect()
+        } else {
+            return Ok(Vec::new());
+        };
+        let mail_document_ids = self.get_document_ids(account_id, Collection::Mail)?;
+
+        let mut counts = Vec::with_capacity(mailbox_ids.len());
+        for mailbox_id in mailbox_ids {
+            counts.push((
+                mailbox_id,
+                self.mailbox_unread_tags(account_id, mailbox_id, mail_document_ids.as_ref())?
+                    .map(|unread| unread.len())
+                    .unwrap_or(0),
+            ));
+        }
+        Ok(counts)
+    }
+
     fn mailbox_get_by_name(
         &self,
         account_id: AccountId,
@@ -422,4 +466,15 @@ where
         )
         .map(|r| r.into_bitmap().min())
     }
+
+    fn mailbox_is_subscribed(
+        &self,
+        account_id: AccountId,
+        document_id: DocumentId,
+        principal_id: AccountId,
+    ) -> store::Result<bool> {
+        Ok(self
+            .get_annotation(account_id, Collection::Mailbox, document_id, principal_id)?
+            .is_some())
+    }
 }
@@ -23,6 +23,7 @@
 
 pub mod changes;
 pub mod get;
+pub mod name;
 pub mod query;
 pub mod raft;
 pub mod schema;
@@ -111,7 +112,7 @@ impl CreateMailbox for TinyORM<Mailbox> {
 #[inline(always)]
 pub fn is_valid_role(role: &str) -> bool {
     [
-        "inbox", "trash", "spam", "junk", "drafts", "archive", "sent",
+        "inbox", "trash", "spam", "junk", "drafts", "archive", "sent", "snoozed",
     ]
     .contains(&role)
 }
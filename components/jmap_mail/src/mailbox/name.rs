@@ -0,0 +1,139 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use unicode_normalization::UnicodeNormalization;
+
+// Characters that cannot be part of a mailbox name, either because they are
+// used as hierarchy separators or because they are IMAP LIST wildcards.
+const FORBIDDEN_CHARS: [char; 3] = ['/', '*', '%'];
+
+/// Normalizes a mailbox name to NFC and validates its contents, returning
+/// the name that should be stored.
+pub fn sanitize_mailbox_name(name: &str, max_len: usize) -> Result<String, &'static str> {
+    let name = name.trim().nfc().collect::<String>();
+
+    if name.is_empty() {
+        Err("Mailbox name cannot be empty.")
+    } else if name.len() > max_len {
+        Err("Mailbox name is too long.")
+    } else if name
+        .chars()
+        .any(|ch| ch.is_control() || FORBIDDEN_CHARS.contains(&ch))
+    {
+        Err("Mailbox name contains invalid characters.")
+    } else {
+        Ok(name)
+    }
+}
+
+const BASE64_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+/// Decodes a mailbox name encoded in the modified UTF-7 of RFC 3501, normalizing
+/// the result so that it matches the name stored by Mailbox/set.
+pub fn from_imap_utf7(name: &str) -> Option<String> {
+    let mut result = String::with_capacity(name.len());
+    let mut chars = name.chars();
+
+    while let Some(ch) = chars.next() {
+        if ch != '&' {
+            if !(' '..='~').contains(&ch) {
+                return None;
+            }
+            result.push(ch);
+            continue;
+        }
+
+        let mut bits = 0u32;
+        let mut num_bits = 0;
+        let mut utf16 = Vec::new();
+        let mut is_empty = true;
+        loop {
+            let value = match chars.next()? {
+                '-' => break,
+                ch => BASE64_CHARS.iter().position(|&b| b as char == ch)? as u32,
+            };
+            is_empty = false;
+            bits = (bits << 6) | value;
+            num_bits += 6;
+            if num_bits >= 16 {
+                num_bits -= 16;
+                utf16.push((bits >> num_bits) as u16);
+                bits &= (1 << num_bits) - 1;
+            }
+        }
+
+        if is_empty {
+            result.push('&');
+        } else if bits != 0 || num_bits >= 6 || utf16.is_empty() {
+            return None;
+        } else {
+            for ch in char::decode_utf16(utf16) {
+                // Printable ASCII characters must not be encoded
+                match ch.ok()? {
+                    ' '..='~' => return None,
+                    ch => result.push(ch),
+                }
+            }
+        }
+    }
+
+    Some(result.nfc().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_imap_utf7, sanitize_mailbox_name};
+
+    #[test]
+    fn imap_utf7() {
+        for (name, encoded) in [
+            ("Inbox", "Inbox"),
+            ("Tom & Jerry", "Tom &- Jerry"),
+            ("Entwürfe", "Entw&APw-rfe"),
+            ("台北", "&U,BTFw-"),
+            ("日本語", "&ZeVnLIqe-"),
+            ("😀 Smiles", "&2D3eAA- Smiles"),
+        ] {
+            assert_eq!(from_imap_utf7(encoded).unwrap(), name);
+        }
+
+        // Decomposed names are normalized
+        assert_eq!(
+            from_imap_utf7("Entwu&Awg-rfe").unwrap(),
+            sanitize_mailbox_name("Entwu\u{308}rfe", 255).unwrap()
+        );
+
+        for invalid in ["&Jjo", "&Jjo!-", "caf\u{e9}", "&AGE-x"] {
+            assert!(from_imap_utf7(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn validate_names() {
+        assert_eq!(sanitize_mailbox_name("  Work ", 255).unwrap(), "Work");
+        assert!(sanitize_mailbox_name("   ", 255).is_err());
+        assert!(sanitize_mailbox_name("a/b", 255).is_err());
+        assert!(sanitize_mailbox_name("tab\there", 255).is_err());
+        assert!(sanitize_mailbox_name("abcdef", 5).is_err());
+    }
+}
@@ -21,6 +21,7 @@
  * for more details.
 */
 
+use super::get::JMAPGetMailbox;
 use super::schema::{Comparator, Filter, Mailbox, Property};
 use crate::mail::sharing::JMAPShareMail;
 use jmap::error::method::MethodError;
@@ -36,15 +37,16 @@ use store::core::tag::Tag;
 use store::read::comparator::{self, FieldComparator};
 use store::read::default_filter_mapper;
 use store::read::filter::{self, Query};
+use store::roaring::RoaringBitmap;
 use store::Store;
-use store::{AccountId, JMAPStore};
+use store::{AccountId, DocumentId, JMAPStore};
 
 #[derive(Debug, Clone, serde::Deserialize, Default)]
 pub struct QueryArguments {
     #[serde(rename = "sortAsTree")]
-    sort_as_tree: Option<bool>,
+    pub sort_as_tree: Option<bool>,
     #[serde(rename = "filterAsTree")]
-    filter_as_tree: Option<bool>,
+    pub filter_as_tree: Option<bool>,
 }
 
 impl QueryObject for Mailbox {
@@ -60,6 +62,16 @@ where
     T: for<'x> Store<'x> + 'static,
 {
     fn mailbox_query(&self, request: QueryRequest<Mailbox>) -> jmap::Result<QueryResponse>;
+    fn mailbox_tree(&self, account_id: AccountId) -> store::Result<MailboxTree>;
+}
+
+// Snapshot of the mailbox hierarchy of an account, where mailboxes at the top
+// level have no parent.
+#[derive(Debug, Default)]
+pub struct MailboxTree {
+    parents: AHashMap<DocumentId, Option<DocumentId>>,
+    children: AHashMap<Option<DocumentId>, Vec<DocumentId>>,
+    max_depth: usize,
 }
 
 impl<T> JMAPMailboxQuery<T> for JMAPStore<T>
@@ -79,6 +91,7 @@ where
         let primary_account_id = helper.request.acl.as_ref().unwrap().primary_id();
         let sort_as_tree = helper.request.arguments.sort_as_tree.unwrap_or(false);
         let filter_as_tree = helper.request.arguments.filter_as_tree.unwrap_or(false);
+        let mut tree = None;
 
         helper.parse_filter(|filter| {
             Ok(match filter {
@@ -86,6 +99,14 @@ where
                     Property::ParentId.into(),
                     Query::LongInteger(value.map(|id| u64::from(id) + 1).unwrap_or(0)),
                 ),
+                Filter::AncestorId { value } => {
+                    if tree.is_none() {
+                        tree = Some(self.mailbox_tree(account_id)?);
+                    }
+                    filter::Filter::DocumentSet(
+                        tree.as_ref().unwrap().descendants(value.get_document_id()),
+                    )
+                }
                 Filter::Name { value } => {
                     #[cfg(feature = "debug")]
                     {
@@ -119,10 +140,27 @@ where
                     }
                 }
                 Filter::IsSubscribed { value } => {
-                    let filter = filter::Filter::eq(
-                        Property::IsSubscribed.into(),
-                        Query::Integer(primary_account_id),
-                    );
+                    let mut subscribed = RoaringBitmap::new();
+                    if let Some(document_ids) =
+                        self.get_document_ids(account_id, Collection::Mailbox)?
+                    {
+                        for document_id in document_ids {
+                            if self.mailbox_is_subscribed(
+                                account_id,
+                                document_id,
+                                primary_account_id,
+                            )? {
+                                subscribed.insert(document_id);
+                            }
+                        }
+                    }
+                    let filter = filter::Filter::or(vec![
+                        filter::Filter::DocumentSet(subscribed),
+                        filter::Filter::eq(
+                            Property::IsSubscribed.into(),
+                            Query::Integer(primary_account_id),
+                        ),
+                    ]);
                     if !value {
                         filter::Filter::not(vec![filter])
                     } else {
@@ -153,91 +191,15 @@ where
             helper.query(
                 default_filter_mapper,
                 Some(|mut results: Vec<JMAPId>| {
-                    let mut hierarchy = AHashMap::default();
-                    let mut tree = AHashMap::default();
-
-                    for doc_id in self
-                        .get_document_ids(account_id, Collection::Mailbox)?
-                        .unwrap_or_default()
-                    {
-                        let parent_id = self
-                            .get_orm::<Mailbox>(account_id, doc_id)?
-                            .and_then(|fields| {
-                                fields.get(&Property::ParentId).and_then(|v| v.as_id())
-                            })
-                            .unwrap_or_default();
-                        hierarchy.insert((doc_id + 1) as u64, parent_id);
-                        tree.entry(parent_id)
-                            .or_insert_with(AHashSet::default)
-                            .insert((doc_id + 1) as u64);
-                    }
-
+                    let tree = match tree.take() {
+                        Some(tree) => tree,
+                        None => self.mailbox_tree(account_id)?,
+                    };
                     if filter_as_tree {
-                        let mut filtered_ids = Vec::with_capacity(results.len());
-
-                        for &doc_id in &results {
-                            let mut keep = false;
-                            let mut jmap_id = u64::from(doc_id) + 1;
-
-                            for _ in 0..self.config.mailbox_max_depth {
-                                if let Some(&parent_id) = hierarchy.get(&jmap_id) {
-                                    if parent_id == 0 {
-                                        keep = true;
-                                        break;
-                                    } else if !results.contains(&JMAPId::from(parent_id - 1)) {
-                                        break;
-                                    } else {
-                                        jmap_id = parent_id;
-                                    }
-                                } else {
-                                    break;
-                                }
-                            }
-
-                            if keep {
-                                filtered_ids.push(doc_id);
-                            }
-                        }
-                        if filtered_ids.len() != results.len() {
-                            results = filtered_ids;
-                        }
+                        results = tree.filter_as_tree(results);
                     }
-
-                    if sort_as_tree && results.len() > 1 {
-                        let mut stack = Vec::new();
-                        let mut sorted_list = Vec::with_capacity(results.len());
-                        let mut jmap_id = 0;
-
-                        'outer: for _ in 0..(results.len() * 10 * self.config.mailbox_max_depth) {
-                            let (mut children, mut it) =
-                                if let Some(children) = tree.remove(&jmap_id) {
-                                    (children, results.iter())
-                                } else if let Some(prev) = stack.pop() {
-                                    prev
-                                } else {
-                                    break;
-                                };
-
-                            while let Some(&doc_id) = it.next() {
-                                jmap_id = u64::from(doc_id) + 1;
-                                if children.remove(&jmap_id) {
-                                    sorted_list.push(doc_id);
-                                    if sorted_list.len() == results.len() {
-                                        break 'outer;
-                                    } else {
-                                        stack.push((children, it));
-                                        continue 'outer;
-                                    }
-                                }
-                            }
-
-                            if !children.is_empty() {
-                                jmap_id = *children.iter().next().unwrap();
-                                children.remove(&jmap_id);
-                                stack.push((children, it));
-                            }
-                        }
-                        results = sorted_list;
+                    if sort_as_tree {
+                        results = tree.sort_as_tree(results);
                     }
                     Ok(results)
                 }),
@@ -246,4 +208,127 @@ where
             helper.query(default_filter_mapper, None::<ExtraFilterFnc>)
         }
     }
+
+    fn mailbox_tree(&self, account_id: AccountId) -> store::Result<MailboxTree> {
+        let mut tree = MailboxTree {
+            max_depth: self.config.mailbox_max_depth,
+            ..Default::default()
+        };
+
+        for document_id in self
+            .get_document_ids(account_id, Collection::Mailbox)?
+            .unwrap_or_default()
+        {
+            let parent_id = self
+                .get_orm::<Mailbox>(account_id, document_id)?
+                .and_then(|fields| fields.get(&Property::ParentId).and_then(|v| v.as_id()))
+                .filter(|parent_id| *parent_id > 0)
+                .map(|parent_id| (parent_id - 1) as DocumentId);
+            tree.parents.insert(document_id, parent_id);
+            tree.children
+                .entry(parent_id)
+                .or_default()
+                .push(document_id);
+        }
+
+        Ok(tree)
+    }
+}
+
+impl MailboxTree {
+    pub fn parent(&self, document_id: DocumentId) -> Option<DocumentId> {
+        self.parents.get(&document_id).copied().flatten()
+    }
+
+    pub fn children(&self, document_id: Option<DocumentId>) -> &[DocumentId] {
+        self.children
+            .get(&document_id)
+            .map(|children| children.as_slice())
+            .unwrap_or_default()
+    }
+
+    // Returns all mailboxes below a mailbox, not including itself.
+    pub fn descendants(&self, document_id: DocumentId) -> RoaringBitmap {
+        let mut descendants = RoaringBitmap::new();
+        let mut stack = vec![document_id];
+
+        while let Some(document_id) = stack.pop() {
+            for &child_id in self.children(document_id.into()) {
+                if descendants.insert(child_id) {
+                    stack.push(child_id);
+                }
+            }
+        }
+
+        descendants
+    }
+
+    // Keeps only the mailboxes whose ancestors all matched the filter.
+    fn filter_as_tree(&self, results: Vec<JMAPId>) -> Vec<JMAPId> {
+        let matches = results
+            .iter()
+            .map(|id| id.get_document_id())
+            .collect::<AHashSet<_>>();
+
+        results
+            .into_iter()
+            .filter(|id| {
+                let mut document_id = id.get_document_id();
+                for _ in 0..self.max_depth {
+                    match self.parent(document_id) {
+                        Some(parent_id) if matches.contains(&parent_id) => {
+                            document_id = parent_id;
+                        }
+                        Some(_) => return false,
+                        None => return true,
+                    }
+                }
+                false
+            })
+            .collect()
+    }
+
+    // Places every mailbox after its ancestors, keeping siblings in the order
+    // given by the sort comparators. Mailboxes whose parent was not matched are
+    // placed under their closest matching ancestor.
+    fn sort_as_tree(&self, results: Vec<JMAPId>) -> Vec<JMAPId> {
+        let matches = results
+            .iter()
+            .map(|id| id.get_document_id())
+            .collect::<AHashSet<_>>();
+        let mut children: AHashMap<Option<DocumentId>, Vec<JMAPId>> = AHashMap::default();
+        for &id in &results {
+            let mut parent_id = self.parent(id.get_document_id());
+            for _ in 0..self.max_depth {
+                match parent_id {
+                    Some(document_id) if !matches.contains(&document_id) => {
+                        parent_id = self.parent(document_id);
+                    }
+                    _ => break,
+                }
+            }
+            children.entry(parent_id).or_default().push(id);
+        }
+
+        let mut sorted_list = Vec::with_capacity(results.len());
+        let mut stack = vec![children.remove(&None).unwrap_or_default().into_iter()];
+        while let Some(siblings) = stack.last_mut() {
+            if let Some(id) = siblings.next() {
+                sorted_list.push(id);
+                if let Some(nested) = children.remove(&Some(id.get_document_id())) {
+                    stack.push(nested.into_iter());
+                }
+            } else {
+                stack.pop();
+            }
+        }
+
+        // Mailboxes caught in a loop are appended at the end
+        if sorted_list.len() != results.len() {
+            let sorted_ids = sorted_list.iter().copied().collect::<AHashSet<_>>();
+            sorted_list.extend(results.into_iter().filter(|id| !sorted_ids.contains(id)));
+        }
+
+        sorted_list
+    }
 }
@@ -194,6 +194,8 @@ pub enum Property {
     IsSubscribed = 10,
     ACL = 11,
     Invalid = 12,
+    ExpungeDays = 13,
+    LegalHold = 14,
 }
 
 impl Display for Property {
@@ -211,6 +213,8 @@ impl Display for Property {
             Property::MyRights => write!(f, "myRights"),
             Property::IsSubscribed => write!(f, "isSubscribed"),
             Property::ACL => write!(f, "acl"),
+            Property::ExpungeDays => write!(f, "expungeDays"),
+            Property::LegalHold => write!(f, "legalHold"),
             Property::Invalid => Ok(()),
         }
     }
@@ -231,6 +235,8 @@ impl Property {
             "unreadThreads" => Property::UnreadThreads,
             "myRights" => Property::MyRights,
             "acl" => Property::ACL,
+            "expungeDays" => Property::ExpungeDays,
+            "legalHold" => Property::LegalHold,
             _ => Property::Invalid,
         }
     }
@@ -244,6 +250,9 @@ pub enum Filter {
     HasAnyRole { value: bool },
     IsSubscribed { value: bool },
     Unsupported { value: String },
+
+    // Non-standard
+    AncestorId { value: JMAPId },
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -278,6 +287,8 @@ impl From<FieldId> for Property {
             9 => Property::MyRights,
             10 => Property::IsSubscribed,
             11 => Property::ACL,
+            13 => Property::ExpungeDays,
+            14 => Property::LegalHold,
             _ => Property::Invalid,
         }
     }
@@ -161,6 +161,24 @@ impl<'de> serde::de::Visitor<'de> for MailboxVisitor {
                         },
                     );
                 }
+                "expungeDays" => {
+                    properties.append(
+                        Property::ExpungeDays,
+                        if let Some(value) = map.next_value::<Option<u32>>()? {
+                            Value::Number { value }
+                        } else {
+                            Value::Null
+                        },
+                    );
+                }
+                "legalHold" => {
+                    properties.append(
+                        Property::LegalHold,
+                        Value::Bool {
+                            value: map.next_value::<Option<bool>>()?.unwrap_or(false),
+                        },
+                    );
+                }
                 "isSubscribed" => {
                     properties.append(
                         Property::IsSubscribed,
@@ -286,6 +304,9 @@ impl FilterDeserializer for Filter {
             "isSubscribed" => Filter::IsSubscribed {
                 value: map.next_value().ok()?,
             },
+            "ancestorId" => Filter::AncestorId {
+                value: map.next_value().ok()?,
+            },
             unsupported => {
                 map.next_value::<IgnoredAny>().ok()?;
                 Filter::Unsupported {
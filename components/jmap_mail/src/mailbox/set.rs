@@ -23,8 +23,11 @@
 
 use std::time::Duration;
 
+use super::get::JMAPGetMailbox;
 use super::is_valid_role;
+use super::name::sanitize_mailbox_name;
 use super::schema::{Mailbox, Property, Value};
+use crate::mail::retention::JMAPMailRetention;
 use crate::mail::schema::Email;
 use crate::mail::set::JMAPSetMail;
 use crate::mail::sharing::JMAPShareMail;
@@ -49,8 +52,9 @@ use store::core::JMAPIdPrefix;
 use store::read::comparator::Comparator;
 use store::read::filter::{ComparisonOperator, Filter, Query};
 use store::read::FilterMapper;
-use store::tracing::debug;
+use store::tracing::{debug, info};
 use store::write::batch::WriteBatch;
+use store::write::options::{IndexOptions, Options};
 use store::write::update::Changes;
 use store::{AccountId, DocumentId, JMAPStore, LongInteger, SharedResource};
 use store::{SharedBitmap, Store};
@@ -100,6 +104,14 @@ where
     T: for<'x> Store<'x> + 'static,
 {
     fn mailbox_set(&self, request: SetRequest<Mailbox>) -> jmap::Result<SetResponse<Mailbox>>;
+    fn mailbox_set_deferred(
+        &self,
+        request: SetRequest<Mailbox>,
+    ) -> jmap::Result<(SetResponse<Mailbox>, WriteBatch)>;
+    fn mailbox_set_changes(
+        &self,
+        request: SetRequest<Mailbox>,
+    ) -> jmap::Result<SetHelper<Mailbox, T>>;
     fn mailbox_delete(&self, account_id: AccountId, document: &mut Document) -> store::Result<()>;
     fn mailbox_create_path(
         &self,
@@ -113,6 +125,20 @@ where
     T: for<'x> Store<'x> + 'static,
 {
     fn mailbox_set(&self, request: SetRequest<Mailbox>) -> jmap::Result<SetResponse<Mailbox>> {
+        self.mailbox_set_changes(request)?.into_response()
+    }
+
+    fn mailbox_set_deferred(
+        &self,
+        request: SetRequest<Mailbox>,
+    ) -> jmap::Result<(SetResponse<Mailbox>, WriteBatch)> {
+        Ok(self.mailbox_set_changes(request)?.into_deferred_response())
+    }
+
+    fn mailbox_set_changes(
+        &self,
+        request: SetRequest<Mailbox>,
+    ) -> jmap::Result<SetHelper<Mailbox, T>> {
         let mut helper = SetHelper::new(self, request)?;
         let on_destroy_remove_emails = helper
             .request
@@ -121,6 +147,11 @@ where
             .unwrap_or(false);
 
         helper.create(|_create_id, mailbox, helper, document| {
+            let subscribe = matches!(
+                mailbox.properties.get(&Property::IsSubscribed),
+                Some(Value::Bool { value: true })
+            );
+
             // Set values
             let mut mailbox = TinyORM::<Mailbox>::new().mailbox_set(helper, mailbox, None, None)?;
 
@@ -154,6 +185,9 @@ where
                 mailbox.set(Property::ParentId, Value::Id { value: 0u64.into() });
             }
             mailbox.insert_validate(document)?;
+            if subscribe {
+                mailbox_subscription(document, helper.acl.primary_id(), true);
+            }
 
             Ok(Mailbox::new(document.document_id.into()))
         })?;
@@ -164,6 +198,15 @@ where
                 .get_orm::<Mailbox>(helper.account_id, document_id)?
                 .ok_or_else(|| SetError::new(SetErrorType::NotFound))?;
 
+            let subscribe = match mailbox.properties.get(&Property::IsSubscribed) {
+                Some(Value::Bool { value }) => Some(*value),
+                _ => None,
+            };
+            let is_subscription_only = mailbox
+                .properties
+                .keys()
+                .all(|property| property == &Property::IsSubscribed);
+
             let fields = TinyORM::track_changes(&current_fields).mailbox_set(
                 helper,
                 mailbox,
@@ -181,8 +224,8 @@ where
                 ));
             }
 
-            // Check ACLs
-            if helper.acl.is_shared(helper.account_id) {
+            // Check ACLs, subscriptions only require read access
+            if helper.acl.is_shared(helper.account_id) && !is_subscription_only {
                 if !helper
                     .store
                     .mail_shared_folders(helper.account_id, &helper.acl.member_of, ACL::Modify)?
@@ -208,6 +251,20 @@ where
                 }
             }
 
+            // Subscriptions are stored as private annotations of each principal,
+            // the change is logged as an update to the mailbox.
+            if let Some(subscribe) = subscribe {
+                if subscribe
+                    != helper.store.mailbox_is_subscribed(
+                        helper.account_id,
+                        document_id,
+                        helper.acl.primary_id(),
+                    )?
+                {
+                    mailbox_subscription(document, helper.acl.primary_id(), subscribe);
+                }
+            }
+
             // Merge changes
             current_fields.merge_validate(document, fields)?;
 
@@ -251,6 +308,22 @@ where
                 }
             }
 
+            // Mailboxes under legal hold cannot be deleted
+            if let Some(Value::Bool { value: true }) = self
+                .get_orm::<Mailbox>(helper.account_id, document_id)?
+                .as_ref()
+                .and_then(|fields| fields.get(&Property::LegalHold))
+            {
+                info!(
+                    target: "audit",
+                    "Blocked deletion of mailbox {}:{} under legal hold.",
+                    helper.account_id,
+                    document_id
+                );
+                return Err(SetError::forbidden()
+                    .with_description("Mailbox is under legal hold and cannot be deleted."));
+            }
+
             // Verify that this mailbox does not have sub-mailboxes
             if !self
                 .query_store::<FilterMapper>(
@@ -291,6 +364,12 @@ where
                         }
                     };
 
+                    // Do not remove any messages if some of them have to be retained
+                    let retention = self.mail_retention(helper.account_id)?;
+                    for message_document_id in &message_doc_ids {
+                        retention.assert_can_destroy(helper.account_id, message_document_id)?;
+                    }
+
                     for message_document_id in message_doc_ids {
                         let mut document = Document::new(Collection::Mail, message_document_id);
                         // Fetch Email's ORM
@@ -353,18 +432,23 @@ where
                 }
             }
 
-            // Delete ORM and index
+            // Delete ORM, index and subscriptions
             if let Some(orm) = helper
                 .store
                 .get_orm::<Mailbox>(helper.account_id, document_id)?
             {
                 orm.delete(document);
             }
+            for (principal_id, _) in
+                self.get_annotations(helper.account_id, Collection::Mailbox, document_id)?
+            {
+                mailbox_subscription(document, principal_id, false);
+            }
 
             Ok(())
         })?;
 
-        helper.into_response()
+        Ok(helper)
     }
 
     fn mailbox_delete(&self, account_id: AccountId, document: &mut Document) -> store::Result<()> {
@@ -377,6 +461,11 @@ where
                 ))
             })?
             .delete(document);
+        for (principal_id, _) in
+            self.get_annotations(account_id, Collection::Mailbox, document.document_id)?
+        {
+            mailbox_subscription(document, principal_id, false);
+        }
 
         Ok(())
     }
@@ -386,17 +475,16 @@ where
         account_id: AccountId,
         path: &str,
     ) -> store::Result<Option<(DocumentId, Option<Changes>)>> {
-        let path = path
-            .split('/')
-            .filter_map(|p| {
-                let p = p.trim();
-                if !p.is_empty() {
-                    p.into()
-                } else {
-                    None
+        let mut parts = Vec::new();
+        for part in path.split('/') {
+            if !part.trim().is_empty() {
+                match sanitize_mailbox_name(part, self.config.mailbox_name_max_len) {
+                    Ok(part) => parts.push(part),
+                    Err(_) => return Ok(None),
                 }
-            })
-            .collect::<Vec<_>>();
+            }
+        }
+        let path = parts;
         if path.is_empty() || path.len() > self.config.mailbox_max_depth {
             return Ok(None);
         }
@@ -464,19 +552,10 @@ where
             let mut batch = WriteBatch::new(account_id);
 
             for name in path {
-                if name.len() > self.config.mailbox_name_max_len {
-                    return Ok(None);
-                }
-
                 let document_id = self.assign_document_id(account_id, Collection::Mailbox)?;
                 let mut document = Document::new(Collection::Mailbox, document_id);
                 let mut orm = TinyORM::<Mailbox>::new();
-                orm.set(
-                    Property::Name,
-                    Value::Text {
-                        value: name.to_string(),
-                    },
-                );
+                orm.set(Property::Name, Value::Text { value: name });
                 orm.set(
                     Property::ParentId,
                     Value::Id {
@@ -496,6 +575,20 @@ where
     }
 }
 
+/// Adds or removes a principal from the subscribers of a mailbox. Each subscription
+/// is stored as an empty private annotation of the subscriber on the mailbox.
+fn mailbox_subscription(document: &mut Document, principal_id: AccountId, subscribe: bool) {
+    document.annotation(
+        principal_id,
+        Vec::new(),
+        if subscribe {
+            IndexOptions::new()
+        } else {
+            IndexOptions::new().clear()
+        },
+    );
+}
+
 trait MailboxSet<T>: Sized
 where
     T: for<'x> Store<'x> + 'static,
@@ -523,15 +616,14 @@ where
         // Set properties
         for (property, value) in mailbox.properties {
             let value = match (property, value) {
-                (Property::Name, Value::Text { value }) => {
-                    if value.len() < helper.store.config.mailbox_name_max_len {
-                        Value::Text { value }
-                    } else {
-                        return Err(SetError::invalid_properties()
-                            .with_property(property)
-                            .with_description("Mailbox name is too long.".to_string()));
-                    }
-                }
+                (Property::Name, Value::Text { value }) => Value::Text {
+                    value: sanitize_mailbox_name(&value, helper.store.config.mailbox_name_max_len)
+                        .map_err(|err| {
+                            SetError::invalid_properties()
+                                .with_property(property)
+                                .with_description(err)
+                        })?,
+                },
                 (Property::ParentId, Value::Id { value }) => {
                     let parent_id = value.get_document_id();
                     if helper.will_destroy.contains(&value) {
@@ -551,49 +643,25 @@ where
                         .into(),
                 },
                 (Property::IsSubscribed, Value::Bool { value: subscribe }) => {
+                    // Remove subscriptions stored in the mailbox document by older versions
                     let account_id = helper.acl.primary_id();
-                    let mut new_value = None;
-                    if let Some(current_fields) = current_fields.as_ref() {
-                        if let Some(Value::Subscriptions { value }) =
-                            current_fields.get(&Property::IsSubscribed)
+                    match current_fields.and_then(|fields| fields.get(&Property::IsSubscribed)) {
+                        Some(Value::Subscriptions { value })
+                            if !subscribe && value.contains(&account_id) =>
                         {
-                            if subscribe {
-                                if !value.contains(&account_id) {
-                                    let mut current_subscriptions = value.clone();
-                                    current_subscriptions.push(account_id);
-                                    new_value = Value::Subscriptions {
-                                        value: current_subscriptions,
-                                    }
-                                    .into();
-                                } else {
-                                    continue;
-                                }
-                            } else if value.contains(&account_id) {
-                                if value.len() > 1 {
-                                    new_value = Value::Subscriptions {
-                                        value: value
-                                            .iter()
-                                            .filter(|&&id| id != account_id)
-                                            .cloned()
-                                            .collect(),
-                                    }
-                                    .into();
-                                } else {
-                                    new_value = Value::Null.into();
+                            if value.len() > 1 {
+                                Value::Subscriptions {
+                                    value: value
+                                        .iter()
+                                        .filter(|&&id| id != account_id)
+                                        .cloned()
+                                        .collect(),
                                 }
                             } else {
-                                continue;
+                                Value::Null
                             }
                         }
-                    }
-                    if let Some(new_value) = new_value {
-                        new_value
-                    } else if subscribe {
-                        Value::Subscriptions {
-                            value: vec![account_id],
-                        }
-                    } else {
-                        continue;
+                        _ => continue,
                     }
                 }
                 (Property::ParentId, Value::Null) => Value::Id { value: 0u64.into() },
@@ -613,6 +681,27 @@ where
                     Value::Null
                 }
                 (Property::SortOrder, value @ Value::Number { .. }) => value,
+                (Property::ExpungeDays, value @ (Value::Number { .. } | Value::Null)) => value,
+                (Property::LegalHold, Value::Bool { value }) => {
+                    // Only administrators can place or lift a legal hold
+                    if !helper.acl.is_member(SUPERUSER_ID) {
+                        return Err(SetError::forbidden()
+                            .with_property(property)
+                            .with_description("Only administrators can change legal holds."));
+                    }
+                    info!(
+                        target: "audit",
+                        "Legal hold {} on mailbox {:?} of account {}.",
+                        if value { "placed" } else { "lifted" },
+                        mailbox_id,
+                        helper.account_id
+                    );
+                    if value {
+                        Value::Bool { value }
+                    } else {
+                        Value::Null
+                    }
+                }
                 (Property::ACL, Value::ACLSet(value)) => {
                     for acl_update in &value {
                         match acl_update {
@@ -654,14 +743,13 @@ where
             self.set(property, value);
         }
 
-        if let (Some(mailbox_id), Some(mut mailbox_parent_id)) = (
-            mailbox_id,
-            self.get(&Property::ParentId).and_then(|v| v.as_id()),
-        ) {
-            // Validate circular parent-child relationship
+        if let Some(mut mailbox_parent_id) = self.get(&Property::ParentId).and_then(|v| v.as_id()) {
+            // Validate circular parent-child relationship and maximum depth
             let mut success = false;
             for _ in 0..helper.store.config.mailbox_max_depth {
-                if mailbox_parent_id == (mailbox_id as store::JMAPId) + 1 {
+                if mailbox_id.map_or(false, |mailbox_id| {
+                    mailbox_parent_id == (mailbox_id as store::JMAPId) + 1
+                }) {
                     return Err(SetError::invalid_properties()
                         .with_description("Mailbox cannot be a parent of itself."));
                 } else if mailbox_parent_id == 0 {
@@ -21,7 +21,10 @@
  * for more details.
 */
 
-use super::schema::{Property, Thread};
+use super::{
+    preview::JMAPThreadPreview,
+    schema::{Property, Thread, ThreadPreview},
+};
 use crate::mail::{sharing::JMAPShareMail, MessageField};
 use jmap::{
     jmap_store::get::{GetHelper, GetObject, IdMapper, SharedDocsFnc},
@@ -52,6 +55,7 @@ impl GetObject for Thread {
         match property {
             Property::Id => vec![self.id],
             Property::EmailIds => self.email_ids.clone(),
+            _ => return None,
         }
         .into()
     }
@@ -82,7 +86,7 @@ where
             helper.properties.push(Property::Id);
         }
 
-        let response = helper.get(|id, _properties| {
+        let response = helper.get(|id, properties| {
             let thread_id = id.get_document_id();
             if let Some(mut doc_ids) = self.get_tag(
                 account_id,
@@ -99,6 +103,15 @@ where
                     }
                 }
 
+                let preview = if properties
+                    .iter()
+                    .any(|property| !matches!(property, Property::Id | Property::EmailIds))
+                {
+                    self.thread_preview(account_id, thread_id, &doc_ids, properties)?
+                } else {
+                    ThreadPreview::default()
+                };
+
                 Ok(Some(Thread {
                     id,
                     email_ids: self
@@ -114,6 +127,7 @@ where
                         .into_iter()
                         .map(|doc_id| JMAPId::from_parts(thread_id, doc_id.get_document_id()))
                         .collect(),
+                    preview,
                 }))
             } else {
                 Ok(None)
@@ -24,10 +24,11 @@
 use jmap::{jmap_store::Object, types::jmap::JMAPId};
 use store::core::collection::Collection;
 
-use self::schema::{Property, Thread};
+use self::schema::{Property, Thread, ThreadPreview};
 
 pub mod changes;
 pub mod get;
+pub mod preview;
 pub mod schema;
 
 impl Object for Thread {
@@ -39,6 +40,7 @@ impl Object for Thread {
         Thread {
             id,
             email_ids: Vec::new(),
+            preview: ThreadPreview::default(),
         }
     }
 
@@ -0,0 +1,292 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use jmap::types::date::JMAPDate;
+use mail_parser::RfcHeader;
+use store::{
+    blob::BlobId,
+    core::{
+        collection::Collection, document::Document, error::StoreError, tag::Tag,
+        thread::ThreadAggregate,
+    },
+    roaring::RoaringBitmap,
+    serialize::{StoreDeserialize, StoreSerialize},
+    write::options::{IndexOptions, Options},
+    AccountId, DocumentId, JMAPStore, Store,
+};
+
+use crate::mail::{
+    schema::{EmailAddress, Keyword},
+    HeaderValue, MessageData, MessageField,
+};
+
+use super::schema::{Property, ThreadPreview};
+
+/// Per message summary stored next to each email when it is indexed, so that
+/// thread aggregates can be computed without fetching message metadata.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct MessagePreview {
+    pub received_at: i64,
+    pub from: Vec<EmailAddress>,
+}
+
+impl StoreSerialize for MessagePreview {
+    fn serialize(&self) -> Option<Vec<u8>> {
+        bincode::serialize(self).ok()
+    }
+}
+
+impl StoreDeserialize for MessagePreview {
+    fn deserialize(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize_from(bytes).ok()
+    }
+}
+
+impl MessagePreview {
+    pub fn new(message_data: &MessageData) -> Self {
+        let mut from = Vec::new();
+        if let Some(values) = message_data.headers.get(&RfcHeader::From) {
+            for value in values {
+                match value {
+                    HeaderValue::Addresses(addresses) => from.extend(addresses.iter().cloned()),
+                    HeaderValue::GroupedAddresses(groups) => {
+                        for group in groups {
+                            from.extend(group.addresses.iter().cloned());
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        MessagePreview {
+            received_at: message_data.received_at,
+            from,
+        }
+    }
+
+    pub fn build_index(&self, document: &mut Document, is_insert: bool) {
+        if is_insert {
+            document.binary(
+                MessageField::ThreadPreview,
+                self.serialize().unwrap_or_default(),
+                IndexOptions::new(),
+            );
+        } else {
+            document.binary(
+                MessageField::ThreadPreview,
+                Vec::with_capacity(0),
+                IndexOptions::new().clear(),
+            );
+        }
+    }
+}
+
+pub trait JMAPThreadPreview<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn thread_preview(
+        &self,
+        account_id: AccountId,
+        thread_id: DocumentId,
+        document_ids: &RoaringBitmap,
+        properties: &[Property],
+    ) -> store::Result<ThreadPreview>;
+}
+
+impl<T> JMAPThreadPreview<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn thread_preview(
+        &self,
+        account_id: AccountId,
+        thread_id: DocumentId,
+        document_ids: &RoaringBitmap,
+        properties: &[Property],
+    ) -> store::Result<ThreadPreview> {
+        let mut preview = ThreadPreview::default();
+
+        if properties.contains(&Property::UnreadCount) {
+            let mut unread_ids = document_ids.clone();
+            if let Some(seen_ids) = self.get_tag(
+                account_id,
+                Collection::Mail,
+                MessageField::Keyword.into(),
+                Tag::Static(Keyword::SEEN),
+            )? {
+                unread_ids -= seen_ids;
+            }
+            preview.unread_count = Some(unread_ids.len());
+        }
+
+        if properties.contains(&Property::HasAttachment) {
+            preview.has_attachment = Some(
+                self.get_tag(
+                    account_id,
+                    Collection::Mail,
+                    MessageField::Attachment.into(),
+                    Tag::Default,
+                )?
+                .map_or(false, |attachment_ids| {
+                    !attachment_ids.is_disjoint(document_ids)
+                }),
+            );
+        }
+
+        if properties.contains(&Property::Participants)
+            || properties.contains(&Property::ReceivedAt)
+        {
+            // Messages are immutable, cached aggregates are valid for as long
+            // as the thread contains the same messages
+            let cache_key = (account_id, thread_id);
+            let aggregate = match self.thread_previews.get(&cache_key) {
+                Some(aggregate) if &aggregate.document_ids == document_ids => aggregate,
+                _ => {
+                    let aggregate = Arc::new(self.thread_aggregate(account_id, document_ids)?);
+                    self.thread_previews.insert(cache_key, aggregate.clone());
+                    aggregate
+                }
+            };
+
+            if properties.contains(&Property::ReceivedAt) {
+                preview.received_at = aggregate.received_at.map(JMAPDate::from_timestamp);
+            }
+
+            if properties.contains(&Property::Participants) {
+                preview.participants = Some(
+                    aggregate
+                        .participants
+                        .iter()
+                        .map(|(name, email)| EmailAddress {
+                            name: name.clone(),
+                            email: email.clone(),
+                        })
+                        .collect(),
+                );
+            }
+        }
+
+        Ok(preview)
+    }
+}
+
+trait ThreadPreviewAggregate {
+    fn thread_aggregate(
+        &self,
+        account_id: AccountId,
+        document_ids: &RoaringBitmap,
+    ) -> store::Result<ThreadAggregate>;
+
+    fn thread_preview_from_metadata(
+        &self,
+        account_id: AccountId,
+        document_id: DocumentId,
+    ) -> store::Result<Option<MessagePreview>>;
+}
+
+impl<T> ThreadPreviewAggregate for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn thread_aggregate(
+        &self,
+        account_id: AccountId,
+        document_ids: &RoaringBitmap,
+    ) -> store::Result<ThreadAggregate> {
+        let mut messages = Vec::with_capacity(document_ids.len() as usize);
+        for (document_id, message) in
+            document_ids
+                .iter()
+                .zip(self.get_multi_document_value::<MessagePreview>(
+                    account_id,
+                    Collection::Mail,
+                    document_ids.iter(),
+                    MessageField::ThreadPreview.into(),
+                )?)
+        {
+            if let Some(message) = message {
+                messages.push(message);
+            } else if let Some(message) =
+                self.thread_preview_from_metadata(account_id, document_id)?
+            {
+                // Messages indexed before previews were introduced
+                messages.push(message);
+            }
+        }
+        messages.sort_unstable_by_key(|message| message.received_at);
+
+        // Most recent senders first, each address listed once
+        let received_at = messages.last().map(|message| message.received_at);
+        let mut participants: Vec<(Option<String>, String)> = Vec::new();
+        for message in messages.into_iter().rev() {
+            for address in message.from {
+                if !participants
+                    .iter()
+                    .any(|(_, email)| email.eq_ignore_ascii_case(&address.email))
+                {
+                    participants.push((address.name, address.email));
+                }
+            }
+        }
+
+        Ok(ThreadAggregate {
+            document_ids: document_ids.clone(),
+            received_at,
+            participants,
+        })
+    }
+
+    fn thread_preview_from_metadata(
+        &self,
+        account_id: AccountId,
+        document_id: DocumentId,
+    ) -> store::Result<Option<MessagePreview>> {
+        let metadata_blob_id = if let Some(metadata_blob_id) = self.get_document_value::<BlobId>(
+            account_id,
+            Collection::Mail,
+            document_id,
+            MessageField::Metadata.into(),
+        )? {
+            metadata_blob_id
+        } else {
+            return Ok(None);
+        };
+
+        if let Some(bytes) = self.blob_get(&metadata_blob_id)? {
+            Ok(Some(MessagePreview::new(
+                &MessageData::deserialize(&bytes).ok_or_else(|| {
+                    StoreError::DataCorruption(format!(
+                        "Failed to deserialize email metadata for {}/{}",
+                        account_id, document_id
+                    ))
+                })?,
+            )))
+        } else {
+            Ok(None)
+        }
+    }
+}
@@ -21,15 +21,35 @@
  * for more details.
 */
 
-use jmap::types::jmap::JMAPId;
+use jmap::types::{date::JMAPDate, jmap::JMAPId};
 use serde::{Deserialize, Serialize};
 use store::FieldId;
 
+use crate::mail::schema::EmailAddress;
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Thread {
     pub id: JMAPId,
     #[serde(rename = "emailIds")]
     pub email_ids: Vec<JMAPId>,
+    #[serde(flatten)]
+    pub preview: ThreadPreview,
+}
+
+/// Aggregates returned when requested through the thread preview extension.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ThreadPreview {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub participants: Option<Vec<EmailAddress>>,
+    #[serde(rename = "receivedAt")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<JMAPDate>,
+    #[serde(rename = "unreadCount")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unread_count: Option<u64>,
+    #[serde(rename = "hasAttachment")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub has_attachment: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Copy)]
@@ -39,6 +59,14 @@ pub enum Property {
     Id = 0,
     #[serde(rename = "emailIds")]
     EmailIds = 1,
+    #[serde(rename = "participants")]
+    Participants = 2,
+    #[serde(rename = "receivedAt")]
+    ReceivedAt = 3,
+    #[serde(rename = "unreadCount")]
+    UnreadCount = 4,
+    #[serde(rename = "hasAttachment")]
+    HasAttachment = 5,
 }
 
 impl Property {
@@ -46,6 +74,10 @@ impl Property {
         match value {
             "id" => Property::Id,
             "emailIds" => Property::EmailIds,
+            "participants" => Property::Participants,
+            "receivedAt" => Property::ReceivedAt,
+            "unreadCount" => Property::UnreadCount,
+            "hasAttachment" => Property::HasAttachment,
             _ => Property::Id,
         }
     }
@@ -60,6 +92,10 @@ impl From<FieldId> for Property {
     fn from(field: FieldId) -> Self {
         match field {
             0 => Property::Id,
+            2 => Property::Participants,
+            3 => Property::ReceivedAt,
+            4 => Property::UnreadCount,
+            5 => Property::HasAttachment,
             _ => Property::EmailIds,
         }
     }
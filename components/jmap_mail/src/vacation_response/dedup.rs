@@ -0,0 +1,160 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use jmap::orm::serialize::JMAPOrm;
+use jmap::orm::TinyORM;
+use jmap::types::date::JMAPDate;
+use jmap::types::jmap::JMAPId;
+use jmap_sieve::sieve_script::schema::{Property, SieveScript, Value};
+use store::core::collection::Collection;
+use store::core::document::Document;
+use store::core::error::StoreError;
+use store::log::changes::ChangeId;
+use store::write::batch::WriteBatch;
+use store::{AccountId, JMAPStore, Store};
+
+use super::get::JMAPGetVacationResponse;
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct VacationDedupReport {
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    #[serde(rename = "isActive")]
+    pub is_active: bool,
+    pub entries: Vec<VacationDedupEntry>,
+    #[serde(skip)]
+    pub change_id: Option<ChangeId>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct VacationDedupEntry {
+    pub hash: String,
+    pub expires: JMAPDate,
+}
+
+pub trait JMAPVacationDedup<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn vacation_dedup_get(
+        &self,
+        account_id: AccountId,
+    ) -> store::Result<Option<VacationDedupReport>>;
+    fn vacation_dedup_reset(
+        &self,
+        account_id: AccountId,
+    ) -> store::Result<Option<VacationDedupReport>>;
+}
+
+impl<T> JMAPVacationDedup<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn vacation_dedup_get(
+        &self,
+        account_id: AccountId,
+    ) -> store::Result<Option<VacationDedupReport>> {
+        let document_id =
+            if let Some(document_id) = self.get_vacation_sieve_script_id(account_id)? {
+                document_id
+            } else {
+                return Ok(None);
+            };
+        let script = self
+            .get_orm::<SieveScript>(account_id, document_id)?
+            .ok_or_else(|| {
+                StoreError::NotFound(format!(
+                    "SieveScript ORM data for {}:{} not found.",
+                    account_id, document_id
+                ))
+            })?;
+
+        let mut entries = if let Some(Value::SeenIds { value }) = script.get(&Property::SeenIds) {
+            value
+                .ids
+                .iter()
+                .map(|id| VacationDedupEntry {
+                    hash: id.hash().iter().map(|b| format!("{:02x}", b)).collect(),
+                    expires: JMAPDate::from_timestamp(id.expiry() as i64),
+                })
+                .collect::<Vec<_>>()
+        } else {
+            Vec::new()
+        };
+        entries.sort_unstable_by_key(|entry| entry.expires.timestamp());
+
+        Ok(Some(VacationDedupReport {
+            account_id: JMAPId::from(account_id),
+            is_active: matches!(
+                script.get(&Property::IsActive),
+                Some(Value::Bool { value: true })
+            ),
+            entries,
+            change_id: None,
+        }))
+    }
+
+    fn vacation_dedup_reset(
+        &self,
+        account_id: AccountId,
+    ) -> store::Result<Option<VacationDedupReport>> {
+        let _lock = self.lock_collection(account_id, Collection::SieveScript);
+        let mut report = if let Some(report) = self.vacation_dedup_get(account_id)? {
+            report
+        } else {
+            return Ok(None);
+        };
+
+        if !report.entries.is_empty() {
+            // Forget about all senders that were already replied to
+            let document_id = self
+                .get_vacation_sieve_script_id(account_id)?
+                .ok_or_else(|| {
+                    StoreError::NotFound(format!(
+                        "Vacation SieveScript for account {} not found.",
+                        account_id
+                    ))
+                })?;
+            let script = self
+                .get_orm::<SieveScript>(account_id, document_id)?
+                .ok_or_else(|| {
+                    StoreError::NotFound(format!(
+                        "SieveScript ORM data for {}:{} not found.",
+                        account_id, document_id
+                    ))
+                })?;
+            let mut updated_script = TinyORM::track_changes(&script);
+            updated_script.remove(&Property::SeenIds);
+
+            let mut document = Document::new(Collection::SieveScript, document_id);
+            script.merge(&mut document, updated_script)?;
+
+            let mut changes = WriteBatch::new(account_id);
+            changes.update_document(document);
+            changes.log_update(Collection::SieveScript, document_id);
+            report.change_id = self.write(changes)?.map(|changes| changes.change_id);
+        }
+
+        Ok(Some(report))
+    }
+}
@@ -53,6 +53,7 @@ impl GetObject for VacationResponse {
             Property::Subject,
             Property::TextBody,
             Property::HtmlBody,
+            Property::SuppressedSenders,
         ]
     }
 
@@ -26,6 +26,7 @@ use store::core::collection::Collection;
 
 use self::schema::{Property, VacationResponse, Value};
 
+pub mod dedup;
 pub mod get;
 pub mod raft;
 pub mod schema;
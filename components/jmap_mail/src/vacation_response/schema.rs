@@ -42,6 +42,7 @@ pub enum Value {
     Bool { value: bool },
     DateTime { value: JMAPDate },
     Null,
+    TextList { value: Vec<String> },
 }
 
 impl Default for Value {
@@ -58,6 +59,7 @@ impl orm::Value for Value {
     fn is_empty(&self) -> bool {
         match self {
             Value::Text { value } => value.is_empty(),
+            Value::TextList { value } => value.is_empty(),
             Value::Null => true,
             _ => false,
         }
@@ -69,6 +71,7 @@ impl orm::Value for Value {
             Value::Text { value } => value.len(),
             Value::Bool { .. } => std::mem::size_of::<bool>(),
             Value::DateTime { .. } => std::mem::size_of::<JMAPDate>(),
+            Value::TextList { value } => value.iter().map(|v| v.len()).sum(),
             Value::Null => 0,
         }
     }
@@ -84,7 +87,8 @@ pub enum Property {
     Subject = 4,
     TextBody = 5,
     HtmlBody = 6,
-    Invalid = 7,
+    SuppressedSenders = 7,
+    Invalid = 8,
 }
 
 impl Property {
@@ -97,6 +101,7 @@ impl Property {
             "subject" => Property::Subject,
             "textBody" => Property::TextBody,
             "htmlBody" => Property::HtmlBody,
+            "suppressedSenders" => Property::SuppressedSenders,
             _ => Property::Invalid,
         }
     }
@@ -112,6 +117,7 @@ impl Display for Property {
             Property::Subject => write!(f, "subject"),
             Property::TextBody => write!(f, "textBody"),
             Property::HtmlBody => write!(f, "htmlBody"),
+            Property::SuppressedSenders => write!(f, "suppressedSenders"),
             Property::Invalid => Ok(()),
         }
     }
@@ -133,6 +139,7 @@ impl From<FieldId> for Property {
             4 => Property::Subject,
             5 => Property::TextBody,
             6 => Property::HtmlBody,
+            7 => Property::SuppressedSenders,
             _ => Property::Invalid,
         }
     }
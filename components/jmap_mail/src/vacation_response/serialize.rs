@@ -79,6 +79,7 @@ impl Serialize for VacationResponse {
                 Value::Null => map.serialize_entry(name, &None::<&str>)?,
                 Value::DateTime { value } => map.serialize_entry(name, value)?,
                 Value::Bool { value } => map.serialize_entry(name, value)?,
+                Value::TextList { value } => map.serialize_entry(name, value)?,
             }
         }
 
@@ -133,6 +134,16 @@ impl<'de> serde::de::Visitor<'de> for VacationResponseVisitor {
                         },
                     );
                 }
+                "suppressedSenders" => {
+                    properties.append(
+                        Property::SuppressedSenders,
+                        if let Some(value) = map.next_value::<Option<Vec<String>>>()? {
+                            Value::TextList { value }
+                        } else {
+                            Value::Null
+                        },
+                    );
+                }
                 "isEnabled" => {
                     properties.append(
                         Property::IsEnabled,
@@ -23,6 +23,7 @@
 
 use std::borrow::Cow;
 
+use crate::mail::template::{write_template_body, JMAPMailTemplate};
 use crate::vacation_response::schema::VacationResponse;
 use jmap::error::method::MethodError;
 use jmap::error::set::{SetError, SetErrorType};
@@ -42,6 +43,7 @@ use mail_builder::MessageBuilder;
 use mail_parser::decoders::html::html_to_text;
 use store::ahash::AHashMap;
 use store::blob::BlobId;
+use store::config::templates::TEMPLATE_VACATION;
 use store::core::collection::Collection;
 use store::core::document::Document;
 use store::core::error::StoreError;
@@ -225,6 +227,36 @@ where
                             .properties
                             .set(property, Value::Text { value });
                     }
+                    (Property::SuppressedSenders, Value::TextList { value })
+                        if value.len() <= 100 =>
+                    {
+                        let mut senders = Vec::with_capacity(value.len());
+                        for sender in &value {
+                            if let Some(sender) = parse_suppressed_sender(sender) {
+                                if !senders.contains(&sender) {
+                                    senders.push(sender);
+                                }
+                            } else {
+                                let error = SetError::invalid_properties()
+                                    .with_property(property)
+                                    .with_description(format!(
+                                        "Invalid address or domain {:?}.",
+                                        sender
+                                    ));
+
+                                if let Some(create_id) = create_id {
+                                    response.not_created.set(create_id, error);
+                                } else {
+                                    response.not_updated.set(JMAPId::singleton(), error);
+                                }
+
+                                return Ok(response);
+                            }
+                        }
+                        vacation_response
+                            .properties
+                            .set(property, Value::TextList { value: senders });
+                    }
                     (Property::ToDate | Property::FromDate, value @ Value::DateTime { .. })
                     | (Property::IsEnabled, value @ Value::Bool { .. }) => {
                         vacation_response.properties.set(property, value);
@@ -238,6 +270,7 @@ where
                         Property::Subject
                         | Property::HtmlBody
                         | Property::TextBody
+                        | Property::SuppressedSenders
                         | Property::ToDate
                         | Property::FromDate,
                         Value::Null,
@@ -278,7 +311,7 @@ where
                 .ok();
                 script.extend_from_slice(b"*/\r\n\r\n");
                 script.extend_from_slice(
-                    b"require [\"vacation\", \"relational\", \"date\"];\r\n\r\n",
+                    b"require [\"vacation\", \"relational\", \"date\", \"envelope\"];\r\n\r\n",
                 );
                 let mut num_blocks = 0;
 
@@ -302,24 +335,57 @@ where
                     num_blocks += 1;
                 }
 
+                // Never auto-reply to suppressed senders, automated and mailing list
+                // messages are filtered out at delivery time
+                if let Some(Value::TextList { value }) = vacation_response
+                    .properties
+                    .get(&Property::SuppressedSenders)
+                {
+                    let (addresses, domains): (Vec<_>, Vec<_>) =
+                        value.iter().partition(|sender| sender.contains('@'));
+                    let mut tests: Vec<Vec<u8>> = Vec::new();
+                    if !addresses.is_empty() {
+                        for test in [
+                            b"envelope :all :is \"from\" " as &[u8],
+                            b"address :all :is \"from\" ",
+                        ] {
+                            let mut line = test.to_vec();
+                            write_string_list(&mut line, addresses.iter().map(|a| a.as_str()));
+                            tests.push(line);
+                        }
+                    }
+                    if !domains.is_empty() {
+                        let subdomains = domains
+                            .iter()
+                            .map(|domain| format!("*.{}", domain))
+                            .collect::<Vec<_>>();
+                        for test in [b"envelope :domain" as &[u8], b"address :domain"] {
+                            let mut line = test.to_vec();
+                            line.extend_from_slice(b" :is \"from\" ");
+                            write_string_list(&mut line, domains.iter().map(|d| d.as_str()));
+                            tests.push(line);
+
+                            let mut line = test.to_vec();
+                            line.extend_from_slice(b" :matches \"from\" ");
+                            write_string_list(&mut line, subdomains.iter().map(|d| d.as_str()));
+                            tests.push(line);
+                        }
+                    }
+                    if !tests.is_empty() {
+                        script.extend_from_slice(b"if not anyof(");
+                        script.extend_from_slice(&tests.join(&b",\r\n    "[..]));
+                        script.extend_from_slice(b") {\r\n");
+                        num_blocks += 1;
+                    }
+                }
+
                 script.extend_from_slice(b"vacation :mime ");
                 if let Some(Value::Text { value }) =
                     vacation_response.properties.get(&Property::Subject)
                 {
-                    script.extend_from_slice(b":subject \"");
-                    for &ch in value.as_bytes().iter() {
-                        match ch {
-                            b'\\' | b'\"' => {
-                                script.push(b'\\');
-                            }
-                            b'\r' | b'\n' => {
-                                continue;
-                            }
-                            _ => (),
-                        }
-                        script.push(ch);
-                    }
-                    script.extend_from_slice(b"\" ");
+                    script.extend_from_slice(b":subject ");
+                    write_string(&mut script, value);
+                    script.push(b' ');
                 }
 
                 let mut text_body = if let Some(Value::Text { value }) =
@@ -336,28 +402,33 @@ where
                 } else {
                     None
                 };
-                match (&html_body, &text_body) {
-                    (Some(html_body), None) => {
+
+                let mut message_body;
+                if html_body.is_none() && text_body.is_none() {
+                    // Use the default vacation template
+                    let template = self
+                        .mail_template_render(account_id, TEMPLATE_VACATION, &[])?
+                        .unwrap_or_default();
+                    message_body = Vec::with_capacity(template.text.len() + 128);
+                    write_template_body(&template, &mut message_body);
+                } else {
+                    if let (Some(html_body), None) = (&html_body, &text_body) {
                         text_body = Cow::from(html_to_text(html_body.as_ref())).into();
                     }
-                    (None, None) => {
-                        text_body = Cow::from("I am away.").into();
-                    }
-                    _ => (),
-                }
 
-                let mut builder = MessageBuilder::new();
-                let mut body_len = 0;
-                if let Some(html_body) = html_body {
-                    body_len = html_body.len();
-                    builder = builder.html_body(html_body);
-                }
-                if let Some(text_body) = text_body {
-                    body_len += text_body.len();
-                    builder = builder.text_body(text_body);
+                    let mut builder = MessageBuilder::new();
+                    let mut body_len = 0;
+                    if let Some(html_body) = html_body {
+                        body_len = html_body.len();
+                        builder = builder.html_body(html_body);
+                    }
+                    if let Some(text_body) = text_body {
+                        body_len += text_body.len();
+                        builder = builder.text_body(text_body);
+                    }
+                    message_body = Vec::with_capacity(body_len + 128);
+                    builder.write_body(&mut message_body).ok();
                 }
-                let mut message_body = Vec::with_capacity(body_len + 128);
-                builder.write_body(&mut message_body).ok();
 
                 script.push(b'\"');
                 for ch in message_body {
@@ -479,3 +550,85 @@ where
         Ok(response)
     }
 }
+
+fn write_string(script: &mut Vec<u8>, value: &str) {
+    script.push(b'\"');
+    for &ch in value.as_bytes().iter() {
+        match ch {
+            b'\\' | b'\"' => {
+                script.push(b'\\');
+            }
+            b'\r' | b'\n' => {
+                continue;
+            }
+            _ => (),
+        }
+        script.push(ch);
+    }
+    script.push(b'\"');
+}
+
+fn write_string_list<'x>(script: &mut Vec<u8>, values: impl Iterator<Item = &'x str>) {
+    script.push(b'[');
+    for (pos, value) in values.enumerate() {
+        if pos > 0 {
+            script.extend_from_slice(b", ");
+        }
+        write_string(script, value);
+    }
+    script.push(b']');
+}
+
+/// Normalizes a suppression list entry, which is either an e-mail
+/// address or a domain name (optionally prefixed with '@').
+fn parse_suppressed_sender(value: &str) -> Option<String> {
+    let value = value.trim();
+    let value = value.strip_prefix('@').unwrap_or(value).to_lowercase();
+    let (local_part, domain) = value.rsplit_once('@').unwrap_or(("", value.as_str()));
+
+    if value.len() < 255
+        && !domain.is_empty()
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !domain.contains("..")
+        && domain
+            .chars()
+            .all(|ch| ch.is_alphanumeric() || ['.', '-', '_'].contains(&ch))
+        && !local_part.contains('@')
+        && local_part
+            .chars()
+            .all(|ch| !ch.is_whitespace() && !ch.is_control() && !['"', '\\'].contains(&ch))
+        && (!local_part.is_empty() || !value.contains('@'))
+    {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_suppressed_sender;
+
+    #[test]
+    fn suppressed_senders() {
+        for (value, expected) in [
+            ("Bill@Example.com", Some("bill@example.com")),
+            ("@example.org", Some("example.org")),
+            (" lists.example.org ", Some("lists.example.org")),
+            ("bill@", None),
+            ("@", None),
+            ("bill smith@example.com", None),
+            ("a@b@example.com", None),
+            ("example..org", None),
+            ("\"quoted\"@example.org", None),
+        ] {
+            assert_eq!(
+                parse_suppressed_sender(value).as_deref(),
+                expected,
+                "{}",
+                value
+            );
+        }
+    }
+}
@@ -30,6 +30,8 @@ use jmap::{
     SUPERUSER_ID,
 };
 use store::{
+    ahash::AHashSet,
+    config::jmap::GroupDelivery,
     core::{acl::ACLToken, collection::Collection, error::StoreError, JMAPIdPrefix},
     read::{
         comparator::Comparator,
@@ -40,9 +42,21 @@ use store::{
     AccountId, JMAPStore, RecipientType, Store,
 };
 
+use super::{
+    otp::{app_password_verify, JMAPAccountOtp},
+    subaddress::subaddress_split,
+};
+
 pub trait JMAPAccountStore {
     fn find_individual(&self, email: &str) -> store::Result<Option<AccountId>>;
+    fn is_local_domain(&self, domain: &str) -> store::Result<bool>;
     fn authenticate(&self, login: &str, password: &str) -> store::Result<Option<AccountId>>;
+    fn authenticate_interactive(
+        &self,
+        login: &str,
+        password: &str,
+        otp: Option<&str>,
+    ) -> store::Result<Option<AccountId>>;
     fn get_acl_token(&self, primary_id: AccountId) -> store::Result<Arc<ACLToken>>;
     fn get_account_details(
         &self,
@@ -50,8 +64,17 @@ pub trait JMAPAccountStore {
     ) -> store::Result<Option<(String, String, Type)>>;
     fn get_account_secret_hash(&self, account_id: AccountId) -> store::Result<Option<String>>;
     fn expand_rcpt(&self, email: String) -> store::Result<Arc<RecipientType>>;
+    fn expand_group(
+        &self,
+        account_id: AccountId,
+        depth: usize,
+        visited: &mut AHashSet<AccountId>,
+        list: &mut Vec<(AccountId, String)>,
+    ) -> store::Result<()>;
 }
 
+const MAX_GROUP_DEPTH: usize = 10;
+
 impl<T> JMAPAccountStore for JMAPStore<T>
 where
     T: for<'x> Store<'x> + 'static,
@@ -72,73 +95,31 @@ where
             .map(|id| id.get_document_id()))
     }
 
+    fn is_local_domain(&self, domain: &str) -> store::Result<bool> {
+        Ok(!self
+            .query_store::<FilterMapper>(
+                SUPERUSER_ID,
+                Collection::Principal,
+                Filter::and(vec![
+                    Filter::eq(Property::Name.into(), Query::Index(domain.to_string())),
+                    Filter::eq(Property::Type.into(), Query::Keyword("d".to_string())),
+                ]),
+                Comparator::None,
+            )?
+            .is_empty())
+    }
+
     fn authenticate(&self, login: &str, password: &str) -> store::Result<Option<AccountId>> {
-        if let Some(account_id) = self.find_individual(login)? {
-            if let Some(mut fields) = self.get_orm::<Principal>(SUPERUSER_ID, account_id)? {
-                if !matches!(
-                    fields.get(&Property::Type),
-                    Some(Value::Type {
-                        value: Type::Individual
-                    })
-                ) {
-                    debug!("Account {} is not an individual", JMAPId::from(account_id));
-                    return Ok(None);
-                }
-                if let (
-                    Some(Value::Text { value: email }),
-                    Some(Value::Text {
-                        value: password_hash,
-                    }),
-                ) = (
-                    fields.remove(&Property::Email),
-                    fields.remove(&Property::Secret),
-                ) {
-                    if email != login {
-                        debug!(
-                            "Login failed: Account {} has email {} but {} was used.",
-                            JMAPId::from(account_id),
-                            email,
-                            login
-                        );
-                        return Ok(None);
-                    }
+        authenticate_principal(self, login, password, None, true)
+    }
 
-                    if let Ok(matches) = argon2::verify_encoded(&password_hash, password.as_bytes())
-                    {
-                        if matches {
-                            Ok(Some(account_id))
-                        } else {
-                            debug!(
-                                "Login failed: Invalid password for account {}.",
-                                JMAPId::from(account_id)
-                            );
-                            Ok(None)
-                        }
-                    } else {
-                        debug!(
-                            "Login failed: Account {} has an invalid password hash.",
-                            JMAPId::from(account_id)
-                        );
-                        Ok(None)
-                    }
-                } else {
-                    debug!(
-                        "Account {} has no email or secret",
-                        JMAPId::from(account_id)
-                    );
-                    Ok(None)
-                }
-            } else {
-                debug!(
-                    "Login failed: ORM for account {} does not exist.",
-                    JMAPId::from(account_id)
-                );
-                Ok(None)
-            }
-        } else {
-            debug!("Login failed: Login '{}' not found.", login);
-            Ok(None)
-        }
+    fn authenticate_interactive(
+        &self,
+        login: &str,
+        password: &str,
+        otp: Option<&str>,
+    ) -> store::Result<Option<AccountId>> {
+        authenticate_principal(self, login, password, otp, false)
     }
 
     fn get_acl_token(&self, primary_id: AccountId) -> store::Result<Arc<ACLToken>> {
@@ -272,7 +253,7 @@ where
                             Collection::Principal,
                             Filter::or(vec![
                                 Filter::eq(Property::Email.into(), Query::Index(email.clone())),
-                                Filter::eq(Property::Aliases.into(), Query::Index(email)),
+                                Filter::eq(Property::Aliases.into(), Query::Index(email.clone())),
                             ]),
                             Comparator::None,
                         )?
@@ -306,6 +287,19 @@ where
                                     }
                                     RecipientType::NotFound
                                 }
+                                Some(Value::Type { value: Type::Group })
+                                    if self.config.group_delivery == GroupDelivery::Members =>
+                                {
+                                    let mut list = Vec::new();
+                                    let mut visited = AHashSet::from_iter([account_id]);
+                                    self.expand_group(account_id, 0, &mut visited, &mut list)?;
+                                    if !list.is_empty() {
+                                        RecipientType::List(list)
+                                    } else {
+                                        // Groups without members keep their own mailboxes
+                                        RecipientType::Individual(account_id)
+                                    }
+                                }
                                 _ => RecipientType::Individual(account_id),
                             }
                         } else {
@@ -315,6 +309,18 @@ where
                             );
                             RecipientType::NotFound
                         }
+                    } else if let Some((address, tag)) = self
+                        .config
+                        .mail_subaddress_separator
+                        .and_then(|separator| subaddress_split(&email, separator))
+                    {
+                        // Route sub-addresses to the base address
+                        match self.expand_rcpt(address)?.as_ref() {
+                            RecipientType::Individual(account_id) => {
+                                RecipientType::SubAddress(*account_id, tag)
+                            }
+                            rt => rt.clone(),
+                        }
                     } else {
                         RecipientType::NotFound
                     },
@@ -322,4 +328,156 @@ where
             })
             .map_err(|e| e.as_ref().clone())
     }
+
+    fn expand_group(
+        &self,
+        account_id: AccountId,
+        depth: usize,
+        visited: &mut AHashSet<AccountId>,
+        list: &mut Vec<(AccountId, String)>,
+    ) -> store::Result<()> {
+        let members = if let Some(Value::Members { value }) = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .and_then(|mut fields| fields.remove(&Property::Members))
+        {
+            value
+        } else {
+            return Ok(());
+        };
+
+        for id in members {
+            let member_id = id.get_document_id();
+            if !visited.insert(member_id) {
+                debug!(
+                    "Group {} expands to {} more than once, skipping.",
+                    JMAPId::from(account_id),
+                    id
+                );
+                continue;
+            }
+
+            match self.get_account_details(member_id)? {
+                Some((email, _, Type::Individual)) => {
+                    list.push((member_id, email));
+                }
+                Some((_, _, Type::Group | Type::List)) if depth < MAX_GROUP_DEPTH => {
+                    self.expand_group(member_id, depth + 1, visited, list)?;
+                }
+                Some((_, _, Type::Group | Type::List)) => {
+                    debug!(
+                        "Group {} exceeds the maximum nesting depth, skipping {}.",
+                        JMAPId::from(account_id),
+                        id
+                    );
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Verifies the credentials of an individual. When two-factor authentication
+/// is enabled the code is either provided separately or appended to the
+/// password after a '$' sign. App passwords bypass the second factor and are
+/// only accepted by non-interactive logins.
+fn authenticate_principal<T>(
+    store: &JMAPStore<T>,
+    login: &str,
+    password: &str,
+    otp: Option<&str>,
+    allow_app_passwords: bool,
+) -> store::Result<Option<AccountId>>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    if let Some(account_id) = store.find_individual(login)? {
+        if let Some(mut fields) = store.get_orm::<Principal>(SUPERUSER_ID, account_id)? {
+            if !matches!(
+                fields.get(&Property::Type),
+                Some(Value::Type {
+                    value: Type::Individual
+                })
+            ) {
+                debug!("Account {} is not an individual", JMAPId::from(account_id));
+                return Ok(None);
+            }
+            if let (
+                Some(Value::Text { value: email }),
+                Some(Value::Text {
+                    value: password_hash,
+                }),
+            ) = (
+                fields.remove(&Property::Email),
+                fields.remove(&Property::Secret),
+            ) {
+                if email != login {
+                    debug!(
+                        "Login failed: Account {} has email {} but {} was used.",
+                        JMAPId::from(account_id),
+                        email,
+                        login
+                    );
+                    return Ok(None);
+                }
+
+                if allow_app_passwords && app_password_verify(&fields, password) {
+                    return Ok(Some(account_id));
+                }
+
+                let has_otp = fields.get(&Property::OtpAuth).is_some();
+                let (password, otp) = match otp {
+                    Some(otp) => (password, Some(otp)),
+                    None if has_otp => password
+                        .rsplit_once('$')
+                        .map_or((password, None), |(password, otp)| (password, Some(otp))),
+                    None => (password, None),
+                };
+
+                if let Ok(matches) = argon2::verify_encoded(&password_hash, password.as_bytes()) {
+                    if matches && has_otp {
+                        if store.otp_verify(account_id, &fields, otp.unwrap_or_default())? {
+                            Ok(Some(account_id))
+                        } else {
+                            debug!(
+                                "Login failed: Missing or invalid 2FA code for account {}.",
+                                JMAPId::from(account_id)
+                            );
+                            Ok(None)
+                        }
+                    } else if matches {
+                        Ok(Some(account_id))
+                    } else {
+                        debug!(
+                            "Login failed: Invalid password for account {}.",
+                            JMAPId::from(account_id)
+                        );
+                        Ok(None)
+                    }
+                } else {
+                    debug!(
+                        "Login failed: Account {} has an invalid password hash.",
+                        JMAPId::from(account_id)
+                    );
+                    Ok(None)
+                }
+            } else {
+                debug!(
+                    "Account {} has no email or secret",
+                    JMAPId::from(account_id)
+                );
+                Ok(None)
+            }
+        } else {
+            debug!(
+                "Login failed: ORM for account {} does not exist.",
+                JMAPId::from(account_id)
+            );
+            Ok(None)
+        }
+    } else {
+        debug!("Login failed: Login '{}' not found.", login);
+        Ok(None)
+    }
 }
@@ -0,0 +1,345 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use jmap::{
+    error::set::{SetError, SetErrorType},
+    orm::{serialize::JMAPOrm, TinyORM},
+    principal::schema::{Forwarding, Principal, Property, Value},
+    sanitize_email,
+    types::jmap::JMAPId,
+    SUPERUSER_ID,
+};
+use serde::{Deserialize, Serialize};
+use store::{
+    core::{acl::ACLToken, collection::Collection, document::Document, vec_map::VecMap},
+    log::changes::ChangeId,
+    write::batch::WriteBatch,
+    AccountId, JMAPStore, Store,
+};
+
+use super::account::JMAPAccountStore;
+
+const SINGLETON_ID: &str = "singleton";
+
+#[derive(Debug, Deserialize)]
+pub struct ForwardingGetRequest {
+    #[serde(skip)]
+    pub acl: Option<Arc<ACLToken>>,
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    pub ids: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ForwardingGetResponse {
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    pub list: Vec<ForwardingObject>,
+    #[serde(rename = "notFound")]
+    pub not_found: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ForwardingObject {
+    pub id: String,
+    #[serde(flatten)]
+    pub details: Forwarding,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForwardingSetRequest {
+    #[serde(skip)]
+    pub acl: Option<Arc<ACLToken>>,
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    pub update: Option<VecMap<String, ForwardingUpdate>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForwardingUpdate {
+    #[serde(rename = "isEnabled")]
+    pub is_enabled: Option<bool>,
+    pub addresses: Option<Vec<String>>,
+    #[serde(rename = "keepCopy")]
+    pub keep_copy: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ForwardingSetResponse {
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    pub updated: Vec<String>,
+    #[serde(rename = "notUpdated")]
+    #[serde(skip_serializing_if = "VecMap::is_empty")]
+    pub not_updated: VecMap<String, SetError<Property>>,
+    #[serde(skip)]
+    pub change_id: Option<ChangeId>,
+}
+
+/// Forwarding instructions for an incoming message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardingDelivery {
+    pub email: String,
+    pub addresses: Vec<String>,
+    pub keep_copy: bool,
+}
+
+pub trait JMAPAccountForwarding<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn forwarding_get(&self, request: ForwardingGetRequest) -> jmap::Result<ForwardingGetResponse>;
+    fn forwarding_set(&self, request: ForwardingSetRequest) -> jmap::Result<ForwardingSetResponse>;
+    fn forwarding_deliver(
+        &self,
+        account_id: AccountId,
+    ) -> store::Result<Option<ForwardingDelivery>>;
+    fn forwarding_is_allowed(&self, account_email: &str, address: &str) -> store::Result<bool>;
+}
+
+impl<T> JMAPAccountForwarding<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn forwarding_get(&self, request: ForwardingGetRequest) -> jmap::Result<ForwardingGetResponse> {
+        let account_id = request.account_id.get_document_id();
+        let forwarding = forwarding_details(
+            &self
+                .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+                .unwrap_or_default(),
+        );
+        let mut response = ForwardingGetResponse {
+            account_id: request.account_id,
+            list: Vec::new(),
+            not_found: Vec::new(),
+        };
+
+        for id in request
+            .ids
+            .unwrap_or_else(|| vec![SINGLETON_ID.to_string()])
+        {
+            if id == SINGLETON_ID {
+                response.list.push(ForwardingObject {
+                    id,
+                    details: forwarding.clone(),
+                });
+            } else {
+                response.not_found.push(id);
+            }
+        }
+
+        Ok(response)
+    }
+
+    fn forwarding_set(&self, request: ForwardingSetRequest) -> jmap::Result<ForwardingSetResponse> {
+        let account_id = request.account_id.get_document_id();
+        let mut response = ForwardingSetResponse {
+            account_id: request.account_id,
+            updated: Vec::new(),
+            not_updated: VecMap::new(),
+            change_id: None,
+        };
+
+        let _lock = self.lock_collection(account_id, Collection::Principal);
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        let account_email = account_email(&fields);
+        let mut forwarding = forwarding_details(&fields);
+        let mut has_changes = false;
+
+        'outer: for (id, update) in request.update.unwrap_or_default() {
+            if id != SINGLETON_ID {
+                response
+                    .not_updated
+                    .append(id, SetError::new(SetErrorType::NotFound));
+                continue;
+            }
+
+            let mut new_forwarding = forwarding.clone();
+            if let Some(addresses) = update.addresses {
+                if addresses.len() > self.config.mail_forward_max_addresses {
+                    response.not_updated.append(
+                        id,
+                        SetError::invalid_properties().with_description(format!(
+                            "Too many forwarding addresses, maximum is {}.",
+                            self.config.mail_forward_max_addresses
+                        )),
+                    );
+                    continue;
+                }
+
+                new_forwarding.addresses = Vec::with_capacity(addresses.len());
+                for address in addresses {
+                    let address = if let Some(address) = sanitize_email(&address) {
+                        address
+                    } else {
+                        response.not_updated.append(
+                            id,
+                            SetError::invalid_properties().with_description(format!(
+                                "Invalid forwarding address {:?}.",
+                                address
+                            )),
+                        );
+                        continue 'outer;
+                    };
+
+                    if address == account_email {
+                        response.not_updated.append(
+                            id,
+                            SetError::invalid_properties().with_description(
+                                "Messages cannot be forwarded to the account's own address.",
+                            ),
+                        );
+                        continue 'outer;
+                    } else if !self.forwarding_is_allowed(&account_email, &address)? {
+                        response.not_updated.append(
+                            id,
+                            SetError::forbidden().with_description(format!(
+                                "Forwarding to external address {:?} is not allowed.",
+                                address
+                            )),
+                        );
+                        continue 'outer;
+                    }
+
+                    if !new_forwarding.addresses.contains(&address) {
+                        new_forwarding.addresses.push(address);
+                    }
+                }
+            }
+            if let Some(is_enabled) = update.is_enabled {
+                new_forwarding.is_enabled = is_enabled;
+            }
+            if let Some(keep_copy) = update.keep_copy {
+                new_forwarding.keep_copy = keep_copy;
+            }
+
+            if new_forwarding.is_enabled && new_forwarding.addresses.is_empty() {
+                response.not_updated.append(
+                    id,
+                    SetError::invalid_properties()
+                        .with_property(Property::Forwarding)
+                        .with_description("At least one forwarding address is required."),
+                );
+                continue;
+            }
+
+            has_changes |= new_forwarding != forwarding;
+            forwarding = new_forwarding;
+            response.updated.push(id);
+        }
+
+        if has_changes {
+            let mut changes = TinyORM::track_changes(&fields);
+            changes.set(
+                Property::Forwarding,
+                if forwarding != Forwarding::default() {
+                    Value::Forwarding { value: forwarding }
+                } else {
+                    Value::Null
+                },
+            );
+            let mut batch = WriteBatch::new(SUPERUSER_ID);
+            let mut document = Document::new(Collection::Principal, account_id);
+            fields.merge(&mut document, changes)?;
+            batch.update_document(document);
+            batch.log_update(Collection::Principal, account_id);
+            response.change_id = self.write(batch)?.map(|changes| changes.change_id);
+        }
+
+        Ok(response)
+    }
+
+    fn forwarding_deliver(
+        &self,
+        account_id: AccountId,
+    ) -> store::Result<Option<ForwardingDelivery>> {
+        let fields = if let Some(fields) = self.get_orm::<Principal>(SUPERUSER_ID, account_id)? {
+            fields
+        } else {
+            return Ok(None);
+        };
+        let forwarding = forwarding_details(&fields);
+        if !forwarding.is_enabled || forwarding.addresses.is_empty() {
+            return Ok(None);
+        }
+
+        // The domain policy might have changed since forwarding was configured
+        let email = account_email(&fields);
+        let mut addresses = Vec::with_capacity(forwarding.addresses.len());
+        for address in forwarding.addresses {
+            if self.forwarding_is_allowed(&email, &address)? {
+                addresses.push(address);
+            }
+        }
+
+        Ok(if !addresses.is_empty() {
+            Some(ForwardingDelivery {
+                email,
+                addresses,
+                keep_copy: forwarding.keep_copy,
+            })
+        } else {
+            None
+        })
+    }
+
+    fn forwarding_is_allowed(&self, account_email: &str, address: &str) -> store::Result<bool> {
+        // Without an SRS secret the envelope sender cannot be rewritten and
+        // forwarded messages would fail SPF checks, so only local domains are allowed.
+        let account_domain = account_email.rsplit_once('@').map_or("", |(_, d)| d);
+        if !self.config.srs_secret.is_empty()
+            && !self
+                .config
+                .mail_forward_disabled_domains
+                .iter()
+                .any(|domain| domain == account_domain)
+        {
+            return Ok(true);
+        }
+
+        match address.rsplit_once('@') {
+            Some((_, domain)) => self.is_local_domain(domain),
+            None => Ok(false),
+        }
+    }
+}
+
+fn forwarding_details(fields: &TinyORM<Principal>) -> Forwarding {
+    if let Some(Value::Forwarding { value }) = fields.get(&Property::Forwarding) {
+        value.clone()
+    } else {
+        Forwarding::default()
+    }
+}
+
+fn account_email(fields: &TinyORM<Principal>) -> String {
+    if let Some(Value::Text { value }) = fields.get(&Property::Email) {
+        value.to_lowercase()
+    } else {
+        String::new()
+    }
+}
@@ -46,6 +46,13 @@ where
     fn principal_get(&self, request: GetRequest<Principal>)
         -> jmap::Result<GetResponse<Principal>>;
     fn dkim_get(&self, domain_name: String) -> store::Result<Option<DKIM<'_>>>;
+    fn domain_key_get(&self, domain_name: &str) -> store::Result<Option<DomainKey>>;
+}
+
+pub struct DomainKey {
+    pub private_key: String,
+    pub selector: String,
+    pub expiration: Option<u64>,
 }
 
 impl<T> JMAPGetPrincipal<T> for JMAPStore<T>
@@ -81,7 +88,15 @@ where
                             Value::ACL(acl_get)
                         }
 
-                        Property::Secret => Value::Null,
+                        Property::Secret
+                        | Property::OtpAuth
+                        | Property::RecoveryCodes
+                        | Property::OtpLastStep
+                        | Property::AppPasswords
+                        | Property::Passkeys
+                        | Property::Onboarded
+                        | Property::SubAddresses
+                        | Property::Forwarding => Value::Null,
                         _ => fields.remove(property).unwrap_or_default(),
                     },
                 );
@@ -93,19 +108,34 @@ where
     }
 
     fn dkim_get(&self, domain_name: String) -> store::Result<Option<DKIM<'_>>> {
+        if let Some(key) = self.domain_key_get(&domain_name)? {
+            let mut dkim = DKIM::from_pkcs1_pem(&key.private_key)
+                .map_err(|err| StoreError::InternalError(format!("Failed to DKIM sign: {}", err)))?
+                .domain(domain_name)
+                .selector(key.selector);
+            if let Some(expiration) = key.expiration {
+                dkim = dkim.expiration(expiration);
+            }
+            Ok(Some(dkim))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn domain_key_get(&self, domain_name: &str) -> store::Result<Option<DomainKey>> {
         if let Some(domain_id) = self
             .query_store::<FilterMapper>(
                 SUPERUSER_ID,
                 Collection::Principal,
                 Filter::and(vec![
                     Filter::eq(Property::DKIM.into(), Query::Tag(Tag::Default)),
-                    Filter::eq(Property::Name.into(), Query::Index(domain_name.clone())),
+                    Filter::eq(Property::Name.into(), Query::Index(domain_name.to_string())),
                 ]),
                 Comparator::None,
             )?
             .next()
         {
-            if let Some((Value::Text { value: dkim }, dkim_settings)) = self
+            if let Some((Value::Text { value: private_key }, dkim_settings)) = self
                 .get_orm::<Principal>(SUPERUSER_ID, domain_id.get_document_id())?
                 .map(|mut p| {
                     (
@@ -114,23 +144,20 @@ where
                     )
                 })
             {
-                let mut dkim = DKIM::from_pkcs1_pem(&dkim)
-                    .map_err(|err| {
-                        StoreError::InternalError(format!("Failed to DKIM sign: {}", err))
-                    })?
-                    .domain(domain_name)
-                    .selector("default");
+                let mut key = DomainKey {
+                    private_key,
+                    selector: "default".to_string(),
+                    expiration: None,
+                };
 
                 if let Value::DKIM { value } = dkim_settings {
-                    if let Some(expiration) = value.dkim_expiration {
-                        dkim = dkim.expiration(expiration as u64);
-                    }
+                    key.expiration = value.dkim_expiration.map(|expiration| expiration as u64);
                     if let Some(selector) = value.dkim_selector {
-                        dkim = dkim.selector(selector);
+                        key.selector = selector;
                     }
                 }
 
-                return Ok(Some(dkim));
+                return Ok(Some(key));
             }
         }
 
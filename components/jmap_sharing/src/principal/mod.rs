@@ -28,9 +28,13 @@ use jmap::{
 use store::rand::{self, Rng};
 
 pub mod account;
+pub mod forwarding;
 pub mod get;
+pub mod otp;
+pub mod passkey;
 pub mod query;
 pub mod set;
+pub mod subaddress;
 
 pub trait CreateAccount: Sized {
     fn new_account(email: &str, secret: &str, name: &str) -> Self;
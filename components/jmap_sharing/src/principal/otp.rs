@@ -0,0 +1,537 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use hmac::{Hmac, Mac};
+use jmap::{
+    orm::{serialize::JMAPOrm, TinyORM},
+    principal::schema::{Principal, Property, Value},
+    SUPERUSER_ID,
+};
+use sha1::Sha1;
+use store::{
+    core::{collection::Collection, document::Document},
+    rand::{distributions::Alphanumeric, thread_rng, Rng},
+    sha2::{Digest, Sha256},
+    write::{batch::WriteBatch, ttl::now},
+    AccountId, JMAPStore, Store,
+};
+
+use super::passkey::passkeys;
+
+const TOTP_STEP: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+const TOTP_SKEW: u64 = 1;
+const TOTP_SECRET_LEN: usize = 20;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+const APP_PASSWORD_LEN: usize = 24;
+const MAX_APP_PASSWORDS: usize = 100;
+
+static BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct OtpStatus {
+    #[serde(rename = "totpEnabled")]
+    pub totp_enabled: bool,
+    #[serde(rename = "recoveryCodesLeft")]
+    pub recovery_codes_left: usize,
+    #[serde(rename = "appPasswords")]
+    pub app_passwords: Vec<String>,
+    pub passkeys: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OtpError {
+    InvalidCode,
+    AlreadyEnabled,
+    NotEnabled,
+    InvalidName,
+    TooManyAppPasswords,
+    TooManyPasskeys,
+    PasskeyExists,
+    InvalidPasskey,
+    NotFound,
+}
+
+pub type OtpResult<T> = store::Result<Result<T, OtpError>>;
+
+pub trait JMAPAccountOtp {
+    fn otp_status(&self, account_id: AccountId) -> store::Result<OtpStatus>;
+    fn otp_enable(&self, account_id: AccountId, secret: &str, code: &str)
+        -> OtpResult<Vec<String>>;
+    fn otp_disable(&self, account_id: AccountId, code: &str) -> OtpResult<()>;
+    fn otp_recovery_codes(&self, account_id: AccountId, code: &str) -> OtpResult<Vec<String>>;
+    fn app_password_create(
+        &self,
+        account_id: AccountId,
+        name: &str,
+        code: Option<&str>,
+    ) -> OtpResult<String>;
+    fn app_password_revoke(&self, account_id: AccountId, name: &str) -> OtpResult<()>;
+    fn otp_verify(
+        &self,
+        account_id: AccountId,
+        fields: &TinyORM<Principal>,
+        code: &str,
+    ) -> store::Result<bool>;
+    fn otp_update(
+        &self,
+        account_id: AccountId,
+        fields: TinyORM<Principal>,
+        changes: TinyORM<Principal>,
+    ) -> store::Result<()>;
+}
+
+impl<T> JMAPAccountOtp for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn otp_status(&self, account_id: AccountId) -> store::Result<OtpStatus> {
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        Ok(OtpStatus {
+            totp_enabled: fields.get(&Property::OtpAuth).is_some(),
+            recovery_codes_left: match fields.get(&Property::RecoveryCodes) {
+                Some(Value::TextList { value }) => value.len(),
+                _ => 0,
+            },
+            app_passwords: match fields.get(&Property::AppPasswords) {
+                Some(Value::TextList { value }) => value
+                    .iter()
+                    .filter_map(|entry| app_password_split(entry).map(|(name, _)| name.to_string()))
+                    .collect(),
+                _ => Vec::new(),
+            },
+            passkeys: passkeys(&fields).into_iter().map(|p| p.name).collect(),
+        })
+    }
+
+    fn otp_enable(
+        &self,
+        account_id: AccountId,
+        secret: &str,
+        code: &str,
+    ) -> OtpResult<Vec<String>> {
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        if fields.get(&Property::OtpAuth).is_some() {
+            return Ok(Err(OtpError::AlreadyEnabled));
+        }
+        let step = if let Some(step) = base32_decode(secret)
+            .filter(|secret| secret.len() >= TOTP_SECRET_LEN / 2)
+            .and_then(|secret| totp_verify(&secret, code, now()))
+        {
+            step
+        } else {
+            return Ok(Err(OtpError::InvalidCode));
+        };
+
+        let (codes, hashes) = generate_recovery_codes();
+        let mut changes = TinyORM::track_changes(&fields);
+        changes.set(
+            Property::OtpAuth,
+            Value::Text {
+                value: secret.to_ascii_uppercase(),
+            },
+        );
+        changes.set(Property::OtpLastStep, Value::Number { value: step as i64 });
+        changes.set(Property::RecoveryCodes, Value::TextList { value: hashes });
+        self.otp_update(account_id, fields, changes)?;
+
+        Ok(Ok(codes))
+    }
+
+    fn otp_disable(&self, account_id: AccountId, code: &str) -> OtpResult<()> {
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        if fields.get(&Property::OtpAuth).is_none() {
+            return Ok(Err(OtpError::NotEnabled));
+        } else if !self.otp_verify(account_id, &fields, code)? {
+            return Ok(Err(OtpError::InvalidCode));
+        }
+
+        // Reload the principal as a recovery code might have been consumed
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        let mut changes = TinyORM::track_changes(&fields);
+        changes.set(Property::OtpAuth, Value::Null);
+        changes.set(Property::RecoveryCodes, Value::Null);
+        self.otp_update(account_id, fields, changes)?;
+
+        Ok(Ok(()))
+    }
+
+    fn otp_recovery_codes(&self, account_id: AccountId, code: &str) -> OtpResult<Vec<String>> {
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        if fields.get(&Property::OtpAuth).is_none() {
+            return Ok(Err(OtpError::NotEnabled));
+        } else if !self.otp_verify(account_id, &fields, code)? {
+            return Ok(Err(OtpError::InvalidCode));
+        }
+
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        let (codes, hashes) = generate_recovery_codes();
+        let mut changes = TinyORM::track_changes(&fields);
+        changes.set(Property::RecoveryCodes, Value::TextList { value: hashes });
+        self.otp_update(account_id, fields, changes)?;
+
+        Ok(Ok(codes))
+    }
+
+    fn app_password_create(
+        &self,
+        account_id: AccountId,
+        name: &str,
+        code: Option<&str>,
+    ) -> OtpResult<String> {
+        let name = name.trim();
+        if name.is_empty() || name.len() > 255 || name.contains('$') {
+            return Ok(Err(OtpError::InvalidName));
+        }
+
+        // Creating app passwords requires a second factor when 2FA is enabled
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        if fields.get(&Property::OtpAuth).is_some()
+            && !self.otp_verify(account_id, &fields, code.unwrap_or_default())?
+        {
+            return Ok(Err(OtpError::InvalidCode));
+        }
+
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        let mut app_passwords = match fields.get(&Property::AppPasswords) {
+            Some(Value::TextList { value }) => value.clone(),
+            _ => Vec::new(),
+        };
+        if app_passwords
+            .iter()
+            .any(|entry| app_password_split(entry).map_or(false, |(n, _)| n == name))
+        {
+            return Ok(Err(OtpError::InvalidName));
+        } else if app_passwords.len() >= MAX_APP_PASSWORDS {
+            return Ok(Err(OtpError::TooManyAppPasswords));
+        }
+
+        let password = generate_token(APP_PASSWORD_LEN);
+        app_passwords.push(format!("{}${}", name, hash_token(&password)));
+        let mut changes = TinyORM::track_changes(&fields);
+        changes.set(
+            Property::AppPasswords,
+            Value::TextList {
+                value: app_passwords,
+            },
+        );
+        self.otp_update(account_id, fields, changes)?;
+
+        Ok(Ok(password))
+    }
+
+    fn app_password_revoke(&self, account_id: AccountId, name: &str) -> OtpResult<()> {
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        let mut app_passwords = match fields.get(&Property::AppPasswords) {
+            Some(Value::TextList { value }) => value.clone(),
+            _ => Vec::new(),
+        };
+        let num_passwords = app_passwords.len();
+        app_passwords.retain(|entry| app_password_split(entry).map_or(true, |(n, _)| n != name));
+        if app_passwords.len() == num_passwords {
+            return Ok(Err(OtpError::NotFound));
+        }
+
+        let mut changes = TinyORM::track_changes(&fields);
+        changes.set(
+            Property::AppPasswords,
+            if !app_passwords.is_empty() {
+                Value::TextList {
+                    value: app_passwords,
+                }
+            } else {
+                Value::Null
+            },
+        );
+        self.otp_update(account_id, fields, changes)?;
+
+        Ok(Ok(()))
+    }
+
+    fn otp_verify(
+        &self,
+        account_id: AccountId,
+        fields: &TinyORM<Principal>,
+        code: &str,
+    ) -> store::Result<bool> {
+        let code = code.trim();
+        if fields.get(&Property::OtpAuth).is_none() {
+            return Ok(false);
+        }
+
+        // Codes are checked and consumed on the stored principal while holding the
+        // lock, so that concurrent requests can't accept the same code twice.
+        let _lock = self.lock_collection(account_id, Collection::Principal);
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        let mut changes = TinyORM::track_changes(&fields);
+
+        let step = match fields.get(&Property::OtpAuth) {
+            Some(Value::Text { value: secret }) => {
+                base32_decode(secret).and_then(|secret| totp_verify(&secret, code, now()))
+            }
+            _ => return Ok(false),
+        };
+        if let Some(step) = step {
+            // TOTP codes can't be replayed, not even within the allowed skew
+            let last_step = match fields.get(&Property::OtpLastStep) {
+                Some(Value::Number { value }) => Some(*value as u64),
+                _ => None,
+            };
+            if last_step.map_or(false, |last_step| step <= last_step) {
+                return Ok(false);
+            }
+            changes.set(Property::OtpLastStep, Value::Number { value: step as i64 });
+        } else if let Some(Value::TextList { value: hashes }) = fields.get(&Property::RecoveryCodes)
+        {
+            // Recovery codes can only be used once
+            let code_hash = hash_token(&code.replace('-', "").to_ascii_lowercase());
+            if let Some(pos) = hashes.iter().position(|hash| hash == &code_hash) {
+                let mut hashes = hashes.clone();
+                hashes.swap_remove(pos);
+                changes.set(
+                    Property::RecoveryCodes,
+                    if !hashes.is_empty() {
+                        Value::TextList { value: hashes }
+                    } else {
+                        Value::Null
+                    },
+                );
+            } else {
+                return Ok(false);
+            }
+        } else {
+            return Ok(false);
+        }
+
+        self.otp_update(account_id, fields, changes)?;
+        Ok(true)
+    }
+
+    fn otp_update(
+        &self,
+        account_id: AccountId,
+        fields: TinyORM<Principal>,
+        changes: TinyORM<Principal>,
+    ) -> store::Result<()> {
+        let mut batch = WriteBatch::new(SUPERUSER_ID);
+        let mut document = Document::new(Collection::Principal, account_id);
+        fields.merge(&mut document, changes)?;
+        batch.update_document(document);
+        batch.log_update(Collection::Principal, account_id);
+        self.write(batch)?;
+        Ok(())
+    }
+}
+
+/// Splits an app password entry into its name and hash.
+pub fn app_password_split(entry: &str) -> Option<(&str, &str)> {
+    entry.rsplit_once('$')
+}
+
+/// Verifies an app password against the hashed entries of a principal.
+pub fn app_password_verify(fields: &TinyORM<Principal>, password: &str) -> bool {
+    if let Some(Value::TextList { value }) = fields.get(&Property::AppPasswords) {
+        let password_hash = hash_token(password);
+        value
+            .iter()
+            .any(|entry| app_password_split(entry).map_or(false, |(_, hash)| hash == password_hash))
+    } else {
+        false
+    }
+}
+
+/// Generates a new random TOTP secret, encoded in base32.
+pub fn totp_generate_secret() -> String {
+    base32_encode(&thread_rng().gen::<[u8; TOTP_SECRET_LEN]>())
+}
+
+/// Returns the provisioning URI to be encoded as a QR code by authenticator apps.
+pub fn totp_url(secret: &str, issuer: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer, account, secret, issuer, TOTP_DIGITS, TOTP_STEP
+    )
+}
+
+pub fn totp_code(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    code % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Returns the time step matched by a code, if any.
+pub fn totp_verify(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+    let code = code.trim();
+    let code = match code.parse::<u32>() {
+        Ok(value)
+            if code.len() == TOTP_DIGITS as usize && code.bytes().all(|c| c.is_ascii_digit()) =>
+        {
+            value
+        }
+        _ => return None,
+    };
+    let counter = now / TOTP_STEP;
+    (counter.saturating_sub(TOTP_SKEW)..=counter + TOTP_SKEW).fold(None, |acc, counter| {
+        if totp_code(secret, counter) == code {
+            Some(counter)
+        } else {
+            acc
+        }
+    })
+}
+
+fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let mut codes = Vec::with_capacity(RECOVERY_CODES);
+    let mut hashes = Vec::with_capacity(RECOVERY_CODES);
+    for _ in 0..RECOVERY_CODES {
+        let code = generate_token(RECOVERY_CODE_LEN).to_ascii_lowercase();
+        hashes.push(hash_token(&code));
+        codes.push(format!(
+            "{}-{}",
+            &code[..RECOVERY_CODE_LEN / 2],
+            &code[RECOVERY_CODE_LEN / 2..]
+        ));
+    }
+    (codes, hashes)
+}
+
+fn generate_token(len: usize) -> String {
+    thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+// Tokens are random and long enough for a plain SHA-256 hash to be sufficient
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity((bytes.len() * 8 + 4) / 5);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    result
+}
+
+pub fn base32_decode(value: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::with_capacity(value.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for ch in value.bytes().filter(|ch| !matches!(ch, b'=' | b' ')) {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&c| c == ch.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base32_decode, base32_encode, totp_code, totp_verify};
+
+    #[test]
+    fn totp() {
+        // RFC 6238 test vectors (SHA-1)
+        let secret = b"12345678901234567890";
+        for (time, code) in [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+        ] {
+            assert_eq!(totp_code(secret, time / 30), code % 1_000_000);
+        }
+
+        let code = format!("{:06}", totp_code(secret, 1234567890 / 30));
+        assert_eq!(
+            totp_verify(secret, &code, 1234567890),
+            Some(1234567890 / 30)
+        );
+        assert_eq!(
+            totp_verify(secret, &code, 1234567890 + 30),
+            Some(1234567890 / 30)
+        );
+        assert_eq!(totp_verify(secret, &code, 1234567890 + 90), None);
+        assert_eq!(totp_verify(secret, "abcdef", 1234567890), None);
+
+        let encoded = base32_encode(secret);
+        assert_eq!(encoded, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32_decode(&encoded).unwrap(), secret);
+        assert_eq!(
+            base32_decode(&encoded.to_lowercase()).unwrap(),
+            secret.to_vec()
+        );
+    }
+}
@@ -0,0 +1,307 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use jmap::{
+    orm::{serialize::JMAPOrm, TinyORM},
+    principal::schema::{Principal, Property, Type, Value},
+    types::jmap::JMAPId,
+    SUPERUSER_ID,
+};
+use store::{tracing::debug, AccountId, JMAPStore, Store};
+
+use super::otp::{JMAPAccountOtp, OtpError, OtpResult};
+
+const MAX_PASSKEYS: usize = 20;
+
+/// A WebAuthn credential registered by a principal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Passkey {
+    pub name: String,
+    pub credential_id: Vec<u8>,
+    pub algorithm: i64,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+pub trait JMAPAccountPasskey {
+    fn passkey_list(&self, account_id: AccountId) -> store::Result<Vec<Passkey>>;
+    fn passkey_add(
+        &self,
+        account_id: AccountId,
+        passkey: Passkey,
+        code: Option<&str>,
+    ) -> OtpResult<()>;
+    fn passkey_remove(&self, account_id: AccountId, name: &str) -> OtpResult<()>;
+    fn passkey_authenticate(
+        &self,
+        account_id: AccountId,
+        credential_id: &[u8],
+        verify: impl FnOnce(&Passkey) -> Option<u32>,
+    ) -> store::Result<Option<AccountId>>;
+}
+
+impl<T> JMAPAccountPasskey for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn passkey_list(&self, account_id: AccountId) -> store::Result<Vec<Passkey>> {
+        Ok(passkeys(
+            &self
+                .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+                .unwrap_or_default(),
+        ))
+    }
+
+    fn passkey_add(
+        &self,
+        account_id: AccountId,
+        passkey: Passkey,
+        code: Option<&str>,
+    ) -> OtpResult<()> {
+        let name = passkey.name.trim();
+        if name.is_empty() || name.len() > 255 || name.contains('$') {
+            return Ok(Err(OtpError::InvalidName));
+        }
+
+        // Registering passkeys requires a second factor when 2FA is enabled
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        if fields.get(&Property::OtpAuth).is_some()
+            && !self.otp_verify(account_id, &fields, code.unwrap_or_default())?
+        {
+            return Ok(Err(OtpError::InvalidCode));
+        }
+
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        let mut passkeys = passkeys(&fields);
+        if passkeys.iter().any(|p| p.name == name) {
+            return Ok(Err(OtpError::InvalidName));
+        } else if passkeys
+            .iter()
+            .any(|p| p.credential_id == passkey.credential_id)
+        {
+            return Ok(Err(OtpError::PasskeyExists));
+        } else if passkeys.len() >= MAX_PASSKEYS {
+            return Ok(Err(OtpError::TooManyPasskeys));
+        }
+
+        passkeys.push(Passkey {
+            name: name.to_string(),
+            ..passkey
+        });
+        self.passkey_update(account_id, fields, passkeys)?;
+
+        Ok(Ok(()))
+    }
+
+    fn passkey_remove(&self, account_id: AccountId, name: &str) -> OtpResult<()> {
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        let mut passkeys = passkeys(&fields);
+        let num_passkeys = passkeys.len();
+        passkeys.retain(|p| p.name != name);
+        if passkeys.len() == num_passkeys {
+            return Ok(Err(OtpError::NotFound));
+        }
+        self.passkey_update(account_id, fields, passkeys)?;
+
+        Ok(Ok(()))
+    }
+
+    fn passkey_authenticate(
+        &self,
+        account_id: AccountId,
+        credential_id: &[u8],
+        verify: impl FnOnce(&Passkey) -> Option<u32>,
+    ) -> store::Result<Option<AccountId>> {
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        if !matches!(
+            fields.get(&Property::Type),
+            Some(Value::Type {
+                value: Type::Individual
+            })
+        ) {
+            debug!("Account {} is not an individual", JMAPId::from(account_id));
+            return Ok(None);
+        }
+
+        let mut passkeys = passkeys(&fields);
+        let passkey = if let Some(passkey) = passkeys
+            .iter_mut()
+            .find(|p| p.credential_id == credential_id)
+        {
+            passkey
+        } else {
+            debug!(
+                "Login failed: Unknown passkey for account {}.",
+                JMAPId::from(account_id)
+            );
+            return Ok(None);
+        };
+        let sign_count = if let Some(sign_count) = verify(passkey) {
+            sign_count
+        } else {
+            debug!(
+                "Login failed: Invalid passkey signature for account {}.",
+                JMAPId::from(account_id)
+            );
+            return Ok(None);
+        };
+
+        // Authenticators without a counter always report zero, otherwise
+        // a counter that does not increase points to a cloned authenticator
+        if sign_count != 0 || passkey.sign_count != 0 {
+            if sign_count <= passkey.sign_count {
+                debug!(
+                    "Login failed: Passkey {:?} of account {} reported counter {} after {}.",
+                    passkey.name,
+                    JMAPId::from(account_id),
+                    sign_count,
+                    passkey.sign_count
+                );
+                return Ok(None);
+            }
+            passkey.sign_count = sign_count;
+            self.passkey_update(account_id, fields, passkeys)?;
+        }
+
+        Ok(Some(account_id))
+    }
+}
+
+trait JMAPAccountPasskeyUpdate {
+    fn passkey_update(
+        &self,
+        account_id: AccountId,
+        fields: TinyORM<Principal>,
+        passkeys: Vec<Passkey>,
+    ) -> store::Result<()>;
+}
+
+impl<T> JMAPAccountPasskeyUpdate for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn passkey_update(
+        &self,
+        account_id: AccountId,
+        fields: TinyORM<Principal>,
+        passkeys: Vec<Passkey>,
+    ) -> store::Result<()> {
+        let mut changes = TinyORM::track_changes(&fields);
+        changes.set(
+            Property::Passkeys,
+            if !passkeys.is_empty() {
+                Value::TextList {
+                    value: passkeys.iter().map(|p| p.to_entry()).collect(),
+                }
+            } else {
+                Value::Null
+            },
+        );
+        self.otp_update(account_id, fields, changes)
+    }
+}
+
+impl Passkey {
+    /// Parses a stored entry, formatted as `name$algorithm$counter$id$key`
+    /// with the credential id and public key encoded in hex.
+    pub fn parse(entry: &str) -> Option<Self> {
+        let mut parts = entry.rsplitn(5, '$');
+        let public_key = hex_decode(parts.next()?)?;
+        let credential_id = hex_decode(parts.next()?)?;
+        let sign_count = parts.next()?.parse().ok()?;
+        let algorithm = parts.next()?.parse().ok()?;
+        Some(Passkey {
+            name: parts.next()?.to_string(),
+            credential_id,
+            algorithm,
+            public_key,
+            sign_count,
+        })
+    }
+
+    pub fn to_entry(&self) -> String {
+        format!(
+            "{}${}${}${}${}",
+            self.name,
+            self.algorithm,
+            self.sign_count,
+            hex_encode(&self.credential_id),
+            hex_encode(&self.public_key)
+        )
+    }
+}
+
+/// Returns the passkeys registered by a principal.
+pub fn passkeys(fields: &TinyORM<Principal>) -> Vec<Passkey> {
+    match fields.get(&Property::Passkeys) {
+        Some(Value::TextList { value }) => value
+            .iter()
+            .filter_map(|entry| Passkey::parse(entry))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 == 0 {
+        (0..value.len())
+            .step_by(2)
+            .map(|pos| u8::from_str_radix(value.get(pos..pos + 2)?, 16).ok())
+            .collect()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Passkey;
+
+    #[test]
+    fn passkey_entry() {
+        let passkey = Passkey {
+            name: "Laptop".to_string(),
+            credential_id: vec![0, 1, 2, 0xfe, 0xff],
+            algorithm: -7,
+            public_key: vec![4; 65],
+            sign_count: 42,
+        };
+        let entry = passkey.to_entry();
+        assert!(entry.starts_with("Laptop$-7$42$000102feff$0404"));
+        assert_eq!(Passkey::parse(&entry), Some(passkey));
+        assert_eq!(Passkey::parse("Laptop$-7$42$0001$0g"), None);
+        assert_eq!(Passkey::parse("Laptop$-7$42$000"), None);
+    }
+}
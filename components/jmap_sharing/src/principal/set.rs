@@ -21,6 +21,7 @@
  * for more details.
 */
 
+use super::account::JMAPAccountStore;
 use jmap::error::set::{SetError, SetErrorType};
 use jmap::jmap_store::set::SetHelper;
 use jmap::jmap_store::Object;
@@ -32,6 +33,7 @@ use jmap::request::set::SetRequest;
 use jmap::request::set::SetResponse;
 use jmap::types::jmap::JMAPId;
 use jmap::{sanitize_domain, sanitize_email, SUPERUSER_ID};
+use jmap_mail::mail::retention::JMAPMailRetention;
 use jmap_mail::mail_send::dkim::DKIM;
 use jmap_mail::mailbox::schema::Mailbox;
 use jmap_mail::mailbox::CreateMailbox;
@@ -44,6 +46,8 @@ use store::rand::Rng;
 use store::read::comparator::Comparator;
 use store::read::filter::{self, Filter, Query};
 use store::read::FilterMapper;
+use store::roaring::RoaringBitmap;
+use store::tracing::info;
 use store::write::batch::WriteBatch;
 use store::write::options::IndexOptions;
 use store::{rand, DocumentId, JMAPStore, Store};
@@ -61,7 +65,8 @@ where
         document: &mut Document,
     ) -> store::Result<()>;
 
-    fn principal_purge(&self) -> store::Result<()>;
+    fn principal_purge(&self) -> store::Result<RoaringBitmap>;
+    fn principal_delete_annotations(&self, document: &mut Document) -> store::Result<()>;
 }
 
 impl<T> JMAPSetPrincipal<T> for JMAPStore<T>
@@ -160,6 +165,16 @@ where
             ) {
                 helper.store.recipients.invalidate(email);
             }
+            if matches!(
+                current_fields.get(&Property::Type),
+                Some(Value::Type {
+                    value: Type::Group | Type::List
+                })
+            ) && fields.get(&Property::Members) != current_fields.get(&Property::Members)
+            {
+                // Groups can be nested, expire all expanded recipients
+                helper.store.recipients.invalidate_all();
+            }
 
             // Merge changes
             current_fields.merge_validate(document, fields)?;
@@ -176,6 +191,29 @@ where
             }
 
             if let Some(fields) = self.get_orm::<Principal>(SUPERUSER_ID, document.document_id)? {
+                // Accounts under legal hold cannot be deleted
+                if let Some(Value::Bool { value: true }) = fields.get(&Property::LegalHold) {
+                    info!(
+                        target: "audit",
+                        "Blocked deletion of account {} under legal hold.",
+                        document.document_id
+                    );
+                    return Err(SetError::forbidden()
+                        .with_description("Account is under legal hold and cannot be deleted."));
+                }
+
+                // So are accounts with mailboxes under legal hold
+                if !self.mailbox_holds(document.document_id)?.is_empty() {
+                    info!(
+                        target: "audit",
+                        "Blocked deletion of account {} with mailboxes under legal hold.",
+                        document.document_id
+                    );
+                    return Err(SetError::forbidden().with_description(
+                        "Account has mailboxes under legal hold and cannot be deleted.",
+                    ));
+                }
+
                 // Remove member from all principals
                 for document_id in self
                     .query_store::<FilterMapper>(
@@ -213,6 +251,7 @@ where
                             );
                             let mut document = Document::new(Collection::Principal, document_id);
                             fields.merge(&mut document, new_fields)?;
+                            helper.store.recipients.invalidate_all();
                             helper.changes.update_document(document);
                             helper
                                 .changes
@@ -235,6 +274,7 @@ where
                 }
                 helper.store.acl_tokens.invalidate(&document.document_id);
                 fields.delete(document);
+                self.principal_delete_annotations(document)?;
             }
             Ok(())
         })?;
@@ -256,6 +296,7 @@ where
                 ))
             })?
             .delete(document);
+        self.principal_delete_annotations(document)?;
 
         // Tag account for deletion
         let mut tag_deletion = Document::new(Collection::Principal, document.document_id);
@@ -269,13 +310,38 @@ where
         Ok(())
     }
 
-    fn principal_purge(&self) -> store::Result<()> {
-        if let Some(accounts_to_delete) = self.get_tag(
+    /// Removes the annotations stored on a principal, such as the
+    /// migrations into its account.
+    fn principal_delete_annotations(&self, document: &mut Document) -> store::Result<()> {
+        for (principal_id, _) in
+            self.get_annotations(SUPERUSER_ID, Collection::Principal, document.document_id)?
+        {
+            document.annotation(principal_id, Vec::new(), IndexOptions::new().clear());
+        }
+        Ok(())
+    }
+
+    fn principal_purge(&self) -> store::Result<RoaringBitmap> {
+        if let Some(mut accounts_to_delete) = self.get_tag(
             SUPERUSER_ID,
             Collection::Principal,
             ACCOUNTS_TO_DELETE,
             Tag::Static(ACCOUNTS_TO_DELETE),
         )? {
+            // Accounts holding messages under legal hold are kept until the hold is lifted
+            for account_id in accounts_to_delete.clone() {
+                if !self.mailbox_holds(account_id)?.is_empty() {
+                    info!(
+                        target: "audit",
+                        "Postponed purge of account {} with mailboxes under legal hold.",
+                        account_id
+                    );
+                    accounts_to_delete.remove(account_id);
+                }
+            }
+            if accounts_to_delete.is_empty() {
+                return Ok(accounts_to_delete);
+            }
             self.delete_accounts(&accounts_to_delete)?;
             self.untag(
                 SUPERUSER_ID,
@@ -284,9 +350,10 @@ where
                 Tag::Static(ACCOUNTS_TO_DELETE),
                 accounts_to_delete.iter(),
             )?;
+            Ok(accounts_to_delete)
+        } else {
+            Ok(RoaringBitmap::new())
         }
-
-        Ok(())
     }
 }
 
@@ -397,11 +464,10 @@ where
                     }
                 }
 
-                (Property::Timezone, value @ (Value::Text { .. } | Value::Null))
-                    if ![Type::Domain, Type::List].contains(&ptype) =>
-                {
-                    value
-                }
+                (
+                    Property::Timezone | Property::Locale,
+                    value @ (Value::Text { .. } | Value::Null),
+                ) if ![Type::Domain, Type::List].contains(&ptype) => value,
 
                 (Property::Capabilities, value @ (Value::TextList { .. } | Value::Null))
                     if ![Type::Domain, Type::List].contains(&ptype) =>
@@ -503,6 +569,20 @@ where
                     Value::Null
                 }
 
+                (Property::OtpAuth, Value::Null) if ptype == Type::Individual => {
+                    // Disabling two-factor authentication also voids the recovery codes
+                    self.set(Property::RecoveryCodes, Value::Null);
+                    Value::Null
+                }
+
+                (Property::AppPasswords, Value::Null) if ptype == Type::Individual => Value::Null,
+
+                // Lets administrators reset the passkeys of a principal that lost them
+                (Property::Passkeys, Value::Null) if ptype == Type::Individual => Value::Null,
+
+                // Clearing the completed onboarding actions runs them again on next login
+                (Property::Onboarded, Value::Null) if ptype == Type::Individual => Value::Null,
+
                 (Property::ACL, Value::Patch(Patch::ACL(value))) => {
                     for acl_update in &value {
                         match acl_update {
@@ -539,6 +619,20 @@ where
 
                 (Property::Quota, value @ (Value::Number { .. } | Value::Null)) => value,
 
+                (
+                    Property::ExpungeTrashDays | Property::ExpungeJunkDays,
+                    value @ (Value::Number { .. } | Value::Null),
+                ) if matches!(ptype, Type::Individual | Type::Domain) => value,
+
+                (Property::LegalHold, Value::Bool { value: true }) if ptype == Type::Individual => {
+                    Value::Bool { value: true }
+                }
+                (Property::LegalHold, Value::Bool { value: false } | Value::Null)
+                    if ptype == Type::Individual =>
+                {
+                    Value::Null
+                }
+
                 (Property::Picture, value @ (Value::Blob { .. } | Value::Null)) => value,
 
                 (Property::Members, Value::Members { value }) if ptype == Type::Group => {
@@ -566,6 +660,18 @@ where
                             return Err(SetError::invalid_properties()
                                 .with_property(property)
                                 .with_description("Cannot add a principal as its member."));
+                        } else if helper
+                            .store
+                            .get_acl_token(document_id)?
+                            .member_of
+                            .contains(&account_id)
+                        {
+                            return Err(SetError::invalid_properties()
+                                .with_property(property)
+                                .with_description(format!(
+                                    "Principal '{}' already contains this group.",
+                                    id
+                                )));
                         } else if helper.document_ids.contains(account_id) {
                             if current_members.as_ref().map_or(true, |l| !l.contains(id)) {
                                 helper.store.acl_tokens.invalidate(&account_id);
@@ -602,6 +708,18 @@ where
                                         .with_description(
                                             "Cannot add a principal as its member.",
                                         ));
+                                } else if helper
+                                    .store
+                                    .get_acl_token(document_id)?
+                                    .member_of
+                                    .contains(&account_id)
+                                {
+                                    return Err(SetError::invalid_properties()
+                                        .with_property(property)
+                                        .with_description(format!(
+                                            "Principal '{}' already contains this group.",
+                                            id
+                                        )));
                                 } else if helper.document_ids.contains(account_id) {
                                     members.push(id);
                                     helper.store.acl_tokens.invalidate(&account_id);
@@ -0,0 +1,324 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use jmap::{
+    error::set::{SetError, SetErrorType},
+    orm::{serialize::JMAPOrm, TinyORM},
+    principal::schema::{Principal, Property, SubAddress, Value},
+    types::jmap::JMAPId,
+    SUPERUSER_ID,
+};
+use serde::{Deserialize, Serialize};
+use store::{
+    core::{acl::ACLToken, collection::Collection, document::Document, vec_map::VecMap},
+    log::changes::ChangeId,
+    write::batch::WriteBatch,
+    AccountId, JMAPStore, Store,
+};
+
+const MAX_SUB_ADDRESSES: usize = 1000;
+const MAX_TAG_LEN: usize = 255;
+
+#[derive(Debug, Deserialize)]
+pub struct SubAddressGetRequest {
+    #[serde(skip)]
+    pub acl: Option<Arc<ACLToken>>,
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    pub ids: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubAddressGetResponse {
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    pub list: Vec<SubAddressObject>,
+    #[serde(rename = "notFound")]
+    pub not_found: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubAddressObject {
+    pub id: String,
+    #[serde(flatten)]
+    pub details: SubAddress,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubAddressSetRequest {
+    #[serde(skip)]
+    pub acl: Option<Arc<ACLToken>>,
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    pub update: Option<VecMap<String, SubAddressUpdate>>,
+    pub destroy: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubAddressUpdate {
+    #[serde(rename = "isDisabled")]
+    pub is_disabled: Option<bool>,
+    #[serde(rename = "autoFile")]
+    pub auto_file: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubAddressSetResponse {
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    pub updated: Vec<String>,
+    pub destroyed: Vec<String>,
+    #[serde(rename = "notUpdated")]
+    #[serde(skip_serializing_if = "VecMap::is_empty")]
+    pub not_updated: VecMap<String, SetError<Property>>,
+    #[serde(rename = "notDestroyed")]
+    #[serde(skip_serializing_if = "VecMap::is_empty")]
+    pub not_destroyed: VecMap<String, SetError<Property>>,
+    #[serde(skip)]
+    pub change_id: Option<ChangeId>,
+}
+
+/// Outcome of delivering a message to a sub-address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubAddressDelivery {
+    Disabled,
+    Inbox,
+    AutoFile,
+}
+
+pub trait JMAPAccountSubAddress<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn subaddress_get(&self, request: SubAddressGetRequest) -> jmap::Result<SubAddressGetResponse>;
+    fn subaddress_set(&self, request: SubAddressSetRequest) -> jmap::Result<SubAddressSetResponse>;
+    fn subaddress_deliver(
+        &self,
+        account_id: AccountId,
+        tag: &str,
+    ) -> store::Result<SubAddressDelivery>;
+}
+
+impl<T> JMAPAccountSubAddress<T> for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn subaddress_get(&self, request: SubAddressGetRequest) -> jmap::Result<SubAddressGetResponse> {
+        let account_id = request.account_id.get_document_id();
+        let mut sub_addresses = subaddress_list(
+            &self
+                .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+                .unwrap_or_default(),
+        );
+        let mut response = SubAddressGetResponse {
+            account_id: request.account_id,
+            list: Vec::new(),
+            not_found: Vec::new(),
+        };
+
+        if let Some(ids) = request.ids {
+            for id in ids {
+                if let Some(details) = sub_addresses.remove(&id.to_lowercase()) {
+                    response.list.push(SubAddressObject { id, details });
+                } else {
+                    response.not_found.push(id);
+                }
+            }
+        } else {
+            response.list = sub_addresses
+                .into_iter()
+                .map(|(id, details)| SubAddressObject { id, details })
+                .collect();
+        }
+
+        Ok(response)
+    }
+
+    fn subaddress_set(&self, request: SubAddressSetRequest) -> jmap::Result<SubAddressSetResponse> {
+        let account_id = request.account_id.get_document_id();
+        let mut response = SubAddressSetResponse {
+            account_id: request.account_id,
+            updated: Vec::new(),
+            destroyed: Vec::new(),
+            not_updated: VecMap::new(),
+            not_destroyed: VecMap::new(),
+            change_id: None,
+        };
+
+        let _lock = self.lock_collection(account_id, Collection::Principal);
+        let fields = self
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+            .unwrap_or_default();
+        let mut sub_addresses = subaddress_list(&fields);
+
+        for (tag, update) in request.update.unwrap_or_default() {
+            let tag_ = tag.to_lowercase();
+            if tag_.is_empty()
+                || tag_.len() > MAX_TAG_LEN
+                || tag_.contains(|ch: char| ch == '@' || ch.is_whitespace())
+            {
+                response.not_updated.append(
+                    tag,
+                    SetError::invalid_properties().with_description("Invalid sub-address tag."),
+                );
+                continue;
+            } else if sub_addresses.get(&tag_).is_none() && sub_addresses.len() >= MAX_SUB_ADDRESSES
+            {
+                response.not_updated.append(
+                    tag,
+                    SetError::invalid_properties().with_description(format!(
+                        "Too many sub-addresses, maximum is {}.",
+                        MAX_SUB_ADDRESSES
+                    )),
+                );
+                continue;
+            }
+
+            let sub_address = sub_addresses.get_mut_or_insert(tag_);
+            if let Some(is_disabled) = update.is_disabled {
+                sub_address.is_disabled = is_disabled;
+            }
+            if let Some(auto_file) = update.auto_file {
+                sub_address.auto_file = auto_file.into();
+            }
+            response.updated.push(tag);
+        }
+
+        for tag in request.destroy.unwrap_or_default() {
+            if sub_addresses.remove(&tag.to_lowercase()).is_some() {
+                response.destroyed.push(tag);
+            } else {
+                response
+                    .not_destroyed
+                    .append(tag, SetError::new(SetErrorType::NotFound));
+            }
+        }
+
+        if !response.updated.is_empty() || !response.destroyed.is_empty() {
+            response.change_id = subaddress_update(self, account_id, fields, sub_addresses)?;
+        }
+
+        Ok(response)
+    }
+
+    fn subaddress_deliver(
+        &self,
+        account_id: AccountId,
+        tag: &str,
+    ) -> store::Result<SubAddressDelivery> {
+        // Only sub-addresses registered by the account are auto-filed, which keeps
+        // senders from creating mailboxes or growing the list at will.
+        Ok(
+            match subaddress_list(
+                &self
+                    .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+                    .unwrap_or_default(),
+            )
+            .remove(&tag.to_lowercase())
+            {
+                Some(sub_address) if sub_address.is_disabled => SubAddressDelivery::Disabled,
+                Some(sub_address)
+                    if sub_address
+                        .auto_file
+                        .unwrap_or(self.config.mail_subaddress_auto_file) =>
+                {
+                    SubAddressDelivery::AutoFile
+                }
+                _ => SubAddressDelivery::Inbox,
+            },
+        )
+    }
+}
+
+/// Splits a sub-address such as `user+tag@domain` into its base address and tag.
+pub fn subaddress_split(email: &str, separator: char) -> Option<(String, String)> {
+    let (local_part, domain) = email.rsplit_once('@')?;
+    let (user, tag) = local_part.split_once(separator)?;
+    if !user.is_empty() && !tag.is_empty() && tag.len() <= MAX_TAG_LEN {
+        Some((format!("{}@{}", user, domain), tag.to_string()))
+    } else {
+        None
+    }
+}
+
+fn subaddress_list(fields: &TinyORM<Principal>) -> VecMap<String, SubAddress> {
+    if let Some(Value::SubAddresses { value }) = fields.get(&Property::SubAddresses) {
+        value.clone()
+    } else {
+        VecMap::new()
+    }
+}
+
+fn subaddress_update<T>(
+    store: &JMAPStore<T>,
+    account_id: AccountId,
+    fields: TinyORM<Principal>,
+    sub_addresses: VecMap<String, SubAddress>,
+) -> store::Result<Option<ChangeId>>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let mut changes = TinyORM::track_changes(&fields);
+    changes.set(
+        Property::SubAddresses,
+        if !sub_addresses.is_empty() {
+            Value::SubAddresses {
+                value: sub_addresses,
+            }
+        } else {
+            Value::Null
+        },
+    );
+    let mut batch = WriteBatch::new(SUPERUSER_ID);
+    let mut document = Document::new(Collection::Principal, account_id);
+    fields.merge(&mut document, changes)?;
+    batch.update_document(document);
+    batch.log_update(Collection::Principal, account_id);
+    Ok(store.write(batch)?.map(|changes| changes.change_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::subaddress_split;
+
+    #[test]
+    fn split_subaddress() {
+        assert_eq!(
+            subaddress_split("jdoe+newsletters@example.org", '+'),
+            Some(("jdoe@example.org".to_string(), "newsletters".to_string()))
+        );
+        assert_eq!(
+            subaddress_split("jdoe+a+b@example.org", '+'),
+            Some(("jdoe@example.org".to_string(), "a+b".to_string()))
+        );
+        assert_eq!(subaddress_split("jdoe@example.org", '+'), None);
+        assert_eq!(subaddress_split("+tag@example.org", '+'), None);
+        assert_eq!(subaddress_split("jdoe+@example.org", '+'), None);
+        assert_eq!(
+            subaddress_split("jdoe-shop@example.org", '-'),
+            Some(("jdoe@example.org".to_string(), "shop".to_string()))
+        );
+    }
+}
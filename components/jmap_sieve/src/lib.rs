@@ -59,6 +59,14 @@ impl SeenIdHash {
             expiry,
         }
     }
+
+    pub fn hash(&self) -> &[u8; 32] {
+        &self.hash
+    }
+
+    pub fn expiry(&self) -> u64 {
+        self.expiry
+    }
 }
 
 impl PartialOrd for SeenIdHash {
@@ -0,0 +1,251 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use ahash::AHashMap;
+use tracing::{debug, info};
+
+use crate::{
+    blob::{local::local_blob_path, BlobId, BLOB_EXTERNAL, BLOB_HASH_LEN},
+    config::env_settings::EnvSettings,
+    log::raft::{prev_raft_id, LogIndex, RaftId, TermId},
+    serialize::StoreDeserialize,
+    ColumnFamily, Direction, JMAPStore, Store, StoreError,
+};
+
+pub const BACKUP_BLOBS_DIR: &str = "blobs";
+pub const BACKUP_SNAPSHOTS_DIR: &str = "snapshots";
+pub const BACKUP_INDEX_DIR: &str = "idx";
+pub const BACKUP_MANIFEST: &str = "manifest";
+
+#[derive(Debug, Clone)]
+pub struct BackupInfo {
+    pub path: PathBuf,
+    pub raft_id: RaftId,
+    pub created_at: u64,
+    pub blobs_copied: usize,
+    pub blobs_total: usize,
+}
+
+impl<T> JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Creates an online snapshot of the database and copies any external blobs
+    /// that are not yet present in the backup directory. Blobs are content addressed,
+    /// which makes consecutive backups to the same directory incremental.
+    pub fn backup(&self, backup_path: &Path) -> crate::Result<BackupInfo> {
+        let created_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        // Block blob purges until the backup is complete
+        let _purge_lock = self.blob_store.purge_lock.lock();
+
+        // Create database checkpoint
+        let mut snapshot_path = backup_path.to_path_buf();
+        snapshot_path.push(BACKUP_SNAPSHOTS_DIR);
+        snapshot_path.push(format!("{}", created_at));
+        if snapshot_path.exists() {
+            return Err(StoreError::InternalError(format!(
+                "Backup snapshot {} already exists.",
+                snapshot_path.display()
+            )));
+        }
+        fs::create_dir_all(&snapshot_path)?;
+        let mut index_path = snapshot_path.clone();
+        index_path.push(BACKUP_INDEX_DIR);
+        self.db.checkpoint(&index_path)?;
+
+        // Obtain the last Raft id from the checkpoint itself, reading it from the live
+        // database would race with entries appended while the checkpoint is created.
+        let raft_id = {
+            let settings = EnvSettings {
+                args: AHashMap::from_iter([(
+                    "db-path".to_string(),
+                    snapshot_path.to_string_lossy().into_owned(),
+                )]),
+            };
+            let checkpoint = T::open(&settings)?;
+            let raft_id = prev_raft_id(&checkpoint, RaftId::new(TermId::MAX, LogIndex::MAX))?
+                .unwrap_or_else(RaftId::none);
+            checkpoint.close()?;
+            raft_id
+        };
+
+        // Copy external blobs
+        let mut blobs_path = backup_path.to_path_buf();
+        blobs_path.push(BACKUP_BLOBS_DIR);
+        let mut blobs_copied = 0;
+        let mut blobs_total = 0;
+
+        for (key, _) in
+            self.db
+                .iterator(ColumnFamily::Blobs, &[BLOB_EXTERNAL], Direction::Forward)?
+        {
+            if key.first() != Some(&BLOB_EXTERNAL) {
+                break;
+            } else if key.len() != BLOB_HASH_LEN + 1 {
+                continue;
+            }
+            let blob_id = BlobId::deserialize(&key).ok_or_else(|| {
+                StoreError::DataCorruption(format!("Failed to deserialize blob key {:?}", key))
+            })?;
+
+            blobs_total += 1;
+            if self.blob_store.copy_to(&blob_id, &blobs_path)? {
+                blobs_copied += 1;
+            }
+        }
+
+        // Write manifest
+        let mut manifest_path = snapshot_path.clone();
+        manifest_path.push(BACKUP_MANIFEST);
+        fs::write(
+            &manifest_path,
+            format!(
+                "created-at: {}\nraft-term: {}\nraft-index: {}\nblobs-total: {}\n",
+                created_at, raft_id.term, raft_id.index, blobs_total
+            ),
+        )?;
+
+        info!(
+            "Created backup {} at Raft index {} ({} of {} blobs copied).",
+            snapshot_path.display(),
+            raft_id.index,
+            blobs_copied,
+            blobs_total
+        );
+
+        Ok(BackupInfo {
+            path: snapshot_path,
+            raft_id,
+            created_at,
+            blobs_copied,
+            blobs_total,
+        })
+    }
+}
+
+/// Returns the most recent snapshot available in a backup directory.
+pub fn latest_snapshot(backup_path: &Path) -> crate::Result<Option<PathBuf>> {
+    let mut snapshots_path = backup_path.to_path_buf();
+    snapshots_path.push(BACKUP_SNAPSHOTS_DIR);
+
+    let mut latest: Option<(u64, PathBuf)> = None;
+    for entry in fs::read_dir(&snapshots_path)? {
+        let entry = entry?;
+        if let Some(created_at) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u64>().ok())
+        {
+            if latest.as_ref().map_or(true, |(ts, _)| created_at > *ts) {
+                latest = Some((created_at, entry.path()));
+            }
+        }
+    }
+
+    Ok(latest.map(|(_, path)| path))
+}
+
+/// Rebuilds a node's data directory from a backup snapshot. The target database
+/// directory must not exist, the node will catch up with the rest of the cluster
+/// from the Raft index stored in the snapshot.
+pub fn restore(
+    backup_path: &Path,
+    snapshot_path: &Path,
+    settings: &EnvSettings,
+) -> crate::Result<RaftId> {
+    let db_path = PathBuf::from(settings.value::<String>(crate::SETTINGS, "db-path"));
+
+    // Read manifest
+    let mut manifest_path = snapshot_path.to_path_buf();
+    manifest_path.push(BACKUP_MANIFEST);
+    let manifest = fs::read_to_string(&manifest_path)?;
+    let mut raft_term = None;
+    let mut raft_index = None;
+    for line in manifest.lines() {
+        if let Some((key, value)) = line.split_once(':') {
+            match key.trim() {
+                "raft-term" => raft_term = value.trim().parse::<TermId>().ok(),
+                "raft-index" => raft_index = value.trim().parse::<LogIndex>().ok(),
+                _ => (),
+            }
+        }
+    }
+    let raft_id = match (raft_term, raft_index) {
+        (Some(term), Some(index)) => RaftId::new(term, index),
+        _ => {
+            return Err(StoreError::DataCorruption(format!(
+                "Invalid or missing Raft id in backup manifest {}.",
+                manifest_path.display()
+            )));
+        }
+    };
+
+    // Copy database snapshot
+    let mut index_src = snapshot_path.to_path_buf();
+    index_src.push(BACKUP_INDEX_DIR);
+    let mut index_dst = db_path;
+    index_dst.push(BACKUP_INDEX_DIR);
+    if index_dst.exists() {
+        return Err(StoreError::InvalidArguments(format!(
+            "Database directory {} already exists.",
+            index_dst.display()
+        )));
+    }
+    copy_dir(&index_src, &index_dst)?;
+
+    // Copy blobs
+    let mut blobs_src = backup_path.to_path_buf();
+    blobs_src.push(BACKUP_BLOBS_DIR);
+    let blobs_dst = local_blob_path(settings);
+    if blobs_src.exists() {
+        copy_dir(&blobs_src, &blobs_dst)?;
+    }
+
+    Ok(raft_id)
+}
+
+fn copy_dir(src: &Path, dst: &Path) -> crate::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let mut dst_path = dst.to_path_buf();
+        dst_path.push(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dst_path)?;
+        } else if !dst_path.exists() {
+            debug!("Restoring {}", dst_path.display());
+            fs::copy(entry.path(), &dst_path)?;
+        }
+    }
+    Ok(())
+}
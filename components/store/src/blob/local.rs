@@ -25,31 +25,82 @@ use std::{
     fs::{self, File},
     io::{Read, Seek, SeekFrom, Write},
     ops::Range,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
-use crate::{config::env_settings::EnvSettings, write::mutex_map::MutexMap};
+use tracing::debug;
 
-use super::{BlobId, BlobStore};
+use crate::config::{env_settings::EnvSettings, settings::Setting};
+
+use super::{pack::BlobPacks, BlobId, BlobStore};
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::one_of("blob-store", &["local", "s3"])
+        .default("local")
+        .describe("Where external blobs are stored, an S3 bucket is always shared"),
+    Setting::path("blob-path").describe("Defaults to <db-path>/blobs"),
+    Setting::integer("blob-nested-levels").max(5).default("2"),
+    Setting::bool("blob-shared-store")
+        .default("false")
+        .describe("Set to true when all cluster nodes share blob-path"),
+    Setting::bytes("blob-pack-max-size")
+        .max(16777216)
+        .default("16384")
+        .describe("Blobs up to this size are stored in pack files, 0 disables packing"),
+    Setting::bytes("blob-pack-file-size")
+        .min(1048576)
+        .max(u32::MAX as u64)
+        .default("67108864"),
+    Setting::integer("blob-pack-compact-ratio")
+        .min(1)
+        .max(100)
+        .default("50")
+        .describe("Percentage of dead space that triggers rewriting a pack"),
+];
+
+/// Returns the directory where local blobs are stored.
+pub fn local_blob_path(settings: &EnvSettings) -> PathBuf {
+    if let Some(blob_path) = settings.get("blob-path") {
+        PathBuf::from(blob_path)
+    } else {
+        let mut base_path = PathBuf::from(settings.value::<String>(crate::SETTINGS, "db-path"));
+        base_path.push("blobs");
+        base_path
+    }
+}
 
 pub struct LocalBlobStore {
-    pub lock: MutexMap<()>,
     pub base_path: PathBuf,
     pub hash_levels: usize,
+    pub shared: bool,
+    pub packs: Option<BlobPacks>,
 }
 
 impl BlobStore for LocalBlobStore {
     fn new(settings: &EnvSettings) -> crate::Result<Self> {
-        let mut base_path = PathBuf::from(
-            settings
-                .get("db-path")
-                .unwrap_or_else(|| "/usr/local/stalwart-jmap/data".to_string()),
-        );
-        base_path.push("blobs");
+        let base_path = local_blob_path(settings);
+        let shared = settings.value(SETTINGS, "blob-shared-store");
+        let pack_max_size: usize = settings.value(SETTINGS, "blob-pack-max-size");
+
+        // Pack indexes are held in memory and cannot be shared between nodes.
+        let packs = if pack_max_size > 0 && !shared {
+            let mut pack_path = base_path.clone();
+            pack_path.push("packs");
+            Some(BlobPacks::open(
+                pack_path,
+                pack_max_size,
+                settings.value(SETTINGS, "blob-pack-file-size"),
+                settings.value(SETTINGS, "blob-pack-compact-ratio"),
+            )?)
+        } else {
+            None
+        };
+
         Ok(LocalBlobStore {
-            lock: MutexMap::with_capacity(1024),
             base_path,
-            hash_levels: std::cmp::min(settings.parse("blob-nested-levels").unwrap_or(2), 5),
+            hash_levels: std::cmp::min(settings.value(SETTINGS, "blob-nested-levels"), 5),
+            shared,
+            packs,
         })
     }
 
@@ -63,6 +114,14 @@ impl BlobStore for LocalBlobStore {
             }
         }
 
+        if let Some(packs) = self
+            .packs
+            .as_ref()
+            .filter(|packs| packs.accepts(blob.len()))
+        {
+            return packs.put(blob_id, blob);
+        }
+
         fs::create_dir_all(blob_path.parent().unwrap())?;
         let mut blob_file = File::create(&blob_path)?;
         blob_file.write_all(blob)?;
@@ -72,6 +131,26 @@ impl BlobStore for LocalBlobStore {
     }
 
     fn get_range(&self, blob_id: &BlobId, range: Range<u32>) -> crate::Result<Option<Vec<u8>>> {
+        if let Some(mut blob) = self
+            .packs
+            .as_ref()
+            .map(|packs| packs.get(blob_id))
+            .transpose()?
+            .flatten()
+        {
+            if range.start != 0 || range.end != u32::MAX {
+                let blob_size = blob.len() as u32;
+                let from_offset = if range.start < blob_size {
+                    range.start
+                } else {
+                    0
+                };
+                blob.truncate(std::cmp::min(range.end, blob_size) as usize);
+                blob.drain(..from_offset as usize);
+            }
+            return Ok(Some(blob));
+        }
+
         let blob_path = self.get_path(blob_id)?;
         if !blob_path.exists() {
             return Ok(None);
@@ -100,26 +179,90 @@ impl BlobStore for LocalBlobStore {
         }))
     }
 
+    fn exists(&self, blob_id: &BlobId) -> crate::Result<bool> {
+        Ok(self
+            .packs
+            .as_ref()
+            .map_or(false, |packs| packs.contains(blob_id))
+            || self.get_path(blob_id)?.exists())
+    }
+
+    fn is_shared(&self) -> bool {
+        self.shared
+    }
+
     fn delete(&self, blob_id: &BlobId) -> crate::Result<bool> {
+        let mut deleted = if let Some(packs) = &self.packs {
+            packs.delete(blob_id)?
+        } else {
+            false
+        };
+
         let blob_path = self.get_path(blob_id)?;
         if blob_path.exists() {
             fs::remove_file(&blob_path)?;
-            Ok(true)
-        } else {
-            Ok(false)
+            deleted = true;
         }
+
+        Ok(deleted)
+    }
+
+    fn compact(&self) -> crate::Result<()> {
+        if let Some(packs) = &self.packs {
+            let reclaimed = packs.compact()?;
+            if reclaimed > 0 {
+                debug!("Reclaimed {} bytes from blob packs.", reclaimed);
+            }
+        }
+        Ok(())
     }
 }
 
 impl LocalBlobStore {
     fn get_path(&self, blob_id: &BlobId) -> crate::Result<PathBuf> {
-        let mut path = self.base_path.clone();
-        let hash = blob_id.hash();
-        for byte in hash.iter().take(self.hash_levels) {
-            path.push(format!("{:x}", byte));
+        self.get_path_at(&self.base_path, blob_id)
+    }
+
+    fn get_path_at(&self, base_path: &Path, blob_id: &BlobId) -> crate::Result<PathBuf> {
+        Ok(blob_path(base_path, blob_id, self.hash_levels))
+    }
+}
+
+// Returns the location of a blob below a directory, nested by its first hash bytes.
+pub fn blob_path(base_path: &Path, blob_id: &BlobId, hash_levels: usize) -> PathBuf {
+    let mut path = base_path.to_path_buf();
+    for byte in blob_id.hash().iter().take(hash_levels) {
+        path.push(format!("{:x}", byte));
+    }
+    path.push(blob_id.to_string());
+    path
+}
+
+impl LocalBlobStore {
+    // Copies a blob to another directory using the same layout, returns false
+    // if the blob already existed at the destination.
+    pub fn copy_to(&self, blob_id: &BlobId, base_path: &Path) -> crate::Result<bool> {
+        let src_path = self.get_path(blob_id)?;
+        let dst_path = self.get_path_at(base_path, blob_id)?;
+
+        if dst_path.exists() {
+            return Ok(false);
+        } else if let Some(packs) = &self.packs {
+            // Packed blobs are written out as standalone files
+            if packs.copy_to(blob_id, &dst_path)? {
+                return Ok(true);
+            }
         }
-        path.push(blob_id.to_string());
 
-        Ok(path)
+        if !src_path.exists() {
+            return Ok(false);
+        }
+
+        fs::create_dir_all(dst_path.parent().unwrap())?;
+        if fs::hard_link(&src_path, &dst_path).is_err() {
+            fs::copy(&src_path, &dst_path)?;
+        }
+
+        Ok(true)
     }
 }
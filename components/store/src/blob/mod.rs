@@ -21,17 +21,23 @@
  * for more details.
 */
 
-use std::{convert::TryInto, fmt::Display, io::Write, ops::Range};
+use std::{convert::TryInto, fmt::Display, io::Write, ops::Range, path::Path};
 
+use parking_lot::Mutex;
 use sha2::{Digest, Sha256};
 
 use crate::{
-    config::env_settings::EnvSettings,
+    config::env_settings::{soft_panic, EnvSettings},
     serialize::{base32::Base32Writer, StoreDeserialize, StoreSerialize},
+    write::mutex_map::MutexMap,
 };
 
+use self::{local::LocalBlobStore, s3::S3BlobStore};
+
 pub mod local;
+pub mod pack;
 pub mod purge;
+pub mod s3;
 pub mod store;
 
 pub const BLOB_HASH_LEN: usize = 32;
@@ -123,6 +129,101 @@ pub trait BlobStore: Sized {
     fn get(&self, blob_id: &BlobId) -> crate::Result<Option<Vec<u8>>> {
         self.get_range(blob_id, 0..u32::MAX)
     }
+    fn exists(&self, blob_id: &BlobId) -> crate::Result<bool> {
+        self.get_range(blob_id, 0..1).map(|blob| blob.is_some())
+    }
     fn put(&self, blob_id: &BlobId, blob: &[u8]) -> crate::Result<bool>;
+    // Returns true when all cluster nodes read external blobs from the same backend.
+    fn is_shared(&self) -> bool {
+        false
+    }
     fn delete(&self, blob_id: &BlobId) -> crate::Result<bool>;
+    // Reclaims space left behind by deleted blobs, called after each purge.
+    fn compact(&self) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+pub enum BlobBackend {
+    Local(LocalBlobStore),
+    S3(S3BlobStore),
+}
+
+// Blob store selected by the 'blob-store' setting.
+pub struct BlobStorage {
+    pub lock: MutexMap<()>,
+    pub purge_lock: Mutex<()>,
+    pub backend: BlobBackend,
+}
+
+impl BlobStore for BlobStorage {
+    fn new(settings: &EnvSettings) -> crate::Result<Self> {
+        Ok(BlobStorage {
+            lock: MutexMap::with_capacity(1024),
+            purge_lock: Mutex::new(()),
+            backend: match settings.get("blob-store").as_deref() {
+                Some("local") | None => BlobBackend::Local(LocalBlobStore::new(settings)?),
+                Some("s3") => BlobBackend::S3(S3BlobStore::new(settings)?),
+                Some(backend) => {
+                    soft_panic(&format!(
+                        "Invalid value '{}' for parameter 'blob-store'.",
+                        backend
+                    ));
+                }
+            },
+        })
+    }
+
+    fn get_range(&self, blob_id: &BlobId, range: Range<u32>) -> crate::Result<Option<Vec<u8>>> {
+        match &self.backend {
+            BlobBackend::Local(store) => store.get_range(blob_id, range),
+            BlobBackend::S3(store) => store.get_range(blob_id, range),
+        }
+    }
+
+    fn exists(&self, blob_id: &BlobId) -> crate::Result<bool> {
+        match &self.backend {
+            BlobBackend::Local(store) => store.exists(blob_id),
+            BlobBackend::S3(store) => store.exists(blob_id),
+        }
+    }
+
+    fn put(&self, blob_id: &BlobId, blob: &[u8]) -> crate::Result<bool> {
+        match &self.backend {
+            BlobBackend::Local(store) => store.put(blob_id, blob),
+            BlobBackend::S3(store) => store.put(blob_id, blob),
+        }
+    }
+
+    fn is_shared(&self) -> bool {
+        match &self.backend {
+            BlobBackend::Local(store) => store.is_shared(),
+            BlobBackend::S3(store) => store.is_shared(),
+        }
+    }
+
+    fn delete(&self, blob_id: &BlobId) -> crate::Result<bool> {
+        match &self.backend {
+            BlobBackend::Local(store) => store.delete(blob_id),
+            BlobBackend::S3(store) => store.delete(blob_id),
+        }
+    }
+
+    fn compact(&self) -> crate::Result<()> {
+        match &self.backend {
+            BlobBackend::Local(store) => store.compact(),
+            BlobBackend::S3(store) => store.compact(),
+        }
+    }
+}
+
+impl BlobStorage {
+    // Copies a blob to a local directory, returns false if the blob already
+    // existed at the destination.
+    pub fn copy_to(&self, blob_id: &BlobId, base_path: &Path) -> crate::Result<bool> {
+        match &self.backend {
+            BlobBackend::Local(store) => store.copy_to(blob_id, base_path),
+            BlobBackend::S3(store) => store.copy_to(blob_id, base_path),
+        }
+    }
 }
@@ -0,0 +1,379 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use ahash::AHashMap;
+use parking_lot::{Mutex, RwLock};
+use tracing::{debug, error};
+
+use crate::StoreError;
+
+use super::{BlobId, BLOB_EXTERNAL, BLOB_HASH_LEN, BLOB_LOCAL};
+
+const PACK_EXT: &str = "pack";
+const INDEX_EXT: &str = "idx";
+const BLOB_KEY_LEN: usize = BLOB_HASH_LEN + 1;
+const INDEX_RECORD_LEN: usize = BLOB_KEY_LEN + (2 * std::mem::size_of::<u32>());
+const TOMBSTONE: u32 = u32::MAX;
+
+// Blobs are keyed by their kind and hash, as local and external blobs
+// with the same contents have independent lifecycles.
+type BlobKey = [u8; BLOB_KEY_LEN];
+
+/// Stores small blobs in append-only pack files instead of one file per blob.
+/// Each pack has an index file listing the offset and length of the blobs
+/// appended to it, followed by tombstones for the blobs deleted from it.
+/// Indexes are loaded in memory at startup, packs with too much dead space
+/// are rewritten by `compact`.
+pub struct BlobPacks {
+    path: PathBuf,
+    max_blob_size: usize,
+    max_pack_size: u64,
+    compact_ratio: u64,
+    state: RwLock<PackState>,
+    compact_lock: Mutex<()>,
+}
+
+#[derive(Default)]
+struct PackState {
+    blobs: AHashMap<BlobKey, BlobLocation>,
+    packs: BTreeMap<u32, PackInfo>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlobLocation {
+    pack_id: u32,
+    offset: u32,
+    len: u32,
+}
+
+#[derive(Debug, Default)]
+struct PackInfo {
+    size: u64,
+    live: u64,
+}
+
+impl BlobPacks {
+    pub fn open(
+        path: PathBuf,
+        max_blob_size: usize,
+        max_pack_size: u64,
+        compact_ratio: u64,
+    ) -> crate::Result<Self> {
+        fs::create_dir_all(&path)?;
+
+        // Replay indexes in pack order, later records override earlier ones.
+        let mut pack_ids = Vec::new();
+        for entry in fs::read_dir(&path)? {
+            let entry_path = entry?.path();
+            if entry_path.extension().map_or(false, |ext| ext == INDEX_EXT) {
+                if let Some(pack_id) = entry_path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u32>().ok())
+                {
+                    pack_ids.push(pack_id);
+                }
+            }
+        }
+        pack_ids.sort_unstable();
+
+        let mut state = PackState::default();
+        for pack_id in pack_ids {
+            let size = match fs::metadata(pack_path(&path, pack_id, PACK_EXT)) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => {
+                    error!("Pack {} has an index but no data, ignoring it.", pack_id);
+                    continue;
+                }
+            };
+            state.packs.insert(pack_id, PackInfo { size, live: 0 });
+
+            let index = fs::read(pack_path(&path, pack_id, INDEX_EXT))?;
+            for record in index.chunks_exact(INDEX_RECORD_LEN) {
+                let key: BlobKey = record[..BLOB_KEY_LEN].try_into().unwrap();
+                let offset =
+                    u32::from_le_bytes(record[BLOB_KEY_LEN..BLOB_KEY_LEN + 4].try_into().unwrap());
+                let len = u32::from_le_bytes(record[BLOB_KEY_LEN + 4..].try_into().unwrap());
+
+                if offset == TOMBSTONE {
+                    if let Some(location) = state.blobs.get(&key) {
+                        if location.pack_id == pack_id {
+                            state.remove(&key);
+                        }
+                    }
+                } else if offset as u64 + len as u64 <= size {
+                    state.remove(&key);
+                    state.insert(
+                        key,
+                        BlobLocation {
+                            pack_id,
+                            offset,
+                            len,
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(BlobPacks {
+            path,
+            max_blob_size,
+            max_pack_size,
+            compact_ratio,
+            state: RwLock::new(state),
+            compact_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn accepts(&self, len: usize) -> bool {
+        len <= self.max_blob_size
+    }
+
+    pub fn contains(&self, blob_id: &BlobId) -> bool {
+        self.state.read().blobs.contains_key(&blob_key(blob_id))
+    }
+
+    pub fn put(&self, blob_id: &BlobId, blob: &[u8]) -> crate::Result<bool> {
+        let key = blob_key(blob_id);
+        let mut state = self.state.write();
+        if state.blobs.contains_key(&key) {
+            return Ok(false);
+        }
+        let location = self.append(&mut state, &key, blob)?;
+        state.insert(key, location);
+        Ok(true)
+    }
+
+    pub fn get(&self, blob_id: &BlobId) -> crate::Result<Option<Vec<u8>>> {
+        let state = self.state.read();
+        if let Some(location) = state.blobs.get(&blob_key(blob_id)) {
+            self.read(location).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn delete(&self, blob_id: &BlobId) -> crate::Result<bool> {
+        let key = blob_key(blob_id);
+        let mut state = self.state.write();
+        if let Some(location) = state.blobs.get(&key).copied() {
+            // The tombstone is written to the pack's own index, so that both
+            // records disappear together when the pack is compacted.
+            let mut record = Vec::with_capacity(INDEX_RECORD_LEN);
+            record.extend_from_slice(&key);
+            record.extend_from_slice(&TOMBSTONE.to_le_bytes());
+            record.extend_from_slice(&location.len.to_le_bytes());
+            self.append_index(location.pack_id, &record)?;
+            state.remove(&key);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Rewrites the live blobs of packs whose dead space exceeds the configured
+    /// ratio into the current pack and removes the old pack. Returns the number
+    /// of bytes reclaimed. The pack state is only locked while each blob is moved,
+    /// so that reads and writes are not blocked for the whole compaction.
+    pub fn compact(&self) -> crate::Result<u64> {
+        let _compact_lock = self.compact_lock.lock();
+        let candidates = {
+            let state = self.state.read();
+            let active_pack_id = state.packs.keys().next_back().copied();
+            state
+                .packs
+                .iter()
+                .filter(|(pack_id, info)| {
+                    Some(**pack_id) != active_pack_id
+                        && (info.live == 0
+                            || (info.size - info.live) * 100 >= info.size * self.compact_ratio)
+                })
+                .map(|(pack_id, _)| *pack_id)
+                .collect::<Vec<_>>()
+        };
+
+        let mut reclaimed = 0;
+        for pack_id in candidates {
+            let live_blobs = self
+                .state
+                .read()
+                .blobs
+                .iter()
+                .filter(|(_, location)| location.pack_id == pack_id)
+                .map(|(key, location)| (*key, *location))
+                .collect::<Vec<_>>();
+            for (key, location) in live_blobs {
+                let blob = self.read(&location)?;
+                let mut state = self.state.write();
+
+                // Skip blobs deleted while the pack was being compacted
+                if state.blobs.get(&key) == Some(&location) {
+                    let new_location = self.append(&mut state, &key, &blob)?;
+                    state.remove(&key);
+                    state.insert(key, new_location);
+                }
+            }
+
+            let mut state = self.state.write();
+            if let Some(info) = state.packs.remove(&pack_id) {
+                reclaimed += info.size - info.live;
+            }
+            for ext in [PACK_EXT, INDEX_EXT] {
+                if let Err(err) = fs::remove_file(pack_path(&self.path, pack_id, ext)) {
+                    error!("Failed to remove pack file {}.{}: {}", pack_id, ext, err);
+                }
+            }
+            drop(state);
+            debug!("Compacted blob pack {}.", pack_id);
+        }
+
+        Ok(reclaimed)
+    }
+
+    /// Copies a packed blob to a standalone file.
+    pub fn copy_to(&self, blob_id: &BlobId, dst_path: &Path) -> crate::Result<bool> {
+        if let Some(blob) = self.get(blob_id)? {
+            fs::create_dir_all(dst_path.parent().unwrap())?;
+            let mut blob_file = File::create(dst_path)?;
+            blob_file.write_all(&blob)?;
+            blob_file.sync_data()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn append(
+        &self,
+        state: &mut PackState,
+        key: &BlobKey,
+        blob: &[u8],
+    ) -> crate::Result<BlobLocation> {
+        // Start a new pack when the current one is full
+        let pack_id = match state.packs.iter().next_back() {
+            Some((pack_id, info)) if info.size + blob.len() as u64 <= self.max_pack_size => {
+                *pack_id
+            }
+            Some((pack_id, _)) => *pack_id + 1,
+            None => 0,
+        };
+        #[cfg(unix)]
+        let is_new_pack = !state.packs.contains_key(&pack_id);
+        let info = state.packs.entry(pack_id).or_default();
+
+        // Blob data is synced before its index record is written, a crash in
+        // between only leaves dead space in the pack.
+        let mut pack_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(pack_path(&self.path, pack_id, PACK_EXT))?;
+        let offset = pack_file.metadata()?.len();
+        pack_file.write_all(blob)?;
+        pack_file.sync_data()?;
+        info.size = offset + blob.len() as u64;
+
+        let location = BlobLocation {
+            pack_id,
+            offset: offset as u32,
+            len: blob.len() as u32,
+        };
+
+        let mut record = Vec::with_capacity(INDEX_RECORD_LEN);
+        record.extend_from_slice(key);
+        record.extend_from_slice(&location.offset.to_le_bytes());
+        record.extend_from_slice(&location.len.to_le_bytes());
+        self.append_index(pack_id, &record)?;
+
+        // Make the new pack files durable
+        #[cfg(unix)]
+        if is_new_pack {
+            File::open(&self.path)?.sync_all()?;
+        }
+
+        Ok(location)
+    }
+
+    fn append_index(&self, pack_id: u32, record: &[u8]) -> crate::Result<()> {
+        let mut index_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(pack_path(&self.path, pack_id, INDEX_EXT))?;
+        index_file.write_all(record)?;
+        index_file.sync_data()?;
+        Ok(())
+    }
+
+    fn read(&self, location: &BlobLocation) -> crate::Result<Vec<u8>> {
+        let mut pack_file = File::open(pack_path(&self.path, location.pack_id, PACK_EXT))?;
+        pack_file.seek(SeekFrom::Start(location.offset as u64))?;
+        let mut blob = vec![0; location.len as usize];
+        pack_file.read_exact(&mut blob).map_err(|err| {
+            StoreError::DataCorruption(format!(
+                "Failed to read blob from pack {}: {}",
+                location.pack_id, err
+            ))
+        })?;
+        Ok(blob)
+    }
+}
+
+impl PackState {
+    fn insert(&mut self, key: BlobKey, location: BlobLocation) {
+        if let Some(info) = self.packs.get_mut(&location.pack_id) {
+            info.live += location.len as u64;
+        }
+        self.blobs.insert(key, location);
+    }
+
+    fn remove(&mut self, key: &BlobKey) {
+        if let Some(location) = self.blobs.remove(key) {
+            if let Some(info) = self.packs.get_mut(&location.pack_id) {
+                info.live -= location.len as u64;
+            }
+        }
+    }
+}
+
+fn blob_key(blob_id: &BlobId) -> BlobKey {
+    let mut key = [0u8; BLOB_KEY_LEN];
+    key[0] = if blob_id.is_local() {
+        BLOB_LOCAL
+    } else {
+        BLOB_EXTERNAL
+    };
+    key[1..].copy_from_slice(blob_id.hash());
+    key
+}
+
+fn pack_path(base_path: &Path, pack_id: u32, ext: &str) -> PathBuf {
+    let mut path = base_path.to_path_buf();
+    path.push(format!("{:08x}.{}", pack_id, ext));
+    path
+}
@@ -36,7 +36,11 @@ impl<T> JMAPStore<T>
 where
     T: for<'x> Store<'x> + 'static,
 {
-    pub fn purge_blobs(&self) -> crate::Result<()> {
+    // Removes expired ephemeral links and unreferenced blobs. When the blob store
+    // is shared by all nodes, only the leader deletes the shared objects: a
+    // follower may not have applied the links that reference them yet.
+    pub fn purge_blobs(&self, is_leader: bool) -> crate::Result<()> {
+        let _purge_lock = self.blob_store.purge_lock.lock();
         let mut batch = Vec::with_capacity(16);
         let now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -56,7 +60,7 @@ where
             }
 
             if key[..BLOB_HASH_LEN + 1] != blob_id {
-                batch = self.delete_blobs(batch, &blob_id, blob_link_count)?;
+                batch = self.delete_blobs(batch, &blob_id, blob_link_count, is_leader)?;
                 blob_link_count = 0;
                 blob_id.copy_from_slice(&key[..BLOB_HASH_LEN + 1]);
                 drop(_blob_lock);
@@ -92,8 +96,10 @@ where
             }
         }
 
-        self.delete_blobs(batch, &blob_id, blob_link_count)
-            .map(|_| ())
+        self.delete_blobs(batch, &blob_id, blob_link_count, is_leader)?;
+        drop(_blob_lock);
+
+        self.blob_store.compact()
     }
 
     fn delete_blobs(
@@ -101,6 +107,7 @@ where
         mut batch: Vec<WriteOperation>,
         blob_id: &[u8],
         blob_link_count: u32,
+        is_leader: bool,
     ) -> crate::Result<Vec<WriteOperation>> {
         if blob_link_count == 0 {
             // Delete blob
@@ -109,8 +116,8 @@ where
                 key: blob_id.to_vec(),
             });
 
-            // Delete external blob
-            if blob_id[0] == BLOB_EXTERNAL {
+            // Delete external blob, shared blobs are only deleted by the leader
+            if blob_id[0] == BLOB_EXTERNAL && (is_leader || !self.blob_store.is_shared()) {
                 let blob_id = BlobId::deserialize(blob_id).unwrap();
 
                 if let Err(err) = self.blob_store.delete(&blob_id) {
@@ -0,0 +1,348 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{fs, ops::Range, path::Path, time::Duration};
+
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use reqwest::{blocking::Client, header, Method, StatusCode};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{
+        env_settings::{soft_panic, EnvSettings},
+        settings::Setting,
+    },
+    StoreError,
+};
+
+use super::{
+    local::{self, blob_path},
+    BlobId, BlobStore,
+};
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::url("blob-s3-endpoint").describe("For example https://s3.us-east-1.amazonaws.com"),
+    Setting::text("blob-s3-bucket"),
+    Setting::text("blob-s3-region").default("us-east-1"),
+    Setting::text("blob-s3-access-key"),
+    Setting::secret("blob-s3-secret-key"),
+    Setting::text("blob-s3-prefix")
+        .default("")
+        .describe("Prepended to the name of each object"),
+    Setting::seconds("blob-s3-timeout").default("30"),
+];
+
+const EMPTY_PAYLOAD_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+// Stores external blobs in an S3 compatible bucket using path-style requests.
+// The bucket is always shared by all cluster nodes.
+pub struct S3BlobStore {
+    endpoint: String,
+    host: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    prefix: String,
+    hash_levels: usize,
+    timeout: Duration,
+    client: Mutex<Option<Client>>,
+}
+
+impl BlobStore for S3BlobStore {
+    fn new(settings: &EnvSettings) -> crate::Result<Self> {
+        let required = |name: &str| {
+            settings
+                .get(name)
+                .filter(|value| !value.is_empty())
+                .unwrap_or_else(|| {
+                    soft_panic(&format!("Missing '{}' parameter.", name));
+                })
+        };
+        let endpoint = required("blob-s3-endpoint")
+            .trim_end_matches('/')
+            .to_string();
+        let host = endpoint
+            .split_once("://")
+            .map_or(endpoint.as_str(), |(_, host)| host)
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+
+        Ok(S3BlobStore {
+            host,
+            endpoint,
+            bucket: required("blob-s3-bucket"),
+            region: settings.value(SETTINGS, "blob-s3-region"),
+            access_key: required("blob-s3-access-key"),
+            secret_key: required("blob-s3-secret-key"),
+            prefix: settings.value(SETTINGS, "blob-s3-prefix"),
+            hash_levels: std::cmp::min(settings.value(local::SETTINGS, "blob-nested-levels"), 5),
+            timeout: Duration::from_secs(settings.value(SETTINGS, "blob-s3-timeout")),
+            client: Mutex::new(None),
+        })
+    }
+
+    fn get_range(&self, blob_id: &BlobId, range: Range<u32>) -> crate::Result<Option<Vec<u8>>> {
+        let range = if range.start != 0 || range.end != u32::MAX {
+            if range.start >= range.end {
+                return Ok(Some(Vec::new()));
+            }
+            format!("bytes={}-{}", range.start, range.end - 1).into()
+        } else {
+            None
+        };
+        let response = self.request(Method::GET, blob_id, None, range)?;
+
+        match response.status() {
+            StatusCode::OK | StatusCode::PARTIAL_CONTENT => Ok(Some(
+                response
+                    .bytes()
+                    .map_err(|err| StoreError::InternalError(err.to_string()))?
+                    .to_vec(),
+            )),
+            StatusCode::RANGE_NOT_SATISFIABLE => Ok(Some(Vec::new())),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(StoreError::InternalError(format!(
+                "Failed to fetch blob {}: {}",
+                blob_id, status
+            ))),
+        }
+    }
+
+    fn exists(&self, blob_id: &BlobId) -> crate::Result<bool> {
+        match self.request(Method::HEAD, blob_id, None, None)?.status() {
+            StatusCode::OK => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            status => Err(StoreError::InternalError(format!(
+                "Failed to look up blob {}: {}",
+                blob_id, status
+            ))),
+        }
+    }
+
+    fn put(&self, blob_id: &BlobId, blob: &[u8]) -> crate::Result<bool> {
+        // Blobs are content addressed, an existing object is never rewritten.
+        if self.exists(blob_id)? {
+            return Ok(false);
+        }
+
+        let status = self
+            .request(Method::PUT, blob_id, Some(blob), None)?
+            .status();
+        if status.is_success() {
+            Ok(true)
+        } else {
+            Err(StoreError::InternalError(format!(
+                "Failed to store blob {}: {}",
+                blob_id, status
+            )))
+        }
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    fn delete(&self, blob_id: &BlobId) -> crate::Result<bool> {
+        if !self.exists(blob_id)? {
+            return Ok(false);
+        }
+
+        let status = self.request(Method::DELETE, blob_id, None, None)?.status();
+        if status.is_success() || status == StatusCode::NOT_FOUND {
+            Ok(status != StatusCode::NOT_FOUND)
+        } else {
+            Err(StoreError::InternalError(format!(
+                "Failed to delete blob {}: {}",
+                blob_id, status
+            )))
+        }
+    }
+}
+
+impl S3BlobStore {
+    // Downloads a blob to a local directory using the layout of the local blob
+    // store, returns false if the blob already
+    // existed at the destination or is not present in the bucket.
+    pub fn copy_to(&self, blob_id: &BlobId, base_path: &Path) -> crate::Result<bool> {
+        let dst_path = blob_path(base_path, blob_id, self.hash_levels);
+        if dst_path.exists() {
+            return Ok(false);
+        }
+
+        if let Some(blob) = self.get(blob_id)? {
+            fs::create_dir_all(dst_path.parent().unwrap())?;
+            fs::write(&dst_path, blob)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn object_path(&self, blob_id: &BlobId) -> String {
+        format!("/{}/{}{}", self.bucket, self.prefix, blob_id)
+    }
+
+    fn request(
+        &self,
+        method: Method,
+        blob_id: &BlobId,
+        payload: Option<&[u8]>,
+        range: Option<String>,
+    ) -> crate::Result<reqwest::blocking::Response> {
+        // The blocking client is created on first use as it can't be built
+        // from within an async context.
+        let client = {
+            let mut client = self.client.lock();
+            if client.is_none() {
+                *client = Client::builder()
+                    .timeout(self.timeout)
+                    .build()
+                    .map_err(|err| StoreError::InternalError(err.to_string()))?
+                    .into();
+            }
+            client.as_ref().unwrap().clone()
+        };
+
+        let path = uri_encode(&self.object_path(blob_id));
+        let payload_hash = payload.map_or_else(|| EMPTY_PAYLOAD_HASH.to_string(), sha256_hex);
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let authorization = self.authorization(method.as_str(), &path, &amz_date, &payload_hash);
+
+        let mut request = client
+            .request(method, format!("{}{}", self.endpoint, path))
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header(header::AUTHORIZATION, authorization);
+        if let Some(range) = range {
+            request = request.header(header::RANGE, range);
+        }
+        if let Some(payload) = payload {
+            request = request.body(payload.to_vec());
+        }
+
+        request
+            .send()
+            .map_err(|err| StoreError::InternalError(format!("S3 request failed: {}", err)))
+    }
+
+    // Builds an AWS Signature Version 4 authorization header signing the host,
+    // x-amz-content-sha256 and x-amz-date headers, expects an encoded path.
+    fn authorization(
+        &self,
+        method: &str,
+        path: &str,
+        amz_date: &str,
+        payload_hash: &str,
+    ) -> String {
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, self.host, payload_hash, amz_date, signed_headers, payload_hash
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+
+        let signature = hex_encode(&hmac_sha256(
+            &signing_key(&self.secret_key, date, &self.region, "s3"),
+            &string_to_sign,
+        ));
+
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        )
+    }
+}
+
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let mut key = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date);
+    for part in [region, service, "aws4_request"] {
+        key = hmac_sha256(&key, part);
+    }
+    key
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    hex_encode(&Sha256::digest(bytes))
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Percent-encodes a path as required by the canonical request, keeping slashes.
+fn uri_encode(path: &str) -> String {
+    let mut result = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            result.push(byte as char);
+        } else {
+            result.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hex_encode, signing_key, uri_encode};
+
+    #[test]
+    fn sigv4_signing_key() {
+        // Example from the AWS Signature Version 4 documentation
+        assert_eq!(
+            hex_encode(&signing_key(
+                "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+                "20120215",
+                "us-east-1",
+                "iam"
+            )),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn sigv4_uri_encode() {
+        assert_eq!(uri_encode("/test$file.text"), "/test%24file.text");
+        assert_eq!(
+            uri_encode("/bucket/blobs/a b~c_d.e-f"),
+            "/bucket/blobs/a%20b~c_d.e-f"
+        );
+    }
+}
@@ -29,13 +29,22 @@ use tracing::error;
 use crate::serialize::leb128::Leb128Reader;
 use crate::write::operation::WriteOperation;
 use crate::{
-    core::collection::Collection,
-    serialize::{key::BlobKey, StoreSerialize},
+    core::{collection::Collection, timing::ReadTimer},
+    serialize::{key::BlobKey, StoreDeserialize, StoreSerialize},
     AccountId, ColumnFamily, Direction, DocumentId, JMAPStore, Store,
 };
 
 use super::{BlobId, BlobStore};
 
+// Used by multi-gets that only need to know whether a key exists.
+struct KeyExists;
+
+impl StoreDeserialize for KeyExists {
+    fn deserialize(_bytes: &[u8]) -> Option<Self> {
+        Some(KeyExists)
+    }
+}
+
 impl<T> JMAPStore<T>
 where
     T: for<'x> Store<'x> + 'static,
@@ -52,14 +61,54 @@ where
         }
 
         // Write blob
-        let (result, value) = if blob_id.is_external() {
-            self.blob_store.put(blob_id, &bytes)?;
-            (bytes, Vec::new())
+        let (result, value, created) = if blob_id.is_external() {
+            let created = self.blob_store.put(blob_id, &bytes)?;
+            (bytes, Vec::new(), created)
         } else {
-            (Vec::new(), bytes)
+            (Vec::new(), bytes, false)
         };
 
         // Write blob or blob reference to database
+        if let Err(err) = self.blob_write_reference(blob_id, key, value) {
+            // There was a problem writing to the store, delete blob unless
+            // it was already present in a shared store used by other nodes.
+            if blob_id.is_external() && (created || !self.blob_store.is_shared()) {
+                if let Err(err) = self.blob_store.delete(blob_id) {
+                    error!("Failed to delete blob {}: {:?}", blob_id, err);
+                }
+            }
+            return Err(err);
+        }
+
+        Ok(result)
+    }
+
+    // Links an external blob that was written to a shared blob store by another
+    // node, returns false if the blob is not present in the shared store.
+    pub fn blob_link_shared(&self, blob_id: &BlobId) -> crate::Result<bool> {
+        if !blob_id.is_external() || !self.blob_store.is_shared() {
+            return Ok(false);
+        }
+
+        let key = BlobKey::serialize(blob_id);
+        let _lock = self.blob_store.lock.lock_hash(blob_id);
+
+        if self.db.exists(ColumnFamily::Blobs, &key)? {
+            Ok(true)
+        } else if self.blob_store.exists(blob_id)? {
+            self.blob_write_reference(blob_id, key, Vec::new())?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn blob_write_reference(
+        &self,
+        blob_id: &BlobId,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> crate::Result<()> {
         let mut batch = Vec::with_capacity(2);
         batch.push(WriteOperation::Set {
             cf: ColumnFamily::Blobs,
@@ -78,17 +127,7 @@ where
         });
 
         // Store blobId including a timestamp
-        if let Err(err) = self.db.write(batch) {
-            // There was a problem writing to the store, delete blob.
-            if blob_id.is_external() {
-                if let Err(err) = self.blob_store.delete(blob_id) {
-                    error!("Failed to delete blob {}: {:?}", blob_id, err);
-                }
-            }
-            return Err(err);
-        }
-
-        Ok(result)
+        self.db.write(batch)
     }
 
     pub fn blob_exists(&self, blob_id: &BlobId) -> crate::Result<bool> {
@@ -96,6 +135,65 @@ where
             .exists(ColumnFamily::Blobs, &BlobKey::serialize(blob_id))
     }
 
+    // Checks the existence of multiple blobs in a single store lookup.
+    pub fn blob_exists_multi(&self, blob_ids: &[&BlobId]) -> crate::Result<Vec<bool>> {
+        if blob_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        Ok(self
+            .db
+            .multi_get::<KeyExists, _>(
+                ColumnFamily::Blobs,
+                blob_ids
+                    .iter()
+                    .map(|blob_id| BlobKey::serialize(blob_id))
+                    .collect(),
+            )?
+            .into_iter()
+            .map(|exists| exists.is_some())
+            .collect())
+    }
+
+    // Batched version of blob_account_has_access. Ephemeral links (such as those
+    // created by uploads) are looked up with a single multi-get, blobs that are
+    // only linked to documents fall back to a prefix scan.
+    pub fn blob_account_has_access_multi(
+        &self,
+        blob_ids: &[&BlobId],
+        account_ids: &[AccountId],
+    ) -> crate::Result<Vec<bool>> {
+        let mut result = vec![false; blob_ids.len()];
+        if blob_ids.is_empty() || account_ids.is_empty() {
+            return Ok(result);
+        }
+
+        let mut keys = Vec::with_capacity(blob_ids.len() * account_ids.len());
+        for blob_id in blob_ids {
+            for account_id in account_ids {
+                keys.push(BlobKey::serialize_prefix(blob_id, *account_id));
+            }
+        }
+        for (pos, exists) in self
+            .db
+            .multi_get::<KeyExists, _>(ColumnFamily::Blobs, keys)?
+            .into_iter()
+            .enumerate()
+        {
+            if exists.is_some() {
+                result[pos / account_ids.len()] = true;
+            }
+        }
+
+        for (blob_id, has_access) in blob_ids.iter().zip(result.iter_mut()) {
+            if !*has_access {
+                *has_access = self.blob_account_has_access(blob_id, account_ids)?;
+            }
+        }
+
+        Ok(result)
+    }
+
     pub fn blob_link_ephemeral(
         &self,
         blob_id: &BlobId,
@@ -115,6 +213,7 @@ where
     }
 
     pub fn blob_get(&self, blob_id: &BlobId) -> crate::Result<Option<Vec<u8>>> {
+        let _timer = ReadTimer::blob();
         if !blob_id.is_local() {
             self.blob_store.get(blob_id)
         } else {
@@ -128,6 +227,7 @@ where
         blob_id: &BlobId,
         range: Range<u32>,
     ) -> crate::Result<Option<Vec<u8>>> {
+        let _timer = ReadTimer::blob();
         if !blob_id.is_local() {
             self.blob_store.get_range(blob_id, range)
         } else {
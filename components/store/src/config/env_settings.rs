@@ -30,6 +30,8 @@ use std::{
 
 use ahash::AHashMap;
 
+use super::settings::{find_setting, Setting};
+
 #[derive(Debug)]
 pub struct EnvSettings {
     pub args: AHashMap<String, String>,
@@ -134,6 +136,21 @@ impl EnvSettings {
         }
     }
 
+    /// Parses a declared setting, falling back to its declared default.
+    pub fn value<T>(&self, schema: &[Setting], name: &str) -> T
+    where
+        T: FromStr,
+    {
+        let setting = find_setting(schema, name);
+        if let Some(value) = self.parse(name) {
+            value
+        } else if let Some(value) = setting.default.and_then(|value| value.parse().ok()) {
+            value
+        } else {
+            soft_panic(&format!("Missing required argument: {}", name));
+        }
+    }
+
     pub fn parse_list(&self, name: &str) -> Option<Vec<String>> {
         if let Some(value) = self.get(name) {
             value
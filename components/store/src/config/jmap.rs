@@ -21,13 +21,190 @@
  * for more details.
 */
 
-use crate::nlp::Language;
+use crate::nlp::{extract::AttachmentExtractor, Language};
 
-use super::env_settings::EnvSettings;
+use super::{
+    env_settings::EnvSettings,
+    nlp::{NLPConfig, Synonyms},
+    settings::Setting,
+    templates::MessageTemplates,
+};
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::bytes("max-size-upload").default("50000000"),
+    Setting::integer("max-concurrent-uploads")
+        .min(1)
+        .default("4"),
+    Setting::bytes("max-size-request").default("10000000"),
+    Setting::integer("max-concurrent-requests")
+        .min(1)
+        .default("4"),
+    Setting::integer("max-calls-in-request")
+        .min(1)
+        .default("16"),
+    Setting::integer("max-objects-in-get").min(1).default("500"),
+    Setting::integer("max-objects-in-set").min(1).default("500"),
+    Setting::seconds("blob-temp-ttl")
+        .default("3600")
+        .describe("Time uploaded blobs are kept before being referenced"),
+    Setting::integer("changes-max-results")
+        .min(1)
+        .default("5000"),
+    Setting::integer("query-max-results").min(1).default("5000"),
+    Setting::integer("query-sort-parallel-min")
+        .default("20000")
+        .describe("Results sorted by several properties are sorted in parallel from this size, 0 = disabled"),
+    Setting::integer("query-prefix-min-len")
+        .min(1)
+        .default("3")
+        .describe("Minimum length of a wildcard search prefix"),
+    Setting::integer("query-prefix-max-terms")
+        .min(1)
+        .default("256")
+        .describe("Maximum number of terms a wildcard search may expand to"),
+    Setting::rate("rate-limit-authenticated").default("1000/60"),
+    Setting::rate("rate-limit-anonymous").default("100/60"),
+    Setting::rate("rate-limit-auth").default("10/60"),
+    Setting::bool("use-forwarded-header").default("false"),
+    Setting::integer("mailbox-name-max-len")
+        .min(1)
+        .default("255"),
+    Setting::integer("mailbox-max-total").default("1000"),
+    Setting::integer("mailbox-max-depth").min(1).default("10"),
+    Setting::bytes("mail-max-size").default("104857600"),
+    Setting::bytes("mail-attachments-max-size").default("50000000"),
+    Setting::integer("mail-import-max-items")
+        .min(1)
+        .default("5"),
+    Setting::integer("mail-get-chunk-size")
+        .min(1)
+        .default("50")
+        .describe("Messages loaded at once by Email/get"),
+    Setting::integer("mail-max-keywords")
+        .default("1000")
+        .describe("Distinct custom keywords per account, 0 = unlimited"),
+    Setting::bytes("mail-annotations-max-size")
+        .default("4096")
+        .describe("Private annotations per message and user"),
+    Setting::integer("mail-parse-max-items").min(1).default("5"),
+    Setting::integer("mail-mime-max-depth")
+        .min(2)
+        .default("20")
+        .describe("Nesting of multipart and message parts"),
+    Setting::integer("mail-mime-max-parts")
+        .min(1)
+        .max(1000)
+        .default("1000"),
+    Setting::integer("mail-mime-max-headers")
+        .min(1)
+        .default("1000")
+        .describe("Headers per message or part"),
+    Setting::bytes("mail-mime-max-header-length").default("65536"),
+    Setting::integer("mail-set-keywords-max-items")
+        .min(1)
+        .default("10000")
+        .describe("Messages updated by a single Email/setKeywordsByQuery call"),
+    Setting::integer("mail-set-keywords-chunk-size")
+        .min(1)
+        .default("500")
+        .describe("Messages written per batch by Email/setKeywordsByQuery"),
+    Setting::bytes("mail-detach-threshold")
+        .default("0")
+        .describe("Detach large attachments from bigger incoming messages, 0 = disabled"),
+    Setting::bytes("mail-detach-min-part-size").default("1048576"),
+    Setting::text("default-language").default("en"),
+    Setting::text("default-locale")
+        .default("und")
+        .describe("Collation used when sorting messages of accounts without a locale"),
+    Setting::days("expunge-trash-days")
+        .default("0")
+        .describe("0 = never"),
+    Setting::days("expunge-junk-days")
+        .default("0")
+        .describe("0 = never"),
+    Setting::bool("expunge-dry-run").default("false"),
+    Setting::list("retention-min-days").describe("role:days, messages cannot be destroyed earlier"),
+    Setting::text("subaddress-separator")
+        .default("false")
+        .describe("user+tag@domain is delivered to user@domain, false = disabled"),
+    Setting::bool("subaddress-auto-file")
+        .default("false")
+        .describe("File messages sent to registered sub-addresses into a mailbox named after the tag"),
+    Setting::integer("forward-max-addresses").default("10"),
+    Setting::list("forward-external-disabled-domains")
+        .describe("Only allow forwarding to local domains"),
+    Setting::millis("unsubscribe-timeout").default("10000"),
+    Setting::secret("srs-secret").describe("Defaults to encryption-key, also signs envelope ids"),
+    Setting::days("srs-max-age")
+        .default("21")
+        .describe("Days an SRS address is accepted for bounces"),
+    Setting::integer("srs-max-recursion").default("3"),
+    Setting::bool("lmtp-auth")
+        .default("false")
+        .describe("Evaluate SPF, DKIM and DMARC and add an Authentication-Results header"),
+    Setting::text("lmtp-authserv-id")
+        .describe("Defaults to the hostname, use the same value on all cluster nodes"),
+    Setting::days("lmtp-trace-days")
+        .default("0")
+        .describe("Days the ingestion trace of each message is kept, 0 = disabled"),
+    Setting::bool("lmtp-trace-header")
+        .default("false")
+        .describe("Add a Received header carrying the trace id to stored messages"),
+    Setting::one_of("group-delivery", &["members", "shared"])
+        .default("shared")
+        .describe("members = one copy per member, shared = group mailboxes"),
+    Setting::integer("submission-max-messages-hour")
+        .default("0")
+        .describe("0 = unlimited"),
+    Setting::integer("submission-max-messages-day")
+        .default("0")
+        .describe("0 = unlimited"),
+    Setting::integer("submission-max-recipients-message")
+        .default("0")
+        .describe("0 = unlimited"),
+    Setting::integer("submission-max-recipients-day")
+        .default("0")
+        .describe("0 = unlimited"),
+    Setting::integer("submission-burst").default("0"),
+    Setting::seconds("submission-undo-window")
+        .default("0")
+        .describe("0 = disabled"),
+    Setting::bool("submission-verify-mx")
+        .default("false")
+        .describe("Reject recipients whose domain does not accept mail"),
+    Setting::list("submission-blocked-domains"),
+    Setting::list("submission-suggest-domains")
+        .default(concat!(
+            "gmail.com, googlemail.com, outlook.com, hotmail.com, live.com, ",
+            "yahoo.com, icloud.com, aol.com, gmx.com, proton.me"
+        ))
+        .describe("Used to hint corrections for mistyped domains"),
+    Setting::integer("sieve-max-scripts").default("256"),
+    Setting::integer("sieve-max-script-name")
+        .min(1)
+        .default("512"),
+    Setting::integer("push-max-total").default("100"),
+    Setting::millis("ws-client-timeout").default("10000"),
+    Setting::millis("ws-heartbeat-interval")
+        .min(1)
+        .default("5000"),
+    Setting::millis("ws-throttle").default("1000"),
+    Setting::integer("ws-max-query-subscriptions")
+        .default("10")
+        .describe("Email queries a WebSocket client can subscribe to, 0 = disabled"),
+    Setting::millis("event-source-throttle").default("1000"),
+    Setting::millis("raft-commit-timeout")
+        .min(1)
+        .default("1000"),
+];
 
 pub struct JMAPConfig {
     pub blob_temp_ttl: u64,
     pub default_language: Language,
+    pub nlp: NLPConfig,
+    pub synonyms: Synonyms,
+    pub attachment_extractor: AttachmentExtractor,
+    pub templates: MessageTemplates,
 
     pub max_size_upload: usize,
     pub max_concurrent_uploads: usize,
@@ -43,6 +220,9 @@ pub struct JMAPConfig {
     pub use_forwarded_header: bool,
 
     pub query_max_results: usize,
+    pub query_sort_parallel_min: u64,
+    pub query_prefix_min_len: usize,
+    pub query_prefix_max_terms: usize,
     pub changes_max_results: usize,
     pub mailbox_name_max_len: usize,
     pub mailbox_max_total: usize,
@@ -50,7 +230,45 @@ pub struct JMAPConfig {
     pub mail_max_size: usize,
     pub mail_attachments_max_size: usize,
     pub mail_import_max_items: usize,
+    pub mail_get_chunk_size: usize,
+    pub mail_default_locale: String,
+    pub mail_max_keywords: usize,
+    pub mail_annotations_max_size: usize,
+    pub mail_detach_threshold: usize,
+    pub mail_detach_min_part_size: usize,
     pub mail_parse_max_items: usize,
+    pub mail_mime_max_depth: usize,
+    pub mail_mime_max_parts: usize,
+    pub mail_mime_max_headers: usize,
+    pub mail_mime_max_header_length: usize,
+    pub mail_set_keywords_max_items: usize,
+    pub mail_set_keywords_chunk_size: usize,
+    pub mail_expunge_trash_days: u32,
+    pub mail_expunge_junk_days: u32,
+    pub mail_expunge_dry_run: bool,
+    pub mail_retention_min_days: Vec<(String, u32)>,
+    pub mail_subaddress_separator: Option<char>,
+    pub mail_subaddress_auto_file: bool,
+    pub mail_forward_max_addresses: usize,
+    pub mail_forward_disabled_domains: Vec<String>,
+    pub mail_unsubscribe_timeout: u64,
+    pub srs_secret: String,
+    pub srs_max_age: u64,
+    pub srs_max_recursion: usize,
+    pub lmtp_auth: bool,
+    pub lmtp_authserv_id: Option<String>,
+    pub lmtp_trace_days: u64,
+    pub lmtp_trace_header: bool,
+
+    pub submission_max_messages_hour: u64,
+    pub submission_max_messages_day: u64,
+    pub submission_max_recipients_message: usize,
+    pub submission_max_recipients_day: u64,
+    pub submission_burst: u64,
+    pub submission_undo_window: u64,
+    pub submission_verify_mx: bool,
+    pub submission_blocked_domains: Vec<String>,
+    pub submission_suggest_domains: Vec<String>,
 
     pub sieve_max_scripts: usize,
     pub sieve_max_script_name: usize,
@@ -59,78 +277,160 @@ pub struct JMAPConfig {
     pub ws_heartbeat_interval: u64,
     pub ws_client_timeout: u64,
     pub ws_throttle: u64,
+    pub ws_max_query_subscriptions: usize,
     pub event_source_throttle: u64,
 
     pub raft_commit_timeout: u64,
+
+    pub group_delivery: GroupDelivery,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupDelivery {
+    // Each member receives their own copy
+    Members,
+    // Messages are delivered to the group's mailboxes, which are shared with its members
+    Shared,
 }
 
 impl From<&EnvSettings> for JMAPConfig {
     fn from(settings: &EnvSettings) -> Self {
         JMAPConfig {
-            max_size_upload: settings.parse("max-size-upload").unwrap_or(50000000),
-            max_concurrent_uploads: settings.parse("max-concurrent-uploads").unwrap_or(4),
-            max_concurrent_requests: settings.parse("max-concurrent-requests").unwrap_or(4),
-            max_size_request: settings.parse("max-size-request").unwrap_or(10000000),
-            max_calls_in_request: settings.parse("max-calls-in-request").unwrap_or(16),
-            max_objects_in_get: settings.parse("max-objects-in-get").unwrap_or(500),
-            max_objects_in_set: settings.parse("max-objects-in-set").unwrap_or(500),
-            blob_temp_ttl: settings.parse("blob-temp-ttl").unwrap_or(3600),
-            changes_max_results: settings.parse("changes-max-results").unwrap_or(5000),
-            query_max_results: settings.parse("query-max-results").unwrap_or(5000),
-            mailbox_name_max_len: settings.parse("mailbox-name-max-len").unwrap_or(255),
-            mailbox_max_total: settings.parse("mailbox-max-total").unwrap_or(1000),
-            mailbox_max_depth: settings.parse("mailbox-max-depth").unwrap_or(10),
-            mail_attachments_max_size: settings
-                .parse("mail-attachments-max-size")
-                .unwrap_or(50000000),
-            mail_max_size: settings.parse("mail-max-size").unwrap_or(104857600),
-            mail_import_max_items: settings.parse("mail-import-max-items").unwrap_or(5),
-            mail_parse_max_items: settings.parse("mail-parse-max-items").unwrap_or(5),
-            sieve_max_script_name: settings.parse("sieve-max-script-name").unwrap_or(512),
-            sieve_max_scripts: settings.parse("sieve-max-scripts").unwrap_or(256),
-            push_max_total: settings.parse("push-max-total").unwrap_or(100),
-            ws_client_timeout: settings.parse("ws-client-timeout").unwrap_or(10 * 1000),
-            ws_heartbeat_interval: settings.parse("ws-heartbeat-interval").unwrap_or(5 * 1000),
-            ws_throttle: settings.parse("ws-throttle").unwrap_or(1000),
-            event_source_throttle: settings.parse("event-source-throttle").unwrap_or(1000),
-            raft_commit_timeout: settings.parse("raft-commit-timeout").unwrap_or(1000),
+            max_size_upload: settings.value(SETTINGS, "max-size-upload"),
+            max_concurrent_uploads: settings.value(SETTINGS, "max-concurrent-uploads"),
+            max_concurrent_requests: settings.value(SETTINGS, "max-concurrent-requests"),
+            max_size_request: settings.value(SETTINGS, "max-size-request"),
+            max_calls_in_request: settings.value(SETTINGS, "max-calls-in-request"),
+            max_objects_in_get: settings.value(SETTINGS, "max-objects-in-get"),
+            max_objects_in_set: settings.value(SETTINGS, "max-objects-in-set"),
+            blob_temp_ttl: settings.value(SETTINGS, "blob-temp-ttl"),
+            changes_max_results: settings.value(SETTINGS, "changes-max-results"),
+            query_max_results: settings.value(SETTINGS, "query-max-results"),
+            query_sort_parallel_min: settings.value(SETTINGS, "query-sort-parallel-min"),
+            query_prefix_min_len: settings.value(SETTINGS, "query-prefix-min-len"),
+            query_prefix_max_terms: settings.value(SETTINGS, "query-prefix-max-terms"),
+            mailbox_name_max_len: settings.value(SETTINGS, "mailbox-name-max-len"),
+            mailbox_max_total: settings.value(SETTINGS, "mailbox-max-total"),
+            mailbox_max_depth: settings.value(SETTINGS, "mailbox-max-depth"),
+            mail_attachments_max_size: settings.value(SETTINGS, "mail-attachments-max-size"),
+            mail_max_size: settings.value(SETTINGS, "mail-max-size"),
+            mail_import_max_items: settings.value(SETTINGS, "mail-import-max-items"),
+            mail_get_chunk_size: settings.value(SETTINGS, "mail-get-chunk-size"),
+            mail_default_locale: settings.value(SETTINGS, "default-locale"),
+            mail_max_keywords: settings.value(SETTINGS, "mail-max-keywords"),
+            mail_annotations_max_size: settings.value(SETTINGS, "mail-annotations-max-size"),
+            mail_detach_threshold: settings.value(SETTINGS, "mail-detach-threshold"),
+            mail_detach_min_part_size: settings.value(SETTINGS, "mail-detach-min-part-size"),
+            mail_parse_max_items: settings.value(SETTINGS, "mail-parse-max-items"),
+            mail_mime_max_depth: settings.value(SETTINGS, "mail-mime-max-depth"),
+            mail_mime_max_parts: settings.value(SETTINGS, "mail-mime-max-parts"),
+            mail_mime_max_headers: settings.value(SETTINGS, "mail-mime-max-headers"),
+            mail_mime_max_header_length: settings.value(SETTINGS, "mail-mime-max-header-length"),
+            mail_set_keywords_max_items: settings.value(SETTINGS, "mail-set-keywords-max-items"),
+            mail_set_keywords_chunk_size: settings.value(SETTINGS, "mail-set-keywords-chunk-size"),
+            mail_expunge_trash_days: settings.value(SETTINGS, "expunge-trash-days"),
+            mail_expunge_junk_days: settings.value(SETTINGS, "expunge-junk-days"),
+            mail_expunge_dry_run: settings.value(SETTINGS, "expunge-dry-run"),
+            mail_retention_min_days: settings
+                .parse_list("retention-min-days")
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|item| {
+                    let (role, days) = item.trim().split_once(':')?;
+                    Some((role.trim().to_lowercase(), days.trim().parse().ok()?))
+                })
+                .collect(),
+            mail_subaddress_separator: match settings
+                .value::<String>(SETTINGS, "subaddress-separator")
+                .as_str()
+            {
+                "false" => None,
+                separator => separator.chars().next(),
+            },
+            mail_subaddress_auto_file: settings.value(SETTINGS, "subaddress-auto-file"),
+            mail_forward_max_addresses: settings.value(SETTINGS, "forward-max-addresses"),
+            mail_forward_disabled_domains: parse_domains(
+                settings,
+                "forward-external-disabled-domains",
+            ),
+            mail_unsubscribe_timeout: settings.value(SETTINGS, "unsubscribe-timeout"),
+            srs_secret: settings
+                .get("srs-secret")
+                .or_else(|| settings.get("encryption-key"))
+                .unwrap_or_default(),
+            srs_max_age: settings.value(SETTINGS, "srs-max-age"),
+            srs_max_recursion: settings.value(SETTINGS, "srs-max-recursion"),
+            lmtp_auth: settings.value(SETTINGS, "lmtp-auth"),
+            lmtp_authserv_id: settings.get("lmtp-authserv-id"),
+            lmtp_trace_days: settings.value(SETTINGS, "lmtp-trace-days"),
+            lmtp_trace_header: settings.value(SETTINGS, "lmtp-trace-header"),
+            submission_max_messages_hour: settings.value(SETTINGS, "submission-max-messages-hour"),
+            submission_max_messages_day: settings.value(SETTINGS, "submission-max-messages-day"),
+            submission_max_recipients_message: settings
+                .value(SETTINGS, "submission-max-recipients-message"),
+            submission_max_recipients_day: settings
+                .value(SETTINGS, "submission-max-recipients-day"),
+            submission_burst: settings.value(SETTINGS, "submission-burst"),
+            submission_undo_window: settings.value(SETTINGS, "submission-undo-window"),
+            submission_verify_mx: settings.value(SETTINGS, "submission-verify-mx"),
+            submission_blocked_domains: parse_domains(settings, "submission-blocked-domains"),
+            submission_suggest_domains: parse_domains(settings, "submission-suggest-domains"),
+            sieve_max_script_name: settings.value(SETTINGS, "sieve-max-script-name"),
+            sieve_max_scripts: settings.value(SETTINGS, "sieve-max-scripts"),
+            push_max_total: settings.value(SETTINGS, "push-max-total"),
+            ws_client_timeout: settings.value(SETTINGS, "ws-client-timeout"),
+            ws_heartbeat_interval: settings.value(SETTINGS, "ws-heartbeat-interval"),
+            ws_throttle: settings.value(SETTINGS, "ws-throttle"),
+            ws_max_query_subscriptions: settings.value(SETTINGS, "ws-max-query-subscriptions"),
+            event_source_throttle: settings.value(SETTINGS, "event-source-throttle"),
+            raft_commit_timeout: settings.value(SETTINGS, "raft-commit-timeout"),
+            group_delivery: match settings
+                .value::<String>(SETTINGS, "group-delivery")
+                .as_str()
+            {
+                "members" => GroupDelivery::Members,
+                _ => GroupDelivery::Shared,
+            },
             default_language: Language::from_iso_639(
-                &settings
-                    .get("default-language")
-                    .unwrap_or_else(|| "en".to_string()),
+                &settings.value::<String>(SETTINGS, "default-language"),
             )
             .unwrap_or(Language::English),
-            rate_limit_authenticated: settings
-                .get("rate-limit-authenticated")
-                .unwrap_or_else(|| "1000/60".to_string())
-                .split_once('/')
-                .and_then(|(a, b)| {
-                    a.parse::<u64>()
-                        .ok()
-                        .map(|a| (a, b.parse::<u64>().unwrap_or(60)))
-                })
-                .unwrap_or((1000, 60)),
-            rate_limit_anonymous: settings
-                .get("rate-limit-anonymous")
-                .unwrap_or_else(|| "100/60".to_string())
-                .split_once('/')
-                .and_then(|(a, b)| {
-                    a.parse::<u64>()
-                        .ok()
-                        .map(|a| (a, b.parse::<u64>().unwrap_or(60)))
-                })
-                .unwrap_or((100, 60)),
-            rate_limit_auth: settings
-                .get("rate-limit-auth")
-                .unwrap_or_else(|| "10/60".to_string())
-                .split_once('/')
-                .and_then(|(a, b)| {
-                    a.parse::<u64>()
-                        .ok()
-                        .map(|a| (a, b.parse::<u64>().unwrap_or(60)))
-                })
-                .unwrap_or((100, 60)),
-            use_forwarded_header: settings.parse("use-forwarded-header").unwrap_or(false),
+            nlp: NLPConfig::from(settings),
+            synonyms: Synonyms::from(settings),
+            attachment_extractor: AttachmentExtractor::from(settings),
+            templates: MessageTemplates::from(settings),
+            rate_limit_authenticated: parse_rate(settings, "rate-limit-authenticated"),
+            rate_limit_anonymous: parse_rate(settings, "rate-limit-anonymous"),
+            rate_limit_auth: parse_rate(settings, "rate-limit-auth"),
+            use_forwarded_header: settings.value(SETTINGS, "use-forwarded-header"),
         }
     }
 }
+
+fn parse_domains(settings: &EnvSettings, name: &str) -> Vec<String> {
+    settings
+        .parse_list(name)
+        .or_else(|| {
+            super::settings::find_setting(SETTINGS, name)
+                .default
+                .map(|value| value.split(',').map(String::from).collect())
+        })
+        .unwrap_or_default()
+        .into_iter()
+        .map(|domain| domain.trim().to_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect()
+}
+
+fn parse_rate(settings: &EnvSettings, name: &str) -> (u64, u64) {
+    settings
+        .value::<String>(SETTINGS, name)
+        .split_once('/')
+        .and_then(|(a, b)| {
+            a.trim()
+                .parse::<u64>()
+                .ok()
+                .map(|a| (a, b.trim().parse::<u64>().unwrap_or(60)))
+        })
+        .unwrap_or((100, 60))
+}
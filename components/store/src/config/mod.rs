@@ -21,5 +21,23 @@
  * for more details.
 */
 
+use self::settings::SettingsSection;
+
 pub mod env_settings;
 pub mod jmap;
+pub mod nlp;
+pub mod settings;
+pub mod templates;
+
+/// Settings declared by the store and its subsystems.
+pub const SCHEMA: &[SettingsSection] = &[
+    ("Store", crate::SETTINGS),
+    ("Blob storage", crate::blob::local::SETTINGS),
+    ("S3 blob storage", crate::blob::s3::SETTINGS),
+    ("Bitmap cache", crate::read::cache::SETTINGS),
+    ("JMAP", jmap::SETTINGS),
+    ("Full-text search", nlp::SETTINGS),
+    ("Index pruning", crate::write::prune::SETTINGS),
+    ("Attachment indexing", crate::nlp::extract::SETTINGS),
+    ("Message templates", templates::SETTINGS),
+];
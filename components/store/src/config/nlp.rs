@@ -0,0 +1,396 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use ahash::{AHashMap, AHashSet};
+use tracing::warn;
+
+use crate::{
+    nlp::{diacritics::fold_diacritics, Language},
+    serialize::{StoreDeserialize, StoreSerialize},
+};
+
+use super::{
+    env_settings::{soft_panic, EnvSettings},
+    settings::Setting,
+};
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::bool("nlp-fold-diacritics").default("false"),
+    Setting::list("nlp-disable-stemming").describe("Language codes"),
+    Setting::list("nlp-stop-words"),
+    Setting::path("nlp-stop-words-file").describe("One word per line"),
+    Setting::text("nlp-synonyms").describe("Rules separated by semicolons"),
+    Setting::path("nlp-synonyms-file").describe("One rule per line"),
+    Setting::one_of("nlp-synonyms-mode", &["query", "index"])
+        .default("query")
+        .describe("Expand synonyms at query or at index time"),
+    Setting::integer("nlp-min-token-length").describe("Shorter tokens are not indexed"),
+    Setting::integer("nlp-max-hex-token-length")
+        .describe("Longer tokens made of hexadecimal digits are not indexed"),
+    Setting::text("nlp-max-token-entropy")
+        .describe("Bits per character, tokens above it are not indexed"),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct NLPConfig {
+    pub stemming_disabled: AHashSet<Language>,
+    pub stop_words: AHashSet<String>,
+    pub fold_diacritics: bool,
+    // Token filters, zero disables them. The entropy limit is kept in
+    // thousandths of a bit so that the settings can be compared.
+    pub min_token_length: usize,
+    pub max_hex_token_length: usize,
+    pub max_token_entropy: u32,
+}
+
+impl NLPConfig {
+    pub fn is_stemming_enabled(&self, language: Language) -> bool {
+        !self.stemming_disabled.contains(&language)
+    }
+
+    pub fn is_stop_word(&self, word: &str) -> bool {
+        !self.stop_words.is_empty() && self.stop_words.contains(word)
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.stemming_disabled.is_empty()
+            && self.stop_words.is_empty()
+            && !self.fold_diacritics
+            && !self.has_token_filters()
+    }
+
+    pub fn has_token_filters(&self) -> bool {
+        self.min_token_length > 0 || self.max_hex_token_length > 0 || self.max_token_entropy > 0
+    }
+
+    /// Whether a token carries too little information to be worth indexing,
+    /// such as fragments of base64 encoded data or long hexadecimal ids.
+    pub fn is_low_value_token(&self, word: &str) -> bool {
+        if self.min_token_length > 0 && word.chars().count() < self.min_token_length {
+            return true;
+        }
+
+        // Hexadecimal digits only, with at least one decimal digit so that
+        // words such as 'deadbeef' or 'facade' are kept.
+        if self.max_hex_token_length > 0
+            && word.len() > self.max_hex_token_length
+            && word.bytes().all(|ch| ch.is_ascii_hexdigit())
+            && word.bytes().any(|ch| ch.is_ascii_digit())
+        {
+            return true;
+        }
+
+        self.max_token_entropy > 0 && token_entropy(word) > self.max_token_entropy
+    }
+}
+
+impl From<&EnvSettings> for NLPConfig {
+    fn from(settings: &EnvSettings) -> Self {
+        let fold_diacritics = settings.value(SETTINGS, "nlp-fold-diacritics");
+
+        // Languages with stemming disabled
+        let mut stemming_disabled = AHashSet::default();
+        for code in settings
+            .parse_list("nlp-disable-stemming")
+            .unwrap_or_default()
+        {
+            let code = code.trim();
+            if code.is_empty() {
+                continue;
+            } else if let Some(language) = Language::from_iso_639(code) {
+                stemming_disabled.insert(language);
+            } else {
+                soft_panic(&format!(
+                    "Invalid language code '{}' in parameter 'nlp-disable-stemming'.",
+                    code
+                ));
+            }
+        }
+
+        // Stop words, either listed inline or read from a file with one word per line
+        let mut words = settings.parse_list("nlp-stop-words").unwrap_or_default();
+        if let Some(path) = settings.get("nlp-stop-words-file") {
+            words.extend(
+                std::fs::read_to_string(&path)
+                    .unwrap_or_else(|err| {
+                        soft_panic(&format!("Failed to read stop words file {}: {}", path, err));
+                    })
+                    .lines()
+                    .filter(|line| !line.starts_with('#'))
+                    .map(|line| line.to_string()),
+            );
+        }
+        let stop_words = words
+            .into_iter()
+            .filter_map(|word| {
+                let word = word.trim().to_lowercase();
+                if !word.is_empty() {
+                    Some(if fold_diacritics {
+                        fold_diacritics(&word).into_owned()
+                    } else {
+                        word
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        // Token filters
+        let max_token_entropy = settings
+            .get("nlp-max-token-entropy")
+            .map(|value| match value.trim().parse::<f64>() {
+                Ok(bits) if bits >= 0.0 => (bits * 1000.0).round() as u32,
+                _ => {
+                    soft_panic(&format!(
+                        "Invalid value '{}' for parameter 'nlp-max-token-entropy'.",
+                        value
+                    ));
+                }
+            })
+            .unwrap_or(0);
+
+        NLPConfig {
+            stemming_disabled,
+            stop_words,
+            fold_diacritics,
+            min_token_length: settings.parse("nlp-min-token-length").unwrap_or(0),
+            max_hex_token_length: settings.parse("nlp-max-hex-token-length").unwrap_or(0),
+            max_token_entropy,
+        }
+    }
+}
+
+// Shannon entropy of a token in thousandths of a bit per character.
+fn token_entropy(word: &str) -> u32 {
+    let mut counts: Vec<(char, u32)> = Vec::new();
+    let mut total = 0;
+    for ch in word.chars() {
+        if let Some((_, count)) = counts.iter_mut().find(|(c, _)| *c == ch) {
+            *count += 1;
+        } else {
+            counts.push((ch, 1));
+        }
+        total += 1;
+    }
+    let total = total as f64;
+    let entropy = counts
+        .into_iter()
+        .map(|(_, count)| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum::<f64>();
+    (entropy * 1000.0).round() as u32
+}
+
+// Synonyms are kept separate from NLPConfig as, unless index-time expansion is
+// enabled, they do not affect the contents of the index.
+#[derive(Debug, Clone, Default)]
+pub struct Synonyms {
+    pub map: AHashMap<String, Vec<String>>,
+    pub index_time: bool,
+}
+
+impl Synonyms {
+    pub fn get(&self, word: &str) -> &[String] {
+        if !self.map.is_empty() {
+            self.map.get(word).map(|s| s.as_slice()).unwrap_or_default()
+        } else {
+            &[]
+        }
+    }
+
+    pub fn expand_query(&self) -> bool {
+        !self.index_time && !self.map.is_empty()
+    }
+
+    pub fn expand_index(&self) -> bool {
+        self.index_time && !self.map.is_empty()
+    }
+
+    // Parses a synonym rule, either a list of equivalent words separated by
+    // commas or a one-way mapping such as 'invoice => bill, receipt'.
+    fn add_rule(&mut self, rule: &str, fold: bool) {
+        let parse = |words: &str| {
+            words
+                .split(',')
+                .filter_map(|word| {
+                    let word = word.trim().to_lowercase();
+                    if word.is_empty() {
+                        None
+                    } else if word.contains(char::is_whitespace) {
+                        warn!("Multi-word synonym '{}' is not supported, ignoring.", word);
+                        None
+                    } else if fold {
+                        Some(fold_diacritics(&word).into_owned())
+                    } else {
+                        Some(word)
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let (sources, targets) = if let Some((sources, targets)) = rule.split_once("=>") {
+            (parse(sources), parse(targets))
+        } else {
+            let words = parse(rule);
+            (words.clone(), words)
+        };
+
+        for source in sources {
+            let entry = self.map.entry(source.clone()).or_insert_with(Vec::new);
+            for target in &targets {
+                if target != &source && !entry.contains(target) {
+                    entry.push(target.clone());
+                }
+            }
+        }
+    }
+}
+
+impl From<&EnvSettings> for Synonyms {
+    fn from(settings: &EnvSettings) -> Self {
+        let fold = settings.value(SETTINGS, "nlp-fold-diacritics");
+        let mut synonyms = Synonyms {
+            map: AHashMap::default(),
+            index_time: match settings
+                .value::<String>(SETTINGS, "nlp-synonyms-mode")
+                .as_str()
+            {
+                "index" => true,
+                "query" => false,
+                mode => {
+                    soft_panic(&format!(
+                        "Invalid value '{}' for parameter 'nlp-synonyms-mode'.",
+                        mode
+                    ));
+                }
+            },
+        };
+
+        // Rules are either listed inline separated by semicolons or read from a
+        // file with one rule per line
+        if let Some(rules) = settings.get("nlp-synonyms") {
+            for rule in rules.split(';') {
+                synonyms.add_rule(rule, fold);
+            }
+        }
+        if let Some(path) = settings.get("nlp-synonyms-file") {
+            for rule in std::fs::read_to_string(&path)
+                .unwrap_or_else(|err| {
+                    soft_panic(&format!("Failed to read synonyms file {}: {}", path, err));
+                })
+                .lines()
+                .filter(|line| !line.starts_with('#'))
+            {
+                synonyms.add_rule(rule, fold);
+            }
+        }
+        synonyms.map.retain(|_, targets| !targets.is_empty());
+
+        synonyms
+    }
+}
+
+impl StoreSerialize for NLPConfig {
+    fn serialize(&self) -> Option<Vec<u8>> {
+        bincode::serialize(self).ok()
+    }
+}
+
+impl StoreDeserialize for NLPConfig {
+    fn deserialize(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok().or_else(|| {
+            // Settings stored before token filters were added
+            bincode::deserialize::<(AHashSet<Language>, AHashSet<String>, bool)>(bytes)
+                .ok()
+                .map(
+                    |(stemming_disabled, stop_words, fold_diacritics)| NLPConfig {
+                        stemming_disabled,
+                        stop_words,
+                        fold_diacritics,
+                        ..Default::default()
+                    },
+                )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::serialize::{StoreDeserialize, StoreSerialize};
+
+    use super::{token_entropy, NLPConfig, Synonyms};
+
+    #[test]
+    fn synonym_rules() {
+        let mut synonyms = Synonyms::default();
+        synonyms.add_rule("Invoice, bill, receipt", false);
+        synonyms.add_rule("car => automobile", false);
+        synonyms.add_rule("café => coffee", true);
+        synonyms.add_rule("big apple, nyc", false);
+
+        assert_eq!(synonyms.get("invoice"), &["bill", "receipt"]);
+        assert_eq!(synonyms.get("receipt"), &["invoice", "bill"]);
+        assert_eq!(synonyms.get("car"), &["automobile"]);
+        assert!(synonyms.get("automobile").is_empty());
+        assert_eq!(synonyms.get("cafe"), &["coffee"]);
+        assert!(synonyms.get("nyc").is_empty());
+    }
+
+    #[test]
+    fn low_value_tokens() {
+        let config = NLPConfig {
+            min_token_length: 2,
+            max_hex_token_length: 8,
+            max_token_entropy: 3500,
+            ..Default::default()
+        };
+
+        for word in ["hello", "deadbeefcafe", "internationalization", "0x1f"] {
+            assert!(!config.is_low_value_token(word), "{}", word);
+        }
+        for word in ["a", "3f2a9c0d41e7", "q29udgvudc1uexbl0ibu"] {
+            assert!(config.is_low_value_token(word), "{}", word);
+        }
+        assert_eq!(token_entropy("aaaa"), 0);
+        assert_eq!(token_entropy("abcd"), 2000);
+        assert!(NLPConfig::default().is_default());
+        assert!(!config.is_default());
+
+        // Settings stored by previous versions are still readable
+        let legacy = bincode::serialize(&(
+            config.stemming_disabled.clone(),
+            config.stop_words.clone(),
+            true,
+        ))
+        .unwrap();
+        let legacy = NLPConfig::deserialize(&legacy).unwrap();
+        assert!(legacy.fold_diacritics && !legacy.has_token_filters());
+        assert_eq!(
+            NLPConfig::deserialize(&config.serialize().unwrap()).unwrap(),
+            config
+        );
+    }
+}
@@ -0,0 +1,498 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{fmt::Display, net::IpAddr};
+
+use super::env_settings::EnvSettings;
+
+pub const REDACTED: &str = "********";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Count,
+    Bytes,
+    Milliseconds,
+    Seconds,
+    Days,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SettingType {
+    Bool,
+    Integer { min: u64, max: u64, unit: Unit },
+    Text,
+    Secret,
+    Path,
+    Url,
+    IpAddr,
+    List,
+    Enum(&'static [&'static str]),
+    Rate,
+    Schedule,
+}
+
+/// Declaration of a configuration setting, used to validate the configuration
+/// at startup, to read typed values and to generate the configuration reference.
+#[derive(Debug, Clone, Copy)]
+pub struct Setting {
+    pub key: &'static str,
+    pub typ: SettingType,
+    pub default: Option<&'static str>,
+    pub description: &'static str,
+}
+
+/// Settings declared by a subsystem, along with the subsystem's name.
+pub type SettingsSection = (&'static str, &'static [Setting]);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SettingError {
+    pub key: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct EffectiveSetting {
+    pub key: &'static str,
+    pub value: Option<String>,
+    #[serde(rename = "isDefault")]
+    pub is_default: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<&'static str>,
+}
+
+impl Setting {
+    const fn new(key: &'static str, typ: SettingType) -> Self {
+        Setting {
+            key,
+            typ,
+            default: None,
+            description: "",
+        }
+    }
+
+    pub const fn bool(key: &'static str) -> Self {
+        Setting::new(key, SettingType::Bool)
+    }
+
+    pub const fn integer(key: &'static str) -> Self {
+        Setting::new(
+            key,
+            SettingType::Integer {
+                min: 0,
+                max: u64::MAX,
+                unit: Unit::Count,
+            },
+        )
+    }
+
+    pub const fn bytes(key: &'static str) -> Self {
+        Setting::integer(key).unit(Unit::Bytes)
+    }
+
+    pub const fn millis(key: &'static str) -> Self {
+        Setting::integer(key).unit(Unit::Milliseconds)
+    }
+
+    pub const fn seconds(key: &'static str) -> Self {
+        Setting::integer(key).unit(Unit::Seconds)
+    }
+
+    pub const fn days(key: &'static str) -> Self {
+        Setting::integer(key).unit(Unit::Days).max(u32::MAX as u64)
+    }
+
+    pub const fn text(key: &'static str) -> Self {
+        Setting::new(key, SettingType::Text)
+    }
+
+    pub const fn secret(key: &'static str) -> Self {
+        Setting::new(key, SettingType::Secret)
+    }
+
+    pub const fn path(key: &'static str) -> Self {
+        Setting::new(key, SettingType::Path)
+    }
+
+    pub const fn url(key: &'static str) -> Self {
+        Setting::new(key, SettingType::Url)
+    }
+
+    pub const fn ip_addr(key: &'static str) -> Self {
+        Setting::new(key, SettingType::IpAddr)
+    }
+
+    pub const fn list(key: &'static str) -> Self {
+        Setting::new(key, SettingType::List)
+    }
+
+    pub const fn one_of(key: &'static str, values: &'static [&'static str]) -> Self {
+        Setting::new(key, SettingType::Enum(values))
+    }
+
+    pub const fn rate(key: &'static str) -> Self {
+        Setting::new(key, SettingType::Rate)
+    }
+
+    pub const fn schedule(key: &'static str) -> Self {
+        Setting::new(key, SettingType::Schedule)
+    }
+
+    pub const fn default(mut self, value: &'static str) -> Self {
+        self.default = Some(value);
+        self
+    }
+
+    pub const fn describe(mut self, description: &'static str) -> Self {
+        self.description = description;
+        self
+    }
+
+    pub const fn min(mut self, value: u64) -> Self {
+        if let SettingType::Integer { max, unit, .. } = self.typ {
+            self.typ = SettingType::Integer {
+                min: value,
+                max,
+                unit,
+            };
+        }
+        self
+    }
+
+    pub const fn max(mut self, value: u64) -> Self {
+        if let SettingType::Integer { min, unit, .. } = self.typ {
+            self.typ = SettingType::Integer {
+                min,
+                max: value,
+                unit,
+            };
+        }
+        self
+    }
+
+    const fn unit(mut self, value: Unit) -> Self {
+        if let SettingType::Integer { min, max, .. } = self.typ {
+            self.typ = SettingType::Integer {
+                min,
+                max,
+                unit: value,
+            };
+        }
+        self
+    }
+
+    pub fn is_secret(&self) -> bool {
+        matches!(self.typ, SettingType::Secret)
+    }
+
+    pub fn validate(&self, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        match self.typ {
+            SettingType::Bool => match value {
+                "true" | "false" => Ok(()),
+                _ => Err(format!("expected 'true' or 'false', found '{}'", value)),
+            },
+            SettingType::Integer { min, max, unit } => match value.parse::<u64>() {
+                Ok(number) if (min..=max).contains(&number) => Ok(()),
+                Ok(number) => Err(if max == u64::MAX {
+                    format!("{} {} is below the minimum of {}", number, unit, min)
+                } else {
+                    format!(
+                        "{} {} is out of range, expected {} to {}",
+                        number, unit, min, max
+                    )
+                }),
+                Err(_) => Err(format!(
+                    "expected a positive integer ({}), found '{}'",
+                    unit, value
+                )),
+            },
+            SettingType::Text | SettingType::Secret | SettingType::Path | SettingType::List => {
+                Ok(())
+            }
+            SettingType::Url => {
+                if value.contains("://") {
+                    Ok(())
+                } else {
+                    Err(format!("expected a URL, found '{}'", value))
+                }
+            }
+            SettingType::IpAddr => value
+                .parse::<IpAddr>()
+                .map(|_| ())
+                .map_err(|_| format!("expected an IP address, found '{}'", value)),
+            SettingType::Enum(values) => {
+                if values.iter().any(|v| v.eq_ignore_ascii_case(value)) {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "expected one of {}, found '{}'",
+                        values.join(", "),
+                        value
+                    ))
+                }
+            }
+            SettingType::Rate => match value.split_once('/') {
+                Some((requests, period))
+                    if requests.trim().parse::<u64>().is_ok()
+                        && period.trim().parse::<u64>().map_or(false, |p| p > 0) =>
+                {
+                    Ok(())
+                }
+                _ => Err(format!(
+                    "expected '<requests>/<seconds>', found '{}'",
+                    value
+                )),
+            },
+            SettingType::Schedule => {
+                let fields = value.split(' ').collect::<Vec<_>>();
+                if fields.len() == 3
+                    && fields[0].parse::<u32>().map_or(false, |m| m < 60)
+                    && fields[1].parse::<u32>().map_or(false, |h| h < 24)
+                    && (fields[2] == "*"
+                        || fields[2]
+                            .parse::<u32>()
+                            .map_or(false, |d| (1..=7).contains(&d)))
+                {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "expected '<minute> <hour> <week-day or *>', found '{}'",
+                        value
+                    ))
+                }
+            }
+        }
+    }
+}
+
+impl Display for Unit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Unit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Unit::Count => "count",
+            Unit::Bytes => "bytes",
+            Unit::Milliseconds => "ms",
+            Unit::Seconds => "seconds",
+            Unit::Days => "days",
+        }
+    }
+}
+
+impl Display for SettingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+/// Looks up the declaration of a setting, panicking if it was never declared
+/// as that is a programming error rather than a configuration one.
+pub fn find_setting<'x>(schema: &'x [Setting], key: &str) -> &'x Setting {
+    schema
+        .iter()
+        .find(|setting| setting.key == key)
+        .unwrap_or_else(|| panic!("Setting '{}' was not declared.", key))
+}
+
+impl EnvSettings {
+    /// Validates all declared settings, returning every error found.
+    pub fn validate(&self, schema: &[SettingsSection]) -> Result<(), Vec<SettingError>> {
+        let mut errors = Vec::new();
+        for setting in schema.iter().flat_map(|(_, settings)| settings.iter()) {
+            if let Some(value) = self.get(setting.key) {
+                if let Err(message) = setting.validate(&value) {
+                    errors.push(SettingError {
+                        key: setting.key.to_string(),
+                        message,
+                    });
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Returns the keys present in the configuration that were not declared
+    /// by any subsystem, which usually are typos.
+    pub fn unknown_keys(&self, schema: &[SettingsSection]) -> Vec<String> {
+        let mut keys = self
+            .args
+            .keys()
+            .filter(|key| {
+                !schema
+                    .iter()
+                    .flat_map(|(_, settings)| settings.iter())
+                    .any(|setting| setting.key == key.as_str())
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Returns the value of every declared setting, with secrets redacted.
+    pub fn effective(&self, schema: &[SettingsSection]) -> Vec<EffectiveSetting> {
+        let mut settings = schema
+            .iter()
+            .flat_map(|(_, settings)| settings.iter())
+            .map(|setting| {
+                let value = self.get(setting.key);
+                let is_default = value.is_none();
+                EffectiveSetting {
+                    key: setting.key,
+                    value: if setting.is_secret() {
+                        value.map(|_| REDACTED.to_string())
+                    } else {
+                        value.or_else(|| setting.default.map(String::from))
+                    },
+                    is_default,
+                    unit: match setting.typ {
+                        SettingType::Integer { unit, .. } if unit != Unit::Count => {
+                            Some(unit.as_str())
+                        }
+                        _ => None,
+                    },
+                }
+            })
+            .collect::<Vec<_>>();
+        settings.sort_unstable_by_key(|setting| setting.key);
+        settings
+    }
+}
+
+/// Generates the Markdown configuration reference.
+pub fn reference(sections: &[SettingsSection]) -> String {
+    let mut reference = String::from("# Configuration reference\n");
+    for (name, settings) in sections {
+        reference.push_str("\n## ");
+        reference.push_str(name);
+        reference.push_str("\n\n| Key | Type | Default | Description |\n|---|---|---|---|\n");
+        for setting in settings.iter() {
+            let typ = match setting.typ {
+                SettingType::Bool => "boolean".to_string(),
+                SettingType::Integer { min, max, unit } => match (min, max) {
+                    (0, u64::MAX) => format!("integer ({})", unit),
+                    (min, u64::MAX) => format!("integer ({}, min. {})", unit, min),
+                    (min, max) => format!("integer ({}, {} to {})", unit, min, max),
+                },
+                SettingType::Text => "string".to_string(),
+                SettingType::Secret => "secret".to_string(),
+                SettingType::Path => "path".to_string(),
+                SettingType::Url => "URL".to_string(),
+                SettingType::IpAddr => "IP address".to_string(),
+                SettingType::List => "list".to_string(),
+                SettingType::Enum(values) => values.join(" \\| "),
+                SettingType::Rate => "requests/seconds".to_string(),
+                SettingType::Schedule => "minute hour week-day".to_string(),
+            };
+            reference.push_str(&format!(
+                "| `{}` | {} | {} | {} |\n",
+                setting.key,
+                typ,
+                setting
+                    .default
+                    .map(|value| format!("`{}`", value))
+                    .unwrap_or_default(),
+                setting.description
+            ));
+        }
+    }
+    reference
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{env_settings::EnvSettings, settings::SettingType};
+
+    use super::Setting;
+
+    #[test]
+    fn validate_settings() {
+        const SETTINGS: &[Setting] = &[
+            Setting::bool("flag").default("false"),
+            Setting::millis("timeout").min(1).default("1000"),
+            Setting::one_of("mode", &["query", "index"]).default("query"),
+            Setting::rate("limit").default("100/60"),
+            Setting::schedule("when").default("0 3 *"),
+            Setting::secret("key"),
+        ];
+        let schema = [("Test", SETTINGS)];
+        let mut settings = EnvSettings {
+            args: Default::default(),
+        };
+        assert_eq!(settings.validate(&schema), Ok(()));
+
+        for (key, value) in [
+            ("flag", "yes"),
+            ("timeout", "0"),
+            ("mode", "both"),
+            ("limit", "100"),
+            ("when", "0 25 *"),
+            ("key", "secret"),
+            ("unknown-key", "1"),
+        ] {
+            settings.set_value(key.to_string(), value.to_string());
+        }
+        assert_eq!(
+            settings
+                .validate(&schema)
+                .unwrap_err()
+                .into_iter()
+                .map(|err| err.key)
+                .collect::<Vec<_>>(),
+            vec!["flag", "timeout", "mode", "limit", "when"]
+        );
+        assert_eq!(settings.unknown_keys(&schema), vec!["unknown-key"]);
+        assert!(settings
+            .effective(&schema)
+            .iter()
+            .any(|s| s.key == "key" && s.value.as_deref() == Some(super::REDACTED)));
+    }
+
+    #[test]
+    fn declared_defaults_are_valid() {
+        for (_, settings) in crate::config::SCHEMA {
+            for setting in settings.iter() {
+                if let Some(default) = setting.default {
+                    assert!(
+                        setting.validate(default).is_ok(),
+                        "{}: {:?}",
+                        setting.key,
+                        setting.validate(default)
+                    );
+                }
+                if let SettingType::Integer { min, max, .. } = setting.typ {
+                    assert!(min <= max, "{}", setting.key);
+                }
+            }
+        }
+    }
+}
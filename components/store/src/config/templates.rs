@@ -0,0 +1,291 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::path::PathBuf;
+
+use ahash::AHashMap;
+use tracing::warn;
+
+use super::{
+    env_settings::{soft_panic, EnvSettings},
+    settings::Setting,
+};
+
+pub const TEMPLATE_VACATION: &str = "vacation";
+pub const TEMPLATE_DSN_FAILURE: &str = "dsn-failure";
+pub const TEMPLATE_WELCOME: &str = "welcome";
+
+pub const SETTINGS: &[Setting] = &[Setting::path("template-path")
+    .describe("Directory with <name>.txt and <name>.<locale>.html overrides")];
+
+// Templates without a locale in their file name apply to all locales.
+const DEFAULT_LOCALE: &str = "";
+
+const BUILTIN_TEMPLATES: &[(&str, &str)] = &[
+    (TEMPLATE_VACATION, "I am away.\r\n"),
+    (
+        TEMPLATE_DSN_FAILURE,
+        concat!(
+            "Subject: Undelivered Mail Returned to Sender\r\n",
+            "\r\n",
+            "Your message with subject \"{{subject}}\" could not be delivered ",
+            "to one or more recipients:\r\n",
+            "\r\n",
+            "{{recipients}}\r\n",
+            "\r\n",
+            "No further delivery attempts will be made.\r\n",
+        ),
+    ),
+    (
+        TEMPLATE_WELCOME,
+        concat!(
+            "Subject: Welcome to your new mailbox\r\n",
+            "\r\n",
+            "Hello {{name}},\r\n",
+            "\r\n",
+            "Your account <{{email}}> is ready to use.\r\n",
+        ),
+    ),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Template {
+    pub subject: Option<String>,
+    pub text: Option<String>,
+    pub html: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RenderedTemplate {
+    pub subject: Option<String>,
+    pub text: String,
+    pub html: Option<String>,
+}
+
+/// Templates used to render the messages generated by the server, keyed by
+/// template name and locale.
+#[derive(Debug, Default)]
+pub struct MessageTemplates {
+    templates: AHashMap<(String, String), Template>,
+}
+
+impl MessageTemplates {
+    /// Returns the template for the requested locale, falling back to the
+    /// template of its language and then to the locale independent one.
+    pub fn get(&self, name: &str, locale: &str) -> Option<&Template> {
+        let locale = locale.to_lowercase().replace('_', "-");
+        let language = locale.split('-').next().unwrap_or_default();
+        [locale.as_str(), language, DEFAULT_LOCALE]
+            .into_iter()
+            .filter_map(|locale| {
+                self.templates
+                    .get(&(name.to_string(), locale.to_string()))
+                    .filter(|template| template.text.is_some())
+            })
+            .next()
+    }
+
+    /// Renders a template replacing each `{{variable}}` with its value. Values
+    /// are HTML escaped when substituted in the HTML variant.
+    pub fn render(
+        &self,
+        name: &str,
+        locale: &str,
+        variables: &[(&str, &str)],
+    ) -> Option<RenderedTemplate> {
+        let template = self.get(name, locale)?;
+        Some(RenderedTemplate {
+            subject: template
+                .subject
+                .as_deref()
+                .map(|subject| substitute(subject, variables, false)),
+            text: substitute(template.text.as_deref()?, variables, false),
+            html: template
+                .html
+                .as_deref()
+                .map(|html| substitute(html, variables, true)),
+        })
+    }
+
+    fn insert(&mut self, name: &str, locale: &str, contents: String, is_html: bool) {
+        let template = self
+            .templates
+            .entry((name.to_string(), locale.to_string()))
+            .or_default();
+        if is_html {
+            template.html = contents.into();
+        } else if let Some((subject, text)) = contents
+            .strip_prefix("Subject:")
+            .and_then(|contents| contents.split_once('\n'))
+        {
+            template.subject = subject.trim().to_string().into();
+            template.text = text.trim_start_matches(['\r', '\n']).to_string().into();
+        } else {
+            template.text = contents.into();
+        }
+    }
+}
+
+impl From<&EnvSettings> for MessageTemplates {
+    /// Loads the built-in templates and overrides them with the files found
+    /// in the directory configured in `template-path`. Files are named
+    /// `<name>.txt` and `<name>.html`, or `<name>.<locale>.txt` and
+    /// `<name>.<locale>.html` for a specific locale. Text templates may start
+    /// with a `Subject:` line followed by a blank line.
+    fn from(settings: &EnvSettings) -> Self {
+        let mut templates = MessageTemplates::default();
+        for (name, contents) in BUILTIN_TEMPLATES {
+            templates.insert(name, DEFAULT_LOCALE, contents.to_string(), false);
+        }
+
+        let path = if let Some(path) = settings.get("template-path") {
+            PathBuf::from(path)
+        } else {
+            return templates;
+        };
+        let entries = std::fs::read_dir(&path).unwrap_or_else(|err| {
+            soft_panic(&format!(
+                "Failed to read template-path {}: {}",
+                path.display(),
+                err
+            ));
+        });
+        for entry in entries.flatten() {
+            let file_path = entry.path();
+            let (stem, is_html) = match (
+                file_path.file_stem().and_then(|s| s.to_str()),
+                file_path.extension().and_then(|s| s.to_str()),
+            ) {
+                (Some(stem), Some("txt")) => (stem.to_lowercase(), false),
+                (Some(stem), Some("html")) => (stem.to_lowercase(), true),
+                _ => continue,
+            };
+            let (name, locale) = stem
+                .split_once('.')
+                .map(|(name, locale)| (name.to_string(), locale.replace('_', "-")))
+                .unwrap_or((stem, DEFAULT_LOCALE.to_string()));
+            match std::fs::read_to_string(&file_path) {
+                Ok(contents) => templates.insert(&name, &locale, contents, is_html),
+                Err(err) => {
+                    soft_panic(&format!(
+                        "Failed to read template {}: {}",
+                        file_path.display(),
+                        err
+                    ));
+                }
+            }
+        }
+
+        for ((name, locale), template) in &templates.templates {
+            if template.text.is_none() {
+                warn!(
+                    "Template '{}' for locale '{}' has no text variant and will be ignored.",
+                    name, locale
+                );
+            }
+        }
+
+        templates
+    }
+}
+
+fn substitute(template: &str, variables: &[(&str, &str)], escape_html: bool) -> String {
+    let mut result = String::with_capacity(template.len() + 64);
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let (name, next) = if let Some(end) = rest[start + 2..].find("}}") {
+            (
+                rest[start + 2..start + 2 + end].trim(),
+                &rest[start + 2 + end + 2..],
+            )
+        } else {
+            break;
+        };
+        if let Some((_, value)) = variables.iter().find(|(var_name, _)| *var_name == name) {
+            if escape_html {
+                for ch in value.chars() {
+                    match ch {
+                        '&' => result.push_str("&amp;"),
+                        '<' => result.push_str("&lt;"),
+                        '>' => result.push_str("&gt;"),
+                        '"' => result.push_str("&quot;"),
+                        _ => result.push(ch),
+                    }
+                }
+            } else {
+                result.push_str(value);
+            }
+        }
+        rest = next;
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MessageTemplates, TEMPLATE_DSN_FAILURE};
+
+    #[test]
+    fn render_templates() {
+        let mut templates = MessageTemplates::default();
+        templates.insert(
+            TEMPLATE_DSN_FAILURE,
+            "",
+            "Subject: Failed: {{ subject }}\r\n\r\nTo: {{recipients}}{{unknown}}".to_string(),
+            false,
+        );
+        templates.insert(
+            TEMPLATE_DSN_FAILURE,
+            "",
+            "<p>{{subject}}</p>".to_string(),
+            true,
+        );
+        templates.insert(
+            TEMPLATE_DSN_FAILURE,
+            "es",
+            "Subject: Fallido\r\n\r\nPara: {{recipients}}".to_string(),
+            false,
+        );
+        // Templates without a text variant are ignored
+        templates.insert(TEMPLATE_DSN_FAILURE, "fr", "<p/>".to_string(), true);
+
+        let variables = [("subject", "<Hi>"), ("recipients", "jdoe@example.org")];
+        let rendered = templates
+            .render(TEMPLATE_DSN_FAILURE, "fr-CA", &variables)
+            .unwrap();
+        assert_eq!(rendered.subject.unwrap(), "Failed: <Hi>");
+        assert_eq!(rendered.text, "To: jdoe@example.org");
+        assert_eq!(rendered.html.unwrap(), "<p>&lt;Hi&gt;</p>");
+
+        let rendered = templates
+            .render(TEMPLATE_DSN_FAILURE, "es_MX", &variables)
+            .unwrap();
+        assert_eq!(rendered.subject.unwrap(), "Fallido");
+        assert_eq!(rendered.text, "Para: jdoe@example.org");
+        assert_eq!(rendered.html, None);
+
+        assert!(templates.render("unknown", "en", &variables).is_none());
+    }
+}
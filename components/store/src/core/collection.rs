@@ -35,6 +35,8 @@ pub enum Collection {
     EmailSubmission = 6,
     SieveScript = 7,
     None = 8,
+    // Private annotations are change-tracked apart from the Mail collection
+    Annotation = 9,
 }
 
 impl Default for Collection {
@@ -54,6 +56,7 @@ impl From<u8> for Collection {
             5 => Collection::Identity,
             6 => Collection::EmailSubmission,
             7 => Collection::SieveScript,
+            9 => Collection::Annotation,
             _ => {
                 debug_assert!(false, "Invalid collection value: {}", value);
                 Collection::None
@@ -85,6 +88,7 @@ impl From<u64> for Collection {
             5 => Collection::Identity,
             6 => Collection::EmailSubmission,
             7 => Collection::SieveScript,
+            9 => Collection::Annotation,
             _ => {
                 debug_assert!(false, "Invalid collection value: {}", value);
                 Collection::None
@@ -21,7 +21,7 @@
  * for more details.
 */
 
-use crate::{blob::BlobId, nlp::Language, write::field::Field, DocumentId, FieldId};
+use crate::{blob::BlobId, nlp::Language, write::field::Field, AccountId, DocumentId, FieldId};
 
 use super::{acl::Permission, collection::Collection, number::Number, tag::Tag};
 
@@ -46,6 +46,7 @@ pub struct Document {
     pub binary_fields: Vec<Field<Vec<u8>>>,
     pub tag_fields: Vec<Field<Tag>>,
     pub acls: Vec<(Permission, u64)>,
+    pub annotations: Vec<(AccountId, Vec<u8>, u64)>,
     pub blobs: Vec<(BlobId, u64)>,
 }
 
@@ -60,6 +61,7 @@ impl Document {
             tag_fields: Vec::new(),
             blobs: Vec::new(),
             acls: Vec::new(),
+            annotations: Vec::new(),
             term_index: None,
         }
     }
@@ -104,6 +106,10 @@ impl Document {
         self.acls.push((acl, options));
     }
 
+    pub fn annotation(&mut self, principal: AccountId, value: Vec<u8>, options: u64) {
+        self.annotations.push((principal, value, options));
+    }
+
     pub fn term_index(&mut self, blob: BlobId, options: u64) {
         self.term_index = Some((blob, options));
     }
@@ -113,5 +119,6 @@ impl Document {
             && self.number_fields.is_empty()
             && self.binary_fields.is_empty()
             && self.tag_fields.is_empty()
+            && self.annotations.is_empty()
     }
 }
@@ -32,6 +32,8 @@ pub enum StoreError {
     AnchorNotFound,
     DataCorruption(String),
     NotFound(String),
+    StaleFencingToken(u64, u64),
+    WritesBlocked(u32),
 }
 
 impl StoreError {
@@ -50,6 +52,16 @@ impl Display for StoreError {
             StoreError::AnchorNotFound => write!(f, "Anchor not found."),
             StoreError::DataCorruption(s) => write!(f, "Data corruption: {}", s),
             StoreError::NotFound(s) => write!(f, "Not found: {}", s),
+            StoreError::StaleFencingToken(token, current) => write!(
+                f,
+                "Rejected write with stale fencing token {} (current term is {}).",
+                token, current
+            ),
+            StoreError::WritesBlocked(account_id) => write!(
+                f,
+                "Writes to account {} are blocked by a maintenance task.",
+                account_id
+            ),
         }
     }
 }
@@ -0,0 +1,136 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use ahash::AHashMap;
+use parking_lot::Mutex;
+
+pub type KeywordId = u32;
+
+#[derive(Debug, Default)]
+struct KeywordIds {
+    ids: AHashMap<String, KeywordId>,
+    next_id: KeywordId,
+}
+
+// Interns the custom keywords in use by an account.
+#[derive(Debug, Default)]
+pub struct KeywordRegistry {
+    keywords: Mutex<KeywordIds>,
+}
+
+impl KeywordRegistry {
+    pub fn new(names: impl IntoIterator<Item = String>) -> Self {
+        let registry = KeywordRegistry::default();
+        {
+            let mut keywords = registry.keywords.lock();
+            for name in names {
+                keywords.intern(name);
+            }
+        }
+        registry
+    }
+
+    pub fn get_id(&self, name: &str) -> Option<KeywordId> {
+        self.keywords.lock().ids.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keywords.lock().ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keywords.lock().ids.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.keywords.lock().ids.keys().cloned().collect()
+    }
+
+    // Registers the given keywords, fails with the resulting number of keywords
+    // when registering them would exceed the limit (zero means no limit).
+    pub fn try_intern<'x>(
+        &self,
+        names: impl IntoIterator<Item = &'x str>,
+        max_keywords: usize,
+    ) -> Result<(), usize> {
+        let mut keywords = self.keywords.lock();
+        let new_names = names
+            .into_iter()
+            .filter(|name| !keywords.ids.contains_key(*name))
+            .collect::<Vec<_>>();
+        let total = keywords.ids.len() + new_names.len();
+
+        if max_keywords > 0 && total > max_keywords && !new_names.is_empty() {
+            Err(total)
+        } else {
+            for name in new_names {
+                keywords.intern(name.to_string());
+            }
+            Ok(())
+        }
+    }
+
+    // Removes keywords that are no longer in use. Ids are not reused.
+    pub fn retain(&self, mut in_use: impl FnMut(&str) -> bool) -> usize {
+        let mut keywords = self.keywords.lock();
+        let len = keywords.ids.len();
+        keywords.ids.retain(|name, _| in_use(name));
+        len - keywords.ids.len()
+    }
+}
+
+impl KeywordIds {
+    fn intern(&mut self, name: String) -> KeywordId {
+        let next_id = &mut self.next_id;
+        *self.ids.entry(name).or_insert_with(|| {
+            let id = *next_id;
+            *next_id += 1;
+            id
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeywordRegistry;
+
+    #[test]
+    fn keyword_registry() {
+        let registry = KeywordRegistry::new(["a".to_string(), "b".to_string()]);
+        assert_eq!(registry.get_id("a"), Some(0));
+        assert_eq!(registry.get_id("b"), Some(1));
+
+        // Known keywords never exceed the limit
+        assert_eq!(registry.try_intern(["a", "b"], 2), Ok(()));
+        assert_eq!(registry.try_intern(["a", "c"], 2), Err(3));
+        assert_eq!(registry.get_id("c"), None);
+        assert_eq!(registry.try_intern(["c"], 0), Ok(()));
+        assert_eq!(registry.get_id("c"), Some(2));
+
+        // Removed keywords free up space, ids are not reused
+        assert_eq!(registry.retain(|name| name != "a"), 1);
+        assert_eq!(registry.try_intern(["d"], 3), Ok(()));
+        assert_eq!(registry.get_id("d"), Some(3));
+        assert_eq!(registry.len(), 3);
+    }
+}
@@ -30,8 +30,12 @@ pub mod bitmap;
 pub mod collection;
 pub mod document;
 pub mod error;
+pub mod keyword;
 pub mod number;
+pub mod quota;
 pub mod tag;
+pub mod thread;
+pub mod timing;
 pub mod vec_map;
 
 pub trait JMAPIdPrefix {
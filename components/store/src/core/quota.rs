@@ -0,0 +1,372 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{sync::Arc, time::Instant};
+
+use parking_lot::Mutex;
+
+use crate::config::jmap::JMAPConfig;
+
+const HOUR: f64 = 3600.0;
+const DAY: f64 = 86400.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmissionQuotaError {
+    MessagesPerHour,
+    MessagesPerDay,
+    RecipientsPerDay,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    rate: f64,
+}
+
+#[derive(Debug)]
+struct SubmissionBuckets {
+    last_update: Instant,
+    messages_hour: Option<Bucket>,
+    messages_day: Option<Bucket>,
+    recipients_day: Option<Bucket>,
+}
+
+#[derive(Debug)]
+pub struct SubmissionQuota {
+    buckets: Mutex<SubmissionBuckets>,
+}
+
+/// Tokens taken by submissions that are not committed yet, they are returned
+/// to the quota if the reservation is dropped before being committed.
+pub struct SubmissionReservation {
+    quota: Arc<SubmissionQuota>,
+    num_messages: usize,
+    num_recipients: usize,
+}
+
+impl Bucket {
+    fn new(limit: u64, burst: u64, interval: f64) -> Option<Self> {
+        if limit > 0 {
+            let capacity = (limit + burst) as f64;
+            Some(Bucket {
+                tokens: capacity,
+                capacity,
+                rate: limit as f64 / interval,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn refill(&mut self, elapsed: f64) {
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+    }
+
+    fn has_tokens(&self, tokens: f64) -> bool {
+        self.tokens >= tokens
+    }
+
+    fn consume(&mut self, tokens: f64) {
+        self.tokens = (self.tokens - tokens).max(0.0);
+    }
+
+    fn refund(&mut self, tokens: f64) {
+        self.tokens = (self.tokens + tokens).min(self.capacity);
+    }
+}
+
+impl SubmissionQuota {
+    pub fn new(config: &JMAPConfig) -> Self {
+        SubmissionQuota {
+            buckets: Mutex::new(SubmissionBuckets {
+                last_update: Instant::now(),
+                messages_hour: Bucket::new(
+                    config.submission_max_messages_hour,
+                    config.submission_burst,
+                    HOUR,
+                ),
+                messages_day: Bucket::new(
+                    config.submission_max_messages_day,
+                    config.submission_burst,
+                    DAY,
+                ),
+                recipients_day: Bucket::new(
+                    config.submission_max_recipients_day,
+                    config.submission_burst,
+                    DAY,
+                ),
+            }),
+        }
+    }
+
+    pub fn is_enabled(config: &JMAPConfig) -> bool {
+        config.submission_max_messages_hour > 0
+            || config.submission_max_messages_day > 0
+            || config.submission_max_recipients_day > 0
+    }
+
+    /// Rebuilds the quota from the submissions of the last day, given as pairs of
+    /// UNIX timestamp and number of recipients. Submissions are replicated, so this
+    /// keeps the limits in place when another node becomes the leader.
+    pub fn with_history(config: &JMAPConfig, history: Vec<(u64, usize)>, now: u64) -> Self {
+        let quota = SubmissionQuota::new(config);
+        quota.replay(history, now);
+        quota
+    }
+
+    fn replay(&self, mut history: Vec<(u64, usize)>, now: u64) {
+        history.sort_unstable();
+
+        let mut guard = self.buckets.lock();
+        let buckets = &mut *guard;
+        let mut last_update = now.saturating_sub(DAY as u64);
+        for (timestamp, num_recipients) in history {
+            // Submissions held for later delivery are counted as of now
+            let timestamp = timestamp.clamp(last_update, now);
+            let elapsed = (timestamp - last_update) as f64;
+            last_update = timestamp;
+
+            for (bucket, tokens) in [
+                (&mut buckets.messages_hour, 1.0),
+                (&mut buckets.messages_day, 1.0),
+                (&mut buckets.recipients_day, num_recipients as f64),
+            ] {
+                if let Some(bucket) = bucket {
+                    bucket.refill(elapsed);
+                    bucket.consume(tokens);
+                }
+            }
+        }
+
+        let elapsed = (now - last_update) as f64;
+        for bucket in [
+            &mut buckets.messages_hour,
+            &mut buckets.messages_day,
+            &mut buckets.recipients_day,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            bucket.refill(elapsed);
+        }
+    }
+
+    // Token bucket limiter, tokens are only consumed when all limits allow the submission.
+    pub fn try_consume(&self, num_recipients: usize) -> Result<(), SubmissionQuotaError> {
+        let mut guard = self.buckets.lock();
+        let buckets = &mut *guard;
+        let elapsed = buckets.last_update.elapsed().as_secs_f64();
+        buckets.last_update = Instant::now();
+
+        let num_recipients = num_recipients as f64;
+        for (bucket, tokens, error) in [
+            (
+                &mut buckets.messages_hour,
+                1.0,
+                SubmissionQuotaError::MessagesPerHour,
+            ),
+            (
+                &mut buckets.messages_day,
+                1.0,
+                SubmissionQuotaError::MessagesPerDay,
+            ),
+            (
+                &mut buckets.recipients_day,
+                num_recipients,
+                SubmissionQuotaError::RecipientsPerDay,
+            ),
+        ] {
+            if let Some(bucket) = bucket {
+                bucket.refill(elapsed);
+                if !bucket.has_tokens(tokens) {
+                    return Err(error);
+                }
+            }
+        }
+
+        for (bucket, tokens) in [
+            (&mut buckets.messages_hour, 1.0),
+            (&mut buckets.messages_day, 1.0),
+            (&mut buckets.recipients_day, num_recipients),
+        ] {
+            if let Some(bucket) = bucket {
+                bucket.consume(tokens);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn refund(&self, num_messages: usize, num_recipients: usize) {
+        let mut guard = self.buckets.lock();
+        let buckets = &mut *guard;
+        for (bucket, tokens) in [
+            (&mut buckets.messages_hour, num_messages),
+            (&mut buckets.messages_day, num_messages),
+            (&mut buckets.recipients_day, num_recipients),
+        ] {
+            if let Some(bucket) = bucket {
+                bucket.refund(tokens as f64);
+            }
+        }
+    }
+}
+
+impl SubmissionReservation {
+    pub fn new(quota: Arc<SubmissionQuota>) -> Self {
+        SubmissionReservation {
+            quota,
+            num_messages: 0,
+            num_recipients: 0,
+        }
+    }
+
+    pub fn try_consume(&mut self, num_recipients: usize) -> Result<(), SubmissionQuotaError> {
+        self.quota.try_consume(num_recipients)?;
+        self.num_messages += 1;
+        self.num_recipients += num_recipients;
+        Ok(())
+    }
+
+    /// Keeps the consumed tokens once the submissions have been written.
+    pub fn commit(mut self) {
+        self.num_messages = 0;
+        self.num_recipients = 0;
+    }
+}
+
+impl Drop for SubmissionReservation {
+    fn drop(&mut self) {
+        if self.num_messages > 0 {
+            self.quota.refund(self.num_messages, self.num_recipients);
+        }
+    }
+}
+
+impl SubmissionQuotaError {
+    pub fn description(&self) -> &'static str {
+        match self {
+            SubmissionQuotaError::MessagesPerHour => {
+                "Hourly message submission limit exceeded, please try again later."
+            }
+            SubmissionQuotaError::MessagesPerDay => {
+                "Daily message submission limit exceeded, please try again later."
+            }
+            SubmissionQuotaError::RecipientsPerDay => {
+                "Daily recipient limit exceeded, please try again later."
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Bucket, SubmissionBuckets, SubmissionQuota, SubmissionQuotaError, SubmissionReservation,
+        DAY, HOUR,
+    };
+    use parking_lot::Mutex;
+    use std::{sync::Arc, time::Instant};
+
+    fn quota(hour: u64, day: u64, rcpt_day: u64, burst: u64) -> SubmissionQuota {
+        SubmissionQuota {
+            buckets: Mutex::new(SubmissionBuckets {
+                last_update: Instant::now(),
+                messages_hour: Bucket::new(hour, burst, HOUR),
+                messages_day: Bucket::new(day, burst, DAY),
+                recipients_day: Bucket::new(rcpt_day, burst, DAY),
+            }),
+        }
+    }
+
+    #[test]
+    fn submission_quota() {
+        // Hourly limit with burst allowance
+        let limiter = quota(2, 0, 0, 1);
+        for _ in 0..3 {
+            assert_eq!(limiter.try_consume(1), Ok(()));
+        }
+        assert_eq!(
+            limiter.try_consume(1),
+            Err(SubmissionQuotaError::MessagesPerHour)
+        );
+
+        // Daily recipient limit, rejected submissions do not consume tokens
+        let limiter = quota(0, 10, 5, 0);
+        assert_eq!(limiter.try_consume(3), Ok(()));
+        assert_eq!(
+            limiter.try_consume(3),
+            Err(SubmissionQuotaError::RecipientsPerDay)
+        );
+        assert_eq!(limiter.try_consume(2), Ok(()));
+        assert_eq!(
+            limiter.try_consume(1),
+            Err(SubmissionQuotaError::RecipientsPerDay)
+        );
+
+        // Unlimited
+        let limiter = quota(0, 0, 0, 0);
+        for _ in 0..100 {
+            assert_eq!(limiter.try_consume(100), Ok(()));
+        }
+
+        // Uncommitted reservations are returned to the quota
+        let limiter = Arc::new(quota(0, 2, 0, 0));
+        let mut reservation = SubmissionReservation::new(limiter.clone());
+        assert_eq!(reservation.try_consume(1), Ok(()));
+        assert_eq!(reservation.try_consume(1), Ok(()));
+        assert_eq!(
+            limiter.try_consume(1),
+            Err(SubmissionQuotaError::MessagesPerDay)
+        );
+        drop(reservation);
+        let mut reservation = SubmissionReservation::new(limiter.clone());
+        assert_eq!(reservation.try_consume(1), Ok(()));
+        reservation.commit();
+        assert_eq!(limiter.try_consume(1), Ok(()));
+        assert_eq!(
+            limiter.try_consume(1),
+            Err(SubmissionQuotaError::MessagesPerDay)
+        );
+
+        // Submissions stored by other nodes are replayed, older ones have been refilled
+        let now = 10 * DAY as u64;
+        let limiter = quota(2, 0, 0, 0);
+        limiter.replay(
+            vec![(now - 60, 1), (now - 2 * HOUR as u64, 1), (now + 60, 1)],
+            now,
+        );
+        assert_eq!(
+            limiter.try_consume(1),
+            Err(SubmissionQuotaError::MessagesPerHour)
+        );
+        let limiter = quota(2, 0, 0, 0);
+        limiter.replay(vec![(now - 60, 1), (now - 2 * HOUR as u64, 1)], now);
+        assert_eq!(limiter.try_consume(1), Ok(()));
+        assert_eq!(
+            limiter.try_consume(1),
+            Err(SubmissionQuotaError::MessagesPerHour)
+        );
+    }
+}
@@ -0,0 +1,55 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use roaring::RoaringBitmap;
+
+use crate::{AccountId, JMAPStore, Store};
+
+/// Thread aggregates derived from immutable message properties. They remain valid
+/// for as long as the thread contains the same messages they were computed from.
+#[derive(Debug, Default)]
+pub struct ThreadAggregate {
+    pub document_ids: RoaringBitmap,
+    pub received_at: Option<i64>,
+    // Sender names and addresses, most recent first
+    pub participants: Vec<(Option<String>, String)>,
+}
+
+impl<T> JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Drops the cached thread aggregates of an account, required whenever
+    /// the document ids of deleted messages are about to be reused.
+    pub fn invalidate_thread_previews(&self, account_id: AccountId) {
+        if self
+            .thread_previews
+            .invalidate_entries_if(move |(cached_account_id, _), _| {
+                *cached_account_id == account_id
+            })
+            .is_err()
+        {
+            self.thread_previews.invalidate_all();
+        }
+    }
+}
@@ -0,0 +1,162 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+thread_local! {
+    static READ_TIMINGS: Cell<Option<ReadTimings>> = Cell::new(None);
+    static IN_BLOB_READ: Cell<bool> = Cell::new(false);
+}
+
+/// Time spent reading from the database and the blob store by the
+/// current thread while a trace is active.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReadTimings {
+    pub store_reads: u32,
+    pub store_read_time: Duration,
+    pub blob_reads: u32,
+    pub blob_read_time: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ReadKind {
+    Store,
+    StoreStep,
+    Blob,
+}
+
+pub struct ReadTimer {
+    kind: ReadKind,
+    started: Option<Instant>,
+}
+
+impl ReadTimings {
+    /// Starts collecting read timings on the current thread.
+    pub fn start() {
+        READ_TIMINGS.with(|t| t.set(Some(ReadTimings::default())));
+    }
+
+    /// Stops collecting read timings on the current thread and returns
+    /// the totals, if a trace was active.
+    pub fn finish() -> Option<ReadTimings> {
+        READ_TIMINGS.with(|t| t.take())
+    }
+
+    fn is_active() -> bool {
+        READ_TIMINGS.with(|t| t.get().is_some())
+    }
+}
+
+impl ReadTimer {
+    pub fn store() -> Self {
+        ReadTimer {
+            kind: ReadKind::Store,
+            // Database reads issued while fetching a blob are accounted as blob reads.
+            started: if ReadTimings::is_active() && !IN_BLOB_READ.with(|b| b.get()) {
+                Instant::now().into()
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Times advancing an iterator, which adds to the read time without
+    /// counting as a separate read.
+    pub fn store_step() -> Self {
+        ReadTimer {
+            kind: ReadKind::StoreStep,
+            started: if ReadTimings::is_active() && !IN_BLOB_READ.with(|b| b.get()) {
+                Instant::now().into()
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn blob() -> Self {
+        ReadTimer {
+            kind: ReadKind::Blob,
+            started: if ReadTimings::is_active() && !IN_BLOB_READ.with(|b| b.replace(true)) {
+                Instant::now().into()
+            } else {
+                None
+            },
+        }
+    }
+}
+
+impl Drop for ReadTimer {
+    fn drop(&mut self) {
+        if let Some(started) = self.started {
+            let elapsed = started.elapsed();
+            READ_TIMINGS.with(|t| {
+                if let Some(mut timings) = t.get() {
+                    match self.kind {
+                        ReadKind::Store => {
+                            timings.store_reads += 1;
+                            timings.store_read_time += elapsed;
+                        }
+                        ReadKind::StoreStep => {
+                            timings.store_read_time += elapsed;
+                        }
+                        ReadKind::Blob => {
+                            timings.blob_reads += 1;
+                            timings.blob_read_time += elapsed;
+                        }
+                    }
+                    t.set(Some(timings));
+                }
+            });
+            if let ReadKind::Blob = self.kind {
+                IN_BLOB_READ.with(|b| b.set(false));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReadTimer, ReadTimings};
+
+    #[test]
+    fn read_timings() {
+        // Nothing is recorded without an active trace
+        drop(ReadTimer::store());
+        assert_eq!(ReadTimings::finish(), None);
+
+        ReadTimings::start();
+        drop(ReadTimer::store());
+        {
+            let _blob = ReadTimer::blob();
+            drop(ReadTimer::store());
+        }
+        drop(ReadTimer::blob());
+        let timings = ReadTimings::finish().unwrap();
+        assert_eq!(timings.store_reads, 1);
+        assert_eq!(timings.blob_reads, 2);
+        assert_eq!(ReadTimings::finish(), None);
+    }
+}
@@ -21,6 +21,7 @@
  * for more details.
 */
 
+pub mod backup;
 pub mod blob;
 pub mod config;
 pub mod core;
@@ -31,24 +32,32 @@ pub mod serialize;
 pub mod write;
 
 use crate::core::acl::ACL;
+use crate::core::keyword::KeywordRegistry;
+use crate::core::quota::SubmissionQuota;
+use crate::core::thread::ThreadAggregate;
 use crate::core::{acl::ACLToken, collection::Collection, error::StoreError};
 use crate::nlp::Language;
-use blob::local::LocalBlobStore;
-use blob::BlobStore;
-use config::{env_settings::EnvSettings, jmap::JMAPConfig};
-use log::raft::{LogIndex, RaftId};
+use blob::{BlobId, BlobStorage, BlobStore};
+use config::{env_settings::EnvSettings, jmap::JMAPConfig, nlp::NLPConfig, settings::Setting};
+use log::raft::{LogIndex, RaftId, TermId};
 use moka::sync::Cache;
-use parking_lot::{Mutex, MutexGuard};
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use read::cache::BitmapCache;
 use roaring::RoaringBitmap;
-use serialize::StoreDeserialize;
+use serialize::key::{FENCING_TOKEN_KEY, NLP_CONFIG_KEY};
+use serialize::{StoreDeserialize, StoreSerialize};
 use sieve::{Compiler, Runtime};
+use std::path::Path;
 use std::sync::atomic::AtomicBool;
 use std::{
     sync::{atomic::AtomicU64, Arc},
     time::Duration,
 };
+use tracing::warn;
 use write::{
+    group_commit::GroupCommit,
     id_assign::{IdAssigner, IdCacheKey},
+    metrics::WriteMetrics,
     mutex_map::MutexMap,
     operation::WriteOperation,
 };
@@ -78,6 +87,66 @@ pub type LongInteger = u64;
 pub type Float = f64;
 pub type JMAPId = u64;
 
+pub const SETTINGS: &[Setting] = &[
+    Setting::path("db-path").default("/usr/local/stalwart-jmap/data"),
+    Setting::bytes("cache-size-ids").default("33554432"),
+    Setting::seconds("cache-tti-ids").default("3600"),
+    Setting::integer("id-lease-size")
+        .min(1)
+        .default("64")
+        .describe("Document ids reserved at a time per account and collection"),
+    Setting::seconds("cache-tti-sharings").default("300"),
+    Setting::seconds("cache-tti-acl").default("3600"),
+    Setting::seconds("cache-tti-recipients").default("86400"),
+    Setting::seconds("cache-tti-keywords").default("3600"),
+    Setting::integer("cache-size-query-snapshots").default("1024"),
+    Setting::seconds("cache-tti-query-snapshots")
+        .default("300")
+        .describe("Results kept for queries paginated with pinnedQueryState"),
+    Setting::bytes("cache-size-body-conversions")
+        .default("16777216")
+        .describe("Text and HTML body values generated for messages lacking that format"),
+    Setting::integer("cache-size-thread-previews")
+        .default("4096")
+        .describe("Threads whose participants and latest received date are kept in memory"),
+    Setting::millis("group-commit-window")
+        .default("0")
+        .describe("Time to wait for concurrent writes to coalesce"),
+    Setting::integer("group-commit-max-batches")
+        .min(1)
+        .default("256"),
+    Setting::millis("slow-commit-threshold")
+        .default("1000")
+        .describe("Commits slower than this are logged, 0 = disabled"),
+    Setting::bytes("sieve-max-script-size").default("1048576"),
+    Setting::bytes("sieve-max-string-size").default("4096"),
+    Setting::bytes("sieve-max-variable-name-size").default("32"),
+    Setting::integer("sieve-max-nested-blocks").default("15"),
+    Setting::integer("sieve-max-nested-tests").default("15"),
+    Setting::integer("sieve-max-nested-foreverypart").default("3"),
+    Setting::integer("sieve-max-match-variables").default("30"),
+    Setting::integer("sieve-max-local-variables").default("128"),
+    Setting::bytes("sieve-max-header-size").default("1024"),
+    Setting::integer("sieve-max-includes").default("3"),
+    Setting::integer("sieve-max-nested-includes").default("3"),
+    Setting::integer("sieve-cpu-limit").default("5000"),
+    Setting::bytes("sieve-max-variable-size").default("4096"),
+    Setting::integer("sieve-max-redirects").default("1"),
+    Setting::integer("sieve-max-received-headers").default("10"),
+    Setting::integer("sieve-max-outgoing-messages").default("3"),
+    Setting::seconds("sieve-default-vacation-expiry").default("2592000"),
+    Setting::seconds("sieve-default-duplicate-expiry").default("604800"),
+    Setting::text("sieve-disable-capabilities").describe("Space separated"),
+    Setting::text("sieve-notification-uris")
+        .default("mailto")
+        .describe("Space separated"),
+    Setting::text("sieve-protected-headers")
+        .default("Original-Subject Original-From Received Auto-Submitted")
+        .describe("Space separated"),
+    Setting::text("sieve-vacation-default-subject").default("Automated reply"),
+    Setting::text("sieve-vacation-subject-prefix").default("Auto: "),
+];
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Ord, PartialOrd)]
 pub enum ColumnFamily {
     Bitmaps,
@@ -97,6 +166,7 @@ where
     Self: Sized + Send + Sync,
 {
     type Iterator: Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'x;
+    type Cursor: StoreCursor + 'x;
 
     fn open(settings: &EnvSettings) -> Result<Self>;
     fn delete(&self, cf: ColumnFamily, key: &[u8]) -> Result<()>;
@@ -118,10 +188,31 @@ where
         start: &[u8],
         direction: Direction,
     ) -> Result<Self::Iterator>;
+    fn cursor<'y: 'x>(
+        &'y self,
+        cf: ColumnFamily,
+        start: &[u8],
+        direction: Direction,
+    ) -> Result<Self::Cursor>;
     fn compact(&self, cf: ColumnFamily) -> Result<()>;
+    fn compact_range(&self, cf: ColumnFamily, from: &[u8], to: &[u8]) -> Result<()>;
+    /// Size in bytes of the data files in use, which shrinks once compaction
+    /// drops deleted keys.
+    fn disk_usage(&self) -> Result<u64>;
+    fn checkpoint(&self, path: &Path) -> Result<()>;
     fn close(&self) -> Result<()>;
 }
 
+/// Iterates over a column family without copying keys or values, which
+/// remain valid until the cursor is advanced.
+pub trait StoreCursor {
+    /// Moves to the next entry, returning `false` once the cursor is exhausted
+    /// or an error if iteration stopped because of a read failure.
+    fn advance(&mut self) -> Result<bool>;
+    fn key(&self) -> &[u8];
+    fn value(&self) -> &[u8];
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct SharedResource {
     pub owner_id: AccountId,
@@ -135,25 +226,38 @@ pub enum RecipientType {
     Individual(AccountId),
     List(Vec<(AccountId, String)>),
     NotFound,
+    SubAddress(AccountId, String),
 }
 
 pub struct JMAPStore<T> {
     pub db: T,
-    pub blob_store: LocalBlobStore,
+    pub blob_store: BlobStorage,
     pub config: JMAPConfig,
 
     pub account_lock: MutexMap<()>,
+    pub write_gates: RwLock<ahash::AHashMap<AccountId, std::thread::ThreadId>>,
+    pub group_commit: GroupCommit,
+    pub write_metrics: WriteMetrics,
 
     pub sieve_compiler: Compiler,
     pub sieve_runtime: Runtime,
 
     pub id_assigner: Cache<IdCacheKey, Arc<Mutex<IdAssigner>>>,
+    pub id_lease_size: DocumentId,
+    pub id_lease_lock: MutexMap<()>,
     pub shared_documents: Cache<SharedResource, Arc<Option<RoaringBitmap>>>,
     pub acl_tokens: Cache<AccountId, Arc<ACLToken>>,
     pub recipients: Cache<String, Arc<RecipientType>>,
+    pub submission_quotas: Cache<AccountId, Arc<SubmissionQuota>>,
+    pub keywords: Cache<AccountId, Arc<KeywordRegistry>>,
+    pub query_snapshots: Cache<(AccountId, u64), Arc<Vec<JMAPId>>>,
+    pub body_conversions: Cache<(BlobId, u32), Arc<String>>,
+    pub thread_previews: Cache<(AccountId, DocumentId), Arc<ThreadAggregate>>,
+    pub bitmap_cache: BitmapCache,
 
     pub raft_term: AtomicU64,
     pub raft_index: AtomicU64,
+    pub fencing_token: RwLock<TermId>,
     pub tombstone_deletions: AtomicBool,
 }
 
@@ -161,79 +265,109 @@ impl<T> JMAPStore<T>
 where
     T: for<'x> Store<'x> + 'static,
 {
-    pub fn new(db: T, config: JMAPConfig, settings: &EnvSettings) -> Self {
+    pub fn new(db: T, config: JMAPConfig, settings: &EnvSettings) -> crate::Result<Self> {
         let mut store = Self {
             config,
-            blob_store: LocalBlobStore::new(settings).unwrap(),
+            blob_store: BlobStorage::new(settings)?,
             id_assigner: Cache::builder()
                 .initial_capacity(128)
-                .max_capacity(settings.parse("cache-size-ids").unwrap_or(32 * 1024 * 1024))
+                .max_capacity(settings.value(SETTINGS, "cache-size-ids"))
                 .time_to_idle(Duration::from_secs(
-                    settings.parse("cache-tti-ids").unwrap_or(3600),
+                    settings.value(SETTINGS, "cache-tti-ids"),
                 ))
                 .build(),
+            id_lease_size: settings
+                .value::<DocumentId>(SETTINGS, "id-lease-size")
+                .max(1),
+            id_lease_lock: MutexMap::with_capacity(1024),
             shared_documents: Cache::builder()
                 .initial_capacity(128)
                 .time_to_idle(Duration::from_secs(
-                    settings.parse("cache-tti-sharings").unwrap_or(300),
+                    settings.value(SETTINGS, "cache-tti-sharings"),
                 ))
                 .build(),
             acl_tokens: Cache::builder()
                 .initial_capacity(128)
                 .time_to_idle(Duration::from_secs(
-                    settings.parse("cache-tti-acl").unwrap_or(3600),
+                    settings.value(SETTINGS, "cache-tti-acl"),
                 ))
                 .build(),
             recipients: Cache::builder()
                 .initial_capacity(128)
                 .time_to_idle(Duration::from_secs(
-                    settings.parse("cache-tti-recipients").unwrap_or(86400),
+                    settings.value(SETTINGS, "cache-tti-recipients"),
+                ))
+                .build(),
+            submission_quotas: Cache::builder()
+                .initial_capacity(128)
+                .time_to_idle(Duration::from_secs(86400))
+                .build(),
+            keywords: Cache::builder()
+                .initial_capacity(128)
+                .time_to_idle(Duration::from_secs(
+                    settings.value(SETTINGS, "cache-tti-keywords"),
+                ))
+                .build(),
+            query_snapshots: Cache::builder()
+                .initial_capacity(128)
+                .max_capacity(settings.value(SETTINGS, "cache-size-query-snapshots"))
+                .time_to_idle(Duration::from_secs(
+                    settings.value(SETTINGS, "cache-tti-query-snapshots"),
                 ))
                 .build(),
+            body_conversions: Cache::builder()
+                .max_capacity(settings.value(SETTINGS, "cache-size-body-conversions"))
+                .weigher(|_: &(BlobId, u32), body: &Arc<String>| {
+                    body.len().try_into().unwrap_or(u32::MAX)
+                })
+                .build(),
+            thread_previews: Cache::builder()
+                .initial_capacity(128)
+                .max_capacity(settings.value(SETTINGS, "cache-size-thread-previews"))
+                .support_invalidation_closures()
+                .build(),
+            bitmap_cache: BitmapCache::new(settings),
             account_lock: MutexMap::with_capacity(1024),
+            write_gates: RwLock::new(ahash::AHashMap::new()),
+            group_commit: GroupCommit::new(
+                Duration::from_millis(settings.value(SETTINGS, "group-commit-window")),
+                settings.value(SETTINGS, "group-commit-max-batches"),
+            ),
+            write_metrics: WriteMetrics::new(Duration::from_millis(
+                settings.value(SETTINGS, "slow-commit-threshold"),
+            )),
             raft_index: 0.into(),
             raft_term: 0.into(),
+            fencing_token: RwLock::new(0),
             tombstone_deletions: false.into(),
             sieve_compiler: Compiler::new()
-                .with_max_script_size(
-                    settings
-                        .parse("sieve-max-script-size")
-                        .unwrap_or(1024 * 1024),
-                )
-                .with_max_string_size(settings.parse("sieve-max-string-size").unwrap_or(4096))
+                .with_max_script_size(settings.value(SETTINGS, "sieve-max-script-size"))
+                .with_max_string_size(settings.value(SETTINGS, "sieve-max-string-size"))
                 .with_max_variable_name_size(
-                    settings.parse("sieve-max-variable-name-size").unwrap_or(32),
+                    settings.value(SETTINGS, "sieve-max-variable-name-size"),
                 )
-                .with_max_nested_blocks(settings.parse("sieve-max-nested-blocks").unwrap_or(15))
-                .with_max_nested_tests(settings.parse("sieve-max-nested-tests").unwrap_or(15))
+                .with_max_nested_blocks(settings.value(SETTINGS, "sieve-max-nested-blocks"))
+                .with_max_nested_tests(settings.value(SETTINGS, "sieve-max-nested-tests"))
                 .with_max_nested_foreverypart(
-                    settings.parse("sieve-max-nested-foreverypart").unwrap_or(3),
+                    settings.value(SETTINGS, "sieve-max-nested-foreverypart"),
                 )
-                .with_max_match_variables(settings.parse("sieve-max-match-variables").unwrap_or(30))
-                .with_max_local_variables(
-                    settings.parse("sieve-max-local-variables").unwrap_or(128),
-                )
-                .with_max_header_size(settings.parse("sieve-max-header-size").unwrap_or(1024))
-                .with_max_includes(settings.parse("sieve-max-includes").unwrap_or(3)),
+                .with_max_match_variables(settings.value(SETTINGS, "sieve-max-match-variables"))
+                .with_max_local_variables(settings.value(SETTINGS, "sieve-max-local-variables"))
+                .with_max_header_size(settings.value(SETTINGS, "sieve-max-header-size"))
+                .with_max_includes(settings.value(SETTINGS, "sieve-max-includes")),
             sieve_runtime: Runtime::new()
-                .with_max_nested_includes(settings.parse("sieve-max-nested-includes").unwrap_or(3))
-                .with_cpu_limit(settings.parse("sieve-cpu-limit").unwrap_or(5000))
-                .with_max_variable_size(settings.parse("sieve-max-variable-size").unwrap_or(4096))
-                .with_max_redirects(settings.parse("sieve-max-redirects").unwrap_or(1))
-                .with_max_received_headers(
-                    settings.parse("sieve-max-received-headers").unwrap_or(10),
-                )
-                .with_max_header_size(settings.parse("sieve-max-header-size").unwrap_or(1024))
-                .with_max_out_messages(settings.parse("sieve-max-outgoing-messages").unwrap_or(3))
+                .with_max_nested_includes(settings.value(SETTINGS, "sieve-max-nested-includes"))
+                .with_cpu_limit(settings.value(SETTINGS, "sieve-cpu-limit"))
+                .with_max_variable_size(settings.value(SETTINGS, "sieve-max-variable-size"))
+                .with_max_redirects(settings.value(SETTINGS, "sieve-max-redirects"))
+                .with_max_received_headers(settings.value(SETTINGS, "sieve-max-received-headers"))
+                .with_max_header_size(settings.value(SETTINGS, "sieve-max-header-size"))
+                .with_max_out_messages(settings.value(SETTINGS, "sieve-max-outgoing-messages"))
                 .with_default_vacation_expiry(
-                    settings
-                        .parse("sieve-default-vacation-expiry")
-                        .unwrap_or(30 * 86400),
+                    settings.value(SETTINGS, "sieve-default-vacation-expiry"),
                 )
                 .with_default_duplicate_expiry(
-                    settings
-                        .parse("sieve-default-duplicate-expiry")
-                        .unwrap_or(7 * 86400),
+                    settings.value(SETTINGS, "sieve-default-duplicate-expiry"),
                 )
                 .without_capabilities(
                     settings
@@ -244,8 +378,7 @@ where
                 )
                 .with_valid_notification_uris(
                     settings
-                        .get("sieve-notification-uris")
-                        .unwrap_or_else(|| "mailto".to_string())
+                        .value::<String>(SETTINGS, "sieve-notification-uris")
                         .split_ascii_whitespace()
                         .filter_map(|c| {
                             if !c.is_empty() {
@@ -257,10 +390,7 @@ where
                 )
                 .with_protected_headers(
                     settings
-                        .get("sieve-protected-headers")
-                        .unwrap_or_else(|| {
-                            "Original-Subject Original-From Received Auto-Submitted".to_string()
-                        })
+                        .value::<String>(SETTINGS, "sieve-protected-headers")
                         .split_ascii_whitespace()
                         .filter_map(|c| {
                             if !c.is_empty() {
@@ -271,14 +401,10 @@ where
                         }),
                 )
                 .with_vacation_default_subject(
-                    settings
-                        .get("sieve-vacation-default-subject")
-                        .unwrap_or_else(|| "Automated reply".to_string()),
+                    settings.value::<String>(SETTINGS, "sieve-vacation-default-subject"),
                 )
                 .with_vacation_subject_prefix(
-                    settings
-                        .get("sieve-vacation-subject-prefix")
-                        .unwrap_or_else(|| "Auto: ".to_string()),
+                    settings.value::<String>(SETTINGS, "sieve-vacation-subject-prefix"),
                 )
                 .with_env_variable("name", "Stalwart JMAP")
                 .with_env_variable("version", env!("CARGO_PKG_VERSION"))
@@ -289,8 +415,7 @@ where
 
         // Obtain last Raft ID
         let raft_id = store
-            .get_prev_raft_id(RaftId::new(LogIndex::MAX, LogIndex::MAX))
-            .unwrap()
+            .get_prev_raft_id(RaftId::new(LogIndex::MAX, LogIndex::MAX))?
             .map(|mut id| {
                 id.index += 1;
                 id
@@ -301,7 +426,45 @@ where
             });
         store.raft_term = raft_id.term.into();
         store.raft_index = raft_id.index.into();
-        store
+        store.fencing_token = RwLock::new(
+            store
+                .db
+                .get::<TermId>(ColumnFamily::Values, FENCING_TOKEN_KEY)?
+                .unwrap_or(0),
+        );
+
+        // Rewrite keys stored using a previous layout
+        store.upgrade_key_layout()?;
+
+        // Use the same text processing settings the index was built with, changing
+        // them would require reindexing all messages.
+        let nlp_config = store
+            .db
+            .get::<NLPConfig>(ColumnFamily::Values, NLP_CONFIG_KEY)?;
+        match nlp_config {
+            Some(nlp_config) if nlp_config != store.config.nlp => {
+                warn!(
+                    "Text processing settings differ from the ones used to build the index, using stored settings. New settings only apply to new data directories."
+                );
+                store.config.nlp = nlp_config;
+            }
+            Some(_) => (),
+            None => {
+                store.write_nlp_config()?;
+            }
+        }
+
+        Ok(store)
+    }
+
+    fn write_nlp_config(&self) -> crate::Result<()> {
+        self.db.set(
+            ColumnFamily::Values,
+            NLP_CONFIG_KEY,
+            &self.config.nlp.serialize().ok_or_else(|| {
+                StoreError::SerializeError("Failed to serialize NLP settings.".to_string())
+            })?,
+        )
     }
 
     #[inline(always)]
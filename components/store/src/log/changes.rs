@@ -197,6 +197,16 @@ where
                 (true, from_change_id, to_change_id)
             }
         };
+
+        // Entries written before the collection was renumbered refer to old ids
+        if !matches!(query, Query::All) {
+            if let Some(renumbered_id) = self.get_renumbered_change_id(account, collection)? {
+                if from_change_id < renumbered_id {
+                    return Ok(None);
+                }
+            }
+        }
+
         let key = LogKey::serialize_change(account, collection, from_change_id);
         let prefix = &key[0..LogKey::CHANGE_ID_POS];
         let mut is_first = true;
@@ -231,10 +231,11 @@ where
                 bytes.push_leb128(account_id);
             }
         }
+        let raft_id = RaftId::new(last_term, up_to);
         write_batch.push(WriteOperation::set(
             ColumnFamily::Logs,
-            LogKey::serialize_raft(&RaftId::new(last_term, up_to)),
-            bytes,
+            LogKey::serialize_raft(&raft_id),
+            Entry::seal(&raft_id, bytes),
         ));
         self.db.write(write_batch)?;
 
@@ -29,6 +29,8 @@ use crate::write::batch;
 use crate::AccountId;
 use std::convert::TryInto;
 
+use super::raft::RaftId;
+
 #[derive(Debug)]
 pub enum Entry {
     Item {
@@ -65,9 +67,61 @@ impl Entry {
     }
 }
 
+impl Entry {
+    // Set on the type byte of raft entries that are followed by a checksum,
+    // previous versions cannot read sealed entries (apply format 3).
+    pub const CHECKSUM: u8 = 0x80;
+    pub const CHECKSUM_LEN: usize = 4;
+
+    /// Calculates the checksum of a raft entry, the raft id is included
+    /// in order to detect entries stored under the wrong key.
+    pub fn checksum(raft_id: &RaftId, bytes: &[u8]) -> [u8; Entry::CHECKSUM_LEN] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&raft_id.term.to_le_bytes());
+        hasher.update(&raft_id.index.to_le_bytes());
+        hasher.update(bytes);
+        let mut checksum = [0u8; Entry::CHECKSUM_LEN];
+        checksum.copy_from_slice(&hasher.finalize().as_bytes()[..Entry::CHECKSUM_LEN]);
+        checksum
+    }
+
+    /// Flags a serialized raft entry and appends its checksum.
+    pub fn seal(raft_id: &RaftId, mut bytes: Vec<u8>) -> Vec<u8> {
+        if let Some(entry_type) = bytes.first_mut() {
+            *entry_type |= Entry::CHECKSUM;
+        }
+        let checksum = Entry::checksum(raft_id, &bytes);
+        bytes.extend_from_slice(&checksum);
+        bytes
+    }
+
+    /// Returns false if the entry is sealed and its checksum does not match.
+    /// Entries written before checksums were introduced are always valid.
+    pub fn verify(raft_id: &RaftId, bytes: &[u8]) -> bool {
+        match bytes.first() {
+            Some(entry_type) if entry_type & Entry::CHECKSUM != 0 => {
+                if let Some(pos) = bytes.len().checked_sub(Entry::CHECKSUM_LEN) {
+                    Entry::checksum(raft_id, &bytes[..pos]) == bytes[pos..]
+                } else {
+                    false
+                }
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
 impl StoreDeserialize for Entry {
     fn deserialize(bytes: &[u8]) -> Option<Self> {
-        match *bytes.first()? {
+        let entry_type = *bytes.first()?;
+        let bytes = if entry_type & Entry::CHECKSUM != 0 {
+            bytes.get(..bytes.len().checked_sub(Entry::CHECKSUM_LEN)?)?
+        } else {
+            bytes
+        };
+
+        match entry_type & !Entry::CHECKSUM {
             batch::Change::ENTRY => Entry::Item {
                 account_id: AccountId::from_le_bytes(
                     bytes
@@ -109,3 +163,44 @@ impl StoreDeserialize for Entry {
         .into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{log::raft::RaftId, serialize::StoreDeserialize, write::batch};
+
+    use super::Entry;
+
+    #[test]
+    fn entry_checksum() {
+        let raft_id = RaftId::new(3, 1024);
+        let mut bytes = vec![batch::Change::ENTRY];
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        bytes.extend_from_slice(&0b101u64.to_le_bytes());
+
+        // Legacy entries are accepted as-is
+        assert!(Entry::verify(&raft_id, &bytes));
+
+        let sealed = Entry::seal(&raft_id, bytes.clone());
+        assert_eq!(sealed.len(), bytes.len() + Entry::CHECKSUM_LEN);
+        assert!(Entry::verify(&raft_id, &sealed));
+        match Entry::deserialize(&sealed).unwrap() {
+            Entry::Item {
+                account_id,
+                changed_collections,
+            } => {
+                assert_eq!(account_id, 7);
+                assert_eq!(changed_collections.bitmap, 0b101);
+            }
+            Entry::Snapshot { .. } => panic!("Expected an item."),
+        }
+
+        // Entries stored under a different key or with flipped bits are rejected
+        assert!(!Entry::verify(&RaftId::new(3, 1025), &sealed));
+        for pos in 0..sealed.len() {
+            let mut corrupted = sealed.clone();
+            corrupted[pos] ^= 0x01;
+            assert!(!Entry::verify(&raft_id, &corrupted), "pos {}", pos);
+        }
+        assert!(!Entry::verify(&raft_id, &[Entry::CHECKSUM]));
+    }
+}
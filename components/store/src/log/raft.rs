@@ -21,9 +21,11 @@
  * for more details.
 */
 
-use crate::serialize::key::LogKey;
+use crate::serialize::key::{LogKey, FENCING_TOKEN_KEY, LAST_APPLIED_KEY};
 use crate::serialize::leb128::{Leb128Iterator, Leb128Vec};
 use crate::serialize::{StoreDeserialize, StoreSerialize};
+use crate::write::batch::WriteBatch;
+use crate::write::operation::WriteOperation;
 use crate::{ColumnFamily, Direction, JMAPStore, Store, StoreError};
 use std::sync::atomic::Ordering;
 pub type TermId = u64;
@@ -71,6 +73,54 @@ impl StoreDeserialize for RaftId {
     }
 }
 
+/// Position of the last follower update applied to the store: the Raft
+/// entry it belongs to and the sequence of the pending update within it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AppliedId {
+    pub raft_id: RaftId,
+    pub seq_id: LogIndex,
+}
+
+impl AppliedId {
+    pub fn new(raft_id: RaftId, seq_id: LogIndex) -> Self {
+        Self { raft_id, seq_id }
+    }
+
+    /// Returns true if the update at `other` was applied at or before this one.
+    /// An entry at the same index but from another term replaced the applied
+    /// one, so it is not included.
+    pub fn includes(&self, other: &AppliedId) -> bool {
+        if other.raft_id.index == self.raft_id.index {
+            other.raft_id.term == self.raft_id.term && other.seq_id <= self.seq_id
+        } else {
+            other.raft_id.index < self.raft_id.index && other.raft_id.term <= self.raft_id.term
+        }
+    }
+}
+
+impl StoreSerialize for AppliedId {
+    fn serialize(&self) -> Option<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(std::mem::size_of::<AppliedId>());
+        bytes.push_leb128(self.raft_id.term);
+        bytes.push_leb128(self.raft_id.index);
+        bytes.push_leb128(self.seq_id);
+        bytes.into()
+    }
+}
+
+impl StoreDeserialize for AppliedId {
+    fn deserialize(bytes: &[u8]) -> Option<Self> {
+        let mut bytes = bytes.iter();
+        Some(Self {
+            raft_id: RaftId {
+                term: bytes.next_leb128()?,
+                index: bytes.next_leb128()?,
+            },
+            seq_id: bytes.next_leb128()?,
+        })
+    }
+}
+
 impl<T> JMAPStore<T>
 where
     T: for<'x> Store<'x> + 'static,
@@ -85,21 +135,106 @@ where
         }
     }
 
-    pub fn get_prev_raft_id(&self, key: RaftId) -> crate::Result<Option<RaftId>> {
-        let key = LogKey::serialize_raft(&key);
+    /// Returns the most recent term known to have been committed by a quorum.
+    pub fn fencing_token(&self) -> TermId {
+        *self.fencing_token.read()
+    }
 
-        if let Some((key, _)) = self
-            .db
-            .iterator(ColumnFamily::Logs, &key, Direction::Backward)?
-            .next()
-        {
-            if key.starts_with(&[LogKey::RAFT_KEY_PREFIX]) {
-                return Ok(Some(LogKey::deserialize_raft(&key).ok_or_else(|| {
-                    StoreError::InternalError(format!("Corrupted raft key for [{:?}]", key))
-                })?));
+    /// Records a term once a quorum has committed entries from it, from then
+    /// on batches fenced with an older term are rejected. Writes in progress
+    /// are waited for, so none lands with a stale token after this returns.
+    pub fn advance_fencing_token(&self, term: TermId) -> crate::Result<()> {
+        if term > self.fencing_token() {
+            let mut fencing_token = self.fencing_token.write();
+            if term > *fencing_token {
+                self.write_operations(vec![WriteOperation::set(
+                    ColumnFamily::Values,
+                    FENCING_TOKEN_KEY.to_vec(),
+                    term.serialize().unwrap(),
+                )])?;
+                *fencing_token = term;
             }
         }
-        Ok(None)
+        Ok(())
+    }
+
+    /// Fails if the batch (or any linked batch) was produced under a term older
+    /// than the fencing token. Batches from the Raft apply path carry the term
+    /// they were tagged with, while those adding entries to the log are fenced
+    /// with the term of those entries. The caller must hold the token until the
+    /// batch is written.
+    pub fn fence_batch(&self, batch: &WriteBatch, fencing_token: TermId) -> crate::Result<()> {
+        for batch in batch.linked_batch.iter().chain(std::iter::once(batch)) {
+            let term = if let Some(term) = batch.fencing_token {
+                term
+            } else if !batch.changes.is_empty() {
+                self.raft_term.load(Ordering::Relaxed)
+            } else {
+                continue;
+            };
+            if term < fencing_token {
+                return Err(StoreError::StaleFencingToken(term, fencing_token));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the operations recording the batch's follower updates as applied
+    /// and removing them from the pending queue, so that a replay after a
+    /// crash finds them either fully applied or not applied at all.
+    pub fn mark_applied(&self, ops: &mut Vec<WriteOperation>, batch: &WriteBatch) {
+        if let Some(applied_id) = batch.applied_id {
+            ops.push(WriteOperation::set(
+                ColumnFamily::Values,
+                LAST_APPLIED_KEY.to_vec(),
+                applied_id.serialize().unwrap(),
+            ));
+            ops.push(WriteOperation::delete(
+                ColumnFamily::Logs,
+                LogKey::serialize_pending_update(applied_id.raft_id.index, applied_id.seq_id),
+            ));
+        }
+    }
+
+    pub fn get_last_applied(&self) -> crate::Result<Option<AppliedId>> {
+        self.db.get(ColumnFamily::Values, LAST_APPLIED_KEY)
+    }
+
+    /// Adds the operations moving the last applied position back to
+    /// `after_index`, to be written along with the removal of the entries
+    /// following it from the log.
+    pub fn rewind_last_applied(
+        &self,
+        after_index: LogIndex,
+        ops: &mut Vec<WriteOperation>,
+    ) -> crate::Result<()> {
+        match self.get_last_applied()? {
+            Some(_) if after_index == LogIndex::MAX => {
+                ops.push(WriteOperation::delete(
+                    ColumnFamily::Values,
+                    LAST_APPLIED_KEY.to_vec(),
+                ));
+            }
+            Some(applied_id) if applied_id.raft_id.index > after_index => {
+                ops.push(WriteOperation::set(
+                    ColumnFamily::Values,
+                    LAST_APPLIED_KEY.to_vec(),
+                    AppliedId::new(
+                        self.get_prev_raft_id(RaftId::new(TermId::MAX, after_index))?
+                            .unwrap_or_else(|| RaftId::new(applied_id.raft_id.term, after_index)),
+                        LogIndex::MAX,
+                    )
+                    .serialize()
+                    .unwrap(),
+                ));
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    pub fn get_prev_raft_id(&self, key: RaftId) -> crate::Result<Option<RaftId>> {
+        prev_raft_id(&self.db, key)
     }
 
     pub fn get_next_raft_id(&self, key: RaftId) -> crate::Result<Option<RaftId>> {
@@ -119,3 +254,24 @@ where
         Ok(None)
     }
 }
+
+/// Returns the last Raft id preceding `key` in a database, which does not need to
+/// be the one backing a `JMAPStore` (i.e. a backup checkpoint).
+pub fn prev_raft_id<T>(db: &T, key: RaftId) -> crate::Result<Option<RaftId>>
+where
+    T: for<'x> Store<'x>,
+{
+    let key = LogKey::serialize_raft(&key);
+
+    if let Some((key, _)) = db
+        .iterator(ColumnFamily::Logs, &key, Direction::Backward)?
+        .next()
+    {
+        if key.starts_with(&[LogKey::RAFT_KEY_PREFIX]) {
+            return Ok(Some(LogKey::deserialize_raft(&key).ok_or_else(|| {
+                StoreError::InternalError(format!("Corrupted raft key for [{:?}]", key))
+            })?));
+        }
+    }
+    Ok(None)
+}
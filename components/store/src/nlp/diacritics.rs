@@ -0,0 +1,76 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::borrow::Cow;
+
+// Base letters for U+00C0 to U+00FF, zero means the character is kept as is.
+static LATIN_1: &[u8; 64] =
+    b"aaaaaa\0ceeeeiiiidnooooo\0ouuuuy\0\0aaaaaa\0ceeeeiiiidnooooo\0ouuuuy\0y";
+
+// Base letters for U+0100 to U+017F (Latin Extended-A).
+static LATIN_EXT_A: &[u8; 128] = b"aaaaaaccccccccddddeeeeeeeeeegggggggghhhhiiiiiiiiii\0\0jjkkkllllllllllnnnnnnnnnoooooo\0\0rrrrrrssssssssttttttuuuuuuuuuuuuwwyyyzzzzzzs";
+
+#[inline(always)]
+pub fn fold_char(ch: char) -> char {
+    let base = match ch as u32 {
+        code @ 0xC0..=0xFF => LATIN_1[(code - 0xC0) as usize],
+        code @ 0x100..=0x17F => LATIN_EXT_A[(code - 0x100) as usize],
+        _ => 0,
+    };
+    if base != 0 {
+        base as char
+    } else {
+        ch
+    }
+}
+
+/// Removes diacritics from Latin characters.
+pub fn fold_diacritics(text: &str) -> Cow<str> {
+    if text.is_ascii() || !text.chars().any(|ch| fold_char(ch) != ch) {
+        text.into()
+    } else {
+        text.chars().map(fold_char).collect::<String>().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fold_diacritics;
+
+    #[test]
+    fn diacritics() {
+        for (input, expected) in [
+            ("hello", "hello"),
+            ("café", "cafe"),
+            ("àéîõüçñ", "aeioucn"),
+            ("łódź", "lodz"),
+            ("žluťoučký", "zlutoucky"),
+            ("straße", "straße"),
+            ("æsir", "æsir"),
+            ("şğıöç", "sgioc"),
+            ("ÀÉÎÕÜ", "aeiou"),
+        ] {
+            assert_eq!(fold_diacritics(input), expected, "{}", input);
+        }
+    }
+}
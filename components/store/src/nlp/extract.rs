@@ -0,0 +1,300 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::Duration;
+
+use tokio::runtime::Handle;
+use tracing::debug;
+
+use crate::config::{
+    env_settings::{soft_panic, EnvSettings},
+    settings::Setting,
+};
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::one_of("attachment-index", &["disabled", "builtin", "external"])
+        .default("disabled")
+        .describe("builtin indexes PDF, DOCX, XLSX and text attachments"),
+    Setting::url("attachment-index-url").describe("Text extraction service used in external mode"),
+    Setting::bytes("attachment-index-max-size").default("10485760"),
+    Setting::seconds("attachment-index-timeout").default("30"),
+];
+
+const MIME_DOCX: &str = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+const MIME_XLSX: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+pub struct AttachmentExtractor {
+    pub mode: ExtractMode,
+    pub max_size: usize,
+    client: reqwest::Client,
+    // Runtime driving the requests to the external service
+    runtime: Option<Handle>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtractMode {
+    Disabled,
+    Builtin,
+    External { url: String },
+}
+
+impl AttachmentExtractor {
+    pub fn is_enabled(&self) -> bool {
+        self.mode != ExtractMode::Disabled
+    }
+
+    /// Extracts the text contained in an attachment, returns None if the
+    /// format is not supported or the attachment could not be parsed.
+    pub fn extract(
+        &self,
+        content_type: Option<&str>,
+        file_name: Option<&str>,
+        bytes: &[u8],
+    ) -> Option<String> {
+        if bytes.is_empty() || bytes.len() > self.max_size {
+            return None;
+        }
+
+        let content_type = content_type
+            .map(|ct| ct.to_ascii_lowercase())
+            .filter(|ct| ct != "application/octet-stream")
+            .or_else(|| {
+                content_type_from_name(file_name?.rsplit_once('.')?.1).map(|ct| ct.to_string())
+            })?;
+
+        let text = match &self.mode {
+            ExtractMode::Disabled => return None,
+            ExtractMode::Builtin => extract_builtin(&content_type, bytes, self.max_size),
+            ExtractMode::External { url } => self
+                .extract_external(url, &content_type, bytes)
+                .or_else(|| extract_builtin(&content_type, bytes, self.max_size)),
+        }?;
+
+        if !text.trim().is_empty() {
+            Some(text)
+        } else {
+            None
+        }
+    }
+
+    fn extract_external(&self, url: &str, content_type: &str, bytes: &[u8]) -> Option<String> {
+        // Extraction runs on the store workers, requests are driven by the server's
+        // runtime which cannot be blocked from one of its own threads.
+        let runtime = match &self.runtime {
+            Some(runtime) if Handle::try_current().is_err() => runtime,
+            _ => {
+                debug!("External text extraction is only available from store workers.");
+                return None;
+            }
+        };
+
+        match runtime.block_on(async {
+            self.client
+                .put(url)
+                .header(reqwest::header::CONTENT_TYPE, content_type)
+                .header(reqwest::header::ACCEPT, "text/plain")
+                .body(bytes.to_vec())
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await
+        }) {
+            Ok(text) => Some(text),
+            Err(err) => {
+                debug!(
+                    "Failed to extract text from {} attachment: {}",
+                    content_type, err
+                );
+                None
+            }
+        }
+    }
+}
+
+impl From<&EnvSettings> for AttachmentExtractor {
+    fn from(settings: &EnvSettings) -> Self {
+        AttachmentExtractor {
+            mode: match settings.get("attachment-index").as_deref() {
+                Some("builtin") => ExtractMode::Builtin,
+                Some("external") => ExtractMode::External {
+                    url: settings.get("attachment-index-url").unwrap_or_else(|| {
+                        soft_panic("Missing 'attachment-index-url' parameter.");
+                    }),
+                },
+                Some("disabled") | None => ExtractMode::Disabled,
+                Some(mode) => {
+                    soft_panic(&format!(
+                        "Invalid value '{}' for parameter 'attachment-index'.",
+                        mode
+                    ));
+                }
+            },
+            max_size: settings.value(SETTINGS, "attachment-index-max-size"),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(
+                    settings.value(SETTINGS, "attachment-index-timeout"),
+                ))
+                .build()
+                .unwrap_or_default(),
+            runtime: Handle::try_current().ok(),
+        }
+    }
+}
+
+#[allow(unused_variables)]
+fn extract_builtin(content_type: &str, bytes: &[u8], max_size: usize) -> Option<String> {
+    match content_type {
+        #[cfg(feature = "pdf")]
+        "application/pdf" => super::pdf::extract_pdf(bytes),
+        #[cfg(feature = "office")]
+        MIME_DOCX => office::extract_docx(bytes, max_size),
+        #[cfg(feature = "office")]
+        MIME_XLSX => office::extract_xlsx(bytes, max_size),
+        _ if content_type.starts_with("text/") => Some(String::from_utf8_lossy(bytes).into_owned()),
+        _ => None,
+    }
+}
+
+fn content_type_from_name(extension: &str) -> Option<&'static str> {
+    match extension.to_ascii_lowercase().as_str() {
+        "pdf" => Some("application/pdf"),
+        "docx" => Some(MIME_DOCX),
+        "xlsx" => Some(MIME_XLSX),
+        "txt" | "log" => Some("text/plain"),
+        "csv" => Some("text/csv"),
+        "md" => Some("text/markdown"),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "office")]
+mod office {
+    use std::io::{Cursor, Read};
+
+    pub fn extract_docx(bytes: &[u8], max_size: usize) -> Option<String> {
+        xml_to_text(
+            &read_entry(bytes, "word/document.xml", max_size)?,
+            &["/w:p", "w:br", "w:tab"],
+        )
+        .into()
+    }
+
+    pub fn extract_xlsx(bytes: &[u8], max_size: usize) -> Option<String> {
+        xml_to_text(
+            &read_entry(bytes, "xl/sharedStrings.xml", max_size)?,
+            &["/si"],
+        )
+        .into()
+    }
+
+    fn read_entry(bytes: &[u8], name: &str, max_size: usize) -> Option<String> {
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).ok()?;
+        let mut contents = String::new();
+        archive
+            .by_name(name)
+            .ok()?
+            .take(max_size as u64)
+            .read_to_string(&mut contents)
+            .ok()?;
+        Some(contents)
+    }
+
+    /// Strips the markup from an Office Open XML part, adding a line break
+    /// after any of the provided tags.
+    pub fn xml_to_text(xml: &str, break_tags: &[&str]) -> String {
+        let mut text = String::with_capacity(xml.len() / 4);
+        let mut rest = xml;
+
+        while let Some(tag_start) = rest.find('<') {
+            decode_entities(&rest[..tag_start], &mut text);
+            rest = &rest[tag_start + 1..];
+            let tag_end = if let Some(tag_end) = rest.find('>') {
+                tag_end
+            } else {
+                break;
+            };
+            let tag_name = rest[..tag_end]
+                .trim_end_matches('/')
+                .split(|c: char| c.is_ascii_whitespace())
+                .next()
+                .unwrap_or("");
+            if break_tags.contains(&tag_name) && !text.ends_with('\n') {
+                text.push('\n');
+            }
+            rest = &rest[tag_end + 1..];
+        }
+
+        text
+    }
+
+    fn decode_entities(value: &str, text: &mut String) {
+        let mut rest = value;
+        while let Some(pos) = rest.find('&') {
+            text.push_str(&rest[..pos]);
+            rest = &rest[pos..];
+            if let Some(end) = rest.find(';') {
+                let entity = &rest[1..end];
+                let ch = match entity {
+                    "amp" => Some('&'),
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    _ => entity
+                        .strip_prefix("#x")
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .or_else(|| entity.strip_prefix('#')?.parse::<u32>().ok())
+                        .and_then(char::from_u32),
+                };
+                if let Some(ch) = ch {
+                    text.push(ch);
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+            }
+            text.push('&');
+            rest = &rest[1..];
+        }
+        text.push_str(rest);
+    }
+
+    #[cfg(test)]
+    mod tests {
+        #[test]
+        fn xml_to_text() {
+            assert_eq!(
+                super::xml_to_text(
+                    concat!(
+                        "<?xml version=\"1.0\"?><w:document><w:body>",
+                        "<w:p><w:r><w:t>Quarterly</w:t></w:r><w:r><w:t xml:space=\"preserve\"> report</w:t></w:r></w:p>",
+                        "<w:p><w:r><w:t>Profit &amp; loss &#x2013; Q3</w:t></w:r></w:p>",
+                        "</w:body></w:document>"
+                    ),
+                    &["/w:p", "w:br", "w:tab"]
+                ),
+                "Quarterly report\nProfit & loss \u{2013} Q3\n"
+            );
+        }
+    }
+}
@@ -21,8 +21,11 @@
  * for more details.
 */
 
+pub mod diacritics;
+pub mod extract;
 pub mod lang;
-//pub mod pdf;
+#[cfg(feature = "pdf")]
+pub mod pdf;
 pub mod search_snippet;
 pub mod stemmer;
 pub mod term_index;
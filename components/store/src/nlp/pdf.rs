@@ -24,6 +24,7 @@
 use std::panic;
 
 use lopdf::Document;
+use pdf_extract::{output_doc, PlainTextOutput};
 
 pub fn extract_pdf(bytes: &[u8]) -> Option<String> {
     panic::catch_unwind(|| {
@@ -40,15 +41,3 @@ pub fn extract_pdf(bytes: &[u8]) -> Option<String> {
     })
     .ok()?
 }
-
-/*
-#[cfg(test)]
-mod tests {
-
-    #[test]
-    fn extract_pdf() {
-        let bytes = include_bytes!("/tmp/pdf/files/ep.pdf");
-        let text = super::extract_pdf(bytes);
-    }
-}
-*/
@@ -25,7 +25,10 @@ use std::borrow::Cow;
 
 use rust_stemmers::Algorithm;
 
-use crate::nlp::{tokenizers::Tokenizer, Language};
+use crate::{
+    config::nlp::NLPConfig,
+    nlp::{tokenizers::Tokenizer, Language},
+};
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct StemmedToken<'x> {
@@ -47,6 +50,22 @@ impl<'x> Stemmer<'x> {
             stemmer: STEMMER_MAP[language as usize].map(rust_stemmers::Stemmer::create),
         }
     }
+
+    pub fn with_config(
+        text: &'x str,
+        language: Language,
+        max_token_length: usize,
+        config: &'x NLPConfig,
+    ) -> Stemmer<'x> {
+        Stemmer {
+            tokenizer: Tokenizer::with_config(text, language, max_token_length, config).full_text(),
+            stemmer: if config.is_stemming_enabled(language) {
+                STEMMER_MAP[language as usize].map(rust_stemmers::Stemmer::create)
+            } else {
+                None
+            },
+        }
+    }
 }
 
 impl<'x> Iterator for Stemmer<'x> {
@@ -165,4 +184,48 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn stemmer_with_config() {
+        let config = NLPConfig {
+            stemming_disabled: [Language::English].into_iter().collect(),
+            stop_words: ["the".to_string(), "a".to_string()].into_iter().collect(),
+            fold_diacritics: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Stemmer::with_config(
+                "The loving café at a corner",
+                Language::English,
+                40,
+                &config
+            )
+            .map(|token| (token.word.into_owned(), token.stemmed_word.is_some()))
+            .collect::<Vec<_>>(),
+            vec![
+                ("loving".to_string(), false),
+                ("cafe".to_string(), false),
+                ("at".to_string(), false),
+                ("corner".to_string(), false)
+            ]
+        );
+
+        assert_eq!(
+            Stemmer::with_config("queremos", Language::Spanish, 40, &config)
+                .next()
+                .unwrap()
+                .stemmed_word
+                .unwrap(),
+            "quer"
+        );
+
+        // Exact-match tokens only have their diacritics folded
+        assert_eq!(
+            Tokenizer::with_config("The café", Language::English, 40, &config)
+                .map(|token| token.word.into_owned())
+                .collect::<Vec<_>>(),
+            vec!["the".to_string(), "cafe".to_string()]
+        );
+    }
 }
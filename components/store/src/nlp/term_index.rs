@@ -677,6 +677,58 @@ impl TermIndex {
             None
         })
     }
+
+    /// Counts the occurrences of each term in the given fields (or in all fields
+    /// if none are specified), returning the frequencies along with the total
+    /// number of terms in those fields.
+    pub fn term_frequencies(
+        &self,
+        match_terms: &[MatchTerm],
+        match_in: &AHashSet<FieldId>,
+    ) -> Result<(Vec<u32>, u32)> {
+        let mut frequencies = vec![0u32; match_terms.len()];
+        let mut num_terms = 0;
+
+        for item in &self.items {
+            if !match_in.is_empty() && !match_in.contains(&item.field_id) {
+                continue;
+            }
+            num_terms += item.terms_len as u32;
+
+            let mut term_pos = 0;
+            let mut byte_pos = 0;
+
+            while term_pos < item.terms_len {
+                let (bytes_read, chunk) = TermIndex::uncompress_chunk(
+                    item.terms.get(byte_pos..).ok_or(Error::DataCorruption)?,
+                    (item.terms_len * 2) - (term_pos * 2),
+                    None,
+                )?;
+                byte_pos += bytes_read;
+
+                for encoded_term in chunk.chunks_exact(2) {
+                    if term_pos == item.terms_len {
+                        break;
+                    }
+                    let term_id = encoded_term[0];
+                    let term_id_stemmed = encoded_term[1];
+
+                    if let Some(match_pos) = match_terms.iter().position(|match_term| {
+                        match_term.id == term_id
+                            || match_term.id == term_id_stemmed
+                            || ((match_term.id_stemmed != match_term.id)
+                                && (match_term.id_stemmed == term_id
+                                    || match_term.id_stemmed == term_id_stemmed))
+                    }) {
+                        frequencies[match_pos] += 1;
+                    }
+                    term_pos += 1;
+                }
+            }
+        }
+
+        Ok((frequencies, num_terms))
+    }
 }
 
 #[derive(Default)]
@@ -28,7 +28,8 @@ pub mod word;
 
 use std::borrow::Cow;
 
-use crate::Language;
+use super::diacritics::fold_diacritics;
+use crate::{config::nlp::NLPConfig, Language};
 
 use self::{
     chinese::ChineseTokenizer, indo_european::IndoEuropeanTokenizer, japanese::JapaneseTokenizer,
@@ -61,11 +62,15 @@ enum LanguageTokenizer<'x> {
 
 pub struct Tokenizer<'x> {
     tokenizer: LanguageTokenizer<'x>,
+    config: Option<&'x NLPConfig>,
+    full_text: bool,
 }
 
 impl<'x> Tokenizer<'x> {
     pub fn new(text: &'x str, language: Language, max_token_length: usize) -> Self {
         Tokenizer {
+            config: None,
+            full_text: false,
             tokenizer: match language {
                 Language::Japanese => {
                     LanguageTokenizer::Japanese(JapaneseTokenizer::new(text, max_token_length))
@@ -80,12 +85,28 @@ impl<'x> Tokenizer<'x> {
             },
         }
     }
-}
 
-impl<'x> Iterator for Tokenizer<'x> {
-    type Item = Token<'x>;
+    pub fn with_config(
+        text: &'x str,
+        language: Language,
+        max_token_length: usize,
+        config: &'x NLPConfig,
+    ) -> Self {
+        let mut tokenizer = Tokenizer::new(text, language, max_token_length);
+        if !config.is_default() {
+            tokenizer.config = config.into();
+        }
+        tokenizer
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    /// Drops stop words and low value tokens, which only applies to full-text
+    /// fields as exact-match fields have to be indexed verbatim.
+    pub fn full_text(mut self) -> Self {
+        self.full_text = true;
+        self
+    }
+
+    fn next_token(&mut self) -> Option<Token<'x>> {
         match &mut self.tokenizer {
             LanguageTokenizer::IndoEuropean(tokenizer) => tokenizer.next(),
             LanguageTokenizer::Chinese(tokenizer) => tokenizer.next(),
@@ -93,3 +114,28 @@ impl<'x> Iterator for Tokenizer<'x> {
         }
     }
 }
+
+impl<'x> Iterator for Tokenizer<'x> {
+    type Item = Token<'x>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(config) = self.config {
+            while let Some(mut token) = self.next_token() {
+                if config.fold_diacritics {
+                    if let Cow::Owned(word) = fold_diacritics(&token.word) {
+                        token.word = word.into();
+                    }
+                }
+                if !self.full_text
+                    || (!config.is_stop_word(&token.word)
+                        && !(config.has_token_filters() && config.is_low_value_token(&token.word)))
+                {
+                    return Some(token);
+                }
+            }
+            None
+        } else {
+            self.next_token()
+        }
+    }
+}
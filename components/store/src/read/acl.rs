@@ -44,46 +44,45 @@ where
         for account_id in member_of {
             let prefix =
                 ValueKey::serialize_acl_prefix(*account_id, AccountId::MAX, Collection::None);
-            for (key, value) in
-                self.db
-                    .iterator(ColumnFamily::Values, &prefix, Direction::Forward)?
-            {
-                if key.starts_with(&prefix)
-                    && key.len() > prefix.len() + 2
-                    && key[prefix.len()] != u8::MAX
-                {
-                    let (to_account_id, to_collection, _) = ValueKey::deserialize_acl_target(
-                        &key[prefix.len()..],
-                    )
+            let mut scan = self.scan_prefix(ColumnFamily::Values, prefix, Direction::Forward)?;
+            while let Some(entry) = scan.next()? {
+                let suffix = entry.key_suffix();
+                if suffix.len() <= 2 || suffix[0] == u8::MAX {
+                    break;
+                }
+                let (to_account_id, to_collection, _) = ValueKey::deserialize_acl_target(suffix)
                     .ok_or_else(|| {
-                        StoreError::InternalError(format!("Corrupted ACL key for [{:?}]", key))
+                        StoreError::InternalError(format!(
+                            "Corrupted ACL key for [{:?}]",
+                            entry.key
+                        ))
                     })?;
-                    let acl = Bitmap::from(u64::deserialize(&value).ok_or_else(|| {
-                        StoreError::InternalError(format!("Corrupted ACL value for [{:?}]", key))
-                    })?);
 
-                    if !member_of.contains(&to_account_id) {
-                        let mut collections: Bitmap<Collection> = Bitmap::new();
-                        if acl.contains(ACL::Read) {
-                            collections.insert(to_collection);
-                        }
-                        if (acl.contains(ACL::ReadItems)) && to_collection == Collection::Mailbox {
-                            collections.insert(Collection::Mail);
-                        }
+                if !member_of.contains(&to_account_id) {
+                    let acl = Bitmap::from(u64::deserialize(entry.value).ok_or_else(|| {
+                        StoreError::InternalError(format!(
+                            "Corrupted ACL value for [{:?}]",
+                            entry.key
+                        ))
+                    })?);
+                    let mut collections: Bitmap<Collection> = Bitmap::new();
+                    if acl.contains(ACL::Read) {
+                        collections.insert(to_collection);
+                    }
+                    if (acl.contains(ACL::ReadItems)) && to_collection == Collection::Mailbox {
+                        collections.insert(Collection::Mail);
+                    }
 
-                        if !collections.is_empty() {
-                            if let Some(sharing) = shared_accounts
-                                .iter_mut()
-                                .find(|(account_id, _)| *account_id == to_account_id)
-                            {
-                                sharing.1.union(&collections);
-                            } else {
-                                shared_accounts.push((to_account_id, collections));
-                            }
+                    if !collections.is_empty() {
+                        if let Some(sharing) = shared_accounts
+                            .iter_mut()
+                            .find(|(account_id, _)| *account_id == to_account_id)
+                        {
+                            sharing.1.union(&collections);
+                        } else {
+                            shared_accounts.push((to_account_id, collections));
                         }
                     }
-                } else {
-                    break;
                 }
             }
         }
@@ -100,29 +99,25 @@ where
         let mut shared_documents = RoaringBitmap::new();
         for account_id in member_of {
             let prefix = ValueKey::serialize_acl_prefix(*account_id, to_account_id, to_collection);
-            for (key, value) in
-                self.db
-                    .iterator(ColumnFamily::Values, &prefix, Direction::Forward)?
-            {
-                if key.starts_with(&prefix) && key.len() > prefix.len() {
-                    let (document_id, _) =
-                        (&key[prefix.len()..]).read_leb128().ok_or_else(|| {
-                            StoreError::InternalError(format!(
-                                "Corrupted ACL members key for [{:?}]",
-                                key
-                            ))
-                        })?;
-
-                    let mut acl = Bitmap::from(u64::deserialize(&value).ok_or_else(|| {
-                        StoreError::InternalError(format!("Corrupted ACL value for [{:?}]", key))
-                    })?);
-                    acl.intersection(&acls);
-                    if !acl.is_empty() {
-                        shared_documents.insert(document_id);
-                    }
-                } else {
+            let mut scan = self.scan_prefix(ColumnFamily::Values, prefix, Direction::Forward)?;
+            while let Some(entry) = scan.next()? {
+                if entry.key_suffix().is_empty() {
                     break;
                 }
+                let (document_id, _) = entry.key_suffix().read_leb128().ok_or_else(|| {
+                    StoreError::InternalError(format!(
+                        "Corrupted ACL members key for [{:?}]",
+                        entry.key
+                    ))
+                })?;
+
+                let mut acl = Bitmap::from(u64::deserialize(entry.value).ok_or_else(|| {
+                    StoreError::InternalError(format!("Corrupted ACL value for [{:?}]", entry.key))
+                })?);
+                acl.intersection(&acls);
+                if !acl.is_empty() {
+                    shared_documents.insert(document_id);
+                }
             }
         }
         Ok(if !shared_documents.is_empty() {
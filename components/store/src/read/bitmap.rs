@@ -21,14 +21,17 @@
  * for more details.
 */
 
-use std::ops::{BitAndAssign, BitOrAssign};
+use std::{
+    ops::{BitAndAssign, BitOrAssign},
+    sync::Arc,
+};
 
 use roaring::RoaringBitmap;
 
 use crate::{
     core::error::StoreError,
     serialize::{key::FIELD_PREFIX_LEN, DeserializeBigEndian},
-    ColumnFamily, Direction, DocumentId, JMAPStore, Store,
+    ColumnFamily, Direction, DocumentId, JMAPStore, Store, StoreCursor,
 };
 
 use super::filter::ComparisonOperator;
@@ -39,20 +42,79 @@ where
 {
     pub fn get_bitmap(&self, key: &[u8]) -> crate::Result<Option<RoaringBitmap>> {
         Ok(self
-            .db
-            .get::<RoaringBitmap>(ColumnFamily::Bitmaps, key)?
+            .get_cached_bitmap(key)?
             .and_then(|bm| if !bm.is_empty() { Some(bm) } else { None }))
     }
 
+    /// Reads a bitmap from the cache, falling back to the backend on a miss.
+    pub fn get_cached_bitmap(&self, key: &[u8]) -> crate::Result<Option<RoaringBitmap>> {
+        if !self.bitmap_cache.is_enabled() {
+            return self.db.get::<RoaringBitmap>(ColumnFamily::Bitmaps, key);
+        }
+        if let Some(bitmap) = self.bitmap_cache.get(key) {
+            return Ok(Some(bitmap.as_ref().clone()));
+        }
+
+        let epoch = self.bitmap_cache.epoch();
+        Ok(
+            if let Some(bitmap) = self.db.get::<RoaringBitmap>(ColumnFamily::Bitmaps, key)? {
+                self.bitmap_cache
+                    .insert(key.to_vec(), Arc::new(bitmap.clone()), epoch);
+                Some(bitmap)
+            } else {
+                None
+            },
+        )
+    }
+
+    /// Reads multiple bitmaps, only requesting from the backend the ones
+    /// missing from the cache.
+    pub fn get_cached_bitmaps(
+        &self,
+        keys: Vec<Vec<u8>>,
+    ) -> crate::Result<Vec<Option<RoaringBitmap>>> {
+        if !self.bitmap_cache.is_enabled() {
+            return self
+                .db
+                .multi_get::<RoaringBitmap, _>(ColumnFamily::Bitmaps, keys);
+        }
+
+        let mut result = Vec::with_capacity(keys.len());
+        let mut missing_keys = Vec::new();
+        let mut missing_pos = Vec::new();
+        for key in keys {
+            if let Some(bitmap) = self.bitmap_cache.get(&key) {
+                result.push(Some(bitmap.as_ref().clone()));
+            } else {
+                missing_pos.push(result.len());
+                missing_keys.push(key);
+                result.push(None);
+            }
+        }
+
+        if !missing_keys.is_empty() {
+            let epoch = self.bitmap_cache.epoch();
+            for ((pos, key), bitmap) in missing_pos.into_iter().zip(missing_keys.iter()).zip(
+                self.db
+                    .multi_get::<RoaringBitmap, _>(ColumnFamily::Bitmaps, missing_keys.clone())?,
+            ) {
+                if let Some(bitmap) = bitmap {
+                    self.bitmap_cache
+                        .insert(key.clone(), Arc::new(bitmap.clone()), epoch);
+                    result[pos] = Some(bitmap);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
     pub fn get_bitmaps_intersection(
         &self,
         keys: Vec<Vec<u8>>,
     ) -> crate::Result<Option<RoaringBitmap>> {
         let mut result: Option<RoaringBitmap> = None;
-        for bitmap in self
-            .db
-            .multi_get::<RoaringBitmap, _>(ColumnFamily::Bitmaps, keys)?
-        {
+        for bitmap in self.get_cached_bitmaps(keys)? {
             if let Some(bitmap) = bitmap {
                 if let Some(result) = &mut result {
                     result.bitand_assign(&bitmap);
@@ -71,12 +133,7 @@ where
 
     pub fn get_bitmaps_union(&self, keys: Vec<Vec<u8>>) -> crate::Result<Option<RoaringBitmap>> {
         let mut result: Option<RoaringBitmap> = None;
-        for bitmap in (self
-            .db
-            .multi_get::<RoaringBitmap, _>(ColumnFamily::Bitmaps, keys)?)
-        .into_iter()
-        .flatten()
-        {
+        for bitmap in self.get_cached_bitmaps(keys)?.into_iter().flatten() {
             if let Some(result) = &mut result {
                 result.bitor_assign(&bitmap);
             } else {
@@ -94,7 +151,7 @@ where
         let mut bm = RoaringBitmap::new();
         let match_prefix = &match_key[0..FIELD_PREFIX_LEN];
         let match_value = &match_key[FIELD_PREFIX_LEN..];
-        for (key, _) in self.db.iterator(
+        let mut cursor = self.db.cursor(
             ColumnFamily::Indexes,
             match_key,
             match op {
@@ -103,7 +160,9 @@ where
                 ComparisonOperator::Equal => Direction::Forward,
                 _ => Direction::Backward,
             },
-        )? {
+        )?;
+        while cursor.advance()? {
+            let key = cursor.key();
             if !key.starts_with(match_prefix) {
                 break;
             }
@@ -133,7 +192,7 @@ where
                 ComparisonOperator::GreaterEqualThan if value < match_value => break,
                 ComparisonOperator::Equal if value != match_value => break,
                 _ => {
-                    bm.insert(key.as_ref().deserialize_be_u32(doc_id_pos).ok_or_else(|| {
+                    bm.insert(key.deserialize_be_u32(doc_id_pos).ok_or_else(|| {
                         StoreError::InternalError(
                             "Invalid key found in 'indexes' column family.".to_string(),
                         )
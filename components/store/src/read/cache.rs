@@ -0,0 +1,265 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use moka::sync::SegmentedCache;
+use roaring::RoaringBitmap;
+
+use crate::{
+    config::{env_settings::EnvSettings, settings::Setting},
+    write::operation::WriteOperation,
+    ColumnFamily,
+};
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::bytes("cache-size-bitmaps")
+        .default("67108864")
+        .describe("Deserialized bitmaps kept in memory, 0 = disabled"),
+    Setting::integer("cache-segments-bitmaps")
+        .min(1)
+        .default("8"),
+];
+
+/// Read-through cache of deserialized bitmaps. Entries are invalidated by the
+/// write path once the operations touching them have been committed.
+pub struct BitmapCache {
+    cache: Option<SegmentedCache<Vec<u8>, Arc<RoaringBitmap>>>,
+    epoch: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+pub enum BitmapInvalidation {
+    Key(Vec<u8>),
+    Range(Vec<u8>, Vec<u8>),
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BitmapCacheSnapshot {
+    pub enabled: bool,
+    pub hits: u64,
+    pub misses: u64,
+    #[serde(rename(serialize = "hitRate"))]
+    pub hit_rate: f64,
+    pub entries: u64,
+    #[serde(rename(serialize = "sizeBytes"))]
+    pub size_bytes: u64,
+}
+
+impl BitmapCache {
+    pub fn new(settings: &EnvSettings) -> Self {
+        let max_size: u64 = settings.value(SETTINGS, "cache-size-bitmaps");
+        BitmapCache {
+            cache: if max_size > 0 {
+                SegmentedCache::builder(settings.value(SETTINGS, "cache-segments-bitmaps"))
+                    .max_capacity(max_size)
+                    .weigher(|key: &Vec<u8>, bitmap: &Arc<RoaringBitmap>| {
+                        (key.len() + bitmap.serialized_size())
+                            .try_into()
+                            .unwrap_or(u32::MAX)
+                    })
+                    .support_invalidation_closures()
+                    .build()
+                    .into()
+            } else {
+                None
+            },
+            epoch: 0.into(),
+            hits: 0.into(),
+            misses: 0.into(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.cache.is_some()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Arc<RoaringBitmap>> {
+        let bitmap = self.cache.as_ref()?.get(key);
+        if bitmap.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        bitmap
+    }
+
+    /// Returns the current write epoch, which has to be obtained before
+    /// reading a bitmap from the backend.
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    /// Caches a bitmap read from the backend at the given epoch. If a write
+    /// was committed in the meantime the entry is discarded, as it might
+    /// have been read before the write took place.
+    pub fn insert(&self, key: Vec<u8>, bitmap: Arc<RoaringBitmap>, epoch: u64) {
+        if let Some(cache) = &self.cache {
+            if self.epoch() == epoch {
+                cache.insert(key.clone(), bitmap);
+                if self.epoch() != epoch {
+                    cache.invalidate(&key);
+                }
+            }
+        }
+    }
+
+    /// Collects the bitmaps affected by a list of operations. This has to
+    /// be called before the operations are written to the backend.
+    pub fn invalidations(&self, ops: &[WriteOperation]) -> Vec<BitmapInvalidation> {
+        if self.cache.is_none() {
+            return Vec::new();
+        }
+
+        ops.iter()
+            .filter_map(|op| match op {
+                WriteOperation::Set {
+                    cf: ColumnFamily::Bitmaps,
+                    key,
+                    ..
+                }
+                | WriteOperation::Merge {
+                    cf: ColumnFamily::Bitmaps,
+                    key,
+                    ..
+                }
+                | WriteOperation::Delete {
+                    cf: ColumnFamily::Bitmaps,
+                    key,
+                } => BitmapInvalidation::Key(key.clone()).into(),
+                WriteOperation::DeleteRange {
+                    cf: ColumnFamily::Bitmaps,
+                    from,
+                    to,
+                } => BitmapInvalidation::Range(from.clone(), to.clone()).into(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Removes from the cache the bitmaps affected by a committed write.
+    pub fn invalidate(&self, invalidations: Vec<BitmapInvalidation>) {
+        if invalidations.is_empty() {
+            return;
+        }
+        let cache = if let Some(cache) = &self.cache {
+            cache
+        } else {
+            return;
+        };
+
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        for invalidation in invalidations {
+            match invalidation {
+                BitmapInvalidation::Key(key) => cache.invalidate(&key),
+                BitmapInvalidation::Range(from, to) => {
+                    if cache
+                        .invalidate_entries_if(move |key, _| key >= &from && key < &to)
+                        .is_err()
+                    {
+                        cache.invalidate_all();
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn invalidate_all(&self) {
+        if let Some(cache) = &self.cache {
+            self.epoch.fetch_add(1, Ordering::AcqRel);
+            cache.invalidate_all();
+        }
+    }
+
+    pub fn snapshot(&self) -> BitmapCacheSnapshot {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        BitmapCacheSnapshot {
+            enabled: self.cache.is_some(),
+            hits,
+            misses,
+            hit_rate: if hits + misses > 0 {
+                hits as f64 / (hits + misses) as f64
+            } else {
+                0.0
+            },
+            entries: self.cache.as_ref().map_or(0, |cache| cache.entry_count()),
+            size_bytes: self.cache.as_ref().map_or(0, |cache| cache.weighted_size()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ahash::AHashMap;
+    use roaring::RoaringBitmap;
+
+    use crate::{
+        config::env_settings::EnvSettings, write::operation::WriteOperation, ColumnFamily,
+    };
+
+    use super::BitmapCache;
+
+    #[test]
+    fn bitmap_cache_invalidation() {
+        let cache = BitmapCache::new(&EnvSettings {
+            args: AHashMap::default(),
+        });
+        let bitmap = Arc::new(RoaringBitmap::from_iter([1, 2, 3]));
+
+        // Entries read before a write are discarded
+        let epoch = cache.epoch();
+        let invalidations = cache.invalidations(&[WriteOperation::merge(
+            ColumnFamily::Bitmaps,
+            vec![1],
+            vec![],
+        )]);
+        cache.invalidate(invalidations);
+        cache.insert(vec![1], bitmap.clone(), epoch);
+        assert!(cache.get(&[1]).is_none());
+
+        // Keys and ranges are invalidated
+        for key in [vec![1], vec![2, 1], vec![3]] {
+            cache.insert(key, bitmap.clone(), cache.epoch());
+        }
+        assert_eq!(cache.get(&[1]).as_deref(), Some(bitmap.as_ref()));
+        let invalidations = cache.invalidations(&[
+            WriteOperation::delete(ColumnFamily::Bitmaps, vec![1]),
+            WriteOperation::delete_range(ColumnFamily::Bitmaps, vec![2], vec![3]),
+            WriteOperation::delete(ColumnFamily::Values, vec![3]),
+        ]);
+        cache.invalidate(invalidations);
+        assert!(cache.get(&[1]).is_none());
+        assert!(cache.get(&[2, 1]).is_none());
+        assert!(cache.get(&[3]).is_some());
+
+        let snapshot = cache.snapshot();
+        assert_eq!((snapshot.hits, snapshot.misses), (2, 3));
+    }
+}
@@ -23,7 +23,7 @@
 
 use roaring::RoaringBitmap;
 
-use crate::FieldId;
+use crate::{nlp::Language, FieldId};
 
 #[derive(Debug)]
 pub struct FieldComparator {
@@ -37,11 +37,29 @@ pub struct DocumentSetComparator {
     pub ascending: bool,
 }
 
+/// Sorts full-text matches by their BM25 score for the given text.
+#[derive(Debug)]
+pub struct RelevanceComparator {
+    pub fields: Vec<FieldId>,
+    pub text: String,
+    pub language: Language,
+    pub ascending: bool,
+}
+
+/// Groups of documents with the same rank, ordered from best to worst.
+#[derive(Debug)]
+pub struct RankedComparator {
+    pub ranks: Vec<RoaringBitmap>,
+    pub ascending: bool,
+}
+
 #[derive(Debug)]
 pub enum Comparator {
     List(Vec<Comparator>),
     Field(FieldComparator),
     DocumentSet(DocumentSetComparator),
+    Relevance(RelevanceComparator),
+    Ranked(RankedComparator),
     None,
 }
 
@@ -65,4 +83,13 @@ impl Comparator {
             ascending: false,
         })
     }
+
+    pub fn relevance(fields: Vec<FieldId>, text: String, language: Language) -> Self {
+        Comparator::Relevance(RelevanceComparator {
+            fields,
+            text,
+            language,
+            ascending: false,
+        })
+    }
 }
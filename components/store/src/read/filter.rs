@@ -145,6 +145,7 @@ pub enum Query {
     Tokenize(String),
     Index(String),
     Match(Text),
+    Prefix(String),
     Integer(Integer),
     LongInteger(LongInteger),
     Float(Float),
@@ -28,10 +28,12 @@ use crate::{
     core::tag::Tag,
     nlp::term_index::TermIndex,
     serialize::{
-        key::{BitmapKey, ValueKey},
+        key::{BitmapKey, ValueKey, ANNOTATION_FIELD},
+        leb128::Leb128Reader,
         StoreDeserialize,
     },
-    AccountId, Collection, ColumnFamily, DocumentId, FieldId, JMAPStore, Store, StoreError,
+    AccountId, Collection, ColumnFamily, Direction, DocumentId, FieldId, JMAPStore, Store,
+    StoreError,
 };
 
 impl<T> JMAPStore<T>
@@ -72,6 +74,42 @@ where
         )
     }
 
+    pub fn get_annotation(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+        document: DocumentId,
+        principal: AccountId,
+    ) -> crate::Result<Option<Vec<u8>>> {
+        self.db.get(
+            ColumnFamily::Values,
+            &ValueKey::serialize_annotation(account_id, collection, document, principal),
+        )
+    }
+
+    /// Returns the annotations stored by all principals on a document.
+    pub fn get_annotations(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+        document: DocumentId,
+    ) -> crate::Result<Vec<(AccountId, Vec<u8>)>> {
+        let prefix = ValueKey::serialize_value(account_id, collection, document, ANNOTATION_FIELD);
+        let mut annotations = Vec::new();
+        for (key, value) in self
+            .db
+            .iterator(ColumnFamily::Values, &prefix, Direction::Forward)?
+        {
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            if let Some((principal, _)) = (&key[prefix.len()..]).read_leb128() {
+                annotations.push((principal, value.to_vec()));
+            }
+        }
+        Ok(annotations)
+    }
+
     pub fn get_tag(
         &self,
         account_id: AccountId,
@@ -79,10 +117,9 @@ where
         field: FieldId,
         tag: Tag,
     ) -> crate::Result<Option<RoaringBitmap>> {
-        if let Some(tagged_docs) = self.db.get::<RoaringBitmap>(
-            ColumnFamily::Bitmaps,
-            &BitmapKey::serialize_tag(account_id, collection, field, &tag),
-        )? {
+        if let Some(tagged_docs) = self.get_cached_bitmap(&BitmapKey::serialize_tag(
+            account_id, collection, field, &tag,
+        ))? {
             if !tagged_docs.is_empty() {
                 return Ok(Some(tagged_docs));
             }
@@ -111,8 +148,7 @@ where
         tags: &[Tag],
     ) -> crate::Result<Vec<Option<RoaringBitmap>>> {
         let mut result = Vec::with_capacity(tags.len());
-        for tagged_docs in self.db.multi_get::<RoaringBitmap, _>(
-            ColumnFamily::Bitmaps,
+        for tagged_docs in self.get_cached_bitmaps(
             tags.iter()
                 .map(|tag| BitmapKey::serialize_tag(account_id, collection, field, tag))
                 .collect(),
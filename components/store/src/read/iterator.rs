@@ -24,10 +24,11 @@
 use std::ops::{BitAndAssign, BitXorAssign};
 
 use roaring::RoaringBitmap;
+use tracing::error;
 
 use crate::{
     core::collection::Collection, serialize::key::IndexKey, AccountId, ColumnFamily, Direction,
-    DocumentId, FieldId, JMAPId, JMAPStore, Store,
+    DocumentId, FieldId, JMAPId, JMAPStore, Store, StoreCursor,
 };
 
 use super::comparator::Comparator;
@@ -48,16 +49,24 @@ struct DocumentSetIndex {
     it: Option<roaring::bitmap::IntoIter>,
 }
 
+/// Yields documents in rank order, documents sharing a rank are passed on to
+/// the next comparator.
+struct RankedIndex {
+    ranks: Vec<RoaringBitmap>,
+    pos: usize,
+    pending: RoaringBitmap,
+}
+
 struct DBIndex<'x, T>
 where
     T: Store<'x>,
 {
-    it: Option<T::Iterator>,
+    it: Option<T::Cursor>,
     prefix: Vec<u8>,
     start_key: Vec<u8>,
     ascending: bool,
     prev_item: Option<DocumentId>,
-    prev_key: Option<Box<[u8]>>,
+    prev_key: Option<Vec<u8>>,
 }
 
 enum IndexType<'x, T>
@@ -65,7 +74,9 @@ where
     T: Store<'x>,
 {
     DocumentSet(DocumentSetIndex),
+    Ranked(RankedIndex),
     DB(DBIndex<'x, T>),
+    Sorted(std::vec::IntoIter<DocumentId>),
     None,
 }
 
@@ -76,6 +87,7 @@ where
     pub fn has_prev_item(&self) -> bool {
         match self {
             IndexType::DB(index) => index.prev_item.is_some(),
+            IndexType::Ranked(index) => !index.pending.is_empty(),
             _ => false,
         }
     }
@@ -150,6 +162,16 @@ where
                         },
                         it: None,
                     }),
+                    Comparator::Ranked(mut comp) => {
+                        if comp.ascending {
+                            comp.ranks.reverse();
+                        }
+                        IndexType::Ranked(RankedIndex {
+                            ranks: comp.ranks,
+                            pos: 0,
+                            pending: RoaringBitmap::new(),
+                        })
+                    }
                     _ => IndexType::None,
                 },
                 eof: false,
@@ -167,6 +189,24 @@ where
         }
     }
 
+    /// Iterates over results that were already sorted by `sort_parallel`.
+    pub fn sorted(
+        store: &'x JMAPStore<T>,
+        results: RoaringBitmap,
+        sorted: Vec<DocumentId>,
+    ) -> Self {
+        StoreIterator {
+            store,
+            iterators: vec![IndexIterator {
+                index: IndexType::Sorted(sorted.into_iter()),
+                remaining: results,
+                eof: false,
+            }],
+            filter_map: None,
+            current: 0,
+        }
+    }
+
     pub fn set_filter_map(mut self, filter_map: U) -> Self {
         self.filter_map = Some(filter_map);
         self
@@ -240,7 +280,7 @@ where
                             index.it = Some(
                                 self.store
                                     .db
-                                    .iterator(
+                                    .cursor(
                                         ColumnFamily::Indexes,
                                         &index.start_key,
                                         if index.ascending {
@@ -272,14 +312,22 @@ where
 
                         let mut is_eof = false;
                         loop {
-                            if let Some((key, _)) = it.next() {
+                            let has_next = match it.advance() {
+                                Ok(has_next) => has_next,
+                                Err(err) => {
+                                    error!("Failed to advance index cursor: {}", err);
+                                    return None;
+                                }
+                            };
+                            if has_next {
+                                let key = it.key();
                                 if !key.starts_with(&index.prefix) {
                                     index.prev_key = None;
                                     is_eof = true;
                                     break;
                                 }
 
-                                doc_id = IndexKey::deserialize_document_id(&key)?;
+                                doc_id = IndexKey::deserialize_document_id(key)?;
                                 if it_opts.remaining.contains(doc_id) {
                                     it_opts.remaining.remove(doc_id);
 
@@ -289,11 +337,11 @@ where
                                                 || !key.starts_with(prev_key_prefix)
                                             {
                                                 index.prev_item = Some(doc_id);
-                                                index.prev_key = Some(key);
+                                                index.prev_key = Some(key.to_vec());
                                                 break;
                                             }
                                         } else {
-                                            index.prev_key = Some(key);
+                                            index.prev_key = Some(key.to_vec());
                                             prev_key_prefix =
                                                 index.prev_key.as_ref().and_then(|key| {
                                                     key.get(
@@ -366,6 +414,52 @@ where
                             }
                         };
                     }
+                    IndexType::Ranked(index) => {
+                        if let Some(pending_id) = index.pending.min() {
+                            index.pending.remove(pending_id);
+                            doc_id = pending_id;
+                            break 'inner;
+                        }
+
+                        let mut has_next = false;
+                        while let Some(rank) = index.ranks.get(index.pos) {
+                            index.pos += 1;
+                            let mut set = rank.clone();
+                            set.bitand_assign(&it_opts.remaining);
+                            if set.is_empty() {
+                                continue;
+                            }
+                            it_opts.remaining.bitxor_assign(&set);
+
+                            match &mut next_it_opts {
+                                Some(next_it_opts) if set.len() > 1 => {
+                                    next_it_opts.remaining = set;
+                                    has_next = true;
+                                    break;
+                                }
+                                _ => {
+                                    doc_id = set.min().unwrap();
+                                    set.remove(doc_id);
+                                    index.pending = set;
+                                    break 'inner;
+                                }
+                            }
+                        }
+
+                        // Unranked documents are sorted by the next comparator
+                        if !has_next && !it_opts.remaining.is_empty() {
+                            if let Some(ref mut next_it_opts) = next_it_opts {
+                                next_it_opts.remaining = std::mem::take(&mut it_opts.remaining);
+                            }
+                        }
+                    }
+                    IndexType::Sorted(it) => {
+                        if let Some(sorted_id) = it.next() {
+                            it_opts.remaining.remove(sorted_id);
+                            doc_id = sorted_id;
+                            break 'inner;
+                        }
+                    }
                     IndexType::None => (),
                 };
 
@@ -382,7 +476,7 @@ where
                                         *it = self
                                             .store
                                             .db
-                                            .iterator(
+                                            .cursor(
                                                 ColumnFamily::Indexes,
                                                 &index.start_key,
                                                 if index.ascending {
@@ -399,7 +493,11 @@ where
                                 IndexType::DocumentSet(index) => {
                                     index.it = None;
                                 }
-                                IndexType::None => (),
+                                IndexType::Ranked(index) => {
+                                    index.pos = 0;
+                                    index.pending.clear();
+                                }
+                                IndexType::Sorted(_) | IndexType::None => (),
                             }
 
                             self.current += 1;
@@ -25,11 +25,15 @@ use crate::{DocumentId, JMAPId};
 
 pub mod acl;
 pub mod bitmap;
+pub mod cache;
 pub mod comparator;
 pub mod filter;
 pub mod get;
 pub mod iterator;
 pub mod query;
+pub mod relevance;
+pub mod scan;
+pub mod sort;
 
 pub type FilterMapper = fn(DocumentId) -> crate::Result<Option<JMAPId>>;
 
@@ -25,7 +25,8 @@ use crate::{
     core::{collection::Collection, document::MAX_TOKEN_LENGTH, error::StoreError},
     nlp::{stemmer::Stemmer, tokenizers::Tokenizer, Language},
     serialize::key::{BitmapKey, IndexKey},
-    AccountId, DocumentId, JMAPId, JMAPStore, Store,
+    write::prune::PrunedTerms,
+    AccountId, ColumnFamily, Direction, DocumentId, FieldId, JMAPId, JMAPStore, Store,
 };
 
 use ahash::AHashSet;
@@ -66,24 +67,16 @@ where
         let filter = match filter {
             Filter::Operator(filter) => filter,
             Filter::None => {
-                return Ok(StoreIterator::new(
-                    self,
-                    document_ids.clone(),
-                    document_ids,
+                return self.sort_results(
                     account_id,
                     collection,
+                    document_ids.clone(),
+                    document_ids,
                     sort,
-                ));
+                );
             }
             Filter::DocumentSet(set) => {
-                return Ok(StoreIterator::new(
-                    self,
-                    set,
-                    document_ids,
-                    account_id,
-                    collection,
-                    sort,
-                ));
+                return self.sort_results(account_id, collection, set, document_ids, sort);
             }
             _ => FilterOperator {
                 operator: LogicalOperator::And,
@@ -98,6 +91,7 @@ where
         };
 
         let mut stack = Vec::new();
+        let mut pruned_terms = None;
 
         'outer: loop {
             while let Some(cond) = state.it.next() {
@@ -119,48 +113,91 @@ where
                             }
                             Query::Tokenize(text) => {
                                 let field_cond_field = filter_cond.field;
-                                state.op.apply(
-                                    &mut state.bm,
-                                    self.get_bitmaps_intersection(
-                                        Tokenizer::new(&text, Language::English, MAX_TOKEN_LENGTH)
-                                            .map(|token| {
-                                                BitmapKey::serialize_term(
-                                                    account_id,
-                                                    collection,
-                                                    field_cond_field,
-                                                    &token.word,
-                                                    true,
-                                                )
-                                            })
-                                            .collect(),
-                                    )?,
-                                    &document_ids,
-                                );
+                                let pruned_terms = self.load_pruned_terms(
+                                    &mut pruned_terms,
+                                    account_id,
+                                    collection,
+                                )?;
+                                let mut keys = Vec::new();
+                                let mut pruned_words = Vec::new();
+                                for token in Tokenizer::with_config(
+                                    &text,
+                                    Language::English,
+                                    MAX_TOKEN_LENGTH,
+                                    &self.config.nlp,
+                                ) {
+                                    let key = BitmapKey::serialize_term(
+                                        account_id,
+                                        collection,
+                                        field_cond_field,
+                                        &token.word,
+                                        true,
+                                    );
+                                    if pruned_terms.contains(&key) {
+                                        pruned_words.push(vec![(token.word.into_owned(), None)]);
+                                    } else {
+                                        keys.push(key);
+                                    }
+                                }
+
+                                let bitmap = if pruned_words.is_empty() {
+                                    self.get_bitmaps_intersection(keys)?
+                                } else {
+                                    self.match_pruned_terms(
+                                        account_id,
+                                        collection,
+                                        field_cond_field,
+                                        if keys.is_empty() {
+                                            Some(document_ids.clone())
+                                        } else {
+                                            self.get_bitmaps_intersection(keys)?
+                                        },
+                                        &pruned_words,
+                                        true,
+                                    )?
+                                };
+                                state.op.apply(&mut state.bm, bitmap, &document_ids);
                             }
                             Query::Match(text) => {
+                                let pruned_terms = self.load_pruned_terms(
+                                    &mut pruned_terms,
+                                    account_id,
+                                    collection,
+                                )?;
                                 if text.match_phrase {
                                     let mut phrase: Vec<String> = Vec::new();
                                     let field = filter_cond.field;
+                                    let keys = Tokenizer::with_config(
+                                        &text.text,
+                                        text.language,
+                                        MAX_TOKEN_LENGTH,
+                                        &self.config.nlp,
+                                    )
+                                    .full_text()
+                                    .filter_map(|token| {
+                                        let word = token.word.into_owned();
+                                        let r = if !phrase.contains(&word) {
+                                            BitmapKey::serialize_term(
+                                                account_id, collection, field, &word, true,
+                                            )
+                                            .into()
+                                        } else {
+                                            None
+                                        };
+                                        phrase.push(word);
+                                        r
+                                    })
+                                    .filter(|key| !pruned_terms.contains(key))
+                                    .collect::<Vec<_>>();
 
-                                    // Retrieve the Term Index for each candidate and match the exact phrase
-                                    if let Some(candidates) = self.get_bitmaps_intersection(
-                                        Tokenizer::new(&text.text, text.language, MAX_TOKEN_LENGTH)
-                                            .into_iter()
-                                            .filter_map(|token| {
-                                                let word = token.word.into_owned();
-                                                let r = if !phrase.contains(&word) {
-                                                    BitmapKey::serialize_term(
-                                                        account_id, collection, field, &word, true,
-                                                    )
-                                                    .into()
-                                                } else {
-                                                    None
-                                                };
-                                                phrase.push(word);
-                                                r
-                                            })
-                                            .collect(),
-                                    )? {
+                                    // Retrieve the Term Index for each candidate and match the exact phrase,
+                                    // checking all documents when every term was pruned.
+                                    let candidates = if keys.is_empty() && !phrase.is_empty() {
+                                        Some(document_ids.clone())
+                                    } else {
+                                        self.get_bitmaps_intersection(keys)?
+                                    };
+                                    if let Some(candidates) = candidates {
                                         let mut results = RoaringBitmap::new();
                                         for document_id in candidates.iter() {
                                             if let Some(term_index) = self.get_term_index(
@@ -204,6 +241,7 @@ where
                                 } else {
                                     let mut requested_keys = AHashSet::default();
                                     let mut text_bitmap = None;
+                                    let mut pruned_groups = Vec::new();
 
                                     // Default language for stemming
                                     let language = if text.language != Language::Unknown {
@@ -212,31 +250,53 @@ where
                                         self.config.default_language
                                     };
 
-                                    for token in
-                                        Stemmer::new(&text.text, language, MAX_TOKEN_LENGTH)
-                                    {
+                                    for token in Stemmer::with_config(
+                                        &text.text,
+                                        language,
+                                        MAX_TOKEN_LENGTH,
+                                        &self.config.nlp,
+                                    ) {
                                         let mut keys = Vec::new();
 
-                                        for (word, is_exact) in [
-                                            (token.word.as_ref().into(), true),
-                                            (token.word.as_ref().into(), false),
-                                            (token.stemmed_word.as_ref().map(|w| w.as_ref()), true),
-                                            (
-                                                token.stemmed_word.as_ref().map(|w| w.as_ref()),
-                                                false,
-                                            ),
-                                        ] {
-                                            if let Some(word) = word {
-                                                let key = BitmapKey::serialize_term(
-                                                    account_id,
-                                                    collection,
-                                                    filter_cond.field,
-                                                    word,
-                                                    is_exact,
-                                                );
-                                                if !requested_keys.contains(&key) {
-                                                    requested_keys.insert(key.clone());
-                                                    keys.push(key);
+                                        // Match any of the token's synonyms as well
+                                        let mut tokens = Vec::new();
+                                        if self.config.synonyms.expand_query() {
+                                            for synonym in self.config.synonyms.get(&token.word) {
+                                                tokens.extend(Stemmer::with_config(
+                                                    synonym,
+                                                    language,
+                                                    MAX_TOKEN_LENGTH,
+                                                    &self.config.nlp,
+                                                ));
+                                            }
+                                        }
+                                        tokens.push(token);
+
+                                        for token in &tokens {
+                                            for (word, is_exact) in [
+                                                (token.word.as_ref().into(), true),
+                                                (token.word.as_ref().into(), false),
+                                                (
+                                                    token.stemmed_word.as_ref().map(|w| w.as_ref()),
+                                                    true,
+                                                ),
+                                                (
+                                                    token.stemmed_word.as_ref().map(|w| w.as_ref()),
+                                                    false,
+                                                ),
+                                            ] {
+                                                if let Some(word) = word {
+                                                    let key = BitmapKey::serialize_term(
+                                                        account_id,
+                                                        collection,
+                                                        filter_cond.field,
+                                                        word,
+                                                        is_exact,
+                                                    );
+                                                    if !requested_keys.contains(&key) {
+                                                        requested_keys.insert(key.clone());
+                                                        keys.push(key);
+                                                    }
                                                 }
                                             }
                                         }
@@ -246,6 +306,25 @@ where
                                             continue;
                                         }
 
+                                        // Pruned terms are matched once the other terms narrowed down the candidates
+                                        if keys.iter().any(|key| pruned_terms.contains(key)) {
+                                            pruned_groups.push(
+                                                tokens
+                                                    .iter()
+                                                    .map(|token| {
+                                                        (
+                                                            token.word.to_string(),
+                                                            token
+                                                                .stemmed_word
+                                                                .as_ref()
+                                                                .map(|word| word.to_string()),
+                                                        )
+                                                    })
+                                                    .collect::<Vec<_>>(),
+                                            );
+                                            continue;
+                                        }
+
                                         LogicalOperator::And.apply(
                                             &mut text_bitmap,
                                             self.get_bitmaps_union(keys)?,
@@ -256,9 +335,61 @@ where
                                             break;
                                         }
                                     }
+                                    if !pruned_groups.is_empty()
+                                        && text_bitmap.as_ref().map_or(true, |bm| !bm.is_empty())
+                                    {
+                                        text_bitmap = self.match_pruned_terms(
+                                            account_id,
+                                            collection,
+                                            filter_cond.field,
+                                            text_bitmap.or_else(|| Some(document_ids.clone())),
+                                            &pruned_groups,
+                                            false,
+                                        )?;
+                                    }
                                     state.op.apply(&mut state.bm, text_bitmap, &document_ids);
                                 }
                             }
+                            Query::Prefix(prefix) => {
+                                let field = filter_cond.field;
+                                let mut bitmap = self.get_bitmaps_union(
+                                    self.expand_prefix(account_id, collection, field, &prefix)?,
+                                )?;
+
+                                // Pruned terms starting with the prefix have no postings
+                                let key_prefix = BitmapKey::serialize_term(
+                                    account_id, collection, field, &prefix, true,
+                                );
+                                let pruned_words = self
+                                    .load_pruned_terms(&mut pruned_terms, account_id, collection)?
+                                    .starting_with(&key_prefix)
+                                    .filter_map(|key| {
+                                        String::from_utf8(
+                                            key[key_prefix.len() - prefix.len()..].to_vec(),
+                                        )
+                                        .ok()
+                                        .map(|word| (word, None))
+                                    })
+                                    .collect::<Vec<_>>();
+                                if !pruned_words.is_empty() {
+                                    let mut candidates = document_ids.clone();
+                                    if let Some(bitmap) = &bitmap {
+                                        candidates -= bitmap;
+                                    }
+                                    if let Some(matches) = self.match_pruned_terms(
+                                        account_id,
+                                        collection,
+                                        field,
+                                        Some(candidates),
+                                        &[pruned_words],
+                                        true,
+                                    )? {
+                                        *bitmap.get_or_insert_with(RoaringBitmap::new) |= matches;
+                                    }
+                                }
+
+                                state.op.apply(&mut state.bm, bitmap, &document_ids);
+                            }
                             Query::Integer(i) => {
                                 state.op.apply(
                                     &mut state.bm,
@@ -362,9 +493,146 @@ where
             }
         }
 
+        let results = state.bm.unwrap_or_else(RoaringBitmap::new);
+        self.sort_results(account_id, collection, results, document_ids, sort)
+    }
+
+    // Pruned terms are loaded at most once per query, and only by text conditions.
+    fn load_pruned_terms<'y>(
+        &self,
+        pruned_terms: &'y mut Option<PrunedTerms>,
+        account_id: AccountId,
+        collection: Collection,
+    ) -> crate::Result<&'y PrunedTerms> {
+        if pruned_terms.is_none() {
+            *pruned_terms = self.get_pruned_terms(account_id, collection)?.into();
+        }
+        Ok(pruned_terms.as_ref().unwrap())
+    }
+
+    // Pruned terms have no postings, so the candidates containing them are found
+    // using their term index. A candidate matches when it contains in `field` any of
+    // the words of every group, either exactly or, unless `is_exact`, by their stem.
+    fn match_pruned_terms(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+        field: FieldId,
+        candidates: Option<RoaringBitmap>,
+        groups: &[Vec<(String, Option<String>)>],
+        is_exact: bool,
+    ) -> crate::Result<Option<RoaringBitmap>> {
+        let candidates = if let Some(candidates) = candidates {
+            candidates
+        } else {
+            return Ok(None);
+        };
+        let mut results = RoaringBitmap::new();
+
+        'outer: for document_id in candidates.iter() {
+            let term_index = if let Some(term_index) =
+                self.get_term_index(account_id, collection, document_id)?
+            {
+                term_index
+            } else {
+                continue;
+            };
+            for group in groups {
+                let mut is_match = false;
+                for (word, stemmed_word) in group {
+                    // Matching a single term as a phrase only compares the exact word
+                    if term_index
+                        .match_terms(
+                            &[term_index.get_match_term(word, stemmed_word.as_deref())],
+                            Some(AHashSet::from_iter([field])),
+                            is_exact,
+                            false,
+                            false,
+                        )
+                        .map_err(|e| {
+                            StoreError::InternalError(format!(
+                                "Corrupted TermIndex for {}: {:?}",
+                                document_id, e
+                            ))
+                        })?
+                        .is_some()
+                    {
+                        is_match = true;
+                        break;
+                    }
+                }
+                if !is_match {
+                    continue 'outer;
+                }
+            }
+            results.insert(document_id);
+        }
+
+        Ok(Some(results))
+    }
+
+    /// Returns the keys of the terms indexed under `field` that start with
+    /// `prefix`, refusing prefixes that are too short or that match more
+    /// terms than allowed by the configuration. The prefix is matched as is,
+    /// callers are expected to normalize it the same way the field is indexed.
+    pub fn expand_prefix(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+        field: FieldId,
+        prefix: &str,
+    ) -> crate::Result<Vec<Vec<u8>>> {
+        if prefix.chars().count() < self.config.query_prefix_min_len {
+            return Err(StoreError::InvalidArguments(format!(
+                "Wildcard searches require at least {} characters before the '*'.",
+                self.config.query_prefix_min_len
+            )));
+        }
+
+        let key_prefix = BitmapKey::serialize_term(account_id, collection, field, prefix, true);
+        let mut keys = Vec::new();
+        for (key, _) in self
+            .db
+            .iterator(ColumnFamily::Bitmaps, &key_prefix, Direction::Forward)?
+        {
+            if !key.starts_with(&key_prefix) {
+                break;
+            }
+            if keys.len() == self.config.query_prefix_max_terms {
+                return Err(StoreError::InvalidArguments(format!(
+                    "Wildcard search '{}*' matches too many terms.",
+                    prefix
+                )));
+            }
+            keys.push(key.to_vec());
+        }
+
+        Ok(keys)
+    }
+
+    fn sort_results<'y: 'x, 'x, U>(
+        &'y self,
+        account_id: AccountId,
+        collection: Collection,
+        results: RoaringBitmap,
+        document_ids: RoaringBitmap,
+        sort: Comparator,
+    ) -> crate::Result<StoreIterator<'x, T, U>>
+    where
+        U: FnMut(DocumentId) -> crate::Result<Option<JMAPId>>,
+    {
+        let sort = self.rank_results(account_id, collection, &results, sort)?;
+        if let Comparator::List(list) = &sort {
+            if self.can_sort_parallel(&results, list) {
+                let sorted =
+                    self.sort_parallel(account_id, collection, &results, &document_ids, list)?;
+                return Ok(StoreIterator::sorted(self, results, sorted));
+            }
+        }
+
         Ok(StoreIterator::new(
             self,
-            state.bm.unwrap_or_else(RoaringBitmap::new),
+            results,
             document_ids,
             account_id,
             collection,
@@ -0,0 +1,203 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use ahash::{AHashMap, AHashSet};
+use roaring::RoaringBitmap;
+
+use crate::{
+    core::{collection::Collection, document::MAX_TOKEN_LENGTH, error::StoreError},
+    nlp::{stemmer::Stemmer, term_index::MatchTerm, Language},
+    serialize::key::BitmapKey,
+    AccountId, DocumentId, JMAPStore, Store,
+};
+
+use super::comparator::{Comparator, RankedComparator, RelevanceComparator};
+
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+const MAX_RELEVANCE_TERMS: usize = 64;
+
+impl<T> JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Scores documents against a full-text query using BM25. Document frequencies
+    /// are obtained from the term bitmaps, term frequencies and document lengths
+    /// from each document's term index. The average document length is taken from
+    /// the scored documents rather than from the whole collection.
+    pub fn score_documents(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+        document_ids: &RoaringBitmap,
+        comparator: &RelevanceComparator,
+    ) -> crate::Result<Vec<(DocumentId, f64)>> {
+        let language = if comparator.language != Language::Unknown {
+            comparator.language
+        } else {
+            self.config.default_language
+        };
+
+        // Obtain the unique terms and their document frequencies
+        let mut terms = Vec::new();
+        let mut seen_terms = AHashSet::new();
+        for token in Stemmer::with_config(
+            &comparator.text,
+            language,
+            MAX_TOKEN_LENGTH,
+            &self.config.nlp,
+        ) {
+            if !seen_terms.insert(token.word.to_string()) {
+                continue;
+            }
+            let mut keys = Vec::new();
+            for field in &comparator.fields {
+                for (word, is_exact) in [
+                    (Some(token.word.as_ref()), true),
+                    (Some(token.word.as_ref()), false),
+                    (token.stemmed_word.as_deref(), true),
+                    (token.stemmed_word.as_deref(), false),
+                ] {
+                    if let Some(word) = word {
+                        keys.push(BitmapKey::serialize_term(
+                            account_id, collection, *field, word, is_exact,
+                        ));
+                    }
+                }
+            }
+            let doc_freq = self
+                .get_bitmaps_union(keys)?
+                .map(|bm| bm.len())
+                .unwrap_or(0);
+            terms.push((
+                token.word.into_owned(),
+                token.stemmed_word.map(|word| word.into_owned()),
+                doc_freq,
+            ));
+            if terms.len() == MAX_RELEVANCE_TERMS {
+                break;
+            }
+        }
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let total_docs = self
+            .get_document_ids(account_id, collection)?
+            .map(|bm| bm.len())
+            .unwrap_or(0) as f64;
+        let idfs = terms
+            .iter()
+            .map(|(_, _, doc_freq)| {
+                let doc_freq = *doc_freq as f64;
+                (1.0 + (total_docs - doc_freq + 0.5) / (doc_freq + 0.5)).ln()
+            })
+            .collect::<Vec<_>>();
+
+        // Count term frequencies on each document
+        let fields = comparator.fields.iter().copied().collect::<AHashSet<_>>();
+        let mut frequencies = AHashMap::with_capacity(document_ids.len() as usize);
+        let mut total_len = 0u64;
+        for document_id in document_ids {
+            if let Some(term_index) = self.get_term_index(account_id, collection, document_id)? {
+                let match_terms = terms
+                    .iter()
+                    .map(|(word, stemmed_word, _)| {
+                        term_index.get_match_term(word, stemmed_word.as_deref())
+                    })
+                    .collect::<Vec<MatchTerm>>();
+                let (term_freqs, doc_len) = term_index
+                    .term_frequencies(&match_terms, &fields)
+                    .map_err(|e| {
+                        StoreError::InternalError(format!(
+                            "Corrupted TermIndex for {}: {:?}",
+                            document_id, e
+                        ))
+                    })?;
+                total_len += doc_len as u64;
+                frequencies.insert(document_id, (term_freqs, doc_len));
+            }
+        }
+        if frequencies.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let avg_len = (total_len as f64 / frequencies.len() as f64).max(1.0);
+        Ok(frequencies
+            .into_iter()
+            .map(|(document_id, (term_freqs, doc_len))| {
+                let norm = BM25_K1 * (1.0 - BM25_B + BM25_B * doc_len as f64 / avg_len);
+                let score = term_freqs
+                    .into_iter()
+                    .zip(idfs.iter())
+                    .filter(|(term_freq, _)| *term_freq > 0)
+                    .map(|(term_freq, idf)| {
+                        let term_freq = term_freq as f64;
+                        idf * (term_freq * (BM25_K1 + 1.0)) / (term_freq + norm)
+                    })
+                    .sum::<f64>();
+                (document_id, score)
+            })
+            .collect())
+    }
+
+    /// Replaces any relevance comparators with the ranking of the results.
+    pub(crate) fn rank_results(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+        results: &RoaringBitmap,
+        sort: Comparator,
+    ) -> crate::Result<Comparator> {
+        Ok(match sort {
+            Comparator::List(list) => Comparator::List(
+                list.into_iter()
+                    .map(|comp| self.rank_results(account_id, collection, results, comp))
+                    .collect::<crate::Result<Vec<_>>>()?,
+            ),
+            Comparator::Relevance(comp) => {
+                let mut scores = self.score_documents(account_id, collection, results, &comp)?;
+                scores.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+                let mut ranks: Vec<RoaringBitmap> = Vec::new();
+                let mut last_score = None;
+                for (document_id, score) in scores {
+                    if last_score == Some(score) {
+                        ranks.last_mut().unwrap().insert(document_id);
+                    } else {
+                        let mut rank = RoaringBitmap::new();
+                        rank.insert(document_id);
+                        ranks.push(rank);
+                        last_score = Some(score);
+                    }
+                }
+
+                Comparator::Ranked(RankedComparator {
+                    ranks,
+                    ascending: comp.ascending,
+                })
+            }
+            comp => comp,
+        })
+    }
+}
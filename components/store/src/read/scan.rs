@@ -0,0 +1,223 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{
+    core::error::StoreError, serialize::StoreDeserialize, ColumnFamily, Direction, JMAPStore,
+    Store, StoreCursor,
+};
+
+/// Scans all keys starting with a prefix, yielding entries that borrow
+/// from the underlying cursor and are only deserialized on demand.
+pub struct PrefixScan<'x, T>
+where
+    T: Store<'x>,
+{
+    cursor: T::Cursor,
+    prefix: Vec<u8>,
+    ascending: bool,
+}
+
+pub struct ScanEntry<'y> {
+    pub key: &'y [u8],
+    pub value: &'y [u8],
+    prefix_len: usize,
+}
+
+impl<'y> ScanEntry<'y> {
+    /// Returns the part of the key that follows the scanned prefix.
+    pub fn key_suffix(&self) -> &'y [u8] {
+        &self.key[self.prefix_len..]
+    }
+
+    pub fn deserialize_value<U>(&self) -> crate::Result<U>
+    where
+        U: StoreDeserialize,
+    {
+        U::deserialize(self.value).ok_or_else(|| {
+            StoreError::DeserializeError(format!("Failed to deserialize value for {:?}", self.key))
+        })
+    }
+}
+
+impl<'x, T> PrefixScan<'x, T>
+where
+    T: Store<'x>,
+{
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> crate::Result<Option<ScanEntry<'_>>> {
+        loop {
+            if !self.cursor.advance()? {
+                return Ok(None);
+            }
+            let key = self.cursor.key();
+            if key.starts_with(&self.prefix) {
+                break;
+            } else if self.ascending || key < self.prefix.as_slice() {
+                return Ok(None);
+            }
+            // Backward scans start past the end of the prefix.
+        }
+
+        Ok(Some(ScanEntry {
+            key: self.cursor.key(),
+            value: self.cursor.value(),
+            prefix_len: self.prefix.len(),
+        }))
+    }
+}
+
+/// Scans several prefixes in ascending order, reusing the same cursor when
+/// the next prefix starts where the previous one ended (e.g. the same index
+/// of consecutive accounts).
+pub struct MultiPrefixScan<'x, T>
+where
+    T: Store<'x>,
+{
+    db: &'x T,
+    cf: ColumnFamily,
+    cursor: Option<T::Cursor>,
+    prefixes: Vec<Vec<u8>>,
+    pos: usize,
+    has_entry: bool,
+}
+
+impl<'x, T> MultiPrefixScan<'x, T>
+where
+    T: Store<'x>,
+{
+    /// Returns the next entry along with the position of its prefix in the
+    /// sorted list of prefixes.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> crate::Result<Option<(usize, ScanEntry<'_>)>> {
+        loop {
+            let prefix = if let Some(prefix) = self.prefixes.get(self.pos) {
+                prefix
+            } else {
+                return Ok(None);
+            };
+            if self.cursor.is_none() {
+                self.cursor = Some(self.db.cursor(self.cf, prefix, Direction::Forward)?);
+                self.has_entry = false;
+            }
+            let cursor = self.cursor.as_mut().unwrap();
+            if !std::mem::take(&mut self.has_entry) && !cursor.advance()? {
+                self.pos = self.prefixes.len();
+                return Ok(None);
+            }
+            let key = cursor.key();
+            if key.starts_with(prefix) {
+                break;
+            }
+
+            // The cursor moved past the current prefix, skip any prefixes it
+            // already left behind.
+            self.pos += 1;
+            while let Some(prefix) = self.prefixes.get(self.pos) {
+                if key.starts_with(prefix) || key < prefix.as_slice() {
+                    break;
+                }
+                self.pos += 1;
+            }
+            match self.prefixes.get(self.pos) {
+                Some(prefix) if key.starts_with(prefix) => self.has_entry = true,
+                Some(_) => self.cursor = None,
+                None => return Ok(None),
+            }
+        }
+
+        let cursor = self.cursor.as_ref().unwrap();
+        Ok(Some((
+            self.pos,
+            ScanEntry {
+                key: cursor.key(),
+                value: cursor.value(),
+                prefix_len: self.prefixes[self.pos].len(),
+            },
+        )))
+    }
+}
+
+impl<T> JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Scans all keys starting with any of the prefixes in ascending order.
+    pub fn scan_multi_prefix(
+        &self,
+        cf: ColumnFamily,
+        mut prefixes: Vec<Vec<u8>>,
+    ) -> MultiPrefixScan<'_, T> {
+        prefixes.sort_unstable();
+        prefixes.dedup();
+        MultiPrefixScan {
+            db: &self.db,
+            cf,
+            cursor: None,
+            prefixes,
+            pos: 0,
+            has_entry: false,
+        }
+    }
+
+    pub fn scan_prefix(
+        &self,
+        cf: ColumnFamily,
+        prefix: Vec<u8>,
+        direction: Direction,
+    ) -> crate::Result<PrefixScan<'_, T>> {
+        let ascending = matches!(direction, Direction::Forward);
+        Ok(PrefixScan {
+            cursor: if ascending {
+                self.db.cursor(cf, &prefix, direction)?
+            } else {
+                self.db.cursor(cf, &prefix_end(&prefix), direction)?
+            },
+            prefix,
+            ascending,
+        })
+    }
+}
+
+/// Returns a key greater than any key starting with the prefix.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(byte) = end.pop() {
+        if byte < u8::MAX {
+            end.push(byte + 1);
+            return end;
+        }
+    }
+    vec![u8::MAX; prefix.len() + 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::prefix_end;
+
+    #[test]
+    fn prefix_end_key() {
+        assert_eq!(prefix_end(&[1, 2, 3]), vec![1, 2, 4]);
+        assert_eq!(prefix_end(&[1, 2, u8::MAX]), vec![1, 3]);
+        assert_eq!(prefix_end(&[u8::MAX, u8::MAX]), vec![u8::MAX; 3]);
+    }
+}
@@ -0,0 +1,260 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use rayon::prelude::*;
+use roaring::RoaringBitmap;
+
+use crate::{
+    core::{collection::Collection, error::StoreError},
+    serialize::key::IndexKey,
+    AccountId, ColumnFamily, Direction, DocumentId, FieldId, JMAPStore, Store, StoreCursor,
+};
+
+use super::comparator::{Comparator, DocumentSetComparator, FieldComparator, RankedComparator};
+
+const UNRANKED: u32 = u32::MAX;
+
+// Head of a sorted partition during the merge, ordered so that the
+// binary heap pops the smallest sort key first.
+struct Head<'x> {
+    key: &'x [u32],
+    pos: u32,
+    partition: usize,
+}
+
+impl<T> JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Whether the results should be sorted with `sort_parallel` rather than by
+    /// walking the indexes, which re-scans the index of every tie-breaking field
+    /// for each group of equal values.
+    pub fn can_sort_parallel(&self, results: &RoaringBitmap, comparators: &[Comparator]) -> bool {
+        self.config.query_sort_parallel_min > 0
+            && results.len() >= self.config.query_sort_parallel_min
+            && comparators.len() > 1
+            && comparators.iter().all(|comparator| {
+                matches!(
+                    comparator,
+                    Comparator::Field(_) | Comparator::DocumentSet(_) | Comparator::Ranked(_)
+                )
+            })
+    }
+
+    /// Sorts the results by a list of comparators. Each comparator is reduced to
+    /// a rank per document, the documents are then split into one partition per
+    /// worker thread, sorted on the rayon pool and merged.
+    pub fn sort_parallel(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+        results: &RoaringBitmap,
+        document_ids: &RoaringBitmap,
+        comparators: &[Comparator],
+    ) -> crate::Result<Vec<DocumentId>> {
+        let doc_ids = results.iter().collect::<Vec<_>>();
+        let num_docs = doc_ids.len();
+        let num_keys = comparators.len();
+        if num_docs == 0 {
+            return Ok(doc_ids);
+        }
+
+        // Sort keys are stored contiguously, one row of ranks per document.
+        let mut keys = vec![UNRANKED; num_docs * num_keys];
+        for (key_pos, comparator) in comparators.iter().enumerate() {
+            let mut set_rank = |document_id: DocumentId, rank: u32| -> bool {
+                if let Ok(doc_pos) = doc_ids.binary_search(&document_id) {
+                    let key = &mut keys[(doc_pos * num_keys) + key_pos];
+                    if *key == UNRANKED {
+                        *key = rank;
+                        return true;
+                    }
+                }
+                false
+            };
+
+            match comparator {
+                Comparator::Field(comparator) => self.rank_field(
+                    account_id,
+                    collection,
+                    comparator,
+                    key_pos == num_keys - 1,
+                    num_docs,
+                    set_rank,
+                )?,
+                Comparator::DocumentSet(DocumentSetComparator { set, ascending }) => {
+                    // Documents in the set come first, or last when descending.
+                    let set = if *ascending {
+                        set.clone()
+                    } else if !set.is_empty() {
+                        set ^ document_ids
+                    } else {
+                        document_ids.clone()
+                    };
+                    for &document_id in &doc_ids {
+                        set_rank(document_id, if set.contains(document_id) { 0 } else { 1 });
+                    }
+                }
+                Comparator::Ranked(RankedComparator { ranks, ascending }) => {
+                    let mut rank_documents = |rank_pos: usize, rank: &RoaringBitmap| {
+                        for document_id in rank & results {
+                            set_rank(document_id, rank_pos as u32);
+                        }
+                    };
+                    if *ascending {
+                        ranks.iter().rev().enumerate().for_each(|(rank_pos, rank)| {
+                            rank_documents(rank_pos, rank);
+                        });
+                    } else {
+                        ranks.iter().enumerate().for_each(|(rank_pos, rank)| {
+                            rank_documents(rank_pos, rank);
+                        });
+                    }
+                }
+                _ => {
+                    return Err(StoreError::InvalidArguments(
+                        "Unsupported comparator for parallel sort.".to_string(),
+                    ))
+                }
+            }
+        }
+
+        // Sort each partition, positions are in document id order so
+        // documents with equal keys are returned by ascending id.
+        let compare = |a: &u32, b: &u32| {
+            let (a, b) = (*a as usize, *b as usize);
+            keys[a * num_keys..(a + 1) * num_keys]
+                .cmp(&keys[b * num_keys..(b + 1) * num_keys])
+                .then(a.cmp(&b))
+        };
+        let mut positions = (0..num_docs as u32).collect::<Vec<_>>();
+        let partition_size =
+            (num_docs + rayon::current_num_threads() - 1) / rayon::current_num_threads();
+        positions
+            .par_chunks_mut(partition_size)
+            .for_each(|partition| partition.sort_unstable_by(compare));
+
+        // Merge the sorted partitions
+        let partitions = positions.chunks(partition_size).collect::<Vec<_>>();
+        let mut offsets = vec![0; partitions.len()];
+        let mut heap = BinaryHeap::with_capacity(partitions.len());
+        let head = |partition: usize, pos: u32| Head {
+            key: &keys[pos as usize * num_keys..(pos as usize + 1) * num_keys],
+            pos,
+            partition,
+        };
+        for (partition, positions) in partitions.iter().enumerate() {
+            heap.push(head(partition, positions[0]));
+        }
+
+        let mut sorted = Vec::with_capacity(num_docs);
+        while let Some(Head { pos, partition, .. }) = heap.pop() {
+            sorted.push(doc_ids[pos as usize]);
+            offsets[partition] += 1;
+            if let Some(&pos) = partitions[partition].get(offsets[partition]) {
+                heap.push(head(partition, pos));
+            }
+        }
+
+        Ok(sorted)
+    }
+
+    // Walks the field index in sort order assigning each document the rank of
+    // its first value. Documents without a value are left unranked, which sorts
+    // them last regardless of the direction. Only the last comparator breaks
+    // ties by index order, matching the sequential iterator.
+    fn rank_field(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+        comparator: &FieldComparator,
+        is_last: bool,
+        num_docs: usize,
+        mut set_rank: impl FnMut(DocumentId, u32) -> bool,
+    ) -> crate::Result<()> {
+        let prefix = IndexKey::serialize_field(account_id, collection as u8, comparator.field);
+        let mut cursor = if comparator.ascending {
+            self.db
+                .cursor(ColumnFamily::Indexes, &prefix, Direction::Forward)?
+        } else {
+            let (key_account_id, key_collection, key_field) = if comparator.field < FieldId::MAX {
+                (account_id, collection as u8, comparator.field + 1)
+            } else if (collection as u8) < u8::MAX {
+                (account_id, (collection as u8) + 1, comparator.field)
+            } else {
+                (account_id + 1, collection as u8, comparator.field)
+            };
+            self.db.cursor(
+                ColumnFamily::Indexes,
+                &IndexKey::serialize_field(key_account_id, key_collection, key_field),
+                Direction::Backward,
+            )?
+        };
+
+        let mut rank = 0;
+        let mut ranked = 0;
+        let mut last_value = Vec::new();
+        while ranked < num_docs && cursor.advance()? {
+            let key = cursor.key();
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let document_id = IndexKey::deserialize_document_id(key).ok_or_else(|| {
+                StoreError::DataCorruption("Failed to deserialize index key.".to_string())
+            })?;
+            let value = &key[..key.len() - std::mem::size_of::<DocumentId>()];
+            let is_new_value = is_last || rank == 0 || value != last_value;
+            if set_rank(document_id, rank + u32::from(is_new_value)) {
+                if is_new_value {
+                    rank += 1;
+                    last_value = value.to_vec();
+                }
+                ranked += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'x> Ord for Head<'x> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.key.cmp(self.key).then(other.pos.cmp(&self.pos))
+    }
+}
+
+impl<'x> PartialOrd for Head<'x> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'x> PartialEq for Head<'x> {
+    fn eq(&self, other: &Self) -> bool {
+        self.pos == other.pos
+    }
+}
+
+impl<'x> Eq for Head<'x> {}
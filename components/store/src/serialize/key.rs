@@ -30,11 +30,11 @@ use crate::{
         changes::ChangeId,
         raft::{LogIndex, RaftId},
     },
-    AccountId, DocumentId, FieldId,
+    AccountId, ColumnFamily, DocumentId, FieldId,
 };
 
 use super::{
-    leb128::{Leb128Iterator, Leb128Reader, Leb128Vec},
+    leb128::{Leb128Iterator, Leb128Vec},
     DeserializeBigEndian,
 };
 
@@ -58,18 +58,100 @@ pub const TAG_ID: u8 = 0x00;
 pub const TAG_TEXT: u8 = 0x01;
 pub const TAG_STATIC: u8 = 0x02;
 
+pub const ANNOTATION_FIELD: FieldId = u8::MAX - 1;
+
 pub const INTERNAL_KEY_PREFIX: u8 = 0;
 
 pub const FOLLOWER_COMMIT_INDEX_KEY: &[u8; 2] = &[INTERNAL_KEY_PREFIX, 1];
 pub const LEADER_COMMIT_INDEX_KEY: &[u8; 2] = &[INTERNAL_KEY_PREFIX, 2];
+pub const NLP_CONFIG_KEY: &[u8; 2] = &[INTERNAL_KEY_PREFIX, 3];
+pub const KEY_LAYOUT_KEY: &[u8; 2] = &[INTERNAL_KEY_PREFIX, 4];
+pub const FENCING_TOKEN_KEY: &[u8; 2] = &[INTERNAL_KEY_PREFIX, 6];
+pub const LAST_APPLIED_KEY: &[u8; 2] = &[INTERNAL_KEY_PREFIX, 7];
+pub const PRUNED_TERMS_KEY_PREFIX: &[u8; 2] = &[INTERNAL_KEY_PREFIX, 8];
+pub const RENUMBERED_KEY_PREFIX: &[u8; 2] = &[INTERNAL_KEY_PREFIX, 9];
+pub const LAYOUT_UPGRADE_KEY: &[u8; 2] = &[INTERNAL_KEY_PREFIX, 10];
+pub const LAYOUT_STAGING_KEY_PREFIX: &[u8; 2] = &[INTERNAL_KEY_PREFIX, 11];
+pub const NAMED_KEY_PREFIX: &[u8; 2] = &[INTERNAL_KEY_PREFIX, u8::MAX - 1];
+pub const ID_LEASE_KEY_NAME: &[u8] = b"id-lease";
+pub const EXPIRING_KEY_PREFIX: &[u8; 2] = &[INTERNAL_KEY_PREFIX, u8::MAX - 2];
+pub const EXPIRY_INDEX_PREFIX: &[u8; 2] = &[INTERNAL_KEY_PREFIX, u8::MAX - 3];
 
 pub struct ValueKey {}
 pub struct BitmapKey {}
 pub struct IndexKey {}
 pub struct LogKey {}
 pub struct BlobKey {}
+pub struct AccountKey {}
 
 impl ValueKey {
+    // Named keys live in the internal key space so they never fall within
+    // the key range of an account.
+    pub fn serialize_named(name: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(NAMED_KEY_PREFIX.len() + name.len());
+        bytes.extend_from_slice(NAMED_KEY_PREFIX);
+        bytes.extend_from_slice(name);
+        bytes
+    }
+
+    pub fn serialize_expiring(name: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(EXPIRING_KEY_PREFIX.len() + name.len());
+        bytes.extend_from_slice(EXPIRING_KEY_PREFIX);
+        bytes.extend_from_slice(name);
+        bytes
+    }
+
+    // Expiry index entries sort by timestamp, so that expired keys can be
+    // found without scanning all expiring values.
+    pub fn serialize_expiry(expires_at: u64, name: &[u8]) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(EXPIRY_INDEX_PREFIX.len() + std::mem::size_of::<u64>() + name.len());
+        bytes.extend_from_slice(EXPIRY_INDEX_PREFIX);
+        bytes.extend_from_slice(&expires_at.to_be_bytes());
+        bytes.extend_from_slice(name);
+        bytes
+    }
+
+    // Id leases are named keys, they are bookkeeping of the local node and
+    // are neither replicated nor compared between nodes.
+    pub fn serialize_id_lease(account: AccountId, collection: Collection) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            NAMED_KEY_PREFIX.len()
+                + ID_LEASE_KEY_NAME.len()
+                + std::mem::size_of::<AccountId>()
+                + std::mem::size_of::<Collection>(),
+        );
+        bytes.extend_from_slice(NAMED_KEY_PREFIX);
+        bytes.extend_from_slice(ID_LEASE_KEY_NAME);
+        bytes.extend_from_slice(&account.to_be_bytes());
+        bytes.push(collection.into());
+        bytes
+    }
+
+    pub fn serialize_pruned_terms(account: AccountId, collection: Collection) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            PRUNED_TERMS_KEY_PREFIX.len()
+                + std::mem::size_of::<AccountId>()
+                + std::mem::size_of::<Collection>(),
+        );
+        bytes.extend_from_slice(PRUNED_TERMS_KEY_PREFIX);
+        bytes.extend_from_slice(&account.to_be_bytes());
+        bytes.push(collection.into());
+        bytes
+    }
+
+    pub fn serialize_renumbered(account: AccountId, collection: Collection) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            RENUMBERED_KEY_PREFIX.len()
+                + std::mem::size_of::<AccountId>()
+                + std::mem::size_of::<Collection>(),
+        );
+        bytes.extend_from_slice(RENUMBERED_KEY_PREFIX);
+        bytes.extend_from_slice(&account.to_be_bytes());
+        bytes.push(collection.into());
+        bytes
+    }
+
     pub fn serialize_collection(account: AccountId, collection: Collection) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(
             std::mem::size_of::<AccountId>() + std::mem::size_of::<Collection>(),
@@ -109,6 +191,17 @@ impl ValueKey {
         bytes
     }
 
+    pub fn serialize_annotation(
+        account: AccountId,
+        collection: Collection,
+        document: DocumentId,
+        principal: AccountId,
+    ) -> Vec<u8> {
+        let mut bytes = ValueKey::serialize_value(account, collection, document, ANNOTATION_FIELD);
+        bytes.push_leb128(principal);
+        bytes
+    }
+
     pub fn serialize_acl(
         grant_account: AccountId,
         to_account: AccountId,
@@ -223,11 +316,11 @@ impl BitmapKey {
         is_exact: bool,
     ) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(ACCOUNT_KEY_LEN + term.len() + 3);
-        bytes.extend_from_slice(term.as_bytes());
-        bytes.push(field);
+        bytes.extend_from_slice(&account.to_be_bytes());
         bytes.push(collection.into());
         bytes.push(BM_TERM | if is_exact { TERM_EXACT } else { TERM_STEMMED });
-        bytes.push_leb128(account);
+        bytes.push(field);
+        bytes.extend_from_slice(term.as_bytes());
         bytes
     }
 
@@ -239,37 +332,34 @@ impl BitmapKey {
         term: &str,
         is_exact: bool,
     ) -> Vec<u8> {
-        let (mut bytes, bm_type) = match term.len() as u32 {
+        let mut bytes = Vec::with_capacity(ACCOUNT_KEY_LEN + (std::mem::size_of::<u64>() * 2) + 8);
+        bytes.extend_from_slice(&account.to_be_bytes());
+        bytes.push(collection.into());
+        let bm_type_pos = bytes.len();
+        bytes.push(0);
+        bytes.push(field);
+
+        let bm_type = match term.len() as u32 {
             1..=9 => {
-                let mut bytes =
-                    Vec::with_capacity(ACCOUNT_KEY_LEN + std::mem::size_of::<u64>() + 3);
                 bytes.extend_from_slice(term.as_bytes());
-                (bytes, TERM_STRING)
+                TERM_STRING
             }
             10..=20 => {
-                let mut bytes =
-                    Vec::with_capacity(ACCOUNT_KEY_LEN + std::mem::size_of::<u64>() + 3);
                 bytes.extend_from_slice(&xxhash_rust::xxh3::xxh3_64(term.as_bytes()).to_be_bytes());
                 bytes.push_leb128(term.len());
-                (bytes, TERM_HASH)
+                TERM_HASH
             }
             21..=u32::MAX => {
-                let mut bytes =
-                    Vec::with_capacity(ACCOUNT_KEY_LEN + (std::mem::size_of::<u64>() * 2) + 3);
                 bytes.extend_from_slice(&xxhash_rust::xxh3::xxh3_64(term.as_bytes()).to_be_bytes());
                 bytes.extend_from_slice(&naive_cityhash::cityhash64(term.as_bytes()).to_be_bytes());
                 bytes.push_leb128(term.len());
-                (bytes, TERM_HASH)
+                TERM_HASH
             }
             0 => {
                 panic!("Term cannot be empty");
             }
         };
-
-        bytes.push(field);
-        bytes.push(collection.into());
-        bytes.push(BM_TERM | bm_type | if is_exact { TERM_EXACT } else { TERM_STEMMED });
-        bytes.push_leb128(account);
+        bytes[bm_type_pos] = BM_TERM | bm_type | if is_exact { TERM_EXACT } else { TERM_STEMMED };
         bytes
     }
 
@@ -279,51 +369,43 @@ impl BitmapKey {
         field: FieldId,
         tag: &Tag,
     ) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(ACCOUNT_KEY_LEN + tag.len() + 1);
-        let bm_type = match tag {
-            Tag::Static(id) => {
-                bytes.push(*id);
-                TAG_STATIC
-            }
-            Tag::Id(id) => {
-                bytes.push_leb128(*id);
-                TAG_ID
-            }
-            Tag::Text(text) => {
-                bytes.extend_from_slice(text.as_bytes());
-                TAG_TEXT
-            }
-            Tag::Default => {
-                bytes.push(0);
-                TAG_STATIC
-            }
-        };
-        bytes.push(field);
+        let mut bytes = Vec::with_capacity(ACCOUNT_KEY_LEN + tag.len() + 3);
+        bytes.extend_from_slice(&account.to_be_bytes());
         bytes.push(collection.into());
-        bytes.push(BM_TAG | bm_type);
-        bytes.push_leb128(account);
+        bytes.push(
+            BM_TAG
+                | match tag {
+                    Tag::Static(_) | Tag::Default => TAG_STATIC,
+                    Tag::Id(_) => TAG_ID,
+                    Tag::Text(_) => TAG_TEXT,
+                },
+        );
+        bytes.push(field);
+        match tag {
+            Tag::Static(id) => bytes.push(*id),
+            Tag::Id(id) => bytes.push_leb128(*id),
+            Tag::Text(text) => bytes.extend_from_slice(text.as_bytes()),
+            Tag::Default => bytes.push(0),
+        }
         bytes
     }
 
     pub fn serialize_document_ids(account: AccountId, collection: Collection) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(ACCOUNT_KEY_LEN + 1);
+        let mut bytes = Vec::with_capacity(COLLECTION_PREFIX_LEN + 1);
+        bytes.extend_from_slice(&account.to_be_bytes());
         bytes.push(collection.into());
         bytes.push(BM_DOCUMENT_IDS);
-        bytes.push_leb128(account);
         bytes
     }
 
     pub fn deserialize_account_id(bytes: &[u8]) -> Option<AccountId> {
-        bytes.get(..bytes.len() - 1).and_then(|range| {
-            range
-                .iter()
-                .rposition(|&byte| byte & 0x80 == 0)
-                .and_then(|start_pos| {
-                    bytes
-                        .get(start_pos + 1..)
-                        .and_then(|range| range.read_leb128().map(|r| r.0))
-                })
-        })
+        bytes.deserialize_be_u32(0)
+    }
+
+    pub fn is_term(bytes: &[u8]) -> bool {
+        bytes
+            .get(COLLECTION_PREFIX_LEN)
+            .map_or(false, |bm_type| bm_type & 0xF0 == BM_TERM)
     }
 }
 
@@ -387,12 +469,43 @@ impl IndexKey {
     }
 }
 
+impl AccountKey {
+    /// Returns the key ranges holding the values, indexes and bitmaps of an account.
+    /// Values are prefixed by the LEB128 encoded account id, which is prefix-free,
+    /// while indexes and bitmaps are prefixed by the big-endian account id.
+    pub fn ranges(account: AccountId) -> Vec<(ColumnFamily, Vec<u8>, Vec<u8>)> {
+        let mut ranges = Vec::with_capacity(3);
+
+        let mut from = Vec::with_capacity(std::mem::size_of::<AccountId>() + 1);
+        from.push_leb128(account);
+        let mut to = from.clone();
+        *to.last_mut().unwrap() += 1;
+        ranges.push((ColumnFamily::Values, from, to));
+
+        let from = account.to_be_bytes().to_vec();
+        let to = if let Some(next_account) = account.checked_add(1) {
+            next_account.to_be_bytes().to_vec()
+        } else {
+            // No collection id is u8::MAX, so this bounds every key of the last account
+            let mut to = from.clone();
+            to.push(u8::MAX);
+            to
+        };
+        for cf in [ColumnFamily::Indexes, ColumnFamily::Bitmaps] {
+            ranges.push((cf, from.clone(), to.clone()));
+        }
+
+        ranges
+    }
+}
+
 impl LogKey {
     pub const CHANGE_KEY_PREFIX: u8 = 0;
     pub const RAFT_KEY_PREFIX: u8 = 1;
     pub const ROLLBACK_KEY_PREFIX: u8 = 2;
     pub const PENDING_UPDATES_KEY_PREFIX: u8 = 3;
     pub const TOMBSTONE_KEY_PREFIX: u8 = 3;
+    pub const STAGED_CHANGE_KEY_PREFIX: u8 = 4;
 
     pub const CHANGE_KEY_LEN: usize = std::mem::size_of::<AccountId>()
         + std::mem::size_of::<Collection>()
@@ -444,6 +557,18 @@ impl LogKey {
         bytes
     }
 
+    // Changelog entries received from the leader are staged under their own prefix
+    // and only published to the changelog once the raft entry is committed.
+    pub fn serialize_staged_change(
+        account: AccountId,
+        collection: Collection,
+        change_id: ChangeId,
+    ) -> Vec<u8> {
+        let mut bytes = LogKey::serialize_change(account, collection, change_id);
+        bytes[0] = LogKey::STAGED_CHANGE_KEY_PREFIX;
+        bytes
+    }
+
     pub fn serialize_rollback(account: AccountId, collection: Collection) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(LogKey::ROLLBACK_KEY_LEN);
         bytes.push(LogKey::ROLLBACK_KEY_PREFIX);
@@ -477,10 +602,10 @@ impl LogKey {
 mod tests {
     use crate::{
         core::{collection::Collection, tag::Tag},
-        AccountId,
+        AccountId, ColumnFamily,
     };
 
-    use super::BitmapKey;
+    use super::{AccountKey, BitmapKey, IndexKey, LogKey, ValueKey};
 
     #[test]
     fn bitmap_account_id() {
@@ -509,4 +634,71 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn account_key_ranges() {
+        for account_id in [1, 127, 128, 300, 16384, AccountId::MAX - 1, AccountId::MAX] {
+            let keys = [
+                (
+                    ColumnFamily::Values,
+                    ValueKey::serialize_value(account_id, Collection::Mail, 5, 2),
+                ),
+                (
+                    ColumnFamily::Values,
+                    ValueKey::serialize_acl(account_id, 3, Collection::Mailbox, 1),
+                ),
+                (
+                    ColumnFamily::Indexes,
+                    IndexKey::serialize(account_id, Collection::Mail, 5, 2, b"key"),
+                ),
+                (
+                    ColumnFamily::Bitmaps,
+                    BitmapKey::serialize_term(account_id, Collection::Mail, 10, "hello", true),
+                ),
+                (
+                    ColumnFamily::Bitmaps,
+                    BitmapKey::serialize_document_ids(account_id, Collection::Mailbox),
+                ),
+            ];
+
+            for other_account_id in [
+                account_id.checked_sub(1),
+                Some(account_id),
+                account_id.checked_add(1),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                let ranges = AccountKey::ranges(other_account_id);
+                for (cf, key) in &keys {
+                    let (_, from, to) = ranges.iter().find(|(r_cf, _, _)| r_cf == cf).unwrap();
+                    assert_eq!(
+                        key >= from && key < to,
+                        other_account_id == account_id,
+                        "{:?} {:?} {}",
+                        cf,
+                        key,
+                        other_account_id
+                    );
+                }
+            }
+        }
+
+        // Named keys never fall within the range of an account
+        let named_key = ValueKey::serialize_named(b"peer_id");
+        for account_id in [1, b'p' as AccountId, 300] {
+            let (_, from, to) = &AccountKey::ranges(account_id)[0];
+            assert!(!(&named_key >= from && &named_key < to));
+        }
+    }
+
+    #[test]
+    fn staged_change_key() {
+        let change_key = LogKey::serialize_change(10, Collection::Mailbox, 1234);
+        let staged_key = LogKey::serialize_staged_change(10, Collection::Mailbox, 1234);
+
+        assert_eq!(staged_key[0], LogKey::STAGED_CHANGE_KEY_PREFIX);
+        assert_eq!(staged_key[1..], change_key[1..]);
+        assert_eq!(LogKey::deserialize_change_id(&staged_key), Some(1234));
+    }
 }
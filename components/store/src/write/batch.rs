@@ -25,6 +25,7 @@ use ahash::AHashSet;
 
 use crate::core::document::Document;
 use crate::core::vec_map::VecMap;
+use crate::log::raft::{AppliedId, TermId};
 use crate::serialize::leb128::Leb128Vec;
 use crate::{AccountId, Collection, DocumentId, JMAPId};
 
@@ -40,6 +41,8 @@ pub struct WriteBatch {
     pub changes: VecMap<Collection, Change>,
     pub documents: Vec<WriteAction>,
     pub linked_batch: Vec<WriteBatch>,
+    pub fencing_token: Option<TermId>,
+    pub applied_id: Option<AppliedId>,
 }
 
 #[derive(Default)]
@@ -57,6 +60,8 @@ impl WriteBatch {
             changes: VecMap::new(),
             documents: Vec::new(),
             linked_batch: Vec::new(),
+            fencing_token: None,
+            applied_id: None,
         }
     }
 
@@ -66,6 +71,8 @@ impl WriteBatch {
             changes: VecMap::new(),
             documents: vec![WriteAction::Insert(document)],
             linked_batch: Vec::new(),
+            fencing_token: None,
+            applied_id: None,
         }
     }
 
@@ -75,6 +82,8 @@ impl WriteBatch {
             changes: VecMap::new(),
             documents: vec![WriteAction::Delete(Document::new(collection, document_id))],
             linked_batch: Vec::new(),
+            fencing_token: None,
+            applied_id: None,
         }
     }
 
@@ -139,12 +148,26 @@ impl WriteBatch {
             changes: std::mem::take(&mut self.changes),
             documents: std::mem::take(&mut self.documents),
             linked_batch: std::mem::take(&mut self.linked_batch),
+            fencing_token: self.fencing_token,
+            applied_id: self.applied_id.take(),
         }
     }
 
     pub fn add_linked_batch(&mut self, batch: WriteBatch) {
         self.linked_batch.push(batch);
     }
+
+    /// Tags the batch with the Raft term it was produced under, the store
+    /// refuses to write it once a newer term has been recorded.
+    pub fn set_fencing_token(&mut self, term: TermId) {
+        self.fencing_token = term.into();
+    }
+
+    /// Marks the batch as the application of the follower updates at
+    /// `applied_id`, which are then recorded as applied in the same write.
+    pub fn set_applied_id(&mut self, applied_id: AppliedId) {
+        self.applied_id = applied_id.into();
+    }
 }
 
 impl From<Change> for Vec<u8> {
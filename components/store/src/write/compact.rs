@@ -0,0 +1,96 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use roaring::RoaringBitmap;
+
+use crate::{serialize::key::AccountKey, ColumnFamily, JMAPStore, Store};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct CompactReport {
+    pub accounts: u64,
+    #[serde(rename(serialize = "bytesBefore"))]
+    pub bytes_before: u64,
+    #[serde(rename(serialize = "bytesAfter"))]
+    pub bytes_after: u64,
+    #[serde(rename(serialize = "reclaimedBytes"))]
+    pub reclaimed_bytes: u64,
+}
+
+impl<T> JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Compacts the key ranges of the given accounts, so the space held by
+    /// the keys of deleted accounts is reclaimed without waiting for the
+    /// backend to compact them on its own. The progress callback receives the
+    /// number of accounts compacted so far and the total.
+    pub fn compact_accounts(
+        &self,
+        account_ids: &RoaringBitmap,
+        mut progress: impl FnMut(u64, u64),
+    ) -> crate::Result<CompactReport> {
+        let total = account_ids.len();
+        self.measure_compaction(total, || {
+            for (done, account_id) in account_ids.iter().enumerate() {
+                for (cf, from, to) in AccountKey::ranges(account_id) {
+                    self.db.compact_range(cf, &from, &to)?;
+                }
+                progress(done as u64 + 1, total);
+            }
+            Ok(())
+        })
+    }
+
+    /// Compacts whole column families, reporting the number compacted so far
+    /// and the total to the progress callback.
+    pub fn compact_column_families(
+        &self,
+        cfs: &[ColumnFamily],
+        mut progress: impl FnMut(u64, u64),
+    ) -> crate::Result<CompactReport> {
+        self.measure_compaction(0, || {
+            for (done, cf) in cfs.iter().enumerate() {
+                self.db.compact(*cf)?;
+                progress(done as u64 + 1, cfs.len() as u64);
+            }
+            Ok(())
+        })
+    }
+
+    fn measure_compaction(
+        &self,
+        accounts: u64,
+        compact: impl FnOnce() -> crate::Result<()>,
+    ) -> crate::Result<CompactReport> {
+        let bytes_before = self.db.disk_usage()?;
+        compact()?;
+        let bytes_after = self.db.disk_usage()?;
+
+        Ok(CompactReport {
+            accounts,
+            bytes_before,
+            bytes_after,
+            reclaimed_bytes: bytes_before.saturating_sub(bytes_after),
+        })
+    }
+}
@@ -24,10 +24,10 @@
 use roaring::RoaringBitmap;
 
 use crate::blob::BLOB_HASH_LEN;
-use crate::serialize::key::BitmapKey;
+use crate::core::collection::Collection;
+use crate::serialize::key::{AccountKey, ValueKey, INTERNAL_KEY_PREFIX};
 use crate::serialize::leb128::{Leb128Iterator, Leb128Reader};
-use crate::serialize::DeserializeBigEndian;
-use crate::{ColumnFamily, Direction, JMAPStore, Store};
+use crate::{AccountId, ColumnFamily, Direction, JMAPStore, Store};
 
 use super::operation::WriteOperation;
 
@@ -40,44 +40,68 @@ where
     pub fn delete_accounts(&self, account_ids: &RoaringBitmap) -> crate::Result<()> {
         let mut batch = Vec::with_capacity(64);
 
-        // Delete values
-        for (key, _) in self
-            .db
-            .iterator(ColumnFamily::Values, &[], Direction::Forward)?
-        {
-            let mut bytes = key.iter();
-            if let Some(account_id) = bytes.next_leb128() {
-                let do_delete = if account_ids.contains(account_id) {
-                    true
-                } else if matches!(bytes.next(), Some(collection) if *collection == u8::MAX) {
-                    // Shared account
-                    matches!(bytes.next_leb128(), Some(shared_account_id) if account_ids.contains(shared_account_id))
-                } else {
-                    false
-                };
-
-                if do_delete {
-                    batch.push(WriteOperation::Delete {
-                        cf: ColumnFamily::Values,
-                        key: key.to_vec(),
-                    });
-                    if batch.len() == DELETE_BATCH_SIZE {
-                        self.db.write(batch)?;
-                        batch = Vec::with_capacity(64);
-                    }
+        // Values, indexes and bitmaps of an account share a common key prefix.
+        // Change logs are kept as they are needed by Raft followers.
+        for account_id in account_ids {
+            for (cf, from, to) in AccountKey::ranges(account_id) {
+                // The values of account 0 share their prefix with internal keys
+                if account_id == 0 && cf == ColumnFamily::Values {
+                    continue;
                 }
+                batch.push(WriteOperation::delete_range(cf, from, to));
             }
+
+            // Document id leases, pruned terms and renumbering change ids are internal
+            // keys indexed by account
+            batch.push(WriteOperation::delete_range(
+                ColumnFamily::Values,
+                ValueKey::serialize_id_lease(account_id, Collection::Principal),
+                ValueKey::serialize_id_lease(account_id, Collection::None),
+            ));
+            batch.push(WriteOperation::delete_range(
+                ColumnFamily::Values,
+                ValueKey::serialize_pruned_terms(account_id, Collection::Principal),
+                ValueKey::serialize_pruned_terms(account_id, Collection::None),
+            ));
+            batch.push(WriteOperation::delete_range(
+                ColumnFamily::Values,
+                ValueKey::serialize_renumbered(account_id, Collection::Principal),
+                ValueKey::serialize_renumbered(account_id, Collection::None),
+            ));
+        }
+        if !batch.is_empty() {
+            self.write_operations(batch)?;
+            batch = Vec::with_capacity(64);
         }
 
-        // Delete indexes
-        for (key, _) in self
+        // Delete ACLs granted to the removed accounts, skipping from one account prefix
+        // to the next one.
+        let mut from_key = vec![INTERNAL_KEY_PREFIX + 1];
+        while let Some((key, _)) = self
             .db
-            .iterator(ColumnFamily::Indexes, &[], Direction::Forward)?
+            .iterator(ColumnFamily::Values, &from_key, Direction::Forward)?
+            .next()
         {
-            if let Some(account_id) = (&key[..]).deserialize_be_u32(0) {
-                if account_ids.contains(account_id) {
+            let account_id = if let Some((account_id, _)) = key.read_leb128::<AccountId>() {
+                account_id
+            } else {
+                break;
+            };
+            let acl_prefix =
+                ValueKey::serialize_acl_prefix(account_id, AccountId::MAX, Collection::None);
+
+            for (key, _) in
+                self.db
+                    .iterator(ColumnFamily::Values, &acl_prefix, Direction::Forward)?
+            {
+                if !key.starts_with(&acl_prefix) {
+                    break;
+                }
+                if matches!(key[acl_prefix.len()..].iter().next_leb128::<AccountId>(),
+                            Some(to_account_id) if account_ids.contains(to_account_id))
+                {
                     batch.push(WriteOperation::Delete {
-                        cf: ColumnFamily::Indexes,
+                        cf: ColumnFamily::Values,
                         key: key.to_vec(),
                     });
                     if batch.len() == DELETE_BATCH_SIZE {
@@ -86,6 +110,8 @@ where
                     }
                 }
             }
+
+            from_key = AccountKey::ranges(account_id).swap_remove(0).2;
         }
 
         // Delete linked blobs
@@ -109,24 +135,6 @@ where
             }
         }
 
-        // Delete bitmaps
-        for (key, _) in self
-            .db
-            .iterator(ColumnFamily::Bitmaps, &[], Direction::Forward)?
-        {
-            if matches!(BitmapKey::deserialize_account_id(&key), Some(account_id) if account_ids.contains(account_id))
-            {
-                batch.push(WriteOperation::Delete {
-                    cf: ColumnFamily::Bitmaps,
-                    key: key.to_vec(),
-                });
-                if batch.len() == DELETE_BATCH_SIZE {
-                    self.db.write(batch)?;
-                    batch = Vec::with_capacity(64);
-                }
-            }
-        }
-
         if !batch.is_empty() {
             self.db.write(batch)?;
         }
@@ -0,0 +1,77 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::thread::{self, ThreadId};
+
+use ahash::AHashMap;
+use parking_lot::{RwLock, RwLockReadGuard};
+
+use crate::{core::error::StoreError, AccountId, JMAPStore, Store};
+
+/// Blocks the writes to an account made by other threads through
+/// `JMAPStore::write` until dropped.
+pub struct WriteGate<'x> {
+    gates: &'x RwLock<AHashMap<AccountId, ThreadId>>,
+    account_id: AccountId,
+}
+
+impl<T> JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Closes the write gate of an account, waiting for the writes in
+    /// progress to finish. Writes from the calling thread are still allowed.
+    pub fn block_writes(&self, account_id: AccountId) -> crate::Result<WriteGate<'_>> {
+        let mut gates = self.write_gates.write();
+        if gates.contains_key(&account_id) {
+            return Err(StoreError::WritesBlocked(account_id));
+        }
+        gates.insert(account_id, thread::current().id());
+
+        Ok(WriteGate {
+            gates: &self.write_gates,
+            account_id,
+        })
+    }
+
+    /// Returns a guard to be held while a write to the account is in
+    /// progress, or an error if its write gate is closed.
+    pub(crate) fn enter_write_gate(
+        &self,
+        account_id: AccountId,
+    ) -> crate::Result<RwLockReadGuard<'_, AHashMap<AccountId, ThreadId>>> {
+        let gates = self.write_gates.read_recursive();
+        match gates.get(&account_id) {
+            Some(owner) if *owner != thread::current().id() => {
+                Err(StoreError::WritesBlocked(account_id))
+            }
+            _ => Ok(gates),
+        }
+    }
+}
+
+impl Drop for WriteGate<'_> {
+    fn drop(&mut self) {
+        self.gates.write().remove(&self.account_id);
+    }
+}
@@ -0,0 +1,199 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::Duration;
+
+use ahash::AHashMap;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+
+use super::operation::WriteOperation;
+
+pub struct GroupCommit {
+    queue: Mutex<CommitQueue>,
+    committed: Condvar,
+    window: Duration,
+    max_batches: usize,
+}
+
+#[derive(Default)]
+struct CommitQueue {
+    pending: Vec<(u64, Vec<WriteOperation>)>,
+    results: AHashMap<u64, crate::Result<()>>,
+    next_id: u64,
+    is_committing: bool,
+}
+
+impl GroupCommit {
+    pub fn new(window: Duration, max_batches: usize) -> Self {
+        GroupCommit {
+            queue: Mutex::new(CommitQueue::default()),
+            committed: Condvar::new(),
+            window,
+            max_batches: std::cmp::max(max_batches, 1),
+        }
+    }
+
+    /// Queues a prepared batch and blocks until it has been committed, either by
+    /// this thread or by another writer that coalesced it into its own transaction.
+    /// The change log entries are serialized by `log_fnc` while holding the queue
+    /// lock, which guarantees that batches are committed in change id order.
+    pub fn write<R>(
+        &self,
+        mut ops: Vec<WriteOperation>,
+        log_fnc: impl FnOnce(&mut Vec<WriteOperation>) -> crate::Result<R>,
+        write_fnc: impl Fn(Vec<WriteOperation>) -> crate::Result<()>,
+    ) -> crate::Result<R> {
+        let mut queue = self.queue.lock();
+        let changes = log_fnc(&mut ops)?;
+        let batch_id = queue.next_id;
+        queue.next_id = queue.next_id.wrapping_add(1);
+        queue.pending.push((batch_id, ops));
+
+        loop {
+            if let Some(result) = queue.results.remove(&batch_id) {
+                return result.map(|_| changes);
+            } else if !queue.is_committing {
+                queue.is_committing = true;
+                self.commit_group(&mut queue, &write_fnc);
+                queue.is_committing = false;
+                self.committed.notify_all();
+            } else {
+                self.committed.wait(&mut queue);
+            }
+        }
+    }
+
+    fn commit_group(
+        &self,
+        queue: &mut MutexGuard<'_, CommitQueue>,
+        write_fnc: &impl Fn(Vec<WriteOperation>) -> crate::Result<()>,
+    ) {
+        // Give other writers a chance to join this group
+        if !self.window.is_zero() && queue.pending.len() < self.max_batches {
+            MutexGuard::unlocked(queue, || std::thread::sleep(self.window));
+        }
+
+        let group_len = std::cmp::min(queue.pending.len(), self.max_batches);
+        let group = queue.pending.drain(..group_len).collect::<Vec<_>>();
+
+        let results = MutexGuard::unlocked(queue, || {
+            if group.len() == 1 {
+                let (batch_id, ops) = group.into_iter().next().unwrap();
+                return vec![(batch_id, write_fnc(ops))];
+            }
+
+            let ops = group
+                .iter()
+                .flat_map(|(_, ops)| ops.iter().cloned())
+                .collect::<Vec<_>>();
+            match write_fnc(ops) {
+                Ok(()) => group
+                    .into_iter()
+                    .map(|(batch_id, _)| (batch_id, Ok(())))
+                    .collect(),
+                Err(_) => {
+                    // Commit batches one by one so that the failure is only
+                    // reported to the batch that caused it.
+                    group
+                        .into_iter()
+                        .map(|(batch_id, ops)| (batch_id, write_fnc(ops)))
+                        .collect()
+                }
+            }
+        });
+
+        queue.results.extend(results);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use parking_lot::Mutex;
+
+    use crate::{write::operation::WriteOperation, ColumnFamily, StoreError};
+
+    use super::GroupCommit;
+
+    #[test]
+    fn group_commit() {
+        let group_commit = Arc::new(GroupCommit::new(Duration::from_millis(5), 64));
+        let change_id = Arc::new(Mutex::new(0u64));
+        let committed = Arc::new(Mutex::new(Vec::new()));
+
+        let mut handles = Vec::new();
+        for thread_num in 0..8u64 {
+            let group_commit = group_commit.clone();
+            let change_id = change_id.clone();
+            let committed = committed.clone();
+
+            handles.push(std::thread::spawn(move || {
+                let mut results = Vec::new();
+                for _ in 0..50 {
+                    results.push(group_commit.write(
+                        Vec::new(),
+                        |ops| {
+                            let mut change_id = change_id.lock();
+                            *change_id += 1;
+                            // Batches from thread 0 are rejected by the backend
+                            ops.push(WriteOperation::set(
+                                ColumnFamily::Logs,
+                                change_id.to_be_bytes().to_vec(),
+                                vec![(thread_num == 0) as u8],
+                            ));
+                            Ok(*change_id)
+                        },
+                        |ops| {
+                            if ops.iter().any(|op| {
+                                matches!(op, WriteOperation::Set { value, .. } if value == &[1])
+                            }) {
+                                return Err(StoreError::InternalError("Rejected".to_string()));
+                            }
+                            let mut committed = committed.lock();
+                            for op in ops {
+                                if let WriteOperation::Set { key, .. } = op {
+                                    committed.push(u64::from_be_bytes(key.try_into().unwrap()));
+                                }
+                            }
+                            Ok(())
+                        },
+                    ));
+                }
+                (thread_num, results)
+            }));
+        }
+
+        for handle in handles {
+            let (thread_num, results) = handle.join().unwrap();
+            for result in results {
+                assert_eq!(result.is_err(), thread_num == 0);
+            }
+        }
+
+        // Change ids must be committed in order
+        let committed = committed.lock();
+        assert_eq!(committed.len(), 7 * 50);
+        assert!(committed.windows(2).all(|w| w[0] < w[1]));
+    }
+}
@@ -27,7 +27,11 @@ use parking_lot::Mutex;
 use roaring::RoaringBitmap;
 
 use crate::{
-    serialize::key::BitmapKey, AccountId, Collection, DocumentId, JMAPStore, Store, StoreError,
+    serialize::{
+        key::{BitmapKey, ValueKey},
+        StoreDeserialize, StoreSerialize,
+    },
+    AccountId, Collection, ColumnFamily, DocumentId, JMAPStore, Store, StoreError,
 };
 
 #[derive(Clone, Hash, PartialEq, Eq)]
@@ -49,6 +53,31 @@ impl IdCacheKey {
 pub struct IdAssigner {
     pub freed_ids: Option<RoaringBitmap>,
     pub next_id: DocumentId,
+    pub reserved_until: DocumentId,
+    pub persisted_until: DocumentId,
+}
+
+/// Persisted reservation of the document ids below `reserved_until`. Ids are
+/// handed out from memory and the lease is renewed ahead of time, so that
+/// neither an assigner rebuilt while other threads still hold uncommitted ids
+/// nor a restart reassigns an id that was handed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdLease {
+    pub reserved_until: DocumentId,
+}
+
+impl StoreSerialize for IdLease {
+    fn serialize(&self) -> Option<Vec<u8>> {
+        Some(self.reserved_until.to_be_bytes().to_vec())
+    }
+}
+
+impl StoreDeserialize for IdLease {
+    fn deserialize(bytes: &[u8]) -> Option<Self> {
+        Some(IdLease {
+            reserved_until: DocumentId::from_be_bytes(bytes.get(..4)?.try_into().ok()?),
+        })
+    }
 }
 
 impl IdAssigner {
@@ -68,7 +97,37 @@ impl IdAssigner {
         } else {
             (0, None)
         };
-        Self { freed_ids, next_id }
+        Self {
+            freed_ids,
+            next_id,
+            reserved_until: 0,
+            persisted_until: 0,
+        }
+    }
+
+    /// Resumes assigning ids after a persisted lease. Holes are not reused,
+    /// as the ids in them might have been handed out to a pending write.
+    pub fn resume(used_ids: Option<RoaringBitmap>, reserved_until: DocumentId) -> Self {
+        Self {
+            freed_ids: None,
+            next_id: std::cmp::max(
+                used_ids.and_then(|ids| ids.max()).map_or(0, |id| id + 1),
+                reserved_until,
+            ),
+            reserved_until,
+            persisted_until: reserved_until,
+        }
+    }
+
+    /// Extends the lease once half of it has been used, returns whether the
+    /// lease has to be written before `document_id` can be used.
+    pub fn renew_lease(&mut self, document_id: DocumentId, lease_size: DocumentId) -> bool {
+        if self.next_id.saturating_add(lease_size / 2) >= self.reserved_until {
+            self.reserved_until = self.next_id.saturating_add(lease_size);
+            true
+        } else {
+            document_id >= self.persisted_until
+        }
     }
 
     pub fn assign_document_id(&mut self) -> DocumentId {
@@ -98,9 +157,26 @@ where
     ) -> crate::Result<Arc<Mutex<IdAssigner>>> {
         self.id_assigner
             .try_get_with::<_, StoreError>(IdCacheKey::new(account_id, collection), || {
-                Ok(Arc::new(Mutex::new(IdAssigner::new(
-                    self.get_document_ids(account_id, collection)?,
-                ))))
+                let used_ids = self.get_document_ids(account_id, collection)?;
+                Ok(Arc::new(Mutex::new(
+                    match self.db.get::<IdLease>(
+                        ColumnFamily::Values,
+                        &ValueKey::serialize_id_lease(account_id, collection),
+                    )? {
+                        // Collections without documents start over from zero
+                        Some(lease) if used_ids.is_some() => {
+                            IdAssigner::resume(used_ids, lease.reserved_until)
+                        }
+                        _ => {
+                            // Freed ids are handed out again, so thread aggregates
+                            // might still refer to the messages that held them
+                            if collection == Collection::Mail {
+                                self.invalidate_thread_previews(account_id);
+                            }
+                            IdAssigner::new(used_ids)
+                        }
+                    },
+                )))
             })
             .map_err(|e| e.as_ref().clone())
     }
@@ -110,10 +186,35 @@ where
         account_id: AccountId,
         collection: Collection,
     ) -> crate::Result<DocumentId> {
-        Ok(self
-            .get_id_assigner(account_id, collection)?
-            .lock()
-            .assign_document_id())
+        let assigner = self.get_id_assigner(account_id, collection)?;
+        let (document_id, renew_lease) = {
+            let mut assigner = assigner.lock();
+            let document_id = assigner.assign_document_id();
+            (
+                document_id,
+                assigner.renew_lease(document_id, self.id_lease_size),
+            )
+        };
+
+        if renew_lease {
+            // Leases are written outside the assigner lock, threads assigned ids
+            // covered by the persisted lease do not wait for the write.
+            let _lock = self.id_lease_lock.lock_hash((account_id, collection));
+            let (reserved_until, persisted_until) = {
+                let assigner = assigner.lock();
+                (assigner.reserved_until, assigner.persisted_until)
+            };
+            if reserved_until > persisted_until {
+                self.db.set(
+                    ColumnFamily::Values,
+                    &ValueKey::serialize_id_lease(account_id, collection),
+                    &IdLease { reserved_until }.serialize().unwrap(),
+                )?;
+                assigner.lock().persisted_until = reserved_until;
+            }
+        }
+
+        Ok(document_id)
     }
 
     pub fn get_document_ids(
@@ -123,13 +224,32 @@ where
     ) -> crate::Result<Option<RoaringBitmap>> {
         self.get_bitmap(&BitmapKey::serialize_document_ids(account_id, collection))
     }
+
+    pub fn get_multi_document_ids(
+        &self,
+        account_ids: &[AccountId],
+        collection: Collection,
+    ) -> crate::Result<Vec<Option<RoaringBitmap>>> {
+        Ok(self
+            .get_cached_bitmaps(
+                account_ids
+                    .iter()
+                    .map(|account_id| BitmapKey::serialize_document_ids(*account_id, collection))
+                    .collect(),
+            )?
+            .into_iter()
+            .map(|bm| bm.filter(|bm| !bm.is_empty()))
+            .collect())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use roaring::RoaringBitmap;
 
-    use super::IdAssigner;
+    use crate::serialize::{StoreDeserialize, StoreSerialize};
+
+    use super::{IdAssigner, IdLease};
 
     #[test]
     fn id_assigner() {
@@ -149,4 +269,66 @@ mod tests {
         assert_eq!(assigner.assign_document_id(), 7);
         assert_eq!(assigner.assign_document_id(), 8);
     }
+
+    #[test]
+    fn id_assigner_resume() {
+        // Leased ids are never reassigned, not even after a restart
+        let mut assigner = IdAssigner::resume(
+            RoaringBitmap::from_sorted_iter([0, 2, 4]).unwrap().into(),
+            10,
+        );
+        assert_eq!(assigner.assign_document_id(), 10);
+        assert!(assigner.renew_lease(10, 64));
+        assert_eq!(assigner.reserved_until, 75);
+
+        let assigner =
+            IdAssigner::resume(RoaringBitmap::from_sorted_iter([0, 20]).unwrap().into(), 10);
+        assert_eq!(assigner.next_id, 21);
+
+        // Holes are reused by assigners built without a lease
+        let mut assigner = IdAssigner::new(RoaringBitmap::from_sorted_iter([1]).unwrap().into());
+        assert_eq!(assigner.assign_document_id(), 0);
+        assert!(assigner.renew_lease(0, 64));
+        assert_eq!(assigner.reserved_until, 66);
+        assigner.persisted_until = 66;
+        assert_eq!(assigner.assign_document_id(), 2);
+        assert!(!assigner.renew_lease(2, 64));
+    }
+
+    #[test]
+    fn id_assigner_renew_lease() {
+        let mut assigner = IdAssigner::new(None);
+        assert_eq!(assigner.assign_document_id(), 0);
+        assert!(assigner.renew_lease(0, 16));
+        assert_eq!(assigner.reserved_until, 17);
+
+        // Until the lease is written, only ids past the persisted one wait for it
+        assigner.persisted_until = 4;
+        for document_id in 1..4 {
+            assert_eq!(assigner.assign_document_id(), document_id);
+            assert!(!assigner.renew_lease(document_id, 16));
+        }
+        assert_eq!(assigner.assign_document_id(), 4);
+        assert!(assigner.renew_lease(4, 16));
+        assert_eq!(assigner.reserved_until, 17);
+
+        // The lease is extended once half of it has been used
+        assigner.persisted_until = 17;
+        for document_id in 5..8 {
+            assert_eq!(assigner.assign_document_id(), document_id);
+            assert!(!assigner.renew_lease(document_id, 16));
+        }
+        assert_eq!(assigner.assign_document_id(), 8);
+        assert!(assigner.renew_lease(8, 16));
+        assert_eq!(assigner.reserved_until, 25);
+    }
+
+    #[test]
+    fn id_lease_serialize() {
+        let lease = IdLease { reserved_until: 64 };
+        assert_eq!(
+            IdLease::deserialize(&lease.serialize().unwrap()).unwrap(),
+            lease
+        );
+    }
 }
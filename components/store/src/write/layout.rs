@@ -0,0 +1,286 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use tracing::info;
+
+use crate::{
+    serialize::{
+        key::{
+            ValueKey, BM_DOCUMENT_IDS, KEY_LAYOUT_KEY, LAYOUT_STAGING_KEY_PREFIX,
+            LAYOUT_UPGRADE_KEY,
+        },
+        leb128::Leb128Reader,
+        StoreSerialize,
+    },
+    AccountId, ColumnFamily, Direction, Integer, JMAPStore, Store,
+};
+
+use super::operation::WriteOperation;
+
+pub const KEY_LAYOUT_VERSION: Integer = 1;
+
+// Named keys that were stored by the server in the key space of accounts
+// before layout version 1.
+const LEGACY_NAMED_KEYS: &[&[u8]] = &[
+    b"peer_id",
+    b"shard_id",
+    b"peer_list",
+    b"warmup:manifest",
+    b"migration:",
+];
+
+const UPGRADE_BATCH_SIZE: usize = 500;
+
+// Upgrade stages, recorded with every batch written.
+const STAGE_STAGING: Integer = 1;
+const STAGE_STAGED: Integer = 2;
+
+impl<T> JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Rewrites keys stored using a previous layout so that all the data belonging
+    /// to an account shares its key prefix.
+    ///
+    /// Legacy and upgraded bitmap keys cannot be told apart, so bitmaps are first
+    /// moved to a staging area in the values column family and, once no legacy keys
+    /// are left, moved back under their new key. Every batch deletes the keys it
+    /// has moved and records the current stage, which allows an interrupted upgrade
+    /// to resume from where it left off.
+    pub fn upgrade_key_layout(&self) -> crate::Result<()> {
+        if self
+            .db
+            .get::<Integer>(ColumnFamily::Values, KEY_LAYOUT_KEY)?
+            .unwrap_or(0)
+            >= KEY_LAYOUT_VERSION
+        {
+            return Ok(());
+        }
+
+        let mut total_keys = 0;
+
+        if self
+            .db
+            .get::<Integer>(ColumnFamily::Values, LAYOUT_UPGRADE_KEY)?
+            .unwrap_or(0)
+            < STAGE_STAGED
+        {
+            // Bitmaps used to be keyed by term, with the account id at the end of the key
+            let mut batch = Vec::with_capacity(UPGRADE_BATCH_SIZE);
+            for (key, value) in self
+                .db
+                .iterator(ColumnFamily::Bitmaps, &[], Direction::Forward)?
+            {
+                if let Some(new_key) = upgrade_bitmap_key(&key) {
+                    let mut staged_key =
+                        Vec::with_capacity(LAYOUT_STAGING_KEY_PREFIX.len() + new_key.len());
+                    staged_key.extend_from_slice(LAYOUT_STAGING_KEY_PREFIX);
+                    staged_key.extend_from_slice(&new_key);
+
+                    batch.push(WriteOperation::delete(ColumnFamily::Bitmaps, key.to_vec()));
+                    batch.push(WriteOperation::set(
+                        ColumnFamily::Values,
+                        staged_key,
+                        value.to_vec(),
+                    ));
+                    total_keys += 1;
+                    if batch.len() >= UPGRADE_BATCH_SIZE {
+                        self.write_upgrade_batch(batch, STAGE_STAGING)?;
+                        batch = Vec::with_capacity(UPGRADE_BATCH_SIZE);
+                    }
+                }
+            }
+            self.write_upgrade_batch(batch, STAGE_STAGED)?;
+        }
+
+        // Move the staged bitmaps to their new key
+        let mut batch = Vec::with_capacity(UPGRADE_BATCH_SIZE);
+        for (key, value) in self.db.iterator(
+            ColumnFamily::Values,
+            LAYOUT_STAGING_KEY_PREFIX,
+            Direction::Forward,
+        )? {
+            if !key.starts_with(LAYOUT_STAGING_KEY_PREFIX) {
+                break;
+            }
+            batch.push(WriteOperation::delete(ColumnFamily::Values, key.to_vec()));
+            batch.push(WriteOperation::set(
+                ColumnFamily::Bitmaps,
+                key[LAYOUT_STAGING_KEY_PREFIX.len()..].to_vec(),
+                value.to_vec(),
+            ));
+            if batch.len() >= UPGRADE_BATCH_SIZE {
+                self.write_upgrade_batch(batch, STAGE_STAGED)?;
+                batch = Vec::with_capacity(UPGRADE_BATCH_SIZE);
+            }
+        }
+
+        // Move named keys out of the account key space
+        for prefix in LEGACY_NAMED_KEYS {
+            for (key, value) in
+                self.db
+                    .iterator(ColumnFamily::Values, prefix, Direction::Forward)?
+            {
+                if !key.starts_with(prefix) {
+                    break;
+                }
+                batch.push(WriteOperation::delete(ColumnFamily::Values, key.to_vec()));
+                batch.push(WriteOperation::set(
+                    ColumnFamily::Values,
+                    ValueKey::serialize_named(&key),
+                    value.to_vec(),
+                ));
+                total_keys += 1;
+                if batch.len() >= UPGRADE_BATCH_SIZE {
+                    self.write_upgrade_batch(batch, STAGE_STAGED)?;
+                    batch = Vec::with_capacity(UPGRADE_BATCH_SIZE);
+                }
+            }
+        }
+
+        batch.push(WriteOperation::delete(
+            ColumnFamily::Values,
+            LAYOUT_UPGRADE_KEY.to_vec(),
+        ));
+        batch.push(WriteOperation::set(
+            ColumnFamily::Values,
+            KEY_LAYOUT_KEY.to_vec(),
+            KEY_LAYOUT_VERSION.serialize().unwrap(),
+        ));
+        self.write_operations(batch)?;
+
+        if total_keys > 0 {
+            info!(
+                "Upgraded {} keys to key layout version {}.",
+                total_keys, KEY_LAYOUT_VERSION
+            );
+        }
+
+        Ok(())
+    }
+
+    fn write_upgrade_batch(
+        &self,
+        mut batch: Vec<WriteOperation>,
+        stage: Integer,
+    ) -> crate::Result<()> {
+        batch.push(WriteOperation::set(
+            ColumnFamily::Values,
+            LAYOUT_UPGRADE_KEY.to_vec(),
+            stage.serialize().unwrap(),
+        ));
+        self.write_operations(batch)
+    }
+}
+
+// Converts a bitmap key from the legacy layout "<term><field><collection><type><account>"
+// to "<account><collection><type><field><term>".
+fn upgrade_bitmap_key(key: &[u8]) -> Option<Vec<u8>> {
+    // The account id is LEB128 encoded, all its bytes but the last one have the high bit set
+    let account_start = key
+        .get(..key.len().checked_sub(1)?)?
+        .iter()
+        .rposition(|&byte| byte & 0x80 == 0)?
+        + 1;
+    let (account_id, _) = (&key[account_start..]).read_leb128::<AccountId>()?;
+    let (&bm_type, prefix) = key[..account_start].split_last()?;
+
+    let mut new_key = Vec::with_capacity(key.len() + std::mem::size_of::<AccountId>());
+    new_key.extend_from_slice(&account_id.to_be_bytes());
+    if bm_type == BM_DOCUMENT_IDS {
+        if prefix.len() != 1 {
+            return None;
+        }
+        new_key.push(prefix[0]);
+        new_key.push(BM_DOCUMENT_IDS);
+    } else {
+        let (&collection, prefix) = prefix.split_last()?;
+        let (&field, term) = prefix.split_last()?;
+        new_key.push(collection);
+        new_key.push(bm_type);
+        new_key.push(field);
+        new_key.extend_from_slice(term);
+    }
+
+    Some(new_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        core::{collection::Collection, tag::Tag},
+        serialize::{
+            key::{BitmapKey, BM_DOCUMENT_IDS, BM_TAG, BM_TERM, TAG_TEXT, TERM_EXACT},
+            leb128::Leb128Vec,
+        },
+        AccountId,
+    };
+
+    use super::upgrade_bitmap_key;
+
+    #[test]
+    fn upgrade_legacy_bitmap_keys() {
+        for account_id in [1, 200, AccountId::MAX] {
+            let mut legacy_key = b"hello".to_vec();
+            legacy_key.push(10);
+            legacy_key.push(Collection::Mail.into());
+            legacy_key.push(BM_TERM | TERM_EXACT);
+            legacy_key.push_leb128(account_id);
+            assert_eq!(
+                upgrade_bitmap_key(&legacy_key),
+                Some(BitmapKey::serialize_term(
+                    account_id,
+                    Collection::Mail,
+                    10,
+                    "hello",
+                    true
+                ))
+            );
+
+            let mut legacy_key = b"$seen".to_vec();
+            legacy_key.push(3);
+            legacy_key.push(Collection::Mail.into());
+            legacy_key.push(BM_TAG | TAG_TEXT);
+            legacy_key.push_leb128(account_id);
+            assert_eq!(
+                upgrade_bitmap_key(&legacy_key),
+                Some(BitmapKey::serialize_tag(
+                    account_id,
+                    Collection::Mail,
+                    3,
+                    &Tag::Text("$seen".to_string())
+                ))
+            );
+
+            let mut legacy_key = vec![Collection::Mailbox.into(), BM_DOCUMENT_IDS];
+            legacy_key.push_leb128(account_id);
+            assert_eq!(
+                upgrade_bitmap_key(&legacy_key),
+                Some(BitmapKey::serialize_document_ids(
+                    account_id,
+                    Collection::Mailbox
+                ))
+            );
+        }
+    }
+}
@@ -0,0 +1,267 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tracing::warn;
+
+use crate::{serialize::key::BitmapKey, AccountId, ColumnFamily};
+
+use super::operation::WriteOperation;
+
+const COUNT_BUCKETS: &[u64] = &[0, 1, 10, 100, 1_000, 10_000, 100_000];
+const TIME_BUCKETS_US: &[u64] = &[
+    100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000,
+];
+
+/// Operations produced by a single write batch, broken down by index type.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct WriteStats {
+    pub bitmaps: u64,
+    #[serde(rename(serialize = "termPostings"))]
+    pub term_postings: u64,
+    pub values: u64,
+    pub indexes: u64,
+    pub blobs: u64,
+    pub logs: u64,
+}
+
+/// Aggregate write path metrics, used to correlate slow commits with
+/// the index types being updated.
+pub struct WriteMetrics {
+    slow_commit_threshold: Duration,
+    prepare_time: Histogram,
+    commit_time: Histogram,
+    bitmaps: Histogram,
+    term_postings: Histogram,
+    values: Histogram,
+    indexes: Histogram,
+    blobs: Histogram,
+}
+
+pub struct Histogram {
+    bounds: &'static [u64],
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct HistogramSnapshot {
+    pub buckets: Vec<HistogramBucket>,
+    pub sum: u64,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct HistogramBucket {
+    /// Inclusive upper bound, `None` for the overflow bucket.
+    pub le: Option<u64>,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct WriteMetricsSnapshot {
+    #[serde(rename(serialize = "prepareUs"))]
+    pub prepare_us: HistogramSnapshot,
+    #[serde(rename(serialize = "commitUs"))]
+    pub commit_us: HistogramSnapshot,
+    pub bitmaps: HistogramSnapshot,
+    #[serde(rename(serialize = "termPostings"))]
+    pub term_postings: HistogramSnapshot,
+    pub values: HistogramSnapshot,
+    pub indexes: HistogramSnapshot,
+    pub blobs: HistogramSnapshot,
+}
+
+impl WriteStats {
+    /// Counts the operations written to each column family. Term bitmaps are
+    /// left out as their postings are counted while the batch is prepared.
+    pub fn add_ops(&mut self, ops: &[WriteOperation]) {
+        for op in ops {
+            let (cf, key) = match op {
+                WriteOperation::Set { cf, key, .. }
+                | WriteOperation::Merge { cf, key, .. }
+                | WriteOperation::Delete { cf, key } => (cf, key),
+                WriteOperation::DeleteRange { cf, from, .. } => (cf, from),
+            };
+            match cf {
+                ColumnFamily::Bitmaps if BitmapKey::is_term(key) => (),
+                ColumnFamily::Bitmaps => self.bitmaps += 1,
+                ColumnFamily::Values => self.values += 1,
+                ColumnFamily::Indexes => self.indexes += 1,
+                ColumnFamily::Blobs => self.blobs += 1,
+                ColumnFamily::Logs => self.logs += 1,
+            }
+        }
+    }
+}
+
+impl WriteMetrics {
+    pub fn new(slow_commit_threshold: Duration) -> Self {
+        WriteMetrics {
+            slow_commit_threshold,
+            prepare_time: Histogram::new(TIME_BUCKETS_US),
+            commit_time: Histogram::new(TIME_BUCKETS_US),
+            bitmaps: Histogram::new(COUNT_BUCKETS),
+            term_postings: Histogram::new(COUNT_BUCKETS),
+            values: Histogram::new(COUNT_BUCKETS),
+            indexes: Histogram::new(COUNT_BUCKETS),
+            blobs: Histogram::new(COUNT_BUCKETS),
+        }
+    }
+
+    pub fn record(
+        &self,
+        account_id: AccountId,
+        stats: &WriteStats,
+        prepare_time: Duration,
+        commit_time: Duration,
+    ) {
+        self.prepare_time.observe(prepare_time.as_micros() as u64);
+        self.commit_time.observe(commit_time.as_micros() as u64);
+        self.bitmaps.observe(stats.bitmaps);
+        self.term_postings.observe(stats.term_postings);
+        self.values.observe(stats.values);
+        self.indexes.observe(stats.indexes);
+        self.blobs.observe(stats.blobs);
+
+        if !self.slow_commit_threshold.is_zero()
+            && prepare_time + commit_time >= self.slow_commit_threshold
+        {
+            warn!(
+                account_id,
+                prepare_ms = prepare_time.as_millis() as u64,
+                commit_ms = commit_time.as_millis() as u64,
+                bitmaps = stats.bitmaps,
+                term_postings = stats.term_postings,
+                values = stats.values,
+                indexes = stats.indexes,
+                blobs = stats.blobs,
+                logs = stats.logs,
+                "Slow commit."
+            );
+        }
+    }
+
+    pub fn snapshot(&self) -> WriteMetricsSnapshot {
+        WriteMetricsSnapshot {
+            prepare_us: self.prepare_time.snapshot(),
+            commit_us: self.commit_time.snapshot(),
+            bitmaps: self.bitmaps.snapshot(),
+            term_postings: self.term_postings.snapshot(),
+            values: self.values.snapshot(),
+            indexes: self.indexes.snapshot(),
+            blobs: self.blobs.snapshot(),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [u64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let pos = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[pos].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self
+                .buckets
+                .iter()
+                .enumerate()
+                .map(|(pos, count)| HistogramBucket {
+                    le: self.bounds.get(pos).copied(),
+                    count: count.load(Ordering::Relaxed),
+                })
+                .collect(),
+            sum: self.sum.load(Ordering::Relaxed),
+            count: self.count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        core::collection::Collection, serialize::key::BitmapKey, write::operation::WriteOperation,
+        ColumnFamily,
+    };
+
+    use super::{Histogram, WriteStats, COUNT_BUCKETS};
+
+    #[test]
+    fn write_stats() {
+        let mut stats = WriteStats::default();
+        stats.add_ops(&[
+            WriteOperation::merge(ColumnFamily::Bitmaps, vec![1], vec![]),
+            WriteOperation::merge(
+                ColumnFamily::Bitmaps,
+                BitmapKey::serialize_term(1, Collection::Mail, 0, "hello", true),
+                vec![],
+            ),
+            WriteOperation::set(ColumnFamily::Values, vec![2], vec![]),
+            WriteOperation::delete(ColumnFamily::Values, vec![3]),
+            WriteOperation::set(ColumnFamily::Blobs, vec![4], vec![]),
+        ]);
+        assert_eq!(
+            stats,
+            WriteStats {
+                bitmaps: 1,
+                values: 2,
+                blobs: 1,
+                ..Default::default()
+            }
+        );
+
+        let histogram = Histogram::new(COUNT_BUCKETS);
+        for value in [0, 1, 5, 10, 250, 1_000_000] {
+            histogram.observe(value);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(
+            snapshot.buckets.iter().map(|b| b.count).collect::<Vec<_>>(),
+            vec![1, 1, 2, 0, 1, 0, 0, 1]
+        );
+        assert_eq!(snapshot.buckets.last().unwrap().le, None);
+        assert_eq!(snapshot.count, 6);
+        assert_eq!(snapshot.sum, 1_000_266);
+    }
+}
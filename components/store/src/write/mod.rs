@@ -22,10 +22,18 @@
 */
 
 pub mod batch;
+pub mod compact;
 pub mod delete;
 pub mod field;
+pub mod gate;
+pub mod group_commit;
 pub mod id_assign;
+pub mod layout;
+pub mod metrics;
 pub mod mutex_map;
 pub mod operation;
 pub mod options;
+pub mod prune;
+pub mod renumber;
+pub mod ttl;
 pub mod update;
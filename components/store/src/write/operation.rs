@@ -39,6 +39,11 @@ pub enum WriteOperation {
         cf: ColumnFamily,
         key: Vec<u8>,
     },
+    DeleteRange {
+        cf: ColumnFamily,
+        from: Vec<u8>,
+        to: Vec<u8>,
+    },
 }
 
 impl WriteOperation {
@@ -53,4 +58,9 @@ impl WriteOperation {
     pub fn delete(cf: ColumnFamily, key: Vec<u8>) -> Self {
         WriteOperation::Delete { cf, key }
     }
+
+    /// Deletes all keys from `from` (inclusive) to `to` (exclusive).
+    pub fn delete_range(cf: ColumnFamily, from: Vec<u8>, to: Vec<u8>) -> Self {
+        WriteOperation::DeleteRange { cf, from, to }
+    }
 }
@@ -0,0 +1,364 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use ahash::AHashMap;
+use roaring::RoaringBitmap;
+use tracing::debug;
+
+use crate::{
+    config::{env_settings::EnvSettings, settings::Setting},
+    core::{collection::Collection, error::StoreError},
+    serialize::{
+        key::{ValueKey, BM_TERM, COLLECTION_PREFIX_LEN, PRUNED_TERMS_KEY_PREFIX},
+        DeserializeBigEndian, StoreDeserialize,
+    },
+    AccountId, ColumnFamily, Direction, FieldId, JMAPStore, Store,
+};
+
+use super::operation::WriteOperation;
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::integer("index-prune-max-frequency")
+        .max(100)
+        .default("0")
+        .describe("Percentage of documents a term may appear in before it is pruned, 0 = disabled"),
+    Setting::integer("index-prune-min-documents")
+        .default("1000")
+        .describe("Collections with fewer documents are not pruned"),
+];
+
+// Position of the bitmap type and field in a term key
+const BM_TYPE_POS: usize = COLLECTION_PREFIX_LEN;
+const BM_FIELD_POS: usize = COLLECTION_PREFIX_LEN + 1;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IndexPruneOptions {
+    pub max_frequency: u64,
+    pub min_documents: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct IndexPruneReport {
+    pub collections: u64,
+    #[serde(rename(serialize = "prunedTerms"))]
+    pub pruned_terms: u64,
+    #[serde(rename(serialize = "prunedPostings"))]
+    pub pruned_postings: u64,
+}
+
+/// Size of the term index of a field, summed over all accounts.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct FieldIndexStats {
+    pub collection: Collection,
+    pub field: FieldId,
+    pub terms: u64,
+    pub postings: u64,
+    pub bytes: u64,
+}
+
+/// Terms whose postings were dropped from a collection, as sorted bitmap keys.
+/// Queries look pruned terms up in the term index of each candidate document.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PrunedTerms {
+    keys: Vec<Vec<u8>>,
+}
+
+impl IndexPruneOptions {
+    pub fn parse(settings: &EnvSettings) -> Self {
+        IndexPruneOptions {
+            max_frequency: settings.value(SETTINGS, "index-prune-max-frequency"),
+            min_documents: settings.value(SETTINGS, "index-prune-min-documents"),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_frequency > 0
+    }
+
+    pub fn exceeds_frequency(&self, postings: u64, documents: u64) -> bool {
+        self.is_enabled()
+            && documents > 0
+            && documents >= self.min_documents
+            && postings * 100 > documents * self.max_frequency
+    }
+}
+
+impl PrunedTerms {
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        !self.keys.is_empty()
+            && self
+                .keys
+                .binary_search_by(|k| k.as_slice().cmp(key))
+                .is_ok()
+    }
+
+    pub fn starting_with<'x>(&'x self, prefix: &'x [u8]) -> impl Iterator<Item = &'x [u8]> {
+        self.keys[self.keys.partition_point(|key| key.as_slice() < prefix)..]
+            .iter()
+            .map(|key| key.as_slice())
+            .take_while(move |key| key.starts_with(prefix))
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>) -> bool {
+        match self.keys.binary_search(&key) {
+            Ok(_) => false,
+            Err(pos) => {
+                self.keys.insert(pos, key);
+                true
+            }
+        }
+    }
+}
+
+impl StoreDeserialize for PrunedTerms {
+    fn deserialize(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+impl<T> JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    pub fn get_pruned_terms(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+    ) -> crate::Result<PrunedTerms> {
+        Ok(self
+            .db
+            .get::<PrunedTerms>(
+                ColumnFamily::Values,
+                &ValueKey::serialize_pruned_terms(account_id, collection),
+            )?
+            .unwrap_or_default())
+    }
+
+    /// Drops the postings of full-text terms that appear in more documents than
+    /// allowed, along with new postings of previously pruned terms. Writes to a
+    /// collection are blocked while it is being pruned.
+    ///
+    /// Each replica builds its own index, so pruning only affects the local node.
+    /// Since queries check pruned terms against the term index, replicas that
+    /// pruned different terms still return the same results.
+    pub fn prune_index(
+        &self,
+        options: IndexPruneOptions,
+        fields: &[(Collection, FieldId)],
+    ) -> crate::Result<IndexPruneReport> {
+        let mut report = IndexPruneReport::default();
+        if !options.is_enabled() || fields.is_empty() {
+            return Ok(report);
+        }
+
+        // Find the collections holding candidate terms without blocking writers
+        let mut candidates: AHashMap<(AccountId, Collection), Vec<Vec<u8>>> = AHashMap::new();
+        let mut current = None;
+        let mut documents = 0;
+        for (key, value) in self
+            .db
+            .iterator(ColumnFamily::Bitmaps, &[], Direction::Forward)?
+        {
+            let prefix = if let Some(prefix) = deserialize_prefix(&key) {
+                prefix
+            } else {
+                continue;
+            };
+            if current != Some(prefix) {
+                current = Some(prefix);
+                documents = 0;
+            }
+            if key.len() == BM_TYPE_POS + 1 {
+                // Document ids sort first within a collection
+                documents = bitmap_len(&value)?;
+            } else if is_term_key(&key)
+                && fields.contains(&(prefix.1, key[BM_FIELD_POS]))
+                && options.exceeds_frequency(bitmap_len(&value)?, documents)
+            {
+                candidates
+                    .entry(prefix)
+                    .or_insert_with(Vec::new)
+                    .push(key.to_vec());
+            }
+        }
+
+        // Previously pruned terms are dropped again as new documents get indexed
+        for (key, _) in self.db.iterator(
+            ColumnFamily::Values,
+            PRUNED_TERMS_KEY_PREFIX,
+            Direction::Forward,
+        )? {
+            if let Some(prefix) = key
+                .strip_prefix(PRUNED_TERMS_KEY_PREFIX.as_ref())
+                .and_then(deserialize_prefix)
+            {
+                candidates.entry(prefix).or_default();
+            } else {
+                break;
+            }
+        }
+
+        for ((account_id, collection), keys) in candidates {
+            let _lock = self.lock_collection(account_id, collection);
+            let mut pruned_terms = self.get_pruned_terms(account_id, collection)?;
+            let documents = self
+                .get_document_ids(account_id, collection)?
+                .map(|ids| ids.len())
+                .unwrap_or(0);
+            let mut batch = Vec::new();
+
+            // Counts might have changed since the scan
+            for (key, bitmap) in keys.iter().zip(
+                self.db
+                    .multi_get::<RoaringBitmap, _>(ColumnFamily::Bitmaps, keys.clone())?,
+            ) {
+                if bitmap.map_or(false, |bitmap| {
+                    options.exceeds_frequency(bitmap.len(), documents)
+                }) && pruned_terms.insert(key.clone())
+                {
+                    report.pruned_terms += 1;
+                }
+            }
+            for (key, bitmap) in
+                pruned_terms
+                    .keys
+                    .iter()
+                    .zip(self.db.multi_get::<RoaringBitmap, _>(
+                        ColumnFamily::Bitmaps,
+                        pruned_terms.keys.clone(),
+                    )?)
+            {
+                if let Some(bitmap) = bitmap {
+                    report.pruned_postings += bitmap.len();
+                    batch.push(WriteOperation::delete(ColumnFamily::Bitmaps, key.clone()));
+                }
+            }
+
+            if !batch.is_empty() {
+                debug!(
+                    "Pruning {} term(s) from collection {:?} of account {}.",
+                    batch.len(),
+                    collection,
+                    account_id
+                );
+                batch.push(WriteOperation::set(
+                    ColumnFamily::Values,
+                    ValueKey::serialize_pruned_terms(account_id, collection),
+                    bincode::serialize(&pruned_terms).map_err(|_| {
+                        StoreError::SerializeError("Failed to serialize pruned terms".to_string())
+                    })?,
+                ));
+                self.write_operations(batch)?;
+                report.collections += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Returns the number of terms, postings and bytes used by the term index
+    /// of each field.
+    pub fn index_stats(&self) -> crate::Result<Vec<FieldIndexStats>> {
+        let mut stats: AHashMap<(Collection, FieldId), FieldIndexStats> = AHashMap::new();
+        for (key, value) in self
+            .db
+            .iterator(ColumnFamily::Bitmaps, &[], Direction::Forward)?
+        {
+            if !is_term_key(&key) {
+                continue;
+            }
+            let collection = Collection::from(key[BM_TYPE_POS - 1]);
+            let field = key[BM_FIELD_POS];
+            let entry = stats
+                .entry((collection, field))
+                .or_insert_with(|| FieldIndexStats {
+                    collection,
+                    field,
+                    terms: 0,
+                    postings: 0,
+                    bytes: 0,
+                });
+            entry.terms += 1;
+            entry.postings += bitmap_len(&value)?;
+            entry.bytes += (key.len() + value.len()) as u64;
+        }
+
+        let mut stats = stats.into_iter().map(|(_, s)| s).collect::<Vec<_>>();
+        stats.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+        Ok(stats)
+    }
+}
+
+fn deserialize_prefix(key: &[u8]) -> Option<(AccountId, Collection)> {
+    if key.len() > BM_TYPE_POS - 1 {
+        Some((
+            key.deserialize_be_u32(0)?,
+            Collection::from(key[BM_TYPE_POS - 1]),
+        ))
+    } else {
+        None
+    }
+}
+
+fn is_term_key(key: &[u8]) -> bool {
+    key.len() > BM_FIELD_POS && key[BM_TYPE_POS] & 0xF0 == BM_TERM
+}
+
+fn bitmap_len(bytes: &[u8]) -> crate::Result<u64> {
+    <RoaringBitmap as StoreDeserialize>::deserialize(bytes)
+        .map(|bm| bm.len())
+        .ok_or_else(|| StoreError::InternalError("Failed to deserialize bitmap.".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IndexPruneOptions, PrunedTerms};
+
+    #[test]
+    fn prune_thresholds() {
+        let options = IndexPruneOptions {
+            max_frequency: 50,
+            min_documents: 10,
+        };
+        assert!(options.exceeds_frequency(6, 10));
+        assert!(!options.exceeds_frequency(5, 10));
+        assert!(!options.exceeds_frequency(9, 9));
+        assert!(!IndexPruneOptions::default().exceeds_frequency(100, 100));
+
+        let mut terms = PrunedTerms::default();
+        assert!(terms.insert(b"the".to_vec()));
+        assert!(terms.insert(b"and".to_vec()));
+        assert!(!terms.insert(b"the".to_vec()));
+        assert!(terms.contains(b"and") && terms.contains(b"the"));
+        assert!(!terms.contains(b"hello"));
+        assert_eq!(terms.len(), 2);
+    }
+}
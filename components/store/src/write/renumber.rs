@@ -0,0 +1,594 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use roaring::{RoaringBitmap, RoaringTreemap};
+use tracing::debug;
+
+use crate::{
+    blob::BLOB_HASH_LEN,
+    core::{bitmap::Bitmap, collection::Collection, error::StoreError},
+    log::{changes::ChangeId, entry::Entry},
+    serialize::{
+        key::{IndexKey, LogKey, ValueKey, BM_TAG, COLLECTION_PREFIX_LEN, TAG_ID},
+        leb128::{Leb128Reader, Leb128Vec},
+        StoreDeserialize, StoreSerialize,
+    },
+    AccountId, ColumnFamily, Direction, DocumentId, FieldId, JMAPStore, Store,
+};
+
+use super::{batch, id_assign::IdAssigner, operation::WriteOperation};
+
+// Position of the bitmap type and field in a tag key
+const BM_TYPE_POS: usize = COLLECTION_PREFIX_LEN;
+const BM_FIELD_POS: usize = COLLECTION_PREFIX_LEN + 1;
+
+const BLOB_LINKS_PAGE_SIZE: usize = 1000;
+
+/// Maps the ids of the documents in a collection to consecutive ids,
+/// keeping their order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DocumentIdMap {
+    old_ids: Vec<DocumentId>,
+}
+
+/// A field of `collection` holding ids of documents from `target`, either as
+/// `Tag::Id` tags or as stored document id values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdReference {
+    pub collection: Collection,
+    pub field: FieldId,
+    pub target: Collection,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct RenumberReport {
+    pub collections: Vec<RenumberedCollection>,
+    #[serde(rename(serialize = "rewrittenKeys"))]
+    pub rewritten_keys: u64,
+    #[serde(rename(serialize = "changeId"))]
+    pub change_id: Option<ChangeId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct RenumberedCollection {
+    pub collection: Collection,
+    pub documents: u64,
+    pub renumbered: u64,
+    #[serde(rename(serialize = "maxIdBefore"))]
+    pub max_id_before: Option<DocumentId>,
+    #[serde(skip)]
+    pub ids: DocumentIdMap,
+}
+
+enum Rewrite {
+    Keep,
+    Drop,
+    Set(Vec<u8>, Vec<u8>),
+}
+
+impl DocumentIdMap {
+    pub fn new(document_ids: &RoaringBitmap) -> Self {
+        DocumentIdMap {
+            old_ids: document_ids.iter().collect(),
+        }
+    }
+
+    pub fn get(&self, old_id: DocumentId) -> Option<DocumentId> {
+        self.old_ids
+            .binary_search(&old_id)
+            .ok()
+            .map(|pos| pos as DocumentId)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.old_ids.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.old_ids.is_empty()
+    }
+
+    /// Returns true if no document id changes.
+    pub fn is_dense(&self) -> bool {
+        self.old_ids
+            .last()
+            .map_or(true, |id| *id as usize + 1 == self.old_ids.len())
+    }
+
+    /// Number of documents whose id changes.
+    pub fn renumbered(&self) -> u64 {
+        self.old_ids
+            .iter()
+            .enumerate()
+            .filter(|(pos, id)| **id as usize != *pos)
+            .count() as u64
+    }
+
+    /// Iterates over the old and new id of each document.
+    pub fn iter(&self) -> impl Iterator<Item = (DocumentId, DocumentId)> + '_ {
+        self.old_ids
+            .iter()
+            .enumerate()
+            .map(|(pos, id)| (*id, pos as DocumentId))
+    }
+
+    pub fn new_ids(&self) -> RoaringBitmap {
+        RoaringBitmap::from_sorted_iter(0..self.old_ids.len() as DocumentId).unwrap()
+    }
+
+    /// Maps the ids in a bitmap, ids of documents that no longer exist are dropped.
+    pub fn map_bitmap(&self, bitmap: &RoaringBitmap) -> RoaringBitmap {
+        // The mapping keeps the order, so mapped ids come out sorted
+        RoaringBitmap::from_sorted_iter(bitmap.iter().filter_map(|id| self.get(id))).unwrap()
+    }
+}
+
+impl IdReference {
+    pub fn new(collection: Collection, field: impl Into<FieldId>, target: Collection) -> Self {
+        IdReference {
+            collection,
+            field: field.into(),
+            target,
+        }
+    }
+}
+
+impl RenumberReport {
+    pub fn ids(&self, collection: Collection) -> Option<&DocumentIdMap> {
+        self.collections
+            .iter()
+            .find(|c| c.collection == collection)
+            .map(|c| &c.ids)
+    }
+}
+
+impl<T> JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Rewrites the keys of the given collections of an account so that their
+    /// document ids become consecutive again, along with the tags and stored
+    /// values listed in `references` that point to them. The change log of each
+    /// collection is replaced by a snapshot of the ids returned by `snapshot`,
+    /// written under a new change id. Changes since an earlier state can no
+    /// longer be calculated.
+    ///
+    /// The keys of the account are rewritten in a single batch built in
+    /// memory, blob links are rewritten in pages beforehand. Writes to the
+    /// account are blocked with `block_writes` while it runs, document ids
+    /// handed out before the call but not yet written are not renumbered.
+    pub fn renumber_documents(
+        &self,
+        account_id: AccountId,
+        collections: &[Collection],
+        references: &[IdReference],
+        snapshot: impl FnOnce(&RenumberReport) -> crate::Result<Vec<(Collection, RoaringTreemap)>>,
+    ) -> crate::Result<RenumberReport> {
+        // The values of account 0 share their prefix with internal keys
+        if account_id == 0 {
+            return Err(StoreError::InvalidArguments(
+                "The documents of account 0 cannot be renumbered.".to_string(),
+            ));
+        }
+
+        if self.write_gates.read().get(&account_id) != Some(&std::thread::current().id()) {
+            return Err(StoreError::InvalidArguments(format!(
+                "Writes to account {} have to be blocked while renumbering.",
+                account_id
+            )));
+        }
+
+        // Hold the id assigners to block new documents from being created
+        let id_assigners = collections
+            .iter()
+            .map(|collection| self.get_id_assigner(account_id, *collection))
+            .collect::<crate::Result<Vec<_>>>()?;
+        let mut assigners = id_assigners.iter().map(|a| a.lock()).collect::<Vec<_>>();
+
+        let mut report = RenumberReport::default();
+        for collection in collections {
+            let document_ids = self
+                .get_document_ids(account_id, *collection)?
+                .unwrap_or_default();
+            let ids = DocumentIdMap::new(&document_ids);
+            report.collections.push(RenumberedCollection {
+                collection: *collection,
+                documents: ids.len(),
+                renumbered: ids.renumbered(),
+                max_id_before: document_ids.max(),
+                ids,
+            });
+        }
+        if report.collections.iter().all(|c| c.ids.is_dense()) {
+            return Ok(report);
+        }
+
+        // Old keys are deleted before the new ones are written, as the new id of
+        // a document is usually the old id of another one.
+        let mut deletes = Vec::new();
+        let mut sets = Vec::new();
+        let mut scan_collections = collections.to_vec();
+        for reference in references {
+            if !scan_collections.contains(&reference.collection) {
+                scan_collections.push(reference.collection);
+            }
+        }
+
+        for collection in scan_collections {
+            let ids = report.ids(collection);
+            let fields = references
+                .iter()
+                .filter(|r| r.collection == collection)
+                .filter_map(|r| report.ids(r.target).map(|ids| (r.field, ids)))
+                .collect::<Vec<_>>();
+
+            for cf in [
+                ColumnFamily::Bitmaps,
+                ColumnFamily::Values,
+                ColumnFamily::Indexes,
+            ] {
+                let from = if cf == ColumnFamily::Values {
+                    ValueKey::serialize_collection(account_id, collection)
+                } else {
+                    IndexKey::serialize_collection(account_id, collection)
+                };
+                let mut to = from.clone();
+                *to.last_mut().unwrap() += 1;
+
+                // All the keys of a renumbered collection are rewritten
+                if ids.is_some() {
+                    deletes.push(WriteOperation::delete_range(cf, from.clone(), to));
+                } else if cf == ColumnFamily::Indexes {
+                    continue;
+                }
+
+                for (key, value) in self.db.iterator(cf, &from, Direction::Forward)? {
+                    if !key.starts_with(&from) {
+                        break;
+                    }
+                    let rewrite = match cf {
+                        ColumnFamily::Bitmaps => rewrite_bitmap(&key, &value, ids, &fields)?,
+                        ColumnFamily::Values => {
+                            rewrite_value(&key, &value, from.len(), ids, &fields)
+                        }
+                        _ => rewrite_index(&key, &value, ids),
+                    };
+                    match rewrite {
+                        Rewrite::Keep => {
+                            if ids.is_some() {
+                                sets.push(WriteOperation::set(cf, key.to_vec(), value.to_vec()));
+                            }
+                        }
+                        Rewrite::Drop => {
+                            if ids.is_none() {
+                                deletes.push(WriteOperation::delete(cf, key.to_vec()));
+                            }
+                        }
+                        Rewrite::Set(new_key, new_value) => {
+                            if ids.is_none() && new_key[..] != key[..] {
+                                deletes.push(WriteOperation::delete(cf, key.to_vec()));
+                            }
+                            sets.push(WriteOperation::set(cf, new_key, new_value));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Leases are dropped so that new ids are assigned right after the last one
+        for collection in collections {
+            deletes.push(WriteOperation::delete(
+                ColumnFamily::Values,
+                ValueKey::serialize_id_lease(account_id, *collection),
+            ));
+        }
+
+        let snapshot = snapshot(&report)?;
+        report.rewritten_keys =
+            sets.len() as u64 + self.renumber_blob_links(account_id, &report)?;
+        debug!(
+            "Renumbering {} document(s) of account {}, rewriting {} key(s).",
+            report.collections.iter().map(|c| c.renumbered).sum::<u64>(),
+            account_id,
+            report.rewritten_keys
+        );
+
+        deletes.append(&mut sets);
+        report.change_id = self
+            .group_commit
+            .write(
+                deletes,
+                |ops| self.log_renumber(ops, account_id, snapshot),
+                |ops| self.write_operations(ops),
+            )?
+            .into();
+
+        // Ids are assigned from the new last id on
+        for (assigner, collection) in assigners.iter_mut().zip(&report.collections) {
+            **assigner = IdAssigner::new(if !collection.ids.is_empty() {
+                Some(collection.ids.new_ids())
+            } else {
+                None
+            });
+        }
+        self.shared_documents.invalidate_all();
+        self.query_snapshots.invalidate_all();
+        self.invalidate_thread_previews(account_id);
+
+        Ok(report)
+    }
+
+    /// Blob links are not prefixed by account, so they are found by scanning
+    /// all of them. They are rewritten before the documents, in pages holding
+    /// all the links of a blob, which keeps the number of links of each blob
+    /// unchanged at all times. If renumbering is interrupted, the links of
+    /// the account may point to the new ids of other documents, which only
+    /// delays purging their blobs.
+    fn renumber_blob_links(
+        &self,
+        account_id: AccountId,
+        report: &RenumberReport,
+    ) -> crate::Result<u64> {
+        let mut deletes = Vec::new();
+        let mut sets = Vec::new();
+        let mut blob_hash = Vec::new();
+        let mut rewritten = 0;
+
+        for (key, value) in self
+            .db
+            .iterator(ColumnFamily::Blobs, &[], Direction::Forward)?
+        {
+            if key.len() > BLOB_HASH_LEN + 1 && key[..BLOB_HASH_LEN + 1] != blob_hash[..] {
+                if sets.len() >= BLOB_LINKS_PAGE_SIZE {
+                    rewritten += sets.len() as u64;
+                    deletes.append(&mut sets);
+                    self.write_operations(std::mem::take(&mut deletes))?;
+                }
+                blob_hash = key[..BLOB_HASH_LEN + 1].to_vec();
+            }
+
+            if let Some(new_key) = rewrite_blob_link(&key, account_id, report) {
+                deletes.push(WriteOperation::delete(ColumnFamily::Blobs, key.to_vec()));
+                sets.push(WriteOperation::set(
+                    ColumnFamily::Blobs,
+                    new_key,
+                    value.to_vec(),
+                ));
+            }
+        }
+
+        if !sets.is_empty() {
+            rewritten += sets.len() as u64;
+            deletes.append(&mut sets);
+            self.write_operations(deletes)?;
+        }
+
+        Ok(rewritten)
+    }
+
+    fn log_renumber(
+        &self,
+        ops: &mut Vec<WriteOperation>,
+        account_id: AccountId,
+        snapshot: Vec<(Collection, RoaringTreemap)>,
+    ) -> crate::Result<ChangeId> {
+        let raft_id = self.assign_raft_id();
+        let mut collections = Bitmap::default();
+
+        for (collection, ids) in snapshot {
+            collections.insert(collection);
+
+            // Earlier entries refer to the old ids
+            ops.push(WriteOperation::delete_range(
+                ColumnFamily::Logs,
+                LogKey::serialize_change(account_id, collection, 0),
+                LogKey::serialize_change(account_id, collection, raft_id.index),
+            ));
+            let mut bytes = Vec::with_capacity(1 + ids.serialized_size());
+            bytes.push(batch::Change::SNAPSHOT);
+            ids.serialize_into(&mut bytes).map_err(|err| {
+                StoreError::InternalError(format!(
+                    "Failed to serialize renumbered ids for [{}/{:?}]: [{:?}]",
+                    account_id, collection, err
+                ))
+            })?;
+            ops.push(WriteOperation::set(
+                ColumnFamily::Logs,
+                LogKey::serialize_change(account_id, collection, raft_id.index),
+                bytes,
+            ));
+            ops.push(WriteOperation::set(
+                ColumnFamily::Values,
+                ValueKey::serialize_renumbered(account_id, collection),
+                raft_id.index.serialize().unwrap(),
+            ));
+        }
+
+        let mut bytes = Vec::with_capacity(
+            std::mem::size_of::<AccountId>() + std::mem::size_of::<u64>() + 1 + Entry::CHECKSUM_LEN,
+        );
+        bytes.push(batch::Change::ENTRY);
+        bytes.extend_from_slice(&account_id.to_le_bytes());
+        bytes.extend_from_slice(&collections.to_le_bytes());
+        ops.push(WriteOperation::set(
+            ColumnFamily::Logs,
+            LogKey::serialize_raft(&raft_id),
+            Entry::seal(&raft_id, bytes),
+        ));
+
+        Ok(raft_id.index)
+    }
+
+    /// Returns the change id at which a collection was last renumbered, changes
+    /// since an earlier state cannot be calculated.
+    pub fn get_renumbered_change_id(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+    ) -> crate::Result<Option<ChangeId>> {
+        self.db.get::<ChangeId>(
+            ColumnFamily::Values,
+            &ValueKey::serialize_renumbered(account_id, collection),
+        )
+    }
+}
+
+fn rewrite_bitmap(
+    key: &[u8],
+    value: &[u8],
+    ids: Option<&DocumentIdMap>,
+    fields: &[(FieldId, &DocumentIdMap)],
+) -> crate::Result<Rewrite> {
+    let mut new_key = None;
+
+    // Tags holding the id of a renumbered document
+    if key.len() > BM_FIELD_POS + 1 && key[BM_TYPE_POS] == BM_TAG | TAG_ID {
+        if let Some((_, tag_ids)) = fields.iter().find(|(field, _)| *field == key[BM_FIELD_POS]) {
+            let tag_id = if let Some((tag_id, _)) = key
+                .get(BM_FIELD_POS + 1..)
+                .and_then(|bytes| bytes.read_leb128::<DocumentId>())
+            {
+                tag_id
+            } else {
+                return Ok(Rewrite::Keep);
+            };
+            match tag_ids.get(tag_id) {
+                Some(new_tag_id) if new_tag_id != tag_id => {
+                    let mut bytes = key[..BM_FIELD_POS + 1].to_vec();
+                    bytes.push_leb128(new_tag_id);
+                    new_key = bytes.into();
+                }
+                Some(_) => (),
+                None => return Ok(Rewrite::Drop),
+            }
+        }
+    }
+
+    let new_value = if let Some(ids) = ids {
+        let bitmap = ids.map_bitmap(&RoaringBitmap::deserialize(value).ok_or_else(|| {
+            StoreError::InternalError(format!("Failed to deserialize bitmap [{:?}].", key))
+        })?);
+        if bitmap.is_empty() {
+            return Ok(Rewrite::Drop);
+        }
+        bitmap.serialize()
+    } else {
+        None
+    };
+
+    Ok(if new_key.is_some() || new_value.is_some() {
+        Rewrite::Set(
+            new_key.unwrap_or_else(|| key.to_vec()),
+            new_value.unwrap_or_else(|| value.to_vec()),
+        )
+    } else {
+        Rewrite::Keep
+    })
+}
+
+fn rewrite_value(
+    key: &[u8],
+    value: &[u8],
+    prefix_len: usize,
+    ids: Option<&DocumentIdMap>,
+    fields: &[(FieldId, &DocumentIdMap)],
+) -> Rewrite {
+    // Values of the collection itself are not tied to a document
+    let (document_id, id_len) = if let Some((document_id, id_len)) = key
+        .get(prefix_len..)
+        .and_then(|bytes| bytes.read_leb128::<DocumentId>())
+    {
+        (document_id, id_len)
+    } else {
+        return Rewrite::Keep;
+    };
+    let suffix = &key[prefix_len + id_len..];
+
+    let new_document_id = if let Some(ids) = ids {
+        if let Some(new_document_id) = ids.get(document_id) {
+            new_document_id
+        } else {
+            return Rewrite::Drop;
+        }
+    } else {
+        document_id
+    };
+
+    // Stored ids of renumbered documents
+    let new_value = match suffix {
+        [field] => fields
+            .iter()
+            .find(|(f, _)| f == field)
+            .and_then(|(_, ids)| ids.get(DocumentId::deserialize(value)?))
+            .and_then(|id| id.serialize()),
+        _ => None,
+    };
+
+    if new_document_id != document_id || new_value.is_some() {
+        let mut new_key = Vec::with_capacity(key.len());
+        new_key.extend_from_slice(&key[..prefix_len]);
+        new_key.push_leb128(new_document_id);
+        new_key.extend_from_slice(suffix);
+        Rewrite::Set(new_key, new_value.unwrap_or_else(|| value.to_vec()))
+    } else {
+        Rewrite::Keep
+    }
+}
+
+fn rewrite_index(key: &[u8], value: &[u8], ids: Option<&DocumentIdMap>) -> Rewrite {
+    let (document_id, ids) = match (IndexKey::deserialize_document_id(key), ids) {
+        (Some(document_id), Some(ids)) => (document_id, ids),
+        _ => return Rewrite::Keep,
+    };
+    if let Some(new_document_id) = ids.get(document_id) {
+        let mut new_key = key[..key.len() - std::mem::size_of::<DocumentId>()].to_vec();
+        new_key.extend_from_slice(&new_document_id.to_be_bytes());
+        Rewrite::Set(new_key, value.to_vec())
+    } else {
+        Rewrite::Drop
+    }
+}
+
+fn rewrite_blob_link(
+    key: &[u8],
+    account_id: AccountId,
+    report: &RenumberReport,
+) -> Option<Vec<u8>> {
+    let bytes = key.get(BLOB_HASH_LEN + 1..)?;
+    let (link_account_id, account_len) = bytes.read_leb128::<AccountId>()?;
+    if link_account_id != account_id {
+        return None;
+    }
+    let collection = Collection::from(*bytes.get(account_len)?);
+    let (document_id, _) = bytes.get(account_len + 1..)?.read_leb128::<DocumentId>()?;
+
+    // Links of unknown documents are left in place
+    let new_document_id = report.ids(collection)?.get(document_id)?;
+    if new_document_id != document_id {
+        let mut new_key = key[..BLOB_HASH_LEN + 1 + account_len + 1].to_vec();
+        new_key.push_leb128(new_document_id);
+        Some(new_key)
+    } else {
+        None
+    }
+}
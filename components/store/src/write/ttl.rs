@@ -0,0 +1,235 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::SystemTime;
+
+use crate::{
+    serialize::{
+        key::{ValueKey, EXPIRY_INDEX_PREFIX},
+        StoreDeserialize,
+    },
+    ColumnFamily, Direction, JMAPStore, Store, StoreError, WriteOperation,
+};
+
+const EXPIRES_AT_LEN: usize = std::mem::size_of::<u64>();
+
+impl WriteOperation {
+    /// Sets a value that stops being visible once the `expires_at` UNIX
+    /// timestamp (in milliseconds) has passed, it is physically removed by
+    /// `purge_expired`. Currently used for OAuth codes and spent passkey
+    /// challenges, rate limiter counters are checked on every request and
+    /// stay in memory.
+    pub fn set_expiring(name: &[u8], value: &[u8], expires_at: u64) -> [WriteOperation; 2] {
+        let mut bytes = Vec::with_capacity(EXPIRES_AT_LEN + value.len());
+        bytes.extend_from_slice(&expires_at.to_be_bytes());
+        bytes.extend_from_slice(value);
+
+        [
+            WriteOperation::set(
+                ColumnFamily::Values,
+                ValueKey::serialize_expiring(name),
+                bytes,
+            ),
+            WriteOperation::set(
+                ColumnFamily::Values,
+                ValueKey::serialize_expiry(expires_at, name),
+                vec![],
+            ),
+        ]
+    }
+}
+
+impl<T> JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    pub fn set_expiring(&self, name: &[u8], value: &[u8], expires_at: u64) -> crate::Result<()> {
+        let _lock = self.account_lock.lock_hash(name);
+        self.db
+            .write(WriteOperation::set_expiring(name, value, expires_at).into())
+    }
+
+    /// Replaces an expiring value only if it has not expired and still holds
+    /// `current`, returns whether it was replaced. The check and the write
+    /// happen under the key lock, so concurrent updates cannot both succeed.
+    pub fn compare_and_set_expiring(
+        &self,
+        name: &[u8],
+        current: &[u8],
+        value: &[u8],
+        expires_at: u64,
+    ) -> crate::Result<bool> {
+        let _lock = self.account_lock.lock_hash(name);
+        match self
+            .db
+            .get::<Vec<u8>>(ColumnFamily::Values, &ValueKey::serialize_expiring(name))?
+        {
+            Some(bytes)
+                if bytes.len() >= EXPIRES_AT_LEN
+                    && !is_expired(u64::from_be_bytes(
+                        bytes[..EXPIRES_AT_LEN].try_into().unwrap(),
+                    ))
+                    && &bytes[EXPIRES_AT_LEN..] == current =>
+            {
+                self.db
+                    .write(WriteOperation::set_expiring(name, value, expires_at).into())?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Sets an expiring value only if it is missing or has expired, returns
+    /// whether it was set. Used to make one-time values single use.
+    pub fn insert_expiring(
+        &self,
+        name: &[u8],
+        value: &[u8],
+        expires_at: u64,
+    ) -> crate::Result<bool> {
+        let _lock = self.account_lock.lock_hash(name);
+        match self
+            .db
+            .get::<Vec<u8>>(ColumnFamily::Values, &ValueKey::serialize_expiring(name))?
+        {
+            Some(bytes)
+                if bytes.len() >= EXPIRES_AT_LEN
+                    && !is_expired(u64::from_be_bytes(
+                        bytes[..EXPIRES_AT_LEN].try_into().unwrap(),
+                    )) =>
+            {
+                Ok(false)
+            }
+            _ => {
+                self.db
+                    .write(WriteOperation::set_expiring(name, value, expires_at).into())?;
+                Ok(true)
+            }
+        }
+    }
+
+    pub fn get_expiring<U>(&self, name: &[u8]) -> crate::Result<Option<U>>
+    where
+        U: StoreDeserialize,
+    {
+        if let Some(bytes) = self
+            .db
+            .get::<Vec<u8>>(ColumnFamily::Values, &ValueKey::serialize_expiring(name))?
+        {
+            if bytes.len() >= EXPIRES_AT_LEN
+                && !is_expired(u64::from_be_bytes(
+                    bytes[..EXPIRES_AT_LEN].try_into().unwrap(),
+                ))
+            {
+                return U::deserialize(&bytes[EXPIRES_AT_LEN..])
+                    .ok_or_else(|| {
+                        StoreError::DeserializeError(format!(
+                            "Failed to deserialize expiring value {:?}",
+                            name
+                        ))
+                    })
+                    .map(Some);
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn delete_expiring(&self, name: &[u8]) -> crate::Result<()> {
+        // The expiry index entry is left for purge_expired to remove.
+        let _lock = self.account_lock.lock_hash(name);
+        self.db
+            .delete(ColumnFamily::Values, &ValueKey::serialize_expiring(name))
+    }
+
+    /// Removes expired values and their expiry index entries, returns the
+    /// number of values removed.
+    pub fn purge_expired(&self) -> crate::Result<usize> {
+        let now = now_millis();
+        let mut expired = Vec::new();
+
+        for (key, _) in self.db.iterator(
+            ColumnFamily::Values,
+            EXPIRY_INDEX_PREFIX,
+            Direction::Forward,
+        )? {
+            if !key.starts_with(EXPIRY_INDEX_PREFIX)
+                || key.len() < EXPIRY_INDEX_PREFIX.len() + EXPIRES_AT_LEN
+            {
+                break;
+            }
+            let expires_at = u64::from_be_bytes(
+                key[EXPIRY_INDEX_PREFIX.len()..EXPIRY_INDEX_PREFIX.len() + EXPIRES_AT_LEN]
+                    .try_into()
+                    .unwrap(),
+            );
+            if expires_at > now {
+                break;
+            }
+            expired.push(key);
+        }
+
+        let mut purged = 0;
+        for key in expired {
+            let name = &key[EXPIRY_INDEX_PREFIX.len() + EXPIRES_AT_LEN..];
+            let value_key = ValueKey::serialize_expiring(name);
+            let _lock = self.account_lock.lock_hash(name);
+            let mut batch = vec![WriteOperation::delete(ColumnFamily::Values, key.to_vec())];
+
+            // Values that were set again with a later expiry are kept
+            if let Some(bytes) = self.db.get::<Vec<u8>>(ColumnFamily::Values, &value_key)? {
+                if bytes.len() < EXPIRES_AT_LEN
+                    || u64::from_be_bytes(bytes[..EXPIRES_AT_LEN].try_into().unwrap()) <= now
+                {
+                    batch.push(WriteOperation::delete(ColumnFamily::Values, value_key));
+                    purged += 1;
+                }
+            }
+            self.db.write(batch)?;
+        }
+
+        Ok(purged)
+    }
+}
+
+/// Returns the expiry timestamp of a value that should live for `ttl` seconds.
+pub fn expires_in(ttl: u64) -> u64 {
+    now_millis() + ttl * 1000
+}
+
+/// Returns the current UNIX timestamp in seconds.
+pub fn now() -> u64 {
+    now_millis() / 1000
+}
+
+/// Returns true once an expiry timestamp, in milliseconds, has passed.
+pub fn is_expired(expires_at: u64) -> bool {
+    expires_at <= now_millis()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
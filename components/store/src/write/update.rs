@@ -21,15 +21,21 @@
  * for more details.
 */
 
+use std::time::Instant;
+
 use ahash::AHashMap;
 
 use crate::{
     blob::BlobId,
     core::{
-        bitmap::Bitmap, collection::Collection, document::MAX_TOKEN_LENGTH, error::StoreError,
+        bitmap::Bitmap,
+        collection::Collection,
+        document::{Document, MAX_TOKEN_LENGTH},
+        error::StoreError,
         tag::Tag,
+        vec_map::VecMap,
     },
-    log::changes::ChangeId,
+    log::{changes::ChangeId, entry::Entry},
     nlp::{
         lang::{LanguageDetector, MIN_LANGUAGE_SCORE},
         stemmer::Stemmer,
@@ -47,6 +53,7 @@ use crate::{
 
 use super::{
     batch::{Change, WriteAction, WriteBatch},
+    metrics::WriteStats,
     operation::WriteOperation,
     options::{IndexOptions, Options},
 };
@@ -57,38 +64,92 @@ pub struct Changes {
     pub change_id: ChangeId,
 }
 
+struct PendingLog {
+    account_id: AccountId,
+    changes: VecMap<Collection, Change>,
+    tombstones: Vec<Document>,
+}
+
 impl<T> JMAPStore<T>
 where
     T: for<'x> Store<'x> + 'static,
 {
     pub fn write(&self, mut batch: WriteBatch) -> crate::Result<Option<Changes>> {
         let mut ops = Vec::with_capacity(batch.documents.len());
+        let mut linked_logs = Vec::with_capacity(batch.linked_batch.len());
+        let mut stats = WriteStats::default();
+        let account_id = batch.account_id;
         let tombstone_deletions = self
             .tombstone_deletions
             .load(std::sync::atomic::Ordering::Relaxed);
+        let started = Instant::now();
+
+        // Maintenance tasks can block writes to the account
+        let _gate = self.enter_write_gate(account_id)?;
+
+        // Reject batches produced under a stale Raft term
+        let fencing_token = self.fencing_token.read();
+        self.fence_batch(&batch, *fencing_token)?;
+        self.mark_applied(&mut ops, &batch);
 
         // Prepare linked batch
         for sub_batch in batch.linked_batch.drain(..) {
-            self.prepare_batch(&mut ops, sub_batch, tombstone_deletions)?;
+            if let Some(log) =
+                self.prepare_batch(&mut ops, sub_batch, tombstone_deletions, &mut stats)?
+            {
+                linked_logs.push(log);
+            }
         }
 
         // Prepare main batch
-        let changes = self.prepare_batch(&mut ops, batch, tombstone_deletions)?;
-
-        // Submit write batch
-        self.db.write(ops)?;
-
-        Ok(changes)
+        let log = self.prepare_batch(&mut ops, batch, tombstone_deletions, &mut stats)?;
+        let prepare_time = started.elapsed();
+        stats.add_ops(&ops);
+
+        // Submit write batch, possibly coalesced with other concurrent writes
+        let started = Instant::now();
+        let result = self.group_commit.write(
+            ops,
+            |ops| {
+                for linked_log in linked_logs {
+                    self.log_batch(ops, linked_log)?;
+                }
+                log.map(|log| self.log_batch(ops, log)).transpose()
+            },
+            |ops| self.write_operations(ops),
+        );
+        drop(fencing_token);
+        self.write_metrics
+            .record(account_id, &stats, prepare_time, started.elapsed());
+
+        result
     }
 
     pub fn commit_write(&self, batch: WriteBatch) -> crate::Result<Option<Changes>> {
         let mut ops = Vec::with_capacity(batch.documents.len());
+        let mut stats = WriteStats::default();
+        let account_id = batch.account_id;
+        let started = Instant::now();
+
+        // Reject batches produced under a stale Raft term
+        let fencing_token = self.fencing_token.read();
+        self.fence_batch(&batch, *fencing_token)?;
+        self.mark_applied(&mut ops, &batch);
 
         // Prepare batch
-        let changes = self.prepare_batch(&mut ops, batch, false)?;
+        let changes = self
+            .prepare_batch(&mut ops, batch, false, &mut stats)?
+            .map(|log| self.log_batch(&mut ops, log))
+            .transpose()?;
+        let prepare_time = started.elapsed();
+        stats.add_ops(&ops);
 
         // Submit write batch
-        self.db.write(ops)?;
+        let started = Instant::now();
+        self.write_operations(ops)?;
+        drop(fencing_token);
+        self.write_metrics
+            .record(account_id, &stats, prepare_time, started.elapsed());
 
         Ok(changes)
     }
@@ -98,7 +159,8 @@ where
         ops: &mut Vec<WriteOperation>,
         batch: WriteBatch,
         tombstone_deletions: bool,
-    ) -> crate::Result<Option<Changes>> {
+        stats: &mut WriteStats,
+    ) -> crate::Result<Option<PendingLog>> {
         let mut bitmap_list = AHashMap::default();
         let mut tombstones = Vec::new();
 
@@ -186,10 +248,11 @@ where
                                 .insert(document.document_id, !is_clear);
                         }
                         <u64 as Options>::F_TOKENIZE => {
-                            for token in Tokenizer::new(
+                            for token in Tokenizer::with_config(
                                 &field.value.text,
                                 field.value.language,
                                 MAX_TOKEN_LENGTH,
+                                &self.config.nlp,
                             ) {
                                 bitmap_list
                                     .entry(BitmapKey::serialize_term(
@@ -212,8 +275,12 @@ where
                             };
                             let mut terms = Vec::new();
 
-                            for token in Stemmer::new(&field.value.text, language, MAX_TOKEN_LENGTH)
-                            {
+                            for token in Stemmer::with_config(
+                                &field.value.text,
+                                language,
+                                MAX_TOKEN_LENGTH,
+                                &self.config.nlp,
+                            ) {
                                 bitmap_list
                                     .entry(BitmapKey::serialize_term(
                                         batch.account_id,
@@ -238,6 +305,36 @@ where
                                         .insert(document.document_id, !is_clear);
                                 }
 
+                                // Index synonyms as if they were part of the text
+                                if self.config.synonyms.expand_index() {
+                                    for synonym in self.config.synonyms.get(&token.word) {
+                                        for synonym in Stemmer::with_config(
+                                            synonym,
+                                            language,
+                                            MAX_TOKEN_LENGTH,
+                                            &self.config.nlp,
+                                        ) {
+                                            for (word, is_exact) in [
+                                                (Some(synonym.word), true),
+                                                (synonym.stemmed_word, false),
+                                            ] {
+                                                if let Some(word) = word {
+                                                    bitmap_list
+                                                        .entry(BitmapKey::serialize_term(
+                                                            batch.account_id,
+                                                            document.collection,
+                                                            field.field,
+                                                            &word,
+                                                            is_exact,
+                                                        ))
+                                                        .or_insert_with(AHashMap::default)
+                                                        .insert(document.document_id, !is_clear);
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+
                                 terms.push(term_index.add_stemmed_token(token));
                             }
 
@@ -454,6 +551,21 @@ where
                 });
             }
 
+            // Process private annotations
+            for (principal, value, options) in document.annotations {
+                let key = ValueKey::serialize_annotation(
+                    batch.account_id,
+                    document.collection,
+                    document.document_id,
+                    principal,
+                );
+                ops.push(if !options.is_clear() {
+                    WriteOperation::set(ColumnFamily::Values, key, value)
+                } else {
+                    WriteOperation::delete(ColumnFamily::Values, key)
+                });
+            }
+
             // Process ACLs
             for (acl, options) in document.acls {
                 let key = ValueKey::serialize_acl(
@@ -479,6 +591,9 @@ where
 
         // Update bitmaps
         for (key, doc_id_list) in bitmap_list {
+            if BitmapKey::is_term(&key) {
+                stats.term_postings += doc_id_list.len() as u64;
+            }
             ops.push(WriteOperation::merge(
                 ColumnFamily::Bitmaps,
                 key,
@@ -486,53 +601,70 @@ where
             ));
         }
 
-        // Serialize Raft and change log
-        if !batch.changes.is_empty() {
-            let raft_id = self.assign_raft_id();
-            let mut collections = Bitmap::default();
-
-            for (collection, log_entry) in batch.changes {
-                collections.insert(collection);
-
-                ops.push(WriteOperation::set(
-                    ColumnFamily::Logs,
-                    LogKey::serialize_change(batch.account_id, collection, raft_id.index),
-                    log_entry.serialize(),
-                ));
+        Ok(if !batch.changes.is_empty() {
+            PendingLog {
+                account_id: batch.account_id,
+                changes: batch.changes,
+                tombstones,
             }
+            .into()
+        } else {
+            None
+        })
+    }
+
+    fn log_batch(&self, ops: &mut Vec<WriteOperation>, log: PendingLog) -> crate::Result<Changes> {
+        // Serialize Raft and change log
+        let raft_id = self.assign_raft_id();
+        let mut collections = Bitmap::default();
+
+        for (collection, log_entry) in log.changes {
+            collections.insert(collection);
 
-            // Serialize raft entry
-            let mut bytes = Vec::with_capacity(
-                std::mem::size_of::<AccountId>() + std::mem::size_of::<u64>() + 1,
-            );
-            bytes.push(Change::ENTRY);
-            bytes.extend_from_slice(&batch.account_id.to_le_bytes());
-            bytes.extend_from_slice(&collections.to_le_bytes());
             ops.push(WriteOperation::set(
                 ColumnFamily::Logs,
-                LogKey::serialize_raft(&raft_id),
-                bytes,
+                LogKey::serialize_change(log.account_id, collection, raft_id.index),
+                log_entry.serialize(),
             ));
+        }
 
-            // Serialize raft tombstones
-            if !tombstones.is_empty() {
-                ops.push(WriteOperation::set(
-                    ColumnFamily::Logs,
-                    LogKey::serialize_tombstone(raft_id.index, batch.account_id),
-                    bincode::serialize(&tombstones).map_err(|_| {
-                        StoreError::SerializeError("Failed to serialize tombstones".to_string())
-                    })?,
-                ));
-            }
-
-            Ok(Changes {
-                collections,
-                change_id: raft_id.index,
-            }
-            .into())
-        } else {
-            Ok(None)
+        // Serialize raft entry
+        let mut bytes = Vec::with_capacity(
+            std::mem::size_of::<AccountId>() + std::mem::size_of::<u64>() + 1 + Entry::CHECKSUM_LEN,
+        );
+        bytes.push(Change::ENTRY);
+        bytes.extend_from_slice(&log.account_id.to_le_bytes());
+        bytes.extend_from_slice(&collections.to_le_bytes());
+        ops.push(WriteOperation::set(
+            ColumnFamily::Logs,
+            LogKey::serialize_raft(&raft_id),
+            Entry::seal(&raft_id, bytes),
+        ));
+
+        // Serialize raft tombstones
+        if !log.tombstones.is_empty() {
+            ops.push(WriteOperation::set(
+                ColumnFamily::Logs,
+                LogKey::serialize_tombstone(raft_id.index, log.account_id),
+                bincode::serialize(&log.tombstones).map_err(|_| {
+                    StoreError::SerializeError("Failed to serialize tombstones".to_string())
+                })?,
+            ));
         }
+
+        Ok(Changes {
+            collections,
+            change_id: raft_id.index,
+        })
+    }
+
+    /// Writes a list of operations to the backend, evicting from the
+    /// bitmap cache the entries they modify.
+    pub fn write_operations(&self, ops: Vec<WriteOperation>) -> crate::Result<()> {
+        let invalidations = self.bitmap_cache.invalidations(&ops);
+        self.db.write(ops)?;
+        self.bitmap_cache.invalidate(invalidations);
+        Ok(())
     }
 
     pub fn untag(
@@ -543,10 +675,10 @@ where
         tag: Tag,
         untag_document_ids: impl Iterator<Item = DocumentId>,
     ) -> crate::Result<()> {
-        self.db.merge(
+        self.write_operations(vec![WriteOperation::merge(
             ColumnFamily::Bitmaps,
-            &BitmapKey::serialize_tag(account_id, collection, field, &tag),
-            &clear_bits(untag_document_ids),
-        )
+            BitmapKey::serialize_tag(account_id, collection, field, &tag),
+            clear_bits(untag_document_ids),
+        )])
     }
 }
@@ -21,17 +21,29 @@
  * for more details.
 */
 
-use std::{convert::TryInto, path::PathBuf, sync::Arc};
+use std::{
+    convert::TryInto,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use rocksdb::{
-    BoundColumnFamily, ColumnFamilyDescriptor, DBIteratorWithThreadMode, DBWithThreadMode,
-    MergeOperands, MultiThreaded, Options,
+    checkpoint::Checkpoint, BoundColumnFamily, ColumnFamilyDescriptor, DBIteratorWithThreadMode,
+    DBRawIteratorWithThreadMode, DBWithThreadMode, MergeOperands, MultiThreaded, Options,
 };
 use store::{
-    config::env_settings::EnvSettings, core::error::StoreError, roaring::RoaringBitmap,
-    serialize::StoreDeserialize, write::operation::WriteOperation, Result, Store,
+    config::{env_settings::EnvSettings, settings::Setting},
+    core::{error::StoreError, timing::ReadTimer},
+    roaring::RoaringBitmap,
+    serialize::StoreDeserialize,
+    write::operation::WriteOperation,
+    Result, Store, StoreCursor,
 };
 
+pub const SETTINGS: &[Setting] = &[Setting::bytes("blob-min-size")
+    .default("16384")
+    .describe("Blobs below this size are stored inline rather than in blob files")];
+
 pub struct RocksDB {
     db: DBWithThreadMode<MultiThreaded>,
 }
@@ -40,12 +52,53 @@ pub struct RocksDBIterator<'x> {
     it: DBIteratorWithThreadMode<'x, DBWithThreadMode<MultiThreaded>>,
 }
 
+pub struct RocksDBCursor<'x> {
+    it: DBRawIteratorWithThreadMode<'x, DBWithThreadMode<MultiThreaded>>,
+    is_forward: bool,
+    is_positioned: bool,
+}
+
+impl StoreCursor for RocksDBCursor<'_> {
+    #[inline(always)]
+    fn advance(&mut self) -> Result<bool> {
+        let _timer = ReadTimer::store_step();
+        // The first call returns the entry found by the initial seek.
+        if self.is_positioned {
+            self.is_positioned = false;
+        } else if self.is_forward {
+            self.it.next();
+        } else {
+            self.it.prev();
+        }
+        if self.it.valid() {
+            Ok(true)
+        } else {
+            // An invalid iterator is either exhausted or failed to read
+            self.it
+                .status()
+                .map(|_| false)
+                .map_err(|err| StoreError::InternalError(format!("cursor failed: {}", err)))
+        }
+    }
+
+    #[inline(always)]
+    fn key(&self) -> &[u8] {
+        self.it.key().unwrap_or_default()
+    }
+
+    #[inline(always)]
+    fn value(&self) -> &[u8] {
+        self.it.value().unwrap_or_default()
+    }
+}
+
 impl Iterator for RocksDBIterator<'_> {
     type Item = (Box<[u8]>, Box<[u8]>);
 
     #[allow(clippy::while_let_on_iterator)]
     #[inline(always)]
     fn next(&mut self) -> Option<Self::Item> {
+        let _timer = ReadTimer::store_step();
         while let Some(result) = self.it.next() {
             if let Ok(item) = result {
                 return Some(item);
@@ -57,6 +110,7 @@ impl Iterator for RocksDBIterator<'_> {
 
 impl<'x> Store<'x> for RocksDB {
     type Iterator = RocksDBIterator<'x>;
+    type Cursor = RocksDBCursor<'x>;
 
     #[inline(always)]
     fn delete(&self, cf: store::ColumnFamily, key: &[u8]) -> Result<()> {
@@ -77,6 +131,7 @@ impl<'x> Store<'x> for RocksDB {
     where
         U: StoreDeserialize,
     {
+        let _timer = ReadTimer::store();
         if let Some(bytes) = self
             .db
             .get_pinned_cf(&self.cf_handle(cf)?, &key)
@@ -133,6 +188,19 @@ impl<'x> Store<'x> for RocksDB {
                         key,
                     );
                 }
+                WriteOperation::DeleteRange { cf, from, to } => {
+                    rocks_batch.delete_range_cf(
+                        match cf {
+                            store::ColumnFamily::Bitmaps => &cf_bitmaps,
+                            store::ColumnFamily::Values => &cf_values,
+                            store::ColumnFamily::Indexes => &cf_indexes,
+                            store::ColumnFamily::Blobs => &cf_blobs,
+                            store::ColumnFamily::Logs => &cf_logs,
+                        },
+                        from,
+                        to,
+                    );
+                }
                 WriteOperation::Merge { cf, key, value } => {
                     rocks_batch.merge_cf(
                         match cf {
@@ -155,6 +223,7 @@ impl<'x> Store<'x> for RocksDB {
 
     #[inline(always)]
     fn exists(&self, cf: store::ColumnFamily, key: &[u8]) -> Result<bool> {
+        let _timer = ReadTimer::store();
         Ok(self
             .db
             .get_pinned_cf(&self.cf_handle(cf)?, &key)
@@ -168,6 +237,7 @@ impl<'x> Store<'x> for RocksDB {
         T: StoreDeserialize,
         U: AsRef<[u8]>,
     {
+        let _timer = ReadTimer::store();
         let cf_handle = self.cf_handle(cf)?;
         let mut results = Vec::with_capacity(keys.len());
         for value in self
@@ -199,6 +269,7 @@ impl<'x> Store<'x> for RocksDB {
         start: &[u8],
         direction: store::Direction,
     ) -> Result<Self::Iterator> {
+        let _timer = ReadTimer::store();
         Ok(RocksDBIterator {
             it: self.db.iterator_cf(
                 &self.cf_handle(cf)?,
@@ -213,19 +284,72 @@ impl<'x> Store<'x> for RocksDB {
         })
     }
 
+    fn cursor<'y: 'x>(
+        &'y self,
+        cf: store::ColumnFamily,
+        start: &[u8],
+        direction: store::Direction,
+    ) -> Result<Self::Cursor> {
+        let _timer = ReadTimer::store();
+        let mut it = self.db.raw_iterator_cf(&self.cf_handle(cf)?);
+        let is_forward = match direction {
+            store::Direction::Forward => {
+                it.seek(start);
+                true
+            }
+            store::Direction::Backward => {
+                it.seek_for_prev(start);
+                false
+            }
+        };
+        Ok(RocksDBCursor {
+            it,
+            is_forward,
+            is_positioned: true,
+        })
+    }
+
     fn compact(&self, cf: store::ColumnFamily) -> Result<()> {
         self.db
             .compact_range_cf(&self.cf_handle(cf)?, None::<&[u8]>, None::<&[u8]>);
         Ok(())
     }
 
+    fn compact_range(&self, cf: store::ColumnFamily, from: &[u8], to: &[u8]) -> Result<()> {
+        self.db
+            .compact_range_cf(&self.cf_handle(cf)?, Some(from), Some(to));
+        Ok(())
+    }
+
+    fn disk_usage(&self) -> Result<u64> {
+        let mut size = 0;
+        for cf in [
+            store::ColumnFamily::Bitmaps,
+            store::ColumnFamily::Values,
+            store::ColumnFamily::Indexes,
+            store::ColumnFamily::Blobs,
+            store::ColumnFamily::Logs,
+        ] {
+            size += self
+                .db
+                .property_int_value_cf(&self.cf_handle(cf)?, "rocksdb.live-sst-files-size")
+                .map_err(|err| {
+                    StoreError::InternalError(format!("Failed to obtain disk usage: {}", err))
+                })?
+                .unwrap_or(0);
+        }
+        Ok(size)
+    }
+
+    fn checkpoint(&self, path: &Path) -> Result<()> {
+        Checkpoint::new(&self.db)
+            .and_then(|checkpoint| checkpoint.create_checkpoint(path))
+            .map_err(|err| StoreError::InternalError(format!("checkpoint failed: {}", err)))
+    }
+
     fn open(settings: &EnvSettings) -> Result<Self> {
         // Create the database directory if it doesn't exist
-        let path = PathBuf::from(
-            &settings
-                .get("db-path")
-                .unwrap_or_else(|| "/usr/local/stalwart-jmap/data".to_string()),
-        );
+        let path = PathBuf::from(settings.value::<String>(store::SETTINGS, "db-path"));
         let mut idx_path = path;
         idx_path.push("idx");
         std::fs::create_dir_all(&idx_path).map_err(|err| {
@@ -262,7 +386,7 @@ impl<'x> Store<'x> for RocksDB {
         let cf_blobs = {
             let mut cf_opts = Options::default();
             cf_opts.set_enable_blob_files(true);
-            cf_opts.set_min_blob_size(settings.parse("blob-min-size").unwrap_or(16384));
+            cf_opts.set_min_blob_size(settings.value(SETTINGS, "blob-min-size"));
             ColumnFamilyDescriptor::new("blobs", cf_opts)
         };
 
@@ -27,6 +27,7 @@ use jmap::types::date::JMAPDate;
 use jmap::types::jmap::JMAPId;
 use jmap::types::json_pointer::JSONPointer;
 use jmap::types::state::JMAPState;
+use jmap_mail::mail::limits::MimeLimits;
 use stalwart_jmap::lmtp::request::RequestParser;
 use libfuzzer_sys::fuzz_target;
 use store::serialize::{
@@ -37,6 +38,14 @@ use store::serialize::{
 static DATE_ALPHABET: &[u8] = b"0123456789TZ+-:.";
 static POINTER_ALPHABET: &[u8] = b"0123456789abcdefghijklm~*/";
 static LMTP_ALPHABET: &[u8] = b" MAILFROM:<>=";
+static MIME_ALPHABET: &[u8] = b"-\r\n\t :;=\"abcontent-yp/multipartmessage/rfc822boundary";
+
+const MIME_LIMITS: MimeLimits = MimeLimits {
+    max_depth: 4,
+    max_parts: 16,
+    max_headers: 8,
+    max_header_length: 64,
+};
 
 fuzz_target!(|data: &[u8]| {
     // Leb128 decoding
@@ -77,6 +86,16 @@ fuzz_target!(|data: &[u8]| {
     RequestParser::new(1024, 1024)
         .parse(&mut into_alphabet(data, LMTP_ALPHABET).iter())
         .ok();
+
+    // Bounded MIME parsing
+    for raw_message in [data.to_vec(), into_alphabet(data, MIME_ALPHABET)] {
+        if let Some(truncation) = MIME_LIMITS.scan(&raw_message) {
+            assert!(truncation.offset <= raw_message.len());
+        }
+        if let Some(message) = MIME_LIMITS.parse(&raw_message) {
+            assert_eq!(message.raw_message.len(), raw_message.len());
+        }
+    }
 });
 
 fn into_alphabet(data: &[u8], alphabet: &[u8]) -> Vec<u8> {
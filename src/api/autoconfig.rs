@@ -0,0 +1,354 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::fmt::Write;
+
+use actix_web::{http::StatusCode, web, HttpResponse};
+use jmap::sanitize_email;
+use jmap_sharing::principal::account::JMAPAccountStore;
+use store::{
+    config::{env_settings::EnvSettings, settings::Setting},
+    tracing::error,
+    Store,
+};
+
+use crate::{server::failed_to, JMAPServer};
+
+use super::RequestError;
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::url("autoconfig-jmap-url").describe("Defaults to jmap-url"),
+    Setting::text("autoconfig-imap-host"),
+    Setting::integer("autoconfig-imap-port")
+        .max(65535)
+        .default("993"),
+    Setting::one_of("autoconfig-imap-security", &["tls", "starttls", "plain"]).default("tls"),
+    Setting::text("autoconfig-smtp-host"),
+    Setting::integer("autoconfig-smtp-port")
+        .max(65535)
+        .default("465"),
+    Setting::one_of("autoconfig-smtp-security", &["tls", "starttls", "plain"]).default("tls"),
+];
+
+const MAX_AUTODISCOVER_REQUEST: usize = 10 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketType {
+    Tls,
+    StartTls,
+    Plain,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailServer {
+    pub host: String,
+    pub port: u16,
+    pub socket: SocketType,
+}
+
+/// Client onboarding settings. Hostnames and the JMAP url may contain a
+/// '{domain}' placeholder, which is replaced with the domain of the address
+/// being configured.
+#[derive(Debug, Clone, Default)]
+pub struct AutoConfig {
+    pub jmap_url: String,
+    pub imap: Option<MailServer>,
+    pub smtp: Option<MailServer>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct Params {
+    emailaddress: Option<String>,
+}
+
+impl AutoConfig {
+    pub fn parse(settings: &EnvSettings) -> Self {
+        AutoConfig {
+            jmap_url: settings
+                .get("autoconfig-jmap-url")
+                .or_else(|| settings.get("jmap-url"))
+                .unwrap_or_default()
+                .trim_end_matches('/')
+                .to_string(),
+            imap: MailServer::parse(settings, "imap"),
+            smtp: MailServer::parse(settings, "smtp"),
+        }
+    }
+
+    pub fn mozilla_autoconfig(&self, email: &str, domain: &str) -> String {
+        let mut xml = String::with_capacity(1024);
+        let _ = write!(
+            xml,
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<clientConfig version=\"1.1\">\n",
+                "  <emailProvider id=\"{domain}\">\n",
+                "    <domain>{domain}</domain>\n",
+                "    <displayName>{email}</displayName>\n",
+                "    <displayShortName>{domain}</displayShortName>\n",
+                "    <incomingServer type=\"jmap\">\n",
+                "      <url>{jmap_url}/.well-known/jmap</url>\n",
+                "      <authentication>http-basic</authentication>\n",
+                "      <username>%EMAILADDRESS%</username>\n",
+                "    </incomingServer>\n",
+            ),
+            domain = xml_escape(domain),
+            email = xml_escape(email),
+            jmap_url = xml_escape(&self.jmap_url.replace("{domain}", domain)),
+        );
+        for (server, tag, server_type) in [
+            (&self.imap, "incomingServer", "imap"),
+            (&self.smtp, "outgoingServer", "smtp"),
+        ] {
+            if let Some(server) = server {
+                let _ = write!(
+                    xml,
+                    concat!(
+                        "    <{tag} type=\"{server_type}\">\n",
+                        "      <hostname>{host}</hostname>\n",
+                        "      <port>{port}</port>\n",
+                        "      <socketType>{socket}</socketType>\n",
+                        "      <authentication>password-cleartext</authentication>\n",
+                        "      <username>%EMAILADDRESS%</username>\n",
+                        "    </{tag}>\n",
+                    ),
+                    tag = tag,
+                    server_type = server_type,
+                    host = xml_escape(&server.host.replace("{domain}", domain)),
+                    port = server.port,
+                    socket = match server.socket {
+                        SocketType::Tls => "SSL",
+                        SocketType::StartTls => "STARTTLS",
+                        SocketType::Plain => "plain",
+                    },
+                );
+            }
+        }
+        xml.push_str("  </emailProvider>\n</clientConfig>\n");
+        xml
+    }
+
+    pub fn ms_autodiscover(&self, email: &str, domain: &str) -> String {
+        let mut xml = String::with_capacity(1024);
+        xml.push_str(concat!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+            "<Autodiscover xmlns=\"http://schemas.microsoft.com/exchange/autodiscover/responseschema/2006\">\n",
+            "  <Response xmlns=\"http://schemas.microsoft.com/exchange/autodiscover/outlook/responseschema/2006a\">\n",
+            "    <Account>\n",
+            "      <AccountType>email</AccountType>\n",
+            "      <Action>settings</Action>\n",
+        ));
+        for (server, server_type) in [(&self.imap, "IMAP"), (&self.smtp, "SMTP")] {
+            if let Some(server) = server {
+                let _ = write!(
+                    xml,
+                    concat!(
+                        "      <Protocol>\n",
+                        "        <Type>{server_type}</Type>\n",
+                        "        <Server>{host}</Server>\n",
+                        "        <Port>{port}</Port>\n",
+                        "        <LoginName>{email}</LoginName>\n",
+                        "        <DomainRequired>off</DomainRequired>\n",
+                        "        <SPA>off</SPA>\n",
+                        "        <SSL>{ssl}</SSL>\n",
+                        "{encryption}",
+                        "        <AuthRequired>on</AuthRequired>\n",
+                        "      </Protocol>\n",
+                    ),
+                    server_type = server_type,
+                    host = xml_escape(&server.host.replace("{domain}", domain)),
+                    port = server.port,
+                    email = xml_escape(email),
+                    ssl = if server.socket != SocketType::Plain {
+                        "on"
+                    } else {
+                        "off"
+                    },
+                    encryption = match server.socket {
+                        SocketType::Tls => "        <Encryption>SSL</Encryption>\n",
+                        SocketType::StartTls => "        <Encryption>TLS</Encryption>\n",
+                        SocketType::Plain => "",
+                    },
+                );
+            }
+        }
+        xml.push_str("    </Account>\n  </Response>\n</Autodiscover>\n");
+        xml
+    }
+}
+
+impl MailServer {
+    fn parse(settings: &EnvSettings, protocol: &str) -> Option<Self> {
+        let host = settings.get(&format!("autoconfig-{}-host", protocol))?;
+        let port = settings.value(SETTINGS, &format!("autoconfig-{}-port", protocol));
+        let socket = match settings
+            .value::<String>(SETTINGS, &format!("autoconfig-{}-security", protocol))
+            .as_str()
+        {
+            "tls" => SocketType::Tls,
+            "starttls" => SocketType::StartTls,
+            "plain" => SocketType::Plain,
+            other => failed_to(&format!(
+                "parse 'autoconfig-{}-security', invalid value '{}'.",
+                protocol, other
+            )),
+        };
+        Some(MailServer { host, port, socket })
+    }
+}
+
+/// Obtains the address from an Outlook autodiscover request.
+pub fn parse_autodiscover_request(body: &[u8]) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?;
+    let start = body.find("<EMailAddress>")? + "<EMailAddress>".len();
+    let end = start + body[start..].find("</EMailAddress>")?;
+    sanitize_email(body[start..end].trim())
+}
+
+fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+pub async fn handle_mozilla_autoconfig<T>(
+    params: web::Query<Params>,
+    core: web::Data<JMAPServer<T>>,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let email = params
+        .into_inner()
+        .emailaddress
+        .and_then(|email| sanitize_email(&email))
+        .ok_or_else(|| {
+            RequestError::invalid_parameters().with_detail("Missing or invalid 'emailaddress'.")
+        })?;
+    let domain = hosted_domain(&core, &email).await?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("application/xml; charset=utf-8")
+        .body(core.autoconfig.mozilla_autoconfig(&email, &domain)))
+}
+
+pub async fn handle_ms_autodiscover<T>(
+    bytes: web::Bytes,
+    core: web::Data<JMAPServer<T>>,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    if bytes.len() > MAX_AUTODISCOVER_REQUEST {
+        return Err(RequestError::limit(super::RequestLimitError::Size));
+    }
+    let email = parse_autodiscover_request(&bytes).ok_or_else(|| {
+        RequestError::invalid_parameters().with_detail("Missing or invalid 'EMailAddress'.")
+    })?;
+    let domain = hosted_domain(&core, &email).await?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("application/xml; charset=utf-8")
+        .body(core.autoconfig.ms_autodiscover(&email, &domain)))
+}
+
+// Only hand out settings for domains served by this server.
+async fn hosted_domain<T>(
+    core: &web::Data<JMAPServer<T>>,
+    email: &str,
+) -> Result<String, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let domain = email.rsplit_once('@').map(|(_, d)| d).unwrap_or_default();
+    let store = core.store.clone();
+    let domain_ = domain.to_string();
+    match core
+        .spawn_worker(move || store.is_local_domain(&domain_))
+        .await
+    {
+        Ok(true) => Ok(domain.to_string()),
+        Ok(false) => Err(RequestError::not_found()),
+        Err(err) => {
+            error!("Failed to look up domain {}: {:?}", domain, err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_autodiscover_request, AutoConfig, MailServer, SocketType};
+
+    #[test]
+    fn autoconfig() {
+        let config = AutoConfig {
+            jmap_url: "https://mail.{domain}".to_string(),
+            imap: MailServer {
+                host: "imap.{domain}".to_string(),
+                port: 993,
+                socket: SocketType::Tls,
+            }
+            .into(),
+            smtp: MailServer {
+                host: "smtp.example.org".to_string(),
+                port: 587,
+                socket: SocketType::StartTls,
+            }
+            .into(),
+        };
+
+        let xml = config.mozilla_autoconfig("jdoe@example.com", "example.com");
+        assert!(xml.contains("<url>https://mail.example.com/.well-known/jmap</url>"));
+        assert!(xml.contains("<hostname>imap.example.com</hostname>"));
+        assert!(xml.contains("<socketType>STARTTLS</socketType>"));
+
+        let xml = config.ms_autodiscover("jdoe@example.com", "example.com");
+        assert!(xml.contains("<Server>imap.example.com</Server>"));
+        assert!(xml.contains("<Encryption>TLS</Encryption>"));
+        assert!(xml.contains("<LoginName>jdoe@example.com</LoginName>"));
+
+        assert_eq!(
+            parse_autodiscover_request(
+                concat!(
+                    "<?xml version=\"1.0\" encoding=\"utf-8\"?><Autodiscover xmlns=\"http://sc",
+                    "hemas.microsoft.com/exchange/autodiscover/outlook/requestschema/2006\">",
+                    "<Request><EMailAddress> JDoe@Example.com </EMailAddress></Request>",
+                    "</Autodiscover>"
+                )
+                .as_bytes()
+            ),
+            Some("jdoe@example.com".to_string())
+        );
+        assert_eq!(parse_autodiscover_request(b"<Request></Request>"), None);
+    }
+}
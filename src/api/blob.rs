@@ -34,7 +34,7 @@ use jmap::request::ACLEnforce;
 use jmap::types::blob::JMAPBlob;
 use jmap::types::jmap::JMAPId;
 use jmap::SUPERUSER_ID;
-use jmap_mail::mail::get::{BlobResult, JMAPGetMail};
+use jmap_mail::mail::get::{BlobResult, CidResult, JMAPGetMail};
 use jmap_mail::mail::sharing::JMAPShareMail;
 use jmap_sharing::principal::account::JMAPAccountStore;
 use reqwest::header::CONTENT_TYPE;
@@ -91,7 +91,7 @@ where
             ))
             .insert_header(("Cache-Control", "private, immutable, max-age=31536000"))
             .body(bytes)),
-        Ok(BlobResult::NotFound) => Err(RequestError::not_found()),
+        Ok(BlobResult::NotFound) => Err(RequestError::blob_not_found()),
         Ok(BlobResult::Unauthorized) => Err(RequestError::forbidden()),
         Err(err) => {
             error!("Blob download failed: {:?}", err);
@@ -100,6 +100,72 @@ where
     }
 }
 
+pub async fn handle_jmap_download_cid<T>(
+    path: web::Path<(JMAPId, JMAPId, String)>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let (id, email_id, cid) = path.into_inner();
+    let account_id = id.get_document_id();
+
+    let store = core.store.clone();
+    match core
+        .spawn_worker(move || {
+            store.mail_cid_get(
+                account_id,
+                &store.get_acl_token(session.account_id())?,
+                email_id.get_document_id(),
+                &cid,
+            )
+        })
+        .await
+    {
+        Ok(CidResult::Part {
+            bytes,
+            content_type,
+            name,
+        }) => {
+            // Only raster images are rendered inline, anything else is served as an attachment
+            let content_type = content_type
+                .filter(|ct| is_inline_safe(ct))
+                .map(|ct| (ct, "inline"))
+                .unwrap_or_else(|| ("application/octet-stream".to_string(), "attachment"));
+            Ok(HttpResponse::build(StatusCode::OK)
+                .insert_header(("Content-Type", content_type.0))
+                .insert_header((
+                    "Content-Disposition",
+                    if let Some(name) = name {
+                        format!(
+                            "{}; filename=\"{}\"",
+                            content_type.1,
+                            name.replace('\"', "\\\"")
+                        )
+                    } else {
+                        content_type.1.to_string()
+                    },
+                ))
+                .insert_header(("X-Content-Type-Options", "nosniff"))
+                .insert_header(("Content-Security-Policy", "default-src 'none'; sandbox"))
+                .insert_header(("Cache-Control", "private, immutable, max-age=31536000"))
+                .body(bytes))
+        }
+        Ok(CidResult::NotFound) => Err(RequestError::not_found()),
+        Ok(CidResult::Unauthorized) => Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Content-ID download failed: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+fn is_inline_safe(content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    content_type.starts_with("image/") && !content_type.starts_with("image/svg")
+}
+
 #[derive(Debug, serde::Serialize)]
 struct UploadResponse {
     #[serde(rename(serialize = "accountId"))]
@@ -148,6 +214,11 @@ where
         return Err(RequestError::limit(RequestLimitError::Size));
     }
 
+    // Read-only impersonated sessions may not upload blobs
+    if session.scope().map_or(false, |scope| scope.read_only) {
+        return Err(RequestError::forbidden());
+    }
+
     let store = core.store.clone();
     let size = bytes.len();
     match core
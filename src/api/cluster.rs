@@ -0,0 +1,109 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use store::{log::raft::LogIndex, tracing::error, Store};
+
+use crate::{authorization::Session, JMAPServer};
+
+use super::{migration::is_superuser, RequestError};
+
+#[derive(serde::Deserialize)]
+pub struct VerifyParams {
+    range: Option<u64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResyncParams {
+    from: LogIndex,
+}
+
+pub async fn handle_admin_cluster_status<T>(
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_superuser(&core, &session).await?;
+
+    match core.node_status().await {
+        Some(status) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(status)),
+        None => Err(RequestError::unavailable()),
+    }
+}
+
+pub async fn handle_admin_cluster_verify<T>(
+    params: web::Query<VerifyParams>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_superuser(&core, &session).await?;
+
+    match core
+        .verify_raft_log(params.range.filter(|r| *r > 0).unwrap_or(1000))
+        .await
+    {
+        Ok(verification) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(verification)),
+        Err(err) => {
+            error!("Failed to verify raft log: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+pub async fn handle_admin_cluster_resync<T>(
+    params: web::Query<ResyncParams>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_superuser(&core, &session).await?;
+
+    // Only followers can discard their log and pull it again from the leader
+    if !core.is_in_cluster() || core.is_leader() {
+        return Err(RequestError::unavailable());
+    }
+
+    match core.resync_raft_log(params.from).await {
+        Ok(()) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .body("{}")),
+        Err(err) => {
+            error!("Failed to resync raft log: {}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
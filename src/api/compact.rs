@@ -0,0 +1,152 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use jmap::{request::ACLEnforce, types::jmap::JMAPId, SUPERUSER_ID};
+use jmap_sharing::principal::account::JMAPAccountStore;
+use store::{
+    roaring::RoaringBitmap, tracing::error, write::compact::CompactReport, AccountId, ColumnFamily,
+    Store,
+};
+
+use crate::{authorization::Session, services::housekeeper::TASK_COMPACT_DB, JMAPServer};
+
+use super::RequestError;
+
+pub async fn handle_admin_compact<T>(
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    compact(core, session, None).await
+}
+
+pub async fn handle_admin_compact_account<T>(
+    path: web::Path<(JMAPId,)>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    compact(core, session, Some(path.into_inner().0.get_document_id())).await
+}
+
+async fn compact<T>(
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+    account_id: Option<AccountId>,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Only administrators may compact the database on demand
+    let store = core.store.clone();
+    let session_id = session.account_id();
+    match core
+        .spawn_worker(move || Ok(store.get_acl_token(session_id)?.is_member(SUPERUSER_ID)))
+        .await
+    {
+        Ok(true) => (),
+        Ok(false) => return Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Failed to obtain ACL token: {:?}", err);
+            return Err(RequestError::internal_server_error());
+        }
+    }
+
+    // Compaction runs in the background on the local replica, one run at a time
+    // per node. Its progress is reported by the maintenance endpoint.
+    if !core.maintenance.start(TASK_COMPACT_DB) {
+        return Err(RequestError::unavailable().with_detail("Compaction is already running."));
+    }
+    let task_core = core.clone();
+    tokio::spawn(async move {
+        let result = compact_db(
+            task_core.clone(),
+            account_id.map(|account_id| {
+                let mut account_ids = RoaringBitmap::new();
+                account_ids.insert(account_id);
+                account_ids
+            }),
+            &[
+                ColumnFamily::Bitmaps,
+                ColumnFamily::Values,
+                ColumnFamily::Indexes,
+                ColumnFamily::Blobs,
+                ColumnFamily::Logs,
+            ],
+        )
+        .await;
+        task_core
+            .maintenance
+            .finish(TASK_COMPACT_DB, result.err().map(|err| err.to_string()));
+    });
+
+    Ok(HttpResponse::build(StatusCode::ACCEPTED)
+        .insert_header(ContentType::json())
+        .json(core.maintenance.status(TASK_COMPACT_DB)))
+}
+
+/// Compacts the given accounts, or the column families if no accounts are
+/// given, on behalf of the caller holding the compact-db task. The progress
+/// and the space reclaimed are recorded in the task status.
+pub async fn compact_db<T>(
+    core: web::Data<JMAPServer<T>>,
+    account_ids: Option<RoaringBitmap>,
+    cfs: &'static [ColumnFamily],
+) -> store::Result<CompactReport>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let task_core = core.clone();
+    let result = core
+        .spawn_worker(move || {
+            let progress = |done, total| {
+                task_core
+                    .maintenance
+                    .set_progress(TASK_COMPACT_DB, done, total)
+            };
+            if let Some(account_ids) = account_ids {
+                store.compact_accounts(&account_ids, progress)
+            } else {
+                store.compact_column_families(cfs, progress)
+            }
+        })
+        .await;
+
+    match &result {
+        Ok(report) => core.maintenance.set_result(
+            TASK_COMPACT_DB,
+            format!("Reclaimed {} bytes.", report.reclaimed_bytes),
+        ),
+        Err(err) => error!("Failed to compact database: {:?}", err),
+    }
+    result
+}
@@ -0,0 +1,81 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use jmap::{request::ACLEnforce, SUPERUSER_ID};
+use store::{
+    config::{env_settings::EnvSettings, settings::EffectiveSetting},
+    tracing::error,
+    Store,
+};
+
+use crate::{authorization::Session, server::settings::schema, JMAPServer};
+
+use super::RequestError;
+
+#[derive(Debug, serde::Serialize)]
+pub struct EffectiveConfig {
+    pub settings: Vec<EffectiveSetting>,
+    #[serde(rename = "unknownKeys")]
+    pub unknown_keys: Vec<String>,
+}
+
+impl EffectiveConfig {
+    pub fn new(settings: &EnvSettings) -> Self {
+        let schema = schema();
+        EffectiveConfig {
+            settings: settings.effective(&schema),
+            unknown_keys: settings.unknown_keys(&schema),
+        }
+    }
+}
+
+pub async fn handle_admin_config<T>(
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Only administrators may inspect the configuration
+    let store = core.store.clone();
+    let session_id = session.account_id();
+    match core
+        .spawn_worker(move || Ok(store.get_acl_token(session_id)?.is_member(SUPERUSER_ID)))
+        .await
+    {
+        Ok(true) => (),
+        Ok(false) => return Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Failed to obtain ACL token: {:?}", err);
+            return Err(RequestError::internal_server_error());
+        }
+    }
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .insert_header(ContentType::json())
+        .json(&core.config))
+}
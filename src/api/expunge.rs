@@ -0,0 +1,110 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use jmap::{request::ACLEnforce, types::jmap::JMAPId, SUPERUSER_ID};
+use store::{tracing::error, Store};
+
+use crate::{authorization::Session, services::housekeeper::expunge_account, JMAPServer};
+
+use super::RequestError;
+
+#[derive(serde::Deserialize)]
+pub struct Params {
+    #[serde(rename = "dryRun")]
+    dry_run: Option<bool>,
+}
+
+pub async fn handle_admin_expunge_report<T>(
+    path: web::Path<(JMAPId,)>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    expunge(path.into_inner().0, core, session, true).await
+}
+
+pub async fn handle_admin_expunge<T>(
+    path: web::Path<(JMAPId,)>,
+    params: web::Query<Params>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    expunge(
+        path.into_inner().0,
+        core,
+        session,
+        params.dry_run.unwrap_or(false),
+    )
+    .await
+}
+
+async fn expunge<T>(
+    id: JMAPId,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+    dry_run: bool,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let account_id = id.get_document_id();
+
+    // Only administrators may expunge mailboxes on demand
+    let store = core.store.clone();
+    let session_id = session.account_id();
+    match core
+        .spawn_worker(move || Ok(store.get_acl_token(session_id)?.is_member(SUPERUSER_ID)))
+        .await
+    {
+        Ok(true) => (),
+        Ok(false) => return Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Failed to obtain ACL token: {:?}", err);
+            return Err(RequestError::internal_server_error());
+        }
+    }
+
+    // Destructive runs have to be executed by the leader
+    if !dry_run && !core.is_leader() {
+        return Err(RequestError::unavailable());
+    }
+
+    match expunge_account(&core, account_id, dry_run).await {
+        Ok(report) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(report)),
+        Err(err) => {
+            error!("Failed to expunge mailboxes: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
@@ -0,0 +1,135 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use jmap::{principal::schema::Type, request::ACLEnforce, types::jmap::JMAPId, SUPERUSER_ID};
+use jmap_sharing::principal::account::JMAPAccountStore;
+use store::{
+    tracing::{error, info},
+    Store,
+};
+
+use crate::{
+    authorization::{impersonate::Scope, Session},
+    JMAPServer,
+};
+
+use super::{migration::is_superuser, RequestError};
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ImpersonateRequest {
+    #[serde(default)]
+    scope: Vec<String>,
+    #[serde(rename = "expiresIn")]
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ImpersonateResponse {
+    #[serde(rename = "accessToken")]
+    access_token: String,
+    #[serde(rename = "tokenType")]
+    token_type: &'static str,
+    #[serde(rename = "expiresIn")]
+    expires_in: u64,
+    scope: Vec<&'static str>,
+}
+
+pub async fn handle_admin_impersonate<T>(
+    path: web::Path<(JMAPId,)>,
+    request: web::Json<ImpersonateRequest>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_superuser(&core, &session).await?;
+
+    let account_id = path.into_inner().0.get_document_id();
+    let request = request.into_inner();
+    let scope = Scope::parse(
+        session.account_id(),
+        request.scope.iter().map(|s| s.as_str()),
+    )
+    .map_err(|_| RequestError::invalid_parameters())?;
+    let expires_in = request
+        .expires_in
+        .unwrap_or(core.oauth.expiry_impersonation_token);
+    if expires_in == 0 || expires_in > core.oauth.max_expiry_impersonation_token {
+        return Err(RequestError::invalid_parameters());
+    }
+
+    // Only individual accounts without administrative rights can be impersonated
+    let store = core.store.clone();
+    match core
+        .spawn_worker(move || {
+            Ok(matches!(
+                store.get_account_details(account_id)?,
+                Some((_, _, Type::Individual))
+            ) && !store.get_acl_token(account_id)?.is_member(SUPERUSER_ID))
+        })
+        .await
+    {
+        Ok(true) => (),
+        Ok(false) => return Err(RequestError::not_found()),
+        Err(err) => {
+            error!("Failed to obtain account details: {:?}", err);
+            return Err(RequestError::internal_server_error());
+        }
+    }
+
+    match core
+        .issue_impersonation_token(account_id, &scope, expires_in)
+        .await
+    {
+        Ok(access_token) => {
+            info!(
+                concat!(
+                    "Account {} issued an impersonation token for account {} ",
+                    "(scope {:?}, expires in {}s)."
+                ),
+                session.account_id(),
+                account_id,
+                scope.names(),
+                expires_in
+            );
+
+            Ok(HttpResponse::build(StatusCode::OK)
+                .insert_header(ContentType::json())
+                .json(ImpersonateResponse {
+                    access_token,
+                    token_type: "bearer",
+                    expires_in,
+                    scope: scope.names(),
+                }))
+        }
+        Err(err) => {
+            error!("Failed to issue impersonation token: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
@@ -21,42 +21,63 @@
  * for more details.
 */
 
-use super::{blob::JMAPBlobCopy, method, request::Request, response::Response};
-use crate::{authorization::Session, services::email_delivery, JMAPServer};
+use std::time::Instant;
+
+use super::{
+    blob::JMAPBlobCopy, method, plugin::CustomResponse, request::Request, response::Response,
+    trace::CallTrace, unsubscribe::handle_email_unsubscribe,
+};
+use crate::{
+    authorization::Session,
+    services::{dns::DnsError, email_delivery, onboarding::spawn_onboarding},
+    JMAPServer,
+};
 use actix_web::web;
 use jmap::{
     error::method::MethodError,
+    jmap_store::{changes::JMAPChanges, set::SetObject},
     push_subscription::{get::JMAPGetPushSubscription, set::JMAPSetPushSubscription},
-    request::ACLEnforce,
-    SUPERUSER_ID,
+    request::{
+        set::{SetRequest, SetResponse},
+        ACLEnforce,
+    },
+    types::state::JMAPState,
+    SUPERUSER_ID, URI,
 };
 use jmap_mail::{
     email_submission::{
         changes::JMAPEmailSubmissionChanges, get::JMAPGetEmailSubmission,
-        query::JMAPEmailSubmissionQuery, set::JMAPSetEmailSubmission,
+        query::JMAPEmailSubmissionQuery, recipients::JMAPSubmissionRecipients,
+        schema::EmailSubmission, set::JMAPSetEmailSubmission,
     },
     identity::{changes::JMAPIdentityChanges, get::JMAPGetIdentity, set::JMAPSetIdentity},
     mail::{
         changes::JMAPMailChanges, copy::JMAPCopyMail, get::JMAPGetMail, import::JMAPMailImport,
-        parse::JMAPMailParse, query::JMAPMailQuery, search_snippet::JMAPMailSearchSnippet,
-        set::JMAPSetMail,
+        parse::JMAPMailParse, query::JMAPMailQuery, schema::Email,
+        search_snippet::JMAPMailSearchSnippet, set::JMAPSetMail, set_keywords::JMAPMailSetKeywords,
     },
     mailbox::{
-        changes::JMAPMailboxChanges, get::JMAPGetMailbox, query::JMAPMailboxQuery,
+        changes::JMAPMailboxChanges, get::JMAPGetMailbox, query::JMAPMailboxQuery, schema::Mailbox,
         set::JMAPSetMailbox,
     },
     thread::{changes::JMAPThreadChanges, get::JMAPGetThread},
     vacation_response::{get::JMAPGetVacationResponse, set::JMAPSetVacationResponse},
 };
 use jmap_sharing::principal::{
-    account::JMAPAccountStore, get::JMAPGetPrincipal, query::JMAPPrincipalQuery,
-    set::JMAPSetPrincipal,
+    account::JMAPAccountStore, forwarding::JMAPAccountForwarding, get::JMAPGetPrincipal,
+    query::JMAPPrincipalQuery, set::JMAPSetPrincipal, subaddress::JMAPAccountSubAddress,
 };
 use jmap_sieve::sieve_script::{
     get::JMAPGetSieveScript, query::JMAPSieveScriptQuery, set::JMAPSetSieveScript,
     validate::JMAPMailSieveScriptValidate,
 };
-use store::{core::collection::Collection, tracing::error, AccountId, Store};
+use store::{
+    ahash::AHashSet,
+    core::{collection::Collection, timing::ReadTimings},
+    tracing::{debug, error},
+    write::batch::WriteBatch,
+    AccountId, Store,
+};
 
 pub async fn handle_method_calls<T>(
     request: Request,
@@ -73,10 +94,21 @@ where
         request.method_calls.len(),
     );
 
-    for call in request.method_calls.into_iter() {
+    let mut method_calls = request.method_calls.into_iter().peekable();
+    let mut linked_mailboxes: Option<LinkedMailboxes> = None;
+    let link_sets = request
+        .using
+        .iter()
+        .any(|capability| capability == URI::LinkedSets.as_str());
+
+    while let Some(call) = method_calls.next() {
         let call_id = call.id;
         let mut call_method = call.method;
 
+        if let method::Request::Custom(request) = &mut call_method {
+            request.is_read_only = core.plugins.is_read_only(&request.name);
+        }
+
         loop {
             // Make sure this node is up to date to handle the request.
             if !core.is_leader() && !core.is_up_to_date() {
@@ -84,14 +116,106 @@ where
                 break;
             }
 
+            // Enforce the scope of impersonated sessions
+            if let Some(Err(err)) = session
+                .scope()
+                .map(|scope| scope.validate_method(&call_method))
+            {
+                response.push_error(call_id, err);
+                break;
+            }
+
             // Prepare request
             if let Err(err) = call_method.prepare_request(&response) {
                 response.push_error(call_id, err);
                 break;
             }
 
+            // Look up the recipient domains of new submissions
+            if let method::Request::SetEmailSubmission(request) = &mut call_method {
+                if core.store.config.submission_verify_mx {
+                    request.arguments.undeliverable_domains =
+                        undeliverable_domains(&core, request, session.account_id()).await;
+                }
+            }
+
             // Execute request
-            match handle_method_call(call_method, &core, session.account_id()).await {
+            let trace = if core.traces.is_enabled() {
+                Some((
+                    call_method.name().to_string(),
+                    call_method.trace_details(),
+                    Instant::now(),
+                ))
+            } else {
+                None
+            };
+            let (result, read_timings) = match call_method {
+                method::Request::SetMailbox(request)
+                    if link_sets && links_mailboxes(&request, method_calls.peek()) =>
+                {
+                    let (result, read_timings) =
+                        handle_deferred_mailbox_set(request, &core, session.account_id()).await;
+                    (
+                        result.map(|(mailbox_response, changes)| {
+                            // The linked Email/set call refers to the new mailboxes
+                            if let Some(created_ids) = mailbox_response.created_ids() {
+                                response.created_ids.extend(created_ids);
+                            }
+                            linked_mailboxes = Some(LinkedMailboxes {
+                                position: response.method_responses.len(),
+                                changes,
+                            });
+                            method::Response::SetMailbox(mailbox_response)
+                        }),
+                        read_timings,
+                    )
+                }
+                method::Request::SetEmail(request) if linked_mailboxes.is_some() => {
+                    let linked = linked_mailboxes.take().unwrap();
+                    let position = linked.position;
+                    let (result, read_timings) = handle_linked_email_set(
+                        request,
+                        linked.changes,
+                        &core,
+                        session.account_id(),
+                    )
+                    .await;
+                    (
+                        match result {
+                            Ok((email_response, Some(mailbox_state))) => {
+                                LinkedMailboxes::commit(&mut response, position, mailbox_state);
+                                Ok(method::Response::SetEmail(email_response))
+                            }
+                            Ok((email_response, None)) => {
+                                LinkedMailboxes::rollback(&mut response, position);
+                                Ok(method::Response::SetEmail(email_response))
+                            }
+                            Err(err) => {
+                                LinkedMailboxes::rollback(&mut response, position);
+                                Err(err)
+                            }
+                        },
+                        read_timings,
+                    )
+                }
+                method::Request::UnsubscribeEmail(request) => {
+                    let (result, read_timings) =
+                        handle_email_unsubscribe(request, &core, session.account_id()).await;
+                    (result.map(method::Response::UnsubscribeEmail), read_timings)
+                }
+                call_method => handle_method_call(call_method, &core, session.account_id()).await,
+            };
+            if let Some((method, details, started)) = trace {
+                response.traces.push(CallTrace::new(
+                    session.account_id(),
+                    method,
+                    details,
+                    started.elapsed(),
+                    read_timings.unwrap_or_default(),
+                ));
+            }
+
+            match result {
                 Ok(mut method_response) => {
                     let next_call_method = match method_response.changes() {
                         method::Changes::Item {
@@ -143,10 +267,41 @@ where
                                         error!("No e-mail delivery configured or something else happened: {}", err);
                                     }
                                 }
-                                method::Response::SetPrincipal(_) => {
+                                method::Response::UnsubscribeEmail(unsubscribe_response)
+                                    if !unsubscribe_response.submission_ids.is_empty() =>
+                                {
+                                    if let Err(err) = core
+                                        .notify_email_delivery(
+                                            email_delivery::Event::new_submission(
+                                                unsubscribe_response.account_id.get_document_id(),
+                                                unsubscribe_response.submission_ids.clone(),
+                                                Vec::new(),
+                                            ),
+                                        )
+                                        .await
+                                    {
+                                        error!("No e-mail delivery configured or something else happened: {}", err);
+                                    }
+                                }
+                                method::Response::SetPrincipal(principal_response) => {
+                                    // Drop cached credentials of modified principals
+                                    core.revoke_credentials(
+                                        principal_response
+                                            .updated
+                                            .keys()
+                                            .chain(principal_response.destroyed.iter())
+                                            .map(|id| id.get_document_id())
+                                            .collect(),
+                                    )
+                                    .await;
                                     core.notify_email_delivery(email_delivery::Event::Reload)
                                         .await
                                         .ok();
+
+                                    // Provision new accounts
+                                    for id in created_ids.iter().flat_map(|ids| ids.values()) {
+                                        spawn_onboarding(core.clone(), id.get_document_id());
+                                    }
                                 }
                                 _ => {}
                             }
@@ -196,6 +351,15 @@ where
                 }
             }
         }
+
+        // Deferred Mailbox/set changes are discarded if the linked Email/set call did not run
+        if let Some(linked) = linked_mailboxes.take() {
+            if linked.position + 1 < response.method_responses.len() {
+                LinkedMailboxes::rollback(&mut response, linked.position);
+            } else {
+                linked_mailboxes = Some(linked);
+            }
+        }
     }
 
     if !include_created_ids {
@@ -205,16 +369,199 @@ where
     response
 }
 
+// Changes of a Mailbox/set call that are written together with the first change
+// of the Email/set call following it. They are rolled back, and reported as such
+// in the Mailbox/set response, when that call fails or changes no message.
+struct LinkedMailboxes {
+    position: usize,
+    changes: WriteBatch,
+}
+
+impl LinkedMailboxes {
+    fn commit(response: &mut Response, position: usize, mailbox_state: JMAPState) {
+        if let Some(method::Call {
+            method: method::Response::SetMailbox(mailbox_response),
+            ..
+        }) = response.method_responses.get_mut(position)
+        {
+            mailbox_response.new_state = mailbox_state.into();
+        }
+    }
+
+    fn rollback(response: &mut Response, position: usize) {
+        if let Some(method::Call {
+            method: method::Response::SetMailbox(mailbox_response),
+            ..
+        }) = response.method_responses.get_mut(position)
+        {
+            for create_id in mailbox_response.created.keys() {
+                response.created_ids.remove(create_id);
+            }
+            mailbox_response.rollback("Not applied, the linked Email/set call failed.");
+        }
+    }
+}
+
+// Returns whether the next call is an Email/set filing messages into mailboxes
+// created by this Mailbox/set call.
+fn links_mailboxes(
+    request: &SetRequest<Mailbox>,
+    next_call: Option<&method::Call<method::Request>>,
+) -> bool {
+    match (&request.create, next_call.map(|call| &call.method)) {
+        (Some(create), Some(method::Request::SetEmail(email_request)))
+            if request.destroy.is_none() && email_request.account_id == request.account_id =>
+        {
+            email_request
+                .create
+                .iter()
+                .flat_map(|create| create.values())
+                .chain(
+                    email_request
+                        .update
+                        .iter()
+                        .flat_map(|update| update.values()),
+                )
+                .any(|email| {
+                    let mut is_linked = false;
+                    email.clone().eval_id_references(|id| {
+                        is_linked |= create.get(id).is_some();
+                        None
+                    });
+                    is_linked
+                })
+        }
+        _ => false,
+    }
+}
+
+async fn handle_deferred_mailbox_set<T>(
+    mut request: SetRequest<Mailbox>,
+    core: &web::Data<JMAPServer<T>>,
+    account_id: AccountId,
+) -> (
+    jmap::Result<(SetResponse<Mailbox>, WriteBatch)>,
+    Option<ReadTimings>,
+)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    core.spawn_traced_jmap_request(move || {
+        request.acl = store
+            .get_acl_token(account_id)?
+            .assert_has_access(request.account_id.get_document_id(), Collection::Mailbox)?
+            .into();
+        store.mailbox_set_deferred(request)
+    })
+    .await
+}
+
+// Returns the Email/set response and, if the linked changes were written, the
+// new Mailbox state.
+async fn handle_linked_email_set<T>(
+    mut request: SetRequest<Email>,
+    changes: WriteBatch,
+    core: &web::Data<JMAPServer<T>>,
+    account_id: AccountId,
+) -> (
+    jmap::Result<(SetResponse<Email>, Option<JMAPState>)>,
+    Option<ReadTimings>,
+)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    core.spawn_traced_jmap_request(move || {
+        let mail_account_id = request.account_id.get_document_id();
+        request.acl = store
+            .get_acl_token(account_id)?
+            .assert_has_access(mail_account_id, Collection::Mail)?
+            .into();
+        let response = store.mail_set_linked(request, changes.into())?;
+        let mailbox_state = if response.has_changes().is_some() {
+            store
+                .get_state(mail_account_id, Collection::Mailbox)?
+                .into()
+        } else {
+            None
+        };
+        Ok((response, mailbox_state))
+    })
+    .await
+}
+
+// Returns the non-local recipient domains of the submissions being created
+// that do not accept mail. Lookup failures other than a missing domain or a
+// null MX are not treated as errors, the delivery will be retried later.
+async fn undeliverable_domains<T>(
+    core: &web::Data<JMAPServer<T>>,
+    request: &SetRequest<EmailSubmission>,
+    session_account_id: AccountId,
+) -> AHashSet<String>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let mut undeliverable = AHashSet::default();
+    let submissions = if let Some(create) = &request.create {
+        create.values().cloned().collect::<Vec<_>>()
+    } else {
+        return undeliverable;
+    };
+    let account_id = request.account_id.get_document_id();
+    let store = core.store.clone();
+
+    let domains = match core
+        .spawn_jmap_request(move || {
+            store
+                .get_acl_token(session_account_id)?
+                .assert_is_member(account_id)?;
+            let mut domains = Vec::new();
+            for domain in store.submission_recipient_domains(account_id, &submissions)? {
+                if !store.is_local_domain(&domain)? {
+                    domains.push(domain);
+                }
+            }
+            Ok(domains)
+        })
+        .await
+    {
+        Ok(domains) => domains,
+        Err(err) => {
+            debug!("Failed to obtain submission recipients: {:?}", err);
+            return undeliverable;
+        }
+    };
+
+    for domain in domains {
+        if let Err(DnsError::NotFound) = core.dns.mx_lookup(&domain).await {
+            undeliverable.insert(domain);
+        }
+    }
+
+    undeliverable
+}
+
 pub async fn handle_method_call<T>(
     call: method::Request,
     core: &web::Data<JMAPServer<T>>,
     account_id: AccountId,
-) -> jmap::Result<method::Response>
+) -> (jmap::Result<method::Response>, Option<ReadTimings>)
 where
     T: for<'x> Store<'x> + 'static,
 {
     let store = core.store.clone();
-    core.spawn_jmap_request(move || {
+    let handler = if let method::Request::Custom(request) = &call {
+        if let Some(handler) = core.plugins.method(&request.name) {
+            Some(handler)
+        } else {
+            return (Err(MethodError::UnknownMethod(request.name.clone())), None);
+        }
+    } else {
+        None
+    };
+
+    core.spawn_traced_jmap_request(move || {
         Ok(match call {
             method::Request::CopyBlob(mut request) => {
                 request.acl = store
@@ -340,6 +687,17 @@ where
                     .into();
                 method::Response::ParseEmail(store.mail_parse(request)?)
             }
+            method::Request::UnsubscribeEmail(_) => {
+                // Handled by handle_email_unsubscribe
+                return Err(MethodError::UnknownMethod("Email/unsubscribe".to_string()));
+            }
+            method::Request::SetKeywordsEmail(mut request) => {
+                request.acl = store
+                    .get_acl_token(account_id)?
+                    .assert_has_access(request.account_id.get_document_id(), Collection::Mail)?
+                    .into();
+                method::Response::SetKeywordsEmail(store.mail_set_keywords(request)?)
+            }
             method::Request::GetSearchSnippet(mut request) => {
                 request.acl = store
                     .get_acl_token(account_id)?
@@ -447,6 +805,34 @@ where
                     .into();
                 method::Response::ValidateSieveScript(store.sieve_script_validate(request)?)
             }
+            method::Request::GetSubAddress(mut request) => {
+                request.acl = store
+                    .get_acl_token(account_id)?
+                    .assert_is_member(request.account_id.get_document_id())?
+                    .into();
+                method::Response::GetSubAddress(store.subaddress_get(request)?)
+            }
+            method::Request::SetSubAddress(mut request) => {
+                request.acl = store
+                    .get_acl_token(account_id)?
+                    .assert_is_member(request.account_id.get_document_id())?
+                    .into();
+                method::Response::SetSubAddress(store.subaddress_set(request)?)
+            }
+            method::Request::GetForwarding(mut request) => {
+                request.acl = store
+                    .get_acl_token(account_id)?
+                    .assert_is_member(request.account_id.get_document_id())?
+                    .into();
+                method::Response::GetForwarding(store.forwarding_get(request)?)
+            }
+            method::Request::SetForwarding(mut request) => {
+                request.acl = store
+                    .get_acl_token(account_id)?
+                    .assert_is_member(request.account_id.get_document_id())?
+                    .into();
+                method::Response::SetForwarding(store.forwarding_set(request)?)
+            }
             method::Request::GetPrincipal(mut request) => {
                 request.acl = store
                     .get_acl_token(account_id)?
@@ -469,6 +855,18 @@ where
                 method::Response::SetPrincipal(store.principal_set(request)?)
             }
             method::Request::Echo(payload) => method::Response::Echo(payload),
+            method::Request::Custom(request) => {
+                let result = handler.unwrap().call(
+                    &store,
+                    store.get_acl_token(account_id)?,
+                    request.arguments,
+                )?;
+                method::Response::Custom(CustomResponse {
+                    name: request.name,
+                    arguments: result.arguments,
+                    change_id: result.change_id,
+                })
+            }
             method::Request::Error(err) => return Err(err),
         })
     })
@@ -0,0 +1,356 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::{web, HttpResponse};
+use jmap::types::jmap::JMAPId;
+use jmap_mail::{
+    mail::{
+        import::JMAPMailImport,
+        limits::MimeLimits,
+        parse::mail_parse_raw,
+        schema::{Email, Keyword},
+        MAX_MESSAGE_PARTS,
+    },
+    mail_parser::{
+        parsers::fields::thread::thread_name, Addr, HeaderName, HeaderValue, Message, RfcHeader,
+    },
+    INBOX_ID,
+};
+use jmap_sharing::principal::account::JMAPAccountStore;
+use jmap_sieve::sieve_script::get::JMAPGetSieveScript;
+use store::{
+    core::{collection::Collection, document::MAX_ID_LENGTH},
+    tracing::error,
+    AccountId, DocumentId, JMAPStore, Store,
+};
+
+use crate::{
+    authorization::Session,
+    lmtp::sieve::{JMAPSieveRun, SieveEnvelope},
+    JMAPServer,
+};
+
+use super::{RequestError, RequestLimitError};
+
+#[derive(Debug, serde::Serialize)]
+pub struct LintResponse {
+    pub email: Email,
+    #[serde(rename = "threadId")]
+    pub thread_id: Option<JMAPId>,
+    #[serde(rename = "mergedThreadIds")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub merged_thread_ids: Vec<JMAPId>,
+    pub sieve: Option<SieveDryRun>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct SieveDryRun {
+    pub script: String,
+    #[serde(rename = "mailboxIds")]
+    pub mailbox_ids: Vec<JMAPId>,
+    pub keywords: Vec<Keyword>,
+    pub actions: Vec<String>,
+}
+
+pub trait JMAPMailLint {
+    fn mail_lint(
+        &self,
+        account_id: AccountId,
+        raw_message: &[u8],
+    ) -> store::Result<Option<LintResponse>>;
+
+    fn mail_sieve_dry_run<'x>(
+        &self,
+        account_id: AccountId,
+        raw_message: &'x [u8],
+        message: Message<'x>,
+    ) -> store::Result<Option<SieveDryRun>>;
+}
+
+impl<T> JMAPMailLint for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn mail_lint(
+        &self,
+        account_id: AccountId,
+        raw_message: &[u8],
+    ) -> store::Result<Option<LintResponse>> {
+        let limits = MimeLimits::from(&self.config);
+        let (email, message) = match (
+            mail_parse_raw(raw_message, &limits),
+            limits.parse(raw_message),
+        ) {
+            (Some(email), Some(message)) => (email, message),
+            _ => return Ok(None),
+        };
+        let mut warnings = Vec::new();
+
+        // Messages the server would refuse to store
+        if raw_message.len() > self.config.mail_max_size {
+            warnings.push(format!(
+                "Message size {} exceeds the maximum of {} bytes and would be rejected.",
+                raw_message.len(),
+                self.config.mail_max_size
+            ));
+        }
+        if let Some(truncation) = limits.scan(raw_message) {
+            warnings.push(format!(
+                "Message exceeds the {}, the contents after byte {} would be stored as an attachment.",
+                truncation.limit, truncation.offset
+            ));
+        }
+        if message.parts.len() > MAX_MESSAGE_PARTS {
+            warnings.push(format!(
+                "Message has {} MIME parts, more than the {} allowed, and would be rejected.",
+                message.parts.len(),
+                MAX_MESSAGE_PARTS
+            ));
+        }
+
+        // Parsing problems
+        for (part_id, part) in message.parts.iter().enumerate() {
+            if part.is_encoding_problem {
+                warnings.push(format!(
+                    "Part {} could not be decoded using its declared charset or encoding.",
+                    part_id
+                ));
+            }
+        }
+        let root_headers = &message.parts[0].headers;
+        for (header, name) in [
+            (RfcHeader::From, "From"),
+            (RfcHeader::Date, "Date"),
+            (RfcHeader::MessageId, "Message-ID"),
+        ] {
+            if !root_headers
+                .iter()
+                .any(|h| matches!(&h.name, HeaderName::Rfc(rfc_header) if *rfc_header == header))
+            {
+                warnings.push(format!("Missing {} header.", name));
+            }
+        }
+        let mut bare_lf = 0;
+        let mut long_lines = 0;
+        let mut line_start = 0;
+        for (pos, &ch) in raw_message.iter().enumerate() {
+            if ch == b'\n' {
+                if pos == 0 || raw_message[pos - 1] != b'\r' {
+                    bare_lf += 1;
+                }
+                if pos - line_start > 998 {
+                    long_lines += 1;
+                }
+                line_start = pos + 1;
+            }
+        }
+        if bare_lf > 0 {
+            warnings.push(format!(
+                "Found {} lines terminated by a bare LF instead of CRLF.",
+                bare_lf
+            ));
+        }
+        if long_lines > 0 {
+            warnings.push(format!(
+                "Found {} lines longer than the 998 characters allowed by RFC 5322.",
+                long_lines
+            ));
+        }
+
+        // Find the thread the message would be added to
+        let mut reference_ids = Vec::new();
+        let mut subject = None;
+        for header in root_headers {
+            match (&header.name, &header.value) {
+                (
+                    HeaderName::Rfc(
+                        RfcHeader::MessageId
+                        | RfcHeader::InReplyTo
+                        | RfcHeader::References
+                        | RfcHeader::ResentMessageId,
+                    ),
+                    value,
+                ) => match value {
+                    HeaderValue::Text(id) => reference_ids.push(id.as_ref()),
+                    HeaderValue::TextList(ids) => {
+                        reference_ids.extend(ids.iter().map(|id| id.as_ref()))
+                    }
+                    _ => (),
+                },
+                (HeaderName::Rfc(RfcHeader::Subject), HeaderValue::Text(text)) => {
+                    subject = text.as_ref().into();
+                }
+                _ => (),
+            }
+        }
+        reference_ids.retain(|id| id.len() <= MAX_ID_LENGTH);
+        let thread_name = subject.map(thread_name).unwrap_or_default();
+        let mut thread_ids = if !reference_ids.is_empty() {
+            self.mail_find_threads(
+                account_id,
+                if !thread_name.is_empty() {
+                    thread_name
+                } else {
+                    "!"
+                },
+                &reference_ids,
+            )?
+            .into_iter()
+            .collect::<Vec<_>>()
+        } else {
+            Vec::new()
+        };
+        thread_ids.sort_unstable();
+        let mut thread_ids = thread_ids
+            .into_iter()
+            .map(|thread_id| JMAPId::new(thread_id as u64))
+            .collect::<Vec<_>>();
+        let (thread_id, merged_thread_ids) = match thread_ids.len() {
+            0 => (None, Vec::new()),
+            1 => (thread_ids.pop(), Vec::new()),
+            _ => {
+                warnings.push(format!(
+                    "Message references {} existing threads which would be merged.",
+                    thread_ids.len()
+                ));
+                (None, thread_ids)
+            }
+        };
+
+        Ok(Some(LintResponse {
+            email,
+            thread_id,
+            merged_thread_ids,
+            sieve: self.mail_sieve_dry_run(account_id, raw_message, message)?,
+            warnings,
+        }))
+    }
+
+    fn mail_sieve_dry_run<'x>(
+        &self,
+        account_id: AccountId,
+        raw_message: &'x [u8],
+        message: Message<'x>,
+    ) -> store::Result<Option<SieveDryRun>> {
+        let active_script = if let Some(active_script) = self.sieve_script_get_active(account_id)? {
+            active_script
+        } else {
+            return Ok(None);
+        };
+        let mailbox_ids = self
+            .get_document_ids(account_id, Collection::Mailbox)?
+            .unwrap_or_default();
+
+        // Use the sender as envelope, as there is no SMTP transaction
+        let envelope_from = match message.get_from() {
+            HeaderValue::Address(Addr {
+                address: Some(addr),
+                ..
+            }) => addr.to_string(),
+            HeaderValue::AddressList(addrs) => addrs
+                .iter()
+                .find_map(|addr| addr.address.as_ref())
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        let envelope_to = self
+            .get_account_details(account_id)?
+            .map(|(email, _, _)| email)
+            .unwrap_or_default();
+
+        let outcome = self.sieve_run(
+            account_id,
+            &active_script,
+            raw_message,
+            message,
+            SieveEnvelope {
+                mail_from: &envelope_from,
+                rcpt_to: &envelope_to,
+                mailbox_ids: &mailbox_ids,
+                keep_id: INBOX_ID,
+            },
+            true,
+        );
+
+        // Only report the deliveries of the original message, mailboxes that
+        // would be created do not have an id yet
+        let message = &outcome.messages[0];
+        Ok(Some(SieveDryRun {
+            script: outcome.script_name,
+            mailbox_ids: message
+                .file_into
+                .iter()
+                .filter(|mailbox_id| **mailbox_id != DocumentId::MAX)
+                .map(|mailbox_id| JMAPId::new(*mailbox_id as u64))
+                .collect(),
+            keywords: message.flags.iter().cloned().map(Keyword::new).collect(),
+            actions: outcome.actions,
+        }))
+    }
+}
+
+pub async fn handle_jmap_lint<T>(
+    path: web::Path<(JMAPId,)>,
+    bytes: web::Bytes,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let (id,) = path.into_inner();
+    let account_id = id.get_document_id();
+
+    if bytes.len() > core.store.config.max_size_upload {
+        return Err(RequestError::limit(RequestLimitError::Size));
+    }
+
+    let store = core.store.clone();
+    match core
+        .spawn_worker(move || {
+            Ok(
+                if store
+                    .get_acl_token(session.account_id())?
+                    .is_member(account_id)
+                {
+                    store.mail_lint(account_id, &bytes)?.into()
+                } else {
+                    None
+                },
+            )
+        })
+        .await
+    {
+        Ok(Some(Some(response))) => Ok(HttpResponse::Ok().json(response)),
+        Ok(Some(None)) => {
+            Err(RequestError::invalid_parameters().with_detail("Failed to parse message."))
+        }
+        Ok(None) => Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Message lint failed: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
@@ -49,11 +49,21 @@ use jmap_mail::{
         parse::{EmailParseRequest, EmailParseResponse},
         schema::Email,
         search_snippet::{SearchSnippetGetRequest, SearchSnippetGetResponse},
+        set_keywords::{EmailSetKeywordsRequest, EmailSetKeywordsResponse},
+        unsubscribe::{EmailUnsubscribeRequest, EmailUnsubscribeResponse},
     },
     mailbox::schema::Mailbox,
     thread::schema::Thread,
     vacation_response::schema::VacationResponse,
 };
+use jmap_sharing::principal::{
+    forwarding::{
+        ForwardingGetRequest, ForwardingGetResponse, ForwardingSetRequest, ForwardingSetResponse,
+    },
+    subaddress::{
+        SubAddressGetRequest, SubAddressGetResponse, SubAddressSetRequest, SubAddressSetResponse,
+    },
+};
 use jmap_sieve::sieve_script::{
     schema::SieveScript,
     validate::{SieveScriptValidateRequest, SieveScriptValidateResponse},
@@ -63,7 +73,10 @@ use store::{ahash::AHashMap, log::changes::ChangeId, AccountId};
 
 use crate::services::state_change::StateChange;
 
-use super::response;
+use super::{
+    plugin::{CustomRequest, CustomResponse},
+    response,
+};
 
 #[derive(Debug)]
 pub struct Call<T> {
@@ -114,6 +127,8 @@ pub enum Request {
     CopyEmail(CopyRequest<Email>),
     ImportEmail(EmailImportRequest),
     ParseEmail(EmailParseRequest),
+    UnsubscribeEmail(EmailUnsubscribeRequest),
+    SetKeywordsEmail(EmailSetKeywordsRequest),
     GetSearchSnippet(SearchSnippetGetRequest),
 
     // Identity
@@ -138,6 +153,12 @@ pub enum Request {
     SetSieveScript(SetRequest<SieveScript>),
     ValidateSieveScript(SieveScriptValidateRequest),
 
+    // Sub-addressing
+    GetSubAddress(SubAddressGetRequest),
+    SetSubAddress(SubAddressSetRequest),
+    GetForwarding(ForwardingGetRequest),
+    SetForwarding(ForwardingSetRequest),
+
     // Principal
     GetPrincipal(GetRequest<Principal>),
     QueryPrincipal(QueryRequest<Principal>),
@@ -147,6 +168,9 @@ pub enum Request {
     CopyBlob(CopyBlobRequest),
     Echo(serde_json::Value),
     Error(MethodError),
+
+    // Registered by plugins
+    Custom(CustomRequest),
 }
 
 #[derive(Debug)]
@@ -175,6 +199,8 @@ pub enum Response {
     CopyEmail(CopyResponse<Email>),
     ImportEmail(EmailImportResponse),
     ParseEmail(EmailParseResponse),
+    UnsubscribeEmail(EmailUnsubscribeResponse),
+    SetKeywordsEmail(EmailSetKeywordsResponse),
     GetSearchSnippet(SearchSnippetGetResponse),
 
     // Identity
@@ -199,6 +225,12 @@ pub enum Response {
     SetSieveScript(SetResponse<SieveScript>),
     ValidateSieveScript(SieveScriptValidateResponse),
 
+    // Sub-addressing
+    GetSubAddress(SubAddressGetResponse),
+    SetSubAddress(SubAddressSetResponse),
+    GetForwarding(ForwardingGetResponse),
+    SetForwarding(ForwardingSetResponse),
+
     // Principal
     GetPrincipal(GetResponse<Principal>),
     QueryPrincipal(QueryResponse),
@@ -208,6 +240,9 @@ pub enum Response {
     CopyBlob(CopyBlobResponse),
     Echo(serde_json::Value),
     Error(MethodError),
+
+    // Registered by plugins
+    Custom(CustomResponse),
 }
 
 impl Request {
@@ -238,6 +273,8 @@ impl Request {
             | Request::GetSieveScript(_)
             | Request::QuerySieveScript(_)
             | Request::ValidateSieveScript(_)
+            | Request::GetSubAddress(_)
+            | Request::GetForwarding(_)
             | Request::Echo(_)
             | Request::Error(_) => true,
 
@@ -246,15 +283,119 @@ impl Request {
             | Request::SetEmail(_)
             | Request::CopyEmail(_)
             | Request::ImportEmail(_)
+            | Request::UnsubscribeEmail(_)
+            | Request::SetKeywordsEmail(_)
             | Request::SetIdentity(_)
             | Request::SetEmailSubmission(_)
             | Request::SetVacationResponse(_)
             | Request::SetPrincipal(_)
             | Request::SetSieveScript(_)
+            | Request::SetSubAddress(_)
+            | Request::SetForwarding(_)
             | Request::CopyBlob(_) => false,
+
+            Request::Custom(request) => request.is_read_only,
+        }
+    }
+
+    /// Returns true for methods operating on the mail capability objects
+    /// (Mailbox, Thread, Email and SearchSnippet) as well as core methods.
+    pub fn is_mail(&self) -> bool {
+        matches!(
+            self,
+            Request::GetMailbox(_)
+                | Request::ChangesMailbox(_)
+                | Request::QueryMailbox(_)
+                | Request::QueryChangesMailbox(_)
+                | Request::SetMailbox(_)
+                | Request::GetThread(_)
+                | Request::ChangesThread(_)
+                | Request::GetEmail(_)
+                | Request::ChangesEmail(_)
+                | Request::QueryEmail(_)
+                | Request::QueryChangesEmail(_)
+                | Request::SetEmail(_)
+                | Request::CopyEmail(_)
+                | Request::ImportEmail(_)
+                | Request::ParseEmail(_)
+                | Request::UnsubscribeEmail(_)
+                | Request::SetKeywordsEmail(_)
+                | Request::GetSearchSnippet(_)
+                | Request::CopyBlob(_)
+                | Request::Echo(_)
+                | Request::Error(_)
+        )
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Request::GetEmail(_) => "Email/get",
+            Request::ChangesEmail(_) => "Email/changes",
+            Request::QueryEmail(_) => "Email/query",
+            Request::QueryChangesEmail(_) => "Email/queryChanges",
+            Request::SetEmail(_) => "Email/set",
+            Request::CopyEmail(_) => "Email/copy",
+            Request::ImportEmail(_) => "Email/import",
+            Request::ParseEmail(_) => "Email/parse",
+            Request::UnsubscribeEmail(_) => "Email/unsubscribe",
+            Request::SetKeywordsEmail(_) => "Email/setKeywordsByQuery",
+            Request::GetMailbox(_) => "Mailbox/get",
+            Request::ChangesMailbox(_) => "Mailbox/changes",
+            Request::QueryMailbox(_) => "Mailbox/query",
+            Request::QueryChangesMailbox(_) => "Mailbox/queryChanges",
+            Request::SetMailbox(_) => "Mailbox/set",
+            Request::GetThread(_) => "Thread/get",
+            Request::ChangesThread(_) => "Thread/changes",
+            Request::GetSearchSnippet(_) => "SearchSnippet/get",
+            Request::GetIdentity(_) => "Identity/get",
+            Request::ChangesIdentity(_) => "Identity/changes",
+            Request::SetIdentity(_) => "Identity/set",
+            Request::GetEmailSubmission(_) => "EmailSubmission/get",
+            Request::ChangesEmailSubmission(_) => "EmailSubmission/changes",
+            Request::QueryEmailSubmission(_) => "EmailSubmission/query",
+            Request::QueryChangesEmailSubmission(_) => "EmailSubmission/queryChanges",
+            Request::SetEmailSubmission(_) => "EmailSubmission/set",
+            Request::GetVacationResponse(_) => "VacationResponse/get",
+            Request::SetVacationResponse(_) => "VacationResponse/set",
+            Request::GetSieveScript(_) => "SieveScript/get",
+            Request::QuerySieveScript(_) => "SieveScript/query",
+            Request::SetSieveScript(_) => "SieveScript/set",
+            Request::ValidateSieveScript(_) => "SieveScript/validate",
+            Request::GetSubAddress(_) => "SubAddress/get",
+            Request::SetSubAddress(_) => "SubAddress/set",
+            Request::GetForwarding(_) => "Forwarding/get",
+            Request::SetForwarding(_) => "Forwarding/set",
+            Request::GetPushSubscription(_) => "PushSubscription/get",
+            Request::SetPushSubscription(_) => "PushSubscription/set",
+            Request::GetPrincipal(_) => "Principal/get",
+            Request::SetPrincipal(_) => "Principal/set",
+            Request::QueryPrincipal(_) => "Principal/query",
+            Request::CopyBlob(_) => "Blob/copy",
+            Request::Echo(_) => "Core/echo",
+            Request::Error(_) => "error",
+            Request::Custom(request) => &request.name,
         }
     }
 
+    pub fn trace_details(&self) -> Option<String> {
+        match self {
+            Request::QueryMailbox(request) => format!("{:?}", request.filter.as_ref()?),
+            Request::QueryChangesMailbox(request) => format!("{:?}", request.filter.as_ref()?),
+            Request::QueryEmail(request) => format!("{:?}", request.filter.as_ref()?),
+            Request::QueryChangesEmail(request) => format!("{:?}", request.filter.as_ref()?),
+            Request::GetSearchSnippet(request) => format!("{:?}", request.filter.as_ref()?),
+            Request::SetKeywordsEmail(request) => format!("{:?}", request.filter.as_ref()?),
+            Request::QueryEmailSubmission(request) => format!("{:?}", request.filter.as_ref()?),
+            Request::QueryChangesEmailSubmission(request) => {
+                format!("{:?}", request.filter.as_ref()?)
+            }
+            Request::QuerySieveScript(request) => format!("{:?}", request.filter.as_ref()?),
+            Request::QueryPrincipal(request) => format!("{:?}", request.filter.as_ref()?),
+            _ => return None,
+        }
+        .into()
+    }
+
     pub fn prepare_request(&mut self, response: &response::Response) -> jmap::Result<()> {
         // Create JSON Pointer evaluation function
         let mut eval_result_ref = |rr: &ResultReference| -> Option<Vec<u64>> {
@@ -324,6 +465,11 @@ impl Request {
                         (Method::QueryPrincipal, Response::QueryPrincipal(response)) => {
                             return response.eval_json_pointer(&rr.path);
                         }
+                        (Method::Custom(name), Response::Custom(response))
+                            if *name == response.name =>
+                        {
+                            return response.arguments.eval_json_pointer(&rr.path);
+                        }
                         _ => {
                             break;
                         }
@@ -377,6 +523,9 @@ impl Request {
             Request::SetPrincipal(request) => {
                 request.eval_references(&mut eval_result_ref, &response.created_ids)?;
             }
+            Request::Custom(request) => {
+                request.eval_result_references(&mut eval_result_ref)?;
+            }
             _ => (),
         }
         Ok(())
@@ -448,6 +597,47 @@ impl Response {
                     Changes::None
                 }
             }
+            Response::SetKeywordsEmail(response) => {
+                if let Some(change_id) = response.change_id {
+                    let mut state_change = vec![(TypeState::Email, change_id)];
+                    if response.has_mailbox_changes {
+                        state_change.push((TypeState::Mailbox, change_id));
+                    }
+                    Changes::Item {
+                        created_ids: None,
+                        change_id,
+                        state_change: StateChange::new(
+                            response.account_id.get_document_id(),
+                            state_change,
+                        )
+                        .into(),
+                        next_call: None,
+                    }
+                } else {
+                    Changes::None
+                }
+            }
+            Response::UnsubscribeEmail(response) => {
+                if let Some(change_id) = response.change_id {
+                    Changes::Item {
+                        created_ids: None,
+                        change_id,
+                        state_change: StateChange::new(
+                            response.account_id.get_document_id(),
+                            vec![
+                                (TypeState::Email, change_id),
+                                (TypeState::Mailbox, change_id),
+                                (TypeState::Thread, change_id),
+                                (TypeState::EmailSubmission, change_id),
+                            ],
+                        )
+                        .into(),
+                        next_call: None,
+                    }
+                } else {
+                    Changes::None
+                }
+            }
             Response::SetIdentity(response) => {
                 if let Some(change_id) = response.has_changes() {
                     Changes::Item {
@@ -530,6 +720,42 @@ impl Response {
                     Changes::None
                 }
             }
+            Response::SetSubAddress(response) => {
+                if let Some(change_id) = response.change_id {
+                    Changes::Item {
+                        created_ids: None,
+                        change_id,
+                        state_change: None,
+                        next_call: None,
+                    }
+                } else {
+                    Changes::None
+                }
+            }
+            Response::SetForwarding(response) => {
+                if let Some(change_id) = response.change_id {
+                    Changes::Item {
+                        created_ids: None,
+                        change_id,
+                        state_change: None,
+                        next_call: None,
+                    }
+                } else {
+                    Changes::None
+                }
+            }
+            Response::Custom(response) => {
+                if let Some(change_id) = response.change_id {
+                    Changes::Item {
+                        created_ids: None,
+                        change_id,
+                        state_change: None,
+                        next_call: None,
+                    }
+                } else {
+                    Changes::None
+                }
+            }
             Response::GetMailbox(_)
             | Response::ChangesMailbox(_)
             | Response::QueryMailbox(_)
@@ -555,6 +781,8 @@ impl Response {
             | Response::GetSieveScript(_)
             | Response::ValidateSieveScript(_)
             | Response::QuerySieveScript(_)
+            | Response::GetSubAddress(_)
+            | Response::GetForwarding(_)
             | Response::Echo(_)
             | Response::Error(_) => Changes::None,
         }
@@ -658,6 +886,16 @@ where
                 .map_err(|err| MatchError::Parse(err.to_string()))?
                 .ok_or(MatchError::Eof)?,
         ),
+        "Email/unsubscribe" => Request::UnsubscribeEmail(
+            seq.next_element()
+                .map_err(|err| MatchError::Parse(err.to_string()))?
+                .ok_or(MatchError::Eof)?,
+        ),
+        "Email/setKeywordsByQuery" => Request::SetKeywordsEmail(
+            seq.next_element()
+                .map_err(|err| MatchError::Parse(err.to_string()))?
+                .ok_or(MatchError::Eof)?,
+        ),
         "Mailbox/get" => Request::GetMailbox(
             seq.next_element()
                 .map_err(|err| MatchError::Parse(err.to_string()))?
@@ -768,6 +1006,26 @@ where
                 .map_err(|err| MatchError::Parse(err.to_string()))?
                 .ok_or(MatchError::Eof)?,
         ),
+        "SubAddress/get" => Request::GetSubAddress(
+            seq.next_element()
+                .map_err(|err| MatchError::Parse(err.to_string()))?
+                .ok_or(MatchError::Eof)?,
+        ),
+        "SubAddress/set" => Request::SetSubAddress(
+            seq.next_element()
+                .map_err(|err| MatchError::Parse(err.to_string()))?
+                .ok_or(MatchError::Eof)?,
+        ),
+        "Forwarding/get" => Request::GetForwarding(
+            seq.next_element()
+                .map_err(|err| MatchError::Parse(err.to_string()))?
+                .ok_or(MatchError::Eof)?,
+        ),
+        "Forwarding/set" => Request::SetForwarding(
+            seq.next_element()
+                .map_err(|err| MatchError::Parse(err.to_string()))?
+                .ok_or(MatchError::Eof)?,
+        ),
         "PushSubscription/get" => Request::GetPushSubscription(
             seq.next_element()
                 .map_err(|err| MatchError::Parse(err.to_string()))?
@@ -803,12 +1061,13 @@ where
                 .map_err(|err| MatchError::Parse(err.to_string()))?
                 .ok_or(MatchError::Eof)?,
         ),
-        _ => {
-            seq.next_element::<serde_json::Value>()
+        // Unknown methods are resolved against the plugin registry on execution
+        _ => Request::Custom(CustomRequest::new(
+            name.to_string(),
+            seq.next_element()
                 .map_err(|err| MatchError::Parse(err.to_string()))?
-                .ok_or(MatchError::Eof)?;
-            Request::Error(MethodError::UnknownMethod(name.to_string()))
-        }
+                .ok_or(MatchError::Eof)?,
+        )),
     })
 }
 
@@ -888,6 +1147,14 @@ impl Serialize for Call<Response> {
                 seq.serialize_element("Email/parse")?;
                 seq.serialize_element(response)?;
             }
+            Response::UnsubscribeEmail(response) => {
+                seq.serialize_element("Email/unsubscribe")?;
+                seq.serialize_element(response)?;
+            }
+            Response::SetKeywordsEmail(response) => {
+                seq.serialize_element("Email/setKeywordsByQuery")?;
+                seq.serialize_element(response)?;
+            }
             Response::GetSearchSnippet(response) => {
                 seq.serialize_element("SearchSnippet/get")?;
                 seq.serialize_element(response)?;
@@ -948,6 +1215,22 @@ impl Serialize for Call<Response> {
                 seq.serialize_element("SieveScript/validate")?;
                 seq.serialize_element(response)?;
             }
+            Response::GetSubAddress(response) => {
+                seq.serialize_element("SubAddress/get")?;
+                seq.serialize_element(response)?;
+            }
+            Response::SetSubAddress(response) => {
+                seq.serialize_element("SubAddress/set")?;
+                seq.serialize_element(response)?;
+            }
+            Response::GetForwarding(response) => {
+                seq.serialize_element("Forwarding/get")?;
+                seq.serialize_element(response)?;
+            }
+            Response::SetForwarding(response) => {
+                seq.serialize_element("Forwarding/set")?;
+                seq.serialize_element(response)?;
+            }
             Response::GetPrincipal(response) => {
                 seq.serialize_element("Principal/get")?;
                 seq.serialize_element(response)?;
@@ -972,6 +1255,10 @@ impl Serialize for Call<Response> {
                 seq.serialize_element("error")?;
                 seq.serialize_element(response)?;
             }
+            Response::Custom(response) => {
+                seq.serialize_element(&response.name)?;
+                seq.serialize_element(&response.arguments)?;
+            }
         }
         seq.serialize_element(&self.id)?;
         seq.end()
@@ -0,0 +1,259 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use jmap::{
+    orm::serialize::JMAPOrm, principal::schema::Principal, request::ACLEnforce,
+    types::jmap::JMAPId, SUPERUSER_ID,
+};
+use store::{tracing::error, Store};
+
+use crate::{
+    authorization::Session,
+    services::migration::{spawn_migration, Migration, MigrationStatus},
+    JMAPServer,
+};
+
+use super::{RequestError, RequestErrorType};
+
+#[derive(Debug, serde::Deserialize)]
+pub struct MigrationRequest {
+    #[serde(rename = "accountId")]
+    account_id: JMAPId,
+    url: String,
+    username: Option<String>,
+    secret: String,
+    #[serde(rename = "sourceAccountId")]
+    source_account_id: Option<String>,
+}
+
+pub(crate) async fn is_superuser<T>(
+    core: &web::Data<JMAPServer<T>>,
+    session: &Session,
+) -> Result<(), RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    if session.scope().is_some() {
+        return Err(RequestError::forbidden());
+    }
+
+    let store = core.store.clone();
+    let account_id = session.account_id();
+    match core
+        .spawn_worker(move || Ok(store.get_acl_token(account_id)?.is_member(SUPERUSER_ID)))
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Failed to obtain ACL token: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+pub async fn handle_admin_migration_create<T>(
+    request: web::Json<MigrationRequest>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_superuser(&core, &session).await?;
+    if !core.is_leader() {
+        return Err(RequestError::unavailable());
+    }
+
+    let request = request.into_inner();
+    let is_imap = request.url.starts_with("imaps://") || request.url.starts_with("imap://");
+    if (!is_imap && !request.url.starts_with("https://") && !request.url.starts_with("http://"))
+        || (is_imap && request.username.is_none())
+    {
+        return Err(RequestError::invalid_parameters());
+    }
+
+    // Make sure the target account exists
+    let store = core.store.clone();
+    let account_id = request.account_id.get_document_id();
+    match core
+        .spawn_worker(move || {
+            Ok(store
+                .get_orm::<Principal>(SUPERUSER_ID, account_id)?
+                .is_some())
+        })
+        .await
+    {
+        Ok(true) => (),
+        Ok(false) => return Err(RequestError::not_found()),
+        Err(err) => {
+            error!("Failed to obtain principal: {:?}", err);
+            return Err(RequestError::internal_server_error());
+        }
+    }
+
+    let migration = Migration::new(
+        account_id,
+        request.url,
+        request.username,
+        request.source_account_id,
+        &request.secret,
+        &core.oauth.key,
+    )
+    .ok_or_else(RequestError::internal_server_error)?;
+    if let Err(err) = core.migration_save(&migration).await {
+        error!("Failed to save migration: {:?}", err);
+        return Err(RequestError::internal_server_error());
+    }
+    let info = migration.info();
+    spawn_migration(core, migration);
+
+    Ok(HttpResponse::build(StatusCode::CREATED)
+        .insert_header(ContentType::json())
+        .json(info))
+}
+
+pub async fn handle_admin_migration_list<T>(
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_superuser(&core, &session).await?;
+
+    match core.migration_list().await {
+        Ok(migrations) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(
+                migrations
+                    .iter()
+                    .map(|migration| migration.info())
+                    .collect::<Vec<_>>(),
+            )),
+        Err(err) => {
+            error!("Failed to list migrations: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+pub async fn handle_admin_migration_get<T>(
+    path: web::Path<(JMAPId,)>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_superuser(&core, &session).await?;
+    let migration = get_migration(&core, path.into_inner().0).await?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .insert_header(ContentType::json())
+        .json(migration.info()))
+}
+
+pub async fn handle_admin_migration_resume<T>(
+    path: web::Path<(JMAPId,)>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_superuser(&core, &session).await?;
+    if !core.is_leader() {
+        return Err(RequestError::unavailable());
+    }
+    let migration = get_migration(&core, path.into_inner().0).await?;
+
+    if !migration.is_resumable() || core.migrations.is_running(migration.id) {
+        return Err(RequestError::new(
+            RequestErrorType::Conflict,
+            409,
+            "Conflict",
+            "The migration is either running or has already completed.",
+        ));
+    }
+    let info = migration.info();
+    spawn_migration(core, migration);
+
+    Ok(HttpResponse::build(StatusCode::ACCEPTED)
+        .insert_header(ContentType::json())
+        .json(info))
+}
+
+pub async fn handle_admin_migration_cancel<T>(
+    path: web::Path<(JMAPId,)>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_superuser(&core, &session).await?;
+    if !core.is_leader() {
+        return Err(RequestError::unavailable());
+    }
+    let mut migration = get_migration(&core, path.into_inner().0).await?;
+
+    // Running migrations stop and record their state after the current chunk
+    if !core.migrations.cancel(migration.id)
+        && matches!(
+            migration.status,
+            MigrationStatus::Pending | MigrationStatus::Running
+        )
+    {
+        migration.status = MigrationStatus::Cancelled;
+        if let Err(err) = core.migration_save(&migration).await {
+            error!("Failed to save migration: {:?}", err);
+            return Err(RequestError::internal_server_error());
+        }
+    }
+
+    Ok(HttpResponse::build(StatusCode::ACCEPTED)
+        .insert_header(ContentType::json())
+        .json(migration.info()))
+}
+
+async fn get_migration<T>(
+    core: &web::Data<JMAPServer<T>>,
+    id: JMAPId,
+) -> Result<Migration, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    match core.migration_get(id.into()).await {
+        Ok(Some(migration)) => Ok(migration),
+        Ok(None) => Err(RequestError::not_found()),
+        Err(err) => {
+            error!("Failed to obtain migration: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
@@ -29,12 +29,32 @@ use std::borrow::Cow;
 use std::fmt::Display;
 use store::core::vec_map::VecMap;
 
+pub mod autoconfig;
 pub mod blob;
+pub mod cluster;
+pub mod compact;
+pub mod config;
+pub mod expunge;
+pub mod impersonate;
+pub mod ingest;
 pub mod invocation;
+pub mod lint;
 pub mod method;
+pub mod migration;
+pub mod otp;
+pub mod plugin;
+pub mod queue;
+pub mod renumber;
+pub mod report;
 pub mod request;
 pub mod response;
 pub mod session;
+pub mod trace;
+pub mod unsubscribe;
+pub mod vacation;
+
+// Versioned prefix under which the JMAP and administration endpoints are also served.
+pub const API_PREFIX: &str = "/api/v1";
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub enum StateChangeType {
@@ -46,6 +66,10 @@ pub struct StateChangeResponse {
     #[serde(rename = "@type")]
     pub type_: StateChangeType,
     pub changed: VecMap<JMAPId, VecMap<TypeState, JMAPState>>,
+    #[serde(rename = "mailboxUnreadDeltas")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "VecMap::is_empty")]
+    pub unread_deltas: VecMap<JMAPId, VecMap<JMAPId, i64>>,
 }
 
 impl StateChangeResponse {
@@ -53,6 +77,7 @@ impl StateChangeResponse {
         Self {
             type_: StateChangeType::StateChange,
             changed: VecMap::new(),
+            unread_deltas: VecMap::new(),
         }
     }
 }
@@ -71,6 +96,8 @@ pub enum RequestLimitError {
     CallsIn,
     #[serde(rename(serialize = "maxConcurrentRequests"))]
     Concurrent,
+    #[serde(rename(serialize = "maxQuerySubscriptions"))]
+    QuerySubscriptions,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -83,6 +110,24 @@ pub enum RequestErrorType {
     NotRequest,
     #[serde(rename(serialize = "urn:ietf:params:jmap:error:limit"))]
     Limit,
+    #[serde(rename(serialize = "urn:stalwart:jmap:error:invalidParameters"))]
+    InvalidParameters,
+    #[serde(rename(serialize = "urn:stalwart:jmap:error:unauthorized"))]
+    Unauthorized,
+    #[serde(rename(serialize = "urn:stalwart:jmap:error:forbidden"))]
+    Forbidden,
+    #[serde(rename(serialize = "urn:stalwart:jmap:error:notFound"))]
+    NotFound,
+    #[serde(rename(serialize = "urn:stalwart:jmap:error:blobNotFound"))]
+    BlobNotFound,
+    #[serde(rename(serialize = "urn:stalwart:jmap:error:conflict"))]
+    Conflict,
+    #[serde(rename(serialize = "urn:stalwart:jmap:error:rateLimited"))]
+    RateLimited,
+    #[serde(rename(serialize = "urn:stalwart:jmap:error:unavailable"))]
+    Unavailable,
+    #[serde(rename(serialize = "urn:stalwart:jmap:error:serverFail"))]
+    ServerFail,
     #[serde(rename(serialize = "about:blank"))]
     Other,
 }
@@ -104,9 +149,18 @@ impl RequestError {
         status: u16,
         title: impl Into<Cow<'static, str>>,
         detail: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        RequestError::new(RequestErrorType::Other, status, title, detail)
+    }
+
+    pub fn new(
+        p_type: RequestErrorType,
+        status: u16,
+        title: impl Into<Cow<'static, str>>,
+        detail: impl Into<Cow<'static, str>>,
     ) -> Self {
         RequestError {
-            p_type: RequestErrorType::Other,
+            p_type,
             status,
             title: Some(title.into()),
             detail: detail.into(),
@@ -114,8 +168,14 @@ impl RequestError {
         }
     }
 
+    pub fn with_detail(mut self, detail: impl Into<Cow<'static, str>>) -> Self {
+        self.detail = detail.into();
+        self
+    }
+
     pub fn internal_server_error() -> Self {
-        RequestError::blank(
+        RequestError::new(
+            RequestErrorType::ServerFail,
             500,
             "Internal Server Error",
             concat!(
@@ -126,7 +186,8 @@ impl RequestError {
     }
 
     pub fn unavailable() -> Self {
-        RequestError::blank(
+        RequestError::new(
+            RequestErrorType::Unavailable,
             503,
             "Temporarily Unavailable",
             concat!(
@@ -137,7 +198,8 @@ impl RequestError {
     }
 
     pub fn invalid_parameters() -> Self {
-        RequestError::blank(
+        RequestError::new(
+            RequestErrorType::InvalidParameters,
             400,
             "Invalid Parameters",
             "One or multiple parameters could not be parsed.",
@@ -145,7 +207,8 @@ impl RequestError {
     }
 
     pub fn forbidden() -> Self {
-        RequestError::blank(
+        RequestError::new(
+            RequestErrorType::Forbidden,
             403,
             "Forbidden",
             "You do not have enough permissions to access this resource.",
@@ -153,7 +216,8 @@ impl RequestError {
     }
 
     pub fn too_many_requests() -> Self {
-        RequestError::blank(
+        RequestError::new(
+            RequestErrorType::RateLimited,
             429,
             "Too Many Requests",
             "Your request has been rate limited. Please try again in a few seconds.",
@@ -161,7 +225,8 @@ impl RequestError {
     }
 
     pub fn too_many_auth_attempts() -> Self {
-        RequestError::blank(
+        RequestError::new(
+            RequestErrorType::RateLimited,
             429,
             "Too Many Authentication Attempts",
             "Your request has been rate limited. Please try again in a few minutes.",
@@ -186,6 +251,10 @@ impl RequestError {
                     "The request exceeds the maximum number ",
                     "of concurrent requests."
                 ),
+                RequestLimitError::QuerySubscriptions => concat!(
+                    "The request exceeds the maximum number ",
+                    "of query subscriptions."
+                ),
             }
             .into(),
             limit: Some(limit_type),
@@ -193,15 +262,30 @@ impl RequestError {
     }
 
     pub fn not_found() -> Self {
-        RequestError::blank(
+        RequestError::new(
+            RequestErrorType::NotFound,
             404,
             "Not Found",
             "The requested resource does not exist on this server.",
         )
     }
 
+    pub fn blob_not_found() -> Self {
+        RequestError::new(
+            RequestErrorType::BlobNotFound,
+            404,
+            "Blob Not Found",
+            "The requested blob does not exist or has expired.",
+        )
+    }
+
     pub fn unauthorized() -> Self {
-        RequestError::blank(401, "Unauthorized", "You have to authenticate first.")
+        RequestError::new(
+            RequestErrorType::Unauthorized,
+            401,
+            "Unauthorized",
+            "You have to authenticate first.",
+        )
     }
 
     pub fn unknown_capability(capability: &str) -> RequestError {
@@ -247,6 +331,10 @@ impl RequestError {
     }
 }
 
+pub async fn handle_not_found() -> Result<HttpResponse, RequestError> {
+    Err(RequestError::not_found())
+}
+
 impl Display for RequestError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&self.detail)
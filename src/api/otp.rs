@@ -0,0 +1,321 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use jmap_sharing::principal::{
+    account::JMAPAccountStore,
+    otp::{totp_generate_secret, totp_url, JMAPAccountOtp, OtpError},
+    passkey::{JMAPAccountPasskey, Passkey},
+};
+use store::{tracing::error, Store};
+
+use crate::{
+    authorization::{
+        auth::RemoteAddress,
+        webauthn::{
+            base64url_decode, challenge_issue, challenge_take, ClientData, CreationOptions,
+        },
+        Session,
+    },
+    server::workers::WorkerPool,
+    JMAPServer,
+};
+
+use super::{RequestError, RequestErrorType};
+
+const TOTP_ISSUER: &str = "Stalwart JMAP";
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "action")]
+pub enum OtpRequest {
+    #[serde(rename = "totpGenerate")]
+    TotpGenerate,
+    #[serde(rename = "totpEnable")]
+    TotpEnable { secret: String, code: String },
+    #[serde(rename = "totpDisable")]
+    TotpDisable { code: String },
+    #[serde(rename = "recoveryCodesGenerate")]
+    RecoveryCodesGenerate { code: String },
+    #[serde(rename = "appPasswordCreate")]
+    AppPasswordCreate { name: String, code: Option<String> },
+    #[serde(rename = "appPasswordRevoke")]
+    AppPasswordRevoke { name: String },
+    #[serde(rename = "passkeyOptions")]
+    PasskeyOptions,
+    #[serde(rename = "passkeyRegister")]
+    PasskeyRegister {
+        name: String,
+        #[serde(rename = "clientDataJSON")]
+        client_data_json: String,
+        #[serde(rename = "attestationObject")]
+        attestation_object: String,
+        code: Option<String>,
+    },
+    #[serde(rename = "passkeyRemove")]
+    PasskeyRemove { name: String },
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct OtpResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(rename = "recoveryCodes")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_codes: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(rename = "publicKey")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key: Option<CreationOptions>,
+}
+
+pub async fn handle_otp_status<T>(
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let account_id = session.account_id();
+    match core
+        .spawn_worker(move || store.otp_status(account_id))
+        .await
+    {
+        Ok(status) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(status)),
+        Err(err) => {
+            error!("Failed to obtain 2FA status: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+pub async fn handle_otp_request<T>(
+    request: web::Json<OtpRequest>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Impersonated sessions may not change credentials
+    if session.scope().is_some() {
+        return Err(RequestError::forbidden());
+    }
+
+    let account_id = session.account_id();
+    let request = request.into_inner();
+
+    // Verifying codes counts as an authentication attempt
+    if !matches!(
+        request,
+        OtpRequest::TotpGenerate
+            | OtpRequest::AppPasswordRevoke { .. }
+            | OtpRequest::PasskeyOptions
+            | OtpRequest::PasskeyRemove { .. }
+    ) {
+        core.is_auth_allowed(RemoteAddress::AccountId(account_id))
+            .await?;
+    }
+
+    // Enabling 2FA or revoking an app password invalidates cached sessions
+    let revoke_sessions = matches!(
+        request,
+        OtpRequest::TotpEnable { .. } | OtpRequest::AppPasswordRevoke { .. }
+    );
+
+    let store = core.store.clone();
+    let relying_party = core.oauth.relying_party.clone();
+    let key = core.oauth.key.clone();
+    let result = core
+        .spawn_worker_on(WorkerPool::Crypto, move || {
+            Ok(match request {
+                OtpRequest::TotpGenerate => {
+                    let secret = totp_generate_secret();
+                    let account = store
+                        .get_account_details(account_id)?
+                        .map(|(email, _, _)| email)
+                        .unwrap_or_default();
+                    Ok(OtpResponse {
+                        url: totp_url(&secret, TOTP_ISSUER, &account).into(),
+                        secret: secret.into(),
+                        ..Default::default()
+                    })
+                }
+                OtpRequest::TotpEnable { secret, code } => store
+                    .otp_enable(account_id, &secret, &code)?
+                    .map(|codes| OtpResponse {
+                        recovery_codes: codes.into(),
+                        ..Default::default()
+                    }),
+                OtpRequest::TotpDisable { code } => store
+                    .otp_disable(account_id, &code)?
+                    .map(|_| OtpResponse::default()),
+                OtpRequest::RecoveryCodesGenerate { code } => store
+                    .otp_recovery_codes(account_id, &code)?
+                    .map(|codes| OtpResponse {
+                        recovery_codes: codes.into(),
+                        ..Default::default()
+                    }),
+                OtpRequest::AppPasswordCreate { name, code } => store
+                    .app_password_create(account_id, &name, code.as_deref())?
+                    .map(|password| OtpResponse {
+                        password: password.into(),
+                        ..Default::default()
+                    }),
+                OtpRequest::AppPasswordRevoke { name } => store
+                    .app_password_revoke(account_id, &name)?
+                    .map(|_| OtpResponse::default()),
+                OtpRequest::PasskeyOptions => {
+                    let (email, name) = store
+                        .get_account_details(account_id)?
+                        .map(|(email, name, _)| (email, name))
+                        .unwrap_or_default();
+                    Ok(OtpResponse {
+                        public_key: relying_party
+                            .creation_options(
+                                challenge_issue(&key, account_id, true),
+                                account_id,
+                                email,
+                                name,
+                                &store.passkey_list(account_id)?,
+                            )
+                            .into(),
+                        ..Default::default()
+                    })
+                }
+                OtpRequest::PasskeyRegister {
+                    name,
+                    client_data_json,
+                    attestation_object,
+                    code,
+                } => {
+                    // The challenge has to be issued to this account for a registration
+                    let client_data = base64url_decode(&client_data_json)
+                        .and_then(|bytes| ClientData::parse(&bytes))
+                        .filter(|client_data| {
+                            relying_party.is_valid_client_data(client_data, "webauthn.create")
+                        });
+                    let passkey = match client_data {
+                        Some(client_data) => {
+                            match challenge_take(&store, &key, &client_data.challenge)? {
+                                Some(challenge)
+                                    if challenge.registration
+                                        && challenge.account_id == account_id =>
+                                {
+                                    base64url_decode(&attestation_object)
+                                        .and_then(|bytes| relying_party.verify_registration(&bytes))
+                                }
+                                _ => None,
+                            }
+                        }
+                        None => None,
+                    };
+                    if let Some(passkey) = passkey {
+                        store
+                            .passkey_add(account_id, Passkey { name, ..passkey }, code.as_deref())?
+                            .map(|_| OtpResponse::default())
+                    } else {
+                        Err(OtpError::InvalidPasskey)
+                    }
+                }
+                OtpRequest::PasskeyRemove { name } => store
+                    .passkey_remove(account_id, &name)?
+                    .map(|_| OtpResponse::default()),
+            })
+        })
+        .await;
+
+    match result {
+        Ok(Ok(response)) => {
+            if revoke_sessions {
+                core.revoke_credentials(vec![account_id]).await;
+            }
+            Ok(HttpResponse::build(StatusCode::OK)
+                .insert_header(ContentType::json())
+                .json(response))
+        }
+        Ok(Err(err)) => Err(match err {
+            OtpError::InvalidCode => RequestError::new(
+                RequestErrorType::InvalidParameters,
+                400,
+                "Invalid Code",
+                "The authentication code is invalid or has expired.",
+            ),
+            OtpError::AlreadyEnabled => RequestError::new(
+                RequestErrorType::InvalidParameters,
+                400,
+                "Already Enabled",
+                "Two-factor authentication is already enabled for this account.",
+            ),
+            OtpError::NotEnabled => RequestError::new(
+                RequestErrorType::InvalidParameters,
+                400,
+                "Not Enabled",
+                "Two-factor authentication is not enabled for this account.",
+            ),
+            OtpError::InvalidName => RequestError::new(
+                RequestErrorType::InvalidParameters,
+                400,
+                "Invalid Name",
+                "The app password name is invalid or already in use.",
+            ),
+            OtpError::TooManyAppPasswords => RequestError::new(
+                RequestErrorType::InvalidParameters,
+                400,
+                "Too Many App Passwords",
+                "The maximum number of app passwords has been reached.",
+            ),
+            OtpError::TooManyPasskeys => RequestError::new(
+                RequestErrorType::InvalidParameters,
+                400,
+                "Too Many Passkeys",
+                "The maximum number of passkeys has been reached.",
+            ),
+            OtpError::PasskeyExists => RequestError::new(
+                RequestErrorType::InvalidParameters,
+                400,
+                "Passkey Exists",
+                "This passkey is already registered.",
+            ),
+            OtpError::InvalidPasskey => RequestError::new(
+                RequestErrorType::InvalidParameters,
+                400,
+                "Invalid Passkey",
+                "The passkey could not be verified or its challenge has expired.",
+            ),
+            OtpError::NotFound => RequestError::not_found(),
+        }),
+        Err(err) => {
+            error!("Failed to process 2FA request: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
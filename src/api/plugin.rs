@@ -0,0 +1,184 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use jmap::{error::method::MethodError, request::ResultReference, types::jmap::JMAPId};
+use store::{
+    ahash::AHashMap, core::acl::ACLToken, log::changes::ChangeId, parking_lot::RwLock, JMAPStore,
+};
+
+/// Handler for a method not implemented by the server, such as `Foo/get`.
+pub trait MethodHandler<T>: Send + Sync {
+    /// Executes the method call. The ACL token belongs to the authenticated
+    /// principal, handlers are responsible for validating the `accountId`
+    /// argument against it.
+    fn call(
+        &self,
+        store: &JMAPStore<T>,
+        acl: Arc<ACLToken>,
+        arguments: serde_json::Value,
+    ) -> jmap::Result<CustomResult>;
+
+    /// Read-only methods are executed on cluster followers and are allowed
+    /// for read-only sessions.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CustomResult {
+    pub arguments: serde_json::Value,
+    /// Must be set by handlers that wrote to the store, so the change is
+    /// committed to the cluster before the response is sent.
+    pub change_id: Option<ChangeId>,
+}
+
+impl From<serde_json::Value> for CustomResult {
+    fn from(arguments: serde_json::Value) -> Self {
+        CustomResult {
+            arguments,
+            change_id: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CustomRequest {
+    pub name: String,
+    pub arguments: serde_json::Value,
+    pub is_read_only: bool,
+}
+
+#[derive(Debug)]
+pub struct CustomResponse {
+    pub name: String,
+    pub arguments: serde_json::Value,
+    pub change_id: Option<ChangeId>,
+}
+
+/// Capabilities and method handlers registered by embedders. Built-in
+/// methods always take precedence over registered ones.
+pub struct PluginRegistry<T> {
+    capabilities: RwLock<Vec<(String, serde_json::Value)>>,
+    methods: RwLock<AHashMap<String, Arc<dyn MethodHandler<T>>>>,
+}
+
+impl<T> Default for PluginRegistry<T> {
+    fn default() -> Self {
+        Self {
+            capabilities: RwLock::new(Vec::new()),
+            methods: RwLock::new(AHashMap::default()),
+        }
+    }
+}
+
+impl<T> PluginRegistry<T> {
+    /// Advertises a capability such as `urn:example:foo` in the session object.
+    pub fn register_capability(&self, uri: impl Into<String>, capabilities: serde_json::Value) {
+        let uri = uri.into();
+        let mut registered = self.capabilities.write();
+        if let Some(item) = registered.iter_mut().find(|(item, _)| *item == uri) {
+            item.1 = capabilities;
+        } else {
+            registered.push((uri, capabilities));
+        }
+    }
+
+    /// Registers a handler for a method such as `Foo/get`, replacing any
+    /// previously registered handler with the same name.
+    pub fn register_method(
+        &self,
+        name: impl Into<String>,
+        handler: impl MethodHandler<T> + 'static,
+    ) {
+        self.methods.write().insert(name.into(), Arc::new(handler));
+    }
+
+    pub fn capabilities(&self) -> Vec<(String, serde_json::Value)> {
+        self.capabilities.read().clone()
+    }
+
+    pub fn method(&self, name: &str) -> Option<Arc<dyn MethodHandler<T>>> {
+        self.methods.read().get(name).cloned()
+    }
+
+    pub fn is_read_only(&self, name: &str) -> bool {
+        self.methods
+            .read()
+            .get(name)
+            .map_or(false, |handler| handler.is_read_only())
+    }
+}
+
+impl CustomRequest {
+    pub fn new(name: String, arguments: serde_json::Value) -> Self {
+        CustomRequest {
+            name,
+            arguments,
+            is_read_only: false,
+        }
+    }
+
+    /// Replaces all `#argument` result references with the list of ids they
+    /// point to.
+    pub fn eval_result_references(
+        &mut self,
+        mut fnc: impl FnMut(&ResultReference) -> Option<Vec<u64>>,
+    ) -> jmap::Result<()> {
+        if let serde_json::Value::Object(arguments) = &mut self.arguments {
+            let references = arguments
+                .keys()
+                .filter(|key| key.starts_with('#'))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            for key in references {
+                let value = arguments.remove(&key).unwrap_or_default();
+                let rr = serde_json::from_value::<ResultReference>(value).map_err(|err| {
+                    MethodError::InvalidResultReference(format!(
+                        "Invalid result reference {}: {}",
+                        key, err
+                    ))
+                })?;
+                let ids = fnc(&rr).ok_or_else(|| {
+                    MethodError::InvalidResultReference(format!(
+                        "Failed to evaluate {} result reference.",
+                        key
+                    ))
+                })?;
+                arguments.insert(
+                    key[1..].to_string(),
+                    serde_json::Value::Array(
+                        ids.into_iter()
+                            .map(|id| serde_json::Value::String(JMAPId::from(id).to_string()))
+                            .collect(),
+                    ),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use jmap::types::jmap::JMAPId;
+use store::{tracing::error, Store};
+
+use crate::{
+    authorization::Session,
+    services::delivery_queue::{QueueError, QueueFilter},
+    JMAPServer,
+};
+
+use super::{migration::is_superuser, RequestError, RequestErrorType};
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ListParams {
+    domain: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RetryParams {
+    at: Option<u64>,
+}
+
+pub async fn handle_admin_queue_list<T>(
+    params: web::Query<ListParams>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_queue_admin(&core, &session).await?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .insert_header(ContentType::json())
+        .json(core.delivery_queue.list(params.domain.as_deref())))
+}
+
+pub async fn handle_admin_queue_get<T>(
+    path: web::Path<(JMAPId,)>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_queue_admin(&core, &session).await?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .insert_header(ContentType::json())
+        .json(
+            core.delivery_queue
+                .info(path.into_inner().0)
+                .ok_or_else(RequestError::not_found)?,
+        ))
+}
+
+pub async fn handle_admin_queue_retry<T>(
+    path: web::Path<(JMAPId,)>,
+    params: web::Query<RetryParams>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_queue_admin(&core, &session).await?;
+
+    let mut result = core
+        .delivery_queue_reschedule(QueueFilter::Id(path.into_inner().0), params.at)
+        .await
+        .map_err(into_request_error)?;
+
+    Ok(HttpResponse::build(StatusCode::ACCEPTED)
+        .insert_header(ContentType::json())
+        .json(result.pop()))
+}
+
+pub async fn handle_admin_queue_cancel<T>(
+    path: web::Path<(JMAPId,)>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_queue_admin(&core, &session).await?;
+
+    let mut result = core
+        .delivery_queue_cancel(QueueFilter::Id(path.into_inner().0))
+        .await
+        .map_err(into_request_error)?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .insert_header(ContentType::json())
+        .json(result.pop()))
+}
+
+pub async fn handle_admin_queue_domain_retry<T>(
+    path: web::Path<(String,)>,
+    params: web::Query<RetryParams>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_queue_admin(&core, &session).await?;
+
+    let domain = path.into_inner().0;
+    let result = core
+        .delivery_queue_reschedule(QueueFilter::Domain(&domain), params.at)
+        .await
+        .map_err(into_request_error)?;
+
+    Ok(HttpResponse::build(StatusCode::ACCEPTED)
+        .insert_header(ContentType::json())
+        .json(result))
+}
+
+pub async fn handle_admin_queue_domain_cancel<T>(
+    path: web::Path<(String,)>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_queue_admin(&core, &session).await?;
+
+    let domain = path.into_inner().0;
+    let result = core
+        .delivery_queue_cancel(QueueFilter::Domain(&domain))
+        .await
+        .map_err(into_request_error)?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .insert_header(ContentType::json())
+        .json(result))
+}
+
+// The queue is only kept by the leader, which delivers the messages
+async fn is_queue_admin<T>(
+    core: &web::Data<JMAPServer<T>>,
+    session: &Session,
+) -> Result<(), RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_superuser(core, session).await?;
+    if core.is_leader() {
+        Ok(())
+    } else {
+        Err(RequestError::unavailable())
+    }
+}
+
+fn into_request_error(err: QueueError) -> RequestError {
+    match err {
+        QueueError::NotFound => RequestError::not_found(),
+        QueueError::InFlight => RequestError::new(
+            RequestErrorType::Conflict,
+            409,
+            "Conflict",
+            "A delivery attempt for this message is in progress.",
+        ),
+        QueueError::Store(err) => {
+            error!("Failed to update email submissions: {:?}", err);
+            RequestError::internal_server_error()
+        }
+    }
+}
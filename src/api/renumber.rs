@@ -0,0 +1,124 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use jmap::types::{jmap::JMAPId, type_state::TypeState};
+use jmap_mail::mail::renumber::JMAPMailRenumber;
+use store::{
+    core::{collection::Collection, error::StoreError},
+    tracing::error,
+    Store,
+};
+
+use crate::{
+    authorization::Session,
+    services::{housekeeper::TASK_RENUMBER_IDS, state_change::StateChange},
+    JMAPServer,
+};
+
+use super::{migration::is_superuser, RequestError};
+
+pub async fn handle_admin_renumber<T>(
+    path: web::Path<(JMAPId, TypeState)>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    is_superuser(&core, &session).await?;
+
+    // Renumbering rewrites keys in place, which replicas would not be able to follow
+    if core.is_in_cluster() {
+        return Err(
+            RequestError::unavailable().with_detail("Renumbering is not supported in a cluster.")
+        );
+    }
+
+    let (account_id, type_state) = path.into_inner();
+    if !matches!(type_state, TypeState::Email | TypeState::Thread) {
+        return Err(RequestError::invalid_parameters().with_detail(format!(
+            "Renumbering {} is not supported, only Email and Thread ids can be renumbered.",
+            type_state
+        )));
+    }
+
+    if !core.maintenance.is_open() {
+        return Err(RequestError::unavailable()
+            .with_detail("Renumbering can only run inside a maintenance window."));
+    }
+    if !core.maintenance.start(TASK_RENUMBER_IDS) {
+        return Err(RequestError::unavailable().with_detail("Renumbering is already running."));
+    }
+    let collection = if type_state == TypeState::Email {
+        Collection::Mail
+    } else {
+        Collection::Thread
+    };
+    let store = core.store.clone();
+    let result = core
+        .spawn_worker(move || store.mail_renumber(account_id.get_document_id(), collection))
+        .await;
+    core.maintenance.finish(
+        TASK_RENUMBER_IDS,
+        result.as_ref().err().map(|err| err.to_string()),
+    );
+
+    match result {
+        Ok(report) => {
+            let mut changes = Vec::new();
+            if let Some(change_id) = report.renumber.change_id {
+                changes.push((TypeState::Email, change_id));
+                changes.push((TypeState::Thread, change_id));
+            }
+            if let Some(change_id) = report.submission_change_id {
+                changes.push((TypeState::EmailSubmission, change_id));
+            }
+            if !changes.is_empty() {
+                if let Err(err) = core
+                    .publish_state_change(StateChange::new(account_id.get_document_id(), changes))
+                    .await
+                {
+                    error!("Failed to publish state change: {}", err);
+                }
+            }
+
+            Ok(HttpResponse::build(StatusCode::OK)
+                .insert_header(ContentType::json())
+                .json(report))
+        }
+        Err(StoreError::InvalidArguments(detail)) => {
+            Err(RequestError::invalid_parameters().with_detail(detail))
+        }
+        Err(err @ StoreError::WritesBlocked(_)) => {
+            Err(RequestError::unavailable().with_detail(err.to_string()))
+        }
+        Err(err) => {
+            error!("Failed to renumber account {}: {:?}", account_id, err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
@@ -0,0 +1,131 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use jmap::{principal::schema::Property, request::ACLEnforce, types::jmap::JMAPId, SUPERUSER_ID};
+use jmap_mail::mail::report::JMAPMailReport;
+use jmap_sharing::principal::account::JMAPAccountStore;
+use store::{
+    core::{collection::Collection, JMAPIdPrefix},
+    read::{
+        comparator::Comparator,
+        filter::{Filter, Query},
+        FilterMapper,
+    },
+    tracing::error,
+    Store,
+};
+
+use crate::{authorization::Session, JMAPServer};
+
+use super::RequestError;
+
+const MAX_LARGEST_MESSAGES: usize = 1000;
+
+#[derive(serde::Deserialize)]
+pub struct Params {
+    limit: Option<usize>,
+}
+
+pub async fn handle_admin_report<T>(
+    path: web::Path<(JMAPId,)>,
+    params: web::Query<Params>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let (id,) = path.into_inner();
+    let account_id = id.get_document_id();
+    let max_largest = params.limit.unwrap_or(20).min(MAX_LARGEST_MESSAGES);
+
+    let store = core.store.clone();
+    let session_id = session.account_id();
+    match core
+        .spawn_worker(move || {
+            // Account owners may see their own report, administrators any report.
+            Ok(if store.get_acl_token(session_id)?.is_member(account_id) {
+                Some(store.mail_storage_report(account_id, max_largest)?)
+            } else {
+                None
+            })
+        })
+        .await
+    {
+        Ok(Some(report)) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(report)),
+        Ok(None) => Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Failed to build storage report: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+pub async fn handle_admin_report_summary<T>(
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let session_id = session.account_id();
+    match core
+        .spawn_worker(move || {
+            if !store.get_acl_token(session_id)?.is_member(SUPERUSER_ID) {
+                return Ok(None);
+            }
+
+            // Totals for all individual accounts, read in batches.
+            let account_ids = store
+                .query_store::<FilterMapper>(
+                    SUPERUSER_ID,
+                    Collection::Principal,
+                    Filter::eq(Property::Type.into(), Query::Keyword("i".to_string())),
+                    Comparator::None,
+                )?
+                .into_iter()
+                .map(|id| id.get_document_id())
+                .collect::<Vec<_>>();
+            let mut report = store.mail_storage_summary(&account_ids)?;
+            report.sort_unstable_by(|a, b| b.total_size.cmp(&a.total_size));
+            Ok(Some(report))
+        })
+        .await
+    {
+        Ok(Some(report)) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(report)),
+        Ok(None) => Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Failed to build storage summary: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
@@ -26,7 +26,12 @@ use actix_web::{
     web, HttpResponse, ResponseError,
 };
 use jmap::types::jmap::JMAPId;
-use store::{ahash::AHashMap, tracing::debug, Store};
+use std::time::Instant;
+use store::{
+    ahash::AHashMap,
+    tracing::{debug, error},
+    Store,
+};
 
 use crate::{
     api::{invocation::handle_method_calls, Redirect, RequestError, RequestLimitError},
@@ -56,9 +61,10 @@ where
     T: for<'x> Store<'x> + 'static,
 {
     if request.len() < core.store.config.max_size_request {
+        let parse_start = Instant::now();
         match serde_json::from_slice::<Request>(&request) {
             Ok(request) => {
-                if request.method_calls.len() < core.store.config.max_calls_in_request {
+                if request.method_calls.len() <= core.store.config.max_calls_in_request {
                     // Make sure this node is still the leader
                     if !core.is_leader() {
                         // Redirect requests if at least one method requires write access
@@ -90,11 +96,23 @@ where
                         }
                     }
 
-                    let result = handle_method_calls(request, core, session).await;
+                    let parse_time = parse_start.elapsed();
+                    let mut result = handle_method_calls(request, core.clone(), session).await;
+
+                    let serialize_start = Instant::now();
+                    let body = serde_json::to_vec(&result).map_err(|err| {
+                        error!("Failed to serialize response: {}", err);
+                        RequestError::internal_server_error()
+                    })?;
+                    core.traces.push(
+                        std::mem::take(&mut result.traces),
+                        parse_time,
+                        serialize_start.elapsed(),
+                    );
 
                     Ok(HttpResponse::build(StatusCode::OK)
                         .insert_header(ContentType::json())
-                        .json(result))
+                        .body(body))
                 } else {
                     Err(RequestError::limit(RequestLimitError::CallsIn))
                 }
@@ -102,7 +120,11 @@ where
             Err(err) => {
                 debug!("Failed to parse request: {}", err);
 
-                Err(RequestError::not_request())
+                Err(if err.is_syntax() || err.is_eof() {
+                    RequestError::not_json()
+                } else {
+                    RequestError::not_request()
+                })
             }
         }
     } else {
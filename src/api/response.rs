@@ -27,7 +27,7 @@ use jmap::{error::method::MethodError, types::jmap::JMAPId};
 use store::ahash::AHashMap;
 use store::core::ahash_is_empty;
 
-use super::method;
+use super::{method, trace::CallTrace};
 
 #[derive(Debug, serde::Serialize)]
 
@@ -42,6 +42,9 @@ pub struct Response {
     #[serde(rename(deserialize = "createdIds"))]
     #[serde(skip_serializing_if = "ahash_is_empty")]
     pub created_ids: AHashMap<String, JMAPId>,
+
+    #[serde(skip)]
+    pub traces: Vec<CallTrace>,
 }
 
 impl Response {
@@ -50,6 +53,7 @@ impl Response {
             session_state,
             created_ids,
             method_responses: Vec::with_capacity(capacity),
+            traces: Vec::new(),
         }
     }
 
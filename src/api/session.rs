@@ -21,28 +21,39 @@
  * for more details.
 */
 
-use std::iter::FromIterator;
+use std::{iter::FromIterator, sync::Arc, time::Duration};
 
 use crate::{api::response::serialize_hex, authorization};
 use actix_web::{
-    http::{header::ContentType, StatusCode},
-    web, HttpResponse,
+    http::{
+        header::{self, ContentType},
+        StatusCode,
+    },
+    web, HttpRequest, HttpResponse,
 };
 use jmap::{principal::schema::Type, request::ACLEnforce, types::jmap::JMAPId, URI};
 use jmap_mail::mail::sharing::JMAPShareMail;
 use jmap_sharing::principal::account::JMAPAccountStore;
 use store::{
     ahash::AHashSet,
-    config::{env_settings::EnvSettings, jmap::JMAPConfig},
+    blake3,
+    config::{env_settings::EnvSettings, jmap::JMAPConfig, settings::Setting},
     core::{acl::ACL, vec_map::VecMap},
+    moka::future::Cache,
     sieve::compiler::grammar::Capability,
-    Store,
+    AccountId, Store,
 };
 
 use crate::JMAPServer;
 
 use super::RequestError;
 
+pub const SETTINGS: &[Setting] = &[Setting::integer("session-history-size")
+    .default("1024")
+    .describe("Session objects remembered to answer '?updated=' requests, 0 = disabled")];
+
+const SESSION_HISTORY_TTL: Duration = Duration::from_secs(60 * 60);
+
 #[derive(Debug, Clone, serde::Serialize)]
 pub struct Session {
     #[serde(rename(serialize = "capabilities"))]
@@ -68,6 +79,29 @@ pub struct Session {
     base_url: String,
 }
 
+// Session object sent in reply to '?updated=', listing only the accounts that
+// were added or changed since the session object identified by 'updatedFrom'.
+#[derive(Debug, serde::Serialize)]
+struct SessionUpdate<'x> {
+    #[serde(flatten)]
+    session: &'x Session,
+    #[serde(rename(serialize = "updatedFrom"))]
+    updated_from: &'x str,
+    #[serde(rename(serialize = "removedAccounts"))]
+    removed_accounts: Vec<JMAPId>,
+}
+
+// Digests of the accounts of recently served session objects, keyed by
+// principal and ETag.
+pub struct SessionHistory {
+    digests: Option<Cache<(AccountId, String), Arc<VecMap<JMAPId, String>>>>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct Params {
+    updated: Option<String>,
+}
+
 #[derive(Debug, Clone, serde::Serialize)]
 struct Account {
     #[serde(rename(serialize = "name"))]
@@ -90,6 +124,13 @@ enum Capabilities {
     VacationResponse(VacationResponseCapabilities),
     WebSocket(WebSocketCapabilities),
     Sieve(SieveCapabilities),
+    Annotations(AnnotationCapabilities),
+    ThreadPreview(ThreadPreviewCapabilities),
+    MailboxCounters(MailboxCountersCapabilities),
+    QuerySubscriptions(QuerySubscriptionsCapabilities),
+    LinkedSets(LinkedSetsCapabilities),
+    Forwarding(ForwardingCapabilities),
+    Custom(serde_json::Value),
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -165,6 +206,40 @@ struct SubmissionCapabilities {
 #[derive(Debug, Clone, serde::Serialize)]
 struct VacationResponseCapabilities {}
 
+#[derive(Debug, Clone, serde::Serialize)]
+struct AnnotationCapabilities {
+    #[serde(rename(serialize = "maxSizeAnnotations"))]
+    max_size_annotations: usize,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct ThreadPreviewCapabilities {
+    properties: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct MailboxCountersCapabilities {}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct QuerySubscriptionsCapabilities {
+    #[serde(rename(serialize = "maxSubscriptions"))]
+    max_subscriptions: usize,
+}
+
+// Clients using this capability have a Mailbox/set call creating mailboxes
+// committed together with the Email/set call that follows and files messages
+// into them. Mailboxes are reported as "rolledBack" when no message changed.
+#[derive(Debug, Clone, serde::Serialize)]
+struct LinkedSetsCapabilities {}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct ForwardingCapabilities {
+    #[serde(rename(serialize = "maxAddresses"))]
+    max_addresses: usize,
+    #[serde(rename(serialize = "mayForwardExternally"))]
+    may_forward_externally: bool,
+}
+
 impl Session {
     pub fn new(settings: &EnvSettings, config: &JMAPConfig) -> Session {
         let base_url = settings.get("jmap-url").unwrap();
@@ -181,6 +256,44 @@ impl Session {
                     URI::Sieve,
                     Capabilities::Sieve(SieveCapabilities::new(settings, config)),
                 ),
+                (
+                    URI::Annotations,
+                    Capabilities::Annotations(AnnotationCapabilities {
+                        max_size_annotations: config.mail_annotations_max_size,
+                    }),
+                ),
+                (
+                    URI::ThreadPreview,
+                    Capabilities::ThreadPreview(ThreadPreviewCapabilities {
+                        properties: vec![
+                            "participants",
+                            "receivedAt",
+                            "unreadCount",
+                            "hasAttachment",
+                        ],
+                    }),
+                ),
+                (
+                    URI::MailboxCounters,
+                    Capabilities::MailboxCounters(MailboxCountersCapabilities {}),
+                ),
+                (
+                    URI::QuerySubscriptions,
+                    Capabilities::QuerySubscriptions(QuerySubscriptionsCapabilities {
+                        max_subscriptions: config.ws_max_query_subscriptions,
+                    }),
+                ),
+                (
+                    URI::LinkedSets,
+                    Capabilities::LinkedSets(LinkedSetsCapabilities {}),
+                ),
+                (
+                    URI::Forwarding,
+                    Capabilities::Forwarding(ForwardingCapabilities {
+                        max_addresses: config.mail_forward_max_addresses,
+                        may_forward_externally: !config.srs_secret.is_empty(),
+                    }),
+                ),
             ]),
             accounts: VecMap::new(),
             primary_accounts: VecMap::new(),
@@ -240,6 +353,11 @@ impl Session {
         );
     }
 
+    pub fn add_capability(&mut self, uri: String, capabilities: serde_json::Value) {
+        self.capabilities
+            .set(URI::Custom(uri), Capabilities::Custom(capabilities));
+    }
+
     pub fn set_state(&mut self, state: u32) {
         self.state = state;
     }
@@ -251,6 +369,56 @@ impl Session {
     pub fn base_url(&self) -> &str {
         &self.base_url
     }
+
+    // Returns a digest of each account sub-object.
+    fn account_digests(&self) -> VecMap<JMAPId, String> {
+        let mut digests = VecMap::with_capacity(self.accounts.len());
+        for (account_id, account) in self.accounts.iter() {
+            digests.append(
+                *account_id,
+                digest(&serde_json::to_vec(account).unwrap_or_default()),
+            );
+        }
+        digests
+    }
+
+    // Drops the accounts that did not change since a previous session object
+    // and returns the ids of the accounts that are no longer present.
+    fn retain_updated(
+        &mut self,
+        previous: &VecMap<JMAPId, String>,
+        current: &VecMap<JMAPId, String>,
+    ) -> Vec<JMAPId> {
+        let mut accounts = VecMap::with_capacity(self.accounts.len());
+        for (account_id, account) in std::mem::take(&mut self.accounts) {
+            if previous.get(&account_id) != current.get(&account_id) {
+                accounts.append(account_id, account);
+            }
+        }
+        self.accounts = accounts;
+        previous
+            .keys()
+            .filter(|account_id| !current.contains_key(account_id))
+            .copied()
+            .collect()
+    }
+}
+
+impl SessionHistory {
+    pub fn parse(settings: &EnvSettings) -> Self {
+        let size: u64 = settings.value(SETTINGS, "session-history-size");
+        SessionHistory {
+            digests: if size > 0 {
+                Cache::builder()
+                    .max_capacity(size)
+                    .time_to_idle(SESSION_HISTORY_TTL)
+                    .build()
+                    .into()
+            } else {
+                None
+            },
+        }
+    }
 }
 
 impl Account {
@@ -371,6 +539,7 @@ impl MailCapabilities {
                 "hasKeyword",
                 "allInThreadHaveKeyword",
                 "someInThreadHaveKeyword",
+                "relevance",
             ]
             .iter()
             .map(|s| s.to_string())
@@ -381,19 +550,25 @@ impl MailCapabilities {
 }
 
 pub async fn handle_jmap_session<T>(
+    request: HttpRequest,
+    params: web::Query<Params>,
     core: web::Data<JMAPServer<T>>,
     session: authorization::Session,
 ) -> Result<HttpResponse, RequestError>
 where
     T: for<'x> Store<'x> + 'static,
 {
+    let account_id = session.account_id();
     let store = core.store.clone();
-    match core
-        .clone()
+    let server = core.clone();
+    let mut response = match core
         .spawn_worker(move || {
-            let mut response = core.base_session.clone();
+            let mut response = server.base_session.clone();
 
             response.set_state(session.state());
+            for (uri, capabilities) in server.plugins.capabilities() {
+                response.add_capability(uri, capabilities);
+            }
 
             // Obtain member and shared accounts
             let acl = store.get_acl_token(session.account_id())?;
@@ -429,7 +604,13 @@ where
                         if !name.is_empty() { name } else { email },
                         matches!(ptype, Type::Individual),
                         is_readonly,
-                        Some(&[URI::Core, URI::Mail, URI::WebSocket]),
+                        Some(&[
+                            URI::Core,
+                            URI::Mail,
+                            URI::WebSocket,
+                            URI::Annotations,
+                            URI::ThreadPreview,
+                        ]),
                     );
                 }
             }
@@ -438,9 +619,60 @@ where
         })
         .await
     {
-        Ok(response) => Ok(HttpResponse::build(StatusCode::OK)
-            .insert_header(ContentType::json())
-            .body(serde_json::to_string(&response).unwrap_or_default())),
-        Err(_) => Err(RequestError::internal_server_error()),
+        Ok(response) => response,
+        Err(_) => return Err(RequestError::internal_server_error()),
+    };
+
+    // The ETag covers the whole session object, including the accounts
+    // omitted from differential responses.
+    let mut body = serde_json::to_string(&response).unwrap_or_default();
+    let etag = digest(body.as_bytes());
+    if if_none_match(&request, &etag) {
+        return Ok(HttpResponse::build(StatusCode::NOT_MODIFIED)
+            .insert_header((header::ETAG, format!("\"{}\"", etag)))
+            .finish());
     }
+
+    if let Some(history) = &core.session_history.digests {
+        let digests = Arc::new(response.account_digests());
+        // Unknown or expired ETags receive the full session object
+        if let Some((updated_from, previous)) = params.updated.as_deref().and_then(|updated| {
+            let updated = updated.trim_matches('"');
+            history
+                .get(&(account_id, updated.to_string()))
+                .map(|previous| (updated, previous))
+        }) {
+            let removed_accounts = response.retain_updated(&previous, &digests);
+            body = serde_json::to_string(&SessionUpdate {
+                session: &response,
+                updated_from,
+                removed_accounts,
+            })
+            .unwrap_or_default();
+        }
+        history.insert((account_id, etag.clone()), digests).await;
+    }
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .insert_header(ContentType::json())
+        .insert_header((header::ETAG, format!("\"{}\"", etag)))
+        .body(body))
+}
+
+fn digest(bytes: &[u8]) -> String {
+    blake3::hash(bytes).to_hex()[..16].to_string()
+}
+
+// Returns true when any of the entity tags in If-None-Match matches.
+fn if_none_match(request: &HttpRequest, etag: &str) -> bool {
+    request
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .map_or(false, |value| {
+            value.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/").trim_matches('"') == etag
+            })
+        })
 }
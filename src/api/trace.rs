@@ -0,0 +1,343 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use jmap::{request::ACLEnforce, types::jmap::JMAPId, SUPERUSER_ID};
+use store::{core::timing::ReadTimings, parking_lot::Mutex, tracing::error, AccountId, Store};
+
+use crate::{authorization::Session, JMAPServer};
+
+use super::RequestError;
+
+const MAX_DETAILS_LEN: usize = 1024;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CallTrace {
+    pub timestamp: u64,
+    #[serde(rename(serialize = "accountId"))]
+    pub account_id: JMAPId,
+    pub method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+    #[serde(rename(serialize = "totalUs"))]
+    pub total_us: u64,
+    #[serde(rename(serialize = "storeReads"))]
+    pub store_reads: u32,
+    #[serde(rename(serialize = "storeReadUs"))]
+    pub store_read_us: u64,
+    #[serde(rename(serialize = "blobReads"))]
+    pub blob_reads: u32,
+    #[serde(rename(serialize = "blobReadUs"))]
+    pub blob_read_us: u64,
+    #[serde(rename(serialize = "requestParseUs"))]
+    pub request_parse_us: u64,
+    #[serde(rename(serialize = "requestSerializeUs"))]
+    pub request_serialize_us: u64,
+}
+
+pub struct TraceBuffer {
+    capacity: usize,
+    traces: Mutex<VecDeque<CallTrace>>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct Params {
+    limit: Option<usize>,
+}
+
+impl CallTrace {
+    pub fn new(
+        account_id: AccountId,
+        method: impl Into<String>,
+        details: Option<String>,
+        elapsed: Duration,
+        timings: ReadTimings,
+    ) -> Self {
+        CallTrace {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            account_id: JMAPId::from(account_id),
+            method: method.into(),
+            details: details.map(|mut details| {
+                if details.len() > MAX_DETAILS_LEN {
+                    let mut pos = MAX_DETAILS_LEN;
+                    while !details.is_char_boundary(pos) {
+                        pos -= 1;
+                    }
+                    details.truncate(pos);
+                }
+                details
+            }),
+            total_us: elapsed.as_micros() as u64,
+            store_reads: timings.store_reads,
+            store_read_us: timings.store_read_time.as_micros() as u64,
+            blob_reads: timings.blob_reads,
+            blob_read_us: timings.blob_read_time.as_micros() as u64,
+            request_parse_us: 0,
+            request_serialize_us: 0,
+        }
+    }
+}
+
+impl TraceBuffer {
+    pub fn new(capacity: usize) -> Self {
+        TraceBuffer {
+            capacity,
+            traces: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Adds the traces of a completed request, evicting the oldest entries
+    /// once the buffer is full. Parsing and serialization are measured for
+    /// the request as a whole and are recorded on each of its calls.
+    pub fn push(&self, traces: Vec<CallTrace>, parse_time: Duration, serialize_time: Duration) {
+        if !self.is_enabled() || traces.is_empty() {
+            return;
+        }
+
+        let mut buffer = self.traces.lock();
+        for mut trace in traces {
+            if buffer.len() == self.capacity {
+                buffer.pop_front();
+            }
+            trace.request_parse_us = parse_time.as_micros() as u64;
+            trace.request_serialize_us = serialize_time.as_micros() as u64;
+            buffer.push_back(trace);
+        }
+    }
+
+    /// Returns the slowest calls currently in the buffer.
+    pub fn slowest(&self, limit: usize) -> Vec<CallTrace> {
+        let mut traces = self.traces.lock().iter().cloned().collect::<Vec<_>>();
+        traces.sort_unstable_by(|a, b| b.total_us.cmp(&a.total_us));
+        traces.truncate(limit);
+        traces
+    }
+}
+
+pub async fn handle_admin_traces<T>(
+    params: web::Query<Params>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let account_id = session.account_id();
+    match core
+        .spawn_worker(move || Ok(store.get_acl_token(account_id)?.is_member(SUPERUSER_ID)))
+        .await
+    {
+        Ok(true) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(core.traces.slowest(params.limit.unwrap_or(100)))),
+        Ok(false) => Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Failed to obtain ACL token: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+pub async fn handle_admin_write_metrics<T>(
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let account_id = session.account_id();
+    match core
+        .spawn_worker(move || Ok(store.get_acl_token(account_id)?.is_member(SUPERUSER_ID)))
+        .await
+    {
+        Ok(true) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(core.store.write_metrics.snapshot())),
+        Ok(false) => Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Failed to obtain ACL token: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+pub async fn handle_admin_bitmap_cache_metrics<T>(
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let account_id = session.account_id();
+    match core
+        .spawn_worker(move || Ok(store.get_acl_token(account_id)?.is_member(SUPERUSER_ID)))
+        .await
+    {
+        Ok(true) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(core.store.bitmap_cache.snapshot())),
+        Ok(false) => Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Failed to obtain ACL token: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+pub async fn handle_admin_auth_metrics<T>(
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let account_id = session.account_id();
+    match core
+        .spawn_worker(move || Ok(store.get_acl_token(account_id)?.is_member(SUPERUSER_ID)))
+        .await
+    {
+        Ok(true) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(core.sessions.snapshot())),
+        Ok(false) => Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Failed to obtain ACL token: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+pub async fn handle_admin_worker_metrics<T>(
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let account_id = session.account_id();
+    match core
+        .spawn_worker(move || Ok(store.get_acl_token(account_id)?.is_member(SUPERUSER_ID)))
+        .await
+    {
+        Ok(true) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(core.worker_pools.snapshot())),
+        Ok(false) => Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Failed to obtain ACL token: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+pub async fn handle_admin_index_metrics<T>(
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let account_id = session.account_id();
+    match core
+        .spawn_worker(move || {
+            Ok(
+                if store.get_acl_token(account_id)?.is_member(SUPERUSER_ID) {
+                    Some(store.index_stats()?)
+                } else {
+                    None
+                },
+            )
+        })
+        .await
+    {
+        Ok(Some(stats)) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(stats)),
+        Ok(None) => Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Failed to obtain index statistics: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use store::core::timing::ReadTimings;
+
+    use super::{CallTrace, TraceBuffer};
+
+    #[test]
+    fn trace_buffer() {
+        let buffer = TraceBuffer::new(3);
+        for (pos, elapsed) in [5, 1, 8, 3, 2].into_iter().enumerate() {
+            buffer.push(
+                vec![CallTrace::new(
+                    pos as u32,
+                    "Email/query",
+                    Some("a".repeat(2000)),
+                    Duration::from_micros(elapsed),
+                    ReadTimings::default(),
+                )],
+                Duration::from_micros(10),
+                Duration::ZERO,
+            );
+        }
+
+        // Only the last three calls are kept, slowest first
+        let traces = buffer.slowest(10);
+        assert_eq!(
+            traces.iter().map(|t| t.total_us).collect::<Vec<_>>(),
+            vec![8, 3, 2]
+        );
+        assert_eq!(traces[0].request_parse_us, 10);
+        assert_eq!(traces[0].details.as_ref().unwrap().len(), 1024);
+        assert_eq!(buffer.slowest(1).len(), 1);
+
+        let disabled = TraceBuffer::new(0);
+        assert!(!disabled.is_enabled());
+    }
+}
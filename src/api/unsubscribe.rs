@@ -0,0 +1,226 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use actix_web::web;
+use futures::future::join_all;
+use jmap::request::ACLEnforce;
+use jmap_mail::mail::unsubscribe::{
+    EmailUnsubscribeRequest, EmailUnsubscribeResponse, JMAPMailUnsubscribe, UnsubscribeMethod,
+};
+use reqwest::{header::CONTENT_TYPE, redirect::Policy, Url};
+use store::{
+    core::timing::ReadTimings,
+    tracing::{debug, info},
+    AccountId, Store,
+};
+
+use crate::JMAPServer;
+
+// Email/unsubscribe looks up the unsubscribe URIs on a store worker, sends the
+// one-click requests from the async runtime and then submits any mailto
+// requests through EmailSubmission/set.
+pub async fn handle_email_unsubscribe<T>(
+    mut request: EmailUnsubscribeRequest,
+    core: &web::Data<JMAPServer<T>>,
+    account_id: AccountId,
+) -> (jmap::Result<EmailUnsubscribeResponse>, Option<ReadTimings>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let (result, read_timings) = core
+        .spawn_traced_jmap_request(move || {
+            let acl = store
+                .get_acl_token(account_id)?
+                .assert_is_member(request.account_id.get_document_id())?;
+            request.acl = acl.clone().into();
+            Ok((acl, store.mail_unsubscribe(request)?))
+        })
+        .await;
+    let (acl, mut response) = match result {
+        Ok(result) => result,
+        Err(err) => return (Err(err), read_timings),
+    };
+
+    // Send one-click requests
+    let timeout = Duration::from_millis(core.store.config.mail_unsubscribe_timeout);
+    let pending = std::mem::take(&mut response.pending);
+    let results = join_all(pending.iter().map(|pending| async move {
+        if let Some(url) = &pending.https_url {
+            match one_click_unsubscribe(core, url, timeout).await {
+                Ok(_) => true,
+                Err(err) => {
+                    debug!("One-click unsubscribe request to {} failed: {}", url, err);
+                    false
+                }
+            }
+        } else {
+            false
+        }
+    }))
+    .await;
+
+    let mut mailto = Vec::new();
+    for (pending, is_unsubscribed) in pending.into_iter().zip(results) {
+        if is_unsubscribed {
+            response.unsubscribed(pending, UnsubscribeMethod::Https);
+        } else if pending.mailto_url.is_some() {
+            mailto.push(pending);
+        } else {
+            response.not_unsubscribed(pending);
+        }
+    }
+    if mailto.is_empty() {
+        return (Ok(response), read_timings);
+    }
+
+    // Submit mailto requests
+    let store = core.store.clone();
+    (
+        core.spawn_jmap_request(move || {
+            store.mail_unsubscribe_mailto(acl, &mut response, mailto)?;
+            Ok(response)
+        })
+        .await,
+        read_timings,
+    )
+}
+
+// Sends an RFC 8058 one-click unsubscribe request. The connection is pinned to
+// the resolved address, which has to be public, and redirects are not followed.
+async fn one_click_unsubscribe<T>(
+    core: &JMAPServer<T>,
+    url: &str,
+    timeout: Duration,
+) -> Result<(), String>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let url = Url::parse(url).map_err(|err| err.to_string())?;
+    if url.scheme() != "https" {
+        return Err("only https URLs are supported".to_string());
+    }
+    let host = url.host_str().ok_or_else(|| "missing host".to_string())?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let ips = if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse() {
+        vec![ip]
+    } else {
+        core.dns
+            .ip_lookup(host)
+            .await
+            .map_err(|err| err.to_string())?
+    };
+    let ip = match ips.first() {
+        Some(ip) if ips.iter().all(|ip| is_public_ip(*ip)) => *ip,
+        Some(ip) => return Err(format!("{} resolves to non-public address {}", host, ip)),
+        None => return Err(format!("{} does not resolve", host)),
+    };
+
+    let response = reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(Policy::none())
+        .resolve(host, SocketAddr::new(ip, port))
+        .build()
+        .map_err(|err| err.to_string())?
+        .post(url)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if response.status().is_success() {
+        info!("One-click unsubscribe request to {} succeeded.", host);
+        Ok(())
+    } else {
+        Err(format!("unexpected status {}", response.status()))
+    }
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || octets[0] == 0
+                || octets[0] >= 240
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+                || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+                || (octets[0] == 198 && (octets[1] & 0xfe) == 18))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (segments[0] & 0xfe00) == 0xfc00
+                || (segments[0] & 0xffc0) == 0xfe80
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+                || (segments[0] == 0x0064 && segments[1] == 0xff9b))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_public_ip;
+
+    #[test]
+    fn unsubscribe_public_ips() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use jmap::types::jmap::JMAPId;
+use jmap_mail::vacation_response::dedup::JMAPVacationDedup;
+use store::{tracing::error, Store};
+
+use crate::{authorization::Session, JMAPServer};
+
+use super::{migration::is_superuser, RequestError};
+
+pub async fn handle_admin_vacation_dedup<T>(
+    path: web::Path<(JMAPId,)>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    vacation_dedup(path.into_inner().0, core, session, false).await
+}
+
+pub async fn handle_admin_vacation_dedup_reset<T>(
+    path: web::Path<(JMAPId,)>,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    vacation_dedup(path.into_inner().0, core, session, true).await
+}
+
+async fn vacation_dedup<T>(
+    id: JMAPId,
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+    reset: bool,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let account_id = id.get_document_id();
+
+    // Only administrators may inspect or reset the vacation response database
+    is_superuser(&core, &session).await?;
+
+    // Resets have to be executed by the leader
+    if reset && !core.is_leader() {
+        return Err(RequestError::unavailable());
+    }
+
+    let store = core.store.clone();
+    match core
+        .spawn_worker(move || {
+            if reset {
+                store.vacation_dedup_reset(account_id)
+            } else {
+                store.vacation_dedup_get(account_id)
+            }
+        })
+        .await
+    {
+        Ok(Some(report)) => {
+            if let Some(change_id) = report.change_id {
+                if core.is_in_cluster() {
+                    core.commit_index(change_id).await;
+                }
+            }
+
+            Ok(HttpResponse::build(StatusCode::OK)
+                .insert_header(ContentType::json())
+                .json(report))
+        }
+        Ok(None) => Err(RequestError::not_found()),
+        Err(err) => {
+            error!("Failed to access vacation response database: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
@@ -35,21 +35,23 @@ use actix_web::{
 };
 use futures::FutureExt;
 use futures_util::future::LocalBoxFuture;
-use jmap::types::jmap::JMAPId;
+use jmap::{types::jmap::JMAPId, SUPERUSER_ID};
 use jmap_mail::mail_parser::decoders::base64::decode_base64;
 use jmap_sharing::principal::account::JMAPAccountStore;
 use store::{
     core::error::StoreError,
-    tracing::{debug, error, warn},
+    tracing::{debug, error, info, warn},
     AccountId, Store,
 };
 
 use crate::{
-    api::{Redirect, RequestError},
+    api::{Redirect, RequestError, API_PREFIX},
+    server::workers::WorkerPool,
+    services::onboarding::spawn_onboarding,
     JMAPServer,
 };
 
-use super::{rate_limit::InFlightRequest, Session};
+use super::{proxy::TrustedProxies, rate_limit::InFlightRequest, Session};
 
 pub struct SessionMiddleware<S, T>
 where
@@ -92,10 +94,13 @@ where
                     .unwrap_or("");
 
                 // Check whether a redirect is needed
+                let route_path = request_path
+                    .strip_prefix(API_PREFIX)
+                    .unwrap_or(request_path);
                 let do_redirect = !core.is_up_to_date()
-                    || request_path.starts_with("/jmap/upload")
-                    || request_path.starts_with("/jmap/ws")
-                    || request_path.starts_with("/jmap/eventsource")
+                    || route_path.starts_with("/jmap/upload")
+                    || route_path.starts_with("/jmap/ws")
+                    || (route_path.starts_with("/jmap/eventsource") && core.push_broker.is_none())
                     || request_path.starts_with("/auth")
                     || request_path.starts_with("/.well-known/oauth-authorization-server");
 
@@ -128,14 +133,18 @@ where
                 .and_then(|h| h.to_str().ok())
                 .and_then(|h| h.split_once(' ').map(|(l, t)| (l, t.trim())))
             {
-                if let Some(session) = core.sessions.get(&token.to_string()) {
+                if let Some(session) = core.sessions.get(token) {
                     authorized = session.into();
                 } else {
+                    // Sessions revoked while their credentials are being verified
+                    // must not be cached.
+                    let generation = core.sessions.generation();
                     let session = if mechanism.eq_ignore_ascii_case("basic") {
                         // Enforce rate limit for authentication requests
-                        core.is_auth_allowed(
-                            req.remote_address(core.store.config.use_forwarded_header),
-                        )
+                        core.is_auth_allowed(req.remote_address(
+                            &core.trusted_proxies,
+                            core.store.config.use_forwarded_header,
+                        ))
                         .await?;
 
                         // Decode the base64 encoded credentials
@@ -148,30 +157,49 @@ where
                             })
                         {
                             let store = core.store.clone();
-                            core.spawn_worker(move || {
-                                // Validate password
-                                Ok(
-                                    if let Some(account_id) = store.authenticate(&login, &secret)? {
-                                        Session::new(
-                                            account_id,
-                                            store.get_acl_token(account_id)?.as_ref(),
-                                        )
-                                        .into()
-                                    } else {
-                                        None
-                                    },
-                                )
-                            })
-                            .await
+                            let require_passkey_admin = core.oauth.require_passkey_admin;
+                            let result = core
+                                .spawn_worker_on(WorkerPool::Crypto, move || {
+                                    // Validate password
+                                    Ok(
+                                        if let Some(account_id) =
+                                            store.authenticate(&login, &secret)?
+                                        {
+                                            let acl_token = store.get_acl_token(account_id)?;
+                                            if require_passkey_admin
+                                                && acl_token.is_member(SUPERUSER_ID)
+                                            {
+                                                debug!(
+                                                    concat!(
+                                                        "Basic auth failed: Account {} has to ",
+                                                        "sign in with a passkey."
+                                                    ),
+                                                    JMAPId::from(account_id)
+                                                );
+                                                None
+                                            } else {
+                                                Session::new(account_id, acl_token.as_ref()).into()
+                                            }
+                                        } else {
+                                            None
+                                        },
+                                    )
+                                })
+                                .await;
+                            if let Ok(session) = &result {
+                                core.sessions.record_verification(session.is_some());
+                            }
+                            result
                         } else {
                             debug!("Failed to decode Basic auth request.",);
                             Ok(None)
                         }
                     } else if mechanism.eq_ignore_ascii_case("bearer") {
                         // Enforce anonymous rate limit for bearer auth requests
-                        core.is_anonymous_allowed(
-                            req.remote_address(core.store.config.use_forwarded_header),
-                        )
+                        core.is_anonymous_allowed(req.remote_address(
+                            &core.trusted_proxies,
+                            core.store.config.use_forwarded_header,
+                        ))
                         .await?;
 
                         // Validate OAuth bearer token
@@ -187,17 +215,45 @@ where
                                 })
                                 .await
                             }
-                            Err(StoreError::DeserializeError(e)) => {
-                                debug!("Failed to deserialize access token: {}", e);
-                                Ok(None)
+                            Err(StoreError::DeserializeError(_)) => {
+                                // Try validating the token as an impersonation token
+                                match core.validate_impersonation_token(token).await {
+                                    Ok((account_id, scope)) => {
+                                        info!(
+                                            concat!(
+                                                "Impersonation token issued by account {} ",
+                                                "used to access account {} (scope {:?})."
+                                            ),
+                                            scope.issued_by,
+                                            account_id,
+                                            scope.names()
+                                        );
+                                        let store = core.store.clone();
+                                        core.spawn_worker(move || {
+                                            Ok(Session::new(
+                                                account_id,
+                                                store.get_acl_token(account_id)?.as_ref(),
+                                            )
+                                            .with_scope(scope)
+                                            .into())
+                                        })
+                                        .await
+                                    }
+                                    Err(StoreError::DeserializeError(e)) => {
+                                        debug!("Failed to deserialize access token: {}", e);
+                                        Ok(None)
+                                    }
+                                    Err(err) => Err(err),
+                                }
                             }
                             Err(err) => Err(err),
                         }
                     } else {
                         // Enforce anonymous rate limit
-                        core.is_anonymous_allowed(
-                            req.remote_address(core.store.config.use_forwarded_header),
-                        )
+                        core.is_anonymous_allowed(req.remote_address(
+                            &core.trusted_proxies,
+                            core.store.config.use_forwarded_header,
+                        ))
                         .await?;
 
                         Ok(None)
@@ -206,9 +262,20 @@ where
                     match session {
                         Ok(Some(session)) => {
                             // Authentication successful, add token to session store
-                            core.sessions
-                                .insert(token.to_string(), session.clone())
-                                .await;
+                            if !core
+                                .sessions
+                                .insert(token, session.clone(), generation)
+                                .await
+                            {
+                                debug!(
+                                    "Account {} was revoked during authentication.",
+                                    JMAPId::from(session.account_id())
+                                );
+                                return service.call(req).await;
+                            }
+                            if session.scope().is_none() {
+                                spawn_onboarding(core.clone(), session.account_id());
+                            }
                             authorized = session.into();
                         }
                         Ok(None) => {
@@ -224,6 +291,7 @@ where
 
             if let Some(session) = authorized {
                 let in_flight_request = core.is_account_allowed(session.account_id).await?;
+                core.warmup.record_access(session.account_id);
 
                 // Add session to request
                 req.extensions_mut()
@@ -234,14 +302,21 @@ where
                     .path_and_query()
                     .map(|pq| pq.as_str())
                     .unwrap_or("");
-                if request_path == "/auth" || request_path == "/auth/code" {
+                if request_path == "/auth"
+                    || request_path == "/auth/code"
+                    || request_path == "/auth/passkey"
+                {
                     // OAuth authentication endpoints
-                    core.is_auth_allowed(req.remote_address(core.store.config.use_forwarded_header))
-                        .await?
+                    core.is_auth_allowed(req.remote_address(
+                        &core.trusted_proxies,
+                        core.store.config.use_forwarded_header,
+                    ))
+                    .await?
                 } else {
-                    core.is_anonymous_allowed(
-                        req.remote_address(core.store.config.use_forwarded_header),
-                    )
+                    core.is_anonymous_allowed(req.remote_address(
+                        &core.trusted_proxies,
+                        core.store.config.use_forwarded_header,
+                    ))
                     .await?
                 }
             }
@@ -253,17 +328,37 @@ where
 }
 
 trait ServiceRequestAddr {
-    fn remote_address(&self, use_forwarded: bool) -> RemoteAddress;
+    fn remote_address(
+        &self,
+        trusted_proxies: &TrustedProxies,
+        use_forwarded: bool,
+    ) -> RemoteAddress;
 }
 
 impl ServiceRequestAddr for ServiceRequest {
-    fn remote_address(&self, use_forwarded: bool) -> RemoteAddress {
+    fn remote_address(
+        &self,
+        trusted_proxies: &TrustedProxies,
+        use_forwarded: bool,
+    ) -> RemoteAddress {
         let peer_addr = self
             .peer_addr()
             .map(|addr| addr.ip())
             .unwrap_or_else(|| Ipv4Addr::new(127, 0, 0, 1).into());
 
-        if use_forwarded || peer_addr.is_loopback() {
+        if !trusted_proxies.is_empty() {
+            // Proxies may append their own header line after the ones sent by the client
+            let header_value = self
+                .headers()
+                .get_all(trusted_proxies.header_name())
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(",");
+            RemoteAddress::IpAddress(trusted_proxies.client_ip(
+                peer_addr,
+                Some(header_value.as_str()).filter(|value| !value.is_empty()),
+            ))
+        } else if use_forwarded || peer_addr.is_loopback() {
             self.connection_info()
                 .realip_remote_addr()
                 .map(|ip| RemoteAddress::IpAddressFwd(ip.to_string()))
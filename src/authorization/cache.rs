@@ -0,0 +1,284 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use store::{
+    blake3,
+    config::{env_settings::EnvSettings, settings::Setting},
+    moka::{future::Cache, sync::Cache as SyncCache},
+    tracing::error,
+    AccountId, Store,
+};
+
+use crate::{cluster, JMAPServer};
+
+use super::Session;
+
+pub const SETTINGS: &[Setting] = &[Setting::seconds("cache-ttl-auth").default("1800")];
+
+const DEFAULT_AUTH_CACHE_TTL: u64 = 30 * 60;
+
+/// Authenticated sessions keyed by a hash of the credentials presented in the
+/// `Authorization` header, so that credential verification only runs once per
+/// TTL window rather than on every request.
+pub struct AuthCache {
+    sessions: Cache<[u8; 32], Session>,
+    /// Revocation generation at which each account was last revoked, used to
+    /// discard sessions whose verification started before the revocation.
+    revoked: SyncCache<AccountId, u64>,
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    verifications: AtomicU64,
+    verification_failures: AtomicU64,
+    revocations: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct AuthCacheMetrics {
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+    #[serde(rename = "hitRate")]
+    pub hit_rate: f64,
+    #[serde(rename = "passwordVerifications")]
+    pub verifications: u64,
+    #[serde(rename = "passwordVerificationFailures")]
+    pub verification_failures: u64,
+    pub revocations: u64,
+}
+
+impl AuthCache {
+    pub fn parse(settings: &EnvSettings) -> Self {
+        AuthCache::new(Duration::from_secs(
+            settings
+                .parse("cache-ttl-auth")
+                .filter(|v| *v > 0)
+                .unwrap_or(DEFAULT_AUTH_CACHE_TTL),
+        ))
+    }
+
+    pub fn new(ttl: Duration) -> Self {
+        AuthCache {
+            sessions: Cache::builder()
+                .initial_capacity(128)
+                .time_to_live(ttl)
+                .support_invalidation_closures()
+                .build(),
+            revoked: SyncCache::builder().time_to_live(ttl).build(),
+            generation: 0.into(),
+            hits: 0.into(),
+            misses: 0.into(),
+            verifications: 0.into(),
+            verification_failures: 0.into(),
+            revocations: 0.into(),
+        }
+    }
+
+    pub fn get(&self, credentials: &str) -> Option<Session> {
+        if let Some(session) = self
+            .sessions
+            .get(&credential_hash(credentials))
+            .filter(|session| !session.scope().map_or(false, |scope| scope.is_expired()))
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            session.into()
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+
+    /// Returns the current revocation generation, which has to be obtained
+    /// before verifying the credentials of a session passed to `insert`.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Caches a session unless its account was revoked after `generation`.
+    pub async fn insert(&self, credentials: &str, session: Session, generation: u64) -> bool {
+        let account_id = session.account_id();
+        if self.is_revoked_since(account_id, generation) {
+            return false;
+        }
+
+        // A revocation might have run between the check and the insertion
+        let key = credential_hash(credentials);
+        self.sessions.insert(key, session).await;
+        if self.is_revoked_since(account_id, generation) {
+            self.sessions.invalidate(&key).await;
+            false
+        } else {
+            true
+        }
+    }
+
+    fn is_revoked_since(&self, account_id: AccountId, generation: u64) -> bool {
+        self.revoked
+            .get(&account_id)
+            .map_or(false, |revoked_at| revoked_at > generation)
+    }
+
+    /// Records the outcome of a password hash verification.
+    pub fn record_verification(&self, is_valid: bool) {
+        self.verifications.fetch_add(1, Ordering::Relaxed);
+        if !is_valid {
+            self.verification_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Drops all cached sessions belonging to the given accounts.
+    pub fn revoke(&self, account_ids: &[AccountId]) {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        for &account_id in account_ids {
+            self.revoked.insert(account_id, generation);
+            if let Err(err) = self
+                .sessions
+                .invalidate_entries_if(move |_, session| session.account_id() == account_id)
+            {
+                error!(
+                    "Failed to revoke sessions of account {}: {}",
+                    account_id, err
+                );
+            }
+        }
+        self.revocations
+            .fetch_add(account_ids.len() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> AuthCacheMetrics {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        AuthCacheMetrics {
+            entries: self.sessions.entry_count(),
+            hits,
+            misses,
+            hit_rate: if hits + misses > 0 {
+                hits as f64 / (hits + misses) as f64
+            } else {
+                0.0
+            },
+            verifications: self.verifications.load(Ordering::Relaxed),
+            verification_failures: self.verification_failures.load(Ordering::Relaxed),
+            revocations: self.revocations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn credential_hash(credentials: &str) -> [u8; 32] {
+    *blake3::hash(credentials.as_bytes()).as_bytes()
+}
+
+impl<T> JMAPServer<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Invalidates the cached sessions of the given accounts on this node and,
+    /// when running in a cluster, on all peers.
+    pub async fn revoke_credentials(&self, account_ids: Vec<AccountId>) {
+        if account_ids.is_empty() {
+            return;
+        }
+        self.sessions.revoke(&account_ids);
+        if let Some(cluster) = &self.cluster {
+            if let Err(err) = cluster
+                .tx
+                .send(cluster::Event::RevokeCredentials { account_ids })
+                .await
+            {
+                error!("Failed to send revocation to cluster: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use store::core::acl::ACLToken;
+
+    use super::{AuthCache, Session};
+
+    #[tokio::test]
+    async fn auth_cache_revoke() {
+        let cache = AuthCache::new(Duration::from_secs(60));
+        let acl_token = ACLToken {
+            member_of: vec![1],
+            access_to: vec![],
+        };
+
+        let generation = cache.generation();
+        for (credentials, account_id) in [
+            ("Basic am9objpzZWNyZXQ=", 1),
+            ("Bearer abc", 1),
+            ("Basic amFuZTpzZWNyZXQ=", 2),
+        ] {
+            assert!(
+                cache
+                    .insert(
+                        credentials,
+                        Session::new(account_id, &acl_token),
+                        generation
+                    )
+                    .await
+            );
+        }
+        assert!(cache.get("Basic am9objpzZWNyZXQ=").is_some());
+        assert!(cache.get("Basic other").is_none());
+
+        cache.revoke(&[1]);
+        assert!(cache.get("Basic am9objpzZWNyZXQ=").is_none());
+        assert!(cache.get("Bearer abc").is_none());
+        assert_eq!(cache.get("Basic amFuZTpzZWNyZXQ=").unwrap().account_id(), 2);
+
+        // Verifications that started before the revocation are not cached
+        assert!(
+            !cache
+                .insert(
+                    "Basic am9objpzZWNyZXQ=",
+                    Session::new(1, &acl_token),
+                    generation
+                )
+                .await
+        );
+        assert!(cache.get("Basic am9objpzZWNyZXQ=").is_none());
+        assert!(
+            cache
+                .insert(
+                    "Basic am9objpzZWNyZXQ=",
+                    Session::new(1, &acl_token),
+                    cache.generation()
+                )
+                .await
+        );
+
+        let metrics = cache.snapshot();
+        assert_eq!((metrics.hits, metrics.misses), (2, 4));
+        assert_eq!(metrics.revocations, 1);
+    }
+}
@@ -0,0 +1,190 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use jmap::error::method::MethodError;
+use jmap_sharing::principal::account::JMAPAccountStore;
+use store::{core::error::StoreError, write::ttl::now, AccountId, Store};
+
+use crate::{api::method, JMAPServer};
+
+pub const IMPERSONATION_GRANT: &str = "impersonation_token";
+const CLIENT_ID_PREFIX: &str = "imp.";
+
+const SCOPE_READ_ONLY: u8 = 1 << 0;
+const SCOPE_MAIL_ONLY: u8 = 1 << 1;
+
+/// Restrictions of a session opened with an impersonation token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scope {
+    pub read_only: bool,
+    pub mail_only: bool,
+    pub issued_by: AccountId,
+    pub expires_at: u64,
+}
+
+impl Scope {
+    pub fn new(issued_by: AccountId) -> Self {
+        Scope {
+            read_only: false,
+            mail_only: false,
+            issued_by,
+            expires_at: 0,
+        }
+    }
+
+    /// Parses a list of scope names, returning the name of the first unknown scope on failure.
+    pub fn parse<'x>(
+        issued_by: AccountId,
+        names: impl IntoIterator<Item = &'x str>,
+    ) -> Result<Self, String> {
+        let mut scope = Scope::new(issued_by);
+        for name in names {
+            match name {
+                "read-only" => scope.read_only = true,
+                "mail-only" => scope.mail_only = true,
+                _ => return Err(name.to_string()),
+            }
+        }
+        Ok(scope)
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.read_only {
+            names.push("read-only");
+        }
+        if self.mail_only {
+            names.push("mail-only");
+        }
+        names
+    }
+
+    pub fn is_expired(&self) -> bool {
+        now() >= self.expires_at
+    }
+
+    pub fn validate_method(&self, method: &method::Request) -> Result<(), MethodError> {
+        if self.read_only && !method.is_read_only() {
+            Err(MethodError::AccountReadOnly)
+        } else if self.mail_only && !method.is_mail() {
+            Err(MethodError::Forbidden(format!(
+                "Method {} is not allowed by the token scope.",
+                method.name()
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn to_client_id(&self) -> String {
+        let mut flags = 0;
+        if self.read_only {
+            flags |= SCOPE_READ_ONLY;
+        }
+        if self.mail_only {
+            flags |= SCOPE_MAIL_ONLY;
+        }
+        format!("{}{}.{}", CLIENT_ID_PREFIX, flags, self.issued_by)
+    }
+
+    fn from_client_id(client_id: &str, expires_in: u64) -> Option<Self> {
+        let (flags, issued_by) = client_id.strip_prefix(CLIENT_ID_PREFIX)?.split_once('.')?;
+        let flags = flags.parse::<u8>().ok()?;
+        Scope {
+            read_only: flags & SCOPE_READ_ONLY != 0,
+            mail_only: flags & SCOPE_MAIL_ONLY != 0,
+            issued_by: issued_by.parse().ok()?,
+            expires_at: now() + expires_in,
+        }
+        .into()
+    }
+}
+
+impl<T> JMAPServer<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    pub async fn issue_impersonation_token(
+        &self,
+        account_id: AccountId,
+        scope: &Scope,
+        expires_in: u64,
+    ) -> store::Result<String> {
+        let store = self.store.clone();
+        let password_hash = self
+            .spawn_worker(move || {
+                store
+                    .get_account_secret_hash(account_id)?
+                    .ok_or_else(|| StoreError::DeserializeError("Account no longer exists".into()))
+            })
+            .await?;
+
+        self.encode_access_token(
+            IMPERSONATION_GRANT,
+            account_id,
+            &password_hash,
+            &scope.to_client_id(),
+            expires_in,
+        )
+    }
+
+    pub async fn validate_impersonation_token(
+        &self,
+        token: &str,
+    ) -> store::Result<(AccountId, Scope)> {
+        let (account_id, client_id, expires_in) = self
+            .validate_access_token(IMPERSONATION_GRANT, token)
+            .await?;
+        Scope::from_client_id(&client_id, expires_in)
+            .map(|scope| (account_id, scope))
+            .ok_or_else(|| StoreError::DeserializeError("Invalid token scope.".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scope;
+
+    #[test]
+    fn scope_client_id() {
+        let scope = Scope::parse(3, ["read-only", "mail-only"]).unwrap();
+        let client_id = scope.to_client_id();
+        assert!(client_id.len() <= 20);
+
+        let parsed = Scope::from_client_id(&client_id, 60).unwrap();
+        assert!(parsed.read_only && parsed.mail_only);
+        assert_eq!(parsed.issued_by, 3);
+        assert!(!parsed.is_expired());
+        assert_eq!(parsed.names(), ["read-only", "mail-only"]);
+
+        assert_eq!(
+            Scope::from_client_id(&Scope::new(u32::MAX).to_client_id(), 0).map(|s| s.issued_by),
+            Some(u32::MAX)
+        );
+        assert!(Scope::from_client_id(&Scope::new(1).to_client_id(), 0)
+            .unwrap()
+            .is_expired());
+        assert!(Scope::from_client_id("my-client", 60).is_none());
+        assert_eq!(Scope::parse(1, ["admin"]), Err("admin".to_string()));
+    }
+}
@@ -22,8 +22,12 @@
 */
 
 pub mod auth;
+pub mod cache;
+pub mod impersonate;
 pub mod oauth;
+pub mod proxy;
 pub mod rate_limit;
+pub mod webauthn;
 
 use std::{
     collections::hash_map::DefaultHasher,
@@ -40,6 +44,7 @@ use store::{blake3, core::acl::ACLToken, AccountId};
 pub struct Session {
     account_id: AccountId,
     state: u32,
+    scope: Option<impersonate::Scope>,
 }
 
 impl Session {
@@ -52,13 +57,25 @@ impl Session {
         Self {
             account_id,
             state: s.finish() as u32,
+            scope: None,
         }
     }
 
+    pub fn with_scope(mut self, scope: impersonate::Scope) -> Self {
+        self.scope = scope.into();
+        self
+    }
+
     pub fn account_id(&self) -> AccountId {
         self.account_id
     }
 
+    /// Returns the scope of the session when it was opened with an
+    /// impersonation token.
+    pub fn scope(&self) -> Option<&impersonate::Scope> {
+        self.scope.as_ref()
+    }
+
     pub fn state(&self) -> u32 {
         self.state
     }
@@ -21,16 +21,10 @@
  * for more details.
 */
 
-use std::{
-    sync::{
-        atomic::{self, AtomicU32},
-        Arc,
-    },
-    time::{Instant, SystemTime},
-};
+use std::time::SystemTime;
 
-use crate::JMAPServer;
-use actix_web::{http::header, web, HttpResponse};
+use crate::{api::RequestError, JMAPServer};
+use actix_web::{http::header, web, HttpResponse, ResponseError};
 use jmap_mail::{
     mail_builder::encoders::base64::base64_encode, mail_parser::decoders::base64::decode_base64,
 };
@@ -45,12 +39,16 @@ use store::{
         distributions::{Alphanumeric, Standard},
         thread_rng, Rng,
     },
-    serialize::leb128::{Leb128Iterator, Leb128Vec},
+    serialize::{
+        leb128::{Leb128Iterator, Leb128Vec},
+        StoreDeserialize, StoreSerialize,
+    },
     tracing::{debug, error},
+    write::ttl::expires_in,
     AccountId, Store,
 };
 
-use super::SymmetricEncrypt;
+use super::{webauthn::RelyingParty, SymmetricEncrypt};
 
 const OAUTH_HTML_HEADER: &str = include_str!("../../resources/oauth/header.htx");
 const OAUTH_HTML_FOOTER: &str = include_str!("../../resources/oauth/footer.htx");
@@ -85,18 +83,35 @@ pub struct OAuth {
     pub expiry_token: u64,
     pub expiry_refresh_token: u64,
     pub expiry_refresh_token_renew: u64,
+    pub expiry_impersonation_token: u64,
+    pub max_expiry_impersonation_token: u64,
     pub max_auth_attempts: u32,
+    pub relying_party: RelyingParty,
+    pub require_passkey_admin: bool,
     pub metadata: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OAuthCode {
-    pub status: AtomicU32,
-    pub account_id: AtomicU32,
-    pub expiry: Instant,
+    pub status: u32,
+    pub account_id: AccountId,
+    pub expires_at: u64, // milliseconds
     pub client_id: String,
     pub redirect_uri: Option<String>,
 }
 
+impl StoreSerialize for OAuthCode {
+    fn serialize(&self) -> Option<Vec<u8>> {
+        bincode::serialize(self).ok()
+    }
+}
+
+impl StoreDeserialize for OAuthCode {
+    fn deserialize(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeviceAuthGet {
     code: Option<String>,
@@ -107,6 +122,8 @@ pub struct DeviceAuthPost {
     code: Option<String>,
     email: Option<String>,
     password: Option<String>,
+    otp: Option<String>,
+    passkey: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -138,6 +155,8 @@ pub struct CodeAuthForm {
     code: String,
     email: Option<String>,
     password: Option<String>,
+    otp: Option<String>,
+    passkey: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -212,7 +231,9 @@ where
 {
     // Validate clientId
     if params.client_id.len() > CLIENT_ID_MAX_LEN {
-        return HttpResponse::BadRequest().body("Client ID is too long");
+        return RequestError::invalid_parameters()
+            .with_detail("Client ID is too long")
+            .error_response();
     }
 
     // Generate device code
@@ -236,18 +257,23 @@ where
         user_code.push(ch);
     }
 
-    // Add OAuth status
-    let oauth_code = Arc::new(OAuthCode {
-        status: STATUS_PENDING.into(),
-        account_id: u32::MAX.into(),
-        expiry: Instant::now(),
+    // Add OAuth status, the user code points to the device code
+    let oauth_code = OAuthCode {
+        status: STATUS_PENDING,
+        account_id: u32::MAX,
+        expires_at: expires_in(core.oauth.expiry_user_code),
         client_id: params.into_inner().client_id,
         redirect_uri: None,
-    });
-    core.oauth_codes
-        .insert(device_code.clone(), oauth_code.clone())
-        .await;
-    core.oauth_codes.insert(user_code.clone(), oauth_code).await;
+    };
+    let code_key = oauth_code_key(&device_code);
+    if let Err(err) = core
+        .set_user_code(&user_code, &code_key, oauth_code.expires_at)
+        .await
+        .and(core.set_oauth_code(code_key, &oauth_code).await)
+    {
+        error!("Failed to store OAuth device code: {}", err);
+        return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).finish();
+    }
 
     // Build response
     let response = DeviceAuthResponse {
@@ -282,30 +308,14 @@ where
         response = if let (Some(code), Some(client_id), Some(redirect_uri)) =
             (&params.code, &params.client_id, &params.redirect_uri)
         {
-            if let Some(oauth) = core.oauth_codes.get(code) {
+            let code_key = oauth_code_key(code);
+            if let Some(oauth) = core.get_oauth_code(code_key.clone()).await {
                 if client_id != &oauth.client_id
                     || redirect_uri != oauth.redirect_uri.as_deref().unwrap_or("")
                 {
                     TokenResponse::error(ErrorType::InvalidClient)
-                } else if oauth.status.load(atomic::Ordering::Relaxed) == STATUS_AUTHORIZED
-                    && oauth.expiry.elapsed().as_secs() < core.oauth.expiry_auth_code
-                {
-                    // Mark this token as issued
-                    oauth
-                        .status
-                        .store(STATUS_TOKEN_ISSUED, atomic::Ordering::Relaxed);
-
-                    // Issue token
-                    core.issue_token(
-                        oauth.account_id.load(atomic::Ordering::Relaxed),
-                        &oauth.client_id,
-                        true,
-                    )
-                    .await
-                    .unwrap_or_else(|err| {
-                        error!("Failed to generate OAuth token: {}", err);
-                        TokenResponse::error(ErrorType::InvalidRequest)
-                    })
+                } else if oauth.status == STATUS_AUTHORIZED {
+                    core.consume_oauth_code(code_key, oauth).await
                 } else {
                     TokenResponse::error(ErrorType::InvalidGrant)
                 }
@@ -321,35 +331,21 @@ where
     {
         response = TokenResponse::error(ErrorType::ExpiredToken);
 
-        if let (Some(oauth), Some(client_id)) = (
-            params
-                .device_code
-                .as_ref()
-                .and_then(|dc| core.oauth_codes.get(dc)),
-            &params.client_id,
-        ) {
+        let oauth = if let Some(device_code) = &params.device_code {
+            let code_key = oauth_code_key(device_code);
+            core.get_oauth_code(code_key.clone())
+                .await
+                .map(|oauth| (code_key, oauth))
+        } else {
+            None
+        };
+
+        if let (Some((code_key, oauth)), Some(client_id)) = (oauth, &params.client_id) {
             if &oauth.client_id != client_id {
                 response = TokenResponse::error(ErrorType::InvalidClient);
-            } else if oauth.expiry.elapsed().as_secs() < core.oauth.expiry_user_code {
-                response = match oauth.status.load(atomic::Ordering::Relaxed) {
-                    STATUS_AUTHORIZED => {
-                        // Mark this token as issued
-                        oauth
-                            .status
-                            .store(STATUS_TOKEN_ISSUED, atomic::Ordering::Relaxed);
-
-                        // Issue token
-                        core.issue_token(
-                            oauth.account_id.load(atomic::Ordering::Relaxed),
-                            &oauth.client_id,
-                            true,
-                        )
-                        .await
-                        .unwrap_or_else(|err| {
-                            error!("Failed to generate OAuth token: {}", err);
-                            TokenResponse::error(ErrorType::InvalidRequest)
-                        })
-                    }
+            } else {
+                response = match oauth.status {
+                    STATUS_AUTHORIZED => core.consume_oauth_code(code_key, oauth).await,
                     status
                         if (STATUS_PENDING..STATUS_PENDING + core.oauth.max_auth_attempts)
                             .contains(&status) =>
@@ -406,9 +402,13 @@ where
 {
     // Validate clientId
     if params.client_id.len() > CLIENT_ID_MAX_LEN {
-        return HttpResponse::BadRequest().body("Client ID is too long");
+        return RequestError::invalid_parameters()
+            .with_detail("Client ID is too long")
+            .error_response();
     } else if !params.redirect_uri.starts_with("https://") {
-        return HttpResponse::BadRequest().body("Redirect URI must be HTTPS");
+        return RequestError::invalid_parameters()
+            .with_detail("Redirect URI must be HTTPS")
+            .error_response();
     }
 
     let params = params.into_inner();
@@ -458,16 +458,21 @@ where
     {
         Some(code) => code,
         None => {
-            return HttpResponse::BadRequest().body("Failed to deserialize code.");
+            return RequestError::invalid_parameters()
+                .with_detail("Failed to deserialize code.")
+                .error_response();
         }
     };
 
     // Authenticate user
-    if let (Some(email), Some(password)) = (params.email, params.password) {
-        let store = core.store.clone();
-
+    if let Some(email) = params.email {
         if let Ok(Some(account_id)) = core
-            .spawn_worker(move || store.authenticate(&email, &password))
+            .authenticate_login(
+                email,
+                params.password,
+                params.otp.filter(|otp| !otp.is_empty()),
+                params.passkey.filter(|passkey| !passkey.is_empty()),
+            )
             .await
         {
             // Generate client code
@@ -478,20 +483,26 @@ where
                 .collect::<String>();
 
             // Add client code
-            core.oauth_codes
-                .insert(
-                    client_code.clone(),
-                    Arc::new(OAuthCode {
-                        status: STATUS_AUTHORIZED.into(),
-                        account_id: account_id.into(),
-                        expiry: Instant::now(),
+            match core
+                .set_oauth_code(
+                    oauth_code_key(&client_code),
+                    &OAuthCode {
+                        status: STATUS_AUTHORIZED,
+                        account_id,
+                        expires_at: expires_in(core.oauth.expiry_auth_code),
                         client_id: code_req.client_id.clone(),
                         redirect_uri: code_req.redirect_uri.clone().into(),
-                    }),
+                    },
                 )
-                .await;
-
-            auth_code = client_code.into();
+                .await
+            {
+                Ok(_) => {
+                    auth_code = client_code.into();
+                }
+                Err(err) => {
+                    error!("Failed to store OAuth authorization code: {}", err);
+                }
+            }
         }
     }
 
@@ -581,33 +592,41 @@ where
     }
 
     let params = params.into_inner();
-    let code = if let Some(oauth) = params
-        .code
-        .as_ref()
-        .and_then(|code| core.oauth_codes.get(code))
-    {
-        if (STATUS_PENDING..STATUS_PENDING + core.oauth.max_auth_attempts)
-            .contains(&oauth.status.load(atomic::Ordering::Relaxed))
-            && oauth.expiry.elapsed().as_secs() < core.oauth.expiry_user_code
-        {
-            if let (Some(email), Some(password)) = (params.email, params.password) {
-                let store = core.store.clone();
+    let oauth = if let Some(user_code) = &params.code {
+        core.get_device_code(user_code).await
+    } else {
+        None
+    };
+    let code = if let Some((code_key, current)) = oauth {
+        let mut oauth = current.clone();
+        if (STATUS_PENDING..STATUS_PENDING + core.oauth.max_auth_attempts).contains(&oauth.status) {
+            if let Some(email) = params.email {
                 match core
-                    .spawn_worker(move || store.authenticate(&email, &password))
+                    .authenticate_login(
+                        email,
+                        params.password,
+                        params.otp.filter(|otp| !otp.is_empty()),
+                        params.passkey.filter(|passkey| !passkey.is_empty()),
+                    )
                     .await
                 {
                     Ok(Some(account_id)) => {
-                        oauth
-                            .account_id
-                            .store(account_id, atomic::Ordering::Relaxed);
-                        oauth
-                            .status
-                            .store(STATUS_AUTHORIZED, atomic::Ordering::Relaxed);
-                        Response::Success
+                        oauth.account_id = account_id;
+                        oauth.status = STATUS_AUTHORIZED;
+                        match core.update_oauth_code(code_key, &current, &oauth).await {
+                            Ok(true) => Response::Success,
+                            Ok(false) => Response::InvalidCode,
+                            Err(_) => Response::Error,
+                        }
                     }
                     Ok(None) => {
-                        oauth.status.fetch_add(1, atomic::Ordering::Relaxed);
-                        Response::Failed
+                        // Concurrent attempts are counted one by one, a
+                        // request that lost the race is reported as failed.
+                        oauth.status += 1;
+                        match core.update_oauth_code(code_key, &current, &oauth).await {
+                            Ok(_) => Response::Failed,
+                            Err(_) => Response::Error,
+                        }
                     }
                     Err(_) => Response::Error,
                 }
@@ -677,6 +696,96 @@ impl<T> JMAPServer<T>
 where
     T: for<'x> Store<'x> + 'static,
 {
+    // OAuth codes are kept as expiring values, so they are no longer visible
+    // once their lifetime has passed. Only hashes of the codes are stored.
+    async fn get_oauth_code(&self, code_key: String) -> Option<OAuthCode> {
+        let store = self.store.clone();
+        self.spawn_worker(move || store.get_expiring::<OAuthCode>(code_key.as_bytes()))
+            .await
+            .unwrap_or_else(|err| {
+                error!("Failed to fetch OAuth code: {}", err);
+                None
+            })
+    }
+
+    async fn get_device_code(&self, user_code: &str) -> Option<(String, OAuthCode)> {
+        let store = self.store.clone();
+        let key = format!("oauth-user:{}", blake3::hash(user_code.as_bytes()).to_hex());
+        let code_key = self
+            .spawn_worker(move || store.get_expiring::<String>(key.as_bytes()))
+            .await
+            .unwrap_or_else(|err| {
+                error!("Failed to fetch OAuth user code: {}", err);
+                None
+            })?;
+        let oauth = self.get_oauth_code(code_key.clone()).await?;
+        Some((code_key, oauth))
+    }
+
+    async fn set_oauth_code(&self, code_key: String, oauth: &OAuthCode) -> store::Result<()> {
+        let store = self.store.clone();
+        let expires_at = oauth.expires_at;
+        let value = oauth.serialize().ok_or_else(|| {
+            StoreError::SerializeError("Failed to serialize OAuth code".to_string())
+        })?;
+        self.spawn_worker(move || store.set_expiring(code_key.as_bytes(), &value, expires_at))
+            .await
+    }
+
+    // Replaces an OAuth code only if it was not modified since it was read.
+    async fn update_oauth_code(
+        &self,
+        code_key: String,
+        current: &OAuthCode,
+        oauth: &OAuthCode,
+    ) -> store::Result<bool> {
+        let store = self.store.clone();
+        let expires_at = oauth.expires_at;
+        let (current, value) = current.serialize().zip(oauth.serialize()).ok_or_else(|| {
+            StoreError::SerializeError("Failed to serialize OAuth code".to_string())
+        })?;
+        self.spawn_worker(move || {
+            store.compare_and_set_expiring(code_key.as_bytes(), &current, &value, expires_at)
+        })
+        .await
+    }
+
+    // Marks an authorized code as used and issues a token, codes can only
+    // be exchanged once.
+    async fn consume_oauth_code(&self, code_key: String, current: OAuthCode) -> TokenResponse {
+        let mut oauth = current.clone();
+        oauth.status = STATUS_TOKEN_ISSUED;
+        match self.update_oauth_code(code_key, &current, &oauth).await {
+            Ok(true) => self
+                .issue_token(oauth.account_id, &oauth.client_id, true)
+                .await
+                .unwrap_or_else(|err| {
+                    error!("Failed to generate OAuth token: {}", err);
+                    TokenResponse::error(ErrorType::InvalidRequest)
+                }),
+            Ok(false) => TokenResponse::error(ErrorType::InvalidGrant),
+            Err(err) => {
+                error!("Failed to update OAuth code: {}", err);
+                TokenResponse::error(ErrorType::InvalidRequest)
+            }
+        }
+    }
+
+    async fn set_user_code(
+        &self,
+        user_code: &str,
+        code_key: &str,
+        expires_at: u64,
+    ) -> store::Result<()> {
+        let store = self.store.clone();
+        let key = format!("oauth-user:{}", blake3::hash(user_code.as_bytes()).to_hex());
+        let code_key = code_key.to_string();
+        self.spawn_worker(move || {
+            store.set_expiring(key.as_bytes(), code_key.as_bytes(), expires_at)
+        })
+        .await
+    }
+
     async fn issue_token(
         &self,
         account_id: AccountId,
@@ -726,7 +835,7 @@ where
         })
     }
 
-    fn encode_access_token(
+    pub(crate) fn encode_access_token(
         &self,
         grant_type: &str,
         account_id: u32,
@@ -874,3 +983,7 @@ impl TokenResponse {
         matches!(self, TokenResponse::Error { .. })
     }
 }
+
+fn oauth_code_key(code: &str) -> String {
+    format!("oauth:{}", blake3::hash(code.as_bytes()).to_hex())
+}
@@ -0,0 +1,351 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use store::config::{env_settings::EnvSettings, settings::Setting};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::server::failed_to;
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::list("trusted-proxies")
+        .describe("Semicolon separated addresses or CIDR ranges allowed to send PROXY headers"),
+    Setting::text("trusted-proxy-header")
+        .default("x-forwarded-for")
+        .describe("Header set by the proxies, 'forwarded' or 'x-forwarded-for'"),
+];
+
+const PROXY_V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+const PROXY_V2_MAX_LEN: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForwardedHeader {
+    Forwarded,
+    #[default]
+    XForwardedFor,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    ranges: Vec<IpRange>,
+    header: ForwardedHeader,
+}
+
+impl IpRange {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix) = if let Some((addr, prefix)) = value.trim().split_once('/') {
+            (addr.parse::<IpAddr>().ok()?, prefix.parse::<u32>().ok()?)
+        } else {
+            let addr = value.trim().parse::<IpAddr>().ok()?;
+            (addr, if addr.is_ipv4() { 32 } else { 128 })
+        };
+
+        if prefix <= if addr.is_ipv4() { 32 } else { 128 } {
+            Some(IpRange { addr, prefix })
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, to_canonical(ip)) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TrustedProxies {
+    pub fn parse(settings: &EnvSettings) -> Self {
+        let mut ranges = Vec::new();
+        if let Some(proxies) = settings.get("trusted-proxies") {
+            for range in proxies.split(';').filter(|r| !r.trim().is_empty()) {
+                ranges.push(IpRange::parse(range).unwrap_or_else(|| {
+                    failed_to(&format!(
+                        "parse 'trusted-proxies', invalid address or range {}.",
+                        range
+                    ));
+                }));
+            }
+        }
+        let header = match settings
+            .value::<String>(SETTINGS, "trusted-proxy-header")
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "forwarded" => ForwardedHeader::Forwarded,
+            "x-forwarded-for" => ForwardedHeader::XForwardedFor,
+            header => {
+                failed_to(&format!(
+                    "parse 'trusted-proxy-header', expected 'forwarded' or 'x-forwarded-for' but found '{}'.",
+                    header
+                ));
+            }
+        };
+        TrustedProxies { ranges, header }
+    }
+
+    /// Name of the header the trusted proxies use to forward the client's address.
+    pub fn header_name(&self) -> &'static str {
+        match self.header {
+            ForwardedHeader::Forwarded => "forwarded",
+            ForwardedHeader::XForwardedFor => "x-forwarded-for",
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(ip))
+    }
+
+    /// Obtains the client's IP address from the configured forwarding header, with
+    /// all its lines joined by commas. Only the header the proxies are configured to
+    /// set is read, and the forwarding chain is only walked back while the hops are
+    /// trusted proxies, so a client can't spoof its address by adding entries to it.
+    pub fn client_ip(&self, peer_addr: IpAddr, header_value: Option<&str>) -> IpAddr {
+        if !self.is_trusted(&peer_addr) {
+            return peer_addr;
+        }
+
+        let header_value = if let Some(header_value) = header_value {
+            header_value
+        } else {
+            return peer_addr;
+        };
+        let hops = match self.header {
+            ForwardedHeader::Forwarded => header_value
+                .split(',')
+                .map(|element| {
+                    element
+                        .split(';')
+                        .filter_map(|pair| pair.split_once('='))
+                        .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                        .map(|(_, value)| value)
+                        .unwrap_or("")
+                })
+                .collect::<Vec<_>>(),
+            ForwardedHeader::XForwardedFor => header_value.split(',').collect::<Vec<_>>(),
+        };
+
+        let mut client_ip = peer_addr;
+        for hop in hops.into_iter().rev() {
+            if let Some(ip) = parse_node(hop) {
+                client_ip = ip;
+                if !self.is_trusted(&ip) {
+                    break;
+                }
+            } else {
+                break;
+            }
+        }
+        client_ip
+    }
+}
+
+/// Reads a PROXY protocol v2 header, returning the original source address
+/// or None for LOCAL connections (such as health checks from the proxy).
+pub async fn read_proxy_header<R>(stream: &mut R) -> io::Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 16];
+    stream.read_exact(&mut header).await?;
+    if header[..12] != PROXY_V2_SIGNATURE || header[12] >> 4 != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid PROXY protocol v2 header",
+        ));
+    }
+
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    if len > PROXY_V2_MAX_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "PROXY protocol v2 header too long",
+        ));
+    }
+    let mut addresses = vec![0u8; len];
+    stream.read_exact(&mut addresses).await?;
+
+    match (header[12] & 0x0F, header[13] >> 4) {
+        (0x00, _) => Ok(None),
+        (0x01, 0x01) if len >= 12 => Ok(Some(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(
+                addresses[0],
+                addresses[1],
+                addresses[2],
+                addresses[3],
+            )),
+            u16::from_be_bytes([addresses[8], addresses[9]]),
+        ))),
+        (0x01, 0x02) if len >= 36 => Ok(Some(SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::from(
+                <[u8; 16]>::try_from(&addresses[..16]).unwrap(),
+            )),
+            u16::from_be_bytes([addresses[32], addresses[33]]),
+        ))),
+        (0x01, _) => Ok(None),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unsupported PROXY protocol v2 command",
+        )),
+    }
+}
+
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(node) = node.strip_prefix('[') {
+        node.split_once(']')?.0.parse().ok()
+    } else if let Ok(ip) = node.parse::<IpAddr>() {
+        Some(ip)
+    } else {
+        node.rsplit_once(':')?
+            .0
+            .parse::<Ipv4Addr>()
+            .ok()
+            .map(IpAddr::V4)
+    }
+}
+
+fn to_canonical(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(*ip)),
+        ip => *ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use super::{read_proxy_header, ForwardedHeader, IpRange, TrustedProxies, PROXY_V2_SIGNATURE};
+
+    #[test]
+    fn forwarded_client_ip() {
+        let ranges = vec![
+            IpRange::parse("10.0.0.0/8").unwrap(),
+            IpRange::parse("2001:db8::/32").unwrap(),
+        ];
+        let proxies = TrustedProxies {
+            ranges: ranges.clone(),
+            header: ForwardedHeader::XForwardedFor,
+        };
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        // Untrusted peers can't set their address
+        assert_eq!(
+            proxies.client_ip(ip("192.0.2.1"), Some("203.0.113.7")),
+            ip("192.0.2.1")
+        );
+
+        // Spoofed entries to the left of the first untrusted hop are ignored
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), Some("1.1.1.1, 203.0.113.7, 10.1.1.1")),
+            ip("203.0.113.7")
+        );
+
+        // Client supplied lines come first once all header lines are joined
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), Some("1.1.1.1,203.0.113.7")),
+            ip("203.0.113.7")
+        );
+
+        // Only the configured header is read
+        let proxies = TrustedProxies {
+            ranges,
+            header: ForwardedHeader::Forwarded,
+        };
+        assert_eq!(
+            proxies.client_ip(
+                ip("10.0.0.1"),
+                Some("for=192.0.2.60;proto=http;by=10.0.0.1, for=\"[2001:db8:cafe::17]:4711\""),
+            ),
+            ip("192.0.2.60")
+        );
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), Some("for=\"198.51.100.17:8080\"")),
+            ip("198.51.100.17")
+        );
+        assert_eq!(proxies.header_name(), "forwarded");
+
+        // Obfuscated identifiers stop the walk
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), Some("for=_hidden")),
+            ip("10.0.0.1")
+        );
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), None), ip("10.0.0.1"));
+
+        assert!(IpRange::parse("10.0.0.0/33").is_none());
+        assert!(IpRange::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(&ip("::ffff:192.0.2.1")));
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_v2() {
+        let mut header = PROXY_V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C]);
+        header.extend_from_slice(&[192, 0, 2, 1, 10, 0, 0, 1, 0x1F, 0x90, 0x00, 0x19]);
+        header.extend_from_slice(b"LHLO");
+
+        let mut stream = &header[..];
+        assert_eq!(
+            read_proxy_header(&mut stream).await.unwrap(),
+            Some("192.0.2.1:8080".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(stream, b"LHLO");
+
+        let mut header = PROXY_V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(read_proxy_header(&mut &header[..]).await.unwrap(), None);
+
+        assert!(read_proxy_header(&mut &b"LHLO example.org\r\n"[..])
+            .await
+            .is_err());
+    }
+}
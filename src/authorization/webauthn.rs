@@ -0,0 +1,977 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::{web, HttpResponse};
+use jmap::{request::ACLEnforce, types::jmap::JMAPId, SUPERUSER_ID};
+use jmap_sharing::principal::account::JMAPAccountStore;
+use jmap_sharing::principal::passkey::{JMAPAccountPasskey, Passkey};
+use p256::ecdsa::signature::Verifier;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use store::{
+    blake3,
+    core::JMAPIdPrefix,
+    rand::{thread_rng, Rng},
+    sha2::{Digest, Sha256},
+    tracing::debug,
+    write::ttl::{expires_in, is_expired},
+    AccountId, JMAPStore, Store,
+};
+
+use crate::{server::workers::WorkerPool, JMAPServer};
+
+const RELYING_PARTY_NAME: &str = "Stalwart JMAP";
+const CHALLENGE_NONCE_LEN: usize = 16;
+const CHALLENGE_PAYLOAD_LEN: usize = CHALLENGE_NONCE_LEN + 8 + 4 + 1;
+const CHALLENGE_LEN: usize = CHALLENGE_PAYLOAD_LEN + blake3::OUT_LEN;
+const CHALLENGE_EXPIRY: u64 = 300; // secs
+const MAX_CREDENTIAL_ID_LEN: usize = 1023;
+const MAX_CBOR_DEPTH: usize = 8;
+
+const COSE_ALG_ES256: i64 = -7;
+const COSE_ALG_EDDSA: i64 = -8;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_DATA: u8 = 0x40;
+
+/// The WebAuthn relying party, derived from the public URL of the server.
+#[derive(Debug, Clone)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+    pub origin: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct PasskeyChallenge {
+    pub account_id: AccountId,
+    pub registration: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    pub type_: &'static str,
+    pub id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    pub type_: &'static str,
+    pub alg: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RequestOptions {
+    pub challenge: String,
+    #[serde(rename = "rpId")]
+    pub rp_id: String,
+    #[serde(rename = "allowCredentials")]
+    pub allow_credentials: Vec<CredentialDescriptor>,
+    #[serde(rename = "userVerification")]
+    pub user_verification: &'static str,
+    pub timeout: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RelyingPartyEntity {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserEntity {
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "displayName")]
+    pub display_name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthenticatorSelection {
+    #[serde(rename = "residentKey")]
+    pub resident_key: &'static str,
+    #[serde(rename = "userVerification")]
+    pub user_verification: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreationOptions {
+    pub challenge: String,
+    pub rp: RelyingPartyEntity,
+    pub user: UserEntity,
+    #[serde(rename = "pubKeyCredParams")]
+    pub pub_key_cred_params: Vec<CredentialParameters>,
+    #[serde(rename = "excludeCredentials")]
+    pub exclude_credentials: Vec<CredentialDescriptor>,
+    #[serde(rename = "authenticatorSelection")]
+    pub authenticator_selection: AuthenticatorSelection,
+    pub attestation: &'static str,
+    pub timeout: u64,
+}
+
+/// An assertion as posted by the login page, with all values base64url encoded.
+#[derive(Debug, Deserialize)]
+struct AssertionRequest {
+    id: String,
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    authenticator_data: String,
+    signature: String,
+    #[serde(rename = "userHandle")]
+    user_handle: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Assertion {
+    pub credential_id: Vec<u8>,
+    pub client_data_json: Vec<u8>,
+    pub authenticator_data: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub user_handle: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClientData {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub challenge: String,
+    pub origin: String,
+}
+
+struct AuthenticatorData<'x> {
+    rp_id_hash: &'x [u8],
+    flags: u8,
+    sign_count: u32,
+    credential: Option<(&'x [u8], Cbor<'x>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Cbor<'x> {
+    Integer(i64),
+    Bytes(&'x [u8]),
+    Text(&'x str),
+    Array(Vec<Cbor<'x>>),
+    Map(Vec<(Cbor<'x>, Cbor<'x>)>),
+    Bool(bool),
+    Null,
+}
+
+// Returns the options needed by the login page to request an assertion.
+// Passkeys are discoverable, so the page does not reveal whether an account
+// exists or which credentials it has.
+pub async fn handle_passkey_options<T>(core: web::Data<JMAPServer<T>>) -> HttpResponse
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    HttpResponse::build(StatusCode::OK)
+        .content_type("application/json")
+        .body(
+            serde_json::to_string(&core.oauth.relying_party.request_options(challenge_issue(
+                &core.oauth.key,
+                AccountId::MAX,
+                false,
+            )))
+            .unwrap_or_default(),
+        )
+}
+
+impl<T> JMAPServer<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Authenticates a login from the OAuth pages, either with a passkey or
+    // with a password and, when enabled, a TOTP code.
+    pub async fn authenticate_login(
+        &self,
+        email: String,
+        password: Option<String>,
+        otp: Option<String>,
+        passkey: Option<String>,
+    ) -> store::Result<Option<AccountId>> {
+        let store = self.store.clone();
+        let relying_party = self.oauth.relying_party.clone();
+        let key = self.oauth.key.clone();
+        let require_passkey_admin = self.oauth.require_passkey_admin;
+
+        self.spawn_worker_on(WorkerPool::Crypto, move || {
+            if let Some(passkey) = passkey {
+                authenticate_passkey(&store, &relying_party, &key, email.trim(), &passkey)
+            } else if let Some(password) = password {
+                match store.authenticate_interactive(&email, &password, otp.as_deref())? {
+                    Some(account_id)
+                        if require_passkey_admin
+                            && store.get_acl_token(account_id)?.is_member(SUPERUSER_ID) =>
+                    {
+                        debug!(
+                            "Login failed: Account {} has to sign in with a passkey.",
+                            JMAPId::from(account_id)
+                        );
+                        Ok(None)
+                    }
+                    account_id => Ok(account_id),
+                }
+            } else {
+                Ok(None)
+            }
+        })
+        .await
+    }
+}
+
+fn authenticate_passkey<T>(
+    store: &JMAPStore<T>,
+    relying_party: &RelyingParty,
+    key: &str,
+    login: &str,
+    assertion: &str,
+) -> store::Result<Option<AccountId>>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let assertion = if let Some(assertion) = Assertion::parse(assertion) {
+        assertion
+    } else {
+        debug!("Login failed: Failed to parse passkey assertion.");
+        return Ok(None);
+    };
+    let client_data = if let Some(client_data) = ClientData::parse(&assertion.client_data_json)
+        .filter(|client_data| relying_party.is_valid_client_data(client_data, "webauthn.get"))
+    {
+        client_data
+    } else {
+        debug!("Login failed: Invalid passkey client data.");
+        return Ok(None);
+    };
+
+    // Discoverable passkeys identify the account by their user handle,
+    // which has to match the login when one is provided.
+    let account_id = match (
+        assertion
+            .user_handle
+            .as_ref()
+            .and_then(|user_handle| std::str::from_utf8(user_handle).ok())
+            .and_then(JMAPId::parse),
+        !login.is_empty(),
+    ) {
+        (Some(user_id), false) => user_id.get_document_id(),
+        (user_id, true) => match store.find_individual(login)? {
+            Some(account_id)
+                if user_id.map_or(true, |user_id| user_id.get_document_id() == account_id) =>
+            {
+                account_id
+            }
+            _ => {
+                debug!("Login failed: Passkey does not belong to {:?}.", login);
+                return Ok(None);
+            }
+        },
+        (None, false) => {
+            debug!("Login failed: Passkey assertion without a user handle.");
+            return Ok(None);
+        }
+    };
+
+    // Challenges can only be used once
+    if !matches!(
+        challenge_take(store, key, &client_data.challenge)?,
+        Some(PasskeyChallenge {
+            registration: false,
+            ..
+        })
+    ) {
+        debug!("Login failed: Unknown or expired passkey challenge.");
+        return Ok(None);
+    }
+
+    store.passkey_authenticate(account_id, &assertion.credential_id, |passkey| {
+        relying_party.verify_assertion(&assertion, passkey)
+    })
+}
+
+/// Issues a random challenge for an account, valid for a few minutes. The
+/// challenge is signed with the encryption key instead of being stored, so
+/// issuing it costs no write and any node of a cluster can verify it.
+pub fn challenge_issue(key: &str, account_id: AccountId, registration: bool) -> String {
+    let mut challenge = Vec::with_capacity(CHALLENGE_LEN);
+    challenge.extend_from_slice(&thread_rng().gen::<[u8; CHALLENGE_NONCE_LEN]>());
+    challenge.extend_from_slice(&expires_in(CHALLENGE_EXPIRY).to_be_bytes());
+    challenge.extend_from_slice(&account_id.to_be_bytes());
+    challenge.push(registration as u8);
+    challenge.extend_from_slice(challenge_mac(key, &challenge).as_bytes());
+    base64url_encode(&challenge)
+}
+
+/// Verifies a challenge previously issued and marks it as spent, returns
+/// `None` if it is invalid, expired or was already used on this node.
+pub fn challenge_take<T>(
+    store: &JMAPStore<T>,
+    key: &str,
+    challenge: &str,
+) -> store::Result<Option<PasskeyChallenge>>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let challenge = match base64url_decode(challenge) {
+        Some(challenge) if challenge.len() == CHALLENGE_LEN => challenge,
+        _ => return Ok(None),
+    };
+    let (payload, mac) = challenge.split_at(CHALLENGE_PAYLOAD_LEN);
+    if challenge_mac(key, payload) != <[u8; blake3::OUT_LEN]>::try_from(mac).unwrap() {
+        return Ok(None);
+    }
+    let (nonce, payload) = payload.split_at(CHALLENGE_NONCE_LEN);
+    let expires_at = u64::from_be_bytes(payload[..8].try_into().unwrap());
+    if is_expired(expires_at) {
+        return Ok(None);
+    }
+
+    // The check and the write happen under the key lock
+    if store.insert_expiring(
+        format!("webauthn:{}", base64url_encode(nonce)).as_bytes(),
+        &[],
+        expires_at,
+    )? {
+        Ok(Some(PasskeyChallenge {
+            account_id: u32::from_be_bytes(payload[8..12].try_into().unwrap()),
+            registration: payload[12] == 1,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+fn challenge_mac(key: &str, payload: &[u8]) -> blake3::Hash {
+    blake3::keyed_hash(
+        &blake3::derive_key("Stalwart JMAP WebAuthn challenge", key.as_bytes()),
+        payload,
+    )
+}
+
+impl RelyingParty {
+    pub fn new(base_url: &str) -> Option<Self> {
+        let url = reqwest::Url::parse(base_url).ok()?;
+        Some(RelyingParty {
+            id: url.host_str()?.to_string(),
+            name: RELYING_PARTY_NAME.to_string(),
+            origin: url.origin().ascii_serialization(),
+        })
+    }
+
+    pub fn request_options(&self, challenge: String) -> RequestOptions {
+        RequestOptions {
+            challenge,
+            rp_id: self.id.clone(),
+            allow_credentials: Vec::new(),
+            user_verification: "required",
+            timeout: CHALLENGE_EXPIRY * 1000,
+        }
+    }
+
+    pub fn creation_options(
+        &self,
+        challenge: String,
+        account_id: AccountId,
+        email: String,
+        name: String,
+        passkeys: &[Passkey],
+    ) -> CreationOptions {
+        CreationOptions {
+            challenge,
+            rp: RelyingPartyEntity {
+                id: self.id.clone(),
+                name: self.name.clone(),
+            },
+            user: UserEntity {
+                id: base64url_encode(JMAPId::from(account_id).to_string().as_bytes()),
+                display_name: if !name.is_empty() {
+                    name
+                } else {
+                    email.clone()
+                },
+                name: email,
+            },
+            pub_key_cred_params: [COSE_ALG_ES256, COSE_ALG_EDDSA]
+                .into_iter()
+                .map(|alg| CredentialParameters {
+                    type_: "public-key",
+                    alg,
+                })
+                .collect(),
+            exclude_credentials: passkeys.iter().map(CredentialDescriptor::from).collect(),
+            authenticator_selection: AuthenticatorSelection {
+                resident_key: "required",
+                user_verification: "required",
+            },
+            attestation: "none",
+            timeout: CHALLENGE_EXPIRY * 1000,
+        }
+    }
+
+    pub fn is_valid_client_data(&self, client_data: &ClientData, type_: &str) -> bool {
+        client_data.type_ == type_ && client_data.origin == self.origin
+    }
+
+    /// Extracts the credential from a registration response. Attestation
+    /// statements are not verified as only "none" attestation is requested,
+    /// the credential is trusted because an authenticated session registers it.
+    pub fn verify_registration(&self, attestation_object: &[u8]) -> Option<Passkey> {
+        let (attestation, _) = Cbor::decode(attestation_object)?;
+        let auth_data = AuthenticatorData::parse(attestation.get_text("authData")?.as_bytes()?)?;
+        if !self.is_valid_authenticator_data(&auth_data) {
+            return None;
+        }
+        let (credential_id, public_key) = auth_data.credential.as_ref()?;
+        let (algorithm, public_key) = cose_public_key(public_key)?;
+
+        Some(Passkey {
+            name: String::new(),
+            credential_id: credential_id.to_vec(),
+            algorithm,
+            public_key,
+            sign_count: auth_data.sign_count,
+        })
+    }
+
+    /// Verifies an assertion signed by a passkey, returning the signature counter.
+    pub fn verify_assertion(&self, assertion: &Assertion, passkey: &Passkey) -> Option<u32> {
+        let auth_data = AuthenticatorData::parse(&assertion.authenticator_data)?;
+        if !self.is_valid_authenticator_data(&auth_data) {
+            return None;
+        }
+
+        let mut message = assertion.authenticator_data.clone();
+        message.extend_from_slice(&Sha256::digest(&assertion.client_data_json));
+        if verify_signature(
+            passkey.algorithm,
+            &passkey.public_key,
+            &message,
+            &assertion.signature,
+        ) {
+            Some(auth_data.sign_count)
+        } else {
+            None
+        }
+    }
+
+    fn is_valid_authenticator_data(&self, auth_data: &AuthenticatorData) -> bool {
+        auth_data.rp_id_hash == Sha256::digest(self.id.as_bytes()).as_slice()
+            && (auth_data.flags & (FLAG_USER_PRESENT | FLAG_USER_VERIFIED))
+                == (FLAG_USER_PRESENT | FLAG_USER_VERIFIED)
+    }
+}
+
+impl Assertion {
+    pub fn parse(value: &str) -> Option<Self> {
+        let request = serde_json::from_str::<AssertionRequest>(value).ok()?;
+        Some(Assertion {
+            credential_id: base64url_decode(&request.id)?,
+            client_data_json: base64url_decode(&request.client_data_json)?,
+            authenticator_data: base64url_decode(&request.authenticator_data)?,
+            signature: base64url_decode(&request.signature)?,
+            user_handle: match request.user_handle {
+                Some(user_handle) if !user_handle.is_empty() => {
+                    base64url_decode(&user_handle)?.into()
+                }
+                _ => None,
+            },
+        })
+    }
+}
+
+impl ClientData {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
+impl From<&Passkey> for CredentialDescriptor {
+    fn from(passkey: &Passkey) -> Self {
+        CredentialDescriptor {
+            type_: "public-key",
+            id: base64url_encode(&passkey.credential_id),
+        }
+    }
+}
+
+impl<'x> AuthenticatorData<'x> {
+    fn parse(bytes: &'x [u8]) -> Option<Self> {
+        let flags = *bytes.get(32)?;
+        let credential = if (flags & FLAG_ATTESTED_DATA) != 0 {
+            // Skip the AAGUID
+            let id_len = u16::from_be_bytes(bytes.get(53..55)?.try_into().ok()?) as usize;
+            if id_len == 0 || id_len > MAX_CREDENTIAL_ID_LEN {
+                return None;
+            }
+            let (public_key, _) = Cbor::decode(bytes.get(55 + id_len..)?)?;
+            Some((bytes.get(55..55 + id_len)?, public_key))
+        } else {
+            None
+        };
+
+        Some(AuthenticatorData {
+            rp_id_hash: bytes.get(..32)?,
+            flags,
+            sign_count: u32::from_be_bytes(bytes.get(33..37)?.try_into().ok()?),
+            credential,
+        })
+    }
+}
+
+impl<'x> Cbor<'x> {
+    /// Decodes a CBOR item, returning it along with the number of bytes read.
+    /// Only the subset used by WebAuthn is supported.
+    fn decode(bytes: &'x [u8]) -> Option<(Self, usize)> {
+        let mut pos = 0;
+        let item = Cbor::decode_item(bytes, &mut pos, 0)?;
+        Some((item, pos))
+    }
+
+    fn decode_item(bytes: &'x [u8], pos: &mut usize, depth: usize) -> Option<Self> {
+        if depth > MAX_CBOR_DEPTH {
+            return None;
+        }
+        let initial = *bytes.get(*pos)?;
+        *pos += 1;
+        let major = initial >> 5;
+        let info = initial & 0x1f;
+        let value = match info {
+            0..=23 => info as u64,
+            24..=27 => {
+                let len = 1 << (info - 24);
+                let value = bytes
+                    .get(*pos..*pos + len)?
+                    .iter()
+                    .fold(0u64, |acc, &byte| (acc << 8) | byte as u64);
+                *pos += len;
+                value
+            }
+            _ => return None, // Indefinite lengths are not used by WebAuthn
+        };
+
+        match major {
+            0 => Cbor::Integer(i64::try_from(value).ok()?).into(),
+            1 => Cbor::Integer(-1 - i64::try_from(value).ok()?).into(),
+            2 | 3 => {
+                let bytes = bytes.get(*pos..pos.checked_add(usize::try_from(value).ok()?)?)?;
+                *pos += bytes.len();
+                if major == 2 {
+                    Cbor::Bytes(bytes).into()
+                } else {
+                    Cbor::Text(std::str::from_utf8(bytes).ok()?).into()
+                }
+            }
+            4 => {
+                let mut items = Vec::with_capacity(std::cmp::min(value as usize, 16));
+                for _ in 0..value {
+                    items.push(Cbor::decode_item(bytes, pos, depth + 1)?);
+                }
+                Cbor::Array(items).into()
+            }
+            5 => {
+                let mut items = Vec::with_capacity(std::cmp::min(value as usize, 16));
+                for _ in 0..value {
+                    items.push((
+                        Cbor::decode_item(bytes, pos, depth + 1)?,
+                        Cbor::decode_item(bytes, pos, depth + 1)?,
+                    ));
+                }
+                Cbor::Map(items).into()
+            }
+            7 => match value {
+                20 => Cbor::Bool(false).into(),
+                21 => Cbor::Bool(true).into(),
+                22 => Cbor::Null.into(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn get(&self, key: &Cbor) -> Option<&Cbor<'x>> {
+        if let Cbor::Map(items) = self {
+            items.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+        } else {
+            None
+        }
+    }
+
+    fn get_text(&self, key: &str) -> Option<&Cbor<'x>> {
+        self.get(&Cbor::Text(key))
+    }
+
+    fn get_integer(&self, key: i64) -> Option<&Cbor<'x>> {
+        self.get(&Cbor::Integer(key))
+    }
+
+    fn as_bytes(&self) -> Option<&'x [u8]> {
+        if let Cbor::Bytes(bytes) = self {
+            Some(bytes)
+        } else {
+            None
+        }
+    }
+
+    fn as_integer(&self) -> Option<i64> {
+        if let Cbor::Integer(value) = self {
+            Some(*value)
+        } else {
+            None
+        }
+    }
+}
+
+// Converts a COSE key to its algorithm and raw public key, SEC1 encoded for ES256
+fn cose_public_key(key: &Cbor) -> Option<(i64, Vec<u8>)> {
+    let key_type = key.get_integer(1)?.as_integer()?;
+    let algorithm = key.get_integer(3)?.as_integer()?;
+    let curve = key.get_integer(-1)?.as_integer()?;
+    let x = key.get_integer(-2)?.as_bytes()?;
+
+    match (key_type, algorithm, curve) {
+        (2, COSE_ALG_ES256, 1) => {
+            let y = key.get_integer(-3)?.as_bytes()?;
+            if x.len() != 32 || y.len() != 32 {
+                return None;
+            }
+            let mut public_key = Vec::with_capacity(65);
+            public_key.push(0x04);
+            public_key.extend_from_slice(x);
+            public_key.extend_from_slice(y);
+            p256::ecdsa::VerifyingKey::from_sec1_bytes(&public_key).ok()?;
+            Some((algorithm, public_key))
+        }
+        (1, COSE_ALG_EDDSA, 6) => {
+            ed25519_dalek::VerifyingKey::from_bytes(x.try_into().ok()?).ok()?;
+            Some((algorithm, x.to_vec()))
+        }
+        _ => None,
+    }
+}
+
+fn verify_signature(algorithm: i64, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    match algorithm {
+        COSE_ALG_ES256 => match (
+            p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key),
+            p256::ecdsa::Signature::from_der(signature),
+        ) {
+            (Ok(public_key), Ok(signature)) => public_key.verify(message, &signature).is_ok(),
+            _ => false,
+        },
+        COSE_ALG_EDDSA => match (
+            <[u8; 32]>::try_from(public_key)
+                .ok()
+                .and_then(|key| ed25519_dalek::VerifyingKey::from_bytes(&key).ok()),
+            ed25519_dalek::Signature::from_slice(signature),
+        ) {
+            (Some(public_key), Ok(signature)) => {
+                public_key.verify_strict(message, &signature).is_ok()
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+pub fn base64url_encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn base64url_decode(value: &str) -> Option<Vec<u8>> {
+    base64::decode_config(value.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use jmap_sharing::principal::passkey::Passkey;
+    use p256::ecdsa::signature::Signer;
+    use store::sha2::{Digest, Sha256};
+
+    use super::{
+        base64url_encode, Assertion, Cbor, ClientData, RelyingParty, COSE_ALG_EDDSA, COSE_ALG_ES256,
+    };
+
+    fn head(major: u8, len: usize) -> Vec<u8> {
+        if len < 24 {
+            vec![(major << 5) | len as u8]
+        } else if len < 256 {
+            vec![(major << 5) | 24, len as u8]
+        } else {
+            let mut bytes = vec![(major << 5) | 25];
+            bytes.extend_from_slice(&(len as u16).to_be_bytes());
+            bytes
+        }
+    }
+
+    fn int(value: i64) -> Vec<u8> {
+        if value >= 0 {
+            head(0, value as usize)
+        } else {
+            head(1, (-1 - value) as usize)
+        }
+    }
+
+    fn bytes(value: &[u8]) -> Vec<u8> {
+        let mut bytes = head(2, value.len());
+        bytes.extend_from_slice(value);
+        bytes
+    }
+
+    fn text(value: &str) -> Vec<u8> {
+        let mut bytes = head(3, value.len());
+        bytes.extend_from_slice(value.as_bytes());
+        bytes
+    }
+
+    fn map(items: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<u8> {
+        let mut bytes = head(5, items.len());
+        for (key, value) in items {
+            bytes.extend(key);
+            bytes.extend(value);
+        }
+        bytes
+    }
+
+    fn auth_data(
+        relying_party: &RelyingParty,
+        flags: u8,
+        sign_count: u32,
+        credential: Option<(&[u8], Vec<u8>)>,
+    ) -> Vec<u8> {
+        let mut bytes = Sha256::digest(relying_party.id.as_bytes()).to_vec();
+        bytes.push(flags);
+        bytes.extend_from_slice(&sign_count.to_be_bytes());
+        if let Some((credential_id, public_key)) = credential {
+            bytes.extend_from_slice(&[0u8; 16]);
+            bytes.extend_from_slice(&(credential_id.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(credential_id);
+            bytes.extend(public_key);
+        }
+        bytes
+    }
+
+    fn attestation(auth_data: Vec<u8>) -> Vec<u8> {
+        map(vec![
+            (text("fmt"), text("none")),
+            (text("attStmt"), map(vec![])),
+            (text("authData"), bytes(&auth_data)),
+        ])
+    }
+
+    fn assertion_request(
+        client_data_json: &[u8],
+        authenticator_data: &[u8],
+        signature: &[u8],
+    ) -> String {
+        serde_json::json!({
+            "id": base64url_encode(b"credential"),
+            "clientDataJSON": base64url_encode(client_data_json),
+            "authenticatorData": base64url_encode(authenticator_data),
+            "signature": base64url_encode(signature),
+        })
+        .to_string()
+    }
+
+    fn signed_message(authenticator_data: &[u8], client_data_json: &[u8]) -> Vec<u8> {
+        let mut message = authenticator_data.to_vec();
+        message.extend_from_slice(&Sha256::digest(client_data_json));
+        message
+    }
+
+    #[test]
+    fn cbor_decode() {
+        let mut value = map(vec![
+            (int(1), int(-7)),
+            (text("list"), {
+                let mut list = head(4, 3);
+                list.extend(bytes(b"abc"));
+                list.push(0xf5);
+                list.push(0xf6);
+                list
+            }),
+            (int(-300), int(7000)),
+        ]);
+        let len = value.len();
+        value.extend_from_slice(b"trailing");
+        let (item, read) = Cbor::decode(&value).unwrap();
+        assert_eq!(read, len);
+        assert_eq!(item.get_integer(1), Some(&Cbor::Integer(-7)));
+        assert_eq!(item.get_integer(-300), Some(&Cbor::Integer(7000)));
+        assert_eq!(
+            item.get_text("list"),
+            Some(&Cbor::Array(vec![
+                Cbor::Bytes(b"abc"),
+                Cbor::Bool(true),
+                Cbor::Null
+            ]))
+        );
+
+        // Truncated, indefinite length and deeply nested items are rejected
+        assert_eq!(Cbor::decode(&value[..len - 1]), None);
+        assert_eq!(Cbor::decode(&[0x9f, 0x01, 0xff]), None);
+        assert_eq!(Cbor::decode(&[0x81; 32]), None);
+        assert_eq!(Cbor::decode(&[0x5a, 0xff, 0xff, 0xff, 0xff]), None);
+    }
+
+    #[test]
+    fn passkey_es256() {
+        let relying_party = RelyingParty::new("https://jmap.example.org:8080/").unwrap();
+        assert_eq!(relying_party.id, "jmap.example.org");
+        assert_eq!(relying_party.origin, "https://jmap.example.org:8080");
+
+        // Register a credential
+        let key = p256::ecdsa::SigningKey::from_bytes(&[7u8; 32]).unwrap();
+        let point = key.verifying_key().to_encoded_point(false);
+        let public_key = map(vec![
+            (int(1), int(2)),
+            (int(3), int(COSE_ALG_ES256)),
+            (int(-1), int(1)),
+            (int(-2), bytes(&point.as_bytes()[1..33])),
+            (int(-3), bytes(&point.as_bytes()[33..])),
+        ]);
+        let attestation = attestation(auth_data(
+            &relying_party,
+            0x45,
+            0,
+            Some((b"credential", public_key)),
+        ));
+        let passkey = relying_party.verify_registration(&attestation).unwrap();
+        assert_eq!(
+            passkey,
+            Passkey {
+                name: String::new(),
+                credential_id: b"credential".to_vec(),
+                algorithm: COSE_ALG_ES256,
+                public_key: point.as_bytes().to_vec(),
+                sign_count: 0,
+            }
+        );
+        assert_eq!(
+            RelyingParty::new("https://other.example.org")
+                .unwrap()
+                .verify_registration(&attestation),
+            None
+        );
+
+        // Sign in with it
+        let client_data_json = br#"{"type":"webauthn.get","challenge":"abc","origin":"https://jmap.example.org:8080","crossOrigin":false}"#;
+        let client_data = ClientData::parse(client_data_json).unwrap();
+        assert_eq!(client_data.challenge, "abc");
+        assert!(relying_party.is_valid_client_data(&client_data, "webauthn.get"));
+        assert!(!relying_party.is_valid_client_data(&client_data, "webauthn.create"));
+
+        let authenticator_data = auth_data(&relying_party, 0x05, 5, None);
+        let signature: p256::ecdsa::Signature =
+            key.sign(&signed_message(&authenticator_data, client_data_json));
+        let request = assertion_request(
+            client_data_json,
+            &authenticator_data,
+            signature.to_der().as_bytes(),
+        );
+        let assertion = Assertion::parse(&request).unwrap();
+        assert_eq!(assertion.credential_id, b"credential");
+        assert_eq!(
+            relying_party.verify_assertion(&assertion, &passkey),
+            Some(5)
+        );
+
+        // Tampered client data or missing user presence or verification flags
+        // fail verification
+        let mut tampered = Assertion::parse(&request).unwrap();
+        tampered.client_data_json.push(b' ');
+        assert_eq!(relying_party.verify_assertion(&tampered, &passkey), None);
+
+        for flags in [0x04, 0x01] {
+            let authenticator_data = auth_data(&relying_party, flags, 6, None);
+            let signature: p256::ecdsa::Signature =
+                key.sign(&signed_message(&authenticator_data, client_data_json));
+            let assertion = Assertion::parse(&assertion_request(
+                client_data_json,
+                &authenticator_data,
+                signature.to_der().as_bytes(),
+            ))
+            .unwrap();
+            assert_eq!(relying_party.verify_assertion(&assertion, &passkey), None);
+        }
+    }
+
+    #[test]
+    fn passkey_eddsa() {
+        let relying_party = RelyingParty::new("https://jmap.example.org").unwrap();
+        let key = ed25519_dalek::SigningKey::from_bytes(&[9u8; 32]);
+        let public_key = map(vec![
+            (int(1), int(1)),
+            (int(3), int(COSE_ALG_EDDSA)),
+            (int(-1), int(6)),
+            (int(-2), bytes(key.verifying_key().as_bytes())),
+        ]);
+        let passkey = relying_party
+            .verify_registration(&attestation(auth_data(
+                &relying_party,
+                0x45,
+                1,
+                Some((b"credential", public_key)),
+            )))
+            .unwrap();
+        assert_eq!(passkey.algorithm, COSE_ALG_EDDSA);
+        assert_eq!(passkey.sign_count, 1);
+
+        let client_data_json =
+            br#"{"type":"webauthn.get","challenge":"abc","origin":"https://jmap.example.org"}"#;
+        let authenticator_data = auth_data(&relying_party, 0x05, 2, None);
+        let signature: ed25519_dalek::Signature = ed25519_dalek::Signer::sign(
+            &key,
+            &signed_message(&authenticator_data, client_data_json),
+        );
+        let assertion = Assertion::parse(&assertion_request(
+            client_data_json,
+            &authenticator_data,
+            &signature.to_bytes(),
+        ))
+        .unwrap();
+        assert_eq!(
+            relying_party.verify_assertion(&assertion, &passkey),
+            Some(2)
+        );
+
+        // Unsupported algorithms are rejected at registration
+        let public_key = map(vec![
+            (int(1), int(3)),
+            (int(3), int(-257)),
+            (int(-1), bytes(&[1, 2, 3])),
+            (int(-2), bytes(&[1, 0, 1])),
+        ]);
+        assert_eq!(
+            relying_party.verify_registration(&attestation(auth_data(
+                &relying_party,
+                0x45,
+                0,
+                Some((b"credential", public_key)),
+            ))),
+            None
+        );
+    }
+}
@@ -22,17 +22,13 @@
 */
 
 use crate::cluster::log::update_apply::RaftStoreApplyUpdate;
-use crate::cluster::log::{PendingUpdate, PendingUpdates};
 use crate::JMAPServer;
-use store::core::collection::Collection;
 use store::core::error::StoreError;
-use store::log::entry::Entry;
 use store::log::raft::{LogIndex, RaftId, TermId};
 use store::serialize::key::{LogKey, FOLLOWER_COMMIT_INDEX_KEY};
-use store::serialize::{DeserializeBigEndian, StoreDeserialize, StoreSerialize};
-use store::write::batch::WriteBatch;
+use store::serialize::StoreSerialize;
 use store::write::operation::WriteOperation;
-use store::{tracing::debug, AccountId, ColumnFamily, Direction, Store};
+use store::{tracing::debug, ColumnFamily, Direction, Store};
 
 impl<T> JMAPServer<T>
 where
@@ -59,6 +55,7 @@ where
         &self,
         apply_up_to: LogIndex,
         do_reset: bool,
+        term: TermId,
     ) -> store::Result<Option<RaftId>> {
         let store = self.store.clone();
         self.spawn_worker(move || {
@@ -84,99 +81,41 @@ where
             );
 
             let mut log_batch = Vec::new();
+            store.apply_pending_updates(apply_up_to, do_reset, term, &mut log_batch)?;
+
+            // Publish the staged changes of committed entries and, on reset,
+            // discard the ones belonging to uncommitted entries.
+            let mut staged_batch = Vec::new();
             for (key, value) in store.db.iterator(
                 ColumnFamily::Logs,
-                &[LogKey::PENDING_UPDATES_KEY_PREFIX],
+                &[LogKey::STAGED_CHANGE_KEY_PREFIX],
                 Direction::Forward,
             )? {
-                if !key.starts_with(&[LogKey::PENDING_UPDATES_KEY_PREFIX]) {
+                if !key.starts_with(&[LogKey::STAGED_CHANGE_KEY_PREFIX]) {
                     break;
                 }
-                let index = (&key[..]).deserialize_be_u64(1).ok_or_else(|| {
+                let change_id = LogKey::deserialize_change_id(&key).ok_or_else(|| {
                     StoreError::InternalError(format!(
-                        "Failed to deserialize index from changelog key: [{:?}]",
+                        "Failed to deserialize staged changelog key: [{:?}]",
                         key
                     ))
                 })?;
 
-                if apply_up_to != LogIndex::MAX && index <= apply_up_to {
-                    let mut write_batch = WriteBatch::new(AccountId::MAX);
-                    let mut account_id = AccountId::MAX;
-                    let mut collection = Collection::None;
-
-                    for update in PendingUpdates::deserialize(&value)
-                        .ok_or_else(|| {
-                            StoreError::InternalError(format!(
-                                "Failed to deserialize pending updates for key [{:?}]",
-                                key
-                            ))
-                        })?
-                        .updates
-                    {
-                        match update {
-                            PendingUpdate::Begin {
-                                account_id: update_account_id,
-                                collection: update_collection,
-                            } => {
-                                account_id = update_account_id;
-                                collection = update_collection;
-                            }
-                            PendingUpdate::Update { update } => {
-                                debug_assert!(
-                                    account_id != AccountId::MAX && collection != Collection::None
-                                );
-
-                                if account_id != write_batch.account_id {
-                                    if !write_batch.is_empty() {
-                                        store.write(write_batch)?;
-                                        write_batch = WriteBatch::new(account_id);
-                                    } else {
-                                        write_batch.account_id = account_id;
-                                    }
-                                }
-                                store.apply_update(&mut write_batch, collection, update)?;
-                            }
-                            PendingUpdate::Delete { document_ids } => {
-                                debug_assert!(
-                                    account_id != AccountId::MAX && collection != Collection::None
-                                );
-
-                                if account_id != write_batch.account_id {
-                                    if !write_batch.is_empty() {
-                                        store.write(write_batch)?;
-                                        write_batch = WriteBatch::new(account_id);
-                                    } else {
-                                        write_batch.account_id = account_id;
-                                    }
-                                }
-
-                                for document_id in document_ids {
-                                    match store.delete_document(
-                                        &mut write_batch,
-                                        collection,
-                                        document_id,
-                                    ) {
-                                        Ok(_) | Err(StoreError::NotFound(_)) => {}
-                                        Err(e) => return Err(e),
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    if !write_batch.is_empty() {
-                        store.write(write_batch)?;
-                    }
-
-                    store.db.delete(ColumnFamily::Logs, &key)?;
-                } else if do_reset {
-                    log_batch.push(WriteOperation::Delete {
-                        cf: ColumnFamily::Logs,
-                        key: key.to_vec(),
-                    });
-                } else {
-                    break;
+                if apply_up_to != LogIndex::MAX && change_id <= apply_up_to {
+                    let mut change_key = key.to_vec();
+                    change_key[0] = LogKey::CHANGE_KEY_PREFIX;
+                    staged_batch.push(WriteOperation::set(
+                        ColumnFamily::Logs,
+                        change_key,
+                        value.to_vec(),
+                    ));
+                } else if !do_reset {
+                    continue;
                 }
+                staged_batch.push(WriteOperation::delete(ColumnFamily::Logs, key.to_vec()));
+            }
+            if !staged_batch.is_empty() {
+                store.db.write(staged_batch)?;
             }
 
             if !do_reset {
@@ -213,10 +152,11 @@ where
                     key: FOLLOWER_COMMIT_INDEX_KEY.to_vec(),
                 });
 
-                for (key, value) in
-                    store
-                        .db
-                        .iterator(ColumnFamily::Logs, &key, Direction::Forward)?
+                // Uncommitted entries never reached the changelog, removing
+                // them from the raft log is enough.
+                for (key, _) in store
+                    .db
+                    .iterator(ColumnFamily::Logs, &key, Direction::Forward)?
                 {
                     if !key.starts_with(&[LogKey::RAFT_KEY_PREFIX]) {
                         break;
@@ -225,46 +165,6 @@ where
                         StoreError::InternalError(format!("Corrupted raft key for [{:?}]", key))
                     })?;
                     if apply_up_to == LogIndex::MAX || raft_id.index > apply_up_to {
-                        match Entry::deserialize(&value).ok_or_else(|| {
-                            StoreError::InternalError(format!(
-                                "Corrupted raft entry for [{:?}]",
-                                key
-                            ))
-                        })? {
-                            Entry::Item {
-                                account_id,
-                                changed_collections,
-                            } => {
-                                for changed_collection in changed_collections {
-                                    log_batch.push(WriteOperation::Delete {
-                                        cf: ColumnFamily::Logs,
-                                        key: LogKey::serialize_change(
-                                            account_id,
-                                            changed_collection,
-                                            raft_id.index,
-                                        ),
-                                    });
-                                }
-                            }
-                            Entry::Snapshot { changed_accounts } => {
-                                for (changed_collections, changed_accounts_ids) in changed_accounts
-                                {
-                                    for changed_collection in changed_collections {
-                                        for changed_account_id in &changed_accounts_ids {
-                                            log_batch.push(WriteOperation::Delete {
-                                                cf: ColumnFamily::Logs,
-                                                key: LogKey::serialize_change(
-                                                    *changed_account_id,
-                                                    changed_collection,
-                                                    raft_id.index,
-                                                ),
-                                            });
-                                        }
-                                    }
-                                }
-                            }
-                        };
-
                         log_batch.push(WriteOperation::Delete {
                             cf: ColumnFamily::Logs,
                             key: key.to_vec(),
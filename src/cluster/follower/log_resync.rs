@@ -0,0 +1,88 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use store::log::raft::{LogIndex, RaftId};
+use store::tracing::{error, info};
+use store::Store;
+
+use crate::cluster::log::AppendEntriesResponse;
+use crate::JMAPServer;
+
+use super::rpc::Response;
+use super::State;
+
+impl<T> JMAPServer<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    pub async fn handle_resync_log(
+        &self,
+        after_index: LogIndex,
+        state: State,
+    ) -> (State, Response) {
+        self.set_up_to_date(false);
+
+        if let Err(err) = self.prepare_rollback_changes(after_index, true).await {
+            error!("Failed to prepare resync rollback changes: {:?}", err);
+            return (state, Response::None);
+        }
+
+        match self.get_last_log().await {
+            Ok(last_log) => {
+                self.update_last_log(last_log.unwrap_or_else(RaftId::none))
+                    .await;
+            }
+            Err(err) => {
+                error!("Failed to obtain last log: {:?}", err);
+                return (state, Response::None);
+            }
+        }
+
+        // The rollback is completed by fetching the affected documents
+        // from the leader on its next request.
+        match self.next_rollback_change().await {
+            Ok(Some((account_id, collection, changes))) => {
+                info!(
+                    "Raft log truncated after index {}, resynchronizing from leader.",
+                    after_index
+                );
+                (
+                    State::Rollback {
+                        account_id,
+                        collection,
+                        changes,
+                    },
+                    Response::AppendEntries(AppendEntriesResponse::Continue),
+                )
+            }
+            Ok(None) => (
+                state,
+                Response::AppendEntries(AppendEntriesResponse::Continue),
+            ),
+            Err(err) => {
+                error!("Failed to obtain rollback changes: {:?}", err);
+                (state, Response::None)
+            }
+        }
+    }
+}
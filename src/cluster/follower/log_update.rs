@@ -29,9 +29,11 @@ use crate::JMAPServer;
 use store::ahash::AHashMap;
 use store::core::bitmap::Bitmap;
 use store::core::collection::Collection;
+use store::core::error::StoreError;
+use store::log::entry::Entry;
 use store::log::raft::LogIndex;
 use store::serialize::key::LogKey;
-use store::tracing::debug;
+use store::tracing::{debug, error};
 use store::write::operation::WriteOperation;
 use store::{AccountId, ColumnFamily, Store};
 
@@ -73,7 +75,7 @@ where
                                     .db
                                     .get::<Vec<u8>>(
                                         ColumnFamily::Logs,
-                                        &LogKey::serialize_change(
+                                        &LogKey::serialize_staged_change(
                                             account_id, collection, last_index,
                                         ),
                                     )
@@ -89,9 +91,10 @@ where
                                 account_id != AccountId::MAX && collection != Collection::None
                             );
 
+                            // Changes stay staged until the entry is committed
                             log_batch.push(WriteOperation::set(
                                 ColumnFamily::Logs,
-                                LogKey::serialize_change(account_id, collection, last_index),
+                                LogKey::serialize_staged_change(account_id, collection, last_index),
                                 change,
                             ));
                             changed_accounts
@@ -120,6 +123,18 @@ where
                                 );
                             }
 
+                            // Reject entries that were corrupted on the leader or in transit
+                            if !Entry::verify(&raft_id, &log) {
+                                error!(
+                                    "Raft entry {:?} received from leader failed checksum verification.",
+                                    raft_id
+                                );
+                                return Err(StoreError::DataCorruption(format!(
+                                    "Checksum mismatch for raft entry {:?}.",
+                                    raft_id
+                                )));
+                            }
+
                             last_index = raft_id.index;
                             if merge_index == LogIndex::MAX {
                                 merge_index = raft_id.index;
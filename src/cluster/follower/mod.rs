@@ -27,6 +27,7 @@ pub mod blobs;
 pub mod commit;
 pub mod log_match;
 pub mod log_merge;
+pub mod log_resync;
 pub mod log_synchronize;
 pub mod log_update;
 pub mod spawn_follower;
@@ -47,7 +48,7 @@ use store::ahash::{AHashMap, AHashSet};
 use store::blob::BlobId;
 use store::core::bitmap::Bitmap;
 use store::core::collection::Collection;
-use store::log::raft::LogIndex;
+use store::log::raft::{LogIndex, TermId};
 use store::AccountId;
 
 #[derive(Debug)]
@@ -84,4 +85,5 @@ pub struct RaftIndexes {
     uncommitted_index: LogIndex,
     merge_index: LogIndex,
     sequence_id: u64,
+    term: TermId,
 }
@@ -39,16 +39,17 @@ where
         let (tx, mut rx) = mpsc::channel::<Event>(IPC_CHANNEL_BUFFER);
         let core = self.core.clone();
         let local_name = self.addr.to_string();
+        let term = self.term;
 
         debug!("[{}] Starting raft follower process.", local_name);
 
         tokio::spawn(async move {
-            if let Err(err) = core.commit_leader(LogIndex::MAX, true).await {
+            if let Err(err) = core.commit_leader(LogIndex::MAX, true, term).await {
                 error!("Failed to rollback uncommitted entries: {:?}", err);
                 return;
             }
 
-            if let Err(err) = core.commit_follower(LogIndex::MAX, true).await {
+            if let Err(err) = core.commit_follower(LogIndex::MAX, true, term).await {
                 error!("Failed to commit pending updates: {:?}", err);
                 return;
             }
@@ -67,6 +68,7 @@ where
                     uncommitted_index: commit_index,
                     merge_index: LogIndex::MAX,
                     sequence_id: 0,
+                    term,
                 }
             };
 
@@ -83,8 +85,19 @@ where
                 }
             };
 
+            // Set after a local resync, the rollback restarts on the next leader request.
+            let mut resume_rollback = false;
+
             while let Some(event) = rx.recv().await {
                 let response = match (event.request, state) {
+                    (AppendEntriesRequest::Resync { after_index }, prev_state) => {
+                        let (next_state, response) =
+                            core.handle_resync_log(after_index, prev_state).await;
+                        resume_rollback = matches!(next_state, State::Rollback { .. });
+                        state = next_state;
+                        response
+                    }
+
                     (AppendEntriesRequest::Match { last_log }, State::Synchronize) => {
                         if let Some(response) = core.handle_match_log(last_log).await {
                             state = State::Synchronize;
@@ -253,7 +266,7 @@ where
                             collection,
                             changes,
                         },
-                    ) => {
+                    ) if !resume_rollback => {
                         debug!(
                             concat!(
                                 "[{}] Received {} rollback entries for account {}, ",
@@ -304,6 +317,7 @@ where
                         );
 
                         // Resume rollback process when a new leader is elected.
+                        resume_rollback = false;
                         if let Some((next_state, response)) = core
                             .handle_rollback_updates(account_id, collection, changes, vec![])
                             .await
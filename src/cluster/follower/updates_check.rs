@@ -42,7 +42,8 @@ where
         changed_accounts: Vec<(AccountId, Bitmap<Collection>)>,
         updates: Vec<Update>,
     ) -> Option<(State, Response)> {
-        // Request any missing blobs
+        // Request any missing blobs, external blobs are linked without
+        // fetching them when the blob store is shared by all nodes.
         let store = self.store.clone();
         match self
             .spawn_worker(move || {
@@ -56,16 +57,11 @@ where
                                     blobs, term_index, ..
                                 },
                         } if !blobs.is_empty() || term_index.is_some() => {
-                            for blob in blobs {
-                                if !store.blob_exists(blob)? {
+                            for blob in blobs.iter().chain(term_index.iter()) {
+                                if !store.blob_exists(blob)? && !store.blob_link_shared(blob)? {
                                     missing_blob_ids.insert(blob.clone());
                                 }
                             }
-                            if let Some(term_index) = term_index {
-                                if !store.blob_exists(term_index)? {
-                                    missing_blob_ids.insert(term_index.clone());
-                                }
-                            }
                         }
                         _ => (),
                     }
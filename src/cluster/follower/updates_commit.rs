@@ -25,6 +25,7 @@ use super::rpc::Response;
 use super::{RaftIndexes, State};
 use crate::cluster::log::AppendEntriesResponse;
 use crate::JMAPServer;
+use std::sync::atomic::Ordering;
 use store::log::raft::LogIndex;
 use store::tracing::{debug, error};
 use store::Store;
@@ -34,11 +35,20 @@ where
     T: for<'x> Store<'x> + 'static,
 {
     pub async fn commit_updates(&self, indexes: &mut RaftIndexes) -> Option<(State, Response)> {
+        if let Some(cluster) = &self.cluster {
+            cluster
+                .leader_commit_index
+                .store(indexes.leader_commit_index, Ordering::Relaxed);
+        }
+
         // Apply changes
         if indexes.leader_commit_index != LogIndex::MAX
             && indexes.uncommitted_index <= indexes.leader_commit_index
         {
-            let last_log = match self.commit_follower(indexes.uncommitted_index, false).await {
+            let last_log = match self
+                .commit_follower(indexes.uncommitted_index, false, indexes.term)
+                .await
+            {
                 Ok(Some(last_log)) => last_log,
                 Ok(None) => {
                     error!(
@@ -54,6 +64,7 @@ where
             };
 
             indexes.commit_index = indexes.uncommitted_index;
+            self.advance_fencing_token(last_log.term).await;
             self.update_last_log(last_log).await;
 
             // Set up to date
@@ -0,0 +1,259 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{sync::atomic::Ordering, time::Instant};
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use serde::Serialize;
+use store::{
+    log::raft::{LogIndex, TermId},
+    tracing::error,
+    Store,
+};
+use tokio::sync::oneshot;
+
+use crate::JMAPServer;
+
+use super::{raft::State, Cluster, Event, PeerId, ShardId};
+
+pub const COMMIT_HISTORY_MAX: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClusterStatus {
+    pub role: Role,
+    #[serde(rename = "peerId")]
+    pub peer_id: PeerId,
+    #[serde(rename = "leaderId")]
+    pub leader_id: Option<PeerId>,
+    pub term: TermId,
+    #[serde(rename = "commitIndex")]
+    pub commit_index: Option<LogIndex>,
+    #[serde(rename = "appliedIndex")]
+    pub applied_index: Option<LogIndex>,
+    #[serde(rename = "lagEntries")]
+    pub lag_entries: u64,
+    pub ready: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub peers: Vec<PeerHealth>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Role {
+    Standalone,
+    Leader,
+    Follower,
+    Candidate,
+    Waiting,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerHealth {
+    #[serde(rename = "peerId")]
+    pub peer_id: PeerId,
+    #[serde(rename = "shardId")]
+    pub shard_id: ShardId,
+    pub hostname: String,
+    pub state: &'static str,
+    #[serde(rename = "lastContactSecs")]
+    pub last_contact_secs: u64,
+    #[serde(rename = "matchIndex")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub match_index: Option<LogIndex>,
+    #[serde(rename = "lagEntries")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lag_entries: Option<u64>,
+    #[serde(rename = "lagSecs")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lag_secs: Option<u64>,
+}
+
+impl<T> Cluster<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Records the time at which the leader advanced its commit index, used
+    /// to report how long ago the oldest entry missing on a peer was committed.
+    pub fn record_commit(&mut self, commit_index: LogIndex) {
+        if self.commit_history.len() == COMMIT_HISTORY_MAX {
+            self.commit_history.pop_front();
+        }
+        self.commit_history
+            .push_back((commit_index, Instant::now()));
+    }
+
+    pub fn build_status(&self) -> ClusterStatus {
+        let role = match &self.state {
+            State::Leader { .. } => Role::Leader,
+            State::Follower { .. } => Role::Follower,
+            State::Candidate { .. } => Role::Candidate,
+            State::Wait { .. } | State::VotedFor { .. } => Role::Waiting,
+        };
+        let applied_index = index_or_none(self.last_log.index);
+        let commit_index = if role == Role::Leader {
+            applied_index
+        } else {
+            index_or_none(
+                self.core
+                    .cluster
+                    .as_ref()
+                    .map(|cluster| cluster.leader_commit_index.load(Ordering::Relaxed))
+                    .unwrap_or(LogIndex::MAX),
+            )
+        };
+        let lag_entries = lag(commit_index, applied_index);
+        let now = Instant::now();
+
+        let peers = self
+            .peers
+            .iter()
+            .map(|peer| {
+                let match_index = if role == Role::Leader && peer.is_in_shard(self.shard_id) {
+                    Some(peer.commit_index)
+                } else {
+                    None
+                };
+                let lag_entries = match_index.map(|index| lag(commit_index, index_or_none(index)));
+                let lag_secs = match_index.map(|index| {
+                    self.commit_history
+                        .iter()
+                        .find(|(committed, _)| index == LogIndex::MAX || *committed > index)
+                        .map_or(0, |(_, committed_at)| {
+                            now.duration_since(*committed_at).as_secs()
+                        })
+                });
+
+                PeerHealth {
+                    peer_id: peer.peer_id,
+                    shard_id: peer.shard_id,
+                    hostname: peer.hostname.clone(),
+                    state: match peer.state {
+                        super::gossip::State::Seed => "seed",
+                        super::gossip::State::Alive => "alive",
+                        super::gossip::State::Suspected => "suspected",
+                        super::gossip::State::Offline => "offline",
+                        super::gossip::State::Left => "left",
+                    },
+                    last_contact_secs: now.duration_since(peer.last_heartbeat).as_secs(),
+                    match_index: match_index.and_then(index_or_none),
+                    lag_entries,
+                    lag_secs,
+                }
+            })
+            .collect();
+
+        ClusterStatus {
+            role,
+            peer_id: self.peer_id,
+            leader_id: self.leader_peer_id(),
+            term: self.term,
+            commit_index,
+            applied_index,
+            lag_entries,
+            ready: matches!(role, Role::Leader | Role::Follower)
+                && lag_entries <= self.config.health_max_lag,
+            peers,
+        }
+    }
+}
+
+fn index_or_none(index: LogIndex) -> Option<LogIndex> {
+    if index != LogIndex::MAX {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+fn lag(commit_index: Option<LogIndex>, applied_index: Option<LogIndex>) -> u64 {
+    match (commit_index, applied_index) {
+        (Some(commit_index), Some(applied_index)) => commit_index.saturating_sub(applied_index),
+        (Some(commit_index), None) => commit_index + 1,
+        _ => 0,
+    }
+}
+
+impl<T> JMAPServer<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    pub async fn cluster_status(&self) -> Option<ClusterStatus> {
+        let cluster = self.cluster.as_ref()?;
+        let (response_tx, rx) = oneshot::channel();
+        if cluster.tx.send(Event::Status { response_tx }).await.is_ok() {
+            rx.await.ok()
+        } else {
+            error!("Failed to send status request to cluster.");
+            None
+        }
+    }
+
+    pub async fn node_status(&self) -> Option<ClusterStatus> {
+        if self.is_in_cluster() {
+            self.cluster_status().await
+        } else {
+            let applied_index = index_or_none(self.store.raft_index.load(Ordering::Relaxed));
+            ClusterStatus {
+                role: Role::Standalone,
+                peer_id: 0,
+                leader_id: None,
+                term: 0,
+                commit_index: applied_index,
+                applied_index,
+                lag_entries: 0,
+                ready: true,
+                peers: Vec::new(),
+            }
+            .into()
+        }
+    }
+}
+
+/// Reports the raft status of this node. Responds with 503 unless a leader is
+/// known and the locally applied index is within `cluster-health-max-lag`
+/// entries of the commit index, so it can be used as a load balancer check.
+/// The endpoint is unauthenticated so peer details are only available from
+/// `/admin/cluster/status`.
+pub async fn handle_cluster_health<T>(core: web::Data<JMAPServer<T>>) -> HttpResponse
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let status = core.node_status().await.map(|mut status| {
+        status.peers.clear();
+        status
+    });
+
+    match status {
+        Some(status) => HttpResponse::build(if status.ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        })
+        .insert_header(ContentType::json())
+        .json(status),
+        None => HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).finish(),
+    }
+}
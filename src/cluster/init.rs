@@ -23,21 +23,22 @@
 
 use crate::{
     cluster::{
-        gossip::spawn::spawn_quidnunc, rpc::listener::spawn_rpc, Cluster, Peer, PeerId, PeerList,
+        gossip::spawn::spawn_quidnunc, health::COMMIT_HISTORY_MAX, log::verify::spawn_log_verifier,
+        rpc::listener::spawn_rpc, Cluster, Peer, PeerId, PeerList,
     },
-    JMAPServer, DEFAULT_RPC_PORT,
+    JMAPServer,
 };
 use actix_web::web;
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, VecDeque},
     hash::{Hash, Hasher},
-    net::{SocketAddr, ToSocketAddrs},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
     sync::Arc,
     thread,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use store::{
-    config::env_settings::EnvSettings,
+    config::{env_settings::EnvSettings, settings::Setting},
     log::raft::{LogIndex, RaftId},
     tracing::{error, info},
 };
@@ -50,6 +51,28 @@ use super::{
     RAFT_LOG_BEHIND,
 };
 
+pub const SETTINGS: &[Setting] = &[
+    Setting::list("seed-nodes").describe("Comma separated host[:port] of existing cluster members"),
+    Setting::ip_addr("rpc-bind-addr").describe("Defaults to jmap-bind-addr"),
+    Setting::ip_addr("rpc-advertise-addr").default("0.0.0.0"),
+    Setting::integer("rpc-port")
+        .min(1)
+        .max(65535)
+        .default("7911"),
+    Setting::text("rpc-tls-domain").describe("Certificates are not verified when unset"),
+    Setting::millis("peer-ping-interval").default("500"),
+    Setting::integer("shard-id").default("0"),
+    Setting::bytes("raft-batch-max").default("10485760"),
+    Setting::millis("raft-election-timeout").default("1000"),
+    Setting::millis("rpc-inactivity-timeout").default("300000"),
+    Setting::millis("rpc-timeout").default("1000"),
+    Setting::integer("rpc-retries-max").default("5"),
+    Setting::millis("rpc-backoff-max").default("180000"),
+    Setting::integer("cluster-health-max-lag")
+        .default("100")
+        .describe("Raft entries a follower may lag behind before /healthz/ready fails"),
+];
+
 pub struct ClusterInit {
     main_rx: mpsc::Receiver<Event>,
     main_tx: mpsc::Sender<Event>,
@@ -66,6 +89,7 @@ pub fn init_cluster(settings: &EnvSettings) -> Option<(ClusterIpc, ClusterInit)>
                 state: RAFT_LOG_BEHIND.into(),
                 commit_index_rx,
                 leader_hostname: None.into(),
+                leader_commit_index: LogIndex::MAX.into(),
             },
             ClusterInit {
                 main_rx,
@@ -103,11 +127,11 @@ pub async fn start_cluster<T>(
 
     let bind_addr = SocketAddr::from((
         if settings.contains_key("rpc-bind-addr") {
-            settings.parse_ipaddr("rpc-bind-addr", "0.0.0.0")
+            settings.value::<IpAddr>(SETTINGS, "rpc-bind-addr")
         } else {
-            settings.parse_ipaddr("jmap-bind-addr", "0.0.0.0")
+            settings.value::<IpAddr>(crate::server::http::SETTINGS, "jmap-bind-addr")
         },
-        settings.parse("rpc-port").unwrap_or(DEFAULT_RPC_PORT),
+        settings.value::<u16>(SETTINGS, "rpc-port"),
     ));
     info!("Starting RPC server at {} (UDP/TCP)...", bind_addr);
     let (shutdown_tx, shutdown_rx) = watch::channel(true);
@@ -128,8 +152,9 @@ pub async fn start_cluster<T>(
         &cluster.config,
     )
     .await;
+    spawn_log_verifier(core.clone(), settings.into());
 
-    let ping_interval = settings.parse("peer-ping-interval").unwrap_or(500);
+    let ping_interval = settings.value(SETTINGS, "peer-ping-interval");
 
     tokio::spawn(async move {
         let mut wait_timeout = Duration::from_millis(ping_interval);
@@ -262,8 +287,8 @@ where
         let config = Config::new(settings);
 
         // Obtain public addresses to advertise
-        let advertise_addr = settings.parse_ipaddr("rpc-advertise-addr", "0.0.0.0");
-        let rpc_port = settings.parse("rpc-port").unwrap_or(DEFAULT_RPC_PORT);
+        let advertise_addr = settings.value::<IpAddr>(SETTINGS, "rpc-advertise-addr");
+        let rpc_port: u16 = settings.value(SETTINGS, "rpc-port");
 
         // Obtain peer id from disk or generate a new one.
         let peer_id = if let Some(peer_id) = core.get_key("peer_id").await.unwrap() {
@@ -288,7 +313,7 @@ where
         let shard_id = if let Some(shard_id) = core.get_key("shard_id").await.unwrap() {
             shard_id
         } else {
-            let shard_id = settings.parse("shard-id").unwrap_or(0);
+            let shard_id = settings.value(SETTINGS, "shard-id");
             core.set_key("shard_id", shard_id).await.unwrap();
             shard_id
         };
@@ -309,11 +334,14 @@ where
         hostname.hash(&mut generation);
 
         // Rollback uncommitted entries for a previous leader term.
-        core.commit_leader(LogIndex::MAX, true).await.unwrap();
+        let term = core.store.fencing_token();
+        core.commit_leader(LogIndex::MAX, true, term).await.unwrap();
 
         // Apply committed updates and rollback uncommited ones for
         // a previous follower term.
-        core.commit_follower(LogIndex::MAX, true).await.unwrap();
+        core.commit_follower(LogIndex::MAX, true, term)
+            .await
+            .unwrap();
 
         let last_log = core
             .get_last_log()
@@ -338,6 +366,7 @@ where
             tx,
             gossip_tx,
             commit_index_tx,
+            commit_history: VecDeque::with_capacity(COMMIT_HISTORY_MAX),
         };
 
         // Add previously discovered peers
@@ -389,14 +418,13 @@ impl Config {
         let tls_domain = settings.get("rpc-tls-domain");
         Config {
             key: settings.get("encryption-key").unwrap(),
-            raft_batch_max: settings.parse("raft-batch-max").unwrap_or(10 * 1024 * 1024),
-            raft_election_timeout: settings.parse("raft-election-timeout").unwrap_or(1000),
-            rpc_inactivity_timeout: settings
-                .parse("rpc-inactivity-timeout")
-                .unwrap_or(5 * 60 * 1000),
-            rpc_timeout: settings.parse("rpc-timeout").unwrap_or(1000),
-            rpc_retries_max: settings.parse("rpc-retries-max").unwrap_or(5),
-            rpc_backoff_max: settings.parse("rpc-backoff-max").unwrap_or(3 * 60 * 1000),
+            raft_batch_max: settings.value(SETTINGS, "raft-batch-max"),
+            raft_election_timeout: settings.value(SETTINGS, "raft-election-timeout"),
+            rpc_inactivity_timeout: settings.value(SETTINGS, "rpc-inactivity-timeout"),
+            rpc_timeout: settings.value(SETTINGS, "rpc-timeout"),
+            rpc_retries_max: settings.value(SETTINGS, "rpc-retries-max"),
+            rpc_backoff_max: settings.value(SETTINGS, "rpc-backoff-max"),
+            health_max_lag: settings.value(SETTINGS, "cluster-health-max-lag"),
             tls_connector: Arc::new(TlsConnector::from(Arc::new(load_tls_client_config(
                 tls_domain.is_none(),
             )))),
@@ -91,6 +91,9 @@ where
                     Collection::SieveScript => {
                         store.raft_prepare_update::<SieveScript>(account_id, document_id, is_insert)
                     }
+                    Collection::Annotation => {
+                        store.raft_prepare_annotations(account_id, Collection::Mail, document_id)
+                    }
                     Collection::Thread | Collection::None => Err(StoreError::InternalError(
                         "Unsupported collection for changes".into(),
                     )),
@@ -106,6 +109,21 @@ where
             if let Some(item) = item {
                 batch_size += item.size();
                 updates.push(Update::Document { update: item });
+
+                // Mailbox subscriptions and account migrations are stored as
+                // annotations of the mailbox and the principal respectively
+                if matches!(collection, Collection::Mailbox | Collection::Principal) {
+                    let store = self.store.clone();
+                    if let Some(item) = self
+                        .spawn_worker(move || {
+                            store.raft_prepare_annotations(account_id, collection, document_id)
+                        })
+                        .await?
+                    {
+                        batch_size += item.size();
+                        updates.push(Update::Document { update: item });
+                    }
+                }
             } else if is_follower_rollback {
                 updates.push(Update::Document {
                     update: DocumentUpdate::Delete { document_id },
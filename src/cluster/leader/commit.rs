@@ -26,7 +26,7 @@ use crate::JMAPServer;
 use store::bincode;
 use store::core::document::Document;
 use store::core::error::StoreError;
-use store::log::raft::LogIndex;
+use store::log::raft::{LogIndex, TermId};
 use store::serialize::key::{LogKey, LEADER_COMMIT_INDEX_KEY};
 use store::serialize::{DeserializeBigEndian, StoreSerialize};
 use store::write::batch::WriteBatch;
@@ -49,7 +49,12 @@ where
         .await
     }
 
-    pub async fn commit_leader(&self, apply_up_to: LogIndex, do_reset: bool) -> store::Result<()> {
+    pub async fn commit_leader(
+        &self,
+        apply_up_to: LogIndex,
+        do_reset: bool,
+        term: TermId,
+    ) -> store::Result<()> {
         let store = self.store.clone();
         self.spawn_worker(move || {
             let apply_up_to: LogIndex = if apply_up_to != LogIndex::MAX {
@@ -103,6 +108,8 @@ where
                             })?,
                     );
 
+                    write_batch.set_fencing_token(term);
+
                     for document in bincode::deserialize::<Vec<Document>>(&value).map_err(|_| {
                         StoreError::SerializeError("Failed to deserialize tombstones".to_string())
                     })? {
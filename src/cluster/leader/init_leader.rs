@@ -23,6 +23,7 @@
 
 use super::Cluster;
 use super::Event;
+use crate::services::migration::spawn_migrations;
 use futures::poll;
 use std::task::Poll;
 use store::log::raft::LogIndex;
@@ -45,11 +46,11 @@ where
 
         let core = self.core.clone();
         tokio::spawn(async move {
-            if let Err(err) = core.commit_leader(LogIndex::MAX, true).await {
+            if let Err(err) = core.commit_leader(LogIndex::MAX, true, term).await {
                 error!("Failed to rollback uncommitted entries: {:?}", err);
                 return;
             }
-            if let Err(err) = core.commit_follower(LogIndex::MAX, true).await {
+            if let Err(err) = core.commit_follower(LogIndex::MAX, true, term).await {
                 error!("Failed to commit pending updates: {:?}", err);
                 return;
             }
@@ -72,10 +73,14 @@ where
                 return;
             }
             core.set_leader(term).await;
+            spawn_migrations(core.clone());
 
             if tx.send(true).is_err() {
                 error!("Failed to send message to raft leader processes.");
             }
+
+            // Reload the caches invalidated after becoming leader.
+            core.warmup_caches().await;
         });
         rx
     }
@@ -24,7 +24,7 @@
 use crate::cluster::leader::State;
 use crate::cluster::log::changes_merge::MergedChanges;
 use crate::cluster::log::entries_get::RaftStoreEntries;
-use crate::cluster::log::{AppendEntriesRequest, AppendEntriesResponse};
+use crate::cluster::log::{AppendEntriesRequest, AppendEntriesResponse, APPLY_FORMAT};
 use futures::poll;
 use std::task::Poll;
 use store::log::raft::{LogIndex, RaftId};
@@ -278,15 +278,26 @@ where
                             );
                             break;
                         }
-                        Response::None => {
-                            // Wait until the peer is back online
-                            debug!(
-                                concat!(
-                                    "[{}] Could not send message to {}, ",
-                                    "waiting until it is confirmed online."
-                                ),
-                                local_name, peer_name
-                            );
+                        response @ (Response::None | Response::Incompatible { .. }) => {
+                            // Wait until the peer is back online, followers that cannot
+                            // apply this node's log entries reconnect once upgraded.
+                            if let Response::Incompatible { version } = response {
+                                error!(
+                                    concat!(
+                                        "[{}] Peer {} cannot apply log entries in format {} ",
+                                        "(supports up to {}), waiting until it is upgraded."
+                                    ),
+                                    local_name, peer_name, APPLY_FORMAT, version.apply_format
+                                );
+                            } else {
+                                debug!(
+                                    concat!(
+                                        "[{}] Could not send message to {}, ",
+                                        "waiting until it is confirmed online."
+                                    ),
+                                    local_name, peer_name
+                                );
+                            }
                             'online: loop {
                                 tokio::select! {
                                     changed = log_index_rx.changed() => {
@@ -349,7 +360,8 @@ where
                         | Response::Vote { .. }
                         | Response::Pong
                         | Response::Command { .. }
-                        | Response::Auth { .. }) => {
+                        | Response::Auth { .. }
+                        | Response::Hello { .. }) => {
                             error!(
                                 "Unexpected response from peer {}: {:?}",
                                 peer_name, response
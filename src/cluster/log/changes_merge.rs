@@ -61,7 +61,8 @@ where
     ) -> store::Result<MergedChanges> {
         let mut changes = MergedChanges::new();
 
-        let key = LogKey::serialize_change(
+        // Only staged changes received from the leader are merged
+        let key = LogKey::serialize_staged_change(
             account,
             collection,
             if from_id != ChangeId::MAX { from_id } else { 0 },
@@ -86,6 +86,13 @@ where
                 })?;
 
                 if raft_id.index > start_index || start_index == LogIndex::MAX {
+                    if !Entry::verify(&raft_id, &value) {
+                        return Err(StoreError::DataCorruption(format!(
+                            "Checksum mismatch for raft entry {:?}.",
+                            raft_id
+                        )));
+                    }
+
                     last_index = raft_id.index;
                     entries_size += value.len() + std::mem::size_of::<RaftId>();
                     entries.push(Update::Log {
@@ -31,6 +31,7 @@ pub mod rollback_prepare;
 pub mod rollback_remove;
 pub mod update_apply;
 pub mod update_prepare;
+pub mod verify;
 
 use super::rpc;
 use store::blob::BlobId;
@@ -41,6 +42,20 @@ use store::{bincode, JMAPId};
 use store::{AccountId, DocumentId};
 use tokio::sync::oneshot;
 
+/// Format of the updates applied by followers to their state machine.
+///
+/// It has to be increased whenever a change to `Update`, `DocumentUpdate` or
+/// the serialized documents they carry cannot be applied by previous versions.
+/// Followers refuse to apply log entries from a leader with a newer format,
+/// so during a rolling upgrade the followers are upgraded first and the
+/// leader last. Newer nodes must always be able to apply older formats.
+///
+/// Format history:
+///  1 - Initial format.
+///  2 - Private annotations (`DocumentUpdate::Annotations`).
+///  3 - Raft entries sealed with a checksum (`Entry::CHECKSUM`).
+pub const APPLY_FORMAT: u8 = 3;
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub enum Update {
     Begin {
@@ -79,6 +94,10 @@ pub enum DocumentUpdate {
     Delete {
         document_id: DocumentId,
     },
+    Annotations {
+        document_id: DocumentId,
+        annotations: Vec<(AccountId, Vec<u8>)>,
+    },
 }
 
 impl DocumentUpdate {
@@ -97,6 +116,13 @@ impl DocumentUpdate {
             }
             DocumentUpdate::Update { fields, .. } => fields.len() + std::mem::size_of::<JMAPId>(),
             DocumentUpdate::Delete { .. } => std::mem::size_of::<DocumentId>(),
+            DocumentUpdate::Annotations { annotations, .. } => {
+                std::mem::size_of::<DocumentId>()
+                    + annotations
+                        .iter()
+                        .map(|(_, value)| value.len() + std::mem::size_of::<AccountId>())
+                        .sum::<usize>()
+            }
         }
     }
 }
@@ -119,6 +145,9 @@ pub enum AppendEntriesRequest {
     AdvanceCommitIndex {
         commit_index: LogIndex,
     },
+    Resync {
+        after_index: LogIndex,
+    },
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -146,6 +146,27 @@ where
             write_batch = Vec::new();
         }
 
+        // Staged changes were never applied, discard them
+        for (key, _) in self.db.iterator(
+            ColumnFamily::Logs,
+            &[LogKey::STAGED_CHANGE_KEY_PREFIX],
+            Direction::Forward,
+        )? {
+            if !key.starts_with(&[LogKey::STAGED_CHANGE_KEY_PREFIX]) {
+                break;
+            }
+            if after_index == LogIndex::MAX
+                || LogKey::deserialize_change_id(&key).ok_or_else(|| {
+                    StoreError::InternalError(format!(
+                        "Failed to deserialize staged changelog key: [{:?}]",
+                        key
+                    ))
+                })? > after_index
+            {
+                write_batch.push(WriteOperation::delete(ColumnFamily::Logs, key.to_vec()));
+            }
+        }
+
         for (key, _) in self.db.iterator(
             ColumnFamily::Logs,
             &[LogKey::RAFT_KEY_PREFIX],
@@ -170,6 +191,9 @@ where
             }
         }
 
+        // Entries after the rollback point will be applied again
+        self.rewind_last_applied(after_index, &mut write_batch)?;
+
         if !write_batch.is_empty() {
             self.db.write(write_batch)?;
         }
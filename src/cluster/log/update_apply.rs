@@ -21,7 +21,7 @@
  * for more details.
 */
 
-use super::DocumentUpdate;
+use super::{DocumentUpdate, PendingUpdate, PendingUpdates};
 use jmap::jmap_store::RaftObject;
 use jmap::orm::serialize::JMAPOrm;
 use jmap::orm::TinyORM;
@@ -43,10 +43,14 @@ use store::core::collection::Collection;
 use store::core::document::Document;
 use store::core::error::StoreError;
 use store::core::JMAPIdPrefix;
-use store::serialize::StoreDeserialize;
+use store::log::raft::{AppliedId, LogIndex, RaftId, TermId};
+use store::serialize::key::LogKey;
+use store::serialize::{DeserializeBigEndian, StoreDeserialize};
+use store::tracing::debug;
 use store::write::batch::WriteBatch;
+use store::write::operation::WriteOperation;
 use store::write::options::IndexOptions;
-use store::{DocumentId, JMAPStore, Store};
+use store::{AccountId, ColumnFamily, Direction, DocumentId, JMAPStore, Store};
 
 pub trait RaftStoreApplyUpdate<T>
 where
@@ -67,12 +71,27 @@ where
     where
         U: RaftObject<T> + 'static;
 
+    fn raft_apply_annotations(
+        &self,
+        write_batch: &mut WriteBatch,
+        collection: Collection,
+        update: DocumentUpdate,
+    ) -> store::Result<()>;
+
     fn delete_document(
         &self,
         write_batch: &mut WriteBatch,
         collection: Collection,
         document_id: DocumentId,
     ) -> store::Result<()>;
+
+    fn apply_pending_updates(
+        &self,
+        apply_up_to: LogIndex,
+        do_reset: bool,
+        term: TermId,
+        log_batch: &mut Vec<WriteOperation>,
+    ) -> store::Result<()>;
 }
 
 impl<T> RaftStoreApplyUpdate<T> for JMAPStore<T>
@@ -87,8 +106,20 @@ where
     ) -> store::Result<()> {
         match collection {
             Collection::Mail => self.raft_apply_update::<Email>(write_batch, update),
-            Collection::Mailbox => self.raft_apply_update::<Mailbox>(write_batch, update),
-            Collection::Principal => self.raft_apply_update::<Principal>(write_batch, update),
+            // Mailbox subscriptions follow the mailbox they belong to
+            Collection::Mailbox => match update {
+                DocumentUpdate::Annotations { .. } => {
+                    self.raft_apply_annotations(write_batch, Collection::Mailbox, update)
+                }
+                update => self.raft_apply_update::<Mailbox>(write_batch, update),
+            },
+            // Account migrations are stored as annotations of the principal
+            Collection::Principal => match update {
+                DocumentUpdate::Annotations { .. } => {
+                    self.raft_apply_annotations(write_batch, Collection::Principal, update)
+                }
+                update => self.raft_apply_update::<Principal>(write_batch, update),
+            },
             Collection::PushSubscription => {
                 self.raft_apply_update::<PushSubscription>(write_batch, update)
             }
@@ -97,6 +128,9 @@ where
                 self.raft_apply_update::<EmailSubmission>(write_batch, update)
             }
             Collection::SieveScript => self.raft_apply_update::<SieveScript>(write_batch, update),
+            Collection::Annotation => {
+                self.raft_apply_annotations(write_batch, Collection::Mail, update)
+            }
             Collection::Thread | Collection::None => {
                 debug_assert!(false, "Unsupported update for {:?}", collection);
                 Ok(())
@@ -168,6 +202,47 @@ where
                     err => return err,
                 }
             }
+            DocumentUpdate::Annotations { .. } => {
+                debug_assert!(false, "Unexpected annotations for {:?}", U::collection());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn raft_apply_annotations(
+        &self,
+        write_batch: &mut WriteBatch,
+        collection: Collection,
+        update: DocumentUpdate,
+    ) -> store::Result<()> {
+        let (document_id, annotations) = match update {
+            DocumentUpdate::Annotations {
+                document_id,
+                annotations,
+            } => (document_id, annotations),
+            DocumentUpdate::Delete { document_id } => (document_id, Vec::new()),
+            _ => {
+                debug_assert!(false, "Unexpected update for annotations: {:?}", update);
+                return Ok(());
+            }
+        };
+
+        // Replace the local annotations with the ones from the leader
+        let mut document = Document::new(collection, document_id);
+        for (principal_id, _) in
+            self.get_annotations(write_batch.account_id, collection, document_id)?
+        {
+            if !annotations.iter().any(|(id, _)| *id == principal_id) {
+                document.annotation(principal_id, Vec::new(), IndexOptions::new().clear());
+            }
+        }
+        for (principal_id, value) in annotations {
+            document.annotation(principal_id, value, IndexOptions::new());
+        }
+
+        if !document.is_empty() {
+            write_batch.update_document(document);
         }
 
         Ok(())
@@ -198,9 +273,154 @@ where
             Collection::SieveScript => {
                 self.sieve_script_delete(write_batch.account_id, &mut document)?
             }
+            Collection::Annotation => {
+                return self.raft_apply_annotations(
+                    write_batch,
+                    Collection::Mail,
+                    DocumentUpdate::Delete { document_id },
+                );
+            }
             Collection::Thread | Collection::None => unreachable!(),
         }
         write_batch.delete_document(document);
         Ok(())
     }
+
+    fn apply_pending_updates(
+        &self,
+        apply_up_to: LogIndex,
+        do_reset: bool,
+        term: TermId,
+        log_batch: &mut Vec<WriteOperation>,
+    ) -> store::Result<()> {
+        let last_applied = self.get_last_applied()?;
+
+        for (key, value) in self.db.iterator(
+            ColumnFamily::Logs,
+            &[LogKey::PENDING_UPDATES_KEY_PREFIX],
+            Direction::Forward,
+        )? {
+            if !key.starts_with(&[LogKey::PENDING_UPDATES_KEY_PREFIX]) {
+                break;
+            }
+            let (index, seq_id) = (&key[..])
+                .deserialize_be_u64(1)
+                .and_then(|index| {
+                    (&key[..])
+                        .deserialize_be_u64(1 + std::mem::size_of::<LogIndex>())
+                        .map(|seq_id| (index, seq_id))
+                })
+                .ok_or_else(|| {
+                    StoreError::InternalError(format!(
+                        "Failed to deserialize index from changelog key: [{:?}]",
+                        key
+                    ))
+                })?;
+
+            if apply_up_to != LogIndex::MAX && index <= apply_up_to {
+                let applied_id = AppliedId::new(
+                    self.get_next_raft_id(RaftId::new(0, index))?
+                        .filter(|raft_id| raft_id.index == index)
+                        .unwrap_or_else(|| RaftId::new(term, index)),
+                    seq_id,
+                );
+
+                // Updates replayed after a crash were already applied together
+                // with the last applied marker, only the pending entry is left.
+                if last_applied.map_or(false, |last_applied| last_applied.includes(&applied_id)) {
+                    debug!(
+                        "Skipping already applied updates for index {} (sequence {}).",
+                        index, seq_id
+                    );
+                    self.db.delete(ColumnFamily::Logs, &key)?;
+                    continue;
+                }
+
+                let mut write_batch = WriteBatch::new(AccountId::MAX);
+                write_batch.set_fencing_token(term);
+                let mut linked_batches = Vec::new();
+                let mut account_id = AccountId::MAX;
+                let mut collection = Collection::None;
+
+                for update in PendingUpdates::deserialize(&value)
+                    .ok_or_else(|| {
+                        StoreError::InternalError(format!(
+                            "Failed to deserialize pending updates for key [{:?}]",
+                            key
+                        ))
+                    })?
+                    .updates
+                {
+                    match update {
+                        PendingUpdate::Begin {
+                            account_id: update_account_id,
+                            collection: update_collection,
+                        } => {
+                            account_id = update_account_id;
+                            collection = update_collection;
+                        }
+                        PendingUpdate::Update { update } => {
+                            debug_assert!(
+                                account_id != AccountId::MAX && collection != Collection::None
+                            );
+
+                            if account_id != write_batch.account_id {
+                                if !write_batch.is_empty() {
+                                    linked_batches.push(write_batch);
+                                    write_batch = WriteBatch::new(account_id);
+                                    write_batch.set_fencing_token(term);
+                                } else {
+                                    write_batch.account_id = account_id;
+                                }
+                            }
+                            self.apply_update(&mut write_batch, collection, update)?;
+                        }
+                        PendingUpdate::Delete { document_ids } => {
+                            debug_assert!(
+                                account_id != AccountId::MAX && collection != Collection::None
+                            );
+
+                            if account_id != write_batch.account_id {
+                                if !write_batch.is_empty() {
+                                    linked_batches.push(write_batch);
+                                    write_batch = WriteBatch::new(account_id);
+                                    write_batch.set_fencing_token(term);
+                                } else {
+                                    write_batch.account_id = account_id;
+                                }
+                            }
+
+                            for document_id in document_ids {
+                                match self.delete_document(
+                                    &mut write_batch,
+                                    collection,
+                                    document_id,
+                                ) {
+                                    Ok(_) | Err(StoreError::NotFound(_)) => {}
+                                    Err(e) => return Err(e),
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // All accounts are written in a single batch along with the
+                // applied marker, so that the entry is never half applied.
+                for linked_batch in linked_batches {
+                    write_batch.add_linked_batch(linked_batch);
+                }
+                write_batch.set_applied_id(applied_id);
+                self.write(write_batch)?;
+            } else if do_reset {
+                log_batch.push(WriteOperation::Delete {
+                    cf: ColumnFamily::Logs,
+                    key: key.to_vec(),
+                });
+            } else {
+                break;
+            }
+        }
+
+        Ok(())
+    }
 }
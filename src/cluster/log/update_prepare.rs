@@ -23,6 +23,7 @@
 
 use super::DocumentUpdate;
 use jmap::{jmap_store::RaftObject, orm::serialize::JMAPOrm};
+use store::core::collection::Collection;
 use store::serialize::StoreSerialize;
 use store::{core::error::StoreError, AccountId, DocumentId, JMAPStore, Store};
 
@@ -38,6 +39,13 @@ where
     ) -> store::Result<Option<DocumentUpdate>>
     where
         U: RaftObject<T> + 'static;
+
+    fn raft_prepare_annotations(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+        document_id: DocumentId,
+    ) -> store::Result<Option<DocumentUpdate>>;
 }
 
 impl<T> RaftStorePrepareUpdate<T> for JMAPStore<T>
@@ -81,4 +89,25 @@ where
             },
         )
     }
+
+    fn raft_prepare_annotations(
+        &self,
+        account_id: AccountId,
+        collection: Collection,
+        document_id: DocumentId,
+    ) -> store::Result<Option<DocumentUpdate>> {
+        Ok(
+            if self
+                .get_document_ids(account_id, collection)?
+                .map_or(false, |document_ids| document_ids.contains(document_id))
+            {
+                Some(DocumentUpdate::Annotations {
+                    document_id,
+                    annotations: self.get_annotations(account_id, collection, document_id)?,
+                })
+            } else {
+                None
+            },
+        )
+    }
 }
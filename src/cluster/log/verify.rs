@@ -0,0 +1,411 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::Duration;
+
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use store::config::{env_settings::EnvSettings, settings::Setting};
+use store::core::error::StoreError;
+use store::log::entry::Entry;
+use store::log::raft::{LogIndex, RaftId};
+use store::serialize::key::LogKey;
+use store::tracing::{debug, error, info};
+use store::write::batch;
+use store::{blake3, ColumnFamily, Direction, JMAPStore, Store};
+use tokio::sync::oneshot;
+
+use crate::cluster::rpc::command::{Command, CommandResponse};
+use crate::cluster::{self, Cluster};
+use crate::JMAPServer;
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::seconds("raft-verify-interval")
+        .default("3600")
+        .describe("Time between Raft log consistency checks, 0 = disabled"),
+    Setting::integer("raft-verify-range")
+        .min(1)
+        .default("1000")
+        .describe("Log entries covered by each range hash"),
+];
+
+/// Hash of all raft entries with an index within [from, to].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogRangeHash {
+    pub from: LogIndex,
+    pub to: LogIndex,
+    pub hash: [u8; 32],
+}
+
+#[derive(Debug, Default)]
+pub struct LogHashes {
+    pub ranges: Vec<LogRangeHash>,
+    pub corrupted: Vec<RaftId>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct LogVerification {
+    #[serde(rename = "corruptedEntries")]
+    pub corrupted: Vec<LogIndex>,
+    #[serde(rename = "divergentRanges")]
+    pub divergent: Vec<(LogIndex, LogIndex)>,
+    #[serde(rename = "verifiedRanges")]
+    pub verified: usize,
+}
+
+pub struct LogVerifyConfig {
+    pub interval: u64,
+    pub range_size: u64,
+}
+
+pub trait RaftStoreVerify {
+    fn get_log_hashes(&self, range_size: u64, up_to: LogIndex) -> store::Result<LogHashes>;
+}
+
+impl<T> RaftStoreVerify for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn get_log_hashes(&self, range_size: u64, up_to: LogIndex) -> store::Result<LogHashes> {
+        let mut hashes = LogHashes::default();
+        let prefix = &[LogKey::RAFT_KEY_PREFIX];
+        let range_size = std::cmp::max(range_size, 1);
+        let mut first_index = LogIndex::MAX;
+        let mut hasher: Option<(LogIndex, blake3::Hasher)> = None;
+
+        for (key, value) in self
+            .db
+            .iterator(ColumnFamily::Logs, prefix, Direction::Forward)?
+        {
+            if !key.starts_with(prefix) {
+                break;
+            }
+
+            let raft_id = LogKey::deserialize_raft(&key).ok_or_else(|| {
+                StoreError::InternalError(format!("Corrupted raft entry for [{:?}]", key))
+            })?;
+            if raft_id.index > up_to {
+                break;
+            }
+            if !Entry::verify(&raft_id, &value) {
+                hashes.corrupted.push(raft_id);
+            }
+
+            // Snapshots differ between peers, only the entries that follow them are hashed.
+            if first_index == LogIndex::MAX {
+                first_index = if value
+                    .first()
+                    .map_or(false, |t| t & !Entry::CHECKSUM == batch::Change::SNAPSHOT)
+                {
+                    raft_id.index + 1
+                } else {
+                    raft_id.index
+                };
+                if first_index > raft_id.index {
+                    continue;
+                }
+            }
+
+            let range_start = raft_id.index - (raft_id.index % range_size);
+            if range_start < first_index {
+                continue;
+            }
+            match &mut hasher {
+                Some((from, _)) if *from == range_start => (),
+                _ => {
+                    hash_range(&mut hashes, hasher.take(), range_size, up_to);
+                    hasher = Some((range_start, blake3::Hasher::new()));
+                }
+            }
+
+            let (_, hasher) = hasher.as_mut().unwrap();
+            hasher.update(&raft_id.term.to_le_bytes());
+            hasher.update(&raft_id.index.to_le_bytes());
+            hasher.update(&(value.len() as u64).to_le_bytes());
+            hasher.update(&value);
+        }
+        hash_range(&mut hashes, hasher.take(), range_size, up_to);
+
+        Ok(hashes)
+    }
+}
+
+fn hash_range(
+    hashes: &mut LogHashes,
+    hasher: Option<(LogIndex, blake3::Hasher)>,
+    range_size: u64,
+    up_to: LogIndex,
+) {
+    // Ranges that are not complete yet are left for the next verification
+    if let Some((from, hasher)) = hasher {
+        let to = from + range_size - 1;
+        if to <= up_to {
+            hashes.ranges.push(LogRangeHash {
+                from,
+                to,
+                hash: *hasher.finalize().as_bytes(),
+            });
+        }
+    }
+}
+
+/// Returns the ranges present on both peers that have different hashes.
+pub fn compare_log_hashes(
+    local: &[LogRangeHash],
+    remote: &[LogRangeHash],
+) -> (Vec<(LogIndex, LogIndex)>, usize) {
+    let mut divergent = Vec::new();
+    let mut verified = 0;
+
+    for remote_range in remote {
+        if let Some(local_range) = local
+            .iter()
+            .find(|r| r.from == remote_range.from && r.to == remote_range.to)
+        {
+            if local_range.hash != remote_range.hash {
+                divergent.push((remote_range.from, remote_range.to));
+            }
+            verified += 1;
+        }
+    }
+
+    (divergent, verified)
+}
+
+impl From<&EnvSettings> for LogVerifyConfig {
+    fn from(settings: &EnvSettings) -> Self {
+        LogVerifyConfig {
+            interval: settings.value(SETTINGS, "raft-verify-interval"),
+            range_size: settings.value(SETTINGS, "raft-verify-range"),
+        }
+    }
+}
+
+impl<T> JMAPServer<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    pub async fn handle_verify_log(
+        &self,
+        peer: String,
+        commit_index: LogIndex,
+        range_size: u64,
+        ranges: Vec<LogRangeHash>,
+    ) -> CommandResponse {
+        // Only committed entries are compared
+        if commit_index == LogIndex::MAX || ranges.is_empty() {
+            return CommandResponse::VerifyLog {
+                divergent: vec![],
+                verified: 0,
+            };
+        }
+        let up_to = std::cmp::min(commit_index, ranges.last().unwrap().to);
+
+        let store = self.store.clone();
+        match self
+            .spawn_worker(move || store.get_log_hashes(range_size, up_to))
+            .await
+        {
+            Ok(hashes) => {
+                let (divergent, verified) = compare_log_hashes(&hashes.ranges, &ranges);
+                if !divergent.is_empty() {
+                    error!(
+                        "Raft log of peer {} diverges from the leader's log at ranges {:?}.",
+                        peer, divergent
+                    );
+                }
+                CommandResponse::VerifyLog {
+                    divergent,
+                    verified,
+                }
+            }
+            Err(err) => {
+                error!("Failed to obtain raft log hashes: {:?}", err);
+                CommandResponse::Error {
+                    message: "Temporary database failure".to_string(),
+                }
+            }
+        }
+    }
+}
+
+impl<T> Cluster<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    pub async fn handle_resync_log(
+        &self,
+        after_index: LogIndex,
+        response_tx: oneshot::Sender<Result<(), String>>,
+    ) {
+        let result = if let cluster::raft::State::Follower { tx, .. } = &self.state {
+            let (event_tx, event_rx) = oneshot::channel();
+            if tx
+                .send(crate::cluster::log::Event {
+                    response_tx: event_tx,
+                    request: crate::cluster::log::AppendEntriesRequest::Resync { after_index },
+                })
+                .await
+                .is_ok()
+            {
+                // Wait for the follower process without blocking the cluster loop
+                tokio::spawn(async move {
+                    let result = match event_rx.await {
+                        Ok(cluster::rpc::Response::AppendEntries(_)) => Ok(()),
+                        _ => Err("Failed to resynchronize log, check the logs.".to_string()),
+                    };
+                    response_tx.send(result).ok();
+                });
+                return;
+            } else {
+                Err("Follower process is not running.".to_string())
+            }
+        } else {
+            Err("Only followers can be resynchronized.".to_string())
+        };
+
+        response_tx.send(result).ok();
+    }
+}
+
+impl<T> JMAPServer<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Verifies the checksums of the local raft log and, on followers, compares
+    /// the hashes of the committed log ranges with the leader's log.
+    pub async fn verify_raft_log(&self, range_size: u64) -> store::Result<LogVerification> {
+        let store = self.store.clone();
+        let hashes = self
+            .spawn_worker(move || store.get_log_hashes(range_size, LogIndex::MAX))
+            .await?;
+        let mut verification = LogVerification {
+            corrupted: hashes.corrupted.iter().map(|id| id.index).collect(),
+            ..Default::default()
+        };
+        if !hashes.corrupted.is_empty() {
+            error!(
+                "Raft log entries {:?} failed checksum verification.",
+                verification.corrupted
+            );
+        }
+
+        if self.is_in_cluster() && !self.is_leader() && self.is_up_to_date() {
+            match self
+                .rpc_command(Command::VerifyLog {
+                    range_size,
+                    ranges: hashes.ranges,
+                })
+                .await
+            {
+                Some(CommandResponse::VerifyLog {
+                    divergent,
+                    verified,
+                }) => {
+                    if !divergent.is_empty() {
+                        error!(
+                            concat!(
+                                "Raft log diverges from the leader's log at ranges {:?}, ",
+                                "resynchronize this node with POST /admin/cluster/resync?from={}."
+                            ),
+                            divergent, divergent[0].0
+                        );
+                    }
+                    verification.divergent = divergent;
+                    verification.verified = verified;
+                }
+                response => {
+                    debug!("Failed to compare log hashes with leader: {:?}", response);
+                }
+            }
+        }
+
+        Ok(verification)
+    }
+
+    pub async fn resync_raft_log(&self, from_index: LogIndex) -> Result<(), String> {
+        let cluster = self
+            .cluster
+            .as_ref()
+            .ok_or_else(|| "Node is not part of a cluster.".to_string())?;
+        let (tx, rx) = oneshot::channel();
+        cluster
+            .tx
+            .send(cluster::Event::ResyncLog {
+                after_index: from_index.checked_sub(1).unwrap_or(LogIndex::MAX),
+                response_tx: tx,
+            })
+            .await
+            .map_err(|_| "Failed to send resync request to cluster.".to_string())?;
+        rx.await
+            .unwrap_or_else(|_| Err("Cluster process exited.".to_string()))
+    }
+}
+
+pub fn spawn_log_verifier<T>(core: web::Data<JMAPServer<T>>, config: LogVerifyConfig)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    if config.interval == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(config.interval)).await;
+            match core.verify_raft_log(config.range_size).await {
+                Ok(verification) => {
+                    if verification.corrupted.is_empty() && verification.divergent.is_empty() {
+                        info!(
+                            "Raft log verified, {} range(s) matched the leader's log.",
+                            verification.verified
+                        );
+                    }
+                }
+                Err(err) => {
+                    error!("Failed to verify raft log: {:?}", err);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare_log_hashes, LogRangeHash};
+
+    #[test]
+    fn compare_ranges() {
+        let range = |from, to, hash| LogRangeHash {
+            from,
+            to,
+            hash: [hash; 32],
+        };
+        let local = vec![range(0, 9, 1), range(10, 19, 2), range(20, 29, 3)];
+        let remote = vec![range(10, 19, 2), range(20, 29, 4), range(30, 39, 5)];
+
+        assert_eq!(compare_log_hashes(&local, &remote), (vec![(20, 29)], 2));
+        assert_eq!(compare_log_hashes(&local, &local), (vec![], 3));
+        assert_eq!(compare_log_hashes(&[], &remote), (vec![], 0));
+    }
+}
@@ -87,7 +87,13 @@ where
                     .send(rpc::Response::Pong)
                     .unwrap_or_else(|_| error!("Oneshot response channel closed.")),
                 rpc::Request::Command { command } => {
-                    self.handle_command(command, response_tx).await;
+                    self.handle_command(peer_id, command, response_tx).await;
+                }
+                rpc::Request::RevokeCredentials { account_ids } => {
+                    self.core.sessions.revoke(&account_ids);
+                    response_tx
+                        .send(rpc::Response::None)
+                        .unwrap_or_else(|_| error!("Oneshot response channel closed."));
                 }
                 _ => response_tx
                     .send(rpc::Response::None)
@@ -142,6 +148,25 @@ where
             } => {
                 self.send_command(command, response_tx).await;
             }
+            Event::RevokeCredentials { account_ids } => {
+                for peer in &self.peers {
+                    peer.dispatch_request(rpc::Request::RevokeCredentials {
+                        account_ids: account_ids.clone(),
+                    })
+                    .await;
+                }
+            }
+            Event::Status { response_tx } => {
+                if response_tx.send(self.build_status()).is_err() {
+                    error!("Failed to send cluster status.");
+                }
+            }
+            Event::ResyncLog {
+                after_index,
+                response_tx,
+            } => {
+                self.handle_resync_log(after_index, response_tx).await;
+            }
             Event::Shutdown => return Ok(false),
 
             #[cfg(test)]
@@ -26,19 +26,22 @@ use self::rpc::command::{Command, CommandResponse};
 use crate::JMAPServer;
 use actix_web::web;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 use std::{net::SocketAddr, sync::atomic::AtomicU8, time::Instant};
 use store::log::raft::{LogIndex, RaftId, TermId};
 use store::{
     bincode,
     serialize::{StoreDeserialize, StoreSerialize},
-    Store,
+    AccountId, Store,
 };
 use tokio::sync::{mpsc, oneshot, watch};
 use tokio_rustls::TlsConnector;
 
 pub mod follower;
 pub mod gossip;
+pub mod health;
 pub mod init;
 pub mod leader;
 pub mod log;
@@ -94,6 +97,7 @@ where
     pub last_log: RaftId,
     pub uncommitted_index: LogIndex,
     pub state: raft::State,
+    pub commit_history: VecDeque<(LogIndex, Instant)>,
 }
 
 pub struct Config {
@@ -104,6 +108,7 @@ pub struct Config {
     pub rpc_timeout: u64,            // 1000
     pub rpc_retries_max: u32,        // 5
     pub rpc_backoff_max: u64,        // 3 * 60 * 1000 (1 minute)
+    pub health_max_lag: u64,         // 100
     pub tls_connector: Arc<TlsConnector>,
     pub tls_domain: String,
 }
@@ -127,6 +132,10 @@ pub enum Event {
         command: Command,
         response_tx: oneshot::Sender<CommandResponse>,
     },
+    ResyncLog {
+        after_index: LogIndex,
+        response_tx: oneshot::Sender<Result<(), String>>,
+    },
     StepDown {
         term: TermId,
     },
@@ -140,6 +149,12 @@ pub enum Event {
         peer_id: PeerId,
         commit_index: LogIndex,
     },
+    RevokeCredentials {
+        account_ids: Vec<AccountId>,
+    },
+    Status {
+        response_tx: oneshot::Sender<health::ClusterStatus>,
+    },
     Shutdown,
 
     #[cfg(test)]
@@ -185,6 +200,7 @@ pub struct ClusterIpc {
     pub state: AtomicU8,
     pub leader_hostname: store::parking_lot::Mutex<Option<String>>,
     pub commit_index_rx: watch::Receiver<LogIndex>,
+    pub leader_commit_index: AtomicU64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -57,13 +57,17 @@ where
         if commit_index > self.last_log.index.wrapping_add(1) {
             self.last_log.index = commit_index.wrapping_sub(1);
             self.last_log.term = self.term;
+            self.record_commit(self.last_log.index);
 
             let last_log_index = self.last_log.index;
+            let term = self.term;
             let core = self.core.clone();
 
-            // Commit pending updates
+            // Commit pending updates, a quorum having accepted entries from this
+            // term, writes produced under earlier terms are fenced off
             tokio::spawn(async move {
-                if let Err(err) = core.commit_leader(last_log_index, false).await {
+                core.advance_fencing_token(term).await;
+                if let Err(err) = core.commit_leader(last_log_index, false, term).await {
                     error!("Failed to commit leader: {:?}", err);
                 }
             });
@@ -85,7 +85,11 @@ where
             .store(false, Ordering::Relaxed);
         self.state_change
             .clone()
-            .send(state_change::Event::Stop)
+            .send(if self.push_broker.is_some() {
+                state_change::Event::Standby
+            } else {
+                state_change::Event::Stop
+            })
             .await
             .ok();
         self.email_delivery
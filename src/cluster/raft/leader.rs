@@ -28,7 +28,7 @@ use crate::services::{email_delivery, state_change};
 use crate::JMAPServer;
 use std::sync::atomic::Ordering;
 use store::log::raft::TermId;
-use store::tracing::debug;
+use store::tracing::{debug, error};
 use store::Store;
 use tokio::sync::watch;
 
@@ -128,6 +128,7 @@ where
         }
         self.store.recipients.invalidate_all();
         self.store.shared_documents.invalidate_all();
+        self.store.submission_quotas.invalidate_all();
 
         // Set leader status
         self.store
@@ -153,6 +154,22 @@ where
             .ok();
     }
 
+    pub async fn advance_fencing_token(&self, term: TermId) {
+        if term <= self.store.fencing_token() {
+            return;
+        }
+        let store = self.store.clone();
+        if let Err(err) = self
+            .spawn_worker(move || store.advance_fencing_token(term))
+            .await
+        {
+            error!(
+                "Failed to persist fencing token for term {}: {:?}",
+                term, err
+            );
+        }
+    }
+
     pub fn is_leader(&self) -> bool {
         self.cluster
             .as_ref()
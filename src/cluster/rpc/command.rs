@@ -23,16 +23,19 @@
 
 use jmap_sharing::principal::account::JMAPAccountStore;
 use serde::{Deserialize, Serialize};
-use store::{tracing::error, RecipientType, Store};
+use store::{log::raft::LogIndex, tracing::error, RecipientType, Store};
 use tokio::sync::oneshot;
 
 use crate::{
-    cluster::{self, Cluster},
-    lmtp::session::RcptType,
+    cluster::{self, log::verify::LogRangeHash, Cluster, PeerId},
+    lmtp::{authentication::AuthOutcome, session::RcptType, trace::IngestTrace},
     JMAPServer,
 };
 
-use super::{Request, Response};
+use super::{
+    serialize::{INGEST_AUTH_VERSION, INGEST_TRACE_VERSION, LEGACY_VERSION},
+    Request, Response,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Command {
@@ -44,6 +47,75 @@ pub enum Command {
         rcpt_to: Vec<RcptType>,
         raw_message: Vec<u8>,
     },
+    VerifyLog {
+        range_size: u64,
+        ranges: Vec<LogRangeHash>,
+    },
+    IngestMessageTraced {
+        mail_from: String,
+        rcpt_to: Vec<RcptType>,
+        raw_message: Vec<u8>,
+        trace: IngestTrace,
+    },
+    IngestMessageAuthenticated {
+        mail_from: String,
+        rcpt_to: Vec<RcptType>,
+        raw_message: Vec<u8>,
+        trace: Option<IngestTrace>,
+        auth: AuthOutcome,
+    },
+}
+
+impl Command {
+    /// Protocol version that introduced the command.
+    pub fn min_version(&self) -> u8 {
+        match self {
+            Command::IngestMessageTraced { .. } => INGEST_TRACE_VERSION,
+            Command::IngestMessageAuthenticated { .. } => INGEST_AUTH_VERSION,
+            _ => LEGACY_VERSION,
+        }
+    }
+
+    /// Leaders speaking a previous version ingest messages without the
+    /// details collected by this node.
+    pub fn downgrade(self, version: u8) -> Self {
+        match self {
+            Command::IngestMessageAuthenticated {
+                mail_from,
+                rcpt_to,
+                raw_message,
+                trace,
+                ..
+            } if version < INGEST_AUTH_VERSION => {
+                if let Some(trace) = trace {
+                    Command::IngestMessageTraced {
+                        mail_from,
+                        rcpt_to,
+                        raw_message,
+                        trace,
+                    }
+                    .downgrade(version)
+                } else {
+                    Command::IngestMessage {
+                        mail_from,
+                        rcpt_to,
+                        raw_message,
+                    }
+                }
+            }
+            Command::IngestMessageTraced {
+                mail_from,
+                rcpt_to,
+                raw_message,
+                ..
+            } if version < INGEST_TRACE_VERSION => Command::IngestMessage {
+                mail_from,
+                rcpt_to,
+                raw_message,
+            },
+            command => command,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -57,6 +129,10 @@ pub enum CommandResponse {
     Error {
         message: String,
     },
+    VerifyLog {
+        divergent: Vec<(LogIndex, LogIndex)>,
+        verified: usize,
+    },
 }
 
 impl<T> Cluster<T>
@@ -97,11 +173,17 @@ where
 
     pub async fn handle_command(
         &mut self,
+        peer_id: PeerId,
         command: Command,
         response_tx: oneshot::Sender<super::Response>,
     ) {
         if self.is_leading() {
             let core = self.core.clone();
+            let commit_index = *self.commit_index_tx.borrow();
+            let peer = self
+                .get_peer(peer_id)
+                .map(|peer| peer.to_string())
+                .unwrap_or_else(|| peer_id.to_string());
             tokio::spawn(async move {
                 let response = match command {
                     Command::ExpandRcpt { mailbox } => {
@@ -123,7 +205,34 @@ where
                         rcpt_to,
                         raw_message,
                     } => CommandResponse::IngestMessage {
-                        result: core.mail_ingest(mail_from, rcpt_to, raw_message).await,
+                        result: core
+                            .mail_ingest(mail_from, rcpt_to, raw_message, None, None)
+                            .await,
+                    },
+                    Command::VerifyLog { range_size, ranges } => {
+                        core.handle_verify_log(peer, commit_index, range_size, ranges)
+                            .await
+                    }
+                    Command::IngestMessageTraced {
+                        mail_from,
+                        rcpt_to,
+                        raw_message,
+                        trace,
+                    } => CommandResponse::IngestMessage {
+                        result: core
+                            .mail_ingest(mail_from, rcpt_to, raw_message, trace.into(), None)
+                            .await,
+                    },
+                    Command::IngestMessageAuthenticated {
+                        mail_from,
+                        rcpt_to,
+                        raw_message,
+                        trace,
+                        auth,
+                    } => CommandResponse::IngestMessage {
+                        result: core
+                            .mail_ingest(mail_from, rcpt_to, raw_message, trace, auth.into())
+                            .await,
                     },
                 };
 
@@ -166,3 +275,86 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cluster::rpc::serialize::{
+        INGEST_AUTH_VERSION, INGEST_TRACE_VERSION, LEGACY_VERSION,
+    };
+    use crate::lmtp::{
+        authentication::{dmarc::DmarcOutput, AuthOutcome, AuthResult, Policy},
+        trace::IngestTrace,
+    };
+
+    use super::Command;
+
+    #[test]
+    fn downgrade_commands() {
+        let trace = || IngestTrace {
+            id: "trace".to_string(),
+            received_at: 0,
+            peer: "192.0.2.1".to_string(),
+            helo: "example.org".to_string(),
+            mail_from: "sender@example.org".to_string(),
+            message_id: None,
+            events: Vec::new(),
+        };
+        let traced = || Command::IngestMessageTraced {
+            mail_from: "sender@example.org".to_string(),
+            rcpt_to: Vec::new(),
+            raw_message: b"Subject: test\r\n\r\ntest".to_vec(),
+            trace: trace(),
+        };
+        let authenticated = |trace: Option<IngestTrace>| Command::IngestMessageAuthenticated {
+            mail_from: "sender@example.org".to_string(),
+            rcpt_to: Vec::new(),
+            raw_message: b"Subject: test\r\n\r\ntest".to_vec(),
+            trace,
+            auth: AuthOutcome {
+                spf: AuthResult::Pass,
+                spf_domain: "example.org".to_string(),
+                dkim: vec![],
+                arc: AuthResult::None,
+                dmarc: DmarcOutput {
+                    result: AuthResult::Pass,
+                    from_domain: "example.org".to_string(),
+                    policy: Policy::Reject,
+                    pct: 100,
+                },
+                disposition: Policy::None,
+            },
+        };
+
+        assert_eq!(traced().min_version(), INGEST_TRACE_VERSION);
+        assert!(matches!(
+            traced().downgrade(INGEST_TRACE_VERSION),
+            Command::IngestMessageTraced { .. }
+        ));
+        let command = traced().downgrade(INGEST_TRACE_VERSION - 1);
+        assert!(matches!(
+            &command,
+            Command::IngestMessage { mail_from, raw_message, .. }
+                if mail_from == "sender@example.org" && raw_message.starts_with(b"Subject")
+        ));
+        assert_eq!(command.min_version(), LEGACY_VERSION);
+
+        // Authentication results are dropped, keeping the trace when possible
+        assert_eq!(authenticated(None).min_version(), INGEST_AUTH_VERSION);
+        assert!(matches!(
+            authenticated(trace().into()).downgrade(INGEST_AUTH_VERSION),
+            Command::IngestMessageAuthenticated { .. }
+        ));
+        assert!(matches!(
+            authenticated(trace().into()).downgrade(INGEST_TRACE_VERSION),
+            Command::IngestMessageTraced { .. }
+        ));
+        assert!(matches!(
+            authenticated(trace().into()).downgrade(LEGACY_VERSION),
+            Command::IngestMessage { .. }
+        ));
+        assert!(matches!(
+            authenticated(None).downgrade(INGEST_TRACE_VERSION),
+            Command::IngestMessage { .. }
+        ));
+    }
+}
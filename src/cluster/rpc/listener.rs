@@ -25,7 +25,7 @@ use futures::{stream::StreamExt, SinkExt};
 use std::sync::Arc;
 use std::{net::SocketAddr, time::Duration};
 use store::blake3;
-use store::config::env_settings::EnvSettings;
+use store::config::{env_settings::EnvSettings, settings::Setting};
 use store::rand::{self, Rng};
 use store::tracing::{debug, error};
 use tokio::sync::watch;
@@ -43,7 +43,13 @@ use crate::server::failed_to;
 
 use super::serialize::RpcEncoder;
 use super::tls::load_tls_server_config;
-use super::{Protocol, Request, Response};
+use super::{PeerVersion, Protocol, Request, Response};
+use crate::cluster::log::APPLY_FORMAT;
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::path("rpc-cert-path").describe("Defaults to jmap-cert-path"),
+    Setting::path("rpc-key-path").describe("Defaults to jmap-key-path"),
+];
 
 pub async fn spawn_rpc(
     bind_addr: SocketAddr,
@@ -133,52 +139,76 @@ async fn handle_conn(
     hasher.update(&challenge);
     let challenge_response = hasher.finalize();
 
-    let peer_id = match time::timeout(Duration::from_millis(rpc_timeout), async {
-        match frames
-            .send(Protocol::Response(Response::Auth { challenge }))
-            .await
+    let (peer_id, response, peer_version, is_hello) =
+        match time::timeout(Duration::from_millis(rpc_timeout), async {
+            match frames
+                .send(Protocol::Response(Response::Auth { challenge }))
+                .await
+            {
+                Ok(_) => frames.next().await,
+                Err(_) => None,
+            }
+        })
+        .await
         {
-            Ok(_) => frames.next().await,
-            Err(_) => None,
-        }
-    })
-    .await
-    {
-        Ok(Some(result)) => match result {
-            Ok(Protocol::Request(Request::Auth { peer_id, response })) => {
-                if challenge_response.as_bytes() == &response[..] {
-                    debug!("Authenticated peer {}.", peer_id);
-                    peer_id
-                } else {
-                    error!("Failed to authenticate peer {}.", peer_id);
+            Ok(Some(result)) => match result {
+                Ok(Protocol::Request(Request::Auth { peer_id, response })) => (
+                    peer_id,
+                    response,
+                    PeerVersion::legacy(frames.codec().version()),
+                    false,
+                ),
+                Ok(Protocol::Request(Request::Hello {
+                    peer_id,
+                    response,
+                    version,
+                })) => (peer_id, response, version, true),
+                Ok(_) => {
+                    error!("Received unexpected RPC request from {}.", peer_addr);
                     return;
                 }
-            }
-            Ok(_) => {
-                error!("Received unexpected RPC request from {}.", peer_addr);
+                Err(err) => {
+                    debug!("RPC connection from {} failed: {}", peer_addr, err);
+                    return;
+                }
+            },
+            Ok(None) => {
+                debug!("RPC connection from {} closed before auth.", peer_addr);
                 return;
             }
-            Err(err) => {
-                debug!("RPC connection from {} failed: {}", peer_addr, err);
+            Err(_) => {
+                error!(
+                    "RPC connection from {} timed out during authentication.",
+                    peer_addr
+                );
                 return;
             }
-        },
-        Ok(None) => {
-            debug!("RPC connection from {} closed before auth.", peer_addr);
-            return;
-        }
-        Err(_) => {
-            error!(
-                "RPC connection from {} timed out during authentication.",
-                peer_addr
-            );
-            return;
-        }
-    };
+        };
 
-    if let Err(err) = frames.send(Protocol::Response(Response::Pong)).await {
+    if challenge_response.as_bytes() == &response[..] {
+        debug!(
+            "Authenticated peer {} (protocol version {}, apply format {}).",
+            peer_id, peer_version.protocol, peer_version.apply_format
+        );
+    } else {
+        error!("Failed to authenticate peer {}.", peer_id);
+        return;
+    }
+
+    // Peers that sent a Hello expect the local versions in return
+    let response = peer_version.handshake_response(is_hello);
+    if !peer_version.is_supported() {
+        error!(
+            "Refusing connection from peer {} using unsupported protocol version {}.",
+            peer_id, peer_version.protocol
+        );
+    }
+    let is_incompatible = matches!(response, Response::Incompatible { .. });
+    if let Err(err) = frames.send(Protocol::Response(response)).await {
         error!("Failed to send auth response: {}", err);
         return;
+    } else if is_incompatible {
+        return;
     }
 
     loop {
@@ -186,6 +216,29 @@ async fn handle_conn(
             frame = frames.next() => {
                 match frame {
                     Some(Ok(Protocol::Request(request))) => {
+                        // Log entries written in a newer format cannot be applied
+                        if matches!(request, Request::AppendEntries { .. })
+                            && peer_version.apply_format > APPLY_FORMAT
+                        {
+                            error!(
+                                concat!(
+                                    "Refusing to apply log entries from peer {} using ",
+                                    "apply format {}, upgrade this node first."
+                                ),
+                                peer_id, peer_version.apply_format
+                            );
+                            if let Err(err) = frames
+                                .send(Protocol::Response(Response::Incompatible {
+                                    version: PeerVersion::local(),
+                                }))
+                                .await
+                            {
+                                error!("Failed to send RPC response: {}", err);
+                                return;
+                            }
+                            continue;
+                        }
+
                         let (response_tx, response_rx) = oneshot::channel();
 
                         if let Err(err) = main_tx
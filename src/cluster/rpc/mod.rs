@@ -29,12 +29,14 @@ pub mod serialize;
 pub mod tls;
 
 use self::command::{Command, CommandResponse};
+use self::serialize::{HELLO_VERSION, LEGACY_VERSION, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 
-use super::log::{AppendEntriesRequest, AppendEntriesResponse};
+use super::log::{AppendEntriesRequest, AppendEntriesResponse, APPLY_FORMAT};
 use super::{gossip::PeerInfo, PeerId};
 use serde::{Deserialize, Serialize};
 use store::log::raft::{RaftId, TermId};
 use store::tracing::error;
+use store::AccountId;
 use tokio::sync::oneshot;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -63,6 +65,14 @@ pub enum Request {
     },
     Ping,
     None,
+    Hello {
+        peer_id: PeerId,
+        response: Vec<u8>,
+        version: PeerVersion,
+    },
+    RevokeCredentials {
+        account_ids: Vec<AccountId>,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -76,6 +86,15 @@ pub enum Response {
     Pong,
     UnregisteredPeer,
     None,
+    Hello { version: PeerVersion },
+    Incompatible { version: PeerVersion },
+}
+
+/// Versions supported by a node, exchanged during the RPC handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerVersion {
+    pub protocol: u8,
+    pub apply_format: u8,
 }
 
 pub enum RpcEvent {
@@ -94,6 +113,70 @@ pub enum Protocol {
     Response(Response),
 }
 
+impl PeerVersion {
+    pub fn local() -> Self {
+        PeerVersion {
+            protocol: PROTOCOL_VERSION,
+            apply_format: APPLY_FORMAT,
+        }
+    }
+
+    // Peers that authenticate without a Hello predate apply formats
+    pub fn legacy(protocol: u8) -> Self {
+        PeerVersion {
+            protocol,
+            apply_format: 1,
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.protocol >= MIN_PROTOCOL_VERSION
+    }
+
+    /// Whether the peer is able to handle a request, followers supporting
+    /// an older apply format cannot receive log entries from this node.
+    pub fn can_apply(&self, request: &Request) -> bool {
+        !matches!(request, Request::AppendEntries { .. }) || self.apply_format >= APPLY_FORMAT
+    }
+
+    /// Response to a peer that authenticated using this version.
+    pub fn handshake_response(&self, is_hello: bool) -> Response {
+        if !self.is_supported() {
+            Response::Incompatible {
+                version: PeerVersion::local(),
+            }
+        } else if is_hello {
+            Response::Hello {
+                version: PeerVersion::local(),
+            }
+        } else {
+            Response::Pong
+        }
+    }
+}
+
+impl Request {
+    /// Protocol version that introduced the request, newer requests are
+    /// never sent to peers speaking a previous version.
+    pub fn min_version(&self) -> u8 {
+        match self {
+            Request::Hello { .. } | Request::RevokeCredentials { .. } => HELLO_VERSION,
+            Request::Command { command } => command.min_version(),
+            _ => LEGACY_VERSION,
+        }
+    }
+
+    /// Rewrites the request for a peer speaking a previous protocol version.
+    pub fn downgrade(self, version: u8) -> Self {
+        match self {
+            Request::Command { command } => Request::Command {
+                command: command.downgrade(version),
+            },
+            request => request,
+        }
+    }
+}
+
 impl RpcEvent {
     pub fn failed(self) {
         if let RpcEvent::NeedResponse { response_tx, .. } = self {
@@ -39,11 +39,14 @@ use tokio_rustls::client::TlsStream;
 use tokio_rustls::TlsConnector;
 use tokio_util::codec::Framed;
 
+use crate::cluster::log::APPLY_FORMAT;
 use crate::cluster::rpc::{Request, Response};
 use crate::cluster::{Config, Event, PeerId, IPC_CHANNEL_BUFFER};
 
-use super::serialize::RpcEncoder;
-use super::{Protocol, RpcEvent};
+use super::serialize::{
+    RpcEncoder, HELLO_VERSION, LEGACY_VERSION, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use super::{PeerVersion, Protocol, RpcEvent};
 
 pub fn spawn_peer_rpc(
     main_tx: mpsc::Sender<Event>,
@@ -65,6 +68,7 @@ pub fn spawn_peer_rpc(
 
     tokio::spawn(async move {
         let mut conn_ = None;
+        let mut peer_version = PeerVersion::legacy(LEGACY_VERSION);
         let mut is_online = false;
 
         'main: loop {
@@ -107,8 +111,9 @@ pub fn spawn_peer_rpc(
                     )
                     .await
                     {
-                        Ok(conn) => {
+                        Ok((conn, version)) => {
                             conn_ = conn.into();
+                            peer_version = version;
 
                             // Notify processes that the peer is online.
                             if !is_online {
@@ -211,7 +216,7 @@ pub fn spawn_peer_rpc(
                 RpcEvent::NeedResponse {
                     response_tx,
                     request,
-                } => match send_rpc(conn, request).await {
+                } => match send_rpc(conn, peer_version, request).await {
                     Ok(response) => {
                         // Send response via oneshot channel
                         if response_tx.send(response).is_err() {
@@ -226,18 +231,20 @@ pub fn spawn_peer_rpc(
                         err
                     }
                 },
-                RpcEvent::FireAndForget { request } => match send_rpc(conn, request).await {
-                    Ok(response) => {
-                        // Send response via the main channel
-                        if let Err(err) =
-                            main_tx.send(Event::RpcResponse { peer_id, response }).await
-                        {
-                            error!("Channel failed while sending message: {}", err);
+                RpcEvent::FireAndForget { request } => {
+                    match send_rpc(conn, peer_version, request).await {
+                        Ok(response) => {
+                            // Send response via the main channel
+                            if let Err(err) =
+                                main_tx.send(Event::RpcResponse { peer_id, response }).await
+                            {
+                                error!("Channel failed while sending message: {}", err);
+                            }
+                            continue;
                         }
-                        continue;
+                        Err(err) => err,
                     }
-                    Err(err) => err,
-                },
+                }
             };
 
             debug!("Failed to send RPC request to peer {}: {}", peer_addr, err);
@@ -261,7 +268,7 @@ async fn connect_peer(
     auth_key: &str,
     peer_id: PeerId,
     rpc_timeout: u64,
-) -> std::io::Result<Framed<TlsStream<TcpStream>, RpcEncoder>> {
+) -> std::io::Result<(Framed<TlsStream<TcpStream>, RpcEncoder>, PeerVersion)> {
     time::timeout(Duration::from_millis(rpc_timeout), async {
         // Connect to peer
         let stream = TcpStream::connect(&addr).await?;
@@ -280,22 +287,47 @@ async fn connect_peer(
             let mut hasher = blake3::Hasher::new();
             hasher.update(auth_key.as_bytes());
             hasher.update(&challenge);
+            let response = hasher.finalize().as_bytes().to_vec();
 
-            if let Response::Pong = send_rpc(
-                &mut conn,
-                Request::Auth {
+            // The challenge advertises the peer's protocol version, peers
+            // speaking a previous version authenticate without a Hello.
+            let peer_version = conn.codec().version();
+            if peer_version < MIN_PROTOCOL_VERSION {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Peer uses unsupported protocol version {}.", peer_version),
+                ));
+            }
+            let request = if peer_version >= HELLO_VERSION {
+                Request::Hello {
                     peer_id,
-                    response: hasher.finalize().as_bytes().to_vec(),
-                },
-            )
-            .await?
-            {
-                Ok(conn)
+                    response,
+                    version: PeerVersion::local(),
+                }
             } else {
-                Err(std::io::Error::new(
+                Request::Auth { peer_id, response }
+            };
+
+            match send_rpc(&mut conn, PeerVersion::local(), request).await? {
+                Response::Pong => Ok((conn, PeerVersion::legacy(peer_version))),
+                Response::Hello { version } => {
+                    debug!(
+                        "Connected to peer {} (protocol version {}, apply format {}).",
+                        addr, version.protocol, version.apply_format
+                    );
+                    Ok((conn, version))
+                }
+                Response::Incompatible { version } => Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "Peer refused protocol version {} (peer version {}).",
+                        PROTOCOL_VERSION, version.protocol
+                    ),
+                )),
+                _ => Err(std::io::Error::new(
                     std::io::ErrorKind::Other,
                     "Failed to authenticate peer.",
-                ))
+                )),
             }
         } else {
             Err(std::io::Error::new(
@@ -318,8 +350,29 @@ async fn connect_peer(
 
 async fn send_rpc(
     conn: &mut Framed<TlsStream<TcpStream>, RpcEncoder>,
+    peer_version: PeerVersion,
     request: Request,
 ) -> std::io::Result<Response> {
+    // Peers speaking a previous version do not understand newer requests
+    let request = request.downgrade(conn.codec().version());
+    if request.min_version() > conn.codec().version() {
+        debug!(
+            "Not sending a version {} request to a peer using protocol version {}.",
+            request.min_version(),
+            conn.codec().version()
+        );
+        return Ok(Response::None);
+    }
+    // Log entries are held back until the follower is able to apply them
+    if !peer_version.can_apply(&request) {
+        debug!(
+            "Not sending log entries in format {} to a peer supporting up to format {}.",
+            APPLY_FORMAT, peer_version.apply_format
+        );
+        return Ok(Response::Incompatible {
+            version: peer_version,
+        });
+    }
     conn.send(Protocol::Request(request)).await?;
     read_rpc(conn).await
 }
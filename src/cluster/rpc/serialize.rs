@@ -21,18 +21,162 @@
  * for more details.
 */
 
-use super::Protocol;
+use super::{Protocol, Request, Response};
 use actix_web::web::{self, Buf};
 use store::{
-    bincode,
+    bincode, lz4_flex,
     serialize::leb128::{Leb128Reader, Leb128Vec},
+    tracing::debug,
 };
 use tokio_util::codec::{Decoder, Encoder};
 
-#[derive(Default)]
-pub struct RpcEncoder {}
+// Frames are encoded as a LEB128 length followed by either a legacy bincode
+// payload or a versioned frame: MAGIC, version, flags and the payload.
+// Legacy payloads always start with the bincode Protocol tag (0 or 1), so
+// both formats can be told apart from the first byte.
+//
+// Until the peer is known to understand versioned frames, legacy frames are
+// sent with a trailer advertising the supported version and capabilities,
+// which older nodes ignore as bincode allows trailing bytes.
+//
+// Version history:
+//  1 - Versioned frames with optional LZ4 compression.
+//  2 - Explicit version negotiation during the handshake (Request::Hello).
+//  3 - Traced message ingestion (Command::IngestMessageTraced).
+//  4 - Authenticated message ingestion (Command::IngestMessageAuthenticated).
+//
+// Nodes keep speaking the previous version with older peers, so a cluster can
+// be upgraded one node at a time. Peers below MIN_PROTOCOL_VERSION are refused.
+// Variants unknown to this node are decoded as Request::None or Response::None
+// rather than dropping the connection.
+pub const PROTOCOL_VERSION: u8 = 4;
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+pub const HELLO_VERSION: u8 = 2;
+pub const INGEST_TRACE_VERSION: u8 = 3;
+pub const INGEST_AUTH_VERSION: u8 = 4;
+pub const LEGACY_VERSION: u8 = 0;
+
+const FRAME_MAGIC: u8 = 0xfe;
+const FLAG_COMPRESSED: u8 = 0x01;
+const CAPABILITY_LZ4: u8 = 0x01;
 
 const MAX_FRAME_LENGTH: usize = 150 * 1024 * 1024;
+const COMPRESS_THRESHOLD: usize = 16 * 1024;
+
+pub struct RpcEncoder {
+    version: u8,
+    compress: bool,
+}
+
+impl Default for RpcEncoder {
+    fn default() -> Self {
+        RpcEncoder {
+            version: LEGACY_VERSION,
+            compress: false,
+        }
+    }
+}
+
+impl RpcEncoder {
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    fn negotiate(&mut self, peer_version: u8, capabilities: u8) {
+        let version = std::cmp::min(peer_version, PROTOCOL_VERSION);
+        if version != self.version {
+            debug!("Using RPC protocol version {}.", version);
+            self.version = version;
+        }
+        self.compress = (capabilities & CAPABILITY_LZ4) != 0;
+    }
+
+    fn decode_legacy(&mut self, frame: &[u8]) -> std::io::Result<Protocol> {
+        let result = bincode::deserialize::<Protocol>(frame).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Failed to deserialize RPC request.: {}", e),
+            )
+        })?;
+
+        // Look for a capabilities trailer
+        let trailer = bincode::serialized_size(&result)
+            .ok()
+            .and_then(|size| frame.get(size as usize..))
+            .unwrap_or_default();
+        if trailer.len() >= 3 && trailer[0] == FRAME_MAGIC && trailer[1] > LEGACY_VERSION {
+            self.negotiate(trailer[1], trailer[2]);
+        }
+
+        Ok(result)
+    }
+
+    fn decode_versioned(&mut self, frame: &[u8]) -> std::io::Result<Protocol> {
+        let (version, flags, payload) = match frame {
+            [_, version, flags, payload @ ..] => (*version, *flags, payload),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Truncated RPC frame header.",
+                ))
+            }
+        };
+
+        // Receiving a versioned frame means the peer accepted our advertisement,
+        // which includes all capabilities supported by this version.
+        if self.version == LEGACY_VERSION {
+            self.negotiate(version, CAPABILITY_LZ4);
+        }
+
+        let decompressed;
+        let payload = if (flags & FLAG_COMPRESSED) != 0 {
+            decompressed = lz4_flex::decompress_size_prepended(payload).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Failed to decompress RPC frame: {}", e),
+                )
+            })?;
+            &decompressed[..]
+        } else {
+            payload
+        };
+
+        match bincode::deserialize::<Protocol>(payload) {
+            Ok(result) => Ok(result),
+            Err(err) if is_unknown_variant(&err) => {
+                // Skip variants introduced by newer versions
+                debug!(
+                    "Skipping unknown RPC message (version {}): {}",
+                    version, err
+                );
+                match payload
+                    .get(0..4)
+                    .map(|tag| u32::from_le_bytes(tag.try_into().unwrap()))
+                {
+                    Some(0) => Ok(Protocol::Request(Request::None)),
+                    Some(1) => Ok(Protocol::Response(Response::None)),
+                    _ => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Failed to deserialize RPC request.: {}", err),
+                    )),
+                }
+            }
+            Err(err) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Failed to deserialize RPC request (version {}).: {}",
+                    version, err
+                ),
+            )),
+        }
+    }
+}
+
+// Serde reports variant indexes out of range as invalid values, any other
+// error means that the frame is corrupted.
+fn is_unknown_variant(err: &bincode::Error) -> bool {
+    matches!(err.as_ref(), bincode::ErrorKind::Custom(message) if message.contains("expected variant index"))
+}
 
 impl Decoder for RpcEncoder {
     type Item = Protocol;
@@ -61,13 +205,12 @@ impl Decoder for RpcEncoder {
             return Ok(None);
         }
 
-        let result = bincode::deserialize::<Protocol>(&src[bytes_read..bytes_read + frame_len])
-            .map_err(|e| {
-                std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Failed to deserialize RPC request.: {}", e),
-                )
-            });
+        let frame = &src[bytes_read..bytes_read + frame_len];
+        let result = if frame.first() == Some(&FRAME_MAGIC) {
+            self.decode_versioned(frame)
+        } else {
+            self.decode_legacy(frame)
+        };
         src.advance(bytes_read + frame_len);
 
         Ok(Some(result?))
@@ -78,18 +221,225 @@ impl Encoder<Protocol> for RpcEncoder {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: Protocol, dst: &mut web::BytesMut) -> Result<(), Self::Error> {
-        let bytes = bincode::serialize(&item).map_err(|e| {
+        let mut bytes = bincode::serialize(&item).map_err(|e| {
             std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("Failed to serialize RPC request.: {}", e),
             )
         })?;
+        let header = if self.version == LEGACY_VERSION {
+            // Advertise the supported version and capabilities
+            bytes.extend_from_slice(&[FRAME_MAGIC, PROTOCOL_VERSION, CAPABILITY_LZ4]);
+            None
+        } else if self.compress && bytes.len() > COMPRESS_THRESHOLD {
+            bytes = lz4_flex::compress_prepend_size(&bytes);
+            Some([FRAME_MAGIC, self.version, FLAG_COMPRESSED])
+        } else {
+            Some([FRAME_MAGIC, self.version, 0])
+        };
+
+        let frame_len = bytes.len() + header.as_ref().map_or(0, |h| h.len());
         let mut bytes_len = Vec::with_capacity(std::mem::size_of::<u32>() + 1);
-        bytes_len.push_leb128(bytes.len());
+        bytes_len.push_leb128(frame_len);
 
-        dst.reserve(bytes_len.len() + bytes.len());
+        dst.reserve(bytes_len.len() + frame_len);
         dst.extend_from_slice(&bytes_len);
+        if let Some(header) = header {
+            dst.extend_from_slice(&header);
+        }
         dst.extend_from_slice(&bytes);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use store::bincode;
+
+    use store::log::raft::RaftId;
+
+    use super::{RpcEncoder, LEGACY_VERSION, PROTOCOL_VERSION};
+    use crate::cluster::log::{AppendEntriesRequest, APPLY_FORMAT};
+    use crate::cluster::rpc::{PeerVersion, Protocol, Request, Response};
+
+    #[test]
+    fn negotiate_versioned_frames() {
+        let mut local = RpcEncoder::default();
+        let mut remote = RpcEncoder::default();
+        let mut buf = BytesMut::new();
+
+        // Legacy frames carry the capabilities trailer
+        local
+            .encode(Protocol::Response(Response::Pong), &mut buf)
+            .unwrap();
+        assert!(matches!(
+            remote.decode(&mut buf).unwrap(),
+            Some(Protocol::Response(Response::Pong))
+        ));
+        assert_eq!(remote.version(), PROTOCOL_VERSION);
+        assert_eq!(local.version(), LEGACY_VERSION);
+
+        // The first versioned frame completes the negotiation
+        remote
+            .encode(Protocol::Request(Request::Ping), &mut buf)
+            .unwrap();
+        assert!(matches!(
+            local.decode(&mut buf).unwrap(),
+            Some(Protocol::Request(Request::Ping))
+        ));
+        assert_eq!(local.version(), PROTOCOL_VERSION);
+
+        // Large frames are compressed
+        local
+            .encode(
+                Protocol::Request(Request::Auth {
+                    peer_id: 1,
+                    response: vec![0; 64 * 1024],
+                }),
+                &mut buf,
+            )
+            .unwrap();
+        assert!(buf.len() < 16 * 1024);
+        assert!(matches!(
+            remote.decode(&mut buf).unwrap(),
+            Some(Protocol::Request(Request::Auth { response, .. })) if response.len() == 64 * 1024
+        ));
+
+        // Unknown variants are skipped, including commands nested in a request
+        for tags in [&[0, u32::MAX][..], &[0, 5, u32::MAX], &[1, u32::MAX]] {
+            let mut frame = vec![0xfe, PROTOCOL_VERSION, 0];
+            for tag in tags {
+                frame.extend_from_slice(&tag.to_le_bytes());
+            }
+            buf.extend_from_slice(&[frame.len() as u8]);
+            buf.extend_from_slice(&frame);
+            let result = remote.decode(&mut buf).unwrap().unwrap();
+            if tags[0] == 0 {
+                assert!(matches!(result, Protocol::Request(Request::None)));
+            } else {
+                assert!(matches!(result, Protocol::Response(Response::None)));
+            }
+        }
+
+        // Corrupted frames are rejected
+        let mut frame = vec![0xfe, PROTOCOL_VERSION, 0];
+        frame.extend_from_slice(&0u32.to_le_bytes());
+        frame.extend_from_slice(&1u32.to_le_bytes());
+        frame.extend_from_slice(&[1, 2]);
+        buf.extend_from_slice(&[frame.len() as u8]);
+        buf.extend_from_slice(&frame);
+        assert_eq!(
+            remote.decode(&mut buf).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn handshake() {
+        let mut leader = RpcEncoder::default();
+        let mut follower = RpcEncoder::default();
+        let mut buf = BytesMut::new();
+
+        // The auth challenge advertises the follower's protocol version
+        follower
+            .encode(
+                Protocol::Response(Response::Auth { challenge: [0; 12] }),
+                &mut buf,
+            )
+            .unwrap();
+        assert!(matches!(
+            leader.decode(&mut buf).unwrap(),
+            Some(Protocol::Response(Response::Auth { .. }))
+        ));
+        assert_eq!(leader.version(), PROTOCOL_VERSION);
+
+        // Followers answer a Hello with their own versions
+        let hello = hello_request();
+        assert!(hello.min_version() <= leader.version());
+        leader.encode(Protocol::Request(hello), &mut buf).unwrap();
+        let (version, is_hello) = match follower.decode(&mut buf).unwrap() {
+            Some(Protocol::Request(Request::Hello { version, .. })) => (version, true),
+            other => panic!("Unexpected frame {:?}", other),
+        };
+        follower
+            .encode(
+                Protocol::Response(version.handshake_response(is_hello)),
+                &mut buf,
+            )
+            .unwrap();
+        let follower_version = match leader.decode(&mut buf).unwrap() {
+            Some(Protocol::Response(Response::Hello { version })) => version,
+            other => panic!("Unexpected frame {:?}", other),
+        };
+        assert_eq!(follower_version, PeerVersion::local());
+        assert!(follower_version.can_apply(&append_entries()));
+
+        // Followers speaking the previous version authenticate without a Hello
+        let mut frame =
+            bincode::serialize(&Protocol::Response(Response::Auth { challenge: [0; 12] })).unwrap();
+        frame.extend_from_slice(&[0xfe, 1, 1]);
+        let mut leader = RpcEncoder::default();
+        buf.extend_from_slice(&[frame.len() as u8]);
+        buf.extend_from_slice(&frame);
+        assert!(matches!(
+            leader.decode(&mut buf).unwrap(),
+            Some(Protocol::Response(Response::Auth { .. }))
+        ));
+        assert_eq!(leader.version(), 1);
+        assert!(hello_request().min_version() > leader.version());
+        assert!(Request::Ping.min_version() <= leader.version());
+        assert!(
+            Request::RevokeCredentials {
+                account_ids: vec![1]
+            }
+            .min_version()
+                > leader.version()
+        );
+
+        // Their Pong carries no apply format, log entries are held back
+        let legacy = PeerVersion::legacy(leader.version());
+        assert!(matches!(legacy.handshake_response(false), Response::Pong));
+        assert!(legacy.apply_format < APPLY_FORMAT);
+        assert!(!legacy.can_apply(&append_entries()));
+        assert!(legacy.can_apply(&Request::Ping));
+
+        // Followers supporting an older format are held back as well
+        let older = PeerVersion {
+            protocol: PROTOCOL_VERSION,
+            apply_format: APPLY_FORMAT - 1,
+        };
+        assert!(!older.can_apply(&append_entries()));
+        assert!(older.can_apply(&hello_request()));
+
+        // Versions below the minimum are refused
+        assert!(matches!(
+            PeerVersion::legacy(LEGACY_VERSION).handshake_response(false),
+            Response::Incompatible { .. }
+        ));
+        assert!(matches!(
+            PeerVersion::legacy(LEGACY_VERSION).handshake_response(true),
+            Response::Incompatible { .. }
+        ));
+    }
+
+    fn hello_request() -> Request {
+        Request::Hello {
+            peer_id: 1,
+            response: vec![],
+            version: PeerVersion::local(),
+        }
+    }
+
+    fn append_entries() -> Request {
+        Request::AppendEntries {
+            term: 1,
+            request: AppendEntriesRequest::Match {
+                last_log: RaftId::new(1, 1),
+            },
+        }
+    }
+}
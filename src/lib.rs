@@ -38,25 +38,36 @@ pub mod services;
 #[cfg(test)]
 pub mod tests;
 
-pub const DEFAULT_HTTP_PORT: u16 = 8080;
-pub const DEFAULT_RPC_PORT: u16 = 7911;
-
 pub struct JMAPServer<T> {
     pub store: Arc<JMAPStore<T>>,
-    pub worker_pool: rayon::ThreadPool,
+    pub worker_pools: server::workers::WorkerPools,
     pub base_session: api::session::Session,
+    pub session_history: api::session::SessionHistory,
     pub cluster: Option<ClusterIpc>,
 
     pub state_change: mpsc::Sender<services::state_change::Event>,
+    pub query_subscriptions: services::state_change::QuerySubscriptions,
+    pub mailbox_counters: services::state_change::MailboxCounters,
     pub email_delivery: mpsc::Sender<services::email_delivery::Event>,
+    pub delivery_queue: services::delivery_queue::DeliveryQueue,
     pub housekeeper: mpsc::Sender<services::housekeeper::Event>,
     pub lmtp: watch::Sender<bool>,
+    pub migrations: services::migration::MigrationManager,
+    pub warmup: services::warmup::WarmupManager,
+    pub maintenance: services::maintenance::MaintenanceScheduler,
+    pub onboarding: services::onboarding::Onboarding,
+    pub push_broker: Option<mpsc::Sender<services::state_change::StateChange>>,
+    pub dns: services::dns::DnsResolver,
 
     pub oauth: Box<authorization::oauth::OAuth>,
-    pub oauth_codes: Cache<String, Arc<authorization::oauth::OAuthCode>>,
 
-    pub sessions: Cache<String, authorization::Session>,
+    pub sessions: authorization::cache::AuthCache,
     pub rate_limiters: Cache<RemoteAddress, Arc<Limiter>>,
+    pub trusted_proxies: authorization::proxy::TrustedProxies,
+    pub traces: api::trace::TraceBuffer,
+    pub autoconfig: api::autoconfig::AutoConfig,
+    pub config: api::config::EffectiveConfig,
+    pub plugins: api::plugin::PluginRegistry<T>,
 
     #[cfg(test)]
     pub is_offline: std::sync::atomic::AtomicBool,
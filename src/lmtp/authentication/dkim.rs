@@ -0,0 +1,706 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::SystemTime;
+
+use ed25519_dalek::Verifier;
+use rsa::{pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, Pkcs1v15Sign, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::services::dns::{DnsError, DnsResolver};
+
+use super::{AuthResult, RawHeader};
+
+// Signatures beyond this limit are ignored
+const MAX_SIGNATURES: usize = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DkimOutput {
+    pub result: AuthResult,
+    pub domain: String,
+    pub selector: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Algorithm {
+    RsaSha1,
+    RsaSha256,
+    Ed25519Sha256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+#[derive(Debug)]
+pub(super) struct Signature {
+    algorithm: Algorithm,
+    pub(super) domain: String,
+    pub(super) selector: String,
+    signed_headers: Vec<String>,
+    body_hash: Vec<u8>,
+    signature: Vec<u8>,
+    header_canonicalization: Canonicalization,
+    body_canonicalization: Canonicalization,
+    body_length: Option<usize>,
+    expires: Option<u64>,
+}
+
+pub async fn verify(dns: &DnsResolver, headers: &[RawHeader<'_>], body: &[u8]) -> Vec<DkimOutput> {
+    let mut results = Vec::new();
+
+    for header in headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("DKIM-Signature"))
+        .take(MAX_SIGNATURES)
+    {
+        results.push(match Signature::parse(header.value) {
+            Some(signature) => DkimOutput {
+                result: signature.verify(dns, headers, header, body).await,
+                domain: signature.domain,
+                selector: signature.selector,
+            },
+            None => DkimOutput {
+                result: AuthResult::PermError,
+                domain: String::new(),
+                selector: String::new(),
+            },
+        });
+    }
+
+    results
+}
+
+impl Signature {
+    fn parse(value: &[u8]) -> Option<Self> {
+        Self::parse_tags(value, false)
+    }
+
+    // Parses an ARC-Message-Signature, which has no version tag and uses
+    // "i=" for the instance number rather than the agent identifier.
+    pub(super) fn parse_arc(value: &[u8]) -> Option<Self> {
+        Self::parse_tags(value, true)
+    }
+
+    fn parse_tags(value: &[u8], is_arc: bool) -> Option<Self> {
+        let mut signature = Signature {
+            algorithm: Algorithm::RsaSha256,
+            domain: String::new(),
+            selector: String::new(),
+            signed_headers: Vec::new(),
+            body_hash: Vec::new(),
+            signature: Vec::new(),
+            header_canonicalization: Canonicalization::Simple,
+            body_canonicalization: Canonicalization::Simple,
+            body_length: None,
+            expires: None,
+        };
+        let mut has_version = is_arc;
+        let mut has_algorithm = false;
+        let mut identity = None;
+
+        for (name, value) in parse_tags(&String::from_utf8_lossy(value)) {
+            match name.as_str() {
+                "v" if !is_arc => {
+                    if value != "1" {
+                        return None;
+                    }
+                    has_version = true;
+                }
+                "a" => {
+                    signature.algorithm = Algorithm::parse(&value)?;
+                    has_algorithm = true;
+                }
+                "d" => signature.domain = value.trim_end_matches('.').to_lowercase(),
+                "s" => signature.selector = value,
+                "h" => {
+                    signature.signed_headers = value
+                        .split(':')
+                        .map(|name| name.trim().to_string())
+                        .filter(|name| !name.is_empty())
+                        .collect()
+                }
+                "bh" => signature.body_hash = base64_decode(&value)?,
+                "b" => signature.signature = base64_decode(&value)?,
+                "c" => {
+                    let (header, body) = value.split_once('/').unwrap_or((&value, "simple"));
+                    signature.header_canonicalization = Canonicalization::parse(header)?;
+                    signature.body_canonicalization = Canonicalization::parse(body)?;
+                }
+                "l" => signature.body_length = Some(value.parse().ok()?),
+                "x" => signature.expires = Some(value.parse().ok()?),
+                "i" if !is_arc => identity = Some(value),
+                _ => (),
+            }
+        }
+
+        // The agent identifier must be within the signing domain (RFC 6376, section 3.5)
+        let is_valid_identity = identity.map_or(true, |identity| {
+            identity.rsplit_once('@').map_or(false, |(_, domain)| {
+                is_subdomain(&domain.to_lowercase(), &signature.domain)
+            })
+        });
+
+        if has_version
+            && has_algorithm
+            && is_valid_identity
+            && !signature.domain.is_empty()
+            && !signature.selector.is_empty()
+            && !signature.body_hash.is_empty()
+            && !signature.signature.is_empty()
+            && signature
+                .signed_headers
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case("From"))
+        {
+            Some(signature)
+        } else {
+            None
+        }
+    }
+
+    pub(super) async fn verify(
+        &self,
+        dns: &DnsResolver,
+        headers: &[RawHeader<'_>],
+        signature_header: &RawHeader<'_>,
+        body: &[u8],
+    ) -> AuthResult {
+        if let Some(expires) = self.expires {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            if expires < now {
+                return AuthResult::Fail;
+            }
+        }
+
+        // Verify body hash
+        let mut body = canonical_body(body, self.body_canonicalization);
+        if let Some(body_length) = self.body_length {
+            if body_length > body.len() {
+                return AuthResult::PermError;
+            }
+            body.truncate(body_length);
+        }
+        if self.algorithm.hash(&body) != self.body_hash {
+            return AuthResult::Fail;
+        }
+
+        // Obtain public key and verify signature
+        let public_key = match public_key(dns, &self.selector, &self.domain, self.algorithm).await {
+            Ok(public_key) => public_key,
+            Err(result) => return result,
+        };
+        verify_signature(
+            self.algorithm,
+            &public_key,
+            &self.canonical_headers(headers, signature_header),
+            &self.signature,
+        )
+    }
+
+    // Builds the canonicalized header data covered by the signature, ending with the
+    // signature header itself with an empty "b=" tag (RFC 6376, section 3.7).
+    fn canonical_headers(
+        &self,
+        headers: &[RawHeader<'_>],
+        signature_header: &RawHeader<'_>,
+    ) -> Vec<u8> {
+        let mut data = Vec::with_capacity(1024);
+        let mut used = vec![false; headers.len()];
+
+        for name in &self.signed_headers {
+            // Multiple instances of a header are selected from the bottom up
+            if let Some(pos) = (0..headers.len()).rev().find(|&pos| {
+                !used[pos]
+                    && headers[pos].name.eq_ignore_ascii_case(name)
+                    && !std::ptr::eq(headers[pos].raw, signature_header.raw)
+            }) {
+                used[pos] = true;
+                canonical_header(
+                    &mut data,
+                    headers[pos].name,
+                    headers[pos].value,
+                    headers[pos].raw,
+                    self.header_canonicalization,
+                );
+            }
+        }
+
+        let raw = strip_signature(signature_header.raw.trim_end_crlf());
+        let value = strip_signature(signature_header.value.trim_end_crlf());
+        canonical_header(
+            &mut data,
+            signature_header.name,
+            &value,
+            &raw,
+            self.header_canonicalization,
+        );
+        if data.ends_with(b"\r\n") {
+            data.truncate(data.len() - 2);
+        }
+
+        data
+    }
+}
+
+// Obtains the public key published for a selector and domain (RFC 6376, section 3.6.2).
+pub(super) async fn public_key(
+    dns: &DnsResolver,
+    selector: &str,
+    domain: &str,
+    algorithm: Algorithm,
+) -> Result<Vec<u8>, AuthResult> {
+    let key = match dns
+        .txt_lookup(&format!("{}._domainkey.{}", selector, domain))
+        .await
+    {
+        Ok(records) => match records.first() {
+            Some(record) => parse_tags(record),
+            None => return Err(AuthResult::PermError),
+        },
+        Err(DnsError::NotFound) => return Err(AuthResult::PermError),
+        Err(DnsError::Failure(_)) => return Err(AuthResult::TempError),
+    };
+    let mut public_key = None;
+    for (name, value) in key {
+        match name.as_str() {
+            "v" if value != "DKIM1" => return Err(AuthResult::PermError),
+            "k" if !value.eq_ignore_ascii_case(algorithm.key_type()) => {
+                return Err(AuthResult::PermError)
+            }
+            "h" if !value
+                .split(':')
+                .any(|hash| hash.trim().eq_ignore_ascii_case(algorithm.hash_name())) =>
+            {
+                return Err(AuthResult::PermError)
+            }
+            "p" => public_key = base64_decode(&value),
+            _ => (),
+        }
+    }
+    match public_key {
+        Some(public_key) if !public_key.is_empty() => Ok(public_key),
+        _ => Err(AuthResult::PermError),
+    }
+}
+
+pub(super) fn verify_signature(
+    algorithm: Algorithm,
+    public_key: &[u8],
+    data: &[u8],
+    signature: &[u8],
+) -> AuthResult {
+    let hash = algorithm.hash(data);
+    match algorithm {
+        Algorithm::RsaSha256 | Algorithm::RsaSha1 => {
+            let public_key = match RsaPublicKey::from_public_key_der(public_key)
+                .or_else(|_| RsaPublicKey::from_pkcs1_der(public_key))
+            {
+                Ok(public_key) => public_key,
+                Err(_) => return AuthResult::PermError,
+            };
+            let scheme = if algorithm == Algorithm::RsaSha256 {
+                Pkcs1v15Sign::new::<Sha256>()
+            } else {
+                Pkcs1v15Sign::new::<Sha1>()
+            };
+            if public_key.verify(scheme, &hash, signature).is_ok() {
+                AuthResult::Pass
+            } else {
+                AuthResult::Fail
+            }
+        }
+        Algorithm::Ed25519Sha256 => {
+            let public_key = match <[u8; 32]>::try_from(public_key)
+                .ok()
+                .and_then(|key| ed25519_dalek::VerifyingKey::from_bytes(&key).ok())
+            {
+                Some(public_key) => public_key,
+                None => return AuthResult::PermError,
+            };
+            match ed25519_dalek::Signature::from_slice(signature) {
+                Ok(signature) if public_key.verify(&hash, &signature).is_ok() => AuthResult::Pass,
+                _ => AuthResult::Fail,
+            }
+        }
+    }
+}
+
+pub(super) fn canonical_header(
+    data: &mut Vec<u8>,
+    name: &str,
+    value: &[u8],
+    raw: &[u8],
+    canonicalization: Canonicalization,
+) {
+    match canonicalization {
+        Canonicalization::Simple => {
+            data.extend_from_slice(raw);
+            if !raw.ends_with(b"\n") {
+                data.extend_from_slice(b"\r\n");
+            }
+        }
+        Canonicalization::Relaxed => {
+            data.extend_from_slice(name.to_ascii_lowercase().as_bytes());
+            data.push(b':');
+            let mut last_ws = false;
+            let mut is_start = true;
+            for &ch in value {
+                match ch {
+                    b'\r' | b'\n' => (),
+                    b' ' | b'\t' => {
+                        last_ws = !is_start;
+                    }
+                    _ => {
+                        if last_ws {
+                            data.push(b' ');
+                            last_ws = false;
+                        }
+                        data.push(ch);
+                        is_start = false;
+                    }
+                }
+            }
+            data.extend_from_slice(b"\r\n");
+        }
+    }
+}
+
+// Canonicalizes the message body (RFC 6376, section 3.4.3 and 3.4.4).
+pub(super) fn canonical_body(body: &[u8], canonicalization: Canonicalization) -> Vec<u8> {
+    let mut data = Vec::with_capacity(body.len() + 2);
+    let mut empty_lines = 0;
+    let mut lines = body.split(|&ch| ch == b'\n').peekable();
+
+    while let Some(line) = lines.next() {
+        // Skip the empty remainder after a final line break
+        if line.is_empty() && lines.peek().is_none() {
+            break;
+        }
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        let mut canonical_line = Vec::with_capacity(line.len());
+        match canonicalization {
+            Canonicalization::Simple => canonical_line.extend_from_slice(line),
+            Canonicalization::Relaxed => {
+                let mut last_ws = false;
+                for &ch in line {
+                    if ch == b' ' || ch == b'\t' {
+                        last_ws = true;
+                    } else {
+                        if last_ws {
+                            canonical_line.push(b' ');
+                            last_ws = false;
+                        }
+                        canonical_line.push(ch);
+                    }
+                }
+            }
+        }
+
+        // Trailing empty lines are ignored
+        if canonical_line.is_empty() {
+            empty_lines += 1;
+        } else {
+            for _ in 0..empty_lines {
+                data.extend_from_slice(b"\r\n");
+            }
+            empty_lines = 0;
+            data.extend_from_slice(&canonical_line);
+            data.extend_from_slice(b"\r\n");
+        }
+    }
+
+    if data.is_empty() && canonicalization == Canonicalization::Simple {
+        data.extend_from_slice(b"\r\n");
+    }
+
+    data
+}
+
+// Removes the value of the "b=" tag from a DKIM-Signature or ARC header.
+pub(super) fn strip_signature(header: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(header.len());
+    let mut is_tag_start = true;
+    let mut in_signature = false;
+    let mut has_tags = false;
+    let mut pos = 0;
+
+    while pos < header.len() {
+        let ch = header[pos];
+        if in_signature {
+            if ch == b';' {
+                in_signature = false;
+                is_tag_start = true;
+                result.push(ch);
+            }
+        } else if ch == b';' || (ch == b':' && !has_tags) {
+            is_tag_start = true;
+            result.push(ch);
+        } else if is_tag_start && ch.is_ascii_whitespace() {
+            result.push(ch);
+        } else if is_tag_start && ch == b'b' {
+            // Match "b" followed by optional whitespace and "=", but not "bh="
+            let mut next = pos + 1;
+            while header
+                .get(next)
+                .map_or(false, |ch| ch.is_ascii_whitespace())
+            {
+                next += 1;
+            }
+            result.push(ch);
+            if header.get(next) == Some(&b'=') {
+                result.extend_from_slice(&header[pos + 1..=next]);
+                pos = next;
+                in_signature = true;
+                has_tags = true;
+            }
+            is_tag_start = false;
+        } else {
+            has_tags |= ch == b'=';
+            is_tag_start = false;
+            result.push(ch);
+        }
+        pos += 1;
+    }
+
+    result
+}
+
+pub(super) fn parse_tags(value: &str) -> Vec<(String, String)> {
+    value
+        .split(';')
+        .filter_map(|tag| {
+            let (name, value) = tag.split_once('=')?;
+            Some((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+pub(super) fn base64_decode(value: &str) -> Option<Vec<u8>> {
+    base64::decode(
+        value
+            .chars()
+            .filter(|ch| !ch.is_ascii_whitespace())
+            .collect::<String>(),
+    )
+    .ok()
+}
+
+fn is_subdomain(domain: &str, parent: &str) -> bool {
+    domain == parent
+        || domain
+            .strip_suffix(parent)
+            .map_or(false, |prefix| prefix.ends_with('.'))
+}
+
+pub(super) trait TrimEndCrlf {
+    fn trim_end_crlf(&self) -> &[u8];
+}
+
+impl TrimEndCrlf for [u8] {
+    fn trim_end_crlf(&self) -> &[u8] {
+        let mut end = self.len();
+        while end > 0 && matches!(self[end - 1], b'\r' | b'\n') {
+            end -= 1;
+        }
+        &self[..end]
+    }
+}
+
+impl Canonicalization {
+    pub(super) fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("simple") {
+            Some(Canonicalization::Simple)
+        } else if value.eq_ignore_ascii_case("relaxed") {
+            Some(Canonicalization::Relaxed)
+        } else {
+            None
+        }
+    }
+}
+
+impl Algorithm {
+    pub(super) fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "rsa-sha256" => Some(Algorithm::RsaSha256),
+            "rsa-sha1" => Some(Algorithm::RsaSha1),
+            "ed25519-sha256" => Some(Algorithm::Ed25519Sha256),
+            _ => None,
+        }
+    }
+
+    pub(super) fn hash(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::RsaSha1 => Sha1::digest(data).to_vec(),
+            Algorithm::RsaSha256 | Algorithm::Ed25519Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    fn hash_name(&self) -> &'static str {
+        match self {
+            Algorithm::RsaSha1 => "sha1",
+            Algorithm::RsaSha256 | Algorithm::Ed25519Sha256 => "sha256",
+        }
+    }
+
+    fn key_type(&self) -> &'static str {
+        match self {
+            Algorithm::RsaSha1 | Algorithm::RsaSha256 => "rsa",
+            Algorithm::Ed25519Sha256 => "ed25519",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        base64_decode, canonical_body, strip_signature, verify_signature, Canonicalization,
+        Signature,
+    };
+    use crate::lmtp::authentication::{parse_headers, AuthResult};
+
+    #[test]
+    fn dkim_canonicalization() {
+        let body = b" C \r\nD \t E\r\n\r\n\r\n";
+        assert_eq!(
+            canonical_body(body, Canonicalization::Simple),
+            b" C \r\nD \t E\r\n"
+        );
+        assert_eq!(
+            canonical_body(body, Canonicalization::Relaxed),
+            b" C\r\nD E\r\n"
+        );
+        assert_eq!(canonical_body(b"", Canonicalization::Simple), b"\r\n");
+        assert_eq!(canonical_body(b"\r\n\r\n", Canonicalization::Relaxed), b"");
+        assert_eq!(canonical_body(b"abc", Canonicalization::Simple), b"abc\r\n");
+
+        assert_eq!(
+            strip_signature(b"DKIM-Signature: v=1; bh=abc=; b=dGVz\r\n\tdA==; d=example.org"),
+            b"DKIM-Signature: v=1; bh=abc=; b=; d=example.org"
+        );
+        assert_eq!(strip_signature(b" v=1; b = dGVzdA=="), b" v=1; b =");
+
+        let signature = Signature::parse(
+            concat!(
+                " v=1; a=rsa-sha256; c=relaxed/simple; d=Example.org;\r\n",
+                "\ts=sel; h=From:Subject; bh=dGVzdA==; b=dGVz\r\n\tdA==\r\n"
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(signature.domain, "example.org");
+        assert_eq!(signature.signed_headers, ["From", "Subject"]);
+        assert_eq!(signature.header_canonicalization, Canonicalization::Relaxed);
+        assert_eq!(signature.body_canonicalization, Canonicalization::Simple);
+        assert_eq!(signature.signature, b"test");
+        assert!(Signature::parse(
+            b" v=1; a=rsa-sha256; d=example.org; s=sel; h=Subject; bh=dGVzdA==; b=dGVzdA=="
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn dkim_rfc8463_vectors() {
+        // Signed message and keys from RFC 8463, appendix A
+        let message = concat!(
+            "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;\r\n",
+            " d=football.example.com; i=@football.example.com;\r\n",
+            " q=dns/txt; s=brisbane; t=1528637909; h=from : to :\r\n",
+            " subject : date : message-id : from : subject : date;\r\n",
+            " bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n",
+            " b=/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11Bus\r\n",
+            " Fa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==\r\n",
+            "DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed;\r\n",
+            " d=football.example.com; i=@football.example.com;\r\n",
+            " q=dns/txt; s=test; t=1528637909; h=from : to : subject :\r\n",
+            " date : message-id : from : subject : date;\r\n",
+            " bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n",
+            " b=F45dVWDfMbQDGHJFlXUNB2HKfbCeLRyhDXgFpEL8GwpsRe0IeIixNTe3\r\n",
+            " DhCVlUrSjV4BwcVcOF6+FF3Zo9Rpo1tFOeS9mPYQTnGdaSGsgeefOsk2Jz\r\n",
+            " dA+L10TeYt9BgDfQNZtKdN1WO//KgIqXP7OdEFE4LjFYNcUxZQ4FADY+8=\r\n",
+            "From: Joe SixPack <joe@football.example.com>\r\n",
+            "To: Suzie Q <suzie@shopping.example.net>\r\n",
+            "Subject: Is dinner ready?\r\n",
+            "Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r\n",
+            "Message-ID: <20030712040037.46341.5F8J@football.example.com>\r\n",
+            "\r\n",
+            "Hi.\r\n",
+            "\r\n",
+            "We lost the game.  Are you hungry yet?\r\n",
+            "\r\n",
+            "Joe.\r\n"
+        );
+        let keys = [
+            "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=",
+            concat!(
+                "MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDkHlOQoBTzWRiGs5V6NpP3idY6Wk08a5qhdR6wy5bdOKb2jLQ",
+                "iY/J16JYi0Qvx/byYzCNb3W91y3FutACDfzwQ/BC/e/8uBsCR+yz1Lxj+PL6lHvqMKrM3rG4hstT5QjvHO9Pzox",
+                "ZyVYLzBfO2EeC3Ip3G+2kryOTIKT+l/K4w3QIDAQAB"
+            ),
+        ];
+
+        let (headers, body_offset) = parse_headers(message.as_bytes());
+        let body = &message.as_bytes()[body_offset..];
+        for (header, key) in headers.iter().take(2).zip(keys) {
+            let signature = Signature::parse(header.value).unwrap();
+            let public_key = base64_decode(key).unwrap();
+            assert_eq!(
+                signature
+                    .algorithm
+                    .hash(&canonical_body(body, signature.body_canonicalization)),
+                signature.body_hash
+            );
+            let data = signature.canonical_headers(&headers, header);
+            assert_eq!(
+                verify_signature(
+                    signature.algorithm,
+                    &public_key,
+                    &data,
+                    &signature.signature
+                ),
+                AuthResult::Pass
+            );
+
+            // Any change to the signed headers breaks the signature
+            let mut data = data;
+            data[5] ^= 0x20;
+            assert_eq!(
+                verify_signature(
+                    signature.algorithm,
+                    &public_key,
+                    &data,
+                    &signature.signature
+                ),
+                AuthResult::Fail
+            );
+        }
+    }
+}
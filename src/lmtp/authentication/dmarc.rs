@@ -0,0 +1,294 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::services::dns::{DnsError, DnsResolver};
+
+use super::{dkim::DkimOutput, AuthResult, Policy, RawHeader};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DmarcOutput {
+    pub result: AuthResult,
+    pub from_domain: String,
+    pub policy: Policy,
+    pub pct: u8,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Record {
+    policy: Policy,
+    subdomain_policy: Option<Policy>,
+    strict_dkim: bool,
+    strict_spf: bool,
+    pct: u8,
+}
+
+pub async fn verify(
+    dns: &DnsResolver,
+    headers: &[RawHeader<'_>],
+    spf_domain: &str,
+    spf: AuthResult,
+    dkim: &[DkimOutput],
+) -> DmarcOutput {
+    let mut output = DmarcOutput {
+        result: AuthResult::None,
+        from_domain: String::new(),
+        policy: Policy::None,
+        pct: 100,
+    };
+
+    // Messages must have exactly one author domain (RFC 7489, section 6.6.1)
+    let mut from = headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("From"));
+    output.from_domain = match (from.next().and_then(|h| from_domain(h.value)), from.next()) {
+        (Some(domain), None) => domain,
+        _ => {
+            output.result = AuthResult::PermError;
+            return output;
+        }
+    };
+
+    // Look up the record of the author domain, falling back to its organizational domain
+    let org_domain = organizational_domain(&output.from_domain);
+    let mut record = lookup_record(dns, &output.from_domain).await;
+    let mut is_org_record = false;
+    if matches!(record, Ok(None)) && org_domain != output.from_domain {
+        record = lookup_record(dns, org_domain).await;
+        is_org_record = true;
+    }
+    let record = match record {
+        Ok(Some(record)) => record,
+        Ok(None) => return output,
+        Err(result) => {
+            output.result = result;
+            return output;
+        }
+    };
+
+    let spf_aligned =
+        spf == AuthResult::Pass && is_aligned(spf_domain, &output.from_domain, record.strict_spf);
+    let dkim_aligned = dkim.iter().any(|dkim| {
+        dkim.result == AuthResult::Pass
+            && is_aligned(&dkim.domain, &output.from_domain, record.strict_dkim)
+    });
+
+    output.result = if spf_aligned || dkim_aligned {
+        AuthResult::Pass
+    } else {
+        AuthResult::Fail
+    };
+    output.policy = match record.subdomain_policy {
+        Some(policy) if is_org_record => policy,
+        _ => record.policy,
+    };
+    output.pct = record.pct;
+    output
+}
+
+async fn lookup_record(dns: &DnsResolver, domain: &str) -> Result<Option<Record>, AuthResult> {
+    match dns.txt_lookup(&format!("_dmarc.{}", domain)).await {
+        Ok(records) => {
+            let mut records = records.iter().filter_map(|txt| parse_record(txt));
+            match (records.next(), records.next()) {
+                (Some(record), None) => Ok(record),
+                _ => Ok(None),
+            }
+        }
+        Err(DnsError::NotFound) => Ok(None),
+        Err(DnsError::Failure(_)) => Err(AuthResult::TempError),
+    }
+}
+
+// Returns the parsed record if the TXT record is a DMARC record, or None within
+// it when the record has no valid policy.
+fn parse_record(txt: &str) -> Option<Option<Record>> {
+    let mut tags = txt.split(';').map(|tag| tag.trim());
+    if !tags.next()?.split_once('=').map_or(false, |(name, value)| {
+        name.trim() == "v" && value.trim() == "DMARC1"
+    }) {
+        return None;
+    }
+
+    let mut record = Record {
+        policy: Policy::None,
+        subdomain_policy: None,
+        strict_dkim: false,
+        strict_spf: false,
+        pct: 100,
+    };
+    let mut has_policy = false;
+
+    for (name, value) in tags.filter_map(|tag| tag.split_once('=')) {
+        let value = value.trim();
+        match name.trim() {
+            "p" => match Policy::parse(value) {
+                Some(policy) => {
+                    record.policy = policy;
+                    has_policy = true;
+                }
+                None => return Some(None),
+            },
+            "sp" => record.subdomain_policy = Policy::parse(value),
+            "adkim" => record.strict_dkim = value.eq_ignore_ascii_case("s"),
+            "aspf" => record.strict_spf = value.eq_ignore_ascii_case("s"),
+            "pct" => record.pct = value.parse::<u8>().map_or(100, |pct| pct.min(100)),
+            _ => (),
+        }
+    }
+
+    Some(if has_policy { Some(record) } else { None })
+}
+
+fn from_domain(value: &[u8]) -> Option<String> {
+    let value = String::from_utf8_lossy(value);
+    let address = match value.rsplit_once('<') {
+        Some((_, address)) => address.split_once('>')?.0,
+        None => value.trim(),
+    };
+    let domain = address
+        .rsplit_once('@')?
+        .1
+        .trim()
+        .trim_end_matches('.')
+        .to_lowercase();
+
+    if !domain.is_empty()
+        && domain
+            .chars()
+            .all(|ch| ch.is_alphanumeric() || matches!(ch, '.' | '-' | '_'))
+    {
+        Some(domain)
+    } else {
+        None
+    }
+}
+
+fn is_aligned(domain: &str, from_domain: &str, strict: bool) -> bool {
+    let domain = domain.trim_end_matches('.');
+    if strict {
+        domain.eq_ignore_ascii_case(from_domain)
+    } else {
+        organizational_domain(&domain.to_lowercase()) == organizational_domain(from_domain)
+    }
+}
+
+// Approximates the organizational domain without the Public Suffix List, treating
+// common second-level labels under country code TLDs (such as "co.uk") as suffixes.
+fn organizational_domain(domain: &str) -> &str {
+    let labels = domain.rsplit('.').take(3).collect::<Vec<_>>();
+    let num_labels = if labels.len() == 3
+        && labels[0].len() == 2
+        && matches!(
+            labels[1],
+            "ac" | "co" | "com" | "edu" | "gov" | "net" | "org" | "ne" | "or"
+        ) {
+        3
+    } else {
+        2
+    };
+
+    domain
+        .char_indices()
+        .rev()
+        .filter(|(_, ch)| *ch == '.')
+        .nth(num_labels - 1)
+        .map_or(domain, |(pos, _)| &domain[pos + 1..])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lmtp::authentication::Policy;
+
+    use super::{from_domain, is_aligned, organizational_domain, parse_record, Record};
+
+    #[test]
+    fn parse_dmarc_record() {
+        assert_eq!(
+            parse_record("v=DMARC1; p=reject; sp=quarantine; adkim=s; pct=50; rua=mailto:a@b.c"),
+            Some(Some(Record {
+                policy: Policy::Reject,
+                subdomain_policy: Some(Policy::Quarantine),
+                strict_dkim: true,
+                strict_spf: false,
+                pct: 50,
+            }))
+        );
+        assert_eq!(parse_record("v=DMARC1; rua=mailto:a@b.c"), Some(None));
+        assert_eq!(parse_record("v=spf1 -all"), None);
+
+        assert_eq!(
+            from_domain(b" \"Doe, Jane\" <jane@Mail.Example.org>\r\n").as_deref(),
+            Some("mail.example.org")
+        );
+        assert_eq!(
+            from_domain(b" jane@example.org\r\n").as_deref(),
+            Some("example.org")
+        );
+        assert_eq!(from_domain(b" undisclosed\r\n"), None);
+
+        assert_eq!(organizational_domain("mail.example.org"), "example.org");
+        assert_eq!(organizational_domain("example.org"), "example.org");
+        assert_eq!(organizational_domain("mx.example.co.uk"), "example.co.uk");
+        assert!(is_aligned("bounces.example.org", "example.org", false));
+        assert!(!is_aligned("bounces.example.org", "example.org", true));
+        assert!(!is_aligned("example.net", "example.org", false));
+    }
+
+    #[test]
+    fn dmarc_rfc7489_examples() {
+        // Policy records from RFC 7489, appendix B.2
+        assert_eq!(
+            parse_record("v=DMARC1; p=none; rua=mailto:dmarc-feedback@example.com"),
+            Some(Some(Record {
+                policy: Policy::None,
+                subdomain_policy: None,
+                strict_dkim: false,
+                strict_spf: false,
+                pct: 100,
+            }))
+        );
+        assert_eq!(
+            parse_record(concat!(
+                "v=DMARC1; p=quarantine; ",
+                "rua=mailto:dmarc-feedback@example.com,mailto:tld-test@thirdparty.example.net!10m; ",
+                "pct=25"
+            )),
+            Some(Some(Record {
+                policy: Policy::Quarantine,
+                subdomain_policy: None,
+                strict_dkim: false,
+                strict_spf: false,
+                pct: 25,
+            }))
+        );
+
+        // Identifier alignment examples from RFC 7489, section 3.1.1
+        assert!(is_aligned("example.com", "example.com", true));
+        assert!(is_aligned("news.example.com", "example.com", false));
+        assert!(!is_aligned("news.example.com", "example.com", true));
+        assert!(is_aligned("example.com", "news.example.com", false));
+        assert!(!is_aligned("example.net", "example.com", false));
+    }
+}
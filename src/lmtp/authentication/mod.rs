@@ -0,0 +1,577 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+pub mod arc;
+pub mod dkim;
+pub mod dmarc;
+pub mod spf;
+
+use std::{fmt::Display, net::IpAddr};
+
+use jmap_mail::mail_parser::{HeaderName, Message};
+use serde::{Deserialize, Serialize};
+use store::{
+    ahash::AHashMap,
+    config::{env_settings::EnvSettings, jmap::JMAPConfig, settings::Setting},
+    rand::{thread_rng, Rng},
+};
+
+use crate::{server::failed_to, services::dns::DnsResolver};
+
+use self::{dkim::DkimOutput, dmarc::DmarcOutput};
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::list("lmtp-auth-overrides")
+        .describe("domain:policy entries, policy is one of none, quarantine or reject"),
+    Setting::bool("lmtp-auth-dmarc-reject").default("true"),
+    Setting::bool("lmtp-auth-dmarc-quarantine").default("true"),
+    Setting::list("lmtp-auth-relays")
+        .describe("Hosts trusted to record the SMTP client in a Received header, any when unset"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthResult {
+    None,
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+    TempError,
+    PermError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Policy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+pub struct AuthConfig {
+    pub dmarc_reject: bool,
+    pub dmarc_quarantine: bool,
+    pub overrides: AHashMap<String, Policy>,
+    pub relays: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthOutcome {
+    pub spf: AuthResult,
+    pub spf_domain: String,
+    pub dkim: Vec<DkimOutput>,
+    pub arc: AuthResult,
+    pub dmarc: DmarcOutput,
+    pub disposition: Policy,
+}
+
+pub struct RawHeader<'x> {
+    pub name: &'x str,
+    pub value: &'x [u8],
+    pub raw: &'x [u8],
+}
+
+impl AuthConfig {
+    pub fn parse(settings: &EnvSettings) -> Self {
+        let mut overrides = AHashMap::new();
+        for entry in settings
+            .parse_list("lmtp-auth-overrides")
+            .unwrap_or_default()
+        {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+            match entry
+                .split_once(':')
+                .and_then(|(domain, policy)| Some((domain.trim(), Policy::parse(policy.trim())?)))
+            {
+                Some((domain, policy)) if !domain.is_empty() => {
+                    overrides.insert(domain.to_lowercase(), policy);
+                }
+                _ => failed_to(&format!(
+                    "parse 'lmtp-auth-overrides', invalid entry {:?}.",
+                    entry
+                )),
+            }
+        }
+
+        AuthConfig {
+            dmarc_reject: settings.value(SETTINGS, "lmtp-auth-dmarc-reject"),
+            dmarc_quarantine: settings.value(SETTINGS, "lmtp-auth-dmarc-quarantine"),
+            overrides,
+            relays: settings
+                .parse_list("lmtp-auth-relays")
+                .unwrap_or_default()
+                .into_iter()
+                .map(|relay| relay.trim().trim_end_matches('.').to_lowercase())
+                .filter(|relay| !relay.is_empty())
+                .collect(),
+        }
+    }
+
+    // Overrides configured for a domain also apply to its subdomains.
+    fn policy_override(&self, domain: &str) -> Option<Policy> {
+        let mut domain = domain;
+        loop {
+            if let Some(policy) = self.overrides.get(domain) {
+                return Some(*policy);
+            }
+            domain = domain.split_once('.')?.1;
+        }
+    }
+}
+
+impl AuthOutcome {
+    pub async fn evaluate(
+        dns: &DnsResolver,
+        config: &AuthConfig,
+        ip: IpAddr,
+        helo: &str,
+        mail_from: &str,
+        message: &[u8],
+    ) -> Self {
+        let (headers, body_offset) = parse_headers(message);
+        let (spf_domain, spf) = spf::verify(dns, ip, helo, mail_from).await;
+        let body = message.get(body_offset..).unwrap_or_default();
+        let dkim = dkim::verify(dns, &headers, body).await;
+        let arc = arc::verify(dns, &headers, body).await;
+        let dmarc = dmarc::verify(dns, &headers, &spf_domain, spf, &dkim).await;
+
+        // Local overrides take precedence over the published policy and its sampling rate
+        let policy = if dmarc.result != AuthResult::Fail {
+            Policy::None
+        } else if let Some(policy) = config.policy_override(&dmarc.from_domain) {
+            policy
+        } else if dmarc.pct >= 100 || thread_rng().gen_range(0..100) < dmarc.pct {
+            dmarc.policy
+        } else {
+            Policy::None
+        };
+
+        AuthOutcome {
+            spf,
+            spf_domain,
+            dkim,
+            arc,
+            dmarc,
+            disposition: match policy {
+                Policy::Reject if config.dmarc_reject => Policy::Reject,
+                Policy::Reject | Policy::Quarantine if config.dmarc_quarantine => {
+                    Policy::Quarantine
+                }
+                _ => Policy::None,
+            },
+        }
+    }
+
+    // Builds an Authentication-Results header (RFC 8601).
+    pub fn to_header(&self, authserv_id: &str) -> String {
+        format!(
+            "Authentication-Results: {};{}\r\n",
+            authserv_id,
+            self.results()
+        )
+    }
+
+    // Formats the results as the folded list of clauses that follows the
+    // authserv-id, as used by both Authentication-Results and ARC-Authentication-Results.
+    pub fn results(&self) -> String {
+        let mut results = String::with_capacity(128);
+
+        if self.dkim.is_empty() {
+            results.push_str("\r\n\tdkim=none;");
+        }
+        for dkim in &self.dkim {
+            if !dkim.domain.is_empty() {
+                results.push_str(&format!(
+                    "\r\n\tdkim={} header.d={} header.s={};",
+                    dkim.result, dkim.domain, dkim.selector
+                ));
+            } else {
+                results.push_str(&format!("\r\n\tdkim={};", dkim.result));
+            }
+        }
+
+        results.push_str(&format!("\r\n\tspf={}", self.spf));
+        if !self.spf_domain.is_empty() {
+            results.push_str(&format!(" smtp.mailfrom={}", self.spf_domain));
+        }
+        results.push(';');
+
+        results.push_str(&format!("\r\n\tarc={};", self.arc));
+
+        results.push_str(&format!("\r\n\tdmarc={}", self.dmarc.result));
+        if self.dmarc.result != AuthResult::None && self.dmarc.result != AuthResult::TempError {
+            results.push_str(&format!(
+                " (p={} dis={})",
+                self.dmarc.policy, self.disposition
+            ));
+        }
+        if !self.dmarc.from_domain.is_empty() {
+            results.push_str(&format!(" header.from={}", self.dmarc.from_domain));
+        }
+
+        results
+    }
+}
+
+// Removes any Authentication-Results headers claiming to have been added by
+// this server, which would otherwise be trusted by the delivery pipeline
+// (RFC 8601, section 5).
+pub fn strip_auth_results(message: &[u8], authserv_id: &str) -> Option<Vec<u8>> {
+    let (headers, _) = parse_headers(message);
+    let forged = headers
+        .iter()
+        .filter(|header| {
+            header.name.eq_ignore_ascii_case("Authentication-Results")
+                && is_authserv_id(header.value, authserv_id)
+        })
+        .collect::<Vec<_>>();
+    if forged.is_empty() {
+        return None;
+    }
+
+    let mut stripped = Vec::with_capacity(message.len());
+    let mut pos = 0;
+    for header in forged {
+        let start = header.raw.as_ptr() as usize - message.as_ptr() as usize;
+        stripped.extend_from_slice(&message[pos..start]);
+        pos = start + header.raw.len();
+    }
+    stripped.extend_from_slice(&message[pos..]);
+
+    Some(stripped)
+}
+
+fn is_authserv_id(value: &[u8], authserv_id: &str) -> bool {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.split(';').next())
+        .and_then(|id| id.split_ascii_whitespace().next())
+        .map_or(false, |id| id.eq_ignore_ascii_case(authserv_id))
+}
+
+// Returns the identifier this server uses in the Authentication-Results headers
+// it adds, which should be shared by all nodes of a cluster (RFC 8601, section 2.5).
+pub fn authserv_id(config: &JMAPConfig) -> String {
+    config.lmtp_authserv_id.clone().unwrap_or_else(|| {
+        gethostname::gethostname()
+            .to_str()
+            .unwrap_or("localhost")
+            .to_string()
+    })
+}
+
+// Returns the DMARC result of a message and whether it was quarantined, either from
+// the outcome evaluated on ingestion or from the Authentication-Results header that
+// this server added to it.
+pub fn auth_verdict(
+    outcome: Option<&AuthOutcome>,
+    message: &Message,
+    raw_message: &[u8],
+    authserv_id: &str,
+) -> Option<(String, bool)> {
+    if let Some(outcome) = outcome {
+        return Some((
+            outcome.dmarc.result.to_string(),
+            outcome.disposition == Policy::Quarantine,
+        ));
+    }
+
+    let value = message
+        .parts
+        .first()?
+        .headers
+        .iter()
+        .filter(|header| {
+            matches!(&header.name, HeaderName::Other(name) if name.eq_ignore_ascii_case("Authentication-Results"))
+        })
+        .filter_map(|header| raw_message.get(header.offset_start..header.offset_end))
+        .find(|value| is_authserv_id(value, authserv_id))?;
+    let value = std::str::from_utf8(value).ok()?;
+
+    value.split(';').skip(1).find_map(|result| {
+        let result = result.trim().strip_prefix("dmarc=")?;
+        let verdict = result
+            .split(|c: char| c.is_ascii_whitespace() || c == '(')
+            .next()?
+            .to_ascii_lowercase();
+        Some((verdict, result.contains("dis=quarantine")))
+    })
+}
+
+// Returns the HELO name and IP address of the client that handed the message to
+// the MTA in front of this server. The topmost Received header is the one added by
+// this server, so only the one below it is considered, and only if it was added by
+// one of the trusted relays when these are configured.
+pub fn received_from(message: &[u8], relays: &[String]) -> Option<(String, IpAddr)> {
+    let (headers, _) = parse_headers(message);
+    let value = headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("Received"))
+        .nth(1)
+        .map(|header| String::from_utf8_lossy(header.value))?;
+    let value = value.trim().strip_prefix("from")?;
+    let (from, by) = value.split_once("by ").unwrap_or((value, ""));
+
+    if !relays.is_empty() {
+        let by = by
+            .split(|c: char| c.is_ascii_whitespace() || c == ';')
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches('.')
+            .to_lowercase();
+        if !relays.contains(&by) {
+            return None;
+        }
+    }
+
+    let ip = from.split('[').skip(1).find_map(|part| {
+        let ip = part.split_once(']')?.0;
+        ip.strip_prefix("IPv6:")
+            .unwrap_or(ip)
+            .parse::<IpAddr>()
+            .ok()
+    })?;
+    let helo = if let Some((_, helo)) = from.split_once("helo=") {
+        helo.split(|c: char| c == ')' || c.is_ascii_whitespace())
+            .next()
+            .unwrap_or_default()
+    } else {
+        from.split_ascii_whitespace().next().unwrap_or_default()
+    };
+
+    Some((helo.to_string(), ip))
+}
+
+// Splits the header section of a message into its fields, returning them
+// along with the offset where the body starts.
+pub fn parse_headers(message: &[u8]) -> (Vec<RawHeader<'_>>, usize) {
+    let mut headers = Vec::new();
+    let mut pos = 0;
+
+    while pos < message.len() {
+        if message[pos..].starts_with(b"\r\n") {
+            return (headers, pos + 2);
+        } else if message[pos] == b'\n' {
+            return (headers, pos + 1);
+        }
+
+        // Find the end of the field, including any folded lines
+        let mut end = pos;
+        loop {
+            match message[end..].iter().position(|&ch| ch == b'\n') {
+                Some(offset) => {
+                    end += offset + 1;
+                    if !matches!(message.get(end), Some(b' ' | b'\t')) {
+                        break;
+                    }
+                }
+                None => {
+                    end = message.len();
+                    break;
+                }
+            }
+        }
+
+        let raw = &message[pos..end];
+        if let Some(colon) = raw.iter().position(|&ch| ch == b':') {
+            if let Ok(name) = std::str::from_utf8(&raw[..colon]) {
+                headers.push(RawHeader {
+                    name: name.trim(),
+                    value: &raw[colon + 1..],
+                    raw,
+                });
+            }
+        }
+        pos = end;
+    }
+
+    (headers, message.len())
+}
+
+impl Policy {
+    pub fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("none") {
+            Some(Policy::None)
+        } else if value.eq_ignore_ascii_case("quarantine") {
+            Some(Policy::Quarantine)
+        } else if value.eq_ignore_ascii_case("reject") {
+            Some(Policy::Reject)
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Policy::None => "none",
+            Policy::Quarantine => "quarantine",
+            Policy::Reject => "reject",
+        })
+    }
+}
+
+impl Display for AuthResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AuthResult::None => "none",
+            AuthResult::Pass => "pass",
+            AuthResult::Fail => "fail",
+            AuthResult::SoftFail => "softfail",
+            AuthResult::Neutral => "neutral",
+            AuthResult::TempError => "temperror",
+            AuthResult::PermError => "permerror",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use jmap_mail::mail_parser::Message;
+
+    use super::{auth_verdict, parse_headers, received_from, strip_auth_results};
+
+    #[test]
+    fn parse_raw_headers() {
+        let message = concat!(
+            "Received: from mx.example.org (mx.example.org [192.0.2.1])\r\n",
+            "\tby mail.example.net (Postfix) with ESMTPS;\r\n",
+            "\tMon, 1 Aug 2022 10:00:00 +0000\r\n",
+            "From: Jane <jane@example.org>\r\n",
+            "Subject: test\r\n",
+            "\r\n",
+            "Body\r\n"
+        );
+        let (headers, body_offset) = parse_headers(message.as_bytes());
+        assert_eq!(
+            headers.iter().map(|h| h.name).collect::<Vec<_>>(),
+            ["Received", "From", "Subject"]
+        );
+        assert_eq!(headers[1].value, b" Jane <jane@example.org>\r\n");
+        assert_eq!(&message[body_offset..], "Body\r\n");
+
+        // The topmost Received header is our own
+        assert_eq!(received_from(message.as_bytes(), &[]), None);
+        let message = concat!(
+            "Received: from mx.example.net ([127.0.0.1])\r\n",
+            "\tby mail.example.net (Stalwart JMAP) with LMTP;\r\n",
+            "\tMon, 1 Aug 2022 10:00:01 +0000\r\n",
+            "Received: from mx.example.org (mx.example.org [192.0.2.1])\r\n",
+            "\tby mx.example.net (Postfix) with ESMTPS;\r\n",
+            "\tMon, 1 Aug 2022 10:00:00 +0000\r\n",
+            "Received: from forged.example.com ([198.51.100.1])\r\n",
+            "\tby mx.example.net (Postfix) with ESMTP;\r\n",
+            "\tMon, 1 Aug 2022 09:00:00 +0000\r\n",
+            "\r\n"
+        );
+        let client = Some((
+            "mx.example.org".to_string(),
+            "192.0.2.1".parse::<IpAddr>().unwrap(),
+        ));
+        assert_eq!(received_from(message.as_bytes(), &[]), client);
+        assert_eq!(
+            received_from(message.as_bytes(), &["mx.example.net".to_string()]),
+            client
+        );
+        assert_eq!(
+            received_from(message.as_bytes(), &["relay.example.net".to_string()]),
+            None
+        );
+
+        // Headers claiming to be ours do not hide the relay's one
+        assert_eq!(
+            received_from(
+                concat!(
+                    "Received: from mx.example.net ([127.0.0.1])\r\n",
+                    "\tby mail.example.net (Stalwart JMAP) with LMTP;\r\n",
+                    "\tMon, 1 Aug 2022 10:00:01 +0000\r\n",
+                    "Received: from [2001:db8::1] (helo=mx.example.org)\r\n",
+                    "\tby mx.example.net with esmtp\r\n",
+                    "Received: from forged.example.com ([198.51.100.1])\r\n",
+                    "\tby mail.example.net (Stalwart JMAP) with LMTP;\r\n",
+                    "\r\n"
+                )
+                .as_bytes(),
+                &[]
+            ),
+            Some((
+                "mx.example.org".to_string(),
+                "2001:db8::1".parse::<IpAddr>().unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn verdict_from_own_results() {
+        let message = concat!(
+            "Authentication-Results: mx.example.com; dmarc=pass header.from=example.org\r\n",
+            "Authentication-Results: mx.example.org;\r\n",
+            "\tspf=pass smtp.mailfrom=example.org;\r\n",
+            "\tdmarc=fail (p=quarantine dis=quarantine) header.from=example.org\r\n",
+            "From: Jane <jane@example.org>\r\n",
+            "Subject: test\r\n",
+            "\r\n",
+            "Body\r\n"
+        );
+        let parsed = Message::parse(message.as_bytes()).unwrap();
+        assert_eq!(
+            auth_verdict(None, &parsed, message.as_bytes(), "mx.example.org"),
+            Some(("fail".to_string(), true))
+        );
+        assert_eq!(
+            auth_verdict(None, &parsed, message.as_bytes(), "mx.example.net"),
+            None
+        );
+    }
+
+    #[test]
+    fn strip_forged_results() {
+        let message = concat!(
+            "Authentication-Results: mx.example.org;\r\n",
+            "\tdmarc=pass header.from=example.org\r\n",
+            "Authentication-Results: mx.example.net; dmarc=fail\r\n",
+            "From: Jane <jane@example.org>\r\n",
+            "authentication-results: MX.EXAMPLE.ORG (forged); dkim=pass\r\n",
+            "Subject: test\r\n",
+            "\r\n",
+            "Authentication-Results: mx.example.org; spf=pass\r\n"
+        );
+        assert_eq!(
+            String::from_utf8(strip_auth_results(message.as_bytes(), "mx.example.org").unwrap())
+                .unwrap(),
+            concat!(
+                "Authentication-Results: mx.example.net; dmarc=fail\r\n",
+                "From: Jane <jane@example.org>\r\n",
+                "Subject: test\r\n",
+                "\r\n",
+                "Authentication-Results: mx.example.org; spf=pass\r\n"
+            )
+        );
+        assert!(strip_auth_results(message.as_bytes(), "mx.example.com").is_none());
+    }
+}
@@ -0,0 +1,697 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use futures::future::BoxFuture;
+
+use crate::services::dns::{DnsError, DnsResolver};
+
+use super::AuthResult;
+
+// Limits on DNS queries per evaluation (RFC 7208, section 4.6.4)
+const MAX_LOOKUPS: u32 = 10;
+const MAX_VOID_LOOKUPS: u32 = 2;
+const MAX_MX_HOSTS: usize = 10;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Mechanism {
+    All,
+    Include(String),
+    A {
+        domain: Option<String>,
+        cidr4: u8,
+        cidr6: u8,
+    },
+    Mx {
+        domain: Option<String>,
+        cidr4: u8,
+        cidr6: u8,
+    },
+    Ptr,
+    Ip4 {
+        addr: Ipv4Addr,
+        cidr: u8,
+    },
+    Ip6 {
+        addr: Ipv6Addr,
+        cidr: u8,
+    },
+    Exists(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Record {
+    directives: Vec<(AuthResult, Mechanism)>,
+    redirect: Option<String>,
+}
+
+struct Context<'x> {
+    dns: &'x DnsResolver,
+    ip: IpAddr,
+    helo: &'x str,
+    local_part: &'x str,
+    sender_domain: &'x str,
+    lookups: u32,
+    void_lookups: u32,
+}
+
+// Evaluates the MAIL FROM identity, or the HELO identity for bounces, returning the
+// domain that was checked and the result.
+pub async fn verify(
+    dns: &DnsResolver,
+    ip: IpAddr,
+    helo: &str,
+    mail_from: &str,
+) -> (String, AuthResult) {
+    let (local_part, domain) = mail_from.rsplit_once('@').unwrap_or(("postmaster", helo));
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    if domain.is_empty() || !domain.contains('.') {
+        return (domain, AuthResult::None);
+    }
+
+    let mut context = Context {
+        dns,
+        ip,
+        helo,
+        local_part,
+        sender_domain: &domain,
+        lookups: 0,
+        void_lookups: 0,
+    };
+    let result = check_host(&mut context, domain.clone()).await;
+
+    (domain, result)
+}
+
+fn check_host<'x, 'y: 'x>(
+    context: &'x mut Context<'y>,
+    domain: String,
+) -> BoxFuture<'x, AuthResult> {
+    Box::pin(async move {
+        let record = match context.dns.txt_lookup(&domain).await {
+            Ok(records) => {
+                let mut records = records.iter().filter_map(|txt| parse_record(txt));
+                match (records.next(), records.next()) {
+                    (Some(Some(record)), None) => record,
+                    (None, _) => return AuthResult::None,
+                    _ => return AuthResult::PermError,
+                }
+            }
+            Err(DnsError::NotFound) => return AuthResult::None,
+            Err(DnsError::Failure(_)) => return AuthResult::TempError,
+        };
+
+        for (qualifier, mechanism) in &record.directives {
+            let matches = match mechanism {
+                Mechanism::All => true,
+                Mechanism::Ip4 { addr, cidr } => ip_matches(context.ip, (*addr).into(), *cidr, 0),
+                Mechanism::Ip6 { addr, cidr } => ip_matches(context.ip, (*addr).into(), 0, *cidr),
+                Mechanism::Include(spec) => {
+                    let target = match context
+                        .new_lookup()
+                        .and_then(|_| context.expand(spec, &domain))
+                    {
+                        Some(target) => target,
+                        None => return AuthResult::PermError,
+                    };
+                    match check_host(context, target).await {
+                        AuthResult::Pass => true,
+                        AuthResult::Fail | AuthResult::SoftFail | AuthResult::Neutral => false,
+                        AuthResult::TempError => return AuthResult::TempError,
+                        AuthResult::PermError | AuthResult::None => return AuthResult::PermError,
+                    }
+                }
+                Mechanism::A {
+                    domain: spec,
+                    cidr4,
+                    cidr6,
+                } => {
+                    let target = match context
+                        .new_lookup()
+                        .and_then(|_| context.expand_or(spec.as_deref(), &domain))
+                    {
+                        Some(target) => target,
+                        None => return AuthResult::PermError,
+                    };
+                    match context.ip_lookup(&target).await {
+                        Ok(ips) => ips
+                            .into_iter()
+                            .any(|ip| ip_matches(context.ip, ip, *cidr4, *cidr6)),
+                        Err(result) => return result,
+                    }
+                }
+                Mechanism::Mx {
+                    domain: spec,
+                    cidr4,
+                    cidr6,
+                } => {
+                    let target = match context
+                        .new_lookup()
+                        .and_then(|_| context.expand_or(spec.as_deref(), &domain))
+                    {
+                        Some(target) => target,
+                        None => return AuthResult::PermError,
+                    };
+                    let mxs = match context.dns.mx_lookup(&target).await {
+                        Ok(mxs) => mxs,
+                        Err(DnsError::NotFound) => {
+                            if context.new_void_lookup().is_none() {
+                                return AuthResult::PermError;
+                            }
+                            Vec::new()
+                        }
+                        Err(DnsError::Failure(_)) => return AuthResult::TempError,
+                    };
+                    if mxs.len() > MAX_MX_HOSTS {
+                        return AuthResult::PermError;
+                    }
+                    let mut matches = false;
+                    for mx in mxs {
+                        match context.ip_lookup(&mx.exchange).await {
+                            Ok(ips) => {
+                                if ips
+                                    .into_iter()
+                                    .any(|ip| ip_matches(context.ip, ip, *cidr4, *cidr6))
+                                {
+                                    matches = true;
+                                    break;
+                                }
+                            }
+                            Err(result) => return result,
+                        }
+                    }
+                    matches
+                }
+                Mechanism::Exists(spec) => {
+                    let target = match context
+                        .new_lookup()
+                        .and_then(|_| context.expand(spec, &domain))
+                    {
+                        Some(target) => target,
+                        None => return AuthResult::PermError,
+                    };
+                    match context.ip_lookup(&target).await {
+                        Ok(ips) => ips.iter().any(|ip| ip.is_ipv4()),
+                        Err(result) => return result,
+                    }
+                }
+                Mechanism::Ptr => {
+                    // Validated reverse lookups are not performed, "ptr" never matches (RFC 7208, section 5.5)
+                    if context.new_lookup().is_none() {
+                        return AuthResult::PermError;
+                    }
+                    false
+                }
+            };
+
+            if matches {
+                return *qualifier;
+            }
+        }
+
+        if let Some(spec) = &record.redirect {
+            let target = match context
+                .new_lookup()
+                .and_then(|_| context.expand(spec, &domain))
+            {
+                Some(target) => target,
+                None => return AuthResult::PermError,
+            };
+            match check_host(context, target).await {
+                AuthResult::None => AuthResult::PermError,
+                result => result,
+            }
+        } else {
+            AuthResult::Neutral
+        }
+    })
+}
+
+impl<'x> Context<'x> {
+    fn new_lookup(&mut self) -> Option<()> {
+        self.lookups += 1;
+        if self.lookups <= MAX_LOOKUPS {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn new_void_lookup(&mut self) -> Option<()> {
+        self.void_lookups += 1;
+        if self.void_lookups <= MAX_VOID_LOOKUPS {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    async fn ip_lookup(&mut self, host: &str) -> Result<Vec<IpAddr>, AuthResult> {
+        match self.dns.ip_lookup(host).await {
+            Ok(ips) => Ok(ips),
+            Err(DnsError::NotFound) => {
+                if self.new_void_lookup().is_some() {
+                    Ok(Vec::new())
+                } else {
+                    Err(AuthResult::PermError)
+                }
+            }
+            Err(DnsError::Failure(_)) => Err(AuthResult::TempError),
+        }
+    }
+
+    fn expand_or(&self, spec: Option<&str>, domain: &str) -> Option<String> {
+        if let Some(spec) = spec {
+            self.expand(spec, domain)
+        } else {
+            Some(domain.to_string())
+        }
+    }
+
+    // Expands the macros in a domain-spec (RFC 7208, section 7).
+    fn expand(&self, spec: &str, domain: &str) -> Option<String> {
+        let mut result = String::with_capacity(spec.len());
+        let mut chars = spec.chars();
+
+        while let Some(ch) = chars.next() {
+            if ch != '%' {
+                result.push(ch);
+                continue;
+            }
+            match chars.next()? {
+                '%' => result.push('%'),
+                '_' => result.push(' '),
+                '-' => result.push_str("%20"),
+                '{' => {
+                    let mut value = match chars.next()?.to_ascii_lowercase() {
+                        's' => format!("{}@{}", self.local_part, self.sender_domain),
+                        'l' => self.local_part.to_string(),
+                        'o' => self.sender_domain.to_string(),
+                        'd' => domain.to_string(),
+                        'i' => match self.ip {
+                            IpAddr::V4(ip) => ip.to_string(),
+                            IpAddr::V6(ip) => ip
+                                .octets()
+                                .iter()
+                                .flat_map(|octet| [octet >> 4, octet & 0x0f])
+                                .map(|nibble| format!("{:x}", nibble))
+                                .collect::<Vec<_>>()
+                                .join("."),
+                        },
+                        'h' => self.helo.to_string(),
+                        'v' => (if self.ip.is_ipv4() { "in-addr" } else { "ip6" }).to_string(),
+                        'p' => "unknown".to_string(),
+                        _ => return None,
+                    };
+
+                    let mut digits = String::new();
+                    let mut reverse = false;
+                    let mut delimiters = String::new();
+                    loop {
+                        match chars.next()? {
+                            '}' => break,
+                            ch @ '0'..='9' if !reverse && delimiters.is_empty() => digits.push(ch),
+                            'r' | 'R' if delimiters.is_empty() => reverse = true,
+                            ch @ ('.' | '-' | '+' | ',' | '/' | '_' | '=') => delimiters.push(ch),
+                            _ => return None,
+                        }
+                    }
+
+                    if !digits.is_empty() || reverse || !delimiters.is_empty() {
+                        let mut parts = value
+                            .split(|ch| {
+                                if delimiters.is_empty() {
+                                    ch == '.'
+                                } else {
+                                    delimiters.contains(ch)
+                                }
+                            })
+                            .collect::<Vec<_>>();
+                        if reverse {
+                            parts.reverse();
+                        }
+                        if !digits.is_empty() {
+                            let keep = digits.parse::<usize>().ok().filter(|&n| n > 0)?;
+                            if parts.len() > keep {
+                                parts.drain(..parts.len() - keep);
+                            }
+                        }
+                        value = parts.join(".");
+                    }
+                    result.push_str(&value);
+                }
+                _ => return None,
+            }
+        }
+
+        Some(result)
+    }
+}
+
+// Returns the parsed record if the TXT record is an SPF record, or None within
+// it when the record is malformed.
+fn parse_record(txt: &str) -> Option<Option<Record>> {
+    let mut terms = txt.split_ascii_whitespace();
+    if !terms.next()?.eq_ignore_ascii_case("v=spf1") {
+        return None;
+    }
+
+    let mut record = Record {
+        directives: Vec::new(),
+        redirect: None,
+    };
+
+    for term in terms {
+        // Modifiers
+        if let Some((name, value)) = term.split_once('=') {
+            if name.eq_ignore_ascii_case("redirect") {
+                if record.redirect.is_some() || value.is_empty() {
+                    return Some(None);
+                }
+                record.redirect = Some(value.to_string());
+            } else if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '.'))
+            {
+                return Some(None);
+            }
+            continue;
+        }
+
+        // Directives
+        let (qualifier, term) = match term.as_bytes()[0] {
+            b'+' => (AuthResult::Pass, &term[1..]),
+            b'-' => (AuthResult::Fail, &term[1..]),
+            b'~' => (AuthResult::SoftFail, &term[1..]),
+            b'?' => (AuthResult::Neutral, &term[1..]),
+            _ => (AuthResult::Pass, term),
+        };
+        let (name, value) = match term.find([':', '/']) {
+            Some(pos) => (&term[..pos], Some(&term[pos..])),
+            None => (term, None),
+        };
+        let mechanism = match parse_mechanism(name, value) {
+            Some(mechanism) => mechanism,
+            None => return Some(None),
+        };
+
+        record.directives.push((qualifier, mechanism));
+    }
+
+    Some(Some(record))
+}
+
+fn parse_mechanism(name: &str, value: Option<&str>) -> Option<Mechanism> {
+    let argument = value.and_then(|value| value.strip_prefix(':'));
+
+    let mechanism = match name.to_ascii_lowercase().as_str() {
+        "all" if value.is_none() => Mechanism::All,
+        "include" => Mechanism::Include(argument.filter(|v| !v.is_empty())?.to_string()),
+        "exists" => Mechanism::Exists(argument.filter(|v| !v.is_empty())?.to_string()),
+        "ptr" => Mechanism::Ptr,
+        "a" | "mx" => {
+            let (domain, cidr4, cidr6) = if let Some(value) = value {
+                let (domain, cidr) = match value.find('/') {
+                    Some(pos) => (&value[..pos], &value[pos..]),
+                    None => (value, ""),
+                };
+                let (cidr4, cidr6) = parse_dual_cidr(cidr)?;
+                let domain = domain.strip_prefix(':').map(|d| d.to_string());
+                if domain.as_ref().map_or(false, |d| d.is_empty()) {
+                    return None;
+                }
+                (domain, cidr4, cidr6)
+            } else {
+                (None, 32, 128)
+            };
+            if name.eq_ignore_ascii_case("a") {
+                Mechanism::A {
+                    domain,
+                    cidr4,
+                    cidr6,
+                }
+            } else {
+                Mechanism::Mx {
+                    domain,
+                    cidr4,
+                    cidr6,
+                }
+            }
+        }
+        "ip4" => {
+            let (addr, cidr) = argument?.split_once('/').unwrap_or((argument?, "32"));
+            match (addr.parse(), cidr.parse()) {
+                (Ok(addr), Ok(cidr)) if cidr <= 32 => Mechanism::Ip4 { addr, cidr },
+                _ => return None,
+            }
+        }
+        "ip6" => {
+            let (addr, cidr) = argument?.split_once('/').unwrap_or((argument?, "128"));
+            match (addr.parse(), cidr.parse()) {
+                (Ok(addr), Ok(cidr)) if cidr <= 128 => Mechanism::Ip6 { addr, cidr },
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    Some(mechanism)
+}
+
+fn parse_dual_cidr(value: &str) -> Option<(u8, u8)> {
+    let (cidr4, cidr6) = match value.strip_prefix('/') {
+        Some(value) => match value.split_once("//") {
+            Some((cidr4, cidr6)) => (cidr4, Some(cidr6)),
+            None => match value.strip_prefix('/') {
+                Some(cidr6) => ("", Some(cidr6)),
+                None => (value, None),
+            },
+        },
+        None if value.is_empty() => ("", None),
+        None => return None,
+    };
+    let cidr4 = if !cidr4.is_empty() {
+        cidr4.parse().ok().filter(|&cidr| cidr <= 32)?
+    } else {
+        32
+    };
+    let cidr6 = if let Some(cidr6) = cidr6 {
+        cidr6.parse().ok().filter(|&cidr| cidr <= 128)?
+    } else {
+        128
+    };
+    Some((cidr4, cidr6))
+}
+
+fn ip_matches(ip: IpAddr, network: IpAddr, cidr4: u8, cidr6: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - cidr4 as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - cidr6 as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ip_matches, parse_record, AuthResult, Mechanism, Record};
+
+    #[test]
+    fn parse_spf_record() {
+        assert_eq!(
+            parse_record("v=spf1 ip4:192.0.2.0/24 a:mail.%{d}/28//64 -mx ~include:_spf.example.org redirect=example.net"),
+            Some(Some(Record {
+                directives: vec![
+                    (
+                        AuthResult::Pass,
+                        Mechanism::Ip4 {
+                            addr: "192.0.2.0".parse().unwrap(),
+                            cidr: 24
+                        }
+                    ),
+                    (
+                        AuthResult::Pass,
+                        Mechanism::A {
+                            domain: Some("mail.%{d}".to_string()),
+                            cidr4: 28,
+                            cidr6: 64
+                        }
+                    ),
+                    (
+                        AuthResult::Fail,
+                        Mechanism::Mx {
+                            domain: None,
+                            cidr4: 32,
+                            cidr6: 128
+                        }
+                    ),
+                    (
+                        AuthResult::SoftFail,
+                        Mechanism::Include("_spf.example.org".to_string())
+                    ),
+                ],
+                redirect: Some("example.net".to_string())
+            }))
+        );
+        assert_eq!(parse_record("v=spf10 -all"), None);
+        assert_eq!(parse_record("google-site-verification=abc"), None);
+        assert_eq!(parse_record("v=spf1 ip4:192.0.2.0/33 -all"), Some(None));
+        assert_eq!(parse_record("v=spf1 foo:bar -all"), Some(None));
+
+        assert!(ip_matches(
+            "192.0.2.10".parse().unwrap(),
+            "192.0.2.0".parse().unwrap(),
+            24,
+            128
+        ));
+        assert!(!ip_matches(
+            "192.0.3.10".parse().unwrap(),
+            "192.0.2.0".parse().unwrap(),
+            24,
+            128
+        ));
+        assert!(ip_matches(
+            "2001:db8::1".parse().unwrap(),
+            "2001:db8::".parse().unwrap(),
+            32,
+            64
+        ));
+        assert!(ip_matches(
+            "198.51.100.1".parse().unwrap(),
+            "192.0.2.1".parse().unwrap(),
+            0,
+            128
+        ));
+    }
+
+    #[test]
+    fn spf_rfc7208_records() {
+        // Record examples from RFC 7208, appendix A
+        let mx = |domain: Option<&str>, cidr4| Mechanism::Mx {
+            domain: domain.map(|d| d.to_string()),
+            cidr4,
+            cidr6: 128,
+        };
+        for (record, directives) in [
+            ("v=spf1 +all", vec![(AuthResult::Pass, Mechanism::All)]),
+            (
+                "v=spf1 a -all",
+                vec![
+                    (
+                        AuthResult::Pass,
+                        Mechanism::A {
+                            domain: None,
+                            cidr4: 32,
+                            cidr6: 128,
+                        },
+                    ),
+                    (AuthResult::Fail, Mechanism::All),
+                ],
+            ),
+            (
+                "v=spf1 mx mx:example.org -all",
+                vec![
+                    (AuthResult::Pass, mx(None, 32)),
+                    (AuthResult::Pass, mx(Some("example.org"), 32)),
+                    (AuthResult::Fail, Mechanism::All),
+                ],
+            ),
+            (
+                "v=spf1 mx/30 mx:example.org/30 -all",
+                vec![
+                    (AuthResult::Pass, mx(None, 30)),
+                    (AuthResult::Pass, mx(Some("example.org"), 30)),
+                    (AuthResult::Fail, Mechanism::All),
+                ],
+            ),
+            (
+                "v=spf1 ptr -all",
+                vec![
+                    (AuthResult::Pass, Mechanism::Ptr),
+                    (AuthResult::Fail, Mechanism::All),
+                ],
+            ),
+            (
+                "v=spf1 ip4:192.0.2.128/28 -all",
+                vec![
+                    (
+                        AuthResult::Pass,
+                        Mechanism::Ip4 {
+                            addr: "192.0.2.128".parse().unwrap(),
+                            cidr: 28,
+                        },
+                    ),
+                    (AuthResult::Fail, Mechanism::All),
+                ],
+            ),
+            (
+                "v=spf1 -include:ip4._spf.%{d} -include:ptr._spf.%{d} +all",
+                vec![
+                    (
+                        AuthResult::Fail,
+                        Mechanism::Include("ip4._spf.%{d}".to_string()),
+                    ),
+                    (
+                        AuthResult::Fail,
+                        Mechanism::Include("ptr._spf.%{d}".to_string()),
+                    ),
+                    (AuthResult::Pass, Mechanism::All),
+                ],
+            ),
+            (
+                "v=spf1 exists:%{ir}.%{l1r+-}._spf.%{d} -all",
+                vec![
+                    (
+                        AuthResult::Pass,
+                        Mechanism::Exists("%{ir}.%{l1r+-}._spf.%{d}".to_string()),
+                    ),
+                    (AuthResult::Fail, Mechanism::All),
+                ],
+            ),
+            (
+                "v=spf1 mx -all exp=explain._spf.%{d}",
+                vec![
+                    (AuthResult::Pass, mx(None, 32)),
+                    (AuthResult::Fail, Mechanism::All),
+                ],
+            ),
+        ] {
+            assert_eq!(
+                parse_record(record),
+                Some(Some(Record {
+                    directives,
+                    redirect: None
+                })),
+                "{}",
+                record
+            );
+        }
+    }
+}
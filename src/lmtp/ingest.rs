@@ -21,7 +21,7 @@
  * for more details.
 */
 
-use std::{borrow::Cow, sync::Arc, time::SystemTime};
+use std::{borrow::Cow, collections::hash_map::Entry, sync::Arc};
 
 use jmap::{
     orm::TinyORM,
@@ -29,42 +29,59 @@ use jmap::{
     types::{jmap::JMAPId, type_state::TypeState},
 };
 use jmap_mail::{
+    email_submission::dsn::{DeliveryReport, JMAPEmailSubmissionDsn},
     mail::{
+        detach::{mail_detach_parts, mail_strip_detached_headers},
         import::JMAPMailImport,
+        limits::MimeLimits,
         schema::{Email, Keyword, Property},
+        MessageField,
     },
-    mail_parser::Message,
-    mailbox::{get::JMAPGetMailbox, is_valid_role, set::JMAPSetMailbox},
-    INBOX_ID, TRASH_ID,
+    mail_parser::{HeaderName, Message},
+    mailbox::{get::JMAPGetMailbox, set::JMAPSetMailbox},
+    INBOX_ID,
+};
+use jmap_sharing::principal::{
+    account::JMAPAccountStore,
+    forwarding::JMAPAccountForwarding,
+    subaddress::{JMAPAccountSubAddress, SubAddressDelivery},
 };
-use jmap_sharing::principal::account::JMAPAccountStore;
 use jmap_sieve::{
-    sieve_script::{
-        get::JMAPGetSieveScript,
-        schema::{CompiledScript, Value},
-    },
-    SeenIdHash, SeenIds,
+    sieve_script::{get::JMAPGetSieveScript, schema::CompiledScript},
+    SeenIds,
 };
 use serde::{Deserialize, Serialize};
 use store::{
-    ahash::{AHashMap, AHashSet},
+    ahash::AHashMap,
     blob::BlobId,
     core::{collection::Collection, document::Document, tag::Tag},
     log::changes::ChangeId,
-    sieve::{Compiler, Envelope, Event, Input, Mailbox, Recipient},
+    sieve::Compiler,
     tracing::{debug, error},
-    write::{batch::WriteBatch, update::Changes},
+    write::{
+        batch::WriteBatch,
+        options::{IndexOptions, Options},
+        update::Changes,
+    },
     AccountId, DocumentId, JMAPStore, RecipientType, Store,
 };
 
 use crate::{
     cluster::rpc::command::{Command, CommandResponse},
+    server::workers::WorkerPool,
     services::{email_delivery, state_change::StateChange},
     JMAPServer,
 };
 
 use super::{
+    authentication::{
+        auth_verdict, authserv_id, received_from, strip_auth_results, AuthOutcome, Policy,
+    },
     session::{RcptType, Session},
+    sieve::{JMAPSieveRun, SieveEnvelope},
+    smtp,
+    srs::srs_forward,
+    trace::{IngestTrace, JMAPIngestTrace, TraceEvent},
     OutgoingMessage,
 };
 
@@ -87,22 +104,170 @@ where
         } else {
             return self.write_bytes(b"503 5.5.1 Missing MAIL FROM.\r\n").await;
         };
-        let message = std::mem::take(&mut self.message);
+        let mut message = std::mem::take(&mut self.message);
         self.rcpt_to_dup.clear();
+        self.num_messages += 1;
+        let config = &self.core.store.config;
+        let mut trace = if config.lmtp_trace_days > 0 || config.lmtp_trace_header {
+            Some(IngestTrace::new(
+                self.peer_addr.ip().to_string(),
+                self.remote_hostname.clone().unwrap_or_default(),
+                mail_from.clone(),
+            ))
+        } else {
+            None
+        };
+
+        // Results claiming to come from this server can only be forged
+        let authserv_id = authserv_id(config);
+        if let Some(stripped) = strip_auth_results(&message, &authserv_id) {
+            message = stripped;
+        }
+
+        // Evaluate SPF, DKIM and DMARC
+        let mut auth_outcome = None;
+        if let Some(auth) = self.auth.clone() {
+            // SMTP clients are the originating hosts, while LMTP messages carry the
+            // client's identity in the Received header added by the MTA.
+            let received_from = if self.smtp.is_none() {
+                received_from(&message, &auth.relays)
+            } else {
+                None
+            };
+            let (helo, ip) = received_from.unwrap_or_else(|| {
+                (
+                    self.remote_hostname.clone().unwrap_or_default(),
+                    self.peer_addr.ip(),
+                )
+            });
+            let outcome =
+                AuthOutcome::evaluate(&self.core.dns, &auth, ip, &helo, &mail_from, &message).await;
+            if let Some(trace) = &mut trace {
+                trace.push(TraceEvent::Authenticated {
+                    dmarc: outcome.dmarc.result.to_string(),
+                    disposition: outcome.disposition.to_string(),
+                });
+            }
+
+            if outcome.disposition == Policy::Reject {
+                debug!(
+                    "Rejecting message from {} failing DMARC policy of {}.",
+                    mail_from, outcome.dmarc.from_domain
+                );
+                if let Some(mut trace) = trace {
+                    trace.push(TraceEvent::Rejected {
+                        reason: format!("DMARC policy of {}", outcome.dmarc.from_domain),
+                    });
+                    let store = self.core.store.clone();
+                    if let Err(err) = self
+                        .core
+                        .spawn_worker(move || store.ingest_trace_set(&trace))
+                        .await
+                    {
+                        error!("Failed to store ingestion trace: {}", err);
+                    }
+                }
+                if self.smtp.is_some() {
+                    self.rcpt_to.clear();
+                    return self
+                        .write_bytes(
+                            format!(
+                                "550 5.7.1 Message rejected due to DMARC policy of {}.\r\n",
+                                outcome.dmarc.from_domain
+                            )
+                            .as_bytes(),
+                        )
+                        .await;
+                }
+                let mut buf = Vec::with_capacity(128);
+                for rcpt in std::mem::take(&mut self.rcpt_to) {
+                    let (RcptType::Mailbox { name, status, .. }
+                    | RcptType::List { name, status, .. }
+                    | RcptType::Relay { name, status, .. }
+                    | RcptType::SubAddress { name, status, .. }) = rcpt;
+                    if !matches!(status, DeliveryStatus::Duplicated) {
+                        buf.extend_from_slice(
+                            format!(
+                                "550 5.7.1 <{}> Message rejected due to DMARC policy of {}.\r\n",
+                                name, outcome.dmarc.from_domain
+                            )
+                            .as_bytes(),
+                        );
+                    }
+                }
+                return self.write_bytes(&buf).await;
+            }
+
+            let header = outcome.to_header(&authserv_id);
+            let mut stamped_message = Vec::with_capacity(header.len() + message.len());
+            stamped_message.extend_from_slice(header.as_bytes());
+            stamped_message.extend_from_slice(&message);
+            message = stamped_message;
+            auth_outcome = Some(outcome);
+        }
+
+        // Stamp the trace id, so that stored messages can be matched to their trace
+        if let Some(trace) = trace
+            .as_ref()
+            .filter(|_| self.core.store.config.lmtp_trace_header)
+        {
+            let header = trace.to_header(
+                &self.hostname,
+                if self.smtp.is_some() { "ESMTP" } else { "LMTP" },
+            );
+            let mut stamped_message = Vec::with_capacity(header.len() + message.len());
+            stamped_message.extend_from_slice(header.as_bytes());
+            stamped_message.extend_from_slice(&message);
+            message = stamped_message;
+        }
+
+        // Keep the headers for SMTP delivery reports
+        let report_from = if self.smtp.is_some() {
+            Some((
+                mail_from.clone(),
+                message[..message.len().min(smtp::MAX_REPORT_HEADERS)].to_vec(),
+            ))
+        } else {
+            None
+        };
 
         // Ingest
         let result = if self.core.is_leader() {
             self.core
-                .mail_ingest(mail_from, std::mem::take(&mut self.rcpt_to), message)
+                .mail_ingest(
+                    mail_from,
+                    std::mem::take(&mut self.rcpt_to),
+                    message,
+                    trace,
+                    auth_outcome,
+                )
                 .await
         } else {
             // Send request to leader
+            let rcpt_to = std::mem::take(&mut self.rcpt_to);
             match self
                 .core
-                .rpc_command(Command::IngestMessage {
-                    mail_from,
-                    rcpt_to: std::mem::take(&mut self.rcpt_to),
-                    raw_message: message,
+                .rpc_command(if let Some(auth) = auth_outcome {
+                    Command::IngestMessageAuthenticated {
+                        mail_from,
+                        rcpt_to,
+                        raw_message: message,
+                        trace,
+                        auth,
+                    }
+                } else if let Some(trace) = trace {
+                    Command::IngestMessageTraced {
+                        mail_from,
+                        rcpt_to,
+                        raw_message: message,
+                        trace,
+                    }
+                } else {
+                    Command::IngestMessage {
+                        mail_from,
+                        rcpt_to,
+                        raw_message: message,
+                    }
                 })
                 .await
             {
@@ -125,10 +290,32 @@ where
         };
 
         // Build response
+        if let Some((mail_from, headers)) = report_from {
+            if let Some(report) =
+                smtp::delivery_report(&self.hostname, &mail_from, &rcpt_to, &headers)
+            {
+                if let Err(err) = self
+                    .core
+                    .notify_email_delivery(email_delivery::Event::OutgoingMessage {
+                        from: report.mail_from,
+                        to: report.rcpt_to,
+                        message: report.message,
+                        seal_domain: report.seal_domain,
+                        auth: report.auth,
+                    })
+                    .await
+                {
+                    error!("Failed to send delivery report to <{}>: {}", mail_from, err);
+                }
+            }
+            return self.write_bytes(&smtp::delivery_response(&rcpt_to)).await;
+        }
         let mut buf = Vec::with_capacity(128);
         for rcpt in &rcpt_to {
-            let (RcptType::Mailbox { name, status, .. } | RcptType::List { name, status, .. }) =
-                rcpt;
+            let (RcptType::Mailbox { name, status, .. }
+            | RcptType::List { name, status, .. }
+            | RcptType::Relay { name, status, .. }
+            | RcptType::SubAddress { name, status, .. }) = rcpt;
             match status {
                 DeliveryStatus::Success => buf.extend_from_slice(b"250 2.1.5 <"),
                 DeliveryStatus::TemporaryFailure { .. } => buf.extend_from_slice(b"451 4.3.0 <"),
@@ -203,11 +390,15 @@ where
         mail_from: String,
         rcpt_to: Vec<RcptType>,
         raw_message: Vec<u8>,
+        trace: Option<IngestTrace>,
+        auth: Option<AuthOutcome>,
     ) -> Result<Vec<RcptType>, String> {
         // Ingest message
         let store = self.store.clone();
         let status = match self
-            .spawn_worker(move || Ok(store.mail_ingest(mail_from, rcpt_to, raw_message)))
+            .spawn_worker_on(WorkerPool::Index, move || {
+                Ok(store.mail_ingest(mail_from, rcpt_to, raw_message, trace, auth))
+            })
             .await
             .unwrap()
         {
@@ -248,11 +439,13 @@ where
         // Send any outgoing messages
         for message in status.messages {
             if let Err(err) = self
-                .notify_email_delivery(email_delivery::Event::outgoing_message(
-                    message.mail_from,
-                    message.rcpt_to,
-                    message.message,
-                ))
+                .notify_email_delivery(email_delivery::Event::OutgoingMessage {
+                    from: message.mail_from,
+                    to: message.rcpt_to,
+                    message: message.message,
+                    seal_domain: message.seal_domain,
+                    auth: message.auth,
+                })
                 .await
             {
                 error!(
@@ -273,8 +466,11 @@ pub trait JMAPMailIngest {
         mail_from: String,
         rcpt_to: Vec<RcptType>,
         raw_message: Vec<u8>,
+        trace: Option<IngestTrace>,
+        auth: Option<AuthOutcome>,
     ) -> Result<IngestResult, Option<&'static str>>;
 
+    #[allow(clippy::too_many_arguments)]
     fn mail_deliver_rcpt(
         &self,
         result: &mut IngestResult,
@@ -283,6 +479,7 @@ pub trait JMAPMailIngest {
         blob_id: &BlobId,
         envelope_from: &str,
         envelope_to: &str,
+        tag: Option<&str>,
     ) -> DeliveryStatus;
 
     #[allow(clippy::result_unit_err)]
@@ -294,6 +491,20 @@ pub trait JMAPMailIngest {
         blob_id: &BlobId,
         mailbox_ids: &[DocumentId],
         flags: Vec<Tag>,
+        thread_id: Option<DocumentId>,
+    ) -> Result<(), ()>;
+
+    #[allow(clippy::result_unit_err)]
+    #[allow(clippy::too_many_arguments)]
+    fn mail_deliver_batch(
+        &self,
+        result: &mut IngestResult,
+        batch: WriteBatch,
+        message: Message,
+        blob_id: &BlobId,
+        mailbox_ids: &[DocumentId],
+        flags: Vec<Tag>,
+        thread_id: Option<DocumentId>,
     ) -> Result<(), ()>;
 }
 
@@ -306,7 +517,40 @@ where
         mail_from: String,
         rcpt_to: Vec<RcptType>,
         raw_message: Vec<u8>,
+        trace: Option<IngestTrace>,
+        auth: Option<AuthOutcome>,
     ) -> Result<IngestResult, Option<&'static str>> {
+        // Only stubs written by the server may reference detached parts
+        let raw_message = mail_strip_detached_headers(&raw_message).unwrap_or(raw_message);
+
+        // Detach large attachments from oversized messages, unless the MIME
+        // structure exceeds the limits and would be parsed only partially
+        let mut detached_parts = Vec::new();
+        let raw_message = if self.config.mail_detach_threshold > 0
+            && raw_message.len() > self.config.mail_detach_threshold
+            && MimeLimits::from(&self.config).scan(&raw_message).is_none()
+        {
+            if let Some(detached) =
+                mail_detach_parts(&raw_message, self.config.mail_detach_min_part_size)
+            {
+                for (blob_id, contents) in detached.parts {
+                    self.blob_store(&blob_id, contents).map_err(|err| {
+                        error!(
+                            "Failed to store detached part during message ingestion: {}",
+                            err
+                        );
+                        None
+                    })?;
+                    detached_parts.push(blob_id);
+                }
+                detached.raw_message
+            } else {
+                raw_message
+            }
+        } else {
+            raw_message
+        };
+
         // Store raw message as a blob
         let blob_id = BlobId::new_external(&raw_message);
         let raw_message = self.blob_store(&blob_id, raw_message).map_err(|err| {
@@ -320,6 +564,9 @@ where
             changes: AHashMap::with_capacity(rcpt_to.len()),
             messages: Vec::new(),
             last_change_id: ChangeId::MAX,
+            detached_parts,
+            trace,
+            auth: auth.map(Arc::new),
         };
         let mut prev_status = if rcpt_to.iter().any(|s| {
             matches!(
@@ -327,6 +574,9 @@ where
                 RcptType::Mailbox {
                     status: DeliveryStatus::Duplicated,
                     ..
+                } | RcptType::SubAddress {
+                    status: DeliveryStatus::Duplicated,
+                    ..
                 } | RcptType::List {
                     status: DeliveryStatus::Duplicated,
                     ..
@@ -339,8 +589,16 @@ where
         };
 
         for mut recipient in rcpt_to {
+            let tag = if let RcptType::SubAddress { tag, .. } = &recipient {
+                Some(tag.to_string())
+            } else {
+                None
+            };
             match &mut recipient {
-                RcptType::Mailbox { id, name, status } => {
+                RcptType::Mailbox { id, name, status }
+                | RcptType::SubAddress {
+                    id, name, status, ..
+                } => {
                     if !matches!(status, DeliveryStatus::Duplicated) {
                         *status = self.mail_deliver_rcpt(
                             &mut result,
@@ -349,7 +607,9 @@ where
                             &blob_id,
                             &mail_from,
                             &*name,
+                            tag.as_deref(),
                         );
+                        result.trace_status(*id, status);
                         if let Some(prev_status) = &mut prev_status {
                             prev_status.insert(*id, status.clone());
                         }
@@ -376,7 +636,9 @@ where
                                 &blob_id,
                                 &mail_from,
                                 &*name,
+                                None,
                             );
+                            result.trace_status(account_id, &status);
 
                             match &status {
                                 DeliveryStatus::Success => {
@@ -409,11 +671,27 @@ where
                         *status = DeliveryStatus::Success;
                     }
                 }
+                RcptType::Relay { address, .. } => {
+                    // Bounce addressed to a rewritten sender, relay it to the original address
+                    result.messages.push(OutgoingMessage {
+                        mail_from: String::new(),
+                        rcpt_to: vec![address.clone()],
+                        message: raw_message.clone(),
+                        seal_domain: None,
+                        auth: None,
+                    });
+                }
             }
 
             result.rcpt_to.push(recipient);
         }
 
+        if let Some(trace) = &result.trace {
+            if let Err(err) = self.ingest_trace_set(trace) {
+                error!("Failed to store ingestion trace: {}", err);
+            }
+        }
+
         Ok(result)
     }
 
@@ -425,7 +703,13 @@ where
         blob_id: &BlobId,
         envelope_from: &str,
         envelope_to: &str,
+        tag: Option<&str>,
     ) -> DeliveryStatus {
+        result.trace(TraceEvent::Recipient {
+            account: account_id.into(),
+            address: envelope_to.to_string(),
+        });
+
         // Verify that this account has an Inbox mailbox
         let mailbox_ids = match self.get_document_ids(account_id, Collection::Mailbox) {
             Ok(Some(mailbox_ids)) if mailbox_ids.contains(INBOX_ID) => mailbox_ids,
@@ -435,12 +719,173 @@ where
             }
         };
 
+        // Sub-addressed messages are either rejected or kept in a mailbox named after the tag
+        let keep_id = if let Some(tag) = tag {
+            match self.subaddress_deliver(account_id, tag) {
+                Ok(SubAddressDelivery::Disabled) => {
+                    return DeliveryStatus::PermanentFailure {
+                        code: "5.1.1".into(),
+                        reason: "Address disabled by recipient".into(),
+                    };
+                }
+                Ok(SubAddressDelivery::AutoFile) => {
+                    match self.mailbox_create_path(account_id, &tag.to_lowercase()) {
+                        Ok(Some((document_id, changes))) => {
+                            if let Some(changes) = changes {
+                                result.last_change_id = changes.change_id;
+                                result.changes.insert(account_id, changes);
+                            }
+                            document_id
+                        }
+                        Ok(None) => INBOX_ID,
+                        Err(err) => {
+                            error!(
+                                "Failed to create mailbox {:?} for {}: {}",
+                                tag, account_id, err
+                            );
+                            INBOX_ID
+                        }
+                    }
+                }
+                Ok(SubAddressDelivery::Inbox) => INBOX_ID,
+                Err(err) => {
+                    error!("Failed to obtain sub-address for {}: {}", account_id, err);
+                    INBOX_ID
+                }
+            }
+        } else {
+            INBOX_ID
+        };
+
         // Parse message
-        let message = if let Some(message) = Message::parse(raw_message) {
+        let message = if let Some(message) = MimeLimits::from(&self.config).parse(raw_message) {
             message
         } else {
             return DeliveryStatus::perm_failure("Failed to parse message.");
         };
+        if let Some(trace) = &mut result.trace {
+            trace.set_message_id(&message, raw_message);
+        }
+
+        // Index the authentication verdict and file quarantined messages into Junk
+        let mut auth_flags = Vec::new();
+        let verdict = if self.config.lmtp_auth {
+            auth_verdict(
+                result.auth.as_deref(),
+                &message,
+                raw_message,
+                &authserv_id(&self.config),
+            )
+        } else {
+            None
+        };
+        let is_quarantined = verdict
+            .as_ref()
+            .map_or(false, |(_, is_quarantined)| *is_quarantined);
+        let mut keep_id = match verdict {
+            Some((verdict, is_quarantined)) => {
+                result.trace(TraceEvent::Verdict {
+                    account: account_id.into(),
+                    verdict: verdict.clone(),
+                    quarantined: is_quarantined,
+                });
+                auth_flags.push(Keyword::parse(&format!("$dmarc-{}", verdict)).tag);
+                if is_quarantined {
+                    auth_flags.push(Tag::Static(Keyword::JUNK));
+                    match self.mailbox_get_by_role(account_id, "junk") {
+                        Ok(Some(junk_id)) => junk_id,
+                        Ok(None) => keep_id,
+                        Err(err) => {
+                            error!("Failed to obtain Junk mailbox for {}: {}", account_id, err);
+                            keep_id
+                        }
+                    }
+                } else {
+                    keep_id
+                }
+            }
+            None => keep_id,
+        };
+
+        // Record bounces of submitted messages and file them next to the original email
+        let mut keep_thread_id = None;
+        if envelope_from.is_empty() && !is_quarantined {
+            if let Some(report) = DeliveryReport::parse(&message, raw_message) {
+                let mut batch = WriteBatch::new(account_id);
+                match self
+                    .email_submission_bounce(&mut batch, &report, blob_id)
+                    .and_then(|bounced| match bounced {
+                        Some(bounced) => Ok(Some((bounced, self.write(batch)?))),
+                        None => Ok(None),
+                    }) {
+                    Ok(Some((bounced, changes))) => {
+                        if let Some(changes) = changes {
+                            result.add_changes(account_id, changes);
+                        }
+                        if let Some(mailbox_id) = bounced.mailbox_ids.first() {
+                            keep_id = *mailbox_id;
+                            keep_thread_id = bounced.thread_id.into();
+                        }
+                    }
+                    Ok(None) => (),
+                    Err(err) => {
+                        error!(
+                            "Failed to match bounce to a submission for {}: {}",
+                            account_id, err
+                        );
+                    }
+                }
+            }
+        }
+
+        // Forward the message to external addresses, unless it already went through this account
+        match self.forwarding_deliver(account_id) {
+            Ok(Some(forwarding)) => {
+                if !has_loop_header(&message, raw_message, &forwarding.email) {
+                    let forward_domain = forwarding
+                        .email
+                        .rsplit_once('@')
+                        .map_or("", |(_, domain)| domain);
+                    let mut forward_message =
+                        Vec::with_capacity(raw_message.len() + forwarding.email.len() + 10);
+                    forward_message.extend_from_slice(b"X-Loop: ");
+                    forward_message.extend_from_slice(forwarding.email.as_bytes());
+                    forward_message.extend_from_slice(b"\r\n");
+                    forward_message.extend_from_slice(raw_message);
+
+                    result.trace(TraceEvent::Forwarded {
+                        account: account_id.into(),
+                        addresses: forwarding.addresses.len(),
+                        keep_copy: forwarding.keep_copy,
+                    });
+                    result.messages.push(OutgoingMessage {
+                        mail_from: srs_forward(
+                            envelope_from,
+                            forward_domain,
+                            &self.config.srs_secret,
+                        ),
+                        rcpt_to: forwarding.addresses,
+                        message: forward_message,
+                        seal_domain: Some(forward_domain.to_string())
+                            .filter(|domain| !domain.is_empty()),
+                        auth: result.auth.clone(),
+                    });
+
+                    if !forwarding.keep_copy {
+                        return DeliveryStatus::Success;
+                    }
+                } else {
+                    debug!(
+                        "Forwarding loop detected for account {}, delivering locally.",
+                        account_id
+                    );
+                }
+            }
+            Ok(None) => (),
+            Err(err) => {
+                error!("Failed to obtain forwarding for {}: {}", account_id, err);
+            }
+        }
 
         let mut active_script = match self.sieve_script_get_active(account_id) {
             Ok(None) => {
@@ -450,8 +895,9 @@ where
                         account_id,
                         message,
                         blob_id,
-                        &[INBOX_ID],
-                        Vec::new(),
+                        &[keep_id],
+                        auth_flags,
+                        keep_thread_id,
                     )
                     .is_ok()
                 {
@@ -469,8 +915,9 @@ where
                         account_id,
                         message,
                         blob_id,
-                        &[INBOX_ID],
-                        Vec::new(),
+                        &[keep_id],
+                        auth_flags,
+                        keep_thread_id,
                     )
                     .is_ok()
                 {
@@ -481,311 +928,41 @@ where
             }
         };
 
-        let mut instance = self.sieve_runtime.filter_parsed(message);
-
-        // Set account details
-        let mail_from = match self.get_account_details(account_id) {
-            Ok(Some((email, name, _))) => {
-                let mail_from = email.clone();
-                instance.set_user_address(email);
-                instance.set_user_full_name(&name);
-                mail_from
-            }
-            _ => {
-                error!("Failed to obtain account details for {}.", account_id);
-                instance.set_user_address(envelope_to.to_string());
-                envelope_to.to_string()
-            }
-        };
-
-        // Set envelope
-        instance.set_envelope(Envelope::From, envelope_from);
-        instance.set_envelope(Envelope::To, envelope_to);
-
-        let mut input = Input::script(
-            if let Some(Value::Text { value }) = active_script
-                .orm
-                .get(&jmap_sieve::sieve_script::schema::Property::Name)
-            {
-                value.to_string()
-            } else {
-                account_id.to_string()
+        let mut outcome = self.sieve_run(
+            account_id,
+            &active_script,
+            raw_message,
+            message,
+            SieveEnvelope {
+                mail_from: envelope_from,
+                rcpt_to: envelope_to,
+                mailbox_ids: &mailbox_ids,
+                keep_id,
             },
-            active_script.script.clone(),
+            false,
         );
-
-        let mut do_discard = false;
-        let mut do_deliver = false;
-
-        let mut new_ids = AHashSet::new();
-        let mut reject_reason = None;
-        let mut messages: Vec<SieveMessage> = vec![SieveMessage {
-            raw_message: raw_message.into(),
-            file_into: Vec::new(),
-            flags: Vec::new(),
-        }];
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .map(|d| d.as_secs())
-            .unwrap_or(0);
-
-        while let Some(event) = instance.run(input) {
-            match event {
-                Ok(event) => match event {
-                    Event::IncludeScript { name, .. } => {
-                        if let Ok(Some(script)) =
-                            self.sieve_script_get_by_name(account_id, name.as_str().to_string())
-                        {
-                            input = Input::script(name, script);
-                        } else {
-                            input = false.into();
-                        }
-                    }
-                    Event::MailboxExists {
-                        mailboxes,
-                        special_use,
-                    } => {
-                        if !mailboxes.is_empty() {
-                            let special_use = special_use
-                                .into_iter()
-                                .map(|role| {
-                                    if role.eq_ignore_ascii_case("inbox") {
-                                        INBOX_ID
-                                    } else if role.eq_ignore_ascii_case("trash") {
-                                        TRASH_ID
-                                    } else {
-                                        let mut mailbox_id = DocumentId::MAX;
-                                        let role = role.to_ascii_lowercase();
-                                        if is_valid_role(&role) {
-                                            if let Ok(Some(mailbox_id_)) =
-                                                self.mailbox_get_by_role(account_id, &role)
-                                            {
-                                                mailbox_id = mailbox_id_;
-                                            }
-                                        }
-                                        mailbox_id
-                                    }
-                                })
-                                .collect::<Vec<_>>();
-
-                            let mut result = true;
-                            for mailbox in mailboxes {
-                                match mailbox {
-                                    Mailbox::Name(name) => {
-                                        if !matches!(
-                                            self.mailbox_get_by_name(account_id, &name),
-                                            Ok(Some(document_id)) if special_use.is_empty() ||
-                                                        special_use.contains(&document_id)
-                                        ) {
-                                            result = false;
-                                            break;
-                                        }
-                                    }
-                                    Mailbox::Id(id) => {
-                                        if !matches!(JMAPId::parse(&id), Some(id) if
-                                                            mailbox_ids.contains(id.get_document_id()) &&
-                                                            (special_use.is_empty() ||
-                                                             special_use.contains(&id.get_document_id())))
-                                        {
-                                            result = false;
-                                            break;
-                                        }
-                                    }
-                                }
-                            }
-                            input = result.into();
-                        } else if !special_use.is_empty() {
-                            let mut result = true;
-
-                            for role in special_use {
-                                if !role.eq_ignore_ascii_case("inbox")
-                                    && !role.eq_ignore_ascii_case("trash")
-                                {
-                                    let role = role.to_ascii_lowercase();
-                                    if !is_valid_role(&role)
-                                        || !matches!(
-                                            self.mailbox_get_by_role(account_id, &role),
-                                            Ok(Some(_))
-                                        )
-                                    {
-                                        result = false;
-                                        break;
-                                    }
-                                }
-                            }
-                            input = result.into();
-                        } else {
-                            input = false.into();
-                        }
-                    }
-                    Event::DuplicateId { id, expiry, last } => {
-                        let id_hash = SeenIdHash::new(&id, expiry + now);
-                        let seen_id = active_script.seen_ids.contains(&id_hash);
-                        if !seen_id || last {
-                            new_ids.insert(id_hash);
-                        }
-
-                        input = seen_id.into();
-                    }
-                    Event::Discard => {
-                        do_discard = true;
-                        input = true.into();
-                    }
-                    Event::Reject { reason, .. } => {
-                        reject_reason = reason.into();
-                        do_discard = true;
-                        input = true.into();
-                    }
-                    Event::Keep { flags, message_id } => {
-                        if let Some(message) = messages.get_mut(message_id) {
-                            message.flags =
-                                flags.into_iter().map(|f| Keyword::parse(&f).tag).collect();
-                            if !message.file_into.contains(&INBOX_ID) {
-                                message.file_into.push(INBOX_ID);
-                            }
-                            do_deliver = true;
-                        } else {
-                            error!("Sieve filter failed: Unknown message id {}.", message_id);
-                        }
-                        input = true.into();
-                    }
-                    Event::FileInto {
-                        folder,
-                        flags,
-                        mailbox_id,
-                        special_use,
-                        create,
-                        message_id,
-                    } => {
-                        let mut target_id = DocumentId::MAX;
-
-                        // Find mailbox by Id
-                        if let Some(mailbox_id) = mailbox_id.and_then(|m| JMAPId::parse(&m)) {
-                            let mailbox_id = mailbox_id.get_document_id();
-                            if mailbox_ids.contains(mailbox_id) {
-                                target_id = mailbox_id;
-                            }
-                        }
-
-                        // Find mailbox by role
-                        if let Some(special_use) = special_use {
-                            if target_id == DocumentId::MAX {
-                                if special_use.eq_ignore_ascii_case("inbox") {
-                                    target_id = INBOX_ID;
-                                } else if special_use.eq_ignore_ascii_case("trash") {
-                                    target_id = TRASH_ID;
-                                } else {
-                                    let role = special_use.to_ascii_lowercase();
-                                    if is_valid_role(&role) {
-                                        if let Ok(Some(mailbox_id_)) =
-                                            self.mailbox_get_by_role(account_id, &role)
-                                        {
-                                            target_id = mailbox_id_;
-                                        }
-                                    }
-                                }
-                            }
-                        }
-
-                        // Find mailbox by name
-                        if target_id == DocumentId::MAX {
-                            if !create {
-                                if let Ok(Some(document_id)) =
-                                    self.mailbox_get_by_name(account_id, &folder)
-                                {
-                                    target_id = document_id;
-                                }
-                            } else if let Ok(Some((document_id, changes))) =
-                                self.mailbox_create_path(account_id, &folder)
-                            {
-                                target_id = document_id;
-                                if let Some(changes) = changes {
-                                    result.last_change_id = changes.change_id;
-                                    result.changes.insert(account_id, changes);
-                                }
-                            }
-                        }
-
-                        // Default to Inbox
-                        if target_id == DocumentId::MAX {
-                            target_id = INBOX_ID;
-                        }
-
-                        if let Some(message) = messages.get_mut(message_id) {
-                            message.flags =
-                                flags.into_iter().map(|f| Keyword::parse(&f).tag).collect();
-                            if !message.file_into.contains(&target_id) {
-                                message.file_into.push(target_id);
-                            }
-                            do_deliver = true;
-                        } else {
-                            error!("Sieve filter failed: Unknown message id {}.", message_id);
-                        }
-                        input = true.into();
-                    }
-                    Event::SendMessage {
-                        recipient,
-                        message_id,
-                        ..
-                    } => {
-                        input = true.into();
-
-                        result.messages.push(OutgoingMessage {
-                            mail_from: mail_from.clone(),
-                            rcpt_to: match recipient {
-                                Recipient::Address(rcpt) => vec![rcpt],
-                                Recipient::Group(rcpts) => rcpts,
-                                Recipient::List(_) => {
-                                    // Not yet implemented
-                                    continue;
-                                }
-                            },
-                            message: if let Some(message) = messages.get(message_id) {
-                                message.raw_message.to_vec()
-                            } else {
-                                error!("Sieve filter failed: Unknown message id {}.", message_id);
-                                continue;
-                            },
-                        });
-                    }
-                    Event::ListContains { .. } | Event::Execute { .. } | Event::Notify { .. } => {
-                        // Not allowed
-                        input = false.into();
-                    }
-                    Event::CreatedMessage { message, .. } => {
-                        messages.push(SieveMessage {
-                            raw_message: message.into(),
-                            file_into: Vec::new(),
-                            flags: Vec::new(),
-                        });
-                        input = true.into();
-                    }
-                    #[allow(unreachable_patterns)]
-                    _ => unreachable!(),
-                },
-
-                #[cfg(test)]
-                Err(store::sieve::runtime::RuntimeError::ScriptErrorMessage(err)) => {
-                    panic!("Sieve test failed: {}", err);
-                }
-
-                Err(err) => {
-                    debug!("Sieve script runtime error: {}", err);
-                    input = true.into();
-                }
-            }
+        for action in std::mem::take(&mut outcome.actions) {
+            result.trace_sieve(account_id, &outcome.script_name, action);
+        }
+        for changes in std::mem::take(&mut outcome.changes) {
+            result.add_changes(account_id, changes);
         }
 
-        for (pos, message) in messages.iter().enumerate() {
-            println!(
-                "----- message {} {:?} {:?}",
-                pos, message.file_into, message.flags
-            );
+        // Redirect messages
+        for redirect in std::mem::take(&mut outcome.redirects) {
+            result.messages.push(OutgoingMessage {
+                mail_from: outcome.mail_from.clone(),
+                rcpt_to: redirect.rcpt_to,
+                message: outcome.messages[redirect.message_id].raw_message.to_vec(),
+                seal_domain: outcome.seal_domain.clone(),
+                auth: result.auth.clone(),
+            });
         }
+        let mut messages = outcome.messages;
 
-        // Fail-safe, no discard and no keep seen, assume that something went wrong and file anyway.
-        if !do_deliver && !do_discard {
-            messages[0].file_into.push(INBOX_ID);
+        // Quarantined messages are filed into Junk regardless of the script's fileinto actions
+        if is_quarantined && !messages[0].file_into.is_empty() {
+            messages[0].file_into = vec![keep_id];
         }
 
         // Deliver messages
@@ -809,16 +986,27 @@ where
                 };
 
                 // Parse message if needed
-                let message = if message_id == 0 && !instance.has_message_changed() {
-                    instance.take_message()
-                } else if let Some(message) = Message::parse(raw_message.as_ref()) {
-                    message
-                } else {
-                    debug!("Failed to parse Sieve generated message.");
-                    continue;
-                };
+                let message =
+                    if let Some(message) = outcome.message.take().filter(|_| message_id == 0) {
+                        message
+                    } else if let Some(message) =
+                        MimeLimits::from(&self.config).parse(raw_message.as_ref())
+                    {
+                        message
+                    } else {
+                        debug!("Failed to parse Sieve generated message.");
+                        continue;
+                    };
 
                 // Deliver message
+                let mut flags = sieve_message.flags;
+                if message_id == 0 {
+                    for tag in &auth_flags {
+                        if !flags.contains(tag) {
+                            flags.push(tag.clone());
+                        }
+                    }
+                }
                 if self
                     .mail_deliver_mailbox(
                         result,
@@ -826,7 +1014,12 @@ where
                         message,
                         &blob_id,
                         &sieve_message.file_into,
-                        sieve_message.flags,
+                        flags,
+                        if message_id == 0 {
+                            keep_thread_id
+                        } else {
+                            None
+                        },
                     )
                     .is_ok()
                 {
@@ -838,9 +1031,8 @@ where
         }
 
         // Save Sieve script changes
-        if active_script.has_changes || !new_ids.is_empty() {
-            drop(instance);
-            active_script.seen_ids.extend(new_ids);
+        if active_script.has_changes || !outcome.new_ids.is_empty() {
+            active_script.seen_ids.extend(outcome.new_ids);
             let mut changes = TinyORM::track_changes(&active_script.orm);
             changes.set(
                 jmap_sieve::sieve_script::schema::Property::SeenIds,
@@ -885,7 +1077,7 @@ where
             }
         }
 
-        if let Some(reject_reason) = reject_reason {
+        if let Some(reject_reason) = outcome.reject_reason {
             DeliveryStatus::PermanentFailure {
                 code: "5.7.1".into(),
                 reason: reject_reason.into(),
@@ -906,9 +1098,30 @@ where
         blob_id: &BlobId,
         mailbox_ids: &[DocumentId],
         flags: Vec<Tag>,
+        thread_id: Option<DocumentId>,
     ) -> Result<(), ()> {
-        // Prepare batch
-        let mut batch = WriteBatch::new(account_id);
+        self.mail_deliver_batch(
+            result,
+            WriteBatch::new(account_id),
+            message,
+            blob_id,
+            mailbox_ids,
+            flags,
+            thread_id,
+        )
+    }
+
+    fn mail_deliver_batch(
+        &self,
+        result: &mut IngestResult,
+        mut batch: WriteBatch,
+        message: Message,
+        blob_id: &BlobId,
+        mailbox_ids: &[DocumentId],
+        flags: Vec<Tag>,
+        thread_id: Option<DocumentId>,
+    ) -> Result<(), ()> {
+        let account_id = batch.account_id;
 
         // Obtain document id
         let document_id = match self.assign_document_id(account_id, Collection::Mail) {
@@ -937,24 +1150,51 @@ where
         }
 
         // Build message document
-        if let Err(err) = self.mail_parse_item(&mut document, blob_id.clone(), message, None) {
+        if let Err(err) =
+            self.mail_parse_item(account_id, &mut document, blob_id.clone(), message, None)
+        {
             error!("Failed to parse message during ingestion: {}", err);
             return Err(());
         }
 
+        // Link the blobs holding the contents of the parts detached by the server
+        for blob_id in &result.detached_parts {
+            document.blob(blob_id.clone(), IndexOptions::new());
+        }
+
         // Lock account while threads are merged
         let _lock = self.lock_collection(account_id, Collection::Mail);
 
-        // Obtain thread Id
-        match self.mail_set_thread(&mut batch, &mut document) {
+        // Obtain thread Id, unless the message has to be filed into an existing thread
+        match if let Some(thread_id) = thread_id {
+            batch.log_child_update(Collection::Thread, thread_id);
+            document.tag(
+                MessageField::ThreadId,
+                Tag::Id(thread_id),
+                IndexOptions::new(),
+            );
+            document.number(
+                MessageField::ThreadId,
+                thread_id,
+                IndexOptions::new().store(),
+            );
+            Ok(thread_id)
+        } else {
+            self.mail_set_thread(&mut batch, &mut document)
+        } {
             Ok(thread_id) => {
                 // Write document to store
-                batch.log_insert(Collection::Mail, JMAPId::from_parts(thread_id, document_id));
+                let id = JMAPId::from_parts(thread_id, document_id);
+                batch.log_insert(Collection::Mail, id);
                 batch.insert_document(document);
                 match self.write(batch) {
                     Ok(Some(changes)) => {
-                        result.last_change_id = changes.change_id;
-                        result.changes.insert(account_id, changes);
+                        result.trace(TraceEvent::Filed {
+                            account: account_id.into(),
+                            id,
+                            mailboxes: mailbox_ids.iter().map(|id| (*id).into()).collect(),
+                        });
+                        result.add_changes(account_id, changes);
                         Ok(())
                     }
                     Ok(None) => {
@@ -975,10 +1215,16 @@ where
     }
 }
 
-struct SieveMessage<'x> {
-    pub raw_message: Cow<'x, [u8]>,
-    pub file_into: Vec<DocumentId>,
-    pub flags: Vec<Tag>,
+fn has_loop_header(message: &Message, raw_message: &[u8], email: &str) -> bool {
+    message.parts.get(0).map_or(false, |part| {
+        part.headers.iter().any(|header| {
+            matches!(&header.name, HeaderName::Other(name) if name.eq_ignore_ascii_case("X-Loop"))
+                && raw_message
+                    .get(header.offset_start..header.offset_end)
+                    .and_then(|value| std::str::from_utf8(value).ok())
+                    .map_or(false, |value| value.trim().eq_ignore_ascii_case(email))
+        })
+    })
 }
 
 pub struct IngestResult {
@@ -986,6 +1232,50 @@ pub struct IngestResult {
     pub changes: AHashMap<AccountId, Changes>,
     pub last_change_id: ChangeId,
     pub messages: Vec<OutgoingMessage>,
+    pub detached_parts: Vec<BlobId>,
+    pub trace: Option<IngestTrace>,
+    pub auth: Option<Arc<AuthOutcome>>,
+}
+
+impl IngestResult {
+    fn add_changes(&mut self, account_id: AccountId, changes: Changes) {
+        self.last_change_id = changes.change_id;
+        match self.changes.entry(account_id) {
+            Entry::Occupied(mut entry) => {
+                let entry = entry.get_mut();
+                entry.collections.union(&changes.collections);
+                entry.change_id = changes.change_id;
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(changes);
+            }
+        }
+    }
+
+    pub fn trace(&mut self, event: TraceEvent) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(event);
+        }
+    }
+
+    fn trace_sieve(&mut self, account_id: AccountId, script: &str, action: String) {
+        self.trace(TraceEvent::Sieve {
+            account: account_id.into(),
+            script: script.to_string(),
+            action,
+        });
+    }
+
+    fn trace_status(&mut self, account_id: AccountId, status: &DeliveryStatus) {
+        match status {
+            DeliveryStatus::TemporaryFailure { reason }
+            | DeliveryStatus::PermanentFailure { reason, .. } => self.trace(TraceEvent::Failed {
+                account: account_id.into(),
+                reason: reason.to_string(),
+            }),
+            DeliveryStatus::Success | DeliveryStatus::Duplicated => (),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
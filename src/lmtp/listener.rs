@@ -29,7 +29,7 @@ use std::{
 
 use actix_web::web;
 use store::{
-    config::env_settings::EnvSettings,
+    config::{env_settings::EnvSettings, settings::Setting},
     tracing::{debug, error, info, warn},
     Store,
 };
@@ -37,43 +37,155 @@ use tokio::{io::AsyncWriteExt, net::TcpListener, sync::watch};
 use tokio_rustls::TlsAcceptor;
 
 use crate::{
-    cluster::rpc::tls::load_tls_server_config, lmtp::session::Session, server::failed_to,
+    authorization::proxy::read_proxy_header,
+    cluster::rpc::tls::load_tls_server_config,
+    lmtp::{authentication::AuthConfig, session::Session, smtp::SmtpConfig},
+    server::failed_to,
     JMAPServer,
 };
 
+pub const SETTINGS: &[Setting] = &[
+    Setting::ip_addr("lmtp-bind-addr").default("127.0.0.1"),
+    Setting::integer("lmtp-port")
+        .min(1)
+        .max(65535)
+        .default("11200"),
+    Setting::path("lmtp-cert-path"),
+    Setting::path("lmtp-key-path"),
+    Setting::bool("lmtp-tls-only").default("false"),
+    Setting::list("lmtp-trusted-ips").describe("Semicolon separated, all addresses when unset"),
+    Setting::bool("lmtp-proxy-protocol").default("false"),
+    Setting::integer("smtp-port")
+        .min(1)
+        .max(65535)
+        .describe("Inbound SMTP is only started when set"),
+    Setting::ip_addr("smtp-bind-addr").default("0.0.0.0"),
+    Setting::path("smtp-cert-path"),
+    Setting::path("smtp-key-path"),
+    Setting::bool("smtp-tls-only").default("false"),
+    Setting::list("smtp-trusted-ips").describe("Semicolon separated, all addresses when unset"),
+    Setting::bool("smtp-proxy-protocol").default("false"),
+    Setting::bool("smtp-auth").default("true"),
+];
+
 const TIMEOUT: Duration = Duration::from_secs(5 * 60); // 5 minutes
-const DEFAULT_LMTP_PORT: u16 = 11200;
+const PROXY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Lmtp,
+    Smtp,
+}
+
+impl Protocol {
+    fn name(&self) -> &'static str {
+        match self {
+            Protocol::Lmtp => "LMTP",
+            Protocol::Smtp => "SMTP",
+        }
+    }
+
+    fn key(&self, name: &str) -> String {
+        match self {
+            Protocol::Lmtp => format!("lmtp-{}", name),
+            Protocol::Smtp => format!("smtp-{}", name),
+        }
+    }
+}
 
 pub fn init_lmtp() -> (watch::Sender<bool>, watch::Receiver<bool>) {
     watch::channel::<bool>(true)
 }
 
 pub fn spawn_lmtp<T>(
+    core: web::Data<JMAPServer<T>>,
+    settings: &EnvSettings,
+    shutdown_rx: watch::Receiver<bool>,
+) where
+    T: for<'x> Store<'x> + 'static,
+{
+    let auth = if core.store.config.lmtp_auth {
+        Some(Arc::new(AuthConfig::parse(settings)))
+    } else {
+        None
+    };
+    spawn_listener(
+        core,
+        settings,
+        shutdown_rx,
+        Protocol::Lmtp,
+        SocketAddr::from((
+            settings.value::<IpAddr>(SETTINGS, "lmtp-bind-addr"),
+            settings.value::<u16>(SETTINGS, "lmtp-port"),
+        )),
+        auth,
+        None,
+    );
+}
+
+// The inbound SMTP listener is only started when a port is configured, for
+// deployments where the server acts as the MX without a fronting MTA.
+pub fn spawn_smtp<T>(
+    core: web::Data<JMAPServer<T>>,
+    settings: &EnvSettings,
+    shutdown_rx: watch::Receiver<bool>,
+) where
+    T: for<'x> Store<'x> + 'static,
+{
+    let port = match settings.get("smtp-port") {
+        Some(port) => port.parse::<u16>().unwrap_or_else(|_| {
+            failed_to(&format!("parse 'smtp-port', invalid port {}.", port));
+        }),
+        None => return,
+    };
+    let smtp = Arc::new(SmtpConfig::parse(settings, &core.store.config));
+    if core.store.config.srs_secret.is_empty() {
+        warn!("No 'srs-secret' or 'encryption-key' configured, bounces to SRS addresses will be rejected.");
+    }
+    let auth = if settings.value(SETTINGS, "smtp-auth") {
+        Some(Arc::new(AuthConfig::parse(settings)))
+    } else {
+        None
+    };
+    spawn_listener(
+        core,
+        settings,
+        shutdown_rx,
+        Protocol::Smtp,
+        SocketAddr::from((settings.value::<IpAddr>(SETTINGS, "smtp-bind-addr"), port)),
+        auth,
+        smtp.into(),
+    );
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_listener<T>(
     core: web::Data<JMAPServer<T>>,
     settings: &EnvSettings,
     mut shutdown_rx: watch::Receiver<bool>,
+    protocol: Protocol,
+    bind_addr: SocketAddr,
+    auth: Option<Arc<AuthConfig>>,
+    smtp: Option<Arc<SmtpConfig>>,
 ) where
     T: for<'x> Store<'x> + 'static,
 {
-    // Parse bind address
-    let bind_addr = SocketAddr::from((
-        settings.parse_ipaddr("lmtp-bind-addr", "127.0.0.1"),
-        settings.parse("lmtp-port").unwrap_or(DEFAULT_LMTP_PORT),
-    ));
-    info!("Starting LMTP service at {}...", bind_addr);
+    let name = protocol.name();
+    info!("Starting {} service at {}...", name, bind_addr);
 
     // Parse allowed IPs
-    let trusted_ips = if let Some(trusted_ips_) = settings.get("lmtp-trusted-ips") {
+    let trusted_ips_key = protocol.key("trusted-ips");
+    let trusted_ips = if let Some(trusted_ips_) = settings.get(&trusted_ips_key) {
         let mut trusted_ips = Vec::new();
         for ip in trusted_ips_.split(';') {
             trusted_ips.push(ip.parse::<IpAddr>().unwrap_or_else(|_| {
-                failed_to(&format!("parse 'lmtp-trusted-ips', invalid ip {}.", ip));
+                failed_to(&format!("parse '{}', invalid ip {}.", trusted_ips_key, ip));
             }));
         }
         if !trusted_ips.is_empty() {
             trusted_ips.into()
         } else {
-            failed_to("parse 'lmtp-trusted-ips', no entries found.");
+            failed_to(&format!("parse '{}', no entries found.", trusted_ips_key));
         }
     } else {
         None
@@ -81,8 +193,8 @@ pub fn spawn_lmtp<T>(
 
     // Build TLS acceptor
     let tls_acceptor = if let (Some(cert_path), Some(key_path)) = (
-        settings.get("lmtp-cert-path"),
-        settings.get("lmtp-key-path"),
+        settings.get(&protocol.key("cert-path")),
+        settings.get(&protocol.key("key-path")),
     ) {
         Arc::new(TlsAcceptor::from(Arc::new(load_tls_server_config(
             &cert_path, &key_path,
@@ -91,18 +203,25 @@ pub fn spawn_lmtp<T>(
     } else {
         None
     };
-    let mut tls_only = settings.parse("lmtp-tls-only").unwrap_or(false);
+    let proxy_protocol = settings.value(SETTINGS, &protocol.key("proxy-protocol"));
+    if proxy_protocol && core.trusted_proxies.is_empty() {
+        failed_to(&format!(
+            "enable '{}', no 'trusted-proxies' were configured.",
+            protocol.key("proxy-protocol")
+        ));
+    }
+    let mut tls_only = settings.value(SETTINGS, &protocol.key("tls-only"));
     if tls_only && tls_acceptor.is_none() {
-        warn!("LMTP server is configured to only accept TLS connections, but no TLS certificate was provided.");
+        warn!("{} server is configured to only accept TLS connections, but no TLS certificate was provided.", name);
         tls_only = false;
     }
 
     tokio::spawn(async move {
-        // Start listening for LMTP connections.
+        // Start listening for connections.
         let listener = match TcpListener::bind(bind_addr).await {
             Ok(listener) => listener,
             Err(err) => {
-                error!("Failed to bind LMTP service to {}: {}", bind_addr, err);
+                error!("Failed to bind {} service to {}: {}", name, bind_addr, err);
                 return;
             }
         };
@@ -116,11 +235,11 @@ pub fn spawn_lmtp<T>(
         let greeting = Arc::new(
             format!(
                 concat!(
-                    "220 {} Stalwart LMTP v",
+                    "220 {} Stalwart {} v",
                     env!("CARGO_PKG_VERSION"),
                     " at your service.\r\n"
                 ),
-                &hostname
+                &hostname, name
             )
             .into_bytes(),
         );
@@ -132,7 +251,7 @@ pub fn spawn_lmtp<T>(
                         Ok((mut stream, peer_addr)) => {
                             if let Some(trusted_ips) = &trusted_ips {
                                 if !trusted_ips.contains(&peer_addr.ip()) {
-                                    debug!("Dropping LMTP connection from unknow address {}.", peer_addr.ip());
+                                    debug!("Dropping {} connection from unknow address {}.", name, peer_addr.ip());
                                     continue;
                                 }
                             }
@@ -142,8 +261,32 @@ pub fn spawn_lmtp<T>(
                             let greeting = greeting.clone();
                             let tls_acceptor = tls_acceptor.clone();
                             let hostname = hostname.clone();
+                            let auth = auth.clone();
+                            let smtp = smtp.clone();
 
                             tokio::spawn(async move {
+                                // Obtain the client's address from the PROXY protocol header
+                                let peer_addr = if proxy_protocol {
+                                    if !core.trusted_proxies.is_trusted(&peer_addr.ip()) {
+                                        debug!("Dropping {} connection from untrusted proxy {}.", name, peer_addr.ip());
+                                        return;
+                                    }
+                                    match tokio::time::timeout(PROXY_TIMEOUT, read_proxy_header(&mut stream)).await {
+                                        Ok(Ok(Some(addr))) => addr,
+                                        Ok(Ok(None)) => peer_addr,
+                                        Ok(Err(err)) => {
+                                            debug!("Failed to read PROXY header from {}: {}", peer_addr, err);
+                                            return;
+                                        }
+                                        Err(_) => {
+                                            debug!("Timed out reading PROXY header from {}.", peer_addr);
+                                            return;
+                                        }
+                                    }
+                                } else {
+                                    peer_addr
+                                };
+
                                 if tls_only {
                                     let mut stream = match tls_acceptor.as_ref().unwrap().accept(stream).await {
                                         Ok(stream) => stream,
@@ -160,7 +303,7 @@ pub fn spawn_lmtp<T>(
                                     }
 
                                     handle_conn(
-                                        Session::new(core, peer_addr, stream.into(), None, hostname),
+                                        Session::new(core, peer_addr, stream.into(), None, hostname, auth, smtp),
                                         shutdown_rx
                                     ).await;
                                 } else {
@@ -171,7 +314,7 @@ pub fn spawn_lmtp<T>(
                                     }
 
                                     handle_conn(
-                                        Session::new(core, peer_addr, stream.into(), tls_acceptor, hostname),
+                                        Session::new(core, peer_addr, stream.into(), tls_acceptor, hostname, auth, smtp),
                                         shutdown_rx
                                     ).await;
                                 }
@@ -183,7 +326,7 @@ pub fn spawn_lmtp<T>(
                     }
                 },
                 _ = shutdown_rx.changed() => {
-                    debug!("LMTP listener shutting down.");
+                    debug!("{} listener shutting down.", name);
                     break;
                 }
             };
@@ -210,7 +353,7 @@ where
                                 return;
                             }
                         } else {
-                            debug!("LMTP connection closed by {}", session.peer_addr);
+                            debug!("Connection closed by {}", session.peer_addr);
                             break;
                         }
                     },
@@ -219,14 +362,14 @@ where
                     },
                     Err(_) => {
                         session.write_bytes(b"221 2.0.0 Disconnecting inactive client.\r\n").await.ok();
-                        debug!("LMTP connection timed out with {}.", session.peer_addr);
+                        debug!("Connection timed out with {}.", session.peer_addr);
                         break;
                     }
                 }
             },
             _ = shutdown_rx.changed() => {
                 session.write_bytes(b"421 4.3.0 Server shutting down.\r\n").await.ok();
-                debug!("LMTP connection with peer {} shutting down.", session.peer_addr);
+                debug!("Connection with peer {} shutting down.", session.peer_addr);
                 return;
             }
         };
@@ -21,14 +21,27 @@
  * for more details.
 */
 
+pub mod authentication;
 pub mod ingest;
 pub mod listener;
 pub mod request;
 pub mod response;
 pub mod session;
+pub mod sieve;
+pub mod smtp;
+pub mod srs;
+pub mod trace;
+
+use std::sync::Arc;
+
+use self::authentication::AuthOutcome;
 
 pub struct OutgoingMessage {
     pub mail_from: String,
     pub rcpt_to: Vec<String>,
     pub message: Vec<u8>,
+    // Domain whose key ARC-seals the message, for mail resent on behalf of an account
+    pub seal_domain: Option<String>,
+    // Authentication results computed when the message was received, recorded in the seal
+    pub auth: Option<Arc<AuthOutcome>>,
 }
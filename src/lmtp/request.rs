@@ -39,6 +39,12 @@ pub enum Request {
     Lhlo {
         domain: String,
     },
+    Ehlo {
+        domain: String,
+    },
+    Helo {
+        domain: String,
+    },
     Mail {
         sender: String,
         params: Vec<Param>,
@@ -123,11 +129,15 @@ impl RequestParser {
     }
 
     pub fn error_reset(&mut self, message: impl Into<Cow<'static, str>>) -> Event {
+        self.reset();
+        Event::parse_error(message)
+    }
+
+    pub fn reset(&mut self) {
         self.buf = Vec::with_capacity(10);
         self.state = State::Start;
         self.tokens.clear();
         self.command_size = 0;
-        Event::parse_error(message)
     }
 
     fn push_buf(&mut self) -> Result<(), Event> {
@@ -205,6 +215,24 @@ impl RequestParser {
                                     },
                                 )?,
                             }),
+                            "ehlo" => Ok(Request::Ehlo {
+                                domain: tokens.next().and_then(|t| t.unwrap_text()).ok_or_else(
+                                    || {
+                                        Event::parse_error(
+                                            "EHLO requires a domain name as argument.",
+                                        )
+                                    },
+                                )?,
+                            }),
+                            "helo" => Ok(Request::Helo {
+                                domain: tokens.next().and_then(|t| t.unwrap_text()).ok_or_else(
+                                    || {
+                                        Event::parse_error(
+                                            "HELO requires a domain name as argument.",
+                                        )
+                                    },
+                                )?,
+                            }),
                             "mail" => {
                                 if matches!(tokens.next(), Some(Token::Text(from)) if from == "from")
                                     && matches!(tokens.next(), Some(Token::Colon))
@@ -580,6 +608,17 @@ mod tests {
                     domain: "foo.edu".to_string(),
                 }],
             ),
+            (
+                vec!["EHLO foo.edu\r\n", "helo bar.edu\r\n"],
+                vec![
+                    Request::Ehlo {
+                        domain: "foo.edu".to_string(),
+                    },
+                    Request::Helo {
+                        domain: "bar.edu".to_string(),
+                    },
+                ],
+            ),
             (
                 vec![
                     "MAIL FROM:<chris@bar.com>\r\n",
@@ -35,9 +35,12 @@ use tokio_rustls::{server::TlsStream, TlsAcceptor};
 use crate::JMAPServer;
 
 use super::{
+    authentication::{spf, AuthConfig, AuthResult},
     ingest::DeliveryStatus,
     request::{Event, Param, Request, RequestParser},
     response::{Extension, Response},
+    smtp::SmtpConfig,
+    srs::{is_srs_address, srs_reverse},
 };
 
 const MAX_COMMAND_LENGTH: usize = 1024;
@@ -49,6 +52,8 @@ where
     pub core: web::Data<JMAPServer<T>>,
     pub tls_acceptor: Option<Arc<TlsAcceptor>>,
     pub hostname: Arc<String>,
+    pub auth: Option<Arc<AuthConfig>>,
+    pub smtp: Option<Arc<SmtpConfig>>,
     pub parser: RequestParser,
     pub peer_addr: SocketAddr,
     pub stream: Stream,
@@ -60,6 +65,8 @@ where
     pub rcpt_to: Vec<RcptType>,
     pub rcpt_to_dup: AHashSet<AccountId>,
     pub message: Vec<u8>,
+    pub policy_rejection: Option<String>,
+    pub num_messages: usize,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -74,6 +81,17 @@ pub enum RcptType {
         name: String,
         status: DeliveryStatus,
     },
+    Relay {
+        name: String,
+        address: String,
+        status: DeliveryStatus,
+    },
+    SubAddress {
+        id: AccountId,
+        name: String,
+        tag: String,
+        status: DeliveryStatus,
+    },
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -93,9 +111,17 @@ where
         stream: Stream,
         tls_acceptor: Option<Arc<TlsAcceptor>>,
         hostname: Arc<String>,
+        auth: Option<Arc<AuthConfig>>,
+        smtp: Option<Arc<SmtpConfig>>,
     ) -> Self {
         Self {
-            parser: RequestParser::new(MAX_COMMAND_LENGTH, core.store.config.mail_max_size),
+            parser: RequestParser::new(
+                MAX_COMMAND_LENGTH,
+                smtp.as_ref()
+                    .map_or(core.store.config.mail_max_size, |smtp| {
+                        smtp.max_message_size
+                    }),
+            ),
             tls_acceptor,
             peer_addr,
             stream,
@@ -106,7 +132,11 @@ where
             rcpt_to: Vec::new(),
             rcpt_to_dup: AHashSet::new(),
             message: Vec::new(),
+            policy_rejection: None,
+            num_messages: 0,
             hostname,
+            auth,
+            smtp,
         }
     }
 
@@ -116,64 +146,151 @@ where
         loop {
             match self.parser.parse(&mut bytes) {
                 Ok(request) => match request {
-                    Request::Lhlo { domain } => {
-                        let mut extensions = vec![
-                            Extension::EnhancedStatusCodes,
-                            Extension::Pipelining,
-                            Extension::Chunking,
-                            Extension::EightBitMime,
-                            Extension::BinaryMime,
-                            Extension::SmtpUtf8,
-                            Extension::Vrfy,
-                            Extension::Help,
-                            Extension::Size(self.core.store.config.mail_max_size as u32),
-                        ];
-                        if !self.stream.is_tls() {
-                            extensions.push(Extension::StartTls);
-                        }
+                    Request::Lhlo { domain } if self.smtp.is_none() => {
+                        self.handle_ehlo(domain).await?;
+                    }
+                    Request::Ehlo { domain } if self.smtp.is_some() => {
+                        self.handle_ehlo(domain).await?;
+                    }
+                    Request::Helo { domain } if self.smtp.is_some() => {
                         self.write_bytes(
-                            &Response::Lhlo {
-                                local_host: self.hostname.as_ref().into(),
-                                remote_host: domain.as_str().into(),
-                                extensions,
-                            }
-                            .into_bytes(),
+                            format!("250 {} welcomes {}\r\n", self.hostname, domain).as_bytes(),
                         )
                         .await?;
                         self.remote_hostname = domain.into();
                     }
-                    Request::Mail { sender, params } => {
-                        self.write_bytes(
-                            format!("250 2.1.0 Sender <{}> accepted.\r\n", sender).as_bytes(),
-                        )
+                    Request::Lhlo { .. } | Request::Ehlo { .. } | Request::Helo { .. } => {
+                        self.write_bytes(if self.smtp.is_some() {
+                            &b"500 5.5.1 Use EHLO or HELO.\r\n"[..]
+                        } else {
+                            &b"500 5.5.1 Use LHLO.\r\n"[..]
+                        })
                         .await?;
-                        self.mail_from = sender.into();
-                        self.mail_size = params.iter().find_map(|p| {
+                    }
+                    Request::Mail { .. }
+                        if self.smtp.is_some() && self.remote_hostname.is_none() =>
+                    {
+                        self.write_bytes(b"503 5.5.1 Polite people say EHLO first.\r\n")
+                            .await?;
+                    }
+                    Request::Mail { .. }
+                        if self
+                            .smtp
+                            .as_ref()
+                            .map_or(false, |smtp| self.num_messages >= smtp.max_messages) =>
+                    {
+                        self.write_bytes(b"421 4.7.0 Too many messages, closing connection.\r\n")
+                            .await?;
+                        return Err(());
+                    }
+                    Request::Mail { sender, params } => {
+                        let mail_size = params.iter().find_map(|p| {
                             if let Param::Size(size) = p {
                                 Some(*size as usize)
                             } else {
                                 None
                             }
                         });
+                        if mail_size.map_or(false, |size| size > self.parser.max_message_size) {
+                            self.write_bytes(
+                                format!(
+                                    "552 5.3.4 Message exceeds maximum size of {} bytes.\r\n",
+                                    self.parser.max_message_size
+                                )
+                                .as_bytes(),
+                            )
+                            .await?;
+                            continue;
+                        }
+                        self.policy_rejection = self.sender_policy(&sender).await;
+                        self.write_bytes(
+                            format!("250 2.1.0 Sender <{}> accepted.\r\n", sender).as_bytes(),
+                        )
+                        .await?;
+                        self.mail_from = sender.into();
+                        self.mail_size = mail_size;
+                    }
+                    Request::Rcpt { .. } if self.smtp.is_some() && self.mail_from.is_none() => {
+                        self.write_bytes(b"503 5.5.1 Missing MAIL FROM.\r\n")
+                            .await?;
+                    }
+                    Request::Rcpt { .. }
+                        if self
+                            .smtp
+                            .as_ref()
+                            .map_or(false, |smtp| self.rcpt_to.len() >= smtp.max_recipients) =>
+                    {
+                        self.write_bytes(b"452 4.5.3 Too many recipients.\r\n")
+                            .await?;
+                    }
+                    // Bounces are only relayed to SRS addresses signed with a secret
+                    Request::Rcpt { recipient, .. }
+                        if is_srs_recipient(&recipient)
+                            && !self.core.store.config.srs_secret.is_empty() =>
+                    {
+                        let config = &self.core.store.config;
+                        match srs_reverse(
+                            &recipient,
+                            &config.srs_secret,
+                            config.srs_max_age,
+                            config.srs_max_recursion,
+                        ) {
+                            Ok(_) if !self.mail_from.as_ref().map_or(true, |f| f.is_empty()) => {
+                                self.write_bytes(
+                                    b"550 5.7.1 Only bounces are accepted for this address.\r\n",
+                                )
+                                .await?;
+                            }
+                            Ok(address) => {
+                                self.write_bytes(
+                                    format!("250 2.1.5 Recipient <{}> accepted.\r\n", recipient)
+                                        .as_bytes(),
+                                )
+                                .await?;
+                                self.rcpt_to.push(RcptType::Relay {
+                                    name: recipient,
+                                    address,
+                                    status: DeliveryStatus::Success,
+                                });
+                            }
+                            Err(err) => {
+                                debug!("Rejected SRS address <{}>: {}", recipient, err);
+                                self.write_bytes(format!("550 5.1.1 {}.\r\n", err).as_bytes())
+                                    .await?;
+                            }
+                        }
                     }
                     Request::Rcpt { recipient, .. } => match self.expand_rcpt(&recipient).await {
                         Some(recipient_) => match recipient_.as_ref() {
-                            RecipientType::Individual(account_id) => {
+                            RecipientType::Individual(account_id)
+                            | RecipientType::SubAddress(account_id, _) => {
                                 self.write_bytes(
                                     format!("250 2.1.5 Recipient <{}> accepted.\r\n", recipient)
                                         .as_bytes(),
                                 )
                                 .await?;
 
-                                self.rcpt_to.push(RcptType::Mailbox {
-                                    id: *account_id,
-                                    name: recipient,
-                                    status: if self.rcpt_to_dup.insert(*account_id) {
-                                        DeliveryStatus::Success
+                                let status = if self.rcpt_to_dup.insert(*account_id) {
+                                    DeliveryStatus::Success
+                                } else {
+                                    DeliveryStatus::Duplicated
+                                };
+                                self.rcpt_to.push(
+                                    if let RecipientType::SubAddress(_, tag) = recipient_.as_ref() {
+                                        RcptType::SubAddress {
+                                            id: *account_id,
+                                            name: recipient,
+                                            tag: tag.to_string(),
+                                            status,
+                                        }
                                     } else {
-                                        DeliveryStatus::Duplicated
+                                        RcptType::Mailbox {
+                                            id: *account_id,
+                                            name: recipient,
+                                            status,
+                                        }
                                     },
-                                });
+                                );
                             }
                             RecipientType::List(account_ids) => {
                                 self.write_bytes(
@@ -212,8 +329,20 @@ where
                         self.message = data;
                         self.ingest_message().await?;
                     }
+                    Request::Bdat { is_last, .. } if self.policy_rejection.is_some() => {
+                        // Chunks are discarded until the last one, which gets the rejection
+                        self.message = Vec::new();
+                        if is_last {
+                            let rejection = self.policy_rejection.take().unwrap_or_default();
+                            self.write_bytes(rejection.as_bytes()).await?;
+                            self.reset_transaction();
+                        } else {
+                            self.write_bytes(b"250 2.1.0 Message chunk accepted.\r\n")
+                                .await?;
+                        }
+                    }
                     Request::Bdat { data, is_last } => {
-                        if self.message.len() + data.len() < self.core.store.config.mail_max_size {
+                        if self.message.len() + data.len() < self.parser.max_message_size {
                             if self.message.is_empty() {
                                 let rp = self.build_return_path();
                                 self.message = Vec::with_capacity(
@@ -234,16 +363,28 @@ where
                             self.write_bytes(
                                 format!(
                                     "500 5.3.4 Message exceeds maximum size of {} bytes.\r\n",
-                                    self.core.store.config.mail_max_size
+                                    self.parser.max_message_size
                                 )
                                 .as_bytes(),
                             )
                             .await?;
                         }
                     }
+                    // Directory lookups are not exposed to the public
+                    Request::Vrfy { .. } if self.smtp.is_some() => {
+                        self.write_bytes(
+                            b"252 2.5.0 Cannot VRFY user, but will accept message.\r\n",
+                        )
+                        .await?;
+                    }
+                    Request::Expn { .. } if self.smtp.is_some() => {
+                        self.write_bytes(b"502 5.5.1 EXPN is disabled.\r\n").await?;
+                    }
                     Request::Vrfy { mailbox } => match self.expand_rcpt(&mailbox).await {
                         Some(recipient_) => match recipient_.as_ref() {
-                            RecipientType::Individual(_) | RecipientType::List(_) => {
+                            RecipientType::Individual(_)
+                            | RecipientType::SubAddress(..)
+                            | RecipientType::List(_) => {
                                 self.write_bytes(
                                     format!("250 2.1.5 Mailbox <{}> exists.\r\n", mailbox)
                                         .as_bytes(),
@@ -275,7 +416,7 @@ where
                                 }
                                 self.write_bytes(&buf).await?;
                             }
-                            RecipientType::Individual(_) => {
+                            RecipientType::Individual(_) | RecipientType::SubAddress(..) => {
                                 self.write_bytes(
                                     format!("550 5.1.0 Address <{}> exists but is not a mailing list.\r\n", list)
                                         .as_bytes(),
@@ -328,11 +469,7 @@ where
                         }
                     },
                     Request::Rset => {
-                        self.mail_from = None;
-                        self.mail_size = None;
-                        self.rcpt_to.clear();
-                        self.rcpt_to_dup.clear();
-                        self.message = Vec::new();
+                        self.reset_transaction();
                         self.write_bytes(b"250 2.0.0 OK\r\n").await?;
                     }
                     Request::Noop => {
@@ -346,8 +483,20 @@ where
                 Err(Event::NeedsMoreBytes) => {
                     break;
                 }
+                Err(Event::Data) if self.policy_rejection.is_some() => {
+                    // Reject the message before its contents are transferred
+                    self.parser.reset();
+                    let rejection = self.policy_rejection.take().unwrap_or_default();
+                    self.write_bytes(rejection.as_bytes()).await?;
+                    self.reset_transaction();
+                }
                 Err(Event::Data) => {
-                    if !self.rcpt_to_dup.is_empty() {
+                    if !self.rcpt_to_dup.is_empty()
+                        || self
+                            .rcpt_to
+                            .iter()
+                            .any(|rcpt| matches!(rcpt, RcptType::Relay { .. }))
+                    {
                         let rp = self.build_return_path();
                         self.parser.buf =
                             Vec::with_capacity(self.mail_size.unwrap_or(1024) + rp.len());
@@ -357,6 +506,7 @@ where
                         )
                         .await?;
                     } else {
+                        self.parser.reset();
                         self.write_bytes(b"503 5.5.1 Missing RCPT TO.\r\n").await?;
                     }
                 }
@@ -369,6 +519,69 @@ where
         Ok(())
     }
 
+    async fn handle_ehlo(&mut self, domain: String) -> Result<(), ()> {
+        let mut extensions = vec![
+            Extension::EnhancedStatusCodes,
+            Extension::Pipelining,
+            Extension::Chunking,
+            Extension::EightBitMime,
+            Extension::BinaryMime,
+            Extension::SmtpUtf8,
+        ];
+        if self.smtp.is_none() {
+            extensions.push(Extension::Vrfy);
+        }
+        extensions.push(Extension::Help);
+        extensions.push(Extension::Size(self.parser.max_message_size as u32));
+        if !self.stream.is_tls() && self.tls_acceptor.is_some() {
+            extensions.push(Extension::StartTls);
+        }
+        self.write_bytes(
+            &Response::Lhlo {
+                local_host: self.hostname.as_ref().into(),
+                remote_host: domain.as_str().into(),
+                extensions,
+            }
+            .into_bytes(),
+        )
+        .await?;
+        self.remote_hostname = domain.into();
+        Ok(())
+    }
+
+    // Evaluates the sender's SPF policy on SMTP sessions, returning the reply
+    // the message will be rejected with once the client attempts to send it.
+    async fn sender_policy(&self, sender: &str) -> Option<String> {
+        if !self.smtp.as_ref()?.spf_reject {
+            return None;
+        }
+        let (domain, result) = spf::verify(
+            &self.core.dns,
+            self.peer_addr.ip(),
+            self.remote_hostname.as_deref().unwrap_or_default(),
+            sender,
+        )
+        .await;
+        if result == AuthResult::Fail {
+            debug!(
+                "Sender <{}> from {} failed SPF validation.",
+                sender, self.peer_addr
+            );
+            format!("550 5.7.23 SPF validation failed for {}.\r\n", domain).into()
+        } else {
+            None
+        }
+    }
+
+    fn reset_transaction(&mut self) {
+        self.mail_from = None;
+        self.mail_size = None;
+        self.rcpt_to.clear();
+        self.rcpt_to_dup.clear();
+        self.message = Vec::new();
+        self.policy_rejection = None;
+    }
+
     pub async fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), ()> {
         match &mut self.stream {
             Stream::Clear(stream) => stream.write_all(bytes).await.map_err(|err| {
@@ -397,12 +610,17 @@ where
         format!(
             concat!(
                 "Received: from {} ([{}])\r\n",
-                "\tby {} (Stalwart JMAP) with LMTP;\r\n",
+                "\tby {} (Stalwart JMAP) with {};\r\n",
                 "\t{}\r\n"
             ),
             self.remote_hostname.as_deref().unwrap_or("unknown"),
-            self.hostname.as_ref(),
             self.peer_addr.ip(),
+            self.hostname.as_ref(),
+            match (&self.smtp, self.stream.is_tls()) {
+                (None, _) => "LMTP",
+                (Some(_), false) => "ESMTP",
+                (Some(_), true) => "ESMTPS",
+            },
             Local::now().to_rfc2822()
         )
     }
@@ -438,3 +656,9 @@ impl Default for Stream {
         Stream::None
     }
 }
+
+fn is_srs_recipient(recipient: &str) -> bool {
+    recipient
+        .rsplit_once('@')
+        .map_or(false, |(local_part, _)| is_srs_address(local_part))
+}
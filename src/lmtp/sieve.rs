@@ -0,0 +1,532 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{borrow::Cow, time::SystemTime};
+
+use jmap::types::jmap::JMAPId;
+use jmap_mail::{
+    mail::schema::Keyword,
+    mail_parser::{HeaderName, Message},
+    mailbox::{get::JMAPGetMailbox, is_valid_role, set::JMAPSetMailbox},
+    INBOX_ID, TRASH_ID,
+};
+use jmap_sharing::principal::account::JMAPAccountStore;
+use jmap_sieve::{
+    sieve_script::{get::ActiveScript, get::JMAPGetSieveScript, schema::Value},
+    SeenIdHash,
+};
+use store::{
+    ahash::AHashSet,
+    core::tag::Tag,
+    roaring::RoaringBitmap,
+    sieve::{Envelope, Event, Input, Mailbox, Recipient},
+    tracing::{debug, error},
+    write::update::Changes,
+    AccountId, DocumentId, JMAPStore, Store,
+};
+
+pub struct SieveMessage<'x> {
+    pub raw_message: Cow<'x, [u8]>,
+    pub file_into: Vec<DocumentId>,
+    pub flags: Vec<Tag>,
+}
+
+pub struct SieveRedirect {
+    pub rcpt_to: Vec<String>,
+    pub message_id: usize,
+}
+
+pub struct SieveEnvelope<'x> {
+    pub mail_from: &'x str,
+    pub rcpt_to: &'x str,
+    pub mailbox_ids: &'x RoaringBitmap,
+    pub keep_id: DocumentId,
+}
+
+pub struct SieveOutcome<'x> {
+    pub script_name: String,
+    pub actions: Vec<String>,
+    pub messages: Vec<SieveMessage<'x>>,
+    // Original message, returned when the script did not modify it
+    pub message: Option<Message<'x>>,
+    pub redirects: Vec<SieveRedirect>,
+    pub mail_from: String,
+    // Redirected messages are only sealed with the key of the account's own domain
+    pub seal_domain: Option<String>,
+    pub new_ids: AHashSet<SeenIdHash>,
+    pub reject_reason: Option<String>,
+    pub changes: Vec<Changes>,
+}
+
+pub trait JMAPSieveRun {
+    /// Runs the active script on a message and returns the actions to perform. Mailboxes
+    /// are only created when not in a dry run, the caller performs all other actions.
+    fn sieve_run<'x>(
+        &self,
+        account_id: AccountId,
+        active_script: &ActiveScript,
+        raw_message: &'x [u8],
+        message: Message<'x>,
+        envelope: SieveEnvelope,
+        dry_run: bool,
+    ) -> SieveOutcome<'x>;
+}
+
+impl<T> JMAPSieveRun for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn sieve_run<'x>(
+        &self,
+        account_id: AccountId,
+        active_script: &ActiveScript,
+        raw_message: &'x [u8],
+        message: Message<'x>,
+        envelope: SieveEnvelope,
+        dry_run: bool,
+    ) -> SieveOutcome<'x> {
+        // Auto-replies to automated and mailing list messages are dropped, this is
+        // enforced here so that previously generated vacation scripts are covered too.
+        let is_automated = is_automated_message(&message, raw_message);
+        let mut instance = self.sieve_runtime.filter_parsed(message);
+
+        // Set account details
+        let (mail_from, seal_domain) = match self.get_account_details(account_id) {
+            Ok(Some((email, name, _))) => {
+                let mail_from = email.clone();
+                let seal_domain = email.rsplit_once('@').map(|(_, domain)| domain.to_string());
+                instance.set_user_address(email);
+                instance.set_user_full_name(&name);
+                (mail_from, seal_domain)
+            }
+            _ => {
+                error!("Failed to obtain account details for {}.", account_id);
+                instance.set_user_address(envelope.rcpt_to.to_string());
+                (envelope.rcpt_to.to_string(), None)
+            }
+        };
+
+        // Set envelope
+        instance.set_envelope(Envelope::From, envelope.mail_from);
+        instance.set_envelope(Envelope::To, envelope.rcpt_to);
+
+        let script_name = if let Some(Value::Text { value }) = active_script
+            .orm
+            .get(&jmap_sieve::sieve_script::schema::Property::Name)
+        {
+            value.to_string()
+        } else {
+            account_id.to_string()
+        };
+        let mut input = Input::script(script_name.clone(), active_script.script.clone());
+
+        let mut do_discard = false;
+        let mut do_deliver = false;
+
+        let mut outcome = SieveOutcome {
+            script_name,
+            actions: Vec::new(),
+            messages: vec![SieveMessage {
+                raw_message: raw_message.into(),
+                file_into: Vec::new(),
+                flags: Vec::new(),
+            }],
+            message: None,
+            redirects: Vec::new(),
+            mail_from,
+            seal_domain,
+            new_ids: AHashSet::new(),
+            reject_reason: None,
+            changes: Vec::new(),
+        };
+        let mailbox_ids = envelope.mailbox_ids;
+        let keep_id = envelope.keep_id;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        while let Some(event) = instance.run(input) {
+            match event {
+                Ok(event) => match event {
+                    Event::IncludeScript { name, .. } => {
+                        if let Ok(Some(script)) =
+                            self.sieve_script_get_by_name(account_id, name.as_str().to_string())
+                        {
+                            input = Input::script(name, script);
+                        } else {
+                            input = false.into();
+                        }
+                    }
+                    Event::MailboxExists {
+                        mailboxes,
+                        special_use,
+                    } => {
+                        if !mailboxes.is_empty() {
+                            let special_use = special_use
+                                .into_iter()
+                                .map(|role| {
+                                    if role.eq_ignore_ascii_case("inbox") {
+                                        INBOX_ID
+                                    } else if role.eq_ignore_ascii_case("trash") {
+                                        TRASH_ID
+                                    } else {
+                                        let mut mailbox_id = DocumentId::MAX;
+                                        let role = role.to_ascii_lowercase();
+                                        if is_valid_role(&role) {
+                                            if let Ok(Some(mailbox_id_)) =
+                                                self.mailbox_get_by_role(account_id, &role)
+                                            {
+                                                mailbox_id = mailbox_id_;
+                                            }
+                                        }
+                                        mailbox_id
+                                    }
+                                })
+                                .collect::<Vec<_>>();
+
+                            let mut result = true;
+                            for mailbox in mailboxes {
+                                match mailbox {
+                                    Mailbox::Name(name) => {
+                                        if !matches!(
+                                            self.mailbox_get_by_name(account_id, &name),
+                                            Ok(Some(document_id)) if special_use.is_empty() ||
+                                                        special_use.contains(&document_id)
+                                        ) {
+                                            result = false;
+                                            break;
+                                        }
+                                    }
+                                    Mailbox::Id(id) => {
+                                        if !matches!(JMAPId::parse(&id), Some(id) if
+                                                            mailbox_ids.contains(id.get_document_id()) &&
+                                                            (special_use.is_empty() ||
+                                                             special_use.contains(&id.get_document_id())))
+                                        {
+                                            result = false;
+                                            break;
+                                        }
+                                    }
+                                }
+                            }
+                            input = result.into();
+                        } else if !special_use.is_empty() {
+                            let mut result = true;
+
+                            for role in special_use {
+                                if !role.eq_ignore_ascii_case("inbox")
+                                    && !role.eq_ignore_ascii_case("trash")
+                                {
+                                    let role = role.to_ascii_lowercase();
+                                    if !is_valid_role(&role)
+                                        || !matches!(
+                                            self.mailbox_get_by_role(account_id, &role),
+                                            Ok(Some(_))
+                                        )
+                                    {
+                                        result = false;
+                                        break;
+                                    }
+                                }
+                            }
+                            input = result.into();
+                        } else {
+                            input = false.into();
+                        }
+                    }
+                    Event::DuplicateId { id, expiry, last } => {
+                        let id_hash = SeenIdHash::new(&id, expiry + now);
+                        let seen_id = active_script.seen_ids.contains(&id_hash);
+                        if !seen_id || last {
+                            outcome.new_ids.insert(id_hash);
+                        }
+
+                        input = seen_id.into();
+                    }
+                    Event::Discard => {
+                        outcome.actions.push("discard".to_string());
+                        do_discard = true;
+                        input = true.into();
+                    }
+                    Event::Reject { reason, .. } => {
+                        outcome.actions.push(format!("reject {}", reason));
+                        outcome.reject_reason = reason.into();
+                        do_discard = true;
+                        input = true.into();
+                    }
+                    Event::Keep { flags, message_id } => {
+                        outcome.actions.push("keep".to_string());
+                        if let Some(message) = outcome.messages.get_mut(message_id) {
+                            message.flags = sieve_flags(flags);
+                            if !message.file_into.contains(&keep_id) {
+                                message.file_into.push(keep_id);
+                            }
+                            do_deliver = true;
+                        } else {
+                            error!("Sieve filter failed: Unknown message id {}.", message_id);
+                        }
+                        input = true.into();
+                    }
+                    Event::FileInto {
+                        folder,
+                        flags,
+                        mailbox_id,
+                        special_use,
+                        create,
+                        message_id,
+                    } => {
+                        let mut target_id = DocumentId::MAX;
+
+                        // Find mailbox by Id
+                        if let Some(mailbox_id) = mailbox_id.and_then(|m| JMAPId::parse(&m)) {
+                            let mailbox_id = mailbox_id.get_document_id();
+                            if mailbox_ids.contains(mailbox_id) {
+                                target_id = mailbox_id;
+                            }
+                        }
+
+                        // Find mailbox by role
+                        if let Some(special_use) = special_use {
+                            if target_id == DocumentId::MAX {
+                                if special_use.eq_ignore_ascii_case("inbox") {
+                                    target_id = INBOX_ID;
+                                } else if special_use.eq_ignore_ascii_case("trash") {
+                                    target_id = TRASH_ID;
+                                } else {
+                                    let role = special_use.to_ascii_lowercase();
+                                    if is_valid_role(&role) {
+                                        if let Ok(Some(mailbox_id_)) =
+                                            self.mailbox_get_by_role(account_id, &role)
+                                        {
+                                            target_id = mailbox_id_;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+
+                        // Find mailbox by name, creating it if requested
+                        let mut action = format!("fileinto {}", folder);
+                        if target_id == DocumentId::MAX {
+                            if let Ok(Some(document_id)) =
+                                self.mailbox_get_by_name(account_id, &folder)
+                            {
+                                target_id = document_id;
+                            } else if create && dry_run {
+                                action.push_str(" (would be created)");
+                            } else if create {
+                                if let Ok(Some((document_id, changes))) =
+                                    self.mailbox_create_path(account_id, &folder)
+                                {
+                                    target_id = document_id;
+                                    if let Some(changes) = changes {
+                                        outcome.changes.push(changes);
+                                    }
+                                }
+                            }
+
+                            // Default to Inbox
+                            if target_id == DocumentId::MAX && !(create && dry_run) {
+                                action.push_str(" (not found, using Inbox)");
+                                target_id = INBOX_ID;
+                            }
+                        }
+                        outcome.actions.push(action);
+
+                        if let Some(message) = outcome.messages.get_mut(message_id) {
+                            message.flags = sieve_flags(flags);
+                            if !message.file_into.contains(&target_id) {
+                                message.file_into.push(target_id);
+                            }
+                            do_deliver = true;
+                        } else {
+                            error!("Sieve filter failed: Unknown message id {}.", message_id);
+                        }
+                        input = true.into();
+                    }
+                    Event::SendMessage {
+                        recipient,
+                        message_id,
+                        ..
+                    } => {
+                        input = true.into();
+
+                        let rcpt_to = match recipient {
+                            Recipient::Address(rcpt) => vec![rcpt],
+                            Recipient::Group(rcpts) => rcpts,
+                            Recipient::List(_) => {
+                                // Not yet implemented
+                                continue;
+                            }
+                        };
+                        if message_id < outcome.messages.len() {
+                            if is_automated
+                                && message_id > 0
+                                && is_auto_reply(&outcome.messages[message_id].raw_message)
+                            {
+                                outcome
+                                    .actions
+                                    .push("vacation suppressed (automated message)".to_string());
+                                continue;
+                            }
+                            outcome
+                                .actions
+                                .push(format!("redirect to {}", rcpt_to.join(", ")));
+                            outcome.redirects.push(SieveRedirect {
+                                rcpt_to,
+                                message_id,
+                            });
+                        } else {
+                            error!("Sieve filter failed: Unknown message id {}.", message_id);
+                        }
+                    }
+                    Event::ListContains { .. } | Event::Execute { .. } | Event::Notify { .. } => {
+                        // Not allowed
+                        input = false.into();
+                    }
+                    Event::CreatedMessage { message, .. } => {
+                        outcome.messages.push(SieveMessage {
+                            raw_message: message.into(),
+                            file_into: Vec::new(),
+                            flags: Vec::new(),
+                        });
+                        input = true.into();
+                    }
+                    #[allow(unreachable_patterns)]
+                    _ => unreachable!(),
+                },
+
+                #[cfg(test)]
+                Err(store::sieve::runtime::RuntimeError::ScriptErrorMessage(err)) if !dry_run => {
+                    panic!("Sieve test failed: {}", err);
+                }
+
+                Err(err) => {
+                    debug!("Sieve script runtime error: {}", err);
+                    outcome.actions.push(format!("runtime error: {}", err));
+                    input = true.into();
+                }
+            }
+        }
+
+        // Fail-safe, no discard and no keep seen, assume that something went wrong and file anyway.
+        if !do_deliver && !do_discard {
+            outcome.messages[0].file_into.push(keep_id);
+        }
+
+        if !instance.has_message_changed() {
+            outcome.message = instance.take_message().into();
+        }
+
+        outcome
+    }
+}
+
+pub fn sieve_flags(flags: Vec<String>) -> Vec<Tag> {
+    let mut tags = Vec::with_capacity(flags.len());
+    for flag in flags {
+        let tag = Keyword::from_sieve_flag(&flag).tag;
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+fn has_header(
+    message: &Message,
+    raw_message: &[u8],
+    name: &str,
+    matches: impl Fn(&str) -> bool,
+) -> bool {
+    message.parts.get(0).map_or(false, |part| {
+        part.headers.iter().any(|header| {
+            match &header.name {
+                HeaderName::Rfc(rfc) => rfc.as_str().eq_ignore_ascii_case(name),
+                HeaderName::Other(other) => other.eq_ignore_ascii_case(name),
+            }
+            &&raw_message
+                .get(header.offset_start..header.offset_end)
+                .and_then(|value| std::str::from_utf8(value).ok())
+                .map_or(false, |value| matches(value.trim()))
+        })
+    })
+}
+
+fn is_automated_message(message: &Message, raw_message: &[u8]) -> bool {
+    has_header(message, raw_message, "List-Id", |_| true)
+        || has_header(message, raw_message, "Precedence", |value| {
+            ["bulk", "list", "junk"]
+                .iter()
+                .any(|precedence| value.eq_ignore_ascii_case(precedence))
+        })
+        || has_header(message, raw_message, "Auto-Submitted", |value| {
+            !value.eq_ignore_ascii_case("no")
+        })
+}
+
+fn is_auto_reply(raw_message: &[u8]) -> bool {
+    Message::parse(raw_message).map_or(false, |message| {
+        has_header(&message, raw_message, "Auto-Submitted", |value| {
+            value.eq_ignore_ascii_case("auto-replied")
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use jmap_mail::mail_parser::Message;
+
+    use super::{is_auto_reply, is_automated_message};
+
+    #[test]
+    fn automated_messages() {
+        for (header, expected) in [
+            ("List-Id: <dev.example.com>", true),
+            ("Precedence: bulk", true),
+            ("Precedence: Junk", true),
+            ("Precedence: first-class", false),
+            ("Auto-Submitted: auto-generated", true),
+            ("Auto-Submitted: no", false),
+            ("X-Mailer: test", false),
+        ] {
+            let raw_message = format!(
+                "From: a@example.com\r\n{}\r\nSubject: test\r\n\r\nbody\r\n",
+                header
+            );
+            let message = Message::parse(raw_message.as_bytes()).unwrap();
+            assert_eq!(
+                is_automated_message(&message, raw_message.as_bytes()),
+                expected,
+                "{}",
+                header
+            );
+        }
+
+        assert!(is_auto_reply(
+            b"From: a@example.com\r\nAuto-Submitted: auto-replied\r\n\r\nbody\r\n"
+        ));
+        assert!(!is_auto_reply(b"From: a@example.com\r\n\r\nbody\r\n"));
+    }
+}
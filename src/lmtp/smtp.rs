@@ -0,0 +1,312 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use store::{
+    chrono::Local,
+    config::{env_settings::EnvSettings, jmap::JMAPConfig, settings::Setting},
+    rand,
+};
+
+use super::{ingest::DeliveryStatus, session::RcptType, OutgoingMessage};
+
+pub const MAX_REPORT_HEADERS: usize = 32 * 1024;
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::bytes("smtp-max-message-size").describe("Capped at mail-max-size"),
+    Setting::integer("smtp-max-recipients").default("100"),
+    Setting::integer("smtp-max-messages")
+        .default("50")
+        .describe("Per connection"),
+    Setting::bool("smtp-spf-reject")
+        .default("false")
+        .describe("Reject messages failing SPF with 550 5.7.23"),
+];
+
+pub struct SmtpConfig {
+    pub max_message_size: usize,
+    pub max_recipients: usize,
+    pub max_messages: usize,
+    pub spf_reject: bool,
+}
+
+impl SmtpConfig {
+    pub fn parse(settings: &EnvSettings, config: &JMAPConfig) -> Self {
+        SmtpConfig {
+            max_message_size: settings
+                .parse("smtp-max-message-size")
+                .unwrap_or(config.mail_max_size)
+                .min(config.mail_max_size),
+            max_recipients: settings.value(SETTINGS, "smtp-max-recipients"),
+            max_messages: settings.value(SETTINGS, "smtp-max-messages"),
+            spf_reject: settings.value(SETTINGS, "smtp-spf-reject"),
+        }
+    }
+}
+
+// Unlike LMTP, SMTP has a single reply per message. The message is accepted if it
+// was delivered to at least one recipient, in which case the remaining failures are
+// reported to the sender by delivery_report. Otherwise the first failure is returned,
+// temporary failures taking precedence so that the sender retries.
+pub fn delivery_response(rcpt_to: &[RcptType]) -> Vec<u8> {
+    let mut temp_failure = None;
+    let mut perm_failure = None;
+
+    for rcpt in rcpt_to {
+        let (RcptType::Mailbox { name, status, .. }
+        | RcptType::List { name, status, .. }
+        | RcptType::Relay { name, status, .. }
+        | RcptType::SubAddress { name, status, .. }) = rcpt;
+        match status {
+            DeliveryStatus::Success => {
+                return b"250 2.0.0 Message accepted for delivery.\r\n".to_vec();
+            }
+            DeliveryStatus::TemporaryFailure { reason } if temp_failure.is_none() => {
+                temp_failure = format!("451 4.3.0 <{}> {}\r\n", name, reason).into();
+            }
+            DeliveryStatus::PermanentFailure { code, reason } if perm_failure.is_none() => {
+                perm_failure = format!("550 {} <{}> {}\r\n", code, name, reason).into();
+            }
+            _ => (),
+        }
+    }
+
+    temp_failure
+        .or(perm_failure)
+        .map(|response| response.into_bytes())
+        .unwrap_or_else(|| b"250 2.0.0 Message accepted for delivery.\r\n".to_vec())
+}
+
+// Builds a delivery status notification (RFC 3464) listing the recipients a partially
+// delivered message could not be delivered to. Temporary failures are final as the
+// message was accepted and will not be retried.
+pub fn delivery_report(
+    hostname: &str,
+    mail_from: &str,
+    rcpt_to: &[RcptType],
+    message: &[u8],
+) -> Option<OutgoingMessage> {
+    if mail_from.is_empty() {
+        return None;
+    }
+
+    let mut has_success = false;
+    let mut failures = Vec::new();
+    for rcpt in rcpt_to {
+        let (RcptType::Mailbox { name, status, .. }
+        | RcptType::List { name, status, .. }
+        | RcptType::Relay { name, status, .. }
+        | RcptType::SubAddress { name, status, .. }) = rcpt;
+        match status {
+            DeliveryStatus::Success => has_success = true,
+            DeliveryStatus::TemporaryFailure { reason } => {
+                failures.push((name, "4.3.0", reason));
+            }
+            DeliveryStatus::PermanentFailure { code, reason } => {
+                failures.push((name, code.as_ref(), reason));
+            }
+            DeliveryStatus::Duplicated => (),
+        }
+    }
+    if !has_success || failures.is_empty() {
+        return None;
+    }
+
+    let boundary = format!("{:016x}", rand::random::<u64>());
+    let mut report = format!(
+        concat!(
+            "From: Mail Delivery Subsystem <MAILER-DAEMON@{}>\r\n",
+            "To: <{}>\r\n",
+            "Subject: Undelivered Mail Returned to Sender\r\n",
+            "Date: {}\r\n",
+            "Message-ID: <{}.{}@{}>\r\n",
+            "Auto-Submitted: auto-replied\r\n",
+            "MIME-Version: 1.0\r\n",
+            "Content-Type: multipart/report; report-type=delivery-status; ",
+            "boundary=\"{}\"\r\n\r\n",
+            "--{}\r\n",
+            "Content-Type: text/plain; charset=utf-8\r\n\r\n",
+            "Your message could not be delivered to the following recipients:\r\n\r\n"
+        ),
+        hostname,
+        mail_from,
+        Local::now().to_rfc2822(),
+        boundary,
+        rand::random::<u32>(),
+        hostname,
+        boundary,
+        boundary,
+    );
+    for (name, _, reason) in &failures {
+        report.push_str(&format!("<{}>: {}\r\n", name, reason));
+    }
+    report.push_str(&format!(
+        concat!(
+            "\r\n--{}\r\n",
+            "Content-Type: message/delivery-status\r\n\r\n",
+            "Reporting-MTA: dns; {}\r\n"
+        ),
+        boundary, hostname
+    ));
+    for (name, code, reason) in &failures {
+        report.push_str(&format!(
+            concat!(
+                "\r\nFinal-Recipient: rfc822; {}\r\n",
+                "Action: failed\r\n",
+                "Status: {}\r\n",
+                "Diagnostic-Code: smtp; {} {} {}\r\n"
+            ),
+            name,
+            code,
+            if code.starts_with('4') { "451" } else { "550" },
+            code,
+            reason
+        ));
+    }
+    report.push_str(&format!(
+        "\r\n--{}\r\nContent-Type: text/rfc822-headers\r\n\r\n",
+        boundary
+    ));
+    let mut report = report.into_bytes();
+    let headers_len = message
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(message.len(), |pos| pos + 2)
+        .min(MAX_REPORT_HEADERS);
+    report.extend_from_slice(&message[..headers_len]);
+    if !report.ends_with(b"\r\n") {
+        report.extend_from_slice(b"\r\n");
+    }
+    report.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    Some(OutgoingMessage {
+        mail_from: String::new(),
+        rcpt_to: vec![mail_from.to_string()],
+        message: report,
+        seal_domain: None,
+        auth: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lmtp::{ingest::DeliveryStatus, session::RcptType};
+
+    use super::{delivery_report, delivery_response};
+
+    #[test]
+    fn smtp_delivery_response() {
+        let rcpt = |name: &str, status: DeliveryStatus| RcptType::Relay {
+            name: name.to_string(),
+            address: name.to_string(),
+            status,
+        };
+
+        for (rcpt_to, expected_response) in [
+            (
+                vec![
+                    rcpt("a@foo.org", DeliveryStatus::perm_failure("Mailbox full")),
+                    rcpt("b@foo.org", DeliveryStatus::Success),
+                ],
+                "250 2.0.0 Message accepted for delivery.\r\n",
+            ),
+            (
+                vec![
+                    rcpt("a@foo.org", DeliveryStatus::perm_failure("Mailbox full")),
+                    rcpt("b@foo.org", DeliveryStatus::internal_error()),
+                ],
+                "451 4.3.0 <b@foo.org> Temporary sever failure\r\n",
+            ),
+            (
+                vec![
+                    rcpt("a@foo.org", DeliveryStatus::Duplicated),
+                    rcpt("b@foo.org", DeliveryStatus::perm_failure("Mailbox full")),
+                ],
+                "550 5.5.0 <b@foo.org> Mailbox full\r\n",
+            ),
+        ] {
+            assert_eq!(
+                String::from_utf8(delivery_response(&rcpt_to)).unwrap(),
+                expected_response
+            );
+        }
+    }
+
+    #[test]
+    fn smtp_delivery_report() {
+        let rcpt = |name: &str, status: DeliveryStatus| RcptType::Relay {
+            name: name.to_string(),
+            address: name.to_string(),
+            status,
+        };
+        let message = b"From: john@foo.org\r\nSubject: Hi\r\n\r\nSecret body\r\n";
+
+        // Failures are only reported when the message was accepted
+        for (mail_from, rcpt_to) in [
+            (
+                "john@foo.org",
+                vec![rcpt("a@foo.org", DeliveryStatus::Success)],
+            ),
+            (
+                "john@foo.org",
+                vec![rcpt("a@foo.org", DeliveryStatus::perm_failure("Rejected"))],
+            ),
+            (
+                "",
+                vec![
+                    rcpt("a@foo.org", DeliveryStatus::Success),
+                    rcpt("b@foo.org", DeliveryStatus::perm_failure("Rejected")),
+                ],
+            ),
+        ] {
+            assert!(delivery_report("mx.foo.org", mail_from, &rcpt_to, message).is_none());
+        }
+
+        let report = delivery_report(
+            "mx.foo.org",
+            "john@foo.org",
+            &[
+                rcpt("a@foo.org", DeliveryStatus::Success),
+                rcpt("b@foo.org", DeliveryStatus::perm_failure("Rejected")),
+                rcpt("c@foo.org", DeliveryStatus::internal_error()),
+                rcpt("d@foo.org", DeliveryStatus::Duplicated),
+            ],
+            message,
+        )
+        .unwrap();
+        assert_eq!(report.mail_from, "");
+        assert_eq!(report.rcpt_to, vec!["john@foo.org".to_string()]);
+        let report = String::from_utf8(report.message).unwrap();
+        for expected in [
+            "To: <john@foo.org>\r\n",
+            "Content-Type: multipart/report; report-type=delivery-status;",
+            "Final-Recipient: rfc822; b@foo.org\r\nAction: failed\r\nStatus: 5.5.0\r\n",
+            "Final-Recipient: rfc822; c@foo.org\r\nAction: failed\r\nStatus: 4.3.0\r\n",
+            "Content-Type: text/rfc822-headers\r\n\r\nFrom: john@foo.org\r\nSubject: Hi\r\n",
+        ] {
+            assert!(report.contains(expected), "{}", report);
+        }
+        assert!(!report.contains("a@foo.org"));
+        assert!(!report.contains("d@foo.org"));
+        assert!(!report.contains("Secret body"));
+    }
+}
@@ -0,0 +1,349 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::fmt::Display;
+
+use store::{blake3, write::ttl::now};
+
+const BASE32_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+const HASH_LEN: usize = 7;
+const TIMESTAMP_SLOTS: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SrsError {
+    InvalidAddress,
+    InvalidHash,
+    Expired,
+    RecursionLimit,
+    NoSecret,
+}
+
+/// Rewrites the envelope sender of a forwarded message using the Sender
+/// Rewriting Scheme, so that SPF checks at the destination are performed
+/// against the forwarding domain. Null senders are left untouched, addresses
+/// already rewritten by a previous hop are converted to the SRS1 format so that
+/// bounces are routed back through the first forwarder. Senders are not
+/// rewritten when no secret is configured, as the addresses could be forged.
+pub fn srs_forward(sender: &str, forward_domain: &str, secret: &str) -> String {
+    srs_forward_at(sender, forward_domain, secret, now())
+}
+
+/// Reverses an SRS0 or SRS1 address, returning the address bounces should be
+/// relayed to. The hash and, for SRS0 addresses, the timestamp are validated.
+/// Addresses that decode to another SRS address on the same domain are
+/// decoded again, up to `max_recursion` times.
+pub fn srs_reverse(
+    address: &str,
+    secret: &str,
+    max_age: u64,
+    max_recursion: usize,
+) -> Result<String, SrsError> {
+    srs_reverse_at(address, secret, max_age, max_recursion, now())
+}
+
+pub fn is_srs_address(local_part: &str) -> bool {
+    local_part.get(..5).map_or(false, |prefix| {
+        prefix.eq_ignore_ascii_case("SRS0=") || prefix.eq_ignore_ascii_case("SRS1=")
+    })
+}
+
+fn srs_forward_at(sender: &str, forward_domain: &str, secret: &str, now: u64) -> String {
+    let (local_part, domain) = match sender.rsplit_once('@') {
+        Some((local_part, domain))
+            if !local_part.is_empty() && !domain.is_empty() && !secret.is_empty() =>
+        {
+            (local_part, domain)
+        }
+        _ => return sender.to_string(),
+    };
+
+    if !is_srs_address(local_part) {
+        let timestamp = srs_timestamp(now);
+        format!(
+            "SRS0={}={}={}={}@{}",
+            srs_hash(secret, &[&timestamp, domain, local_part]),
+            timestamp,
+            domain,
+            local_part,
+            forward_domain
+        )
+    } else {
+        // Keep the first forwarder and its opaque part, only the hash is replaced.
+        let (first_hop, opaque) = if local_part[..4].eq_ignore_ascii_case("SRS0") {
+            (domain, &local_part[5..])
+        } else if let Some(parts) = parse_srs1(local_part) {
+            parts
+        } else {
+            return sender.to_string();
+        };
+        format!(
+            "SRS1={}={}=={}@{}",
+            srs_hash(secret, &[first_hop, opaque]),
+            first_hop,
+            opaque,
+            forward_domain
+        )
+    }
+}
+
+fn srs_reverse_at(
+    address: &str,
+    secret: &str,
+    max_age: u64,
+    max_recursion: usize,
+    now: u64,
+) -> Result<String, SrsError> {
+    if secret.is_empty() {
+        return Err(SrsError::NoSecret);
+    }
+    let mut address = address.to_string();
+
+    for _ in 0..=max_recursion {
+        let (local_part, domain) = address
+            .rsplit_once('@')
+            .filter(|(local_part, _)| is_srs_address(local_part))
+            .ok_or(SrsError::InvalidAddress)?;
+
+        let next_address = if local_part[..4].eq_ignore_ascii_case("SRS0") {
+            let mut parts = local_part[5..].splitn(4, '=');
+            let (hash, timestamp, orig_domain, orig_local_part) =
+                match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                    (Some(hash), Some(timestamp), Some(orig_domain), Some(orig_local_part))
+                        if !orig_domain.is_empty() && !orig_local_part.is_empty() =>
+                    {
+                        (hash, timestamp, orig_domain, orig_local_part)
+                    }
+                    _ => return Err(SrsError::InvalidAddress),
+                };
+            if !hash.eq_ignore_ascii_case(&srs_hash(
+                secret,
+                &[timestamp, orig_domain, orig_local_part],
+            )) {
+                return Err(SrsError::InvalidHash);
+            }
+            match srs_timestamp_age(timestamp, now) {
+                Some(age) if age <= max_age => (),
+                Some(_) => return Err(SrsError::Expired),
+                None => return Err(SrsError::InvalidAddress),
+            }
+            format!("{}@{}", orig_local_part, orig_domain)
+        } else {
+            let hash = local_part[5..]
+                .split_once('=')
+                .map(|(hash, _)| hash)
+                .ok_or(SrsError::InvalidAddress)?;
+            let (first_hop, opaque) = parse_srs1(local_part).ok_or(SrsError::InvalidAddress)?;
+            if !hash.eq_ignore_ascii_case(&srs_hash(secret, &[first_hop, opaque])) {
+                return Err(SrsError::InvalidHash);
+            }
+            format!("SRS0={}@{}", opaque, first_hop)
+        };
+
+        // Keep decoding while the address points back to this domain
+        match next_address.rsplit_once('@') {
+            Some((next_local_part, next_domain))
+                if is_srs_address(next_local_part) && next_domain.eq_ignore_ascii_case(domain) =>
+            {
+                address = next_address;
+            }
+            _ => return Ok(next_address),
+        }
+    }
+
+    Err(SrsError::RecursionLimit)
+}
+
+// Splits an SRS1 local part into the first forwarder's domain and its opaque part.
+fn parse_srs1(local_part: &str) -> Option<(&str, &str)> {
+    let (_, rest) = local_part.get(5..)?.split_once('=')?;
+    let (first_hop, opaque) = rest.split_once("==")?;
+    if !first_hop.is_empty() && !opaque.is_empty() {
+        Some((first_hop, opaque))
+    } else {
+        None
+    }
+}
+
+/// Two base32 characters holding the number of days since the epoch, modulo 1024.
+fn srs_timestamp(now: u64) -> String {
+    let days = (now / 86400) % TIMESTAMP_SLOTS;
+    let mut timestamp = String::with_capacity(2);
+    timestamp.push(BASE32_ALPHABET[(days >> 5) as usize] as char);
+    timestamp.push(BASE32_ALPHABET[(days & 31) as usize] as char);
+    timestamp
+}
+
+/// Number of days elapsed since the timestamp was issued.
+fn srs_timestamp_age(timestamp: &str, now: u64) -> Option<u64> {
+    let mut days = 0;
+    if timestamp.len() != 2 {
+        return None;
+    }
+    for ch in timestamp.bytes() {
+        days = (days << 5)
+            | BASE32_ALPHABET
+                .iter()
+                .position(|&b| b == ch.to_ascii_lowercase())? as u64;
+    }
+    Some(((now / 86400) + TIMESTAMP_SLOTS - days) % TIMESTAMP_SLOTS)
+}
+
+fn srs_hash(secret: &str, items: &[&str]) -> String {
+    let mut hasher = blake3::Hasher::new_keyed(blake3::hash(secret.as_bytes()).as_bytes());
+    for item in items {
+        hasher.update(item.to_lowercase().as_bytes());
+        hasher.update(b"=");
+    }
+    let hash = hasher.finalize();
+    let mut bits = u64::from_be_bytes(hash.as_bytes()[..8].try_into().unwrap());
+    let mut result = String::with_capacity(HASH_LEN);
+    for _ in 0..HASH_LEN {
+        result.push(BASE32_ALPHABET[(bits >> 59) as usize] as char);
+        bits <<= 5;
+    }
+    result
+}
+
+impl Display for SrsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SrsError::InvalidAddress => write!(f, "Invalid SRS address"),
+            SrsError::InvalidHash => write!(f, "Invalid SRS signature"),
+            SrsError::Expired => write!(f, "SRS address expired"),
+            SrsError::RecursionLimit => write!(f, "Too many SRS rewrites"),
+            SrsError::NoSecret => write!(f, "SRS is not configured"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{srs_forward, srs_forward_at, srs_reverse_at, srs_timestamp, SrsError};
+
+    #[test]
+    fn srs_rewrite() {
+        let rewritten = srs_forward("jdoe@example.org", "example.net", "secret");
+        let (local_part, domain) = rewritten.rsplit_once('@').unwrap();
+        let parts = local_part.split('=').collect::<Vec<_>>();
+        assert_eq!(domain, "example.net");
+        assert_eq!(parts.len(), 5);
+        assert_eq!(parts[0], "SRS0");
+        assert_eq!(parts[1].len(), 7);
+        assert_eq!(parts[2].len(), 2);
+        assert_eq!(parts[3], "example.org");
+        assert_eq!(parts[4], "jdoe");
+
+        // Same input produces the same address, a different secret does not
+        assert_eq!(
+            rewritten,
+            srs_forward("jdoe@example.org", "example.net", "secret")
+        );
+        assert_ne!(
+            rewritten,
+            srs_forward("jdoe@example.org", "example.net", "other")
+        );
+
+        // Null senders are preserved, already rewritten senders become SRS1
+        assert_eq!(srs_forward("", "example.net", "secret"), "");
+        let srs1 = srs_forward(
+            "SRS0=abcd=ab=example.org=jdoe@example.com",
+            "example.net",
+            "secret",
+        );
+        assert!(srs1.starts_with("SRS1="));
+        assert!(srs1.ends_with("=example.com==abcd=ab=example.org=jdoe@example.net"));
+        let srs1_again = srs_forward(&srs1, "example.edu", "secret");
+        assert!(srs1_again.ends_with("=example.com==abcd=ab=example.org=jdoe@example.edu"));
+
+        // Without a secret senders are not rewritten
+        assert_eq!(
+            srs_forward("jdoe@example.org", "example.net", ""),
+            "jdoe@example.org"
+        );
+
+        assert_eq!(srs_timestamp(0), "aa");
+        assert_eq!(srs_timestamp(1023 * 86400), "77");
+        assert_eq!(srs_timestamp(1024 * 86400), "aa");
+    }
+
+    #[test]
+    fn srs_reverse() {
+        let now = 19000 * 86400;
+
+        // SRS0 round trip, addresses may have been lowercased by other MTAs
+        let srs0 = srs_forward_at("John.Doe@example.org", "example.net", "secret", now);
+        assert_eq!(
+            srs_reverse_at(&srs0, "secret", 21, 3, now),
+            Ok("John.Doe@example.org".to_string())
+        );
+        assert_eq!(
+            srs_reverse_at(&srs0.to_lowercase(), "secret", 21, 3, now),
+            Ok("john.doe@example.org".to_string())
+        );
+
+        // Invalid signatures and expired addresses are rejected
+        assert_eq!(
+            srs_reverse_at(&srs0, "other", 21, 3, now),
+            Err(SrsError::InvalidHash)
+        );
+        assert_eq!(
+            srs_reverse_at(&srs0, "secret", 21, 3, now + 22 * 86400),
+            Err(SrsError::Expired)
+        );
+        assert_eq!(
+            srs_reverse_at("jdoe@example.net", "secret", 21, 3, now),
+            Err(SrsError::InvalidAddress)
+        );
+        assert_eq!(
+            srs_reverse_at(&srs0, "", 21, 3, now),
+            Err(SrsError::NoSecret)
+        );
+        assert_eq!(
+            srs_reverse_at("SRS0=abcd=ab@example.net", "secret", 21, 3, now),
+            Err(SrsError::InvalidAddress)
+        );
+
+        // SRS1 addresses are routed back to the first forwarder
+        let srs1 = srs_forward_at(
+            "SRS0=abcd=ab=example.org=jdoe@example.com",
+            "example.net",
+            "secret",
+            now,
+        );
+        assert_eq!(
+            srs_reverse_at(&srs1, "secret", 21, 3, now),
+            Ok("SRS0=abcd=ab=example.org=jdoe@example.com".to_string())
+        );
+
+        // Addresses pointing back to this domain are decoded again
+        let srs1 = srs_forward_at(&srs0, "example.net", "secret", now);
+        assert_eq!(
+            srs_reverse_at(&srs1, "secret", 21, 3, now),
+            Ok("John.Doe@example.org".to_string())
+        );
+        assert_eq!(
+            srs_reverse_at(&srs1, "secret", 21, 0, now),
+            Err(SrsError::RecursionLimit)
+        );
+    }
+}
@@ -0,0 +1,253 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use jmap::types::jmap::JMAPId;
+use jmap_mail::mail_parser::{HeaderName, Message, RfcHeader};
+use serde::{Deserialize, Serialize};
+use store::{
+    bincode, chrono,
+    serialize::{StoreDeserialize, StoreSerialize},
+    write::ttl::expires_in,
+    JMAPStore, Store, StoreError,
+};
+
+// Longest Message-ID that is indexed for trace lookups.
+const MAX_MESSAGE_ID_LEN: usize = 255;
+
+/// Events recorded while a message is received and delivered, kept for
+/// `lmtp-trace-days` so that administrators can find out what happened to it.
+/// Traces are not replicated, they are stored by the leader for ingested
+/// messages and by the receiving node for messages rejected before ingestion.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestTrace {
+    pub id: String,
+    #[serde(rename = "receivedAt")]
+    pub received_at: u64,
+    pub peer: String,
+    pub helo: String,
+    #[serde(rename = "mailFrom")]
+    pub mail_from: String,
+    #[serde(rename = "messageId")]
+    pub message_id: Option<String>,
+    pub events: Vec<TraceEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceEntry {
+    #[serde(rename = "elapsedMs")]
+    pub elapsed_ms: u64,
+    pub event: TraceEvent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TraceEvent {
+    Authenticated {
+        dmarc: String,
+        disposition: String,
+    },
+    Rejected {
+        reason: String,
+    },
+    Recipient {
+        account: JMAPId,
+        address: String,
+    },
+    Verdict {
+        account: JMAPId,
+        verdict: String,
+        quarantined: bool,
+    },
+    Forwarded {
+        account: JMAPId,
+        addresses: usize,
+        #[serde(rename = "keepCopy")]
+        keep_copy: bool,
+    },
+    Sieve {
+        account: JMAPId,
+        script: String,
+        action: String,
+    },
+    // Recorded once the message has been indexed and committed.
+    Filed {
+        account: JMAPId,
+        id: JMAPId,
+        mailboxes: Vec<JMAPId>,
+    },
+    Failed {
+        account: JMAPId,
+        reason: String,
+    },
+}
+
+impl IngestTrace {
+    pub fn new(peer: String, helo: String, mail_from: String) -> Self {
+        let received_at = expires_in(0);
+        IngestTrace {
+            id: format!("{:x}{:08x}", received_at, store::rand::random::<u32>()),
+            received_at,
+            peer,
+            helo,
+            mail_from,
+            message_id: None,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, event: TraceEvent) {
+        self.events.push(TraceEntry {
+            elapsed_ms: expires_in(0).saturating_sub(self.received_at),
+            event,
+        });
+    }
+
+    pub fn set_message_id(&mut self, message: &Message, raw_message: &[u8]) {
+        if self.message_id.is_some() {
+            return;
+        }
+        self.message_id = message.parts.get(0).and_then(|part| {
+            part.headers
+                .iter()
+                .find(|header| matches!(header.name, HeaderName::Rfc(RfcHeader::MessageId)))
+                .and_then(|header| raw_message.get(header.offset_start..header.offset_end))
+                .and_then(|value| std::str::from_utf8(value).ok())
+                .map(|value| value.trim().trim_start_matches('<').trim_end_matches('>'))
+                .filter(|value| !value.is_empty() && value.len() <= MAX_MESSAGE_ID_LEN)
+                .map(|value| value.to_string())
+        });
+    }
+
+    /// Builds a Received header (RFC 5321) naming the trace id, so that a
+    /// stored message can be matched to its ingestion trace.
+    pub fn to_header(&self, hostname: &str, protocol: &str) -> String {
+        format!(
+            "Received: from {} ({})\r\n\tby {} with {} id {};\r\n\t{}\r\n",
+            if !self.helo.is_empty() {
+                self.helo.as_str()
+            } else {
+                "unknown"
+            },
+            self.peer,
+            hostname,
+            protocol,
+            self.id,
+            chrono::Local::now().to_rfc2822()
+        )
+    }
+}
+
+impl StoreSerialize for IngestTrace {
+    fn serialize(&self) -> Option<Vec<u8>> {
+        bincode::serialize(self).ok()
+    }
+}
+
+impl StoreDeserialize for IngestTrace {
+    fn deserialize(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+pub trait JMAPIngestTrace {
+    fn ingest_trace_set(&self, trace: &IngestTrace) -> store::Result<()>;
+    fn ingest_trace_get(&self, id: &str) -> store::Result<Option<IngestTrace>>;
+}
+
+impl<T> JMAPIngestTrace for JMAPStore<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn ingest_trace_set(&self, trace: &IngestTrace) -> store::Result<()> {
+        if self.config.lmtp_trace_days == 0 {
+            return Ok(());
+        }
+        let expires_at = trace.received_at + self.config.lmtp_trace_days * 86400 * 1000;
+        let value = trace
+            .serialize()
+            .ok_or_else(|| StoreError::SerializeError("Failed to serialize ingest trace".into()))?;
+        self.set_expiring(
+            format!("ingest-trace/{}", trace.id).as_bytes(),
+            &value,
+            expires_at,
+        )?;
+
+        // Index the trace by Message-ID as well, the latest delivery wins
+        if let Some(message_id) = &trace.message_id {
+            self.set_expiring(
+                format!("ingest-trace-mid/{}", message_id).as_bytes(),
+                trace.id.as_bytes(),
+                expires_at,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn ingest_trace_get(&self, id: &str) -> store::Result<Option<IngestTrace>> {
+        if let Some(trace) =
+            self.get_expiring::<IngestTrace>(format!("ingest-trace/{}", id).as_bytes())?
+        {
+            Ok(Some(trace))
+        } else if let Some(trace_id) = self.get_expiring::<String>(
+            format!(
+                "ingest-trace-mid/{}",
+                id.trim_start_matches('<').trim_end_matches('>')
+            )
+            .as_bytes(),
+        )? {
+            self.get_expiring::<IngestTrace>(format!("ingest-trace/{}", trace_id).as_bytes())
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jmap_mail::mail_parser::Message;
+
+    use super::IngestTrace;
+
+    #[test]
+    fn ingest_trace_header() {
+        let mut trace = IngestTrace::new(
+            "192.168.0.1".to_string(),
+            "mx.example.org".to_string(),
+            "bill@example.org".to_string(),
+        );
+        let header = trace.to_header("jmap.example.com", "LMTP");
+        assert!(header.starts_with(&format!(
+            "Received: from mx.example.org (192.168.0.1)\r\n\tby jmap.example.com with LMTP id {};\r\n",
+            trace.id
+        )));
+        assert!(header.ends_with("\r\n"));
+
+        let raw_message = format!(
+            "{}Message-ID: <1234@example.org>\r\nSubject: test\r\n\r\ntest",
+            header
+        );
+        let message = Message::parse(raw_message.as_bytes()).unwrap();
+        trace.set_message_id(&message, raw_message.as_bytes());
+        assert_eq!(trace.message_id.as_deref(), Some("1234@example.org"));
+    }
+}
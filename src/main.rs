@@ -36,7 +36,8 @@ static GLOBAL: Jemalloc = Jemalloc;*/
 use stalwart_jmap::{
     cluster::init::{init_cluster, start_cluster},
     server::{
-        http::{build_jmap_server, init_jmap_server},
+        http::{self, build_jmap_server, init_jmap_server},
+        settings::schema,
         UnwrapFailure,
     },
 };
@@ -44,7 +45,7 @@ use stalwart_jmap::{
 use std::time::Duration;
 
 use store::{
-    config::env_settings::EnvSettings,
+    config::env_settings::{soft_panic, EnvSettings},
     tracing::{self, debug, info, warn, Level},
     Store,
 };
@@ -55,13 +56,25 @@ async fn main() -> std::io::Result<()> {
     // Read configuration parameters
     let mut settings = EnvSettings::new();
 
+    // Validate all settings before starting any service
+    let schema = schema();
+    if let Err(errors) = settings.validate(&schema) {
+        for error in &errors {
+            println!("Invalid setting {}", error);
+        }
+        soft_panic(&format!("Found {} invalid setting(s).", errors.len()));
+    }
+
     // Enable logging
     tracing::subscriber::set_global_default(
         tracing_subscriber::FmtSubscriber::builder()
-            .with_max_level(settings.parse("log-level").unwrap_or(Level::INFO))
+            .with_max_level(settings.value::<Level>(http::SETTINGS, "log-level"))
             .finish(),
     )
     .failed_to("set default subscriber");
+    for key in settings.unknown_keys(&schema) {
+        warn!("Ignoring unknown setting '{}'.", key);
+    }
 
     // Set base URL if missing
     if !settings.contains_key("jmap-url") {
@@ -0,0 +1,229 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    future::{ready, Ready},
+    sync::Arc,
+};
+
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{self, HeaderValue},
+    Error,
+};
+use futures::FutureExt;
+use futures_util::future::LocalBoxFuture;
+use store::config::{env_settings::EnvSettings, settings::Setting};
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::bool("compression")
+        .default("true")
+        .describe("gzip, br and zstd, negotiated using Accept-Encoding"),
+    Setting::bytes("compression-min-size")
+        .default("1024")
+        .describe("Smaller responses are sent uncompressed"),
+    Setting::text("compression-types")
+        .default("application/json;text/*")
+        .describe("Separated by semicolons"),
+];
+
+pub struct CompressionConfig {
+    pub enabled: bool,
+    pub min_size: u64,
+    pub content_types: Vec<String>,
+}
+
+/// Excludes small responses and content types not in the allowlist from compression.
+/// Has to be wrapped by actix's Compress middleware, which skips any response that
+/// already has a Content-Encoding header.
+#[derive(Clone)]
+pub struct CompressionFilter {
+    config: Arc<CompressionConfig>,
+}
+
+pub struct CompressionFilterMiddleware<S> {
+    config: Arc<CompressionConfig>,
+    service: Arc<S>,
+}
+
+impl From<&EnvSettings> for CompressionConfig {
+    fn from(settings: &EnvSettings) -> Self {
+        CompressionConfig {
+            enabled: settings.value(SETTINGS, "compression"),
+            min_size: settings.value(SETTINGS, "compression-min-size"),
+            content_types: settings
+                .value::<String>(SETTINGS, "compression-types")
+                .split(';')
+                .filter_map(|content_type| {
+                    let content_type = content_type.trim();
+                    if !content_type.is_empty() {
+                        Some(content_type.to_lowercase())
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn is_compressible(&self, content_type: &str, size: BodySize) -> bool {
+        match size {
+            BodySize::Sized(size) if size >= self.min_size => (),
+            BodySize::Stream => (),
+            _ => return false,
+        }
+
+        let content_type = content_type
+            .split_once(';')
+            .map(|(content_type, _)| content_type)
+            .unwrap_or(content_type)
+            .trim()
+            .to_lowercase();
+
+        // Event streams are never compressed, as the encoder would hold back events
+        if content_type == "text/event-stream" {
+            return false;
+        }
+
+        self.content_types.iter().any(|allowed| {
+            if let Some(prefix) = allowed.strip_suffix('*') {
+                content_type.starts_with(prefix)
+            } else {
+                &content_type == allowed
+            }
+        })
+    }
+}
+
+impl CompressionFilter {
+    pub fn new(config: CompressionConfig) -> Self {
+        CompressionFilter {
+            config: Arc::new(config),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CompressionFilter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = CompressionFilterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CompressionFilterMiddleware {
+            config: self.config.clone(),
+            service: service.into(),
+        }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for CompressionFilterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let config = self.config.clone();
+        let service = self.service.clone();
+
+        async move {
+            let mut response = service.call(req).await?;
+            if !response.headers().contains_key(header::CONTENT_ENCODING)
+                && !config.is_compressible(
+                    response
+                        .headers()
+                        .get(header::CONTENT_TYPE)
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or(""),
+                    response.response().body().size(),
+                )
+            {
+                response.headers_mut().insert(
+                    header::CONTENT_ENCODING,
+                    HeaderValue::from_static("identity"),
+                );
+            }
+            Ok(response)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::BodySize;
+
+    use super::CompressionConfig;
+
+    #[test]
+    fn compressible_responses() {
+        let config = CompressionConfig {
+            enabled: true,
+            min_size: 1024,
+            content_types: vec!["application/json".to_string(), "text/*".to_string()],
+        };
+
+        for (content_type, size, expected) in [
+            ("application/json", BodySize::Sized(2048), true),
+            (
+                "application/json; charset=utf-8",
+                BodySize::Sized(2048),
+                true,
+            ),
+            ("Application/JSON", BodySize::Sized(1024), true),
+            ("text/html", BodySize::Stream, true),
+            ("text/event-stream", BodySize::Stream, false),
+            ("application/json", BodySize::Sized(1023), false),
+            ("application/json", BodySize::None, false),
+            ("image/png", BodySize::Sized(2048), false),
+            ("application/jsonx", BodySize::Sized(2048), false),
+            ("", BodySize::Sized(2048), false),
+        ] {
+            assert_eq!(
+                config.is_compressible(content_type, size),
+                expected,
+                "{} {:?}",
+                content_type,
+                size
+            );
+        }
+    }
+}
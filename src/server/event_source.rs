@@ -21,7 +21,7 @@
  * for more details.
 */
 
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use async_stream::stream;
 use jmap::types::type_state::TypeState;
 use std::time::{Duration, Instant};
@@ -49,6 +49,8 @@ pub struct Params {
     types: String,
     closeafter: CloseAfter,
     ping: u32,
+    #[serde(default)]
+    mailboxcounters: bool,
 }
 
 struct Ping {
@@ -58,6 +60,7 @@ struct Ping {
 }
 
 pub async fn handle_jmap_event_source<T>(
+    request: HttpRequest,
     params: web::Query<Params>,
     core: web::Data<JMAPServer<T>>,
     session: Session,
@@ -102,9 +105,23 @@ where
     let close_after_state = matches!(params.closeafter, CloseAfter::State);
     let throttle_ms = core.store.config.event_source_throttle;
 
+    // Clients reconnecting with Last-Event-ID receive any changes they missed
+    let mut last_event_id = request
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.trim().parse::<u64>().ok());
+
     // Register with state manager
     let mut change_rx = if let Some(change_rx) = core
-        .subscribe_state_manager(session.account_id(), session.account_id(), types)
+        .subscribe_state_manager(
+            session.account_id(),
+            session.account_id(),
+            types,
+            last_event_id,
+            params.mailboxcounters,
+            Vec::new(),
+        )
         .await
     {
         change_rx
@@ -123,12 +140,21 @@ where
             loop {
                 match time::timeout(timeout, change_rx.recv()).await {
                     Ok(Some(state_change)) => {
+                        if state_change.event_id > last_event_id.unwrap_or(0) {
+                            last_event_id = state_change.event_id.into();
+                        }
                         for (type_state, change_id) in state_change.types {
                             response
                                 .changed
                                 .get_mut_or_insert(state_change.account_id.into())
                                 .set(type_state, change_id.into());
                         }
+                        for (mailbox_id, delta) in state_change.unread_deltas {
+                            *response
+                                .unread_deltas
+                                .get_mut_or_insert(state_change.account_id.into())
+                                .get_mut_or_insert(mailbox_id.into()) += delta;
+                        }
                     }
                     Ok(None) => {
                         debug!("Broadcast channel was closed.");
@@ -141,16 +167,25 @@ where
                     let elapsed = last_message.elapsed().as_millis() as u64;
                     if elapsed >= throttle_ms {
                         last_message = Instant::now();
-                        yield Ok(web::Bytes::from(format!(
-                            "event: state\ndata: {}\n\n",
-                            serde_json::to_string(&response).unwrap()
-                        )));
+                        yield Ok(web::Bytes::from(if let Some(last_event_id) = last_event_id {
+                            format!(
+                                "event: state\nid: {}\ndata: {}\n\n",
+                                last_event_id,
+                                serde_json::to_string(&response).unwrap()
+                            )
+                        } else {
+                            format!(
+                                "event: state\ndata: {}\n\n",
+                                serde_json::to_string(&response).unwrap()
+                            )
+                        }));
 
                         if close_after_state {
                             break;
                         }
 
                         response.changed.clear();
+                        response.unread_deltas.clear();
                         Duration::from_millis(
                             ping.as_ref().map(|p| p.interval).unwrap_or(LONG_SLUMBER_MS),
                         )
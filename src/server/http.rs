@@ -21,7 +21,10 @@
  * for more details.
 */
 
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
 use actix_cors::Cors;
 use actix_web::{
@@ -33,11 +36,12 @@ use actix_web::{
 use jmap::{
     orm::{serialize::JMAPOrm, TinyORM},
     principal::schema::Principal,
+    types::state::JMAPState,
     SUPERUSER_ID,
 };
 use jmap_sharing::principal::CreateAccount;
 use store::{
-    config::{env_settings::EnvSettings, jmap::JMAPConfig},
+    config::{env_settings::EnvSettings, jmap::JMAPConfig, settings::Setting},
     core::{collection::Collection, document::Document},
     moka::future::Cache,
     rand::{distributions::Alphanumeric, thread_rng, Rng},
@@ -48,33 +52,113 @@ use store::{
 
 use crate::{
     api::{
-        blob::{handle_jmap_download, handle_jmap_upload},
+        autoconfig::{handle_mozilla_autoconfig, handle_ms_autodiscover, AutoConfig},
+        blob::{handle_jmap_download, handle_jmap_download_cid, handle_jmap_upload},
+        cluster::{
+            handle_admin_cluster_resync, handle_admin_cluster_status, handle_admin_cluster_verify,
+        },
+        compact::{handle_admin_compact, handle_admin_compact_account},
+        config::{handle_admin_config, EffectiveConfig},
+        expunge::{handle_admin_expunge, handle_admin_expunge_report},
+        handle_not_found,
+        impersonate::handle_admin_impersonate,
+        ingest::handle_admin_ingest_trace,
+        lint::handle_jmap_lint,
+        migration::{
+            handle_admin_migration_cancel, handle_admin_migration_create,
+            handle_admin_migration_get, handle_admin_migration_list, handle_admin_migration_resume,
+        },
+        otp::{handle_otp_request, handle_otp_status},
+        plugin::PluginRegistry,
+        queue::{
+            handle_admin_queue_cancel, handle_admin_queue_domain_cancel,
+            handle_admin_queue_domain_retry, handle_admin_queue_get, handle_admin_queue_list,
+            handle_admin_queue_retry,
+        },
+        renumber::handle_admin_renumber,
+        report::{handle_admin_report, handle_admin_report_summary},
         request::handle_jmap_request,
-        session::{handle_jmap_session, Session},
+        session::{handle_jmap_session, Session, SessionHistory},
+        trace::{
+            handle_admin_auth_metrics, handle_admin_bitmap_cache_metrics,
+            handle_admin_index_metrics, handle_admin_traces, handle_admin_worker_metrics,
+            handle_admin_write_metrics, TraceBuffer,
+        },
+        vacation::{handle_admin_vacation_dedup, handle_admin_vacation_dedup_reset},
+        RequestError,
     },
     authorization::{
         auth::SessionFactory,
+        cache::AuthCache,
         oauth::{
             handle_device_auth, handle_oauth_metadata, handle_token_request, handle_user_code_auth,
             handle_user_code_auth_post, handle_user_device_auth, handle_user_device_auth_post,
             OAuth, OAuthMetadata,
         },
+        proxy::TrustedProxies,
+        webauthn::{handle_passkey_options, RelyingParty},
+    },
+    cluster::{health::handle_cluster_health, rpc::tls::load_tls_server_config, ClusterIpc},
+    lmtp::listener::{init_lmtp, spawn_lmtp, spawn_smtp},
+    server::{
+        compression::{CompressionConfig, CompressionFilter},
+        event_source::handle_jmap_event_source,
+        websocket::handle_ws,
+        workers::WorkerPools,
     },
-    cluster::{rpc::tls::load_tls_server_config, ClusterIpc},
-    lmtp::listener::{init_lmtp, spawn_lmtp},
-    server::{event_source::handle_jmap_event_source, websocket::handle_ws},
     services::{
+        delivery_queue::DeliveryQueue,
+        dns::DnsResolver,
         email_delivery::{init_email_delivery, spawn_email_delivery},
         housekeeper::{init_housekeeper, spawn_housekeeper},
-        state_change::{init_state_manager, spawn_state_manager},
+        maintenance::{handle_admin_maintenance, MaintenanceScheduler},
+        migration::{spawn_migrations, MigrationManager},
+        onboarding::Onboarding,
+        push_broker::spawn_push_broker,
+        snooze::spawn_snooze,
+        state_change::{
+            init_state_manager, spawn_state_manager, MailboxCounters, QuerySubscriptions,
+        },
+        warmup::{handle_ready, spawn_warmup, WarmupManager},
     },
-    JMAPServer, DEFAULT_HTTP_PORT,
+    JMAPServer,
 };
 
 use super::{failed_to, UnwrapFailure};
 
+pub const SETTINGS: &[Setting] = &[
+    Setting::one_of("log-level", &["trace", "debug", "info", "warn", "error"]).default("info"),
+    Setting::url("jmap-url").describe("Public URL of the server"),
+    Setting::ip_addr("jmap-bind-addr").default("0.0.0.0"),
+    Setting::integer("jmap-port")
+        .min(1)
+        .max(65535)
+        .default("8080"),
+    Setting::path("jmap-cert-path"),
+    Setting::path("jmap-key-path"),
+    Setting::secret("encryption-key"),
+    Setting::secret("set-admin-password").describe("Resets the admin password on startup"),
+    Setting::bool("state-accept-unsigned")
+        .default("true")
+        .describe("Accept state strings issued before state signing was enabled"),
+    Setting::bool("strict-cors").default("false"),
+    Setting::integer("trace-buffer-size")
+        .default("1024")
+        .describe("Recent calls kept for /admin/traces, 0 = disabled"),
+    Setting::seconds("oauth-user-code-expiry").default("1800"),
+    Setting::seconds("oauth-auth-code-expiry").default("600"),
+    Setting::seconds("oauth-token-expiry").default("3600"),
+    Setting::seconds("oauth-refresh-token-expiry").default("2592000"),
+    Setting::seconds("oauth-refresh-token-renew").default("345600"),
+    Setting::seconds("oauth-impersonation-expiry").default("900"),
+    Setting::seconds("oauth-impersonation-max-expiry").default("3600"),
+    Setting::integer("oauth-max-attempts").min(1).default("3"),
+    Setting::bool("oauth-require-passkey-admin")
+        .default("false")
+        .describe("Administrators have to sign in to the OAuth pages with a passkey"),
+];
+
 const ONE_HOUR_EXPIRY: Duration = Duration::from_secs(60 * 60);
-const HALF_HOUR_EXPIRY: Duration = Duration::from_secs(30 * 60);
 
 pub fn init_jmap_server<T>(
     settings: &EnvSettings,
@@ -90,7 +174,8 @@ where
         T::open(settings).failed_to("open database"),
         config,
         settings,
-    );
+    )
+    .failed_to("open store");
     store.sieve_runtime.set_env_variable(
         "host",
         gethostname::gethostname()
@@ -151,6 +236,7 @@ where
     let (housekeeper_tx, housekeeper_rx) = init_housekeeper();
     let (change_tx, change_rx) = init_state_manager();
     let (lmtp_tx, lmtp_rx) = init_lmtp();
+    let push_broker = spawn_push_broker(settings, change_tx.clone());
     let is_in_cluster = cluster.is_some();
 
     // Load OAuth settings
@@ -162,16 +248,17 @@ where
                 .map(char::from)
                 .collect::<String>()
         }),
-        expiry_user_code: settings.parse("oauth-user-code-expiry").unwrap_or(1800),
-        expiry_auth_code: settings.parse("oauth-auth-code-expiry").unwrap_or(600),
-        expiry_token: settings.parse("oauth-token-expiry").unwrap_or(3600),
-        expiry_refresh_token: settings
-            .parse("oauth-refresh-token-expiry")
-            .unwrap_or(30 * 86400),
-        expiry_refresh_token_renew: settings
-            .parse("oauth-refresh-token-renew")
-            .unwrap_or(4 * 86400),
-        max_auth_attempts: settings.parse("oauth-max-attempts").unwrap_or(3),
+        expiry_user_code: settings.value(SETTINGS, "oauth-user-code-expiry"),
+        expiry_auth_code: settings.value(SETTINGS, "oauth-auth-code-expiry"),
+        expiry_token: settings.value(SETTINGS, "oauth-token-expiry"),
+        expiry_refresh_token: settings.value(SETTINGS, "oauth-refresh-token-expiry"),
+        expiry_refresh_token_renew: settings.value(SETTINGS, "oauth-refresh-token-renew"),
+        expiry_impersonation_token: settings.value(SETTINGS, "oauth-impersonation-expiry"),
+        max_expiry_impersonation_token: settings.value(SETTINGS, "oauth-impersonation-max-expiry"),
+        max_auth_attempts: settings.value(SETTINGS, "oauth-max-attempts"),
+        relying_party: RelyingParty::new(base_session.base_url())
+            .failed_to("parse the relying party from 'jmap-url'"),
+        require_passkey_admin: settings.value(SETTINGS, "oauth-require-passkey-admin"),
         metadata: serde_json::to_string(&OAuthMetadata::new(base_session.base_url()))
             .failed_to("serialize OAuth metadata"),
     });
@@ -185,30 +272,39 @@ where
         ));
     }
 
+    // Sign state strings with a key derived from the encryption key
+    JMAPState::init_signing(
+        &oauth.key,
+        settings.value(SETTINGS, "state-accept-unsigned"),
+    );
+
     let server = web::Data::new(JMAPServer {
         store: store.into(),
-        worker_pool: rayon::ThreadPoolBuilder::new()
-            .num_threads(
-                settings
-                    .parse("worker-pool-size")
-                    .filter(|v| *v > 0)
-                    .unwrap_or_else(num_cpus::get),
-            )
-            .build()
-            .unwrap(),
+        worker_pools: WorkerPools::parse(settings),
         state_change: change_tx,
+        query_subscriptions: QuerySubscriptions::default(),
+        mailbox_counters: MailboxCounters::default(),
         email_delivery: email_tx.clone(),
+        delivery_queue: DeliveryQueue::parse(settings),
         housekeeper: housekeeper_tx,
         lmtp: lmtp_tx,
-        sessions: Cache::builder()
-            .initial_capacity(128)
-            .time_to_live(HALF_HOUR_EXPIRY)
-            .build(),
+        migrations: MigrationManager::parse(settings),
+        warmup: WarmupManager::parse(settings),
+        maintenance: MaintenanceScheduler::parse(settings),
+        onboarding: Onboarding::parse(settings),
+        push_broker,
+        dns: DnsResolver::parse(settings),
+        sessions: AuthCache::parse(settings),
+        session_history: SessionHistory::parse(settings),
         rate_limiters: Cache::builder()
             .initial_capacity(128)
             .time_to_idle(ONE_HOUR_EXPIRY)
             .build(),
-        oauth_codes: Cache::builder().time_to_live(ONE_HOUR_EXPIRY).build(),
+        trusted_proxies: TrustedProxies::parse(settings),
+        traces: TraceBuffer::new(settings.value(SETTINGS, "trace-buffer-size")),
+        autoconfig: AutoConfig::parse(settings),
+        config: EffectiveConfig::new(settings),
+        plugins: PluginRegistry::default(),
         oauth,
         cluster,
         base_session,
@@ -217,10 +313,17 @@ where
     });
 
     // Spawn LMTP service
+    spawn_smtp(server.clone(), settings, lmtp_rx.clone());
     spawn_lmtp(server.clone(), settings, lmtp_rx);
 
-    // Spawn TypeState manager
-    spawn_state_manager(server.clone(), settings, !is_in_cluster, change_rx);
+    // Spawn TypeState manager, followers deliver changes to their own
+    // subscribers when a push broker is available.
+    spawn_state_manager(
+        server.clone(),
+        settings,
+        !is_in_cluster || server.push_broker.is_some(),
+        change_rx,
+    );
 
     // Spawn email delivery service
     spawn_email_delivery(server.clone(), settings, email_tx, email_rx);
@@ -228,6 +331,15 @@ where
     // Spawn housekeeper
     spawn_housekeeper(server.clone(), settings, housekeeper_rx);
 
+    // Spawn snoozed messages scheduler
+    spawn_snooze(server.clone(), settings);
+
+    // Resume interrupted migrations
+    spawn_migrations(server.clone());
+
+    // Pre-load the caches of recently active accounts
+    spawn_warmup(server.clone());
+
     server
 }
 
@@ -240,8 +352,8 @@ where
 {
     // Start JMAP server
     let http_addr = SocketAddr::from((
-        settings.parse_ipaddr("jmap-bind-addr", "0.0.0.0"),
-        settings.parse("jmap-port").unwrap_or(DEFAULT_HTTP_PORT),
+        settings.value::<IpAddr>(SETTINGS, "jmap-bind-addr"),
+        settings.value::<u16>(SETTINGS, "jmap-port"),
     ));
 
     // Obtain TLS path
@@ -268,10 +380,19 @@ where
         }
     );
 
-    let strict_cors = settings.parse("strict-cors").unwrap_or(false);
+    let strict_cors = settings.value(SETTINGS, "strict-cors");
+    let compression = CompressionFilter::new(CompressionConfig::from(&settings));
     let server = HttpServer::new(move || {
         App::new()
             .wrap(SessionFactory::new(jmap_server.clone()))
+            .wrap(middleware::Condition::new(
+                compression.is_enabled(),
+                compression.clone(),
+            ))
+            .wrap(middleware::Condition::new(
+                compression.is_enabled(),
+                middleware::Compress::default(),
+            ))
             .wrap(if strict_cors {
                 Cors::default()
                     .allow_any_origin()
@@ -286,21 +407,49 @@ where
                 jmap_server.store.config.max_size_request,
             )))
             .app_data(jmap_server.clone())
+            .app_data(web::JsonConfig::default().error_handler(|err, _| {
+                RequestError::invalid_parameters()
+                    .with_detail(err.to_string())
+                    .into()
+            }))
+            .app_data(web::QueryConfig::default().error_handler(|err, _| {
+                RequestError::invalid_parameters()
+                    .with_detail(err.to_string())
+                    .into()
+            }))
+            .app_data(web::PathConfig::default().error_handler(|err, _| {
+                RequestError::invalid_parameters()
+                    .with_detail(err.to_string())
+                    .into()
+            }))
+            .app_data(web::FormConfig::default().error_handler(|err, _| {
+                RequestError::invalid_parameters()
+                    .with_detail(err.to_string())
+                    .into()
+            }))
             .route("/.well-known/jmap", web::get().to(handle_jmap_session::<T>))
-            .route("/jmap", web::post().to(handle_jmap_request::<T>))
             .route(
-                "/jmap/upload/{accountId}",
-                web::post().to(handle_jmap_upload::<T>),
+                "/.well-known/oauth-authorization-server",
+                web::get().to(handle_oauth_metadata::<T>),
             )
+            .route("/healthz/ready", web::get().to(handle_ready::<T>))
+            .route("/health/cluster", web::get().to(handle_cluster_health::<T>))
             .route(
-                "/jmap/download/{accountId}/{blobId}/{name}",
-                web::get().to(handle_jmap_download::<T>),
+                "/mail/config-v1.1.xml",
+                web::get().to(handle_mozilla_autoconfig::<T>),
             )
             .route(
-                "/jmap/eventsource",
-                web::get().to(handle_jmap_event_source::<T>),
+                "/.well-known/autoconfig/mail/config-v1.1.xml",
+                web::get().to(handle_mozilla_autoconfig::<T>),
+            )
+            .route(
+                "/autodiscover/autodiscover.xml",
+                web::post().to(handle_ms_autodiscover::<T>),
+            )
+            .route(
+                "/Autodiscover/Autodiscover.xml",
+                web::post().to(handle_ms_autodiscover::<T>),
             )
-            .route("/jmap/ws", web::get().to(handle_ws::<T>))
             .route("/auth", web::get().to(handle_user_device_auth::<T>))
             .route("/auth", web::post().to(handle_user_device_auth_post::<T>))
             .route("/auth/code", web::get().to(handle_user_code_auth::<T>))
@@ -310,10 +459,12 @@ where
             )
             .route("/auth/device", web::post().to(handle_device_auth::<T>))
             .route("/auth/token", web::post().to(handle_token_request::<T>))
-            .route(
-                "/.well-known/oauth-authorization-server",
-                web::get().to(handle_oauth_metadata::<T>),
-            )
+            .route("/auth/2fa", web::get().to(handle_otp_status::<T>))
+            .route("/auth/2fa", web::post().to(handle_otp_request::<T>))
+            .route("/auth/passkey", web::post().to(handle_passkey_options::<T>))
+            .configure(configure_api::<T>)
+            .service(web::scope(API_PREFIX).configure(configure_api::<T>))
+            .default_service(web::to(handle_not_found))
     });
     if let Some(tls_config) = tls_config {
         server.bind_rustls(http_addr, tls_config)
@@ -322,3 +473,152 @@ where
     }
     .map(|s| s.run())
 }
+
+// JMAP and administration endpoints, registered both at the root and under API_PREFIX.
+fn configure_api<T>(cfg: &mut web::ServiceConfig)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    cfg.route("/jmap", web::post().to(handle_jmap_request::<T>))
+        .route(
+            "/jmap/upload/{accountId}",
+            web::post().to(handle_jmap_upload::<T>),
+        )
+        .route(
+            "/jmap/lint/{accountId}",
+            web::post().to(handle_jmap_lint::<T>),
+        )
+        .route(
+            "/jmap/download/{accountId}/{blobId}/{name}",
+            web::get().to(handle_jmap_download::<T>),
+        )
+        .route(
+            "/jmap/download/{accountId}/{emailId}/cid/{contentId}",
+            web::get().to(handle_jmap_download_cid::<T>),
+        )
+        .route(
+            "/jmap/eventsource",
+            web::get().to(handle_jmap_event_source::<T>),
+        )
+        .route("/jmap/ws", web::get().to(handle_ws::<T>))
+        .route("/admin/config", web::get().to(handle_admin_config::<T>))
+        .route("/admin/traces", web::get().to(handle_admin_traces::<T>))
+        .route(
+            "/admin/maintenance",
+            web::get().to(handle_admin_maintenance::<T>),
+        )
+        .route(
+            "/admin/metrics/write",
+            web::get().to(handle_admin_write_metrics::<T>),
+        )
+        .route(
+            "/admin/metrics/bitmap-cache",
+            web::get().to(handle_admin_bitmap_cache_metrics::<T>),
+        )
+        .route(
+            "/admin/metrics/auth",
+            web::get().to(handle_admin_auth_metrics::<T>),
+        )
+        .route(
+            "/admin/metrics/workers",
+            web::get().to(handle_admin_worker_metrics::<T>),
+        )
+        .route(
+            "/admin/metrics/index",
+            web::get().to(handle_admin_index_metrics::<T>),
+        )
+        .route(
+            "/admin/report",
+            web::get().to(handle_admin_report_summary::<T>),
+        )
+        .route(
+            "/admin/report/{accountId}",
+            web::get().to(handle_admin_report::<T>),
+        )
+        .route(
+            "/admin/expunge/{accountId}",
+            web::get().to(handle_admin_expunge_report::<T>),
+        )
+        .route(
+            "/admin/expunge/{accountId}",
+            web::post().to(handle_admin_expunge::<T>),
+        )
+        .route("/admin/compact", web::post().to(handle_admin_compact::<T>))
+        .route(
+            "/admin/compact/{accountId}",
+            web::post().to(handle_admin_compact_account::<T>),
+        )
+        .route(
+            "/admin/renumber/{accountId}/{type}",
+            web::post().to(handle_admin_renumber::<T>),
+        )
+        .route(
+            "/admin/vacation/{accountId}",
+            web::get().to(handle_admin_vacation_dedup::<T>),
+        )
+        .route(
+            "/admin/vacation/{accountId}",
+            web::delete().to(handle_admin_vacation_dedup_reset::<T>),
+        )
+        .route(
+            "/admin/ingest/{id}",
+            web::get().to(handle_admin_ingest_trace::<T>),
+        )
+        .route(
+            "/admin/impersonate/{accountId}",
+            web::post().to(handle_admin_impersonate::<T>),
+        )
+        .route(
+            "/admin/cluster/status",
+            web::get().to(handle_admin_cluster_status::<T>),
+        )
+        .route(
+            "/admin/cluster/verify",
+            web::get().to(handle_admin_cluster_verify::<T>),
+        )
+        .route(
+            "/admin/cluster/resync",
+            web::post().to(handle_admin_cluster_resync::<T>),
+        )
+        .route(
+            "/admin/migrations",
+            web::get().to(handle_admin_migration_list::<T>),
+        )
+        .route(
+            "/admin/migrations",
+            web::post().to(handle_admin_migration_create::<T>),
+        )
+        .route(
+            "/admin/migrations/{id}",
+            web::get().to(handle_admin_migration_get::<T>),
+        )
+        .route(
+            "/admin/migrations/{id}",
+            web::post().to(handle_admin_migration_resume::<T>),
+        )
+        .route(
+            "/admin/migrations/{id}",
+            web::delete().to(handle_admin_migration_cancel::<T>),
+        )
+        .route("/admin/queue", web::get().to(handle_admin_queue_list::<T>))
+        .route(
+            "/admin/queue/{id}",
+            web::get().to(handle_admin_queue_get::<T>),
+        )
+        .route(
+            "/admin/queue/{id}",
+            web::post().to(handle_admin_queue_retry::<T>),
+        )
+        .route(
+            "/admin/queue/{id}",
+            web::delete().to(handle_admin_queue_cancel::<T>),
+        )
+        .route(
+            "/admin/queue/domain/{domain}",
+            web::post().to(handle_admin_queue_domain_retry::<T>),
+        )
+        .route(
+            "/admin/queue/domain/{domain}",
+            web::delete().to(handle_admin_queue_domain_cancel::<T>),
+        );
+}
@@ -21,13 +21,17 @@
  * for more details.
 */
 
+pub mod compression;
 pub mod event_source;
 pub mod http;
+pub mod settings;
 pub mod websocket;
+pub mod workers;
 
 use crate::services::{email_delivery, housekeeper, state_change};
 use crate::{cluster, JMAPServer};
-use store::core::error::StoreError;
+use store::core::{error::StoreError, timing::ReadTimings};
+use store::serialize::key::ValueKey;
 use store::tracing::{debug, error};
 use store::ColumnFamily;
 use store::{
@@ -35,6 +39,7 @@ use store::{
     Store,
 };
 use tokio::sync::oneshot;
+use workers::WorkerPool;
 
 impl<T> JMAPServer<T>
 where
@@ -45,8 +50,13 @@ where
         U: StoreDeserialize + Send + Sync + 'static,
     {
         let store = self.store.clone();
-        self.spawn_worker(move || store.db.get(ColumnFamily::Values, key.as_bytes()))
-            .await
+        self.spawn_worker(move || {
+            store.db.get(
+                ColumnFamily::Values,
+                &ValueKey::serialize_named(key.as_bytes()),
+            )
+        })
+        .await
     }
 
     pub async fn set_key<U>(&self, key: &'static str, value: U) -> store::Result<()>
@@ -57,7 +67,7 @@ where
         self.spawn_worker(move || {
             store.db.set(
                 ColumnFamily::Values,
-                key.as_bytes(),
+                &ValueKey::serialize_named(key.as_bytes()),
                 &value.serialize().ok_or_else(|| {
                     StoreError::SerializeError(format!("Failed to serialize value for key {}", key))
                 })?,
@@ -72,7 +82,7 @@ where
     {
         let store = self.store.clone();
 
-        self.worker_pool.spawn(move || {
+        self.worker_pools.spawn(WorkerPool::Store, move || {
             let bytes = match value.serialize() {
                 Some(bytes) => bytes,
                 None => {
@@ -81,20 +91,32 @@ where
                 }
             };
 
-            if let Err(err) = store.db.set(ColumnFamily::Values, key.as_bytes(), &bytes) {
+            if let Err(err) = store.db.set(
+                ColumnFamily::Values,
+                &ValueKey::serialize_named(key.as_bytes()),
+                &bytes,
+            ) {
                 error!("Failed to set key: {:?}", err);
             }
         });
     }
 
     pub async fn spawn_worker<U, V>(&self, f: U) -> store::Result<V>
+    where
+        U: FnOnce() -> store::Result<V> + Send + 'static,
+        V: Sync + Send + 'static,
+    {
+        self.spawn_worker_on(WorkerPool::Store, f).await
+    }
+
+    pub async fn spawn_worker_on<U, V>(&self, pool: WorkerPool, f: U) -> store::Result<V>
     where
         U: FnOnce() -> store::Result<V> + Send + 'static,
         V: Sync + Send + 'static,
     {
         let (tx, rx) = oneshot::channel();
 
-        self.worker_pool.spawn(move || {
+        self.worker_pools.spawn(pool, move || {
             tx.send(f()).ok();
         });
 
@@ -109,7 +131,7 @@ where
     {
         let (tx, rx) = oneshot::channel();
 
-        self.worker_pool.spawn(move || {
+        self.worker_pools.spawn(WorkerPool::Index, move || {
             tx.send(f()).ok();
         });
 
@@ -117,6 +139,33 @@ where
             .map_err(|e| StoreError::InternalError(format!("Await error: {}", e)))?
     }
 
+    pub async fn spawn_traced_jmap_request<U, V>(
+        &self,
+        f: U,
+    ) -> (jmap::Result<V>, Option<ReadTimings>)
+    where
+        U: FnOnce() -> jmap::Result<V> + Send + 'static,
+        V: Sync + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let is_tracing = self.traces.is_enabled();
+
+        self.worker_pools.spawn(WorkerPool::Index, move || {
+            if is_tracing {
+                ReadTimings::start();
+            }
+            let result = f();
+            tx.send((result, ReadTimings::finish())).ok();
+        });
+
+        rx.await.unwrap_or_else(|e| {
+            (
+                Err(StoreError::InternalError(format!("Await error: {}", e)).into()),
+                None,
+            )
+        })
+    }
+
     pub async fn shutdown(&self) {
         if let Some(cluster) = &self.cluster {
             if cluster.tx.send(cluster::Event::Shutdown).await.is_err() {
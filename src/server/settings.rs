@@ -0,0 +1,84 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use store::config::settings::SettingsSection;
+
+use crate::{api, authorization, cluster, lmtp, services};
+
+// Every setting read by the server, used to validate the configuration at
+// startup, to dump the effective configuration and to generate the reference.
+pub fn schema() -> Vec<SettingsSection> {
+    let mut schema = store::config::SCHEMA.to_vec();
+    schema.extend_from_slice(&[
+        ("Database", store_rocksdb::SETTINGS),
+        ("HTTP server", super::http::SETTINGS),
+        ("Worker pools", super::workers::SETTINGS),
+        ("Compression", super::compression::SETTINGS),
+        ("Authentication cache", authorization::cache::SETTINGS),
+        ("Proxy protocol", authorization::proxy::SETTINGS),
+        ("Session object", api::session::SETTINGS),
+        ("Autoconfig", api::autoconfig::SETTINGS),
+        ("DNS resolver", services::dns::SETTINGS),
+        ("Cache warmup", services::warmup::SETTINGS),
+        ("Event source", services::state_change::SETTINGS),
+        ("Snooze", services::snooze::SETTINGS),
+        ("Footers", services::footer::SETTINGS),
+        ("Housekeeper", services::housekeeper::SETTINGS),
+        ("Maintenance windows", services::maintenance::SETTINGS),
+        ("Push subscriptions", services::push_subscription::SETTINGS),
+        ("Push broker", services::push_broker::SETTINGS),
+        ("Outbound delivery", services::email_delivery::SETTINGS),
+        ("Mailbox migration", services::migration::SETTINGS),
+        ("Account onboarding", services::onboarding::SETTINGS),
+        ("LMTP and SMTP listeners", lmtp::listener::SETTINGS),
+        ("SMTP", lmtp::smtp::SETTINGS),
+        ("Message authentication", lmtp::authentication::SETTINGS),
+        ("Cluster", cluster::init::SETTINGS),
+        ("Cluster RPC", cluster::rpc::listener::SETTINGS),
+        ("Raft log verification", cluster::log::verify::SETTINGS),
+    ]);
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use store::ahash::AHashSet;
+
+    #[test]
+    fn schema_is_consistent() {
+        let mut keys = AHashSet::new();
+        for (section, settings) in super::schema() {
+            for setting in settings {
+                assert!(
+                    keys.insert(setting.key),
+                    "{} declared twice ({})",
+                    setting.key,
+                    section
+                );
+                if let Some(default) = setting.default {
+                    assert_eq!(setting.validate(default), Ok(()), "{}", setting.key);
+                }
+            }
+        }
+    }
+}
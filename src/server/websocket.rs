@@ -31,9 +31,11 @@ use crate::JMAPServer;
 use actix::{Actor, ActorContext, AsyncContext, Handler, Message, StreamHandler};
 use actix_web::{web, HttpRequest, HttpResponse};
 use actix_web_actors::ws::{self, WsResponseBuilder};
+use jmap::request::query_changes::QueryChangesResponse;
 use jmap::types::jmap::JMAPId;
 use jmap::types::state::JMAPState;
 use jmap::types::type_state::TypeState;
+use jmap_mail::mail::query_subscription::QuerySubscription;
 use std::borrow::Cow;
 use std::time::{Duration, Instant};
 use store::ahash::AHashMap;
@@ -86,7 +88,7 @@ enum WebSocketResponseType {
     Response,
 }
 
-#[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+#[derive(Debug, serde::Deserialize)]
 struct WebSocketPushEnable {
     #[serde(rename = "@type")]
     _type: WebSocketPushEnableType,
@@ -94,6 +96,12 @@ struct WebSocketPushEnable {
     data_types: Option<Vec<TypeState>>,
     #[serde(rename = "pushState")]
     push_state: Option<String>,
+    #[serde(rename = "mailboxCounters")]
+    #[serde(default)]
+    mailbox_counters: bool,
+    #[serde(rename = "querySubscriptions")]
+    #[serde(default)]
+    query_subscriptions: VecMap<String, QuerySubscription>,
 }
 
 #[derive(Debug, PartialEq, Eq, serde::Deserialize)]
@@ -135,6 +143,14 @@ pub struct WebSocketStateChange {
     #[serde(rename = "@type")]
     pub type_: WebSocketStateChangeType,
     pub changed: VecMap<JMAPId, VecMap<TypeState, JMAPState>>,
+    #[serde(rename = "mailboxUnreadDeltas")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "VecMap::is_empty")]
+    pub unread_deltas: VecMap<JMAPId, VecMap<JMAPId, i64>>,
+    #[serde(rename = "queryChanges")]
+    #[serde(skip_deserializing)]
+    #[serde(skip_serializing_if = "VecMap::is_empty")]
+    pub query_changes: VecMap<String, Vec<QueryChangesResponse>>,
     #[serde(rename = "pushState")]
     #[serde(skip_serializing_if = "Option::is_none")]
     push_state: Option<String>,
@@ -260,11 +276,13 @@ where
             }
             Ok(ws::Message::Text(request)) => {
                 let error = if request.len() < self.core.store.config.max_size_request {
+                    let parse_start = Instant::now();
                     match serde_json::from_slice::<WebSocketMessage>(request.as_bytes()) {
                         Ok(message) => match message {
                             WebSocketMessage::Request(request) => {
+                                let parse_time = parse_start.elapsed();
                                 if request.method_calls.len()
-                                    < self.core.store.config.max_calls_in_request
+                                    <= self.core.store.config.max_calls_in_request
                                 {
                                     let addr = ctx.address();
                                     let core = self.core.clone();
@@ -274,18 +292,26 @@ where
                                         if let Ok(_in_flight_request) =
                                             core.is_account_allowed(session.account_id()).await
                                         {
+                                            let mut response = handle_method_calls(
+                                                Request {
+                                                    using: request.using,
+                                                    method_calls: request.method_calls,
+                                                    created_ids: request.created_ids,
+                                                },
+                                                core.clone(),
+                                                session,
+                                            )
+                                            .await;
+
+                                            // Responses are serialized by the actor, only
+                                            // parsing is measured for WebSocket requests.
+                                            core.traces.push(
+                                                std::mem::take(&mut response.traces),
+                                                parse_time,
+                                                Duration::ZERO,
+                                            );
                                             addr.do_send(WebSocketResponse::from_response(
-                                                handle_method_calls(
-                                                    Request {
-                                                        using: request.using,
-                                                        method_calls: request.method_calls,
-                                                        created_ids: request.created_ids,
-                                                    },
-                                                    core,
-                                                    session,
-                                                )
-                                                .await,
-                                                request.id,
+                                                response, request.id,
                                             ));
                                         } else {
                                             addr.do_send(WebSocketRequestError::from_error(
@@ -302,10 +328,35 @@ where
                                     )
                                 }
                             }
+                            WebSocketMessage::PushEnable(request)
+                                if request.query_subscriptions.len()
+                                    > self.core.store.config.ws_max_query_subscriptions =>
+                            {
+                                WebSocketRequestError::from_error(
+                                    RequestError::limit(RequestLimitError::QuerySubscriptions),
+                                    None,
+                                )
+                            }
+                            WebSocketMessage::PushEnable(request)
+                                if request
+                                    .query_subscriptions
+                                    .values()
+                                    .any(|subscription| subscription.is_thread_dependent()) =>
+                            {
+                                WebSocketRequestError::from_error(
+                                    RequestError::invalid_parameters().with_detail(concat!(
+                                        "Queries that depend on the other messages in a ",
+                                        "thread cannot be subscribed to."
+                                    )),
+                                    None,
+                                )
+                            }
                             WebSocketMessage::PushEnable(request) => {
                                 let core = self.core.clone();
                                 let account_id = self.session.account_id();
                                 let throttle_ms = core.store.config.ws_throttle;
+                                let mailbox_counters = request.mailbox_counters;
+                                let queries = request.query_subscriptions.into_iter().collect();
                                 let types = if let Some(data_types) = request.data_types {
                                     if !data_types.is_empty() {
                                         data_types.into()
@@ -318,7 +369,14 @@ where
 
                                 self.state_handle = Some(ctx.add_stream(async_stream::stream! {
                                     let mut change_rx = if let Some(change_rx) = core
-                                        .subscribe_state_manager(account_id, account_id, types)
+                                        .subscribe_state_manager(
+                                            account_id,
+                                            account_id,
+                                            types,
+                                            None,
+                                            mailbox_counters,
+                                            queries,
+                                        )
                                         .await
                                     {
                                         change_rx
@@ -341,6 +399,18 @@ where
                                                         .get_mut_or_insert(state_change.account_id.into())
                                                         .set(type_state, change_id.into());
                                                 }
+                                                for (mailbox_id, delta) in state_change.unread_deltas {
+                                                    *response
+                                                        .unread_deltas
+                                                        .get_mut_or_insert(state_change.account_id.into())
+                                                        .get_mut_or_insert(mailbox_id.into()) += delta;
+                                                }
+                                                for (id, query_changes) in state_change.query_changes {
+                                                    response
+                                                        .query_changes
+                                                        .get_mut_or_insert(id)
+                                                        .push(query_changes);
+                                                }
                                             }
                                             Ok(None) => {
                                                 debug!("Broadcast channel was closed.");
@@ -349,7 +419,9 @@ where
                                             Err(_) => (),
                                         }
 
-                                        timeout = if !response.changed.is_empty() {
+                                        timeout = if !response.changed.is_empty()
+                                            || !response.query_changes.is_empty()
+                                        {
                                             let elapsed = last_message.elapsed().as_millis() as u64;
                                             if elapsed >= throttle_ms {
                                                 last_message = Instant::now();
@@ -444,6 +516,8 @@ impl WebSocketStateChange {
         WebSocketStateChange {
             type_: WebSocketStateChangeType::StateChange,
             changed: VecMap::new(),
+            unread_deltas: VecMap::new(),
+            query_changes: VecMap::new(),
             push_state,
         }
     }
@@ -0,0 +1,205 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+use store::config::{env_settings::EnvSettings, settings::Setting};
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::integer("worker-pool-size").describe("Store IO pool, defaults to the number of CPUs"),
+    Setting::integer("worker-pool-crypto-size")
+        .describe("Password hashing pool, defaults to half the number of CPUs"),
+    Setting::integer("worker-pool-index-size")
+        .describe("Message parsing and indexing pool, defaults to the number of CPUs"),
+];
+
+/// Selects the thread pool a blocking task runs on. Password hashing and
+/// message parsing are CPU-bound and can take hundreds of milliseconds each,
+/// so they get their own pools to keep them from starving short store reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerPool {
+    Crypto,
+    Index,
+    Store,
+}
+
+struct Pool {
+    threads: rayon::ThreadPool,
+    queued: Arc<AtomicUsize>,
+    completed: Arc<AtomicU64>,
+}
+
+pub struct WorkerPools {
+    crypto: Pool,
+    index: Pool,
+    store: Pool,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct WorkerPoolMetrics {
+    pub threads: usize,
+    #[serde(rename = "queueDepth")]
+    pub queue_depth: usize,
+    pub completed: u64,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct WorkerPoolsMetrics {
+    pub crypto: WorkerPoolMetrics,
+    pub index: WorkerPoolMetrics,
+    pub store: WorkerPoolMetrics,
+}
+
+impl WorkerPools {
+    pub fn parse(settings: &EnvSettings) -> Self {
+        let size = |key: &str, default: usize| {
+            settings
+                .parse::<usize>(key)
+                .filter(|v| *v > 0)
+                .unwrap_or(default)
+        };
+        let num_cpus = num_cpus::get();
+
+        WorkerPools::new(
+            size("worker-pool-crypto-size", std::cmp::max(num_cpus / 2, 1)),
+            size("worker-pool-index-size", num_cpus),
+            size("worker-pool-size", num_cpus),
+        )
+    }
+
+    pub fn new(crypto_size: usize, index_size: usize, store_size: usize) -> Self {
+        WorkerPools {
+            crypto: Pool::new("crypto", crypto_size),
+            index: Pool::new("index", index_size),
+            store: Pool::new("store", store_size),
+        }
+    }
+
+    pub fn spawn<F>(&self, pool: WorkerPool, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let pool = self.pool(pool);
+        let queued = pool.queued.clone();
+        let completed = pool.completed.clone();
+
+        queued.fetch_add(1, Ordering::Relaxed);
+        pool.threads.spawn(move || {
+            queued.fetch_sub(1, Ordering::Relaxed);
+            f();
+            completed.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    /// Number of tasks waiting for a free thread on the given pool.
+    pub fn queue_depth(&self, pool: WorkerPool) -> usize {
+        self.pool(pool).queued.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> WorkerPoolsMetrics {
+        WorkerPoolsMetrics {
+            crypto: self.crypto.snapshot(),
+            index: self.index.snapshot(),
+            store: self.store.snapshot(),
+        }
+    }
+
+    fn pool(&self, pool: WorkerPool) -> &Pool {
+        match pool {
+            WorkerPool::Crypto => &self.crypto,
+            WorkerPool::Index => &self.index,
+            WorkerPool::Store => &self.store,
+        }
+    }
+}
+
+impl Pool {
+    fn new(name: &'static str, size: usize) -> Self {
+        Pool {
+            threads: rayon::ThreadPoolBuilder::new()
+                .num_threads(size)
+                .thread_name(move |i| format!("{}-worker-{}", name, i))
+                .build()
+                .unwrap(),
+            queued: Arc::new(0.into()),
+            completed: Arc::new(0.into()),
+        }
+    }
+
+    fn snapshot(&self) -> WorkerPoolMetrics {
+        WorkerPoolMetrics {
+            threads: self.threads.current_num_threads(),
+            queue_depth: self.queued.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::{WorkerPool, WorkerPools};
+
+    #[test]
+    fn worker_pool_separation() {
+        let pools = WorkerPools::new(1, 1, 2);
+        let (block_tx, block_rx) = mpsc::channel::<()>();
+        let (done_tx, done_rx) = mpsc::channel();
+
+        // Saturate the crypto pool
+        pools.spawn(WorkerPool::Crypto, move || {
+            block_rx.recv().ok();
+        });
+        for _ in 0..3 {
+            pools.spawn(WorkerPool::Crypto, || ());
+        }
+
+        // Store tasks still run while the crypto pool is busy
+        let tx = done_tx.clone();
+        pools.spawn(WorkerPool::Store, move || {
+            tx.send(std::thread::current().name().unwrap_or("").to_string())
+                .ok();
+        });
+        assert!(done_rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap()
+            .starts_with("store-worker-"));
+        assert_eq!(pools.queue_depth(WorkerPool::Crypto), 3);
+        assert_eq!(pools.queue_depth(WorkerPool::Store), 0);
+
+        block_tx.send(()).unwrap();
+        let tx = done_tx;
+        pools.spawn(WorkerPool::Crypto, move || {
+            tx.send(String::new()).ok();
+        });
+        done_rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(pools.queue_depth(WorkerPool::Crypto), 0);
+        assert!(pools.snapshot().crypto.completed >= 4);
+    }
+}
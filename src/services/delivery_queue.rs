@@ -0,0 +1,617 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::Duration;
+
+use actix_web::web;
+use jmap::{
+    orm::{serialize::JMAPOrm, TinyORM},
+    types::{jmap::JMAPId, type_state::TypeState},
+    SUPERUSER_ID,
+};
+use jmap_mail::email_submission::schema::{
+    set_delivery_status, Delivered, DeliveryRetry, DeliveryStatus, Displayed, EmailSubmission,
+    Property, UndoStatus, Value,
+};
+use store::{
+    ahash::AHashMap,
+    config::env_settings::EnvSettings,
+    core::{collection::Collection, document::Document, error::StoreError, JMAPIdPrefix},
+    parking_lot::Mutex,
+    read::{
+        comparator::Comparator,
+        filter::{Filter, Query},
+        FilterMapper,
+    },
+    tracing::error,
+    write::{batch::WriteBatch, ttl::now},
+    AccountId, DocumentId, Store,
+};
+use tokio::sync::{mpsc, Notify};
+
+use crate::JMAPServer;
+
+use super::{
+    email_delivery::{Event, SETTINGS},
+    state_change::StateChange,
+    LONG_SLUMBER_MS,
+};
+
+/// Schedule of the submissions waiting for another delivery attempt after a
+/// temporary failure. The retry state is stored in each submission, the
+/// leader keeps the schedule in memory and rebuilds it when it starts.
+pub struct DeliveryQueue {
+    pub max_retries: u32,
+    pub retry_interval: u64,
+    entries: Mutex<AHashMap<JMAPId, QueuedMessage>>,
+    changed: Notify,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueuedMessage {
+    pub account_id: AccountId,
+    pub submission_id: DocumentId,
+    pub mail_from: String,
+    pub retry: DeliveryRetry,
+    in_flight: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct QueuedMessageInfo {
+    pub id: JMAPId,
+    #[serde(rename = "accountId")]
+    pub account_id: JMAPId,
+    #[serde(rename = "submissionId")]
+    pub submission_id: JMAPId,
+    #[serde(rename = "mailFrom")]
+    pub mail_from: String,
+    pub recipients: Vec<String>,
+    pub attempts: u32,
+    #[serde(rename = "nextAttempt")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_attempt: Option<u64>,
+    #[serde(rename = "lastError")]
+    pub last_error: String,
+    #[serde(rename = "queuedAt")]
+    pub queued_at: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum QueueFilter<'x> {
+    Id(JMAPId),
+    Domain(&'x str),
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    NotFound,
+    InFlight,
+    Store(StoreError),
+}
+
+impl DeliveryQueue {
+    pub fn parse(settings: &EnvSettings) -> Self {
+        DeliveryQueue {
+            max_retries: settings.value(SETTINGS, "smtp-relay-max-retries"),
+            retry_interval: settings.value(SETTINGS, "smtp-relay-retry-interval"),
+            entries: Mutex::new(AHashMap::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn can_retry(&self, retry: Option<&DeliveryRetry>) -> bool {
+        retry.map_or(0, |retry| retry.attempts) < self.max_retries
+    }
+
+    /// Schedules another attempt for the recipients that failed temporarily,
+    /// waiting twice as long after each attempt. The returned state has to be
+    /// stored in the submission.
+    pub fn defer(
+        &self,
+        account_id: AccountId,
+        submission_id: DocumentId,
+        mail_from: &str,
+        recipients: Vec<String>,
+        last_error: String,
+        previous: Option<&DeliveryRetry>,
+    ) -> DeliveryRetry {
+        let now = now();
+        let attempts = previous.map_or(0, |retry| retry.attempts) + 1;
+        let retry = DeliveryRetry {
+            recipients,
+            attempts,
+            next_attempt: now
+                + self
+                    .retry_interval
+                    .saturating_mul(1 << std::cmp::min(attempts - 1, 16)),
+            last_error,
+            queued_at: previous.map_or(now, |retry| retry.queued_at),
+        };
+        self.schedule(account_id, submission_id, mail_from, retry.clone());
+        retry
+    }
+
+    fn schedule(
+        &self,
+        account_id: AccountId,
+        submission_id: DocumentId,
+        mail_from: &str,
+        retry: DeliveryRetry,
+    ) {
+        self.entries.lock().insert(
+            JMAPId::from_parts(account_id, submission_id),
+            QueuedMessage {
+                account_id,
+                submission_id,
+                mail_from: mail_from.to_string(),
+                retry,
+                in_flight: false,
+            },
+        );
+        self.changed.notify_one();
+    }
+
+    pub fn remove(&self, account_id: AccountId, submission_id: DocumentId) {
+        self.entries
+            .lock()
+            .remove(&JMAPId::from_parts(account_id, submission_id));
+    }
+
+    /// Makes in flight entries that were not attempted available again.
+    pub fn release(&self, account_id: AccountId, submission_ids: &[DocumentId]) {
+        let next_attempt = now() + self.retry_interval;
+        let mut entries = self.entries.lock();
+        for submission_id in submission_ids {
+            if let Some(message) = entries.get_mut(&JMAPId::from_parts(account_id, *submission_id))
+            {
+                if message.in_flight {
+                    message.in_flight = false;
+                    message.retry.next_attempt = next_attempt;
+                }
+            }
+        }
+        drop(entries);
+
+        self.changed.notify_one();
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().clear();
+    }
+
+    pub fn list(&self, domain: Option<&str>) -> Vec<QueuedMessageInfo> {
+        let mut list = self
+            .entries
+            .lock()
+            .iter()
+            .filter(|(id, message)| {
+                domain.map_or(true, |domain| {
+                    message.matches(**id, QueueFilter::Domain(domain))
+                })
+            })
+            .map(|(id, message)| message.info(*id))
+            .collect::<Vec<_>>();
+        list.sort_unstable_by_key(|info| (info.next_attempt.unwrap_or(0), u64::from(info.id)));
+        list
+    }
+
+    pub fn info(&self, id: JMAPId) -> Option<QueuedMessageInfo> {
+        self.entries.lock().get(&id).map(|message| message.info(id))
+    }
+
+    /// Moves the next attempt of the matching entries to the specified time,
+    /// or retries them right away.
+    fn reschedule(
+        &self,
+        filter: QueueFilter,
+        at: Option<u64>,
+    ) -> Result<Vec<(QueuedMessageInfo, DeliveryRetry)>, QueueError> {
+        let next_attempt = at.unwrap_or(0).max(now());
+        let mut entries = self.entries.lock();
+        let mut result = Vec::new();
+        for (id, message) in entries.iter_mut() {
+            if message.matches(*id, filter) {
+                if !message.in_flight {
+                    message.retry.next_attempt = next_attempt;
+                    result.push((message.info(*id), message.retry.clone()));
+                } else if matches!(filter, QueueFilter::Id(_)) {
+                    return Err(QueueError::InFlight);
+                }
+            }
+        }
+        drop(entries);
+
+        if !result.is_empty() || !matches!(filter, QueueFilter::Id(_)) {
+            self.changed.notify_one();
+            Ok(result)
+        } else {
+            Err(QueueError::NotFound)
+        }
+    }
+
+    /// Removes the matching recipients from the queue, returning the canceled
+    /// recipients of each submission and the retry state left, if any.
+    #[allow(clippy::type_complexity)]
+    fn cancel(
+        &self,
+        filter: QueueFilter,
+    ) -> Result<Vec<(QueuedMessageInfo, Vec<String>, Option<DeliveryRetry>)>, QueueError> {
+        let mut entries = self.entries.lock();
+        let mut result = Vec::new();
+        for (id, message) in entries.iter_mut() {
+            if message.matches(*id, filter) {
+                if message.in_flight {
+                    if matches!(filter, QueueFilter::Id(_)) {
+                        return Err(QueueError::InFlight);
+                    }
+                    continue;
+                }
+                let info = message.info(*id);
+                let canceled = if let QueueFilter::Domain(domain) = filter {
+                    let (canceled, remaining): (Vec<_>, Vec<_>) =
+                        std::mem::take(&mut message.retry.recipients)
+                            .into_iter()
+                            .partition(|rcpt| has_domain(rcpt, domain));
+                    message.retry.recipients = remaining;
+                    canceled
+                } else {
+                    std::mem::take(&mut message.retry.recipients)
+                };
+                result.push((
+                    info,
+                    canceled,
+                    if !message.retry.recipients.is_empty() {
+                        Some(message.retry.clone())
+                    } else {
+                        None
+                    },
+                ));
+            }
+        }
+        entries.retain(|_, message| !message.retry.recipients.is_empty());
+
+        if !result.is_empty() || !matches!(filter, QueueFilter::Id(_)) {
+            Ok(result)
+        } else {
+            Err(QueueError::NotFound)
+        }
+    }
+
+    /// Marks the entries due for another attempt as in flight.
+    fn take_due(&self) -> (Vec<(AccountId, Vec<DocumentId>)>, Option<u64>) {
+        let now = now();
+        let mut due: Vec<(AccountId, Vec<DocumentId>)> = Vec::new();
+        let mut next_attempt = None;
+        for message in self.entries.lock().values_mut() {
+            if message.in_flight {
+                continue;
+            } else if message.retry.next_attempt <= now {
+                message.in_flight = true;
+                if let Some((_, ids)) = due
+                    .iter_mut()
+                    .find(|(account_id, _)| *account_id == message.account_id)
+                {
+                    ids.push(message.submission_id);
+                } else {
+                    due.push((message.account_id, vec![message.submission_id]));
+                }
+            } else if next_attempt.map_or(true, |next| message.retry.next_attempt < next) {
+                next_attempt = message.retry.next_attempt.into();
+            }
+        }
+        (due, next_attempt)
+    }
+}
+
+impl QueuedMessage {
+    fn matches(&self, id: JMAPId, filter: QueueFilter) -> bool {
+        match filter {
+            QueueFilter::Id(filter_id) => id == filter_id,
+            QueueFilter::Domain(domain) => self
+                .retry
+                .recipients
+                .iter()
+                .any(|rcpt| has_domain(rcpt, domain)),
+        }
+    }
+
+    fn info(&self, id: JMAPId) -> QueuedMessageInfo {
+        QueuedMessageInfo {
+            id,
+            account_id: self.account_id.into(),
+            submission_id: self.submission_id.into(),
+            mail_from: self.mail_from.clone(),
+            recipients: self.retry.recipients.clone(),
+            attempts: self.retry.attempts,
+            next_attempt: if !self.in_flight {
+                Some(self.retry.next_attempt)
+            } else {
+                None
+            },
+            last_error: self.retry.last_error.clone(),
+            queued_at: self.retry.queued_at,
+        }
+    }
+}
+
+fn has_domain(rcpt: &str, domain: &str) -> bool {
+    rcpt.rsplit_once('@').map_or(false, |(_, rcpt_domain)| {
+        rcpt_domain.eq_ignore_ascii_case(domain)
+    })
+}
+
+pub fn spawn_delivery_retries<T>(core: web::Data<JMAPServer<T>>, queue_tx: mpsc::Sender<Event>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            let (due, next_attempt) = core.delivery_queue.take_due();
+            for (account_id, submission_ids) in due {
+                if queue_tx
+                    .send(Event::new_submission(
+                        account_id,
+                        submission_ids,
+                        Vec::new(),
+                    ))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+
+            let wait = next_attempt.map_or(Duration::from_millis(LONG_SLUMBER_MS), |at| {
+                Duration::from_secs(at.saturating_sub(now()))
+            });
+            tokio::select! {
+                _ = core.delivery_queue.changed.notified() => (),
+                _ = tokio::time::sleep(wait) => (),
+            }
+        }
+    });
+}
+
+impl<T> JMAPServer<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Rebuilds the schedule from the retry state stored in the submissions.
+    pub async fn delivery_queue_load(&self) -> store::Result<()> {
+        let store = self.store.clone();
+        let queued = self
+            .spawn_worker(move || {
+                let mut queued = Vec::new();
+                for account_id in store
+                    .get_document_ids(SUPERUSER_ID, Collection::Principal)?
+                    .unwrap_or_default()
+                {
+                    for submission_id in store
+                        .query_store::<FilterMapper>(
+                            account_id,
+                            Collection::EmailSubmission,
+                            Filter::ge(Property::DeliveryRetry.into(), Query::LongInteger(0)),
+                            Comparator::None,
+                        )?
+                        .into_iter()
+                        .map(|id| id.get_document_id())
+                    {
+                        if let Some(mut fields) =
+                            store.get_orm::<EmailSubmission>(account_id, submission_id)?
+                        {
+                            if let (
+                                Some(Value::DeliveryRetry { value: retry }),
+                                Some(Value::Envelope { value: envelope }),
+                            ) = (
+                                fields.remove(&Property::DeliveryRetry),
+                                fields.get(&Property::Envelope),
+                            ) {
+                                queued.push((
+                                    account_id,
+                                    submission_id,
+                                    envelope.mail_from.email.clone(),
+                                    retry,
+                                ));
+                            }
+                        }
+                    }
+                }
+                Ok(queued)
+            })
+            .await?;
+
+        self.delivery_queue.clear();
+        for (account_id, submission_id, mail_from, retry) in queued {
+            self.delivery_queue
+                .schedule(account_id, submission_id, &mail_from, retry);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the pending submissions held for their undo window, which are
+    /// only scheduled in memory once they are created.
+    pub async fn held_submissions_load(&self) -> store::Result<Vec<(AccountId, Vec<DocumentId>)>> {
+        let store = self.store.clone();
+        self.spawn_worker(move || {
+            let mut held = Vec::new();
+            for account_id in store
+                .get_document_ids(SUPERUSER_ID, Collection::Principal)?
+                .unwrap_or_default()
+            {
+                let submission_ids = store
+                    .query_store::<FilterMapper>(
+                        account_id,
+                        Collection::EmailSubmission,
+                        Filter::and(vec![
+                            Filter::ge(Property::UndoData.into(), Query::LongInteger(0)),
+                            Filter::eq(
+                                Property::UndoStatus.into(),
+                                Query::Keyword("p".to_string()),
+                            ),
+                        ]),
+                        Comparator::None,
+                    )?
+                    .into_iter()
+                    .map(|id| id.get_document_id())
+                    .collect::<Vec<_>>();
+                if !submission_ids.is_empty() {
+                    held.push((account_id, submission_ids));
+                }
+            }
+            Ok(held)
+        })
+        .await
+    }
+
+    /// Moves the next attempt of the matching entries to the specified time,
+    /// or retries them right away.
+    pub async fn delivery_queue_reschedule(
+        &self,
+        filter: QueueFilter<'_>,
+        at: Option<u64>,
+    ) -> Result<Vec<QueuedMessageInfo>, QueueError> {
+        let rescheduled = self.delivery_queue.reschedule(filter, at)?;
+        let mut updates = Vec::with_capacity(rescheduled.len());
+        let mut result = Vec::with_capacity(rescheduled.len());
+        for (info, retry) in rescheduled {
+            updates.push((info.account_id, info.submission_id, Vec::new(), Some(retry)));
+            result.push(info);
+        }
+        self.delivery_queue_update(updates).await?;
+        Ok(result)
+    }
+
+    /// Cancels the delivery of the matching queued recipients and records the
+    /// cancellation in their submissions.
+    pub async fn delivery_queue_cancel(
+        &self,
+        filter: QueueFilter<'_>,
+    ) -> Result<Vec<QueuedMessageInfo>, QueueError> {
+        let canceled = self.delivery_queue.cancel(filter)?;
+        let mut updates = Vec::with_capacity(canceled.len());
+        let mut result = Vec::with_capacity(canceled.len());
+        for (info, recipients, retry) in canceled {
+            updates.push((info.account_id, info.submission_id, recipients, retry));
+            result.push(info);
+        }
+        self.delivery_queue_update(updates).await?;
+        Ok(result)
+    }
+
+    /// Stores the retry state of the submissions, marking the recipients
+    /// removed from the queue as canceled.
+    async fn delivery_queue_update(
+        &self,
+        updates: Vec<(JMAPId, JMAPId, Vec<String>, Option<DeliveryRetry>)>,
+    ) -> Result<(), QueueError> {
+        let mut accounts: AHashMap<AccountId, Vec<_>> = AHashMap::new();
+        for (account_id, submission_id, canceled, retry) in updates {
+            accounts
+                .entry(account_id.get_document_id())
+                .or_default()
+                .push((submission_id.get_document_id(), canceled, retry));
+        }
+
+        for (account_id, submissions) in accounts {
+            let store = self.store.clone();
+            match self
+                .spawn_worker(move || {
+                    let mut batch = WriteBatch::new(account_id);
+                    for (submission_id, canceled, retry) in submissions {
+                        let current = if let Some(current) =
+                            store.get_orm::<EmailSubmission>(account_id, submission_id)?
+                        {
+                            current
+                        } else {
+                            continue;
+                        };
+                        let mut fields = TinyORM::track_changes(&current);
+                        if !canceled.is_empty() {
+                            let mut delivery_status = match current.get(&Property::DeliveryStatus) {
+                                Some(Value::DeliveryStatus { value }) => value.clone(),
+                                _ => AHashMap::new(),
+                            };
+                            for rcpt in canceled {
+                                delivery_status.insert(
+                                    rcpt,
+                                    DeliveryStatus::new(
+                                        "Delivery canceled by the administrator.".to_string(),
+                                        Delivered::No,
+                                        Displayed::Unknown,
+                                    ),
+                                );
+                            }
+                            if retry.is_none()
+                                && !delivery_status
+                                    .values()
+                                    .any(|status| status.delivered == Delivered::Queued)
+                            {
+                                fields.set(
+                                    Property::UndoStatus,
+                                    Value::UndoStatus {
+                                        value: UndoStatus::Canceled,
+                                    },
+                                );
+                            }
+                            set_delivery_status(&current, &mut fields, delivery_status);
+                        }
+                        fields.set(
+                            Property::DeliveryRetry,
+                            retry.map_or(Value::Null, |value| Value::DeliveryRetry { value }),
+                        );
+
+                        let mut document =
+                            Document::new(Collection::EmailSubmission, submission_id);
+                        current.merge(&mut document, fields)?;
+                        if !document.is_empty() {
+                            batch.update_document(document);
+                            batch.log_update(Collection::EmailSubmission, submission_id);
+                        }
+                    }
+                    store.write(batch)
+                })
+                .await
+            {
+                Ok(Some(changes)) => {
+                    if self.is_in_cluster() {
+                        self.commit_index(changes.change_id).await;
+                    }
+                    if let Err(err) = self
+                        .publish_state_change(StateChange::new(
+                            account_id,
+                            vec![(TypeState::EmailSubmission, changes.change_id)],
+                        ))
+                        .await
+                    {
+                        error!("Failed to publish state change: {}", err);
+                    }
+                }
+                Ok(None) => (),
+                Err(err) => return Err(QueueError::Store(err)),
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,293 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use hickory_resolver::{
+    config::{NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    TokioAsyncResolver,
+};
+use store::{
+    config::{env_settings::EnvSettings, settings::Setting},
+    tracing::{debug, error},
+};
+
+use crate::server::failed_to;
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::one_of("dns-resolver", &["system", "cloudflare", "google", "quad9"]).default("system"),
+    Setting::list("dns-servers").describe("Custom upstream resolvers, overrides dns-resolver"),
+    Setting::integer("dns-cache-size").default("1024"),
+    Setting::seconds("dns-cache-min-ttl"),
+    Setting::seconds("dns-cache-max-ttl"),
+    Setting::seconds("dns-negative-cache-ttl").default("300"),
+    Setting::millis("dns-timeout").default("5000"),
+    Setting::integer("dns-attempts").default("2"),
+];
+
+// Resolves the MX, TXT and address records needed for recipient validation and
+// SPF/DKIM/DMARC checks. Answers (including negative ones) are cached by the
+// resolver itself for the record's TTL, bounded by the configured limits.
+pub struct DnsResolver {
+    resolver: TokioAsyncResolver,
+    #[cfg(test)]
+    pub txt_records: std::sync::Mutex<store::ahash::AHashMap<String, Vec<String>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MX {
+    pub exchange: String,
+    pub preference: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsError {
+    NotFound,
+    Failure(String),
+}
+
+pub type Result<T> = std::result::Result<T, DnsError>;
+
+impl DnsResolver {
+    pub fn parse(settings: &EnvSettings) -> Self {
+        let config = if let Some(servers) = settings.get("dns-servers") {
+            let mut group = Vec::new();
+            for server in servers
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+            {
+                let socket_addr = parse_server(server).unwrap_or_else(|| {
+                    failed_to(&format!("parse 'dns-servers', invalid address {}.", server));
+                });
+                group.push(NameServerConfig::new(socket_addr, Protocol::Udp));
+                group.push(NameServerConfig::new(socket_addr, Protocol::Tcp));
+            }
+            Some(ResolverConfig::from_parts(
+                None,
+                vec![],
+                NameServerConfigGroup::from(group),
+            ))
+        } else {
+            match settings.value::<String>(SETTINGS, "dns-resolver").as_str() {
+                "cloudflare" => Some(ResolverConfig::cloudflare()),
+                "google" => Some(ResolverConfig::google()),
+                "quad9" => Some(ResolverConfig::quad9()),
+                "system" => None,
+                other => failed_to(&format!(
+                    "parse 'dns-resolver', unknown resolver {}.",
+                    other
+                )),
+            }
+        };
+
+        let mut opts = ResolverOpts::default();
+        opts.cache_size = settings.value(SETTINGS, "dns-cache-size");
+        opts.timeout = Duration::from_millis(settings.value(SETTINGS, "dns-timeout"));
+        opts.attempts = settings.value(SETTINGS, "dns-attempts");
+        opts.positive_min_ttl = settings.parse("dns-cache-min-ttl").map(Duration::from_secs);
+        opts.positive_max_ttl = settings.parse("dns-cache-max-ttl").map(Duration::from_secs);
+        opts.negative_max_ttl =
+            Duration::from_secs(settings.value(SETTINGS, "dns-negative-cache-ttl")).into();
+
+        let resolver = match config {
+            Some(config) => TokioAsyncResolver::tokio(config, opts),
+            None => {
+                let (config, mut system_opts) = hickory_resolver::system_conf::read_system_conf()
+                    .unwrap_or_else(|err| {
+                        error!(
+                            "Failed to read system DNS configuration, using defaults: {}",
+                            err
+                        );
+                        (ResolverConfig::default(), ResolverOpts::default())
+                    });
+                system_opts.cache_size = opts.cache_size;
+                system_opts.timeout = opts.timeout;
+                system_opts.attempts = opts.attempts;
+                system_opts.positive_min_ttl = opts.positive_min_ttl;
+                system_opts.positive_max_ttl = opts.positive_max_ttl;
+                system_opts.negative_max_ttl = opts.negative_max_ttl;
+                TokioAsyncResolver::tokio(config, system_opts)
+            }
+        };
+
+        DnsResolver {
+            resolver,
+            #[cfg(test)]
+            txt_records: Default::default(),
+        }
+    }
+
+    // Returns the mail exchangers for a domain sorted by preference, falling back
+    // to the domain itself when it has no MX records (RFC 5321, section 5.1).
+    pub async fn mx_lookup(&self, domain: &str) -> Result<Vec<MX>> {
+        match self.resolver.mx_lookup(fqdn(domain)).await {
+            Ok(lookup) => {
+                let mut mxs = lookup
+                    .iter()
+                    .map(|mx| MX {
+                        exchange: mx.exchange().to_utf8().trim_end_matches('.').to_lowercase(),
+                        preference: mx.preference(),
+                    })
+                    .collect::<Vec<_>>();
+                mxs.sort_unstable_by_key(|mx| mx.preference);
+
+                // A single null MX indicates that the domain does not accept email (RFC 7505)
+                if mxs.len() == 1 && mxs[0].exchange.is_empty() {
+                    Err(DnsError::NotFound)
+                } else {
+                    Ok(mxs)
+                }
+            }
+            Err(err) if is_not_found(&err) && !is_nxdomain(&err) => Ok(vec![MX {
+                exchange: domain.trim_end_matches('.').to_lowercase(),
+                preference: 0,
+            }]),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub async fn ip_lookup(&self, host: &str) -> Result<Vec<IpAddr>> {
+        self.resolver
+            .lookup_ip(fqdn(host))
+            .await
+            .map(|lookup| lookup.iter().collect())
+            .map_err(Into::into)
+    }
+
+    // Returns each TXT record with its character strings concatenated.
+    pub async fn txt_lookup(&self, name: &str) -> Result<Vec<String>> {
+        #[cfg(test)]
+        if let Some(records) = self
+            .txt_records
+            .lock()
+            .unwrap()
+            .get(name.trim_end_matches('.'))
+        {
+            return Ok(records.clone());
+        }
+
+        self.resolver
+            .txt_lookup(fqdn(name))
+            .await
+            .map(|lookup| {
+                lookup
+                    .iter()
+                    .map(|txt| {
+                        txt.txt_data()
+                            .iter()
+                            .map(|data| String::from_utf8_lossy(data))
+                            .collect::<String>()
+                    })
+                    .collect()
+            })
+            .map_err(Into::into)
+    }
+}
+
+fn parse_server(server: &str) -> Option<SocketAddr> {
+    server.parse::<SocketAddr>().ok().or_else(|| {
+        server
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, 53))
+    })
+}
+
+// Queries are always absolute so that the system search domains are not appended.
+fn fqdn(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_string()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+fn is_not_found(err: &ResolveError) -> bool {
+    matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+fn is_nxdomain(err: &ResolveError) -> bool {
+    matches!(
+        err.kind(),
+        ResolveErrorKind::NoRecordsFound { response_code, .. }
+            if *response_code == hickory_resolver::proto::op::ResponseCode::NXDomain
+    )
+}
+
+impl From<ResolveError> for DnsError {
+    fn from(err: ResolveError) -> Self {
+        if is_not_found(&err) {
+            DnsError::NotFound
+        } else {
+            DnsError::Failure(err.to_string())
+        }
+    }
+}
+
+impl Display for DnsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DnsError::NotFound => write!(f, "Record not found"),
+            DnsError::Failure(err) => write!(f, "DNS failure: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{fqdn, parse_server};
+
+    #[test]
+    fn parse_dns_servers() {
+        assert_eq!(
+            parse_server("9.9.9.9"),
+            Some("9.9.9.9:53".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(
+            parse_server("127.0.0.1:5353"),
+            Some("127.0.0.1:5353".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(
+            parse_server("[2620:fe::fe]:53"),
+            Some("[2620:fe::fe]:53".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(
+            parse_server("2620:fe::fe"),
+            Some("[2620:fe::fe]:53".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(parse_server("dns.example.org"), None);
+
+        assert_eq!(fqdn("example.org"), "example.org.");
+        assert_eq!(fqdn("example.org."), "example.org.");
+    }
+}
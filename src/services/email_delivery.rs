@@ -21,22 +21,32 @@
  * for more details.
 */
 
-use std::{collections::VecDeque, time::Duration};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use actix_web::web;
 use jmap::{
     orm::{serialize::JMAPOrm, TinyORM},
     types::type_state::TypeState,
 };
+use jmap_mail::email_submission::dsn::envelope_id;
 use jmap_mail::email_submission::schema::{
-    Delivered, DeliveryStatus, Displayed, EmailSubmission, Property, UndoStatus, Value,
+    set_delivery_status, Address, Delivered, DeliveryStatus, Displayed, EmailSubmission, Property,
+    UndoStatus, Value,
 };
-use jmap_mail::mail_send::{smtp::message::Message, Transport};
-use jmap_sharing::principal::get::JMAPGetPrincipal;
+use jmap_mail::mail::limits::MimeLimits;
+use jmap_mail::mail::template::{build_delivery_failure, message_headers, JMAPMailTemplate};
+use jmap_mail::mail_parser::Message as ParsedMessage;
+use jmap_mail::mail_send::{self, smtp::message::Message, Transport};
+use jmap_sharing::principal::{account::JMAPAccountStore, get::JMAPGetPrincipal};
 use store::{
-    ahash::AHashMap,
+    ahash::{AHashMap, AHashSet},
     blob::BlobId,
-    config::env_settings::EnvSettings,
+    config::{env_settings::EnvSettings, settings::Setting, templates::TEMPLATE_DSN_FAILURE},
     core::{collection::Collection, document::Document},
     tracing::{debug, log::error},
     write::batch::WriteBatch,
@@ -44,11 +54,43 @@ use store::{
 };
 use tokio::sync::mpsc;
 
-use crate::{cluster::IPC_CHANNEL_BUFFER, JMAPServer};
+use crate::{
+    cluster::IPC_CHANNEL_BUFFER,
+    lmtp::{
+        authentication::{arc::ArcSealer, AuthOutcome},
+        ingest::DeliveryStatus as IngestStatus,
+        session::RcptType,
+        srs::srs_forward,
+    },
+    JMAPServer,
+};
 
-use super::state_change::StateChange;
+use super::{
+    delivery_queue::spawn_delivery_retries, footer::MessageFooters, state_change::StateChange,
+};
 
-const DEFAULT_SMTP_TIMEOUT_MS: u64 = 60000;
+pub const SETTINGS: &[Setting] = &[
+    Setting::text("smtp-relay-host"),
+    Setting::integer("smtp-relay-port")
+        .max(65535)
+        .default("0")
+        .describe("0 = default port"),
+    Setting::text("smtp-relay-auth"),
+    Setting::secret("smtp-relay-secret"),
+    Setting::bool("smtp-relay-tls").default("false"),
+    Setting::bool("smtp-relay-dsn")
+        .default("false")
+        .describe("Relay supports DSN, bounces are matched by envelope id"),
+    Setting::millis("smtp-relay-timeout").default("60000"),
+    Setting::integer("smtp-relay-max-retries")
+        .max(u32::MAX as u64)
+        .default("6")
+        .describe("0 = temporary failures are final"),
+    Setting::seconds("smtp-relay-retry-interval")
+        .min(1)
+        .default("300")
+        .describe("Doubled after each attempt"),
+];
 
 pub enum Event {
     EmailSubmission {
@@ -60,6 +102,8 @@ pub enum Event {
         from: String,
         to: Vec<String>,
         message: Vec<u8>,
+        seal_domain: Option<String>,
+        auth: Option<Arc<AuthOutcome>>,
     },
     RelayReady,
     Reload,
@@ -81,7 +125,13 @@ impl Event {
     }
 
     pub fn outgoing_message(from: String, to: Vec<String>, message: Vec<u8>) -> Self {
-        Event::OutgoingMessage { from, to, message }
+        Event::OutgoingMessage {
+            from,
+            to,
+            message,
+            seal_domain: None,
+            auth: None,
+        }
     }
 }
 
@@ -99,7 +149,13 @@ pub fn spawn_email_delivery<T>(
 {
     // Parse SMTP relay
     let relay_tx = if let Some(smtp_relay) = parse_smtp_settings(settings) {
-        spawn_email_relay(core, smtp_relay, tx)
+        spawn_delivery_retries(core.clone(), tx.clone());
+        spawn_email_relay(
+            core.clone(),
+            smtp_relay,
+            MessageFooters::parse(settings),
+            tx,
+        )
     } else {
         return;
     };
@@ -108,6 +164,11 @@ pub fn spawn_email_delivery<T>(
         let mut queue = VecDeque::new();
         let mut is_ready = true;
 
+        // In a cluster the queue is loaded by the leader once elected
+        if !core.is_in_cluster() {
+            load_queues(&core, &relay_tx).await;
+        }
+
         while let Some(event) = rx.recv().await {
             match event {
                 Event::RelayReady => {
@@ -124,8 +185,11 @@ pub fn spawn_email_delivery<T>(
                         error!("Error sending event to relay: {}", err);
                     }
                     queue.clear();
+                    core.delivery_queue.clear();
+                }
+                Event::Start => {
+                    load_queues(&core, &relay_tx).await;
                 }
-                Event::Start => (),
                 event => {
                     if is_ready {
                         if let Err(err) = relay_tx.send(event).await {
@@ -140,9 +204,40 @@ pub fn spawn_email_delivery<T>(
     });
 }
 
+/// Rebuilds the retry schedule and requeues the submissions held for their
+/// undo window, as both are only kept in memory by the leader.
+async fn load_queues<T>(core: &JMAPServer<T>, relay_tx: &mpsc::Sender<Event>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    if let Err(err) = core.delivery_queue_load().await {
+        error!("Failed to load the delivery queue: {}", err);
+    }
+    match core.held_submissions_load().await {
+        Ok(held) => {
+            for (account_id, submission_ids) in held {
+                if let Err(err) = relay_tx
+                    .send(Event::new_submission(
+                        account_id,
+                        submission_ids,
+                        Vec::new(),
+                    ))
+                    .await
+                {
+                    error!("Error sending event to relay: {}", err);
+                }
+            }
+        }
+        Err(err) => {
+            error!("Failed to load held submissions: {}", err);
+        }
+    }
+}
+
 fn spawn_email_relay<T>(
     core: web::Data<JMAPServer<T>>,
     smtp_relay: SMTPRelay,
+    footers: MessageFooters,
     queue_tx: mpsc::Sender<Event>,
 ) -> mpsc::Sender<Event>
 where
@@ -159,7 +254,13 @@ where
             client = client.credentials(username, secret);
         }
         let is_tls = smtp_relay.tls;
+        let mime_limits = MimeLimits::from(&core.store.config);
         let mut dkim_map = AHashMap::new();
+        let mut arc_sealers = AHashMap::new();
+        let hostname = gethostname::gethostname()
+            .to_str()
+            .unwrap_or("localhost")
+            .to_string();
 
         while let Some(event) = rx.recv().await {
             match event {
@@ -170,15 +271,49 @@ where
                 } => {
                     // Fetch submissions
                     let account_id = account_id;
-                    let store = core.store.clone();
-                    let messages = match core
+                    let submission_ids = created_ids.clone();
+                    let server = core.clone();
+                    let (messages, held_ids, release_at) = match core
                         .spawn_worker(move || {
+                            let store = &server.store;
                             let mut messages = Vec::with_capacity(created_ids.len());
+                            let mut held_ids = Vec::new();
+                            let mut release_at = i64::MAX;
+                            let now = SystemTime::now()
+                                .duration_since(SystemTime::UNIX_EPOCH)
+                                .map(|d| d.as_secs())
+                                .unwrap_or(0) as i64;
 
-                            for created_id in created_ids {
+                            for created_id in created_ids.iter().copied() {
                                 if let Some(email_submission) =
                                     store.get_orm::<EmailSubmission>(account_id, created_id)?
                                 {
+                                    // Skip submissions canceled during the undo window,
+                                    // partially delivered messages are final but may
+                                    // still have recipients waiting to be retried.
+                                    match email_submission.get(&Property::UndoStatus) {
+                                        Some(Value::UndoStatus {
+                                            value: UndoStatus::Pending,
+                                        }) => (),
+                                        Some(Value::UndoStatus {
+                                            value: UndoStatus::Final,
+                                        }) if email_submission
+                                            .has_property(&Property::DeliveryRetry) => {}
+                                        _ => continue,
+                                    }
+
+                                    // Hold submissions until their undo window expires
+                                    if let Some(Value::UndoData { value }) =
+                                        email_submission.get(&Property::UndoData)
+                                    {
+                                        if value.release_at > now {
+                                            release_at =
+                                                std::cmp::min(release_at, value.release_at);
+                                            held_ids.push(created_id);
+                                            continue;
+                                        }
+                                    }
+
                                     if let Some(blob_id) = store.get_document_value::<BlobId>(
                                         account_id,
                                         Collection::EmailSubmission,
@@ -192,288 +327,415 @@ where
                                 }
                             }
 
-                            Ok(messages)
+                            // Drop retries of submissions that were canceled or removed
+                            for created_id in created_ids {
+                                if !held_ids.contains(&created_id)
+                                    && !messages.iter().any(|(id, _, _)| *id == created_id)
+                                {
+                                    server.delivery_queue.remove(account_id, created_id);
+                                }
+                            }
+
+                            Ok((messages, held_ids, release_at - now))
                         })
                         .await
                     {
-                        Ok(messages) => {
-                            if messages.is_empty() {
-                                continue;
-                            }
-                            messages
-                        }
+                        Ok((messages, held_ids, release_at)) => (messages, held_ids, release_at),
                         Err(err) => {
                             error!("Error getting email submissions: {}", err);
+                            core.delivery_queue.release(account_id, &submission_ids);
                             continue;
                         }
                     };
 
+                    // Requeue held submissions, allowing a short grace period for
+                    // cancellations that were accepted right before the deadline.
+                    if !held_ids.is_empty() {
+                        let queue_tx = queue_tx.clone();
+                        let core = core.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(Duration::from_secs(release_at as u64 + 1)).await;
+                            // Leadership might have changed while the messages were held
+                            if core.is_in_cluster() && !core.is_leader() {
+                                return;
+                            }
+                            if let Err(err) = queue_tx
+                                .send(Event::new_submission(account_id, held_ids, Vec::new()))
+                                .await
+                            {
+                                error!("Error requeueing held submissions: {}", err);
+                            }
+                        });
+                    }
+                    if messages.is_empty() {
+                        continue;
+                    }
+
                     // Connect to relay server
                     let mut results = Vec::with_capacity(messages.len());
-                    match if is_tls {
+                    let mut failures = Vec::new();
+                    let (mut connection, connect_error, connect_is_temporary) = match if is_tls {
                         client.clone().connect_tls().await
                     } else {
                         client.clone().connect().await
                     } {
-                        Ok(mut client) => {
-                            for (email_submission_id, current_email_submission, raw_message) in
-                                messages
-                            {
-                                // Track changes
-                                let mut email_submission =
-                                    TinyORM::track_changes(&current_email_submission);
-
-                                // Access envelope
-                                let envelope = if let Some(envelope) = current_email_submission
-                                    .get(&Property::Envelope)
-                                    .and_then(|value| {
-                                        if let Value::Envelope { value } = value {
-                                            Some(value)
-                                        } else {
-                                            None
-                                        }
-                                    }) {
-                                    envelope
+                        Ok(client) => (Some(client), String::new(), false),
+                        Err(err) => {
+                            // Fail or defer all submissions
+                            error!("Failed to connect to relay server: {}", err);
+                            (None, err.to_string(), is_temporary_error(&err))
+                        }
+                    };
+
+                    for (email_submission_id, current_email_submission, raw_message) in messages {
+                        // Track changes
+                        let mut email_submission =
+                            TinyORM::track_changes(&current_email_submission);
+
+                        // Access envelope
+                        let envelope = if let Some(envelope) = current_email_submission
+                            .get(&Property::Envelope)
+                            .and_then(|value| {
+                                if let Value::Envelope { value } = value {
+                                    Some(value)
                                 } else {
-                                    error!(
-                                        "Missing envelope for {}/{}",
-                                        account_id, email_submission_id
-                                    );
-                                    continue;
+                                    None
+                                }
+                            }) {
+                            envelope
+                        } else {
+                            error!(
+                                "Missing envelope for {}/{}",
+                                account_id, email_submission_id
+                            );
+                            continue;
+                        };
+
+                        // Retries are only sent to the recipients that failed temporarily
+                        let queued = match current_email_submission.get(&Property::DeliveryRetry) {
+                            Some(Value::DeliveryRetry { value }) => Some(value),
+                            _ => None,
+                        };
+                        let rcpt_to = envelope
+                            .rcpt_to
+                            .iter()
+                            .filter(|rcpt| {
+                                queued
+                                    .map_or(true, |queued| queued.recipients.contains(&rcpt.email))
+                            })
+                            .collect::<Vec<_>>();
+
+                        // Keep the original headers in case a report has to be sent
+                        let original_headers = message_headers(&raw_message).to_vec();
+
+                        // Create delivery status list, keeping track of the
+                        // recipients that can be retried later
+                        let mut delivery_status = AHashMap::with_capacity(rcpt_to.len());
+                        let mut temporary_failures = AHashSet::new();
+
+                        if let Some(client) = &mut connection {
+                            // Fetch dkim settings
+                            let domain_name = envelope
+                                .mail_from
+                                .email
+                                .split_once('@')
+                                .unwrap()
+                                .1
+                                .to_string();
+                            let dkim = if let Some(dkim) = dkim_map.get(&domain_name) {
+                                dkim
+                            } else {
+                                match core.store.dkim_get(domain_name.clone()) {
+                                    Ok(dkim) => {
+                                        dkim_map.insert(
+                                            domain_name.clone(),
+                                            if let Some(dkim) = dkim {
+                                                dkim.headers([
+                                                    "From",
+                                                    "To",
+                                                    "Subject",
+                                                    "Date",
+                                                    "Cc",
+                                                    "Bcc",
+                                                    "Message-ID",
+                                                    "References",
+                                                    "In-Reply-To",
+                                                ])
+                                                .into()
+                                            } else {
+                                                None
+                                            },
+                                        );
+                                        dkim_map.get(&domain_name).unwrap()
+                                    }
+                                    Err(err) => {
+                                        error!(
+                                            "Error getting DKIM settings for domain '{}': {}",
+                                            domain_name, err
+                                        );
+                                        continue;
+                                    }
+                                }
+                            };
+
+                            // Senders outside the local domains are rewritten, so that SPF
+                            // checks pass and bounces are routed back through this server
+                            let envelope_from =
+                                match srs_sender(&core, account_id, &envelope.mail_from.email) {
+                                    Some(email) => Cow::Owned(Address {
+                                        email,
+                                        parameters: envelope.mail_from.parameters.clone(),
+                                    }),
+                                    None => Cow::Borrowed(&envelope.mail_from),
                                 };
 
-                                // Fetch dkim settings
-                                let domain_name = envelope
+                            // Send mail-from, with a signed envelope id bounces can be matched by
+                            let mail_from = if smtp_relay.dsn
+                                && !core.store.config.srs_secret.is_empty()
+                                && !envelope
                                     .mail_from
-                                    .email
-                                    .split_once('@')
-                                    .unwrap()
-                                    .1
-                                    .to_string();
-                                let dkim = if let Some(dkim) = dkim_map.get(&domain_name) {
-                                    dkim
-                                } else {
-                                    match core.store.dkim_get(domain_name.clone()) {
-                                        Ok(dkim) => {
-                                            dkim_map.insert(
-                                                domain_name.clone(),
-                                                if let Some(dkim) = dkim {
-                                                    dkim.headers([
-                                                        "From",
-                                                        "To",
-                                                        "Subject",
-                                                        "Date",
-                                                        "Cc",
-                                                        "Bcc",
-                                                        "Message-ID",
-                                                        "References",
-                                                        "In-Reply-To",
-                                                    ])
-                                                    .into()
-                                                } else {
-                                                    None
-                                                },
+                                    .parameters
+                                    .as_ref()
+                                    .map_or(false, |params| {
+                                        params
+                                            .iter()
+                                            .any(|(name, _)| name.eq_ignore_ascii_case("ENVID"))
+                                    }) {
+                                format!(
+                                    "MAIL FROM:{} ENVID={}\r\n",
+                                    envelope_from,
+                                    envelope_id(
+                                        &core.store.config.srs_secret,
+                                        account_id,
+                                        email_submission_id
+                                    )
+                                )
+                            } else {
+                                format!("MAIL FROM:{}\r\n", envelope_from)
+                            };
+                            if let Err(err) = client.cmd(mail_from.as_bytes()).await {
+                                let is_temporary = is_temporary_error(&err);
+                                let err = err.to_string();
+                                for rcpt in &rcpt_to {
+                                    if is_temporary {
+                                        temporary_failures.insert(rcpt.email.to_string());
+                                    }
+                                    delivery_status.insert(
+                                        rcpt.email.to_string(),
+                                        DeliveryStatus::new(
+                                            err.clone(),
+                                            Delivered::No,
+                                            Displayed::Unknown,
+                                        ),
+                                    );
+                                }
+                            } else {
+                                // Send recipients
+                                let mut accepted_rcpt = Vec::new();
+                                for rcpt in &rcpt_to {
+                                    match client
+                                        .cmd(format!("RCPT TO:{}\r\n", &rcpt).as_bytes())
+                                        .await
+                                    {
+                                        Ok(reply) => {
+                                            let smtp_reply = reply.to_string();
+                                            if smtp_reply.starts_with('4') {
+                                                temporary_failures.insert(rcpt.email.to_string());
+                                            }
+                                            delivery_status.insert(
+                                                rcpt.email.to_string(),
+                                                DeliveryStatus::new(
+                                                    smtp_reply,
+                                                    if reply.is_positive_completion() {
+                                                        accepted_rcpt.push(rcpt);
+                                                        Delivered::Queued
+                                                    } else {
+                                                        Delivered::No
+                                                    },
+                                                    Displayed::Unknown,
+                                                ),
                                             );
-                                            dkim_map.get(&domain_name).unwrap()
                                         }
                                         Err(err) => {
-                                            error!(
-                                                "Error getting DKIM settings for domain '{}': {}",
-                                                domain_name, err
+                                            if is_temporary_error(&err) {
+                                                temporary_failures.insert(rcpt.email.to_string());
+                                            }
+                                            delivery_status.insert(
+                                                rcpt.email.to_string(),
+                                                DeliveryStatus::new(
+                                                    err.to_string(),
+                                                    Delivered::No,
+                                                    Displayed::Unknown,
+                                                ),
                                             );
-                                            continue;
                                         }
                                     }
-                                };
+                                }
 
-                                // Create delivery status list
-                                let mut delivery_status =
-                                    AHashMap::with_capacity(envelope.rcpt_to.len());
+                                // Do not submit message if no recipients were accepted
+                                if !accepted_rcpt.is_empty() {
+                                    // Append domain footer
+                                    let raw_message = if !footers.is_empty() {
+                                        footers
+                                            .apply(&domain_name, &raw_message, &mime_limits)
+                                            .unwrap_or(raw_message)
+                                    } else {
+                                        raw_message
+                                    };
 
-                                // Send mail-from
-                                let undo_status = if let Err(err) = client
-                                    .cmd(
-                                        format!("MAIL FROM:{}\r\n", &envelope.mail_from).as_bytes(),
-                                    )
-                                    .await
-                                {
-                                    let err = err.to_string();
-                                    for rcpt in &envelope.rcpt_to {
-                                        delivery_status.insert(
-                                            rcpt.email.to_string(),
-                                            DeliveryStatus::new(
-                                                err.clone(),
-                                                Delivered::No,
-                                                Displayed::Unknown,
-                                            ),
-                                        );
-                                    }
-                                    UndoStatus::Canceled
-                                } else {
-                                    // Send recipients
-                                    let mut accepted_rcpt = false;
-                                    for rcpt in &envelope.rcpt_to {
-                                        match client
-                                            .cmd(format!("RCPT TO:{}\r\n", &rcpt).as_bytes())
-                                            .await
-                                        {
-                                            Ok(reply) => {
-                                                delivery_status.insert(
-                                                    rcpt.email.to_string(),
-                                                    DeliveryStatus::new(
-                                                        reply.to_string(),
-                                                        if reply.is_positive_completion() {
-                                                            accepted_rcpt = true;
-                                                            Delivered::Queued
-                                                        } else {
-                                                            Delivered::No
-                                                        },
-                                                        Displayed::Unknown,
-                                                    ),
-                                                );
+                                    // Sign message
+                                    let mut headers = None;
+                                    if let Some(dkim) = dkim {
+                                        match dkim.sign(&raw_message) {
+                                            Ok(signature) => {
+                                                headers = signature.to_header().into();
                                             }
                                             Err(err) => {
-                                                delivery_status.insert(
-                                                    rcpt.email.to_string(),
-                                                    DeliveryStatus::new(
-                                                        err.to_string(),
-                                                        Delivered::No,
-                                                        Displayed::Unknown,
-                                                    ),
+                                                error!(
+                                                    "Error signing message for domain '{}': {}",
+                                                    domain_name, err
                                                 );
                                             }
                                         }
                                     }
 
-                                    // Do not submit message if no recipients were accepted
-                                    if accepted_rcpt {
-                                        // Sign message
-                                        let mut headers = None;
-                                        if let Some(dkim) = dkim {
-                                            match dkim.sign(&raw_message) {
-                                                Ok(signature) => {
-                                                    headers = signature.to_header().into();
-                                                }
-                                                Err(err) => {
-                                                    error!(
-                                                        "Error signing message for domain '{}': {}",
-                                                        domain_name, err
-                                                    );
-                                                }
-                                            }
-                                        }
+                                    // Send message
+                                    let result = if let Some(headers) = headers {
+                                        client
+                                            .data_with_headers(headers.as_bytes(), &raw_message)
+                                            .await
+                                    } else {
+                                        client.data(&raw_message).await
+                                    };
 
-                                        // Send message
-                                        let result = if let Some(headers) = headers {
-                                            client
-                                                .data_with_headers(headers.as_bytes(), &raw_message)
-                                                .await
-                                        } else {
-                                            client.data(&raw_message).await
-                                        };
-
-                                        match result {
-                                            Ok(_) => UndoStatus::Final,
-                                            Err(err) => {
-                                                let err = err.to_string();
-                                                for rcpt in &envelope.rcpt_to {
-                                                    delivery_status.insert(
-                                                        rcpt.email.to_string(),
-                                                        DeliveryStatus::new(
-                                                            err.clone(),
-                                                            Delivered::No,
-                                                            Displayed::Unknown,
-                                                        ),
-                                                    );
-                                                }
-                                                UndoStatus::Canceled
+                                    if let Err(err) = result {
+                                        let is_temporary = is_temporary_error(&err);
+                                        let err = err.to_string();
+                                        for rcpt in accepted_rcpt {
+                                            if is_temporary {
+                                                temporary_failures.insert(rcpt.email.to_string());
                                             }
+                                            delivery_status.insert(
+                                                rcpt.email.to_string(),
+                                                DeliveryStatus::new(
+                                                    err.clone(),
+                                                    Delivered::No,
+                                                    Displayed::Unknown,
+                                                ),
+                                            );
                                         }
-                                    } else {
-                                        UndoStatus::Canceled
                                     }
-                                };
-
-                                // Update submission
-                                email_submission.set(
-                                    Property::UndoStatus,
-                                    Value::UndoStatus { value: undo_status },
-                                );
-                                email_submission.set(
-                                    Property::DeliveryStatus,
-                                    Value::DeliveryStatus {
-                                        value: delivery_status,
-                                    },
+                                }
+                            }
+                            client.rset().await.ok();
+                        } else {
+                            // Fail all recipients
+                            for rcpt in &rcpt_to {
+                                if connect_is_temporary {
+                                    temporary_failures.insert(rcpt.email.to_string());
+                                }
+                                delivery_status.insert(
+                                    rcpt.email.to_string(),
+                                    DeliveryStatus::new(
+                                        connect_error.clone(),
+                                        Delivered::No,
+                                        Displayed::Unknown,
+                                    ),
                                 );
-                                results.push((
-                                    email_submission_id,
-                                    current_email_submission,
-                                    email_submission,
-                                ));
-                                client.rset().await.ok();
                             }
-
-                            // Send QUIT
-                            client.quit().await.ok();
                         }
-                        Err(err) => {
-                            // Fail all submissions
-                            let err = err.to_string();
-                            error!("Failed to connect to relay server: {}", err);
 
-                            for (email_submission_id, current_email_submission, _) in messages {
-                                // Track changes
-                                let mut email_submission =
-                                    TinyORM::track_changes(&current_email_submission);
-
-                                // Access envelope
-                                if let Some(envelope) = current_email_submission
-                                    .get(&Property::Envelope)
-                                    .and_then(|value| {
-                                        if let Value::Envelope { value } = value {
-                                            Some(value)
-                                        } else {
-                                            None
-                                        }
-                                    })
+                        // Defer temporary failures while attempts are left
+                        let mut deferred = Vec::new();
+                        let mut last_error = String::new();
+                        if core.delivery_queue.can_retry(queued) {
+                            for (rcpt, status) in delivery_status.iter_mut() {
+                                if status.delivered == Delivered::No
+                                    && temporary_failures.contains(rcpt)
                                 {
-                                    // Create delivery status list
-                                    let mut delivery_status =
-                                        AHashMap::with_capacity(envelope.rcpt_to.len());
-
-                                    // Fail all recipients
-                                    for rcpt in &envelope.rcpt_to {
-                                        delivery_status.insert(
-                                            rcpt.email.to_string(),
-                                            DeliveryStatus::new(
-                                                err.clone(),
-                                                Delivered::No,
-                                                Displayed::Unknown,
-                                            ),
-                                        );
-                                    }
-                                    email_submission.set(
-                                        Property::UndoStatus,
-                                        Value::UndoStatus {
-                                            value: UndoStatus::Canceled,
-                                        },
-                                    );
-                                    email_submission.set(
-                                        Property::DeliveryStatus,
-                                        Value::DeliveryStatus {
-                                            value: delivery_status,
-                                        },
-                                    );
-                                    results.push((
-                                        email_submission_id,
-                                        current_email_submission,
-                                        email_submission,
-                                    ));
+                                    status.delivered = Delivered::Unknown;
+                                    last_error = status.smtp_reply.clone();
+                                    deferred.push(rcpt.to_string());
                                 }
                             }
                         }
+
+                        // Report failed recipients to the sender
+                        if let Some(failure) = DeliveryFailure::new(
+                            &envelope.mail_from.email,
+                            &delivery_status,
+                            original_headers,
+                        ) {
+                            failures.push(failure);
+                        }
+
+                        // Keep the results of previous attempts
+                        if queued.is_some() {
+                            if let Some(Value::DeliveryStatus { value }) =
+                                current_email_submission.get(&Property::DeliveryStatus)
+                            {
+                                for (rcpt, status) in value {
+                                    delivery_status
+                                        .entry(rcpt.to_string())
+                                        .or_insert_with(|| status.clone());
+                                }
+                            }
+                        }
+                        let undo_status = if delivery_status
+                            .values()
+                            .any(|status| status.delivered == Delivered::Queued)
+                        {
+                            UndoStatus::Final
+                        } else if !deferred.is_empty() {
+                            UndoStatus::Pending
+                        } else {
+                            UndoStatus::Canceled
+                        };
+                        if !deferred.is_empty() {
+                            deferred.sort_unstable();
+                            email_submission.set(
+                                Property::DeliveryRetry,
+                                Value::DeliveryRetry {
+                                    value: core.delivery_queue.defer(
+                                        account_id,
+                                        email_submission_id,
+                                        &envelope.mail_from.email,
+                                        deferred,
+                                        last_error,
+                                        queued,
+                                    ),
+                                },
+                            );
+                        } else if queued.is_some() {
+                            core.delivery_queue.remove(account_id, email_submission_id);
+                            email_submission.set(Property::DeliveryRetry, Value::Null);
+                        }
+
+                        // Update submission
+                        email_submission.set(
+                            Property::UndoStatus,
+                            Value::UndoStatus { value: undo_status },
+                        );
+                        set_delivery_status(
+                            &current_email_submission,
+                            &mut email_submission,
+                            delivery_status,
+                        );
+                        results.push((
+                            email_submission_id,
+                            current_email_submission,
+                            email_submission,
+                        ));
+                    }
+
+                    // Send QUIT
+                    if let Some(client) = connection {
+                        client.quit().await.ok();
                     }
 
+                    // Submissions that could not be attempted are retried later
+                    core.delivery_queue.release(account_id, &submission_ids);
+
                     // Update store with submission results
                     let store = core.store.clone();
                     match core
@@ -508,10 +770,10 @@ where
 
                             // Notify subscribers
                             if let Err(err) = core
-                                .publish_state_change(StateChange {
+                                .publish_state_change(StateChange::new(
                                     account_id,
-                                    types: vec![(TypeState::EmailSubmission, changes.change_id)],
-                                })
+                                    vec![(TypeState::EmailSubmission, changes.change_id)],
+                                ))
                                 .await
                             {
                                 error!("Failed to publish state change: {}", err);
@@ -522,8 +784,60 @@ where
                             error!("Failed to update email submissions: {}", err);
                         }
                     }
+
+                    // Deliver non-delivery reports
+                    for failure in failures {
+                        core.deliver_failure_report(account_id, failure).await;
+                    }
                 }
-                Event::OutgoingMessage { from, to, message } => {
+                Event::OutgoingMessage {
+                    from,
+                    to,
+                    mut message,
+                    seal_domain,
+                    auth,
+                } => {
+                    // Seal messages resent on behalf of an account so that their
+                    // original authentication results survive the extra hop
+                    if let (Some(domain_name), Some(auth)) = (seal_domain, auth) {
+                        let sealer = if let Some(sealer) = arc_sealers.get(&domain_name) {
+                            sealer
+                        } else {
+                            match core.store.domain_key_get(&domain_name) {
+                                Ok(key) => {
+                                    arc_sealers.insert(
+                                        domain_name.clone(),
+                                        key.and_then(|key| {
+                                            ArcSealer::from_pkcs1_pem(
+                                                &key.private_key,
+                                                domain_name.clone(),
+                                                key.selector,
+                                            )
+                                        }),
+                                    );
+                                    arc_sealers.get(&domain_name).unwrap()
+                                }
+                                Err(err) => {
+                                    error!(
+                                        "Error getting DKIM settings for domain '{}': {}",
+                                        domain_name, err
+                                    );
+                                    &None
+                                }
+                            }
+                        };
+                        if let Some(headers) = sealer
+                            .as_ref()
+                            .and_then(|sealer| sealer.seal(&message, &hostname, &auth))
+                        {
+                            let mut sealed_message =
+                                Vec::with_capacity(headers.len() + message.len());
+                            sealed_message.extend_from_slice(headers.as_bytes());
+                            sealed_message.extend_from_slice(&message);
+                            message = sealed_message;
+                        }
+                    }
+
                     match if is_tls {
                         client.clone().connect_tls().await
                     } else {
@@ -542,6 +856,7 @@ where
                 }
                 Event::Reload => {
                     dkim_map.clear();
+                    arc_sealers.clear();
                 }
                 _ => (),
             }
@@ -555,18 +870,97 @@ where
     tx
 }
 
+/// Returns the SRS address for senders that do not belong to a local domain,
+/// rewritten using the domain of the submitting account.
+fn srs_sender<T>(core: &JMAPServer<T>, account_id: AccountId, sender: &str) -> Option<String>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let secret = &core.store.config.srs_secret;
+    let (_, domain) = sender.rsplit_once('@')?;
+    if secret.is_empty() {
+        return None;
+    }
+    match core.store.is_local_domain(&domain.to_lowercase()) {
+        Ok(false) => (),
+        Ok(true) => return None,
+        Err(err) => {
+            error!("Failed to look up domain '{}': {}", domain, err);
+            return None;
+        }
+    }
+    let email = match core.store.get_account_details(account_id) {
+        Ok(Some((email, _, _))) => email,
+        Ok(None) => return None,
+        Err(err) => {
+            error!(
+                "Failed to obtain account details for {}: {}",
+                account_id, err
+            );
+            return None;
+        }
+    };
+    let (_, forward_domain) = email.rsplit_once('@')?;
+    Some(srs_forward(sender, forward_domain, secret))
+}
+
+/// Network errors and 4xx replies are temporary, local errors and any
+/// other replies are not worth retrying.
+fn is_temporary_error(err: &mail_send::Error) -> bool {
+    match err {
+        mail_send::Error::Io(_) | mail_send::Error::Timeout => true,
+        mail_send::Error::UnexpectedReply(reply) => reply.to_string().starts_with('4'),
+        _ => false,
+    }
+}
+
+struct DeliveryFailure {
+    mail_from: String,
+    recipients: Vec<(String, String)>,
+    headers: Vec<u8>,
+}
+
+impl DeliveryFailure {
+    fn new(
+        mail_from: &str,
+        delivery_status: &AHashMap<String, DeliveryStatus>,
+        headers: Vec<u8>,
+    ) -> Option<Self> {
+        // Bounces are never sent to the null sender
+        if mail_from.is_empty() {
+            return None;
+        }
+        let mut recipients = delivery_status
+            .iter()
+            .filter(|(_, status)| status.delivered == Delivered::No)
+            .map(|(rcpt, status)| (rcpt.to_string(), status.smtp_reply.to_string()))
+            .collect::<Vec<_>>();
+        if !recipients.is_empty() {
+            recipients.sort_unstable();
+            Some(DeliveryFailure {
+                mail_from: mail_from.to_string(),
+                recipients,
+                headers,
+            })
+        } else {
+            None
+        }
+    }
+}
+
 struct SMTPRelay {
     hostname: String,
     port: u16,
     credentials: Option<(String, String)>,
     tls: bool,
+    dsn: bool,
     timeout: Duration,
 }
 
 fn parse_smtp_settings(settings: &EnvSettings) -> Option<SMTPRelay> {
     Some(SMTPRelay {
         hostname: settings.get("smtp-relay-host")?,
-        port: settings.parse("smtp-relay-port").unwrap_or(0),
+        port: settings.value(SETTINGS, "smtp-relay-port"),
         credentials: if let (Some(auth), Some(pass)) = (
             settings.get("smtp-relay-auth"),
             settings.get("smtp-relay-secret"),
@@ -575,12 +969,9 @@ fn parse_smtp_settings(settings: &EnvSettings) -> Option<SMTPRelay> {
         } else {
             None
         },
-        tls: settings.parse("smtp-relay-tls").unwrap_or(false),
-        timeout: Duration::from_millis(
-            settings
-                .parse("smtp-relay-timeout")
-                .unwrap_or(DEFAULT_SMTP_TIMEOUT_MS),
-        ),
+        tls: settings.value(SETTINGS, "smtp-relay-tls"),
+        dsn: settings.value(SETTINGS, "smtp-relay-dsn"),
+        timeout: Duration::from_millis(settings.value(SETTINGS, "smtp-relay-timeout")),
     })
 }
 
@@ -588,6 +979,69 @@ impl<T> JMAPServer<T>
 where
     T: for<'x> Store<'x> + 'static,
 {
+    async fn deliver_failure_report(&self, account_id: AccountId, failure: DeliveryFailure) {
+        let store = self.store.clone();
+        let mail_from = failure.mail_from.clone();
+        let message = match self
+            .spawn_worker(move || {
+                let subject = ParsedMessage::parse(&failure.headers)
+                    .and_then(|message| message.subject().map(|subject| subject.to_string()))
+                    .unwrap_or_default();
+                let recipients = failure
+                    .recipients
+                    .iter()
+                    .map(|(rcpt, reason)| format!("<{}>: {}", rcpt, reason.trim()))
+                    .collect::<Vec<_>>()
+                    .join("\r\n");
+                let template = if let Some(template) = store.mail_template_render(
+                    account_id,
+                    TEMPLATE_DSN_FAILURE,
+                    &[("subject", &subject), ("recipients", &recipients)],
+                )? {
+                    template
+                } else {
+                    return Ok(None);
+                };
+
+                Ok(Some(build_delivery_failure(
+                    &template,
+                    failure
+                        .mail_from
+                        .rsplit_once('@')
+                        .map_or("localhost", |(_, domain)| domain),
+                    &failure.mail_from,
+                    &failure.recipients,
+                    &failure.headers,
+                )))
+            })
+            .await
+        {
+            Ok(Some(message)) => message,
+            Ok(None) => return,
+            Err(err) => {
+                error!("Failed to build non-delivery report: {}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = self
+            .mail_ingest(
+                String::new(),
+                vec![RcptType::Mailbox {
+                    id: account_id,
+                    name: mail_from,
+                    status: IngestStatus::Success,
+                }],
+                message,
+                None,
+                None,
+            )
+            .await
+        {
+            error!("Failed to deliver non-delivery report: {}", err.trim_end());
+        }
+    }
+
     pub async fn notify_email_delivery(&self, event: Event) -> jmap::Result<()> {
         let email_tx = self.email_delivery.clone();
         if let Err(err) = email_tx.clone().send(event).await {
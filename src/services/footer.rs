@@ -0,0 +1,382 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::path::PathBuf;
+
+use jmap_mail::{
+    mail::limits::MimeLimits,
+    mail_parser::{HeaderName, HeaderValue, Message, MessagePart, PartType, RfcHeader},
+};
+use store::{
+    ahash::AHashMap,
+    config::{env_settings::EnvSettings, settings::Setting},
+};
+
+use crate::server::UnwrapFailure;
+
+pub const SETTINGS: &[Setting] = &[Setting::path("footer-path")
+    .describe("Directory with <domain>.txt, <domain>.html and default.txt footers")];
+
+const DEFAULT_FOOTER: &str = "default";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Footer {
+    pub text: Option<String>,
+    pub html: Option<String>,
+}
+
+/// Footers appended to outgoing messages, keyed by sender domain.
+#[derive(Debug, Default)]
+pub struct MessageFooters {
+    domains: AHashMap<String, Footer>,
+    default: Option<Footer>,
+}
+
+impl MessageFooters {
+    /// Loads `<domain>.txt` and `<domain>.html` footers from the directory
+    /// configured in `footer-path`. Footers named `default` apply to domains
+    /// without their own.
+    pub fn parse(settings: &EnvSettings) -> Self {
+        let path = if let Some(path) = settings.get("footer-path") {
+            PathBuf::from(path)
+        } else {
+            return MessageFooters::default();
+        };
+
+        let mut domains: AHashMap<String, Footer> = AHashMap::new();
+        for entry in std::fs::read_dir(&path).failed_to(&format!("read footer-path {:?}", path)) {
+            let entry = entry.failed_to("read footer directory entry");
+            let file_path = entry.path();
+            let (domain, is_html) = match (
+                file_path.file_stem().and_then(|s| s.to_str()),
+                file_path.extension().and_then(|s| s.to_str()),
+            ) {
+                (Some(domain), Some("txt")) => (domain.to_lowercase(), false),
+                (Some(domain), Some("html")) => (domain.to_lowercase(), true),
+                _ => continue,
+            };
+            let contents = std::fs::read_to_string(&file_path)
+                .failed_to(&format!("read footer {:?}", file_path));
+            let footer = domains.entry(domain).or_default();
+            if is_html {
+                footer.html = contents.into();
+            } else {
+                footer.text = contents.into();
+            }
+        }
+
+        MessageFooters {
+            default: domains.remove(DEFAULT_FOOTER),
+            domains,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty() && self.default.is_none()
+    }
+
+    /// Returns the message with the footer of the sender domain appended, or
+    /// `None` if the message was left unchanged.
+    pub fn apply(&self, domain: &str, raw_message: &[u8], limits: &MimeLimits) -> Option<Vec<u8>> {
+        let footer = self
+            .domains
+            .get(&domain.to_lowercase())
+            .or(self.default.as_ref())?;
+        append_footer(raw_message, footer, limits)
+    }
+}
+
+/// Appends the footer to the last text/plain and text/html body parts. The
+/// text footer is converted to HTML when no HTML variant is configured.
+/// Signed and encrypted messages, as well as messages exceeding the MIME
+/// limits, are never modified.
+pub fn append_footer(raw_message: &[u8], footer: &Footer, limits: &MimeLimits) -> Option<Vec<u8>> {
+    if limits.scan(raw_message).is_some() {
+        return None;
+    }
+    let message = Message::parse(raw_message)?;
+    let root_part = message.parts.get(0)?;
+    match content_type(root_part) {
+        Some((c_type, Some(c_subtype)))
+            if (c_type == "multipart" && (c_subtype == "signed" || c_subtype == "encrypted"))
+                || (c_type == "application" && c_subtype == "pkcs7-mime") =>
+        {
+            return None;
+        }
+        _ => (),
+    }
+
+    let html_footer = footer
+        .html
+        .clone()
+        .or_else(|| footer.text.as_deref().map(text_to_html));
+    let mut replacements = Vec::with_capacity(2);
+    if let Some(text_footer) = &footer.text {
+        if let Some(part_id) = message.text_body.iter().rev().find(|part_id| {
+            matches!(
+                message.parts.get(**part_id).map(|p| &p.body),
+                Some(PartType::Text(_))
+            )
+        }) {
+            replacements.push((*part_id, text_footer.as_str(), false));
+        }
+    }
+    if let Some(html_footer) = &html_footer {
+        if let Some(part_id) = message.html_body.iter().rev().find(|part_id| {
+            matches!(
+                message.parts.get(**part_id).map(|p| &p.body),
+                Some(PartType::Html(_))
+            )
+        }) {
+            replacements.push((*part_id, html_footer.as_str(), true));
+        }
+    }
+    if replacements.is_empty() {
+        return None;
+    }
+    replacements.sort_unstable_by_key(|(part_id, _, _)| *part_id);
+
+    let mut result = Vec::with_capacity(raw_message.len() + 1024);
+    let mut pos = 0;
+    for (part_id, footer, is_html) in replacements {
+        let part = &message.parts[part_id];
+        if part.is_encoding_problem
+            || part.offset_header < pos
+            || part.offset_body > part.offset_end
+            || part.offset_end > raw_message.len()
+        {
+            return None;
+        }
+        let body = match &part.body {
+            PartType::Text(text) | PartType::Html(text) => text.as_ref(),
+            _ => return None,
+        };
+
+        // Rewrite the part headers and encode the new body as UTF-8
+        result.extend_from_slice(&raw_message[pos..part.offset_header]);
+        let mut has_mime_version = false;
+        for header in &part.headers {
+            match &header.name {
+                HeaderName::Rfc(RfcHeader::ContentType | RfcHeader::ContentTransferEncoding) => {
+                    continue;
+                }
+                HeaderName::Rfc(RfcHeader::MimeVersion) => {
+                    has_mime_version = true;
+                }
+                _ => (),
+            }
+            let raw_header = raw_message.get(header.offset_field..header.offset_end)?;
+            result.extend_from_slice(raw_header);
+            if !raw_header.ends_with(b"\n") {
+                result.extend_from_slice(b"\r\n");
+            }
+        }
+        if part_id == 0 && !has_mime_version {
+            result.extend_from_slice(b"MIME-Version: 1.0\r\n");
+        }
+        result.extend_from_slice(if is_html {
+            b"Content-Type: text/html; charset=\"utf-8\"\r\n".as_ref()
+        } else {
+            b"Content-Type: text/plain; charset=\"utf-8\"\r\n".as_ref()
+        });
+        result.extend_from_slice(b"Content-Transfer-Encoding: base64\r\n\r\n");
+
+        let body = if is_html {
+            html_append(body, footer)
+        } else {
+            let mut body = body.trim_end().to_string();
+            body.push_str("\r\n\r\n");
+            body.push_str(footer);
+            body
+        };
+        for (line_num, line) in base64::encode(body.as_bytes())
+            .as_bytes()
+            .chunks(76)
+            .enumerate()
+        {
+            if line_num > 0 {
+                result.extend_from_slice(b"\r\n");
+            }
+            result.extend_from_slice(line);
+        }
+        pos = part.offset_end;
+    }
+    result.extend_from_slice(&raw_message[pos..]);
+
+    Some(result)
+}
+
+fn content_type<'x>(part: &'x MessagePart) -> Option<(&'x str, Option<&'x str>)> {
+    part.headers.iter().rev().find_map(|header| {
+        if let (HeaderName::Rfc(RfcHeader::ContentType), HeaderValue::ContentType(ct)) =
+            (&header.name, &header.value)
+        {
+            Some((ct.c_type.as_ref(), ct.c_subtype.as_deref()))
+        } else {
+            None
+        }
+    })
+}
+
+fn html_append(html: &str, footer: &str) -> String {
+    let mut result = String::with_capacity(html.len() + footer.len());
+    if let Some(pos) = html.to_ascii_lowercase().rfind("</body>") {
+        result.push_str(&html[..pos]);
+        result.push_str(footer);
+        result.push_str(&html[pos..]);
+    } else {
+        result.push_str(html);
+        result.push_str(footer);
+    }
+    result
+}
+
+fn text_to_html(text: &str) -> String {
+    let mut html = String::with_capacity(text.len() + 16);
+    html.push_str("<p>");
+    for ch in text.trim_end().chars() {
+        match ch {
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '&' => html.push_str("&amp;"),
+            '"' => html.push_str("&quot;"),
+            '\n' => html.push_str("<br>"),
+            '\r' => (),
+            _ => html.push(ch),
+        }
+    }
+    html.push_str("</p>");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use jmap_mail::{mail::limits::MimeLimits, mail_parser::Message};
+
+    use super::{append_footer, Footer};
+
+    const LIMITS: MimeLimits = MimeLimits {
+        max_depth: 3,
+        max_parts: 4,
+        max_headers: 10,
+        max_header_length: 512,
+    };
+
+    #[test]
+    fn message_footer() {
+        let footer = Footer {
+            text: "-- \r\nSent from example.org".to_string().into(),
+            html: None,
+        };
+
+        // Plain text message
+        let result = append_footer(
+            concat!(
+                "From: john@example.org\r\n",
+                "Subject: Footer test\r\n",
+                "\r\n",
+                "Hello world\r\n"
+            )
+            .as_bytes(),
+            &footer,
+            &LIMITS,
+        )
+        .unwrap();
+        let message = Message::parse(&result).unwrap();
+        assert_eq!(
+            message.get_text_body(0).unwrap(),
+            "Hello world\r\n\r\n-- \r\nSent from example.org"
+        );
+        assert_eq!(message.get_subject().unwrap(), "Footer test");
+
+        // Both alternatives get their footer
+        let result = append_footer(
+            concat!(
+                "From: john@example.org\r\n",
+                "Content-Type: multipart/alternative; boundary=\"xyz\"\r\n\r\n",
+                "--xyz\r\n",
+                "Content-Type: text/plain\r\n",
+                "Content-Transfer-Encoding: quoted-printable\r\n\r\n",
+                "Caf=C3=A9\r\n",
+                "--xyz\r\n",
+                "Content-Type: text/html\r\n\r\n",
+                "<html><body><p>Caf&eacute;</p></body></html>\r\n",
+                "--xyz--\r\n"
+            )
+            .as_bytes(),
+            &footer,
+            &LIMITS,
+        )
+        .unwrap();
+        let message = Message::parse(&result).unwrap();
+        assert!(message
+            .get_text_body(0)
+            .unwrap()
+            .ends_with("Sent from example.org"));
+        assert!(message
+            .get_html_body(0)
+            .unwrap()
+            .contains("<p>-- <br>Sent from example.org</p></body>"));
+
+        // Signed messages are left untouched
+        assert_eq!(
+            append_footer(
+                concat!(
+                    "From: john@example.org\r\n",
+                    "Content-Type: multipart/signed; protocol=\"application/pgp-signature\"; ",
+                    "boundary=\"xyz\"\r\n\r\n",
+                    "--xyz\r\n",
+                    "Content-Type: text/plain\r\n\r\n",
+                    "Hello world\r\n",
+                    "--xyz\r\n",
+                    "Content-Type: application/pgp-signature\r\n\r\n",
+                    "signature\r\n",
+                    "--xyz--\r\n"
+                )
+                .as_bytes(),
+                &footer,
+                &LIMITS,
+            ),
+            None
+        );
+
+        // Messages exceeding the MIME limits are left untouched
+        assert_eq!(
+            append_footer(
+                concat!(
+                    "From: john@example.org\r\n",
+                    "Content-Type: multipart/mixed; boundary=\"xyz\"\r\n\r\n",
+                    "--xyz\r\n\r\none\r\n",
+                    "--xyz\r\n\r\ntwo\r\n",
+                    "--xyz\r\n\r\nthree\r\n",
+                    "--xyz\r\n\r\nfour\r\n",
+                    "--xyz--\r\n"
+                )
+                .as_bytes(),
+                &footer,
+                &LIMITS,
+            ),
+            None
+        );
+    }
+}
@@ -21,29 +21,66 @@
  * for more details.
 */
 
-use std::time::{Duration, SystemTime};
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
 
 use actix_web::web;
+use jmap::{types::type_state::TypeState, SUPERUSER_ID};
+use jmap_mail::{
+    mail::{
+        collation::JMAPMailCollation,
+        expunge::{ExpungeReport, JMAPMailExpunge},
+        MessageField,
+    },
+    mail_parser::RfcHeader,
+};
 use jmap_sharing::principal::set::JMAPSetPrincipal;
 use store::{
     chrono::{self, Datelike, TimeZone},
-    config::env_settings::EnvSettings,
+    config::{env_settings::EnvSettings, settings::Setting},
+    core::collection::Collection,
     tracing::{debug, error, info},
-    ColumnFamily, Store,
+    write::prune::IndexPruneOptions,
+    AccountId, ColumnFamily, FieldId, Store,
 };
-use tokio::sync::mpsc;
+use tokio::{sync::mpsc, time};
 
 use crate::{
+    api::compact::compact_db,
     cluster::IPC_CHANNEL_BUFFER,
     server::{failed_to, UnwrapFailure},
+    services::{maintenance::TaskScope, state_change::StateChange},
     JMAPServer,
 };
 
+pub const SETTINGS: &[Setting] = &[
+    Setting::schedule("schedule-purge-accounts").default("0 3 *"),
+    Setting::schedule("schedule-purge-blobs").default("30 3 *"),
+    Setting::schedule("schedule-snapshot-log").default("45 3 *"),
+    Setting::schedule("schedule-compact-db").default("0 4 *"),
+    Setting::schedule("schedule-expunge-mailboxes").default("15 3 *"),
+    Setting::schedule("schedule-backup").default("30 4 *"),
+    Setting::schedule("schedule-purge-expired").default("50 3 *"),
+    Setting::schedule("schedule-prune-index")
+        .default("15 4 *")
+        .describe("Only runs when 'index-prune-max-frequency' is set"),
+    Setting::schedule("schedule-reindex-collation").default("45 4 *"),
+    Setting::path("backup-path").describe("Backups are only taken when set"),
+    Setting::integer("max-changelog-entries").default("10000"),
+];
+
 pub enum Event {
     PurgeAccounts,
     PurgeBlobs,
     SnapshotLog,
     CompactDb,
+    Backup,
+    ExpungeMailboxes,
+    PurgeExpired,
+    PruneIndex,
+    ReindexCollation,
     Exit,
 }
 
@@ -55,7 +92,16 @@ enum SimpleCron {
 const TASK_PURGE_ACCOUNTS: usize = 0;
 const TASK_PURGE_BLOBS: usize = 1;
 const TASK_SNAPSHOT_LOG: usize = 2;
-const TASK_COMPACT_DB: usize = 3;
+pub const TASK_COMPACT_DB: usize = 3;
+const TASK_BACKUP: usize = 4;
+const TASK_EXPUNGE_MAILBOXES: usize = 5;
+const TASK_PURGE_EXPIRED: usize = 6;
+const TASK_PRUNE_INDEX: usize = 7;
+const TASK_REINDEX_COLLATION: usize = 8;
+// Only started on demand through the admin API
+pub const TASK_RENUMBER_IDS: usize = 9;
+
+const COMPACT_WAIT_SECS: u64 = 5;
 
 pub fn spawn_housekeeper<T>(
     core: web::Data<JMAPServer<T>>,
@@ -64,50 +110,103 @@ pub fn spawn_housekeeper<T>(
 ) where
     T: for<'x> Store<'x> + 'static,
 {
-    let purge_accounts_at = SimpleCron::parse(
-        &settings
-            .get("schedule-purge-accounts")
-            .unwrap_or_else(|| "0 3 *".to_string()),
-    );
-    let purge_blobs_at = SimpleCron::parse(
-        &settings
-            .get("schedule-purge-blobs")
-            .unwrap_or_else(|| "30 3 *".to_string()),
-    );
-    let snapshot_log_at = SimpleCron::parse(
-        &settings
-            .get("schedule-snapshot-log")
-            .unwrap_or_else(|| "45 3 *".to_string()),
-    );
-    let compact_db_at = SimpleCron::parse(
-        &settings
-            .get("schedule-compact-db")
-            .unwrap_or_else(|| "0 4 *".to_string()),
-    );
-    let max_log_entries: u64 = settings.parse("max-changelog-entries").unwrap_or(10000);
+    let purge_accounts_at =
+        SimpleCron::parse(&settings.value::<String>(SETTINGS, "schedule-purge-accounts"));
+    let purge_blobs_at =
+        SimpleCron::parse(&settings.value::<String>(SETTINGS, "schedule-purge-blobs"));
+    let snapshot_log_at =
+        SimpleCron::parse(&settings.value::<String>(SETTINGS, "schedule-snapshot-log"));
+    let compact_db_at =
+        SimpleCron::parse(&settings.value::<String>(SETTINGS, "schedule-compact-db"));
+    let backup_at = settings.get("backup-path").map(|backup_path| {
+        (
+            SimpleCron::parse(&settings.value::<String>(SETTINGS, "schedule-backup")),
+            PathBuf::from(backup_path),
+        )
+    });
+    let expunge_mailboxes_at =
+        SimpleCron::parse(&settings.value::<String>(SETTINGS, "schedule-expunge-mailboxes"));
+    let purge_expired_at =
+        SimpleCron::parse(&settings.value::<String>(SETTINGS, "schedule-purge-expired"));
+    let max_log_entries: u64 = settings.value(SETTINGS, "max-changelog-entries");
+    let prune_index_at =
+        SimpleCron::parse(&settings.value::<String>(SETTINGS, "schedule-prune-index"));
+    let prune_options = IndexPruneOptions::parse(settings);
+    let reindex_collation_at =
+        SimpleCron::parse(&settings.value::<String>(SETTINGS, "schedule-reindex-collation"));
+
+    // Only full-text fields are pruned, keyword fields have to match exactly
+    let prune_fields: Vec<(Collection, FieldId)> = vec![
+        (Collection::Mail, RfcHeader::Subject.into()),
+        (Collection::Mail, MessageField::Body.into()),
+        (Collection::Mail, MessageField::Attachment.into()),
+    ];
+
+    // Register tasks in the same order as their ids
+    for (name, scope) in [
+        ("purge-accounts", TaskScope::Node),
+        ("purge-blobs", TaskScope::Node),
+        ("snapshot-log", TaskScope::Node),
+        ("compact-db", TaskScope::Node),
+        ("backup", TaskScope::Node),
+        ("expunge-mailboxes", TaskScope::Cluster),
+        ("purge-expired", TaskScope::Node),
+        ("prune-index", TaskScope::Node),
+        ("reindex-collation", TaskScope::Node),
+        ("renumber-ids", TaskScope::Node),
+    ] {
+        core.maintenance.register(name, scope);
+    }
 
     tokio::spawn(async move {
         debug!("Housekeeper task started.");
+        let mut pending_tasks = [false; 9];
         loop {
             let time_to_next = [
                 purge_accounts_at.time_to_next(),
                 purge_blobs_at.time_to_next(),
                 snapshot_log_at.time_to_next(),
                 compact_db_at.time_to_next(),
+                backup_at
+                    .as_ref()
+                    .map(|(backup_at, _)| backup_at.time_to_next())
+                    .unwrap_or(Duration::MAX),
+                expunge_mailboxes_at.time_to_next(),
+                purge_expired_at.time_to_next(),
+                if prune_options.is_enabled() {
+                    prune_index_at.time_to_next()
+                } else {
+                    Duration::MAX
+                },
+                reindex_collation_at.time_to_next(),
             ];
-            let mut tasks_to_run = [false, false, false, false];
+            let mut tasks_to_run = pending_tasks;
             let start_time = SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .map(|d| d.as_secs())
                 .unwrap_or(0);
+            for (task_id, time_to_next) in time_to_next.iter().enumerate() {
+                core.maintenance.set_next_run(task_id, *time_to_next);
+            }
 
-            match tokio::time::timeout(time_to_next.iter().min().copied().unwrap(), rx.recv()).await
-            {
+            // Wake up when the next task is due or, with deferred tasks, when the
+            // next maintenance window opens.
+            let mut timeout = time_to_next.iter().min().copied().unwrap();
+            if pending_tasks.iter().any(|p| *p) {
+                timeout = std::cmp::min(timeout, core.maintenance.time_to_open());
+            }
+
+            match tokio::time::timeout(timeout, rx.recv()).await {
                 Ok(Some(event)) => match event {
                     Event::PurgeAccounts => tasks_to_run[TASK_PURGE_ACCOUNTS] = true,
                     Event::PurgeBlobs => tasks_to_run[TASK_PURGE_BLOBS] = true,
                     Event::SnapshotLog => tasks_to_run[TASK_SNAPSHOT_LOG] = true,
                     Event::CompactDb => tasks_to_run[TASK_COMPACT_DB] = true,
+                    Event::Backup => tasks_to_run[TASK_BACKUP] = true,
+                    Event::ExpungeMailboxes => tasks_to_run[TASK_EXPUNGE_MAILBOXES] = true,
+                    Event::PurgeExpired => tasks_to_run[TASK_PURGE_EXPIRED] = true,
+                    Event::PruneIndex => tasks_to_run[TASK_PRUNE_INDEX] = true,
+                    Event::ReindexCollation => tasks_to_run[TASK_REINDEX_COLLATION] = true,
                     Event::Exit => {
                         debug!("Housekeeper task exiting.");
                         return;
@@ -126,29 +225,86 @@ pub fn spawn_housekeeper<T>(
                 .map(|d| d.as_secs())
                 .unwrap_or(0);
             for (pos, time_to_next) in time_to_next.into_iter().enumerate() {
-                if start_time + time_to_next.as_secs() <= now {
+                if start_time.saturating_add(time_to_next.as_secs()) <= now {
                     tasks_to_run[pos] = true;
                 }
             }
 
+            // Defer tasks until the next maintenance window
+            if !core.maintenance.is_open() {
+                for (task_id, do_run) in tasks_to_run.into_iter().enumerate() {
+                    // Cluster-wide tasks are only deferred on the leader
+                    if do_run
+                        && !pending_tasks[task_id]
+                        && (task_id != TASK_EXPUNGE_MAILBOXES || core.is_leader())
+                    {
+                        debug!(
+                            "Deferring task {} until the next maintenance window.",
+                            task_id
+                        );
+                        core.maintenance.set_pending(task_id);
+                        pending_tasks[task_id] = true;
+                    }
+                }
+                continue;
+            }
+            pending_tasks = [false; 9];
+
             // Spawn tasks
             for (task_id, do_run) in tasks_to_run.into_iter().enumerate() {
                 if !do_run {
                     continue;
                 }
 
+                // Cluster-wide tasks run only once, on the leader
+                if task_id == TASK_EXPUNGE_MAILBOXES && !core.is_leader() {
+                    // Deferred while this node was leading
+                    core.maintenance.clear_pending(task_id);
+                    continue;
+                }
+
+                if !core.maintenance.start(task_id) {
+                    debug!("Task {} is still running, skipping.", task_id);
+                    continue;
+                }
+
                 let store = core.store.clone();
                 let core = core.clone();
+                let backup_path = backup_at.as_ref().map(|(_, path)| path.clone());
+                let prune_fields = prune_fields.clone();
 
                 tokio::spawn(async move {
                     let result = match task_id {
                         TASK_PURGE_ACCOUNTS => {
                             info!("Purging deleted accounts.");
-                            core.spawn_worker(move || store.principal_purge()).await
+                            match core.spawn_worker(move || store.principal_purge()).await {
+                                Ok(account_ids) if !account_ids.is_empty() => {
+                                    // Reclaim the space of the purged accounts right away,
+                                    // after any compaction already running on this node.
+                                    while !core.maintenance.start(TASK_COMPACT_DB) {
+                                        time::sleep(Duration::from_secs(COMPACT_WAIT_SECS)).await;
+                                    }
+                                    let result =
+                                        compact_db(core.clone(), account_ids.into(), &[]).await;
+                                    core.maintenance.finish(
+                                        TASK_COMPACT_DB,
+                                        result.as_ref().err().map(|err| err.to_string()),
+                                    );
+                                    result.map(|report| {
+                                        info!(
+                                            "Compacted {} purged account(s), reclaimed {} bytes.",
+                                            report.accounts, report.reclaimed_bytes
+                                        );
+                                    })
+                                }
+                                result => result.map(|_| ()),
+                            }
                         }
                         TASK_PURGE_BLOBS => {
                             info!("Purging removed and expired blobs.");
-                            core.spawn_worker(move || store.purge_blobs()).await
+                            let is_leader = core.is_leader();
+                            core.spawn_worker(move || store.purge_blobs(is_leader))
+                                .await
                         }
                         TASK_SNAPSHOT_LOG => {
                             info!("Compacting changes and Raft logs.");
@@ -157,21 +313,185 @@ pub fn spawn_housekeeper<T>(
                         }
                         TASK_COMPACT_DB => {
                             info!("Compacting database.");
-                            core.spawn_worker(move || store.db.compact(ColumnFamily::Bitmaps))
+                            compact_db(core.clone(), None, &[ColumnFamily::Bitmaps])
                                 .await
+                                .map(|report| {
+                                    debug!(
+                                        "Compaction reclaimed {} bytes.",
+                                        report.reclaimed_bytes
+                                    );
+                                })
+                        }
+                        TASK_BACKUP => {
+                            if let Some(backup_path) = backup_path {
+                                if core.is_up_to_date() {
+                                    info!("Creating backup at {}.", backup_path.display());
+                                    core.spawn_worker(move || {
+                                        store.backup(&backup_path).map(|_| ())
+                                    })
+                                    .await
+                                } else {
+                                    info!("Skipping backup, node is not up to date.");
+                                    Ok(())
+                                }
+                            } else {
+                                error!("Backup requested but 'backup-path' is not configured.");
+                                Ok(())
+                            }
+                        }
+                        TASK_EXPUNGE_MAILBOXES => {
+                            info!("Expunging messages from Trash and Junk mailboxes.");
+                            expunge_mailboxes(&core).await
+                        }
+                        TASK_PURGE_EXPIRED => {
+                            info!("Purging expired values.");
+                            core.spawn_worker(move || {
+                                store.purge_expired().map(|purged| {
+                                    debug!("Purged {} expired values.", purged);
+                                })
+                            })
+                            .await
+                        }
+                        TASK_PRUNE_INDEX => {
+                            info!("Pruning full-text index.");
+                            core.spawn_worker(move || {
+                                store.prune_index(prune_options, &prune_fields).map(|report| {
+                                    debug!(
+                                        "Pruned {} term(s) and {} posting(s) from {} collection(s).",
+                                        report.pruned_terms,
+                                        report.pruned_postings,
+                                        report.collections
+                                    );
+                                })
+                            })
+                            .await
+                        }
+                        TASK_REINDEX_COLLATION => {
+                            info!("Rebuilding message sort keys.");
+                            reindex_collation(&core).await
                         }
                         _ => unreachable!(),
                     };
 
-                    if let Err(err) = result {
+                    if let Err(err) = &result {
                         error!("Error while running housekeeper task: {}", err);
                     }
+                    core.maintenance
+                        .finish(task_id, result.err().map(|err| err.to_string()));
                 });
             }
         }
     });
 }
 
+async fn reindex_collation<T>(core: &web::Data<JMAPServer<T>>) -> store::Result<()>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let account_ids = core
+        .spawn_worker(move || store.get_document_ids(SUPERUSER_ID, Collection::Principal))
+        .await?
+        .unwrap_or_default();
+
+    for account_id in account_ids {
+        let store = core.store.clone();
+        if let Err(err) = core
+            .spawn_worker(move || store.mail_reindex_collation(account_id))
+            .await
+        {
+            error!(
+                "Failed to rebuild the sort keys of account {}: {}",
+                account_id, err
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn expunge_mailboxes<T>(core: &web::Data<JMAPServer<T>>) -> store::Result<()>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let account_ids = core
+        .spawn_worker(move || store.get_document_ids(SUPERUSER_ID, Collection::Principal))
+        .await?
+        .unwrap_or_default();
+    let dry_run = core.store.config.mail_expunge_dry_run;
+
+    for account_id in account_ids {
+        let report = match expunge_account(core, account_id, dry_run).await {
+            Ok(report) => report,
+            Err(err) => {
+                error!(
+                    "Failed to expunge mailboxes of account {}: {}",
+                    account_id, err
+                );
+                continue;
+            }
+        };
+        if dry_run {
+            for mailbox in &report.mailboxes {
+                info!(
+                    "Dry run: would expunge {} message(s) older than {} day(s) from mailbox {} of account {}.",
+                    mailbox.destroyed.len(),
+                    mailbox.days,
+                    mailbox.id,
+                    report.account_id
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn expunge_account<T>(
+    core: &web::Data<JMAPServer<T>>,
+    account_id: AccountId,
+    dry_run: bool,
+) -> store::Result<ExpungeReport>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let report = core
+        .spawn_worker(move || store.mail_expunge(account_id, dry_run))
+        .await?;
+
+    if let Some(change_id) = report.change_id {
+        debug!(
+            "Expunged {} message(s) from account {}.",
+            report.total_destroyed(),
+            report.account_id
+        );
+
+        // Commit change
+        if core.is_in_cluster() {
+            core.commit_index(change_id).await;
+        }
+
+        // Notify subscribers
+        if let Err(err) = core
+            .publish_state_change(StateChange::new(
+                account_id,
+                vec![
+                    (TypeState::Email, change_id),
+                    (TypeState::Mailbox, change_id),
+                    (TypeState::Thread, change_id),
+                ],
+            ))
+            .await
+        {
+            error!("Failed to publish state change: {}", err);
+        }
+    }
+
+    Ok(report)
+}
+
 pub fn init_housekeeper() -> (mpsc::Sender<Event>, mpsc::Receiver<Event>) {
     mpsc::channel::<Event>(IPC_CHANNEL_BUFFER)
 }
@@ -0,0 +1,583 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{sync::Arc, time::Duration};
+
+use rustls::ServerName;
+use store::chrono::DateTime;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::TlsConnector;
+
+use crate::cluster::rpc::tls::load_tls_client_config;
+
+const MAX_LINE_LENGTH: u64 = 1024 * 1024;
+
+pub trait ImapStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ImapStream for T {}
+
+/// Minimal IMAP4rev1 client used to migrate accounts from IMAP servers.
+/// It only issues the handful of commands needed to read a mailbox tree
+/// and download its messages, one command at a time.
+pub struct ImapClient {
+    stream: BufReader<Box<dyn ImapStream>>,
+    tag: usize,
+    timeout: Duration,
+    max_literal: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Atom(String),
+    Quoted(String),
+    Literal(Vec<u8>),
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    No,
+    Bad,
+}
+
+pub struct Response {
+    pub status: Status,
+    pub text: String,
+    pub untagged: Vec<Vec<Token>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ImapMailbox {
+    pub name: String,
+    pub delimiter: Option<char>,
+    pub attributes: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ImapMessage {
+    pub uid: u32,
+    pub size: usize,
+    pub flags: Vec<String>,
+    pub internal_date: Option<i64>,
+}
+
+impl ImapClient {
+    /// Connects to an `imaps://` (implicit TLS) or `imap://` URL, the latter
+    /// is upgraded with STARTTLS when the server supports it.
+    pub async fn connect(url: &str, timeout: Duration, max_literal: usize) -> Result<Self, String> {
+        let (is_tls, address) = if let Some(address) = url.strip_prefix("imaps://") {
+            (true, address)
+        } else if let Some(address) = url.strip_prefix("imap://") {
+            (false, address)
+        } else {
+            return Err(format!("Invalid IMAP URL {:?}.", url));
+        };
+        let address = address.trim_end_matches('/');
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse::<u16>()
+                    .map_err(|_| format!("Invalid IMAP URL {:?}.", url))?,
+            ),
+            _ => (address, if is_tls { 993 } else { 143 }),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let stream = tokio::time::timeout(timeout, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| "IMAP connection timed out.".to_string())?
+            .map_err(|err| format!("Failed to connect to IMAP server: {}", err))?;
+        let mut client = ImapClient {
+            stream: BufReader::new(if is_tls {
+                Box::new(tls_connect(host, stream).await?) as Box<dyn ImapStream>
+            } else {
+                Box::new(stream) as Box<dyn ImapStream>
+            }),
+            tag: 0,
+            timeout,
+            max_literal,
+        };
+
+        // Read greeting
+        let greeting = client.read_tokens().await?;
+        if !greeting.get(1).map_or(false, |status| status.is_atom("OK")) {
+            return Err("IMAP server did not send a valid greeting.".to_string());
+        }
+
+        if !is_tls
+            && client
+                .command("CAPABILITY")
+                .await?
+                .ok()?
+                .untagged
+                .iter()
+                .flatten()
+                .any(|token| token.is_atom("STARTTLS"))
+        {
+            client.command("STARTTLS").await?.ok()?;
+            let stream = std::mem::replace(
+                &mut client.stream,
+                BufReader::new(Box::new(tokio::io::duplex(1).0) as Box<dyn ImapStream>),
+            )
+            .into_inner();
+            client.stream = BufReader::new(Box::new(tls_connect(host, stream).await?));
+        }
+
+        Ok(client)
+    }
+
+    pub async fn login(&mut self, username: &str, password: &str) -> Result<(), String> {
+        self.command(&format!("LOGIN {} {}", quote(username)?, quote(password)?))
+            .await?
+            .ok()
+            .map(|_| ())
+    }
+
+    pub async fn list(&mut self) -> Result<Vec<ImapMailbox>, String> {
+        let mut mailboxes = Vec::new();
+        for tokens in self.command("LIST \"\" \"*\"").await?.ok()?.untagged {
+            if let [Token::Atom(name), Token::Open, rest @ ..] = tokens.as_slice() {
+                if !name.eq_ignore_ascii_case("LIST") {
+                    continue;
+                }
+                let pos = rest
+                    .iter()
+                    .position(|token| matches!(token, Token::Close))
+                    .ok_or_else(|| "Invalid LIST response.".to_string())?;
+                let attributes = rest[..pos]
+                    .iter()
+                    .filter_map(|token| token.as_string())
+                    .collect::<Vec<_>>();
+                if let [delimiter, name, ..] = &rest[pos + 1..] {
+                    mailboxes.push(ImapMailbox {
+                        name: name
+                            .as_string()
+                            .ok_or_else(|| "Invalid LIST response.".to_string())?,
+                        delimiter: match delimiter {
+                            Token::Quoted(delimiter) => delimiter.chars().next(),
+                            _ => None,
+                        },
+                        attributes,
+                    });
+                }
+            }
+        }
+        Ok(mailboxes)
+    }
+
+    /// Returns the number of messages in a mailbox.
+    pub async fn status(&mut self, mailbox: &str) -> Result<usize, String> {
+        let response = self
+            .command(&format!("STATUS {} (MESSAGES)", quote(mailbox)?))
+            .await?;
+        if response.status != Status::Ok {
+            return Ok(0);
+        }
+        for tokens in response.untagged {
+            if let Some(pos) = tokens.iter().position(|token| token.is_atom("MESSAGES")) {
+                return Ok(tokens
+                    .get(pos + 1)
+                    .and_then(|token| token.as_number())
+                    .unwrap_or(0) as usize);
+            }
+        }
+        Ok(0)
+    }
+
+    /// Opens a mailbox read-only, returns false if it cannot be selected.
+    pub async fn examine(&mut self, mailbox: &str) -> Result<bool, String> {
+        self.command(&format!("EXAMINE {}", quote(mailbox)?))
+            .await
+            .map(|response| response.status == Status::Ok)
+    }
+
+    /// Fetches the size, flags and arrival date of the messages with a
+    /// UID greater than or equal to `from_uid` in the selected mailbox.
+    pub async fn fetch_messages(&mut self, from_uid: u32) -> Result<Vec<ImapMessage>, String> {
+        let mut messages = Vec::new();
+        for tokens in self
+            .command(&format!(
+                "UID FETCH {}:* (UID RFC822.SIZE FLAGS INTERNALDATE)",
+                from_uid.max(1)
+            ))
+            .await?
+            .ok()?
+            .untagged
+        {
+            let items = match fetch_items(&tokens) {
+                Some(items) => items,
+                None => continue,
+            };
+            let mut message = ImapMessage {
+                uid: 0,
+                size: 0,
+                flags: Vec::new(),
+                internal_date: None,
+            };
+            let mut items = items.iter();
+            while let Some(item) = items.next() {
+                match item {
+                    Token::Atom(name) if name.eq_ignore_ascii_case("UID") => {
+                        message.uid = items
+                            .next()
+                            .and_then(|token| token.as_number())
+                            .unwrap_or(0);
+                    }
+                    Token::Atom(name) if name.eq_ignore_ascii_case("RFC822.SIZE") => {
+                        message.size = items
+                            .next()
+                            .and_then(|token| token.as_string())
+                            .and_then(|size| size.parse().ok())
+                            .unwrap_or(0);
+                    }
+                    Token::Atom(name) if name.eq_ignore_ascii_case("FLAGS") => {
+                        if items.next() == Some(&Token::Open) {
+                            for flag in items.by_ref() {
+                                match flag {
+                                    Token::Close => break,
+                                    flag => message.flags.extend(flag.as_string()),
+                                }
+                            }
+                        }
+                    }
+                    Token::Atom(name) if name.eq_ignore_ascii_case("INTERNALDATE") => {
+                        message.internal_date = items
+                            .next()
+                            .and_then(|token| token.as_string())
+                            .and_then(|date| parse_internal_date(&date));
+                    }
+                    _ => (),
+                }
+            }
+
+            // "n:*" always returns the last message, even if its UID is lower
+            if message.uid >= from_uid && message.uid > 0 {
+                messages.push(message);
+            }
+        }
+        messages.sort_unstable_by_key(|message| message.uid);
+        Ok(messages)
+    }
+
+    /// Downloads a message from the selected mailbox.
+    pub async fn fetch_body(&mut self, uid: u32) -> Result<Option<Vec<u8>>, String> {
+        for tokens in self
+            .command(&format!("UID FETCH {} BODY.PEEK[]", uid))
+            .await?
+            .ok()?
+            .untagged
+        {
+            if let Some(items) = fetch_items(&tokens) {
+                let mut items = items.iter();
+                while let Some(item) = items.next() {
+                    if item.is_atom("BODY[]") {
+                        match items.next() {
+                            Some(Token::Literal(body)) => return Ok(Some(body.clone())),
+                            Some(Token::Quoted(body)) => return Ok(Some(body.as_bytes().to_vec())),
+                            _ => (),
+                        }
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn logout(&mut self) {
+        self.command("LOGOUT").await.ok();
+    }
+
+    pub async fn command(&mut self, command: &str) -> Result<Response, String> {
+        self.tag += 1;
+        let tag = format!("A{}", self.tag);
+        let timeout = self.timeout;
+        tokio::time::timeout(timeout, async {
+            let stream = self.stream.get_mut();
+            stream
+                .write_all(format!("{} {}\r\n", tag, command).as_bytes())
+                .await
+                .map_err(|err| format!("Failed to write to IMAP server: {}", err))?;
+            stream
+                .flush()
+                .await
+                .map_err(|err| format!("Failed to write to IMAP server: {}", err))?;
+            self.read_response(&tag).await
+        })
+        .await
+        .map_err(|_| "IMAP command timed out.".to_string())?
+    }
+
+    async fn read_response(&mut self, tag: &str) -> Result<Response, String> {
+        let mut untagged = Vec::new();
+        loop {
+            let mut tokens = self.read_tokens().await?;
+            match tokens.first() {
+                Some(Token::Atom(prefix)) if prefix == "*" => {
+                    tokens.remove(0);
+                    untagged.push(tokens);
+                }
+                Some(Token::Atom(prefix)) if prefix == tag => {
+                    let status = match tokens.get(1) {
+                        Some(Token::Atom(status)) if status.eq_ignore_ascii_case("OK") => {
+                            Status::Ok
+                        }
+                        Some(Token::Atom(status)) if status.eq_ignore_ascii_case("NO") => {
+                            Status::No
+                        }
+                        _ => Status::Bad,
+                    };
+                    return Ok(Response {
+                        status,
+                        text: tokens
+                            .iter()
+                            .skip(2)
+                            .filter_map(|token| token.as_string())
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                        untagged,
+                    });
+                }
+                _ => return Err("Unexpected IMAP response.".to_string()),
+            }
+        }
+    }
+
+    /// Reads a response line, including any literals it contains.
+    async fn read_tokens(&mut self) -> Result<Vec<Token>, String> {
+        let mut tokens = Vec::new();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            (&mut self.stream)
+                .take(MAX_LINE_LENGTH)
+                .read_until(b'\n', &mut line)
+                .await
+                .map_err(|err| format!("Failed to read from IMAP server: {}", err))?;
+            if !line.ends_with(b"\n") {
+                return Err("IMAP connection closed or line too long.".to_string());
+            }
+
+            if let Some((text, size)) = literal_size(&line) {
+                if size > self.max_literal {
+                    return Err(format!("IMAP literal exceeds {} bytes.", self.max_literal));
+                }
+                tokenize(text, &mut tokens);
+                let mut literal = vec![0; size];
+                self.stream
+                    .read_exact(&mut literal)
+                    .await
+                    .map_err(|err| format!("Failed to read from IMAP server: {}", err))?;
+                tokens.push(Token::Literal(literal));
+            } else {
+                tokenize(&line, &mut tokens);
+                return Ok(tokens);
+            }
+        }
+    }
+}
+
+impl Response {
+    pub fn ok(self) -> Result<Self, String> {
+        if self.status == Status::Ok {
+            Ok(self)
+        } else {
+            Err(format!("IMAP command failed: {}", self.text))
+        }
+    }
+}
+
+impl Token {
+    pub fn is_atom(&self, name: &str) -> bool {
+        matches!(self, Token::Atom(value) if value.eq_ignore_ascii_case(name))
+    }
+
+    pub fn as_string(&self) -> Option<String> {
+        match self {
+            Token::Atom(value) | Token::Quoted(value) => Some(value.to_string()),
+            Token::Literal(value) => String::from_utf8(value.clone()).ok(),
+            Token::Open | Token::Close => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<u32> {
+        match self {
+            Token::Atom(value) => value.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+async fn tls_connect(
+    host: &str,
+    stream: impl ImapStream + 'static,
+) -> Result<impl ImapStream, String> {
+    let domain =
+        ServerName::try_from(host).map_err(|_| format!("Invalid IMAP server name {:?}.", host))?;
+    TlsConnector::from(Arc::new(load_tls_client_config(false)))
+        .connect(domain, stream)
+        .await
+        .map_err(|err| format!("TLS handshake with IMAP server failed: {}", err))
+}
+
+fn quote(value: &str) -> Result<String, String> {
+    if value.contains(['\r', '\n', '\0']) {
+        return Err("Invalid characters in IMAP string.".to_string());
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for ch in value.chars() {
+        if ch == '"' || ch == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(ch);
+    }
+    quoted.push('"');
+    Ok(quoted)
+}
+
+/// Returns the items of a "* n FETCH (...)" response.
+fn fetch_items(tokens: &[Token]) -> Option<&[Token]> {
+    match tokens {
+        [Token::Atom(_), Token::Atom(name), Token::Open, items @ .., Token::Close]
+            if name.eq_ignore_ascii_case("FETCH") =>
+        {
+            Some(items)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the line without its literal marker and the size of the literal
+/// that follows it, if any.
+fn literal_size(line: &[u8]) -> Option<(&[u8], usize)> {
+    let line = line.strip_suffix(b"\n")?;
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let line = line.strip_suffix(b"}")?;
+    let start = line.iter().rposition(|&ch| ch == b'{')?;
+    let size = &line[start + 1..];
+    let size = size.strip_suffix(b"+").unwrap_or(size);
+    Some((
+        &line[..start],
+        std::str::from_utf8(size).ok()?.parse().ok()?,
+    ))
+}
+
+fn tokenize(text: &[u8], tokens: &mut Vec<Token>) {
+    let mut iter = text.iter().peekable();
+    while let Some(&ch) = iter.next() {
+        match ch {
+            b' ' | b'\r' | b'\n' | b'\t' => (),
+            b'(' => tokens.push(Token::Open),
+            b')' => tokens.push(Token::Close),
+            b'"' => {
+                let mut value = Vec::new();
+                while let Some(&ch) = iter.next() {
+                    match ch {
+                        b'"' => break,
+                        b'\\' => value.extend(iter.next()),
+                        _ => value.push(ch),
+                    }
+                }
+                tokens.push(Token::Quoted(String::from_utf8_lossy(&value).into_owned()));
+            }
+            _ => {
+                // Brackets are part of the atom, as in "BODY[]" or "[UIDVALIDITY 1]"
+                let mut value = vec![ch];
+                let mut depth = u32::from(ch == b'[');
+                while let Some(&&ch) = iter.peek() {
+                    match ch {
+                        b'[' => depth += 1,
+                        b']' => depth = depth.saturating_sub(1),
+                        b' ' | b'(' | b')' | b'"' | b'\r' | b'\n' if depth == 0 => break,
+                        _ => (),
+                    }
+                    value.push(ch);
+                    iter.next();
+                }
+                tokens.push(Token::Atom(String::from_utf8_lossy(&value).into_owned()));
+            }
+        }
+    }
+}
+
+fn parse_internal_date(date: &str) -> Option<i64> {
+    DateTime::parse_from_str(date.trim(), "%d-%b-%Y %H:%M:%S %z")
+        .ok()
+        .map(|date| date.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{literal_size, parse_internal_date, tokenize, Token};
+
+    #[test]
+    fn imap_tokenize() {
+        let mut tokens = Vec::new();
+        tokenize(
+            concat!(
+                "* 1 FETCH (UID 42 FLAGS (\\Seen $Junk) ",
+                "INTERNALDATE \"17-Jul-1996 02:44:25 -0700\" BODY[] "
+            )
+            .as_bytes(),
+            &mut tokens,
+        );
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Atom("*".to_string()),
+                Token::Atom("1".to_string()),
+                Token::Atom("FETCH".to_string()),
+                Token::Open,
+                Token::Atom("UID".to_string()),
+                Token::Atom("42".to_string()),
+                Token::Atom("FLAGS".to_string()),
+                Token::Open,
+                Token::Atom("\\Seen".to_string()),
+                Token::Atom("$Junk".to_string()),
+                Token::Close,
+                Token::Atom("INTERNALDATE".to_string()),
+                Token::Quoted("17-Jul-1996 02:44:25 -0700".to_string()),
+                Token::Atom("BODY[]".to_string()),
+            ]
+        );
+
+        assert_eq!(
+            literal_size(b"* 1 FETCH (BODY[] {310}\r\n"),
+            Some((&b"* 1 FETCH (BODY[] "[..], 310))
+        );
+        assert_eq!(
+            literal_size(b"* LIST (\\HasNoChildren) \"/\" {5+}\r\n").map(|(_, size)| size),
+            Some(5)
+        );
+        assert_eq!(literal_size(b"A1 OK done\r\n"), None);
+
+        assert_eq!(
+            parse_internal_date(" 7-Jul-1996 02:44:25 -0700"),
+            Some(836732665)
+        );
+    }
+}
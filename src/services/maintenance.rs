@@ -0,0 +1,382 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::Duration;
+
+use actix_web::{
+    http::{header::ContentType, StatusCode},
+    web, HttpResponse,
+};
+use jmap::{request::ACLEnforce, SUPERUSER_ID};
+use jmap_sharing::principal::account::JMAPAccountStore;
+use store::{
+    chrono::{self, Timelike},
+    config::{env_settings::EnvSettings, settings::Setting},
+    parking_lot::Mutex,
+    tracing::error,
+    write::ttl::now,
+    Store,
+};
+
+use crate::{api::RequestError, authorization::Session, server::failed_to, JMAPServer};
+
+pub const SETTINGS: &[Setting] = &[Setting::list("maintenance-windows").describe(
+    "Local time HH:MM-HH:MM ranges, tasks due outside a window are deferred to the next one",
+)];
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskScope {
+    // Runs on every node against its local replica
+    Node,
+    // Runs once for the whole cluster, on the leader
+    Cluster,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    Idle,
+    Pending,
+    Running,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct TaskProgress {
+    pub done: u64,
+    pub total: u64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TaskStatus {
+    pub name: &'static str,
+    pub scope: TaskScope,
+    pub state: TaskState,
+    #[serde(rename(serialize = "nextRun"))]
+    pub next_run: Option<u64>,
+    #[serde(rename(serialize = "lastStarted"))]
+    pub last_started: Option<u64>,
+    #[serde(rename(serialize = "lastFinished"))]
+    pub last_finished: Option<u64>,
+    #[serde(rename(serialize = "lastError"))]
+    pub last_error: Option<String>,
+    #[serde(rename(serialize = "lastResult"))]
+    pub last_result: Option<String>,
+    pub progress: Option<TaskProgress>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    // Minutes since local midnight, a window ending before it starts wraps around midnight
+    start: u32,
+    end: u32,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MaintenanceReport {
+    #[serde(rename(serialize = "windowOpen"))]
+    pub window_open: bool,
+    pub windows: Vec<String>,
+    pub tasks: Vec<TaskStatus>,
+}
+
+// Confines heavy background tasks to the configured maintenance windows and
+// keeps track of their status. Without windows, tasks run as soon as they are due.
+pub struct MaintenanceScheduler {
+    windows: Vec<MaintenanceWindow>,
+    tasks: Mutex<Vec<TaskStatus>>,
+}
+
+impl MaintenanceWindow {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (start, end) = value.trim().split_once('-')?;
+        let parse_time = |time: &str| -> Option<u32> {
+            let (hour, minute) = time.trim().split_once(':')?;
+            let hour = hour.parse::<u32>().ok().filter(|h| *h < 24)?;
+            let minute = minute.parse::<u32>().ok().filter(|m| *m < 60)?;
+            Some(hour * 60 + minute)
+        };
+        let window = MaintenanceWindow {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        };
+        if window.start != window.end {
+            Some(window)
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    pub fn minutes_to_open(&self, minute: u32) -> u32 {
+        if self.contains(minute) {
+            0
+        } else {
+            (self.start + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY
+        }
+    }
+}
+
+impl std::fmt::Display for MaintenanceWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+impl MaintenanceScheduler {
+    pub fn parse(settings: &EnvSettings) -> Self {
+        let mut windows = Vec::new();
+        if let Some(value) = settings.get("maintenance-windows") {
+            for window in value.split(';').filter(|w| !w.trim().is_empty()) {
+                windows.push(MaintenanceWindow::parse(window).unwrap_or_else(|| {
+                    failed_to(&format!(
+                        "parse 'maintenance-windows', invalid window '{}', expected HH:MM-HH:MM.",
+                        window
+                    ));
+                }));
+            }
+        }
+        MaintenanceScheduler::new(windows)
+    }
+
+    pub fn new(windows: Vec<MaintenanceWindow>) -> Self {
+        MaintenanceScheduler {
+            windows,
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Registers a task, returning its id.
+    pub fn register(&self, name: &'static str, scope: TaskScope) -> usize {
+        let mut tasks = self.tasks.lock();
+        tasks.push(TaskStatus {
+            name,
+            scope,
+            state: TaskState::Idle,
+            next_run: None,
+            last_started: None,
+            last_finished: None,
+            last_error: None,
+            last_result: None,
+            progress: None,
+        });
+        tasks.len() - 1
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.time_to_open() == Duration::ZERO
+    }
+
+    /// Time until the next maintenance window opens, zero if one is open.
+    pub fn time_to_open(&self) -> Duration {
+        let now = chrono::Local::now();
+        let minute = now.hour() * 60 + now.minute();
+        self.windows
+            .iter()
+            .map(|window| window.minutes_to_open(minute))
+            .min()
+            .map(|minutes| {
+                if minutes > 0 {
+                    // Wake up at the start of the minute the window opens
+                    Duration::from_secs(minutes as u64 * 60 - now.second() as u64)
+                } else {
+                    Duration::ZERO
+                }
+            })
+            .unwrap_or(Duration::ZERO)
+    }
+
+    pub fn set_next_run(&self, task_id: usize, time_to_next: Duration) {
+        if let Some(task) = self.tasks.lock().get_mut(task_id) {
+            task.next_run = if time_to_next != Duration::MAX {
+                Some(now().saturating_add(time_to_next.as_secs()))
+            } else {
+                None
+            };
+        }
+    }
+
+    pub fn set_pending(&self, task_id: usize) {
+        if let Some(task) = self.tasks.lock().get_mut(task_id) {
+            if task.state == TaskState::Idle {
+                task.state = TaskState::Pending;
+            }
+        }
+    }
+
+    /// Returns a deferred task to idle, for tasks that are not run on this node.
+    pub fn clear_pending(&self, task_id: usize) {
+        if let Some(task) = self.tasks.lock().get_mut(task_id) {
+            if task.state == TaskState::Pending {
+                task.state = TaskState::Idle;
+            }
+        }
+    }
+
+    /// Marks a task as running, returns false if it is already running.
+    pub fn start(&self, task_id: usize) -> bool {
+        if let Some(task) = self.tasks.lock().get_mut(task_id) {
+            if task.state != TaskState::Running {
+                task.state = TaskState::Running;
+                task.last_started = now().into();
+                task.last_result = None;
+                task.progress = None;
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn finish(&self, task_id: usize, error: Option<String>) {
+        if let Some(task) = self.tasks.lock().get_mut(task_id) {
+            task.state = TaskState::Idle;
+            task.last_finished = now().into();
+            task.last_error = error;
+            task.progress = None;
+        }
+    }
+
+    /// Updates the progress of a running task.
+    pub fn set_progress(&self, task_id: usize, done: u64, total: u64) {
+        if let Some(task) = self.tasks.lock().get_mut(task_id) {
+            if task.state == TaskState::Running {
+                task.progress = TaskProgress { done, total }.into();
+            }
+        }
+    }
+
+    /// Records a summary of the outcome of the current run of a task.
+    pub fn set_result(&self, task_id: usize, result: String) {
+        if let Some(task) = self.tasks.lock().get_mut(task_id) {
+            task.last_result = result.into();
+        }
+    }
+
+    pub fn status(&self, task_id: usize) -> Option<TaskStatus> {
+        self.tasks.lock().get(task_id).cloned()
+    }
+
+    pub fn report(&self) -> MaintenanceReport {
+        MaintenanceReport {
+            window_open: self.is_open(),
+            windows: self.windows.iter().map(|w| w.to_string()).collect(),
+            tasks: self.tasks.lock().clone(),
+        }
+    }
+}
+
+pub async fn handle_admin_maintenance<T>(
+    core: web::Data<JMAPServer<T>>,
+    session: Session,
+) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let account_id = session.account_id();
+    match core
+        .spawn_worker(move || Ok(store.get_acl_token(account_id)?.is_member(SUPERUSER_ID)))
+        .await
+    {
+        Ok(true) => Ok(HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType::json())
+            .json(core.maintenance.report())),
+        Ok(false) => Err(RequestError::forbidden()),
+        Err(err) => {
+            error!("Failed to obtain ACL token: {:?}", err);
+            Err(RequestError::internal_server_error())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MaintenanceScheduler, MaintenanceWindow, TaskProgress, TaskScope, TaskState};
+
+    #[test]
+    fn maintenance_windows() {
+        let night = MaintenanceWindow::parse("02:00-05:30").unwrap();
+        assert!(night.contains(2 * 60));
+        assert!(night.contains(5 * 60 + 29));
+        assert!(!night.contains(5 * 60 + 30));
+        assert_eq!(night.minutes_to_open(60), 60);
+        assert_eq!(night.minutes_to_open(6 * 60), 20 * 60);
+        assert_eq!(night.to_string(), "02:00-05:30");
+
+        let wrapping = MaintenanceWindow::parse(" 23:00 - 01:00 ").unwrap();
+        assert!(wrapping.contains(23 * 60 + 30));
+        assert!(wrapping.contains(30));
+        assert!(!wrapping.contains(60));
+        assert_eq!(wrapping.minutes_to_open(22 * 60), 60);
+
+        for invalid in ["", "02:00", "24:00-01:00", "02:60-03:00", "03:00-03:00"] {
+            assert_eq!(MaintenanceWindow::parse(invalid), None, "{}", invalid);
+        }
+
+        // Without windows tasks may always run
+        let scheduler = MaintenanceScheduler::new(Vec::new());
+        assert!(scheduler.is_open());
+        let task_id = scheduler.register("compact-db", TaskScope::Node);
+        scheduler.set_pending(task_id);
+        assert_eq!(scheduler.report().tasks[0].state, TaskState::Pending);
+        scheduler.clear_pending(task_id);
+        assert_eq!(scheduler.report().tasks[0].state, TaskState::Idle);
+        scheduler.set_pending(task_id);
+        assert!(scheduler.start(task_id));
+        scheduler.clear_pending(task_id);
+        assert_eq!(scheduler.report().tasks[0].state, TaskState::Running);
+        assert!(!scheduler.start(task_id));
+        scheduler.set_progress(task_id, 1, 3);
+        assert_eq!(
+            scheduler.status(task_id).unwrap().progress,
+            Some(TaskProgress { done: 1, total: 3 })
+        );
+        scheduler.set_result(task_id, "partial".to_string());
+        scheduler.finish(task_id, Some("failed".to_string()));
+        let task = &scheduler.report().tasks[0];
+        assert_eq!(task.state, TaskState::Idle);
+        assert_eq!(task.last_error.as_deref(), Some("failed"));
+        assert_eq!(task.last_result.as_deref(), Some("partial"));
+        assert_eq!(task.progress, None);
+
+        // Progress is only kept while the task runs
+        scheduler.set_progress(task_id, 2, 3);
+        assert_eq!(scheduler.status(task_id).unwrap().progress, None);
+    }
+}
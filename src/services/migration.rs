@@ -0,0 +1,1522 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::Duration;
+
+use actix_web::web;
+use futures::{stream, StreamExt};
+use jmap::{
+    orm::{serialize::JMAPOrm, TinyORM},
+    principal::schema::{Principal, Property as PrincipalProperty, Value as PrincipalValue},
+    types::type_state::TypeState,
+    SUPERUSER_ID,
+};
+use jmap_mail::{
+    identity::schema::{Identity, Property as IdentityProperty, Value as IdentityValue},
+    mail::{import::JMAPMailImport, schema::Keyword},
+    mailbox::{
+        is_valid_role,
+        name::from_imap_utf7,
+        schema::{Mailbox, Property as MailboxProperty, Value as MailboxValue},
+        CreateMailbox,
+    },
+};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use serde_json::json;
+use store::{
+    ahash::{AHashMap, AHashSet},
+    bincode,
+    blob::BlobId,
+    chrono::DateTime,
+    config::{env_settings::EnvSettings, settings::Setting},
+    core::{collection::Collection, document::Document, error::StoreError, tag::Tag},
+    parking_lot::Mutex,
+    rand::{thread_rng, Rng},
+    tracing::{debug, error, info},
+    write::{batch::WriteBatch, options::IndexOptions, ttl::now},
+    AccountId, DocumentId, JMAPStore, Store,
+};
+
+use crate::{authorization::SymmetricEncrypt, JMAPServer};
+
+use super::{
+    imap_client::{ImapClient, ImapMailbox, ImapMessage},
+    state_change::StateChange,
+};
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::integer("migration-concurrency")
+        .default("4")
+        .describe("Parallel message downloads from JMAP sources"),
+    Setting::integer("migration-chunk-size")
+        .default("50")
+        .describe("Messages per checkpoint"),
+    Setting::millis("migration-timeout").default("60000"),
+];
+
+const CREDENTIALS_CONTEXT: &str = "migration credentials";
+
+const USING: [&str; 3] = [
+    "urn:ietf:params:jmap:core",
+    "urn:ietf:params:jmap:mail",
+    "urn:ietf:params:jmap:submission",
+];
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Migration {
+    pub id: u64,
+    pub account_id: AccountId,
+    pub source: MigrationSource,
+    pub status: MigrationStatus,
+    pub error: Option<String>,
+    pub progress: MigrationProgress,
+    pub checkpoint: MigrationCheckpoint,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MigrationSource {
+    pub url: String,
+    pub username: Option<String>,
+    pub account_id: Option<String>,
+    secret: Vec<u8>,
+    nonce: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum MigrationStatus {
+    #[serde(rename = "pending")]
+    Pending,
+    #[serde(rename = "running")]
+    Running,
+    #[serde(rename = "completed")]
+    Completed,
+    #[serde(rename = "failed")]
+    Failed,
+    #[serde(rename = "cancelled")]
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct MigrationProgress {
+    #[serde(rename = "mailboxesCreated")]
+    pub mailboxes_created: usize,
+    #[serde(rename = "identitiesCreated")]
+    pub identities_created: usize,
+    #[serde(rename = "messagesTotal")]
+    pub messages_total: usize,
+    #[serde(rename = "messagesProcessed")]
+    pub messages_processed: usize,
+    #[serde(rename = "messagesImported")]
+    pub messages_imported: usize,
+    #[serde(rename = "messagesSkipped")]
+    pub messages_skipped: usize,
+    #[serde(rename = "messagesFailed")]
+    pub messages_failed: usize,
+    #[serde(rename = "bytesImported")]
+    pub bytes_imported: u64,
+}
+
+/// Everything needed to resume an interrupted migration: the mapping of
+/// remote mailbox ids to local ones and the position of the next chunk of
+/// messages, which are fetched sorted by ascending arrival date. IMAP sources
+/// are copied one mailbox at a time, `position` is then the index of the
+/// mailbox being copied and `uid` the next UID to fetch from it.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct MigrationCheckpoint {
+    pub mailboxes: Option<Vec<(String, DocumentId)>>,
+    pub inbox_id: Option<DocumentId>,
+    pub identities_done: bool,
+    pub position: usize,
+    pub uid: u32,
+}
+
+/// Read-only view of a migration returned by the admin API.
+#[derive(Debug, serde::Serialize)]
+pub struct MigrationInfo {
+    pub id: jmap::types::jmap::JMAPId,
+    #[serde(rename = "accountId")]
+    pub account_id: jmap::types::jmap::JMAPId,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    pub status: MigrationStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub progress: MigrationProgress,
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: u64,
+}
+
+pub struct MigrationManager {
+    pub concurrency: usize,
+    pub chunk_size: usize,
+    pub timeout: Duration,
+    running: Mutex<AHashMap<u64, bool>>,
+}
+
+struct RemoteSession {
+    client: reqwest::Client,
+    authorization: String,
+    api_url: String,
+    download_url: String,
+    account_id: String,
+}
+
+struct RemoteMailbox {
+    id: String,
+    name: String,
+    parent_id: Option<String>,
+    role: Option<String>,
+}
+
+struct RemoteMessage {
+    blob_id: String,
+    mailbox_ids: Vec<String>,
+    keywords: Vec<String>,
+    received_at: Option<i64>,
+}
+
+enum MigrationOutcome {
+    Completed,
+    Cancelled,
+    Interrupted,
+}
+
+struct ImportItem {
+    blob: Vec<u8>,
+    mailbox_ids: Vec<DocumentId>,
+    keywords: Vec<Tag>,
+    received_at: Option<i64>,
+}
+
+#[derive(Default)]
+struct ChunkResult {
+    imported: usize,
+    skipped: usize,
+    failed: usize,
+    bytes: u64,
+}
+
+impl MigrationManager {
+    pub fn parse(settings: &EnvSettings) -> Self {
+        MigrationManager {
+            concurrency: settings
+                .parse("migration-concurrency")
+                .filter(|v| *v > 0)
+                .unwrap_or(4),
+            chunk_size: settings
+                .parse("migration-chunk-size")
+                .filter(|v| *v > 0)
+                .unwrap_or(50),
+            timeout: Duration::from_millis(settings.value(SETTINGS, "migration-timeout")),
+            running: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    fn start(&self, id: u64) -> bool {
+        let mut running = self.running.lock();
+        if !running.contains_key(&id) {
+            running.insert(id, false);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn finish(&self, id: u64) {
+        self.running.lock().remove(&id);
+    }
+
+    pub fn is_running(&self, id: u64) -> bool {
+        self.running.lock().contains_key(&id)
+    }
+
+    /// Requests a running migration to stop after its current chunk.
+    pub fn cancel(&self, id: u64) -> bool {
+        if let Some(cancelled) = self.running.lock().get_mut(&id) {
+            *cancelled = true;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_cancelled(&self, id: u64) -> bool {
+        self.running.lock().get(&id).copied().unwrap_or(false)
+    }
+}
+
+impl Migration {
+    pub fn new(
+        account_id: AccountId,
+        url: String,
+        username: Option<String>,
+        source_account_id: Option<String>,
+        secret: &str,
+        encryption_key: &str,
+    ) -> Option<Self> {
+        let mut rng = thread_rng();
+        let nonce = (0..SymmetricEncrypt::NONCE_LEN)
+            .map(|_| rng.gen::<u8>())
+            .collect::<Vec<_>>();
+        let secret = SymmetricEncrypt::new(encryption_key.as_bytes(), CREDENTIALS_CONTEXT)
+            .encrypt(secret.as_bytes(), &nonce)
+            .ok()?;
+        let now = now();
+
+        Some(Migration {
+            id: ((account_id as u64) << 32) | rng.gen::<u32>() as u64,
+            account_id,
+            source: MigrationSource {
+                url,
+                username,
+                account_id: source_account_id,
+                secret,
+                nonce,
+            },
+            status: MigrationStatus::Pending,
+            error: None,
+            progress: MigrationProgress::default(),
+            checkpoint: MigrationCheckpoint::default(),
+            created_at: now,
+            updated_at: now,
+        })
+    }
+
+    pub fn info(&self) -> MigrationInfo {
+        MigrationInfo {
+            id: self.id.into(),
+            account_id: self.account_id.into(),
+            url: self.source.url.clone(),
+            username: self.source.username.clone(),
+            status: self.status,
+            error: self.error.clone(),
+            progress: self.progress.clone(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+
+    pub fn is_resumable(&self) -> bool {
+        !matches!(self.status, MigrationStatus::Completed)
+    }
+
+    pub fn is_imap(&self) -> bool {
+        self.source.url.starts_with("imap://") || self.source.url.starts_with("imaps://")
+    }
+
+    fn slot(&self) -> AccountId {
+        self.id as AccountId
+    }
+
+    fn mailbox_map(&self) -> AHashMap<String, DocumentId> {
+        self.checkpoint
+            .mailboxes
+            .as_ref()
+            .map(|mailboxes| mailboxes.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn secret(&self, encryption_key: &str) -> Option<String> {
+        SymmetricEncrypt::new(encryption_key.as_bytes(), CREDENTIALS_CONTEXT)
+            .decrypt(&self.source.secret, &self.source.nonce)
+            .ok()
+            .and_then(|secret| String::from_utf8(secret).ok())
+    }
+}
+
+impl<T> JMAPServer<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    /// Migrations are stored as annotations of the principal of the target
+    /// account, keyed by the lower half of their id, so they are replicated
+    /// along with the principal.
+    pub async fn migration_save(&self, migration: &Migration) -> store::Result<()> {
+        let store = self.store.clone();
+        let account_id = migration.account_id;
+        let slot = migration.slot();
+        let value = bincode::serialize(migration).map_err(|err| {
+            StoreError::SerializeError(format!("Failed to serialize migration: {}", err))
+        })?;
+        self.spawn_worker(move || {
+            let mut document = Document::new(Collection::Principal, account_id);
+            document.annotation(slot, value, IndexOptions::new());
+            let mut batch = WriteBatch::new(SUPERUSER_ID);
+            batch.update_document(document);
+            batch.log_update(Collection::Principal, account_id);
+            store.write(batch).map(|_| ())
+        })
+        .await
+    }
+
+    pub async fn migration_get(&self, id: u64) -> store::Result<Option<Migration>> {
+        let store = self.store.clone();
+        self.spawn_worker(move || {
+            store
+                .get_annotation(
+                    SUPERUSER_ID,
+                    Collection::Principal,
+                    (id >> 32) as AccountId,
+                    id as AccountId,
+                )?
+                .map(|bytes| deserialize_migration(&bytes))
+                .transpose()
+        })
+        .await
+    }
+
+    pub async fn migration_list(&self) -> store::Result<Vec<Migration>> {
+        let store = self.store.clone();
+        self.spawn_worker(move || {
+            let mut migrations = Vec::new();
+            for account_id in store
+                .get_document_ids(SUPERUSER_ID, Collection::Principal)?
+                .unwrap_or_default()
+            {
+                for (_, bytes) in
+                    store.get_annotations(SUPERUSER_ID, Collection::Principal, account_id)?
+                {
+                    migrations.push(deserialize_migration(&bytes)?);
+                }
+            }
+            Ok(migrations)
+        })
+        .await
+    }
+}
+
+fn deserialize_migration(bytes: &[u8]) -> store::Result<Migration> {
+    bincode::deserialize(bytes).map_err(|err| {
+        StoreError::DeserializeError(format!("Failed to deserialize migration: {}", err))
+    })
+}
+
+/// Resumes any migrations that were interrupted by a shutdown or by a
+/// change of leader, called on startup and whenever this node becomes leader.
+pub fn spawn_migrations<T>(core: web::Data<JMAPServer<T>>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    tokio::spawn(async move {
+        if !core.is_leader() {
+            return;
+        }
+        match core.migration_list().await {
+            Ok(migrations) => {
+                for migration in migrations {
+                    if matches!(
+                        migration.status,
+                        MigrationStatus::Pending | MigrationStatus::Running
+                    ) {
+                        info!(
+                            "Resuming migration {} into account {}.",
+                            migration.id, migration.account_id
+                        );
+                        spawn_migration(core.clone(), migration);
+                    }
+                }
+            }
+            Err(err) => {
+                error!("Failed to list migrations: {:?}", err);
+            }
+        }
+    });
+}
+
+/// Starts or resumes a migration, returns false if it is already running.
+pub fn spawn_migration<T>(core: web::Data<JMAPServer<T>>, mut migration: Migration) -> bool
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    if !core.migrations.start(migration.id) {
+        return false;
+    }
+
+    tokio::spawn(async move {
+        let id = migration.id;
+        migration.status = MigrationStatus::Running;
+        migration.error = None;
+
+        match run_migration(&core, &mut migration).await {
+            Ok(MigrationOutcome::Completed) => {
+                info!(
+                    "Migration {} into account {} completed: {:?}",
+                    id, migration.account_id, migration.progress
+                );
+                migration.status = MigrationStatus::Completed;
+            }
+            Ok(MigrationOutcome::Cancelled) => {
+                info!("Migration {} cancelled.", id);
+                migration.status = MigrationStatus::Cancelled;
+            }
+            Ok(MigrationOutcome::Interrupted) => {
+                // The new leader resumes it from the last checkpoint
+                info!(
+                    "Migration {} interrupted, this node is no longer the leader.",
+                    id
+                );
+                core.migrations.finish(id);
+                return;
+            }
+            Err(reason) => {
+                error!("Migration {} failed: {}", id, reason);
+                migration.status = MigrationStatus::Failed;
+                migration.error = reason.into();
+            }
+        }
+        migration.updated_at = now();
+        if let Err(err) = core.migration_save(&migration).await {
+            error!("Failed to save migration {}: {:?}", id, err);
+        }
+        core.migrations.finish(id);
+    });
+
+    true
+}
+
+async fn run_migration<T>(
+    core: &web::Data<JMAPServer<T>>,
+    migration: &mut Migration,
+) -> Result<MigrationOutcome, String>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    save_checkpoint(core, migration).await?;
+
+    let secret = migration
+        .secret(&core.oauth.key)
+        .ok_or_else(|| "Failed to decrypt source credentials.".to_string())?;
+
+    if migration.is_imap() {
+        let username = migration
+            .source
+            .username
+            .clone()
+            .ok_or_else(|| "IMAP sources require a username.".to_string())?;
+        let mut client = ImapClient::connect(
+            &migration.source.url,
+            core.migrations.timeout,
+            core.store.config.mail_max_size,
+        )
+        .await?;
+        client.login(&username, &secret).await?;
+        let result = run_imap_migration(core, migration, &mut client).await;
+        client.logout().await;
+        result
+    } else {
+        let remote =
+            RemoteSession::connect(&migration.source, &secret, core.migrations.timeout).await?;
+        run_jmap_migration(core, migration, &remote).await
+    }
+}
+
+async fn run_jmap_migration<T>(
+    core: &web::Data<JMAPServer<T>>,
+    migration: &mut Migration,
+    remote: &RemoteSession,
+) -> Result<MigrationOutcome, String>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let account_id = migration.account_id;
+
+    // Map remote mailboxes to local ones, creating any that are missing.
+    if migration.checkpoint.mailboxes.is_none() {
+        let response = remote
+            .call(vec![json!([
+                "Mailbox/get",
+                {
+                    "accountId": remote.account_id,
+                    "properties": ["id", "name", "parentId", "role"]
+                },
+                "0"
+            ])])
+            .await?;
+        let mailboxes = response[0]
+            .get("list")
+            .and_then(|list| list.as_array())
+            .ok_or_else(|| "Invalid Mailbox/get response.".to_string())?
+            .iter()
+            .filter_map(RemoteMailbox::parse)
+            .collect::<Vec<_>>();
+        migrate_mailbox_tree(core, migration, mailboxes).await?;
+    }
+
+    // Copy identities for addresses that belong to the local account.
+    if !migration.checkpoint.identities_done {
+        match remote
+            .call(vec![json!([
+                "Identity/get",
+                { "accountId": remote.account_id },
+                "0"
+            ])])
+            .await
+        {
+            Ok(response) => {
+                let identities = response[0]
+                    .get("list")
+                    .and_then(|list| list.as_array())
+                    .cloned()
+                    .unwrap_or_default();
+                let store = core.store.clone();
+                migration.progress.identities_created = core
+                    .spawn_worker(move || migrate_identities(&store, account_id, identities))
+                    .await
+                    .map_err(|err| format!("Failed to create identities: {:?}", err))?;
+                publish_changes(core, account_id, &[Collection::Identity]).await;
+            }
+            Err(err) => {
+                debug!(
+                    "Skipping identities for migration {}: {}",
+                    migration.id, err
+                );
+            }
+        }
+        migration.checkpoint.identities_done = true;
+        save_checkpoint(core, migration).await?;
+    }
+
+    let mailbox_map = migration.mailbox_map();
+    let max_size = core.store.config.mail_max_size;
+
+    // Import messages in chunks, checkpointing after each one.
+    loop {
+        if let Some(outcome) = interruption(core, migration) {
+            return Ok(outcome);
+        }
+
+        let response = remote
+            .call(vec![
+                json!([
+                    "Email/query",
+                    {
+                        "accountId": remote.account_id,
+                        "sort": [{ "property": "receivedAt", "isAscending": true }],
+                        "position": migration.checkpoint.position,
+                        "limit": core.migrations.chunk_size,
+                        "calculateTotal": true
+                    },
+                    "0"
+                ]),
+                json!([
+                    "Email/get",
+                    {
+                        "accountId": remote.account_id,
+                        "#ids": { "resultOf": "0", "name": "Email/query", "path": "/ids" },
+                        "properties": ["id", "blobId", "mailboxIds", "keywords", "receivedAt"]
+                    },
+                    "1"
+                ]),
+            ])
+            .await?;
+        let num_ids = response[0]
+            .get("ids")
+            .and_then(|ids| ids.as_array())
+            .map(|ids| ids.len())
+            .ok_or_else(|| "Invalid Email/query response.".to_string())?;
+        if let Some(total) = response[0].get("total").and_then(|total| total.as_u64()) {
+            migration.progress.messages_total = total as usize;
+        }
+        if num_ids == 0 {
+            break;
+        }
+        let messages = response[1]
+            .get("list")
+            .and_then(|list| list.as_array())
+            .ok_or_else(|| "Invalid Email/get response.".to_string())?
+            .iter()
+            .filter_map(RemoteMessage::parse)
+            .collect::<Vec<_>>();
+
+        // Download message blobs concurrently.
+        let mut failed = num_ids.saturating_sub(messages.len());
+        let mut items = Vec::with_capacity(messages.len());
+        let mut downloads = stream::iter(messages.into_iter().map(move |message| async move {
+            let blob = remote.download(&message.blob_id, max_size).await;
+            (message, blob)
+        }))
+        .buffer_unordered(core.migrations.concurrency);
+        while let Some((message, blob)) = downloads.next().await {
+            match blob {
+                Ok(blob) => {
+                    items.push(message.into_import_item(
+                        blob,
+                        &mailbox_map,
+                        migration.checkpoint.inbox_id,
+                    ));
+                }
+                Err(err) => {
+                    debug!(
+                        "Failed to download blob {} for migration {}: {}",
+                        message.blob_id, migration.id, err
+                    );
+                    failed += 1;
+                }
+            }
+        }
+        drop(downloads);
+
+        // Import them into the local account.
+        import_chunk(core, migration, items, failed).await?;
+        migration.checkpoint.position += num_ids;
+        migration.progress.messages_processed += num_ids;
+        save_checkpoint(core, migration).await?;
+    }
+
+    Ok(MigrationOutcome::Completed)
+}
+
+async fn run_imap_migration<T>(
+    core: &web::Data<JMAPServer<T>>,
+    migration: &mut Migration,
+    client: &mut ImapClient,
+) -> Result<MigrationOutcome, String>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Map remote mailboxes to local ones, creating any that are missing.
+    if migration.checkpoint.mailboxes.is_none() {
+        let mailboxes = client
+            .list()
+            .await?
+            .iter()
+            .map(RemoteMailbox::from_imap)
+            .collect::<Vec<_>>();
+        migrate_mailbox_tree(core, migration, mailboxes).await?;
+    }
+
+    // Identities are not available over IMAP.
+    migration.checkpoint.identities_done = true;
+
+    let mailbox_map = migration.mailbox_map();
+    let mailbox_names = migration
+        .checkpoint
+        .mailboxes
+        .as_ref()
+        .map(|mailboxes| {
+            mailboxes
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let max_size = core.store.config.mail_max_size;
+
+    if migration.progress.messages_total == 0 {
+        for name in &mailbox_names {
+            migration.progress.messages_total += client.status(name).await?;
+        }
+    }
+
+    // Copy each mailbox in chunks, checkpointing after each one.
+    while let Some(name) = mailbox_names.get(migration.checkpoint.position) {
+        // Mailboxes that cannot be selected only hold other mailboxes
+        if client.examine(name).await? {
+            let messages = client.fetch_messages(migration.checkpoint.uid).await?;
+            for chunk in messages.chunks(core.migrations.chunk_size) {
+                if let Some(outcome) = interruption(core, migration) {
+                    return Ok(outcome);
+                }
+
+                // Download the chunk, skipping messages that are too large.
+                let mut failed = 0;
+                let mut items = Vec::with_capacity(chunk.len());
+                for message in chunk {
+                    let blob = if message.size <= max_size {
+                        client.fetch_body(message.uid).await?
+                    } else {
+                        None
+                    };
+                    match blob {
+                        Some(blob) if blob.len() <= max_size => {
+                            items.push(RemoteMessage::from_imap(name, message).into_import_item(
+                                blob,
+                                &mailbox_map,
+                                migration.checkpoint.inbox_id,
+                            ));
+                        }
+                        _ => {
+                            debug!(
+                                "Failed to download message {} from {:?} for migration {}.",
+                                message.uid, name, migration.id
+                            );
+                            failed += 1;
+                        }
+                    }
+                }
+
+                // Import them into the local account.
+                import_chunk(core, migration, items, failed).await?;
+                migration.checkpoint.uid = chunk
+                    .last()
+                    .map_or(0, |message| message.uid.saturating_add(1));
+                migration.progress.messages_processed += chunk.len();
+                save_checkpoint(core, migration).await?;
+            }
+        }
+
+        migration.checkpoint.position += 1;
+        migration.checkpoint.uid = 0;
+        save_checkpoint(core, migration).await?;
+    }
+
+    Ok(MigrationOutcome::Completed)
+}
+
+/// Returns why a migration has to stop before its next chunk, if it has to.
+fn interruption<T>(
+    core: &web::Data<JMAPServer<T>>,
+    migration: &Migration,
+) -> Option<MigrationOutcome>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    if core.migrations.is_cancelled(migration.id) {
+        Some(MigrationOutcome::Cancelled)
+    } else if !core.is_leader() {
+        Some(MigrationOutcome::Interrupted)
+    } else {
+        None
+    }
+}
+
+async fn migrate_mailbox_tree<T>(
+    core: &web::Data<JMAPServer<T>>,
+    migration: &mut Migration,
+    mailboxes: Vec<RemoteMailbox>,
+) -> Result<(), String>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let account_id = migration.account_id;
+    let store = core.store.clone();
+    let (mapping, inbox_id, created) = core
+        .spawn_worker(move || migrate_mailboxes(&store, account_id, mailboxes))
+        .await
+        .map_err(|err| format!("Failed to create mailboxes: {:?}", err))?;
+    migration.checkpoint.mailboxes = mapping.into();
+    migration.checkpoint.inbox_id = inbox_id;
+    migration.progress.mailboxes_created = created;
+    save_checkpoint(core, migration).await?;
+    publish_changes(core, account_id, &[Collection::Mailbox]).await;
+    Ok(())
+}
+
+/// Imports a chunk of downloaded messages into the local account and adds
+/// the result to the progress of the migration.
+async fn import_chunk<T>(
+    core: &web::Data<JMAPServer<T>>,
+    migration: &mut Migration,
+    items: Vec<ImportItem>,
+    failed: usize,
+) -> Result<(), String>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let account_id = migration.account_id;
+    let store = core.store.clone();
+    let result = core
+        .spawn_worker(move || {
+            let mut result = ChunkResult {
+                failed,
+                ..Default::default()
+            };
+            for item in items {
+                let size = item.blob.len() as u64;
+                match import_message(&store, account_id, item) {
+                    Ok(true) => {
+                        result.imported += 1;
+                        result.bytes += size;
+                    }
+                    Ok(false) => {
+                        result.skipped += 1;
+                    }
+                    Err(err) => {
+                        debug!("Failed to import message: {:?}", err);
+                        result.failed += 1;
+                    }
+                }
+            }
+            Ok(result)
+        })
+        .await
+        .map_err(|err| format!("Failed to import messages: {:?}", err))?;
+
+    migration.progress.messages_imported += result.imported;
+    migration.progress.messages_skipped += result.skipped;
+    migration.progress.messages_failed += result.failed;
+    migration.progress.bytes_imported += result.bytes;
+
+    if result.imported > 0 {
+        publish_changes(
+            core,
+            account_id,
+            &[Collection::Mail, Collection::Thread, Collection::Mailbox],
+        )
+        .await;
+    }
+
+    Ok(())
+}
+
+async fn save_checkpoint<T>(
+    core: &web::Data<JMAPServer<T>>,
+    migration: &mut Migration,
+) -> Result<(), String>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    migration.updated_at = now();
+    core.migration_save(migration)
+        .await
+        .map_err(|err| format!("Failed to save checkpoint: {:?}", err))
+}
+
+async fn publish_changes<T>(
+    core: &web::Data<JMAPServer<T>>,
+    account_id: AccountId,
+    collections: &'static [Collection],
+) where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let types = match core
+        .spawn_worker(move || {
+            let mut types = Vec::with_capacity(collections.len());
+            for collection in collections {
+                if let (Ok(type_state), Some(change_id)) = (
+                    TypeState::try_from(*collection),
+                    store.get_last_change_id(account_id, *collection)?,
+                ) {
+                    types.push((type_state, change_id));
+                }
+            }
+            Ok(types)
+        })
+        .await
+    {
+        Ok(types) if !types.is_empty() => types,
+        Ok(_) => return,
+        Err(err) => {
+            error!("Failed to obtain change ids: {:?}", err);
+            return;
+        }
+    };
+
+    if let Err(err) = core
+        .publish_state_change(StateChange::new(account_id, types))
+        .await
+    {
+        error!("Failed to publish state change: {}", err);
+    }
+}
+
+fn migrate_mailboxes<T>(
+    store: &JMAPStore<T>,
+    account_id: AccountId,
+    mut remote: Vec<RemoteMailbox>,
+) -> store::Result<(Vec<(String, DocumentId)>, Option<DocumentId>, usize)>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Index local mailboxes by role and by parent and name.
+    let mut roles = AHashMap::new();
+    let mut names = AHashMap::new();
+    for document_id in store
+        .get_document_ids(account_id, Collection::Mailbox)?
+        .unwrap_or_default()
+    {
+        if let Some(mailbox) = store.get_orm::<Mailbox>(account_id, document_id)? {
+            if let Some(MailboxValue::Text { value }) = mailbox.get(&MailboxProperty::Role) {
+                roles.insert(value.to_string(), document_id);
+            }
+            if let (
+                Some(MailboxValue::Text { value: name }),
+                Some(MailboxValue::Id { value: parent_id }),
+            ) = (
+                mailbox.get(&MailboxProperty::Name),
+                mailbox.get(&MailboxProperty::ParentId),
+            ) {
+                names.insert((u64::from(parent_id), name.to_string()), document_id);
+            }
+        }
+    }
+
+    // Parents have to be mapped before their children.
+    let mut mapping: AHashMap<String, DocumentId> = AHashMap::with_capacity(remote.len());
+    let mut batch = WriteBatch::new(account_id);
+    let mut created = 0;
+    while !remote.is_empty() {
+        let pos = remote
+            .iter()
+            .position(|mailbox| {
+                mailbox
+                    .parent_id
+                    .as_ref()
+                    .map_or(true, |parent_id| mapping.contains_key(parent_id))
+            })
+            .unwrap_or(0);
+        let mailbox = remote.swap_remove(pos);
+        let parent_id = mailbox
+            .parent_id
+            .as_ref()
+            .and_then(|parent_id| mapping.get(parent_id))
+            .map_or(0, |document_id| *document_id as u64 + 1);
+        let role = mailbox
+            .role
+            .map(|role| role.to_lowercase())
+            .filter(|role| is_valid_role(role));
+
+        let document_id = if let Some(document_id) = role
+            .as_ref()
+            .and_then(|role| roles.get(role))
+            .or_else(|| names.get(&(parent_id, mailbox.name.clone())))
+            .copied()
+        {
+            document_id
+        } else {
+            let document_id = store.assign_document_id(account_id, Collection::Mailbox)?;
+            let mut fields = if let Some(role) = &role {
+                roles.insert(role.to_string(), document_id);
+                TinyORM::<Mailbox>::new_mailbox(&mailbox.name, role)
+            } else {
+                let mut fields = TinyORM::<Mailbox>::new();
+                fields.set(
+                    MailboxProperty::Name,
+                    MailboxValue::Text {
+                        value: mailbox.name.clone(),
+                    },
+                );
+                fields
+            };
+            fields.set(
+                MailboxProperty::ParentId,
+                MailboxValue::Id {
+                    value: parent_id.into(),
+                },
+            );
+            let mut document = Document::new(Collection::Mailbox, document_id);
+            fields.insert(&mut document)?;
+            batch.log_insert(Collection::Mailbox, document_id);
+            batch.insert_document(document);
+            names.insert((parent_id, mailbox.name), document_id);
+            created += 1;
+            document_id
+        };
+        mapping.insert(mailbox.id, document_id);
+    }
+
+    if !batch.is_empty() {
+        store.write(batch)?;
+    }
+
+    let mut mapping = mapping.into_iter().collect::<Vec<_>>();
+    mapping.sort_unstable();
+
+    Ok((mapping, roles.get("inbox").copied(), created))
+}
+
+fn migrate_identities<T>(
+    store: &JMAPStore<T>,
+    account_id: AccountId,
+    identities: Vec<serde_json::Value>,
+) -> store::Result<usize>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Only addresses configured for the local account can be used.
+    let mut addresses = AHashSet::new();
+    if let Some(principal) = store.get_orm::<Principal>(SUPERUSER_ID, account_id)? {
+        if let Some(PrincipalValue::Text { value }) = principal.get(&PrincipalProperty::Email) {
+            addresses.insert(value.to_lowercase());
+        }
+        if let Some(PrincipalValue::TextList { value }) = principal.get(&PrincipalProperty::Aliases)
+        {
+            addresses.extend(value.iter().map(|address| address.to_lowercase()));
+        }
+    }
+    for document_id in store
+        .get_document_ids(account_id, Collection::Identity)?
+        .unwrap_or_default()
+    {
+        if let Some(IdentityValue::Text { value }) = store
+            .get_orm::<Identity>(account_id, document_id)?
+            .as_ref()
+            .and_then(|identity| identity.get(&IdentityProperty::Email))
+        {
+            addresses.remove(&value.to_lowercase());
+        }
+    }
+
+    let mut batch = WriteBatch::new(account_id);
+    let mut created = 0;
+    for identity in identities {
+        let email = match identity.get("email").and_then(|email| email.as_str()) {
+            Some(email) if addresses.remove(&email.to_lowercase()) => email.to_lowercase(),
+            _ => continue,
+        };
+        let mut fields = TinyORM::<Identity>::new();
+        fields.set(
+            IdentityProperty::Email,
+            IdentityValue::Text { value: email },
+        );
+        for (property, name) in [
+            (IdentityProperty::Name, "name"),
+            (IdentityProperty::TextSignature, "textSignature"),
+            (IdentityProperty::HtmlSignature, "htmlSignature"),
+        ] {
+            if let Some(value) = identity.get(name).and_then(|value| value.as_str()) {
+                fields.set(
+                    property,
+                    IdentityValue::Text {
+                        value: value.to_string(),
+                    },
+                );
+            }
+        }
+        let document_id = store.assign_document_id(account_id, Collection::Identity)?;
+        let mut document = Document::new(Collection::Identity, document_id);
+        fields.insert(&mut document)?;
+        batch.log_insert(Collection::Identity, document_id);
+        batch.insert_document(document);
+        created += 1;
+    }
+
+    if !batch.is_empty() {
+        store.write(batch)?;
+    }
+
+    Ok(created)
+}
+
+/// Imports a single message, returns false if it was already imported
+/// by an earlier attempt.
+fn import_message<T>(
+    store: &JMAPStore<T>,
+    account_id: AccountId,
+    item: ImportItem,
+) -> jmap::Result<bool>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let blob_id = BlobId::new_external(&item.blob);
+    {
+        let _lock = store.lock_collection(account_id, Collection::Mail);
+        if store
+            .blob_any_linked_document(&blob_id, account_id, Collection::Mail)?
+            .is_some()
+        {
+            return Ok(false);
+        }
+    }
+
+    let blob = store.blob_store(&blob_id, item.blob)?;
+    store.mail_import_item(
+        account_id,
+        blob_id,
+        &blob,
+        item.mailbox_ids,
+        item.keywords,
+        item.received_at,
+    )?;
+
+    Ok(true)
+}
+
+impl RemoteSession {
+    async fn connect(
+        source: &MigrationSource,
+        secret: &str,
+        timeout: Duration,
+    ) -> Result<Self, String> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|err| format!("Failed to create HTTP client: {}", err))?;
+        let authorization = if let Some(username) = &source.username {
+            format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, secret))
+            )
+        } else {
+            format!("Bearer {}", secret)
+        };
+        let session_url = if source.url.contains("/.well-known/jmap") {
+            source.url.to_string()
+        } else {
+            format!("{}/.well-known/jmap", source.url.trim_end_matches('/'))
+        };
+
+        let session = client
+            .get(&session_url)
+            .header(AUTHORIZATION, &authorization)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("Failed to fetch JMAP session: {}", err))?
+            .bytes()
+            .await
+            .map_err(|err| format!("Failed to fetch JMAP session: {}", err))?;
+        let session = serde_json::from_slice::<serde_json::Value>(&session)
+            .map_err(|err| format!("Failed to parse JMAP session: {}", err))?;
+
+        let account_id = source
+            .account_id
+            .as_deref()
+            .or_else(|| {
+                session
+                    .get("primaryAccounts")?
+                    .get("urn:ietf:params:jmap:mail")?
+                    .as_str()
+            })
+            .ok_or_else(|| "JMAP session has no mail account.".to_string())?
+            .to_string();
+        let api_url = session
+            .get("apiUrl")
+            .and_then(|url| url.as_str())
+            .ok_or_else(|| "JMAP session has no apiUrl.".to_string())?
+            .to_string();
+        let download_url = session
+            .get("downloadUrl")
+            .and_then(|url| url.as_str())
+            .ok_or_else(|| "JMAP session has no downloadUrl.".to_string())?
+            .to_string();
+
+        Ok(RemoteSession {
+            client,
+            authorization,
+            api_url,
+            download_url,
+            account_id,
+        })
+    }
+
+    /// Sends a request and returns the arguments of each method response.
+    async fn call(
+        &self,
+        method_calls: Vec<serde_json::Value>,
+    ) -> Result<Vec<serde_json::Value>, String> {
+        let num_calls = method_calls.len();
+        let request = serde_json::to_vec(&json!({
+            "using": USING,
+            "methodCalls": method_calls,
+        }))
+        .map_err(|err| format!("Failed to serialize request: {}", err))?;
+        let response = self
+            .client
+            .post(&self.api_url)
+            .header(AUTHORIZATION, &self.authorization)
+            .header(CONTENT_TYPE, "application/json")
+            .body(request)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("JMAP request failed: {}", err))?
+            .bytes()
+            .await
+            .map_err(|err| format!("JMAP request failed: {}", err))?;
+        let response = serde_json::from_slice::<serde_json::Value>(&response)
+            .map_err(|err| format!("Failed to parse JMAP response: {}", err))?;
+
+        let mut results = Vec::new();
+        for method_response in response
+            .get("methodResponses")
+            .and_then(|responses| responses.as_array())
+            .ok_or_else(|| "JMAP response has no methodResponses.".to_string())?
+        {
+            match method_response.as_array().map(|r| r.as_slice()) {
+                Some([name, arguments, _]) if name != "error" => {
+                    results.push(arguments.clone());
+                }
+                Some([_, arguments, _]) => {
+                    return Err(format!("JMAP method failed: {}", arguments));
+                }
+                _ => return Err("Invalid JMAP method response.".to_string()),
+            }
+        }
+
+        if results.len() == num_calls {
+            Ok(results)
+        } else {
+            Err("Unexpected number of JMAP method responses.".to_string())
+        }
+    }
+
+    async fn download(&self, blob_id: &str, max_size: usize) -> Result<Vec<u8>, String> {
+        let url = self
+            .download_url
+            .replace("{accountId}", &self.account_id)
+            .replace("{blobId}", blob_id)
+            .replace("{name}", "message.eml")
+            .replace("{type}", "message%2Frfc822");
+        let mut response = self
+            .client
+            .get(&url)
+            .header(AUTHORIZATION, &self.authorization)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("Download failed: {}", err))?;
+
+        // Stop reading as soon as the message exceeds the size limit
+        if response
+            .content_length()
+            .map_or(false, |size| size > max_size as u64)
+        {
+            return Err(format!("Message exceeds {} bytes.", max_size));
+        }
+        let mut blob = Vec::with_capacity(response.content_length().unwrap_or(0) as usize);
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|err| format!("Download failed: {}", err))?
+        {
+            if blob.len() + chunk.len() > max_size {
+                return Err(format!("Message exceeds {} bytes.", max_size));
+            }
+            blob.extend_from_slice(&chunk);
+        }
+
+        Ok(blob)
+    }
+}
+
+impl RemoteMailbox {
+    fn parse(value: &serde_json::Value) -> Option<Self> {
+        Some(RemoteMailbox {
+            id: value.get("id")?.as_str()?.to_string(),
+            name: value.get("name")?.as_str()?.to_string(),
+            parent_id: value
+                .get("parentId")
+                .and_then(|id| id.as_str())
+                .map(|id| id.to_string()),
+            role: value
+                .get("role")
+                .and_then(|role| role.as_str())
+                .map(|role| role.to_string()),
+        })
+    }
+}
+
+impl RemoteMailbox {
+    fn from_imap(mailbox: &ImapMailbox) -> Self {
+        let (parent_id, name) = match mailbox
+            .delimiter
+            .and_then(|delimiter| mailbox.name.rsplit_once(delimiter))
+        {
+            Some((parent_id, name)) => (Some(parent_id.to_string()), name),
+            None => (None, mailbox.name.as_str()),
+        };
+        let role = if mailbox.name.eq_ignore_ascii_case("INBOX") {
+            Some("inbox".to_string())
+        } else {
+            // Special-use attributes (RFC 6154) are named after JMAP roles
+            mailbox
+                .attributes
+                .iter()
+                .filter_map(|attribute| attribute.strip_prefix('\\'))
+                .map(|attribute| attribute.to_lowercase())
+                .find(|role| is_valid_role(role))
+        };
+
+        RemoteMailbox {
+            id: mailbox.name.to_string(),
+            name: from_imap_utf7(name).unwrap_or_else(|| name.to_string()),
+            parent_id,
+            role,
+        }
+    }
+}
+
+impl RemoteMessage {
+    fn from_imap(mailbox: &str, message: &ImapMessage) -> Self {
+        RemoteMessage {
+            blob_id: message.uid.to_string(),
+            mailbox_ids: vec![mailbox.to_string()],
+            keywords: message
+                .flags
+                .iter()
+                .filter_map(|flag| match flag.strip_prefix('\\') {
+                    Some(flag) if flag.eq_ignore_ascii_case("recent") => None,
+                    Some(flag) => Some(format!("${}", flag)),
+                    None => Some(flag.to_string()),
+                })
+                .collect(),
+            received_at: message.internal_date,
+        }
+    }
+
+    fn into_import_item(
+        self,
+        blob: Vec<u8>,
+        mailbox_map: &AHashMap<String, DocumentId>,
+        inbox_id: Option<DocumentId>,
+    ) -> ImportItem {
+        let mut mailbox_ids = self
+            .mailbox_ids
+            .iter()
+            .filter_map(|id| mailbox_map.get(id).copied())
+            .collect::<Vec<_>>();
+        if mailbox_ids.is_empty() {
+            mailbox_ids.extend(inbox_id);
+        }
+
+        ImportItem {
+            blob,
+            mailbox_ids,
+            keywords: self
+                .keywords
+                .iter()
+                .map(|keyword| Keyword::parse(keyword).tag)
+                .collect(),
+            received_at: self.received_at,
+        }
+    }
+
+    fn parse(value: &serde_json::Value) -> Option<Self> {
+        Some(RemoteMessage {
+            blob_id: value.get("blobId")?.as_str()?.to_string(),
+            mailbox_ids: value
+                .get("mailboxIds")
+                .and_then(|ids| ids.as_object())
+                .map(|ids| {
+                    ids.iter()
+                        .filter(|(_, set)| set.as_bool().unwrap_or(false))
+                        .map(|(id, _)| id.to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            keywords: value
+                .get("keywords")
+                .and_then(|keywords| keywords.as_object())
+                .map(|keywords| {
+                    keywords
+                        .iter()
+                        .filter(|(_, set)| set.as_bool().unwrap_or(false))
+                        .map(|(keyword, _)| keyword.to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            received_at: value
+                .get("receivedAt")
+                .and_then(|date| date.as_str())
+                .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+                .map(|date| date.timestamp()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::services::imap_client::{ImapMailbox, ImapMessage};
+
+    use super::{deserialize_migration, Migration, RemoteMailbox, RemoteMessage};
+
+    #[test]
+    fn migration_credentials() {
+        let migration = Migration::new(
+            1,
+            "https://jmap.example.org".to_string(),
+            Some("john".to_string()),
+            None,
+            "secret",
+            "encryption key",
+        )
+        .unwrap();
+        assert_ne!(migration.source.secret, b"secret");
+
+        let migration =
+            deserialize_migration(&store::bincode::serialize(&migration).unwrap()).unwrap();
+        assert_eq!(
+            migration.secret("encryption key").as_deref(),
+            Some("secret")
+        );
+        assert_eq!(migration.secret("other key"), None);
+    }
+
+    #[test]
+    fn parse_remote_message() {
+        let message = RemoteMessage::parse(&json!({
+            "id": "m1",
+            "blobId": "b1",
+            "mailboxIds": { "a": true, "b": false },
+            "keywords": { "$seen": true },
+            "receivedAt": "2022-01-01T00:00:00Z"
+        }))
+        .unwrap();
+        assert_eq!(message.blob_id, "b1");
+        assert_eq!(message.mailbox_ids, vec!["a".to_string()]);
+        assert_eq!(message.keywords, vec!["$seen".to_string()]);
+        assert_eq!(message.received_at, Some(1640995200));
+
+        assert!(RemoteMessage::parse(&json!({ "id": "m2" })).is_none());
+    }
+
+    #[test]
+    fn parse_imap_mailbox() {
+        let mailbox = RemoteMailbox::from_imap(&ImapMailbox {
+            name: "INBOX/Caf&AOk-".to_string(),
+            delimiter: Some('/'),
+            attributes: vec!["\\HasNoChildren".to_string(), "\\Sent".to_string()],
+        });
+        assert_eq!(mailbox.id, "INBOX/Caf&AOk-");
+        assert_eq!(mailbox.name, "Café");
+        assert_eq!(mailbox.parent_id.as_deref(), Some("INBOX"));
+        assert_eq!(mailbox.role.as_deref(), Some("sent"));
+
+        let mailbox = RemoteMailbox::from_imap(&ImapMailbox {
+            name: "Inbox".to_string(),
+            delimiter: None,
+            attributes: vec![],
+        });
+        assert_eq!(mailbox.parent_id, None);
+        assert_eq!(mailbox.role.as_deref(), Some("inbox"));
+
+        let message = RemoteMessage::from_imap(
+            "INBOX",
+            &ImapMessage {
+                uid: 5,
+                size: 100,
+                flags: vec![
+                    "\\Seen".to_string(),
+                    "\\Recent".to_string(),
+                    "$Forwarded".to_string(),
+                ],
+                internal_date: Some(1640995200),
+            },
+        );
+        assert_eq!(message.mailbox_ids, vec!["INBOX".to_string()]);
+        assert_eq!(
+            message.keywords,
+            vec!["$Seen".to_string(), "$Forwarded".to_string()]
+        );
+        assert_eq!(message.received_at, Some(1640995200));
+    }
+}
@@ -21,10 +21,20 @@
  * for more details.
 */
 
+pub mod delivery_queue;
+pub mod dns;
 pub mod email_delivery;
+pub mod footer;
 pub mod housekeeper;
+pub mod imap_client;
+pub mod maintenance;
+pub mod migration;
+pub mod onboarding;
+pub mod push_broker;
 pub mod push_subscription;
 pub mod push_subscription_ece;
+pub mod snooze;
 pub mod state_change;
+pub mod warmup;
 
 pub const LONG_SLUMBER_MS: u64 = 60 * 60 * 24 * 1000;
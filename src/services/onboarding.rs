@@ -0,0 +1,529 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::web;
+use jmap::{
+    orm::{serialize::JMAPOrm, TinyORM},
+    principal::schema::{Principal, Property as PrincipalProperty, Type, Value as PrincipalValue},
+    types::type_state::TypeState,
+    SUPERUSER_ID,
+};
+use jmap_mail::{
+    identity::schema::{Identity, Property as IdentityProperty, Value as IdentityValue},
+    mail::template::{build_notification, JMAPMailTemplate},
+    mailbox::{
+        is_valid_role,
+        schema::{Mailbox, Property as MailboxProperty, Value as MailboxValue},
+        CreateMailbox,
+    },
+};
+use store::{
+    ahash::AHashSet,
+    config::{env_settings::EnvSettings, settings::Setting, templates::TEMPLATE_WELCOME},
+    core::{collection::Collection, document::Document},
+    log::changes::ChangeId,
+    parking_lot::Mutex,
+    tracing::{debug, error},
+    write::batch::WriteBatch,
+    AccountId, JMAPStore, Store,
+};
+
+use crate::{
+    lmtp::{ingest::DeliveryStatus as IngestStatus, session::RcptType},
+    server::failed_to,
+    JMAPServer,
+};
+
+use super::state_change::StateChange;
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::list("onboarding-actions")
+        .describe("Run once per account on creation or first login: welcome, mailboxes, identity"),
+    Setting::list("onboarding-mailboxes")
+        .describe("Extra mailboxes created by the 'mailboxes' action, as name or name:role"),
+    Setting::text("onboarding-from")
+        .describe("Sender of the welcome message, defaults to postmaster@<account domain>"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnboardingAction {
+    // Delivers the 'welcome' template to the Inbox of the account
+    Welcome,
+    // Creates the mailboxes listed in 'onboarding-mailboxes'
+    Mailboxes,
+    // Creates an identity from the directory name and address of the account
+    Identity,
+}
+
+// Provisioning actions that run exactly once per account. Completed actions are
+// recorded in the 'onboarded' property of the principal, clearing it runs them again.
+pub struct Onboarding {
+    actions: Vec<OnboardingAction>,
+    mailboxes: Vec<(String, Option<String>)>,
+    from: Option<String>,
+    in_progress: Mutex<AHashSet<AccountId>>,
+}
+
+impl OnboardingAction {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "welcome" => Some(OnboardingAction::Welcome),
+            "mailboxes" => Some(OnboardingAction::Mailboxes),
+            "identity" => Some(OnboardingAction::Identity),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OnboardingAction::Welcome => "welcome",
+            OnboardingAction::Mailboxes => "mailboxes",
+            OnboardingAction::Identity => "identity",
+        }
+    }
+}
+
+impl Onboarding {
+    pub fn parse(settings: &EnvSettings) -> Self {
+        let mut actions = Vec::new();
+        for action in settings
+            .parse_list("onboarding-actions")
+            .unwrap_or_default()
+            .iter()
+            .map(|action| action.trim())
+            .filter(|action| !action.is_empty())
+        {
+            actions.push(OnboardingAction::parse(action).unwrap_or_else(|| {
+                failed_to(&format!(
+                    "parse 'onboarding-actions', invalid action '{}', expected welcome, mailboxes or identity.",
+                    action
+                ));
+            }));
+        }
+
+        let mut mailboxes = Vec::new();
+        for mailbox in settings
+            .parse_list("onboarding-mailboxes")
+            .unwrap_or_default()
+            .iter()
+            .map(|mailbox| mailbox.trim())
+            .filter(|mailbox| !mailbox.is_empty())
+        {
+            mailboxes.push(match mailbox.rsplit_once(':') {
+                Some((name, role)) if is_valid_role(role.trim()) => {
+                    (name.trim().to_string(), Some(role.trim().to_string()))
+                }
+                Some(_) => failed_to(&format!(
+                    "parse 'onboarding-mailboxes', invalid role in '{}'.",
+                    mailbox
+                )),
+                None => (mailbox.to_string(), None),
+            });
+        }
+
+        Onboarding::new(actions, mailboxes, settings.get("onboarding-from"))
+    }
+
+    pub fn new(
+        actions: Vec<OnboardingAction>,
+        mailboxes: Vec<(String, Option<String>)>,
+        from: Option<String>,
+    ) -> Self {
+        Onboarding {
+            actions,
+            mailboxes,
+            from,
+            in_progress: Mutex::new(AHashSet::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.actions.is_empty()
+    }
+}
+
+// Details of the account needed to run the actions that happen outside the store.
+pub struct PendingOnboarding {
+    pub name: String,
+    pub email: String,
+    pub welcome: bool,
+    pub change_id: Option<ChangeId>,
+}
+
+/// Runs the pending onboarding actions of an account in the background.
+pub fn spawn_onboarding<T>(core: web::Data<JMAPServer<T>>, account_id: AccountId)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    if core.onboarding.is_enabled() && core.is_leader() {
+        tokio::spawn(async move {
+            onboard_account(&core, account_id).await;
+        });
+    }
+}
+
+pub async fn onboard_account<T>(core: &web::Data<JMAPServer<T>>, account_id: AccountId)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Concurrent logins of the same account run the actions only once.
+    if !core.onboarding.in_progress.lock().insert(account_id) {
+        return;
+    }
+
+    let store = core.store.clone();
+    let server = core.clone();
+    match core
+        .spawn_worker(move || onboard_store_actions(&store, &server.onboarding, account_id))
+        .await
+    {
+        Ok(Some(pending)) => {
+            if let Some(change_id) = pending.change_id {
+                // Commit change
+                if core.is_in_cluster() {
+                    core.commit_index(change_id).await;
+                }
+
+                if let Err(err) = core
+                    .publish_state_change(StateChange::new(
+                        account_id,
+                        vec![
+                            (TypeState::Mailbox, change_id),
+                            (TypeState::Identity, change_id),
+                        ],
+                    ))
+                    .await
+                {
+                    error!("Failed to publish state change: {}", err);
+                }
+            }
+
+            if pending.welcome {
+                deliver_welcome(core, account_id, pending).await;
+            }
+        }
+        Ok(None) => (),
+        Err(err) => {
+            error!("Failed to onboard account {}: {:?}", account_id, err);
+        }
+    }
+
+    core.onboarding.in_progress.lock().remove(&account_id);
+}
+
+async fn deliver_welcome<T>(
+    core: &web::Data<JMAPServer<T>>,
+    account_id: AccountId,
+    pending: PendingOnboarding,
+) where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    let from = core.onboarding.from.clone().unwrap_or_else(|| {
+        format!(
+            "postmaster@{}",
+            pending
+                .email
+                .rsplit_once('@')
+                .map_or("localhost", |(_, domain)| domain)
+        )
+    });
+    let to = pending.email.clone();
+    let message = match core
+        .spawn_worker(move || {
+            Ok(store
+                .mail_template_render(
+                    account_id,
+                    TEMPLATE_WELCOME,
+                    &[("name", &pending.name), ("email", &pending.email)],
+                )?
+                .map(|template| build_notification(&template, &from, &pending.email)))
+        })
+        .await
+    {
+        Ok(Some(message)) => message,
+        Ok(None) => {
+            debug!("No welcome template found, skipping welcome message.");
+            return;
+        }
+        Err(err) => {
+            error!("Failed to build welcome message: {:?}", err);
+            return;
+        }
+    };
+
+    if let Err(err) = core
+        .mail_ingest(
+            String::new(),
+            vec![RcptType::Mailbox {
+                id: account_id,
+                name: to,
+                status: IngestStatus::Success,
+            }],
+            message,
+            None,
+            None,
+        )
+        .await
+    {
+        error!("Failed to deliver welcome message: {}", err.trim_end());
+        return;
+    }
+
+    // Record the delivery only once the message is in the Inbox
+    let store = core.store.clone();
+    if let Err(err) = core
+        .spawn_worker(move || {
+            if let Some(fields) = store.get_orm::<Principal>(SUPERUSER_ID, account_id)? {
+                store.write(record_actions(
+                    fields,
+                    account_id,
+                    &[OnboardingAction::Welcome],
+                )?)?;
+            }
+            Ok(())
+        })
+        .await
+    {
+        error!("Failed to record welcome message: {:?}", err);
+    }
+}
+
+/// Creates the pending mailboxes and identity of an account, recording them as
+/// completed in the same write.
+pub fn onboard_store_actions<T>(
+    store: &JMAPStore<T>,
+    onboarding: &Onboarding,
+    account_id: AccountId,
+) -> store::Result<Option<PendingOnboarding>>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let fields = match store.get_orm::<Principal>(SUPERUSER_ID, account_id)? {
+        Some(fields)
+            if matches!(
+                fields.get(&PrincipalProperty::Type),
+                Some(PrincipalValue::Type {
+                    value: Type::Individual
+                })
+            ) =>
+        {
+            fields
+        }
+        _ => return Ok(None),
+    };
+    let completed = match fields.get(&PrincipalProperty::Onboarded) {
+        Some(PrincipalValue::TextList { value }) => value.as_slice(),
+        _ => &[],
+    };
+    let pending = onboarding
+        .actions
+        .iter()
+        .filter(|action| !completed.iter().any(|done| done == action.as_str()))
+        .copied()
+        .collect::<Vec<_>>();
+    if pending.is_empty() {
+        return Ok(None);
+    }
+
+    let text = |property| match fields.get(&property) {
+        Some(PrincipalValue::Text { value }) => value.to_string(),
+        _ => String::new(),
+    };
+    let name = text(PrincipalProperty::Name);
+    let email = text(PrincipalProperty::Email);
+
+    let mut batch = WriteBatch::new(account_id);
+    let mut done = Vec::with_capacity(pending.len());
+    for action in &pending {
+        match action {
+            OnboardingAction::Mailboxes => {
+                create_mailboxes(store, account_id, &onboarding.mailboxes, &mut batch)?;
+                done.push(*action);
+            }
+            OnboardingAction::Identity => {
+                if !email.is_empty() {
+                    create_identity(store, account_id, &name, &email, &mut batch)?;
+                }
+                done.push(*action);
+            }
+            OnboardingAction::Welcome => (),
+        }
+    }
+
+    let mut change_id = None;
+    if !done.is_empty() {
+        let principal_batch = record_actions(fields, account_id, &done)?;
+        change_id = if !batch.is_empty() {
+            batch.add_linked_batch(principal_batch);
+            store.write(batch)?.map(|changes| changes.change_id)
+        } else {
+            store.write(principal_batch)?;
+            None
+        };
+    }
+
+    Ok(Some(PendingOnboarding {
+        welcome: pending.contains(&OnboardingAction::Welcome) && !email.is_empty(),
+        name,
+        email,
+        change_id,
+    }))
+}
+
+fn create_mailboxes<T>(
+    store: &JMAPStore<T>,
+    account_id: AccountId,
+    mailboxes: &[(String, Option<String>)],
+    batch: &mut WriteBatch,
+) -> store::Result<()>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Mailboxes and roles that already exist at the top level are left untouched.
+    let mut names = AHashSet::new();
+    let mut roles = AHashSet::new();
+    for document_id in store
+        .get_document_ids(account_id, Collection::Mailbox)?
+        .unwrap_or_default()
+    {
+        if let Some(mailbox) = store.get_orm::<Mailbox>(account_id, document_id)? {
+            if let Some(MailboxValue::Text { value }) = mailbox.get(&MailboxProperty::Role) {
+                roles.insert(value.to_string());
+            }
+            if let (
+                Some(MailboxValue::Text { value: name }),
+                Some(MailboxValue::Id { value: parent_id }),
+            ) = (
+                mailbox.get(&MailboxProperty::Name),
+                mailbox.get(&MailboxProperty::ParentId),
+            ) {
+                if u64::from(parent_id) == 0 {
+                    names.insert(name.to_string());
+                }
+            }
+        }
+    }
+
+    for (name, role) in mailboxes {
+        if !names.insert(name.to_string()) {
+            continue;
+        }
+        let fields = match role {
+            Some(role) if roles.insert(role.to_string()) => {
+                TinyORM::<Mailbox>::new_mailbox(name, role)
+            }
+            _ => {
+                let mut fields = TinyORM::<Mailbox>::new();
+                fields.set(
+                    MailboxProperty::Name,
+                    MailboxValue::Text {
+                        value: name.to_string(),
+                    },
+                );
+                fields.set(
+                    MailboxProperty::ParentId,
+                    MailboxValue::Id { value: 0u64.into() },
+                );
+                fields
+            }
+        };
+        let document_id = store.assign_document_id(account_id, Collection::Mailbox)?;
+        let mut document = Document::new(Collection::Mailbox, document_id);
+        fields.insert(&mut document)?;
+        batch.log_insert(Collection::Mailbox, document_id);
+        batch.insert_document(document);
+    }
+
+    Ok(())
+}
+
+fn create_identity<T>(
+    store: &JMAPStore<T>,
+    account_id: AccountId,
+    name: &str,
+    email: &str,
+    batch: &mut WriteBatch,
+) -> store::Result<()>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Identities created by the user take precedence.
+    if !store
+        .get_document_ids(account_id, Collection::Identity)?
+        .unwrap_or_default()
+        .is_empty()
+    {
+        return Ok(());
+    }
+
+    let mut fields = TinyORM::<Identity>::new();
+    fields.set(
+        IdentityProperty::Name,
+        IdentityValue::Text {
+            value: name.to_string(),
+        },
+    );
+    fields.set(
+        IdentityProperty::Email,
+        IdentityValue::Text {
+            value: email.to_string(),
+        },
+    );
+    let document_id = store.assign_document_id(account_id, Collection::Identity)?;
+    let mut document = Document::new(Collection::Identity, document_id);
+    fields.insert(&mut document)?;
+    batch.log_insert(Collection::Identity, document_id);
+    batch.insert_document(document);
+
+    Ok(())
+}
+
+// Appends the completed actions to the 'onboarded' property of the principal.
+fn record_actions(
+    fields: TinyORM<Principal>,
+    account_id: AccountId,
+    actions: &[OnboardingAction],
+) -> store::Result<WriteBatch> {
+    let mut completed = match fields.get(&PrincipalProperty::Onboarded) {
+        Some(PrincipalValue::TextList { value }) => value.clone(),
+        _ => Vec::with_capacity(actions.len()),
+    };
+    for action in actions {
+        if !completed.iter().any(|done| done == action.as_str()) {
+            completed.push(action.as_str().to_string());
+        }
+    }
+
+    let mut changes = TinyORM::track_changes(&fields);
+    changes.set(
+        PrincipalProperty::Onboarded,
+        PrincipalValue::TextList { value: completed },
+    );
+    let mut batch = WriteBatch::new(SUPERUSER_ID);
+    let mut document = Document::new(Collection::Principal, account_id);
+    fields.merge(&mut document, changes)?;
+    batch.update_document(document);
+    batch.log_update(Collection::Principal, account_id);
+    Ok(batch)
+}
@@ -0,0 +1,283 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::Duration;
+
+use futures::StreamExt;
+use jmap::types::type_state::TypeState;
+use store::{
+    config::{env_settings::EnvSettings, settings::Setting},
+    rand::{thread_rng, Rng},
+    serialize::leb128::{Leb128Iterator, Leb128Vec},
+    tracing::{debug, error, info, warn},
+};
+use tokio::sync::mpsc;
+
+use crate::{cluster::IPC_CHANNEL_BUFFER, server::failed_to};
+
+use super::state_change::{self, StateChange};
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::url("push-broker-url").describe("redis:// or nats:// URL"),
+    Setting::text("push-broker-channel").default("stalwart-jmap-push"),
+];
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BrokerType {
+    Redis,
+    Nats,
+}
+
+struct PushBroker {
+    broker_type: BrokerType,
+    url: String,
+    channel: String,
+    node_id: u64,
+    state_tx: mpsc::Sender<state_change::Event>,
+}
+
+/// Spawns the push broker, which publishes local state changes to an external
+/// pub/sub service and relays the changes published by other nodes to the local
+/// state manager. The broker is only used for push fan-out, state changes are
+/// never persisted through it.
+pub fn spawn_push_broker(
+    settings: &EnvSettings,
+    state_tx: mpsc::Sender<state_change::Event>,
+) -> Option<mpsc::Sender<StateChange>> {
+    let url = settings.get("push-broker-url")?;
+    let broker_type = if url.starts_with("redis://") || url.starts_with("rediss://") {
+        BrokerType::Redis
+    } else if url.starts_with("nats://") {
+        BrokerType::Nats
+    } else {
+        failed_to(&format!(
+            "start push broker, unsupported URL '{}' (expected redis:// or nats://).",
+            url
+        ));
+    };
+    let broker = PushBroker {
+        broker_type,
+        url,
+        channel: settings.value(SETTINGS, "push-broker-channel"),
+        node_id: thread_rng().gen(),
+        state_tx,
+    };
+    let (tx, mut rx) = mpsc::channel::<StateChange>(IPC_CHANNEL_BUFFER);
+
+    tokio::spawn(async move {
+        loop {
+            let result = match broker.broker_type {
+                BrokerType::Redis => broker.run_redis(&mut rx).await,
+                BrokerType::Nats => broker.run_nats(&mut rx).await,
+            };
+
+            match result {
+                Ok(_) => {
+                    debug!("Push broker shutting down.");
+                    break;
+                }
+                Err(err) => {
+                    error!(
+                        "Push broker connection to {} failed: {}, retrying in {} seconds.",
+                        broker.url,
+                        err,
+                        RETRY_INTERVAL.as_secs()
+                    );
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    });
+
+    Some(tx)
+}
+
+impl PushBroker {
+    async fn run_redis(&self, rx: &mut mpsc::Receiver<StateChange>) -> Result<(), String> {
+        let client = redis::Client::open(self.url.as_str()).map_err(|err| err.to_string())?;
+        let mut publisher = client
+            .get_multiplexed_tokio_connection()
+            .await
+            .map_err(|err| err.to_string())?;
+        let mut pubsub = client
+            .get_async_connection()
+            .await
+            .map_err(|err| err.to_string())?
+            .into_pubsub();
+        pubsub
+            .subscribe(&self.channel)
+            .await
+            .map_err(|err| err.to_string())?;
+        let mut messages = pubsub.on_message();
+
+        info!("Push broker connected to {}.", self.url);
+
+        loop {
+            tokio::select! {
+                state_change = rx.recv() => {
+                    if let Some(state_change) = state_change {
+                        redis::cmd("PUBLISH")
+                            .arg(&self.channel)
+                            .arg(self.serialize(&state_change))
+                            .query_async::<_, ()>(&mut publisher)
+                            .await
+                            .map_err(|err| err.to_string())?;
+                    } else {
+                        return Ok(());
+                    }
+                }
+                message = messages.next() => {
+                    if let Some(message) = message {
+                        self.deliver(message.get_payload_bytes());
+                    } else {
+                        return Err("subscription was closed".to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run_nats(&self, rx: &mut mpsc::Receiver<StateChange>) -> Result<(), String> {
+        let client = async_nats::connect(self.url.as_str())
+            .await
+            .map_err(|err| err.to_string())?;
+        let mut messages = client
+            .subscribe(self.channel.clone())
+            .await
+            .map_err(|err| err.to_string())?;
+
+        info!("Push broker connected to {}.", self.url);
+
+        loop {
+            tokio::select! {
+                state_change = rx.recv() => {
+                    if let Some(state_change) = state_change {
+                        client
+                            .publish(self.channel.clone(), self.serialize(&state_change).into())
+                            .await
+                            .map_err(|err| err.to_string())?;
+                    } else {
+                        return Ok(());
+                    }
+                }
+                message = messages.next() => {
+                    if let Some(message) = message {
+                        self.deliver(&message.payload);
+                    } else {
+                        return Err("subscription was closed".to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    fn deliver(&self, bytes: &[u8]) {
+        match self.deserialize(bytes) {
+            Some((node_id, _)) if node_id == self.node_id => (),
+            Some((_, state_change)) => {
+                // Never wait on the state manager, which might be waiting on this task
+                if let Err(err) = self.state_tx.try_send(state_change::Event::Publish {
+                    state_change,
+                    is_relayed: true,
+                }) {
+                    warn!("Dropping state change relayed by push broker: {}", err);
+                }
+            }
+            None => {
+                debug!("Ignoring invalid push broker message.");
+            }
+        }
+    }
+
+    fn serialize(&self, state_change: &StateChange) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16 + state_change.types.len() * 10);
+        bytes.push_leb128(self.node_id);
+        bytes.push_leb128(state_change.account_id);
+        bytes.push_leb128(state_change.types.len());
+        for (type_state, change_id) in &state_change.types {
+            bytes.push_leb128(u64::from(*type_state));
+            bytes.push_leb128(*change_id);
+        }
+        bytes
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Option<(u64, StateChange)> {
+        let mut bytes_it = bytes.iter();
+        let node_id = bytes_it.next_leb128()?;
+        let account_id = bytes_it.next_leb128()?;
+        let num_types: usize = bytes_it.next_leb128()?;
+        if num_types > TypeState::None as usize {
+            return None;
+        }
+        let mut types = Vec::with_capacity(num_types);
+        for _ in 0..num_types {
+            let type_state: u64 = bytes_it.next_leb128()?;
+            if type_state >= TypeState::None as u64 {
+                return None;
+            }
+            types.push((TypeState::from(type_state), bytes_it.next_leb128()?));
+        }
+        Some((node_id, StateChange::new(account_id, types)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jmap::types::type_state::TypeState;
+    use tokio::sync::mpsc;
+
+    use crate::services::state_change::StateChange;
+
+    use super::{BrokerType, PushBroker};
+
+    #[test]
+    fn serialize_state_change() {
+        let (state_tx, _state_rx) = mpsc::channel(1);
+        let broker = PushBroker {
+            broker_type: BrokerType::Redis,
+            url: "redis://127.0.0.1".to_string(),
+            channel: "test".to_string(),
+            node_id: u64::MAX - 1,
+            state_tx,
+        };
+        let state_change = StateChange::new(
+            12345,
+            vec![(TypeState::Email, 0), (TypeState::Mailbox, u64::MAX)],
+        );
+
+        let (node_id, result) = broker
+            .deserialize(&broker.serialize(&state_change))
+            .unwrap();
+        assert_eq!(node_id, broker.node_id);
+        assert_eq!(result.account_id, state_change.account_id);
+        assert_eq!(result.types, state_change.types);
+
+        assert!(broker.deserialize(&[]).is_none());
+        assert!(broker.deserialize(&[1, 1, 1, 99, 0]).is_none());
+        assert!(broker
+            .deserialize(&[1, 1, 0xff, 0xff, 0xff, 0xff, 0x0f])
+            .is_none());
+    }
+}
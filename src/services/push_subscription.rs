@@ -35,13 +35,22 @@ use std::{
 };
 use store::{
     ahash::{AHashMap, AHashSet},
-    config::env_settings::EnvSettings,
+    config::{env_settings::EnvSettings, settings::Setting},
     core::{bitmap::Bitmap, collection::Collection, error::StoreError},
     tracing::debug,
     AccountId, DocumentId, Store,
 };
 use tokio::{sync::mpsc, time};
 
+pub const SETTINGS: &[Setting] = &[
+    Setting::millis("push-attempt-interval").default("60000"),
+    Setting::integer("push-attempts-max").default("3"),
+    Setting::millis("push-retry-interval").default("1000"),
+    Setting::millis("push-timeout").default("10000"),
+    Setting::millis("push-verify-timeout").default("60000"),
+    Setting::millis("push-throttle").default("1000"),
+];
+
 #[derive(Debug)]
 pub enum UpdateSubscription {
     Unverified {
@@ -60,6 +69,7 @@ pub struct PushSubscription {
     pub expires: u64,
     pub types: Bitmap<TypeState>,
     pub keys: Option<EncryptionKeys>,
+    pub mailbox_counters: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -74,7 +84,7 @@ pub enum Event {
         updates: Vec<PushUpdate>,
     },
     Push {
-        ids: Vec<store::JMAPId>,
+        ids: Vec<(store::JMAPId, Vec<(DocumentId, i64)>)>,
         state_change: StateChange,
     },
     DeliverySuccess {
@@ -120,12 +130,12 @@ pub fn spawn_push_manager(settings: &EnvSettings) -> mpsc::Sender<Event> {
     let (push_tx_, mut push_rx) = mpsc::channel::<Event>(IPC_CHANNEL_BUFFER);
     let push_tx = push_tx_.clone();
 
-    let push_attempt_interval: u64 = settings.parse("push-attempt-interval").unwrap_or(60 * 1000);
-    let push_attempts_max: u32 = settings.parse("push-attempts-max").unwrap_or(3);
-    let push_retry_interval: u64 = settings.parse("push-retry-interval").unwrap_or(1000);
-    let push_timeout: u64 = settings.parse("push-timeout").unwrap_or(10 * 1000);
-    let push_verify_timeout: u64 = settings.parse("push-verify-timeout").unwrap_or(60 * 1000);
-    let push_throttle: u64 = settings.parse("push-throttle").unwrap_or(1000);
+    let push_attempt_interval: u64 = settings.value(SETTINGS, "push-attempt-interval");
+    let push_attempts_max: u32 = settings.value(SETTINGS, "push-attempts-max");
+    let push_retry_interval: u64 = settings.value(SETTINGS, "push-retry-interval");
+    let push_timeout: u64 = settings.value(SETTINGS, "push-timeout");
+    let push_verify_timeout: u64 = settings.value(SETTINGS, "push-verify-timeout");
+    let push_throttle: u64 = settings.value(SETTINGS, "push-throttle");
 
     tokio::spawn(async move {
         let mut subscriptions = AHashMap::default();
@@ -217,9 +227,11 @@ pub fn spawn_push_manager(settings: &EnvSettings) -> mpsc::Sender<Event> {
                         }
                     }
                     Event::Push { ids, state_change } => {
-                        for id in ids {
+                        for (id, unread_deltas) in ids {
                             if let Some(subscription) = subscriptions.get_mut(&id) {
-                                subscription.state_changes.push(state_change.clone());
+                                let mut state_change = state_change.clone();
+                                state_change.unread_deltas = unread_deltas;
+                                subscription.state_changes.push(state_change);
                                 let last_request =
                                     subscription.last_request.elapsed().as_millis() as u64;
 
@@ -343,6 +355,12 @@ impl PushServer {
                         .get_mut_or_insert(state_change.account_id.into())
                         .set(*type_state, (*change_id).into());
                 }
+                for (mailbox_id, delta) in &state_change.unread_deltas {
+                    *response
+                        .unread_deltas
+                        .get_mut_or_insert(state_change.account_id.into())
+                        .get_mut_or_insert((*mailbox_id).into()) += delta;
+                }
             }
 
             push_tx
@@ -496,6 +514,11 @@ where
                             Bitmap::all()
                         };
 
+                        let mailbox_counters = matches!(
+                            subscription.get(&Property::MailboxCounters),
+                            Some(Value::Bool { value: true })
+                        );
+
                         // Add verified subscription
                         subscriptions.push(UpdateSubscription::Verified(PushSubscription {
                             id: document_id,
@@ -503,6 +526,7 @@ where
                             expires,
                             types,
                             keys,
+                            mailbox_counters,
                         }));
                     } else {
                         // Add unverified subscription
@@ -0,0 +1,126 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::Duration;
+
+use actix_web::web;
+use jmap::{types::type_state::TypeState, SUPERUSER_ID};
+use jmap_mail::mail::snooze::JMAPMailSnooze;
+use store::{
+    config::{env_settings::EnvSettings, settings::Setting},
+    core::collection::Collection,
+    tracing::{debug, error},
+    AccountId, Store,
+};
+
+use crate::{services::state_change::StateChange, JMAPServer};
+
+pub const SETTINGS: &[Setting] = &[Setting::seconds("snooze-poll-interval")
+    .default("60")
+    .describe("Time between checks for snoozed messages due, 0 disables the scheduler")];
+
+pub fn spawn_snooze<T>(core: web::Data<JMAPServer<T>>, settings: &EnvSettings)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let poll_interval = Duration::from_secs(settings.value(SETTINGS, "snooze-poll-interval"));
+    if poll_interval.is_zero() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        debug!("Snooze task started.");
+        loop {
+            tokio::time::sleep(poll_interval).await;
+
+            // Snoozed messages are resurfaced only once, by the leader
+            if core.is_leader() {
+                if let Err(err) = wake_snoozed(&core).await {
+                    error!("Failed to wake up snoozed messages: {}", err);
+                }
+            }
+        }
+    });
+}
+
+async fn wake_snoozed<T>(core: &web::Data<JMAPServer<T>>) -> store::Result<()>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Due messages are looked up in the wake-up time index, which is a single
+    // range lookup for accounts without snoozed messages.
+    let store = core.store.clone();
+    let account_ids = core
+        .spawn_worker(move || store.get_document_ids(SUPERUSER_ID, Collection::Principal))
+        .await?
+        .unwrap_or_default();
+
+    for account_id in account_ids {
+        if let Err(err) = wake_account(core, account_id).await {
+            error!(
+                "Failed to wake up snoozed messages of account {}: {}",
+                account_id, err
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn wake_account<T>(
+    core: &web::Data<JMAPServer<T>>,
+    account_id: AccountId,
+) -> store::Result<()>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let store = core.store.clone();
+    if let Some(change_id) = core
+        .spawn_worker(move || store.mail_snooze_wake(account_id))
+        .await?
+    {
+        debug!("Woke up snoozed messages of account {}.", account_id);
+
+        // Commit change
+        if core.is_in_cluster() {
+            core.commit_index(change_id).await;
+        }
+
+        // Notify subscribers
+        if let Err(err) = core
+            .publish_state_change(StateChange::new(
+                account_id,
+                vec![
+                    (TypeState::Email, change_id),
+                    (TypeState::Mailbox, change_id),
+                    (TypeState::Thread, change_id),
+                ],
+            ))
+            .await
+        {
+            error!("Failed to publish state change: {}", err);
+        }
+    }
+
+    Ok(())
+}
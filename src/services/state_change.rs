@@ -22,16 +22,30 @@
 */
 
 use actix_web::web;
-use jmap::types::type_state::TypeState;
+use jmap::{request::query_changes::QueryChangesResponse, types::type_state::TypeState};
+use jmap_mail::{
+    mail::{
+        query_subscription::{JMAPMailQuerySubscription, QueryResults, QuerySubscription},
+        sharing::JMAPShareMail,
+    },
+    mailbox::get::JMAPGetMailbox,
+};
 use jmap_sharing::principal::account::JMAPAccountStore;
-use std::time::{Duration, Instant, SystemTime};
+use std::{
+    collections::{hash_map::Entry, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 use store::{
-    ahash::AHashMap,
-    config::env_settings::EnvSettings,
-    core::bitmap::Bitmap,
-    log::changes::ChangeId,
-    tracing::{debug, error},
-    AccountId, JMAPId, Store,
+    ahash::{AHashMap, AHashSet},
+    config::{env_settings::EnvSettings, settings::Setting},
+    core::{acl::ACL, bitmap::Bitmap, collection::Collection},
+    log::changes::{self, ChangeId},
+    parking_lot::Mutex,
+    rand::{thread_rng, Rng},
+    roaring::RoaringBitmap,
+    tracing::{debug, error, warn},
+    AccountId, JMAPId, SharedBitmap, Store,
 };
 use store::{core::JMAPIdPrefix, DocumentId};
 use tokio::sync::mpsc;
@@ -40,18 +54,28 @@ use crate::{cluster::IPC_CHANNEL_BUFFER, JMAPServer};
 
 use super::push_subscription::{spawn_push_manager, UpdateSubscription};
 
+pub const SETTINGS: &[Setting] = &[Setting::integer("event-source-replay-size")
+    .default("32")
+    .describe(
+    "Recent changes kept per account for Last-Event-ID replay, older ids receive the full state",
+)];
+
 #[derive(Debug)]
 pub enum Event {
     Start,
     Stop,
+    Standby,
     Subscribe {
         id: DocumentId,
         account_id: AccountId,
         types: Bitmap<TypeState>,
         tx: mpsc::Sender<StateChange>,
+        last_event_id: Option<u64>,
+        mailbox_counters: bool,
     },
     Publish {
         state_change: StateChange,
+        is_relayed: bool,
     },
     UpdateSharedAccounts {
         account_id: AccountId,
@@ -66,18 +90,40 @@ pub enum Event {
 pub struct StateChange {
     pub account_id: AccountId,
     pub types: Vec<(TypeState, ChangeId)>,
+    /// Sequence number assigned by the state manager on publish, used as the
+    /// event id by the EventSource endpoint.
+    pub event_id: u64,
+    /// Unread email count deltas for the mailboxes affected by a Mailbox
+    /// change, only sent to subscribers that requested mailbox counters.
+    pub unread_deltas: Vec<(DocumentId, i64)>,
+    /// Changes to the results of the Email queries the subscriber registered,
+    /// keyed by the client provided subscription id.
+    pub query_changes: Vec<(String, QueryChangesResponse)>,
 }
 
 impl StateChange {
     pub fn new(account_id: AccountId, types: Vec<(TypeState, ChangeId)>) -> Self {
-        Self { account_id, types }
+        Self {
+            account_id,
+            types,
+            event_id: 0,
+            unread_deltas: Vec::new(),
+            query_changes: Vec::new(),
+        }
     }
 }
 
+struct RecentChanges {
+    since: u64,
+    updated: Instant,
+    changes: VecDeque<StateChange>,
+}
+
 #[derive(Debug)]
 struct Subscriber {
     types: Bitmap<TypeState>,
     subscription: SubscriberType,
+    mailbox_counters: bool,
 }
 
 #[derive(Debug)]
@@ -95,6 +141,109 @@ impl Subscriber {
     }
 }
 
+/// Email queries subscribed to by WebSocket clients. Their results are owned
+/// here rather than by the state manager, as they are updated by the writers.
+#[derive(Default)]
+pub struct QuerySubscriptions {
+    subscribers: Mutex<AHashMap<AccountId, Vec<Arc<QuerySubscriber>>>>,
+}
+
+struct QuerySubscriber {
+    id: DocumentId,
+    tx: mpsc::Sender<StateChange>,
+    results: Mutex<Vec<QueryResults>>,
+}
+
+impl QuerySubscriptions {
+    fn register(&self, account_id: AccountId, subscriber: QuerySubscriber) {
+        let mut subscribers = self.subscribers.lock();
+        let account_subscribers = subscribers.entry(account_id).or_insert_with(Vec::new);
+        account_subscribers.retain(|s| s.id != subscriber.id && !s.tx.is_closed());
+        account_subscribers.push(Arc::new(subscriber));
+    }
+
+    fn get(&self, account_id: AccountId) -> Vec<Arc<QuerySubscriber>> {
+        let mut subscribers = self.subscribers.lock();
+        if let Some(account_subscribers) = subscribers.get_mut(&account_id) {
+            account_subscribers.retain(|s| !s.tx.is_closed());
+            if !account_subscribers.is_empty() {
+                return account_subscribers.clone();
+            }
+            subscribers.remove(&account_id);
+        }
+        Vec::new()
+    }
+
+    pub fn is_empty(&self, account_id: AccountId) -> bool {
+        self.get(account_id).is_empty()
+    }
+}
+
+/// Last known unread counts of the mailboxes in the accounts visible to
+/// subscribers that requested mailbox counters. The deltas are computed by the
+/// writers from the mailboxes changed in each transaction.
+#[derive(Default)]
+pub struct MailboxCounters {
+    accounts: Mutex<AHashMap<AccountId, AHashMap<DocumentId, (u64, ChangeId)>>>,
+}
+
+impl MailboxCounters {
+    pub fn is_tracked(&self, account_id: AccountId) -> bool {
+        self.accounts.lock().contains_key(&account_id)
+    }
+
+    fn track(&self, account_id: AccountId, counts: Vec<(DocumentId, u64)>) {
+        self.accounts.lock().entry(account_id).or_insert_with(|| {
+            counts
+                .into_iter()
+                .map(|(mailbox_id, count)| (mailbox_id, (count, 0)))
+                .collect()
+        });
+    }
+
+    fn update(
+        &self,
+        account_id: AccountId,
+        change_id: ChangeId,
+        counts: Vec<(DocumentId, u64)>,
+    ) -> Vec<(DocumentId, i64)> {
+        let mut deltas = Vec::new();
+        if let Some(account) = self.accounts.lock().get_mut(&account_id) {
+            for (mailbox_id, count) in counts {
+                let prev_count = match account.entry(mailbox_id) {
+                    Entry::Occupied(mut entry) => {
+                        // Counts read by a later transaction already include this one
+                        let (prev_count, prev_change_id) = *entry.get();
+                        if prev_change_id > change_id {
+                            continue;
+                        }
+                        entry.insert((count, change_id));
+                        prev_count
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert((count, change_id));
+                        0
+                    }
+                };
+                if count != prev_count {
+                    deltas.push((mailbox_id, count as i64 - prev_count as i64));
+                }
+            }
+        }
+        deltas
+    }
+
+    fn retain(&self, account_ids: &AHashSet<AccountId>) {
+        self.accounts
+            .lock()
+            .retain(|account_id, _| account_ids.contains(account_id));
+    }
+
+    fn clear(&self) {
+        self.accounts.lock().clear();
+    }
+}
+
 const PURGE_EVERY_SECS: u64 = 3600;
 const SEND_TIMEOUT_MS: u64 = 500;
 
@@ -111,6 +260,7 @@ pub fn spawn_state_manager<T>(
     T: for<'x> Store<'x> + 'static,
 {
     let push_tx = spawn_push_manager(settings);
+    let replay_size: usize = settings.value(SETTINGS, "event-source-replay-size");
 
     tokio::spawn(async move {
         let mut subscribers: AHashMap<AccountId, AHashMap<DocumentId, Subscriber>> =
@@ -119,6 +269,20 @@ pub fn spawn_state_manager<T>(
         let mut shared_accounts_map: AHashMap<AccountId, Vec<(AccountId, Bitmap<TypeState>)>> =
             AHashMap::default();
 
+        // Mailboxes of the accounts shared with a subscriber, keyed by subscriber and
+        // owner, used to limit the unread deltas to the mailboxes it can read.
+        let mut shared_mailboxes: AHashMap<(AccountId, AccountId), Arc<Option<RoaringBitmap>>> =
+            AHashMap::default();
+
+        // Recently published changes per account, replayed to reconnecting clients.
+        // Event ids start at a random offset, so that ids issued before a restart
+        // or by another node fall outside the range known to this instance.
+        let mut recent_changes: AHashMap<AccountId, RecentChanges> = AHashMap::default();
+        let mut next_event_id = thread_rng().gen_range(1..(u64::MAX >> 2));
+
+        // Accounts without recent changes had none after this event id
+        let mut missing_since = next_event_id - 1;
+
         let mut last_purge = Instant::now();
 
         while let Some(event) = change_rx.recv().await {
@@ -134,6 +298,26 @@ pub fn spawn_state_manager<T>(
                     subscribers.clear();
                     shared_accounts.clear();
                     shared_accounts_map.clear();
+                    shared_mailboxes.clear();
+                    recent_changes.clear();
+                    missing_since = next_event_id - 1;
+                    core.mailbox_counters.clear();
+
+                    if let Err(err) = push_tx.send(super::push_subscription::Event::Reset).await {
+                        debug!("Error sending push reset: {}", err);
+                    }
+                }
+                Event::Standby => {
+                    // Keep notifying local subscribers, push subscriptions
+                    // are delivered by the leader.
+                    started = true;
+
+                    for subscriber_map in subscribers.values_mut() {
+                        subscriber_map.retain(|_, subscriber| {
+                            matches!(subscriber.subscription, SubscriberType::Ipc { .. })
+                        });
+                    }
+                    subscribers.retain(|_, subscriber_map| !subscriber_map.is_empty());
 
                     if let Err(err) = push_tx.send(super::push_subscription::Event::Reset).await {
                         debug!("Error sending push reset: {}", err);
@@ -142,8 +326,26 @@ pub fn spawn_state_manager<T>(
                 Event::UpdateSharedAccounts { account_id } => {
                     // Obtain account membership and shared mailboxes
                     let store = core.store.clone();
-                    let acl = match core
-                        .spawn_worker(move || store.get_acl_token(account_id))
+                    let (acl, mailboxes) = match core
+                        .spawn_worker(move || {
+                            let acl = store.get_acl_token(account_id)?;
+                            let mut mailboxes = Vec::new();
+                            for (shared_account_id, shared_collections) in acl.access_to.iter() {
+                                if shared_collections.contains(Collection::Mail)
+                                    && !acl.member_of.contains(shared_account_id)
+                                {
+                                    mailboxes.push((
+                                        *shared_account_id,
+                                        store.mail_shared_folders(
+                                            *shared_account_id,
+                                            &acl.member_of,
+                                            ACL::ReadItems,
+                                        )?,
+                                    ));
+                                }
+                            }
+                            Ok((acl, mailboxes))
+                        })
                         .await
                     {
                         Ok(result) => result,
@@ -209,13 +411,90 @@ pub fn spawn_state_manager<T>(
                         }
                     }
                     shared_accounts.insert(account_id, shared_account_ids);
+
+                    shared_mailboxes.retain(|(subscriber_id, _), _| *subscriber_id != account_id);
+                    for (shared_account_id, mailbox_ids) in mailboxes {
+                        shared_mailboxes.insert((account_id, shared_account_id), mailbox_ids);
+                    }
                 }
                 Event::Subscribe {
                     id,
                     account_id,
                     types,
                     tx,
+                    last_event_id,
+                    mailbox_counters,
                 } if started => {
+                    // Replay any changes the client missed since its last event
+                    if let (Some(last_event_id), Some(shared_account_ids)) =
+                        (last_event_id, shared_accounts.get(&account_id))
+                    {
+                        let mut full_state = Vec::new();
+                        for shared_account_id in shared_account_ids {
+                            let allowed_types = if let Some(allowed_types) = shared_accounts_map
+                                .get(shared_account_id)
+                                .and_then(|list| list.iter().find(|(id, _)| *id == account_id))
+                                .map(|(_, types)| types)
+                            {
+                                allowed_types
+                            } else {
+                                continue;
+                            };
+
+                            // Send the current state when the missed changes are no longer
+                            // known, the client then compares it against its own.
+                            let recent = recent_changes.get(shared_account_id);
+                            let since = recent.map_or(missing_since, |recent| recent.since);
+                            if last_event_id < since || last_event_id >= next_event_id {
+                                let mut full_types = types.clone();
+                                full_types.intersection(allowed_types);
+                                if !full_types.is_empty() {
+                                    full_state.push((*shared_account_id, full_types));
+                                }
+                                continue;
+                            }
+
+                            // Collapse all missed changes into a single state change,
+                            // keeping the latest change id for each type.
+                            let mut missed = StateChange::new(*shared_account_id, Vec::new());
+                            for state_change in recent
+                                .into_iter()
+                                .flat_map(|recent| recent.changes.iter())
+                                .filter(|state_change| state_change.event_id > last_event_id)
+                            {
+                                for (state_type, change_id) in &state_change.types {
+                                    if types.contains(*state_type)
+                                        && allowed_types.contains(*state_type)
+                                    {
+                                        if let Some(item) =
+                                            missed.types.iter_mut().find(|(t, _)| t == state_type)
+                                        {
+                                            item.1 = *change_id;
+                                        } else {
+                                            missed.types.push((*state_type, *change_id));
+                                        }
+                                    }
+                                }
+                                missed.event_id = state_change.event_id;
+                            }
+
+                            if !missed.types.is_empty() {
+                                if let Err(err) = tx.try_send(missed) {
+                                    debug!("Error replaying state change to subscriber: {}", err);
+                                }
+                            }
+                        }
+
+                        if !full_state.is_empty() {
+                            let core = core.clone();
+                            let tx = tx.clone();
+                            let event_id = next_event_id - 1;
+                            tokio::spawn(async move {
+                                core.send_full_state(full_state, event_id, tx).await;
+                            });
+                        }
+                    }
+
                     subscribers
                         .entry(account_id)
                         .or_insert_with(AHashMap::default)
@@ -224,10 +503,58 @@ pub fn spawn_state_manager<T>(
                             Subscriber {
                                 types,
                                 subscription: SubscriberType::Ipc { tx },
+                                mailbox_counters,
                             },
                         );
                 }
-                Event::Publish { state_change } if started => {
+                Event::Publish {
+                    mut state_change,
+                    is_relayed: true,
+                } if started => {
+                    // Changes relayed from other nodes did not go through the local
+                    // write path, update the subscribed queries and mailbox counters
+                    // in the background before publishing them.
+                    let core = core.clone();
+                    tokio::spawn(async move {
+                        core.update_subscribed_queries(&state_change).await;
+                        core.update_mailbox_counters(&mut state_change).await;
+                        if let Err(err) = core
+                            .state_change
+                            .send(Event::Publish {
+                                state_change,
+                                is_relayed: false,
+                            })
+                            .await
+                        {
+                            error!("Channel failure while publishing state change: {}", err);
+                        }
+                    });
+                }
+                Event::Publish {
+                    mut state_change, ..
+                } if started => {
+                    state_change.event_id = next_event_id;
+                    next_event_id += 1;
+
+                    let recent = recent_changes
+                        .entry(state_change.account_id)
+                        .or_insert_with(|| RecentChanges {
+                            since: missing_since,
+                            updated: Instant::now(),
+                            changes: VecDeque::new(),
+                        });
+                    if recent.changes.len() >= replay_size {
+                        if let Some(evicted) = recent.changes.pop_front() {
+                            recent.since = evicted.event_id;
+                        } else {
+                            recent.since = state_change.event_id;
+                        }
+                    }
+                    if replay_size > 0 {
+                        recent.changes.push_back(state_change.clone());
+                    }
+                    recent.updated = Instant::now();
+
                     if let Some(shared_accounts) = shared_accounts_map.get(&state_change.account_id)
                     {
                         let current_time = SystemTime::now()
@@ -248,18 +575,44 @@ pub fn spawn_state_manager<T>(
                                         }
                                     }
                                     if !types.is_empty() {
+                                        // Shared accounts only receive the deltas of the
+                                        // mailboxes the subscriber can read.
+                                        let unread_deltas = if subscriber.mailbox_counters
+                                            && types.iter().any(|(t, _)| *t == TypeState::Mailbox)
+                                        {
+                                            if let Some(mailbox_ids) = shared_mailboxes
+                                                .get(&(*owner_account_id, state_change.account_id))
+                                            {
+                                                state_change
+                                                    .unread_deltas
+                                                    .iter()
+                                                    .filter(|(mailbox_id, _)| {
+                                                        mailbox_ids.has_access(*mailbox_id)
+                                                    })
+                                                    .copied()
+                                                    .collect()
+                                            } else {
+                                                state_change.unread_deltas.clone()
+                                            }
+                                        } else {
+                                            Vec::new()
+                                        };
+
                                         match &subscriber.subscription {
                                             SubscriberType::Ipc { tx } if !tx.is_closed() => {
                                                 let subscriber_tx = tx.clone();
-                                                let state_change = state_change.clone();
-
+                                                let account_id = state_change.account_id;
+                                                let event_id = state_change.event_id;
                                                 tokio::spawn(async move {
                                                     // Timeout after 500ms in case there is a blocked client
                                                     if let Err(err) = subscriber_tx
                                                         .send_timeout(
                                                             StateChange {
-                                                                account_id: state_change.account_id,
+                                                                account_id,
                                                                 types,
+                                                                event_id,
+                                                                unread_deltas,
+                                                                query_changes: Vec::new(),
                                                             },
                                                             Duration::from_millis(SEND_TIMEOUT_MS),
                                                         )
@@ -275,9 +628,12 @@ pub fn spawn_state_manager<T>(
                                             SubscriberType::Push { expires }
                                                 if expires > &current_time =>
                                             {
-                                                push_ids.push(JMAPId::from_parts(
-                                                    *owner_account_id,
-                                                    *subscriber_id,
+                                                push_ids.push((
+                                                    JMAPId::from_parts(
+                                                        *owner_account_id,
+                                                        *subscriber_id,
+                                                    ),
+                                                    unread_deltas,
                                                 ));
                                             }
                                             _ => {
@@ -362,6 +718,7 @@ pub fn spawn_state_manager<T>(
                                             subscription: SubscriberType::Push {
                                                 expires: verified.expires,
                                             },
+                                            mailbox_counters: verified.mailbox_counters,
                                         },
                                     );
 
@@ -419,6 +776,30 @@ pub fn spawn_state_manager<T>(
                     subscribers.remove(&remove_account_id);
                 }
 
+                // Changes older than the purge interval are sent as the full state
+                let num_recent = recent_changes.len();
+                recent_changes.retain(|_, recent| {
+                    recent.updated.elapsed() < Duration::from_secs(PURGE_EVERY_SECS)
+                });
+                if recent_changes.len() != num_recent {
+                    missing_since = next_event_id - 1;
+                }
+
+                // Keep the counters of the accounts visible to counter subscribers
+                let mut counter_account_ids = AHashSet::default();
+                for (account_id, subscriber_map) in &subscribers {
+                    if subscriber_map
+                        .values()
+                        .any(|subscriber| subscriber.mailbox_counters)
+                    {
+                        counter_account_ids.insert(*account_id);
+                        if let Some(shared_account_ids) = shared_accounts.get(account_id) {
+                            counter_account_ids.extend(shared_account_ids.iter().copied());
+                        }
+                    }
+                }
+                core.mailbox_counters.retain(&counter_account_ids);
+
                 last_purge = Instant::now();
             }
         }
@@ -434,10 +815,62 @@ where
         id: DocumentId,
         account_id: DocumentId,
         types: Bitmap<TypeState>,
+        last_event_id: Option<u64>,
+        mailbox_counters: bool,
+        queries: Vec<(String, QuerySubscription)>,
     ) -> Option<mpsc::Receiver<StateChange>> {
         let (change_tx, change_rx) = mpsc::channel::<StateChange>(IPC_CHANNEL_BUFFER);
         let state_tx = self.state_change.clone();
 
+        // Run the subscribed queries and send their initial results
+        if !queries.is_empty() {
+            let store = self.store.clone();
+            match self
+                .spawn_jmap_request(move || {
+                    let acl = store.get_acl_token(account_id)?;
+                    let mut results = Vec::with_capacity(queries.len());
+                    let mut changes = Vec::with_capacity(queries.len());
+                    for (query_id, subscription) in queries {
+                        let (query_results, query_changes) = store.mail_query_subscribe(
+                            acl.clone(),
+                            account_id,
+                            query_id.clone(),
+                            subscription,
+                        )?;
+                        results.push(query_results);
+                        changes.push((query_id, query_changes));
+                    }
+                    Ok((results, changes))
+                })
+                .await
+            {
+                Ok((results, changes)) => {
+                    let mut state_change = StateChange::new(account_id, Vec::new());
+                    state_change.query_changes = changes;
+                    if let Err(err) = change_tx.try_send(state_change) {
+                        debug!("Error sending query results to subscriber: {}", err);
+                    }
+                    self.query_subscriptions.register(
+                        account_id,
+                        QuerySubscriber {
+                            id,
+                            tx: change_tx.clone(),
+                            results: Mutex::new(results),
+                        },
+                    );
+                }
+                Err(err) => {
+                    error!("Error running subscribed queries: {}", err);
+                    return None;
+                }
+            }
+        }
+
+        // Obtain the unread counts deltas are computed against
+        if mailbox_counters {
+            self.track_mailbox_counters(account_id).await;
+        }
+
         for event in [
             Event::UpdateSharedAccounts { account_id },
             Event::Subscribe {
@@ -445,6 +878,8 @@ where
                 account_id,
                 types,
                 tx: change_tx,
+                last_event_id,
+                mailbox_counters,
             },
         ] {
             if let Err(err) = state_tx.send(event).await {
@@ -459,20 +894,249 @@ where
         change_rx.into()
     }
 
-    pub async fn publish_state_change(&self, state_change: StateChange) -> jmap::Result<()> {
+    // Sends the current state of each account to a subscriber whose last event
+    // predates the changes known to the state manager.
+    async fn send_full_state(
+        &self,
+        accounts: Vec<(AccountId, Bitmap<TypeState>)>,
+        event_id: u64,
+        tx: mpsc::Sender<StateChange>,
+    ) {
+        let store = self.store.clone();
+        match self
+            .spawn_worker(move || {
+                let mut state_changes = Vec::with_capacity(accounts.len());
+                for (account_id, types) in accounts {
+                    let mut state_change = StateChange::new(account_id, Vec::new());
+                    state_change.event_id = event_id;
+                    for type_state in types {
+                        let collection = match type_state {
+                            TypeState::Email | TypeState::EmailDelivery => Collection::Mail,
+                            TypeState::EmailSubmission => Collection::EmailSubmission,
+                            TypeState::Mailbox => Collection::Mailbox,
+                            TypeState::Thread => Collection::Thread,
+                            TypeState::Identity => Collection::Identity,
+                            TypeState::None => continue,
+                        };
+                        if let Some(change_id) = store.get_last_change_id(account_id, collection)? {
+                            state_change.types.push((type_state, change_id));
+                        }
+                    }
+                    if !state_change.types.is_empty() {
+                        state_changes.push(state_change);
+                    }
+                }
+                Ok(state_changes)
+            })
+            .await
+        {
+            Ok(state_changes) => {
+                for state_change in state_changes {
+                    if let Err(err) = tx.send(state_change).await {
+                        debug!("Error sending full state to subscriber: {}", err);
+                    }
+                }
+            }
+            Err(err) => {
+                error!("Error obtaining full state: {}", err);
+            }
+        }
+    }
+
+    pub async fn publish_state_change(&self, mut state_change: StateChange) -> jmap::Result<()> {
+        if let Some(push_broker) = &self.push_broker {
+            // The broker is only a fan-out, updates are dropped rather than
+            // blocking writes while it is disconnected or falling behind.
+            if let Err(err) = push_broker.try_send(state_change.clone()) {
+                warn!("Dropping state change for push broker: {}", err);
+            }
+        }
+
+        self.update_subscribed_queries(&state_change).await;
+        self.update_mailbox_counters(&mut state_change).await;
+
         let state_tx = self.state_change.clone();
-        if let Err(err) = state_tx.clone().send(Event::Publish { state_change }).await {
+        if let Err(err) = state_tx
+            .clone()
+            .send(Event::Publish {
+                state_change,
+                is_relayed: false,
+            })
+            .await
+        {
             error!("Channel failure while publishing state change: {}", err);
         }
         Ok(())
     }
 
+    /// Sends the changes to the results of the Email queries subscribed to in the
+    /// account, evaluating only the messages changed since they were last sent.
+    pub async fn update_subscribed_queries(&self, state_change: &StateChange) {
+        if !state_change
+            .types
+            .iter()
+            .any(|(t, _)| *t == TypeState::Email)
+        {
+            return;
+        }
+        let account_id = state_change.account_id;
+        let subscribers = self.query_subscriptions.get(account_id);
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let store = self.store.clone();
+        if let Err(err) = self
+            .spawn_worker(move || {
+                let acl = store.get_acl_token(account_id)?;
+                for subscriber in subscribers {
+                    // Changes are sent while the results are locked, so that concurrent
+                    // writers deliver them in order.
+                    let mut results = subscriber.results.lock();
+                    let query_changes =
+                        store.mail_query_update(acl.clone(), account_id, &mut results);
+                    if !query_changes.is_empty() {
+                        let mut state_change = StateChange::new(account_id, Vec::new());
+                        state_change.query_changes = query_changes;
+                        if let Err(err) = subscriber.tx.try_send(state_change) {
+                            debug!("Error sending query changes to subscriber: {}", err);
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .await
+        {
+            error!("Error updating subscribed queries: {}", err);
+        }
+    }
+
+    /// Obtains the unread counts of the accounts visible to a subscriber that
+    /// requested mailbox counters, unless they are already being tracked.
+    async fn track_mailbox_counters(&self, account_id: AccountId) {
+        let store = self.store.clone();
+        let account_ids = match self
+            .spawn_worker(move || {
+                let acl = store.get_acl_token(account_id)?;
+                Ok(acl
+                    .member_of
+                    .iter()
+                    .copied()
+                    .chain(
+                        acl.access_to
+                            .iter()
+                            .filter(|(_, collections)| collections.contains(Collection::Mail))
+                            .map(|(account_id, _)| *account_id),
+                    )
+                    .collect::<Vec<_>>())
+            })
+            .await
+        {
+            Ok(account_ids) => account_ids
+                .into_iter()
+                .filter(|account_id| !self.mailbox_counters.is_tracked(*account_id))
+                .collect::<Vec<_>>(),
+            Err(err) => {
+                error!("Error obtaining mailbox unread counts: {}", err);
+                return;
+            }
+        };
+        if account_ids.is_empty() {
+            return;
+        }
+
+        let store = self.store.clone();
+        match self
+            .spawn_worker(move || {
+                let mut counts = Vec::with_capacity(account_ids.len());
+                for account_id in account_ids {
+                    counts.push((account_id, store.mailbox_unread_counts(account_id, None)?));
+                }
+                Ok(counts)
+            })
+            .await
+        {
+            Ok(counts) => {
+                for (account_id, counts) in counts {
+                    self.mailbox_counters.track(account_id, counts);
+                }
+            }
+            Err(err) => {
+                error!("Error obtaining mailbox unread counts: {}", err);
+            }
+        }
+    }
+
+    /// Adds the unread count deltas of the mailboxes changed in the transaction,
+    /// for accounts whose counters are tracked.
+    pub async fn update_mailbox_counters(&self, state_change: &mut StateChange) {
+        let account_id = state_change.account_id;
+        let change_id = if let Some(change_id) = state_change
+            .types
+            .iter()
+            .find(|(t, _)| *t == TypeState::Mailbox)
+            .map(|(_, change_id)| *change_id)
+        {
+            change_id
+        } else {
+            return;
+        };
+        if !self.mailbox_counters.is_tracked(account_id) {
+            return;
+        }
+
+        let store = self.store.clone();
+        match self
+            .spawn_worker(move || {
+                let mailbox_ids = store
+                    .get_changes(
+                        account_id,
+                        Collection::Mailbox,
+                        changes::Query::RangeInclusive(change_id, change_id),
+                    )?
+                    .map(|changelog| {
+                        changelog
+                            .changes
+                            .into_iter()
+                            .map(|change| match change {
+                                changes::Change::Insert(id)
+                                | changes::Change::Update(id)
+                                | changes::Change::ChildUpdate(id)
+                                | changes::Change::Delete(id) => id.get_document_id(),
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                if !mailbox_ids.is_empty() {
+                    store.mailbox_unread_counts(account_id, mailbox_ids.into())
+                } else {
+                    Ok(Vec::new())
+                }
+            })
+            .await
+        {
+            Ok(counts) => {
+                state_change.unread_deltas =
+                    self.mailbox_counters.update(account_id, change_id, counts);
+            }
+            Err(err) => {
+                error!("Error obtaining mailbox unread counts: {}", err);
+            }
+        }
+    }
+
     pub async fn update_push_subscriptions(&self, account_id: AccountId) -> jmap::Result<()> {
+        let event = self.fetch_push_subscriptions(account_id).await?;
+        if let Event::UpdateSubscriptions { subscriptions, .. } = &event {
+            if subscriptions.iter().any(|subscription| {
+                matches!(subscription, UpdateSubscription::Verified(verified) if verified.mailbox_counters)
+            }) {
+                self.track_mailbox_counters(account_id).await;
+            }
+        }
+
         let state_tx = self.state_change.clone();
-        for event in [
-            Event::UpdateSharedAccounts { account_id },
-            self.fetch_push_subscriptions(account_id).await?,
-        ] {
+        for event in [Event::UpdateSharedAccounts { account_id }, event] {
             if let Err(err) = state_tx.send(event).await {
                 error!("Channel failure while publishing state change: {}", err);
                 break;
@@ -482,3 +1146,29 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::MailboxCounters;
+
+    #[test]
+    fn mailbox_counter_deltas() {
+        let counters = MailboxCounters::default();
+
+        // Untracked accounts produce no deltas
+        assert!(counters.update(1, 1, vec![(0, 5)]).is_empty());
+
+        counters.track(1, vec![(0, 5), (1, 0)]);
+        assert!(counters.is_tracked(1));
+
+        // Tracking an account again keeps its counters
+        counters.track(1, vec![(0, 100)]);
+
+        assert_eq!(counters.update(1, 3, vec![(0, 7), (1, 0)]), vec![(0, 2)]);
+        assert_eq!(counters.update(1, 4, vec![(2, 1)]), vec![(2, 1)]);
+
+        // Counts read for an older transaction are ignored
+        assert!(counters.update(1, 2, vec![(0, 6)]).is_empty());
+        assert_eq!(counters.update(1, 5, vec![(0, 4)]), vec![(0, -3)]);
+    }
+}
@@ -0,0 +1,311 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use actix_web::{web, HttpResponse};
+use jmap_mail::mail::{schema::Keyword, MessageField};
+use jmap_sharing::principal::account::JMAPAccountStore;
+use serde::{Deserialize, Serialize};
+use store::{
+    bincode,
+    config::{env_settings::EnvSettings, settings::Setting},
+    core::{collection::Collection, tag::Tag},
+    moka::sync::Cache,
+    serialize::{StoreDeserialize, StoreSerialize},
+    tracing::{debug, error, info},
+    write::ttl::now,
+    AccountId, JMAPStore, Store,
+};
+
+use crate::{api::RequestError, JMAPServer};
+
+pub const SETTINGS: &[Setting] = &[
+    Setting::bool("warmup-enable").default("false").describe(
+        "Pre-load caches of recently active accounts, /healthz/ready returns 503 until done",
+    ),
+    Setting::integer("warmup-max-accounts").default("1000"),
+    Setting::seconds("warmup-persist-interval").default("300"),
+];
+
+pub const MANIFEST_KEY: &str = "warmup_manifest";
+
+// Recently active accounts along with their last access time, most recent first.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct WarmupManifest {
+    pub accounts: Vec<(AccountId, u64)>,
+}
+
+// Tracks recently active accounts so that their metadata can be loaded
+// into the caches before the node reports itself as ready.
+pub struct WarmupManager {
+    enabled: bool,
+    max_accounts: usize,
+    persist_interval: Duration,
+    is_ready: AtomicBool,
+    recent: Cache<AccountId, u64>,
+}
+
+impl WarmupManager {
+    pub fn parse(settings: &EnvSettings) -> Self {
+        let enabled = settings.value(SETTINGS, "warmup-enable");
+        let max_accounts = settings
+            .parse("warmup-max-accounts")
+            .filter(|v| *v > 0)
+            .unwrap_or(1000);
+        WarmupManager {
+            enabled,
+            max_accounts,
+            persist_interval: Duration::from_secs(
+                settings
+                    .parse("warmup-persist-interval")
+                    .filter(|v| *v > 0)
+                    .unwrap_or(300),
+            ),
+            is_ready: (!enabled).into(),
+            recent: Cache::builder()
+                .initial_capacity(128)
+                .max_capacity(max_accounts as u64)
+                .build(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.is_ready.load(Ordering::Relaxed)
+    }
+
+    pub fn record_access(&self, account_id: AccountId) {
+        if self.enabled {
+            self.recent.insert(account_id, now());
+        }
+    }
+
+    // Returns the recently accessed accounts, most recent first.
+    pub fn manifest(&self, persisted: Vec<(AccountId, u64)>) -> Vec<(AccountId, u64)> {
+        for (account_id, accessed_at) in persisted {
+            if self
+                .recent
+                .get(&account_id)
+                .map_or(true, |t| t < accessed_at)
+            {
+                self.recent.insert(account_id, accessed_at);
+            }
+        }
+        let mut manifest = self
+            .recent
+            .iter()
+            .map(|(account_id, accessed_at)| (*account_id, accessed_at))
+            .collect::<Vec<_>>();
+        manifest.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        manifest.truncate(self.max_accounts);
+        manifest
+    }
+}
+
+impl<T> JMAPServer<T>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    pub async fn warmup_manifest(&self) -> store::Result<Vec<(AccountId, u64)>> {
+        let persisted = self
+            .get_key::<WarmupManifest>(MANIFEST_KEY)
+            .await?
+            .unwrap_or_default();
+        Ok(self.warmup.manifest(persisted.accounts))
+    }
+
+    pub async fn warmup_persist(&self) -> store::Result<()> {
+        let accounts = self.warmup_manifest().await?;
+        self.set_key(MANIFEST_KEY, WarmupManifest { accounts })
+            .await
+    }
+
+    // Loads the metadata of recently active accounts into the caches,
+    // the node reports itself as not ready while this is in progress.
+    pub async fn warmup_caches(&self) {
+        if !self.warmup.is_enabled() {
+            return;
+        }
+        self.warmup.is_ready.store(false, Ordering::Relaxed);
+
+        let start_time = Instant::now();
+        let result = match self.warmup_manifest().await {
+            Ok(manifest) => {
+                self.warmup_accounts(
+                    manifest
+                        .into_iter()
+                        .map(|(account_id, _)| account_id)
+                        .collect(),
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(num_accounts) => {
+                info!(
+                    "Warm-up completed for {} accounts in {} ms.",
+                    num_accounts,
+                    start_time.elapsed().as_millis()
+                );
+            }
+            Err(err) => {
+                error!("Cache warm-up failed: {:?}", err);
+            }
+        }
+        self.warmup.is_ready.store(true, Ordering::Relaxed);
+    }
+
+    pub async fn warmup_accounts(&self, account_ids: Vec<AccountId>) -> store::Result<usize> {
+        let store = self.store.clone();
+        self.spawn_worker(move || {
+            for &account_id in &account_ids {
+                warmup_account(&store, account_id)?;
+            }
+            Ok(account_ids.len())
+        })
+        .await
+    }
+}
+
+// Populates the caches read on every request of an account: its ACL token,
+// the id assigners and, when the bitmap cache is enabled, the bitmaps used to
+// list the account's messages and mailboxes.
+fn warmup_account<T>(store: &JMAPStore<T>, account_id: AccountId) -> store::Result<()>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    store.get_acl_token(account_id)?;
+
+    let mut mailbox_ids = None;
+    for collection in [Collection::Mail, Collection::Mailbox] {
+        store.get_id_assigner(account_id, collection)?;
+        let document_ids = store.get_document_ids(account_id, collection)?;
+        if collection == Collection::Mailbox {
+            mailbox_ids = document_ids;
+        }
+    }
+
+    if store.bitmap_cache.is_enabled() {
+        // Mailbox contents and unread counts
+        for mailbox_id in mailbox_ids.unwrap_or_default() {
+            store.get_tag(
+                account_id,
+                Collection::Mail,
+                MessageField::Mailbox.into(),
+                Tag::Id(mailbox_id),
+            )?;
+        }
+        store.get_tag(
+            account_id,
+            Collection::Mail,
+            MessageField::Keyword.into(),
+            Tag::Static(Keyword::SEEN),
+        )?;
+    }
+
+    Ok(())
+}
+
+impl StoreSerialize for WarmupManifest {
+    fn serialize(&self) -> Option<Vec<u8>> {
+        bincode::serialize(self).ok()
+    }
+}
+
+impl StoreDeserialize for WarmupManifest {
+    fn deserialize(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+pub fn spawn_warmup<T>(core: web::Data<JMAPServer<T>>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    if !core.warmup.is_enabled() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        core.warmup_caches().await;
+
+        loop {
+            tokio::time::sleep(core.warmup.persist_interval).await;
+            if let Err(err) = core.warmup_persist().await {
+                error!("Failed to persist warm-up manifest: {:?}", err);
+            } else {
+                debug!("Warm-up manifest persisted.");
+            }
+        }
+    });
+}
+
+pub async fn handle_ready<T>(core: web::Data<JMAPServer<T>>) -> Result<HttpResponse, RequestError>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    if core.warmup.is_ready() {
+        Ok(HttpResponse::Ok().body("ready"))
+    } else {
+        Err(RequestError::unavailable().with_detail("The server is warming up its caches."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use store::{ahash::AHashMap, config::env_settings::EnvSettings};
+
+    use super::WarmupManager;
+
+    #[test]
+    fn manifest_merge() {
+        let manager = WarmupManager::parse(&EnvSettings {
+            args: AHashMap::from_iter([
+                ("warmup-enable".to_string(), "true".to_string()),
+                ("warmup-max-accounts".to_string(), "3".to_string()),
+            ]),
+        });
+        manager.recent.insert(1, 100);
+        manager.recent.insert(2, 500);
+
+        // Newer access times win and the result is sorted by access time
+        assert_eq!(
+            manager.manifest(vec![(1, 300), (2, 200), (3, 400)]),
+            vec![(2, 500), (3, 400), (1, 300)]
+        );
+
+        // The manifest never exceeds the configured number of accounts
+        let manifest = manager.manifest(vec![(4, 50), (5, 600)]);
+        assert!(manifest.len() <= 3);
+        assert!(manifest.windows(2).all(|w| w[0].1 >= w[1].1));
+    }
+}
@@ -21,8 +21,12 @@
  * for more details.
 */
 
+use std::time::Duration;
+
+use reqwest::StatusCode;
 use store::Store;
 
+use crate::cluster::health::Role;
 use crate::tests::cluster::utils::{
     activate_all_peers, assert_cluster_updated, assert_leader_elected, assert_no_quorum,
     shutdown_all, Cluster,
@@ -38,6 +42,48 @@ where
     let peers = cluster.start_cluster().await;
 
     assert_cluster_updated(&peers).await;
+
+    // Test cluster health status
+    let status = assert_leader_elected(&peers)
+        .await
+        .cluster_status()
+        .await
+        .unwrap();
+    assert_eq!(status.role, Role::Leader);
+    assert!(status.ready);
+    assert_eq!(status.peers.len(), 4);
+    assert!(status.peers.iter().all(|peer| peer.lag_entries.is_some()));
+
+    // Peer details are not exposed by the unauthenticated health check
+    let base_url = assert_leader_elected(&peers)
+        .await
+        .base_session
+        .base_url()
+        .to_string();
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_millis(500))
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap_or_default();
+    let response = client
+        .get(format!("{}/health/cluster", base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.json::<serde_json::Value>().await.unwrap();
+    assert_eq!(body["role"], "leader");
+    assert!(body.get("peers").is_none(), "{}", body);
+    assert_eq!(
+        client
+            .get(format!("{}/api/v1/admin/cluster/status", base_url))
+            .send()
+            .await
+            .unwrap()
+            .status(),
+        StatusCode::UNAUTHORIZED
+    );
+
     assert_leader_elected(&peers)
         .await
         .set_offline(true, true)
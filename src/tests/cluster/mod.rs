@@ -28,6 +28,7 @@ pub mod election;
 pub mod fuzz;
 pub mod log_conflict;
 pub mod mail_thread_merge;
+pub mod replay;
 pub mod utils;
 
 #[actix_web::test]
@@ -46,6 +47,14 @@ async fn cluster_tests() {
     log_conflict::test::<RocksDB>().await;
 }
 
+#[test]
+#[ignore]
+fn cluster_replay() {
+    let (db, temp_dir) = super::store::init_db::<RocksDB>("st_cluster_replay", true);
+    replay::test(std::sync::Arc::new(db));
+    super::store::utils::destroy_temp_dir(&temp_dir);
+}
+
 #[actix_web::test]
 #[ignore]
 async fn cluster_fuzz() {
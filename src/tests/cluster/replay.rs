@@ -0,0 +1,218 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use jmap::orm::{serialize::JMAPOrm, TinyORM};
+use jmap_mail::mailbox::schema::{Mailbox, Property, Value};
+use store::{
+    core::collection::Collection,
+    log::raft::{AppliedId, LogIndex, RaftId, TermId},
+    serialize::{key::LogKey, StoreSerialize},
+    ColumnFamily, Direction, DocumentId, JMAPStore, Store,
+};
+
+use crate::cluster::log::{
+    update_apply::RaftStoreApplyUpdate, DocumentUpdate, PendingUpdate, PendingUpdates,
+};
+
+const ACCOUNT_ID: u32 = 1;
+
+fn stage_update<T>(store: &JMAPStore<T>, index: LogIndex, seq_id: LogIndex, update: DocumentUpdate)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    store
+        .db
+        .set(
+            ColumnFamily::Logs,
+            &LogKey::serialize_pending_update(index, seq_id),
+            &PendingUpdates::new(vec![
+                PendingUpdate::Begin {
+                    account_id: ACCOUNT_ID,
+                    collection: Collection::Mailbox,
+                },
+                PendingUpdate::Update { update },
+            ])
+            .serialize()
+            .unwrap(),
+        )
+        .unwrap();
+}
+
+fn mailbox(name: &str) -> Vec<u8> {
+    let mut mailbox = TinyORM::<Mailbox>::new();
+    mailbox.set(
+        Property::Name,
+        Value::Text {
+            value: name.to_string(),
+        },
+    );
+    mailbox.set(Property::ParentId, Value::Id { value: 0u64.into() });
+    mailbox.serialize().unwrap()
+}
+
+fn insert(document_id: DocumentId, name: &str) -> DocumentUpdate {
+    DocumentUpdate::Insert {
+        jmap_id: document_id as u64,
+        fields: mailbox(name),
+        blobs: vec![],
+        term_index: None,
+        annotations: vec![],
+    }
+}
+
+fn update(document_id: DocumentId, name: &str) -> DocumentUpdate {
+    DocumentUpdate::Update {
+        jmap_id: document_id as u64,
+        fields: mailbox(name),
+        annotations: vec![],
+    }
+}
+
+fn mailbox_name<T>(store: &JMAPStore<T>, document_id: DocumentId) -> String
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    match store
+        .get_orm::<Mailbox>(ACCOUNT_ID, document_id)
+        .unwrap()
+        .unwrap()
+        .get(&Property::Name)
+    {
+        Some(Value::Text { value }) => value.to_string(),
+        other => panic!("Unexpected name {:?}", other),
+    }
+}
+
+fn apply<T>(store: &JMAPStore<T>, apply_up_to: LogIndex)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    apply_term(store, apply_up_to, 1);
+}
+
+fn apply_term<T>(store: &JMAPStore<T>, apply_up_to: LogIndex, term: TermId)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let mut log_batch = Vec::new();
+    store
+        .apply_pending_updates(apply_up_to, false, term, &mut log_batch)
+        .unwrap();
+    assert!(log_batch.is_empty());
+}
+
+fn assert_no_pending<T>(store: &JMAPStore<T>, up_to: LogIndex)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    for (key, _) in store
+        .db
+        .iterator(
+            ColumnFamily::Logs,
+            &[LogKey::PENDING_UPDATES_KEY_PREFIX],
+            Direction::Forward,
+        )
+        .unwrap()
+    {
+        if !key.starts_with(&[LogKey::PENDING_UPDATES_KEY_PREFIX]) {
+            break;
+        }
+        assert!(
+            key[1..9] > up_to.to_be_bytes()[..],
+            "Pending update {:?} was not removed.",
+            key
+        );
+    }
+}
+
+pub fn test<T>(store: Arc<JMAPStore<T>>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Apply the first two entries
+    stage_update(&store, 0, 0, insert(0, "Inbox"));
+    stage_update(&store, 1, 1, insert(1, "Sent"));
+    apply(&store, 1);
+    assert_no_pending(&store, 1);
+    assert_eq!(
+        store.get_last_applied().unwrap(),
+        Some(AppliedId::new(RaftId::new(1, 1), 1))
+    );
+
+    // Replay an overlapping range, the entries already applied are skipped
+    stage_update(&store, 0, 0, insert(0, "Replayed Inbox"));
+    stage_update(&store, 1, 1, update(1, "Replayed Sent"));
+    stage_update(&store, 2, 2, insert(2, "Drafts"));
+    stage_update(&store, 2, 3, update(0, "Archive"));
+    apply(&store, 2);
+    assert_no_pending(&store, 2);
+    assert_eq!(mailbox_name(&store, 0), "Archive");
+    assert_eq!(mailbox_name(&store, 1), "Sent");
+    assert_eq!(mailbox_name(&store, 2), "Drafts");
+    assert_eq!(
+        store
+            .get_document_ids(ACCOUNT_ID, Collection::Mailbox)
+            .unwrap()
+            .unwrap()
+            .len(),
+        3
+    );
+
+    // Replaying the whole range again changes nothing
+    stage_update(&store, 1, 1, update(1, "Replayed Sent"));
+    stage_update(&store, 2, 2, update(2, "Replayed Drafts"));
+    stage_update(&store, 2, 3, update(0, "Replayed Archive"));
+    apply(&store, 2);
+    assert_no_pending(&store, 2);
+    assert_eq!(mailbox_name(&store, 0), "Archive");
+    assert_eq!(mailbox_name(&store, 1), "Sent");
+    assert_eq!(mailbox_name(&store, 2), "Drafts");
+    assert_eq!(
+        store.get_last_applied().unwrap(),
+        Some(AppliedId::new(RaftId::new(1, 2), 3))
+    );
+
+    // An entry from a newer term at an applied index replaced it and is applied
+    stage_update(&store, 2, 0, update(2, "Outbox"));
+    apply_term(&store, 2, 2);
+    assert_no_pending(&store, 2);
+    assert_eq!(mailbox_name(&store, 2), "Outbox");
+    assert_eq!(
+        store.get_last_applied().unwrap(),
+        Some(AppliedId::new(RaftId::new(2, 2), 0))
+    );
+    assert!(!AppliedId::new(RaftId::new(2, 2), 0).includes(&AppliedId::new(RaftId::new(1, 2), 0)));
+    assert!(AppliedId::new(RaftId::new(2, 2), 0).includes(&AppliedId::new(RaftId::new(1, 1), 5)));
+    assert!(!AppliedId::new(RaftId::new(1, 2), 0).includes(&AppliedId::new(RaftId::new(2, 1), 0)));
+
+    // Entries after a rollback point are applied again
+    let mut ops = Vec::new();
+    store.rewind_last_applied(1, &mut ops).unwrap();
+    store.db.write(ops).unwrap();
+    stage_update(&store, 2, 0, update(2, "Templates"));
+    apply(&store, 2);
+    assert_no_pending(&store, 2);
+    assert_eq!(mailbox_name(&store, 2), "Templates");
+}
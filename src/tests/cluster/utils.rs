@@ -43,7 +43,8 @@ use store::{
     log::raft::RaftId,
     parking_lot::Mutex,
     rand::{self, Rng},
-    AccountId, Store,
+    serialize::key::LogKey,
+    AccountId, ColumnFamily, Direction, JMAPStore, Store,
 };
 use tokio::{sync::oneshot, time::sleep};
 
@@ -346,7 +347,8 @@ impl Clients {
             .account_id(JMAPId::from(account_id).to_string())
             .update(&mailbox_id)
             .name(format!("Mailbox {}/{}", id1, id2))
-            .sort_order(id2);
+            .sort_order(id2)
+            .is_subscribed(id2 % 2 == 0);
 
         request
             .send_set_mailbox()
@@ -643,6 +645,7 @@ pub async fn assert_mirrored_stores<T>(
                             }
                         }
                         assert!(follower.is_up_to_date());
+                        assert_no_staged_changes(&follower.store);
                         println!(
                             "Comparing leader {} with follower {}.",
                             leader_pos + 1,
@@ -672,6 +675,26 @@ pub async fn assert_mirrored_stores<T>(
     .unwrap();
 }
 
+// Once a follower is up to date, the changes it received from the leader have
+// either been published to the changelog or discarded along with their entry.
+pub fn assert_no_staged_changes<T>(store: &JMAPStore<T>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let staged_keys = store
+        .db
+        .iterator(
+            ColumnFamily::Logs,
+            &[LogKey::STAGED_CHANGE_KEY_PREFIX],
+            Direction::Forward,
+        )
+        .unwrap()
+        .take_while(|(key, _)| key.starts_with(&[LogKey::STAGED_CHANGE_KEY_PREFIX]))
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>();
+    assert!(staged_keys.is_empty(), "Staged changes: {:?}", staged_keys);
+}
+
 pub async fn shutdown_all<T>(peers: Arc<Vec<web::Data<JMAPServer<T>>>>)
 where
     T: for<'x> Store<'x> + 'static,
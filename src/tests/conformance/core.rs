@@ -0,0 +1,275 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use futures::future::join_all;
+use jmap::types::jmap::JMAPId;
+use reqwest::StatusCode;
+use serde_json::json;
+
+use super::{ConformanceClient, USING};
+
+// RFC 8620, Sections 3 to 5
+pub async fn test(client: &ConformanceClient) {
+    println!("Running core conformance tests...");
+
+    // Core/echo returns its arguments unchanged
+    let arguments = json!({"hello": true, "high": 5, "list": ["a", {"b": null}]});
+    assert_eq!(client.call("Core/echo", arguments.clone()).await, arguments);
+
+    // Request level errors
+    for (body, error_type) in [
+        ("{\"using\": [", "urn:ietf:params:jmap:error:notJSON"),
+        ("[1, 2, 3]", "urn:ietf:params:jmap:error:notRequest"),
+        (
+            "{\"using\": [], \"methodCalls\": {}}",
+            "urn:ietf:params:jmap:error:notRequest",
+        ),
+    ] {
+        let (status, response) = client.post(body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+        assert_eq!(response["type"], error_type, "{}: {}", body, response);
+        assert_eq!(response["status"], 400, "{}", response);
+    }
+    let max_calls = client.core_capability("maxCallsInRequest");
+    let (status, response) = client
+        .post(
+            json!({
+                "using": USING,
+                "methodCalls": (0..=max_calls)
+                    .map(|n| json!(["Core/echo", {}, format!("c{}", n)]))
+                    .collect::<Vec<_>>(),
+            })
+            .to_string(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(response["type"], "urn:ietf:params:jmap:error:limit");
+    assert_eq!(response["limit"], "maxCallsInRequest");
+
+    // The maximum number of calls is accepted
+    assert_eq!(
+        client
+            .request(
+                (0..max_calls)
+                    .map(|n| json!(["Core/echo", {}, format!("c{}", n)]))
+                    .collect::<Vec<_>>()
+                    .into(),
+            )
+            .await
+            .len(),
+        max_calls
+    );
+
+    // Method level errors do not abort the remaining calls
+    let response = client
+        .request(json!([
+            ["Unknown/method", {}, "c0"],
+            ["Mailbox/get", {"accountId": client.account_id, "ids": []}, "c1"],
+            ["Core/echo", {"ok": true}, "c2"]
+        ]))
+        .await;
+    assert_eq!(response.len(), 3);
+    assert_eq!(response[0], json!(["error", response[0][1], "c0"]));
+    assert_eq!(response[0][1]["type"], "unknownMethod");
+    assert_eq!(response[1][0], "Mailbox/get");
+    assert_eq!(response[1][1]["list"], json!([]));
+    assert_eq!(response[2], json!(["Core/echo", {"ok": true}, "c2"]));
+
+    // Accessing an account without permission
+    let error_type = client
+        .call_error(
+            "Mailbox/get",
+            json!({"accountId": JMAPId::new(u32::MAX as u64 - 1)}),
+        )
+        .await;
+    assert!(
+        ["accountNotFound", "forbidden"].contains(&error_type.as_str()),
+        "{}",
+        error_type
+    );
+
+    // Requesting more objects than allowed
+    let max_objects = client.core_capability("maxObjectsInGet");
+    assert_eq!(
+        client
+            .call_error(
+                "Mailbox/get",
+                json!({
+                    "accountId": client.account_id,
+                    "ids": vec![client.account_id.clone(); max_objects + 1],
+                }),
+            )
+            .await,
+        "requestTooLarge"
+    );
+    let max_objects = client.core_capability("maxObjectsInSet");
+    assert_eq!(
+        client
+            .call_error(
+                "Mailbox/set",
+                json!({
+                    "accountId": client.account_id,
+                    "destroy": vec![client.account_id.clone(); max_objects + 1],
+                }),
+            )
+            .await,
+        "requestTooLarge"
+    );
+
+    // Back-references
+    let response = client
+        .request(json!([
+            ["Mailbox/query", {"accountId": client.account_id}, "q"],
+            ["Mailbox/get", {
+                "accountId": client.account_id,
+                "#ids": {"resultOf": "q", "name": "Mailbox/query", "path": "/ids"},
+                "properties": ["id"]
+            }, "g"],
+            ["Mailbox/get", {
+                "accountId": client.account_id,
+                "#ids": {"resultOf": "missing", "name": "Mailbox/query", "path": "/ids"}
+            }, "e1"],
+            ["Mailbox/get", {
+                "accountId": client.account_id,
+                "#ids": {"resultOf": "q", "name": "Mailbox/get", "path": "/ids"}
+            }, "e2"]
+        ]))
+        .await;
+    let query_ids = &response[0][1]["ids"];
+    assert_eq!(
+        response[1][1]["list"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["id"].clone())
+            .collect::<Vec<_>>(),
+        query_ids.as_array().unwrap().clone(),
+        "{:?}",
+        response
+    );
+    for response in &response[2..] {
+        assert_eq!(response[0], "error", "{}", response);
+        assert_eq!(
+            response[1]["type"], "invalidResultReference",
+            "{}",
+            response
+        );
+    }
+
+    // Creation ids can be referenced by later calls and are returned in createdIds
+    let (status, response) = client
+        .post(
+            json!({
+                "using": USING,
+                "methodCalls": [
+                    ["Mailbox/set", {
+                        "accountId": client.account_id,
+                        "create": {"parent": {"name": "Conformance parent"}}
+                    }, "c0"],
+                    ["Mailbox/set", {
+                        "accountId": client.account_id,
+                        "create": {"child": {"name": "Conformance child", "parentId": "#parent"}}
+                    }, "c1"]
+                ],
+                "createdIds": {}
+            })
+            .to_string(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let parent_id = response["methodResponses"][0][1]["created"]["parent"]["id"].clone();
+    let child_id = response["methodResponses"][1][1]["created"]["child"]["id"].clone();
+    assert!(
+        parent_id.is_string() && child_id.is_string(),
+        "{}",
+        response
+    );
+    assert_eq!(response["createdIds"]["parent"], parent_id, "{}", response);
+    assert_eq!(response["createdIds"]["child"], child_id, "{}", response);
+    let mailbox = client
+        .call(
+            "Mailbox/get",
+            json!({"accountId": client.account_id, "ids": [child_id]}),
+        )
+        .await;
+    assert_eq!(mailbox["list"][0]["parentId"], parent_id);
+    let response = client
+        .call(
+            "Mailbox/set",
+            json!({
+                "accountId": client.account_id,
+                "destroy": [child_id, parent_id]
+            }),
+        )
+        .await;
+    assert_eq!(
+        response["destroyed"].as_array().unwrap().len(),
+        2,
+        "{}",
+        response
+    );
+
+    // State mismatch
+    assert_eq!(
+        client
+            .call_error(
+                "Mailbox/set",
+                json!({
+                    "accountId": client.account_id,
+                    "ifInState": "n",
+                    "create": {"a": {"name": "Conformance"}}
+                }),
+            )
+            .await,
+        "stateMismatch"
+    );
+
+    // Concurrent requests either succeed or fail with a limit error
+    let max_concurrent = client.core_capability("maxConcurrentRequests");
+    let results = join_all((0..max_concurrent * 2).map(|n| {
+        client.post(
+            json!({
+                "using": USING,
+                "methodCalls": [["Core/echo", {"n": n}, "c0"]],
+            })
+            .to_string(),
+        )
+    }))
+    .await;
+    let mut num_success = 0;
+    for (status, response) in results {
+        match status {
+            StatusCode::OK => {
+                assert_eq!(response["methodResponses"][0][0], "Core/echo");
+                num_success += 1;
+            }
+            StatusCode::BAD_REQUEST => {
+                assert_eq!(response["type"], "urn:ietf:params:jmap:error:limit");
+                assert_eq!(response["limit"], "maxConcurrentRequests");
+            }
+            StatusCode::TOO_MANY_REQUESTS => (),
+            _ => panic!("Unexpected response {}: {}", status, response),
+        }
+    }
+    assert!(num_success > 0);
+}
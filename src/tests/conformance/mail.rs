@@ -0,0 +1,446 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use jmap::types::jmap::JMAPId;
+use reqwest::StatusCode;
+use serde_json::json;
+
+use super::ConformanceClient;
+
+const TEST_MESSAGE: &str = concat!(
+    "From: Conformance Sender <sender@example.org>\r\n",
+    "To: Conformance Recipient <rcpt@example.org>\r\n",
+    "Subject: Conformance test message\r\n",
+    "Message-ID: <conformance-1@example.org>\r\n",
+    "Date: Sat, 1 Jan 2022 00:00:00 +0000\r\n",
+    "Content-Type: text/plain; charset=utf-8\r\n",
+    "\r\n",
+    "This is a conformance test message about zebras.\r\n"
+);
+
+// RFC 8621
+pub async fn test(client: &ConformanceClient) {
+    println!("Running mail conformance tests...");
+    let account_id = client.account_id.as_str();
+    let unknown_id = JMAPId::new(u32::MAX as u64 - 1).to_string();
+
+    // Mailboxes
+    let response = client
+        .call("Mailbox/get", json!({"accountId": account_id}))
+        .await;
+    let mailbox_state = response["state"].clone();
+    let inbox_id = response["list"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|mailbox| mailbox["role"] == "inbox")
+        .unwrap_or_else(|| panic!("No inbox found: {}", response))["id"]
+        .clone();
+    for property in [
+        "name",
+        "parentId",
+        "role",
+        "sortOrder",
+        "totalEmails",
+        "unreadEmails",
+        "totalThreads",
+        "unreadThreads",
+        "myRights",
+        "isSubscribed",
+    ] {
+        assert!(
+            response["list"][0].get(property).is_some(),
+            "Missing {}: {}",
+            property,
+            response
+        );
+    }
+    assert_eq!(
+        client
+            .call(
+                "Mailbox/query",
+                json!({"accountId": account_id, "filter": {"role": "inbox"}}),
+            )
+            .await["ids"],
+        json!([inbox_id])
+    );
+
+    let response = client
+        .call(
+            "Mailbox/set",
+            json!({
+                "accountId": account_id,
+                "create": {"m": {"name": "Conformance"}}
+            }),
+        )
+        .await;
+    let mailbox_id = response["created"]["m"]["id"].clone();
+    assert!(mailbox_id.is_string(), "{}", response);
+    assert_eq!(response["oldState"], mailbox_state);
+    let response = client
+        .call(
+            "Mailbox/changes",
+            json!({"accountId": account_id, "sinceState": mailbox_state}),
+        )
+        .await;
+    assert_eq!(response["created"], json!([mailbox_id]), "{}", response);
+    assert_eq!(response["hasMoreChanges"], false, "{}", response);
+
+    // Invalid properties are reported per object
+    let response = client
+        .call(
+            "Mailbox/set",
+            json!({
+                "accountId": account_id,
+                "create": {"m": {"name": ""}},
+                "update": {inbox_id.as_str().unwrap(): {"parentId": unknown_id}}
+            }),
+        )
+        .await;
+    assert_eq!(response["notCreated"]["m"]["type"], "invalidProperties");
+    assert!(response["notUpdated"][inbox_id.as_str().unwrap()]["type"].is_string());
+
+    // Upload and download
+    let upload = client
+        .upload(TEST_MESSAGE.as_bytes().to_vec(), "message/rfc822")
+        .await;
+    assert_eq!(upload["accountId"], account_id);
+    assert_eq!(upload["type"], "message/rfc822");
+    assert_eq!(upload["size"], TEST_MESSAGE.len());
+    let blob_id = upload["blobId"].as_str().unwrap().to_string();
+    let (status, contents) = client.download(&blob_id).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(contents, TEST_MESSAGE.as_bytes());
+
+    // Email/parse
+    let response = client
+        .call(
+            "Email/parse",
+            json!({
+                "accountId": account_id,
+                "blobIds": [blob_id],
+                "properties": ["subject", "messageId"]
+            }),
+        )
+        .await;
+    assert_eq!(
+        response["parsed"][&blob_id]["subject"],
+        "Conformance test message"
+    );
+    assert_eq!(
+        response["parsed"][&blob_id]["messageId"],
+        json!(["conformance-1@example.org"])
+    );
+
+    // Email/import
+    let email_state = client
+        .call("Email/get", json!({"accountId": account_id, "ids": []}))
+        .await["state"]
+        .clone();
+    let response = client
+        .call(
+            "Email/import",
+            json!({
+                "accountId": account_id,
+                "emails": {"e": {
+                    "blobId": blob_id,
+                    "mailboxIds": {mailbox_id.as_str().unwrap(): true},
+                    "keywords": {"$seen": true},
+                    "receivedAt": "2022-01-01T00:00:00Z"
+                }}
+            }),
+        )
+        .await;
+    let email = &response["created"]["e"];
+    let email_id = email["id"].as_str().unwrap().to_string();
+    let thread_id = email["threadId"].as_str().unwrap().to_string();
+    assert_eq!(email["blobId"], blob_id);
+    assert_eq!(email["size"], TEST_MESSAGE.len());
+    assert_eq!(response["oldState"], email_state);
+
+    // Email/get
+    let response = client
+        .call(
+            "Email/get",
+            json!({
+                "accountId": account_id,
+                "ids": [email_id],
+                "properties": [
+                    "id", "blobId", "threadId", "mailboxIds", "keywords", "size",
+                    "receivedAt", "from", "to", "subject", "sentAt", "textBody",
+                    "bodyValues", "preview", "hasAttachment", "header:Subject:asText"
+                ],
+                "fetchTextBodyValues": true
+            }),
+        )
+        .await;
+    let email = &response["list"][0];
+    assert_eq!(email["id"], email_id);
+    assert_eq!(email["threadId"], thread_id);
+    assert_eq!(
+        email["mailboxIds"],
+        json!({mailbox_id.as_str().unwrap(): true})
+    );
+    assert_eq!(email["keywords"], json!({"$seen": true}));
+    assert_eq!(email["receivedAt"], "2022-01-01T00:00:00Z");
+    assert_eq!(
+        email["from"],
+        json!([{"name": "Conformance Sender", "email": "sender@example.org"}])
+    );
+    assert_eq!(email["subject"], "Conformance test message");
+    assert_eq!(email["header:Subject:asText"], "Conformance test message");
+    assert_eq!(email["hasAttachment"], false);
+    let part_id = email["textBody"][0]["partId"].as_str().unwrap();
+    assert!(email["bodyValues"][part_id]["value"]
+        .as_str()
+        .unwrap()
+        .starts_with("This is a conformance test message about zebras."));
+    assert!(email["preview"]
+        .as_str()
+        .unwrap()
+        .starts_with("This is a conformance test message"));
+    let response = client
+        .call(
+            "Email/get",
+            json!({"accountId": account_id, "ids": [unknown_id]}),
+        )
+        .await;
+    assert_eq!(response["notFound"], json!([unknown_id]));
+
+    // Email/query
+    let response = client
+        .call(
+            "Email/query",
+            json!({
+                "accountId": account_id,
+                "filter": {"operator": "AND", "conditions": [
+                    {"inMailbox": mailbox_id},
+                    {"text": "zebras"}
+                ]},
+                "sort": [{"property": "receivedAt", "isAscending": false}],
+                "calculateTotal": true
+            }),
+        )
+        .await;
+    assert_eq!(response["ids"], json!([email_id]), "{}", response);
+    assert_eq!(response["total"], 1);
+    assert_eq!(response["position"], 0);
+    assert!(response["queryState"].is_string());
+
+    // Thread/get and SearchSnippet/get
+    let response = client
+        .call(
+            "Thread/get",
+            json!({"accountId": account_id, "ids": [thread_id]}),
+        )
+        .await;
+    assert_eq!(response["list"][0]["emailIds"], json!([email_id]));
+    assert!(response["list"][0].get("participants").is_none());
+
+    // Thread previews (urn:stalwart:params:jmap:threadpreview)
+    assert!(
+        client.session["capabilities"]["urn:stalwart:params:jmap:threadpreview"].is_object(),
+        "Missing thread preview capability: {}",
+        client.session
+    );
+    let response = client
+        .call(
+            "Thread/get",
+            json!({
+                "accountId": account_id,
+                "ids": [thread_id],
+                "properties": ["emailIds", "participants", "receivedAt", "unreadCount", "hasAttachment"]
+            }),
+        )
+        .await;
+    let thread = &response["list"][0];
+    assert_eq!(thread["emailIds"], json!([email_id]), "{}", response);
+    assert_eq!(
+        thread["participants"],
+        json!([{"name": "Conformance Sender", "email": "sender@example.org"}])
+    );
+    assert_eq!(thread["receivedAt"], "2022-01-01T00:00:00Z");
+    assert_eq!(thread["unreadCount"], 0);
+    assert_eq!(thread["hasAttachment"], false);
+    let response = client
+        .call(
+            "SearchSnippet/get",
+            json!({
+                "accountId": account_id,
+                "emailIds": [email_id],
+                "filter": {"text": "zebras"}
+            }),
+        )
+        .await;
+    assert_eq!(response["list"][0]["emailId"], email_id);
+    assert!(response["list"][0]["preview"]
+        .as_str()
+        .unwrap()
+        .contains("<mark>zebras</mark>"));
+
+    // Email/set and Email/changes
+    let response = client
+        .call(
+            "Email/set",
+            json!({
+                "accountId": account_id,
+                "update": {
+                    &email_id: {"keywords/$flagged": true},
+                    &unknown_id: {"keywords/$flagged": true}
+                }
+            }),
+        )
+        .await;
+    assert!(response["updated"].get(&email_id).is_some(), "{}", response);
+    assert_eq!(response["notUpdated"][&unknown_id]["type"], "notFound");
+    let response = client
+        .call(
+            "Email/changes",
+            json!({"accountId": account_id, "sinceState": email_state}),
+        )
+        .await;
+    assert_eq!(response["created"], json!([email_id]), "{}", response);
+    assert_eq!(response["destroyed"], json!([]), "{}", response);
+
+    // Private annotations (urn:stalwart:params:jmap:annotations)
+    let max_size = client.session["capabilities"]["urn:stalwart:params:jmap:annotations"]
+        ["maxSizeAnnotations"]
+        .as_u64()
+        .unwrap_or_else(|| panic!("Missing annotations capability: {}", client.session));
+    let email_state = client
+        .call(
+            "Email/get",
+            json!({"accountId": account_id, "ids": [], "properties": ["id"]}),
+        )
+        .await["state"]
+        .clone();
+    let response = client
+        .call(
+            "Email/set",
+            json!({
+                "accountId": account_id,
+                "update": {
+                    &email_id: {"annotations": {"snooze": "2022-01-02T00:00:00Z", "note": "zebras"}}
+                }
+            }),
+        )
+        .await;
+    assert!(response["updated"].get(&email_id).is_some(), "{}", response);
+    let response = client
+        .call(
+            "Email/set",
+            json!({
+                "accountId": account_id,
+                "update": {
+                    &email_id: {"annotations/snooze": null, "annotations/color": "red"}
+                }
+            }),
+        )
+        .await;
+    assert!(response["updated"].get(&email_id).is_some(), "{}", response);
+    let response = client
+        .call(
+            "Email/get",
+            json!({
+                "accountId": account_id,
+                "ids": [email_id],
+                "properties": ["annotations"]
+            }),
+        )
+        .await;
+    assert_eq!(
+        response["list"][0]["annotations"],
+        json!({"note": "zebras", "color": "red"}),
+        "{}",
+        response
+    );
+    assert_eq!(
+        response["state"], email_state,
+        "Annotations must not change the Email state: {}",
+        response
+    );
+    let response = client
+        .call(
+            "Email/set",
+            json!({
+                "accountId": account_id,
+                "update": {
+                    &email_id: {"annotations/note": "z".repeat(max_size as usize + 1)}
+                }
+            }),
+        )
+        .await;
+    assert_eq!(
+        response["notUpdated"][&email_id]["type"], "tooLarge",
+        "{}",
+        response
+    );
+
+    // Mailboxes holding messages can only be destroyed on request
+    let response = client
+        .call(
+            "Mailbox/set",
+            json!({"accountId": account_id, "destroy": [mailbox_id]}),
+        )
+        .await;
+    assert_eq!(
+        response["notDestroyed"][mailbox_id.as_str().unwrap()]["type"],
+        "mailboxHasEmail"
+    );
+
+    // Other mail related objects
+    let response = client
+        .call("Identity/get", json!({"accountId": account_id}))
+        .await;
+    assert!(response["list"].is_array(), "{}", response);
+    let response = client
+        .call("VacationResponse/get", json!({"accountId": account_id}))
+        .await;
+    for vacation_response in response["list"].as_array().unwrap() {
+        assert_eq!(vacation_response["id"], "singleton");
+    }
+    let response = client
+        .call("EmailSubmission/query", json!({"accountId": account_id}))
+        .await;
+    assert!(response["ids"].is_array(), "{}", response);
+
+    // Cleanup
+    let response = client
+        .call(
+            "Mailbox/set",
+            json!({
+                "accountId": account_id,
+                "destroy": [mailbox_id],
+                "onDestroyRemoveEmails": true
+            }),
+        )
+        .await;
+    assert_eq!(response["destroyed"], json!([mailbox_id]), "{}", response);
+    let response = client
+        .call(
+            "Email/get",
+            json!({"accountId": account_id, "ids": [email_id]}),
+        )
+        .await;
+    assert_eq!(response["notFound"], json!([email_id]), "{}", response);
+}
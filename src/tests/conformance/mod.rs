@@ -0,0 +1,249 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::Duration;
+
+use jmap::{types::jmap::JMAPId, SUPERUSER_ID};
+use reqwest::{header, StatusCode};
+use serde_json::{json, Value};
+use store_rocksdb::RocksDB;
+
+use super::{jmap::init_jmap_tests, store::utils::destroy_temp_dir};
+
+pub mod core;
+pub mod mail;
+pub mod session;
+
+const USING: &[&str] = &[
+    "urn:ietf:params:jmap:core",
+    "urn:ietf:params:jmap:mail",
+    "urn:ietf:params:jmap:submission",
+    "urn:ietf:params:jmap:vacationresponse",
+];
+
+/// Minimal JMAP client speaking raw JSON over HTTP, so that the suite
+/// validates the wire format rather than the behaviour of a client library.
+pub struct ConformanceClient {
+    pub http: reqwest::Client,
+    pub base_url: String,
+    pub authorization: String,
+    pub session: Value,
+    pub account_id: String,
+}
+
+impl ConformanceClient {
+    pub async fn connect(base_url: &str, username: &str, secret: &str) -> Self {
+        let base_url = base_url.trim_end_matches('/').to_string();
+        let mut client = ConformanceClient {
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .danger_accept_invalid_certs(true)
+                .build()
+                .unwrap_or_default(),
+            authorization: format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, secret))
+            ),
+            base_url,
+            session: Value::Null,
+            account_id: String::new(),
+        };
+
+        let response = client
+            .http
+            .get(format!("{}/.well-known/jmap", client.base_url))
+            .header(header::AUTHORIZATION, &client.authorization)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        client.session = response.json::<Value>().await.unwrap();
+        client.account_id = client.session["primaryAccounts"]["urn:ietf:params:jmap:mail"]
+            .as_str()
+            .expect("Session has no primary mail account.")
+            .to_string();
+        client
+    }
+
+    pub fn core_capability(&self, name: &str) -> usize {
+        self.session["capabilities"]["urn:ietf:params:jmap:core"][name]
+            .as_u64()
+            .unwrap_or_else(|| panic!("Missing core capability {}", name)) as usize
+    }
+
+    pub fn api_url(&self) -> &str {
+        self.session["apiUrl"].as_str().unwrap()
+    }
+
+    /// Posts a raw body to the API endpoint and returns the status and JSON response.
+    pub async fn post(&self, body: impl Into<reqwest::Body>) -> (StatusCode, Value) {
+        let response = self
+            .http
+            .post(self.api_url())
+            .header(header::AUTHORIZATION, &self.authorization)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        let status = response.status();
+        (
+            status,
+            response.json::<Value>().await.unwrap_or(Value::Null),
+        )
+    }
+
+    /// Sends a list of method calls and returns the method responses.
+    pub async fn request(&self, method_calls: Value) -> Vec<Value> {
+        let (status, mut response) = self
+            .post(
+                json!({
+                    "using": USING,
+                    "methodCalls": method_calls,
+                })
+                .to_string(),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", response);
+        assert!(response["sessionState"].is_string(), "{}", response);
+        match response["methodResponses"].take() {
+            Value::Array(responses) => responses,
+            _ => panic!("Invalid response: {}", response),
+        }
+    }
+
+    /// Sends a single method call and returns its arguments, asserting success.
+    pub async fn call(&self, method: &str, arguments: Value) -> Value {
+        let mut response = self.request(json!([[method, arguments, "c0"]])).await;
+        assert_eq!(response.len(), 1, "{:?}", response);
+        let mut response = response.pop().unwrap();
+        assert_eq!(response[0], method, "{}", response);
+        assert_eq!(response[2], "c0", "{}", response);
+        response[1].take()
+    }
+
+    /// Sends a single method call that is expected to fail, returning the error type.
+    pub async fn call_error(&self, method: &str, arguments: Value) -> String {
+        let mut response = self.request(json!([[method, arguments, "c0"]])).await;
+        let response = response.pop().unwrap();
+        assert_eq!(response[0], "error", "{}", response);
+        response[1]["type"].as_str().unwrap().to_string()
+    }
+
+    pub async fn upload(&self, contents: Vec<u8>, content_type: &str) -> Value {
+        let response = self
+            .http
+            .post(
+                self.session["uploadUrl"]
+                    .as_str()
+                    .unwrap()
+                    .replace("{accountId}", &self.account_id),
+            )
+            .header(header::AUTHORIZATION, &self.authorization)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(contents)
+            .send()
+            .await
+            .unwrap();
+        assert!(
+            [StatusCode::OK, StatusCode::CREATED].contains(&response.status()),
+            "{}",
+            response.status()
+        );
+        response.json::<Value>().await.unwrap()
+    }
+
+    pub async fn download(&self, blob_id: &str) -> (StatusCode, Vec<u8>) {
+        let response = self
+            .http
+            .get(
+                self.session["downloadUrl"]
+                    .as_str()
+                    .unwrap()
+                    .replace("{accountId}", &self.account_id)
+                    .replace("{blobId}", blob_id)
+                    .replace("{name}", "blob")
+                    .replace("{type}", "application/octet-stream"),
+            )
+            .header(header::AUTHORIZATION, &self.authorization)
+            .send()
+            .await
+            .unwrap();
+        (
+            response.status(),
+            response.bytes().await.unwrap_or_default().to_vec(),
+        )
+    }
+}
+
+/// Runs the RFC 8620/8621 conformance suite. Operators can validate a live
+/// deployment by setting JMAP_CONFORMANCE_URL, JMAP_CONFORMANCE_USER and
+/// JMAP_CONFORMANCE_SECRET, otherwise the suite runs against a spawned server.
+/// The account used must be dedicated to testing, as the suite creates and
+/// destroys mailboxes and messages.
+#[actix_web::test]
+#[ignore]
+async fn jmap_conformance_tests() {
+    if let Ok(base_url) = std::env::var("JMAP_CONFORMANCE_URL") {
+        let client = ConformanceClient::connect(
+            &base_url,
+            &std::env::var("JMAP_CONFORMANCE_USER").expect("Missing JMAP_CONFORMANCE_USER."),
+            &std::env::var("JMAP_CONFORMANCE_SECRET").expect("Missing JMAP_CONFORMANCE_SECRET."),
+        )
+        .await;
+        run(&client).await;
+        return;
+    }
+
+    let (server, mut admin_client, temp_dir) =
+        init_jmap_tests::<RocksDB>("jmap_conformance_tests").await;
+
+    // Create a domain name and a test account
+    let domain_id = admin_client
+        .set_default_account_id(JMAPId::new(SUPERUSER_ID as u64))
+        .domain_create("example.com")
+        .await
+        .unwrap()
+        .take_id();
+    let account_id = admin_client
+        .individual_create("jdoe@example.com", "12345", "John Doe")
+        .await
+        .unwrap()
+        .take_id();
+
+    let client =
+        ConformanceClient::connect(server.base_session.base_url(), "jdoe@example.com", "12345")
+            .await;
+    assert_eq!(client.account_id, account_id);
+    run(&client).await;
+
+    admin_client.principal_destroy(&account_id).await.unwrap();
+    admin_client.principal_destroy(&domain_id).await.unwrap();
+    destroy_temp_dir(&temp_dir);
+}
+
+pub async fn run(client: &ConformanceClient) {
+    session::test(client).await;
+    core::test(client).await;
+    mail::test(client).await;
+}
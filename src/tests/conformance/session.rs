@@ -0,0 +1,177 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use reqwest::{header, StatusCode};
+use serde_json::{json, Value};
+
+use super::ConformanceClient;
+
+// RFC 8620, Section 2
+pub async fn test(client: &ConformanceClient) {
+    println!("Running session conformance tests...");
+    let session = &client.session;
+
+    // Core capabilities
+    let core = &session["capabilities"]["urn:ietf:params:jmap:core"];
+    for name in [
+        "maxSizeUpload",
+        "maxConcurrentUpload",
+        "maxSizeRequest",
+        "maxConcurrentRequests",
+        "maxCallsInRequest",
+        "maxObjectsInGet",
+        "maxObjectsInSet",
+    ] {
+        assert!(
+            core[name].as_u64().map_or(false, |v| v > 0),
+            "Invalid core capability {}: {}",
+            name,
+            core
+        );
+    }
+    assert!(core["collationAlgorithms"].is_array(), "{}", core);
+    assert!(
+        session["capabilities"]["urn:ietf:params:jmap:mail"].is_object(),
+        "{}",
+        session
+    );
+
+    // Accounts
+    let account = &session["accounts"][&client.account_id];
+    assert!(account["name"].is_string(), "{}", session);
+    assert_eq!(account["isPersonal"], true, "{}", session);
+    assert!(account["isReadOnly"].is_boolean(), "{}", session);
+    let mail = &account["accountCapabilities"]["urn:ietf:params:jmap:mail"];
+    for name in [
+        "maxSizeMailboxName",
+        "maxSizeAttachmentsPerEmail",
+        "emailQuerySortOptions",
+        "mayCreateTopLevelMailbox",
+    ] {
+        assert!(
+            !mail[name].is_null(),
+            "Missing mail capability {}: {}",
+            name,
+            mail
+        );
+    }
+
+    // Resource URLs and their template variables
+    assert!(session["username"].is_string(), "{}", session);
+    assert!(session["state"].is_string(), "{}", session);
+    for (name, variables) in [
+        ("apiUrl", &[][..]),
+        (
+            "downloadUrl",
+            &["{accountId}", "{blobId}", "{type}", "{name}"][..],
+        ),
+        ("uploadUrl", &["{accountId}"][..]),
+        ("eventSourceUrl", &["{types}", "{closeafter}", "{ping}"][..]),
+    ] {
+        let url = session[name]
+            .as_str()
+            .unwrap_or_else(|| panic!("Missing {}: {}", name, session));
+        for variable in variables {
+            assert!(url.contains(variable), "{} lacks {}", url, variable);
+        }
+    }
+
+    // Unchanged session objects are validated with their ETag
+    let url = format!("{}/.well-known/jmap", client.base_url);
+    let response = client
+        .http
+        .get(&url)
+        .header(header::AUTHORIZATION, &client.authorization)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response.headers()[header::ETAG]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let response = client
+        .http
+        .get(&url)
+        .header(header::AUTHORIZATION, &client.authorization)
+        .header(header::IF_NONE_MATCH, &etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+    // Differential requests only list the accounts that changed
+    let response = client
+        .http
+        .get(format!("{}?updated={}", url, etag.trim_matches('"')))
+        .header(header::AUTHORIZATION, &client.authorization)
+        .send()
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    assert_eq!(
+        response["updatedFrom"],
+        etag.trim_matches('"'),
+        "{}",
+        response
+    );
+    assert_eq!(response["accounts"], json!({}), "{}", response);
+    assert_eq!(response["removedAccounts"], json!([]), "{}", response);
+    assert_eq!(response["state"], session["state"], "{}", response);
+
+    // Unknown ETags receive the full session object
+    let response = client
+        .http
+        .get(format!("{}?updated=unknown", url))
+        .header(header::AUTHORIZATION, &client.authorization)
+        .send()
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    assert!(response["updatedFrom"].is_null(), "{}", response);
+    assert_eq!(response["accounts"], session["accounts"], "{}", response);
+
+    // The session resource requires authentication
+    let response = client
+        .http
+        .get(format!("{}/.well-known/jmap", client.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(response.headers().contains_key(header::WWW_AUTHENTICATE));
+
+    // Wrong credentials are rejected
+    let response = client
+        .http
+        .get(session["apiUrl"].as_str().unwrap())
+        .basic_auth(session["username"].as_str().unwrap(), Some("!wrong!"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,93 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::Duration;
+
+use actix_web::web;
+use jmap_client::client::Client;
+use reqwest::{header, StatusCode};
+use store::Store;
+
+use crate::JMAPServer;
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, _client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running API error tests...");
+    let base_url = server.base_session.base_url();
+
+    for (path, status, error_type) in [
+        // Unknown routes
+        (
+            "/unknown",
+            StatusCode::NOT_FOUND,
+            "urn:stalwart:jmap:error:notFound",
+        ),
+        // Authentication is required
+        (
+            "/admin/traces",
+            StatusCode::UNAUTHORIZED,
+            "urn:stalwart:jmap:error:unauthorized",
+        ),
+        (
+            "/api/v1/admin/traces",
+            StatusCode::UNAUTHORIZED,
+            "urn:stalwart:jmap:error:unauthorized",
+        ),
+        // Extractor errors
+        (
+            "/api/v1/admin/report/!!!",
+            StatusCode::BAD_REQUEST,
+            "urn:stalwart:jmap:error:invalidParameters",
+        ),
+        (
+            "/auth/code?client_id=test",
+            StatusCode::BAD_REQUEST,
+            "urn:stalwart:jmap:error:invalidParameters",
+        ),
+    ] {
+        let response = reqwest::Client::builder()
+            .timeout(Duration::from_millis(500))
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap_or_default()
+            .get(format!("{}{}", base_url, path))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{}", path);
+        assert_eq!(
+            response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|h| h.to_str().ok()),
+            Some("application/problem+json"),
+            "{}",
+            path
+        );
+        let body = response.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(body["type"], error_type, "{}: {}", path, body);
+        assert_eq!(body["status"], status.as_u16(), "{}: {}", path, body);
+    }
+}
@@ -21,7 +21,11 @@
  * for more details.
 */
 
-use std::{fmt::Debug, sync::Arc, time::Duration};
+use std::{
+    fmt::Debug,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use actix_web::web;
 use jmap::{types::jmap::JMAPId, SUPERUSER_ID};
@@ -33,10 +37,15 @@ use jmap_client::{
     },
     mailbox::{self},
 };
-use jmap_sharing::principal::set::JMAPSetPrincipal;
+use jmap_sharing::principal::{
+    account::JMAPAccountStore,
+    otp::{base32_decode, totp_code, totp_generate_secret, JMAPAccountOtp, OtpError},
+    passkey::{JMAPAccountPasskey, Passkey},
+    set::JMAPSetPrincipal,
+};
 use store::Store;
 
-use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
+use crate::{authorization::impersonate::Scope, tests::store::utils::StoreCompareWith, JMAPServer};
 
 pub async fn test<T>(server: web::Data<JMAPServer<T>>, admin_client: &mut Client)
 where
@@ -219,6 +228,302 @@ where
         }))
     ));
 
+    // Enable two-factor authentication
+    let document_id = JMAPId::parse(&account_id).unwrap().get_document_id();
+    let secret = totp_generate_secret();
+    // Each code can be used once, codes for the next time step are accepted
+    // within the allowed skew.
+    let step = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 30;
+    let totp_at = |step: u64| format!("{:06}", totp_code(&base32_decode(&secret).unwrap(), step));
+    assert_eq!(
+        server
+            .store
+            .otp_enable(document_id, &secret, "000000x")
+            .unwrap(),
+        Err(OtpError::InvalidCode)
+    );
+    let recovery_codes = server
+        .store
+        .otp_enable(document_id, &secret, &totp_at(step))
+        .unwrap()
+        .unwrap();
+    assert_eq!(recovery_codes.len(), 10);
+
+    // The password alone is no longer enough
+    assert!(server
+        .store
+        .authenticate("jdoe@example.com", "12345")
+        .unwrap()
+        .is_none());
+    assert!(server
+        .store
+        .authenticate_interactive("jdoe@example.com", "12345", Some("123"))
+        .unwrap()
+        .is_none());
+    assert!(server
+        .store
+        .authenticate("jdoe@example.com", &format!("12345${}", totp_at(step)))
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        server
+            .store
+            .authenticate("jdoe@example.com", &format!("12345${}", totp_at(step + 1)))
+            .unwrap(),
+        Some(document_id)
+    );
+    assert!(server
+        .store
+        .authenticate_interactive("jdoe@example.com", "12345", Some(&totp_at(step + 1)))
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        server
+            .store
+            .authenticate_interactive("jdoe@example.com", "12345", Some(&recovery_codes[1]))
+            .unwrap(),
+        Some(document_id)
+    );
+
+    // Recovery codes can be used only once
+    let recovery_login = format!("12345${}", recovery_codes[0]);
+    assert_eq!(
+        server
+            .store
+            .authenticate("jdoe@example.com", &recovery_login)
+            .unwrap(),
+        Some(document_id)
+    );
+    assert!(server
+        .store
+        .authenticate("jdoe@example.com", &recovery_login)
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        server
+            .store
+            .otp_status(document_id)
+            .unwrap()
+            .recovery_codes_left,
+        8
+    );
+
+    // App passwords bypass 2FA on non-interactive logins only
+    assert_eq!(
+        server
+            .store
+            .app_password_create(document_id, "imap", None)
+            .unwrap(),
+        Err(OtpError::InvalidCode)
+    );
+    let app_password = server
+        .store
+        .app_password_create(document_id, "imap", Some(&recovery_codes[2]))
+        .unwrap()
+        .unwrap();
+    Client::new()
+        .credentials(Credentials::basic("jdoe@example.com", &app_password))
+        .connect(server.base_session.base_url())
+        .await
+        .unwrap();
+    assert!(server
+        .store
+        .authenticate_interactive("jdoe@example.com", &app_password, None)
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        server.store.otp_status(document_id).unwrap().app_passwords,
+        vec!["imap".to_string()]
+    );
+    server
+        .store
+        .app_password_revoke(document_id, "imap")
+        .unwrap()
+        .unwrap();
+    assert!(server
+        .store
+        .authenticate("jdoe@example.com", &app_password)
+        .unwrap()
+        .is_none());
+
+    // Registering a passkey also requires a second factor
+    let passkey = Passkey {
+        name: "laptop".to_string(),
+        credential_id: b"credential".to_vec(),
+        algorithm: -7,
+        public_key: vec![4; 65],
+        sign_count: 0,
+    };
+    assert_eq!(
+        server
+            .store
+            .passkey_add(document_id, passkey.clone(), None)
+            .unwrap(),
+        Err(OtpError::InvalidCode)
+    );
+    server
+        .store
+        .passkey_add(document_id, passkey.clone(), Some(&recovery_codes[3]))
+        .unwrap()
+        .unwrap();
+
+    // Disable 2FA
+    server
+        .store
+        .otp_disable(document_id, &recovery_codes[4])
+        .unwrap()
+        .unwrap();
+
+    // Passkeys are unique and listed by name
+    assert_eq!(
+        server
+            .store
+            .passkey_add(document_id, passkey.clone(), None)
+            .unwrap(),
+        Err(OtpError::InvalidName)
+    );
+    assert_eq!(
+        server
+            .store
+            .passkey_add(
+                document_id,
+                Passkey {
+                    name: "phone".to_string(),
+                    ..passkey.clone()
+                },
+                None
+            )
+            .unwrap(),
+        Err(OtpError::PasskeyExists)
+    );
+    assert_eq!(
+        server.store.otp_status(document_id).unwrap().passkeys,
+        vec!["laptop".to_string()]
+    );
+
+    // Signature counters have to increase, unless the authenticator has none
+    assert_eq!(
+        server
+            .store
+            .passkey_authenticate(document_id, b"credential", |_| Some(0))
+            .unwrap(),
+        Some(document_id)
+    );
+    assert_eq!(
+        server
+            .store
+            .passkey_authenticate(document_id, b"credential", |_| Some(3))
+            .unwrap(),
+        Some(document_id)
+    );
+    assert_eq!(
+        server.store.passkey_list(document_id).unwrap()[0].sign_count,
+        3
+    );
+    for (credential_id, sign_count) in [
+        (&b"credential"[..], Some(3)),
+        (&b"credential"[..], Some(0)),
+        (&b"credential"[..], None),
+        (&b"unknown"[..], Some(4)),
+    ] {
+        assert!(server
+            .store
+            .passkey_authenticate(document_id, credential_id, |_| sign_count)
+            .unwrap()
+            .is_none());
+    }
+
+    server
+        .store
+        .passkey_remove(document_id, "laptop")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        server.store.passkey_remove(document_id, "laptop").unwrap(),
+        Err(OtpError::NotFound)
+    );
+    assert_eq!(
+        server
+            .store
+            .authenticate("jdoe@example.com", "12345")
+            .unwrap(),
+        Some(document_id)
+    );
+
+    // Impersonation tokens are limited by their scope
+    let read_only_token = server
+        .issue_impersonation_token(
+            document_id,
+            &Scope::parse(SUPERUSER_ID, ["read-only"]).unwrap(),
+            60,
+        )
+        .await
+        .unwrap();
+    let client = Client::new()
+        .credentials(Credentials::bearer(read_only_token))
+        .connect(server.base_session.base_url())
+        .await
+        .unwrap();
+    assert_eq!(client.session().username(), "jdoe@example.com");
+    client
+        .mailbox_query(None::<mailbox::query::Filter>, None::<Vec<_>>)
+        .await
+        .unwrap();
+    assert!(matches!(
+        client
+            .mailbox_create("Impersonated", None::<String>, mailbox::Role::None)
+            .await,
+        Err(jmap_client::Error::Method(MethodError {
+            p_type: MethodErrorType::AccountReadOnly
+        }))
+    ));
+
+    let mail_only_token = server
+        .issue_impersonation_token(
+            document_id,
+            &Scope::parse(SUPERUSER_ID, ["mail-only"]).unwrap(),
+            60,
+        )
+        .await
+        .unwrap();
+    let client = Client::new()
+        .credentials(Credentials::bearer(mail_only_token))
+        .connect(server.base_session.base_url())
+        .await
+        .unwrap();
+    let mailbox_id = client
+        .mailbox_create("Impersonated", None::<String>, mailbox::Role::None)
+        .await
+        .unwrap()
+        .take_id();
+    client.mailbox_destroy(&mailbox_id, true).await.unwrap();
+    assert_forbidden(
+        client
+            .sieve_script_create("impersonated", b"keep;".to_vec(), false)
+            .await,
+    );
+
+    // Expired impersonation tokens are rejected
+    let expired_token = server
+        .issue_impersonation_token(document_id, &Scope::new(SUPERUSER_ID), 1)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert!(matches!(
+        Client::new()
+            .credentials(Credentials::bearer(expired_token))
+            .connect(server.base_session.base_url())
+            .await,
+        Err(jmap_client::Error::Problem(ProblemDetails {
+            status: Some(401),
+            ..
+        }))
+    ));
+
     // Destroy test accounts
     admin_client
         .set_default_account_id(JMAPId::new(SUPERUSER_ID as u64))
@@ -129,9 +129,134 @@ where
     assert_ping(&mut event_rx).await;
     assert_ping(&mut event_rx).await;
 
+    // Event ids unknown to this instance, such as those issued before a restart
+    // or by another node, return the full state
+    let base_url = server.base_session.base_url();
+    let body = event_source_request(base_url, "0")
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(body.starts_with("event: state\n"), "{}", body);
+    assert!(body.contains(&JMAPId::new(1).to_string()), "{}", body);
+    assert!(body.contains("Mailbox"), "{}", body);
+    let last_event_id = body
+        .lines()
+        .find_map(|line| line.strip_prefix("id: "))
+        .unwrap()
+        .to_string();
+
+    // Reconnecting with Last-Event-ID replays missed changes
+    let replay_mailbox_id = client
+        .mailbox_create("Replay Test", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+    let body = event_source_request(base_url, &last_event_id)
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(body.contains("Mailbox"), "{}", body);
+    let replayed_event_id = body
+        .lines()
+        .find_map(|line| line.strip_prefix("id: "))
+        .unwrap()
+        .to_string();
+    assert!(
+        replayed_event_id.parse::<u64>().unwrap() > last_event_id.parse::<u64>().unwrap(),
+        "{}",
+        body
+    );
+    let last_event_id = replayed_event_id;
+
+    // No changes are replayed when the client is up to date
+    let mut response = event_source_request(base_url, &last_event_id).await;
+    assert!(
+        tokio::time::timeout(Duration::from_millis(600), response.chunk())
+            .await
+            .is_err()
+    );
+    drop(response);
+
+    // Subscribers requesting mailbox counters receive unread deltas
+    let mailbox_id = client
+        .mailbox_create("Counters Test", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+    let response = event_source_counters_request(base_url).await;
+    let email_id = client
+        .email_import(
+            b"Subject: counters test\n\nUnread message.".to_vec(),
+            [&mailbox_id],
+            None::<Vec<String>>,
+            None,
+        )
+        .await
+        .unwrap()
+        .take_id();
+    let body = response.text().await.unwrap();
+    assert!(body.contains("mailboxUnreadDeltas"), "{}", body);
+    assert!(body.contains(&format!("\"{}\":1", mailbox_id)), "{}", body);
+
+    let response = event_source_counters_request(base_url).await;
+    client
+        .email_set_keyword(&email_id, "$seen", true)
+        .await
+        .unwrap();
+    let body = response.text().await.unwrap();
+    assert!(body.contains(&format!("\"{}\":-1", mailbox_id)), "{}", body);
+
+    // Deltas are not sent unless requested
+    let response = event_source_request(base_url, "").await;
+    client
+        .email_set_keyword(&email_id, "$seen", false)
+        .await
+        .unwrap();
+    let body = response.text().await.unwrap();
+    assert!(!body.contains("mailboxUnreadDeltas"), "{}", body);
+
+    client.mailbox_destroy(&mailbox_id, true).await.unwrap();
+    client
+        .mailbox_destroy(&replay_mailbox_id, true)
+        .await
+        .unwrap();
+
     server.store.assert_is_empty();
 }
 
+async fn event_source_request(base_url: &str, last_event_id: &str) -> reqwest::Response {
+    reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap_or_default()
+        .get(format!(
+            "{}/jmap/eventsource/?types=Mailbox&closeafter=state&ping=0",
+            base_url
+        ))
+        .bearer_auth("DO_NOT_ATTEMPT_THIS_AT_HOME")
+        .header("Last-Event-ID", last_event_id)
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn event_source_counters_request(base_url: &str) -> reqwest::Response {
+    reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap_or_default()
+        .get(format!(
+            "{}/jmap/eventsource/?types=Mailbox&closeafter=state&ping=0&mailboxcounters=true",
+            base_url
+        ))
+        .bearer_auth("DO_NOT_ATTEMPT_THIS_AT_HOME")
+        .send()
+        .await
+        .unwrap()
+}
+
 async fn assert_state(event_rx: &mut mpsc::Receiver<Changes>, state: &[TypeState]) {
     match tokio::time::timeout(Duration::from_millis(700), event_rx.recv()).await {
         Ok(Some(changes)) => {
@@ -0,0 +1,500 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::time::Duration;
+
+use actix_web::web;
+use jmap::{orm::serialize::JMAPOrm, types::jmap::JMAPId, SUPERUSER_ID};
+use jmap_client::{
+    client::{Client, Credentials},
+    mailbox::{self, Role},
+};
+use jmap_mail::{
+    mail::{schema::Keyword, MessageField},
+    mailbox::schema::{Mailbox, Property, Value},
+};
+use serde_json::json;
+use store::{
+    core::{collection::Collection, tag::Tag},
+    AccountId, Store,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
+use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, admin_client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running account migration tests...");
+    let base_url = server.base_session.base_url().to_string();
+
+    let domain_id = admin_client
+        .set_default_account_id(JMAPId::new(SUPERUSER_ID as u64))
+        .domain_create("example.com")
+        .await
+        .unwrap()
+        .take_id();
+    let source_id = admin_client
+        .individual_create("source@example.com", "12345", "Source")
+        .await
+        .unwrap()
+        .take_id();
+    let target_id = admin_client
+        .individual_create("target@example.com", "abcde", "Target")
+        .await
+        .unwrap()
+        .take_id();
+    let target_account = JMAPId::parse(&target_id).unwrap().get_document_id();
+
+    // Populate the source account
+    let mut source_client = Client::new()
+        .credentials(Credentials::basic("source@example.com", "12345"))
+        .connect(&base_url)
+        .await
+        .unwrap();
+    let inbox_id = source_client
+        .set_default_account_id(&source_id)
+        .mailbox_query(
+            mailbox::query::Filter::role(Role::Inbox).into(),
+            [mailbox::query::Comparator::name()].into(),
+        )
+        .await
+        .unwrap()
+        .ids()[0]
+        .to_string();
+    let projects_id = source_client
+        .mailbox_create("Projects", Some(&inbox_id), Role::None)
+        .await
+        .unwrap()
+        .take_id();
+    for (subject, mailbox_id, keywords) in [
+        ("jmap 1", &inbox_id, vec!["$seen"]),
+        ("jmap 2", &inbox_id, vec![]),
+        ("jmap 3", &projects_id, vec!["$flagged"]),
+    ] {
+        source_client
+            .email_import(
+                format!("Subject: {}\r\n\r\nMigrated over JMAP.\r\n", subject).into_bytes(),
+                [mailbox_id],
+                Some(keywords),
+                Some(1640995200),
+            )
+            .await
+            .unwrap();
+    }
+
+    // Migrate over JMAP from this same server
+    let migration = migrate(
+        &base_url,
+        json!({
+            "accountId": target_id,
+            "url": base_url,
+            "username": "source@example.com",
+            "secret": "12345"
+        }),
+    )
+    .await;
+    assert_eq!(migration["status"], "completed", "{}", migration);
+    assert_eq!(migration["progress"]["messagesTotal"], 3, "{}", migration);
+    assert_eq!(
+        migration["progress"]["messagesImported"], 3,
+        "{}",
+        migration
+    );
+    assert_eq!(migration["progress"]["messagesFailed"], 0, "{}", migration);
+    assert_eq!(
+        migration["progress"]["mailboxesCreated"], 1,
+        "{}",
+        migration
+    );
+    assert_eq!(
+        mailbox_contents(&server, target_account),
+        vec![
+            ("Deleted Items".to_string(), 0),
+            ("Drafts".to_string(), 0),
+            ("Inbox".to_string(), 2),
+            ("Inbox/Projects".to_string(), 1),
+            ("Junk Mail".to_string(), 0),
+            ("Sent Items".to_string(), 0),
+        ]
+    );
+    assert_eq!(keyword_count(&server, target_account, Keyword::SEEN), 1);
+    assert_eq!(keyword_count(&server, target_account, Keyword::FLAGGED), 1);
+
+    // Migrations are stored with the principal of the target account
+    let migration_id = JMAPId::parse(migration["id"].as_str().unwrap()).unwrap();
+    assert_eq!(
+        server
+            .store
+            .get_annotations(SUPERUSER_ID, Collection::Principal, target_account)
+            .unwrap()
+            .len(),
+        1
+    );
+    assert!(server
+        .migration_get(migration_id.into())
+        .await
+        .unwrap()
+        .unwrap()
+        .checkpoint
+        .mailboxes
+        .is_some());
+
+    // Completed migrations cannot be resumed
+    assert_eq!(
+        admin_request(
+            reqwest::Method::POST,
+            &format!("{}/admin/migrations/{}", base_url, migration_id),
+            None
+        )
+        .await
+        .0,
+        409
+    );
+
+    // Migrate over IMAP, merging into the existing mailboxes
+    let imap_url = spawn_imap_server().await;
+    let migration = migrate(
+        &base_url,
+        json!({
+            "accountId": target_id,
+            "url": imap_url,
+            "username": "imap@example.com",
+            "secret": "secret"
+        }),
+    )
+    .await;
+    assert_eq!(migration["status"], "completed", "{}", migration);
+    assert_eq!(migration["progress"]["messagesTotal"], 4, "{}", migration);
+    assert_eq!(
+        migration["progress"]["messagesProcessed"], 4,
+        "{}",
+        migration
+    );
+    assert_eq!(
+        migration["progress"]["messagesImported"], 3,
+        "{}",
+        migration
+    );
+    assert_eq!(migration["progress"]["messagesFailed"], 1, "{}", migration);
+    assert_eq!(
+        migration["progress"]["mailboxesCreated"], 2,
+        "{}",
+        migration
+    );
+    assert_eq!(
+        mailbox_contents(&server, target_account),
+        vec![
+            ("Café".to_string(), 0),
+            ("Deleted Items".to_string(), 0),
+            ("Drafts".to_string(), 0),
+            ("Inbox".to_string(), 4),
+            ("Inbox/Projects".to_string(), 2),
+            ("Junk Mail".to_string(), 0),
+            ("Sent Items".to_string(), 0),
+            ("Shared".to_string(), 0),
+        ]
+    );
+    assert_eq!(keyword_count(&server, target_account, Keyword::SEEN), 2);
+    assert_eq!(keyword_count(&server, target_account, Keyword::FLAGGED), 2);
+
+    // Wrong IMAP credentials fail the migration
+    let migration = migrate(
+        &base_url,
+        json!({
+            "accountId": target_id,
+            "url": spawn_imap_server().await,
+            "username": "imap@example.com",
+            "secret": "wrong"
+        }),
+    )
+    .await;
+    assert_eq!(migration["status"], "failed", "{}", migration);
+    assert_eq!(
+        admin_request(
+            reqwest::Method::GET,
+            &format!("{}/admin/migrations", base_url),
+            None
+        )
+        .await
+        .1
+        .as_array()
+        .unwrap()
+        .len(),
+        3
+    );
+
+    // Destroy test accounts, including their migrations
+    for principal_id in [source_id, target_id, domain_id] {
+        admin_client.principal_destroy(&principal_id).await.unwrap();
+    }
+    server.store.principal_purge().unwrap();
+    server.store.assert_is_empty();
+}
+
+async fn migrate(base_url: &str, request: serde_json::Value) -> serde_json::Value {
+    let (status, migration) = admin_request(
+        reqwest::Method::POST,
+        &format!("{}/admin/migrations", base_url),
+        request.into(),
+    )
+    .await;
+    assert_eq!(status, 201, "{}", migration);
+
+    let url = format!(
+        "{}/admin/migrations/{}",
+        base_url,
+        migration["id"].as_str().unwrap()
+    );
+    for _ in 0..100 {
+        let (_, migration) = admin_request(reqwest::Method::GET, &url, None).await;
+        if !["pending", "running"].contains(&migration["status"].as_str().unwrap()) {
+            return migration;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("Migration did not finish in time.");
+}
+
+async fn admin_request(
+    method: reqwest::Method,
+    url: &str,
+    body: Option<serde_json::Value>,
+) -> (u16, serde_json::Value) {
+    let mut request = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap_or_default()
+        .request(method, url)
+        .bearer_auth("DO_NOT_ATTEMPT_THIS_AT_HOME");
+    if let Some(body) = body {
+        request = request.json(&body);
+    }
+    let response = request.send().await.unwrap();
+    (
+        response.status().as_u16(),
+        response.json::<serde_json::Value>().await.unwrap(),
+    )
+}
+
+/// Returns the path of each mailbox along with the number of messages in it.
+fn mailbox_contents<T>(server: &JMAPServer<T>, account_id: AccountId) -> Vec<(String, u64)>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let mut mailboxes = Vec::new();
+    for document_id in server
+        .store
+        .get_document_ids(account_id, Collection::Mailbox)
+        .unwrap()
+        .unwrap_or_default()
+    {
+        let mailbox = server
+            .store
+            .get_orm::<Mailbox>(account_id, document_id)
+            .unwrap()
+            .unwrap();
+        let name = match mailbox.get(&Property::Name) {
+            Some(Value::Text { value }) => value.to_string(),
+            _ => unreachable!(),
+        };
+        let parent_id = match mailbox.get(&Property::ParentId) {
+            Some(Value::Id { value }) => u64::from(value),
+            _ => 0,
+        };
+        mailboxes.push((document_id, name, parent_id));
+    }
+
+    let mut contents = mailboxes
+        .iter()
+        .map(|(document_id, name, parent_id)| {
+            let path = match mailboxes
+                .iter()
+                .find(|(id, _, _)| *id as u64 + 1 == *parent_id)
+            {
+                Some((_, parent_name, _)) => format!("{}/{}", parent_name, name),
+                None => name.to_string(),
+            };
+            let count = server
+                .store
+                .get_tag(
+                    account_id,
+                    Collection::Mail,
+                    MessageField::Mailbox.into(),
+                    Tag::Id(*document_id),
+                )
+                .unwrap()
+                .map_or(0, |messages| messages.len());
+            (path, count)
+        })
+        .collect::<Vec<_>>();
+    contents.sort_unstable();
+    contents
+}
+
+fn keyword_count<T>(server: &JMAPServer<T>, account_id: AccountId, keyword: u8) -> u64
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    server
+        .store
+        .get_tag(
+            account_id,
+            Collection::Mail,
+            MessageField::Keyword.into(),
+            Tag::Static(keyword),
+        )
+        .unwrap()
+        .map_or(0, |messages| messages.len())
+}
+
+/// Spawns an IMAP server holding a fixed set of mailboxes that accepts a
+/// single connection, returns its URL.
+async fn spawn_imap_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("imap://{}", listener.local_addr().unwrap());
+    let mailboxes: Vec<(&str, &str, Vec<(u32, usize, &str, &str)>)> = vec![
+        (
+            "INBOX",
+            "\\HasChildren",
+            vec![
+                (
+                    3,
+                    0,
+                    "\\Seen",
+                    "Subject: imap 1\r\n\r\nMigrated over IMAP.\r\n",
+                ),
+                (7, 0, "", "Subject: imap 2\r\n\r\nMigrated over IMAP.\r\n"),
+            ],
+        ),
+        (
+            "INBOX/Projects",
+            "\\HasNoChildren",
+            vec![(
+                1,
+                0,
+                "\\Flagged \\Recent",
+                "Subject: imap 3\r\n\r\nMigrated over IMAP.\r\n",
+            )],
+        ),
+        (
+            "Caf&AOk-",
+            "\\HasNoChildren",
+            vec![(2, usize::MAX / 2, "", "Subject: too large\r\n\r\n")],
+        ),
+        ("Sent Items", "\\HasNoChildren \\Sent", vec![]),
+        ("Shared", "\\Noselect \\HasNoChildren", vec![]),
+    ];
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut selected = None;
+        writer.write_all(b"* OK IMAP ready\r\n").await.unwrap();
+
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let (tag, command) = line.split_once(' ').unwrap();
+            let mut response = String::new();
+            let mut status = "OK";
+            if command == "CAPABILITY" {
+                response.push_str("* CAPABILITY IMAP4rev1\r\n");
+            } else if let Some(credentials) = command.strip_prefix("LOGIN ") {
+                if credentials != "\"imap@example.com\" \"secret\"" {
+                    status = "NO";
+                }
+            } else if command == "LIST \"\" \"*\"" {
+                for (name, attributes, _) in &mailboxes {
+                    response.push_str(&format!("* LIST ({}) \"/\" \"{}\"\r\n", attributes, name));
+                }
+            } else if let Some(args) = command.strip_prefix("STATUS ") {
+                match mailboxes.iter().find(|(name, attributes, _)| {
+                    args.starts_with(&format!("\"{}\"", name)) && !attributes.contains("Noselect")
+                }) {
+                    Some((name, _, messages)) => response.push_str(&format!(
+                        "* STATUS \"{}\" (MESSAGES {})\r\n",
+                        name,
+                        messages.len()
+                    )),
+                    None => status = "NO",
+                }
+            } else if let Some(args) = command.strip_prefix("EXAMINE ") {
+                selected = mailboxes.iter().position(|(name, attributes, _)| {
+                    args == format!("\"{}\"", name) && !attributes.contains("Noselect")
+                });
+                if selected.is_none() {
+                    status = "NO";
+                }
+            } else if let Some(args) = command.strip_prefix("UID FETCH ") {
+                let messages = &mailboxes[selected.unwrap()].2;
+                if let Some(range) = args.strip_suffix(" (UID RFC822.SIZE FLAGS INTERNALDATE)") {
+                    let from_uid = range.strip_suffix(":*").unwrap().parse::<u32>().unwrap();
+                    for (seq, (uid, size, flags, body)) in messages.iter().enumerate() {
+                        if *uid >= from_uid || seq == messages.len() - 1 {
+                            response.push_str(&format!(
+                                concat!(
+                                    "* {} FETCH (UID {} RFC822.SIZE {} FLAGS ({}) ",
+                                    "INTERNALDATE \" 1-Jan-2022 00:00:00 +0000\")\r\n"
+                                ),
+                                seq + 1,
+                                uid,
+                                if *size > 0 { *size } else { body.len() },
+                                flags
+                            ));
+                        }
+                    }
+                } else {
+                    let uid = args
+                        .strip_suffix(" BODY.PEEK[]")
+                        .unwrap()
+                        .parse::<u32>()
+                        .unwrap();
+                    let (seq, (_, _, _, body)) = messages
+                        .iter()
+                        .enumerate()
+                        .find(|(_, (id, _, _, _))| *id == uid)
+                        .unwrap();
+                    response.push_str(&format!(
+                        "* {} FETCH (UID {} BODY[] {{{}}}\r\n{})\r\n",
+                        seq + 1,
+                        uid,
+                        body.len(),
+                        body
+                    ));
+                }
+            } else if command == "LOGOUT" {
+                response.push_str("* BYE\r\n");
+            } else {
+                status = "BAD";
+            }
+            response.push_str(&format!("{} {} done\r\n", tag, status));
+            writer.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+
+    url
+}
@@ -39,12 +39,17 @@ use crate::{
 use super::store::utils::{destroy_temp_dir, init_settings};
 
 pub mod acl;
+pub mod api_errors;
 pub mod authorization;
 pub mod event_source;
+pub mod migration;
 pub mod oauth;
+pub mod onboarding;
+pub mod plugins;
 pub mod push_subscription;
 pub mod references;
 pub mod stress_test;
+pub mod warmup;
 pub mod websocket;
 
 pub async fn init_jmap_tests_opts<T>(
@@ -117,7 +122,7 @@ where
     server
         .sessions
         .insert(
-            "DO_NOT_ATTEMPT_THIS_AT_HOME".to_string(),
+            "DO_NOT_ATTEMPT_THIS_AT_HOME",
             Session::new(SUPERUSER_ID, acl_token.as_ref()),
         )
         .await;
@@ -140,9 +145,14 @@ async fn jmap_core_tests() {
     oauth::test(server.clone(), &mut client).await;
     acl::test(server.clone(), &mut client).await;
     authorization::test(server.clone(), &mut client).await;
+    onboarding::test(server.clone(), &mut client).await;
+    migration::test(server.clone(), &mut client).await;
     event_source::test(server.clone(), &mut client).await;
     push_subscription::test(server.clone(), &mut client).await;
     websocket::test(server.clone(), &mut client).await;
+    api_errors::test(server.clone(), &mut client).await;
+    plugins::test(server.clone(), &mut client).await;
+    warmup::test(server.clone(), &mut client).await;
 
     destroy_temp_dir(&temp_dir);
 }
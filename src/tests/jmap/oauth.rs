@@ -21,21 +21,30 @@
  * for more details.
 */
 
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use actix_web::web::{self, Bytes};
 use jmap::{types::jmap::JMAPId, SUPERUSER_ID};
 use jmap_client::{
     client::{Client, Credentials},
+    core::error::ProblemDetails,
     mailbox::query::Filter,
 };
-use jmap_sharing::principal::set::JMAPSetPrincipal;
+use jmap_sharing::principal::{
+    passkey::{JMAPAccountPasskey, Passkey},
+    set::JMAPSetPrincipal,
+};
+use p256::ecdsa::signature::Signer;
 use reqwest::{header, redirect::Policy};
 use serde::de::DeserializeOwned;
-use store::{ahash::AHashMap, Store};
+use sha2::{Digest, Sha256};
+use store::{ahash::AHashMap, core::acl::ACLToken, Store};
 
 use crate::{
-    authorization::oauth::{DeviceAuthResponse, ErrorType, OAuthMetadata, TokenResponse},
+    authorization::{
+        oauth::{DeviceAuthResponse, ErrorType, OAuthMetadata, TokenResponse},
+        webauthn::base64url_encode,
+    },
     tests::store::utils::StoreCompareWith,
     JMAPServer,
 };
@@ -306,6 +315,114 @@ where
         }
     );
 
+    // ------------------------
+    // Passkey login
+    // ------------------------
+
+    // Register a passkey and make John an administrator
+    let john_account_id = JMAPId::parse(&john_id).unwrap().get_document_id();
+    let signing_key = p256::ecdsa::SigningKey::from_bytes(&[7u8; 32]).unwrap();
+    server
+        .store
+        .passkey_add(
+            john_account_id,
+            Passkey {
+                name: "laptop".to_string(),
+                credential_id: b"credential".to_vec(),
+                algorithm: -7,
+                public_key: signing_key
+                    .verifying_key()
+                    .to_encoded_point(false)
+                    .as_bytes()
+                    .to_vec(),
+                sign_count: 0,
+            },
+            None,
+        )
+        .unwrap()
+        .unwrap();
+    server.store.acl_tokens.insert(
+        john_account_id,
+        Arc::new(ACLToken {
+            member_of: vec![john_account_id, SUPERUSER_ID],
+            access_to: vec![],
+        }),
+    );
+
+    // Administrators can't sign in with a password
+    assert!(matches!(
+        Client::new()
+            .credentials(Credentials::basic("jdoe@example.com", "abcde"))
+            .connect(server.base_session.base_url())
+            .await,
+        Err(jmap_client::Error::Problem(ProblemDetails {
+            status: Some(401),
+            ..
+        }))
+    ));
+    auth_request.insert(
+        "code".to_string(),
+        parse_code_input(get_bytes(&auth_endpoint).await),
+    );
+    assert_eq!(
+        post_expect_redirect(&metadata.authorization_endpoint, &auth_request).await,
+        "https://localhost?error=access_denied&state=xyz"
+    );
+
+    // Passkey challenges do not reveal any credentials
+    let options: serde_json::Value = post(
+        &format!("{}/auth/passkey", server.base_session.base_url()),
+        &AHashMap::new(),
+    )
+    .await;
+    assert_eq!(options["allowCredentials"], serde_json::json!([]));
+    assert_eq!(options["userVerification"], "required");
+    let challenge = options["challenge"].as_str().unwrap();
+
+    // Sign in with the passkey, identified by its user handle
+    let origin = server.oauth.relying_party.origin.clone();
+    let client_data_json = format!(
+        r#"{{"type":"webauthn.get","challenge":"{}","origin":"{}"}}"#,
+        challenge, origin
+    );
+    let mut authenticator_data = Sha256::digest(server.oauth.relying_party.id.as_bytes()).to_vec();
+    authenticator_data.push(0x05);
+    authenticator_data.extend_from_slice(&1u32.to_be_bytes());
+    let mut message = authenticator_data.clone();
+    message.extend_from_slice(&Sha256::digest(client_data_json.as_bytes()));
+    let signature: p256::ecdsa::Signature = signing_key.sign(&message);
+    let assertion = serde_json::json!({
+        "id": base64url_encode(b"credential"),
+        "clientDataJSON": base64url_encode(client_data_json.as_bytes()),
+        "authenticatorData": base64url_encode(&authenticator_data),
+        "signature": base64url_encode(signature.to_der().as_bytes()),
+        "userHandle": base64url_encode(john_id.as_bytes()),
+    })
+    .to_string();
+    let mut passkey_request = AHashMap::from_iter([
+        ("email".to_string(), "".to_string()),
+        ("passkey".to_string(), assertion),
+        (
+            "code".to_string(),
+            parse_code_input(get_bytes(&auth_endpoint).await),
+        ),
+    ]);
+    parse_code_redirect(
+        post_expect_redirect(&metadata.authorization_endpoint, &passkey_request).await,
+        "xyz",
+    );
+
+    // Challenges can't be used twice
+    passkey_request.insert(
+        "code".to_string(),
+        parse_code_input(get_bytes(&auth_endpoint).await),
+    );
+    assert_eq!(
+        post_expect_redirect(&metadata.authorization_endpoint, &passkey_request).await,
+        "https://localhost?error=access_denied&state=xyz"
+    );
+    server.store.acl_tokens.invalidate(&john_account_id);
+
     // Destroy test accounts
     for principal_id in [john_id, domain_id] {
         admin_client.principal_destroy(&principal_id).await.unwrap();
@@ -0,0 +1,136 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::web;
+use jmap::{
+    orm::serialize::JMAPOrm,
+    principal::schema::{Principal, Property, Value},
+    types::jmap::JMAPId,
+    SUPERUSER_ID,
+};
+use jmap_client::client::Client;
+use jmap_mail::identity::schema::{Identity, Property as IdentityProperty, Value as IdentityValue};
+use store::{core::collection::Collection, AccountId, Store};
+
+use crate::{
+    services::onboarding::{onboard_store_actions, Onboarding, OnboardingAction},
+    tests::store::utils::StoreCompareWith,
+    JMAPServer,
+};
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, admin_client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running Onboarding tests...");
+
+    let domain_id = admin_client
+        .set_default_account_id(JMAPId::new(SUPERUSER_ID as u64))
+        .domain_create("example.com")
+        .await
+        .unwrap()
+        .take_id();
+    let principal_id = admin_client
+        .individual_create("jane@example.com", "12345", "Jane Doe")
+        .await
+        .unwrap()
+        .take_id();
+    let account_id = JMAPId::parse(&principal_id).unwrap().get_document_id();
+
+    // Existing mailboxes and roles are not created twice
+    let onboarding = Onboarding::new(
+        vec![
+            OnboardingAction::Mailboxes,
+            OnboardingAction::Identity,
+            OnboardingAction::Welcome,
+        ],
+        vec![
+            ("Archive".to_string(), Some("archive".to_string())),
+            ("Inbox".to_string(), None),
+            ("Receipts".to_string(), None),
+        ],
+        None,
+    );
+    let pending = onboard_store_actions(&server.store, &onboarding, account_id)
+        .unwrap()
+        .unwrap();
+    assert!(pending.welcome);
+    assert!(pending.change_id.is_some());
+    assert_eq!(pending.email, "jane@example.com");
+    assert_eq!(mailbox_count(&server, account_id), 7);
+
+    let identity_ids = server
+        .store
+        .get_document_ids(account_id, Collection::Identity)
+        .unwrap()
+        .unwrap();
+    assert_eq!(identity_ids.len(), 1);
+    let identity = server
+        .store
+        .get_orm::<Identity>(account_id, identity_ids.min().unwrap())
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        identity.get(&IdentityProperty::Name),
+        Some(&IdentityValue::Text {
+            value: "Jane Doe".to_string()
+        })
+    );
+
+    // Completed actions are not repeated, the welcome message is still pending
+    let pending = onboard_store_actions(&server.store, &onboarding, account_id)
+        .unwrap()
+        .unwrap();
+    assert!(pending.welcome);
+    assert!(pending.change_id.is_none());
+    assert_eq!(mailbox_count(&server, account_id), 7);
+    assert_eq!(
+        server
+            .store
+            .get_orm::<Principal>(SUPERUSER_ID, account_id)
+            .unwrap()
+            .unwrap()
+            .get(&Property::Onboarded),
+        Some(&Value::TextList {
+            value: vec!["mailboxes".to_string(), "identity".to_string()]
+        })
+    );
+
+    // Destroy test accounts
+    admin_client.principal_destroy(&principal_id).await.unwrap();
+    admin_client.principal_destroy(&domain_id).await.unwrap();
+    server.store.principal_purge().unwrap();
+    server.store.assert_is_empty();
+}
+
+fn mailbox_count<T>(server: &JMAPServer<T>, account_id: AccountId) -> u64
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    server
+        .store
+        .get_document_ids(account_id, Collection::Mailbox)
+        .unwrap()
+        .unwrap()
+        .len()
+}
@@ -0,0 +1,190 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use actix_web::web;
+use jmap::{error::method::MethodError, request::ACLEnforce, types::jmap::JMAPId};
+use jmap_client::client::Client;
+use serde_json::{json, Value};
+use store::{
+    core::{acl::ACLToken, collection::Collection},
+    JMAPStore, Store,
+};
+
+use crate::{
+    api::plugin::{CustomResult, MethodHandler},
+    JMAPServer,
+};
+
+struct FooQuery;
+struct FooGet;
+
+impl<T> MethodHandler<T> for FooQuery
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn call(
+        &self,
+        _store: &JMAPStore<T>,
+        acl: Arc<ACLToken>,
+        arguments: Value,
+    ) -> jmap::Result<CustomResult> {
+        let account_id = parse_account_id(&acl, &arguments)?;
+        Ok(json!({
+            "accountId": account_id.to_string(),
+            "ids": (1..=3).map(|id| JMAPId::new(id).to_string()).collect::<Vec<_>>(),
+        })
+        .into())
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+impl<T> MethodHandler<T> for FooGet
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    fn call(
+        &self,
+        _store: &JMAPStore<T>,
+        acl: Arc<ACLToken>,
+        arguments: Value,
+    ) -> jmap::Result<CustomResult> {
+        let account_id = parse_account_id(&acl, &arguments)?;
+        let list = arguments["ids"]
+            .as_array()
+            .ok_or_else(|| MethodError::InvalidArguments("Missing ids.".to_string()))?
+            .iter()
+            .map(|id| json!({"id": id, "name": format!("Foo {}", id.as_str().unwrap_or(""))}))
+            .collect::<Vec<_>>();
+        Ok(json!({
+            "accountId": account_id.to_string(),
+            "list": list,
+            "notFound": [],
+        })
+        .into())
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+fn parse_account_id(acl: &Arc<ACLToken>, arguments: &Value) -> jmap::Result<JMAPId> {
+    let account_id = arguments["accountId"]
+        .as_str()
+        .and_then(JMAPId::parse)
+        .ok_or_else(|| MethodError::InvalidArguments("Invalid accountId.".to_string()))?;
+    acl.clone()
+        .assert_has_access(account_id.get_document_id(), Collection::Mail)?;
+    Ok(account_id)
+}
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, _client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running Plugin tests...");
+
+    server
+        .plugins
+        .register_capability("urn:example:foo", json!({"maxFoos": 3}));
+    server.plugins.register_method("Foo/query", FooQuery);
+    server.plugins.register_method("Foo/get", FooGet);
+
+    // Registered capabilities are advertised in the session
+    let http = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap_or_default();
+    let base_url = server.base_session.base_url();
+    let session = http
+        .get(format!("{}/.well-known/jmap", base_url))
+        .bearer_auth("DO_NOT_ATTEMPT_THIS_AT_HOME")
+        .send()
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    assert_eq!(
+        session["capabilities"]["urn:example:foo"],
+        json!({"maxFoos": 3})
+    );
+
+    // Custom methods take part in result reference resolution
+    let account_id = JMAPId::new(1).to_string();
+    let response = http
+        .post(format!("{}/jmap", base_url))
+        .bearer_auth("DO_NOT_ATTEMPT_THIS_AT_HOME")
+        .json(&json!({
+            "using": ["urn:ietf:params:jmap:core", "urn:example:foo"],
+            "methodCalls": [
+                ["Foo/query", {"accountId": account_id}, "c0"],
+                ["Foo/get", {
+                    "accountId": account_id,
+                    "#ids": {"resultOf": "c0", "name": "Foo/query", "path": "/ids"}
+                }, "c1"],
+                ["Foo/get", {
+                    "accountId": account_id,
+                    "#ids": {"resultOf": "c1", "name": "Foo/get", "path": "/list/*/id"}
+                }, "c2"],
+                ["Foo/get", {"accountId": 7}, "c3"],
+                ["Bar/get", {"accountId": account_id}, "c4"]
+            ]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    let responses = response["methodResponses"].as_array().unwrap();
+    assert_eq!(responses.len(), 5, "{}", response);
+
+    let ids = (1..=3)
+        .map(|id| JMAPId::new(id).to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(responses[0][0], "Foo/query");
+    assert_eq!(responses[0][1]["ids"], json!(ids));
+    for (pos, call_id) in [(1, "c1"), (2, "c2")] {
+        assert_eq!(responses[pos][0], "Foo/get");
+        assert_eq!(responses[pos][2], call_id);
+        assert_eq!(
+            responses[pos][1]["list"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["id"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>(),
+            ids
+        );
+    }
+    assert_eq!(responses[3][0], "error");
+    assert_eq!(responses[3][1]["type"], "invalidArguments");
+    assert_eq!(responses[4][0], "error");
+    assert_eq!(responses[4][1]["type"], "unknownMethod");
+}
@@ -0,0 +1,135 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::web;
+use jmap::{types::jmap::JMAPId, SUPERUSER_ID};
+use jmap_client::{client::Client, mailbox::Role};
+use jmap_mail::mail::{schema::Keyword, MessageField};
+use store::{
+    core::{collection::Collection, tag::Tag},
+    serialize::key::BitmapKey,
+    Store,
+};
+
+use crate::{
+    services::warmup::{WarmupManifest, MANIFEST_KEY},
+    tests::store::utils::StoreCompareWith,
+    JMAPServer,
+};
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, admin_client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running cache warm-up tests...");
+
+    let domain_id = admin_client
+        .set_default_account_id(JMAPId::new(SUPERUSER_ID as u64))
+        .domain_create("example.com")
+        .await
+        .unwrap()
+        .take_id();
+    let principal_id = admin_client
+        .individual_create("jane@example.com", "12345", "Jane Doe")
+        .await
+        .unwrap()
+        .take_id();
+    let account_id = JMAPId::parse(&principal_id).unwrap().get_document_id();
+    let mailbox_id = admin_client
+        .set_default_account_id(&principal_id)
+        .mailbox_create("Warm-up", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+    admin_client
+        .email_import(
+            b"Subject: warm-up\n\nhello".to_vec(),
+            [&mailbox_id],
+            Some(vec!["$seen"]),
+            None,
+        )
+        .await
+        .unwrap();
+
+    // The manifest is persisted and merged with the recently accessed accounts
+    server
+        .set_key(
+            MANIFEST_KEY,
+            WarmupManifest {
+                accounts: vec![(account_id, 1000), (SUPERUSER_ID, 2000)],
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        server.warmup_manifest().await.unwrap(),
+        vec![(SUPERUSER_ID, 2000), (account_id, 1000)]
+    );
+
+    // Warming up an account fills its caches
+    let store = &server.store;
+    let mailbox_key = BitmapKey::serialize_tag(
+        account_id,
+        Collection::Mail,
+        MessageField::Mailbox.into(),
+        &Tag::Id(JMAPId::parse(&mailbox_id).unwrap().get_document_id()),
+    );
+    let seen_key = BitmapKey::serialize_tag(
+        account_id,
+        Collection::Mail,
+        MessageField::Keyword.into(),
+        &Tag::Static(Keyword::SEEN),
+    );
+    store.acl_tokens.invalidate_all();
+    store.id_assigner.invalidate_all();
+    store.bitmap_cache.invalidate_all();
+    assert!(!store.acl_tokens.contains_key(&account_id));
+
+    assert_eq!(server.warmup_accounts(vec![account_id]).await.unwrap(), 1);
+    assert!(store.acl_tokens.contains_key(&account_id));
+    assert!(store.bitmap_cache.is_enabled());
+    for key in [
+        BitmapKey::serialize_document_ids(account_id, Collection::Mail),
+        BitmapKey::serialize_document_ids(account_id, Collection::Mailbox),
+        mailbox_key,
+        seen_key,
+    ] {
+        assert!(store.bitmap_cache.get(&key).is_some());
+    }
+
+    // Destroy test accounts
+    admin_client
+        .set_default_account_id(&principal_id)
+        .mailbox_destroy(&mailbox_id, true)
+        .await
+        .unwrap();
+    server
+        .set_key(MANIFEST_KEY, WarmupManifest::default())
+        .await
+        .unwrap();
+    admin_client.set_default_account_id(JMAPId::new(SUPERUSER_ID as u64));
+    admin_client.principal_destroy(&principal_id).await.unwrap();
+    admin_client.principal_destroy(&domain_id).await.unwrap();
+    server.store.principal_purge().unwrap();
+    server.store.assert_is_empty();
+}
@@ -21,10 +21,10 @@
  * for more details.
 */
 
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use actix_web::web;
-use futures::StreamExt;
+use futures::{SinkExt, StreamExt};
 use jmap::types::jmap::JMAPId;
 use jmap_client::{
     client::Client,
@@ -33,12 +33,22 @@ use jmap_client::{
         response::{Response, TaggedMethodResponse},
         set::SetObject,
     },
+    mailbox::Role,
     TypeState,
 };
-use store::{ahash::AHashSet, Store};
-use tokio::sync::mpsc;
+use serde_json::{json, Value};
+use store::{ahash::AHashSet, core::acl::ACLToken, AccountId, Store};
+use tokio::{net::TcpStream, sync::mpsc};
+use tokio_tungstenite::{
+    tungstenite::{client::IntoClientRequest, Message},
+    MaybeTlsStream, WebSocketStream,
+};
 
-use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
+use crate::{
+    authorization::{auth::RemoteAddress, rate_limit::Limiter, Session},
+    tests::store::utils::StoreCompareWith,
+    JMAPServer,
+};
 
 pub async fn test<T>(server: web::Data<JMAPServer<T>>, client: &mut Client)
 where
@@ -118,9 +128,200 @@ where
         .unwrap();
     expect_nothing(&mut stream_rx).await;
 
+    // Subscribed queries receive the changes to their results
+    let mailbox_id = client
+        .mailbox_create("Query Subscription Test", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+    let mut ws = connect_ws_as_account(&server, 1).await;
+
+    // Exceeding the maximum number of subscribed queries is not allowed
+    let mut queries = serde_json::Map::new();
+    for num in 0..=server.store.config.ws_max_query_subscriptions {
+        queries.insert(
+            format!("q{}", num),
+            json!({"filter": {"inMailbox": mailbox_id}}),
+        );
+    }
+    let error = push_enable(&mut ws, Value::Object(queries)).await;
+    assert_eq!(error["@type"], "RequestError", "{}", error);
+    assert_eq!(error["limit"], "maxQuerySubscriptions", "{}", error);
+
+    // Queries that depend on other messages in the thread cannot be subscribed to
+    let error = push_enable(
+        &mut ws,
+        json!({"q1": {"filter": {"someInThreadHaveKeyword": "$seen"}}}),
+    )
+    .await;
+    assert_eq!(error["@type"], "RequestError", "{}", error);
+    assert_eq!(
+        error["type"], "urn:stalwart:jmap:error:invalidParameters",
+        "{}",
+        error
+    );
+
+    let changes = push_enable(
+        &mut ws,
+        json!({"q1": {
+            "filter": {"inMailbox": mailbox_id},
+            "sort": [{"property": "subject", "isAscending": true}]
+        }}),
+    )
+    .await;
+    let changes = query_changes(&changes);
+    assert_eq!(changes.len(), 1, "{:?}", changes);
+    assert_eq!(changes[0]["added"], json!([]), "{}", changes[0]);
+
+    let mut email_ids = Vec::new();
+    for subject in ["b", "a", "c"] {
+        email_ids.push(
+            client
+                .email_import(
+                    format!("Subject: {}\n\nQuery subscription test.", subject).into_bytes(),
+                    [&mailbox_id],
+                    None::<Vec<String>>,
+                    None,
+                )
+                .await
+                .unwrap()
+                .take_id(),
+        );
+
+        // Results are sorted by subject
+        let changes = expect_query_changes(&mut ws).await;
+        assert_eq!(changes["removed"], json!([]), "{}", changes);
+        assert_eq!(
+            changes["added"],
+            json!([{
+                "id": email_ids.last().unwrap(),
+                "index": if subject == "c" { 2 } else { 0 }
+            }]),
+            "{}",
+            changes
+        );
+        assert_eq!(changes["total"], email_ids.len(), "{}", changes);
+    }
+
+    // Changes that do not affect the results or their order are not sent
+    client
+        .email_set_keyword(&email_ids[0], "$seen", true)
+        .await
+        .unwrap();
+    expect_no_query_changes(&mut ws).await;
+
+    client.email_destroy(&email_ids[0]).await.unwrap();
+    let changes = expect_query_changes(&mut ws).await;
+    assert_eq!(changes["removed"], json!([email_ids[0]]), "{}", changes);
+    assert_eq!(changes["added"], json!([]), "{}", changes);
+    assert_eq!(changes["total"], 2, "{}", changes);
+
+    // Closing the WebSocket drops the subscription
+    ws.close(None).await.unwrap();
+    drop(ws);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(server.query_subscriptions.is_empty(1));
+
+    client.mailbox_destroy(&mailbox_id, true).await.unwrap();
+
     server.store.assert_is_empty();
 }
 
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn connect_ws_as_account<T>(server: &JMAPServer<T>, account_id: AccountId) -> WsStream
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let acl_token = Arc::new(ACLToken {
+        member_of: vec![account_id],
+        access_to: vec![],
+    });
+    let token = format!("WEBSOCKET_TEST_{}", account_id);
+    server
+        .sessions
+        .insert(&token, Session::new(account_id, acl_token.as_ref()))
+        .await;
+    server.store.acl_tokens.insert(account_id, acl_token);
+    server
+        .rate_limiters
+        .insert(
+            RemoteAddress::AccountId(account_id),
+            Arc::new(Limiter::new_authenticated(1000, 1000)),
+        )
+        .await;
+
+    let mut request = format!(
+        "{}/jmap/ws",
+        server.base_session.base_url().replacen("http", "ws", 1)
+    )
+    .into_client_request()
+    .unwrap();
+    request.headers_mut().insert(
+        "Authorization",
+        format!("Bearer {}", token).parse().unwrap(),
+    );
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", "jmap".parse().unwrap());
+
+    tokio_tungstenite::connect_async(request).await.unwrap().0
+}
+
+async fn push_enable(ws: &mut WsStream, query_subscriptions: Value) -> Value {
+    ws.send(Message::Text(
+        json!({
+            "@type": "WebSocketPushEnable",
+            "dataTypes": ["Email"],
+            "querySubscriptions": query_subscriptions
+        })
+        .to_string(),
+    ))
+    .await
+    .unwrap();
+    expect_ws_message(ws, 700)
+        .await
+        .expect("Timeout waiting for WebSocket message")
+}
+
+async fn expect_ws_message(ws: &mut WsStream, timeout_ms: u64) -> Option<Value> {
+    loop {
+        match tokio::time::timeout(Duration::from_millis(timeout_ms), ws.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => return serde_json::from_str(&text).unwrap(),
+            Ok(Some(Ok(Message::Ping(_) | Message::Pong(_)))) => (),
+            Err(_) => return None,
+            result => {
+                panic!("Unexpected WebSocket message: {:?}", result);
+            }
+        }
+    }
+}
+
+fn query_changes(message: &Value) -> Vec<Value> {
+    assert_eq!(message["@type"], "StateChange", "{}", message);
+    message["queryChanges"]["q1"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+}
+
+async fn expect_query_changes(ws: &mut WsStream) -> Value {
+    loop {
+        let message = expect_ws_message(ws, 1000)
+            .await
+            .expect("Timeout waiting for query changes");
+        if let Some(changes) = query_changes(&message).pop() {
+            return changes;
+        }
+    }
+}
+
+async fn expect_no_query_changes(ws: &mut WsStream) {
+    while let Some(message) = expect_ws_message(ws, 1000).await {
+        assert_eq!(query_changes(&message), Vec::<Value>::new(), "{}", message);
+    }
+}
+
 async fn expect_response(
     stream_rx: &mut mpsc::Receiver<WebSocketMessage>,
 ) -> Response<TaggedMethodResponse> {
@@ -0,0 +1,115 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::web;
+
+use jmap::types::jmap::JMAPId;
+use jmap_client::{client::Client, mailbox::Role};
+use jmap_mail::mail::get::{CidResult, JMAPGetMail};
+use jmap_sharing::principal::account::JMAPAccountStore;
+use store::Store;
+
+use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running Content-ID download tests...");
+
+    let mailbox_id = client
+        .set_default_account_id(JMAPId::new(1).to_string())
+        .mailbox_create("Content-ID", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+    let email_id = client
+        .email_import(
+            concat!(
+                "Subject: Inline image\r\n",
+                "Content-Type: multipart/related; boundary=\"boundary\"\r\n",
+                "\r\n",
+                "--boundary\r\n",
+                "Content-Type: text/html\r\n",
+                "\r\n",
+                "<img src=\"cid:logo@example.com\">\r\n",
+                "--boundary\r\n",
+                "Content-Type: image/png; name=\"logo.png\"\r\n",
+                "Content-Transfer-Encoding: base64\r\n",
+                "Content-ID: <logo@example.com>\r\n",
+                "\r\n",
+                "aGVsbG8gd29ybGQ=\r\n",
+                "--boundary--\r\n"
+            )
+            .as_bytes()
+            .to_vec(),
+            [&mailbox_id],
+            None::<Vec<String>>,
+            None,
+        )
+        .await
+        .unwrap()
+        .take_id();
+    let document_id = JMAPId::parse(&email_id).unwrap().get_document_id();
+    let acl = server.store.get_acl_token(1).unwrap();
+
+    // Angle brackets are optional
+    for cid in ["logo@example.com", "<logo@example.com>"] {
+        match server
+            .store
+            .mail_cid_get(1, &acl, document_id, cid)
+            .unwrap()
+        {
+            CidResult::Part {
+                bytes,
+                content_type,
+                name,
+            } => {
+                assert_eq!(bytes, b"hello world");
+                assert_eq!(content_type.as_deref(), Some("image/png"));
+                assert_eq!(name.as_deref(), Some("logo.png"));
+            }
+            _ => panic!("Content-ID {} not found.", cid),
+        }
+    }
+
+    // Unknown Content-IDs and messages are not found
+    assert!(matches!(
+        server
+            .store
+            .mail_cid_get(1, &acl, document_id, "unknown@example.com")
+            .unwrap(),
+        CidResult::NotFound
+    ));
+    assert!(matches!(
+        server
+            .store
+            .mail_cid_get(1, &acl, document_id + 1, "logo@example.com")
+            .unwrap(),
+        CidResult::NotFound
+    ));
+
+    client.mailbox_destroy(&mailbox_id, true).await.unwrap();
+
+    server.store.assert_is_empty();
+}
@@ -0,0 +1,159 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::web;
+
+use jmap::{
+    principal::schema::Principal, request::set::SetRequest, types::jmap::JMAPId, SUPERUSER_ID,
+};
+use jmap_client::{client::Client, email, mailbox::Role};
+use jmap_mail::mail::collation::JMAPMailCollation;
+use jmap_sharing::principal::{account::JMAPAccountStore, set::JMAPSetPrincipal};
+use store::Store;
+
+use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running Email collation tests...");
+
+    let domain_id = client
+        .set_default_account_id(JMAPId::new(SUPERUSER_ID as u64))
+        .domain_create("example.com")
+        .await
+        .unwrap()
+        .take_id();
+    let account_id = client
+        .individual_create("jdoe@example.com", "12345", "John Doe")
+        .await
+        .unwrap()
+        .take_id();
+    let mailbox_id = client
+        .set_default_account_id(&account_id)
+        .mailbox_create("Collation", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+
+    let mut message_ids = Vec::new();
+    for subject in ["Zoë", "Ösel", "Andersson", "Ångström"] {
+        message_ids.push((
+            client
+                .email_import(
+                    format!("Subject: {}\r\n\r\nCollation test.", subject).into_bytes(),
+                    [&mailbox_id],
+                    None::<Vec<&str>>,
+                    None,
+                )
+                .await
+                .unwrap()
+                .take_id(),
+            subject,
+        ));
+    }
+
+    // Accounts without a locale sort accented letters as their base letters
+    assert_eq!(
+        query_subjects(client, &mailbox_id, &message_ids).await,
+        ["Andersson", "Ångström", "Ösel", "Zoë"]
+    );
+
+    // Changing the locale keeps the old sort keys until they are rebuilt
+    let mut request = serde_json::from_value::<SetRequest<Principal>>(serde_json::json!({
+        "accountId": JMAPId::new(SUPERUSER_ID as u64).to_string(),
+        "update": {&account_id: {"locale": "sv-SE"}},
+    }))
+    .unwrap();
+    request.acl = server.store.get_acl_token(SUPERUSER_ID).unwrap().into();
+    server.store.principal_set(request).unwrap();
+    assert_eq!(
+        query_subjects(client, &mailbox_id, &message_ids).await,
+        ["Andersson", "Ångström", "Ösel", "Zoë"]
+    );
+
+    let document_id = JMAPId::parse(&account_id).unwrap().get_document_id();
+    assert_eq!(server.store.mail_reindex_collation(document_id).unwrap(), 4);
+    assert_eq!(server.store.mail_reindex_collation(document_id).unwrap(), 0);
+    assert_eq!(
+        query_subjects(client, &mailbox_id, &message_ids).await,
+        ["Andersson", "Zoë", "Ångström", "Ösel"]
+    );
+
+    // Messages added afterwards are sorted with the new collation right away
+    message_ids.push((
+        client
+            .email_import(
+                b"Subject: Zebra\r\n\r\nCollation test.".to_vec(),
+                [&mailbox_id],
+                None::<Vec<&str>>,
+                None,
+            )
+            .await
+            .unwrap()
+            .take_id(),
+        "Zebra",
+    ));
+    assert_eq!(
+        query_subjects(client, &mailbox_id, &message_ids).await,
+        ["Andersson", "Zebra", "Zoë", "Ångström", "Ösel"]
+    );
+
+    // Sort keys are removed together with the messages
+    for (message_id, _) in &message_ids {
+        client.email_destroy(message_id).await.unwrap();
+    }
+    client
+        .set_default_account_id(JMAPId::new(SUPERUSER_ID as u64))
+        .principal_destroy(&account_id)
+        .await
+        .unwrap();
+    client.principal_destroy(&domain_id).await.unwrap();
+    server.store.principal_purge().unwrap();
+    server.store.assert_is_empty();
+}
+
+async fn query_subjects<'x>(
+    client: &mut Client,
+    mailbox_id: &str,
+    message_ids: &[(String, &'x str)],
+) -> Vec<&'x str> {
+    client
+        .email_query(
+            email::query::Filter::in_mailbox(mailbox_id).into(),
+            [email::query::Comparator::subject()].into(),
+        )
+        .await
+        .unwrap()
+        .take_ids()
+        .into_iter()
+        .map(|id| {
+            message_ids
+                .iter()
+                .find(|(message_id, _)| message_id == &id)
+                .unwrap()
+                .1
+        })
+        .collect()
+}
@@ -27,6 +27,7 @@ use actix_web::web;
 use jmap::types::jmap::JMAPId;
 use jmap_client::{
     client::Client,
+    core::error::{MethodError, MethodErrorType},
     email::{self, Header, HeaderForm},
     mailbox::Role,
 };
@@ -189,6 +190,141 @@ where
         }
     }
 
+    // HTML-only messages are converted to text when only text body values are requested
+    let email = client
+        .email_import(
+            concat!(
+                "From: john@example.org\r\n",
+                "Subject: HTML only\r\n",
+                "Content-Type: text/html; charset=utf-8\r\n\r\n",
+                "<html><body><p>Hello <b>world</b>!</p>",
+                "<p><a href=\"https://example.org\">Link</a></p></body></html>\r\n"
+            )
+            .as_bytes()
+            .to_vec(),
+            [mailbox_id.clone()],
+            None::<Vec<String>>,
+            None,
+        )
+        .await
+        .unwrap();
+    for _ in 0..2 {
+        let mut request = client.build();
+        request
+            .get_email()
+            .ids([email.id().unwrap()])
+            .properties([email::Property::TextBody, email::Property::BodyValues])
+            .arguments()
+            .fetch_text_body_values(true);
+        let email = request
+            .send_get_email()
+            .await
+            .unwrap()
+            .take_list()
+            .pop()
+            .unwrap();
+        let part_id = email.text_body().unwrap()[0].part_id().unwrap();
+        assert_eq!(
+            email.body_value(part_id).unwrap().value(),
+            "Hello world!\n\nLink <https://example.org>"
+        );
+    }
+
+    // Header properties are validated and fetched from the raw message when needed
+    let email = client
+        .email_import(
+            concat!(
+                "Return-Path: <bounces@example.org>\r\n",
+                "Received: from mx1.example.org by mx2.example.org\r\n",
+                "Received: from client.example.org by mx1.example.org\r\n",
+                "From: john@example.org\r\n",
+                "Subject: Header forms\r\n",
+                "X-Custom: =?utf-8?q?caf=C3=A9?=\r\n\r\n",
+                "Hello world!\r\n"
+            )
+            .as_bytes()
+            .to_vec(),
+            [mailbox_id.clone()],
+            None::<Vec<String>>,
+            None,
+        )
+        .await
+        .unwrap();
+    for (name, form) in [
+        ("Received", HeaderForm::Text),
+        ("Return-Path", HeaderForm::Addresses),
+        ("From", HeaderForm::Date),
+        ("Subject", HeaderForm::Addresses),
+    ] {
+        let mut request = client.build();
+        request
+            .get_email()
+            .ids([email.id().unwrap()])
+            .properties([email::Property::Header(Header {
+                name: name.into(),
+                form,
+                all: false,
+            })]);
+        assert!(
+            matches!(
+                request.send_get_email().await,
+                Err(jmap_client::Error::Method(MethodError {
+                    p_type: MethodErrorType::InvalidArguments
+                }))
+            ),
+            "{}",
+            name
+        );
+    }
+
+    let mut request = client.build();
+    request.get_email().ids([email.id().unwrap()]).properties([
+        email::Property::Header(Header {
+            name: "Received".into(),
+            form: HeaderForm::Raw,
+            all: true,
+        }),
+        email::Property::Header(Header {
+            name: "Return-Path".into(),
+            form: HeaderForm::Raw,
+            all: false,
+        }),
+        email::Property::Header(Header {
+            name: "X-Custom".into(),
+            form: HeaderForm::Text,
+            all: false,
+        }),
+        email::Property::Header(Header {
+            name: "Subject".into(),
+            form: HeaderForm::Text,
+            all: true,
+        }),
+    ]);
+    let result = serde_json::to_value(
+        request
+            .send_get_email()
+            .await
+            .unwrap()
+            .take_list()
+            .pop()
+            .unwrap()
+            .into_test(),
+    )
+    .unwrap();
+    assert_eq!(
+        result["header:Received:all"],
+        serde_json::json!([
+            " from mx1.example.org by mx2.example.org",
+            " from client.example.org by mx1.example.org"
+        ])
+    );
+    assert_eq!(result["header:Return-Path"], " <bounces@example.org>");
+    assert_eq!(result["header:X-Custom:asText"], "café");
+    assert_eq!(
+        result["header:Subject:asText:all"],
+        serde_json::json!(["Header forms"])
+    );
+
     client.mailbox_destroy(&mailbox_id, true).await.unwrap();
 
     server.store.assert_is_empty();
@@ -0,0 +1,126 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::web;
+
+use jmap::types::jmap::JMAPId;
+use jmap_client::{
+    client::Client,
+    core::set::{SetError, SetErrorType},
+    mailbox::Role,
+};
+use jmap_mail::mail::keywords::JMAPMailKeywords;
+use store::Store;
+
+use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running keyword registry tests...");
+    let max_keywords = server.store.config.mail_max_keywords;
+    let mailbox_id = client
+        .set_default_account_id(JMAPId::new(1).to_string())
+        .mailbox_create("Keywords", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+
+    // Exceeding the custom keyword limit fails
+    let keywords = (0..=max_keywords)
+        .map(|n| format!("keyword{}", n))
+        .collect::<Vec<_>>();
+    assert!(matches!(
+        client
+            .email_import(
+                b"Subject: too many keywords\r\n\r\nhello".to_vec(),
+                [&mailbox_id],
+                Some(&keywords),
+                None,
+            )
+            .await,
+        Err(jmap_client::Error::Set(SetError {
+            type_: SetErrorType::TooManyKeywords,
+            ..
+        }))
+    ));
+
+    // System keywords do not count towards the limit
+    let mut keywords = keywords[..max_keywords].to_vec();
+    keywords.push("$seen".to_string());
+    let email_id = client
+        .email_import(
+            b"Subject: keywords\r\n\r\nhello".to_vec(),
+            [&mailbox_id],
+            Some(&keywords),
+            None,
+        )
+        .await
+        .unwrap()
+        .take_id();
+    assert_eq!(server.store.mail_keywords(1).unwrap().len(), max_keywords);
+
+    // Adding a new keyword to an existing message fails
+    assert!(matches!(
+        client
+            .email_set_keyword(&email_id, "new_keyword", true)
+            .await,
+        Err(jmap_client::Error::Set(SetError {
+            type_: SetErrorType::TooManyKeywords,
+            ..
+        }))
+    ));
+    client
+        .email_set_keyword(&email_id, "keyword0", false)
+        .await
+        .unwrap();
+    client
+        .email_set_keyword(&email_id, "new_keyword", true)
+        .await
+        .unwrap();
+
+    // Keywords no longer used by any message are removed
+    client.email_destroy(&email_id).await.unwrap();
+    client
+        .email_import(
+            b"Subject: other keywords\r\n\r\nhello".to_vec(),
+            [&mailbox_id],
+            Some(
+                &(0..max_keywords)
+                    .map(|n| format!("other{}", n))
+                    .collect::<Vec<_>>(),
+            ),
+            None,
+        )
+        .await
+        .unwrap();
+    let registry = server.store.mail_keywords(1).unwrap();
+    assert_eq!(registry.len(), max_keywords);
+    assert!(registry.get_id("keyword1").is_none());
+    assert!(registry.get_id("other1").is_some());
+
+    client.mailbox_destroy(&mailbox_id, true).await.unwrap();
+
+    server.store.assert_is_empty();
+}
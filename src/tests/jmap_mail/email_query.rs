@@ -39,6 +39,7 @@ use store::{
         bitmap::{clear_bits, set_bits},
         key::BitmapKey,
     },
+    write::operation::WriteOperation,
     ColumnFamily, Store,
 };
 
@@ -63,17 +64,16 @@ where
     // Add some "virtual" mailbox ids so create doesn't fail
     server
         .store
-        .db
-        .merge(
+        .write_operations(vec![WriteOperation::merge(
             ColumnFamily::Bitmaps,
-            &BitmapKey::serialize_document_ids(
+            BitmapKey::serialize_document_ids(
                 JMAPId::parse(client.default_account_id())
                     .unwrap()
                     .get_document_id(),
                 Collection::Mailbox,
             ),
-            &set_bits(0..99999),
-        )
+            set_bits(0..99999),
+        )])
         .unwrap();
 
     // Create test messages
@@ -83,17 +83,16 @@ where
     // Remove mailboxes
     server
         .store
-        .db
-        .merge(
+        .write_operations(vec![WriteOperation::merge(
             ColumnFamily::Bitmaps,
-            &BitmapKey::serialize_document_ids(
+            BitmapKey::serialize_document_ids(
                 JMAPId::parse(client.default_account_id())
                     .unwrap()
                     .get_document_id(),
                 Collection::Mailbox,
             ),
-            &clear_bits(0..99999),
-        )
+            clear_bits(0..99999),
+        )])
         .unwrap();
 
     for thread_id in 0..MAX_THREADS {
@@ -381,6 +380,46 @@ pub async fn query(client: &mut Client) {
             expected_results
         );
     }
+
+    // Trailing wildcards match every term starting with the prefix
+    for (prefix, word) in [
+        (
+            email::query::Filter::text("colou*"),
+            email::query::Filter::text("colour"),
+        ),
+        (
+            email::query::Filter::from("georg*"),
+            email::query::Filter::from("george"),
+        ),
+        (
+            email::query::Filter::header("Message-Id", Some("T1096*")),
+            email::query::Filter::header("Message-Id", Some("T10965")),
+        ),
+    ] {
+        let prefix_ids = client
+            .email_query(prefix.into(), None::<Vec<_>>)
+            .await
+            .unwrap()
+            .take_ids();
+        let word_ids = client
+            .email_query(word.into(), None::<Vec<_>>)
+            .await
+            .unwrap()
+            .take_ids();
+        assert!(!word_ids.is_empty());
+        assert!(
+            word_ids.iter().all(|id| prefix_ids.contains(id)),
+            "{:?} not in {:?}",
+            word_ids,
+            prefix_ids
+        );
+    }
+
+    // Prefixes that are too short are rejected
+    assert!(client
+        .email_query(email::query::Filter::text("co*").into(), None::<Vec<_>>)
+        .await
+        .is_err());
 }
 
 pub async fn query_options(client: &mut Client) {
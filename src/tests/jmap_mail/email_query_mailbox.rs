@@ -0,0 +1,140 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::web;
+
+use jmap::{request::query::QueryRequest, types::jmap::JMAPId};
+use jmap_client::{client::Client, mailbox::Role};
+use jmap_mail::mail::{query::JMAPMailQuery, schema::Email};
+use jmap_sharing::principal::account::JMAPAccountStore;
+use store::Store;
+
+use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running Email Query mailbox role and thread tests...");
+
+    let archive_id = client
+        .set_default_account_id(JMAPId::new(1).to_string())
+        .mailbox_create("Archive Role", None::<String>, Role::Archive)
+        .await
+        .unwrap()
+        .take_id();
+    let folder_id = client
+        .mailbox_create("Folder Role", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+
+    // Two messages of the same thread in different mailboxes plus an unrelated one
+    let mut message_ids = Vec::new();
+    for (raw_message, mailbox_id) in [
+        (
+            "Message-ID: <role1@example.com>\nSubject: role test\n\nHello.",
+            &archive_id,
+        ),
+        (
+            "References: <role1@example.com>\nSubject: Re: role test\n\nHi.",
+            &folder_id,
+        ),
+        ("Subject: another role test\n\nBye.", &folder_id),
+    ] {
+        message_ids.push(
+            client
+                .email_import(
+                    raw_message.as_bytes().to_vec(),
+                    [mailbox_id],
+                    None::<Vec<String>>,
+                    None,
+                )
+                .await
+                .unwrap()
+                .take_id(),
+        );
+    }
+
+    for (filter, expected_ids) in [
+        (
+            serde_json::json!({"inMailboxRole": "archive"}),
+            vec![&message_ids[0]],
+        ),
+        (
+            serde_json::json!({"inMailboxRole": "Archive"}),
+            vec![&message_ids[0]],
+        ),
+        (serde_json::json!({"inMailboxRole": "drafts"}), vec![]),
+        (
+            serde_json::json!({"operator": "AND", "conditions": [
+                {"id": &message_ids},
+                {"inMailboxRoleOtherThan": ["archive", "drafts"]}
+            ]}),
+            vec![&message_ids[1], &message_ids[2]],
+        ),
+        (
+            serde_json::json!({"operator": "AND", "conditions": [
+                {"inMailbox": &folder_id},
+                {"someInThreadInMailbox": &archive_id}
+            ]}),
+            vec![&message_ids[1]],
+        ),
+        (
+            serde_json::json!({"operator": "AND", "conditions": [
+                {"inMailbox": &folder_id},
+                {"noneInThreadInMailbox": &archive_id}
+            ]}),
+            vec![&message_ids[2]],
+        ),
+    ] {
+        let mut request = serde_json::from_value::<QueryRequest<Email>>(serde_json::json!({
+            "accountId": JMAPId::new(1).to_string(),
+            "filter": filter.clone(),
+            "sort": [{"property": "receivedAt", "isAscending": true}],
+        }))
+        .unwrap();
+        request.acl = server.store.get_acl_token(1).unwrap().into();
+
+        let mut ids = server
+            .store
+            .mail_query(request)
+            .unwrap()
+            .ids
+            .into_iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        let mut expected_ids = expected_ids
+            .into_iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>();
+        expected_ids.sort_unstable();
+        assert_eq!(ids, expected_ids, "{}", filter);
+    }
+
+    client.mailbox_destroy(&archive_id, true).await.unwrap();
+    client.mailbox_destroy(&folder_id, true).await.unwrap();
+
+    server.store.assert_is_empty();
+}
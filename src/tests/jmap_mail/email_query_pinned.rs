@@ -0,0 +1,156 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::web;
+
+use jmap::{
+    request::query::{QueryRequest, QueryResponse},
+    types::{jmap::JMAPId, state::JMAPState},
+};
+use jmap_client::{client::Client, mailbox::Role};
+use jmap_mail::mail::{query::JMAPMailQuery, schema::Email};
+use jmap_sharing::principal::account::JMAPAccountStore;
+use store::Store;
+
+use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running Email Query pinned state tests...");
+
+    let mailbox_id = client
+        .set_default_account_id(JMAPId::new(1).to_string())
+        .mailbox_create("Pinned Query", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+    let mut message_ids = Vec::new();
+    for num in 0..5 {
+        message_ids.push(
+            client
+                .email_import(
+                    format!("Subject: pinned test {}\n\nHello.", num)
+                        .as_bytes()
+                        .to_vec(),
+                    [&mailbox_id],
+                    None::<Vec<String>>,
+                    Some(10000 + num),
+                )
+                .await
+                .unwrap()
+                .take_id(),
+        );
+    }
+
+    // Pinning the current state takes a snapshot of the results
+    let first_page = query(&server, &mailbox_id, 0, None);
+    let pinned_state = first_page.query_state.clone();
+    let response = query(&server, &mailbox_id, 0, pinned_state.clone().into());
+    assert_eq!(ids(&response), &message_ids[..2]);
+    assert_eq!(response.query_state, pinned_state);
+    assert_eq!(response.total, Some(5));
+
+    // Insert a message at the top, shifting all pages
+    let new_id = client
+        .email_import(
+            b"Subject: pinned test 5\n\nHello.".to_vec(),
+            [&mailbox_id],
+            None::<Vec<String>>,
+            Some(9000),
+        )
+        .await
+        .unwrap()
+        .take_id();
+
+    // Unpinned pages reflect the change
+    let response = query(&server, &mailbox_id, 0, None);
+    assert_eq!(ids(&response), [new_id, message_ids[0].clone()]);
+    assert_ne!(response.query_state, pinned_state);
+    assert_eq!(response.total, Some(6));
+
+    // Pinned pages keep paginating over the snapshot
+    for (position, expected_ids) in [(2, &message_ids[2..4]), (4, &message_ids[4..])] {
+        let response = query(&server, &mailbox_id, position, pinned_state.clone().into());
+        assert_eq!(ids(&response), expected_ids);
+        assert_eq!(response.query_state, pinned_state);
+        assert_eq!(response.total, Some(5));
+    }
+
+    // Without a snapshot, a stale pinned state returns the current results
+    let mut request = request(&mailbox_id, 0, pinned_state.clone().into());
+    request.sort = serde_json::from_value(serde_json::json!([
+        {"property": "receivedAt", "isAscending": false}
+    ]))
+    .unwrap();
+    request.acl = server.store.get_acl_token(1).unwrap().into();
+    let response = server.store.mail_query(request).unwrap();
+    assert_ne!(response.query_state, pinned_state);
+    assert_eq!(
+        ids(&response),
+        [message_ids[4].clone(), message_ids[3].clone()]
+    );
+    assert_eq!(response.total, Some(6));
+
+    client.mailbox_destroy(&mailbox_id, true).await.unwrap();
+
+    server.store.assert_is_empty();
+}
+
+fn request(
+    mailbox_id: &str,
+    position: i32,
+    pinned_state: Option<JMAPState>,
+) -> QueryRequest<Email> {
+    let mut request = serde_json::json!({
+        "accountId": JMAPId::new(1).to_string(),
+        "filter": {"inMailbox": mailbox_id},
+        "sort": [{"property": "receivedAt", "isAscending": true}],
+        "position": position,
+        "limit": 2,
+        "calculateTotal": true,
+    });
+    if let Some(pinned_state) = pinned_state {
+        request["pinnedQueryState"] = serde_json::to_value(pinned_state).unwrap();
+    }
+    serde_json::from_value(request).unwrap()
+}
+
+fn query<T>(
+    server: &JMAPServer<T>,
+    mailbox_id: &str,
+    position: i32,
+    pinned_state: Option<JMAPState>,
+) -> QueryResponse
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let mut request = request(mailbox_id, position, pinned_state);
+    request.acl = server.store.get_acl_token(1).unwrap().into();
+    server.store.mail_query(request).unwrap()
+}
+
+fn ids(response: &QueryResponse) -> Vec<String> {
+    response.ids.iter().map(|id| id.to_string()).collect()
+}
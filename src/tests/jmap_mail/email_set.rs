@@ -33,6 +33,7 @@ use jmap_client::{
     mailbox::Role,
     Error, Set,
 };
+use serde_json::{json, Value};
 use store::Store;
 
 use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
@@ -53,7 +54,10 @@ where
         .take_id();
 
     create(client, &mailbox_id).await;
+    create_from_blob(&server, client, &mailbox_id).await;
+    create_with_new_mailbox(&server, client, &mailbox_id).await;
     update(client, &mailbox_id).await;
+    validate(&server, &mailbox_id).await;
 
     client.mailbox_destroy(&mailbox_id, true).await.unwrap();
 
@@ -188,6 +192,376 @@ async fn create(client: &mut Client, mailbox_id: &str) {
     }
 }
 
+async fn create_from_blob<T>(
+    server: &web::Data<JMAPServer<T>>,
+    client: &mut Client,
+    mailbox_id: &str,
+) where
+    T: for<'x> Store<'x> + 'static,
+{
+    // Unusual folding and spacing that would not survive re-serialization
+    let raw_message = concat!(
+        "From:   \"Jane Doe\" <jane@example.org>\r\n",
+        "To: john@example.org\r\n",
+        "Subject: Raw\r\n import\r\n",
+        "X-Custom:no-space\r\n",
+        "\r\n",
+        "Hello,   world\r\n"
+    );
+    let blob_id = client
+        .upload(None, raw_message.as_bytes().to_vec(), None)
+        .await
+        .unwrap()
+        .take_blob_id();
+
+    let http = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap_or_default();
+    let response = http
+        .post(format!("{}/jmap", server.base_session.base_url()))
+        .bearer_auth("DO_NOT_ATTEMPT_THIS_AT_HOME")
+        .json(&json!({
+            "using": ["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
+            "methodCalls": [
+                ["Email/set", {
+                    "accountId": JMAPId::new(1).to_string(),
+                    "create": {
+                        "a": {
+                            "mailboxIds": {mailbox_id: true},
+                            "keywords": {"$seen": true},
+                            "blobId": blob_id,
+                            "receivedAt": "2020-01-01T00:00:00Z"
+                        },
+                        "b": {
+                            "mailboxIds": {mailbox_id: true},
+                            "blobId": blob_id,
+                            "subject": "Not allowed"
+                        }
+                    }
+                }, "c0"],
+                ["Email/get", {
+                    "accountId": JMAPId::new(1).to_string(),
+                    "#ids": {
+                        "resultOf": "c0",
+                        "name": "Email/set",
+                        "path": "/created/*/id"
+                    },
+                    "properties": ["blobId", "size", "receivedAt", "subject", "keywords"]
+                }, "c1"]
+            ]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+
+    // Properties stored in the message cannot be overridden
+    let not_created = &response["methodResponses"][0][1]["notCreated"];
+    assert_eq!(
+        not_created["b"]["type"], "invalidProperties",
+        "{}",
+        response
+    );
+    assert_eq!(
+        not_created["b"]["properties"],
+        json!(["subject"]),
+        "{}",
+        response
+    );
+
+    // The uploaded blob is the message, with the client supplied receivedAt
+    let created = &response["methodResponses"][0][1]["created"]["a"];
+    assert_eq!(created["blobId"], blob_id, "{}", response);
+    assert_eq!(created["size"], raw_message.len(), "{}", response);
+    let email = &response["methodResponses"][1][1]["list"][0];
+    assert_eq!(email["blobId"], blob_id, "{}", response);
+    assert_eq!(email["size"], raw_message.len(), "{}", response);
+    assert_eq!(email["receivedAt"], "2020-01-01T00:00:00Z", "{}", response);
+    assert_eq!(email["subject"], "Raw import", "{}", response);
+    assert_eq!(email["keywords"], json!({"$seen": true}), "{}", response);
+    assert_eq!(
+        client.download(&blob_id).await.unwrap(),
+        raw_message.as_bytes()
+    );
+
+    client
+        .email_destroy(created["id"].as_str().unwrap())
+        .await
+        .unwrap();
+}
+
+async fn create_with_new_mailbox<T>(
+    server: &web::Data<JMAPServer<T>>,
+    client: &mut Client,
+    mailbox_id: &str,
+) where
+    T: for<'x> Store<'x> + 'static,
+{
+    let http = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap_or_default();
+    let request = |name: &str, emails: Value, is_linked: bool| {
+        let mut using = vec!["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"];
+        if is_linked {
+            using.push("urn:stalwart:params:jmap:linkedsets");
+        }
+        json!({
+            "using": using,
+            "methodCalls": [
+                ["Mailbox/set", {
+                    "accountId": JMAPId::new(1).to_string(),
+                    "create": {
+                        "newbox": {
+                            "name": name,
+                            "parentId": mailbox_id
+                        }
+                    }
+                }, "c0"],
+                ["Email/set", {
+                    "accountId": JMAPId::new(1).to_string(),
+                    "create": emails
+                }, "c1"],
+                ["Mailbox/query", {
+                    "accountId": JMAPId::new(1).to_string(),
+                    "filter": {"name": name}
+                }, "c2"]
+            ]
+        })
+    };
+    let send = |body: Value| {
+        let http = http.clone();
+        async move {
+            http.post(format!("{}/jmap", server.base_session.base_url()))
+                .bearer_auth("DO_NOT_ATTEMPT_THIS_AT_HOME")
+                .json(&body)
+                .send()
+                .await
+                .unwrap()
+                .json::<Value>()
+                .await
+                .unwrap()
+        }
+    };
+
+    // Both calls succeed, messages in the same thread are committed one by one
+    // so the second one is threaded with the first.
+    let response = send(request(
+        "Linked",
+        json!({
+            "a": {
+                "mailboxIds": {"#newbox": true},
+                "subject": "Linked",
+                "messageId": ["linked-1@example.org"],
+                "textBody": [{"partId": "1", "type": "text/plain"}],
+                "bodyValues": {"1": {"value": "Hello"}}
+            },
+            "b": {
+                "mailboxIds": {"#newbox": true},
+                "subject": "Re: Linked",
+                "messageId": ["linked-2@example.org"],
+                "inReplyTo": ["linked-1@example.org"],
+                "references": ["linked-1@example.org"],
+                "textBody": [{"partId": "1", "type": "text/plain"}],
+                "bodyValues": {"1": {"value": "Hello again"}}
+            }
+        }),
+        true,
+    ))
+    .await;
+
+    let new_mailbox_id = response["methodResponses"][0][1]["created"]["newbox"]["id"]
+        .as_str()
+        .unwrap_or_else(|| panic!("{}", response))
+        .to_string();
+    assert_ne!(
+        response["methodResponses"][0][1]["newState"],
+        response["methodResponses"][0][1]["oldState"],
+        "{}",
+        response
+    );
+    let created = &response["methodResponses"][1][1]["created"];
+    assert!(created["a"]["threadId"].is_string(), "{}", response);
+    assert_eq!(
+        created["a"]["threadId"], created["b"]["threadId"],
+        "{}",
+        response
+    );
+    assert_eq!(
+        response["methodResponses"][2][1]["ids"],
+        json!([new_mailbox_id]),
+        "{}",
+        response
+    );
+    client.mailbox_destroy(&new_mailbox_id, true).await.unwrap();
+
+    // The new mailbox is kept once any of the messages is created
+    let response = send(request(
+        "Partially linked",
+        json!({
+            "a": {
+                "mailboxIds": {"#newbox": true},
+                "subject": "Partially linked",
+                "textBody": [{"partId": "1", "type": "text/plain"}],
+                "bodyValues": {"1": {"value": "Hello"}}
+            },
+            "b": {
+                "mailboxIds": {"#newbox": true},
+                "blobId": "invalid"
+            }
+        }),
+        true,
+    ))
+    .await;
+    let new_mailbox_id = response["methodResponses"][0][1]["created"]["newbox"]["id"]
+        .as_str()
+        .unwrap_or_else(|| panic!("{}", response))
+        .to_string();
+    assert!(
+        response["methodResponses"][1][1]["created"]["a"]["id"].is_string(),
+        "{}",
+        response
+    );
+    assert!(
+        response["methodResponses"][1][1]["notCreated"]["b"].is_object(),
+        "{}",
+        response
+    );
+    assert_eq!(
+        response["methodResponses"][2][1]["ids"],
+        json!([new_mailbox_id]),
+        "{}",
+        response
+    );
+    client.mailbox_destroy(&new_mailbox_id, true).await.unwrap();
+
+    // An Email/set call that changes nothing discards the new mailbox
+    let invalid_email = json!({
+        "a": {
+            "mailboxIds": {"#newbox": true},
+            "blobId": "invalid"
+        }
+    });
+    let response = send(request("Rolled back", invalid_email.clone(), true)).await;
+
+    assert_eq!(
+        response["methodResponses"][0][1]["notCreated"]["newbox"]["type"], "rolledBack",
+        "{}",
+        response
+    );
+    assert_eq!(
+        response["methodResponses"][0][1]["newState"],
+        response["methodResponses"][0][1]["oldState"],
+        "{}",
+        response
+    );
+    assert!(
+        response["methodResponses"][1][1]["notCreated"]["a"].is_object(),
+        "{}",
+        response
+    );
+    assert_eq!(
+        response["methodResponses"][2][1]["ids"],
+        json!([]),
+        "{}",
+        response
+    );
+
+    // Calls are only linked when the client opts in
+    let response = send(request("Not linked", invalid_email, false)).await;
+    let new_mailbox_id = response["methodResponses"][0][1]["created"]["newbox"]["id"]
+        .as_str()
+        .unwrap_or_else(|| panic!("{}", response))
+        .to_string();
+    assert_eq!(
+        response["methodResponses"][2][1]["ids"],
+        json!([new_mailbox_id]),
+        "{}",
+        response
+    );
+    client.mailbox_destroy(&new_mailbox_id, true).await.unwrap();
+}
+
+async fn validate<T>(server: &web::Data<JMAPServer<T>>, mailbox_id: &str)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let http = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap_or_default();
+    let response = http
+        .post(format!("{}/jmap", server.base_session.base_url()))
+        .bearer_auth("DO_NOT_ATTEMPT_THIS_AT_HOME")
+        .json(&json!({
+            "using": ["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
+            "methodCalls": [
+                ["Email/set", {
+                    "accountId": JMAPId::new(1).to_string(),
+                    "create": {
+                        "a": {
+                            "mailboxIds": {mailbox_id: true},
+                            "subject": 3,
+                            "bodyValues": {"1": {"value": "Hello"}},
+                            "bodyStructure": {
+                                "type": "multipart/mixed",
+                                "subParts": [
+                                    {"partId": "1", "type": "text/plain"},
+                                    {"partId": 5, "type": "text/plain"},
+                                    {"partId": "2", "charset": "us-ascii"}
+                                ]
+                            }
+                        },
+                        "b": {
+                            "mailboxIds": {mailbox_id: true},
+                            "textBody": [{"partId": "1", "type": "text/html"}],
+                            "bodyValues": {"1": {"value": "Hello"}}
+                        }
+                    }
+                }, "c0"]
+            ]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+
+    // Each violation is reported with its JSON pointer path
+    let not_created = &response["methodResponses"][0][1]["notCreated"];
+    assert_eq!(
+        not_created["a"]["type"], "invalidProperties",
+        "{}",
+        response
+    );
+    assert_eq!(
+        not_created["a"]["properties"],
+        json!([
+            "subject",
+            "bodyStructure/subParts/1/partId",
+            "bodyStructure/subParts/2/partId",
+            "bodyStructure/subParts/2/charset"
+        ]),
+        "{}",
+        response
+    );
+    assert_eq!(
+        not_created["b"]["type"], "invalidProperties",
+        "{}",
+        response
+    );
+    assert_eq!(
+        not_created["b"]["properties"],
+        json!(["textBody/0/type"]),
+        "{}",
+        response
+    );
+}
+
 async fn update(client: &mut Client, root_mailbox_id: &str) {
     // Obtain all messageIds previously created
     let mailbox = client
@@ -0,0 +1,172 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::web;
+
+use jmap::types::jmap::JMAPId;
+use jmap_client::{client::Client, email, mailbox::Role};
+use serde_json::{json, Value};
+use store::Store;
+
+use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running Email/setKeywordsByQuery tests...");
+
+    let mailbox_id = client
+        .set_default_account_id(JMAPId::new(1).to_string())
+        .mailbox_create("Bulk Keywords", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+    let mut message_ids = Vec::new();
+    for (num, keywords) in [
+        vec![],
+        vec!["$seen"],
+        vec![],
+        vec!["$seen", "$flagged"],
+        vec!["$flagged"],
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        message_ids.push(
+            client
+                .email_import(
+                    format!("Subject: bulk test {}\n\nHello world.", num).into_bytes(),
+                    [&mailbox_id],
+                    Some(keywords),
+                    None,
+                )
+                .await
+                .unwrap()
+                .take_id(),
+        );
+    }
+
+    // Only messages requiring changes are updated, up to the requested limit
+    let response = set_keywords(
+        &server,
+        json!({
+            "filter": {"inMailbox": &mailbox_id},
+            "keywords": {"$seen": true},
+            "limit": 2,
+        }),
+    )
+    .await;
+    assert_eq!(response[0], "Email/setKeywordsByQuery", "{}", response);
+    assert_eq!(response[1]["total"], 3, "{}", response);
+    assert_eq!(response[1]["updated"], 2, "{}", response);
+    assert_eq!(response[1]["hasMore"], true, "{}", response);
+    assert_ne!(response[1]["oldState"], response[1]["newState"]);
+    let old_state = response[1]["oldState"].as_str().unwrap().to_string();
+    let new_state = response[1]["newState"].as_str().unwrap().to_string();
+
+    // Stale states are rejected
+    let response = set_keywords(
+        &server,
+        json!({
+            "ifInState": old_state,
+            "filter": {"inMailbox": &mailbox_id},
+            "keywords": {"$seen": true},
+        }),
+    )
+    .await;
+    assert_eq!(response[0], "error", "{}", response);
+    assert_eq!(response[1]["type"], "stateMismatch", "{}", response);
+
+    // Messages are written in chunks, each one logging its changes
+    let response = set_keywords(
+        &server,
+        json!({
+            "filter": {"inMailbox": &mailbox_id},
+            "keywords": {"$seen": true, "$flagged": false},
+        }),
+    )
+    .await;
+    assert_eq!(response[1]["total"], 3, "{}", response);
+    assert_eq!(response[1]["updated"], 3, "{}", response);
+    assert_eq!(response[1]["hasMore"], false, "{}", response);
+    let changes = client.email_changes(new_state, None).await.unwrap();
+    assert_eq!(changes.updated().len(), 3);
+    assert_eq!(
+        changes.new_state(),
+        response[1]["newState"].as_str().unwrap()
+    );
+
+    for message_id in &message_ids {
+        let email = client
+            .email_get(message_id, [email::Property::Keywords].into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(email.keywords(), ["$seen"]);
+    }
+
+    // Nothing left to change, the state stays the same
+    let response = set_keywords(
+        &server,
+        json!({
+            "filter": {"inMailbox": &mailbox_id},
+            "keywords": {"$seen": true},
+        }),
+    )
+    .await;
+    assert_eq!(response[1]["updated"], 0, "{}", response);
+    assert_eq!(response[1]["oldState"], response[1]["newState"]);
+
+    // At least one keyword is required
+    let response = set_keywords(&server, json!({"keywords": {}})).await;
+    assert_eq!(response[1]["type"], "invalidArguments", "{}", response);
+
+    client.mailbox_destroy(&mailbox_id, true).await.unwrap();
+
+    server.store.assert_is_empty();
+}
+
+async fn set_keywords<T>(server: &JMAPServer<T>, mut arguments: Value) -> Value
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    arguments["accountId"] = JMAPId::new(1).to_string().into();
+    let mut response = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap_or_default()
+        .post(format!("{}/jmap", server.base_session.base_url()))
+        .bearer_auth("DO_NOT_ATTEMPT_THIS_AT_HOME")
+        .json(&json!({
+            "using": ["urn:ietf:params:jmap:core", "urn:ietf:params:jmap:mail"],
+            "methodCalls": [["Email/setKeywordsByQuery", arguments, "c0"]]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    response["methodResponses"][0].take()
+}
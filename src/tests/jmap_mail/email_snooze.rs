@@ -0,0 +1,207 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::web;
+
+use jmap::{request::set::SetRequest, types::jmap::JMAPId};
+use jmap_client::{client::Client, email, mailbox::Role};
+use jmap_mail::{
+    mail::{renumber::JMAPMailRenumber, schema::Email, set::JMAPSetMail, snooze::JMAPMailSnooze},
+    mailbox::{schema::Mailbox, set::JMAPSetMailbox},
+};
+use jmap_sharing::principal::account::JMAPAccountStore;
+use store::{ahash::AHashSet, core::collection::Collection, Store};
+
+use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running Email snooze tests...");
+
+    let folder_id = client
+        .set_default_account_id(JMAPId::new(1).to_string())
+        .mailbox_create("Snooze Folder", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+    let other_folder_id = client
+        .mailbox_create("Other Snooze Folder", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+    let gap_id = client
+        .email_import(
+            b"Subject: gap\n\nLeaves a gap in the ids.".to_vec(),
+            [&folder_id],
+            None::<Vec<&str>>,
+            None,
+        )
+        .await
+        .unwrap()
+        .take_id();
+    let mut message_id = client
+        .email_import(
+            b"Subject: snooze test\n\nWake me up later.".to_vec(),
+            [&folder_id, &other_folder_id],
+            Some(vec!["$seen"]),
+            None,
+        )
+        .await
+        .unwrap()
+        .take_id();
+    client.email_destroy(&gap_id).await.unwrap();
+
+    // Snoozing requires a mailbox with the snoozed role
+    let snooze = serde_json::json!({"until": "2022-01-01T00:00:00Z"});
+    assert_eq!(
+        email_update(&server, &message_id, serde_json::json!({"snoozed": snooze}))["notUpdated"]
+            [&message_id]["type"],
+        "invalidProperties"
+    );
+    let mut request = serde_json::from_value::<SetRequest<Mailbox>>(serde_json::json!({
+        "accountId": JMAPId::new(1).to_string(),
+        "create": {"s": {"name": "Snoozed", "role": "snoozed"}},
+    }))
+    .unwrap();
+    request.acl = server.store.get_acl_token(1).unwrap().into();
+    let snoozed_id = serde_json::to_value(server.store.mailbox_set(request).unwrap()).unwrap()
+        ["created"]["s"]["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // Snoozed messages are moved to the snoozed mailbox
+    let response = email_update(&server, &message_id, serde_json::json!({"snoozed": snooze}));
+    assert!(
+        response["updated"].get(&message_id).is_some(),
+        "{}",
+        response
+    );
+    let email = client
+        .email_get(
+            &message_id,
+            [email::Property::MailboxIds, email::Property::Keywords].into(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(email.mailbox_ids(), [snoozed_id.as_str()]);
+
+    // Wake-up times are found through an index that follows renumbered ids
+    server.store.mail_renumber(1, Collection::Mail).unwrap();
+    message_id = client
+        .email_query(
+            email::query::Filter::in_mailbox(&snoozed_id).into(),
+            None::<Vec<_>>,
+        )
+        .await
+        .unwrap()
+        .take_ids()
+        .pop()
+        .unwrap();
+
+    // Messages are moved back as unread to all their mailboxes once the snooze
+    // period is over
+    assert!(server.store.mail_snooze_wake(1).unwrap().is_some());
+    assert!(server.store.mail_snooze_wake(1).unwrap().is_none());
+    let email = client
+        .email_get(
+            &message_id,
+            [email::Property::MailboxIds, email::Property::Keywords].into(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        email.mailbox_ids().into_iter().collect::<AHashSet<_>>(),
+        [folder_id.as_str(), other_folder_id.as_str()]
+            .into_iter()
+            .collect::<AHashSet<_>>()
+    );
+    assert!(email.keywords().is_empty());
+
+    // Snoozes in the future are kept until woken up explicitly
+    let response = email_update(
+        &server,
+        &message_id,
+        serde_json::json!({"snoozed": {"until": "2100-01-01T00:00:00Z"}}),
+    );
+    assert!(
+        response["updated"].get(&message_id).is_some(),
+        "{}",
+        response
+    );
+    assert!(server.store.mail_snooze_wake(1).unwrap().is_none());
+    email_update(&server, &message_id, serde_json::json!({"snoozed": null}));
+    let email = client
+        .email_get(
+            &message_id,
+            [email::Property::MailboxIds, email::Property::Keywords].into(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        email.mailbox_ids().into_iter().collect::<AHashSet<_>>(),
+        [folder_id.as_str(), other_folder_id.as_str()]
+            .into_iter()
+            .collect::<AHashSet<_>>()
+    );
+
+    // Destroying a snoozed message removes its wake-up time
+    email_update(
+        &server,
+        &message_id,
+        serde_json::json!({"snoozed": {"until": "2022-01-01T00:00:00Z"}}),
+    );
+    client.email_destroy(&message_id).await.unwrap();
+    assert!(server.store.mail_snooze_wake(1).unwrap().is_none());
+
+    client
+        .mailbox_destroy(&other_folder_id, true)
+        .await
+        .unwrap();
+    client.mailbox_destroy(&folder_id, true).await.unwrap();
+    client.mailbox_destroy(&snoozed_id, true).await.unwrap();
+
+    server.store.assert_is_empty();
+}
+
+fn email_update<T>(
+    server: &web::Data<JMAPServer<T>>,
+    message_id: &str,
+    update: serde_json::Value,
+) -> serde_json::Value
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let mut request = serde_json::from_value::<SetRequest<Email>>(serde_json::json!({
+        "accountId": JMAPId::new(1).to_string(),
+        "update": {message_id: update},
+    }))
+    .unwrap();
+    request.acl = server.store.get_acl_token(1).unwrap().into();
+    serde_json::to_value(server.store.mail_set(request).unwrap()).unwrap()
+}
@@ -23,9 +23,16 @@
 
 use actix_web::web;
 
-use jmap::types::jmap::JMAPId;
+use jmap::types::{date::JMAPDate, jmap::JMAPId};
 use jmap_client::{client::Client, mailbox::Role};
-use store::Store;
+use jmap_mail::{
+    mail::MessageField,
+    thread::{preview::JMAPThreadPreview, schema::Property},
+};
+use store::{
+    core::{collection::Collection, tag::Tag},
+    Store,
+};
 
 use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
 
@@ -48,7 +55,11 @@ where
     for num in [5, 3, 1, 2, 4] {
         let mut email = client
             .email_import(
-                format!("Subject: test\nReferences: <1234>\n\n{}", num).into_bytes(),
+                format!(
+                    "From: sender{}@example.com\nSubject: test\nReferences: <1234>\n\n{}",
+                    num, num
+                )
+                .into_bytes(),
                 [&mailbox_id],
                 None::<Vec<String>>,
                 Some(10000i64 + num as i64),
@@ -69,6 +80,56 @@ where
         expected_result
     );
 
+    // Cached aggregates are recomputed when the thread contents differ
+    let thread_doc_id = JMAPId::parse(&thread_id).unwrap().get_document_id();
+    let mut doc_ids = server
+        .store
+        .get_tag(
+            1,
+            Collection::Mail,
+            MessageField::ThreadId.into(),
+            Tag::Id(thread_doc_id),
+        )
+        .unwrap()
+        .unwrap();
+    let properties = [Property::ReceivedAt, Property::Participants];
+    for expected_latest in [5, 4] {
+        let preview = server
+            .store
+            .thread_preview(1, thread_doc_id, &doc_ids, &properties)
+            .unwrap();
+        assert_eq!(
+            preview.received_at,
+            Some(JMAPDate::from_timestamp(10000 + expected_latest))
+        );
+        assert_eq!(
+            preview
+                .participants
+                .unwrap()
+                .into_iter()
+                .map(|address| address.email)
+                .collect::<Vec<_>>(),
+            (1..=expected_latest)
+                .rev()
+                .map(|num| format!("sender{}@example.com", num))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            server
+                .store
+                .thread_previews
+                .get(&(1, thread_doc_id))
+                .unwrap()
+                .document_ids,
+            doc_ids
+        );
+        doc_ids.remove(
+            JMAPId::parse(&expected_result[expected_latest as usize - 1])
+                .unwrap()
+                .get_document_id(),
+        );
+    }
+
     client.mailbox_destroy(&mailbox_id, true).await.unwrap();
 
     server.store.assert_is_empty();
@@ -0,0 +1,245 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::web;
+
+use jmap::{
+    orm::{serialize::JMAPOrm, TinyORM},
+    types::jmap::JMAPId,
+};
+use jmap_client::{
+    client::Client,
+    core::set::{SetError, SetErrorType},
+    email,
+    mailbox::Role,
+};
+use jmap_mail::{
+    mail::{
+        expunge::JMAPMailExpunge, retention::JMAPMailRetention, set::JMAPSetMail, MessageField,
+    },
+    mailbox::schema::{Mailbox, Property, Value},
+};
+use store::{
+    core::{collection::Collection, document::Document},
+    write::{
+        batch::WriteBatch,
+        options::{IndexOptions, Options},
+    },
+    Store,
+};
+
+use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running Expunge tests...");
+
+    let mailbox_id = client
+        .set_default_account_id(JMAPId::new(1).to_string())
+        .mailbox_create("Expunge", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+
+    let mut message_ids = Vec::new();
+    for received_at in [Some(311923920), None] {
+        message_ids.push(
+            client
+                .email_import(
+                    b"Subject: expunge test\n\nHello world.".to_vec(),
+                    [&mailbox_id],
+                    None::<Vec<String>>,
+                    received_at,
+                )
+                .await
+                .unwrap()
+                .take_id(),
+        );
+    }
+
+    // Mailboxes without a policy are left untouched
+    let report = server.store.mail_expunge(1, true).unwrap();
+    assert!(report.mailboxes.is_empty());
+
+    // Expunge messages older than a week
+    let document_id = JMAPId::parse(&mailbox_id).unwrap().get_document_id();
+    let current_fields = server
+        .store
+        .get_orm::<Mailbox>(1, document_id)
+        .unwrap()
+        .unwrap();
+    let mut fields = TinyORM::track_changes(&current_fields);
+    fields.set(Property::ExpungeDays, Value::Number { value: 7 });
+    let mut document = Document::new(Collection::Mailbox, document_id);
+    current_fields.merge(&mut document, fields).unwrap();
+    let mut batch = WriteBatch::new(1);
+    batch.update_document(document);
+    batch.log_update(Collection::Mailbox, document_id);
+    server.store.write(batch).unwrap();
+
+    // A receivedAt set by the client does not age a message
+    let report = server.store.mail_expunge(1, true).unwrap();
+    assert!(report.mailboxes.is_empty());
+
+    // Backdate the time the first message was stored
+    let message_document_id = JMAPId::parse(&message_ids[0]).unwrap().get_document_id();
+    let inserted_at = server
+        .store
+        .get_document_value::<u64>(
+            1,
+            Collection::Mail,
+            message_document_id,
+            MessageField::InsertedAt.into(),
+        )
+        .unwrap()
+        .unwrap();
+    for (inserted_at, options) in [
+        (inserted_at, IndexOptions::new().store().index().clear()),
+        (311923920, IndexOptions::new().store().index()),
+    ] {
+        let mut document = Document::new(Collection::Mail, message_document_id);
+        document.number(MessageField::InsertedAt, inserted_at, options);
+        let mut batch = WriteBatch::new(1);
+        batch.update_document(document);
+        server.store.write(batch).unwrap();
+    }
+
+    // A dry run reports the old message without deleting it
+    let report = server.store.mail_expunge(1, true).unwrap();
+    assert_eq!(report.mailboxes.len(), 1);
+    assert_eq!(report.mailboxes[0].days, 7);
+    assert_eq!(
+        report.mailboxes[0]
+            .destroyed
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>(),
+        vec![message_ids[0].clone()]
+    );
+    assert!(report.change_id.is_none());
+    assert!(client
+        .email_get(&message_ids[0], [email::Property::Id].into())
+        .await
+        .unwrap()
+        .is_some());
+
+    // Messages in a mailbox under legal hold are neither expunged nor destroyed
+    set_legal_hold(&server, document_id, true);
+    let report = server.store.mail_expunge(1, false).unwrap();
+    assert_eq!(report.total_destroyed(), 0);
+    assert_eq!(report.mailboxes[0].retained.len(), 1);
+    assert!(report.change_id.is_none());
+    assert!(matches!(
+        client.email_destroy(&message_ids[0]).await,
+        Err(jmap_client::Error::Set(SetError {
+            type_: SetErrorType::Forbidden,
+            ..
+        }))
+    ));
+    assert!(matches!(
+        client.mailbox_destroy(&mailbox_id, true).await,
+        Err(jmap_client::Error::Set(SetError {
+            type_: SetErrorType::Forbidden,
+            ..
+        }))
+    ));
+
+    // Moving a held message elsewhere before destroying it is not allowed either
+    let other_mailbox_id = client
+        .mailbox_create("Elsewhere", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+    assert!(matches!(
+        client
+            .email_set_mailboxes(&message_ids[0], [&other_mailbox_id])
+            .await,
+        Err(jmap_client::Error::Set(SetError {
+            type_: SetErrorType::Forbidden,
+            ..
+        }))
+    ));
+    assert!(server.store.mail_is_held(1, message_document_id).unwrap());
+    assert!(server.store.mailbox_holds(1).unwrap().contains(document_id));
+    assert!(server
+        .store
+        .mail_delete(
+            1,
+            None,
+            &mut Document::new(Collection::Mail, message_document_id)
+        )
+        .is_err());
+    client
+        .mailbox_destroy(&other_mailbox_id, true)
+        .await
+        .unwrap();
+    set_legal_hold(&server, document_id, false);
+    assert!(!server.store.mail_is_held(1, message_document_id).unwrap());
+
+    // Expunge the old message
+    let report = server.store.mail_expunge(1, false).unwrap();
+    assert_eq!(report.total_destroyed(), 1);
+    assert!(report.change_id.is_some());
+    assert!(client
+        .email_get(&message_ids[0], [email::Property::Id].into())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(client
+        .email_get(&message_ids[1], [email::Property::Id].into())
+        .await
+        .unwrap()
+        .is_some());
+
+    client.mailbox_destroy(&mailbox_id, true).await.unwrap();
+
+    server.store.assert_is_empty();
+}
+
+fn set_legal_hold<T>(server: &web::Data<JMAPServer<T>>, document_id: u32, value: bool)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let current_fields = server
+        .store
+        .get_orm::<Mailbox>(1, document_id)
+        .unwrap()
+        .unwrap();
+    let mut fields = TinyORM::track_changes(&current_fields);
+    fields.set(
+        Property::LegalHold,
+        if value {
+            Value::Bool { value }
+        } else {
+            Value::Null
+        },
+    );
+    let mut document = Document::new(Collection::Mailbox, document_id);
+    current_fields.merge(&mut document, fields).unwrap();
+    let mut batch = WriteBatch::new(1);
+    batch.update_document(document);
+    batch.log_update(Collection::Mailbox, document_id);
+    server.store.write(batch).unwrap();
+}
@@ -29,14 +29,34 @@ use jmap_client::{
     client::Client,
     core::set::{SetError, SetErrorType},
 };
-use jmap_sharing::principal::set::JMAPSetPrincipal;
-use store::{core::collection::Collection, Store};
+use jmap_sharing::principal::{
+    forwarding::{
+        ForwardingGetRequest, ForwardingSetRequest, ForwardingUpdate, JMAPAccountForwarding,
+    },
+    set::JMAPSetPrincipal,
+    subaddress::{
+        JMAPAccountSubAddress, SubAddressGetRequest, SubAddressSetRequest, SubAddressUpdate,
+    },
+};
+use store::{
+    core::{collection::Collection, vec_map::VecMap},
+    Store,
+};
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf},
     net::TcpStream,
 };
 
-use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
+use crate::{
+    lmtp::{
+        authentication::{dmarc::DmarcOutput, AuthOutcome, AuthResult, Policy},
+        ingest::{DeliveryStatus, JMAPMailIngest},
+        session::RcptType,
+        trace::{JMAPIngestTrace, TraceEvent},
+    },
+    tests::store::utils::StoreCompareWith,
+    JMAPServer,
+};
 
 pub async fn test<T>(server: web::Data<JMAPServer<T>>, client: &mut Client)
 where
@@ -300,12 +320,488 @@ where
         );
     }
 
+    // Nested groups are expanded to their individual members
+    let group_id = client
+        .group_create("team@example.com", "Team", [&list_id, &account_id_1])
+        .await
+        .unwrap()
+        .take_id();
+    let outer_group_id = client
+        .group_create(
+            "everyone@example.com",
+            "Everyone",
+            [&group_id, &account_id_2],
+        )
+        .await
+        .unwrap()
+        .take_id();
+    assert!(matches!(
+        client
+            .principal_set_members(&group_id, [&list_id, &outer_group_id].into())
+            .await,
+        Err(jmap_client::Error::Set(SetError {
+            type_: SetErrorType::InvalidProperties,
+            ..
+        }))
+    ));
+    lmtp.ingest(
+        "bill@example.com",
+        &["everyone@example.com"],
+        concat!(
+            "From: bill@example.com\r\n",
+            "To: everyone@example.com\r\n",
+            "Subject: Office party\r\n",
+            "\r\n",
+            "Bring your TPS reports."
+        ),
+    )
+    .await;
+    for (account_id, num_messages) in [
+        (&account_id_1, 5),
+        (&account_id_2, 4),
+        (&account_id_3, 4),
+        (&group_id, 0),
+        (&outer_group_id, 0),
+    ] {
+        assert_eq!(
+            server
+                .store
+                .get_document_ids(
+                    JMAPId::parse(account_id).unwrap().get_document_id(),
+                    Collection::Mail
+                )
+                .unwrap()
+                .map_or(0, |ids| ids.len()),
+            num_messages,
+            "for {}",
+            account_id
+        );
+    }
+
+    // Sub-addresses are routed to the base account without being recorded
+    let document_id_1 = JMAPId::parse(&account_id_1).unwrap().get_document_id();
+    let num_mailboxes = server
+        .store
+        .get_document_ids(document_id_1, Collection::Mailbox)
+        .unwrap()
+        .unwrap()
+        .len();
+    lmtp.ingest(
+        "bill@example.com",
+        &["jdoe+reports@example.com"],
+        concat!(
+            "From: bill@example.com\r\n",
+            "To: jdoe+reports@example.com\r\n",
+            "Subject: TPS Report cover sheets\r\n",
+            "\r\n",
+            "Did you get the memo about the new cover sheets?"
+        ),
+    )
+    .await;
+    assert_eq!(
+        server
+            .store
+            .get_document_ids(document_id_1, Collection::Mail)
+            .unwrap()
+            .unwrap()
+            .len(),
+        6
+    );
+    let response = server
+        .store
+        .subaddress_get(SubAddressGetRequest {
+            acl: None,
+            account_id: JMAPId::from(document_id_1),
+            ids: None,
+        })
+        .unwrap();
+    assert!(response.list.is_empty());
+
+    // Unregistered sub-addresses are never auto-filed
+    lmtp.ingest(
+        "bill@example.com",
+        &["jdoe+spam1@example.com", "jdoe+spam2@example.com"],
+        concat!(
+            "From: bill@example.com\r\n",
+            "To: jdoe+spam1@example.com\r\n",
+            "Subject: Mailbox flood\r\n",
+            "\r\n",
+            "One mailbox per tag?"
+        ),
+    )
+    .await;
+    assert_eq!(
+        server
+            .store
+            .get_document_ids(document_id_1, Collection::Mailbox)
+            .unwrap()
+            .unwrap()
+            .len(),
+        num_mailboxes
+    );
+
+    // Registered sub-addresses are filed into a mailbox named after the tag
+    let response = server
+        .store
+        .subaddress_set(SubAddressSetRequest {
+            acl: None,
+            account_id: JMAPId::from(document_id_1),
+            update: Some(VecMap::from_iter([(
+                "Reports".to_string(),
+                SubAddressUpdate {
+                    is_disabled: None,
+                    auto_file: true.into(),
+                },
+            )])),
+            destroy: None,
+        })
+        .unwrap();
+    assert_eq!(response.updated, vec!["Reports".to_string()]);
+    lmtp.ingest(
+        "bill@example.com",
+        &["jdoe+REPORTS@example.com"],
+        concat!(
+            "From: bill@example.com\r\n",
+            "To: jdoe+REPORTS@example.com\r\n",
+            "Subject: TPS Report cover sheets (filed)\r\n",
+            "\r\n",
+            "Did you get the memo about the new cover sheets?"
+        ),
+    )
+    .await;
+    assert_eq!(
+        server
+            .store
+            .get_document_ids(document_id_1, Collection::Mailbox)
+            .unwrap()
+            .unwrap()
+            .len(),
+        num_mailboxes + 1
+    );
+    assert_eq!(
+        server
+            .store
+            .get_document_ids(document_id_1, Collection::Mail)
+            .unwrap()
+            .unwrap()
+            .len(),
+        8
+    );
+
+    // Disabled sub-addresses bounce at delivery
+    let response = server
+        .store
+        .subaddress_set(SubAddressSetRequest {
+            acl: None,
+            account_id: JMAPId::from(document_id_1),
+            update: Some(VecMap::from_iter([(
+                "reports".to_string(),
+                SubAddressUpdate {
+                    is_disabled: true.into(),
+                    auto_file: None,
+                },
+            )])),
+            destroy: None,
+        })
+        .unwrap();
+    assert_eq!(response.updated, vec!["reports".to_string()]);
+    lmtp.ingest_with_code(
+        "bill@example.com",
+        &["jdoe+reports@example.com"],
+        concat!(
+            "From: bill@example.com\r\n",
+            "To: jdoe+reports@example.com\r\n",
+            "Subject: TPS Report cover sheets (again)\r\n",
+            "\r\n",
+            "Did you get the memo about the new cover sheets?"
+        ),
+        5,
+    )
+    .await;
+    assert_eq!(
+        server
+            .store
+            .get_document_ids(document_id_1, Collection::Mail)
+            .unwrap()
+            .unwrap()
+            .len(),
+        8
+    );
+
+    // Forwarding is advertised, external addresses are allowed as an SRS secret is set
+    let session = serde_json::to_value(&server.base_session).unwrap();
+    let capability = &session["capabilities"]["urn:stalwart:params:jmap:forwarding"];
+    assert_eq!(capability["mayForwardExternally"], true, "{}", session);
+    assert!(capability["maxAddresses"].as_u64().unwrap() > 0);
+
+    // Forwarded messages are not kept unless requested
+    let document_id_2 = JMAPId::parse(&account_id_2).unwrap().get_document_id();
+    let response = server
+        .store
+        .forwarding_set(ForwardingSetRequest {
+            acl: None,
+            account_id: JMAPId::from(document_id_2),
+            update: Some(VecMap::from_iter([
+                (
+                    "singleton".to_string(),
+                    ForwardingUpdate {
+                        is_enabled: true.into(),
+                        addresses: vec!["Jane.Smith@example.net".to_string()].into(),
+                        keep_copy: false.into(),
+                    },
+                ),
+                (
+                    "other".to_string(),
+                    ForwardingUpdate {
+                        is_enabled: None,
+                        addresses: vec!["jane@example.com".to_string()].into(),
+                        keep_copy: None,
+                    },
+                ),
+            ])),
+        })
+        .unwrap();
+    assert_eq!(response.updated, vec!["singleton".to_string()]);
+    assert!(response.not_updated.get("other").is_some());
+    let response = server
+        .store
+        .forwarding_get(ForwardingGetRequest {
+            acl: None,
+            account_id: JMAPId::from(document_id_2),
+            ids: None,
+        })
+        .unwrap();
+    assert_eq!(response.list.len(), 1);
+    assert!(response.list[0].details.is_enabled);
+    assert_eq!(
+        response.list[0].details.addresses,
+        vec!["jane.smith@example.net".to_string()]
+    );
+
+    // Forwarding to the account's own address is not allowed
+    let response = server
+        .store
+        .forwarding_set(ForwardingSetRequest {
+            acl: None,
+            account_id: JMAPId::from(document_id_2),
+            update: Some(VecMap::from_iter([(
+                "singleton".to_string(),
+                ForwardingUpdate {
+                    is_enabled: None,
+                    addresses: vec!["JANE@example.com".to_string()].into(),
+                    keep_copy: None,
+                },
+            )])),
+        })
+        .unwrap();
+    assert!(response.updated.is_empty());
+
+    lmtp.ingest(
+        "bill@example.com",
+        &["jane@example.com"],
+        concat!(
+            "From: bill@example.com\r\n",
+            "To: jane@example.com\r\n",
+            "Subject: Forwarded\r\n",
+            "\r\n",
+            "This message should be forwarded."
+        ),
+    )
+    .await;
+    assert_eq!(
+        server
+            .store
+            .get_document_ids(document_id_2, Collection::Mail)
+            .unwrap()
+            .unwrap()
+            .len(),
+        4
+    );
+
+    // Looping messages are delivered locally
+    lmtp.ingest(
+        "bill@example.com",
+        &["jane@example.com"],
+        concat!(
+            "X-Loop: jane@example.com\r\n",
+            "From: bill@example.com\r\n",
+            "To: jane@example.com\r\n",
+            "Subject: Looping\r\n",
+            "\r\n",
+            "This message already went through this account."
+        ),
+    )
+    .await;
+    assert_eq!(
+        server
+            .store
+            .get_document_ids(document_id_2, Collection::Mail)
+            .unwrap()
+            .unwrap()
+            .len(),
+        5
+    );
+
+    // Forwarded messages are sealed with the account's domain, but only when
+    // authentication results were computed on reception
+    for auth in [None, Some(AuthResult::Pass)] {
+        let result = server
+            .store
+            .mail_ingest(
+                "bill@example.net".to_string(),
+                vec![RcptType::Mailbox {
+                    id: document_id_2,
+                    name: "jane@example.com".to_string(),
+                    status: DeliveryStatus::Success,
+                }],
+                concat!(
+                    "From: bill@example.net\r\n",
+                    "To: jane@example.com\r\n",
+                    "Subject: Sealed\r\n",
+                    "\r\n",
+                    "This message should be sealed when forwarded."
+                )
+                .as_bytes()
+                .to_vec(),
+                None,
+                auth.map(|arc| AuthOutcome {
+                    spf: AuthResult::Pass,
+                    spf_domain: "example.net".to_string(),
+                    dkim: vec![],
+                    arc,
+                    dmarc: DmarcOutput {
+                        result: AuthResult::Pass,
+                        from_domain: "example.net".to_string(),
+                        policy: Policy::None,
+                        pct: 100,
+                    },
+                    disposition: Policy::None,
+                }),
+            )
+            .unwrap();
+        assert_eq!(result.messages.len(), 1);
+        let message = &result.messages[0];
+        assert_eq!(message.rcpt_to, ["jane.smith@example.net"]);
+        assert_eq!(message.seal_domain.as_deref(), Some("example.com"));
+        assert_eq!(
+            message.auth.as_ref().map(|auth| auth.arc),
+            auth,
+            "{:?}",
+            message.auth
+        );
+    }
+
+    // Ingestion traces can be looked up by Message-ID
+    lmtp.ingest(
+        "bill@example.com",
+        &["jane@example.com"],
+        concat!(
+            "Message-ID: <trace-test@example.com>\r\n",
+            "From: bill@example.com\r\n",
+            "To: jane@example.com\r\n",
+            "Subject: Traced\r\n",
+            "\r\n",
+            "Where did this message go?"
+        ),
+    )
+    .await;
+    let trace = server
+        .store
+        .ingest_trace_get("<trace-test@example.com>")
+        .unwrap()
+        .unwrap();
+    assert_eq!(trace.mail_from, "bill@example.com");
+    assert_eq!(trace.message_id.as_deref(), Some("trace-test@example.com"));
+    assert!(matches!(
+        &trace.events[0].event,
+        TraceEvent::Recipient { account, address }
+            if account.get_document_id() == document_id_2 && address == "jane@example.com"
+    ));
+    assert!(trace.events.iter().any(|entry| matches!(
+        &entry.event,
+        TraceEvent::Filed { account, mailboxes, .. }
+            if account.get_document_id() == document_id_2 && mailboxes.len() == 1
+    )));
+    assert_eq!(
+        server
+            .store
+            .ingest_trace_get(&trace.id)
+            .unwrap()
+            .unwrap()
+            .events
+            .len(),
+        trace.events.len()
+    );
+
     // Size checks
     lmtp.send("MAIL FROM:<hello@world> SIZE=943718400").await;
     lmtp.read(1, 5).await;
     lmtp.send("BDAT 943718400").await;
     lmtp.read(1, 5).await;
 
+    // SMTP sessions require EHLO and do not expose the directory
+    let mut smtp = SmtpConnection::connect_smtp(1).await;
+    smtp.mail_from("bill@remote.org", 5).await;
+    smtp.send("EHLO remote.org").await;
+    smtp.read(1, 2).await;
+    smtp.vrfy("jdoe@example.com", 2)
+        .await
+        .assert_contains("252 2.5.0");
+    smtp.expn("members@example.com", 5).await;
+    smtp.rcpt_to("jdoe@example.com", 5)
+        .await
+        .assert_contains("Missing MAIL FROM");
+
+    // Forged SRS addresses and unknown recipients are rejected
+    smtp.mail_from("", 2).await;
+    smtp.rcpt_to("SRS0=abcd=XY=remote.org=bill@example.com", 5)
+        .await;
+    smtp.rcpt_to("unknown@example.com", 5).await;
+
+    // Recipients are limited per message
+    smtp.rset().await;
+    smtp.mail_from("bill@remote.org", 2).await;
+    for rcpt in ["jdoe@example.com", "jane@example.com", "bill@example.com"] {
+        smtp.rcpt_to(rcpt, 2).await;
+    }
+    smtp.rcpt_to("john.doe@example.com", 4)
+        .await
+        .assert_contains("Too many recipients");
+
+    // Messages get a single reply
+    smtp.data(3).await;
+    smtp.data_bytes(
+        concat!(
+            "From: bill@remote.org\r\n",
+            "To: jdoe@example.com\r\n",
+            "Subject: SMTP test\r\n",
+            "\r\n",
+            "Delivered over SMTP."
+        ),
+        1,
+        2,
+    )
+    .await
+    .assert_contains("250 2.0.0");
+    assert_eq!(
+        server
+            .store
+            .get_document_ids(document_id_1, Collection::Mail)
+            .unwrap()
+            .unwrap()
+            .len(),
+        79
+    );
+
+    // Messages are limited per connection
+    smtp.mail_from("bill@remote.org", 2).await;
+    smtp.rcpt_to("jdoe@example.com", 2).await;
+    smtp.bdat_last("Subject: SMTP test\r\n\r\nChunked.", 1, 2)
+        .await;
+    smtp.mail_from("bill@remote.org", 4)
+        .await
+        .assert_contains("421 4.7.0");
+
     // Remove test data
     for account_id in [&account_id_1, &account_id_2, &account_id_3] {
         client
@@ -314,6 +810,8 @@ where
             .await
             .unwrap();
     }
+    client.principal_destroy(&outer_group_id).await.unwrap();
+    client.principal_destroy(&group_id).await.unwrap();
     client.principal_destroy(&list_id).await.unwrap();
     client.principal_destroy(&domain_id).await.unwrap();
     server.store.principal_purge().unwrap();
@@ -380,6 +878,20 @@ impl SmtpConnection {
         conn
     }
 
+    pub async fn connect_smtp(peer_num: usize) -> Self {
+        let (reader, writer) = tokio::io::split(
+            TcpStream::connect(format!("127.0.0.1:{}", 11300 + peer_num))
+                .await
+                .unwrap(),
+        );
+        let mut conn = SmtpConnection {
+            reader: BufReader::new(reader).lines(),
+            writer,
+        };
+        conn.read(1, 2).await;
+        conn
+    }
+
     pub async fn lhlo(&mut self) -> Vec<String> {
         self.send("LHLO localhost").await;
         self.read(1, 2).await
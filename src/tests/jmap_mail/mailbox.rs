@@ -22,7 +22,10 @@
 */
 
 use actix_web::web;
-use jmap::types::{jmap::JMAPId, state::JMAPState};
+use jmap::{
+    request::query::QueryRequest,
+    types::{jmap::JMAPId, state::JMAPState},
+};
 use jmap_client::{
     client::Client,
     core::{
@@ -32,6 +35,8 @@ use jmap_client::{
     mailbox::{self, Mailbox, Role},
     Error, Set,
 };
+use jmap_mail::mailbox::{query::JMAPMailboxQuery, schema::Mailbox as MailboxSchema};
+use jmap_sharing::principal::account::JMAPAccountStore;
 use serde::{Deserialize, Serialize};
 
 use store::{ahash::AHashMap, Store};
@@ -180,6 +185,33 @@ where
         Vec::<&str>::new()
     );
 
+    // Filter by ancestor
+    assert_eq!(
+        query_mailboxes(
+            &server,
+            &id_map,
+            serde_json::json!({
+                "accountId": client.default_account_id(),
+                "filter": {"ancestorId": id_map["1"]},
+                "sort": [{"property": "name"}],
+                "sortAsTree": true,
+            })
+        ),
+        ["1.1", "1.1.1", "1.1.1.1", "1.1.1.1.1", "1.2", "1.2.1"]
+    );
+    assert_eq!(
+        query_mailboxes(
+            &server,
+            &id_map,
+            serde_json::json!({
+                "accountId": client.default_account_id(),
+                "filter": {"operator": "NOT", "conditions": [{"ancestorId": id_map["inbox"]}]},
+                "sort": [{"property": "name"}],
+            })
+        ),
+        ["drafts", "spam2", "inbox", "sent", "spam", "trash", "spam1"]
+    );
+
     // Filter by role
     assert_eq!(
         client
@@ -319,6 +351,38 @@ where
         .updated(&id_map["1.1.1.1.1"])
         .is_ok());
 
+    // Query changes should report the moved mailbox at its new position
+    let mut request = client.build();
+    request
+        .query_mailbox_changes(state.clone())
+        .sort([mailbox::query::Comparator::name()])
+        .arguments()
+        .sort_as_tree(true);
+    let changes = request.send_query_mailbox_changes().await.unwrap();
+    let mut request = client.build();
+    request
+        .query_mailbox()
+        .sort([mailbox::query::Comparator::name()])
+        .arguments()
+        .sort_as_tree(true);
+    let position = request
+        .send_query_mailbox()
+        .await
+        .unwrap()
+        .ids()
+        .iter()
+        .position(|id| id == &id_map["1.1.1.1.1"])
+        .unwrap();
+    assert_eq!(changes.removed(), [id_map["1.1.1.1.1"].to_string()]);
+    assert_eq!(
+        changes
+            .added()
+            .iter()
+            .map(|item| (item.id(), item.index()))
+            .collect::<Vec<_>>(),
+        [(id_map["1.1.1.1.1"].as_str(), position)]
+    );
+
     // Verify changes
     let state = client.mailbox_changes(state, 0).await.unwrap();
     assert_eq!(state.created().len(), 0);
@@ -409,6 +473,15 @@ where
     );
     let state = state.new_state().to_string();
 
+    // Counter updates do not alter the results of a query
+    let mut request = client.build();
+    request
+        .query_mailbox_changes(prev_state.clone())
+        .sort([mailbox::query::Comparator::name()]);
+    let changes = request.send_query_mailbox_changes().await.unwrap();
+    assert_eq!(changes.removed().len(), 0);
+    assert_eq!(changes.added().len(), 0);
+
     // Use updatedProperties in a query
     let mut request = client.build();
     let changes_request = request.changes_mailbox(prev_state).max_changes(0);
@@ -607,6 +680,66 @@ where
         ["inbox", "sent", "spam"]
     );
 
+    // Subscriptions are kept outside the mailbox document and logged as mailbox updates
+    let state = client
+        .mailbox_changes(JMAPState::Initial.to_string(), 0)
+        .await
+        .unwrap()
+        .new_state()
+        .to_string();
+    client
+        .mailbox_subscribe(&id_map["inbox"], true)
+        .await
+        .unwrap();
+    assert!(client
+        .mailbox_get(&id_map["inbox"], None::<Vec<_>>)
+        .await
+        .unwrap()
+        .unwrap()
+        .is_subscribed());
+    assert_eq!(
+        client
+            .mailbox_query(
+                mailbox::query::Filter::is_subscribed(true).into(),
+                None::<Vec<_>>
+            )
+            .await
+            .unwrap()
+            .ids()
+            .iter()
+            .map(|id| id_map.get(id).unwrap())
+            .collect::<Vec<_>>(),
+        ["inbox"]
+    );
+    let changes = client.mailbox_changes(state, 0).await.unwrap();
+    assert_eq!(changes.updated(), [id_map["inbox"].to_string()]);
+
+    // Subscribing again does not log a change
+    let state = changes.new_state().to_string();
+    client
+        .mailbox_subscribe(&id_map["inbox"], true)
+        .await
+        .unwrap();
+    assert_eq!(
+        client
+            .mailbox_changes(state, 0)
+            .await
+            .unwrap()
+            .updated()
+            .len(),
+        0
+    );
+    client
+        .mailbox_subscribe(&id_map["inbox"], false)
+        .await
+        .unwrap();
+    assert!(!client
+        .mailbox_get(&id_map["inbox"], None::<Vec<_>>)
+        .await
+        .unwrap()
+        .unwrap()
+        .is_subscribed());
+
     let mut request = client.build();
     request.query_mailbox().arguments().sort_as_tree(true);
     let mut ids = request.send_query_mailbox().await.unwrap().take_ids();
@@ -617,6 +750,30 @@ where
     server.store.assert_is_empty();
 }
 
+fn query_mailboxes<T>(
+    server: &JMAPServer<T>,
+    id_map: &AHashMap<String, String>,
+    request: serde_json::Value,
+) -> Vec<String>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let mut request = serde_json::from_value::<QueryRequest<MailboxSchema>>(request).unwrap();
+    request.acl = server
+        .store
+        .get_acl_token(request.account_id.get_document_id())
+        .unwrap()
+        .into();
+    server
+        .store
+        .mailbox_query(request)
+        .unwrap()
+        .ids
+        .iter()
+        .map(|id| id_map[&id.to_string()].clone())
+        .collect()
+}
+
 async fn create_test_mailboxes(client: &mut Client) -> AHashMap<String, String> {
     let mut mailbox_map = AHashMap::default();
     let mut request = client.build();
@@ -26,19 +26,28 @@ use store_rocksdb::RocksDB;
 use super::{jmap::init_jmap_tests, store::utils::destroy_temp_dir};
 
 pub mod email_changes;
+pub mod email_cid;
+pub mod email_collation;
 pub mod email_copy;
 pub mod email_get;
+pub mod email_keywords;
 pub mod email_parse;
 pub mod email_query;
 pub mod email_query_changes;
+pub mod email_query_mailbox;
+pub mod email_query_pinned;
 pub mod email_set;
+pub mod email_set_keywords;
+pub mod email_snooze;
 pub mod email_submission;
 pub mod email_thread;
 pub mod email_thread_merge;
+pub mod expunge;
 pub mod lmtp;
 pub mod mailbox;
 pub mod search_snippet;
 pub mod sieve;
+pub mod storage_report;
 pub mod vacation_response;
 
 #[actix_web::test]
@@ -62,6 +71,15 @@ async fn jmap_mail_tests() {
     mailbox::test(server.clone(), &mut client).await;
     search_snippet::test(server.clone(), &mut client).await;
     sieve::test(server.clone(), &mut client).await;
+    storage_report::test(server.clone(), &mut client).await;
+    expunge::test(server.clone(), &mut client).await;
+    email_cid::test(server.clone(), &mut client).await;
+    email_query_mailbox::test(server.clone(), &mut client).await;
+    email_query_pinned::test(server.clone(), &mut client).await;
+    email_keywords::test(server.clone(), &mut client).await;
+    email_snooze::test(server.clone(), &mut client).await;
+    email_set_keywords::test(server.clone(), &mut client).await;
+    email_collation::test(server.clone(), &mut client).await;
 
     destroy_temp_dir(&temp_dir);
 }
@@ -32,13 +32,22 @@ use jmap_client::{
     sieve::query::{Comparator, Filter},
     Error,
 };
+use jmap_mail::mail::schema::Keyword;
 use jmap_sharing::principal::set::JMAPSetPrincipal;
 use store::Store;
 
 use crate::{
+    api::lint::JMAPMailLint,
+    lmtp::{
+        authentication::{dmarc::DmarcOutput, AuthOutcome, AuthResult, Policy},
+        ingest::DeliveryStatus,
+        session::RcptType,
+    },
     tests::{
         jmap_mail::{
-            email_submission::{assert_message_delivery, spawn_mock_smtp_server, MockMessage},
+            email_submission::{
+                assert_message_delivery, spawn_mock_smtp_server, MockMessage, TEST_DKIM_KEY,
+            },
             lmtp::{AssertResult, SmtpConnection},
         },
         store::utils::StoreCompareWith,
@@ -172,6 +181,54 @@ where
         .sieve_script_create("test_mailbox", get_script("test_mailbox"), true)
         .await
         .unwrap();
+
+    // Linting the message runs the script without creating any mailboxes
+    let lint = server
+        .store
+        .mail_lint(
+            JMAPId::parse(&account_id).unwrap().get_document_id(),
+            concat!(
+                "From: bill@example.com\r\n",
+                "To: jdoe@example.com\r\n",
+                "Subject: TPS Report\r\n",
+                "\r\n",
+                "I'm going to need those TPS reports ASAP. ",
+                "So, if you could do that, that'd be great."
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+        .unwrap();
+    assert!(lint
+        .warnings
+        .contains(&"Missing Message-ID header.".to_string()));
+    let sieve = lint.sieve.unwrap();
+    assert_eq!(sieve.script, "test_mailbox");
+    assert_eq!(sieve.mailbox_ids, Vec::<JMAPId>::new());
+    assert_eq!(
+        sieve.keywords,
+        vec![Keyword::parse("$important"), Keyword::parse("$seen")]
+    );
+    assert_eq!(
+        sieve
+            .actions
+            .iter()
+            .filter(|action| action.ends_with("(would be created)"))
+            .count(),
+        2,
+        "{:?}",
+        sieve.actions
+    );
+    assert!(client
+        .mailbox_query(
+            mailbox::query::Filter::name("levels").into(),
+            None::<Vec<_>>
+        )
+        .await
+        .unwrap()
+        .ids()
+        .is_empty());
+
     lmtp.ingest(
         "bill@example.com",
         &["jdoe@example.com"],
@@ -388,6 +445,60 @@ where
         "Redirected message was stored."
     );
 
+    // Redirected messages are ARC-sealed with the key of the account's domain,
+    // recording the authentication results computed when they were received
+    client
+        .set_default_account_id(JMAPId::from(SUPERUSER_ID))
+        .domain_enable_dkim(&domain_id, TEST_DKIM_KEY, "my-selector", None)
+        .await
+        .unwrap();
+    client.set_default_account_id(&account_id);
+    server
+        .mail_ingest(
+            "bill@example.net".to_string(),
+            vec![RcptType::Mailbox {
+                id: JMAPId::parse(&account_id).unwrap().get_document_id(),
+                name: "jdoe@example.com".to_string(),
+                status: DeliveryStatus::Success,
+            }],
+            concat!(
+                "From: bill@example.net\r\n",
+                "To: jdoe@example.com\r\n",
+                "Subject: TPS Report\r\n",
+                "\r\n",
+                "Did you get the memo?"
+            )
+            .as_bytes()
+            .to_vec(),
+            None,
+            AuthOutcome {
+                spf: AuthResult::Pass,
+                spf_domain: "example.net".to_string(),
+                dkim: vec![],
+                arc: AuthResult::None,
+                dmarc: DmarcOutput {
+                    result: AuthResult::Pass,
+                    from_domain: "example.net".to_string(),
+                    policy: Policy::Reject,
+                    pct: 100,
+                },
+                disposition: Policy::None,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    assert_message_delivery(
+        &mut smtp_rx,
+        MockMessage::new(
+            "<jdoe@example.com>",
+            ["<jane@example.com>"],
+            "@ARC-Seal: i=1; a=rsa-sha256;",
+        ),
+        false,
+    )
+    .await;
+
     // Run notify + editheader + notify + fcc tests
     client
         .sieve_script_create("test_notify_fcc", get_script("test_notify_fcc"), true)
@@ -471,6 +582,50 @@ where
         panic!("Email {:?} not found in: {:#?}", subject, emails);
     }
 
+    // Run imap4flags + relational + editheader tests
+    client
+        .sieve_script_create(
+            "test_flags_relational",
+            get_script("test_flags_relational"),
+            true,
+        )
+        .await
+        .unwrap();
+    lmtp.ingest(
+        "bill@example.com",
+        &["jdoe@example.com"],
+        concat!(
+            "Received: from mx1.example.com\r\n",
+            "Received: from mx2.example.com\r\n",
+            "From: bill@example.com\r\n",
+            "To: jdoe@example.com\r\n",
+            "Cc: jane@example.com\r\n",
+            "X-Spam-Score: 7\r\n",
+            "Subject: Cheap TPS reports\r\n",
+            "\r\n",
+            "Buy now."
+        ),
+    )
+    .await;
+    let message_id = client
+        .email_query(
+            email::query::Filter::subject("Multiple recipients").into(),
+            None::<Vec<_>>,
+        )
+        .await
+        .unwrap()
+        .take_ids()
+        .pop()
+        .expect("Subject was not replaced.");
+    let email = client
+        .email_get(&message_id, [email::Property::Keywords].into())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut keywords = email.keywords();
+    keywords.sort_unstable();
+    assert_eq!(keywords, ["$flagged", "$junk", "$twohops"]);
+
     smtp_settings.lock().do_stop = true;
 
     // Remove test data
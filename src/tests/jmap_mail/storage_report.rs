@@ -0,0 +1,127 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use actix_web::web;
+
+use jmap::types::jmap::JMAPId;
+use jmap_client::{client::Client, mailbox::Role};
+use jmap_mail::mail::report::JMAPMailReport;
+use store::Store;
+
+use crate::{tests::store::utils::StoreCompareWith, JMAPServer};
+
+pub async fn test<T>(server: web::Data<JMAPServer<T>>, client: &mut Client)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    println!("Running Storage Report tests...");
+
+    let mailbox_id = client
+        .set_default_account_id(JMAPId::new(1).to_string())
+        .mailbox_create("Storage Report", None::<String>, Role::None)
+        .await
+        .unwrap()
+        .take_id();
+
+    let mut message_ids = Vec::new();
+    for size in [100, 3000, 1000] {
+        message_ids.push(
+            client
+                .email_import(
+                    format!("Subject: message {}\n\n{}", size, "a".repeat(size)).into_bytes(),
+                    [&mailbox_id],
+                    None::<Vec<String>>,
+                    None,
+                )
+                .await
+                .unwrap()
+                .take_id(),
+        );
+    }
+    message_ids.push(
+        client
+            .email_import(
+                concat!(
+                    "Subject: attachment\n",
+                    "Content-Type: multipart/mixed; boundary=\"b\"\n\n",
+                    "--b\nContent-Type: text/plain\n\nhello\n",
+                    "--b\nContent-Type: application/pdf\n",
+                    "Content-Disposition: attachment; filename=\"a.pdf\"\n\n",
+                    "%PDF-1.4\n",
+                    "--b--\n"
+                )
+                .as_bytes()
+                .to_vec(),
+                [&mailbox_id],
+                None::<Vec<String>>,
+                None,
+            )
+            .await
+            .unwrap()
+            .take_id(),
+    );
+
+    let report = server.store.mail_storage_report(1, 2).unwrap();
+    assert_eq!(report.total_messages, 4);
+    assert!(report.total_size > 4100);
+
+    // Largest messages come first
+    assert_eq!(
+        report
+            .largest_messages
+            .iter()
+            .map(|m| m.id.to_string())
+            .collect::<Vec<_>>(),
+        vec![message_ids[1].clone(), message_ids[2].clone()]
+    );
+    assert_eq!(
+        report.largest_messages[0].subject.as_deref(),
+        Some("message 3000")
+    );
+
+    let mailbox = report
+        .mailboxes
+        .iter()
+        .find(|m| m.id.to_string() == mailbox_id)
+        .unwrap();
+    assert_eq!(mailbox.total_messages, 4);
+    assert_eq!(mailbox.total_size, report.total_size);
+
+    assert_eq!(report.attachment_types.len(), 1);
+    assert_eq!(report.attachment_types[0].type_, "application/pdf");
+    assert_eq!(report.attachment_types[0].count, 1);
+
+    // Multi-account summaries match the per-account report
+    let summary = server.store.mail_storage_summary(&[5, 1, 5]).unwrap();
+    assert_eq!(summary.len(), 2);
+    assert_eq!(summary[0].account_id, JMAPId::new(1));
+    assert_eq!(summary[0].total_messages, report.total_messages);
+    assert_eq!(summary[0].total_size, report.total_size);
+    assert_eq!(summary[1].account_id, JMAPId::new(5));
+    assert_eq!(summary[1].total_messages, 0);
+    assert_eq!(summary[1].total_size, 0);
+
+    client.mailbox_destroy(&mailbox_id, true).await.unwrap();
+
+    server.store.assert_is_empty();
+}
@@ -22,8 +22,11 @@
 */
 
 use actix_web::web;
-use jmap::{types::jmap::JMAPId, SUPERUSER_ID};
+use jmap::{request::set::SetRequest, types::jmap::JMAPId, SUPERUSER_ID};
 use jmap_client::client::Client;
+use jmap_mail::vacation_response::{
+    dedup::JMAPVacationDedup, schema::VacationResponse, set::JMAPSetVacationResponse,
+};
 use jmap_sharing::principal::set::JMAPSetPrincipal;
 use store::{
     chrono::{Duration, Utc},
@@ -136,6 +139,112 @@ where
 
     expect_nothing(&mut smtp_rx).await;
 
+    // Automated and bulk messages should not trigger a vacation response
+    for (sender, header) in [
+        ("alerts@example.com", "Auto-Submitted: auto-generated"),
+        ("newsletter@example.com", "Precedence: bulk"),
+        ("dev-list@example.com", "List-Id: <dev.example.com>"),
+    ] {
+        lmtp.ingest(
+            sender,
+            &["jdoe@example.com"],
+            &format!(
+                concat!(
+                    "From: {}\r\n",
+                    "To: jdoe@example.com\r\n",
+                    "{}\r\n",
+                    "Subject: Automated message\r\n",
+                    "\r\n",
+                    "This message was sent automatically.",
+                ),
+                sender, header
+            ),
+        )
+        .await;
+
+        expect_nothing(&mut smtp_rx).await;
+    }
+
+    // Resetting the dedup database should allow replying to Bill again
+    let document_id = JMAPId::parse(&account_id).unwrap().get_document_id();
+    let report = server
+        .store
+        .vacation_dedup_get(document_id)
+        .unwrap()
+        .unwrap();
+    assert!(report.is_active);
+    assert_eq!(report.entries.len(), 1, "{:?}", report);
+    assert_eq!(
+        server
+            .store
+            .vacation_dedup_reset(document_id)
+            .unwrap()
+            .unwrap()
+            .entries
+            .len(),
+        1
+    );
+    assert!(server
+        .store
+        .vacation_dedup_get(document_id)
+        .unwrap()
+        .unwrap()
+        .entries
+        .is_empty());
+    lmtp.ingest(
+        "bill@example.com",
+        &["jdoe@example.com"],
+        concat!(
+            "From: bill@example.com\r\n",
+            "To: jdoe@example.com\r\n",
+            "Subject: TPS Report -- final reminder\r\n",
+            "\r\n",
+            "We're also going to need you to come in on Saturday.",
+        ),
+    )
+    .await;
+    assert_message_delivery(
+        &mut smtp_rx,
+        MockMessage::new("<jdoe@example.com>", ["<bill@example.com>"], "@Kokomo"),
+        false,
+    )
+    .await;
+
+    // Suppressed senders and domains should never receive a vacation response
+    let mut request = serde_json::from_value::<SetRequest<VacationResponse>>(serde_json::json!({
+        "accountId": account_id,
+        "update": {"singleton": {"suppressedSenders": ["Milton@example.com", "@example.org"]}}
+    }))
+    .unwrap();
+    request.acl = server.store.get_acl_token(document_id).unwrap().into();
+    assert_eq!(
+        serde_json::to_value(server.store.vacation_response_set(request).unwrap()).unwrap()
+            ["updated"]
+            .as_object()
+            .unwrap()
+            .len(),
+        1
+    );
+    for sender in ["milton@example.com", "peter@initech.example.org"] {
+        lmtp.ingest(
+            sender,
+            &["jdoe@example.com"],
+            &format!(
+                concat!(
+                    "From: {}\r\n",
+                    "To: jdoe@example.com\r\n",
+                    "Subject: Have you seen my stapler?\r\n",
+                    "\r\n",
+                    "I believe you have my stapler.",
+                ),
+                sender
+            ),
+        )
+        .await;
+
+        expect_nothing(&mut smtp_rx).await;
+    }
+
     // Vacation responses should honor the configured date ranges
     client
         .vacation_response_set_dates((Utc::now() + Duration::days(1)).timestamp().into(), None)
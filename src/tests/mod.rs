@@ -22,6 +22,7 @@
 */
 
 pub mod cluster;
+pub mod conformance;
 pub mod jmap;
 pub mod jmap_mail;
 pub mod store;
@@ -0,0 +1,118 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{sync::Arc, time::Duration};
+
+use store::{
+    ahash::AHashMap,
+    backup::{latest_snapshot, restore},
+    blob::BlobId,
+    config::{env_settings::EnvSettings, jmap::JMAPConfig},
+    core::error::StoreError,
+    log::raft::{LogIndex, RaftId, TermId},
+    JMAPStore, Store,
+};
+
+use super::utils::{destroy_temp_dir, make_temp_dir};
+
+pub fn test<T>(db: Arc<JMAPStore<T>>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let backup_dir = make_temp_dir("strdb_backup", 1);
+    let restore_dir = make_temp_dir("strdb_restore", 1);
+    destroy_temp_dir(&backup_dir);
+    destroy_temp_dir(&restore_dir);
+
+    let blob = vec![b'c'; 1024];
+    let blob_id = BlobId::new_external(&blob);
+    db.blob_store(&blob_id, blob.clone()).unwrap();
+    db.blob_link_ephemeral(&blob_id, 1).unwrap();
+
+    // First backup copies all external blobs
+    let first_backup = db.backup(&backup_dir).unwrap();
+    assert!(first_backup.blobs_total > 0);
+    assert_eq!(first_backup.blobs_copied, first_backup.blobs_total);
+
+    // Subsequent backups are incremental
+    std::thread::sleep(Duration::from_millis(1100));
+    let second_backup = db.backup(&backup_dir).unwrap();
+    assert_eq!(second_backup.blobs_copied, 0);
+    assert_eq!(second_backup.blobs_total, first_backup.blobs_total);
+    assert_eq!(
+        latest_snapshot(&backup_dir).unwrap().unwrap(),
+        second_backup.path
+    );
+
+    // Restore backup into a new node with a separate blob directory
+    let settings = EnvSettings {
+        args: AHashMap::from_iter([
+            (
+                "db-path".to_string(),
+                restore_dir.to_str().unwrap().to_string(),
+            ),
+            (
+                "blob-path".to_string(),
+                restore_dir.join("blob-store").to_str().unwrap().to_string(),
+            ),
+        ]),
+    };
+
+    // Manifests without a valid Raft id are rejected
+    let corrupted_snapshot = backup_dir.join("corrupted");
+    std::fs::create_dir_all(&corrupted_snapshot).unwrap();
+    std::fs::write(
+        corrupted_snapshot.join("manifest"),
+        "created-at: 0\nraft-term: 1\nraft-index: abc\n",
+    )
+    .unwrap();
+    assert!(matches!(
+        restore(&backup_dir, &corrupted_snapshot, &settings),
+        Err(StoreError::DataCorruption(_))
+    ));
+
+    let raft_id = restore(&backup_dir, &second_backup.path, &settings).unwrap();
+    assert_eq!(raft_id, second_backup.raft_id);
+
+    let restored_db: JMAPStore<T> = JMAPStore::new(
+        T::open(&settings).unwrap(),
+        JMAPConfig::from(&settings),
+        &settings,
+    )
+    .unwrap();
+    assert_eq!(restored_db.blob_get(&blob_id).unwrap(), Some(blob));
+    assert!(restore_dir.join("blob-store").exists());
+    assert!(!restore_dir.join("blobs").exists());
+    assert_eq!(
+        restored_db
+            .get_prev_raft_id(RaftId::new(TermId::MAX, LogIndex::MAX))
+            .unwrap()
+            .unwrap_or_else(RaftId::none),
+        second_backup.raft_id
+    );
+    restored_db.db.close().unwrap();
+    drop(restored_db);
+
+    destroy_temp_dir(&backup_dir);
+    destroy_temp_dir(&restore_dir);
+}
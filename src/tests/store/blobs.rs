@@ -25,7 +25,8 @@ use std::{sync::Arc, time::SystemTime};
 
 use store::{
     ahash::AHashMap,
-    blob::{BlobId, BLOB_HASH_LEN},
+    blob::{pack::BlobPacks, BlobId, BLOB_HASH_LEN},
+    config::jmap::JMAPConfig,
     core::{collection::Collection, document::Document},
     serialize::{key::BlobKey, leb128::Leb128Reader, StoreDeserialize, StoreSerialize},
     write::{
@@ -34,6 +35,9 @@ use store::{
     },
     ColumnFamily, Direction, JMAPStore, Store,
 };
+use store_rocksdb::RocksDB;
+
+use super::utils::{destroy_temp_dir, init_settings, make_temp_dir};
 
 pub fn test<T>(db: Arc<JMAPStore<T>>)
 where
@@ -82,8 +86,21 @@ where
     ]);
     assert_eq!(expected_count, db.get_all_blobs());
 
+    // Batched existence and access checks
+    let blob_missing = BlobId::new_local(&[b'c'; 1024]);
+    assert_eq!(
+        db.blob_exists_multi(&[&blob_local, &blob_external, &blob_missing])
+            .unwrap(),
+        vec![true, true, false]
+    );
+    assert_eq!(
+        db.blob_account_has_access_multi(&[&blob_local, &blob_external, &blob_missing], &[1])
+            .unwrap(),
+        vec![false, true, false]
+    );
+
     // Purgimg should not delete any blobs at this point
-    db.purge_blobs().unwrap();
+    db.purge_blobs(true).unwrap();
     assert_eq!(expected_count, db.get_all_blobs());
 
     // Link blob to an account
@@ -103,7 +120,7 @@ where
             &expired_timestamp.serialize().unwrap(),
         )
         .unwrap();
-    db.purge_blobs().unwrap();
+    db.purge_blobs(true).unwrap();
     expected_count.insert(blob_local.clone(), (1, 0));
     assert_eq!(expected_count, db.get_all_blobs());
 
@@ -113,7 +130,7 @@ where
     let mut wb = WriteBatch::new(2);
     wb.update_document(document);
     db.write(wb).unwrap();
-    db.purge_blobs().unwrap();
+    db.purge_blobs(true).unwrap();
     expected_count.remove(&blob_local);
     assert_eq!(expected_count, db.get_all_blobs());
 
@@ -127,11 +144,143 @@ where
             )
             .unwrap();
     }
-    db.purge_blobs().unwrap();
+    db.purge_blobs(true).unwrap();
     expected_count.remove(&blob_external);
     assert_eq!(expected_count, db.get_all_blobs());
 }
 
+#[test]
+fn blob_packs() {
+    let temp_dir = make_temp_dir("strdb_blob_packs", 1);
+    if temp_dir.exists() {
+        std::fs::remove_dir_all(&temp_dir).unwrap();
+    }
+
+    // Small packs so that the blobs span several files
+    let packs = BlobPacks::open(temp_dir.clone(), 1024, 4096, 50).unwrap();
+    let blobs = (0..32u8)
+        .map(|n| {
+            let blob = vec![n; 512 + n as usize];
+            (BlobId::new_external(&blob), blob)
+        })
+        .collect::<Vec<_>>();
+    assert!(packs.accepts(1024));
+    assert!(!packs.accepts(1025));
+
+    for (blob_id, blob) in &blobs {
+        assert!(packs.put(blob_id, blob).unwrap());
+        assert!(!packs.put(blob_id, blob).unwrap());
+    }
+    for (blob_id, blob) in &blobs {
+        assert_eq!(packs.get(blob_id).unwrap().as_ref(), Some(blob));
+    }
+
+    // Delete every blob but one in three
+    for (blob_id, _) in blobs.iter().filter(|(id, _)| id.hash()[0] % 3 != 0) {
+        assert!(packs.delete(blob_id).unwrap());
+        assert!(!packs.delete(blob_id).unwrap());
+    }
+    let pack_count = std::fs::read_dir(&temp_dir).unwrap().count();
+    assert!(packs.compact().unwrap() > 0);
+    assert!(std::fs::read_dir(&temp_dir).unwrap().count() < pack_count);
+
+    // Indexes are replayed on reopen, including the compacted blobs
+    drop(packs);
+    let packs = BlobPacks::open(temp_dir.clone(), 1024, 4096, 50).unwrap();
+    for (blob_id, blob) in &blobs {
+        if blob_id.hash()[0] % 3 != 0 {
+            assert!(!packs.contains(blob_id));
+            assert_eq!(packs.get(blob_id).unwrap(), None);
+        } else {
+            assert_eq!(packs.get(blob_id).unwrap().as_ref(), Some(blob));
+        }
+    }
+    assert_eq!(packs.compact().unwrap(), 0);
+
+    // Local and external blobs with the same contents are stored separately
+    let blob = vec![b'x'; 100];
+    let local_id = BlobId::new_local(&blob);
+    let external_id = BlobId::new_external(&blob);
+    assert!(packs.put(&local_id, &blob).unwrap());
+    assert!(packs.put(&external_id, &blob).unwrap());
+    assert!(packs.delete(&external_id).unwrap());
+    assert!(!packs.contains(&external_id));
+    assert_eq!(packs.get(&local_id).unwrap().as_ref(), Some(&blob));
+    drop(packs);
+    let packs = BlobPacks::open(temp_dir.clone(), 1024, 4096, 50).unwrap();
+    assert!(!packs.contains(&external_id));
+    assert_eq!(packs.get(&local_id).unwrap().as_ref(), Some(&blob));
+    drop(packs);
+
+    destroy_temp_dir(&temp_dir);
+}
+
+#[test]
+#[ignore]
+fn blob_shared_purge() {
+    let shared_dir = make_temp_dir("strdb_blob_shared", 0);
+    if shared_dir.exists() {
+        std::fs::remove_dir_all(&shared_dir).unwrap();
+    }
+
+    // Two nodes that store their external blobs in the same directory
+    let init_node = |peer_num| {
+        let (mut settings, temp_dir) = init_settings("strdb_blob_shared", peer_num, 2, true);
+        settings.set_value(
+            "blob-path".to_string(),
+            shared_dir.to_str().unwrap().to_string(),
+        );
+        settings.set_value("blob-shared-store".to_string(), "true".to_string());
+        (
+            JMAPStore::new(
+                RocksDB::open(&settings).unwrap(),
+                JMAPConfig::from(&settings),
+                &settings,
+            )
+            .unwrap(),
+            temp_dir,
+        )
+    };
+    let leader = init_node(1);
+    let follower = init_node(2);
+
+    let blob = vec![b'c'; 1024];
+    let blob_id = BlobId::new_external(&blob);
+    leader.0.blob_store(&blob_id, blob.clone()).unwrap();
+    assert!(follower.0.blob_link_shared(&blob_id).unwrap());
+    assert_eq!(follower.0.blob_get(&blob_id).unwrap(), Some(blob.clone()));
+
+    // The follower drops its expired reference but keeps the shared blob
+    let expired_timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        - (follower.0.config.blob_temp_ttl + 2);
+    for (store, _) in [&leader, &follower] {
+        store
+            .db
+            .set(
+                ColumnFamily::Blobs,
+                &BlobKey::serialize_prefix(&blob_id, 0),
+                &expired_timestamp.serialize().unwrap(),
+            )
+            .unwrap();
+    }
+    follower.0.purge_blobs(false).unwrap();
+    assert!(follower.0.get_all_blobs().is_empty());
+    assert_eq!(leader.0.blob_get(&blob_id).unwrap(), Some(blob.clone()));
+
+    // The leader deletes the shared blob once it is no longer referenced
+    leader.0.purge_blobs(true).unwrap();
+    assert!(leader.0.get_all_blobs().is_empty());
+    assert!(!follower.0.blob_link_shared(&blob_id).unwrap());
+
+    for (_, temp_dir) in [leader, follower] {
+        destroy_temp_dir(&temp_dir);
+    }
+    destroy_temp_dir(&shared_dir);
+}
+
 trait GetAllBlobs {
     fn get_all_blobs(&self) -> AHashMap<BlobId, (u32, u32)>;
 }
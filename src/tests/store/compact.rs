@@ -0,0 +1,90 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use store::{
+    core::{collection::Collection, document::Document},
+    nlp::Language,
+    roaring::RoaringBitmap,
+    write::{
+        batch::WriteBatch,
+        options::{IndexOptions, Options},
+    },
+    ColumnFamily, JMAPStore, Store,
+};
+
+pub fn test<T>(db: Arc<JMAPStore<T>>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let account_id = 40;
+
+    let mut batch = WriteBatch::new(account_id);
+    for num in 0..100 {
+        let mut document = Document::new(
+            Collection::Mail,
+            db.assign_document_id(account_id, Collection::Mail).unwrap(),
+        );
+        document.text(
+            0,
+            format!("compaction test document number{} ", num).repeat(50),
+            Language::English,
+            IndexOptions::new().index().full_text(0),
+        );
+        batch.insert_document(document);
+    }
+    db.write(batch).unwrap();
+
+    // A full compaction leaves every key on disk
+    let mut progress = Vec::new();
+    let report = db
+        .compact_column_families(
+            &[
+                ColumnFamily::Bitmaps,
+                ColumnFamily::Values,
+                ColumnFamily::Indexes,
+            ],
+            |done, total| progress.push((done, total)),
+        )
+        .unwrap();
+    assert_eq!(report.accounts, 0);
+    assert_eq!(progress, vec![(1, 3), (2, 3), (3, 3)]);
+    assert!(report.bytes_after > 0, "{:?}", report);
+
+    // Compacting a deleted account reclaims its space
+    let mut account_ids = RoaringBitmap::new();
+    account_ids.insert(account_id);
+    db.delete_accounts(&account_ids).unwrap();
+    let mut progress = Vec::new();
+    let report = db
+        .compact_accounts(&account_ids, |done, total| progress.push((done, total)))
+        .unwrap();
+    assert_eq!(progress, vec![(1, 1)]);
+    assert_eq!(report.accounts, 1);
+    assert!(report.reclaimed_bytes > 0, "{:?}", report);
+    assert_eq!(
+        report.reclaimed_bytes,
+        report.bytes_before - report.bytes_after
+    );
+}
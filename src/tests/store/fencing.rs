@@ -0,0 +1,136 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::Duration,
+};
+
+use store::{
+    core::{collection::Collection, error::StoreError},
+    log::raft::{LogIndex, RaftId, TermId},
+    serialize::key::FENCING_TOKEN_KEY,
+    write::batch::WriteBatch,
+    ColumnFamily, JMAPStore, Store,
+};
+
+pub fn test<T>(db: Arc<JMAPStore<T>>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let fenced_batch = |term: TermId| {
+        let mut batch = WriteBatch::new(0);
+        batch.set_fencing_token(term);
+        batch
+    };
+    let leader_batch = |document_id: u64| {
+        let mut batch = WriteBatch::new(0);
+        batch.log_insert(Collection::Mail, document_id);
+        batch
+    };
+    let last_raft_id = || {
+        db.get_prev_raft_id(RaftId::new(LogIndex::MAX, LogIndex::MAX))
+            .unwrap()
+    };
+
+    // A node elected for term 3 keeps serving JMAP writes while a second
+    // leader is elected for term 4
+    db.advance_fencing_token(3).unwrap();
+    db.raft_term.store(3, Ordering::Relaxed);
+    db.write(leader_batch(1)).unwrap();
+    assert_eq!(last_raft_id().unwrap().term, 3);
+
+    // Once a quorum commits entries from term 4, the old leader can no
+    // longer write, neither can apply processes started under its term
+    db.advance_fencing_token(4).unwrap();
+    assert!(matches!(
+        db.write(leader_batch(2)),
+        Err(StoreError::StaleFencingToken(3, 4))
+    ));
+    assert!(matches!(
+        db.write(fenced_batch(3)),
+        Err(StoreError::StaleFencingToken(3, 4))
+    ));
+    assert!(matches!(
+        db.commit_write(fenced_batch(3)),
+        Err(StoreError::StaleFencingToken(3, 4))
+    ));
+    assert_eq!(last_raft_id().unwrap().term, 3);
+
+    // Updates replicated from the new leader are applied, and writes that
+    // do not add entries to the log are not fenced
+    db.write(fenced_batch(4)).unwrap();
+    db.write(WriteBatch::new(0)).unwrap();
+
+    // A stale linked batch fails the whole write
+    let mut batch = fenced_batch(4);
+    batch.add_linked_batch(leader_batch(3));
+    assert!(matches!(
+        db.write(batch),
+        Err(StoreError::StaleFencingToken(3, 4))
+    ));
+
+    // Only quorum commits advance the token, which never moves backwards
+    db.write(fenced_batch(6)).unwrap();
+    assert_eq!(db.fencing_token(), 4);
+    db.advance_fencing_token(2).unwrap();
+    assert_eq!(db.fencing_token(), 4);
+    assert_eq!(
+        db.db
+            .get::<TermId>(ColumnFamily::Values, FENCING_TOKEN_KEY)
+            .unwrap(),
+        Some(4)
+    );
+
+    // The node is elected again for term 5 and its writes are accepted
+    db.raft_term.store(5, Ordering::Relaxed);
+    db.write(leader_batch(4)).unwrap();
+    assert_eq!(last_raft_id().unwrap().term, 5);
+
+    // Writes in progress when a newer term is committed land before the
+    // token advances, none from the stale term is written afterwards
+    let writer = {
+        let db = db.clone();
+        thread::spawn(move || {
+            let mut document_id = 5;
+            loop {
+                let mut batch = WriteBatch::new(0);
+                batch.log_insert(Collection::Mail, document_id);
+                match db.write(batch) {
+                    Ok(_) => document_id += 1,
+                    Err(err) => return err,
+                }
+            }
+        })
+    };
+    thread::sleep(Duration::from_millis(100));
+    db.advance_fencing_token(6).unwrap();
+    let last_id = last_raft_id();
+    assert!(matches!(
+        writer.join().unwrap(),
+        StoreError::StaleFencingToken(5, 6)
+    ));
+    assert_eq!(last_raft_id(), last_id);
+    assert_eq!(last_id.unwrap().term, 5);
+}
@@ -0,0 +1,187 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use store::{
+    core::collection::Collection,
+    serialize::{
+        key::{
+            BitmapKey, ValueKey, BM_TERM, KEY_LAYOUT_KEY, LAYOUT_STAGING_KEY_PREFIX,
+            LAYOUT_UPGRADE_KEY, TERM_EXACT,
+        },
+        leb128::Leb128Vec,
+        StoreSerialize,
+    },
+    write::{layout::KEY_LAYOUT_VERSION, operation::WriteOperation},
+    AccountId, ColumnFamily, Direction, Integer, JMAPStore, Store,
+};
+
+// Stages recorded by an interrupted upgrade
+const STAGE_STAGING: Integer = 1;
+const STAGE_STAGED: Integer = 2;
+
+pub fn test<T>(db: JMAPStore<T>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let account_ids = [1, 200, AccountId::MAX];
+
+    // Interrupted while moving bitmaps to the staging area: the bitmaps of the
+    // first account were already staged.
+    let mut batch = vec![WriteOperation::set(
+        ColumnFamily::Values,
+        b"peer_id".to_vec(),
+        b"peer".to_vec(),
+    )];
+    for (pos, account_id) in account_ids.into_iter().enumerate() {
+        if pos == 0 {
+            batch.push(WriteOperation::set(
+                ColumnFamily::Values,
+                staged_key(account_id),
+                bitmap_value(account_id),
+            ));
+        } else {
+            batch.push(WriteOperation::set(
+                ColumnFamily::Bitmaps,
+                legacy_key(account_id),
+                bitmap_value(account_id),
+            ));
+        }
+    }
+    start_upgrade(&db, batch, STAGE_STAGING);
+    db.upgrade_key_layout().unwrap();
+    assert_upgraded(&db, &account_ids);
+    assert_eq!(
+        db.db
+            .get::<Vec<u8>>(ColumnFamily::Values, &ValueKey::serialize_named(b"peer_id"))
+            .unwrap(),
+        Some(b"peer".to_vec())
+    );
+    assert!(!db.db.exists(ColumnFamily::Values, b"peer_id").unwrap());
+
+    // Interrupted while moving staged bitmaps to their new key: bitmaps already
+    // under the new layout must not be parsed as legacy keys again.
+    let mut batch = Vec::new();
+    for (pos, account_id) in account_ids.into_iter().enumerate() {
+        batch.push(WriteOperation::delete(
+            ColumnFamily::Bitmaps,
+            new_key(account_id),
+        ));
+        batch.push(if pos == 0 {
+            WriteOperation::set(
+                ColumnFamily::Bitmaps,
+                new_key(account_id),
+                bitmap_value(account_id),
+            )
+        } else {
+            WriteOperation::set(
+                ColumnFamily::Values,
+                staged_key(account_id),
+                bitmap_value(account_id),
+            )
+        });
+    }
+    start_upgrade(&db, batch, STAGE_STAGED);
+    db.upgrade_key_layout().unwrap();
+    assert_upgraded(&db, &account_ids);
+
+    // Upgrading again is a no-op
+    db.upgrade_key_layout().unwrap();
+    assert_upgraded(&db, &account_ids);
+}
+
+fn start_upgrade<T>(db: &JMAPStore<T>, mut batch: Vec<WriteOperation>, stage: Integer)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    batch.push(WriteOperation::set(
+        ColumnFamily::Values,
+        KEY_LAYOUT_KEY.to_vec(),
+        0u32.serialize().unwrap(),
+    ));
+    batch.push(WriteOperation::set(
+        ColumnFamily::Values,
+        LAYOUT_UPGRADE_KEY.to_vec(),
+        stage.serialize().unwrap(),
+    ));
+    db.write_operations(batch).unwrap();
+}
+
+fn assert_upgraded<T>(db: &JMAPStore<T>, account_ids: &[AccountId])
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    assert_eq!(
+        db.db
+            .iterator(ColumnFamily::Bitmaps, &[], Direction::Forward)
+            .unwrap()
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect::<Vec<_>>(),
+        account_ids
+            .iter()
+            .map(|account_id| (new_key(*account_id), bitmap_value(*account_id)))
+            .collect::<Vec<_>>()
+    );
+    assert!(db
+        .db
+        .iterator(
+            ColumnFamily::Values,
+            LAYOUT_STAGING_KEY_PREFIX,
+            Direction::Forward
+        )
+        .unwrap()
+        .next()
+        .map_or(true, |(key, _)| !key.starts_with(LAYOUT_STAGING_KEY_PREFIX)));
+    assert!(!db
+        .db
+        .exists(ColumnFamily::Values, LAYOUT_UPGRADE_KEY)
+        .unwrap());
+    assert_eq!(
+        db.db
+            .get::<Integer>(ColumnFamily::Values, KEY_LAYOUT_KEY)
+            .unwrap(),
+        Some(KEY_LAYOUT_VERSION)
+    );
+}
+
+fn legacy_key(account_id: AccountId) -> Vec<u8> {
+    let mut key = b"hello".to_vec();
+    key.push(10);
+    key.push(Collection::Mail.into());
+    key.push(BM_TERM | TERM_EXACT);
+    key.push_leb128(account_id);
+    key
+}
+
+fn new_key(account_id: AccountId) -> Vec<u8> {
+    BitmapKey::serialize_term(account_id, Collection::Mail, 10, "hello", true)
+}
+
+fn staged_key(account_id: AccountId) -> Vec<u8> {
+    let mut key = LAYOUT_STAGING_KEY_PREFIX.to_vec();
+    key.extend_from_slice(&new_key(account_id));
+    key
+}
+
+fn bitmap_value(account_id: AccountId) -> Vec<u8> {
+    account_id.to_be_bytes().to_vec()
+}
@@ -21,9 +21,17 @@
  * for more details.
 */
 
+pub mod backup;
 pub mod blobs;
+pub mod compact;
+pub mod fencing;
+pub mod layout;
 pub mod log;
+pub mod prune;
 pub mod query;
+pub mod renumber;
+pub mod scan;
+pub mod ttl;
 pub mod utils;
 
 use std::{path::PathBuf, sync::Arc};
@@ -56,7 +64,8 @@ where
             T::open(&settings).unwrap(),
             JMAPConfig::from(&settings),
             &settings,
-        ),
+        )
+        .unwrap(),
         temp_dir,
     )
 }
@@ -68,8 +77,23 @@ fn store_tests() {
     let db = Arc::new(db);
 
     blobs::test(db.clone());
+    backup::test(db.clone());
+    scan::test(db.clone());
     log::test(db.clone());
-    query::test(db, true);
+    query::test(db.clone(), true);
+    ttl::test(db.clone());
+    prune::test(db.clone());
+    compact::test(db.clone());
+    renumber::test(db.clone());
+    fencing::test(db);
 
     destroy_temp_dir(&temp_dir);
 }
+
+#[test]
+#[ignore]
+fn key_layout_tests() {
+    let (db, temp_dir) = init_db::<RocksDB>("strdb_layout", true);
+    layout::test(db);
+    destroy_temp_dir(&temp_dir);
+}
@@ -0,0 +1,172 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use store::{
+    core::{collection::Collection, document::Document},
+    nlp::Language,
+    read::{
+        comparator::Comparator,
+        filter::{Filter, Query},
+        FilterMapper,
+    },
+    serialize::key::BitmapKey,
+    write::{
+        batch::WriteBatch,
+        options::{IndexOptions, Options},
+        prune::IndexPruneOptions,
+    },
+    ColumnFamily, JMAPStore, Store,
+};
+
+pub fn test<T>(db: Arc<JMAPStore<T>>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let account_id = 30;
+    let options = IndexPruneOptions {
+        max_frequency: 50,
+        min_documents: 5,
+    };
+    let fields = [(Collection::Mail, 0)];
+
+    // 'common' appears in every document and 'report' in seven of them
+    let mut batch = WriteBatch::new(account_id);
+    for num in 0..10 {
+        let mut document = Document::new(
+            Collection::Mail,
+            db.assign_document_id(account_id, Collection::Mail).unwrap(),
+        );
+        document.text(
+            0,
+            match num {
+                0 => "common report apple".to_string(),
+                1..=6 => format!("common report number{}", num),
+                _ => format!("common summary number{}", num),
+            },
+            Language::English,
+            IndexOptions::new().index().full_text(0),
+        );
+        batch.insert_document(document);
+    }
+    db.write(batch).unwrap();
+
+    let stats = db.index_stats().unwrap();
+    let field_stats = stats
+        .iter()
+        .find(|s| s.collection == Collection::Mail && s.field == 0)
+        .unwrap();
+    assert!(field_stats.terms >= 13, "{:?}", field_stats);
+    assert!(field_stats.postings >= 30, "{:?}", field_stats);
+
+    // Pruning is disabled by default
+    assert_eq!(
+        db.prune_index(IndexPruneOptions::default(), &fields)
+            .unwrap()
+            .pruned_terms,
+        0
+    );
+
+    let report = db.prune_index(options, &fields).unwrap();
+    assert_eq!(report.pruned_terms, 2);
+    assert_eq!(report.pruned_postings, 17);
+    assert_eq!(
+        db.get_pruned_terms(account_id, Collection::Mail)
+            .unwrap()
+            .len(),
+        2
+    );
+    assert!(!db
+        .db
+        .exists(
+            ColumnFamily::Bitmaps,
+            &BitmapKey::serialize_term(account_id, Collection::Mail, 0, "common", true)
+        )
+        .unwrap());
+
+    // Pruned terms are matched using the term index
+    let match_text =
+        |text: &str| Filter::eq(0, Query::match_text(text.to_string(), Language::English));
+    for (filter, expected) in [
+        (match_text("common apple"), 1),
+        (match_text("report"), 7),
+        (match_text("\"common report\""), 7),
+        (match_text("\"report common\""), 0),
+        (match_text("number3"), 1),
+        (match_text("report summary"), 0),
+        (match_text("common summary"), 3),
+        (Filter::eq(0, Query::Tokenize("report".to_string())), 7),
+        (Filter::eq(0, Query::Prefix("repo".to_string())), 7),
+        (Filter::eq(0, Query::Prefix("comm".to_string())), 10),
+        (
+            Filter::or(vec![match_text("report"), match_text("summary")]),
+            10,
+        ),
+        (Filter::not(vec![match_text("report")]), 3),
+        (
+            Filter::and(vec![
+                match_text("common"),
+                Filter::not(vec![match_text("summary")]),
+            ]),
+            7,
+        ),
+    ] {
+        let description = format!("{:?}", filter);
+        assert_eq!(
+            db.query_store::<FilterMapper>(account_id, Collection::Mail, filter, Comparator::None)
+                .unwrap()
+                .into_bitmap()
+                .len(),
+            expected,
+            "{}",
+            description
+        );
+    }
+
+    // Postings of pruned terms are dropped again once new documents are indexed
+    let mut batch = WriteBatch::new(account_id);
+    let mut document = Document::new(
+        Collection::Mail,
+        db.assign_document_id(account_id, Collection::Mail).unwrap(),
+    );
+    document.text(
+        0,
+        "common ground".to_string(),
+        Language::English,
+        IndexOptions::new().index().full_text(0),
+    );
+    batch.insert_document(document);
+    db.write(batch).unwrap();
+
+    let report = db.prune_index(options, &fields).unwrap();
+    assert_eq!(report.pruned_terms, 0);
+    assert_eq!(report.pruned_postings, 1);
+    assert!(!db
+        .db
+        .exists(
+            ColumnFamily::Bitmaps,
+            &BitmapKey::serialize_term(account_id, Collection::Mail, 0, "common", true)
+        )
+        .unwrap());
+}
@@ -31,8 +31,9 @@ use store::{
     core::{collection::Collection, document::Document, JMAPIdPrefix},
     nlp::Language,
     read::{
-        comparator::Comparator,
+        comparator::{Comparator, DocumentSetComparator},
         filter::{ComparisonOperator, Filter, Query},
+        iterator::StoreIterator,
         FilterMapper,
     },
     write::{
@@ -210,7 +211,13 @@ where
     test_filter(db.clone());
 
     println!("Running sort tests...");
-    test_sort(db);
+    test_sort(db.clone());
+
+    println!("Running parallel sort tests...");
+    test_sort_parallel(db.clone());
+
+    println!("Running relevance tests...");
+    test_relevance(db);
 }
 
 pub fn test_filter<T>(db: Arc<JMAPStore<T>>)
@@ -557,3 +564,135 @@ where
         assert_eq!(results, expected_results);
     }
 }
+
+pub fn test_sort_parallel<T>(db: Arc<JMAPStore<T>>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let mut fields = AHashMap::default();
+    for (field_num, field) in FIELDS.iter().enumerate() {
+        fields.insert(field.to_string(), field_num as u8);
+    }
+
+    let document_ids = db.get_document_ids(0, Collection::Mail).unwrap().unwrap();
+    let results = db
+        .query_store::<FilterMapper>(
+            0,
+            Collection::Mail,
+            Filter::gt(fields["year"], Query::Integer(1900)),
+            Comparator::None,
+        )
+        .unwrap()
+        .into_bitmap();
+    let wide = db
+        .query_store::<FilterMapper>(
+            0,
+            Collection::Mail,
+            Filter::gt(fields["width"], Query::Integer(1000)),
+            Comparator::None,
+        )
+        .unwrap()
+        .into_bitmap();
+
+    for sort in [
+        vec![("year", false), ("acquisitionYear", true), ("width", true)],
+        vec![("artistRole", false), ("medium", true)],
+        vec![("units", true), ("wide", false), ("height", false)],
+        vec![("wide", true), ("accession_number", false)],
+    ] {
+        let comparators = || {
+            sort.iter()
+                .map(|(field, ascending)| match (*field, *ascending) {
+                    ("wide", ascending) => Comparator::DocumentSet(DocumentSetComparator {
+                        set: wide.clone(),
+                        ascending,
+                    }),
+                    (field, true) => Comparator::ascending(fields[field]),
+                    (field, false) => Comparator::descending(fields[field]),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let now = Instant::now();
+        let sequential = StoreIterator::<T, FilterMapper>::new(
+            &db,
+            results.clone(),
+            document_ids.clone(),
+            0,
+            Collection::Mail,
+            Comparator::List(comparators()),
+        )
+        .map(|jmap_id| jmap_id.get_document_id())
+        .collect::<Vec<_>>();
+        let sequential_ms = now.elapsed().as_millis();
+
+        let now = Instant::now();
+        let parallel = db
+            .sort_parallel(0, Collection::Mail, &results, &document_ids, &comparators())
+            .unwrap();
+        println!(
+            "Sorted {} entries by {:?} in {} ms (sequential {} ms).",
+            parallel.len(),
+            sort,
+            now.elapsed().as_millis(),
+            sequential_ms
+        );
+
+        assert_eq!(sequential, parallel, "{:?}", sort);
+    }
+}
+
+pub fn test_relevance<T>(db: Arc<JMAPStore<T>>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let account_id = 1;
+    let mut batch = WriteBatch::new(account_id);
+    let mut document_ids = Vec::new();
+    for text in [
+        "apple banana",
+        "apple apple apple banana cherry",
+        "banana cherry",
+        "apple kiwi kiwi kiwi kiwi kiwi kiwi kiwi kiwi",
+    ] {
+        let document_id = db.assign_document_id(account_id, Collection::Mail).unwrap();
+        let mut document = Document::new(Collection::Mail, document_id);
+        document.text(
+            0,
+            text.to_string(),
+            Language::English,
+            IndexOptions::new().full_text(0),
+        );
+        batch.insert_document(document);
+        document_ids.push(document_id as u64);
+    }
+    db.write(batch).unwrap();
+
+    // More occurrences rank higher, shorter documents break ties
+    for (ascending, expected) in [
+        (
+            false,
+            vec![document_ids[1], document_ids[0], document_ids[3]],
+        ),
+        (
+            true,
+            vec![document_ids[3], document_ids[0], document_ids[1]],
+        ),
+    ] {
+        let mut comparator = Comparator::relevance(vec![0], "apple".to_string(), Language::English);
+        if let Comparator::Relevance(comparator) = &mut comparator {
+            comparator.ascending = ascending;
+        }
+        assert_eq!(
+            db.query_store::<FilterMapper>(
+                account_id,
+                Collection::Mail,
+                Filter::eq(0, Query::match_text("apple".to_string(), Language::English)),
+                Comparator::List(vec![comparator]),
+            )
+            .unwrap()
+            .collect::<Vec<_>>(),
+            expected
+        );
+    }
+}
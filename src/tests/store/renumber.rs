@@ -0,0 +1,194 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use store::{
+    core::{collection::Collection, document::Document, error::StoreError, tag::Tag},
+    log::changes::Query,
+    write::{
+        batch::WriteBatch,
+        options::{IndexOptions, Options},
+        renumber::IdReference,
+    },
+    DocumentId, JMAPStore, Store,
+};
+
+const THREAD_FIELD: u8 = 1;
+const SEQ_FIELD: u8 = 2;
+
+pub fn test<T>(db: Arc<JMAPStore<T>>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let account_id = 41;
+
+    // Sparse ids, as left behind by years of deletions
+    let mut batch = WriteBatch::new(account_id);
+    for num in 0..10u32 {
+        let thread_id = (num + 1) * 5;
+        batch.insert_document(Document::new(Collection::Thread, thread_id));
+        batch.log_insert(Collection::Thread, thread_id);
+    }
+    for num in 0..20u32 {
+        let document_id = (num + 1) * 3;
+        let thread_id = ((num % 10) + 1) * 5;
+        let mut document = Document::new(Collection::Mail, document_id);
+        document.tag(THREAD_FIELD, Tag::Id(thread_id), IndexOptions::new());
+        document.number(THREAD_FIELD, thread_id, IndexOptions::new().store());
+        document.number(SEQ_FIELD, num, IndexOptions::new().store().index());
+        batch.insert_document(document);
+        batch.log_insert(Collection::Mail, document_id);
+    }
+    db.write(batch).unwrap();
+
+    let references = [IdReference::new(
+        Collection::Mail,
+        THREAD_FIELD,
+        Collection::Thread,
+    )];
+    // Renumbering requires writes to the account to be blocked
+    assert!(matches!(
+        db.renumber_documents(
+            account_id,
+            &[Collection::Mail, Collection::Thread],
+            &references,
+            |_| Ok(Vec::new()),
+        ),
+        Err(StoreError::InvalidArguments(_))
+    ));
+    let gate = db.block_writes(account_id).unwrap();
+    assert!(matches!(
+        db.block_writes(account_id),
+        Err(StoreError::WritesBlocked(_))
+    ));
+
+    // Other threads can't write to the account while the gate is held
+    let db_ = db.clone();
+    let result = std::thread::spawn(move || {
+        let mut batch = WriteBatch::new(account_id);
+        batch.log_insert(Collection::Mail, 1000);
+        db_.write(batch)
+    })
+    .join()
+    .unwrap();
+    assert!(
+        matches!(result, Err(StoreError::WritesBlocked(id)) if id == account_id),
+        "{:?}",
+        result
+    );
+
+    let report = db
+        .renumber_documents(
+            account_id,
+            &[Collection::Mail, Collection::Thread],
+            &references,
+            |_| Ok(Vec::new()),
+        )
+        .unwrap();
+    drop(gate);
+    assert!(report.change_id.is_some(), "{:?}", report);
+    assert_eq!(report.collections.len(), 2);
+    assert!(report.collections.iter().all(|c| c.renumbered > 0));
+
+    // Ids are dense and keep their order
+    assert_eq!(
+        db.get_document_ids(account_id, Collection::Mail)
+            .unwrap()
+            .unwrap(),
+        (0..20).collect()
+    );
+    assert_eq!(
+        db.get_document_ids(account_id, Collection::Thread)
+            .unwrap()
+            .unwrap(),
+        (0..10).collect()
+    );
+    for document_id in 0..20u32 {
+        assert_eq!(
+            db.get_document_value::<u32>(account_id, Collection::Mail, document_id, SEQ_FIELD)
+                .unwrap(),
+            Some(document_id)
+        );
+        assert_eq!(
+            db.get_document_value::<DocumentId>(
+                account_id,
+                Collection::Mail,
+                document_id,
+                THREAD_FIELD
+            )
+            .unwrap(),
+            Some(document_id % 10)
+        );
+    }
+    for thread_id in 0..10u32 {
+        assert_eq!(
+            db.get_tag(
+                account_id,
+                Collection::Mail,
+                THREAD_FIELD,
+                Tag::Id(thread_id)
+            )
+            .unwrap()
+            .unwrap(),
+            [thread_id, thread_id + 10].into_iter().collect()
+        );
+    }
+    assert_eq!(
+        db.get_tag(account_id, Collection::Mail, THREAD_FIELD, Tag::Id(50))
+            .unwrap()
+            .unwrap_or_default()
+            .len(),
+        0
+    );
+
+    // Changes recorded before the renumbering can no longer be calculated
+    assert_eq!(
+        db.get_renumbered_change_id(account_id, Collection::Mail)
+            .unwrap(),
+        report.change_id
+    );
+    assert!(db
+        .get_changes(account_id, Collection::Mail, Query::Since(0))
+        .unwrap()
+        .is_none());
+    assert!(db
+        .get_changes(account_id, Collection::Mail, Query::All)
+        .unwrap()
+        .is_some());
+
+    // New ids continue after the renumbered ones
+    assert_eq!(
+        db.assign_document_id(account_id, Collection::Mail).unwrap(),
+        20
+    );
+
+    // A dense collection is left untouched
+    let _gate = db.block_writes(account_id).unwrap();
+    let report = db
+        .renumber_documents(account_id, &[Collection::Thread], &references, |_| {
+            Ok(Vec::new())
+        })
+        .unwrap();
+    assert_eq!(report.change_id, None);
+}
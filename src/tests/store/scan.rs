@@ -0,0 +1,82 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use store::{ColumnFamily, Direction, JMAPStore, Store};
+
+pub fn test<T>(db: Arc<JMAPStore<T>>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let prefix = b"scan_test_".to_vec();
+
+    // Keys right outside the prefix on both ends must not be returned
+    db.db.set(ColumnFamily::Values, b"scan_tesa", b"x").unwrap();
+    db.db.set(ColumnFamily::Values, b"scan_tesu", b"x").unwrap();
+    for n in 0u64..10 {
+        let mut key = prefix.clone();
+        key.push(n as u8);
+        db.db
+            .set(ColumnFamily::Values, &key, &n.to_le_bytes())
+            .unwrap();
+    }
+
+    for direction in [Direction::Forward, Direction::Backward] {
+        let is_backward = matches!(direction, Direction::Backward);
+        let mut scan = db
+            .scan_prefix(ColumnFamily::Values, prefix.clone(), direction)
+            .unwrap();
+        let mut results = Vec::new();
+        while let Some(entry) = scan.next().unwrap() {
+            assert_eq!(entry.key_suffix().len(), 1);
+            let value = entry.deserialize_value::<u64>().unwrap();
+            assert_eq!(entry.key_suffix()[0] as u64, value);
+            results.push(value);
+        }
+
+        let mut expected = (0u64..10).collect::<Vec<_>>();
+        if is_backward {
+            expected.reverse();
+        }
+        assert_eq!(results, expected);
+    }
+
+    // Empty prefixes yield no entries
+    let mut scan = db
+        .scan_prefix(
+            ColumnFamily::Values,
+            b"scan_none_".to_vec(),
+            Direction::Forward,
+        )
+        .unwrap();
+    assert!(scan.next().unwrap().is_none());
+
+    for n in 0u8..10 {
+        let mut key = prefix.clone();
+        key.push(n);
+        db.db.delete(ColumnFamily::Values, &key).unwrap();
+    }
+    db.db.delete(ColumnFamily::Values, b"scan_tesa").unwrap();
+    db.db.delete(ColumnFamily::Values, b"scan_tesu").unwrap();
+}
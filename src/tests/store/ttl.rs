@@ -0,0 +1,128 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart JMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{sync::Arc, time::SystemTime};
+
+use store::{
+    serialize::key::{ValueKey, EXPIRY_INDEX_PREFIX},
+    ColumnFamily, Direction, JMAPStore, Store,
+};
+
+pub fn test<T>(db: Arc<JMAPStore<T>>)
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+
+    // Expired values are not visible even before they are purged
+    db.set_expiring(b"ttl_live", b"live", now + 60_000).unwrap();
+    db.set_expiring(b"ttl_expired", b"expired", now - 1)
+        .unwrap();
+    db.set_expiring(b"ttl_renewed", b"old", now - 1).unwrap();
+    db.set_expiring(b"ttl_renewed", b"new", now + 60_000)
+        .unwrap();
+    db.set_expiring(b"ttl_deleted", b"deleted", now - 1)
+        .unwrap();
+    db.delete_expiring(b"ttl_deleted").unwrap();
+    assert_eq!(
+        db.get_expiring::<String>(b"ttl_live").unwrap(),
+        Some("live".to_string())
+    );
+    assert_eq!(db.get_expiring::<String>(b"ttl_expired").unwrap(), None);
+    assert_eq!(
+        db.get_expiring::<String>(b"ttl_renewed").unwrap(),
+        Some("new".to_string())
+    );
+    assert_eq!(db.get_expiring::<String>(b"ttl_deleted").unwrap(), None);
+
+    // Values are only replaced if they still hold the expected contents
+    assert!(!db
+        .compare_and_set_expiring(b"ttl_live", b"other", b"updated", now + 60_000)
+        .unwrap());
+    assert!(db
+        .compare_and_set_expiring(b"ttl_live", b"live", b"updated", now + 60_000)
+        .unwrap());
+    assert!(!db
+        .compare_and_set_expiring(b"ttl_live", b"live", b"updated again", now + 60_000)
+        .unwrap());
+    assert!(!db
+        .compare_and_set_expiring(b"ttl_expired", b"expired", b"updated", now + 60_000)
+        .unwrap());
+    assert_eq!(
+        db.get_expiring::<String>(b"ttl_live").unwrap(),
+        Some("updated".to_string())
+    );
+    assert_eq!(db.get_expiring::<String>(b"ttl_expired").unwrap(), None);
+
+    // Purging removes expired values and all stale index entries
+    assert_eq!(db.purge_expired().unwrap(), 1);
+    assert!(!db
+        .db
+        .exists(
+            ColumnFamily::Values,
+            &ValueKey::serialize_expiring(b"ttl_expired")
+        )
+        .unwrap());
+    assert_eq!(
+        db.get_expiring::<String>(b"ttl_renewed").unwrap(),
+        Some("new".to_string())
+    );
+    assert_eq!(expiry_index_len(&db), 2);
+
+    // Deleted values are no longer visible
+    for name in [&b"ttl_live"[..], &b"ttl_renewed"[..]] {
+        db.delete_expiring(name).unwrap();
+        assert_eq!(db.get_expiring::<String>(name).unwrap(), None);
+    }
+
+    // Inserting only succeeds while there is no live value
+    assert!(db
+        .insert_expiring(b"ttl_live", b"first", now + 60_000)
+        .unwrap());
+    assert!(!db
+        .insert_expiring(b"ttl_live", b"second", now + 60_000)
+        .unwrap());
+    assert_eq!(
+        db.get_expiring::<String>(b"ttl_live").unwrap(),
+        Some("first".to_string())
+    );
+    db.delete_expiring(b"ttl_live").unwrap();
+}
+
+fn expiry_index_len<T>(db: &JMAPStore<T>) -> usize
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    db.db
+        .iterator(
+            ColumnFamily::Values,
+            EXPIRY_INDEX_PREFIX,
+            Direction::Forward,
+        )
+        .unwrap()
+        .take_while(|(key, _)| key.starts_with(EXPIRY_INDEX_PREFIX))
+        .count()
+}
@@ -34,7 +34,7 @@ use jmap_mail::mail::schema::Email;
 use jmap_mail::mailbox::schema::Mailbox;
 use jmap_sieve::sieve_script::schema::SieveScript;
 use store::ahash::AHashSet;
-use store::serialize::key::ValueKey;
+use store::serialize::key::{BitmapKey, ValueKey};
 use store::serialize::leb128::Leb128Reader;
 use store::{ahash::AHashMap, blob::BLOB_HASH_LEN};
 use store::{
@@ -42,7 +42,10 @@ use store::{
     core::collection::Collection,
     roaring::RoaringBitmap,
     serialize::{
-        key::{FOLLOWER_COMMIT_INDEX_KEY, LEADER_COMMIT_INDEX_KEY},
+        key::{
+            EXPIRING_KEY_PREFIX, EXPIRY_INDEX_PREFIX, FOLLOWER_COMMIT_INDEX_KEY, KEY_LAYOUT_KEY,
+            LAST_APPLIED_KEY, LEADER_COMMIT_INDEX_KEY, NAMED_KEY_PREFIX, NLP_CONFIG_KEY,
+        },
         StoreDeserialize,
     },
     AccountId, ColumnFamily, JMAPStore, Store,
@@ -176,12 +179,26 @@ pub fn init_settings(
                 format!("http://127.0.0.1:{}", 8000 + peer_num),
             ),
             ("lmtp-port".to_string(), (11200 + peer_num).to_string()),
+            ("lmtp-trace-days".to_string(), "1".to_string()),
+            ("smtp-port".to_string(), (11300 + peer_num).to_string()),
+            ("smtp-auth".to_string(), "false".to_string()),
+            ("smtp-max-recipients".to_string(), "3".to_string()),
+            ("smtp-max-messages".to_string(), "2".to_string()),
             ("max-objects-in-set".to_string(), "100000".to_string()),
             ("query-max-results".to_string(), "100000".to_string()),
+            ("mail-max-keywords".to_string(), "100".to_string()),
+            ("mail-set-keywords-chunk-size".to_string(), "2".to_string()),
+            ("subaddress-separator".to_string(), "+".to_string()),
+            ("group-delivery".to_string(), "members".to_string()),
             ("jmap-port".to_string(), (8000 + peer_num).to_string()),
             ("smtp-relay-host".to_string(), "127.0.0.1".to_string()),
             ("smtp-relay-port".to_string(), "9999".to_string()),
             ("smtp-relay-tls".to_string(), "false".to_string()),
+            ("smtp-relay-dsn".to_string(), "true".to_string()),
+            (
+                "submission-blocked-domains".to_string(),
+                "blocked.example.net".to_string(),
+            ),
             ("max-concurrent-uploads".to_string(), "4".to_string()),
             ("max-concurrent-requests".to_string(), "8".to_string()),
             ("push-attempt-interval".to_string(), "500".to_string()),
@@ -193,6 +210,10 @@ pub fn init_settings(
             ("oauth-refresh-token-expiry".to_string(), "3".to_string()),
             ("oauth-refresh-token-renew".to_string(), "2".to_string()),
             ("oauth-max-attempts".to_string(), "1".to_string()),
+            (
+                "oauth-require-passkey-admin".to_string(),
+                "true".to_string(),
+            ),
             ("rate-limit-anonymous".to_string(), "100/60".to_string()),
             ("rate-limit-auth".to_string(), "100/60".to_string()),
             (
@@ -287,8 +308,8 @@ where
             {
                 match cf {
                     ColumnFamily::Bitmaps => {
-                        let account_id = key[key.len() - 1] as AccountId;
-                        let collection = key[key.len() - 3].into();
+                        let account_id = BitmapKey::deserialize_account_id(&key).unwrap();
+                        let collection = key[std::mem::size_of::<AccountId>()].into();
 
                         if account_id != last_account_id || last_collection != collection {
                             last_account_id = account_id;
@@ -339,10 +360,7 @@ where
                         }
                     }
                     ColumnFamily::Values => {
-                        if (0..=9).contains(&key[0])
-                            && &key[..] != FOLLOWER_COMMIT_INDEX_KEY
-                            && &key[..] != LEADER_COMMIT_INDEX_KEY
-                        {
+                        if (0..=9).contains(&key[0]) && !is_internal_key(&key) {
                             let (account_id, pos) = key.read_leb128().unwrap();
                             let collection = key[pos].into();
                             let (document_id, _) = (&key[pos + 1..]).read_leb128().unwrap();
@@ -429,7 +447,9 @@ where
                                                 TinyORM::<SieveScript>::deserialize(&other_value)
                                                     .unwrap()
                                             ),
-                                            Collection::Thread | Collection::None => unreachable!(),
+                                            Collection::Thread
+                                            | Collection::Annotation
+                                            | Collection::None => unreachable!(),
                                         }
                                     } else if ASSERT {
                                         panic!(
@@ -633,7 +653,7 @@ where
                             value
                         );
                     }
-                    ColumnFamily::Values if (0..=9).contains(&key[0]) => {
+                    ColumnFamily::Values if (0..=9).contains(&key[0]) && !is_internal_key(&key) => {
                         panic!("{:?} {:?}={:?}", cf, key, value);
                     }
                     ColumnFamily::Indexes => {
@@ -662,3 +682,17 @@ where
         self.id_assigner.invalidate_all();
     }
 }
+
+fn is_internal_key(key: &[u8]) -> bool {
+    [
+        &FOLLOWER_COMMIT_INDEX_KEY[..],
+        &LEADER_COMMIT_INDEX_KEY[..],
+        &NLP_CONFIG_KEY[..],
+        &KEY_LAYOUT_KEY[..],
+        &LAST_APPLIED_KEY[..],
+    ]
+    .contains(&key)
+        || key.starts_with(NAMED_KEY_PREFIX)
+        || key.starts_with(EXPIRING_KEY_PREFIX)
+        || key.starts_with(EXPIRY_INDEX_PREFIX)
+}
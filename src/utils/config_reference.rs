@@ -0,0 +1,7 @@
+use stalwart_jmap::server::settings::schema;
+use store::config::settings::reference;
+
+// Prints the Markdown reference of all configuration settings.
+fn main() {
+    print!("{}", reference(&schema()));
+}
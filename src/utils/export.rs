@@ -15,7 +15,8 @@ pub fn main() {
         RocksDB::open(&settings).expect("failed to open database"),
         JMAPConfig::from(&settings),
         &settings,
-    );
+    )
+    .expect("failed to open store");
 
     dump_message(
         &store,
@@ -0,0 +1,694 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use jmap::{
+    jmap_store::Object, principal::schema::Principal, push_subscription::schema::PushSubscription,
+};
+use jmap_mail::{
+    email_submission::schema::EmailSubmission, identity::schema::Identity, mail::MessageField,
+    mail_parser::RfcHeader, mailbox::schema::Mailbox,
+};
+use jmap_sieve::sieve_script::schema::SieveScript;
+use parquet::{
+    basic::{ConvertedType, Repetition, Type as PhysicalType},
+    data_type::{BoolType, ByteArray, ByteArrayType, Int64Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::types::Type,
+};
+use store::{
+    ahash::{AHashMap, AHashSet},
+    config::{env_settings::EnvSettings, jmap::JMAPConfig},
+    core::{collection::Collection, error::StoreError},
+    nlp::term_index::TokenIndex,
+    serialize::{key::IndexKey, StoreDeserialize},
+    write::options::Options,
+    AccountId, ColumnFamily, Direction, DocumentId, FieldId, JMAPStore, Store,
+};
+use store_rocksdb::RocksDB;
+
+const COLLECTIONS: [Collection; 8] = [
+    Collection::Principal,
+    Collection::PushSubscription,
+    Collection::Mail,
+    Collection::Mailbox,
+    Collection::Thread,
+    Collection::Identity,
+    Collection::EmailSubmission,
+    Collection::SieveScript,
+];
+
+const MAIL_HEADERS: [RfcHeader; 29] = [
+    RfcHeader::Subject,
+    RfcHeader::From,
+    RfcHeader::To,
+    RfcHeader::Cc,
+    RfcHeader::Date,
+    RfcHeader::Bcc,
+    RfcHeader::ReplyTo,
+    RfcHeader::Sender,
+    RfcHeader::Comments,
+    RfcHeader::InReplyTo,
+    RfcHeader::Keywords,
+    RfcHeader::Received,
+    RfcHeader::MessageId,
+    RfcHeader::References,
+    RfcHeader::ReturnPath,
+    RfcHeader::ResentTo,
+    RfcHeader::ResentFrom,
+    RfcHeader::ResentBcc,
+    RfcHeader::ResentCc,
+    RfcHeader::ResentSender,
+    RfcHeader::ResentDate,
+    RfcHeader::ResentMessageId,
+    RfcHeader::ListArchive,
+    RfcHeader::ListHelp,
+    RfcHeader::ListId,
+    RfcHeader::ListOwner,
+    RfcHeader::ListPost,
+    RfcHeader::ListSubscribe,
+    RfcHeader::ListUnsubscribe,
+];
+
+const ROW_GROUP_SIZE: usize = 65536;
+
+const TERM_COLUMNS: &[(&str, ColumnType)] = &[
+    ("collection", ColumnType::Text),
+    ("documentId", ColumnType::Number),
+    ("field", ColumnType::Text),
+    ("term", ColumnType::Text),
+    ("isStemmed", ColumnType::Bool),
+];
+
+const INDEX_COLUMNS: &[(&str, ColumnType)] = &[
+    ("collection", ColumnType::Text),
+    ("documentId", ColumnType::Number),
+    ("field", ColumnType::Text),
+    ("value", ColumnType::Number),
+];
+
+const MAIL_FIELDS: [(MessageField, &str); 12] = [
+    (MessageField::Metadata, "metadata"),
+    (MessageField::Body, "body"),
+    (MessageField::Attachment, "attachment"),
+    (MessageField::ReceivedAt, "receivedAt"),
+    (MessageField::Size, "size"),
+    (MessageField::Keyword, "keywords"),
+    (MessageField::Thread, "thread"),
+    (MessageField::ThreadName, "threadName"),
+    (MessageField::MessageIdRef, "messageIdRef"),
+    (MessageField::ThreadId, "threadId"),
+    (MessageField::Mailbox, "mailboxIds"),
+    (MessageField::HasHeader, "hasHeader"),
+];
+
+pub fn main() {
+    // Read configuration parameters
+    let settings = EnvSettings::new();
+
+    let account_id: AccountId = settings
+        .parse("account-id")
+        .expect("A valid 'account-id' parameter.");
+    let output_path = PathBuf::from(
+        settings
+            .get("output-path")
+            .expect("A valid 'output-path' parameter."),
+    );
+    let format = match settings.get("format").as_deref() {
+        Some("csv") | None => Format::Csv,
+        Some("parquet") => Format::Parquet,
+        Some(format) => panic!(
+            "Unsupported export format {:?}, use 'csv' or 'parquet'.",
+            format
+        ),
+    };
+
+    let store: JMAPStore<RocksDB> = JMAPStore::new(
+        RocksDB::open(&settings).expect("failed to open database"),
+        JMAPConfig::from(&settings),
+        &settings,
+    )
+    .expect("failed to open store");
+    let schema = FieldSchema::new();
+
+    let mut terms_path = output_path.clone();
+    terms_path.push(format!("terms_{}.{}", account_id, format.extension()));
+    let num_terms = export_terms(
+        &store,
+        account_id,
+        &schema,
+        format
+            .writer("terms", TERM_COLUMNS, &terms_path)
+            .expect("Failed to create file"),
+    )
+    .expect("Failed to export term index");
+
+    let mut indexes_path = output_path;
+    indexes_path.push(format!("indexes_{}.{}", account_id, format.extension()));
+    let num_indexes = export_indexes(
+        &store,
+        account_id,
+        &schema,
+        format
+            .writer("indexes", INDEX_COLUMNS, &indexes_path)
+            .expect("Failed to create file"),
+    )
+    .expect("Failed to export numeric indexes");
+
+    println!(
+        "Exported {} terms to {} and {} index entries to {}.",
+        num_terms,
+        terms_path.display(),
+        num_indexes,
+        indexes_path.display()
+    );
+}
+
+/// Field names and numeric index flags obtained from the collection schemas.
+struct FieldSchema {
+    names: AHashMap<(Collection, FieldId), String>,
+    numeric: AHashSet<(Collection, FieldId)>,
+}
+
+impl FieldSchema {
+    fn new() -> Self {
+        let mut schema = FieldSchema {
+            names: AHashMap::new(),
+            numeric: AHashSet::new(),
+        };
+        schema.add_object::<Principal>();
+        schema.add_object::<PushSubscription>();
+        schema.add_object::<Mailbox>();
+        schema.add_object::<Identity>();
+        schema.add_object::<EmailSubmission>();
+        schema.add_object::<SieveScript>();
+
+        // Messages are indexed directly rather than through the ORM
+        for header in MAIL_HEADERS {
+            schema.names.insert(
+                (Collection::Mail, header.into()),
+                format!("header:{}", header.as_str()),
+            );
+        }
+        for (field, name) in MAIL_FIELDS {
+            schema
+                .names
+                .insert((Collection::Mail, field.into()), name.to_string());
+        }
+        for field in [
+            MessageField::ReceivedAt as FieldId,
+            MessageField::Size as FieldId,
+            FieldId::from(RfcHeader::Date),
+        ] {
+            schema.numeric.insert((Collection::Mail, field));
+        }
+
+        schema
+    }
+
+    fn add_object<O>(&mut self)
+    where
+        O: Object,
+        O::Property: Display,
+    {
+        let collection = O::collection();
+        for field in 0..=u8::MAX {
+            let property = O::Property::from(field);
+            let name = property.to_string();
+            if !name.is_empty() && Into::<FieldId>::into(property) == field {
+                self.names.insert((collection, field), name);
+            }
+        }
+        for (property, options) in O::indexed() {
+            if options.is_index() && options.get_text_options() == 0 {
+                self.numeric
+                    .insert((collection, Into::<FieldId>::into(property.clone())));
+            }
+        }
+    }
+
+    fn name(&self, collection: Collection, field: FieldId) -> String {
+        self.names
+            .get(&(collection, field))
+            .cloned()
+            .unwrap_or_else(|| format!("field:{}", field))
+    }
+}
+
+/// Writes one row per distinct term and field of each document, reading
+/// the term indexes one document at a time.
+fn export_terms<T>(
+    store: &JMAPStore<T>,
+    account_id: AccountId,
+    schema: &FieldSchema,
+    mut out: Box<dyn RowWriter>,
+) -> store::Result<u64>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let mut num_rows = 0;
+
+    for collection in COLLECTIONS {
+        let document_ids =
+            if let Some(document_ids) = store.get_document_ids(account_id, collection)? {
+                document_ids
+            } else {
+                continue;
+            };
+        let collection_name = collection_name(collection);
+
+        for document_id in document_ids {
+            let token_index = if let Some(token_index) = store
+                .get_term_index_id(account_id, collection, document_id)?
+                .and_then(|blob_id| store.blob_get(&blob_id).transpose())
+                .transpose()?
+                .and_then(|bytes| TokenIndex::deserialize(&bytes))
+            {
+                token_index
+            } else {
+                continue;
+            };
+
+            for terms in &token_index.terms {
+                let field_name = schema.name(collection, terms.field_id);
+                for (term_ids, is_stemmed) in
+                    [(&terms.exact_terms, false), (&terms.stemmed_terms, true)]
+                {
+                    for term_id in term_ids {
+                        if let Some(token) = token_index.tokens.get(*term_id as usize) {
+                            out.write_row(&[
+                                Cell::Text(collection_name),
+                                Cell::Number(document_id as u64),
+                                Cell::Text(&field_name),
+                                Cell::Text(token),
+                                Cell::Bool(is_stemmed),
+                            ])?;
+                            num_rows += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    out.finish()?;
+    Ok(num_rows)
+}
+
+/// Writes one row per numeric index entry by scanning the account's
+/// index keys in order.
+fn export_indexes<T>(
+    store: &JMAPStore<T>,
+    account_id: AccountId,
+    schema: &FieldSchema,
+    mut out: Box<dyn RowWriter>,
+) -> store::Result<u64>
+where
+    T: for<'x> Store<'x> + 'static,
+{
+    let mut num_rows = 0;
+    let prefix = account_id.to_be_bytes();
+    let prefix_len = prefix.len() + 2;
+
+    for (key, _) in store
+        .db
+        .iterator(ColumnFamily::Indexes, &prefix, Direction::Forward)?
+    {
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        let (collection, field) = match key.get(prefix.len()..prefix_len) {
+            Some(&[collection, field]) => (Collection::from(collection), field),
+            _ => continue,
+        };
+        if !schema.numeric.contains(&(collection, field)) {
+            continue;
+        }
+        let document_id: DocumentId =
+            if let Some(document_id) = IndexKey::deserialize_document_id(&key) {
+                document_id
+            } else {
+                continue;
+            };
+        let value = match key.get(prefix_len..key.len() - std::mem::size_of::<DocumentId>()) {
+            Some(bytes) if bytes.len() == std::mem::size_of::<u32>() => {
+                u32::from_be_bytes(bytes.try_into().unwrap()) as u64
+            }
+            Some(bytes) if bytes.len() == std::mem::size_of::<u64>() => {
+                u64::from_be_bytes(bytes.try_into().unwrap())
+            }
+            _ => continue,
+        };
+
+        out.write_row(&[
+            Cell::Text(collection_name(collection)),
+            Cell::Number(document_id as u64),
+            Cell::Text(&schema.name(collection, field)),
+            Cell::Number(value),
+        ])?;
+        num_rows += 1;
+    }
+
+    out.finish()?;
+    Ok(num_rows)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Csv,
+    Parquet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnType {
+    Text,
+    Number,
+    Bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Cell<'x> {
+    Text(&'x str),
+    Number(u64),
+    Bool(bool),
+}
+
+/// Destination of the exported rows, cells are written in the order of
+/// the columns the writer was created with.
+trait RowWriter {
+    fn write_row(&mut self, row: &[Cell]) -> store::Result<()>;
+    fn finish(self: Box<Self>) -> store::Result<()>;
+}
+
+impl Format {
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Parquet => "parquet",
+        }
+    }
+
+    fn writer(
+        &self,
+        table: &str,
+        columns: &'static [(&'static str, ColumnType)],
+        path: &Path,
+    ) -> store::Result<Box<dyn RowWriter>> {
+        let file = File::create(path)?;
+        Ok(match self {
+            Format::Csv => Box::new(CsvWriter::new(BufWriter::new(file), columns)?),
+            Format::Parquet => Box::new(ParquetWriter::new(file, table, columns)?),
+        })
+    }
+}
+
+struct CsvWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> CsvWriter<W> {
+    fn new(mut out: W, columns: &[(&str, ColumnType)]) -> store::Result<Self> {
+        writeln!(
+            out,
+            "{}",
+            columns
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(",")
+        )?;
+        Ok(CsvWriter { out })
+    }
+}
+
+impl<W: Write> RowWriter for CsvWriter<W> {
+    fn write_row(&mut self, row: &[Cell]) -> store::Result<()> {
+        for (pos, cell) in row.iter().enumerate() {
+            if pos > 0 {
+                self.out.write_all(b",")?;
+            }
+            match cell {
+                Cell::Text(value) => write!(self.out, "{}", csv_escape(value))?,
+                Cell::Number(value) => write!(self.out, "{}", value)?,
+                Cell::Bool(value) => write!(self.out, "{}", value)?,
+            }
+        }
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> store::Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+enum ColumnBuffer {
+    Text(Vec<ByteArray>),
+    Number(Vec<i64>),
+    Bool(Vec<bool>),
+}
+
+/// Buffers rows column by column and writes them out as a Parquet row group
+/// every `ROW_GROUP_SIZE` rows, so memory usage does not depend on the
+/// size of the account.
+struct ParquetWriter<W: Write + Send> {
+    writer: SerializedFileWriter<W>,
+    columns: Vec<ColumnBuffer>,
+    num_rows: usize,
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    fn new(out: W, table: &str, columns: &[(&str, ColumnType)]) -> store::Result<Self> {
+        let fields = columns
+            .iter()
+            .map(|(name, column_type)| {
+                match column_type {
+                    ColumnType::Text => {
+                        Type::primitive_type_builder(name, PhysicalType::BYTE_ARRAY)
+                            .with_converted_type(ConvertedType::UTF8)
+                    }
+                    ColumnType::Number => Type::primitive_type_builder(name, PhysicalType::INT64)
+                        .with_converted_type(ConvertedType::UINT_64),
+                    ColumnType::Bool => Type::primitive_type_builder(name, PhysicalType::BOOLEAN),
+                }
+                .with_repetition(Repetition::REQUIRED)
+                .build()
+                .map(Arc::new)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(parquet_error)?;
+        let schema = Type::group_type_builder(table)
+            .with_fields(fields)
+            .build()
+            .map_err(parquet_error)?;
+
+        Ok(ParquetWriter {
+            writer: SerializedFileWriter::new(
+                out,
+                Arc::new(schema),
+                Arc::new(WriterProperties::builder().build()),
+            )
+            .map_err(parquet_error)?,
+            columns: columns
+                .iter()
+                .map(|(_, column_type)| match column_type {
+                    ColumnType::Text => ColumnBuffer::Text(Vec::new()),
+                    ColumnType::Number => ColumnBuffer::Number(Vec::new()),
+                    ColumnType::Bool => ColumnBuffer::Bool(Vec::new()),
+                })
+                .collect(),
+            num_rows: 0,
+        })
+    }
+
+    fn write_row_group(&mut self) -> store::Result<()> {
+        if self.num_rows == 0 {
+            return Ok(());
+        }
+        let mut row_group = self.writer.next_row_group().map_err(parquet_error)?;
+        for column in &mut self.columns {
+            let mut column_writer = row_group
+                .next_column()
+                .map_err(parquet_error)?
+                .ok_or_else(|| StoreError::InternalError("Missing Parquet column.".into()))?;
+            match column {
+                ColumnBuffer::Text(values) => {
+                    column_writer
+                        .typed::<ByteArrayType>()
+                        .write_batch(values, None, None)
+                        .map_err(parquet_error)?;
+                    values.clear();
+                }
+                ColumnBuffer::Number(values) => {
+                    column_writer
+                        .typed::<Int64Type>()
+                        .write_batch(values, None, None)
+                        .map_err(parquet_error)?;
+                    values.clear();
+                }
+                ColumnBuffer::Bool(values) => {
+                    column_writer
+                        .typed::<BoolType>()
+                        .write_batch(values, None, None)
+                        .map_err(parquet_error)?;
+                    values.clear();
+                }
+            }
+            column_writer.close().map_err(parquet_error)?;
+        }
+        row_group.close().map_err(parquet_error)?;
+        self.num_rows = 0;
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> RowWriter for ParquetWriter<W> {
+    fn write_row(&mut self, row: &[Cell]) -> store::Result<()> {
+        // Validate the whole row first so that columns never get out of step
+        if row.len() != self.columns.len()
+            || self.columns.iter().zip(row).any(|(column, cell)| {
+                !matches!(
+                    (column, cell),
+                    (ColumnBuffer::Text(_), Cell::Text(_))
+                        | (ColumnBuffer::Number(_), Cell::Number(_))
+                        | (ColumnBuffer::Bool(_), Cell::Bool(_))
+                )
+            })
+        {
+            return Err(StoreError::InternalError(
+                "Row does not match the export columns.".into(),
+            ));
+        }
+
+        for (column, cell) in self.columns.iter_mut().zip(row) {
+            match (column, cell) {
+                (ColumnBuffer::Text(values), Cell::Text(value)) => {
+                    values.push(ByteArray::from(*value))
+                }
+                (ColumnBuffer::Number(values), Cell::Number(value)) => values.push(*value as i64),
+                (ColumnBuffer::Bool(values), Cell::Bool(value)) => values.push(*value),
+                _ => unreachable!(),
+            }
+        }
+        self.num_rows += 1;
+        if self.num_rows == ROW_GROUP_SIZE {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> store::Result<()> {
+        self.write_row_group()?;
+        self.writer.close().map_err(parquet_error)?;
+        Ok(())
+    }
+}
+
+fn parquet_error(err: parquet::errors::ParquetError) -> StoreError {
+    StoreError::InternalError(format!("Parquet failure: {}", err))
+}
+
+fn collection_name(collection: Collection) -> &'static str {
+    match collection {
+        Collection::Principal => "Principal",
+        Collection::PushSubscription => "PushSubscription",
+        Collection::Mail => "Email",
+        Collection::Mailbox => "Mailbox",
+        Collection::Thread => "Thread",
+        Collection::Identity => "Identity",
+        Collection::EmailSubmission => "EmailSubmission",
+        Collection::SieveScript => "SieveScript",
+        Collection::Annotation => "Annotation",
+        Collection::None => "None",
+    }
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains(|ch| matches!(ch, ',' | '"' | '\r' | '\n')) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    use super::{
+        Cell, CsvWriter, ParquetWriter, RowWriter, INDEX_COLUMNS, ROW_GROUP_SIZE, TERM_COLUMNS,
+    };
+
+    #[test]
+    fn export_csv() {
+        let mut bytes = Vec::new();
+        let mut writer = Box::new(CsvWriter::new(&mut bytes, TERM_COLUMNS).unwrap());
+        writer
+            .write_row(&[
+                Cell::Text("Email"),
+                Cell::Number(1),
+                Cell::Text("header:Subject"),
+                Cell::Text("hello, \"world\""),
+                Cell::Bool(false),
+            ])
+            .unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            concat!(
+                "collection,documentId,field,term,isStemmed\n",
+                "Email,1,header:Subject,\"hello, \"\"world\"\"\",false\n"
+            )
+        );
+    }
+
+    #[test]
+    fn export_parquet() {
+        let path = std::env::temp_dir().join("st_index_export.parquet");
+        let mut writer = Box::new(
+            ParquetWriter::new(File::create(&path).unwrap(), "indexes", INDEX_COLUMNS).unwrap(),
+        );
+        for document_id in 0..=ROW_GROUP_SIZE as u64 {
+            writer
+                .write_row(&[
+                    Cell::Text("Email"),
+                    Cell::Number(document_id),
+                    Cell::Text("receivedAt"),
+                    Cell::Number(document_id * 1000),
+                ])
+                .unwrap();
+        }
+        assert!(writer.write_row(&[Cell::Bool(true)]).is_err());
+        writer.finish().unwrap();
+
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.num_row_groups(), 2);
+        assert_eq!(
+            metadata.file_metadata().num_rows(),
+            ROW_GROUP_SIZE as i64 + 1
+        );
+        assert_eq!(
+            metadata
+                .file_metadata()
+                .schema_descr()
+                .columns()
+                .iter()
+                .map(|column| column.name())
+                .collect::<Vec<_>>(),
+            INDEX_COLUMNS
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,36 @@
+use std::path::PathBuf;
+
+use store::{
+    backup::{latest_snapshot, restore},
+    config::env_settings::EnvSettings,
+};
+
+pub fn main() {
+    // Read configuration parameters
+    let settings = EnvSettings::new();
+
+    let backup_path = PathBuf::from(
+        settings
+            .get("backup-path")
+            .expect("A valid 'backup-path' parameter."),
+    );
+    let snapshot_path = if let Some(snapshot) = settings.get("snapshot") {
+        let mut snapshot_path = backup_path.clone();
+        snapshot_path.push(store::backup::BACKUP_SNAPSHOTS_DIR);
+        snapshot_path.push(snapshot);
+        snapshot_path
+    } else {
+        latest_snapshot(&backup_path)
+            .expect("Failed to read backup directory")
+            .expect("No snapshots found in backup directory")
+    };
+
+    let raft_id = restore(&backup_path, &snapshot_path, &settings).expect("Failed to restore");
+
+    println!(
+        "Restored snapshot {} (Raft term {}, index {}).",
+        snapshot_path.display(),
+        raft_id.term,
+        raft_id.index
+    );
+}